@@ -0,0 +1,78 @@
+//! Compares the cost of processing ACKs with 4 MB in flight. Each ACK trims a segment from the
+//! beginning of the queue, appends a new segment to the end, and reads the first segment for a
+//! retransmission.
+
+use pcap2socks::cacher::Cacher;
+use std::time::{Duration, Instant};
+
+/// Represents the bytes in flight.
+const IN_FLIGHT: usize = 4 * 1024 * 1024;
+/// Represents the size of a segment.
+const SEGMENT_SIZE: usize = 1460;
+/// Represents the ACKs processed in a round.
+const ACKS: usize = 100_000;
+
+/// Processes ACKs on a queue which shifts its bytes on every ACK.
+fn bench_vec() -> Duration {
+    let segment = vec![0u8; SEGMENT_SIZE];
+    let mut queue = vec![0u8; IN_FLIGHT];
+
+    let instant = Instant::now();
+    for _ in 0..ACKS {
+        queue.drain(..SEGMENT_SIZE);
+        queue.extend_from_slice(&segment);
+        let payload = queue[..SEGMENT_SIZE].to_vec();
+        assert_eq!(payload.len(), SEGMENT_SIZE);
+    }
+
+    instant.elapsed()
+}
+
+/// Processes ACKs on a `Cacher`, reading all the bytes for a retransmission.
+fn bench_cacher_copy() -> Duration {
+    let segment = vec![0u8; SEGMENT_SIZE];
+    let mut cache = Cacher::with_capacity(u32::MAX - 1024, IN_FLIGHT);
+    cache.append(&vec![0u8; IN_FLIGHT]).unwrap();
+
+    let instant = Instant::now();
+    // Reading all the bytes copies the whole queue, fewer rounds are run
+    for _ in 0..ACKS / 100 {
+        cache.invalidate_to(cache.get_sequence().wrapping_add(SEGMENT_SIZE as u32));
+        cache.append(&segment).unwrap();
+        let payload = cache.get_all().unwrap();
+        assert_eq!(payload.len(), IN_FLIGHT);
+    }
+
+    instant.elapsed() * 100
+}
+
+/// Processes ACKs on a `Cacher`, reading the segment in place for a retransmission.
+fn bench_cacher_slices() -> Duration {
+    let segment = vec![0u8; SEGMENT_SIZE];
+    let mut cache = Cacher::with_capacity(u32::MAX - 1024, IN_FLIGHT);
+    cache.append(&vec![0u8; IN_FLIGHT]).unwrap();
+
+    let instant = Instant::now();
+    for _ in 0..ACKS {
+        cache.invalidate_to(cache.get_sequence().wrapping_add(SEGMENT_SIZE as u32));
+        cache.append(&segment).unwrap();
+        let (a, b) = cache.get_slices(0, SEGMENT_SIZE).unwrap();
+        assert_eq!(a.len() + b.len(), SEGMENT_SIZE);
+    }
+
+    instant.elapsed()
+}
+
+fn main() {
+    let report = |name: &str, duration: Duration| {
+        println!(
+            "{:<32} {:>10.1} ns/ACK",
+            name,
+            duration.as_nanos() as f64 / ACKS as f64
+        );
+    };
+
+    report("shifting queue", bench_vec());
+    report("ring buffer, copied reads", bench_cacher_copy());
+    report("ring buffer, in-place reads", bench_cacher_slices());
+}
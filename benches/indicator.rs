@@ -0,0 +1,104 @@
+//! Compares the cost of parsing and relaying UDP datagrams with 1400 Bytes of payload. Each
+//! datagram is copied out of the capture buffer, parsed, and its payload is written to the relay.
+
+use pcap2socks::packet::layer::payload::{Frame, FramePool};
+use pcap2socks::packet::Indicator;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Represents the size of the payload of a datagram.
+const PAYLOAD_SIZE: usize = 1400;
+/// Represents the datagrams processed in a round.
+const DATAGRAMS: usize = 1_000_000;
+
+/// Creates an Ethernet frame of an IPv4 UDP datagram.
+fn new_frame() -> Vec<u8> {
+    let mut frame = vec![0u8; 14 + 20 + 8 + PAYLOAD_SIZE];
+    // Ethernet
+    frame[0..6].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+    frame[6..12].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+    frame[12..14].copy_from_slice(&[0x08, 0x00]);
+    // IPv4
+    let total_length = (20 + 8 + PAYLOAD_SIZE) as u16;
+    frame[14] = 0x45;
+    frame[16..18].copy_from_slice(&total_length.to_be_bytes());
+    frame[22] = 64;
+    frame[23] = 17;
+    frame[26..30].copy_from_slice(&[10, 6, 0, 1]);
+    frame[30..34].copy_from_slice(&[1, 1, 1, 1]);
+    // UDP
+    let length = (8 + PAYLOAD_SIZE) as u16;
+    frame[34..36].copy_from_slice(&40000u16.to_be_bytes());
+    frame[36..38].copy_from_slice(&53u16.to_be_bytes());
+    frame[38..40].copy_from_slice(&length.to_be_bytes());
+
+    frame
+}
+
+/// Relays the payload of the datagram, like writing it to a socket.
+fn relay(indicator: &Indicator, buffer: &mut Vec<u8>) {
+    let udp = indicator.get_udp().unwrap();
+    buffer.clear();
+    buffer.extend_from_slice(udp.get_payload());
+    assert_eq!(buffer.len(), PAYLOAD_SIZE);
+}
+
+/// Processes datagrams copied into new frames, whose payloads are copied again in parsing.
+fn bench_copied() -> Duration {
+    let capture = new_frame();
+    let mut buffer = Vec::with_capacity(PAYLOAD_SIZE);
+
+    let instant = Instant::now();
+    for _ in 0..DATAGRAMS {
+        let frame = capture.to_vec();
+        let indicator = Indicator::from(&frame).unwrap();
+        relay(&indicator, &mut buffer);
+    }
+
+    instant.elapsed()
+}
+
+/// Processes datagrams copied into new shared frames, whose payloads refer to the frames.
+fn bench_shared() -> Duration {
+    let capture = new_frame();
+    let mut buffer = Vec::with_capacity(PAYLOAD_SIZE);
+
+    let instant = Instant::now();
+    for _ in 0..DATAGRAMS {
+        let frame: Frame = Arc::new(capture.to_vec());
+        let indicator = Indicator::from_shared(&frame).unwrap();
+        relay(&indicator, &mut buffer);
+    }
+
+    instant.elapsed()
+}
+
+/// Processes datagrams copied into pooled frames, whose payloads refer to the frames.
+fn bench_pooled() -> Duration {
+    let capture = new_frame();
+    let mut buffer = Vec::with_capacity(PAYLOAD_SIZE);
+    let mut pool = FramePool::new();
+
+    let instant = Instant::now();
+    for _ in 0..DATAGRAMS {
+        let frame = pool.copy_from(&capture);
+        let indicator = Indicator::from_shared(&frame).unwrap();
+        relay(&indicator, &mut buffer);
+    }
+
+    instant.elapsed()
+}
+
+fn main() {
+    let report = |name: &str, duration: Duration| {
+        println!(
+            "{:<32} {:>10.0} packets/s",
+            name,
+            DATAGRAMS as f64 / duration.as_secs_f64()
+        );
+    };
+
+    report("copied payloads", bench_copied());
+    report("shared payloads", bench_shared());
+    report("shared payloads, pooled frames", bench_pooled());
+}
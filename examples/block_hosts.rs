@@ -0,0 +1,101 @@
+//! Redirects traffic of a source to a SOCKS proxy like pcap2socks, except packets to the blocked
+//! destinations are dropped by a hook. Connections opened and closed are printed.
+//!
+//! Usage: `cargo run --example block_hosts -- <SOURCE> <PROXY> <DESTINATION>...`, like
+//! `cargo run --example block_hosts -- 10.6.0.1 127.0.0.1:1080 93.184.216.34`
+
+use pcap2socks::event::{CloseReason, Connection};
+use pcap2socks::hook::{Direction, Hook, HookAction};
+use pcap2socks::packet::Indicator;
+use pcap2socks::socks::{SocksConnector, SocksOption};
+use pcap2socks::Pcap2Socks;
+use std::collections::HashSet;
+use std::env;
+use std::net::{IpAddr, Ipv4Addr};
+use std::process;
+use std::sync::Arc;
+
+/// Represents a hook dropping packets from sources to the blocked destinations.
+struct BlockHook {
+    blocked: HashSet<Ipv4Addr>,
+}
+
+impl Hook for BlockHook {
+    fn on_packet(&self, indicator: &Indicator, direction: Direction) -> HookAction {
+        if direction != Direction::Received {
+            return HookAction::Continue;
+        }
+        match indicator.get_ipv4() {
+            Some(ipv4) if self.blocked.contains(&ipv4.get_dst()) => HookAction::Drop,
+            _ => HookAction::Continue,
+        }
+    }
+
+    fn on_connection_open(&self, connection: &Connection) {
+        println!(
+            "open {} {}:{} -> {} ({})",
+            connection.get_protocol(),
+            connection.get_client(),
+            connection.get_src_port(),
+            connection.get_dst(),
+            match connection.is_proxied() {
+                true => "proxy",
+                false => "direct",
+            }
+        );
+    }
+
+    fn on_connection_close(&self, connection: &Connection, reason: CloseReason) {
+        println!(
+            "close {} {}:{} -> {} for {}",
+            connection.get_protocol(),
+            connection.get_client(),
+            connection.get_src_port(),
+            connection.get_dst(),
+            reason
+        );
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("usage: block_hosts <SOURCE> <PROXY> <DESTINATION>...");
+        process::exit(1);
+    }
+    let src = match args[0].parse() {
+        Ok(src) => src,
+        Err(ref e) => {
+            eprintln!("parse source {}: {}", args[0], e);
+            process::exit(1);
+        }
+    };
+    let proxy = match args[1].parse() {
+        Ok(proxy) => SocksConnector::new(proxy, SocksOption::default()),
+        Err(ref e) => {
+            eprintln!("parse proxy {}: {}", args[1], e);
+            process::exit(1);
+        }
+    };
+    let mut blocked = HashSet::new();
+    for arg in &args[2..] {
+        match arg.parse() {
+            Ok(IpAddr::V4(ip_addr)) => {
+                blocked.insert(ip_addr);
+            }
+            _ => {
+                eprintln!("parse destination {}: not an IPv4 address", arg);
+                process::exit(1);
+            }
+        }
+    }
+    println!("Block {} destinations", blocked.len());
+
+    let result = Pcap2Socks::new(src, Arc::new(proxy))
+        .hook(Arc::new(BlockHook { blocked }))
+        .run();
+    if let Err(ref e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
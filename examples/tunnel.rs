@@ -0,0 +1,49 @@
+//! Companion server of the `tunnel` proxy. Every TCP connection from pcap2socks carries
+//! multiplexed streams, and the server connects to the destination of each stream directly.
+//!
+//! Usage: `cargo run --example tunnel -- 0.0.0.0:1082`
+
+use pcap2socks::socks::MuxSession;
+use std::env;
+use std::net::{SocketAddrV4, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// Represents the address the server listens on if no address is designated.
+const DEFAULT_LISTEN: &str = "0.0.0.0:1082";
+/// Represents the timeout of connections to destinations.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn main() {
+    let listen = env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_LISTEN.to_string());
+    let listener = match TcpListener::bind(&listen) {
+        Ok(listener) => listener,
+        Err(ref e) => {
+            eprintln!("listen on {}: {}", listen, e);
+            return;
+        }
+    };
+    println!("Listen on {}", listen);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                thread::spawn(move || {
+                    let peer = stream.peer_addr();
+                    let accept = Box::new(|dst: SocketAddrV4| {
+                        TcpStream::connect_timeout(&dst.into(), CONNECT_TIMEOUT)
+                    });
+                    if let Err(ref e) = MuxSession::serve(stream, accept) {
+                        match peer {
+                            Ok(peer) => eprintln!("tunnel {}: {}", peer, e),
+                            Err(_) => eprintln!("tunnel: {}", e),
+                        }
+                    }
+                });
+            }
+            Err(ref e) => eprintln!("accept: {}", e),
+        }
+    }
+}
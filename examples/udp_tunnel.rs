@@ -0,0 +1,135 @@
+//! Companion endpoint of the `tcp` UDP fallback. Every TCP connection tunneled through the proxy
+//! gets its own UDP socket, frames from the connection are sent to their destinations, and
+//! datagrams from peers are sent back in frames with their sources.
+//!
+//! Usage: `cargo run --example udp_tunnel -- 0.0.0.0:1081`
+
+use pcap2socks::socks::{new_frame, FrameDecoder};
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::{
+    Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+};
+use std::thread;
+
+/// Represents the address the endpoint listens on if no address is designated.
+const DEFAULT_LISTEN: &str = "0.0.0.0:1081";
+
+fn main() {
+    let listen = env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_LISTEN.to_string());
+    let listener = match TcpListener::bind(&listen) {
+        Ok(listener) => listener,
+        Err(ref e) => {
+            eprintln!("listen on {}: {}", listen, e);
+            return;
+        }
+    };
+    println!("Listen on {}", listen);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                thread::spawn(move || {
+                    let peer = stream.peer_addr();
+                    if let Err(ref e) = serve(stream) {
+                        match peer {
+                            Ok(peer) => eprintln!("tunnel {}: {}", peer, e),
+                            Err(_) => eprintln!("tunnel: {}", e),
+                        }
+                    }
+                });
+            }
+            Err(ref e) => eprintln!("accept: {}", e),
+        }
+    }
+}
+
+/// Relays frames of a tunnel until the connection is closed.
+fn serve(mut stream: TcpStream) -> io::Result<()> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+
+    // Peers to the tunnel
+    let socket_cloned = socket.try_clone()?;
+    let mut stream_cloned = stream.try_clone()?;
+    thread::spawn(move || {
+        let mut buffer = [0u8; u16::MAX as usize];
+        loop {
+            let (size, addr) = match socket_cloned.recv_from(&mut buffer) {
+                Ok(result) => result,
+                Err(_) => break,
+            };
+            let addr = match addr {
+                SocketAddr::V4(addr) => addr,
+                SocketAddr::V6(_) => continue,
+            };
+
+            let mut datagram = vec![0, 0, 0, 1];
+            datagram.extend_from_slice(&addr.ip().octets());
+            datagram.extend_from_slice(&addr.port().to_be_bytes());
+            datagram.extend_from_slice(&buffer[..size]);
+            if let Some(frame) = new_frame(&datagram) {
+                if stream_cloned.write_all(&frame).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    // Tunnel to peers
+    let mut decoder = FrameDecoder::new();
+    let mut buffer = [0u8; u16::MAX as usize];
+    loop {
+        let size = stream.read(&mut buffer)?;
+        if size == 0 {
+            return Ok(());
+        }
+        decoder.push(&buffer[..size]);
+
+        while let Some(datagram) = decoder.next_datagram()? {
+            let (addr, payload) = match parse_datagram(&datagram) {
+                Some(result) => result,
+                None => continue,
+            };
+            // Datagrams without payload to `0.0.0.0:0` are keepalives
+            if payload.is_empty()
+                && addr == SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+            {
+                continue;
+            }
+            let _ = socket.send_to(payload, addr);
+        }
+    }
+}
+
+/// Parses the destination and the payload of a SOCKS5 UDP datagram.
+fn parse_datagram(datagram: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    match *datagram.get(3)? {
+        // IPv4
+        1 if datagram.len() >= 10 => {
+            let addr = SocketAddrV4::new(
+                Ipv4Addr::new(datagram[4], datagram[5], datagram[6], datagram[7]),
+                u16::from_be_bytes([datagram[8], datagram[9]]),
+            );
+
+            Some((SocketAddr::V4(addr), &datagram[10..]))
+        }
+        // Domain
+        3 => {
+            let len = *datagram.get(4)? as usize;
+            if datagram.len() < 7 + len {
+                return None;
+            }
+            let domain = String::from_utf8_lossy(&datagram[5..5 + len]);
+            let port = u16::from_be_bytes([datagram[5 + len], datagram[6 + len]]);
+            let addr = (domain.as_ref(), port)
+                .to_socket_addrs()
+                .ok()?
+                .find(|addr| addr.is_ipv4())?;
+
+            Some((addr, &datagram[7 + len..]))
+        }
+        _ => None,
+    }
+}
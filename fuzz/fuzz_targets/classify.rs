@@ -0,0 +1,19 @@
+//! Sniffs arbitrary bytes as the first payload of HTTP and HTTPS connections. Payloads sniffed
+//! at once and sniffed in segments of a byte must be classified the same.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use pcap2socks::classify::Classifier;
+
+fuzz_target!(|data: &[u8]| {
+    for &port in &[80, 443] {
+        let mut classifier = Classifier::new(port);
+        classifier.sniff(data);
+
+        let mut segmented = Classifier::new(port);
+        for b in data {
+            segmented.sniff(&[*b]);
+        }
+        assert_eq!(segmented.get_class(), classifier.get_class());
+    }
+});
@@ -0,0 +1,21 @@
+//! Parses arbitrary bytes as a DHCP message, which is handled by a server. Messages parsed and
+//! their replies are serialized, which must be parsed into the same messages.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use pcap2socks::dhcp::{Message, Server};
+use std::net::Ipv4Addr;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(message) = Message::parse(data) {
+        // Round trip
+        let buffer = message.serialize();
+        assert_eq!(Message::parse(&buffer).as_ref(), Some(&message));
+
+        let ip_addr = Ipv4Addr::new(10, 6, 0, 254);
+        let mut server = Server::new(ip_addr, "10.6.0.0/24".parse().unwrap(), ip_addr);
+        if let Some(reply) = server.handle(&message) {
+            assert_eq!(Message::parse(&reply.serialize()), Some(reply));
+        }
+    }
+});
@@ -0,0 +1,20 @@
+//! Parses arbitrary bytes as a DNS query and truncates them as a DNS response. Truncated
+//! responses of queries and responses must fit in their sizes, or be the messages untouched.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use pcap2socks::dns::{self, Query};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(query) = Query::parse(data) {
+        let response = query.new_truncated_response(data);
+        assert!(response.len() <= data.len());
+        assert_eq!(&response[..2], &data[..2]);
+        assert_eq!(Query::parse(&response), None);
+    }
+
+    for &size in &[12, 512, 1232] {
+        let truncated = dns::truncate(data, size);
+        assert!(truncated == data || truncated.len() <= size);
+    }
+});
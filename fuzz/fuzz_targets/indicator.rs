@@ -0,0 +1,22 @@
+//! Parses arbitrary bytes as a frame. Frames parsed are briefed and converted into frames again,
+//! which must be parsed into the same frames of the sizes of their layers.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use pcap2socks::packet::Indicator;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(indicator) = Indicator::from(data) {
+        let _ = indicator.brief();
+
+        // Round trip
+        if let Ok(frame) = indicator.to_vec() {
+            assert_eq!(frame.len(), indicator.get_size());
+            let new_indicator = Indicator::from(&frame).expect("parse serialized frame");
+            assert_eq!(
+                new_indicator.to_vec().expect("serialize parsed frame"),
+                frame
+            );
+        }
+    }
+});
@@ -0,0 +1,35 @@
+//! Parses arbitrary bytes as a QUIC datagram. Long headers parsed are converted into bytes
+//! again, which must be the beginning of the datagram, and flows of them must match datagrams
+//! addressed with their connection IDs.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use pcap2socks::quic::{self, Flow, LongHeader};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(header) = quic::detect(data) {
+        assert_eq!(LongHeader::parse(data), Some(header));
+        assert!(header.is_initial());
+    }
+
+    if let Some(header) = LongHeader::parse(data) {
+        // Round trip
+        let dcid = header.get_dcid();
+        let scid = header.get_scid();
+        let mut buffer = vec![data[0]];
+        buffer.extend_from_slice(&header.get_version().to_be_bytes());
+        buffer.push(dcid.as_slice().len() as u8);
+        buffer.extend_from_slice(dcid.as_slice());
+        buffer.push(scid.as_slice().len() as u8);
+        buffer.extend_from_slice(scid.as_slice());
+        assert_eq!(&data[..buffer.len()], &buffer[..]);
+
+        let dst = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 443);
+        let mut flow = Flow::new(dst, &header);
+        flow.update(data);
+        let mut short = vec![0x41];
+        short.extend_from_slice(dcid.as_slice());
+        assert_eq!(flow.is_match(&short), !dcid.as_slice().is_empty());
+    }
+});
@@ -1,63 +1,1029 @@
+use crate::bench::{self, Mix};
+use crate::config::{self, ConfigError};
+use crate::congestion::Algorithm;
+use crate::event::{Format, Protocol};
+use crate::memory;
+use crate::packet::verify::VerifyMode;
+use crate::packet::{ChecksumMode, DEFAULT_REASSEMBLY_TIMEOUT};
+use crate::pcap::capture::DEFAULT_CAPTURE_FRAMES;
+use crate::pcap::chaos::Chaos;
+use crate::pcap::dump::Filter;
+use crate::pcap::filter::{Expression, FilterError};
+use crate::pcap::gateway::SpoofMac;
+use crate::pcap::trace::TraceRule;
+use crate::resolver::{Name, DEFAULT_RESOLVE_TTL};
+use crate::rule::{Access, AccessList, Action, Rewrite, Rewrites, Rule, Rules};
+use crate::selftest::{Level, DEFAULT_SELF_TEST_CONNECT};
+use crate::shaper::{ClientQuota, Quota};
+use crate::socks::{
+    AuthSource, HttpConnector, MuxConnector, Outbound, PortRange, ProxyChain, ProxyConnector,
+    ProxyGroup, ProxyPolicy, ResolvedProxy, SocksAuth, SocksConnector, SocksOption, UdpFallback,
+    Version, DEFAULT_PROXY_CHECK_INTERVAL, DEFAULT_SHARED_SOCKETS,
+};
+use crate::{
+    UdpNat, DEFAULT_CONNECT_RETRIES, DEFAULT_DELAYED_ACK, DEFAULT_MAX_CONNECTIONS,
+    DEFAULT_MAX_HALF_OPEN, DEFAULT_MIN_RTO, DEFAULT_PROXY_TIMEOUT, DEFAULT_SHUTDOWN_TIMEOUT,
+    DEFAULT_SYN_ACK_MAX_INTERVAL, DEFAULT_SYN_ACK_RETRIES, DEFAULT_UDP_PROBE_INTERVAL,
+    DEFAULT_UDP_TIMEOUT, MAX_RELAY_BUFFER_SIZE, MAX_SOURCES, MAX_TCP_BUFFER_SIZE, MAX_WORKERS,
+};
 use clap::{crate_description, crate_version, Clap};
+use ipnetwork::{IpNetworkError, Ipv4Network, Ipv6Network};
 use std::clone::Clone;
+use std::cmp::min;
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
-use std::net::{AddrParseError, Ipv4Addr, SocketAddrV4};
+use std::fs;
+use std::io;
+use std::net::{AddrParseError, Ipv4Addr, Ipv6Addr, SocketAddrV4};
+use std::path::PathBuf;
 use std::result;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-/// Represents the flags of the application.
-#[derive(Clap)]
+/// Represents the default format of logs.
+pub const DEFAULT_LOG_FORMAT: &str = "human";
+/// Represents the default MTU, which is used if the MTU of the device is unknown.
+pub const DEFAULT_MTU: u16 = 1400;
+/// Represents the default max number of frames sent in a batch.
+pub const DEFAULT_TX_BATCH: usize = 32;
+/// Represents the default max size in bytes of each packet traced.
+pub const DEFAULT_TRACE_MAX_SIZE: usize = 256;
+/// Represents the default mode of checksums.
+pub const DEFAULT_CHECKSUM_MODE: &str = "auto";
+/// Represents the default filtering of UDP datagrams to sources.
+pub const DEFAULT_UDP_NAT: &str = "full-cone";
+/// Represents the default fallback of UDP traffic when the proxy does not support UDP.
+pub const DEFAULT_UDP_FALLBACK: &str = "none";
+/// Represents the default policy of choosing a proxy of a group.
+pub const DEFAULT_PROXY_POLICY: &str = "failover";
+/// Represents the default source hardware address of frames sent to sources.
+pub const DEFAULT_SPOOF_MAC: &str = "own";
+/// Represents the default mode of verifying frames sent if it is enabled without a mode.
+pub const DEFAULT_VERIFY_TX: &str = "log";
+/// Represents the default congestion control.
+pub const DEFAULT_CC: &str = "cubic";
+/// Represents the default destination.
+pub const DEFAULT_DESTINATION: &str = "127.0.0.1:1080";
+/// Represents the default SOCKS version.
+pub const DEFAULT_SOCKS_VERSION: &str = "5";
+/// Represents the default action of destinations no rules match.
+pub const DEFAULT_ACTION: &str = "proxy";
+
+/// Represents the flags of the application. Options with a default value are `None` if they are
+/// not set, so flags can be merged with a configuration file.
+#[derive(Clap, Default)]
 #[clap(
     version = crate_version!(),
     about = crate_description!()
 )]
 pub struct Flags {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+    #[clap(
+        long,
+        short = "c",
+        about = "Configuration file in TOML, overridden by flags",
+        value_name = "FILE"
+    )]
+    pub config: Option<String>,
+    #[clap(
+        long = "print-config",
+        about = "Prints the effective configuration and exits"
+    )]
+    pub print_config: bool,
     #[clap(long, short, about = "Prints verbose information")]
     pub verbose: bool,
     #[clap(long, short = "V", about = "Prints vverbose information")]
     pub vverbose: bool,
+    #[clap(
+        long = "log-format",
+        about = "Format of logs",
+        value_name = "FORMAT",
+        possible_values = &["human", "json"]
+    )]
+    pub log_format: Option<String>,
+    #[clap(
+        long = "log-file",
+        about = "Log file written instead of the standard output",
+        value_name = "FILE"
+    )]
+    pub log_file: Option<String>,
     #[clap(
         long = "interface",
         short,
-        about = "Interface for listening",
-        value_name = "INTERFACE"
+        about = "Interface for listening, by the name, an address or the index in devices, can be repeated for bridging interfaces",
+        value_name = "INTERFACE",
+        number_of_values = 1
+    )]
+    pub inter: Vec<String>,
+    #[clap(
+        long,
+        about = "TUN device read instead of the interface",
+        value_name = "NAME"
+    )]
+    pub tun: Option<String>,
+    #[clap(
+        long,
+        about = "Capture filter in the syntax of pcap filters",
+        value_name = "FILTER"
+    )]
+    pub filter: Option<String>,
+    #[clap(
+        long = "input-file",
+        about = "Capture file read instead of the interface",
+        value_name = "FILE"
+    )]
+    pub input_file: Option<String>,
+    #[clap(
+        long = "output-file",
+        about = "Capture file written with frames sent",
+        value_name = "FILE"
+    )]
+    pub output_file: Option<String>,
+    #[clap(
+        long = "replay-timing",
+        about = "Reads the capture file in its original timing"
+    )]
+    pub replay_timing: bool,
+    #[clap(
+        long,
+        about = "Dump file of frames received and sent",
+        value_name = "FILE"
+    )]
+    pub dump: Option<String>,
+    #[clap(
+        long = "dump-max-size",
+        about = "Max size in MB of the dump file before rotation",
+        value_name = "VALUE"
+    )]
+    pub dump_max_size: Option<u64>,
+    #[clap(
+        long = "dump-filter",
+        about = "Filter of frames dumped by an address or a flow",
+        value_name = "FILTER"
+    )]
+    pub dump_filter: Option<String>,
+    #[clap(
+        long = "capture-on-error",
+        about = "Directory captures of connections closed for errors are written to",
+        value_name = "DIR"
+    )]
+    pub capture_on_error: Option<String>,
+    #[clap(
+        long = "capture-frames",
+        about = "Number of the last frames of each connection captured on errors",
+        value_name = "VALUE"
+    )]
+    pub capture_frames: Option<usize>,
+    #[clap(
+        long = "trace",
+        about = "Flow whose packets are logged in hex, like \"udp,10.6.0.2:3074\", can be repeated",
+        value_name = "RULE",
+        number_of_values = 1
+    )]
+    pub traces: Vec<String>,
+    #[clap(
+        long = "trace-max-size",
+        about = "Max size in bytes of each packet traced",
+        value_name = "VALUE"
+    )]
+    pub trace_max_size: Option<usize>,
+    #[clap(
+        long,
+        about = "MTU, default as the MTU of the device",
+        value_name = "VALUE"
+    )]
+    pub mtu: Option<u16>,
+    #[clap(long, about = "MSS for clamping", value_name = "VALUE")]
+    pub mss: Option<u16>,
+    #[clap(
+        long = "tx-batch",
+        about = "Max number of frames sent in a batch, 0 for disabled",
+        value_name = "VALUE"
+    )]
+    pub tx_batch: Option<usize>,
+    #[clap(
+        long = "min-rto",
+        about = "Minimum RTO in milliseconds",
+        value_name = "VALUE"
+    )]
+    pub min_rto: Option<u64>,
+    #[clap(
+        long,
+        about = "Congestion control",
+        value_name = "ALGORITHM",
+        possible_values = &["reno", "cubic"]
+    )]
+    pub cc: Option<String>,
+    #[clap(
+        long = "tcp-keepalive",
+        about = "Idle time in seconds before TCP keepalive probes",
+        value_name = "VALUE"
+    )]
+    pub tcp_keepalive: Option<u64>,
+    #[clap(
+        long = "no-delay",
+        about = "Sends small TCP segments immediately to sources and to the proxy"
+    )]
+    pub no_delay: bool,
+    #[clap(
+        long = "delayed-ack",
+        about = "Timeout in milliseconds of delayed TCP ACKs to sources",
+        value_name = "VALUE"
+    )]
+    pub delayed_ack: Option<u64>,
+    #[clap(
+        long = "recv-window",
+        about = "Receive window in bytes of TCP connections advertised to sources like 1M, default \
+                 as 768 segments",
+        value_name = "SIZE"
+    )]
+    pub recv_window: Option<String>,
+    #[clap(
+        long = "send-buffer",
+        about = "Max bytes of TCP connections not acknowledged by sources like 4M, default as 3072 \
+                 segments",
+        value_name = "SIZE"
+    )]
+    pub send_buffer: Option<String>,
+    #[clap(
+        long = "relay-buffer",
+        about = "Size in bytes of reads of TCP connections from the proxy like 64K, default as 48 \
+                 segments",
+        value_name = "SIZE"
+    )]
+    pub relay_buffer: Option<String>,
+    #[clap(
+        long = "max-memory",
+        about = "Memory budget in bytes of buffers like 256M, buffers shrink when it is tight",
+        value_name = "SIZE"
+    )]
+    pub max_memory: Option<String>,
+    #[clap(
+        long = "max-connections",
+        about = "Max TCP connections of all sources",
+        value_name = "VALUE"
+    )]
+    pub max_connections: Option<usize>,
+    #[clap(
+        long = "max-half-open",
+        about = "Max TCP connections of a source waiting for the handshake",
+        value_name = "VALUE"
+    )]
+    pub max_half_open: Option<usize>,
+    #[clap(
+        long = "syn-ack-retries",
+        about = "Retransmissions of TCP ACK/SYNs before the handshake times out",
+        value_name = "VALUE"
+    )]
+    pub syn_ack_retries: Option<usize>,
+    #[clap(
+        long = "syn-ack-max-interval",
+        about = "Max interval in seconds between retransmissions of TCP ACK/SYNs",
+        value_name = "VALUE"
+    )]
+    pub syn_ack_max_interval: Option<u64>,
+    #[clap(
+        long = "evict-idle",
+        about = "Closes the least recently active TCP connection when connections are full"
+    )]
+    pub evict_idle: bool,
+    #[clap(
+        long = "pickup-established",
+        about = "TCP connections picked up mid-stream, like tcp:22, can be repeated",
+        value_name = "RULE",
+        number_of_values = 1
+    )]
+    pub pickup_established: Vec<String>,
+    #[clap(
+        long = "proxy-timeout",
+        about = "Timeout in seconds of the handshake with the proxy",
+        value_name = "VALUE"
+    )]
+    pub proxy_timeout: Option<u64>,
+    #[clap(
+        long = "relay-timeout",
+        about = "Timeout in seconds of inactive TCP connections to the proxy",
+        value_name = "VALUE"
+    )]
+    pub relay_timeout: Option<u64>,
+    #[clap(
+        long = "relay-stale",
+        about = "Timeout in seconds of TCP connections whose writes to the proxy are not drained",
+        value_name = "VALUE"
+    )]
+    pub relay_stale: Option<u64>,
+    #[clap(
+        long = "linger",
+        about = "Time in seconds TCP connections to the proxy closed for errors are read until their ends, 0 resets them",
+        value_name = "VALUE"
+    )]
+    pub linger: Option<u64>,
+    #[clap(
+        long = "connect-retries",
+        about = "Max retries of a TCP connection when the proxy is unreachable",
+        value_name = "VALUE"
+    )]
+    pub connect_retries: Option<usize>,
+    #[clap(
+        long = "proxy-pool",
+        about = "Max number of idle connections pooled to each proxy",
+        value_name = "VALUE"
+    )]
+    pub proxy_pool: Option<usize>,
+    #[clap(
+        long = "outbound",
+        about = "Local address or interface of sockets to the proxy",
+        value_name = "ADDRESS"
+    )]
+    pub outbound: Option<String>,
+    #[clap(
+        long = "local-port-range",
+        about = "Range of local ports of sockets to the proxy",
+        value_name = "RANGE"
+    )]
+    pub local_port_range: Option<String>,
+    #[clap(
+        long = "nat-ports",
+        about = "Range of translated ports of flows of sources through the proxy",
+        value_name = "RANGE"
+    )]
+    pub nat_ports: Option<String>,
+    #[clap(
+        long = "udp-timeout",
+        about = "Timeout in seconds of idle UDP associations",
+        value_name = "VALUE"
+    )]
+    pub udp_timeout: Option<u64>,
+    #[clap(
+        long = "udp-keepalive",
+        about = "Interval in seconds of keepalives of SOCKS UDP associations",
+        value_name = "VALUE"
+    )]
+    pub udp_keepalive: Option<u64>,
+    #[clap(
+        long = "udp-assoc-strict",
+        about = "Closes SOCKS UDP associations once their control connections are closed"
+    )]
+    pub udp_assoc_strict: bool,
+    #[clap(
+        long = "udp-probe",
+        about = "Endpoint of liveness probes through idle relays of SOCKS UDP associations",
+        value_name = "ADDRESS"
+    )]
+    pub udp_probe: Option<String>,
+    #[clap(
+        long = "udp-probe-interval",
+        about = "Interval in seconds of liveness probes through idle relays",
+        value_name = "VALUE"
+    )]
+    pub udp_probe_interval: Option<u64>,
+    #[clap(
+        long = "udp-summary",
+        about = "Min bytes of UDP associations whose summaries are logged when they close",
+        value_name = "VALUE"
+    )]
+    pub udp_summary: Option<u64>,
+    #[clap(
+        long = "udp-nat",
+        about = "Filtering of UDP datagrams to sources from peers",
+        value_name = "TYPE",
+        possible_values = &["full-cone", "restricted"]
+    )]
+    pub udp_nat: Option<String>,
+    #[clap(
+        long = "udp-fallback",
+        about = "Fallback of UDP traffic when the proxy does not support UDP ASSOCIATE",
+        value_name = "TYPE",
+        possible_values = &["none", "drop", "tcp"]
+    )]
+    pub udp_fallback: Option<String>,
+    #[clap(
+        long = "udp-tunnel",
+        about = "Companion endpoint UDP traffic is tunneled to over TCP in the TCP fallback",
+        value_name = "ADDRESS"
+    )]
+    pub udp_tunnel: Option<String>,
+    #[clap(long = "no-udp-frag", about = "Drops fragmented SOCKS5 UDP datagrams")]
+    pub no_udp_frag: bool,
+    #[clap(
+        long = "udp-pmtu",
+        about = "Probes the path MTU to the relay of UDP associations"
+    )]
+    pub udp_pmtu: bool,
+    #[clap(
+        long = "udp-send-frag",
+        about = "Sends UDP datagrams larger than the path MTU to the relay in SOCKS5 fragments"
+    )]
+    pub udp_send_frag: bool,
+    #[clap(
+        long = "udp-sockets",
+        about = "Local UDP sockets of each address family shared by UDP associations",
+        value_name = "VALUE"
+    )]
+    pub udp_sockets: Option<usize>,
+    #[clap(
+        long = "lan-passthrough",
+        about = "Passes broadcast and multicast UDP datagrams through to the LAN"
+    )]
+    pub lan_passthrough: bool,
+    #[clap(
+        long = "state-file",
+        about = "File UDP associations are kept in across restarts",
+        value_name = "FILE"
+    )]
+    pub state_file: Option<String>,
+    #[clap(
+        long = "reassembly-timeout",
+        about = "Timeout in seconds of the IPv4 reassembly",
+        value_name = "VALUE"
+    )]
+    pub reassembly_timeout: Option<u64>,
+    #[clap(
+        long = "checksum-mode",
+        about = "Mode of TCP and UDP checksums from sources",
+        value_name = "MODE",
+        possible_values = &["auto", "strict", "fix", "ignore"]
+    )]
+    pub checksum_mode: Option<String>,
+    #[clap(
+        long = "shutdown-timeout",
+        about = "Timeout in seconds of draining TCP connections in shutting down",
+        value_name = "VALUE"
+    )]
+    pub shutdown_timeout: Option<u64>,
+    #[clap(
+        long = "limit-up",
+        about = "Rate limit in bytes per second from all the sources, with a suffix K, M or G",
+        value_name = "RATE"
+    )]
+    pub limit_up: Option<String>,
+    #[clap(
+        long = "limit-down",
+        about = "Rate limit in bytes per second to all the sources, with a suffix K, M or G",
+        value_name = "RATE"
+    )]
+    pub limit_down: Option<String>,
+    #[clap(
+        long = "limit-per-client",
+        about = "Rate limit in bytes per second of each source in either direction",
+        value_name = "RATE"
+    )]
+    pub limit_per_client: Option<String>,
+    #[clap(
+        long = "client-quota",
+        about = "Quota of a client or a network like 10.6.0.2=50mbit,20conns, can be repeated",
+        value_name = "QUOTA",
+        number_of_values = 1
+    )]
+    pub client_quotas: Vec<String>,
+    #[clap(
+        long = "client-quota-default",
+        about = "Quota of clients no quotas match, default as unlimited",
+        value_name = "QUOTA"
+    )]
+    pub client_quota_default: Option<String>,
+    #[clap(
+        long,
+        about = "Number of workers processing packets, default as the number of CPUs",
+        value_name = "VALUE"
+    )]
+    pub workers: Option<usize>,
+    #[clap(
+        long = "arp-interval",
+        about = "Interval in seconds of gratuitous ARP announcements",
+        value_name = "VALUE"
+    )]
+    pub arp_interval: Option<u64>,
+    #[clap(
+        long = "device-retry",
+        about = "Timeout in seconds of reopening the device after it is lost",
+        value_name = "VALUE"
+    )]
+    pub device_retry: Option<u64>,
+    #[clap(
+        long = "run-as",
+        about = "User to run as after the device is opened on Unix",
+        value_name = "USER"
+    )]
+    pub run_as: Option<String>,
+    #[clap(
+        long = "keep-privileges",
+        about = "Keep the privileges of capturing after the device is opened"
+    )]
+    pub keep_privileges: bool,
+    #[clap(
+        long,
+        about = "Impairment of frames sent for testing, like \"delay=50ms,jitter=10ms,loss=1%,reorder=0.5%,seed=1\"",
+        value_name = "SETTINGS"
+    )]
+    pub chaos: Option<String>,
+    #[clap(
+        long = "spoof-mac",
+        about = "Source MAC of frames sent to sources, \"own\", \"gateway\" or a MAC",
+        value_name = "MODE"
+    )]
+    pub spoof_mac: Option<String>,
+    #[clap(
+        long = "verify-tx",
+        about = "Verifies frames sent by parsing them again for debugging, \"log\" or \"fatal\"",
+        value_name = "MODE",
+        require_equals = true
+    )]
+    pub verify_tx: Option<Option<String>>,
+    #[clap(long, about = "Leases source addresses by DHCP")]
+    pub dhcp: bool,
+    #[clap(
+        long = "dhcp-force",
+        about = "Leases source addresses even if another DHCP server is present"
+    )]
+    pub dhcp_force: bool,
+    #[clap(long, about = "Answers UPnP IGD and NAT-PMP requests of port mappings")]
+    pub igd: bool,
+    #[clap(
+        long = "test-responder",
+        about = "Port of the published address echoing UDP and TCP in local without the proxy, test it first and then an external echo through the proxy to tell if the proxy breaks the path",
+        value_name = "PORT"
+    )]
+    pub test_responder: Option<u16>,
+    #[clap(
+        long = "self-test",
+        about = "Checks the device and the proxy thoroughly before starting"
+    )]
+    pub self_test: bool,
+    #[clap(
+        long = "self-test-connect",
+        about = "Endpoint connected through the proxy in the self-test",
+        value_name = "ADDRESS"
+    )]
+    pub self_test_connect: Option<String>,
+    #[clap(
+        long = "self-test-echo",
+        about = "UDP echo server datagrams are relayed to in the self-test",
+        value_name = "ADDRESS"
+    )]
+    pub self_test_echo: Option<String>,
+    #[clap(
+        long,
+        short,
+        about = "ARP publishing address or CIDR",
+        value_name = "ADDRESS"
     )]
-    pub inter: Option<String>,
-    #[clap(long, about = "MTU", value_name = "VALUE", default_value = "1400")]
-    pub mtu: u16,
-    #[clap(long, short, about = "ARP publishing address", value_name = "ADDRESS")]
     pub publish: Option<String>,
-    #[clap(long = "source", short, about = "Source", value_name = "ADDRESS")]
-    pub src: String,
+    #[clap(
+        long = "force-publish",
+        about = "Publishes the address even if another host owns it"
+    )]
+    pub force_publish: bool,
+    #[clap(
+        long = "publish6",
+        about = "NDP publishing IPv6 address",
+        value_name = "ADDRESS"
+    )]
+    pub publish6: Option<String>,
+    #[clap(
+        long = "ipv6-ra",
+        about = "Prefix of IPv6 router advertisements, like \"fd00::/64\"",
+        value_name = "PREFIX"
+    )]
+    pub ipv6_ra: Option<String>,
+    #[clap(
+        long = "source",
+        short,
+        about = "Source address or CIDR",
+        value_name = "ADDRESS"
+    )]
+    pub src: Option<String>,
     #[clap(
         long = "destination",
         short,
-        about = "Destination",
+        about = "Destination, can be repeated for a group of proxies",
         value_name = "ADDRESS",
-        default_value = "127.0.0.1:1080"
+        number_of_values = 1
+    )]
+    pub dst: Vec<String>,
+    #[clap(
+        long = "socks-version",
+        about = "SOCKS version",
+        value_name = "VERSION",
+        possible_values = &["4", "4a", "5"]
+    )]
+    pub socks_version: Option<String>,
+    #[clap(long, about = "Username of the SOCKS proxy", value_name = "USERNAME")]
+    pub username: Option<String>,
+    #[clap(
+        long,
+        about = "Password of the SOCKS proxy",
+        value_name = "PASSWORD",
+        requires = "username"
+    )]
+    pub password: Option<String>,
+    #[clap(
+        long = "auth-file",
+        about = "File containing the username and the password of the SOCKS proxy",
+        value_name = "FILE"
+    )]
+    pub auth_file: Option<String>,
+    #[clap(
+        long,
+        about = "Upstream proxy, overrides the destination",
+        value_name = "URL"
+    )]
+    pub proxy: Option<String>,
+    #[clap(
+        long = "proxy-policy",
+        about = "Policy of choosing a proxy of a group for new connections",
+        value_name = "POLICY",
+        possible_values = &["failover", "round-robin"]
+    )]
+    pub proxy_policy: Option<String>,
+    #[clap(
+        long = "proxy-check-interval",
+        about = "Interval in seconds of health checks of proxies in a group",
+        value_name = "VALUE"
+    )]
+    pub proxy_check_interval: Option<u64>,
+    #[clap(
+        long = "resolve-ttl",
+        about = "Min time in seconds answers of host names of proxies and rules are cached",
+        value_name = "VALUE"
+    )]
+    pub resolve_ttl: Option<u64>,
+    #[clap(
+        long = "rule",
+        about = "Rule of destinations, can be repeated",
+        value_name = "RULE",
+        number_of_values = 1
+    )]
+    pub rules: Vec<String>,
+    #[clap(long = "rules-file", about = "File of rules", value_name = "FILE")]
+    pub rules_file: Option<String>,
+    #[clap(
+        long = "default-action",
+        about = "Action of destinations no rules match",
+        value_name = "ACTION",
+        possible_values = &["proxy", "direct", "reject"]
+    )]
+    pub default_action: Option<String>,
+    #[clap(
+        long = "direct-private",
+        about = "Sends traffic to private and link-local networks directly"
+    )]
+    pub direct_private: bool,
+    #[clap(
+        long = "allow",
+        about = "Relayed traffic allowed, like udp:3074-3658, can be repeated",
+        value_name = "RULE",
+        number_of_values = 1
+    )]
+    pub allow: Vec<String>,
+    #[clap(
+        long = "deny",
+        about = "Relayed traffic denied, like tcp:25, can be repeated",
+        value_name = "RULE",
+        number_of_values = 1
+    )]
+    pub deny: Vec<String>,
+    #[clap(
+        long = "deny-silent",
+        about = "Drops denied traffic silently instead of answering it"
+    )]
+    pub deny_silent: bool,
+    #[clap(
+        long = "blocklist",
+        about = "Blocklist file of destinations, which is reloaded when it changes",
+        value_name = "FILE"
+    )]
+    pub blocklist: Option<String>,
+    #[clap(
+        long = "rewrite",
+        about = "Rewrites destinations, like \"tcp,203.0.113.10:443 -> 198.51.100.7:8443\", can be repeated",
+        value_name = "RULE",
+        number_of_values = 1
+    )]
+    pub rewrites: Vec<String>,
+    #[clap(
+        long = "redirect-dns",
+        about = "Redirects DNS queries to the resolver through the proxy",
+        value_name = "ADDRESS"
+    )]
+    pub redirect_dns: Option<String>,
+    #[clap(
+        long = "bind-ports",
+        about = "Ports listening for inbound connections through the proxy",
+        value_name = "PORTS"
+    )]
+    pub bind_ports: Option<String>,
+    #[clap(
+        long,
+        about = "Address serving metrics over HTTP",
+        value_name = "ADDRESS"
     )]
-    pub dst: String,
+    pub metrics: Option<String>,
+    #[clap(
+        long = "stats-interval",
+        about = "Interval in seconds of printing statistics",
+        value_name = "VALUE"
+    )]
+    pub stats_interval: Option<u64>,
+    #[clap(
+        long,
+        about = "Counts bytes of TCP connections by ports and names of services"
+    )]
+    pub classify: bool,
 }
 
-/// Parses the arguments.
-pub fn parse() -> Flags {
-    Flags::parse()
+/// Represents the subcommands of the application.
+#[derive(Clap, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Command {
+    #[clap(about = "Lists the interfaces which can be captured and exits")]
+    Devices,
+    #[clap(about = "Benchmarks the parse and serialize pipeline on synthetic traffic and exits")]
+    Bench(BenchFlags),
+}
+
+/// Represents the flags of the bench subcommand.
+#[derive(Clap, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BenchFlags {
+    #[clap(long, about = "Number of packets generated", value_name = "VALUE")]
+    pub packets: Option<usize>,
+    #[clap(long, about = "Seed of the traffic generator", value_name = "VALUE")]
+    pub seed: Option<u64>,
+    #[clap(
+        long = "tcp-percent",
+        about = "Percentage of TCP segments, the others are UDP datagrams",
+        value_name = "VALUE"
+    )]
+    pub tcp_percent: Option<u8>,
+    #[clap(
+        long = "min-size",
+        about = "Min size of payloads",
+        value_name = "VALUE"
+    )]
+    pub min_size: Option<usize>,
+    #[clap(
+        long = "max-size",
+        about = "Max size of payloads",
+        value_name = "VALUE"
+    )]
+    pub max_size: Option<usize>,
+    #[clap(
+        long = "fragment-percent",
+        about = "Percentage of packets sent in IPv4 fragments",
+        value_name = "VALUE"
+    )]
+    pub fragment_percent: Option<u8>,
+    #[clap(long, about = "Verifies checksums of packets in a separate stage")]
+    pub checksum: bool,
+}
+
+impl BenchFlags {
+    /// Get the number of packets generated.
+    pub fn get_packets(&self) -> usize {
+        self.packets.unwrap_or(bench::DEFAULT_PACKETS)
+    }
+
+    /// Get the seed of the traffic generator.
+    pub fn get_seed(&self) -> u64 {
+        self.seed.unwrap_or(bench::DEFAULT_SEED)
+    }
+
+    /// Validates the flags into the mix of the traffic.
+    pub fn to_mix(&self) -> result::Result<Mix, ParseError> {
+        let default = Mix::default();
+        let mix = Mix {
+            tcp_percent: self.tcp_percent.unwrap_or(default.tcp_percent),
+            min_size: self.min_size.unwrap_or(default.min_size),
+            max_size: self.max_size.unwrap_or(default.max_size),
+            fragment_percent: self.fragment_percent.unwrap_or(default.fragment_percent),
+        };
+        if mix.tcp_percent > 100 {
+            return Err(ParseError::OutOfRangeError("TCP percent", "[0, 100]"));
+        }
+        if mix.fragment_percent > 100 {
+            return Err(ParseError::OutOfRangeError("fragment percent", "[0, 100]"));
+        }
+        if mix.max_size > bench::MAX_PAYLOAD_SIZE {
+            return Err(ParseError::OutOfRangeError("max size", "[0, 65000]"));
+        }
+        if mix.min_size > mix.max_size {
+            return Err(ParseError::OutOfRangeError("min size", "[0, max size]"));
+        }
+        let packets = self.get_packets();
+        if packets == 0 || packets > 100_000_000 {
+            return Err(ParseError::OutOfRangeError("packets", "[1, 100000000]"));
+        }
+
+        Ok(mix)
+    }
+}
+
+/// Parses the arguments, and merges the configuration file if there is one.
+pub fn parse() -> result::Result<Flags, ParseError> {
+    let mut flags = Flags::parse();
+    if let Some(ref path) = flags.config {
+        let file = config::read(&PathBuf::from(path))?;
+        flags.merge(file);
+    }
+
+    Ok(flags)
+}
+
+impl Flags {
+    /// Merges the flags of a configuration file, where the flags set are kept. Switches are set if
+    /// they are set in either, and rules are evaluated before the rules of the file.
+    pub fn merge(&mut self, file: Flags) {
+        self.verbose |= file.verbose;
+        self.vverbose |= file.vverbose;
+        self.log_format = self.log_format.take().or(file.log_format);
+        self.log_file = self.log_file.take().or(file.log_file);
+        if self.inter.is_empty() {
+            self.inter = file.inter;
+        }
+        self.tun = self.tun.take().or(file.tun);
+        self.filter = self.filter.take().or(file.filter);
+        self.input_file = self.input_file.take().or(file.input_file);
+        self.output_file = self.output_file.take().or(file.output_file);
+        self.replay_timing |= file.replay_timing;
+        self.dump = self.dump.take().or(file.dump);
+        self.dump_max_size = self.dump_max_size.or(file.dump_max_size);
+        self.dump_filter = self.dump_filter.take().or(file.dump_filter);
+        self.capture_on_error = self.capture_on_error.take().or(file.capture_on_error);
+        self.capture_frames = self.capture_frames.or(file.capture_frames);
+        self.traces.extend(file.traces);
+        self.trace_max_size = self.trace_max_size.or(file.trace_max_size);
+        self.mtu = self.mtu.or(file.mtu);
+        self.mss = self.mss.or(file.mss);
+        self.tx_batch = self.tx_batch.or(file.tx_batch);
+        self.min_rto = self.min_rto.or(file.min_rto);
+        self.cc = self.cc.take().or(file.cc);
+        self.tcp_keepalive = self.tcp_keepalive.or(file.tcp_keepalive);
+        self.no_delay |= file.no_delay;
+        self.delayed_ack = self.delayed_ack.or(file.delayed_ack);
+        self.recv_window = self.recv_window.take().or(file.recv_window);
+        self.send_buffer = self.send_buffer.take().or(file.send_buffer);
+        self.relay_buffer = self.relay_buffer.take().or(file.relay_buffer);
+        self.max_memory = self.max_memory.take().or(file.max_memory);
+        self.max_connections = self.max_connections.or(file.max_connections);
+        self.max_half_open = self.max_half_open.or(file.max_half_open);
+        self.syn_ack_retries = self.syn_ack_retries.or(file.syn_ack_retries);
+        self.syn_ack_max_interval = self.syn_ack_max_interval.or(file.syn_ack_max_interval);
+        self.evict_idle |= file.evict_idle;
+        self.pickup_established.extend(file.pickup_established);
+        self.proxy_timeout = self.proxy_timeout.or(file.proxy_timeout);
+        self.relay_timeout = self.relay_timeout.or(file.relay_timeout);
+        self.relay_stale = self.relay_stale.or(file.relay_stale);
+        self.linger = self.linger.or(file.linger);
+        self.connect_retries = self.connect_retries.or(file.connect_retries);
+        self.proxy_pool = self.proxy_pool.or(file.proxy_pool);
+        self.outbound = self.outbound.take().or(file.outbound);
+        self.local_port_range = self.local_port_range.take().or(file.local_port_range);
+        self.nat_ports = self.nat_ports.take().or(file.nat_ports);
+        self.udp_timeout = self.udp_timeout.or(file.udp_timeout);
+        self.udp_keepalive = self.udp_keepalive.or(file.udp_keepalive);
+        self.udp_assoc_strict |= file.udp_assoc_strict;
+        self.udp_probe = self.udp_probe.take().or(file.udp_probe);
+        self.udp_probe_interval = self.udp_probe_interval.or(file.udp_probe_interval);
+        self.udp_summary = self.udp_summary.or(file.udp_summary);
+        self.udp_nat = self.udp_nat.take().or(file.udp_nat);
+        self.udp_fallback = self.udp_fallback.take().or(file.udp_fallback);
+        self.udp_tunnel = self.udp_tunnel.take().or(file.udp_tunnel);
+        self.no_udp_frag |= file.no_udp_frag;
+        self.udp_pmtu |= file.udp_pmtu;
+        self.udp_send_frag |= file.udp_send_frag;
+        self.udp_sockets = self.udp_sockets.or(file.udp_sockets);
+        self.lan_passthrough |= file.lan_passthrough;
+        self.state_file = self.state_file.take().or(file.state_file);
+        self.reassembly_timeout = self.reassembly_timeout.or(file.reassembly_timeout);
+        self.checksum_mode = self.checksum_mode.take().or(file.checksum_mode);
+        self.shutdown_timeout = self.shutdown_timeout.or(file.shutdown_timeout);
+        self.limit_up = self.limit_up.take().or(file.limit_up);
+        self.limit_down = self.limit_down.take().or(file.limit_down);
+        self.limit_per_client = self.limit_per_client.take().or(file.limit_per_client);
+        self.client_quotas.extend(file.client_quotas);
+        self.client_quota_default = self
+            .client_quota_default
+            .take()
+            .or(file.client_quota_default);
+        self.workers = self.workers.or(file.workers);
+        self.arp_interval = self.arp_interval.or(file.arp_interval);
+        self.device_retry = self.device_retry.or(file.device_retry);
+        self.run_as = self.run_as.take().or(file.run_as);
+        self.keep_privileges |= file.keep_privileges;
+        self.chaos = self.chaos.take().or(file.chaos);
+        self.spoof_mac = self.spoof_mac.take().or(file.spoof_mac);
+        self.verify_tx = self.verify_tx.take().or(file.verify_tx);
+        self.dhcp |= file.dhcp;
+        self.dhcp_force |= file.dhcp_force;
+        self.igd |= file.igd;
+        self.test_responder = self.test_responder.or(file.test_responder);
+        self.self_test |= file.self_test;
+        self.self_test_connect = self.self_test_connect.take().or(file.self_test_connect);
+        self.self_test_echo = self.self_test_echo.take().or(file.self_test_echo);
+        self.publish = self.publish.take().or(file.publish);
+        self.force_publish |= file.force_publish;
+        self.publish6 = self.publish6.take().or(file.publish6);
+        self.ipv6_ra = self.ipv6_ra.take().or(file.ipv6_ra);
+        self.src = self.src.take().or(file.src);
+        if self.dst.is_empty() {
+            self.dst = file.dst;
+        }
+        self.socks_version = self.socks_version.take().or(file.socks_version);
+        self.username = self.username.take().or(file.username);
+        self.password = self.password.take().or(file.password);
+        self.auth_file = self.auth_file.take().or(file.auth_file);
+        self.proxy = self.proxy.take().or(file.proxy);
+        self.proxy_policy = self.proxy_policy.take().or(file.proxy_policy);
+        self.proxy_check_interval = self.proxy_check_interval.or(file.proxy_check_interval);
+        self.resolve_ttl = self.resolve_ttl.or(file.resolve_ttl);
+        self.rules.extend(file.rules);
+        self.rules_file = self.rules_file.take().or(file.rules_file);
+        self.default_action = self.default_action.take().or(file.default_action);
+        self.direct_private |= file.direct_private;
+        self.allow.extend(file.allow);
+        self.deny.extend(file.deny);
+        self.deny_silent |= file.deny_silent;
+        self.blocklist = self.blocklist.take().or(file.blocklist);
+        self.rewrites.extend(file.rewrites);
+        self.redirect_dns = self.redirect_dns.take().or(file.redirect_dns);
+        self.bind_ports = self.bind_ports.take().or(file.bind_ports);
+        self.metrics = self.metrics.take().or(file.metrics);
+        self.stats_interval = self.stats_interval.or(file.stats_interval);
+        self.classify |= file.classify;
+    }
 }
 
 /// Represents an error when parse arguments.
 #[derive(Debug)]
 pub enum ParseError {
     AddrParseError(AddrParseError),
+    NetworkParseError(IpNetworkError),
     OutOfRangeError(&'static str, &'static str),
+    RequirementError(&'static str, &'static str),
+    ConflictError(&'static str, &'static str),
+    ProxyParseError(String),
+    ResolveError(String, io::Error),
+    RuleParseError(String),
+    AccessParseError(String),
+    RewriteParseError(String),
+    RateParseError(String),
+    SizeParseError(String),
+    OutboundParseError(String),
+    PortRangeParseError(String),
+    ChaosParseError(String),
+    SpoofMacParseError(String),
+    VerifyModeParseError(String),
+    QuotaParseError(String),
+    FilterParseError(String),
+    TraceParseError(String),
+    ExpressionParseError(FilterError),
+    ConfigParseError(ConfigError),
+    IoError(io::Error),
 }
 
 impl Display for ParseError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match &self {
             ParseError::AddrParseError(ref e) => write!(f, "parse: {}", e),
+            ParseError::NetworkParseError(ref e) => write!(f, "parse: {}", e),
             ParseError::OutOfRangeError(ref value, ref range) => {
                 write!(f, "parse: {} is out of range {}", value, range)
             }
+            ParseError::RequirementError(ref value, ref requirement) => {
+                write!(f, "parse: {} requires {}", value, requirement)
+            }
+            ParseError::ConflictError(ref value, ref conflict) => {
+                write!(f, "parse: {} conflicts with {}", value, conflict)
+            }
+            ParseError::ProxyParseError(ref s) => write!(f, "parse: invalid proxy {}", s),
+            ParseError::ResolveError(ref s, ref e) => write!(f, "parse: resolve {}: {}", s, e),
+            ParseError::RuleParseError(ref s) => write!(f, "parse: invalid rule {}", s),
+            ParseError::AccessParseError(ref s) => {
+                write!(f, "parse: invalid access rule {}", s)
+            }
+            ParseError::RewriteParseError(ref s) => {
+                write!(f, "parse: invalid rewrite rule {}", s)
+            }
+            ParseError::RateParseError(ref s) => write!(f, "parse: invalid rate {}", s),
+            ParseError::SizeParseError(ref s) => write!(f, "parse: invalid size {}", s),
+            ParseError::OutboundParseError(ref s) => write!(f, "parse: invalid outbound {}", s),
+            ParseError::PortRangeParseError(ref s) => {
+                write!(f, "parse: invalid local port range {}", s)
+            }
+            ParseError::ChaosParseError(ref s) => write!(f, "parse: invalid chaos {}", s),
+            ParseError::SpoofMacParseError(ref s) => {
+                write!(f, "parse: invalid spoof MAC {}", s)
+            }
+            ParseError::VerifyModeParseError(ref s) => {
+                write!(f, "parse: invalid verify mode {}", s)
+            }
+            ParseError::QuotaParseError(ref s) => write!(f, "parse: invalid quota {}", s),
+            ParseError::FilterParseError(ref s) => write!(f, "parse: invalid filter {}", s),
+            ParseError::TraceParseError(ref s) => write!(f, "parse: invalid trace rule {}", s),
+            ParseError::ExpressionParseError(ref e) => write!(f, "parse: {}", e),
+            ParseError::ConfigParseError(ref e) => write!(f, "parse: {}", e),
+            ParseError::IoError(ref e) => write!(f, "parse: {}", e),
         }
     }
 }
@@ -66,28 +1032,170 @@ impl Error for ParseError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match &self {
             ParseError::AddrParseError(ref e) => Some(e),
+            ParseError::NetworkParseError(ref e) => Some(e),
             ParseError::OutOfRangeError(_, _) => None,
+            ParseError::RequirementError(_, _) => None,
+            ParseError::ConflictError(_, _) => None,
+            ParseError::ProxyParseError(_) => None,
+            ParseError::ResolveError(_, ref e) => Some(e),
+            ParseError::RuleParseError(_) => None,
+            ParseError::AccessParseError(_) => None,
+            ParseError::RewriteParseError(_) => None,
+            ParseError::RateParseError(_) => None,
+            ParseError::SizeParseError(_) => None,
+            ParseError::OutboundParseError(_) => None,
+            ParseError::PortRangeParseError(_) => None,
+            ParseError::ChaosParseError(_) => None,
+            ParseError::SpoofMacParseError(_) => None,
+            ParseError::VerifyModeParseError(_) => None,
+            ParseError::QuotaParseError(_) => None,
+            ParseError::FilterParseError(_) => None,
+            ParseError::TraceParseError(_) => None,
+            ParseError::ExpressionParseError(ref e) => Some(e),
+            ParseError::ConfigParseError(ref e) => Some(e),
+            ParseError::IoError(ref e) => Some(e),
         }
     }
 }
 
+impl From<FilterError> for ParseError {
+    fn from(s: FilterError) -> Self {
+        ParseError::ExpressionParseError(s)
+    }
+}
+
+impl From<ConfigError> for ParseError {
+    fn from(s: ConfigError) -> Self {
+        ParseError::ConfigParseError(s)
+    }
+}
+
 impl From<AddrParseError> for ParseError {
     fn from(s: AddrParseError) -> Self {
         ParseError::AddrParseError(s)
     }
 }
 
+impl From<IpNetworkError> for ParseError {
+    fn from(s: IpNetworkError) -> Self {
+        ParseError::NetworkParseError(s)
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(s: io::Error) -> Self {
+        ParseError::IoError(s)
+    }
+}
+
 type Result = result::Result<Opts, ParseError>;
 
 /// Represents the options of the application.
 pub struct Opts {
     pub verbose: bool,
     pub vverbose: bool,
-    pub inter: Option<String>,
-    pub mtu: u16,
-    pub publish: Option<Ipv4Addr>,
-    pub src: Ipv4Addr,
-    pub dst: SocketAddrV4,
+    pub log_format: Format,
+    pub log_file: Option<String>,
+    /// Represents the interfaces, the ones after the first are bridged with it
+    pub inter: Vec<String>,
+    pub tun: Option<String>,
+    pub mtu: Option<u16>,
+    pub mss: Option<u16>,
+    pub tx_batch: usize,
+    pub min_rto: u64,
+    pub cc: Algorithm,
+    pub tcp_keepalive: Option<u64>,
+    pub no_delay: bool,
+    pub delayed_ack: u64,
+    pub recv_window: Option<usize>,
+    pub send_buffer: Option<usize>,
+    pub relay_buffer: Option<usize>,
+    pub max_memory: usize,
+    pub max_connections: usize,
+    pub max_half_open: usize,
+    pub syn_ack_retries: usize,
+    pub syn_ack_max_interval: u64,
+    pub evict_idle: bool,
+    pub pickup_established: Vec<Access>,
+    pub proxy_timeout: u64,
+    pub relay_timeout: Option<u64>,
+    pub relay_stale: Option<u64>,
+    pub linger: Option<u64>,
+    pub connect_retries: usize,
+    pub proxy_pool: usize,
+    pub outbound: Option<Outbound>,
+    pub local_port_range: Option<PortRange>,
+    pub nat_ports: Option<PortRange>,
+    pub udp_timeout: u64,
+    pub udp_keepalive: Option<u64>,
+    pub udp_assoc_strict: bool,
+    pub udp_probe: Option<SocketAddrV4>,
+    pub udp_probe_interval: u64,
+    pub udp_summary: Option<u64>,
+    pub udp_nat: UdpNat,
+    pub udp_fallback: UdpFallback,
+    pub udp_tunnel: Option<SocketAddrV4>,
+    pub udp_frag: bool,
+    pub udp_pmtu: bool,
+    pub udp_send_frag: bool,
+    pub udp_sockets: usize,
+    pub lan_passthrough: bool,
+    pub state_file: Option<String>,
+    pub reassembly_timeout: u64,
+    pub checksum_mode: ChecksumMode,
+    pub shutdown_timeout: u64,
+    pub limit_up: Option<u64>,
+    pub limit_down: Option<u64>,
+    pub limit_per_client: Option<u64>,
+    pub client_quotas: Vec<ClientQuota>,
+    pub client_quota_default: Option<Quota>,
+    pub workers: usize,
+    pub arp_interval: Option<u64>,
+    pub device_retry: Option<u64>,
+    pub run_as: Option<String>,
+    pub keep_privileges: bool,
+    pub chaos: Option<Chaos>,
+    pub spoof_mac: SpoofMac,
+    pub verify_tx: Option<VerifyMode>,
+    pub dhcp: bool,
+    pub dhcp_force: bool,
+    pub igd: bool,
+    pub test_responder: Option<u16>,
+    pub self_test: Level,
+    pub self_test_connect: SocketAddrV4,
+    pub self_test_echo: Option<SocketAddrV4>,
+    pub filter: Option<Expression>,
+    pub input_file: Option<String>,
+    pub output_file: Option<String>,
+    pub replay_timing: bool,
+    pub dump: Option<String>,
+    pub dump_max_size: Option<u64>,
+    pub dump_filter: Option<Filter>,
+    pub capture_on_error: Option<PathBuf>,
+    pub capture_frames: usize,
+    pub traces: Vec<TraceRule>,
+    pub trace_max_size: usize,
+    pub publish: Option<Ipv4Network>,
+    pub force_publish: bool,
+    pub publish6: Option<Ipv6Addr>,
+    pub ipv6_ra: Option<Ipv6Network>,
+    pub src: Ipv4Network,
+    pub proxy: Arc<dyn ProxyConnector>,
+    /// Represents the group of proxies if there are multiple destinations, which is also the proxy
+    pub proxy_group: Option<Arc<ProxyGroup>>,
+    pub proxy_check_interval: u64,
+    /// Represents the min time in seconds answers of host names are cached
+    pub resolve_ttl: u64,
+    pub auth_source: Option<AuthSource>,
+    pub rules: Rules,
+    pub access_list: AccessList,
+    pub blocklist: Option<String>,
+    pub rewrites: Rewrites,
+    pub redirect_dns: Option<SocketAddrV4>,
+    pub bind_ports: Vec<u16>,
+    pub metrics: Option<SocketAddrV4>,
+    pub stats_interval: Option<u64>,
+    pub classify: bool,
 }
 
 impl Opts {
@@ -96,34 +1204,1029 @@ impl Opts {
         Opts {
             verbose: false,
             vverbose: false,
-            inter: None,
-            mtu: 1400,
+            log_format: Format::Human,
+            log_file: None,
+            inter: Vec::new(),
+            tun: None,
+            mtu: None,
+            mss: None,
+            tx_batch: DEFAULT_TX_BATCH,
+            min_rto: DEFAULT_MIN_RTO,
+            cc: Algorithm::Cubic,
+            tcp_keepalive: None,
+            no_delay: false,
+            delayed_ack: DEFAULT_DELAYED_ACK,
+            recv_window: None,
+            send_buffer: None,
+            relay_buffer: None,
+            max_memory: memory::DEFAULT_MAX_MEMORY,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_half_open: DEFAULT_MAX_HALF_OPEN,
+            syn_ack_retries: DEFAULT_SYN_ACK_RETRIES,
+            syn_ack_max_interval: DEFAULT_SYN_ACK_MAX_INTERVAL,
+            evict_idle: false,
+            pickup_established: Vec::new(),
+            proxy_timeout: DEFAULT_PROXY_TIMEOUT,
+            relay_timeout: None,
+            relay_stale: None,
+            linger: None,
+            connect_retries: DEFAULT_CONNECT_RETRIES,
+            proxy_pool: 0,
+            outbound: None,
+            local_port_range: None,
+            nat_ports: None,
+            udp_timeout: DEFAULT_UDP_TIMEOUT,
+            udp_keepalive: None,
+            udp_assoc_strict: false,
+            udp_probe: None,
+            udp_probe_interval: DEFAULT_UDP_PROBE_INTERVAL,
+            udp_summary: None,
+            udp_nat: UdpNat::FullCone,
+            udp_fallback: UdpFallback::None,
+            udp_tunnel: None,
+            udp_frag: true,
+            udp_pmtu: false,
+            udp_send_frag: false,
+            udp_sockets: DEFAULT_SHARED_SOCKETS,
+            lan_passthrough: false,
+            state_file: None,
+            reassembly_timeout: DEFAULT_REASSEMBLY_TIMEOUT,
+            checksum_mode: ChecksumMode::Auto,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            limit_up: None,
+            limit_down: None,
+            limit_per_client: None,
+            client_quotas: Vec::new(),
+            client_quota_default: None,
+            workers: 1,
+            arp_interval: None,
+            device_retry: None,
+            run_as: None,
+            keep_privileges: false,
+            chaos: None,
+            spoof_mac: SpoofMac::Own,
+            verify_tx: None,
+            dhcp: false,
+            dhcp_force: false,
+            igd: false,
+            test_responder: None,
+            self_test: Level::Light,
+            self_test_connect: DEFAULT_SELF_TEST_CONNECT.parse().unwrap(),
+            self_test_echo: None,
+            filter: None,
+            input_file: None,
+            output_file: None,
+            replay_timing: false,
+            dump: None,
+            dump_max_size: None,
+            dump_filter: None,
+            capture_on_error: None,
+            capture_frames: DEFAULT_CAPTURE_FRAMES,
+            traces: Vec::new(),
+            trace_max_size: DEFAULT_TRACE_MAX_SIZE,
             publish: None,
-            src: Ipv4Addr::UNSPECIFIED,
-            dst: SocketAddrV4::new("127.0.0.1".parse().unwrap(), 1080),
+            force_publish: false,
+            publish6: None,
+            ipv6_ra: None,
+            src: Ipv4Network::from(Ipv4Addr::UNSPECIFIED),
+            proxy: Arc::new(SocksConnector::new(
+                SocketAddrV4::new("127.0.0.1".parse().unwrap(), 1080),
+                SocksOption::default(),
+            )),
+            proxy_group: None,
+            proxy_check_interval: DEFAULT_PROXY_CHECK_INTERVAL,
+            resolve_ttl: DEFAULT_RESOLVE_TTL,
+            auth_source: None,
+            rules: Rules::default(),
+            access_list: AccessList::new(),
+            blocklist: None,
+            rewrites: Rewrites::new(),
+            redirect_dns: None,
+            bind_ports: Vec::new(),
+            metrics: None,
+            stats_interval: None,
+            classify: false,
         }
     }
 
     /// Validates flags and creates a new `Opts`.
     pub fn validate(flags: &Flags) -> Result {
-        if flags.mtu < 576 {
-            return Err(ParseError::OutOfRangeError("MTU", "[576, 65535]"));
+        let log_format = Format::parse(flags.log_format.as_deref().unwrap_or(DEFAULT_LOG_FORMAT))
+            .ok_or(ParseError::OutOfRangeError("log format", "[human, json]"))?;
+        let checksum_mode = ChecksumMode::parse(
+            flags
+                .checksum_mode
+                .as_deref()
+                .unwrap_or(DEFAULT_CHECKSUM_MODE),
+        )
+        .ok_or(ParseError::OutOfRangeError(
+            "checksum mode",
+            "[auto, strict, fix, ignore]",
+        ))?;
+        let udp_nat = UdpNat::parse(flags.udp_nat.as_deref().unwrap_or(DEFAULT_UDP_NAT)).ok_or(
+            ParseError::OutOfRangeError("UDP NAT", "[full-cone, restricted]"),
+        )?;
+        let udp_fallback = UdpFallback::parse(
+            flags
+                .udp_fallback
+                .as_deref()
+                .unwrap_or(DEFAULT_UDP_FALLBACK),
+        )
+        .ok_or(ParseError::OutOfRangeError(
+            "UDP fallback",
+            "[none, drop, tcp]",
+        ))?;
+        let udp_tunnel = match flags.udp_tunnel {
+            Some(ref udp_tunnel) => Some(udp_tunnel.parse()?),
+            None => None,
+        };
+        if udp_fallback == UdpFallback::Tcp && udp_tunnel.is_none() {
+            return Err(ParseError::RequirementError(
+                "TCP UDP fallback",
+                "a UDP tunnel",
+            ));
+        }
+        let self_test = match flags.self_test {
+            true => Level::Full,
+            false => Level::Light,
+        };
+        let self_test_connect = flags
+            .self_test_connect
+            .as_deref()
+            .unwrap_or(DEFAULT_SELF_TEST_CONNECT)
+            .parse()?;
+        let self_test_echo = match flags.self_test_echo {
+            Some(ref self_test_echo) => Some(self_test_echo.parse()?),
+            None => None,
+        };
+        let tx_batch = flags.tx_batch.unwrap_or(DEFAULT_TX_BATCH);
+        let min_rto = flags.min_rto.unwrap_or(DEFAULT_MIN_RTO);
+        let delayed_ack = flags.delayed_ack.unwrap_or(DEFAULT_DELAYED_ACK);
+        let max_connections = flags.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS);
+        let max_half_open = flags.max_half_open.unwrap_or(DEFAULT_MAX_HALF_OPEN);
+        let syn_ack_retries = flags.syn_ack_retries.unwrap_or(DEFAULT_SYN_ACK_RETRIES);
+        let syn_ack_max_interval = flags
+            .syn_ack_max_interval
+            .unwrap_or(DEFAULT_SYN_ACK_MAX_INTERVAL);
+        let proxy_timeout = flags.proxy_timeout.unwrap_or(DEFAULT_PROXY_TIMEOUT);
+        let connect_retries = flags.connect_retries.unwrap_or(DEFAULT_CONNECT_RETRIES);
+        let proxy_pool = flags.proxy_pool.unwrap_or(0);
+        let udp_sockets = flags.udp_sockets.unwrap_or(DEFAULT_SHARED_SOCKETS);
+        let outbound = match flags.outbound {
+            Some(ref outbound) => Some(
+                Outbound::parse(outbound)
+                    .ok_or_else(|| ParseError::OutboundParseError(outbound.clone()))?,
+            ),
+            None => None,
+        };
+        let local_port_range = match flags.local_port_range {
+            Some(ref range) => Some(
+                PortRange::parse(range)
+                    .ok_or_else(|| ParseError::PortRangeParseError(range.clone()))?,
+            ),
+            None => None,
+        };
+        let nat_ports = match flags.nat_ports {
+            Some(ref range) => Some(
+                PortRange::parse(range)
+                    .ok_or_else(|| ParseError::PortRangeParseError(range.clone()))?,
+            ),
+            None => None,
+        };
+        let udp_timeout = flags.udp_timeout.unwrap_or(DEFAULT_UDP_TIMEOUT);
+        let reassembly_timeout = flags
+            .reassembly_timeout
+            .unwrap_or(DEFAULT_REASSEMBLY_TIMEOUT);
+        let shutdown_timeout = flags.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+        if let Some(mtu) = flags.mtu {
+            if mtu < 576 {
+                return Err(ParseError::OutOfRangeError("MTU", "[576, 65535]"));
+            }
+        }
+        if let Some(mss) = flags.mss {
+            if mss < 536 {
+                return Err(ParseError::OutOfRangeError("MSS", "[536, 65535]"));
+            }
+        }
+        if tx_batch > 1024 {
+            return Err(ParseError::OutOfRangeError("TX batch", "[0, 1024]"));
+        }
+        if min_rto < 1 || min_rto > 60000 {
+            return Err(ParseError::OutOfRangeError("minimum RTO", "[1, 60000]"));
+        }
+        let cc = match flags.cc.as_deref().unwrap_or(DEFAULT_CC) {
+            "reno" => Algorithm::Reno,
+            "cubic" => Algorithm::Cubic,
+            _ => {
+                return Err(ParseError::OutOfRangeError(
+                    "congestion control",
+                    "[reno, cubic]",
+                ))
+            }
+        };
+        if let Some(tcp_keepalive) = flags.tcp_keepalive {
+            if tcp_keepalive < 1 || tcp_keepalive > 86400 {
+                return Err(ParseError::OutOfRangeError("TCP keepalive", "[1, 86400]"));
+            }
+        }
+        if delayed_ack > 500 {
+            return Err(ParseError::OutOfRangeError("delayed ACK", "[0, 500]"));
+        }
+        let recv_window = match flags.recv_window {
+            Some(ref s) => Some(parse_size(s)?),
+            None => None,
+        };
+        if let Some(recv_window) = recv_window {
+            if recv_window < 1024 || recv_window > MAX_TCP_BUFFER_SIZE {
+                return Err(ParseError::OutOfRangeError("receive window", "[1K, 4M]"));
+            }
+        }
+        let send_buffer = match flags.send_buffer {
+            Some(ref s) => Some(parse_size(s)?),
+            None => None,
+        };
+        if let Some(send_buffer) = send_buffer {
+            if send_buffer < 1024 || send_buffer > MAX_TCP_BUFFER_SIZE {
+                return Err(ParseError::OutOfRangeError("send buffer", "[1K, 4M]"));
+            }
+        }
+        let relay_buffer = match flags.relay_buffer {
+            Some(ref s) => Some(parse_size(s)?),
+            None => None,
+        };
+        if let Some(relay_buffer) = relay_buffer {
+            if relay_buffer < 1024 || relay_buffer > MAX_RELAY_BUFFER_SIZE {
+                return Err(ParseError::OutOfRangeError("relay buffer", "[1K, 1M]"));
+            }
+        }
+        let max_memory = match flags.max_memory {
+            Some(ref s) => parse_size(s)?,
+            None => memory::DEFAULT_MAX_MEMORY,
+        };
+        if max_connections < 1 || max_connections > 1048576 {
+            return Err(ParseError::OutOfRangeError(
+                "max connections",
+                "[1, 1048576]",
+            ));
+        }
+        if max_half_open < 1 || max_half_open > max_connections {
+            return Err(ParseError::OutOfRangeError(
+                "max half-open connections",
+                "[1, max connections]",
+            ));
+        }
+        if syn_ack_retries > 10 {
+            return Err(ParseError::OutOfRangeError("ACK/SYN retries", "[0, 10]"));
+        }
+        if syn_ack_max_interval < 1 || syn_ack_max_interval > 60 {
+            return Err(ParseError::OutOfRangeError(
+                "ACK/SYN max interval",
+                "[1, 60]",
+            ));
+        }
+        if proxy_timeout < 1 || proxy_timeout > 3600 {
+            return Err(ParseError::OutOfRangeError("proxy timeout", "[1, 3600]"));
+        }
+        let proxy_policy = ProxyPolicy::parse(
+            flags
+                .proxy_policy
+                .as_deref()
+                .unwrap_or(DEFAULT_PROXY_POLICY),
+        )
+        .ok_or(ParseError::OutOfRangeError(
+            "proxy policy",
+            "[failover, round-robin]",
+        ))?;
+        let proxy_check_interval = flags
+            .proxy_check_interval
+            .unwrap_or(DEFAULT_PROXY_CHECK_INTERVAL);
+        if proxy_check_interval < 1 || proxy_check_interval > 3600 {
+            return Err(ParseError::OutOfRangeError(
+                "proxy check interval",
+                "[1, 3600]",
+            ));
         }
-        let mut publish = None;
+        let resolve_ttl = flags.resolve_ttl.unwrap_or(DEFAULT_RESOLVE_TTL);
+        if !(1..=86400).contains(&resolve_ttl) {
+            return Err(ParseError::OutOfRangeError("resolve TTL", "[1, 86400]"));
+        }
+        let ttl = Duration::from_secs(resolve_ttl);
+        if let Some(relay_timeout) = flags.relay_timeout {
+            if !(1..=86400).contains(&relay_timeout) {
+                return Err(ParseError::OutOfRangeError("relay timeout", "[1, 86400]"));
+            }
+        }
+        if let Some(relay_stale) = flags.relay_stale {
+            if !(1..=86400).contains(&relay_stale) {
+                return Err(ParseError::OutOfRangeError("relay stale", "[1, 86400]"));
+            }
+        }
+        if let Some(linger) = flags.linger {
+            if linger > 300 {
+                return Err(ParseError::OutOfRangeError("linger", "[0, 300]"));
+            }
+        }
+        if connect_retries > 16 {
+            return Err(ParseError::OutOfRangeError("connect retries", "[0, 16]"));
+        }
+        if proxy_pool > 64 {
+            return Err(ParseError::OutOfRangeError("proxy pool", "[0, 64]"));
+        }
+        if udp_timeout < 1 || udp_timeout > 86400 {
+            return Err(ParseError::OutOfRangeError("UDP timeout", "[1, 86400]"));
+        }
+        if let Some(udp_keepalive) = flags.udp_keepalive {
+            if !(1..=3600).contains(&udp_keepalive) {
+                return Err(ParseError::OutOfRangeError("UDP keepalive", "[1, 3600]"));
+            }
+        }
+        let udp_probe = match flags.udp_probe {
+            Some(ref udp_probe) => Some(udp_probe.parse()?),
+            None => None,
+        };
+        let udp_probe_interval = flags
+            .udp_probe_interval
+            .unwrap_or(DEFAULT_UDP_PROBE_INTERVAL);
+        if !(1..=3600).contains(&udp_probe_interval) {
+            return Err(ParseError::OutOfRangeError(
+                "UDP probe interval",
+                "[1, 3600]",
+            ));
+        }
+        if udp_sockets > 64 {
+            return Err(ParseError::OutOfRangeError("UDP sockets", "[0, 64]"));
+        }
+        if reassembly_timeout < 1 || reassembly_timeout > 3600 {
+            return Err(ParseError::OutOfRangeError(
+                "reassembly timeout",
+                "[1, 3600]",
+            ));
+        }
+        if shutdown_timeout > 3600 {
+            return Err(ParseError::OutOfRangeError("shutdown timeout", "[0, 3600]"));
+        }
+        let limit_up = match flags.limit_up {
+            Some(ref s) => Some(parse_rate(s)?),
+            None => None,
+        };
+        let limit_down = match flags.limit_down {
+            Some(ref s) => Some(parse_rate(s)?),
+            None => None,
+        };
+        let limit_per_client = match flags.limit_per_client {
+            Some(ref s) => Some(parse_rate(s)?),
+            None => None,
+        };
+        let client_quotas = flags
+            .client_quotas
+            .iter()
+            .map(|s| parse_client_quota(s))
+            .collect::<result::Result<Vec<_>, _>>()?;
+        let client_quota_default = match flags.client_quota_default {
+            Some(ref s) => {
+                Some(Quota::parse(s).ok_or_else(|| ParseError::QuotaParseError(s.clone()))?)
+            }
+            None => None,
+        };
+        if let Some(arp_interval) = flags.arp_interval {
+            if !(1..=3600).contains(&arp_interval) {
+                return Err(ParseError::OutOfRangeError("ARP interval", "[1, 3600]"));
+            }
+        }
+        if let Some(stats_interval) = flags.stats_interval {
+            if !(1..=86400).contains(&stats_interval) {
+                return Err(ParseError::OutOfRangeError("stats interval", "[1, 86400]"));
+            }
+        }
+        if let Some(device_retry) = flags.device_retry {
+            if !(1..=3600).contains(&device_retry) {
+                return Err(ParseError::OutOfRangeError("device retry", "[1, 3600]"));
+            }
+        }
+        if flags.run_as.is_some() {
+            if cfg!(not(unix)) {
+                return Err(ParseError::RequirementError("run as", "Unix"));
+            }
+            if flags.keep_privileges {
+                return Err(ParseError::ConflictError("run as", "keeping privileges"));
+            }
+            // The device can never be reopened by the user
+            if flags.device_retry.is_some() {
+                return Err(ParseError::ConflictError("run as", "device retry"));
+            }
+            // Binding to interfaces requires CAP_NET_RAW
+            if let Some(Outbound::Interface(_)) = outbound {
+                return Err(ParseError::ConflictError("run as", "an outbound interface"));
+            }
+            if let Some(range) = local_port_range {
+                if range.get_lo() < 1024 {
+                    return Err(ParseError::ConflictError(
+                        "run as",
+                        "a local port range below 1024",
+                    ));
+                }
+            }
+        }
+        let chaos = match flags.chaos {
+            Some(ref chaos) => Some(
+                Chaos::parse(chaos).ok_or_else(|| ParseError::ChaosParseError(chaos.clone()))?,
+            ),
+            None => None,
+        };
+        let spoof_mac = flags.spoof_mac.as_deref().unwrap_or(DEFAULT_SPOOF_MAC);
+        let spoof_mac = SpoofMac::parse(spoof_mac)
+            .ok_or_else(|| ParseError::SpoofMacParseError(String::from(spoof_mac)))?;
+        let verify_tx = match flags.verify_tx {
+            Some(ref mode) => {
+                let mode = mode.as_deref().unwrap_or(DEFAULT_VERIFY_TX);
+                Some(
+                    VerifyMode::parse(mode)
+                        .ok_or_else(|| ParseError::VerifyModeParseError(String::from(mode)))?,
+                )
+            }
+            None => None,
+        };
+        let workers = match flags.workers {
+            Some(workers) => {
+                if !(1..=MAX_WORKERS).contains(&workers) {
+                    return Err(ParseError::OutOfRangeError("workers", "[1, 64]"));
+                }
+                workers
+            }
+            None => thread::available_parallelism()
+                .map(|n| min(n.get(), MAX_WORKERS))
+                .unwrap_or(1),
+        };
+        let mut publish: Option<Ipv4Network> = None;
         if let Some(p) = &flags.publish {
             publish = Some(p.parse()?);
         }
-        let src = flags.src.parse()?;
-        let dst = flags.dst.parse()?;
+        let publish6: Option<Ipv6Addr> = match flags.publish6 {
+            Some(ref p) => Some(p.parse()?),
+            None => None,
+        };
+        let ipv6_ra: Option<Ipv6Network> = match flags.ipv6_ra {
+            Some(ref p) => Some(p.parse()?),
+            None => None,
+        };
+        // Addresses are only autoconfigured in prefixes of 64 bits
+        if let Some(ipv6_ra) = ipv6_ra {
+            if ipv6_ra.prefix() != 64 {
+                return Err(ParseError::OutOfRangeError("IPv6 RA prefix", "[/64]"));
+            }
+        }
+        let src: Ipv4Network = match flags.src {
+            Some(ref src) => src.parse()?,
+            None => return Err(ParseError::RequirementError("pcap2socks", "a source")),
+        };
+        if src.size() as usize > MAX_SOURCES {
+            return Err(ParseError::OutOfRangeError("source", "[/23, /32]"));
+        }
+        let filter = match flags.filter {
+            Some(ref s) => Some(Expression::parse(s)?),
+            None => None,
+        };
+        if flags.input_file.is_none() {
+            if flags.output_file.is_some() {
+                return Err(ParseError::RequirementError("output file", "an input file"));
+            }
+            if flags.replay_timing {
+                return Err(ParseError::RequirementError(
+                    "replay timing",
+                    "an input file",
+                ));
+            }
+        }
+        if flags.inter.len() > 1 {
+            if flags.input_file.is_some() {
+                return Err(ParseError::ConflictError(
+                    "bridged interfaces",
+                    "an input file",
+                ));
+            }
+            if flags.device_retry.is_some() {
+                return Err(ParseError::ConflictError(
+                    "bridged interfaces",
+                    "device retry",
+                ));
+            }
+        }
+        if flags.tun.is_some() {
+            if cfg!(not(all(target_os = "linux", feature = "tun"))) {
+                return Err(ParseError::RequirementError(
+                    "TUN device",
+                    "the tun feature on Linux",
+                ));
+            }
+            if !flags.inter.is_empty() {
+                return Err(ParseError::ConflictError("TUN device", "an interface"));
+            }
+            if flags.input_file.is_some() {
+                return Err(ParseError::ConflictError("TUN device", "an input file"));
+            }
+            // There is no ARP in TUN devices
+            if publish.is_some() {
+                return Err(ParseError::ConflictError(
+                    "TUN device",
+                    "a publishing address",
+                ));
+            }
+            if publish6.is_some() || ipv6_ra.is_some() {
+                return Err(ParseError::ConflictError("TUN device", "NDP"));
+            }
+        }
+        if flags.dump.is_none() {
+            if flags.dump_max_size.is_some() {
+                return Err(ParseError::RequirementError("dump max size", "a dump file"));
+            }
+            if flags.dump_filter.is_some() {
+                return Err(ParseError::RequirementError("dump filter", "a dump file"));
+            }
+        }
+        if let Some(dump_max_size) = flags.dump_max_size {
+            if !(1..=65536).contains(&dump_max_size) {
+                return Err(ParseError::OutOfRangeError("dump max size", "[1, 65536]"));
+            }
+        }
+        if flags.capture_on_error.is_none() && flags.capture_frames.is_some() {
+            return Err(ParseError::RequirementError(
+                "capture frames",
+                "a capture directory",
+            ));
+        }
+        let capture_frames = flags.capture_frames.unwrap_or(DEFAULT_CAPTURE_FRAMES);
+        if !(1..=4096).contains(&capture_frames) {
+            return Err(ParseError::OutOfRangeError("capture frames", "[1, 4096]"));
+        }
+        let dump_filter = match flags.dump_filter {
+            Some(ref s) => {
+                Some(Filter::parse(s).ok_or_else(|| ParseError::FilterParseError(s.to_string()))?)
+            }
+            None => None,
+        };
+        if flags.traces.is_empty() && flags.trace_max_size.is_some() {
+            return Err(ParseError::RequirementError(
+                "trace max size",
+                "trace rules",
+            ));
+        }
+        if let Some(trace_max_size) = flags.trace_max_size {
+            if !(1..=65536).contains(&trace_max_size) {
+                return Err(ParseError::OutOfRangeError("trace max size", "[1, 65536]"));
+            }
+        }
+        let traces = flags
+            .traces
+            .iter()
+            .map(|s| TraceRule::parse(s).ok_or_else(|| ParseError::TraceParseError(s.clone())))
+            .collect::<result::Result<Vec<_>, _>>()?;
+        if flags.dhcp || flags.dhcp_force {
+            match publish {
+                Some(publish) if publish.prefix() == 32 => {}
+                _ => {
+                    return Err(ParseError::RequirementError(
+                        "DHCP",
+                        "a single publishing address",
+                    ))
+                }
+            }
+        }
+        if flags.igd {
+            match publish {
+                Some(publish) if publish.prefix() == 32 => {}
+                _ => {
+                    return Err(ParseError::RequirementError(
+                        "IGD",
+                        "a single publishing address",
+                    ))
+                }
+            }
+        }
+        if let Some(test_responder) = flags.test_responder {
+            if test_responder == 0 {
+                return Err(ParseError::OutOfRangeError(
+                    "test responder port",
+                    "[1, 65535]",
+                ));
+            }
+            match publish {
+                Some(publish) if publish.prefix() == 32 => {}
+                _ => {
+                    return Err(ParseError::RequirementError(
+                        "test responder",
+                        "a single publishing address",
+                    ))
+                }
+            }
+        }
+        let version = match flags
+            .socks_version
+            .as_deref()
+            .unwrap_or(DEFAULT_SOCKS_VERSION)
+        {
+            "4" => Version::V4,
+            "4a" => Version::V4a,
+            "5" => Version::V5,
+            _ => return Err(ParseError::OutOfRangeError("SOCKS version", "[4, 4a, 5]")),
+        };
+        let (auth, auth_source) = match flags.username {
+            Some(ref username) => (
+                Some(SocksAuth::new(
+                    username.clone(),
+                    flags.password.clone().unwrap_or_default(),
+                )),
+                Some(AuthSource::Flags),
+            ),
+            None => match flags.auth_file {
+                Some(ref auth_file) => {
+                    let path = PathBuf::from(auth_file);
+                    (
+                        Some(SocksAuth::from_file(&path)?),
+                        Some(AuthSource::File(path)),
+                    )
+                }
+                None => match SocksAuth::from_env() {
+                    Some(auth) => (Some(auth), Some(AuthSource::Env)),
+                    None => (None, None),
+                },
+            },
+        };
+        let default =
+            Action::parse(flags.default_action.as_deref().unwrap_or(DEFAULT_ACTION)).ok_or(
+                ParseError::OutOfRangeError("default action", "[proxy, direct, reject]"),
+            )?;
+        let mut rules = Rules::new(default);
+        for rule in flags.rules.iter() {
+            rules.push(parse_rule(rule, ttl)?);
+        }
+        if let Some(ref path) = flags.rules_file {
+            // Empty lines and comments starting with `#` are ignored
+            let mut file_rules = Vec::new();
+            for line in fs::read_to_string(path)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                file_rules.push(parse_rule(line, ttl)?);
+            }
+            rules.extend(file_rules);
+        }
+        if flags.direct_private {
+            rules.push_private();
+        }
+        let mut access_list = AccessList::new();
+        for access in flags.allow.iter() {
+            access_list.push_allow(parse_access(access)?);
+        }
+        for access in flags.deny.iter() {
+            access_list.push_deny(parse_access(access)?);
+        }
+        access_list.set_silent(flags.deny_silent);
+        // Only TCP connections are picked up
+        let mut pickup_established = Vec::new();
+        for access in flags.pickup_established.iter() {
+            let access = parse_access(access)?;
+            if access.get_protocol() != Protocol::Tcp {
+                return Err(ParseError::AccessParseError(access.to_string()));
+            }
+            pickup_established.push(access);
+        }
+        let mut rewrites = Rewrites::new();
+        for rewrite in flags.rewrites.iter() {
+            rewrites.push(parse_rewrite(rewrite)?);
+        }
+        // The port of the resolver is 53 if not specified
+        let mut redirect_dns = None;
+        if let Some(ref resolver) = flags.redirect_dns {
+            redirect_dns = Some(match resolver.parse::<Ipv4Addr>() {
+                Ok(ip_addr) => SocketAddrV4::new(ip_addr, 53),
+                Err(_) => resolver.parse()?,
+            });
+        }
+        let metrics = match flags.metrics {
+            Some(ref metrics) => Some(metrics.parse()?),
+            None => None,
+        };
+        let mut bind_ports = Vec::new();
+        if let Some(ref ports) = flags.bind_ports {
+            for port in ports.split(',') {
+                match port.trim().parse::<u16>() {
+                    Ok(port) if port != 0 => bind_ports.push(port),
+                    _ => return Err(ParseError::OutOfRangeError("bind port", "[1, 65535]")),
+                }
+            }
+        }
+        let mut proxy_group = None;
+        let proxy = match flags.proxy {
+            Some(ref proxy) => parse_chain(proxy, version, auth, ttl)?,
+            None => match flags.dst.as_slice() {
+                [] => parse_chain(DEFAULT_DESTINATION, version, auth, ttl)?,
+                [dst] => parse_chain(dst, version, auth, ttl)?,
+                dsts => {
+                    let proxies = dsts
+                        .iter()
+                        .map(|dst| parse_chain(dst, version, auth.clone(), ttl))
+                        .collect::<result::Result<Vec<_>, _>>()?;
+                    let group = Arc::new(ProxyGroup::new(proxies, proxy_policy));
+                    proxy_group = Some(Arc::clone(&group));
+
+                    group
+                }
+            },
+        };
 
         Ok(Opts {
             verbose: flags.verbose,
             vverbose: flags.vverbose,
+            log_format,
+            log_file: flags.log_file.clone(),
             mtu: flags.mtu,
+            mss: flags.mss,
+            tx_batch,
+            min_rto,
+            cc,
+            tcp_keepalive: flags.tcp_keepalive,
+            no_delay: flags.no_delay,
+            delayed_ack,
+            recv_window,
+            send_buffer,
+            relay_buffer,
+            max_memory,
+            max_connections,
+            max_half_open,
+            syn_ack_retries,
+            syn_ack_max_interval,
+            evict_idle: flags.evict_idle,
+            pickup_established,
+            proxy_timeout,
+            relay_timeout: flags.relay_timeout,
+            relay_stale: flags.relay_stale,
+            linger: flags.linger,
+            connect_retries,
+            proxy_pool,
+            outbound,
+            local_port_range,
+            nat_ports,
+            udp_timeout,
+            udp_keepalive: flags.udp_keepalive,
+            udp_assoc_strict: flags.udp_assoc_strict,
+            udp_probe,
+            udp_probe_interval,
+            udp_summary: flags.udp_summary,
+            udp_nat,
+            udp_fallback,
+            udp_tunnel,
+            udp_frag: !flags.no_udp_frag,
+            udp_pmtu: flags.udp_pmtu,
+            udp_send_frag: flags.udp_send_frag,
+            udp_sockets,
+            lan_passthrough: flags.lan_passthrough,
+            state_file: flags.state_file.clone(),
+            reassembly_timeout,
+            checksum_mode,
+            shutdown_timeout,
+            limit_up,
+            limit_down,
+            limit_per_client,
+            client_quotas,
+            client_quota_default,
+            workers,
+            arp_interval: flags.arp_interval,
+            device_retry: flags.device_retry,
+            run_as: flags.run_as.clone(),
+            keep_privileges: flags.keep_privileges,
+            chaos,
+            spoof_mac,
+            verify_tx,
+            dhcp: flags.dhcp || flags.dhcp_force,
+            dhcp_force: flags.dhcp_force,
+            igd: flags.igd,
+            test_responder: flags.test_responder,
+            self_test,
+            self_test_connect,
+            self_test_echo,
+            filter,
+            input_file: flags.input_file.clone(),
+            output_file: flags.output_file.clone(),
+            replay_timing: flags.replay_timing,
+            dump: flags.dump.clone(),
+            dump_max_size: flags.dump_max_size,
+            dump_filter,
+            capture_on_error: flags.capture_on_error.as_ref().map(PathBuf::from),
+            capture_frames,
+            traces,
+            trace_max_size: flags.trace_max_size.unwrap_or(DEFAULT_TRACE_MAX_SIZE),
             inter: flags.inter.clone(),
+            tun: flags.tun.clone(),
             publish,
+            force_publish: flags.force_publish,
+            publish6,
+            ipv6_ra,
             src,
-            dst,
+            proxy,
+            proxy_group,
+            proxy_check_interval,
+            resolve_ttl,
+            auth_source,
+            rules,
+            access_list,
+            blocklist: flags.blocklist.clone(),
+            rewrites,
+            redirect_dns,
+            bind_ports,
+            metrics,
+            stats_interval: flags.stats_interval,
+            classify: flags.classify,
         })
     }
 }
+
+/// Parses a rate in bytes per second with an optional suffix K, M or G in multiples of 1024.
+fn parse_rate(s: &str) -> result::Result<u64, ParseError> {
+    parse_multiple(s).ok_or_else(|| ParseError::RateParseError(s.to_string()))
+}
+
+/// Parses a size in bytes with an optional suffix K, M or G in multiples of 1024.
+fn parse_size(s: &str) -> result::Result<usize, ParseError> {
+    let invalid = || ParseError::SizeParseError(s.to_string());
+
+    let size = parse_multiple(s).ok_or_else(invalid)?;
+    if size > usize::MAX as u64 {
+        return Err(invalid());
+    }
+
+    Ok(size as usize)
+}
+
+/// Parses a positive number with an optional suffix K, M or G in multiples of 1024.
+fn parse_multiple(s: &str) -> Option<u64> {
+    let (value, unit) = match s.trim().to_ascii_uppercase() {
+        ref t if t.ends_with('K') => (t[..t.len() - 1].to_string(), 1 << 10),
+        ref t if t.ends_with('M') => (t[..t.len() - 1].to_string(), 1 << 20),
+        ref t if t.ends_with('G') => (t[..t.len() - 1].to_string(), 1 << 30),
+        t => (t, 1),
+    };
+    let value: u64 = value.parse().ok()?;
+    if value == 0 {
+        return None;
+    }
+
+    value.checked_mul(unit)
+}
+
+/// Parses a quota of clients.
+fn parse_client_quota(s: &str) -> result::Result<ClientQuota, ParseError> {
+    ClientQuota::parse(s).ok_or_else(|| ParseError::QuotaParseError(s.to_string()))
+}
+
+/// Parses a rule.
+/// Parses a rule, whose host name is resolved with answers cached for at least the TTL.
+fn parse_rule(s: &str, ttl: Duration) -> result::Result<Rule, ParseError> {
+    if let Some(rule) = Rule::parse(s) {
+        return Ok(rule);
+    }
+    let (host, ports, action) =
+        Rule::parse_host(s).ok_or_else(|| ParseError::RuleParseError(s.to_string()))?;
+    let name =
+        Name::resolve(host, ttl).map_err(|e| ParseError::ResolveError(host.to_string(), e))?;
+
+    Ok(Rule::with_host(name, ports, action))
+}
+
+/// Parses an entry of the access lists.
+fn parse_access(s: &str) -> result::Result<Access, ParseError> {
+    Access::parse(s).ok_or_else(|| ParseError::AccessParseError(s.to_string()))
+}
+
+/// Parses a rule rewriting destinations.
+fn parse_rewrite(s: &str) -> result::Result<Rewrite, ParseError> {
+    Rewrite::parse(s).ok_or_else(|| ParseError::RewriteParseError(s.to_string()))
+}
+
+/// Parses a comma-separated chain of proxies. A proxy is either an address of a SOCKS proxy in
+/// the given version and authentication, or in the form of a URL. Host names are resolved with
+/// answers cached for at least the TTL.
+fn parse_chain(
+    s: &str,
+    version: Version,
+    auth: Option<SocksAuth>,
+    ttl: Duration,
+) -> result::Result<Arc<dyn ProxyConnector>, ParseError> {
+    let mut proxies: Vec<Arc<dyn ProxyConnector>> = Vec::new();
+    for proxy in s.split(',').map(|proxy| proxy.trim()) {
+        if proxy.contains("://") {
+            proxies.push(parse_proxy(proxy, auth.clone(), ttl)?);
+        } else {
+            let option = SocksOption::new(version, auth.clone());
+            proxies.push(parse_remote(proxy, ttl, move |remote| {
+                Arc::new(SocksConnector::new(remote, option.clone()))
+            })?);
+        }
+    }
+
+    match proxies.len() {
+        1 => Ok(proxies.pop().unwrap()),
+        _ => Ok(Arc::new(ProxyChain::new(proxies))),
+    }
+}
+
+/// Parses a proxy in the form of `scheme://[username[:password]@]host:port`. The scheme can be
+/// `socks4`, `socks4a`, `socks5`, `http` or `tunnel`. The given authentication is used if the
+/// proxy does not carry one, a tunnel has no authentication.
+fn parse_proxy(
+    s: &str,
+    auth: Option<SocksAuth>,
+    ttl: Duration,
+) -> result::Result<Arc<dyn ProxyConnector>, ParseError> {
+    let invalid = || ParseError::ProxyParseError(s.to_string());
+
+    let pos = s.find("://").ok_or_else(invalid)?;
+    let scheme = s[..pos].to_ascii_lowercase();
+    let rest = s[pos + 3..].trim_end_matches('/');
+
+    // Authentication
+    let (auth, host) = match rest.rfind('@') {
+        Some(pos) => {
+            let (username, password) = match rest[..pos].find(':') {
+                Some(i) => (&rest[..i], &rest[i + 1..pos]),
+                None => (&rest[..pos], ""),
+            };
+            let username = percent_decode(username).ok_or_else(invalid)?;
+            let password = percent_decode(password).ok_or_else(invalid)?;
+
+            (Some(SocksAuth::new(username, password)), &rest[pos + 1..])
+        }
+        None => (auth, rest),
+    };
+
+    // Address, host names are resolved locally
+    let version = match scheme.as_str() {
+        "socks4" => Version::V4,
+        "socks4a" => Version::V4a,
+        "socks5" => Version::V5,
+        "http" => {
+            return parse_remote(host, ttl, move |remote| {
+                Arc::new(HttpConnector::new(remote, auth.clone()))
+            })
+        }
+        "tunnel" => {
+            if rest.contains('@') {
+                return Err(invalid());
+            }
+
+            return parse_remote(host, ttl, |remote| Arc::new(MuxConnector::new(remote)));
+        }
+        _ => return Err(invalid()),
+    };
+    let option = SocksOption::new(version, auth);
+
+    parse_remote(host, ttl, move |remote| {
+        Arc::new(SocksConnector::new(remote, option.clone()))
+    })
+}
+
+/// Parses the address of a proxy in the form of `host:port`, whose proxy is created by the given
+/// function. A proxy addressed by a host name follows the addresses of the name, which is
+/// resolved with answers cached for at least the TTL.
+fn parse_remote<F>(
+    s: &str,
+    ttl: Duration,
+    new_proxy: F,
+) -> result::Result<Arc<dyn ProxyConnector>, ParseError>
+where
+    F: Fn(SocketAddrV4) -> Arc<dyn ProxyConnector> + Send + Sync + 'static,
+{
+    if let Ok(remote) = s.parse::<SocketAddrV4>() {
+        return Ok(new_proxy(remote));
+    }
+    let invalid = || ParseError::ProxyParseError(s.to_string());
+    let pos = s.rfind(':').ok_or_else(invalid)?;
+    let port = s[pos + 1..].parse::<u16>().map_err(|_| invalid())?;
+    let host = &s[..pos];
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    let name =
+        Name::resolve(host, ttl).map_err(|e| ParseError::ResolveError(host.to_string(), e))?;
+
+    Ok(Arc::new(ResolvedProxy::new(name, port, new_proxy)))
+}
+
+/// Decodes a percent-encoded string. Returns `None` if the string is not valid.
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
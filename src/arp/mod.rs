@@ -0,0 +1,286 @@
+use log::trace;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+use crate::packet::layer::arp::Arp;
+use crate::packet::layer::ethernet::Ethernet;
+use crate::packet::layer::{LayerTypes, Layers};
+use crate::packet::Indicator;
+use crate::pcap::{HardwareAddr, Receiver, SendQueue};
+
+/// Represents the interval of handling the timers of the resolver.
+const TIMER_INTERVAL: Duration = Duration::from_millis(100);
+/// Represents the time a resolved hardware address is cached.
+const TTL: Duration = Duration::from_secs(60);
+/// Represents the time before the expiry a hardware address in use is refreshed.
+const REFRESH_AHEAD: Duration = Duration::from_secs(15);
+/// Represents the time an entry is in use after a frame is sent to it.
+const USE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Represents the interval between ARP requests of the same address.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Represents the max number of ARP requests of an address before it is unresolved.
+const MAX_RETRIES: usize = 3;
+/// Represents the max number of frames queued for an unresolved address.
+const MAX_PENDING_FRAMES: usize = 4;
+/// Represents the max number of addresses cached and resolving.
+const MAX_ENTRIES: usize = 256;
+/// Represents the time waited for answers of an ARP probe.
+const PROBE_WAIT: Duration = Duration::from_secs(1);
+
+/// Represents the resolution of a frame to an address.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Resolution {
+    /// Represents the address is resolved, and the frame can be sent to the hardware address.
+    Resolved(HardwareAddr),
+    /// Represents the frame is queued, and an ARP request of the address should be sent.
+    Request,
+    /// Represents the frame is queued until the address is resolved.
+    Pending,
+    /// Represents the frame is dropped because the queue of the address is full.
+    Dropped,
+}
+
+#[derive(Debug)]
+enum State {
+    Resolved(HardwareAddr),
+    /// Represents the address is being resolved, with the frames queued for it
+    Pending(VecDeque<Vec<u8>>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    state: State,
+    /// Represents the instant the address is resolved, or the entry is created if it is pending
+    instant: Instant,
+    /// Represents the instant of the last ARP request
+    request_instant: Instant,
+    /// Represents the count of ARP requests without a reply
+    retries: usize,
+    /// Represents the instant a frame is sent to the address
+    use_instant: Instant,
+}
+
+/// Represents a resolver of hardware addresses of hosts in the LAN by ARP. The resolver never
+/// sends frames itself, ARP requests are asked for and frames queued are returned when the
+/// address is resolved.
+#[derive(Debug, Default)]
+pub struct Resolver {
+    entries: HashMap<Ipv4Addr, Entry>,
+    /// Represents the instant the timers are handled
+    timer_instant: Option<Instant>,
+}
+
+impl Resolver {
+    /// Creates a new `Resolver`.
+    pub fn new() -> Resolver {
+        Resolver::default()
+    }
+
+    /// Resolves the address of a frame. The frame is queued if the address is not resolved yet,
+    /// and its destination hardware address is left to be set when it is returned by `learn`.
+    pub fn resolve(&mut self, ip_addr: Ipv4Addr, frame: &[u8]) -> Resolution {
+        let now = Instant::now();
+        if let Some(entry) = self.entries.get_mut(&ip_addr) {
+            entry.use_instant = now;
+            return match entry.state {
+                State::Resolved(hardware_addr) => Resolution::Resolved(hardware_addr),
+                State::Pending(ref mut frames) => {
+                    if frames.len() >= MAX_PENDING_FRAMES {
+                        metrics::ARP_QUEUE_DROPS.increase();
+                        trace!("drop frame to unresolved {} for a full queue", ip_addr);
+
+                        Resolution::Dropped
+                    } else {
+                        frames.push_back(frame.to_vec());
+
+                        Resolution::Pending
+                    }
+                }
+            };
+        }
+
+        if self.entries.len() >= MAX_ENTRIES {
+            self.expire();
+            if self.entries.len() >= MAX_ENTRIES {
+                metrics::ARP_QUEUE_DROPS.increase();
+                trace!("drop frame to unresolved {} for too many entries", ip_addr);
+
+                return Resolution::Dropped;
+            }
+        }
+        let mut frames = VecDeque::with_capacity(MAX_PENDING_FRAMES);
+        frames.push_back(frame.to_vec());
+        self.entries.insert(
+            ip_addr,
+            Entry {
+                state: State::Pending(frames),
+                instant: now,
+                request_instant: now,
+                retries: 1,
+                use_instant: now,
+            },
+        );
+        metrics::ARP_REQUESTS.increase();
+
+        Resolution::Request
+    }
+
+    /// Learns the hardware address of an address from ARP. Only addresses being resolved or
+    /// cached are learned, so other hosts cannot fill the cache. Returns the frames queued for
+    /// the address.
+    pub fn learn(&mut self, ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) -> Vec<Vec<u8>> {
+        let entry = match self.entries.get_mut(&ip_addr) {
+            Some(entry) => entry,
+            None => return vec![],
+        };
+
+        let frames = match entry.state {
+            State::Pending(ref mut frames) => frames.drain(..).collect(),
+            State::Resolved(_) => vec![],
+        };
+        entry.state = State::Resolved(hardware_addr);
+        entry.instant = Instant::now();
+        entry.retries = 0;
+        trace!("resolve {} at {}", ip_addr, hardware_addr);
+
+        frames
+    }
+
+    /// Handles the timers of the resolver. Addresses unresolved after the retries are
+    /// abandoned with their frames, and addresses in use are refreshed before they expire.
+    /// Returns the addresses whose ARP requests should be sent.
+    pub fn handle_timers(&mut self) -> Vec<Ipv4Addr> {
+        let now = Instant::now();
+        match self.timer_instant {
+            Some(instant) if now.duration_since(instant) < TIMER_INTERVAL => return vec![],
+            _ => self.timer_instant = Some(now),
+        }
+        if self.entries.is_empty() {
+            return vec![];
+        }
+        self.expire();
+
+        let mut requests = Vec::new();
+        for (ip_addr, entry) in self.entries.iter_mut() {
+            let is_due = match entry.state {
+                State::Pending(_) => true,
+                State::Resolved(_) => {
+                    now.duration_since(entry.instant) + REFRESH_AHEAD >= TTL
+                        && now.duration_since(entry.use_instant) < USE_TIMEOUT
+                }
+            };
+            if is_due
+                && entry.retries < MAX_RETRIES
+                && (entry.retries == 0
+                    || now.duration_since(entry.request_instant) >= RETRY_INTERVAL)
+            {
+                entry.request_instant = now;
+                entry.retries += 1;
+                metrics::ARP_REQUESTS.increase();
+                requests.push(*ip_addr);
+            }
+        }
+
+        requests
+    }
+
+    /// Returns the hardware address of the address if it is resolved.
+    pub fn get(&self, ip_addr: Ipv4Addr) -> Option<HardwareAddr> {
+        match self.entries.get(&ip_addr) {
+            Some(Entry {
+                state: State::Resolved(hardware_addr),
+                ..
+            }) => Some(*hardware_addr),
+            _ => None,
+        }
+    }
+
+    /// Removes the entries expired and the pending entries failing all the retries.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.entries.retain(|ip_addr, entry| match entry.state {
+            State::Resolved(_) if now.duration_since(entry.instant) >= TTL => {
+                trace!("expire {}", ip_addr);
+
+                false
+            }
+            State::Pending(ref frames)
+                if entry.retries >= MAX_RETRIES
+                    && now.duration_since(entry.request_instant) >= RETRY_INTERVAL =>
+            {
+                metrics::ARP_FAILURES.increase();
+                metrics::ARP_QUEUE_DROPS.add(frames.len() as u64);
+                trace!(
+                    "cannot resolve {}, drop {} frames queued",
+                    ip_addr,
+                    frames.len()
+                );
+
+                false
+            }
+            _ => true,
+        });
+    }
+}
+
+/// Get the hardware address claiming the address in the ARP message, which is the sender of any
+/// ARP message of the address, like a reply or an announcement, except the local hardware
+/// address. Probes never claim addresses.
+pub fn get_claimant(
+    arp: &Arp,
+    ip_addr: Ipv4Addr,
+    local_hardware_addr: HardwareAddr,
+) -> Option<HardwareAddr> {
+    let hardware_addr = arp.get_src_hardware_addr();
+    if arp.get_claimed() == Some(ip_addr) && hardware_addr != local_hardware_addr {
+        Some(hardware_addr)
+    } else {
+        None
+    }
+}
+
+/// Probes the address by an ARP probe (RFC 5227) from the hardware address, and waits for
+/// answers briefly. Returns the hardware address of the host owning the address if any host
+/// claims it. Frames captured in the meantime are discarded.
+pub fn probe(
+    tx: &mut SendQueue,
+    rx: &mut Receiver,
+    hardware_addr: HardwareAddr,
+    ip_addr: Ipv4Addr,
+) -> io::Result<Option<HardwareAddr>> {
+    let request = Arp::new_request(hardware_addr, Ipv4Addr::UNSPECIFIED, ip_addr);
+    let indicator = Indicator::new(
+        Layers::Ethernet(
+            Ethernet::new(LayerTypes::Arp, hardware_addr, HardwareAddr::broadcast()).unwrap(),
+        ),
+        Some(Layers::Arp(request)),
+        None,
+    );
+    let frame = indicator.to_vec()?;
+    tx.send(&frame, true)?;
+    trace!("probe {} by ARP", ip_addr);
+
+    let instant = Instant::now();
+    while instant.elapsed() < PROBE_WAIT {
+        let captured = match rx.next_frame() {
+            Ok(captured) => captured,
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let indicator = match Indicator::from(captured) {
+            Ok(indicator) => indicator,
+            Err(_) => continue,
+        };
+        if let Some(arp) = indicator.get_arp() {
+            if let Some(claimant) = get_claimant(arp, ip_addr, hardware_addr) {
+                return Ok(Some(claimant));
+            }
+        }
+    }
+
+    Ok(None)
+}
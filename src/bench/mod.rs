@@ -0,0 +1,599 @@
+//! Benchmarks of the parse and serialize pipeline on synthetic traffic, which run the functions
+//! frames go through in forwarding without a device or a proxy.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::packet::layer::ethernet::Ethernet;
+use crate::packet::layer::ipv4::Ipv4;
+use crate::packet::layer::payload::FramePool;
+use crate::packet::layer::tcp::Tcp;
+use crate::packet::layer::udp::Udp;
+use crate::packet::layer::{Layer, LayerType, LayerTypes, Layers};
+use crate::packet::{self, Defraggler, Indicator};
+use crate::pcap::{self, HardwareAddr};
+use crate::pool::BufferPool;
+use crate::{new_link, serialize_frame};
+
+/// Represents the default number of packets generated.
+pub const DEFAULT_PACKETS: usize = 1_000_000;
+/// Represents the default seed of the generator.
+pub const DEFAULT_SEED: u64 = 0x7063_6170_3273_6f63;
+/// Represents the max size of the payload of a packet.
+pub const MAX_PAYLOAD_SIZE: usize = 65000;
+
+/// Represents the number of frames processed by a stage at a time.
+const BATCH_SIZE: usize = 1024;
+/// Represents the number of flows of sources the traffic is spread over.
+const FLOWS: u32 = 64;
+/// Represents the max size of the payload of an IPv4 fragment, aligned to 8 Bytes.
+const FRAGMENT_SIZE: usize = 1480;
+/// Represents the window of TCP segments.
+const WINDOW: u16 = 65535;
+
+const SRC_HARDWARE_ADDR: HardwareAddr = pnet::datalink::MacAddr(0x02, 0, 0, 0, 0, 0x01);
+const LOCAL_HARDWARE_ADDR: HardwareAddr = pnet::datalink::MacAddr(0x02, 0, 0, 0, 0, 0x02);
+
+/// Represents if allocations are counted.
+static IS_COUNTING: AtomicBool = AtomicBool::new(false);
+/// Represents the number of allocations counted.
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Represents an allocator counting allocations while a stage of the benchmark is timed. It
+/// forwards to the system allocator, and counts only once it is the global allocator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CountingAllocator;
+
+impl CountingAllocator {
+    fn count() {
+        if IS_COUNTING.load(Ordering::Relaxed) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        CountingAllocator::count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        CountingAllocator::count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        CountingAllocator::count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Represents the mix of the synthetic traffic.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Mix {
+    /// Represents the percentage of TCP segments, the others are UDP datagrams
+    pub tcp_percent: u8,
+    /// Represents the min size of payloads
+    pub min_size: usize,
+    /// Represents the max size of payloads
+    pub max_size: usize,
+    /// Represents the percentage of packets sent in IPv4 fragments
+    pub fragment_percent: u8,
+}
+
+impl Default for Mix {
+    fn default() -> Mix {
+        Mix {
+            tcp_percent: 80,
+            min_size: 0,
+            max_size: 1400,
+            fragment_percent: 5,
+        }
+    }
+}
+
+impl Display for Mix {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}% TCP, {}% UDP, payloads of {}-{} Bytes, {}% fragmented",
+            self.tcp_percent,
+            100 - self.tcp_percent,
+            self.min_size,
+            self.max_size,
+            self.fragment_percent
+        )
+    }
+}
+
+/// Represents a generator of synthetic traffic, which yields Ethernet frames of IPv4 TCP
+/// segments and UDP datagrams from sources to the internet. The same seed and mix always yield
+/// the same frames.
+#[derive(Debug)]
+pub struct Generator {
+    state: u64,
+    mix: Mix,
+    identification: u16,
+    sequences: Vec<u32>,
+    payload: Vec<u8>,
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl Generator {
+    /// Creates a `Generator` with the seed and the mix.
+    pub fn new(seed: u64, mix: Mix) -> Generator {
+        let mut generator = Generator {
+            // The state of xorshift must never be zero
+            state: (seed ^ DEFAULT_SEED) | 1,
+            mix,
+            identification: 0,
+            sequences: vec![0; FLOWS as usize],
+            payload: Vec::new(),
+            pending: VecDeque::new(),
+        };
+        generator.payload = (0..mix.max_size)
+            .map(|_| generator.next_u64() as u8)
+            .collect();
+
+        generator
+    }
+
+    /// Get the mix of the traffic.
+    pub fn get_mix(&self) -> Mix {
+        self.mix
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        // xorshift64*
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn next_below(&mut self, bound: u64) -> u64 {
+        match bound {
+            0 => 0,
+            _ => self.next_u64() % bound,
+        }
+    }
+
+    fn is_chosen(&mut self, percent: u8) -> bool {
+        self.next_below(100) < percent as u64
+    }
+
+    /// Generates a packet, and queues its frames.
+    fn generate(&mut self) {
+        let flow = self.next_below(FLOWS as u64) as u32;
+        let src = Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 6, 0, 2)) + flow);
+        let dst = Ipv4Addr::from(0x0101_0000 | self.next_below(0xffff) as u32);
+        let src_port = 40000 + flow as u16;
+        let size = self.mix.min_size
+            + self.next_below((self.mix.max_size - self.mix.min_size + 1) as u64) as usize;
+        let offset = self.next_below((self.mix.max_size - size + 1) as u64) as usize;
+
+        let (t, transport) = if self.is_chosen(self.mix.tcp_percent) {
+            let sequence = self.sequences[flow as usize];
+            self.sequences[flow as usize] = sequence.wrapping_add(size as u32);
+            let tcp = Tcp::new_ack(
+                IpAddr::V4(src),
+                IpAddr::V4(dst),
+                src_port,
+                443,
+                sequence,
+                0,
+                WINDOW,
+            );
+
+            (LayerTypes::Tcp, Layers::Tcp(tcp))
+        } else {
+            let udp = Udp::new(IpAddr::V4(src), IpAddr::V4(dst), src_port, 443);
+
+            (LayerTypes::Udp, Layers::Udp(udp))
+        };
+        self.identification = self.identification.wrapping_add(1);
+        let ipv4 = Ipv4::new(self.identification, t, src, dst).unwrap();
+        let ethernet =
+            Ethernet::new(LayerTypes::Ipv4, SRC_HARDWARE_ADDR, LOCAL_HARDWARE_ADDR).unwrap();
+        let indicator = Indicator::new(
+            Layers::Ethernet(ethernet),
+            Some(Layers::Ipv4(ipv4)),
+            Some(transport),
+        );
+        let frame = indicator
+            .to_vec_with_payload(&self.payload[offset..offset + size])
+            .unwrap();
+
+        let header_size = indicator.get_link_size() + indicator.get_ipv4().unwrap().get_size();
+        if frame.len() - header_size >= 16 && self.is_chosen(self.mix.fragment_percent) {
+            self.fragment(&indicator, &frame[header_size..]);
+        } else {
+            self.pending.push_back(frame);
+        }
+    }
+
+    /// Queues the frames of the IPv4 payload in fragments (RFC 791).
+    fn fragment(&mut self, indicator: &Indicator, payload: &[u8]) {
+        // A packet is split into at least 2 fragments, which are aligned to 8 Bytes except the
+        // last one
+        let max_length = FRAGMENT_SIZE.min((payload.len() / 2 + 7) / 8 * 8);
+        let ipv4 = indicator.get_ipv4().unwrap();
+        let mut n = 0;
+        while n < payload.len() {
+            let length = max_length.min(payload.len() - n);
+            let ipv4 = Ipv4::fragment(ipv4, (n / 8) as u16, n + length < payload.len());
+            let fragment =
+                Indicator::new(indicator.get_link().clone(), Some(Layers::Ipv4(ipv4)), None);
+            self.pending.push_back(
+                fragment
+                    .to_vec_with_payload(&payload[n..n + length])
+                    .unwrap(),
+            );
+            n += length;
+        }
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if self.pending.is_empty() {
+            self.generate();
+        }
+
+        self.pending.pop_front()
+    }
+}
+
+/// Represents the stages of the pipeline.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Stage {
+    /// Represents frames are copied out of the capture buffer and parsed.
+    Parse,
+    /// Represents IPv4 fragments are reassembled.
+    Reassemble,
+    /// Represents replies are built and serialized like the forwarder sends them.
+    Serialize,
+    /// Represents checksums of IPv4 packets are verified.
+    Checksum,
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            Stage::Parse => write!(f, "parse"),
+            Stage::Reassemble => write!(f, "reassemble"),
+            Stage::Serialize => write!(f, "serialize"),
+            Stage::Checksum => write!(f, "checksum"),
+        }
+    }
+}
+
+/// Represents the measurement of a stage.
+#[derive(Clone, Copy, Debug)]
+pub struct StageReport {
+    stage: Stage,
+    packets: usize,
+    bytes: usize,
+    duration: Duration,
+    allocations: u64,
+}
+
+impl StageReport {
+    fn new(stage: Stage) -> StageReport {
+        StageReport {
+            stage,
+            packets: 0,
+            bytes: 0,
+            duration: Duration::from_secs(0),
+            allocations: 0,
+        }
+    }
+
+    /// Times the closure and counts its allocations.
+    fn measure<T, F: FnOnce() -> T>(&mut self, f: F) -> T {
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        IS_COUNTING.store(true, Ordering::Relaxed);
+        let instant = Instant::now();
+        let result = f();
+        self.duration += instant.elapsed();
+        IS_COUNTING.store(false, Ordering::Relaxed);
+        self.allocations += ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+        result
+    }
+
+    /// Get the stage.
+    pub fn get_stage(&self) -> Stage {
+        self.stage
+    }
+
+    /// Get the number of packets processed.
+    pub fn get_packets(&self) -> usize {
+        self.packets
+    }
+
+    /// Get the number of bytes processed.
+    pub fn get_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Get the time spent.
+    pub fn get_duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Get the number of allocations, which is 0 if the `CountingAllocator` is not the global
+    /// allocator.
+    pub fn get_allocations(&self) -> u64 {
+        self.allocations
+    }
+
+    /// Get the packets processed per second.
+    pub fn get_packet_rate(&self) -> f64 {
+        match self.duration.as_secs_f64() {
+            secs if secs > 0.0 => self.packets as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// Get the MB processed per second.
+    pub fn get_byte_rate(&self) -> f64 {
+        match self.duration.as_secs_f64() {
+            secs if secs > 0.0 => self.bytes as f64 / secs / 1_000_000.0,
+            _ => 0.0,
+        }
+    }
+}
+
+impl Display for StageReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.packets == 0 {
+            return write!(f, "{:<10} no packets", self.stage.to_string());
+        }
+
+        write!(
+            f,
+            "{:<10} {:>10} packets {:>12.0} packets/s {:>10.1} MB/s {:>8.2} allocations/packet",
+            self.stage.to_string(),
+            self.packets,
+            self.get_packet_rate(),
+            self.get_byte_rate(),
+            self.allocations as f64 / self.packets as f64
+        )
+    }
+}
+
+/// Represents the result of a benchmark.
+#[derive(Clone, Debug)]
+pub struct Report {
+    mix: Mix,
+    frames: usize,
+    stages: Vec<StageReport>,
+}
+
+impl Report {
+    /// Get the number of frames generated.
+    pub fn get_frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Get the measurements of stages.
+    pub fn get_stages(&self) -> &[StageReport] {
+        &self.stages
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "Traffic: {} frames, {}", self.frames, self.mix)?;
+        for stage in &self.stages {
+            writeln!(f, "{}", stage)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Represents a packet parsed, which is replied in the serialize stage.
+struct Parsed {
+    t: LayerType,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    src_port: u16,
+    dst_port: u16,
+    sequence: u32,
+    size: usize,
+}
+
+impl Parsed {
+    fn from(indicator: &Indicator, size: usize) -> Option<Parsed> {
+        let ipv4 = indicator.get_ipv4()?;
+        let (t, src_port, dst_port, sequence) = match indicator.get_transport()? {
+            Layers::Tcp(tcp) => (
+                LayerTypes::Tcp,
+                tcp.get_src(),
+                tcp.get_dst(),
+                tcp.get_sequence(),
+            ),
+            Layers::Udp(udp) => (LayerTypes::Udp, udp.get_src(), udp.get_dst(), 0),
+            _ => return None,
+        };
+
+        Some(Parsed {
+            t,
+            src: ipv4.get_src(),
+            dst: ipv4.get_dst(),
+            src_port,
+            dst_port,
+            sequence,
+            size,
+        })
+    }
+
+    /// Builds the reply of the packet, like the forwarder builds it from the proxy.
+    fn reply(&self, identification: u16) -> Indicator {
+        let transport = match self.t {
+            LayerTypes::Tcp => Layers::Tcp(Tcp::new_ack(
+                IpAddr::V4(self.dst),
+                IpAddr::V4(self.src),
+                self.dst_port,
+                self.src_port,
+                0,
+                self.sequence.wrapping_add(self.size as u32),
+                WINDOW,
+            )),
+            _ => Layers::Udp(Udp::new(
+                IpAddr::V4(self.dst),
+                IpAddr::V4(self.src),
+                self.dst_port,
+                self.src_port,
+            )),
+        };
+        let ipv4 = Ipv4::new(identification, self.t, self.dst, self.src).unwrap();
+        let (link, vlan) = new_link(
+            LayerTypes::Ipv4,
+            LOCAL_HARDWARE_ADDR,
+            SRC_HARDWARE_ADDR,
+            None,
+        );
+
+        Indicator::with_vlan(link, vlan, Some(Layers::Ipv4(ipv4)), Some(transport))
+    }
+}
+
+/// Runs the frames of the generator through the stages of the pipeline, and measures each stage
+/// separately. Frames are processed in batches, and inputs of a stage are prepared before it is
+/// timed, so a stage is measured in the functions used in forwarding only.
+pub fn run(generator: &mut Generator, packets: usize, is_checksum: bool) -> Report {
+    let mut parse = StageReport::new(Stage::Parse);
+    let mut reassemble = StageReport::new(Stage::Reassemble);
+    let mut serialize = StageReport::new(Stage::Serialize);
+    let mut checksum = StageReport::new(Stage::Checksum);
+
+    let mut frame_pool = FramePool::new();
+    let mut defraggler = Defraggler::new();
+    let mut buffer_pool = BufferPool::new(u16::MAX as usize);
+    let payload = vec![0u8; MAX_PAYLOAD_SIZE];
+    let mut identification = 0u16;
+
+    let mut frames = 0;
+    let mut remaining = packets;
+    while remaining > 0 {
+        let batch = generator
+            .take(BATCH_SIZE.min(remaining))
+            .collect::<Vec<_>>();
+        remaining -= batch.len();
+        frames += batch.len();
+
+        // Parse
+        let errors = parse.measure(|| {
+            let mut errors = 0;
+            for frame in &batch {
+                let frame = frame_pool.copy_from(frame);
+                if Indicator::from_shared(&frame).is_err() {
+                    errors += 1;
+                }
+            }
+
+            errors
+        });
+        parse.packets += batch.len() - errors;
+        parse.bytes += batch.iter().map(|frame| frame.len()).sum::<usize>();
+
+        // Reassemble
+        let mut parsed = Vec::with_capacity(batch.len());
+        let mut fragments = Vec::new();
+        for frame in &batch {
+            let indicator = match Indicator::from(frame) {
+                Ok(indicator) => indicator,
+                Err(_) => continue,
+            };
+            match indicator.get_ipv4() {
+                Some(ipv4) if ipv4.is_fragment() => fragments.push((indicator, frame)),
+                _ => {
+                    if let Some(p) = Parsed::from(&indicator, frame.len() - indicator.get_size()) {
+                        parsed.push(p);
+                    }
+                }
+            }
+        }
+        let mut datagrams = Vec::with_capacity(fragments.len());
+        reassemble.measure(|| {
+            for (indicator, frame) in &fragments {
+                if let Some(frag) = defraggler.add(indicator, frame) {
+                    if let Some(datagram) = frag.concatenate() {
+                        datagrams.push(datagram);
+                    }
+                }
+            }
+        });
+        reassemble.packets += fragments.len();
+        reassemble.bytes += fragments
+            .iter()
+            .map(|(_, frame)| frame.len())
+            .sum::<usize>();
+        for (indicator, datagram) in &datagrams {
+            if let Some(p) = Parsed::from(indicator, datagram.len() - indicator.get_size()) {
+                parsed.push(p);
+            }
+        }
+
+        // Serialize
+        let bytes = serialize.measure(|| {
+            let mut bytes = 0;
+            for p in &parsed {
+                identification = identification.wrapping_add(1);
+                let indicator = p.reply(identification);
+                let (buffer, result) = serialize_frame(
+                    &mut buffer_pool,
+                    &indicator,
+                    Some(&payload[..p.size.min(MAX_PAYLOAD_SIZE)]),
+                );
+                if let Ok(n) = result {
+                    bytes += n;
+                }
+                buffer_pool.put(buffer);
+            }
+
+            bytes
+        });
+        serialize.packets += parsed.len();
+        serialize.bytes += bytes;
+
+        // Checksum
+        if is_checksum {
+            checksum.measure(|| {
+                for frame in &batch {
+                    if let Some((_, ip)) = pcap::get_network(frame) {
+                        packet::check_checksum(ip);
+                    }
+                }
+            });
+            checksum.packets += batch.len();
+            checksum.bytes += batch.iter().map(|frame| frame.len()).sum::<usize>();
+        }
+    }
+
+    let mut stages = vec![parse, reassemble, serialize];
+    if is_checksum {
+        stages.push(checksum);
+    }
+
+    Report {
+        mix: generator.get_mix(),
+        frames,
+        stages,
+    }
+}
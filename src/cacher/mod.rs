@@ -3,6 +3,8 @@ use std::collections::BTreeMap;
 use std::io;
 use std::ops::Bound::Included;
 
+use crate::memory::Allocation;
+
 /// Represents the initial size of cache.
 const INITIAL_SIZE: usize = 64 * 1024;
 /// Represents the expansion factor of the cache. The cache will be expanded by the factor.
@@ -10,12 +12,17 @@ const EXPANSION_FACTOR: f64 = 1.5;
 
 /// Represents the max distance of u32 values between packets in an u32 window.
 const MAX_U32_WINDOW_SIZE: usize = 4 * 1024 * 1024;
+/// Represents the size of the ring of sequences.
+const SEQUENCE_RING: u64 = 1 << 32;
 
-/// Represents the linear cache.
+/// Represents the linear cache, which is a ring buffer indexed by the sequence. Bytes are
+/// trimmed from the beginning by moving the head, and read in place as slices.
 #[derive(Debug)]
 pub struct Cacher {
     buffer: Vec<u8>,
-    expandable: bool,
+    /// Represents the max size the buffer can grow to
+    capacity: usize,
+    allocation: Allocation,
     sequence: u32,
     head: usize,
     size: usize,
@@ -24,9 +31,18 @@ pub struct Cacher {
 impl Cacher {
     /// Creates a new `Cacher`.
     pub fn new(sequence: u32) -> Cacher {
+        Cacher::with_capacity(sequence, INITIAL_SIZE)
+    }
+
+    /// Creates a new `Cacher` in the given capacity. The buffer is allocated in a smaller size
+    /// and grows to the capacity on demand.
+    pub fn with_capacity(sequence: u32, capacity: usize) -> Cacher {
+        let size = min(capacity, INITIAL_SIZE);
+
         Cacher {
-            buffer: vec![0; INITIAL_SIZE],
-            expandable: false,
+            buffer: vec![0; size],
+            capacity,
+            allocation: Allocation::new(size),
             sequence,
             head: 0,
             size: 0,
@@ -35,41 +51,22 @@ impl Cacher {
 
     /// Creates a new `Cacher` which can increase its size dynamically.
     pub fn new_expandable(sequence: u32) -> Cacher {
-        let mut cacher = Cacher::new(sequence);
-        cacher.expandable = true;
-
-        cacher
+        Cacher::with_capacity(sequence, usize::MAX)
     }
 
     /// Appends some bytes to the end of the cache.
     pub fn append(&mut self, buffer: &[u8]) -> io::Result<()> {
         if buffer.len() > self.buffer.len() - self.size {
-            if self.is_expandable() {
-                // Extend the buffer
-                let size = max(
-                    (self.buffer.len() as f64 * EXPANSION_FACTOR) as usize,
-                    self.buffer.len() + buffer.len(),
-                );
-
-                let mut new_buffer = vec![0u8; size];
-
-                // From the head to the end of the buffer
-                let length_a = min(self.size, self.buffer.len() - self.head);
-                new_buffer[..length_a]
-                    .copy_from_slice(&self.buffer[self.head..self.head + length_a]);
-
-                // From the begin of the buffer to the tail
-                let length_b = self.size - length_a;
-                if length_b > 0 {
-                    new_buffer[length_a..length_a + length_b]
-                        .copy_from_slice(&self.buffer[..length_b]);
-                }
-
-                self.buffer = new_buffer;
-                self.head = 0;
-            } else {
+            if self.size + buffer.len() > self.capacity {
                 return Err(io::Error::new(io::ErrorKind::Other, "cache is full"));
             }
+
+            // Extend the buffer
+            let size = max(
+                (self.buffer.len() as f64 * EXPANSION_FACTOR) as usize,
+                self.size + buffer.len(),
+            );
+            self.grow(min(size, self.capacity));
         }
 
         // From the tail to the end of the buffer
@@ -91,13 +88,41 @@ impl Cacher {
         Ok(())
     }
 
-    // Invalidates cache to the certain sequence.
+    /// Extends the buffer to the given size, bytes are moved to the beginning of the new buffer.
+    fn grow(&mut self, size: usize) {
+        let mut new_buffer = vec![0u8; size];
+
+        // From the head to the end of the buffer
+        let length_a = min(self.size, self.buffer.len() - self.head);
+        new_buffer[..length_a].copy_from_slice(&self.buffer[self.head..self.head + length_a]);
+
+        // From the begin of the buffer to the tail
+        let length_b = self.size - length_a;
+        if length_b > 0 {
+            new_buffer[length_a..length_a + length_b].copy_from_slice(&self.buffer[..length_b]);
+        }
+
+        self.buffer = new_buffer;
+        self.allocation.resize(size);
+        self.head = 0;
+    }
+
+    /// Releases the buffer grown beyond the initial size if the cache is empty.
+    pub fn shrink(&mut self) {
+        let size = min(self.capacity, INITIAL_SIZE);
+        if self.size == 0 && self.buffer.len() > size {
+            self.buffer = vec![0u8; size];
+            self.allocation.resize(size);
+            self.head = 0;
+        }
+    }
+
+    /// Invalidates cache to the certain sequence. Sequences behind the beginning or too far away
+    /// are ignored.
     pub fn invalidate_to(&mut self, sequence: u32) {
-        let size = sequence
-            .checked_sub(self.sequence)
-            .unwrap_or_else(|| u32::MAX - self.sequence + sequence) as usize;
+        let size = sequence.wrapping_sub(self.sequence) as usize;
 
-        if size <= MAX_U32_WINDOW_SIZE as usize {
+        if size <= MAX_U32_WINDOW_SIZE {
             self.sequence = sequence;
             self.size = self.size.checked_sub(size).unwrap_or(0);
             if self.size == 0 {
@@ -110,29 +135,39 @@ impl Cacher {
 
     /// Get the buffer from the beginning of the cache in the given size.
     pub fn get(&self, size: usize) -> io::Result<Vec<u8>> {
-        if size == 0 {
-            return Ok(Vec::new());
-        }
-        if self.size < size {
+        let (a, b) = self.get_slices(0, size)?;
+
+        let mut vector = Vec::with_capacity(size);
+        vector.extend_from_slice(a);
+        vector.extend_from_slice(b);
+
+        Ok(vector)
+    }
+
+    /// Get the bytes in the given range from the beginning of the cache without copying. The
+    /// bytes are returned in two slices as the range may wrap around the end of the buffer.
+    pub fn get_slices(&self, offset: usize, size: usize) -> io::Result<(&[u8], &[u8])> {
+        if offset + size > self.size {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "request size too big",
             ));
         }
-
-        let mut vector = vec![0u8; size];
+        if size == 0 {
+            return Ok((&[], &[]));
+        }
 
         // From the head to the end of the buffer
-        let length_a = min(size, self.buffer.len() - self.head);
-        vector[..length_a].copy_from_slice(&self.buffer[self.head..self.head + length_a]);
+        let begin = (self.head + offset) % self.buffer.len();
+        let length_a = min(size, self.buffer.len() - begin);
 
         // From the begin of the buffer to the tail
         let length_b = size - length_a;
-        if length_b > 0 {
-            vector[length_a..].copy_from_slice(&self.buffer[..length_b]);
-        }
 
-        Ok(vector)
+        Ok((
+            &self.buffer[begin..begin + length_a],
+            &self.buffer[..length_b],
+        ))
     }
 
     /// Get all the buffer of the cache.
@@ -150,8 +185,16 @@ impl Cacher {
         self.size
     }
 
+    /// Get the remaining size of the cache. An expandable cache may hold more bytes than this.
+    pub fn get_remaining_size(&self) -> usize {
+        match self.is_expandable() {
+            true => self.buffer.len() - self.size,
+            false => self.capacity - self.size,
+        }
+    }
+
     fn is_expandable(&self) -> bool {
-        self.expandable
+        self.capacity == usize::MAX
     }
 }
 
@@ -159,21 +202,33 @@ impl Cacher {
 #[derive(Debug)]
 pub struct RandomCacher {
     buffer: Vec<u8>,
-    expandable: bool,
+    /// Represents the max size the buffer can grow to
+    capacity: usize,
+    allocation: Allocation,
     sequence: u32,
     head: usize,
     /// Represents the expected size from the head to the tail. NOT all the bytes in [head, head + size) are existed.
     size: usize,
     /// Represents edges of existing values. Use an u64 instead of an u32 because the sequence is used as a ring.
+    /// Edges are disjoint and not adjacent.
     edges: BTreeMap<u64, usize>,
 }
 
 impl RandomCacher {
     /// Creates a new `RandomCacher`.
     pub fn new(sequence: u32) -> RandomCacher {
+        RandomCacher::with_capacity(sequence, INITIAL_SIZE)
+    }
+
+    /// Creates a new `RandomCacher` in the given capacity. The buffer is allocated in a smaller
+    /// size and grows to the capacity on demand.
+    pub fn with_capacity(sequence: u32, capacity: usize) -> RandomCacher {
+        let size = min(capacity, INITIAL_SIZE);
+
         RandomCacher {
-            buffer: vec![0u8; INITIAL_SIZE],
-            expandable: false,
+            buffer: vec![0u8; size],
+            capacity,
+            allocation: Allocation::new(size),
             sequence,
             head: 0,
             size: 0,
@@ -183,46 +238,43 @@ impl RandomCacher {
 
     /// Creates a new `RandomCacher` which can increase its size dynamically.
     pub fn new_expandable(sequence: u32) -> RandomCacher {
-        let mut cacher = RandomCacher::new(sequence);
-        cacher.expandable = true;
-
-        cacher
+        RandomCacher::with_capacity(sequence, usize::MAX)
     }
 
-    /// Appends some bytes to the cache and returns continuous bytes from the beginning.
+    /// Appends some bytes to the cache and returns continuous bytes from the beginning. Bytes which
+    /// have been returned are trimmed, and bytes beyond the capacity are refused if the cache is not
+    /// expandable.
     pub fn append(&mut self, sequence: u32, buffer: &[u8]) -> io::Result<Option<Vec<u8>>> {
-        let sub_sequence = sequence
-            .checked_sub(self.sequence)
-            .unwrap_or_else(|| sequence + (u32::MAX - self.sequence))
-            as usize;
+        let mut sequence = sequence;
+        let mut buffer = buffer;
+
+        // Trim bytes before the beginning
+        let sub_sequence = sequence.wrapping_sub(self.sequence) as usize;
         if sub_sequence > MAX_U32_WINDOW_SIZE {
-            return Ok(None);
+            let returned = self.sequence.wrapping_sub(sequence) as usize;
+            if returned > MAX_U32_WINDOW_SIZE || returned >= buffer.len() {
+                return Ok(None);
+            }
+            buffer = &buffer[returned..];
+            sequence = self.sequence;
         }
+        let sub_sequence = sequence.wrapping_sub(self.sequence) as usize;
 
+        if sub_sequence + buffer.len() > self.capacity {
+            // Refuse bytes beyond the capacity
+            if sub_sequence >= self.capacity {
+                return Ok(None);
+            }
+            buffer = &buffer[..self.capacity - sub_sequence];
+        }
         let size = sub_sequence + buffer.len();
         if size > self.buffer.len() {
-            if self.is_expandable() {
-                // Extend the buffer
-                let size = max((self.buffer.len() as f64 * EXPANSION_FACTOR) as usize, size);
-
-                let mut new_buffer = vec![0u8; size];
-
-                // TODO: the procedure may by optimized to copy valid bytes only
-                // From the head to the end of the buffer
-                new_buffer[..self.buffer.len() - self.head]
-                    .copy_from_slice(&self.buffer[self.head..]);
-
-                // From the begin of the buffer to the tail
-                if self.head > 0 {
-                    new_buffer[self.buffer.len() - self.head..self.buffer.len()]
-                        .copy_from_slice(&self.buffer[..self.head]);
-                }
-
-                self.buffer = new_buffer;
-                self.head = 0;
-            } else {
-                return Err(io::Error::new(io::ErrorKind::Other, "cache is full"));
-            }
+            // Extend the buffer
+            let size = max((self.buffer.len() as f64 * EXPANSION_FACTOR) as usize, size);
+            self.grow(min(size, self.capacity));
+        }
+        if buffer.is_empty() {
+            return Ok(None);
         }
 
         // TODO: the procedure may by optimized to copy valid bytes only
@@ -237,73 +289,53 @@ impl RandomCacher {
         // From the begin of the buffer
         let length_b = buffer.len() - length_a;
         if length_b > 0 {
-            self.buffer[..length_b].copy_from_slice(&buffer[length_a..]);
+            let begin = (self.head + sub_sequence + length_a) % self.buffer.len();
+            self.buffer[begin..begin + length_b].copy_from_slice(&buffer[length_a..]);
         }
 
         // Update size
-        let tail = sequence
-            .checked_add(buffer.len() as u32)
-            .unwrap_or_else(|| buffer.len() as u32 - (u32::MAX - sequence));
-        let record_tail = self
-            .sequence
-            .checked_add(self.size as u32)
-            .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence));
-        let sub_tail = tail
-            .checked_sub(record_tail)
-            .unwrap_or_else(|| tail + (u32::MAX - record_tail));
+        let tail = sequence.wrapping_add(buffer.len() as u32);
+        let record_tail = self.sequence.wrapping_add(self.size as u32);
+        let sub_tail = tail.wrapping_sub(record_tail);
         if sub_tail as usize <= MAX_U32_WINDOW_SIZE {
             self.size += sub_tail as usize;
         }
 
         // Insert and merge ranges
         {
+            // The sequence is not before the beginning after trimming, so it wraps around if it
+            // is less than the beginning
             let mut sequence = sequence as u64;
             if (sequence as u32) < self.sequence {
-                sequence += u32::MAX as u64;
+                sequence += SEQUENCE_RING;
             }
 
-            // Select ranges which can be merged in a loop
+            // Select ranges which can be merged
             let mut end = sequence + buffer.len() as u64;
-            loop {
-                let mut pop_keys = Vec::new();
-                for (&key, &value) in self.edges.range((
-                    Included(&sequence),
-                    Included(&(sequence + buffer.len() as u64)),
-                )) {
-                    pop_keys.push(key);
-                    end = max(end, key + value as u64);
-                }
-
-                if pop_keys.len() <= 0 {
-                    break;
-                }
-
-                // Pop
-                for ref pop_key in pop_keys {
-                    self.edges.remove(pop_key);
-                }
+            let mut pop_keys = Vec::new();
+            for (&key, &value) in self.edges.range((
+                Included(&sequence),
+                Included(&(sequence + buffer.len() as u64)),
+            )) {
+                pop_keys.push(key);
+                end = max(end, key + value as u64);
             }
 
-            // Select the previous range if exists
-            let mut prev_key = None;
-            for &key in self.edges.keys() {
-                if key < sequence {
-                    prev_key = Some(key);
-                }
+            // Pop
+            for ref pop_key in pop_keys {
+                self.edges.remove(pop_key);
             }
 
-            // Merge previous range
-            let mut size = end - sequence;
-            if let Some(prev_key) = prev_key {
-                let prev_size = *self.edges.get(&prev_key).unwrap();
+            // Merge the previous range if exists
+            if let Some((&prev_key, &prev_size)) = self.edges.range(..sequence).next_back() {
                 if prev_key + (prev_size as u64) >= sequence {
-                    size += sequence - prev_key;
+                    end = max(end, prev_key + prev_size as u64);
                     sequence = prev_key;
                 }
             }
 
             // Insert range
-            self.edges.insert(sequence, size as usize);
+            self.edges.insert(sequence, (end - sequence) as usize);
         }
 
         // Pop if possible
@@ -312,12 +344,12 @@ impl RandomCacher {
             let size = self.edges.remove(&first_key).unwrap();
 
             // Shrink range sequence is possible
-            if ((u32::MAX - self.sequence) as usize) < size {
+            if self.sequence as u64 + size as u64 >= SEQUENCE_RING {
                 let keys: Vec<_> = self.edges.keys().map(|x| *x).collect();
 
                 for key in keys {
                     let value = self.edges.remove(&key).unwrap();
-                    self.edges.insert(key - u32::MAX as u64, value);
+                    self.edges.insert(key - SEQUENCE_RING, value);
                 }
             }
 
@@ -333,10 +365,7 @@ impl RandomCacher {
                 vector[length_a..].copy_from_slice(&self.buffer[..length_b]);
             }
 
-            self.sequence = self
-                .sequence
-                .checked_add(size as u32)
-                .unwrap_or_else(|| size as u32 - (u32::MAX - self.sequence));
+            self.sequence = self.sequence.wrapping_add(size as u32);
             self.head = (self.head + (size % self.buffer.len())) % self.buffer.len();
             self.size -= vector.len();
 
@@ -346,21 +375,201 @@ impl RandomCacher {
         Ok(None)
     }
 
+    /// Extends the buffer to the given size, bytes are moved to the beginning of the new buffer.
+    fn grow(&mut self, size: usize) {
+        let mut new_buffer = vec![0u8; size];
+
+        // TODO: the procedure may by optimized to copy valid bytes only
+        // From the head to the end of the buffer
+        new_buffer[..self.buffer.len() - self.head].copy_from_slice(&self.buffer[self.head..]);
+
+        // From the begin of the buffer to the tail
+        if self.head > 0 {
+            new_buffer[self.buffer.len() - self.head..self.buffer.len()]
+                .copy_from_slice(&self.buffer[..self.head]);
+        }
+
+        self.buffer = new_buffer;
+        self.allocation.resize(size);
+        self.head = 0;
+    }
+
+    /// Discards the bytes cached but not continuous from the beginning, which are retransmitted
+    /// by the sender later (RFC 2018), and releases the buffer grown beyond the initial size.
+    /// Returns `false` if there is nothing to discard.
+    pub fn discard(&mut self) -> bool {
+        let is_discarded = !self.edges.is_empty();
+        self.edges.clear();
+        self.size = 0;
+
+        let size = min(self.capacity, INITIAL_SIZE);
+        if self.buffer.len() > size {
+            self.buffer = vec![0u8; size];
+            self.allocation.resize(size);
+            self.head = 0;
+        }
+
+        is_discarded
+    }
+
     /// Get the sequence of the cache.
     pub fn get_sequence(&self) -> u32 {
         self.sequence
     }
 
-    /// Get the remaining size of the `RandomCacher`.
-    pub fn get_remaining_size(&self) -> u16 {
-        if self.buffer.len() - self.size > u16::MAX as usize {
-            u16::MAX
-        } else {
-            (self.buffer.len() - self.size) as u16
+    /// Get the ranges of bytes which are cached but not continuous from the beginning, each range
+    /// is represented by its left edge and right edge in sequence.
+    pub fn get_blocks(&self) -> Vec<(u32, u32)> {
+        self.edges
+            .iter()
+            .map(|(&key, &value)| {
+                let left = key as u32;
+
+                (left, left.wrapping_add(value as u32))
+            })
+            .collect()
+    }
+
+    /// Get the remaining size of the `RandomCacher`. An expandable cache may hold more bytes than
+    /// this.
+    pub fn get_remaining_size(&self) -> usize {
+        match self.is_expandable() {
+            true => self.buffer.len() - self.size,
+            false => self.capacity - self.size,
         }
     }
 
     fn is_expandable(&self) -> bool {
-        self.expandable
+        self.capacity == usize::MAX
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get data of the size, each byte is different from its neighbors.
+    fn new_data(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn cacher_wrap() {
+        let sequence = u32::MAX - 100;
+        let data = new_data(300);
+        let mut cache = Cacher::new(sequence);
+        cache.append(&data).unwrap();
+        assert_eq!(cache.get_all().unwrap(), data);
+
+        // Acknowledged across the wrap-around
+        cache.invalidate_to(sequence.wrapping_add(200));
+        assert_eq!(cache.get_sequence(), 99);
+        assert_eq!(cache.get_size(), 100);
+        assert_eq!(cache.get_all().unwrap(), &data[200..]);
+
+        // Acknowledgements before the beginning are ignored
+        cache.invalidate_to(sequence.wrapping_add(150));
+        assert_eq!(cache.get_sequence(), 99);
+        assert_eq!(cache.get_size(), 100);
+        cache.invalidate_to(sequence);
+        assert_eq!(cache.get_sequence(), 99);
+
+        cache.invalidate_to(199);
+        assert_eq!(cache.get_size(), 0);
+    }
+
+    #[test]
+    fn random_cacher_wrap() {
+        let sequence = u32::MAX - 100;
+        let data = new_data(300);
+        let mut cache = RandomCacher::new(sequence);
+
+        // Out of order after the wrap-around
+        assert_eq!(cache.append(150, &data[251..]).unwrap(), None);
+        assert_eq!(cache.append(50, &data[151..201]).unwrap(), None);
+        assert_eq!(cache.get_blocks(), vec![(50, 100), (150, 199)]);
+
+        // Retransmission before the beginning is ignored
+        assert_eq!(cache.append(sequence - 10, &data[..1]).unwrap(), None);
+
+        // The hole is filled across the wrap-around
+        let received = cache.append(sequence, &data[..151]).unwrap().unwrap();
+        assert_eq!(received, &data[..201]);
+        assert_eq!(cache.get_sequence(), 100);
+        assert_eq!(cache.get_blocks(), vec![(150, 199)]);
+        let received = cache.append(100, &data[201..251]).unwrap().unwrap();
+        assert_eq!(received, &data[201..]);
+        assert_eq!(cache.get_sequence(), 199);
+        assert!(cache.get_blocks().is_empty());
+
+        // Retransmission of bytes partially received
+        assert_eq!(cache.append(150, &data[251..]).unwrap(), None);
+        let received = cache.append(190, &[1u8; 20]).unwrap().unwrap();
+        assert_eq!(received, &[1u8; 11]);
+        assert_eq!(cache.get_sequence(), 210);
+    }
+
+    #[test]
+    fn random_cacher_overlap() {
+        let data = new_data(2000);
+        let mut cache = RandomCacher::with_capacity(1000, 1000);
+
+        // Duplicated and overlapping segments out of order are merged
+        assert_eq!(cache.append(1200, &data[200..400]).unwrap(), None);
+        assert_eq!(cache.append(1200, &data[200..400]).unwrap(), None);
+        assert_eq!(cache.get_blocks(), vec![(1200, 1400)]);
+        assert_eq!(cache.append(1300, &data[300..600]).unwrap(), None);
+        assert_eq!(cache.append(1100, &data[100..250]).unwrap(), None);
+        assert_eq!(cache.get_blocks(), vec![(1100, 1600)]);
+
+        // Overlapping bytes are returned only once
+        let received = cache.append(1000, &data[..150]).unwrap().unwrap();
+        assert_eq!(received, &data[..600]);
+        assert_eq!(cache.get_sequence(), 1600);
+        assert!(cache.get_blocks().is_empty());
+        assert_eq!(cache.append(1000, &data[..600]).unwrap(), None);
+        let received = cache.append(1500, &data[500..700]).unwrap().unwrap();
+        assert_eq!(received, &data[600..700]);
+
+        // Bytes beyond the capacity are refused
+        assert_eq!(cache.append(2600, &data[1600..1800]).unwrap(), None);
+        assert_eq!(cache.get_blocks(), vec![(2600, 2700)]);
+        assert_eq!(cache.append(2700, &data[1700..1800]).unwrap(), None);
+        assert_eq!(cache.get_blocks(), vec![(2600, 2700)]);
+        let received = cache.append(1700, &data[700..2000]).unwrap().unwrap();
+        assert_eq!(received, &data[700..1700]);
+        assert_eq!(cache.get_sequence(), 2700);
+        assert_eq!(cache.get_remaining_size(), 1000);
+    }
+    #[test]
+    fn random_cacher_wrap_shuffled() {
+        let sequence = u32::MAX - 3000;
+        let data = new_data(10000);
+        let mut cache = RandomCacher::new(sequence);
+
+        // Segments in a fixed shuffle, some are duplicated
+        let mut offsets: Vec<usize> = (0..data.len()).step_by(500).collect();
+        let mut state = 0x2545f4914f6cdd1du64;
+        for i in (1..offsets.len()).rev() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            offsets.swap(i, state as usize % (i + 1));
+        }
+        offsets.extend_from_slice(&offsets.clone()[..5]);
+
+        let mut received = Vec::new();
+        for offset in offsets {
+            let segment = &data[offset..offset + 500];
+            if let Some(bytes) = cache
+                .append(sequence.wrapping_add(offset as u32), segment)
+                .unwrap()
+            {
+                received.extend_from_slice(&bytes);
+            }
+        }
+        assert_eq!(received, data);
+        assert_eq!(cache.get_sequence(), sequence.wrapping_add(10000));
+        assert!(cache.get_blocks().is_empty());
     }
 }
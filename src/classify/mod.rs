@@ -0,0 +1,624 @@
+//! Classification of TCP connections by their destination ports and the names of the services
+//! in their first payloads, which are the TLS SNI and the HTTP Host. Payloads are only sniffed
+//! in copies of the first bytes, so relaying is never delayed, and nothing but the name is kept.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Represents the max size of the first payload sniffed.
+const MAX_SNIFF_SIZE: usize = 2048;
+/// Represents the max number of classes counted, classes after it are counted by their ports
+/// alone.
+const MAX_CLASSES: usize = 1024;
+/// Represents the max length of a name.
+const MAX_NAME_LENGTH: usize = 253;
+
+/// Represents the port of HTTP, whose Host is sniffed.
+const HTTP_PORT: u16 = 80;
+/// Represents the port of HTTPS, whose TLS SNI is sniffed.
+const HTTPS_PORT: u16 = 443;
+
+/// Represents the content type of TLS handshake records.
+const TLS_HANDSHAKE: u8 = 0x16;
+/// Represents the type of TLS ClientHello messages.
+const TLS_CLIENT_HELLO: u8 = 0x01;
+/// Represents the type of the server name extension of TLS.
+const TLS_SERVER_NAME: u16 = 0x0000;
+/// Represents the size of TLS record headers.
+const TLS_RECORD_HEADER_SIZE: usize = 5;
+
+/// Represents if connections are classified.
+static IS_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Represents the bytes up and down of classes.
+static CLASSES: Mutex<Option<HashMap<Class, (u64, u64)>>> = Mutex::new(None);
+
+/// Sets if TCP connections opened later are classified.
+pub fn set_enabled(is_enabled: bool) {
+    IS_ENABLED.store(is_enabled, Ordering::Relaxed);
+}
+
+/// Returns if TCP connections are classified.
+pub fn is_enabled() -> bool {
+    IS_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Get the classes with their bytes up and down, in the descending order of the total bytes.
+pub fn get_classes() -> Vec<(Class, u64, u64)> {
+    let classes = CLASSES.lock().unwrap();
+    let mut classes: Vec<_> = match *classes {
+        Some(ref classes) => classes
+            .iter()
+            .map(|(class, (up, down))| (class.clone(), *up, *down))
+            .collect(),
+        None => Vec::new(),
+    };
+    classes.sort_by(|a, b| (b.1 + b.2).cmp(&(a.1 + a.2)).then(a.0.cmp(&b.0)));
+
+    classes
+}
+
+/// Counts bytes up and down of the class.
+fn count(class: &Class, up: u64, down: u64) {
+    if up == 0 && down == 0 {
+        return;
+    }
+
+    let mut classes = CLASSES.lock().unwrap();
+    let classes = classes.get_or_insert_with(HashMap::new);
+    let key = match classes.len() < MAX_CLASSES || classes.contains_key(class) {
+        true => class.clone(),
+        false => Class::new(None, class.port),
+    };
+    let entry = classes.entry(key).or_insert((0, 0));
+    entry.0 += up;
+    entry.1 += down;
+}
+
+/// Represents the class of a connection, which is the destination port and the name of the
+/// service if it is sniffed.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Class {
+    name: Option<String>,
+    port: u16,
+}
+
+impl Class {
+    fn new(name: Option<String>, port: u16) -> Class {
+        Class { name, port }
+    }
+
+    /// Get the name of the service.
+    pub fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Get the destination port.
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Display for Class {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "{}:{}", name, self.port),
+            None => write!(f, "port {}", self.port),
+        }
+    }
+}
+
+/// Represents the results of sniffing a payload.
+#[derive(Debug, Eq, PartialEq)]
+enum Sniff {
+    /// Represents the name is found.
+    Name(String),
+    /// Represents more bytes are required.
+    Incomplete,
+    /// Represents the payload has no name, or it is malformed.
+    Unknown,
+}
+
+#[derive(Debug)]
+enum State {
+    /// Represents the first bytes of the payload are being sniffed
+    Sniffing(Vec<u8>),
+    Classified(Class),
+}
+
+/// Represents the classifier of a TCP connection. Bytes counted before the connection is
+/// classified are held, and counted to the class once it is classified.
+#[derive(Debug)]
+pub struct Classifier {
+    port: u16,
+    state: State,
+    /// Represents the bytes up and down held while sniffing
+    held: (u64, u64),
+}
+
+impl Classifier {
+    /// Creates a new `Classifier` of a TCP connection to the destination port. Only the payloads
+    /// to the ports of HTTP and HTTPS are sniffed, the other connections are classified by their
+    /// ports immediately.
+    pub fn new(port: u16) -> Classifier {
+        let state = match port {
+            HTTP_PORT | HTTPS_PORT => State::Sniffing(Vec::new()),
+            _ => State::Classified(Class::new(None, port)),
+        };
+
+        Classifier {
+            port,
+            state,
+            held: (0, 0),
+        }
+    }
+
+    /// Sniffs a payload from the source. Payloads after the connection is classified are
+    /// ignored.
+    pub fn sniff(&mut self, payload: &[u8]) {
+        let buffer = match self.state {
+            State::Sniffing(ref mut buffer) => buffer,
+            State::Classified(_) => return,
+        };
+        let size = payload.len().min(MAX_SNIFF_SIZE - buffer.len());
+        buffer.extend_from_slice(&payload[..size]);
+
+        let sniff = match self.port {
+            HTTPS_PORT => sniff_sni(buffer),
+            _ => sniff_host(buffer),
+        };
+        let name = match sniff {
+            Sniff::Name(name) => Some(name),
+            Sniff::Incomplete if buffer.len() < MAX_SNIFF_SIZE => return,
+            Sniff::Incomplete | Sniff::Unknown => None,
+        };
+        self.classify(name);
+    }
+
+    fn classify(&mut self, name: Option<String>) {
+        let class = Class::new(name, self.port);
+        count(&class, self.held.0, self.held.1);
+        self.held = (0, 0);
+        self.state = State::Classified(class);
+    }
+
+    /// Counts bytes sent from the source to the destination.
+    pub fn count_up(&mut self, n: usize) {
+        match self.state {
+            State::Sniffing(_) => self.held.0 += n as u64,
+            State::Classified(ref class) => count(class, n as u64, 0),
+        }
+    }
+
+    /// Counts bytes sent from the destination to the source.
+    pub fn count_down(&mut self, n: usize) {
+        match self.state {
+            State::Sniffing(_) => self.held.1 += n as u64,
+            State::Classified(ref class) => count(class, 0, n as u64),
+        }
+    }
+
+    /// Get the class of the connection if it is classified.
+    pub fn get_class(&self) -> Option<&Class> {
+        match self.state {
+            State::Sniffing(_) => None,
+            State::Classified(ref class) => Some(class),
+        }
+    }
+}
+
+impl Drop for Classifier {
+    fn drop(&mut self) {
+        // A connection closed before it is classified is classified by its port
+        if let State::Sniffing(_) = self.state {
+            self.classify(None);
+        }
+    }
+}
+
+/// Represents a reader of a buffer which may be truncated.
+struct Reader<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buffer: &'a [u8]) -> Reader<'a> {
+        Reader { buffer, offset: 0 }
+    }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.buffer.get(self.offset..self.offset + n)?;
+        self.offset += n;
+
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3)
+            .map(|bytes| u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+}
+
+/// Sniffs the server name in the TLS ClientHello at the beginning of the buffer. A ClientHello
+/// spanning multiple records is read through them.
+fn sniff_sni(buffer: &[u8]) -> Sniff {
+    match buffer.first() {
+        Some(&TLS_HANDSHAKE) => {}
+        Some(_) => return Sniff::Unknown,
+        None => return Sniff::Incomplete,
+    }
+
+    // Concatenate the bodies of the handshake records received
+    let mut handshake = Vec::new();
+    let mut begin = 0;
+    while begin < buffer.len() {
+        let header = match buffer.get(begin..begin + TLS_RECORD_HEADER_SIZE) {
+            Some(header) => header,
+            None => break,
+        };
+        if header[0] != TLS_HANDSHAKE || header[1] != 0x03 {
+            return Sniff::Unknown;
+        }
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let end = begin + TLS_RECORD_HEADER_SIZE + length;
+        handshake.extend_from_slice(&buffer[begin + TLS_RECORD_HEADER_SIZE..end.min(buffer.len())]);
+        begin = end;
+    }
+
+    match parse_client_hello(&handshake) {
+        Ok(Some(name)) => Sniff::Name(name),
+        Ok(None) => Sniff::Unknown,
+        Err(sniff) => sniff,
+    }
+}
+
+/// Parses the server name in a ClientHello (RFC 8446), returns `Ok(None)` if it has no server
+/// name. Extensions are parsed as they are received, so the server name is found without the
+/// rest of the ClientHello.
+fn parse_client_hello(handshake: &[u8]) -> Result<Option<String>, Sniff> {
+    let mut reader = Reader::new(handshake);
+    match reader.u8() {
+        Some(TLS_CLIENT_HELLO) => {}
+        Some(_) => return Err(Sniff::Unknown),
+        None => return Err(Sniff::Incomplete),
+    }
+    let length = reader.u24().ok_or(Sniff::Incomplete)?;
+    let end = 4 + length;
+    // Reading beyond the bytes received requires more bytes, or the message is malformed if it
+    // is complete
+    let is_complete = handshake.len() >= end;
+    let truncated = || match is_complete {
+        true => Sniff::Unknown,
+        false => Sniff::Incomplete,
+    };
+    let mut reader = Reader::new(&handshake[4..handshake.len().min(end)]);
+
+    // Version and random
+    reader.bytes(2 + 32).ok_or_else(truncated)?;
+    // Session ID, cipher suites and compression methods
+    let size = reader.u8().ok_or_else(truncated)? as usize;
+    reader.bytes(size).ok_or_else(truncated)?;
+    let size = reader.u16().ok_or_else(truncated)? as usize;
+    reader.bytes(size).ok_or_else(truncated)?;
+    let size = reader.u8().ok_or_else(truncated)? as usize;
+    reader.bytes(size).ok_or_else(truncated)?;
+    // Extensions, a ClientHello without extensions has no server name
+    let size = match reader.u16() {
+        Some(size) => size as usize,
+        None if is_complete => return Ok(None),
+        None => return Err(Sniff::Incomplete),
+    };
+    let rest = &reader.buffer[reader.offset..];
+    let mut reader = Reader::new(&rest[..rest.len().min(size)]);
+    while reader.offset < size {
+        let t = reader.u16().ok_or_else(truncated)?;
+        let size = reader.u16().ok_or_else(truncated)? as usize;
+        let data = reader.bytes(size).ok_or_else(truncated)?;
+        if t == TLS_SERVER_NAME {
+            return parse_server_name(data).map(Some).ok_or(Sniff::Unknown);
+        }
+    }
+
+    Ok(None)
+}
+
+/// Parses the host name in the server name extension of TLS (RFC 6066).
+fn parse_server_name(data: &[u8]) -> Option<String> {
+    let mut reader = Reader::new(data);
+    let size = reader.u16()? as usize;
+    let mut reader = Reader::new(reader.bytes(size)?);
+    while let Some(name_type) = reader.u8() {
+        let size = reader.u16()? as usize;
+        let name = reader.bytes(size)?;
+        if name_type == 0 {
+            return parse_name(name);
+        }
+    }
+
+    None
+}
+
+/// Sniffs the Host of the HTTP request at the beginning of the buffer.
+fn sniff_host(buffer: &[u8]) -> Sniff {
+    // Method, which is a token of letters
+    let method_size = buffer.iter().take_while(|b| b.is_ascii_uppercase()).count();
+    match buffer.get(method_size) {
+        Some(b' ') if method_size > 0 => {}
+        None => return Sniff::Incomplete,
+        Some(_) => return Sniff::Unknown,
+    }
+
+    // Headers, the request line is skipped
+    let mut lines = buffer.split(|b| *b == b'\n');
+    lines.next();
+    let mut begin = buffer
+        .iter()
+        .position(|b| *b == b'\n')
+        .map_or(buffer.len(), |i| i + 1);
+    for line in lines {
+        // The last line is incomplete without a line feed
+        if begin + line.len() >= buffer.len() {
+            return Sniff::Incomplete;
+        }
+        begin += line.len() + 1;
+
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            // End of the headers
+            return Sniff::Unknown;
+        }
+        let colon = match line.iter().position(|b| *b == b':') {
+            Some(colon) => colon,
+            None => return Sniff::Unknown,
+        };
+        if !line[..colon].eq_ignore_ascii_case(b"host") {
+            continue;
+        }
+
+        let value = &line[colon + 1..];
+        let value = match value.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(i) => &value[i..],
+            None => return Sniff::Unknown,
+        };
+        let value = value
+            .iter()
+            .rposition(|b| !b.is_ascii_whitespace())
+            .map_or(value, |i| &value[..i + 1]);
+        // Strip the port
+        let host = match value.iter().rposition(|b| *b == b':') {
+            Some(i) if value[i + 1..].iter().all(|b| b.is_ascii_digit()) => &value[..i],
+            _ => value,
+        };
+
+        return match parse_name(host) {
+            Some(name) => Sniff::Name(name),
+            None => Sniff::Unknown,
+        };
+    }
+
+    Sniff::Incomplete
+}
+
+/// Parses a host name in lowercase, returns `None` if it is not a valid name.
+fn parse_name(name: &[u8]) -> Option<String> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return None;
+    }
+    if !name
+        .iter()
+        .all(|b| b.is_ascii_alphanumeric() || *b == b'.' || *b == b'-' || *b == b'_')
+    {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(name).to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a TLS record of a ClientHello, with an extension of the padding before the server
+    /// name extension of the name if any.
+    fn new_client_hello(name: Option<&str>, padding: usize) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        extensions.extend_from_slice(&[0x00, 0x15]);
+        extensions.extend_from_slice(&(padding as u16).to_be_bytes());
+        extensions.resize(extensions.len() + padding, 0);
+        if let Some(name) = name {
+            let size = name.len() as u16;
+            extensions.extend_from_slice(&TLS_SERVER_NAME.to_be_bytes());
+            extensions.extend_from_slice(&(size + 5).to_be_bytes());
+            extensions.extend_from_slice(&(size + 3).to_be_bytes());
+            extensions.push(0);
+            extensions.extend_from_slice(&size.to_be_bytes());
+            extensions.extend_from_slice(name.as_bytes());
+        }
+
+        // Version, random, session ID, cipher suites and compression methods
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(32);
+        body.extend_from_slice(&[0u8; 32]);
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![TLS_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        new_records(&handshake, handshake.len())
+    }
+
+    /// Splits a handshake message into TLS records of the size.
+    fn new_records(handshake: &[u8], size: usize) -> Vec<u8> {
+        let mut records = Vec::new();
+        for fragment in handshake.chunks(size) {
+            records.extend_from_slice(&[TLS_HANDSHAKE, 0x03, 0x01]);
+            records.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            records.extend_from_slice(fragment);
+        }
+
+        records
+    }
+
+    #[test]
+    fn sni() {
+        let client_hello = new_client_hello(Some("Game.Example.com"), 100);
+        assert_eq!(
+            sniff_sni(&client_hello),
+            Sniff::Name("game.example.com".to_string())
+        );
+
+        // Split at any byte
+        for size in 0..client_hello.len() {
+            assert_eq!(
+                sniff_sni(&client_hello[..size]),
+                Sniff::Incomplete,
+                "size {}",
+                size
+            );
+        }
+
+        // Spanning multiple records
+        let handshake = &client_hello[TLS_RECORD_HEADER_SIZE..];
+        for &size in &[1, 50, 200] {
+            assert_eq!(
+                sniff_sni(&new_records(handshake, size)),
+                Sniff::Name("game.example.com".to_string())
+            );
+        }
+
+        // The server name is found without the rest of the ClientHello
+        let mut client_hello = new_client_hello(Some("example.com"), 0);
+        client_hello.extend_from_slice(&[0x00, 0x15, 0x00, 0x10]);
+        let size = client_hello.len() as u16 - TLS_RECORD_HEADER_SIZE as u16 + 16;
+        client_hello[3..5].copy_from_slice(&size.to_be_bytes());
+        assert_eq!(
+            sniff_sni(&client_hello),
+            Sniff::Name("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn sni_unknown() {
+        // Without the server name
+        assert_eq!(sniff_sni(&new_client_hello(None, 100)), Sniff::Unknown);
+
+        // Not a handshake, a ClientHello or TLS
+        let client_hello = new_client_hello(Some("example.com"), 0);
+        let mut record = client_hello.clone();
+        record[0] = 0x17;
+        assert_eq!(sniff_sni(&record), Sniff::Unknown);
+        let mut handshake = client_hello.clone();
+        handshake[TLS_RECORD_HEADER_SIZE] = 0x02;
+        assert_eq!(sniff_sni(&handshake), Sniff::Unknown);
+        let mut version = client_hello.clone();
+        version[1] = 0x02;
+        assert_eq!(sniff_sni(&version), Sniff::Unknown);
+        assert_eq!(sniff_sni(b"GET / HTTP/1.1\r\n"), Sniff::Unknown);
+
+        // A record following is not a handshake
+        let mut records = new_records(&client_hello[TLS_RECORD_HEADER_SIZE..], 20);
+        records[25] = 0x17;
+        assert_eq!(sniff_sni(&records), Sniff::Unknown);
+
+        // An invalid name, and a name overflowing the extension
+        assert_eq!(
+            sniff_sni(&new_client_hello(Some("exa mple.com"), 0)),
+            Sniff::Unknown
+        );
+        let mut overflow = client_hello.clone();
+        let len = overflow.len();
+        overflow[len - 12] = 0xff;
+        assert_eq!(sniff_sni(&overflow), Sniff::Unknown);
+    }
+
+    #[test]
+    fn host() {
+        let request = b"GET / HTTP/1.1\r\nUser-Agent: test\r\nHost: Example.com:8080 \r\n\r\n";
+        assert_eq!(sniff_host(request), Sniff::Name("example.com".to_string()));
+        for size in 0..request.len() - 2 {
+            assert_eq!(
+                sniff_host(&request[..size]),
+                Sniff::Incomplete,
+                "size {}",
+                size
+            );
+        }
+        assert_eq!(
+            sniff_host(b"POST /a HTTP/1.0\nhost:example.com\n"),
+            Sniff::Name("example.com".to_string())
+        );
+
+        // Without the Host, not HTTP, or an invalid Host
+        assert_eq!(sniff_host(b"GET / HTTP/1.1\r\n\r\n"), Sniff::Unknown);
+        assert_eq!(sniff_host(b"\x16\x03\x01"), Sniff::Unknown);
+        assert_eq!(sniff_host(b"get / HTTP/1.1\r\n"), Sniff::Unknown);
+        assert_eq!(sniff_host(b"GET / HTTP/1.1\r\nHost\r\n"), Sniff::Unknown);
+        assert_eq!(
+            sniff_host(b"GET / HTTP/1.1\r\nHost: a/b\r\n"),
+            Sniff::Unknown
+        );
+    }
+
+    #[test]
+    fn classifier_split() {
+        let client_hello = new_client_hello(Some("split.example.com"), 300);
+
+        // A ClientHello split across segments is classified once the server name is received
+        let mut classifier = Classifier::new(HTTPS_PORT);
+        let (a, b) = client_hello.split_at(200);
+        classifier.sniff(a);
+        classifier.count_up(a.len());
+        classifier.count_down(10);
+        assert_eq!(classifier.get_class(), None);
+        classifier.sniff(b);
+        let class = Class::new(Some("split.example.com".to_string()), HTTPS_PORT);
+        assert_eq!(classifier.get_class(), Some(&class));
+        classifier.count_up(b.len());
+
+        // Bytes held while sniffing are counted to the class
+        let classes = get_classes();
+        let (_, up, down) = classes.iter().find(|(c, _, _)| *c == class).unwrap();
+        assert_eq!(*up, client_hello.len() as u64);
+        assert_eq!(*down, 10);
+    }
+
+    #[test]
+    fn classifier_unknown() {
+        // Sniffing is abandoned at the max size
+        let mut classifier = Classifier::new(HTTP_PORT);
+        classifier.sniff(b"GET / HTTP/1.1\r\nCookie: ");
+        classifier.sniff(&[b'a'; 1000]);
+        classifier.sniff(&[b'a'; 1000]);
+        assert_eq!(classifier.get_class(), None);
+        classifier.sniff(&[b'a'; 1000]);
+        assert_eq!(classifier.get_class(), Some(&Class::new(None, HTTP_PORT)));
+
+        // Other ports are classified immediately, and connections closed while sniffing are
+        // classified by their ports
+        let classifier = Classifier::new(22);
+        assert_eq!(classifier.get_class(), Some(&Class::new(None, 22)));
+        let mut classifier = Classifier::new(HTTPS_PORT);
+        classifier.sniff(&[TLS_HANDSHAKE]);
+        assert_eq!(classifier.get_class(), None);
+        classifier.count_up(1);
+        drop(classifier);
+        assert!(get_classes()
+            .iter()
+            .any(|(c, _, _)| *c == Class::new(None, HTTPS_PORT)));
+    }
+}
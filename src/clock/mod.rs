@@ -0,0 +1,101 @@
+use log::warn;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Represents the minimum gap between two readings of the monotonic clock regarded as the system
+/// is suspended. Workers read the clock every 100 ms, so a gap far longer than it is never a
+/// busy worker.
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// Represents a source of time of timeouts, which is injected into upstreamers and downstreamers
+/// so timers can be driven by tests.
+pub trait Clock: Debug + Send + Sync {
+    /// Get the current instant.
+    fn now(&self) -> Instant;
+
+    /// Get the time elapsed since the instant, which is zero if the instant is later than now.
+    fn elapsed(&self, instant: Instant) -> Duration {
+        self.now()
+            .checked_duration_since(instant)
+            .unwrap_or_default()
+    }
+}
+
+/// Represents the state of the monotonic clock, which is shared by all handles.
+static MONOTONIC: Mutex<Option<(Instant, Duration)>> = Mutex::new(None);
+
+/// Represents the monotonic clock of the system. Instants on some systems keep advancing while
+/// the system is suspended, so a gap longer than the threshold between two readings is excluded
+/// from the clock, which stretches all the deadlines by the gap instead of expiring them at once
+/// after the system resumes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MonotonicClock;
+
+impl MonotonicClock {
+    /// Creates a `MonotonicClock`.
+    pub fn new() -> MonotonicClock {
+        MonotonicClock
+    }
+
+    /// Get the total time the system is regarded as suspended.
+    pub fn get_suspended() -> Duration {
+        match *MONOTONIC.lock().unwrap() {
+            Some((_, offset)) => offset,
+            None => Duration::from_secs(0),
+        }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        let real = Instant::now();
+
+        let mut state = MONOTONIC.lock().unwrap();
+        let (last, offset) = state.get_or_insert((real, Duration::from_secs(0)));
+        let gap = real.checked_duration_since(*last).unwrap_or_default();
+        if gap >= SUSPEND_THRESHOLD {
+            warn!(
+                "clock jumps {} s, timeouts are extended for the system is likely suspended",
+                gap.as_secs()
+            );
+            *offset += gap;
+        }
+        *last = real;
+
+        real.checked_sub(*offset).unwrap_or(real)
+    }
+}
+
+/// Represents a clock advanced manually, which never advances by itself. Handles of a
+/// `ManualClock` cloned share the same time.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    instant: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    /// Creates a `ManualClock` starting from now.
+    pub fn new() -> ManualClock {
+        ManualClock {
+            instant: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Advances the clock by the duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.instant.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.instant.lock().unwrap()
+    }
+}
@@ -0,0 +1,825 @@
+//! Configuration files in TOML with the same options as the flags.
+//!
+//! ```toml
+//! source = "10.6.0.1"
+//! publish = "10.6.0.2"
+//!
+//! [proxy]
+//! destination = "127.0.0.1:1080"
+//!
+//! [tcp]
+//! mss = 1360
+//!
+//! [rules]
+//! list = ["192.168.0.0/16,direct", "0.0.0.0/0,25,reject"]
+//! ```
+
+use crate::args::{self, Flags};
+use crate::packet::DEFAULT_REASSEMBLY_TIMEOUT;
+use crate::pcap::capture::DEFAULT_CAPTURE_FRAMES;
+use crate::socks::{DEFAULT_PROXY_CHECK_INTERVAL, DEFAULT_SHARED_SOCKETS};
+use crate::{
+    DEFAULT_CONNECT_RETRIES, DEFAULT_DELAYED_ACK, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_HALF_OPEN,
+    DEFAULT_MIN_RTO, DEFAULT_PROXY_TIMEOUT, DEFAULT_SHUTDOWN_TIMEOUT, DEFAULT_SYN_ACK_MAX_INTERVAL,
+    DEFAULT_SYN_ACK_RETRIES, DEFAULT_UDP_PROBE_INTERVAL, DEFAULT_UDP_TIMEOUT,
+};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::Path;
+use std::result;
+use toml::value::{Table, Value};
+
+/// Represents an error when read a configuration file. The path of the key is kept if the error
+/// is caused by a key.
+#[derive(Debug)]
+pub struct ConfigError {
+    path: String,
+    reason: String,
+}
+
+impl ConfigError {
+    fn new(path: &str, reason: String) -> ConfigError {
+        ConfigError {
+            path: path.to_string(),
+            reason,
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.path.is_empty() {
+            true => write!(f, "config: {}", self.reason),
+            false => write!(f, "config: {}: {}", self.path, self.reason),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+type Result<T> = result::Result<T, ConfigError>;
+
+/// Reads the configuration file into `Flags`. Unknown keys are errors.
+pub fn read(path: &Path) -> Result<Flags> {
+    let s = fs::read_to_string(path).map_err(|e| ConfigError::new("", e.to_string()))?;
+
+    parse(&s)
+}
+
+/// Parses the configuration in TOML into `Flags`. Unknown keys are errors.
+pub fn parse(s: &str) -> Result<Flags> {
+    let value: Value = s
+        .parse()
+        .map_err(|e: toml::de::Error| ConfigError::new("", e.to_string()))?;
+
+    let mut flags = Flags::default();
+    for (key, value) in value.as_table().unwrap() {
+        let path = key.as_str();
+        match path {
+            "source" => flags.src = Some(get_string(value, path)?),
+            "publish" => flags.publish = Some(get_string(value, path)?),
+            "force-publish" => flags.force_publish = get_bool(value, path)?,
+            "publish6" => flags.publish6 = Some(get_string(value, path)?),
+            "ipv6-ra" => flags.ipv6_ra = Some(get_string(value, path)?),
+            "workers" => flags.workers = Some(get_integer(value, path)?),
+            "max-memory" => flags.max_memory = Some(get_rate(value, path)?),
+            "metrics" => flags.metrics = Some(get_string(value, path)?),
+            "stats-interval" => flags.stats_interval = Some(get_integer(value, path)?),
+            "classify" => flags.classify = get_bool(value, path)?,
+            "arp-interval" => flags.arp_interval = Some(get_integer(value, path)?),
+            "dhcp" => flags.dhcp = get_bool(value, path)?,
+            "dhcp-force" => flags.dhcp_force = get_bool(value, path)?,
+            "igd" => flags.igd = get_bool(value, path)?,
+            "test-responder" => flags.test_responder = Some(get_integer(value, path)?),
+            "self-test" => flags.self_test = get_bool(value, path)?,
+            "self-test-connect" => flags.self_test_connect = Some(get_string(value, path)?),
+            "self-test-echo" => flags.self_test_echo = Some(get_string(value, path)?),
+            "shutdown-timeout" => flags.shutdown_timeout = Some(get_integer(value, path)?),
+            "logging" => parse_logging(&mut flags, get_table(value, path)?)?,
+            "capture" => parse_capture(&mut flags, get_table(value, path)?)?,
+            "proxy" => parse_proxy(&mut flags, get_table(value, path)?)?,
+            "tcp" => parse_tcp(&mut flags, get_table(value, path)?)?,
+            "udp" => parse_udp(&mut flags, get_table(value, path)?)?,
+            "limit" => parse_limit(&mut flags, get_table(value, path)?)?,
+            "rules" => parse_rules(&mut flags, get_table(value, path)?)?,
+            "access" => parse_access(&mut flags, get_table(value, path)?)?,
+            _ => return Err(unknown(path)),
+        }
+    }
+
+    Ok(flags)
+}
+
+fn parse_logging(flags: &mut Flags, table: &Table) -> Result<()> {
+    for (key, value) in table {
+        let path = &format!("logging.{}", key);
+        match key.as_str() {
+            "verbose" => flags.verbose = get_bool(value, path)?,
+            "vverbose" => flags.vverbose = get_bool(value, path)?,
+            "format" => flags.log_format = Some(get_string(value, path)?),
+            "file" => flags.log_file = Some(get_string(value, path)?),
+            _ => return Err(unknown(path)),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_capture(flags: &mut Flags, table: &Table) -> Result<()> {
+    for (key, value) in table {
+        let path = &format!("capture.{}", key);
+        match key.as_str() {
+            "interface" => flags.inter = get_string_or_strings(value, path)?,
+            "tun" => flags.tun = Some(get_string(value, path)?),
+            "filter" => flags.filter = Some(get_string(value, path)?),
+            "input-file" => flags.input_file = Some(get_string(value, path)?),
+            "output-file" => flags.output_file = Some(get_string(value, path)?),
+            "replay-timing" => flags.replay_timing = get_bool(value, path)?,
+            "dump" => flags.dump = Some(get_string(value, path)?),
+            "dump-max-size" => flags.dump_max_size = Some(get_integer(value, path)?),
+            "dump-filter" => flags.dump_filter = Some(get_string(value, path)?),
+            "capture-on-error" => flags.capture_on_error = Some(get_string(value, path)?),
+            "capture-frames" => flags.capture_frames = Some(get_integer(value, path)?),
+            "traces" => flags.traces = get_strings(value, path)?,
+            "trace-max-size" => flags.trace_max_size = Some(get_integer(value, path)?),
+            "mtu" => flags.mtu = Some(get_integer(value, path)?),
+            "tx-batch" => flags.tx_batch = Some(get_integer(value, path)?),
+            "reassembly-timeout" => flags.reassembly_timeout = Some(get_integer(value, path)?),
+            "checksum-mode" => flags.checksum_mode = Some(get_string(value, path)?),
+            "device-retry" => flags.device_retry = Some(get_integer(value, path)?),
+            "run-as" => flags.run_as = Some(get_string(value, path)?),
+            "keep-privileges" => flags.keep_privileges = get_bool(value, path)?,
+            "chaos" => flags.chaos = Some(get_string(value, path)?),
+            "spoof-mac" => flags.spoof_mac = Some(get_string(value, path)?),
+            "verify-tx" => flags.verify_tx = Some(Some(get_string(value, path)?)),
+            _ => return Err(unknown(path)),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_proxy(flags: &mut Flags, table: &Table) -> Result<()> {
+    for (key, value) in table {
+        let path = &format!("proxy.{}", key);
+        match key.as_str() {
+            "destination" => flags.dst = get_string_or_strings(value, path)?,
+            "url" => flags.proxy = Some(get_string(value, path)?),
+            "socks-version" => flags.socks_version = Some(get_string(value, path)?),
+            "username" => flags.username = Some(get_string(value, path)?),
+            "password" => flags.password = Some(get_string(value, path)?),
+            "auth-file" => flags.auth_file = Some(get_string(value, path)?),
+            "policy" => flags.proxy_policy = Some(get_string(value, path)?),
+            "check-interval" => flags.proxy_check_interval = Some(get_integer(value, path)?),
+            "resolve-ttl" => flags.resolve_ttl = Some(get_integer(value, path)?),
+            "timeout" => flags.proxy_timeout = Some(get_integer(value, path)?),
+            "relay-timeout" => flags.relay_timeout = Some(get_integer(value, path)?),
+            "relay-stale" => flags.relay_stale = Some(get_integer(value, path)?),
+            "linger" => flags.linger = Some(get_integer(value, path)?),
+            "connect-retries" => flags.connect_retries = Some(get_integer(value, path)?),
+            "pool" => flags.proxy_pool = Some(get_integer(value, path)?),
+            "outbound" => flags.outbound = Some(get_string(value, path)?),
+            "local-port-range" => flags.local_port_range = Some(get_string(value, path)?),
+            "nat-ports" => flags.nat_ports = Some(get_string(value, path)?),
+            "redirect-dns" => flags.redirect_dns = Some(get_string(value, path)?),
+            "bind-ports" => {
+                let ports = get_array(value, path)?
+                    .iter()
+                    .map(|port| get_integer::<u16>(port, path).map(|port| port.to_string()))
+                    .collect::<Result<Vec<_>>>()?;
+                flags.bind_ports = Some(ports.join(","));
+            }
+            _ => return Err(unknown(path)),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_tcp(flags: &mut Flags, table: &Table) -> Result<()> {
+    for (key, value) in table {
+        let path = &format!("tcp.{}", key);
+        match key.as_str() {
+            "mss" => flags.mss = Some(get_integer(value, path)?),
+            "min-rto" => flags.min_rto = Some(get_integer(value, path)?),
+            "cc" => flags.cc = Some(get_string(value, path)?),
+            "keepalive" => flags.tcp_keepalive = Some(get_integer(value, path)?),
+            "no-delay" => flags.no_delay = get_bool(value, path)?,
+            "delayed-ack" => flags.delayed_ack = Some(get_integer(value, path)?),
+            "recv-window" => flags.recv_window = Some(get_rate(value, path)?),
+            "send-buffer" => flags.send_buffer = Some(get_rate(value, path)?),
+            "relay-buffer" => flags.relay_buffer = Some(get_rate(value, path)?),
+            "max-connections" => flags.max_connections = Some(get_integer(value, path)?),
+            "max-half-open" => flags.max_half_open = Some(get_integer(value, path)?),
+            "syn-ack-retries" => flags.syn_ack_retries = Some(get_integer(value, path)?),
+            "syn-ack-max-interval" => flags.syn_ack_max_interval = Some(get_integer(value, path)?),
+            "evict-idle" => flags.evict_idle = get_bool(value, path)?,
+            "pickup-established" => flags.pickup_established = get_strings(value, path)?,
+            _ => return Err(unknown(path)),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_udp(flags: &mut Flags, table: &Table) -> Result<()> {
+    for (key, value) in table {
+        let path = &format!("udp.{}", key);
+        match key.as_str() {
+            "timeout" => flags.udp_timeout = Some(get_integer(value, path)?),
+            "keepalive" => flags.udp_keepalive = Some(get_integer(value, path)?),
+            "assoc-strict" => flags.udp_assoc_strict = get_bool(value, path)?,
+            "probe" => flags.udp_probe = Some(get_string(value, path)?),
+            "probe-interval" => flags.udp_probe_interval = Some(get_integer(value, path)?),
+            "summary" => flags.udp_summary = Some(get_integer(value, path)?),
+            "nat" => flags.udp_nat = Some(get_string(value, path)?),
+            "fallback" => flags.udp_fallback = Some(get_string(value, path)?),
+            "tunnel" => flags.udp_tunnel = Some(get_string(value, path)?),
+            "fragmentation" => flags.no_udp_frag = !get_bool(value, path)?,
+            "pmtu" => flags.udp_pmtu = get_bool(value, path)?,
+            "send-fragmentation" => flags.udp_send_frag = get_bool(value, path)?,
+            "sockets" => flags.udp_sockets = Some(get_integer(value, path)?),
+            "lan-passthrough" => flags.lan_passthrough = get_bool(value, path)?,
+            "state-file" => flags.state_file = Some(get_string(value, path)?),
+            _ => return Err(unknown(path)),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_limit(flags: &mut Flags, table: &Table) -> Result<()> {
+    for (key, value) in table {
+        let path = &format!("limit.{}", key);
+        match key.as_str() {
+            "up" => flags.limit_up = Some(get_rate(value, path)?),
+            "down" => flags.limit_down = Some(get_rate(value, path)?),
+            "per-client" => flags.limit_per_client = Some(get_rate(value, path)?),
+            // Quotas are kept in order
+            "client-quotas" => flags.client_quotas = get_strings(value, path)?,
+            "client-quota-default" => flags.client_quota_default = Some(get_string(value, path)?),
+            _ => return Err(unknown(path)),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_rules(flags: &mut Flags, table: &Table) -> Result<()> {
+    for (key, value) in table {
+        let path = &format!("rules.{}", key);
+        match key.as_str() {
+            // Rules are kept in order
+            "list" => flags.rules = get_strings(value, path)?,
+            "file" => flags.rules_file = Some(get_string(value, path)?),
+            "default-action" => flags.default_action = Some(get_string(value, path)?),
+            "direct-private" => flags.direct_private = get_bool(value, path)?,
+            // Rewrites are kept in order
+            "rewrites" => flags.rewrites = get_strings(value, path)?,
+            _ => return Err(unknown(path)),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_access(flags: &mut Flags, table: &Table) -> Result<()> {
+    for (key, value) in table {
+        let path = &format!("access.{}", key);
+        match key.as_str() {
+            "allow" => flags.allow = get_strings(value, path)?,
+            "deny" => flags.deny = get_strings(value, path)?,
+            "deny-silent" => flags.deny_silent = get_bool(value, path)?,
+            "blocklist" => flags.blocklist = Some(get_string(value, path)?),
+            _ => return Err(unknown(path)),
+        }
+    }
+
+    Ok(())
+}
+
+fn unknown(path: &str) -> ConfigError {
+    ConfigError::new(path, String::from("unknown key"))
+}
+
+fn get_strings(value: &Value, path: &str) -> Result<Vec<String>> {
+    get_array(value, path)?
+        .iter()
+        .map(|value| get_string(value, path))
+        .collect()
+}
+
+/// Get a string as a list of one string, or a list of strings.
+fn get_string_or_strings(value: &Value, path: &str) -> Result<Vec<String>> {
+    match value {
+        Value::Array(_) => get_strings(value, path),
+        _ => value
+            .as_str()
+            .map(|s| vec![s.to_string()])
+            .ok_or_else(|| ConfigError::new(path, String::from("expect a string or an array"))),
+    }
+}
+
+fn get_table<'a>(value: &'a Value, path: &str) -> Result<&'a Table> {
+    value
+        .as_table()
+        .ok_or_else(|| ConfigError::new(path, String::from("expect a table")))
+}
+
+fn get_array<'a>(value: &'a Value, path: &str) -> Result<&'a Vec<Value>> {
+    value
+        .as_array()
+        .ok_or_else(|| ConfigError::new(path, String::from("expect an array")))
+}
+
+fn get_string(value: &Value, path: &str) -> Result<String> {
+    value
+        .as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| ConfigError::new(path, String::from("expect a string")))
+}
+
+fn get_bool(value: &Value, path: &str) -> Result<bool> {
+    value
+        .as_bool()
+        .ok_or_else(|| ConfigError::new(path, String::from("expect a boolean")))
+}
+
+fn get_integer<T: TryFrom<i64>>(value: &Value, path: &str) -> Result<T> {
+    let integer = value
+        .as_integer()
+        .ok_or_else(|| ConfigError::new(path, String::from("expect an integer")))?;
+
+    T::try_from(integer).map_err(|_| ConfigError::new(path, format!("{} is out of range", integer)))
+}
+
+/// Get a rate or a size, which is either an integer in bytes (per second) or a string with a
+/// suffix.
+fn get_rate(value: &Value, path: &str) -> Result<String> {
+    match value {
+        Value::Integer(_) => get_integer::<u64>(value, path).map(|rate| rate.to_string()),
+        _ => get_string(value, path)
+            .map_err(|_| ConfigError::new(path, String::from("expect an integer or a string"))),
+    }
+}
+
+/// Prints the effective configuration of the flags in TOML, where options not set are in their
+/// default values.
+pub fn print(flags: &Flags) -> String {
+    Value::Table(table(flags)).to_string()
+}
+
+/// Get the effective configuration of the flags in a table of TOML, where options not set are
+/// in their default values.
+pub fn table(flags: &Flags) -> Table {
+    let mut root = Table::new();
+    insert_option(&mut root, "source", &flags.src);
+    insert_option(&mut root, "publish", &flags.publish);
+    root.insert(
+        String::from("force-publish"),
+        Value::Boolean(flags.force_publish),
+    );
+    insert_option(&mut root, "publish6", &flags.publish6);
+    insert_option(&mut root, "ipv6-ra", &flags.ipv6_ra);
+    if let Some(workers) = flags.workers {
+        insert_integer(&mut root, "workers", workers as i64);
+    }
+    insert_option(&mut root, "max-memory", &flags.max_memory);
+    insert_option(&mut root, "metrics", &flags.metrics);
+    if let Some(stats_interval) = flags.stats_interval {
+        insert_integer(&mut root, "stats-interval", stats_interval as i64);
+    }
+    root.insert(String::from("classify"), Value::Boolean(flags.classify));
+    if let Some(arp_interval) = flags.arp_interval {
+        insert_integer(&mut root, "arp-interval", arp_interval as i64);
+    }
+    root.insert(String::from("dhcp"), Value::Boolean(flags.dhcp));
+    root.insert(String::from("dhcp-force"), Value::Boolean(flags.dhcp_force));
+    root.insert(String::from("igd"), Value::Boolean(flags.igd));
+    if let Some(test_responder) = flags.test_responder {
+        insert_integer(&mut root, "test-responder", test_responder as i64);
+    }
+    root.insert(String::from("self-test"), Value::Boolean(flags.self_test));
+    insert_option(&mut root, "self-test-connect", &flags.self_test_connect);
+    insert_option(&mut root, "self-test-echo", &flags.self_test_echo);
+    insert_integer(
+        &mut root,
+        "shutdown-timeout",
+        flags.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT) as i64,
+    );
+
+    let mut logging = Table::new();
+    logging.insert(String::from("verbose"), Value::Boolean(flags.verbose));
+    logging.insert(String::from("vverbose"), Value::Boolean(flags.vverbose));
+    logging.insert(
+        String::from("format"),
+        Value::String(
+            flags
+                .log_format
+                .clone()
+                .unwrap_or_else(|| String::from(args::DEFAULT_LOG_FORMAT)),
+        ),
+    );
+    insert_option(&mut logging, "file", &flags.log_file);
+    root.insert(String::from("logging"), Value::Table(logging));
+
+    let mut capture = Table::new();
+    // Interfaces bridged are an array
+    match flags.inter.as_slice() {
+        [] => {}
+        [inter] => {
+            capture.insert(String::from("interface"), Value::String(inter.clone()));
+        }
+        _ => {
+            capture.insert(String::from("interface"), strings(&flags.inter));
+        }
+    }
+    insert_option(&mut capture, "tun", &flags.tun);
+    insert_option(&mut capture, "filter", &flags.filter);
+    insert_option(&mut capture, "input-file", &flags.input_file);
+    insert_option(&mut capture, "output-file", &flags.output_file);
+    capture.insert(
+        String::from("replay-timing"),
+        Value::Boolean(flags.replay_timing),
+    );
+    insert_option(&mut capture, "dump", &flags.dump);
+    if let Some(dump_max_size) = flags.dump_max_size {
+        insert_integer(&mut capture, "dump-max-size", dump_max_size as i64);
+    }
+    insert_option(&mut capture, "dump-filter", &flags.dump_filter);
+    insert_option(&mut capture, "capture-on-error", &flags.capture_on_error);
+    if flags.capture_on_error.is_some() {
+        insert_integer(
+            &mut capture,
+            "capture-frames",
+            flags.capture_frames.unwrap_or(DEFAULT_CAPTURE_FRAMES) as i64,
+        );
+    }
+    capture.insert(String::from("traces"), strings(&flags.traces));
+    if let Some(trace_max_size) = flags.trace_max_size {
+        insert_integer(&mut capture, "trace-max-size", trace_max_size as i64);
+    }
+    // The MTU defaults to the MTU of the device, which is known only after it is opened
+    if let Some(mtu) = flags.mtu {
+        insert_integer(&mut capture, "mtu", mtu as i64);
+    }
+    insert_integer(
+        &mut capture,
+        "tx-batch",
+        flags.tx_batch.unwrap_or(args::DEFAULT_TX_BATCH) as i64,
+    );
+    insert_integer(
+        &mut capture,
+        "reassembly-timeout",
+        flags
+            .reassembly_timeout
+            .unwrap_or(DEFAULT_REASSEMBLY_TIMEOUT) as i64,
+    );
+    capture.insert(
+        String::from("checksum-mode"),
+        Value::String(
+            flags
+                .checksum_mode
+                .clone()
+                .unwrap_or_else(|| String::from(args::DEFAULT_CHECKSUM_MODE)),
+        ),
+    );
+    if let Some(device_retry) = flags.device_retry {
+        insert_integer(&mut capture, "device-retry", device_retry as i64);
+    }
+    insert_option(&mut capture, "run-as", &flags.run_as);
+    capture.insert(
+        String::from("keep-privileges"),
+        Value::Boolean(flags.keep_privileges),
+    );
+    insert_option(&mut capture, "chaos", &flags.chaos);
+    capture.insert(
+        String::from("spoof-mac"),
+        Value::String(
+            flags
+                .spoof_mac
+                .clone()
+                .unwrap_or_else(|| String::from(args::DEFAULT_SPOOF_MAC)),
+        ),
+    );
+    if let Some(ref verify_tx) = flags.verify_tx {
+        capture.insert(
+            String::from("verify-tx"),
+            Value::String(
+                verify_tx
+                    .clone()
+                    .unwrap_or_else(|| String::from(args::DEFAULT_VERIFY_TX)),
+            ),
+        );
+    }
+    root.insert(String::from("capture"), Value::Table(capture));
+
+    let mut proxy = Table::new();
+    // Destinations of a group are an array
+    proxy.insert(
+        String::from("destination"),
+        match flags.dst.as_slice() {
+            [] => Value::String(String::from(args::DEFAULT_DESTINATION)),
+            [dst] => Value::String(dst.clone()),
+            _ => strings(&flags.dst),
+        },
+    );
+    insert_option(&mut proxy, "url", &flags.proxy);
+    proxy.insert(
+        String::from("socks-version"),
+        Value::String(
+            flags
+                .socks_version
+                .clone()
+                .unwrap_or_else(|| String::from(args::DEFAULT_SOCKS_VERSION)),
+        ),
+    );
+    insert_option(&mut proxy, "username", &flags.username);
+    insert_option(&mut proxy, "password", &flags.password);
+    insert_option(&mut proxy, "auth-file", &flags.auth_file);
+    if flags.dst.len() > 1 {
+        proxy.insert(
+            String::from("policy"),
+            Value::String(
+                flags
+                    .proxy_policy
+                    .clone()
+                    .unwrap_or_else(|| String::from(args::DEFAULT_PROXY_POLICY)),
+            ),
+        );
+        insert_integer(
+            &mut proxy,
+            "check-interval",
+            flags
+                .proxy_check_interval
+                .unwrap_or(DEFAULT_PROXY_CHECK_INTERVAL) as i64,
+        );
+    }
+    if let Some(resolve_ttl) = flags.resolve_ttl {
+        insert_integer(&mut proxy, "resolve-ttl", resolve_ttl as i64);
+    }
+    insert_integer(
+        &mut proxy,
+        "timeout",
+        flags.proxy_timeout.unwrap_or(DEFAULT_PROXY_TIMEOUT) as i64,
+    );
+    if let Some(relay_timeout) = flags.relay_timeout {
+        insert_integer(&mut proxy, "relay-timeout", relay_timeout as i64);
+    }
+    if let Some(relay_stale) = flags.relay_stale {
+        insert_integer(&mut proxy, "relay-stale", relay_stale as i64);
+    }
+    if let Some(linger) = flags.linger {
+        insert_integer(&mut proxy, "linger", linger as i64);
+    }
+    insert_integer(
+        &mut proxy,
+        "connect-retries",
+        flags.connect_retries.unwrap_or(DEFAULT_CONNECT_RETRIES) as i64,
+    );
+    if let Some(proxy_pool) = flags.proxy_pool {
+        insert_integer(&mut proxy, "pool", proxy_pool as i64);
+    }
+    insert_option(&mut proxy, "outbound", &flags.outbound);
+    insert_option(&mut proxy, "local-port-range", &flags.local_port_range);
+    insert_option(&mut proxy, "nat-ports", &flags.nat_ports);
+    insert_option(&mut proxy, "redirect-dns", &flags.redirect_dns);
+    if let Some(ref ports) = flags.bind_ports {
+        let ports = ports
+            .split(',')
+            .filter_map(|port| port.trim().parse::<u16>().ok())
+            .map(|port| Value::Integer(port as i64))
+            .collect();
+        proxy.insert(String::from("bind-ports"), Value::Array(ports));
+    }
+    root.insert(String::from("proxy"), Value::Table(proxy));
+
+    let mut tcp = Table::new();
+    if let Some(mss) = flags.mss {
+        insert_integer(&mut tcp, "mss", mss as i64);
+    }
+    insert_integer(
+        &mut tcp,
+        "min-rto",
+        flags.min_rto.unwrap_or(DEFAULT_MIN_RTO) as i64,
+    );
+    tcp.insert(
+        String::from("cc"),
+        Value::String(
+            flags
+                .cc
+                .clone()
+                .unwrap_or_else(|| String::from(args::DEFAULT_CC)),
+        ),
+    );
+    if let Some(tcp_keepalive) = flags.tcp_keepalive {
+        insert_integer(&mut tcp, "keepalive", tcp_keepalive as i64);
+    }
+    tcp.insert(String::from("no-delay"), Value::Boolean(flags.no_delay));
+    insert_integer(
+        &mut tcp,
+        "delayed-ack",
+        flags.delayed_ack.unwrap_or(DEFAULT_DELAYED_ACK) as i64,
+    );
+    insert_option(&mut tcp, "recv-window", &flags.recv_window);
+    insert_option(&mut tcp, "send-buffer", &flags.send_buffer);
+    insert_option(&mut tcp, "relay-buffer", &flags.relay_buffer);
+    insert_integer(
+        &mut tcp,
+        "max-connections",
+        flags.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS) as i64,
+    );
+    insert_integer(
+        &mut tcp,
+        "max-half-open",
+        flags.max_half_open.unwrap_or(DEFAULT_MAX_HALF_OPEN) as i64,
+    );
+    insert_integer(
+        &mut tcp,
+        "syn-ack-retries",
+        flags.syn_ack_retries.unwrap_or(DEFAULT_SYN_ACK_RETRIES) as i64,
+    );
+    insert_integer(
+        &mut tcp,
+        "syn-ack-max-interval",
+        flags
+            .syn_ack_max_interval
+            .unwrap_or(DEFAULT_SYN_ACK_MAX_INTERVAL) as i64,
+    );
+    tcp.insert(String::from("evict-idle"), Value::Boolean(flags.evict_idle));
+    tcp.insert(
+        String::from("pickup-established"),
+        strings(&flags.pickup_established),
+    );
+    root.insert(String::from("tcp"), Value::Table(tcp));
+
+    let mut udp = Table::new();
+    insert_integer(
+        &mut udp,
+        "timeout",
+        flags.udp_timeout.unwrap_or(DEFAULT_UDP_TIMEOUT) as i64,
+    );
+    if let Some(udp_keepalive) = flags.udp_keepalive {
+        insert_integer(&mut udp, "keepalive", udp_keepalive as i64);
+    }
+    udp.insert(
+        String::from("assoc-strict"),
+        Value::Boolean(flags.udp_assoc_strict),
+    );
+    insert_option(&mut udp, "probe", &flags.udp_probe);
+    insert_integer(
+        &mut udp,
+        "probe-interval",
+        flags
+            .udp_probe_interval
+            .unwrap_or(DEFAULT_UDP_PROBE_INTERVAL) as i64,
+    );
+    if let Some(udp_summary) = flags.udp_summary {
+        insert_integer(&mut udp, "summary", udp_summary as i64);
+    }
+    udp.insert(
+        String::from("nat"),
+        Value::String(
+            flags
+                .udp_nat
+                .clone()
+                .unwrap_or_else(|| String::from(args::DEFAULT_UDP_NAT)),
+        ),
+    );
+    udp.insert(
+        String::from("fallback"),
+        Value::String(
+            flags
+                .udp_fallback
+                .clone()
+                .unwrap_or_else(|| String::from(args::DEFAULT_UDP_FALLBACK)),
+        ),
+    );
+    insert_option(&mut udp, "tunnel", &flags.udp_tunnel);
+    udp.insert(
+        String::from("fragmentation"),
+        Value::Boolean(!flags.no_udp_frag),
+    );
+    udp.insert(String::from("pmtu"), Value::Boolean(flags.udp_pmtu));
+    udp.insert(
+        String::from("send-fragmentation"),
+        Value::Boolean(flags.udp_send_frag),
+    );
+    insert_integer(
+        &mut udp,
+        "sockets",
+        flags.udp_sockets.unwrap_or(DEFAULT_SHARED_SOCKETS) as i64,
+    );
+    udp.insert(
+        String::from("lan-passthrough"),
+        Value::Boolean(flags.lan_passthrough),
+    );
+    insert_option(&mut udp, "state-file", &flags.state_file);
+    root.insert(String::from("udp"), Value::Table(udp));
+
+    let mut limit = Table::new();
+    insert_option(&mut limit, "up", &flags.limit_up);
+    insert_option(&mut limit, "down", &flags.limit_down);
+    insert_option(&mut limit, "per-client", &flags.limit_per_client);
+    limit.insert(String::from("client-quotas"), strings(&flags.client_quotas));
+    insert_option(
+        &mut limit,
+        "client-quota-default",
+        &flags.client_quota_default,
+    );
+    root.insert(String::from("limit"), Value::Table(limit));
+
+    let mut rules = Table::new();
+    rules.insert(String::from("list"), strings(&flags.rules));
+    insert_option(&mut rules, "file", &flags.rules_file);
+    rules.insert(
+        String::from("default-action"),
+        Value::String(
+            flags
+                .default_action
+                .clone()
+                .unwrap_or_else(|| String::from(args::DEFAULT_ACTION)),
+        ),
+    );
+    rules.insert(
+        String::from("direct-private"),
+        Value::Boolean(flags.direct_private),
+    );
+    rules.insert(String::from("rewrites"), strings(&flags.rewrites));
+    root.insert(String::from("rules"), Value::Table(rules));
+
+    let mut access = Table::new();
+    access.insert(String::from("allow"), strings(&flags.allow));
+    access.insert(String::from("deny"), strings(&flags.deny));
+    access.insert(
+        String::from("deny-silent"),
+        Value::Boolean(flags.deny_silent),
+    );
+    insert_option(&mut access, "blocklist", &flags.blocklist);
+    root.insert(String::from("access"), Value::Table(access));
+
+    root
+}
+
+/// Get the keys whose values differ between two effective configurations, like
+/// `proxy.destination`, in the order of the keys.
+pub fn diff(old: &Table, new: &Table) -> Vec<String> {
+    let mut keys = Vec::new();
+    diff_table(old, new, "", &mut keys);
+
+    keys
+}
+
+fn diff_table(old: &Table, new: &Table, prefix: &str, keys: &mut Vec<String>) {
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+    for name in names {
+        let path = format!("{}{}", prefix, name);
+        match (old.get(name), new.get(name)) {
+            (Some(Value::Table(old)), Some(Value::Table(new))) => {
+                diff_table(old, new, &format!("{}.", path), keys)
+            }
+            (old, new) if old != new => keys.push(path),
+            _ => {}
+        }
+    }
+}
+
+/// Sets the value of the key in a configuration to the one in another configuration, like
+/// `proxy.destination`. The key is removed if it is not in the other configuration.
+pub fn copy(from: &Table, to: &mut Table, key: &str) {
+    match key.split_once('.') {
+        Some((section, key)) => {
+            if let (Some(Value::Table(from)), Some(Value::Table(to))) =
+                (from.get(section), to.get_mut(section))
+            {
+                copy(from, to, key);
+            }
+        }
+        None => match from.get(key) {
+            Some(value) => {
+                to.insert(key.to_string(), value.clone());
+            }
+            None => {
+                to.remove(key);
+            }
+        },
+    }
+}
+
+fn strings(values: &[String]) -> Value {
+    Value::Array(values.iter().cloned().map(Value::String).collect())
+}
+
+fn insert_option(table: &mut Table, key: &str, value: &Option<String>) {
+    if let Some(value) = value {
+        table.insert(key.to_string(), Value::String(value.clone()));
+    }
+}
+
+fn insert_integer(table: &mut Table, key: &str, value: i64) {
+    table.insert(key.to_string(), Value::Integer(value));
+}
@@ -0,0 +1,334 @@
+use std::cmp::{max, min};
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// Represents the congestion control of a TCP connection. All the sizes are in bytes.
+pub trait CongestionControl: Send {
+    /// Updates the congestion window with an acknowledgement of new data at the instant of the
+    /// clock of timers.
+    fn on_ack(&mut self, acknowledged: usize, rtt: Option<Duration>, now: Instant);
+
+    /// Updates the congestion window with a loss detected by duplicate acknowledgements, the
+    /// flight size is the size of the data sent but not acknowledged.
+    fn on_loss(&mut self, flight: usize);
+
+    /// Updates the congestion window with a retransmission timeout.
+    fn on_rto(&mut self, flight: usize);
+
+    /// Get the congestion window.
+    fn cwnd(&self) -> usize;
+}
+
+/// Represents the algorithm of the congestion control.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Algorithm {
+    Reno,
+    Cubic,
+}
+
+impl Algorithm {
+    /// Creates a new `CongestionControl` in the algorithm with the given MSS.
+    pub fn new_congestion_control(&self, mss: usize) -> Box<dyn CongestionControl> {
+        match self {
+            Algorithm::Reno => Box::new(Reno::new(mss)),
+            Algorithm::Cubic => Box::new(Cubic::new(mss)),
+        }
+    }
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            Algorithm::Reno => write!(f, "Reno"),
+            Algorithm::Cubic => write!(f, "CUBIC"),
+        }
+    }
+}
+
+/// Get the initial congestion window in the given MSS (RFC 3390).
+fn initial_cwnd(mss: usize) -> usize {
+    min(4 * mss, max(2 * mss, 4380))
+}
+
+/// Represents the Reno congestion control (RFC 5681).
+#[derive(Debug)]
+pub struct Reno {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+}
+
+impl Reno {
+    /// Creates a new `Reno`.
+    pub fn new(mss: usize) -> Reno {
+        Reno {
+            mss,
+            cwnd: initial_cwnd(mss),
+            ssthresh: usize::MAX,
+        }
+    }
+}
+
+impl CongestionControl for Reno {
+    fn on_ack(&mut self, acknowledged: usize, _: Option<Duration>, _: Instant) {
+        if self.cwnd < self.ssthresh {
+            // Slow start
+            self.cwnd += min(acknowledged, self.mss);
+        } else {
+            // Congestion avoidance
+            self.cwnd += max(1, self.mss * self.mss / self.cwnd);
+        }
+    }
+
+    fn on_loss(&mut self, flight: usize) {
+        self.ssthresh = max(flight / 2, 2 * self.mss);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_rto(&mut self, flight: usize) {
+        self.ssthresh = max(flight / 2, 2 * self.mss);
+        self.cwnd = self.mss;
+    }
+
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+}
+
+/// Represents the scaling constant of CUBIC.
+const CUBIC_C: f64 = 0.4;
+/// Represents the multiplicative decrease factor of CUBIC.
+const CUBIC_BETA: f64 = 0.7;
+
+/// Represents the CUBIC congestion control (RFC 8312).
+#[derive(Debug)]
+pub struct Cubic {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    /// Represents the window before the last reduction in MSS.
+    w_max: f64,
+    /// Represents the time period to reach `w_max` in seconds.
+    k: f64,
+    epoch: Option<Instant>,
+}
+
+impl Cubic {
+    /// Creates a new `Cubic`.
+    pub fn new(mss: usize) -> Cubic {
+        Cubic {
+            mss,
+            cwnd: initial_cwnd(mss),
+            ssthresh: usize::MAX,
+            w_max: 0.0,
+            k: 0.0,
+            epoch: None,
+        }
+    }
+
+    fn reduce(&mut self) {
+        let cwnd = self.cwnd as f64 / self.mss as f64;
+        // Fast convergence
+        self.w_max = if cwnd < self.w_max {
+            cwnd * (1.0 + CUBIC_BETA) / 2.0
+        } else {
+            cwnd
+        };
+        self.k = (self.w_max * (1.0 - CUBIC_BETA) / CUBIC_C).cbrt();
+        self.epoch = None;
+        self.ssthresh = max((self.cwnd as f64 * CUBIC_BETA) as usize, 2 * self.mss);
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn on_ack(&mut self, acknowledged: usize, rtt: Option<Duration>, now: Instant) {
+        if self.cwnd < self.ssthresh {
+            // Slow start
+            self.cwnd += min(acknowledged, self.mss);
+            return;
+        }
+
+        // Congestion avoidance
+        let epoch = match self.epoch {
+            Some(epoch) => epoch,
+            None => {
+                self.epoch = Some(now);
+                if self.w_max < self.cwnd as f64 / self.mss as f64 {
+                    self.w_max = self.cwnd as f64 / self.mss as f64;
+                    self.k = 0.0;
+                }
+                now
+            }
+        };
+        let t = now
+            .checked_duration_since(epoch)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let rtt = rtt
+            .unwrap_or_else(|| Duration::from_millis(100))
+            .as_secs_f64();
+        let cwnd = self.cwnd as f64 / self.mss as f64;
+
+        // The window of CUBIC at one RTT later, and the estimated window of Reno
+        let target = CUBIC_C * (t + rtt - self.k).powi(3) + self.w_max;
+        let w_est =
+            self.w_max * CUBIC_BETA + 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA) * (t / rtt);
+        let target = target.max(w_est).min(cwnd * 1.5);
+
+        let increment = if target > cwnd {
+            ((target - cwnd) / cwnd * self.mss as f64) as usize
+        } else {
+            // Increase slowly in the concave region near the max
+            self.mss / 100
+        };
+        self.cwnd += max(1, increment);
+    }
+
+    fn on_loss(&mut self, _: usize) {
+        self.reduce();
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_rto(&mut self, _: usize) {
+        self.reduce();
+        self.cwnd = self.mss;
+    }
+
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1460;
+
+    #[test]
+    fn reno_slow_start() {
+        let mut cc = Reno::new(MSS);
+        assert_eq!(cc.cwnd(), initial_cwnd(MSS));
+
+        // Each ACK grows the window by at most 1 MSS
+        let cwnd = cc.cwnd();
+        cc.on_ack(100, None, Instant::now());
+        assert_eq!(cc.cwnd(), cwnd + 100);
+        cc.on_ack(4 * MSS, None, Instant::now());
+        assert_eq!(cc.cwnd(), cwnd + 100 + MSS);
+    }
+
+    #[test]
+    fn reno_congestion_avoidance() {
+        let mut cc = Reno::new(MSS);
+        cc.on_loss(20 * MSS);
+        assert_eq!(cc.cwnd(), 10 * MSS);
+
+        // The window grows by about 1 MSS every window of ACKs
+        for _ in 0..10 {
+            cc.on_ack(MSS, None, Instant::now());
+        }
+        assert!(cc.cwnd() > 10 * MSS && cc.cwnd() <= 11 * MSS);
+    }
+
+    #[test]
+    fn reno_on_loss() {
+        let mut cc = Reno::new(MSS);
+        cc.on_loss(20 * MSS);
+        assert_eq!(cc.cwnd(), 10 * MSS);
+
+        // The threshold is at least 2 MSS
+        cc.on_loss(MSS);
+        assert_eq!(cc.cwnd(), 2 * MSS);
+    }
+
+    #[test]
+    fn reno_on_rto() {
+        let mut cc = Reno::new(MSS);
+        cc.on_rto(20 * MSS);
+        assert_eq!(cc.cwnd(), MSS);
+
+        // Slow start up to the threshold, and congestion avoidance after it
+        let now = Instant::now();
+        for _ in 0..9 {
+            cc.on_ack(MSS, None, now);
+        }
+        assert_eq!(cc.cwnd(), 10 * MSS);
+        cc.on_ack(MSS, None, now);
+        assert_eq!(cc.cwnd(), 10 * MSS + MSS / 10);
+    }
+
+    #[test]
+    fn cubic_slow_start() {
+        let mut cc = Cubic::new(MSS);
+        assert_eq!(cc.cwnd(), initial_cwnd(MSS));
+
+        let cwnd = cc.cwnd();
+        cc.on_ack(100, None, Instant::now());
+        assert_eq!(cc.cwnd(), cwnd + 100);
+        cc.on_ack(4 * MSS, None, Instant::now());
+        assert_eq!(cc.cwnd(), cwnd + 100 + MSS);
+    }
+
+    #[test]
+    fn cubic_on_loss() {
+        let mut cc = Cubic::new(MSS);
+        cc.cwnd = 100 * MSS;
+        cc.on_loss(100 * MSS);
+        assert_eq!(cc.cwnd(), (100.0 * MSS as f64 * CUBIC_BETA) as usize);
+        assert_eq!(cc.w_max, 100.0);
+
+        // Fast convergence lowers the max if the window is lost before reaching it
+        cc.on_loss(cc.cwnd());
+        assert_eq!(cc.w_max, 70.0 * (1.0 + CUBIC_BETA) / 2.0);
+    }
+
+    #[test]
+    fn cubic_on_rto() {
+        let mut cc = Cubic::new(MSS);
+        cc.cwnd = 100 * MSS;
+        cc.on_rto(100 * MSS);
+        assert_eq!(cc.cwnd(), MSS);
+        assert_eq!(cc.ssthresh, (100.0 * MSS as f64 * CUBIC_BETA) as usize);
+
+        // Slow start again up to the threshold
+        let now = Instant::now();
+        cc.on_ack(MSS, None, now);
+        assert_eq!(cc.cwnd(), 2 * MSS);
+    }
+
+    #[test]
+    fn cubic_congestion_avoidance() {
+        let mut cc = Cubic::new(MSS);
+        cc.cwnd = 100 * MSS;
+        cc.on_loss(100 * MSS);
+        let rtt = Duration::from_millis(100);
+
+        // The window is concave below the max, it grows fast after the loss and slowly near the
+        // max in K seconds
+        let epoch = Instant::now();
+        let k = Duration::from_secs_f64(cc.k);
+        let mut now = epoch;
+        let mut growths = Vec::new();
+        while now < epoch + k {
+            let before = cc.cwnd();
+            for _ in 0..(cc.cwnd() / MSS) {
+                cc.on_ack(MSS, Some(rtt), now);
+            }
+            growths.push(cc.cwnd() - before);
+            now += rtt;
+        }
+        assert!(cc.cwnd() > 95 * MSS && cc.cwnd() < 105 * MSS);
+        assert!(growths[0] > 4 * growths[growths.len() * 3 / 4]);
+
+        // The window is convex beyond the max
+        let before = cc.cwnd();
+        for _ in 0..50 {
+            for _ in 0..(cc.cwnd() / MSS) {
+                cc.on_ack(MSS, Some(rtt), now);
+            }
+            now += rtt;
+        }
+        assert!(cc.cwnd() > before + 10 * MSS);
+    }
+}
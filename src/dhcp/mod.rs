@@ -0,0 +1,863 @@
+use ipnetwork::Ipv4Network;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::pcap::HardwareAddr;
+
+/// Represents the port of DHCP servers.
+pub const SERVER_PORT: u16 = 67;
+/// Represents the port of DHCP clients.
+pub const CLIENT_PORT: u16 = 68;
+
+/// Represents the size of the fixed part of a DHCP message.
+const HEADER_SIZE: usize = 236;
+/// Represents the magic cookie before options (RFC 2131).
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Represents the min size of a BOOTP message, shorter messages are padded (RFC 1542).
+const MIN_SIZE: usize = 300;
+
+/// Represents the BOOTREQUEST operation.
+const OP_REQUEST: u8 = 1;
+/// Represents the BOOTREPLY operation.
+const OP_REPLY: u8 = 2;
+/// Represents the hardware type of Ethernet.
+const HTYPE_ETHERNET: u8 = 1;
+/// Represents the broadcast bit in the flags.
+const FLAG_BROADCAST: u16 = 0x8000;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_CLIENT_ID: u8 = 61;
+const OPTION_END: u8 = 255;
+
+/// Represents the lease time of addresses.
+const LEASE_TIME: Duration = Duration::from_secs(3600);
+/// Represents the time an offered address is reserved for the client.
+const OFFER_TIMEOUT: Duration = Duration::from_secs(60);
+/// Represents the time a declined address is not leased again.
+const DECLINE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Represents the type of a DHCP message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MessageType {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+    Inform,
+}
+
+impl MessageType {
+    fn from_u8(t: u8) -> Option<MessageType> {
+        match t {
+            1 => Some(MessageType::Discover),
+            2 => Some(MessageType::Offer),
+            3 => Some(MessageType::Request),
+            4 => Some(MessageType::Decline),
+            5 => Some(MessageType::Ack),
+            6 => Some(MessageType::Nak),
+            7 => Some(MessageType::Release),
+            8 => Some(MessageType::Inform),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            MessageType::Discover => 1,
+            MessageType::Offer => 2,
+            MessageType::Request => 3,
+            MessageType::Decline => 4,
+            MessageType::Ack => 5,
+            MessageType::Nak => 6,
+            MessageType::Release => 7,
+            MessageType::Inform => 8,
+        }
+    }
+}
+
+impl Display for MessageType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            MessageType::Discover => write!(f, "DHCPDISCOVER"),
+            MessageType::Offer => write!(f, "DHCPOFFER"),
+            MessageType::Request => write!(f, "DHCPREQUEST"),
+            MessageType::Decline => write!(f, "DHCPDECLINE"),
+            MessageType::Ack => write!(f, "DHCPACK"),
+            MessageType::Nak => write!(f, "DHCPNAK"),
+            MessageType::Release => write!(f, "DHCPRELEASE"),
+            MessageType::Inform => write!(f, "DHCPINFORM"),
+        }
+    }
+}
+
+/// Represents a DHCP message over Ethernet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Message {
+    op: u8,
+    xid: u32,
+    flags: u16,
+    ciaddr: Ipv4Addr,
+    yiaddr: Ipv4Addr,
+    giaddr: Ipv4Addr,
+    chaddr: HardwareAddr,
+    message_type: MessageType,
+    requested_ip_addr: Option<Ipv4Addr>,
+    server_id: Option<Ipv4Addr>,
+    client_id: Option<Vec<u8>>,
+    subnet_mask: Option<Ipv4Addr>,
+    router: Option<Ipv4Addr>,
+    dns: Option<Ipv4Addr>,
+    lease_time: Option<u32>,
+}
+
+impl Message {
+    /// Parses a DHCP message. Returns `None` if the message is malformed, not over Ethernet, or
+    /// has no message type.
+    pub fn parse(buffer: &[u8]) -> Option<Message> {
+        if buffer.len() < HEADER_SIZE + MAGIC_COOKIE.len() {
+            return None;
+        }
+        let op = buffer[0];
+        if (op != OP_REQUEST && op != OP_REPLY) || buffer[1] != HTYPE_ETHERNET || buffer[2] != 6 {
+            return None;
+        }
+        if buffer[HEADER_SIZE..HEADER_SIZE + MAGIC_COOKIE.len()] != MAGIC_COOKIE {
+            return None;
+        }
+        let xid = u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
+        let flags = u16::from_be_bytes([buffer[10], buffer[11]]);
+        let ciaddr = get_ipv4_addr(&buffer[12..16])?;
+        let yiaddr = get_ipv4_addr(&buffer[16..20])?;
+        let giaddr = get_ipv4_addr(&buffer[24..28])?;
+        let chaddr = HardwareAddr::new(
+            buffer[28], buffer[29], buffer[30], buffer[31], buffer[32], buffer[33],
+        );
+
+        // Options
+        let mut message_type = None;
+        let mut requested_ip_addr = None;
+        let mut server_id = None;
+        let mut client_id = None;
+        let mut subnet_mask = None;
+        let mut router = None;
+        let mut dns = None;
+        let mut lease_time = None;
+        let mut offset = HEADER_SIZE + MAGIC_COOKIE.len();
+        while offset < buffer.len() {
+            let code = buffer[offset];
+            match code {
+                OPTION_PAD => {
+                    offset += 1;
+                    continue;
+                }
+                OPTION_END => break,
+                _ => {}
+            }
+            let len = *buffer.get(offset + 1)? as usize;
+            let value = buffer.get(offset + 2..offset + 2 + len)?;
+            match code {
+                OPTION_MESSAGE_TYPE if len == 1 => message_type = MessageType::from_u8(value[0]),
+                OPTION_REQUESTED_IP => requested_ip_addr = get_ipv4_addr(value),
+                OPTION_SERVER_ID => server_id = get_ipv4_addr(value),
+                OPTION_CLIENT_ID if len > 0 => client_id = Some(value.to_vec()),
+                OPTION_SUBNET_MASK => subnet_mask = get_ipv4_addr(value),
+                OPTION_ROUTER if len >= 4 => router = get_ipv4_addr(&value[..4]),
+                OPTION_DNS if len >= 4 => dns = get_ipv4_addr(&value[..4]),
+                OPTION_LEASE_TIME if len == 4 => {
+                    lease_time = Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+                }
+                _ => {}
+            }
+            offset += 2 + len;
+        }
+
+        Some(Message {
+            op,
+            xid,
+            flags,
+            ciaddr,
+            yiaddr,
+            giaddr,
+            chaddr,
+            message_type: message_type?,
+            requested_ip_addr,
+            server_id,
+            client_id,
+            subnet_mask,
+            router,
+            dns,
+            lease_time,
+        })
+    }
+
+    /// Creates a reply to the message.
+    fn new_reply(
+        &self,
+        message_type: MessageType,
+        yiaddr: Ipv4Addr,
+        server_id: Ipv4Addr,
+    ) -> Message {
+        Message {
+            op: OP_REPLY,
+            xid: self.xid,
+            flags: self.flags,
+            ciaddr: match message_type {
+                MessageType::Nak => Ipv4Addr::UNSPECIFIED,
+                _ => self.ciaddr,
+            },
+            yiaddr,
+            giaddr: self.giaddr,
+            chaddr: self.chaddr,
+            message_type,
+            requested_ip_addr: None,
+            server_id: Some(server_id),
+            client_id: None,
+            subnet_mask: None,
+            router: None,
+            dns: None,
+            lease_time: None,
+        }
+    }
+
+    /// Serializes the message.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buffer = vec![0u8; HEADER_SIZE];
+        buffer[0] = self.op;
+        buffer[1] = HTYPE_ETHERNET;
+        buffer[2] = 6;
+        buffer[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        buffer[10..12].copy_from_slice(&self.flags.to_be_bytes());
+        buffer[12..16].copy_from_slice(&self.ciaddr.octets());
+        buffer[16..20].copy_from_slice(&self.yiaddr.octets());
+        buffer[24..28].copy_from_slice(&self.giaddr.octets());
+        buffer[28..34].copy_from_slice(&[
+            self.chaddr.0,
+            self.chaddr.1,
+            self.chaddr.2,
+            self.chaddr.3,
+            self.chaddr.4,
+            self.chaddr.5,
+        ]);
+        buffer.extend_from_slice(&MAGIC_COOKIE);
+
+        // Options
+        buffer.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, self.message_type.to_u8()]);
+        let addrs = [
+            (OPTION_SERVER_ID, self.server_id),
+            (OPTION_REQUESTED_IP, self.requested_ip_addr),
+            (OPTION_SUBNET_MASK, self.subnet_mask),
+            (OPTION_ROUTER, self.router),
+            (OPTION_DNS, self.dns),
+        ];
+        for (code, addr) in addrs.iter() {
+            if let Some(addr) = addr {
+                buffer.extend_from_slice(&[*code, 4]);
+                buffer.extend_from_slice(&addr.octets());
+            }
+        }
+        if let Some(lease_time) = self.lease_time {
+            // Renew at 50% and rebind at 87.5% of the lease time (RFC 2131)
+            let times = [
+                (OPTION_LEASE_TIME, lease_time),
+                (OPTION_RENEWAL_TIME, lease_time / 2),
+                (OPTION_REBINDING_TIME, lease_time / 8 * 7),
+            ];
+            for (code, time) in times.iter() {
+                buffer.extend_from_slice(&[*code, 4]);
+                buffer.extend_from_slice(&time.to_be_bytes());
+            }
+        }
+        if let Some(ref client_id) = self.client_id {
+            buffer.extend_from_slice(&[OPTION_CLIENT_ID, client_id.len() as u8]);
+            buffer.extend_from_slice(client_id);
+        }
+        buffer.push(OPTION_END);
+        if buffer.len() < MIN_SIZE {
+            buffer.resize(MIN_SIZE, OPTION_PAD);
+        }
+
+        buffer
+    }
+
+    /// Returns if the message is sent by a client.
+    pub fn is_request(&self) -> bool {
+        self.op == OP_REQUEST
+    }
+
+    /// Returns if the reply should be broadcast.
+    pub fn is_broadcast(&self) -> bool {
+        self.flags & FLAG_BROADCAST != 0
+    }
+
+    /// Get the type of the message.
+    pub fn get_type(&self) -> MessageType {
+        self.message_type
+    }
+
+    /// Get the client address of the message.
+    pub fn get_ciaddr(&self) -> Ipv4Addr {
+        self.ciaddr
+    }
+
+    /// Get the address assigned to the client of the message.
+    pub fn get_yiaddr(&self) -> Ipv4Addr {
+        self.yiaddr
+    }
+
+    /// Get the relay agent address of the message.
+    pub fn get_giaddr(&self) -> Ipv4Addr {
+        self.giaddr
+    }
+
+    /// Get the client hardware address of the message.
+    pub fn get_chaddr(&self) -> HardwareAddr {
+        self.chaddr
+    }
+
+    /// Get the server identifier of the message.
+    pub fn get_server_id(&self) -> Option<Ipv4Addr> {
+        self.server_id
+    }
+
+    /// Get the requested address of the message.
+    pub fn get_requested_ip_addr(&self) -> Option<Ipv4Addr> {
+        self.requested_ip_addr
+    }
+
+    /// Get the key identifying the client of the message, which is the client identifier, or
+    /// the hardware type and the client hardware address if there is no client identifier
+    /// (RFC 2132).
+    fn get_client_key(&self) -> Vec<u8> {
+        match self.client_id {
+            Some(ref client_id) => client_id.clone(),
+            None => self.get_hardware_key(),
+        }
+    }
+
+    fn get_hardware_key(&self) -> Vec<u8> {
+        vec![
+            HTYPE_ETHERNET,
+            self.chaddr.0,
+            self.chaddr.1,
+            self.chaddr.2,
+            self.chaddr.3,
+            self.chaddr.4,
+            self.chaddr.5,
+        ]
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} of {}", self.message_type, self.chaddr)?;
+        match self.message_type {
+            MessageType::Offer | MessageType::Ack => write!(f, ", {}", self.yiaddr),
+            MessageType::Request | MessageType::Decline => match self.requested_ip_addr {
+                Some(ip_addr) => write!(f, ", {}", ip_addr),
+                None if !self.ciaddr.is_unspecified() => write!(f, ", {}", self.ciaddr),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Represents a lease of an address.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Lease {
+    ip_addr: Ipv4Addr,
+    hardware_addr: HardwareAddr,
+    /// Represents if the lease is acknowledged, or only offered
+    is_bound: bool,
+    expiry: Instant,
+}
+
+impl Lease {
+    fn is_expired(&self) -> bool {
+        self.expiry <= Instant::now()
+    }
+}
+
+/// Represents a DHCP server leasing addresses of a pool.
+#[derive(Debug)]
+pub struct Server {
+    ip_addr: Ipv4Addr,
+    pool: Ipv4Network,
+    subnet_mask: Ipv4Addr,
+    dns: Ipv4Addr,
+    leases: HashMap<Vec<u8>, Lease>,
+    declines: HashMap<Ipv4Addr, Instant>,
+    /// Represents another DHCP server seen on the network
+    other_server: Option<Ipv4Addr>,
+    is_force: bool,
+}
+
+impl Server {
+    /// Creates a new `Server`, which leases addresses in the pool, and advertises itself as the
+    /// router and the DNS server. The subnet mask is the smallest network covering the pool and
+    /// the server.
+    pub fn new(ip_addr: Ipv4Addr, pool: Ipv4Network, dns: Ipv4Addr) -> Server {
+        let mut prefix = pool.prefix();
+        while prefix > 0
+            && !Ipv4Network::new(pool.ip(), prefix)
+                .unwrap()
+                .contains(ip_addr)
+        {
+            prefix -= 1;
+        }
+        let subnet_mask = Ipv4Network::new(pool.ip(), prefix).unwrap().mask();
+
+        Server {
+            ip_addr,
+            pool,
+            subnet_mask,
+            dns,
+            leases: HashMap::new(),
+            declines: HashMap::new(),
+            other_server: None,
+            is_force: false,
+        }
+    }
+
+    /// Sets if the server answers even if another DHCP server is present.
+    pub fn set_force(&mut self, is_force: bool) {
+        self.is_force = is_force;
+    }
+
+    /// Handles a DHCP message from a client, returns the reply if there is one.
+    pub fn handle(&mut self, message: &Message) -> Option<Message> {
+        if !message.is_request() || !message.get_giaddr().is_unspecified() {
+            return None;
+        }
+        if self.other_server.is_some() && !self.is_force {
+            return None;
+        }
+        // Ignore messages to other servers
+        if let Some(server_id) = message.get_server_id() {
+            if server_id != self.ip_addr {
+                // The client selects another server
+                if message.get_type() == MessageType::Request {
+                    let key = self.get_key(message);
+                    if let Some(lease) = self.leases.get(&key) {
+                        if !lease.is_bound {
+                            self.leases.remove(&key);
+                        }
+                    }
+                }
+                return None;
+            }
+        }
+
+        let key = self.get_key(message);
+        match message.get_type() {
+            MessageType::Discover => {
+                // The requested address is only a hint
+                let ip_addr = self
+                    .allocate(&key, message.get_requested_ip_addr())
+                    .or_else(|| self.allocate(&key, None))?;
+                self.leases.insert(
+                    key,
+                    Lease {
+                        ip_addr,
+                        hardware_addr: message.get_chaddr(),
+                        is_bound: false,
+                        expiry: Instant::now() + OFFER_TIMEOUT,
+                    },
+                );
+
+                Some(self.new_lease_reply(message, MessageType::Offer, ip_addr))
+            }
+            MessageType::Request => {
+                // SELECTING and INIT-REBOOT states carry the requested address, RENEWING and
+                // REBINDING states carry the client address
+                let ip_addr = match message.get_requested_ip_addr() {
+                    Some(ip_addr) => ip_addr,
+                    None if !message.get_ciaddr().is_unspecified() => message.get_ciaddr(),
+                    None => return None,
+                };
+                if self.allocate(&key, Some(ip_addr)) != Some(ip_addr) {
+                    return Some(message.new_reply(
+                        MessageType::Nak,
+                        Ipv4Addr::UNSPECIFIED,
+                        self.ip_addr,
+                    ));
+                }
+                self.leases.insert(
+                    key,
+                    Lease {
+                        ip_addr,
+                        hardware_addr: message.get_chaddr(),
+                        is_bound: true,
+                        expiry: Instant::now() + LEASE_TIME,
+                    },
+                );
+
+                Some(self.new_lease_reply(message, MessageType::Ack, ip_addr))
+            }
+            MessageType::Decline => {
+                let ip_addr = message.get_requested_ip_addr()?;
+                if let Some(lease) = self.leases.get(&key) {
+                    if lease.ip_addr == ip_addr {
+                        self.leases.remove(&key);
+                    }
+                }
+                if self.pool.contains(ip_addr) {
+                    self.declines
+                        .insert(ip_addr, Instant::now() + DECLINE_TIMEOUT);
+                }
+
+                None
+            }
+            MessageType::Release => {
+                if let Some(lease) = self.leases.get(&key) {
+                    if lease.ip_addr == message.get_ciaddr() {
+                        self.leases.remove(&key);
+                    }
+                }
+
+                None
+            }
+            MessageType::Inform => {
+                let mut reply =
+                    message.new_reply(MessageType::Ack, Ipv4Addr::UNSPECIFIED, self.ip_addr);
+                reply.subnet_mask = Some(self.subnet_mask);
+                reply.router = Some(self.ip_addr);
+                reply.dns = Some(self.dns);
+
+                Some(reply)
+            }
+            _ => None,
+        }
+    }
+
+    /// Handles a DHCP message from another server. Returns `true` if the server is seen for the
+    /// first time.
+    pub fn handle_other(&mut self, message: &Message) -> bool {
+        if message.is_request() {
+            return false;
+        }
+        let server_id = match message.get_server_id() {
+            Some(server_id) if server_id != self.ip_addr => server_id,
+            _ => return false,
+        };
+        if self.other_server == Some(server_id) {
+            return false;
+        }
+        self.other_server = Some(server_id);
+
+        true
+    }
+
+    fn new_lease_reply(&self, message: &Message, t: MessageType, ip_addr: Ipv4Addr) -> Message {
+        let mut reply = message.new_reply(t, ip_addr, self.ip_addr);
+        reply.subnet_mask = Some(self.subnet_mask);
+        reply.router = Some(self.ip_addr);
+        reply.dns = Some(self.dns);
+        reply.lease_time = Some(LEASE_TIME.as_secs() as u32);
+
+        reply
+    }
+
+    /// Get the key of the client of the message. A client identifier which is already leased to
+    /// another hardware address is duplicate and the hardware address is used instead.
+    fn get_key(&self, message: &Message) -> Vec<u8> {
+        let key = message.get_client_key();
+        match self.leases.get(&key) {
+            Some(lease) if lease.hardware_addr != message.get_chaddr() && !lease.is_expired() => {
+                message.get_hardware_key()
+            }
+            _ => key,
+        }
+    }
+
+    /// Allocates an address to the client, the leased address of the client is preferred, and
+    /// then the requested address. Returns `None` if there is no available address.
+    fn allocate(&mut self, key: &[u8], requested: Option<Ipv4Addr>) -> Option<Ipv4Addr> {
+        let now = Instant::now();
+        self.leases
+            .retain(|k, lease| k.as_slice() == key || lease.expiry > now);
+        self.declines.retain(|_, expiry| *expiry > now);
+
+        if let Some(lease) = self.leases.get(key) {
+            match requested {
+                Some(requested) if requested != lease.ip_addr => {}
+                _ => return Some(lease.ip_addr),
+            }
+        }
+        if let Some(requested) = requested {
+            return match self.is_available(key, requested) {
+                true => Some(requested),
+                false => None,
+            };
+        }
+
+        self.pool
+            .iter()
+            .find(|ip_addr| self.is_available(key, *ip_addr))
+    }
+
+    fn is_available(&self, key: &[u8], ip_addr: Ipv4Addr) -> bool {
+        if !self.pool.contains(ip_addr) || ip_addr == self.ip_addr {
+            return false;
+        }
+        // Exclude the network and the broadcast addresses
+        if self.pool.prefix() < 31
+            && (ip_addr == self.pool.network() || ip_addr == self.pool.broadcast())
+        {
+            return false;
+        }
+        if self.declines.contains_key(&ip_addr) {
+            return false;
+        }
+
+        !self.leases.iter().any(|(k, lease)| {
+            k.as_slice() != key && lease.ip_addr == ip_addr && !lease.is_expired()
+        })
+    }
+}
+
+/// Get the IPv4 address of exactly 4 bytes.
+fn get_ipv4_addr(buffer: &[u8]) -> Option<Ipv4Addr> {
+    match buffer.len() {
+        4 => Some(Ipv4Addr::new(buffer[0], buffer[1], buffer[2], buffer[3])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 254);
+
+    fn new_server() -> Server {
+        Server::new(
+            SERVER_IP_ADDR,
+            "10.6.0.0/24".parse().unwrap(),
+            SERVER_IP_ADDR,
+        )
+    }
+
+    /// Creates a DHCP message from the client of the hardware address and the client
+    /// identifier.
+    fn new_request(
+        message_type: MessageType,
+        chaddr: HardwareAddr,
+        client_id: Option<&[u8]>,
+        requested_ip_addr: Option<Ipv4Addr>,
+    ) -> Message {
+        Message {
+            op: OP_REQUEST,
+            xid: 0x12345678,
+            flags: FLAG_BROADCAST,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: Ipv4Addr::UNSPECIFIED,
+            giaddr: Ipv4Addr::UNSPECIFIED,
+            chaddr,
+            message_type,
+            requested_ip_addr,
+            server_id: match message_type {
+                MessageType::Discover => None,
+                _ => Some(SERVER_IP_ADDR),
+            },
+            client_id: client_id.map(|client_id| client_id.to_vec()),
+            subnet_mask: None,
+            router: None,
+            dns: None,
+            lease_time: None,
+        }
+    }
+
+    /// Leases an address to the client by a DISCOVER and a REQUEST, returns the address.
+    fn lease(server: &mut Server, chaddr: HardwareAddr, client_id: Option<&[u8]>) -> Ipv4Addr {
+        let discover = new_request(MessageType::Discover, chaddr, client_id, None);
+        let offer = server.handle(&discover).unwrap();
+        assert_eq!(offer.get_type(), MessageType::Offer);
+        let ip_addr = offer.get_yiaddr();
+
+        let request = new_request(MessageType::Request, chaddr, client_id, Some(ip_addr));
+        let ack = server.handle(&request).unwrap();
+        assert_eq!(ack.get_type(), MessageType::Ack);
+        assert_eq!(ack.get_yiaddr(), ip_addr);
+
+        ip_addr
+    }
+
+    #[test]
+    fn round_trip() {
+        let chaddr = HardwareAddr::new(0x02, 0, 0, 0, 0, 0x01);
+        let request = new_request(
+            MessageType::Request,
+            chaddr,
+            Some(b"\x01client"),
+            Some(Ipv4Addr::new(10, 6, 0, 1)),
+        );
+        let buffer = request.serialize();
+        assert_eq!(buffer.len(), MIN_SIZE);
+        assert_eq!(Message::parse(&buffer), Some(request));
+
+        let mut server = new_server();
+        let discover = new_request(MessageType::Discover, chaddr, None, None);
+        let offer = server.handle(&discover).unwrap();
+        let reply = Message::parse(&offer.serialize()).unwrap();
+        assert_eq!(reply, offer);
+        assert!(!reply.is_request());
+        assert!(reply.is_broadcast());
+        assert_eq!(reply.subnet_mask, Some(Ipv4Addr::new(255, 255, 255, 0)));
+        assert_eq!(reply.router, Some(SERVER_IP_ADDR));
+        assert_eq!(reply.dns, Some(SERVER_IP_ADDR));
+        assert_eq!(reply.lease_time, Some(LEASE_TIME.as_secs() as u32));
+    }
+
+    #[test]
+    fn parse_malformed() {
+        let chaddr = HardwareAddr::new(0x02, 0, 0, 0, 0, 0x01);
+        let buffer =
+            new_request(MessageType::Discover, chaddr, Some(b"\x01client"), None).serialize();
+
+        // Truncated before the options, and in an option
+        assert_eq!(Message::parse(&buffer[..HEADER_SIZE + 3]), None);
+        let client_id = HEADER_SIZE + MAGIC_COOKIE.len() + 3;
+        assert_eq!(Message::parse(&buffer[..client_id + 4]), None);
+
+        // Not over Ethernet, without the magic cookie, or the message type
+        let mut htype = buffer.clone();
+        htype[1] = 6;
+        assert_eq!(Message::parse(&htype), None);
+        let mut cookie = buffer.clone();
+        cookie[HEADER_SIZE] = 0;
+        assert_eq!(Message::parse(&cookie), None);
+        let mut message_type = buffer.clone();
+        message_type[HEADER_SIZE + MAGIC_COOKIE.len() + 2] = 0;
+        assert_eq!(Message::parse(&message_type), None);
+    }
+
+    #[test]
+    fn discover_request() {
+        let mut server = new_server();
+        let chaddr_a = HardwareAddr::new(0x02, 0, 0, 0, 0, 0x01);
+        let chaddr_b = HardwareAddr::new(0x02, 0, 0, 0, 0, 0x02);
+
+        // The network address is not leased
+        let ip_addr_a = lease(&mut server, chaddr_a, None);
+        assert_eq!(ip_addr_a, Ipv4Addr::new(10, 6, 0, 1));
+        let ip_addr_b = lease(&mut server, chaddr_b, None);
+        assert_eq!(ip_addr_b, Ipv4Addr::new(10, 6, 0, 2));
+
+        // An address leased to another client is refused
+        let request = new_request(MessageType::Request, chaddr_b, None, Some(ip_addr_a));
+        let nak = server.handle(&request).unwrap();
+        assert_eq!(nak.get_type(), MessageType::Nak);
+        assert_eq!(nak.get_yiaddr(), Ipv4Addr::UNSPECIFIED);
+
+        // Renewing by the client address
+        let mut request = new_request(MessageType::Request, chaddr_a, None, None);
+        request.ciaddr = ip_addr_a;
+        request.server_id = None;
+        assert_eq!(server.handle(&request).unwrap().get_yiaddr(), ip_addr_a);
+
+        // The client selects another server
+        let chaddr_c = HardwareAddr::new(0x02, 0, 0, 0, 0, 0x03);
+        let discover = new_request(MessageType::Discover, chaddr_c, None, None);
+        let offer = server.handle(&discover).unwrap();
+        assert_eq!(offer.get_yiaddr(), Ipv4Addr::new(10, 6, 0, 3));
+        let mut request = new_request(MessageType::Request, chaddr_c, None, Some(offer.yiaddr));
+        request.server_id = Some(Ipv4Addr::new(10, 6, 0, 253));
+        assert_eq!(server.handle(&request), None);
+        let chaddr_d = HardwareAddr::new(0x02, 0, 0, 0, 0, 0x04);
+        assert_eq!(
+            lease(&mut server, chaddr_d, None),
+            Ipv4Addr::new(10, 6, 0, 3)
+        );
+    }
+
+    #[test]
+    fn decline() {
+        let mut server = new_server();
+        let chaddr = HardwareAddr::new(0x02, 0, 0, 0, 0, 0x01);
+        let ip_addr = lease(&mut server, chaddr, None);
+
+        // The declined address is not leased again to any client
+        let decline = new_request(MessageType::Decline, chaddr, None, Some(ip_addr));
+        assert_eq!(server.handle(&decline), None);
+        assert_eq!(lease(&mut server, chaddr, None), Ipv4Addr::new(10, 6, 0, 2));
+        let request = new_request(MessageType::Request, chaddr, None, Some(ip_addr));
+        assert_eq!(
+            server.handle(&request).unwrap().get_type(),
+            MessageType::Nak
+        );
+
+        // A decline without the address, or of an address out of the pool
+        let decline = new_request(MessageType::Decline, chaddr, None, None);
+        assert_eq!(server.handle(&decline), None);
+        let decline = new_request(
+            MessageType::Decline,
+            chaddr,
+            None,
+            Some(Ipv4Addr::new(192, 0, 2, 1)),
+        );
+        assert_eq!(server.handle(&decline), None);
+        assert_eq!(server.declines.len(), 1);
+    }
+
+    #[test]
+    fn duplicate_client_id() {
+        let mut server = new_server();
+        let chaddr_a = HardwareAddr::new(0x02, 0, 0, 0, 0, 0x01);
+        let chaddr_b = HardwareAddr::new(0x02, 0, 0, 0, 0, 0x02);
+        let client_id: &[u8] = b"\x00console";
+
+        // Clients of the same identifier on different hardware addresses get different leases
+        let ip_addr_a = lease(&mut server, chaddr_a, Some(client_id));
+        let ip_addr_b = lease(&mut server, chaddr_b, Some(client_id));
+        assert_ne!(ip_addr_a, ip_addr_b);
+        assert_eq!(server.leases.len(), 2);
+
+        // And keep their leases
+        assert_eq!(lease(&mut server, chaddr_a, Some(client_id)), ip_addr_a);
+        assert_eq!(lease(&mut server, chaddr_b, Some(client_id)), ip_addr_b);
+
+        // A release of the duplicate does not release the other
+        let mut release = new_request(MessageType::Release, chaddr_b, Some(client_id), None);
+        release.ciaddr = ip_addr_b;
+        assert_eq!(server.handle(&release), None);
+        assert_eq!(server.leases.len(), 1);
+        assert_eq!(lease(&mut server, chaddr_a, Some(client_id)), ip_addr_a);
+    }
+
+    #[test]
+    fn other_server() {
+        let mut server = new_server();
+        let chaddr = HardwareAddr::new(0x02, 0, 0, 0, 0, 0x01);
+        let discover = new_request(MessageType::Discover, chaddr, None, None);
+
+        // Another server offers
+        let other_ip_addr = Ipv4Addr::new(10, 6, 0, 253);
+        let offer = discover.new_reply(
+            MessageType::Offer,
+            Ipv4Addr::new(10, 6, 0, 100),
+            other_ip_addr,
+        );
+        assert!(server.handle_other(&offer));
+        assert!(!server.handle_other(&offer));
+        assert!(!server.handle_other(&discover));
+        assert_eq!(server.handle(&discover), None);
+
+        server.set_force(true);
+        assert!(server.handle(&discover).is_some());
+    }
+}
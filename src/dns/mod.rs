@@ -0,0 +1,314 @@
+use std::cmp::{max, min};
+
+/// Represents the size of the header of a DNS message.
+const HEADER_SIZE: usize = 12;
+/// Represents the max size of a DNS message over UDP without EDNS (RFC 1035).
+const DEFAULT_UDP_SIZE: usize = 512;
+/// Represents the type of the OPT pseudo-RR (RFC 6891).
+const TYPE_OPT: u16 = 41;
+
+/// Represents the QR bit in the flags.
+const FLAG_QR: u16 = 0x8000;
+/// Represents the OPCODE field in the flags.
+const FLAG_OPCODE: u16 = 0x7800;
+/// Represents the TC bit in the flags.
+const FLAG_TC: u16 = 0x0200;
+/// Represents the RD bit in the flags.
+const FLAG_RD: u16 = 0x0100;
+/// Represents the RA bit in the flags.
+const FLAG_RA: u16 = 0x0080;
+
+/// Represents a standard DNS query with a single question.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Query {
+    id: u16,
+    flags: u16,
+    /// Represents the end of the question section
+    question_end: usize,
+    /// Represents the max size of a response over UDP the client accepts
+    udp_size: usize,
+}
+
+impl Query {
+    /// Parses a DNS query. Returns `None` if the message is not a standard query with a single
+    /// question.
+    pub fn parse(buffer: &[u8]) -> Option<Query> {
+        if buffer.len() < HEADER_SIZE {
+            return None;
+        }
+        let id = get_u16(buffer, 0)?;
+        let flags = get_u16(buffer, 2)?;
+        if flags & FLAG_QR != 0 || flags & FLAG_OPCODE != 0 {
+            return None;
+        }
+        let qdcount = get_u16(buffer, 4)?;
+        let ancount = get_u16(buffer, 6)?;
+        let nscount = get_u16(buffer, 8)?;
+        let arcount = get_u16(buffer, 10)?;
+        if qdcount != 1 || ancount != 0 || nscount != 0 {
+            return None;
+        }
+
+        // Question
+        let question_end = skip_name(buffer, HEADER_SIZE)? + 4;
+        if question_end > buffer.len() {
+            return None;
+        }
+
+        // EDNS
+        let mut udp_size = DEFAULT_UDP_SIZE;
+        let mut offset = question_end;
+        for _ in 0..arcount {
+            offset = skip_name(buffer, offset)?;
+            let rtype = get_u16(buffer, offset)?;
+            let class = get_u16(buffer, offset + 2)?;
+            let rdlength = get_u16(buffer, offset + 8)? as usize;
+            if rtype == TYPE_OPT {
+                udp_size = max(class as usize, DEFAULT_UDP_SIZE);
+            }
+            offset += 10 + rdlength;
+            if offset > buffer.len() {
+                return None;
+            }
+        }
+
+        Some(Query {
+            id,
+            flags,
+            question_end,
+            udp_size,
+        })
+    }
+
+    /// Get the ID of the query.
+    pub fn get_id(&self) -> u16 {
+        self.id
+    }
+
+    /// Get the max size of a response over UDP the client accepts.
+    pub fn get_udp_size(&self) -> usize {
+        self.udp_size
+    }
+
+    /// Creates an empty truncated response of the query, which lets the client retry over TCP.
+    pub fn new_truncated_response(&self, query: &[u8]) -> Vec<u8> {
+        let mut response = query[..self.question_end].to_vec();
+        let flags = FLAG_QR | FLAG_TC | FLAG_RA | (self.flags & FLAG_RD);
+        response[2..4].copy_from_slice(&flags.to_be_bytes());
+        // ANCOUNT, NSCOUNT and ARCOUNT
+        for b in response[6..HEADER_SIZE].iter_mut() {
+            *b = 0;
+        }
+
+        response
+    }
+}
+
+/// Creates a DNS query of the NS records of the root with the ID, which any recursive resolver
+/// answers from its cache.
+pub fn new_root_query(id: u16) -> Vec<u8> {
+    let mut query = vec![0u8; HEADER_SIZE];
+    query[0..2].copy_from_slice(&id.to_be_bytes());
+    query[2..4].copy_from_slice(&FLAG_RD.to_be_bytes());
+    query[4..6].copy_from_slice(&1u16.to_be_bytes());
+    // Root, NS, IN
+    query.extend_from_slice(&[0, 0, 2, 0, 1]);
+
+    query
+}
+
+/// Truncates a DNS response to the given size. A response larger than the size is cut to its
+/// question section with the TC bit set (RFC 2181). Malformed responses are returned untouched.
+pub fn truncate(response: &[u8], size: usize) -> Vec<u8> {
+    if response.len() <= size {
+        return response.to_vec();
+    }
+    let question_end = match get_u16(response, 4) {
+        Some(qdcount) => {
+            let mut offset = Some(HEADER_SIZE);
+            for _ in 0..qdcount {
+                offset = offset
+                    .and_then(|offset| skip_name(response, offset))
+                    .map(|offset| offset + 4);
+            }
+            match offset {
+                Some(offset) if offset <= min(size, response.len()) => offset,
+                _ => return response.to_vec(),
+            }
+        }
+        None => return response.to_vec(),
+    };
+
+    let mut truncated = response[..question_end].to_vec();
+    let flags = get_u16(response, 2).unwrap() | FLAG_TC;
+    truncated[2..4].copy_from_slice(&flags.to_be_bytes());
+    for b in truncated[6..HEADER_SIZE].iter_mut() {
+        *b = 0;
+    }
+
+    truncated
+}
+
+/// Get the 16-bit big-endian integer at the given offset.
+fn get_u16(buffer: &[u8], offset: usize) -> Option<u16> {
+    let bytes = buffer.get(offset..offset + 2)?;
+
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Skips a domain name at the given offset, returns the offset after the name.
+fn skip_name(buffer: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *buffer.get(offset)? as usize;
+        match len {
+            0 => return Some(offset + 1),
+            // Compression pointer
+            l if l & 0xc0 == 0xc0 => {
+                buffer.get(offset + 1)?;
+                return Some(offset + 2);
+            }
+            l if l & 0xc0 != 0 => return None,
+            l => offset += 1 + l,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a DNS query of the A record of `example.com` with the ID, and an OPT pseudo-RR
+    /// of the UDP size if any.
+    fn new_query(id: u16, udp_size: Option<u16>) -> Vec<u8> {
+        let mut query = vec![0u8; HEADER_SIZE];
+        query[0..2].copy_from_slice(&id.to_be_bytes());
+        query[2..4].copy_from_slice(&FLAG_RD.to_be_bytes());
+        query[4..6].copy_from_slice(&1u16.to_be_bytes());
+        query.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        if let Some(udp_size) = udp_size {
+            query[10..12].copy_from_slice(&1u16.to_be_bytes());
+            query.extend_from_slice(&[0, 0, 41]);
+            query.extend_from_slice(&udp_size.to_be_bytes());
+            query.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        }
+
+        query
+    }
+
+    /// Creates a DNS response of the query with the given count of A records.
+    fn new_response(query: &[u8], answers: u16) -> Vec<u8> {
+        let question_end = skip_name(query, HEADER_SIZE).unwrap() + 4;
+        let mut response = query[..question_end].to_vec();
+        response[2..4].copy_from_slice(&(FLAG_QR | FLAG_RD | FLAG_RA).to_be_bytes());
+        response[6..8].copy_from_slice(&answers.to_be_bytes());
+        for i in 0..answers {
+            // Pointer to the question, A, IN, TTL, RDLENGTH and RDATA
+            response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4]);
+            response.extend_from_slice(&[192, 0, 2, i as u8]);
+        }
+
+        response
+    }
+
+    #[test]
+    fn parse_query() {
+        let query = Query::parse(&new_query(0x1234, None)).unwrap();
+        assert_eq!(query.get_id(), 0x1234);
+        assert_eq!(query.get_udp_size(), DEFAULT_UDP_SIZE);
+
+        // EDNS, a UDP size below the default is raised to the default
+        let query = Query::parse(&new_query(0x1234, Some(4096))).unwrap();
+        assert_eq!(query.get_udp_size(), 4096);
+        let query = Query::parse(&new_query(0x1234, Some(100))).unwrap();
+        assert_eq!(query.get_udp_size(), DEFAULT_UDP_SIZE);
+
+        let query = Query::parse(&new_root_query(0x5678)).unwrap();
+        assert_eq!(query.get_id(), 0x5678);
+    }
+
+    #[test]
+    fn parse_malformed() {
+        let query = new_query(0x1234, Some(4096));
+
+        // Truncated anywhere
+        for size in 0..query.len() {
+            assert_eq!(Query::parse(&query[..size]), None, "size {}", size);
+        }
+
+        // Not a standard query with a single question
+        let response = new_response(&new_query(0x1234, None), 1);
+        assert_eq!(Query::parse(&response), None);
+        let mut notify = query.clone();
+        notify[2] |= 0x20;
+        assert_eq!(Query::parse(&notify), None);
+        let mut questions = query.clone();
+        questions[5] = 2;
+        assert_eq!(Query::parse(&questions), None);
+        let mut authorities = query.clone();
+        authorities[9] = 1;
+        assert_eq!(Query::parse(&authorities), None);
+
+        // A label of the reserved type, and a pointer cut off
+        let mut label = query.clone();
+        label[HEADER_SIZE] = 0x47;
+        assert_eq!(Query::parse(&label), None);
+        let mut pointer = query[..HEADER_SIZE].to_vec();
+        pointer.push(0xc0);
+        assert_eq!(Query::parse(&pointer), None);
+
+        // An additional record overflowing the message
+        let mut additional = query.clone();
+        let len = additional.len();
+        additional[len - 1] = 1;
+        assert_eq!(Query::parse(&additional), None);
+    }
+
+    #[test]
+    fn truncated_response() {
+        let query = new_query(0x1234, Some(4096));
+        let response = Query::parse(&query).unwrap().new_truncated_response(&query);
+
+        // The question is kept, and the records are removed
+        let question_end = skip_name(&query, HEADER_SIZE).unwrap() + 4;
+        assert_eq!(response.len(), question_end);
+        assert_eq!(&response[..2], &query[..2]);
+        assert_eq!(&response[HEADER_SIZE..], &query[HEADER_SIZE..question_end]);
+        assert_eq!(
+            get_u16(&response, 2),
+            Some(FLAG_QR | FLAG_TC | FLAG_RD | FLAG_RA)
+        );
+        assert_eq!(&response[4..HEADER_SIZE], &[0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn truncate_response() {
+        let query = new_query(0x1234, None);
+
+        // A response in the size is untouched
+        let response = new_response(&query, 10);
+        assert_eq!(truncate(&response, response.len()), response);
+
+        // A larger response is cut to its question with the TC bit set
+        let response = new_response(&query, 40);
+        assert!(response.len() > DEFAULT_UDP_SIZE);
+        let truncated = truncate(&response, DEFAULT_UDP_SIZE);
+        let question_end = skip_name(&query, HEADER_SIZE).unwrap() + 4;
+        assert_eq!(truncated, {
+            let mut expected = response[..question_end].to_vec();
+            expected[2..4].copy_from_slice(&(FLAG_QR | FLAG_TC | FLAG_RD | FLAG_RA).to_be_bytes());
+            expected[6..8].copy_from_slice(&[0, 0]);
+            expected
+        });
+
+        // Malformed responses are untouched
+        let mut label = response.clone();
+        label[HEADER_SIZE] = 0x80;
+        assert_eq!(truncate(&label, DEFAULT_UDP_SIZE), label);
+        let mut questions = response.clone();
+        questions[4..6].copy_from_slice(&100u16.to_be_bytes());
+        assert_eq!(truncate(&questions, DEFAULT_UDP_SIZE), questions);
+        assert_eq!(truncate(&response[..5], 4), &response[..5]);
+        // A question larger than the size
+        assert_eq!(truncate(&response, 20), response);
+    }
+}
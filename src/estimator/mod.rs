@@ -0,0 +1,72 @@
+use std::cmp::{max, min};
+use std::time::Duration;
+
+/// Represents the initial retransmission timeout (RFC 6298).
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+/// Represents the max retransmission timeout.
+const MAX_RTO: Duration = Duration::from_secs(60);
+/// Represents the clock granularity.
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// Represents the estimator of the round-trip time and the retransmission timeout of a TCP
+/// connection (RFC 6298).
+#[derive(Debug)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    min_rto: Duration,
+    backoff: u32,
+}
+
+impl RttEstimator {
+    /// Creates a new `RttEstimator` with the given minimum retransmission timeout.
+    pub fn new(min_rto: Duration) -> RttEstimator {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::from_millis(0),
+            rto: max(INITIAL_RTO, min_rto),
+            min_rto,
+            backoff: 0,
+        }
+    }
+
+    /// Updates the estimator with a round-trip time measurement.
+    pub fn update(&mut self, rtt: Duration) {
+        match self.srtt {
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+        }
+
+        let rto = self.srtt.unwrap() + max(CLOCK_GRANULARITY, self.rttvar * 4);
+        self.rto = min(max(rto, self.min_rto), MAX_RTO);
+        self.backoff = 0;
+    }
+
+    /// Backs off the retransmission timeout after a retransmission.
+    pub fn backoff(&mut self) {
+        if self.get_rto() < MAX_RTO {
+            self.backoff += 1;
+        }
+    }
+
+    /// Get the smoothed round-trip time. Returns `None` if there is no measurement.
+    pub fn get_srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Get the retransmission timeout, including the backoff.
+    pub fn get_rto(&self) -> Duration {
+        match self.rto.checked_mul(1 << min(self.backoff, 16)) {
+            Some(rto) => min(rto, MAX_RTO),
+            None => MAX_RTO,
+        }
+    }
+}
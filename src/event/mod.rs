@@ -0,0 +1,1052 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::cmp::{max, min};
+use std::fmt::{self, Display, Formatter, Write as FmtWrite};
+use std::fs::OpenOptions;
+use std::io::{self, LineWriter, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::classify::{self, Class, Classifier};
+use crate::hook;
+use crate::metrics::{self, DropReason};
+use crate::nat;
+use crate::packet::layer::LayerType;
+use crate::pcap::bridge;
+use crate::pcap::capture;
+
+/// Represents the target of log records of events.
+const EVENT_TARGET: &str = "pcap2socks::event";
+
+/// Represents if events are logged, events are only logged in the JSON format.
+static IS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Represents if there is a subscriber of events.
+static IS_SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+
+/// Represents the subscriber of events.
+static SUBSCRIBER: Mutex<Option<mpsc::Sender<Event>>> = Mutex::new(None);
+
+/// Represents the ID of the last connection, IDs are assigned in order from 1.
+static CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Represents the min bytes of UDP associations whose summaries are logged when they close, the
+/// summaries are off if it is 0.
+static UDP_SUMMARY_THRESHOLD: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the min bytes of UDP associations whose summaries are logged when they close, like
+/// associations of DNS queries below it are never logged.
+pub fn set_udp_summary(threshold: u64) {
+    UDP_SUMMARY_THRESHOLD.store(max(threshold, 1), Ordering::Relaxed);
+}
+
+/// Represents the formats of logs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// Represents lines of messages for humans.
+    Human,
+    /// Represents a JSON object per line, including events of connections.
+    Json,
+}
+
+impl Format {
+    /// Parses a format from its name.
+    pub fn parse(s: &str) -> Option<Format> {
+        match s {
+            "human" => Some(Format::Human),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            Format::Human => write!(f, "human"),
+            Format::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Represents a logger writing to a file or the standard output in the format. Lines are
+/// written as soon as they are complete.
+pub struct Logger {
+    level: LevelFilter,
+    format: Format,
+    writer: Mutex<LineWriter<Box<dyn Write + Send>>>,
+}
+
+impl Logger {
+    /// Creates a new `Logger` writing to the file, logs are appended to the file if it exists.
+    /// Logs are written to the standard output if there is no file.
+    pub fn new(level: LevelFilter, format: Format, file: Option<&str>) -> io::Result<Logger> {
+        let writer: Box<dyn Write + Send> = match file {
+            Some(file) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(file)
+                    .map_err(|e| io::Error::new(e.kind(), format!("open {}: {}", file, e)))?,
+            ),
+            None => Box::new(io::stdout()),
+        };
+
+        Ok(Logger {
+            level,
+            format,
+            writer: Mutex::new(LineWriter::new(writer)),
+        })
+    }
+
+    /// Installs the logger as the global logger. Events are logged if the format is JSON.
+    pub fn init(self) -> Result<(), log::SetLoggerError> {
+        IS_ENABLED.store(self.format == Format::Json, Ordering::Relaxed);
+        log::set_max_level(self.level);
+
+        log::set_boxed_logger(Box::new(self))
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // The max level changes in reloads
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = match self.format {
+            Format::Human => {
+                let level = match record.level() {
+                    Level::Error => "error: ",
+                    Level::Warn => "warning: ",
+                    _ => "",
+                };
+                format!("{}{}", level, record.args())
+            }
+            Format::Json => {
+                let time = humantime::format_rfc3339_millis(SystemTime::now());
+                let level = record.level().to_string().to_ascii_lowercase();
+                // Events are already serialized as fields
+                match record.target() {
+                    EVENT_TARGET => format!(
+                        "{{\"time\":\"{}\",\"level\":\"{}\",{}}}",
+                        time,
+                        level,
+                        record.args()
+                    ),
+                    _ => format!(
+                        "{{\"time\":\"{}\",\"level\":\"{}\",\"event\":\"log\",\"message\":{}}}",
+                        time,
+                        level,
+                        quote(&record.args().to_string())
+                    ),
+                }
+            }
+        };
+
+        let _ = writeln!(self.writer.lock().unwrap(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = self.writer.lock().unwrap().flush();
+    }
+}
+
+/// Quotes a string as a JSON string.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// Represents the transport protocols of connections.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// Represents the reasons a connection is closed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    /// Represents the connection is closed by both sides, and the source closes first.
+    Fin,
+    /// Represents the connection is closed by both sides, and the destination closes first.
+    Eof,
+    /// Represents the connection is reset by the source.
+    Reset,
+    /// Represents the stream or the association met an error.
+    Error,
+    /// Represents the stream to the proxy met an error while relaying.
+    ProxyError,
+    /// Represents the stream to the proxy or the destination cannot be connected.
+    ConnectFailed,
+    /// Represents the stream is inactive for the relay timeout.
+    RelayTimeout,
+    /// Represents the writes to the stream are not drained for the stale timeout, as the proxy
+    /// is dead without a RST or a FIN.
+    RelayStale,
+    /// Represents the source does not answer TCP keepalive probes.
+    KeepaliveTimeout,
+    /// Represents the source does not acknowledge the TCP FIN.
+    FinTimeout,
+    /// Represents the association is idle for the UDP timeout.
+    Idle,
+    /// Represents the association is reused by another source port.
+    Replaced,
+    /// Represents the application is shutting down.
+    Shutdown,
+    /// Represents the TCP connection is closed for a new one when connections are full.
+    Evicted,
+    /// Represents the TCP connection is closed to free the memory when the budget is exhausted.
+    MemoryExhausted,
+    /// Represents the source does not complete the TCP handshake.
+    HandshakeTimeout,
+    /// Represents the control connection of the SOCKS association is closed or met an error.
+    ControlClosed,
+    /// Represents the relay of the SOCKS association stops relaying, and the association is
+    /// re-established.
+    RelayDead,
+}
+
+/// Represents all the reasons a connection is closed, in the order of the counters.
+pub const CLOSE_REASONS: [CloseReason; 18] = [
+    CloseReason::Fin,
+    CloseReason::Eof,
+    CloseReason::Reset,
+    CloseReason::Error,
+    CloseReason::ProxyError,
+    CloseReason::ConnectFailed,
+    CloseReason::RelayTimeout,
+    CloseReason::RelayStale,
+    CloseReason::KeepaliveTimeout,
+    CloseReason::FinTimeout,
+    CloseReason::Idle,
+    CloseReason::Replaced,
+    CloseReason::Shutdown,
+    CloseReason::Evicted,
+    CloseReason::MemoryExhausted,
+    CloseReason::HandshakeTimeout,
+    CloseReason::ControlClosed,
+    CloseReason::RelayDead,
+];
+
+impl CloseReason {
+    /// Get the name of the reason.
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            CloseReason::Fin => "fin",
+            CloseReason::Eof => "eof",
+            CloseReason::Reset => "reset",
+            CloseReason::Error => "error",
+            CloseReason::ProxyError => "proxy_error",
+            CloseReason::ConnectFailed => "connect_failed",
+            CloseReason::RelayTimeout => "relay_timeout",
+            CloseReason::RelayStale => "relay_stale",
+            CloseReason::KeepaliveTimeout => "keepalive_timeout",
+            CloseReason::FinTimeout => "fin_timeout",
+            CloseReason::Idle => "idle",
+            CloseReason::Replaced => "replaced",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::Evicted => "evicted",
+            CloseReason::MemoryExhausted => "memory_exhausted",
+            CloseReason::HandshakeTimeout => "handshake_timeout",
+            CloseReason::ControlClosed => "control_closed",
+            CloseReason::RelayDead => "relay_dead",
+        }
+    }
+
+    /// Returns if the reason is graceful, a TCP connection closed for a graceful reason is
+    /// signaled to the source by a FIN, and by a RST otherwise.
+    pub fn is_graceful(&self) -> bool {
+        matches!(
+            self,
+            CloseReason::Fin | CloseReason::Eof | CloseReason::Idle
+        )
+    }
+
+    /// Returns if the reason is an error of the stream, the proxy or the source, whose
+    /// connections are captured for bug reports.
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            CloseReason::Error
+                | CloseReason::ProxyError
+                | CloseReason::ConnectFailed
+                | CloseReason::RelayTimeout
+                | CloseReason::RelayStale
+                | CloseReason::KeepaliveTimeout
+                | CloseReason::FinTimeout
+                | CloseReason::HandshakeTimeout
+                | CloseReason::ControlClosed
+                | CloseReason::RelayDead
+        )
+    }
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
+
+/// Represents the tag of a connection prefixed to log lines of it, like `#42 `, so lines of a
+/// connection are found by its ID. The tag is empty for traffic of no connection.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Tag(pub Option<u64>);
+
+impl Display for Tag {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, "#{} ", id),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Represents an estimate of the inter-arrival jitter of datagrams in the way of RFC 3550. As
+/// the times datagrams are sent are unknown, the transit time is replaced with the interval
+/// between arrivals, so a steady rate has no jitter.
+#[derive(Debug, Default)]
+struct Jitter {
+    last: Option<Instant>,
+    /// Represents the last interval between arrivals in microseconds
+    interval: Option<u64>,
+    /// Represents the estimate in microseconds scaled by 16
+    jitter: u64,
+    sum: u64,
+    samples: u64,
+}
+
+impl Jitter {
+    /// Updates the estimate by a datagram arrived.
+    fn update(&mut self, instant: Instant) {
+        if let Some(last) = self.last {
+            let interval = instant.saturating_duration_since(last).as_micros() as u64;
+            if let Some(prev_interval) = self.interval {
+                let d = max(interval, prev_interval) - min(interval, prev_interval);
+                // J += (|D| - J) / 16, in the integer way of RFC 3550 A.8
+                self.jitter = self.jitter + d - ((self.jitter + 8) >> 4);
+                self.sum += self.jitter >> 4;
+                self.samples += 1;
+                metrics::observe_udp_jitter(self.jitter >> 4);
+            }
+            self.interval = Some(interval);
+        }
+        self.last = Some(instant);
+    }
+
+    /// Get the current estimate.
+    fn get(&self) -> Duration {
+        Duration::from_micros(self.jitter >> 4)
+    }
+
+    /// Get the mean of the estimates over all the datagrams.
+    fn get_mean(&self) -> Duration {
+        match self.samples {
+            0 => Duration::from_micros(0),
+            samples => Duration::from_micros(self.sum / samples),
+        }
+    }
+}
+
+/// Represents the lifecycle of a connection of a source, which is a TCP connection or a UDP
+/// association. The destination of a UDP association is the one of its first datagram.
+#[derive(Debug)]
+pub struct Connection {
+    /// Represents the ID of the connection, which is unique in the application
+    id: u64,
+    client: Ipv4Addr,
+    src_port: u16,
+    dst: SocketAddrV4,
+    /// Represents the destination the connection is rewritten to
+    rewritten: Option<SocketAddrV4>,
+    protocol: Protocol,
+    is_proxied: bool,
+    /// Represents if the TCP connection is picked up mid-stream without a handshake
+    is_picked_up: bool,
+    /// Represents if the UDP association is detected as QUIC
+    is_quic: bool,
+    /// Represents the proxy the connection goes through
+    proxy: Option<String>,
+    /// Represents the translated port of the connection in the NAT
+    nat_port: Option<u16>,
+    instant: Instant,
+    /// Represents the bytes of unique payload, which exclude retransmissions
+    bytes_up: u64,
+    bytes_down: u64,
+    /// Represents the bytes of payload retransmitted
+    retransmitted_up: u64,
+    retransmitted_down: u64,
+    /// Represents the bytes of frames on the wire, including headers
+    wire_up: u64,
+    wire_down: u64,
+    /// Represents the bytes of SOCKS5 UDP headers of datagrams to and from the relay
+    overhead_up: u64,
+    overhead_down: u64,
+    packets_up: u64,
+    packets_down: u64,
+    /// Represents the count of datagrams which cannot be sent to the relay
+    send_failures: u64,
+    /// Represents the count of datagrams larger than the path MTU to the relay
+    oversized: u64,
+    /// Represents the path MTU to the relay
+    relay_mtu: Option<u16>,
+    /// Represents the jitter of datagrams sent to the source
+    jitter: Jitter,
+    /// Represents the classifier of a TCP connection if connections are classified
+    classifier: Option<Classifier>,
+    is_open: bool,
+}
+
+impl Connection {
+    /// Creates a new `Connection`.
+    pub fn new(
+        client: Ipv4Addr,
+        src_port: u16,
+        dst: SocketAddrV4,
+        protocol: Protocol,
+        is_proxied: bool,
+    ) -> Connection {
+        Connection {
+            id: CONNECTION_ID.fetch_add(1, Ordering::Relaxed) + 1,
+            client,
+            src_port,
+            dst,
+            rewritten: None,
+            protocol,
+            is_proxied,
+            is_picked_up: false,
+            is_quic: false,
+            proxy: None,
+            nat_port: None,
+            instant: Instant::now(),
+            bytes_up: 0,
+            bytes_down: 0,
+            retransmitted_up: 0,
+            retransmitted_down: 0,
+            wire_up: 0,
+            wire_down: 0,
+            overhead_up: 0,
+            overhead_down: 0,
+            packets_up: 0,
+            packets_down: 0,
+            send_failures: 0,
+            oversized: 0,
+            relay_mtu: None,
+            jitter: Jitter::default(),
+            classifier: match protocol {
+                Protocol::Tcp if classify::is_enabled() => Some(Classifier::new(dst.port())),
+                _ => None,
+            },
+            is_open: false,
+        }
+    }
+
+    /// Get the ID of the connection.
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    /// Get the tag of the connection prefixed to log lines.
+    pub fn get_tag(&self) -> Tag {
+        Tag(Some(self.id))
+    }
+
+    /// Get the client of the connection.
+    pub fn get_client(&self) -> Ipv4Addr {
+        self.client
+    }
+
+    /// Get the device the client is learned on if devices are bridged, which follows the client
+    /// once it moves to another device.
+    pub fn get_device(&self) -> Option<String> {
+        bridge::get_device(self.client)
+    }
+
+    /// Get the source port of the connection.
+    pub fn get_src_port(&self) -> u16 {
+        self.src_port
+    }
+
+    /// Get the destination of the connection.
+    pub fn get_dst(&self) -> SocketAddrV4 {
+        self.dst
+    }
+
+    /// Get the destination the connection is rewritten to.
+    pub fn get_rewritten(&self) -> Option<SocketAddrV4> {
+        self.rewritten
+    }
+
+    /// Sets the destination the connection is rewritten to.
+    pub fn set_rewritten(&mut self, rewritten: SocketAddrV4) {
+        self.rewritten = Some(rewritten);
+    }
+
+    /// Returns if the TCP connection is picked up mid-stream without a handshake.
+    pub fn is_picked_up(&self) -> bool {
+        self.is_picked_up
+    }
+
+    /// Sets the TCP connection is picked up mid-stream without a handshake, like a connection
+    /// established before a restart.
+    pub fn set_picked_up(&mut self) {
+        self.is_picked_up = true;
+    }
+
+    /// Returns if the UDP association is detected as QUIC.
+    pub fn is_quic(&self) -> bool {
+        self.is_quic
+    }
+
+    /// Sets the UDP association is detected as QUIC.
+    pub fn set_quic(&mut self) {
+        self.is_quic = true;
+    }
+
+    /// Get the protocol of the connection.
+    pub fn get_protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Returns if the connection is through the proxy.
+    pub fn is_proxied(&self) -> bool {
+        self.is_proxied
+    }
+
+    /// Get the proxy the connection goes through.
+    pub fn get_proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+
+    /// Sets the proxy the connection goes through.
+    pub fn set_proxy(&mut self, proxy: String) {
+        self.proxy = Some(proxy);
+    }
+
+    /// Get the translated port of the connection in the NAT.
+    pub fn get_nat_port(&self) -> Option<u16> {
+        self.nat_port
+    }
+
+    /// Maps the connection through the proxy to a translated port in the NAT if it is enabled.
+    /// The port is released once the connection is closed or dropped. Returns an error if the
+    /// ports of the NAT are exhausted, and the connection should fail.
+    pub fn map_nat(&mut self) -> io::Result<()> {
+        if !self.is_proxied || self.nat_port.is_some() {
+            return Ok(());
+        }
+        if let Some(result) = nat::map(self.client, self.src_port, self.protocol, self.id) {
+            self.nat_port = Some(result?);
+        }
+
+        Ok(())
+    }
+
+    /// Releases the translated port of the connection in the NAT.
+    fn release_nat(&mut self) {
+        if self.nat_port.take().is_some() {
+            nat::release(self.client, self.src_port, self.protocol, self.id);
+        }
+    }
+
+    /// Migrates the connection to a new source port, like a QUIC connection whose source moves
+    /// to a new port. The translated port in the NAT is mapped again for the new source port.
+    pub fn migrate(&mut self, src_port: u16) -> io::Result<()> {
+        if self.is_open {
+            capture::close(
+                self.id,
+                self.protocol,
+                SocketAddrV4::new(self.client, self.src_port),
+                self.dst,
+                false,
+            );
+        }
+        let is_mapped = self.nat_port.is_some();
+        self.release_nat();
+        self.src_port = src_port;
+        if self.is_open {
+            capture::open(
+                self.protocol,
+                SocketAddrV4::new(self.client, self.src_port),
+                self.dst,
+            );
+        }
+        if is_mapped {
+            self.map_nat()?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the bytes sent from the source to the destination.
+    pub fn get_bytes_up(&self) -> u64 {
+        self.bytes_up
+    }
+
+    /// Get the bytes sent from the destination to the source.
+    pub fn get_bytes_down(&self) -> u64 {
+        self.bytes_down
+    }
+
+    /// Get the bytes of payload retransmitted from and to the source.
+    pub fn get_retransmitted_bytes(&self) -> (u64, u64) {
+        (self.retransmitted_up, self.retransmitted_down)
+    }
+
+    /// Get the bytes of frames received from and sent to the source, including headers.
+    pub fn get_wire_bytes(&self) -> (u64, u64) {
+        (self.wire_up, self.wire_down)
+    }
+
+    /// Get the bytes of SOCKS5 UDP headers of datagrams sent to and received from the relay.
+    pub fn get_overhead_bytes(&self) -> (u64, u64) {
+        (self.overhead_up, self.overhead_down)
+    }
+
+    /// Get the datagrams sent from and to the source.
+    pub fn get_packets(&self) -> (u64, u64) {
+        (self.packets_up, self.packets_down)
+    }
+
+    /// Get the count of datagrams which cannot be sent to the relay.
+    pub fn get_send_failures(&self) -> u64 {
+        self.send_failures
+    }
+
+    /// Get the count of datagrams larger than the path MTU to the relay.
+    pub fn get_oversized(&self) -> u64 {
+        self.oversized
+    }
+
+    /// Get the path MTU to the relay.
+    pub fn get_relay_mtu(&self) -> Option<u16> {
+        self.relay_mtu
+    }
+
+    /// Sets the path MTU to the relay.
+    pub fn set_relay_mtu(&mut self, mtu: Option<u16>) {
+        self.relay_mtu = mtu;
+    }
+
+    /// Get the current jitter of datagrams sent to the source.
+    pub fn get_jitter(&self) -> Duration {
+        self.jitter.get()
+    }
+
+    /// Opens the connection, the duration is counted from now.
+    pub fn open(&mut self) {
+        self.instant = Instant::now();
+        self.is_open = true;
+        metrics::open_connection(self.protocol);
+        capture::open(
+            self.protocol,
+            SocketAddrV4::new(self.client, self.src_port),
+            self.dst,
+        );
+        hook::open_connection(self);
+        if is_subscribed() {
+            notify(Event::ConnectionOpen {
+                id: self.id,
+                client: self.client,
+                src_port: self.src_port,
+                dst: self.dst,
+                protocol: self.protocol,
+                is_proxied: self.is_proxied,
+            });
+        }
+        if is_enabled() {
+            log::info!(
+                target: EVENT_TARGET,
+                "\"event\":\"connection_open\",{},\"action\":\"{}\"{}",
+                self.fields(),
+                match self.is_proxied {
+                    true => "proxy",
+                    false => "direct",
+                },
+                match self.proxy {
+                    Some(ref proxy) => format!(",\"proxy\":{}", quote(proxy)),
+                    None => String::new(),
+                }
+            );
+        }
+    }
+
+    /// Counts bytes sent from the source to the destination.
+    pub fn add_bytes_up(&mut self, n: usize) {
+        self.bytes_up += n as u64;
+        metrics::count_bytes_up(self.protocol, n);
+        if let Some(ref mut classifier) = self.classifier {
+            classifier.count_up(n);
+        }
+    }
+
+    /// Counts bytes sent from the destination to the source.
+    pub fn add_bytes_down(&mut self, n: usize) {
+        self.bytes_down += n as u64;
+        metrics::count_bytes_down(self.protocol, n);
+        if let Some(ref mut classifier) = self.classifier {
+            classifier.count_down(n);
+        }
+    }
+
+    /// Counts bytes of payload retransmitted by the source, which are received already.
+    pub fn add_retransmitted_up(&mut self, n: usize) {
+        self.retransmitted_up += n as u64;
+        metrics::count_retransmitted_up(self.protocol, n);
+    }
+
+    /// Counts bytes of payload retransmitted to the source.
+    pub fn add_retransmitted_down(&mut self, n: usize) {
+        self.retransmitted_down += n as u64;
+        metrics::count_retransmitted_down(self.protocol, n);
+    }
+
+    /// Counts bytes of a frame received from the source, including headers. Frames are counted
+    /// in metrics as they are captured, including the ones of no connection.
+    pub fn add_wire_up(&mut self, n: usize) {
+        self.wire_up += n as u64;
+    }
+
+    /// Counts bytes of frames sent to the source, including headers. Frames are counted in
+    /// metrics as they are sent, including the ones of no connection.
+    pub fn add_wire_down(&mut self, n: usize) {
+        self.wire_down += n as u64;
+    }
+
+    /// Counts bytes of SOCKS5 UDP headers of datagrams sent to the relay.
+    pub fn add_overhead_up(&mut self, n: usize) {
+        self.overhead_up += n as u64;
+        metrics::UDP_OVERHEAD_UP.add(n as u64);
+    }
+
+    /// Counts bytes of SOCKS5 UDP headers of datagrams received from the relay.
+    pub fn add_overhead_down(&mut self, n: usize) {
+        self.overhead_down += n as u64;
+        metrics::UDP_OVERHEAD_DOWN.add(n as u64);
+    }
+
+    /// Counts a TCP payload sent from the source to the destination, which is sniffed by the
+    /// classifier before it is counted.
+    pub fn add_payload_up(&mut self, payload: &[u8]) {
+        if let Some(ref mut classifier) = self.classifier {
+            classifier.sniff(payload);
+        }
+        self.add_bytes_up(payload.len());
+    }
+
+    /// Get the class of the connection if it is classified.
+    pub fn get_class(&self) -> Option<&Class> {
+        self.classifier
+            .as_ref()
+            .and_then(|classifier| classifier.get_class())
+    }
+
+    /// Counts a datagram sent from the source to the destination.
+    pub fn add_datagram_up(&mut self, n: usize) {
+        self.packets_up += 1;
+        self.add_bytes_up(n);
+        metrics::UDP_PACKETS_UP.increase();
+    }
+
+    /// Counts a datagram sent from the destination to the source, which updates the jitter.
+    pub fn add_datagram_down(&mut self, n: usize) {
+        self.packets_down += 1;
+        self.add_bytes_down(n);
+        self.jitter.update(Instant::now());
+        metrics::UDP_PACKETS_DOWN.increase();
+    }
+
+    /// Counts a datagram which cannot be sent to the relay.
+    pub fn add_send_failure(&mut self) {
+        self.send_failures += 1;
+        metrics::UDP_SEND_FAILURES.increase();
+    }
+
+    /// Counts a datagram larger than the path MTU to the relay.
+    pub fn add_oversized(&mut self) {
+        self.oversized += 1;
+        metrics::UDP_OVERSIZED.increase();
+    }
+
+    /// Closes the connection for the reason, a connection is closed only once.
+    pub fn close(&mut self, reason: CloseReason) {
+        if !self.is_open {
+            return;
+        }
+        self.is_open = false;
+        metrics::close_connection(self.protocol, reason);
+        metrics::rank_connection(self);
+        hook::close_connection(self, reason);
+        let capture = capture::close(
+            self.id,
+            self.protocol,
+            SocketAddrV4::new(self.client, self.src_port),
+            self.dst,
+            reason.is_error(),
+        );
+        if let Some(ref capture) = capture {
+            log::info!(
+                "{}capture {} {}:{} -> {} closed for {} to {}",
+                self.get_tag(),
+                self.protocol,
+                self.client,
+                self.src_port,
+                self.dst,
+                reason,
+                capture.display()
+            );
+        }
+        let threshold = UDP_SUMMARY_THRESHOLD.load(Ordering::Relaxed);
+        if self.protocol == Protocol::Udp
+            && threshold > 0
+            && self.bytes_up + self.bytes_down >= threshold
+        {
+            log::info!(
+                "{}UDP {}:{} -> {} closed for {} after {} s: {} packets up ({} Bytes, {} Bytes on the wire, {} Bytes of SOCKS5 headers), {} packets down ({} Bytes, {} Bytes on the wire, {} Bytes of SOCKS5 headers), {} send failures, {} oversized, relay MTU {}, mean jitter {:.1} ms",
+                self.get_tag(),
+                self.client,
+                self.src_port,
+                self.dst,
+                reason,
+                self.instant.elapsed().as_secs(),
+                self.packets_up,
+                self.bytes_up,
+                self.wire_up,
+                self.overhead_up,
+                self.packets_down,
+                self.bytes_down,
+                self.wire_down,
+                self.overhead_down,
+                self.send_failures,
+                self.oversized,
+                self.relay_mtu
+                    .map_or_else(|| String::from("-"), |mtu| mtu.to_string()),
+                self.jitter.get_mean().as_micros() as f64 / 1000.0
+            );
+        }
+        if is_subscribed() {
+            notify(Event::ConnectionClose {
+                id: self.id,
+                client: self.client,
+                src_port: self.src_port,
+                dst: self.dst,
+                protocol: self.protocol,
+                duration: self.instant.elapsed(),
+                bytes_up: self.bytes_up,
+                bytes_down: self.bytes_down,
+                bytes_retransmitted_up: self.retransmitted_up,
+                bytes_retransmitted_down: self.retransmitted_down,
+                bytes_wire_up: self.wire_up,
+                bytes_wire_down: self.wire_down,
+                reason,
+            });
+        }
+        if is_enabled() {
+            log::info!(
+                target: EVENT_TARGET,
+                "\"event\":\"connection_close\",{},\"duration_ms\":{},\"bytes_up\":{},\"bytes_down\":{},\"bytes_retransmitted_up\":{},\"bytes_retransmitted_down\":{},\"bytes_wire_up\":{},\"bytes_wire_down\":{},\"bytes_overhead_up\":{},\"bytes_overhead_down\":{},\"close_reason\":\"{}\"{}",
+                self.fields(),
+                self.instant.elapsed().as_millis(),
+                self.bytes_up,
+                self.bytes_down,
+                self.retransmitted_up,
+                self.retransmitted_down,
+                self.wire_up,
+                self.wire_down,
+                self.overhead_up,
+                self.overhead_down,
+                reason,
+                match capture {
+                    Some(ref capture) => {
+                        format!(",\"capture\":{}", quote(&capture.display().to_string()))
+                    }
+                    None => String::new(),
+                }
+            );
+        }
+        self.release_nat();
+    }
+
+    /// Reports an error of the proxy on the connection. Errors of connections not through the
+    /// proxy are not reported.
+    pub fn fail(&self, e: &io::Error) {
+        if !self.is_proxied {
+            return;
+        }
+        if is_subscribed() {
+            notify(Event::ProxyError {
+                id: self.id,
+                client: self.client,
+                src_port: self.src_port,
+                dst: self.dst,
+                protocol: self.protocol,
+                error: e.to_string(),
+            });
+        }
+        if is_enabled() {
+            log::warn!(
+                target: EVENT_TARGET,
+                "\"event\":\"proxy_error\",{},\"error\":{}",
+                self.fields(),
+                quote(&e.to_string())
+            );
+        }
+    }
+
+    fn fields(&self) -> String {
+        format!(
+            "\"id\":{},\"client\":\"{}\",\"src_port\":{},\"dst\":\"{}\",\"protocol\":\"{}\"{}{}{}{}",
+            self.id,
+            self.client,
+            self.src_port,
+            self.dst,
+            self.protocol,
+            match self.get_device() {
+                Some(device) => format!(",\"device\":{}", quote(&device)),
+                None => String::new(),
+            },
+            match self.nat_port {
+                Some(nat_port) => format!(",\"nat_port\":{}", nat_port),
+                None => String::new(),
+            },
+            match self.is_picked_up {
+                true => ",\"picked_up\":true",
+                false => "",
+            },
+            match self.is_quic {
+                true => ",\"quic\":true",
+                false => "",
+            }
+        )
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Connections failed before they are opened are never closed
+        self.release_nat();
+    }
+}
+
+/// Reports a packet dropped for the reason in the layer, which is counted in metrics. Drops are
+/// reported in the verbose level.
+pub fn drop_packet(reason: DropReason, layer: LayerType) {
+    metrics::count_drop(reason, layer);
+    if is_enabled() {
+        log::debug!(
+            target: EVENT_TARGET,
+            "\"event\":\"packet_drop\",\"reason\":\"{}\",\"layer\":{}",
+            reason.get_name(),
+            quote(&layer.to_string())
+        );
+    }
+}
+
+/// Returns if events are logged.
+fn is_enabled() -> bool {
+    IS_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Represents an event of a connection sent to the subscriber.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum Event {
+    /// Represents a connection is opened.
+    ConnectionOpen {
+        id: u64,
+        client: Ipv4Addr,
+        src_port: u16,
+        dst: SocketAddrV4,
+        protocol: Protocol,
+        is_proxied: bool,
+    },
+    /// Represents a connection is closed for the reason.
+    ConnectionClose {
+        id: u64,
+        client: Ipv4Addr,
+        src_port: u16,
+        dst: SocketAddrV4,
+        protocol: Protocol,
+        duration: Duration,
+        /// Represents the bytes of unique payload
+        bytes_up: u64,
+        bytes_down: u64,
+        /// Represents the bytes of payload retransmitted
+        bytes_retransmitted_up: u64,
+        bytes_retransmitted_down: u64,
+        /// Represents the bytes of frames on the wire, including headers
+        bytes_wire_up: u64,
+        bytes_wire_down: u64,
+        reason: CloseReason,
+    },
+    /// Represents the proxy met an error on a connection.
+    ProxyError {
+        id: u64,
+        client: Ipv4Addr,
+        src_port: u16,
+        dst: SocketAddrV4,
+        protocol: Protocol,
+        error: String,
+    },
+}
+
+/// Subscribes events with the channel, which replaces the former subscriber. Events are no
+/// longer sent once the receiver is dropped.
+pub fn subscribe(tx: mpsc::Sender<Event>) {
+    *SUBSCRIBER.lock().unwrap() = Some(tx);
+    IS_SUBSCRIBED.store(true, Ordering::Relaxed);
+}
+
+/// Unsubscribes events.
+pub fn unsubscribe() {
+    IS_SUBSCRIBED.store(false, Ordering::Relaxed);
+    *SUBSCRIBER.lock().unwrap() = None;
+}
+
+/// Sends an event to the subscriber, the subscriber is removed if its receiver is dropped.
+fn notify(event: Event) {
+    let mut subscriber = SUBSCRIBER.lock().unwrap();
+    if let Some(ref tx) = *subscriber {
+        if tx.send(event).is_err() {
+            IS_SUBSCRIBED.store(false, Ordering::Relaxed);
+            *subscriber = None;
+        }
+    }
+}
+
+/// Returns if there is a subscriber of events.
+fn is_subscribed() -> bool {
+    IS_SUBSCRIBED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,122 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::event::{CloseReason, Connection};
+use crate::packet::layer::payload::Payload;
+use crate::packet::layer::{LayerTypes, Layers};
+use crate::packet::Indicator;
+
+pub use crate::pcap::dump::Direction;
+
+/// Represents the actions of the hook on a packet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HookAction {
+    /// Represents the packet is handled as usual.
+    Continue,
+    /// Represents the packet is dropped, which is counted as dropped for `hook`.
+    Drop,
+    /// Represents the payload of the TCP segment or the UDP datagram is replaced, the lengths and
+    /// checksums are fixed in serializing. Replacing a TCP payload with one of another size
+    /// shifts the sequences the peers see, so it is only safe in the same size.
+    ReplacePayload(Vec<u8>),
+}
+
+/// Represents a hook observing and vetoing packets and connections of sources. Packets from
+/// sources are passed to the hook after they are parsed and before they are relayed, and packets
+/// to sources are passed before they are sent. Hooks are called in the threads handling packets,
+/// so they are expected to return quickly.
+pub trait Hook: Send + Sync {
+    /// Decides the action on an IPv4 packet in the direction. `Received` packets are from sources
+    /// and `Sent` packets are to sources.
+    fn on_packet(&self, _indicator: &Indicator, _direction: Direction) -> HookAction {
+        HookAction::Continue
+    }
+
+    /// Observes a connection is opened, with its 4-tuple and if it is through the proxy.
+    fn on_connection_open(&self, _connection: &Connection) {}
+
+    /// Observes a connection is closed for the reason.
+    fn on_connection_close(&self, _connection: &Connection, _reason: CloseReason) {}
+}
+
+/// Represents if there is a hook of connections.
+static IS_HOOKED: AtomicBool = AtomicBool::new(false);
+
+/// Represents the hook of connections.
+static HOOK: Mutex<Option<Arc<dyn Hook>>> = Mutex::new(None);
+
+/// Sets the hook of connections, which replaces the former hook. Hooks of packets are set on the
+/// upstreamers and downstreamers instead.
+pub fn set_hook(hook: Arc<dyn Hook>) {
+    *HOOK.lock().unwrap() = Some(hook);
+    IS_HOOKED.store(true, Ordering::Relaxed);
+}
+
+/// Removes the hook of connections.
+pub fn unset_hook() {
+    IS_HOOKED.store(false, Ordering::Relaxed);
+    *HOOK.lock().unwrap() = None;
+}
+
+/// Passes a connection opened to the hook.
+pub(crate) fn open_connection(connection: &Connection) {
+    if !IS_HOOKED.load(Ordering::Relaxed) {
+        return;
+    }
+    let hook = HOOK.lock().unwrap().clone();
+    if let Some(hook) = hook {
+        hook.on_connection_open(connection);
+    }
+}
+
+/// Passes a connection closed to the hook.
+pub(crate) fn close_connection(connection: &Connection, reason: CloseReason) {
+    if !IS_HOOKED.load(Ordering::Relaxed) {
+        return;
+    }
+    let hook = HOOK.lock().unwrap().clone();
+    if let Some(hook) = hook {
+        hook.on_connection_close(connection, reason);
+    }
+}
+
+/// Creates an `Indicator` of the packet whose TCP or UDP payload is replaced. The packet is
+/// serialized and parsed again, so its lengths and checksums are fixed.
+pub fn replace_payload(indicator: &Indicator, payload: &[u8]) -> io::Result<Indicator> {
+    let transport = match indicator.get_transport() {
+        Some(Layers::Tcp(ref tcp)) => {
+            let mut tcp = tcp.clone();
+            tcp.payload = Payload::copy_from(payload);
+
+            Layers::Tcp(tcp)
+        }
+        Some(Layers::Udp(ref udp)) => {
+            let mut udp = udp.clone();
+            udp.payload = Payload::copy_from(payload);
+
+            Layers::Udp(udp)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "cannot replace payload of {}",
+                    indicator
+                        .get_transport_type()
+                        .or_else(|| indicator.get_network_type())
+                        .unwrap_or(LayerTypes::Ethernet)
+                ),
+            ))
+        }
+    };
+    let replaced = Indicator::with_vlan(
+        indicator.get_link().clone(),
+        indicator.vlan.clone(),
+        indicator.get_network().cloned(),
+        Some(transport),
+    );
+    let frame = Arc::new(replaced.to_vec()?);
+
+    Ok(Indicator::from_shared(&frame)?)
+}
@@ -0,0 +1,795 @@
+use ipnetwork::Ipv4Network;
+use log::{debug, info, trace, warn};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::event::Protocol;
+
+/// Represents the port of SSDP.
+pub const SSDP_PORT: u16 = 1900;
+/// Represents the multicast address of SSDP.
+pub const SSDP_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+/// Represents the port of NAT-PMP servers.
+pub const NATPMP_PORT: u16 = 5351;
+/// Represents the port of the device description on the published address.
+pub const HTTP_PORT: u16 = 5000;
+
+/// Represents the interval of checking if the server is stopped in milliseconds.
+const POLL_INTERVAL: u64 = 100;
+/// Represents the timeout of reading a request and writing the response.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Represents the max size of a request.
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+/// Represents the max number of mappings of all sources.
+const MAX_MAPPINGS: usize = 256;
+/// Represents the max lease time of mappings in seconds, which is also the lease time of the
+/// mappings asked to be permanent.
+const MAX_LEASE: u32 = 86400;
+/// Represents the max age of SSDP responses in seconds.
+const SSDP_MAX_AGE: u32 = 1800;
+
+/// Represents the type of the device.
+const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// Represents the type of the WAN device.
+const WAN_DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:WANDevice:1";
+/// Represents the type of the WAN connection device.
+const WAN_CONNECTION_DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:WANConnectionDevice:1";
+/// Represents the type of the service.
+const SERVICE_TYPE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+const DESCRIPTION_PATH: &str = "/rootDesc.xml";
+const SCPD_PATH: &str = "/WANIPCn.xml";
+const CONTROL_PATH: &str = "/ctl/IPConn";
+
+/// Represents the NAT-PMP operation of the external address.
+const NATPMP_OP_EXTERNAL: u8 = 0;
+/// Represents the NAT-PMP operation of mapping UDP.
+const NATPMP_OP_UDP: u8 = 1;
+/// Represents the NAT-PMP operation of mapping TCP.
+const NATPMP_OP_TCP: u8 = 2;
+const NATPMP_SUCCESS: u16 = 0;
+const NATPMP_UNSUPPORTED_VERSION: u16 = 1;
+const NATPMP_NOT_AUTHORIZED: u16 = 2;
+const NATPMP_NETWORK_FAILURE: u16 = 3;
+const NATPMP_OUT_OF_RESOURCES: u16 = 4;
+const NATPMP_UNSUPPORTED_OPCODE: u16 = 5;
+
+/// Represents an error of an action of the service.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ActionError {
+    InvalidAction,
+    InvalidArgs,
+    NotAuthorized,
+    ArrayIndexInvalid,
+    NoSuchEntry,
+    Conflict,
+    SamePortValuesRequired,
+    NoPortMapsAvailable,
+}
+
+impl ActionError {
+    fn get_code(&self) -> u16 {
+        match self {
+            ActionError::InvalidAction => 401,
+            ActionError::InvalidArgs => 402,
+            ActionError::NotAuthorized => 606,
+            ActionError::ArrayIndexInvalid => 713,
+            ActionError::NoSuchEntry => 714,
+            ActionError::Conflict => 718,
+            ActionError::SamePortValuesRequired => 724,
+            ActionError::NoPortMapsAvailable => 728,
+        }
+    }
+
+    fn to_natpmp(self) -> u16 {
+        match self {
+            ActionError::NoPortMapsAvailable => NATPMP_OUT_OF_RESOURCES,
+            _ => NATPMP_NOT_AUTHORIZED,
+        }
+    }
+}
+
+impl Display for ActionError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            ActionError::InvalidAction => write!(f, "Invalid Action"),
+            ActionError::InvalidArgs => write!(f, "Invalid Args"),
+            ActionError::NotAuthorized => write!(f, "Action not authorized"),
+            ActionError::ArrayIndexInvalid => write!(f, "SpecifiedArrayIndexInvalid"),
+            ActionError::NoSuchEntry => write!(f, "NoSuchEntryInArray"),
+            ActionError::Conflict => write!(f, "ConflictInMappingEntry"),
+            ActionError::SamePortValuesRequired => write!(f, "SamePortValuesRequired"),
+            ActionError::NoPortMapsAvailable => write!(f, "NoPortMapsAvailable"),
+        }
+    }
+}
+
+/// Represents a port mapping asked by a source. The external port is always the internal port,
+/// as inbound connections and datagrams come through the proxy to the same port.
+#[derive(Clone, Debug)]
+struct Mapping {
+    client: Ipv4Addr,
+    description: String,
+    lease: u32,
+    expiry: Instant,
+}
+
+impl Mapping {
+    fn is_expired(&self) -> bool {
+        self.expiry <= Instant::now()
+    }
+
+    /// Get the remaining lease time in seconds.
+    fn get_remaining(&self) -> u32 {
+        self.expiry
+            .saturating_duration_since(Instant::now())
+            .as_secs() as u32
+    }
+}
+
+/// Represents the state of the server shared with the thread of the HTTP listener.
+#[derive(Debug)]
+struct State {
+    ip_addr: Ipv4Addr,
+    src: Ipv4Network,
+    uuid: String,
+    /// Represents if TCP mappings are supported, which requires the proxy to bind
+    is_tcp: bool,
+    external_ip_addr: Mutex<Option<Ipv4Addr>>,
+    mappings: Mutex<HashMap<(Protocol, u16), Mapping>>,
+    epoch: Instant,
+}
+
+impl State {
+    fn get_external_ip_addr(&self) -> Option<Ipv4Addr> {
+        *self.external_ip_addr.lock().unwrap()
+    }
+
+    /// Adds or renews a mapping of a source.
+    fn add(
+        &self,
+        protocol: Protocol,
+        client: Ipv4Addr,
+        port: u16,
+        lease: u32,
+        description: &str,
+    ) -> Result<u32, ActionError> {
+        if port == 0 {
+            return Err(ActionError::InvalidArgs);
+        }
+        if !self.src.contains(client) || client == self.ip_addr {
+            return Err(ActionError::NotAuthorized);
+        }
+        if protocol == Protocol::Tcp && !self.is_tcp {
+            return Err(ActionError::NotAuthorized);
+        }
+        let lease = match lease {
+            0 => MAX_LEASE,
+            lease => lease.min(MAX_LEASE),
+        };
+
+        let mut mappings = self.mappings.lock().unwrap();
+        expire(&mut mappings);
+        match mappings.get(&(protocol, port)) {
+            Some(mapping) if mapping.client != client => return Err(ActionError::Conflict),
+            Some(_) => {}
+            None if mappings.len() >= MAX_MAPPINGS => return Err(ActionError::NoPortMapsAvailable),
+            None => {}
+        }
+        mappings.insert(
+            (protocol, port),
+            Mapping {
+                client,
+                description: description.to_string(),
+                lease,
+                expiry: Instant::now() + Duration::from_secs(lease as u64),
+            },
+        );
+
+        Ok(lease)
+    }
+
+    /// Removes a mapping of a source, or of any source if the source is not given.
+    fn remove(
+        &self,
+        protocol: Protocol,
+        client: Option<Ipv4Addr>,
+        port: u16,
+    ) -> Result<(), ActionError> {
+        let mut mappings = self.mappings.lock().unwrap();
+        expire(&mut mappings);
+        match mappings.get(&(protocol, port)) {
+            Some(mapping) if client.is_none() || client == Some(mapping.client) => {
+                mappings.remove(&(protocol, port));
+
+                Ok(())
+            }
+            Some(_) => Err(ActionError::NotAuthorized),
+            None => Err(ActionError::NoSuchEntry),
+        }
+    }
+}
+
+/// Removes expired mappings.
+fn expire(mappings: &mut HashMap<(Protocol, u16), Mapping>) {
+    mappings.retain(|&(protocol, port), mapping| {
+        if mapping.is_expired() {
+            info!(
+                "Unmap {} {}:{} for expiry ({})",
+                protocol, mapping.client, port, mapping.description
+            );
+            return false;
+        }
+
+        true
+    });
+}
+
+/// Represents a responder of UPnP IGD and NAT-PMP, which lets sources ask for port mappings.
+/// TCP mappings make the proxy listen for inbound connections by SOCKS BIND, and UDP mappings
+/// make the associations of the port accept datagrams from any peer.
+#[derive(Debug)]
+pub struct Server {
+    state: Arc<State>,
+    addr: SocketAddrV4,
+    is_stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Opens a `Server` published on the address for the sources in the network. The device
+    /// description and the control of the service are served by a listener in local on its own
+    /// thread, which connections to the HTTP port of the published address are redirected to.
+    pub fn open(ip_addr: Ipv4Addr, src: Ipv4Network, is_tcp: bool) -> io::Result<Server> {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        listener.set_nonblocking(true)?;
+        let addr = match listener.local_addr()? {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let octets = ip_addr.octets();
+        let state = Arc::new(State {
+            ip_addr,
+            src,
+            uuid: format!(
+                "5c2fb2e8-9a7d-4f3c-8a3e-{:02x}{:02x}{:02x}{:02x}0000",
+                octets[0], octets[1], octets[2], octets[3]
+            ),
+            is_tcp,
+            external_ip_addr: Mutex::new(None),
+            mappings: Mutex::new(HashMap::new()),
+            epoch: Instant::now(),
+        });
+
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let is_stopped_cloned = Arc::clone(&is_stopped);
+        let state_cloned = Arc::clone(&state);
+        let thread = thread::spawn(move || {
+            while !is_stopped_cloned.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let state = Arc::clone(&state_cloned);
+                        thread::spawn(move || {
+                            if let Err(ref e) = serve(&state, stream) {
+                                debug!("serve {}: {}", "IGD", e);
+                            }
+                        });
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(POLL_INTERVAL))
+                    }
+                    Err(ref e) => {
+                        warn!("handle {}: {}", "IGD", e);
+                        thread::sleep(Duration::from_millis(POLL_INTERVAL));
+                    }
+                }
+            }
+        });
+
+        Ok(Server {
+            state,
+            addr,
+            is_stopped,
+            thread: Some(thread),
+        })
+    }
+
+    /// Get the address of the listener in local.
+    pub fn get_addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    /// Get the published address of the server.
+    pub fn get_ip_addr(&self) -> Ipv4Addr {
+        self.state.ip_addr
+    }
+
+    /// Sets the external address of the proxy, which is answered to sources.
+    pub fn set_external_ip_addr(&self, ip_addr: Ipv4Addr) {
+        let mut external_ip_addr = self.state.external_ip_addr.lock().unwrap();
+        if *external_ip_addr != Some(ip_addr) {
+            *external_ip_addr = Some(ip_addr);
+            debug!("set IGD external address to {}", ip_addr);
+        }
+    }
+
+    /// Get the ports mapped for the source.
+    pub fn get_ports(&self, protocol: Protocol, client: Ipv4Addr) -> Vec<u16> {
+        let mut mappings = self.state.mappings.lock().unwrap();
+        expire(&mut mappings);
+
+        mappings
+            .iter()
+            .filter(|(key, mapping)| key.0 == protocol && mapping.client == client)
+            .map(|(&(_, port), _)| port)
+            .collect()
+    }
+
+    /// Returns if the port of the source is mapped.
+    pub fn is_mapped(&self, protocol: Protocol, client: Ipv4Addr, port: u16) -> bool {
+        match self.state.mappings.lock().unwrap().get(&(protocol, port)) {
+            Some(mapping) => mapping.client == client && !mapping.is_expired(),
+            None => false,
+        }
+    }
+
+    /// Handles an SSDP message from a source, returns the responses to an M-SEARCH for the
+    /// device or the service.
+    pub fn handle_ssdp(&self, message: &[u8]) -> Vec<Vec<u8>> {
+        let message = match std::str::from_utf8(message) {
+            Ok(message) => message,
+            Err(_) => return Vec::new(),
+        };
+        let mut lines = message.split("\r\n");
+        if !lines
+            .next()
+            .map(|line| line.starts_with("M-SEARCH * "))
+            .unwrap_or(false)
+        {
+            return Vec::new();
+        }
+        let headers = parse_headers(lines);
+        match headers.get("man") {
+            Some(man) if man.trim_matches('"') == "ssdp:discover" => {}
+            _ => return Vec::new(),
+        }
+        let st = match headers.get("st") {
+            Some(st) => st.as_str(),
+            None => return Vec::new(),
+        };
+
+        let uuid = format!("uuid:{}", self.state.uuid);
+        let targets = [
+            "upnp:rootdevice",
+            DEVICE_TYPE,
+            WAN_DEVICE_TYPE,
+            WAN_CONNECTION_DEVICE_TYPE,
+            SERVICE_TYPE,
+            uuid.as_str(),
+        ];
+        targets
+            .iter()
+            .filter(|&&target| st == "ssdp:all" || st == target)
+            .map(|&target| {
+                let usn = match target {
+                    target if target == uuid => uuid.clone(),
+                    target => format!("{}::{}", uuid, target),
+                };
+                format!(
+                    "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age={}\r\nEXT:\r\nLOCATION: http://{}:{}{}\r\nSERVER: {}\r\nST: {}\r\nUSN: {}\r\n\r\n",
+                    SSDP_MAX_AGE,
+                    self.state.ip_addr,
+                    HTTP_PORT,
+                    DESCRIPTION_PATH,
+                    server_name(),
+                    target,
+                    usn
+                )
+                .into_bytes()
+            })
+            .collect()
+    }
+
+    /// Handles a NAT-PMP request from a source, returns the response if there is one.
+    pub fn handle_natpmp(&self, client: Ipv4Addr, request: &[u8]) -> Option<Vec<u8>> {
+        if request.len() < 2 || request[1] >= 128 {
+            return None;
+        }
+        let op = request[1];
+        let epoch = self.state.epoch.elapsed().as_secs() as u32;
+        let mut response = vec![0, 128 + op];
+        if request[0] != 0 {
+            response.extend_from_slice(&NATPMP_UNSUPPORTED_VERSION.to_be_bytes());
+            response.extend_from_slice(&epoch.to_be_bytes());
+
+            return Some(response);
+        }
+
+        match op {
+            NATPMP_OP_EXTERNAL => {
+                let (result, ip_addr) = match self.state.get_external_ip_addr() {
+                    Some(ip_addr) => (NATPMP_SUCCESS, ip_addr),
+                    None => (NATPMP_NETWORK_FAILURE, Ipv4Addr::UNSPECIFIED),
+                };
+                debug!("{} asks for the external address by NAT-PMP", client);
+                response.extend_from_slice(&result.to_be_bytes());
+                response.extend_from_slice(&epoch.to_be_bytes());
+                response.extend_from_slice(&ip_addr.octets());
+            }
+            NATPMP_OP_UDP | NATPMP_OP_TCP if request.len() >= 12 => {
+                let protocol = match op {
+                    NATPMP_OP_UDP => Protocol::Udp,
+                    _ => Protocol::Tcp,
+                };
+                let port = u16::from_be_bytes([request[4], request[5]]);
+                let lifetime =
+                    u32::from_be_bytes([request[8], request[9], request[10], request[11]]);
+
+                let (result, lifetime) = if lifetime == 0 {
+                    // A request of deleting all mappings of the protocol of the source
+                    if port == 0 {
+                        for port in self.get_ports(protocol, client) {
+                            let _ = self.state.remove(protocol, Some(client), port);
+                            info!("Unmap {} {}:{} by NAT-PMP", protocol, client, port);
+                        }
+                    } else if self.state.remove(protocol, Some(client), port).is_ok() {
+                        info!("Unmap {} {}:{} by NAT-PMP", protocol, client, port);
+                    }
+
+                    (NATPMP_SUCCESS, 0)
+                } else {
+                    match self.state.add(protocol, client, port, lifetime, "NAT-PMP") {
+                        Ok(lease) => {
+                            info!(
+                                "Map {} {}:{} for {} s by NAT-PMP",
+                                protocol, client, port, lease
+                            );
+
+                            (NATPMP_SUCCESS, lease)
+                        }
+                        Err(e) => {
+                            info!(
+                                "Refuse mapping {} {}:{} by NAT-PMP: {}",
+                                protocol, client, port, e
+                            );
+
+                            (e.to_natpmp(), 0)
+                        }
+                    }
+                };
+                response.extend_from_slice(&result.to_be_bytes());
+                response.extend_from_slice(&epoch.to_be_bytes());
+                response.extend_from_slice(&port.to_be_bytes());
+                match result {
+                    NATPMP_SUCCESS => response.extend_from_slice(&port.to_be_bytes()),
+                    _ => response.extend_from_slice(&0u16.to_be_bytes()),
+                }
+                response.extend_from_slice(&lifetime.to_be_bytes());
+            }
+            NATPMP_OP_UDP | NATPMP_OP_TCP => return None,
+            _ => {
+                response.extend_from_slice(&NATPMP_UNSUPPORTED_OPCODE.to_be_bytes());
+                response.extend_from_slice(&epoch.to_be_bytes());
+            }
+        }
+
+        Some(response)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Returns if the address is a public address, which may be the external address of the proxy.
+pub fn is_public(ip_addr: Ipv4Addr) -> bool {
+    let octets = ip_addr.octets();
+    // The shared address space (RFC 6598)
+    let is_shared = octets[0] == 100 && octets[1] & 0xc0 == 64;
+
+    !(ip_addr.is_unspecified()
+        || ip_addr.is_loopback()
+        || ip_addr.is_private()
+        || ip_addr.is_link_local()
+        || ip_addr.is_broadcast()
+        || ip_addr.is_multicast()
+        || is_shared)
+}
+
+/// Get the name of the server in SSDP responses and HTTP responses.
+fn server_name() -> String {
+    format!(
+        "{}/1.0 UPnP/1.0 pcap2socks/{}",
+        std::env::consts::OS,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// Parses the headers of a message, the names are in lowercase.
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>) -> HashMap<String, String> {
+    lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let i = line.find(':')?;
+            Some((
+                line[..i].trim().to_ascii_lowercase(),
+                line[i + 1..].trim().to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Serves a request on a connection redirected from a source.
+fn serve(state: &State, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+    // Read the head
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+    let head_size = loop {
+        if let Some(i) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break i + 4;
+        }
+        if buffer.len() >= MAX_REQUEST_SIZE {
+            return respond(&mut stream, "413 Payload Too Large", "text/plain", "");
+        }
+        let size = stream.read(&mut chunk)?;
+        if size == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        buffer.extend_from_slice(&chunk[..size]);
+    };
+    let head = String::from_utf8_lossy(&buffer[..head_size]).to_string();
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    let mut parts = request_line.split(' ');
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+    let headers = parse_headers(lines);
+
+    // Read the body
+    let length = match headers.get("content-length") {
+        Some(length) => match length.parse::<usize>() {
+            Ok(length) if head_size + length <= MAX_REQUEST_SIZE => length,
+            _ => return respond(&mut stream, "413 Payload Too Large", "text/plain", ""),
+        },
+        None => 0,
+    };
+    while buffer.len() < head_size + length {
+        let size = stream.read(&mut chunk)?;
+        if size == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        buffer.extend_from_slice(&chunk[..size]);
+    }
+    let body = String::from_utf8_lossy(&buffer[head_size..head_size + length]).to_string();
+    trace!("receive IGD request {} {}", method, path);
+
+    match (method, path) {
+        ("GET", DESCRIPTION_PATH) => {
+            respond(&mut stream, "200 OK", "text/xml", &description(state))
+        }
+        ("GET", SCPD_PATH) => respond(&mut stream, "200 OK", "text/xml", SCPD),
+        ("POST", CONTROL_PATH) => {
+            let action = headers
+                .get("soapaction")
+                .map(|soap_action| soap_action.trim_matches('"'))
+                .and_then(|soap_action| soap_action.rsplit('#').next())
+                .unwrap_or("")
+                .to_string();
+            match handle_action(state, &action, &body) {
+                Ok(args) => respond(
+                    &mut stream,
+                    "200 OK",
+                    "text/xml; charset=\"utf-8\"",
+                    &format!(
+                        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{}Response xmlns:u=\"{}\">{}</u:{}Response></s:Body></s:Envelope>\r\n",
+                        action, SERVICE_TYPE, args, action
+                    ),
+                ),
+                Err(e) => {
+                    debug!("answer IGD action {} with error {}: {}", action, e.get_code(), e);
+                    respond(
+                        &mut stream,
+                        "500 Internal Server Error",
+                        "text/xml; charset=\"utf-8\"",
+                        &format!(
+                            "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\"><errorCode>{}</errorCode><errorDescription>{}</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>\r\n",
+                            e.get_code(),
+                            e
+                        ),
+                    )
+                }
+            }
+        }
+        ("GET", _) | ("POST", _) => respond(&mut stream, "404 Not Found", "text/plain", ""),
+        _ => respond(&mut stream, "405 Method Not Allowed", "text/plain", ""),
+    }
+}
+
+/// Writes a response and closes the connection.
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nServer: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        server_name(),
+        body
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+/// Handles an action of the service, returns the arguments of the response.
+fn handle_action(state: &State, action: &str, body: &str) -> Result<String, ActionError> {
+    match action {
+        "GetExternalIPAddress" => {
+            let ip_addr = state.get_external_ip_addr();
+            debug!("answer IGD external address {:?}", ip_addr);
+
+            Ok(format!(
+                "<NewExternalIPAddress>{}</NewExternalIPAddress>",
+                ip_addr.map(|ip_addr| ip_addr.to_string()).unwrap_or_default()
+            ))
+        }
+        "GetStatusInfo" => Ok(String::from(
+            "<NewConnectionStatus>Connected</NewConnectionStatus><NewLastConnectionError>ERROR_NONE</NewLastConnectionError><NewUptime>0</NewUptime>",
+        )),
+        "GetConnectionTypeInfo" => Ok(String::from(
+            "<NewConnectionType>IP_Routed</NewConnectionType><NewPossibleConnectionTypes>IP_Routed</NewPossibleConnectionTypes>",
+        )),
+        "GetNATRSIPStatus" => {
+            Ok(String::from("<NewRSIPAvailable>0</NewRSIPAvailable><NewNATEnabled>1</NewNATEnabled>"))
+        }
+        "AddPortMapping" => {
+            let external_port = get_port(body, "NewExternalPort")?;
+            let internal_port = get_port(body, "NewInternalPort")?;
+            let protocol = get_protocol(body)?;
+            let client = get_argument(body, "NewInternalClient")
+                .and_then(|client| client.parse::<Ipv4Addr>().ok())
+                .ok_or(ActionError::InvalidArgs)?;
+            let lease = match get_argument(body, "NewLeaseDuration") {
+                Some(lease) => lease.parse().map_err(|_| ActionError::InvalidArgs)?,
+                None => 0,
+            };
+            let description = get_argument(body, "NewPortMappingDescription").unwrap_or_default();
+            info!(
+                "{} asks for mapping {} {} to {} for {} s by UPnP ({})",
+                client, protocol, external_port, internal_port, lease, description
+            );
+            if external_port != internal_port {
+                return Err(ActionError::SamePortValuesRequired);
+            }
+
+            let lease = state.add(protocol, client, internal_port, lease, &description)?;
+            info!(
+                "Map {} {}:{} for {} s by UPnP",
+                protocol, client, internal_port, lease
+            );
+
+            Ok(String::new())
+        }
+        "DeletePortMapping" => {
+            let port = get_port(body, "NewExternalPort")?;
+            let protocol = get_protocol(body)?;
+            state.remove(protocol, None, port)?;
+            info!("Unmap {} {} by UPnP", protocol, port);
+
+            Ok(String::new())
+        }
+        "GetSpecificPortMappingEntry" => {
+            let port = get_port(body, "NewExternalPort")?;
+            let protocol = get_protocol(body)?;
+            let mut mappings = state.mappings.lock().unwrap();
+            expire(&mut mappings);
+            let mapping = mappings
+                .get(&(protocol, port))
+                .ok_or(ActionError::NoSuchEntry)?;
+
+            Ok(format!(
+                "<NewInternalPort>{}</NewInternalPort><NewInternalClient>{}</NewInternalClient><NewEnabled>1</NewEnabled><NewPortMappingDescription>{}</NewPortMappingDescription><NewLeaseDuration>{}</NewLeaseDuration>",
+                port,
+                mapping.client,
+                escape(&mapping.description),
+                mapping.get_remaining()
+            ))
+        }
+        "GetGenericPortMappingEntry" => {
+            let index = get_argument(body, "NewPortMappingIndex")
+                .and_then(|index| index.parse::<usize>().ok())
+                .ok_or(ActionError::InvalidArgs)?;
+            let mut mappings = state.mappings.lock().unwrap();
+            expire(&mut mappings);
+            let mut entries = mappings.iter().collect::<Vec<_>>();
+            entries.sort_by_key(|(key, _)| (key.1, key.0 == Protocol::Udp));
+            let &(&(protocol, port), mapping) = entries
+                .get(index)
+                .ok_or(ActionError::ArrayIndexInvalid)?;
+
+            Ok(format!(
+                "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort><NewProtocol>{}</NewProtocol><NewInternalPort>{}</NewInternalPort><NewInternalClient>{}</NewInternalClient><NewEnabled>1</NewEnabled><NewPortMappingDescription>{}</NewPortMappingDescription><NewLeaseDuration>{}</NewLeaseDuration>",
+                port,
+                protocol.to_string().to_uppercase(),
+                port,
+                mapping.client,
+                escape(&mapping.description),
+                mapping.get_remaining().min(mapping.lease)
+            ))
+        }
+        _ => Err(ActionError::InvalidAction),
+    }
+}
+
+/// Get the value of an argument in the body of an action.
+fn get_argument(body: &str, name: &str) -> Option<String> {
+    if body.contains(&format!("<{}/>", name)) {
+        return Some(String::new());
+    }
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let start = body.find(&open)? + open.len();
+    let end = start + body[start..].find(&close)?;
+
+    Some(unescape(body[start..end].trim()))
+}
+
+fn get_port(body: &str, name: &str) -> Result<u16, ActionError> {
+    get_argument(body, name)
+        .and_then(|port| port.parse::<u16>().ok())
+        .ok_or(ActionError::InvalidArgs)
+}
+
+fn get_protocol(body: &str) -> Result<Protocol, ActionError> {
+    match get_argument(body, "NewProtocol").as_deref() {
+        Some("TCP") | Some("tcp") => Ok(Protocol::Tcp),
+        Some("UDP") | Some("udp") => Ok(Protocol::Udp),
+        _ => Err(ActionError::InvalidArgs),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Get the device description.
+fn description(state: &State) -> String {
+    format!(
+        "<?xml version=\"1.0\"?>\r\n<root xmlns=\"urn:schemas-upnp-org:device-1-0\"><specVersion><major>1</major><minor>0</minor></specVersion><URLBase>http://{}:{}</URLBase><device><deviceType>{}</deviceType><friendlyName>pcap2socks</friendlyName><manufacturer>pcap2socks</manufacturer><modelName>pcap2socks</modelName><modelNumber>{}</modelNumber><UDN>uuid:{}</UDN><deviceList><device><deviceType>{}</deviceType><friendlyName>WAN Device</friendlyName><manufacturer>pcap2socks</manufacturer><modelName>pcap2socks</modelName><UDN>uuid:{}</UDN><deviceList><device><deviceType>{}</deviceType><friendlyName>WAN Connection Device</friendlyName><manufacturer>pcap2socks</manufacturer><modelName>pcap2socks</modelName><UDN>uuid:{}</UDN><serviceList><service><serviceType>{}</serviceType><serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId><SCPDURL>{}</SCPDURL><controlURL>{}</controlURL><eventSubURL>/evt/IPConn</eventSubURL></service></serviceList></device></deviceList></device></deviceList></device></root>\r\n",
+        state.ip_addr,
+        HTTP_PORT,
+        DEVICE_TYPE,
+        env!("CARGO_PKG_VERSION"),
+        state.uuid,
+        WAN_DEVICE_TYPE,
+        state.uuid,
+        WAN_CONNECTION_DEVICE_TYPE,
+        state.uuid,
+        SERVICE_TYPE,
+        SCPD_PATH,
+        CONTROL_PATH
+    )
+}
+
+/// Represents the service description, which lists the actions answered.
+const SCPD: &str = "<?xml version=\"1.0\"?>\r\n<scpd xmlns=\"urn:schemas-upnp-org:service-1-0\"><specVersion><major>1</major><minor>0</minor></specVersion><actionList><action><name>GetExternalIPAddress</name></action><action><name>GetStatusInfo</name></action><action><name>GetConnectionTypeInfo</name></action><action><name>GetNATRSIPStatus</name></action><action><name>AddPortMapping</name></action><action><name>DeletePortMapping</name></action><action><name>GetSpecificPortMappingEntry</name></action><action><name>GetGenericPortMappingEntry</name></action></actionList><serviceStateTable></serviceStateTable></scpd>\r\n";
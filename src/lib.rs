@@ -1,45 +1,131 @@
-use log::{debug, trace, warn};
+use ipnetwork::{Ipv4Network, Ipv6Network};
+use log::{debug, info, trace, warn};
 use lru::LruCache;
-use std::cmp::min;
-use std::collections::HashMap;
-use std::io::{self, Read, Write};
-use std::net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpStream};
-use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::cmp::{max, min};
+use std::collections::hash_map::{DefaultHasher, Entry, RandomState};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::hash::{BuildHasher, Hash, Hasher};
+#[cfg(not(feature = "async"))]
+use std::io::Read;
+use std::io::{self, Write};
+#[cfg(not(feature = "async"))]
+use std::net::Shutdown;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, TcpStream};
+use std::ops::Range;
+use std::process;
+#[cfg(not(feature = "async"))]
+use std::sync::atomic::AtomicU16;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 pub mod args;
+pub mod arp;
+pub mod bench;
 pub mod cacher;
+pub mod classify;
+pub mod clock;
+pub mod config;
+pub mod congestion;
+pub mod dhcp;
+pub mod dns;
+pub mod estimator;
+pub mod event;
+pub mod hook;
+pub mod igd;
+pub mod memory;
+pub mod metrics;
+pub mod nat;
 pub mod packet;
 pub mod pcap;
+pub mod pool;
+pub mod privilege;
+pub mod quic;
+#[cfg(feature = "async")]
+mod relay;
+pub mod reload;
+pub mod resolver;
+pub mod responder;
+pub mod rule;
+pub mod selftest;
+pub mod service;
+pub mod shaper;
 pub mod socks;
+pub mod state;
+use crate::arp::{Resolution, Resolver};
+use crate::dns::Query;
+use crate::rule::{Access, AccessList, Action, BlockAction, BlocklistFile, Rewrites, Rules};
+#[cfg(not(feature = "async"))]
 use crate::socks::SocksDatagram;
+use crate::socks::{OversizedError, ProxyConnector, ReplyError, UnreachableError};
 use cacher::{Cacher, RandomCacher};
-use packet::layer::arp::Arp;
-use packet::layer::ethernet::Ethernet;
-use packet::layer::ipv4::Ipv4;
+use clock::{Clock, MonotonicClock};
+use congestion::{Algorithm, CongestionControl};
+use estimator::RttEstimator;
+use event::{CloseReason, Connection, Protocol, Tag};
+use hook::{Hook, HookAction};
+use memory::Pressure;
+use metrics::DropReason;
+use packet::igmp::{self as igmp_packet, Igmp};
+use packet::layer::arp::{Arp, ArpKind};
+use packet::layer::ethernet::{self, Ethernet};
+use packet::layer::icmpv4::Icmpv4;
+use packet::layer::icmpv6::{self, Icmpv6};
+use packet::layer::ipv4::{self, Ipv4, ECN_CE, ECN_ECT0};
+use packet::layer::ipv6::Ipv6;
+use packet::layer::payload::{Frame, FramePool};
 use packet::layer::tcp::Tcp;
 use packet::layer::udp::Udp;
-use packet::layer::{Layer, LayerType, LayerTypes, Layers};
-use packet::{Defraggler, Indicator};
-use pcap::{HardwareAddr, Interface, Receiver, Sender};
-
-/// Sets the logger.
-pub fn set_logger(flags: &args::Flags) {
+use packet::layer::vlan::{Vlan, VLAN_TAG_SIZE};
+use packet::layer::{Layer, LayerType, LayerTypes, Layers, ParseError, Verbosity};
+use packet::verify::{self, VerifyMode};
+use packet::{ChecksumMode, ChecksumState, Defraggler, Indicator};
+use pcap::capture;
+use pcap::dump::{Direction, Dumper};
+use pcap::filter::Expression;
+use pcap::trace::{hex_dump, Tracer};
+use pcap::{Congestion, HardwareAddr, Interface, Receiver, SendQueue, Sender};
+use pool::BufferPool;
+#[cfg(feature = "async")]
+use relay::{DatagramWorker, StreamWorker};
+use responder::Responder;
+use shaper::{Limits, Shaper};
+use state::UdpState;
+
+pub use event::Event;
+pub use metrics::Stats;
+pub use service::{Device, Error, Handle, Pcap2Socks, Summary};
+
+/// Sets the logger. Logs in the human format to the standard output are colored.
+pub fn set_logger(flags: &args::Flags) -> io::Result<()> {
     use env_logger::fmt::{Color, Target};
     use log::{Level, LevelFilter};
 
-    let level = match &flags.vverbose {
-        true => LevelFilter::Trace,
-        false => match flags.verbose {
-            true => LevelFilter::Debug,
-            false => LevelFilter::Info,
-        },
-    };
+    let level = get_log_level(flags);
+    let format = event::Format::parse(
+        flags
+            .log_format
+            .as_deref()
+            .unwrap_or(args::DEFAULT_LOG_FORMAT),
+    )
+    .unwrap_or(event::Format::Human);
+    if format != event::Format::Human || flags.log_file.is_some() {
+        let logger = event::Logger::new(level, format, flags.log_file.as_deref())?;
+        logger
+            .init()
+            .map_err(|e| io::Error::new(io::ErrorKind::AlreadyExists, e.to_string()))?;
+        set_log_level(level);
+
+        return Ok(());
+    }
+
+    // Logs are filtered by the max level, which changes in reloads
     env_logger::builder()
         .target(Target::Stdout)
-        .filter_level(level)
+        .filter_level(LevelFilter::Trace)
         .format(|buf, record| {
             let mut style = buf.style();
 
@@ -55,16 +141,136 @@ pub fn set_logger(flags: &args::Flags) {
             writeln!(buf, "{}{}", level, record.args())
         })
         .init();
+    set_log_level(level);
+
+    Ok(())
+}
+
+/// Get the level of logs of the flags.
+pub(crate) fn get_log_level(flags: &args::Flags) -> log::LevelFilter {
+    use log::LevelFilter;
+
+    match &flags.vverbose {
+        true => LevelFilter::Trace,
+        false => match flags.verbose {
+            true => LevelFilter::Debug,
+            false => LevelFilter::Info,
+        },
+    }
+}
+
+/// Sets the level of logs, which applies to the logger at once.
+pub(crate) fn set_log_level(level: log::LevelFilter) {
+    use log::LevelFilter;
+
+    log::set_max_level(level);
+    // Packets are summarized in more fields in more verbose logs
+    packet::layer::set_verbosity(match level {
+        LevelFilter::Trace => Verbosity::Verbose,
+        LevelFilter::Debug => Verbosity::Normal,
+        _ => Verbosity::Brief,
+    });
 }
 
 /// Gets a list of available network interfaces for the current machine.
 pub fn interfaces() -> Vec<Interface> {
     pcap::interfaces()
         .into_iter()
-        .filter(|inter| !inter.is_loopback)
+        .filter(|inter| !inter.is_loopback || inter.is_npcap_loopback())
         .collect()
 }
 
+/// Formats the table of available network interfaces, with their indexes, hardware addresses,
+/// IPv4 addresses, link status, MTU and if they can be opened. The interface selected if no
+/// device is designated is marked with `*`.
+pub fn interface_table() -> String {
+    let inters = interfaces();
+    let selected = service::select_interface(&Device::Auto, inters.clone())
+        .ok()
+        .map(|inter| inter.name);
+
+    let header = [
+        "",
+        "#",
+        "Name",
+        "Hardware Address",
+        "Addresses",
+        "Status",
+        "MTU",
+        "Open",
+    ];
+    let rows: Vec<Vec<String>> = inters
+        .iter()
+        .enumerate()
+        .map(|(i, inter)| {
+            let name = match inter.alias {
+                Some(ref alias) => format!("{} ({})", inter.name, alias),
+                None => inter.name.clone(),
+            };
+            let ip_addrs = inter
+                .ip_addrs
+                .iter()
+                .map(|ip_addr| ip_addr.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let mut status = String::from(match inter.is_up {
+                true => "up",
+                false => "down",
+            });
+            if inter.is_npcap_loopback() {
+                status.push_str(", Npcap Loopback");
+            } else if inter.is_loopback {
+                status.push_str(", Loopback");
+            } else if inter.link_type != pcap::link::LinkType::Ethernet {
+                status.push_str(&format!(", {}", inter.link_type));
+            }
+
+            vec![
+                String::from(match selected {
+                    Some(ref name) if name == &inter.name => "*",
+                    _ => "",
+                }),
+                (i + 1).to_string(),
+                name,
+                inter.hardware_addr.to_string(),
+                ip_addrs,
+                status,
+                inter
+                    .mtu
+                    .map_or_else(|| String::from("-"), |mtu| mtu.to_string()),
+                String::from(match inter.is_openable() {
+                    true => "yes",
+                    false => "no",
+                }),
+            ]
+        })
+        .collect();
+
+    // Columns are as wide as their widest cells
+    let mut widths: Vec<usize> = header.iter().map(|cell| cell.len()).collect();
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = max(*width, cell.len());
+        }
+    }
+    let format_row = |row: Vec<&str>| {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+
+        format!("{}\n", cells.join("  ").trim_end())
+    };
+
+    let mut table = format_row(header.to_vec());
+    for row in rows.iter() {
+        table.push_str(&format_row(row.iter().map(|cell| cell.as_str()).collect()));
+    }
+
+    table
+}
+
 /// Gets a list of available network interfaces which is up for the current machine.
 fn up_interfaces() -> Vec<Interface> {
     interfaces()
@@ -91,33 +297,347 @@ pub fn interface(name: Option<String>) -> Option<Interface> {
     }
 }
 
+/// Get the IPv4 address of an `IpAddr`, or `None` of an IPv6 address. Only IPv4 traffic is
+/// redirected for now.
+fn ipv4_addr(ip_addr: IpAddr) -> Option<Ipv4Addr> {
+    match ip_addr {
+        IpAddr::V4(ip_addr) => Some(ip_addr),
+        IpAddr::V6(_) => None,
+    }
+}
+
+/// Get the string of the translated port of a connection in the NAT, like ` ⇄ nat:41002`.
+fn nat_string(nat_port: Option<u16>) -> String {
+    match nat_port {
+        Some(nat_port) => format!(" ⇄ nat:{}", nat_port),
+        None => String::new(),
+    }
+}
+
+/// Get the string of the destination a connection is rewritten to, like ` ⇒ 10.0.0.1:53`.
+fn rewrite_string(rewritten: Option<SocketAddrV4>) -> String {
+    match rewritten {
+        Some(rewritten) => format!(" ⇒ {}", rewritten),
+        None => String::new(),
+    }
+}
+
+/// Get the IP header and the first 8 bytes of the datagram of the packet, which are quoted in
+/// ICMPv4 error messages.
+fn quote_ipv4(indicator: &Indicator) -> io::Result<Vec<u8>> {
+    let size = match indicator.get_ipv4() {
+        Some(ipv4) => ipv4.get_size(),
+        None => 0,
+    };
+
+    let buffer = match indicator.to_vec() {
+        Ok(buffer) => buffer,
+        Err(ref e) => return Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+    };
+    let begin = indicator.get_link_size();
+    let end = min(begin + size + 8, buffer.len());
+
+    Ok(buffer[begin..end].to_vec())
+}
+
+/// Converts SACK blocks into sorted and merged ranges relative to the given sequence. Blocks which
+/// are before the sequence or beyond the given size are ignored.
+fn sack_ranges(sequence: u32, size: usize, sacks: &[(u32, u32)]) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    for &(left, right) in sacks {
+        let end = right.wrapping_sub(sequence) as usize;
+        if end == 0 || end > size {
+            continue;
+        }
+        let begin = left.wrapping_sub(sequence) as usize;
+        // The block is partially acknowledged
+        let begin = if begin > end { 0 } else { begin };
+        if begin < end {
+            ranges.push((begin, end));
+        }
+    }
+    ranges.sort();
+
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (begin, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if begin <= last.1 => last.1 = max(last.1, end),
+            _ => merged.push((begin, end)),
+        }
+    }
+
+    merged
+}
+
+/// Trims a segment to the receive window beginning at the next sequence expected (RFC 9293
+/// 3.10.7.4). Bytes before the next sequence are received already, and bytes beyond the right
+/// edge of the window are discarded, the source retransmits them. Returns the sequence and the
+/// range of the payload in the window, or `None` if no byte of the payload is in it.
+fn trim_to_window(
+    next: u32,
+    window: usize,
+    sequence: u32,
+    size: usize,
+) -> Option<(u32, Range<usize>)> {
+    let begin = match seq_lt(sequence, next) {
+        true => next.wrapping_sub(sequence) as usize,
+        false => 0,
+    };
+    if begin >= size {
+        return None;
+    }
+    let sequence = sequence.wrapping_add(begin as u32);
+    let offset = sequence.wrapping_sub(next) as usize;
+    if offset >= window {
+        return None;
+    }
+    let end = min(size, begin + (window - offset));
+
+    Some((sequence, begin..end))
+}
+
+/// Returns if the sequence `a` is before the sequence `b`. Sequences are compared in the
+/// modular space of `u32` (RFC 1982), so a sequence just after a wrap-around is after the
+/// sequences just before it.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Returns if the sequence `a` is before or the same as the sequence `b`.
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
 /// Represents the max distance of `u32` values between packets in an `u32` window.
-const MAX_U32_WINDOW_SIZE: usize = 256 * 1024;
+const MAX_U32_WINDOW_SIZE: usize = 4 * 1024 * 1024;
 
 /// Represents the wait time after a `TimedOut` `IoError`.
 const TIMEDOUT_WAIT: u64 = 20;
 
+/// Represents the size of the Ethernet header.
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+/// Represents the size of the IPv4 and TCP header without options.
+const IPV4_TCP_HEADER_SIZE: u16 = 40;
+/// Represents the size of the IPv4 and UDP header without options.
+const IPV4_UDP_HEADER_SIZE: usize = 28;
+
+/// Represents the window scale shift count advertised to the source.
+const TCP_WINDOW_SCALE: u8 = 4;
+/// Represents the max window scale shift count (RFC 7323).
+const MAX_TCP_WINDOW_SCALE: u8 = 14;
+/// Represents the size of TCP caches of a connection with window scaling.
+const SCALED_CACHE_SIZE: usize = (u16::MAX as usize + 1) << TCP_WINDOW_SCALE;
+/// Represents the max size of the sent TCP cache of a connection with window scaling, the cache
+/// grows with the window of the source.
+const SCALED_SENT_CACHE_SIZE: usize = MAX_U32_WINDOW_SIZE;
+/// Represents the max size of the receive window and the send buffer of a TCP connection.
+pub const MAX_TCP_BUFFER_SIZE: usize = MAX_U32_WINDOW_SIZE;
+/// Represents the default receive window of a TCP connection in segments.
+const DEFAULT_RECV_WINDOW_SEGMENTS: usize = 768;
+/// Represents the default send buffer of a TCP connection in segments.
+const DEFAULT_SEND_BUFFER_SEGMENTS: usize = 3072;
+/// Represents the default relay buffer of a TCP connection in segments.
+const DEFAULT_RELAY_BUFFER_SEGMENTS: usize = 48;
+/// Represents the max size of the relay buffer of a TCP connection.
+pub const MAX_RELAY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Get the default receive window, send buffer and relay buffer of a TCP connection in the MSS,
+/// which are fixed numbers of segments, so a 1500 Bytes MTU gets windows of about 1 MB and 4 MB
+/// and a 64 KB relay buffer.
+pub fn default_tcp_buffer_sizes(mss: usize) -> (usize, usize, usize) {
+    let mss = max(mss, 1);
+
+    (
+        min(mss * DEFAULT_RECV_WINDOW_SEGMENTS, MAX_TCP_BUFFER_SIZE),
+        min(mss * DEFAULT_SEND_BUFFER_SEGMENTS, MAX_TCP_BUFFER_SIZE),
+        min(mss * DEFAULT_RELAY_BUFFER_SEGMENTS, MAX_RELAY_BUFFER_SIZE),
+    )
+}
+
+/// Get the window scale shift count advertised for the receive window, which is the smallest
+/// shift count the window fits in.
+pub fn tcp_window_scale_of(window: usize) -> u8 {
+    let mut wscale = 0;
+    while wscale < MAX_TCP_WINDOW_SCALE && (u16::MAX as usize + 1) << wscale < window {
+        wscale += 1;
+    }
+
+    wscale
+}
+
+/// Represents the default minimum retransmission timeout in milliseconds (RFC 6298).
+pub const DEFAULT_MIN_RTO: u64 = 1000;
+/// Represents the default timeout of delayed TCP ACKs in milliseconds (RFC 1122).
+pub const DEFAULT_DELAYED_ACK: u64 = 40;
+
+/// Represents the max retransmissions of a TCP FIN before the connection is given up.
+const MAX_FIN_RETRANSMISSIONS: usize = 8;
+/// Represents the max interval of TCP zero window probes in milliseconds.
+const MAX_PERSIST_TIMEOUT: u128 = 60000;
+
+/// Represents the state of the FIN of a TCP connection sent to the source.
+#[derive(Clone, Copy, Debug)]
+enum TcpFinState {
+    /// The FIN will be sent after all the data in the cache is sent.
+    Pending,
+    /// The FIN is sent in the sequence at the instant, with the count of retransmissions.
+    Sent(u32, Instant, usize),
+    Acknowledged,
+}
+
+/// Represents the state of the ECN of a TCP connection negotiated with the source (RFC 3168).
+#[derive(Clone, Copy, Debug, Default)]
+struct TcpEcnState {
+    /// Represents if a CE mark is received and echoed by ECE until the source answers with CWR
+    is_ece: bool,
+    /// Represents if the congestion window is reduced and CWR is pending on the next data
+    is_cwr: bool,
+    /// Represents the sequence until which ECE from the source is ignored after a reduction
+    recover: Option<u32>,
+}
+
+/// Represents the max number of UDP flows with their DSCP kept.
+const MAX_UDP_DSCP_FLOWS: usize = 1024;
+/// Represents the max number of UDP flows with their DF bit kept.
+const MAX_UDP_DONT_FRAGMENT_FLOWS: usize = 1024;
+/// Represents the max number of UDP flows with their checks against the blocklist kept.
+const MAX_UDP_BLOCK_FLOWS: usize = 4096;
+
+/// Represents the max number of flows with their last ICMPv4 destination unreachable kept.
+const MAX_ICMPV4_UNREACHABLE_FLOWS: usize = 1024;
+/// Represents the min interval of ICMPv4 destination unreachable packets in a flow.
+const ICMPV4_UNREACHABLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Represents the max size of UDP payload sent immediately without batching.
+const SMALL_UDP_SIZE: usize = 256;
+/// Represents the interval of flushing the send queue in milliseconds.
+const FLUSH_INTERVAL: u64 = 1;
+
+/// Opens a thread flushing frames in the send queue after their max age, and sending frames
+/// delayed by the chaos when they are due.
+pub fn open_flusher(tx: Arc<Mutex<SendQueue>>) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(FLUSH_INTERVAL));
+
+        let mut tx_locked = tx.lock().unwrap();
+        if let Err(ref e) = tx_locked.release() {
+            warn!("handle {}: {}", "pcap", e);
+        }
+        if tx_locked.is_expired() {
+            if let Err(ref e) = tx_locked.flush() {
+                warn!("handle {}: {}", "pcap", e);
+            }
+        }
+    })
+}
+
 /// Represents the channel downstream traffic to the source in pcap.
 pub struct Downstreamer {
-    tx: Sender,
+    tx: Arc<Mutex<SendQueue>>,
     mtu: u16,
+    mss: Option<u16>,
     src_hardware_addr: HardwareAddr,
+    /// Represents the VLAN tag of the source, which is applied to all the frames sent
+    src_vlan: Option<Vlan>,
     local_hardware_addr: HardwareAddr,
     src_ip_addr: Ipv4Addr,
     local_ip_addr: Ipv4Addr,
-    ipv4_identification_map: HashMap<Ipv4Addr, u16>,
-    tcp_send_window_map: HashMap<(u16, SocketAddrV4), u16>,
+    /// Represents the next IPv4 identifications of packets by their sources and protocols
+    ipv4_identification_map: HashMap<(Ipv4Addr, LayerType), u16>,
+    tcp_send_window_map: HashMap<(u16, SocketAddrV4), usize>,
     tcp_sequence_map: HashMap<(u16, SocketAddrV4), u32>,
+    /// Represents the initial sequence of TCP connections, from which the TCP SYN is resent
+    tcp_initial_sequence_map: HashMap<(u16, SocketAddrV4), u32>,
     tcp_acknowledgement_map: HashMap<(u16, SocketAddrV4), u32>,
-    tcp_window_map: HashMap<(u16, SocketAddrV4), u16>,
+    tcp_window_map: HashMap<(u16, SocketAddrV4), usize>,
     tcp_cache_map: HashMap<(u16, SocketAddrV4), Cacher>,
     tcp_cache2_map: HashMap<(u16, SocketAddrV4), Cacher>,
+    tcp_mss_map: HashMap<(u16, SocketAddrV4), u16>,
+    /// Represents the tags of TCP connections prefixed to log lines
+    tcp_tag_map: HashMap<(u16, SocketAddrV4), Tag>,
+    /// Represents the connections of TCP connections, which count the bytes sent to the source
+    tcp_connection_map: HashMap<(u16, SocketAddrV4), Arc<Mutex<Connection>>>,
+    tcp_wscale_map: HashMap<(u16, SocketAddrV4), u8>,
+    tcp_sack_perm_set: HashSet<(u16, SocketAddrV4)>,
+    tcp_sack_map: HashMap<(u16, SocketAddrV4), Vec<(u32, u32)>>,
+    tcp_recv_sack_map: HashMap<(u16, SocketAddrV4), Vec<(u32, u32)>>,
+    /// Represents if small segments are held while data is in flight (RFC 896)
+    is_nagle: bool,
+    delayed_ack: Duration,
+    /// Represents the tracer of frames sent, which are never formatted if it is not set
+    tracer: Option<Arc<Tracer>>,
+    /// Represents the mode of verifying frames sent, which are never verified if it is not set
+    verify_tx: Option<VerifyMode>,
+    /// Represents the hook of packets sent, which are never parsed again if it is not set
+    hook: Option<Arc<dyn Hook>>,
+    /// Represents the map mapping a TCP connection to the instant of the first segment not
+    /// acknowledged yet
+    tcp_delayed_ack_map: HashMap<(u16, SocketAddrV4), Instant>,
+    /// Represents the receive window advertised to the source in TCP connections with window
+    /// scaling, and the window scale shift count advertised for it
+    tcp_recv_window: usize,
+    tcp_window_scale: u8,
+    /// Represents the max size of data not acknowledged by the source in TCP connections with
+    /// window scaling
+    tcp_send_buffer: usize,
+    /// Represents the size of reads from proxies in TCP connections
+    relay_buffer: usize,
+    min_rto: Duration,
+    /// Represents the clock of timers and timestamps
+    clock: Arc<dyn Clock>,
+    timestamp_epoch: Instant,
+    /// Represents the secret key of the initial sequences of TCP connections
+    isn_key: RandomState,
+    tcp_ts_recent_map: HashMap<(u16, SocketAddrV4), u32>,
+    tcp_timing_map: HashMap<(u16, SocketAddrV4), (u32, Instant)>,
+    tcp_rtt_map: HashMap<(u16, SocketAddrV4), RttEstimator>,
+    tcp_duplicate_map: HashMap<(u16, SocketAddrV4), usize>,
+    tcp_recovery_map: HashMap<(u16, SocketAddrV4), u32>,
+    tcp_last_retransmission_map: HashMap<(u16, SocketAddrV4), Instant>,
+    tcp_retransmission_count_map: HashMap<(u16, SocketAddrV4), usize>,
+    cc: Algorithm,
+    tcp_cc_map: HashMap<(u16, SocketAddrV4), Box<dyn CongestionControl>>,
+    tcp_fin_map: HashMap<(u16, SocketAddrV4), TcpFinState>,
+    tcp_persist_map: HashMap<(u16, SocketAddrV4), (Instant, u32)>,
+    /// Represents the map mapping a TCP connection to the right edge of the send window after the
+    /// source shrinks the window below the data in flight
+    tcp_shrink_map: HashMap<(u16, SocketAddrV4), u32>,
+    tcp_ecn_map: HashMap<(u16, SocketAddrV4), TcpEcnState>,
+    /// Represents the DSCP of the source in TCP connections and recent UDP flows, which is
+    /// applied to all the packets sent in the flow
+    tcp_dscp_map: HashMap<(u16, SocketAddrV4), u8>,
+    udp_dscp_map: LruCache<(u16, SocketAddrV4), u8>,
+    /// Represents the instant of the last ICMPv4 destination unreachable in recent flows, which
+    /// limits the rate of them against amplification
+    icmpv4_unreachable_map: LruCache<(u16, SocketAddrV4), Instant>,
+    /// Represents the TCP connections and recent UDP flows whose source sets the DF bit, which is
+    /// propagated to all the packets sent in the flow, and the count of packets dropped in the
+    /// flow because they cannot be fragmented
+    tcp_dont_fragment_map: HashMap<(u16, SocketAddrV4), usize>,
+    udp_dont_fragment_map: LruCache<(u16, SocketAddrV4), usize>,
+    /// Represents the count of IPv4 packets dropped because they cannot be fragmented
+    ipv4_dont_fragment_drop_count: usize,
+    /// Represents the bytes of all the frames transmitted, from which the frames of a packet are
+    /// measured
+    wire_size: u64,
+    buffer_pool: BufferPool,
 }
 
 impl Downstreamer {
+    /// Get the MTU of the device.
+    pub fn get_mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// Get the congestion of the send queue, streams stop reading while it is congested.
+    pub fn get_congestion(&self) -> Arc<Congestion> {
+        self.tx.lock().unwrap().get_congestion()
+    }
+
     /// Creates a new `Downstreamer`.
     pub fn new(
-        tx: Sender,
+        tx: Arc<Mutex<SendQueue>>,
         mtu: u16,
         local_hardware_addr: HardwareAddr,
         src_ip_addr: Ipv4Addr,
@@ -126,17 +646,62 @@ impl Downstreamer {
         Downstreamer {
             tx,
             mtu,
+            mss: None,
             src_hardware_addr: pcap::HARDWARE_ADDR_UNSPECIFIED,
+            src_vlan: None,
             local_hardware_addr,
             src_ip_addr,
             local_ip_addr,
             ipv4_identification_map: HashMap::new(),
             tcp_send_window_map: HashMap::new(),
             tcp_sequence_map: HashMap::new(),
+            tcp_initial_sequence_map: HashMap::new(),
             tcp_acknowledgement_map: HashMap::new(),
             tcp_window_map: HashMap::new(),
             tcp_cache_map: HashMap::new(),
             tcp_cache2_map: HashMap::new(),
+            tcp_mss_map: HashMap::new(),
+            tcp_tag_map: HashMap::new(),
+            tcp_connection_map: HashMap::new(),
+            tcp_wscale_map: HashMap::new(),
+            tcp_sack_perm_set: HashSet::new(),
+            tcp_sack_map: HashMap::new(),
+            tcp_recv_sack_map: HashMap::new(),
+            is_nagle: true,
+            delayed_ack: Duration::from_millis(DEFAULT_DELAYED_ACK),
+            tracer: None,
+            verify_tx: None,
+            hook: None,
+            tcp_delayed_ack_map: HashMap::new(),
+            tcp_recv_window: SCALED_CACHE_SIZE,
+            tcp_window_scale: TCP_WINDOW_SCALE,
+            tcp_send_buffer: SCALED_SENT_CACHE_SIZE,
+            relay_buffer: u16::MAX as usize,
+            min_rto: Duration::from_millis(DEFAULT_MIN_RTO),
+            clock: Arc::new(MonotonicClock::new()),
+            timestamp_epoch: Instant::now(),
+            isn_key: RandomState::new(),
+            tcp_ts_recent_map: HashMap::new(),
+            tcp_timing_map: HashMap::new(),
+            tcp_rtt_map: HashMap::new(),
+            tcp_duplicate_map: HashMap::new(),
+            tcp_recovery_map: HashMap::new(),
+            tcp_last_retransmission_map: HashMap::new(),
+            tcp_retransmission_count_map: HashMap::new(),
+            cc: Algorithm::Cubic,
+            tcp_cc_map: HashMap::new(),
+            tcp_fin_map: HashMap::new(),
+            tcp_persist_map: HashMap::new(),
+            tcp_shrink_map: HashMap::new(),
+            tcp_ecn_map: HashMap::new(),
+            tcp_dscp_map: HashMap::new(),
+            udp_dscp_map: LruCache::new(MAX_UDP_DSCP_FLOWS),
+            icmpv4_unreachable_map: LruCache::new(MAX_ICMPV4_UNREACHABLE_FLOWS),
+            tcp_dont_fragment_map: HashMap::new(),
+            udp_dont_fragment_map: LruCache::new(MAX_UDP_DONT_FRAGMENT_FLOWS),
+            ipv4_dont_fragment_drop_count: 0,
+            wire_size: 0,
+            buffer_pool: BufferPool::new(ETHERNET_HEADER_SIZE + VLAN_TAG_SIZE + mtu as usize),
         }
     }
 
@@ -146,21 +711,171 @@ impl Downstreamer {
         trace!("set source hardware address to {}", hardware_addr);
     }
 
+    /// Sets the VLAN tag of the source.
+    pub fn set_src_vlan(&mut self, vlan: Option<Vlan>) {
+        if let Some(ref vlan) = vlan {
+            trace!("set source VLAN to {}", vlan.get_vid());
+        }
+        self.src_vlan = vlan;
+    }
+
     /// Sets the local IP address.
     pub fn set_local_ip_addr(&mut self, ip_addr: Ipv4Addr) {
         self.local_ip_addr = ip_addr;
         trace!("set local IP address to {}", ip_addr);
     }
 
-    fn increase_ipv4_identification(&mut self, ip_addr: Ipv4Addr) {
-        let entry = self.ipv4_identification_map.entry(ip_addr).or_insert(0);
-        *entry = entry.checked_add(1).unwrap_or(0);
-        trace!("increase IPv4 identification of {} to {}", ip_addr, entry);
+    /// Sets the MSS for clamping.
+    pub fn set_mss(&mut self, mss: u16) {
+        self.mss = Some(mss);
+        trace!("set MSS to {}", mss);
+    }
+
+    /// Sets the algorithm of the congestion control.
+    pub fn set_cc(&mut self, cc: Algorithm) {
+        self.cc = cc;
+        trace!("set congestion control to {}", cc);
+    }
+
+    /// Sets the minimum retransmission timeout.
+    pub fn set_min_rto(&mut self, min_rto: Duration) {
+        self.min_rto = min_rto;
+        trace!("set minimum RTO to {} ms", min_rto.as_millis());
+    }
+
+    /// Sets if small segments are held while data is in flight, or they will be sent
+    /// immediately.
+    pub fn set_nagle(&mut self, is_nagle: bool) {
+        self.is_nagle = is_nagle;
+        trace!("set Nagle to {}", is_nagle);
+    }
+
+    /// Sets the timeout of delayed TCP ACKs, every segment is acknowledged immediately if the
+    /// timeout is 0.
+    pub fn set_delayed_ack(&mut self, timeout: Duration) {
+        self.delayed_ack = timeout;
+        trace!("set delayed ACK to {} ms", timeout.as_millis());
+    }
+
+    /// Sets the receive window of TCP connections, the window scale shift count advertised is
+    /// the smallest one the window fits in.
+    pub fn set_tcp_recv_window(&mut self, window: usize) {
+        self.tcp_recv_window = window;
+        self.tcp_window_scale = tcp_window_scale_of(window);
+        trace!(
+            "set TCP receive window to {} (window scale {})",
+            window,
+            self.tcp_window_scale
+        );
+    }
+
+    /// Sets the send buffer of TCP connections.
+    pub fn set_tcp_send_buffer(&mut self, size: usize) {
+        self.tcp_send_buffer = size;
+        trace!("set TCP send buffer to {}", size);
+    }
+
+    /// Sets the relay buffer of TCP connections.
+    pub fn set_relay_buffer(&mut self, size: usize) {
+        self.relay_buffer = size;
+        trace!("set relay buffer to {}", size);
+    }
+
+    /// Get the relay buffer of TCP connections.
+    pub fn get_relay_buffer(&self) -> usize {
+        self.relay_buffer
+    }
+
+    /// Get the capacity of the receive cache of a TCP connection, which is the receive window if
+    /// the window scaling is enabled, or no more than 64 KB. The capacity shrinks under the
+    /// memory pressure.
+    pub fn get_tcp_recv_capacity(&self, dst: SocketAddrV4, src_port: u16) -> usize {
+        let capacity = match self.tcp_wscale_map.contains_key(&(src_port, dst)) {
+            true => self.tcp_recv_window,
+            false => min(self.tcp_recv_window, u16::MAX as usize + 1),
+        };
+
+        memory::scale(capacity)
+    }
+
+    /// Sets the tracer of frames sent.
+    pub fn set_tracer(&mut self, tracer: Arc<Tracer>) {
+        self.tracer = Some(tracer);
+        trace!("set tracer of {}", self.src_ip_addr);
+    }
+
+    /// Sets the mode of verifying frames sent before they are transmitted.
+    pub fn set_verify_tx(&mut self, mode: VerifyMode) {
+        self.verify_tx = Some(mode);
+        trace!("set TX verification to {}", mode);
+    }
+
+    /// Sets the hook of packets sent.
+    pub fn set_hook(&mut self, hook: Arc<dyn Hook>) {
+        self.hook = Some(hook);
+        trace!("set hook of {}", self.src_ip_addr);
+    }
+
+    /// Sets the clock of timers, which is expected to be set before any connection is opened,
+    /// for TCP timestamps restart from the clock.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.timestamp_epoch = clock.now();
+        self.clock = clock;
+        trace!("set clock of {}", self.src_ip_addr);
+    }
+
+    /// Get the IPv4 identification of the next packet from the address in the protocol. The
+    /// identifications of an address and a protocol start from a secret random value, so they do
+    /// not repeat the ones of another source or sent before a restart in the reassembly window
+    /// (RFC 6864). Fragments of a packet keep its identification.
+    fn next_ipv4_identification(&mut self, ip_addr: Ipv4Addr, t: LayerType) -> u16 {
+        let isn_key = &self.isn_key;
+        let src_ip_addr = self.src_ip_addr;
+        let entry = self
+            .ipv4_identification_map
+            .entry((ip_addr, t))
+            .or_insert_with(|| {
+                let mut hasher = isn_key.build_hasher();
+                (ip_addr, src_ip_addr, t).hash(&mut hasher);
+                hasher.finish() as u16
+            });
+        let identification = *entry;
+        *entry = entry.wrapping_add(1);
+        trace!(
+            "increase IPv4 identification of {} in {} to {}",
+            ip_addr,
+            t,
+            entry
+        );
+
+        identification
     }
 
-    /// Sets the send window size of a TCP connection. This window
-    pub fn set_tcp_send_window(&mut self, dst: SocketAddrV4, src_port: u16, window: u16) {
-        self.tcp_send_window_map.insert((src_port, dst), window);
+    /// Sets the send window size of a TCP connection. The window should have been scaled if the
+    /// window scaling is enabled.
+    pub fn set_tcp_send_window(&mut self, dst: SocketAddrV4, src_port: u16, window: usize) {
+        let key = (src_port, dst);
+
+        self.tcp_send_window_map.insert(key, window);
+        // The persist timer is started by a zero window, and stopped by any window update
+        if window == 0 {
+            if let Entry::Vacant(entry) = self.tcp_persist_map.entry(key) {
+                entry.insert((self.clock.now(), 0));
+                trace!(
+                    "{}enter TCP zero window of {} -> {}",
+                    self.get_tcp_tag(dst, src_port),
+                    dst,
+                    src_port
+                );
+            }
+        } else if self.tcp_persist_map.remove(&key).is_some() {
+            trace!(
+                "{}exit TCP zero window of {} -> {}",
+                self.get_tcp_tag(dst, src_port),
+                dst,
+                src_port
+            );
+        }
         trace!(
             "set TCP send window of {} -> {} to {}",
             src_port,
@@ -169,6 +884,73 @@ impl Downstreamer {
         );
     }
 
+    /// Updates the right edge of the send window of a TCP connection after the window is set. If
+    /// the source shrinks the window below the data in flight, including a zero window, the data
+    /// beyond the right edge is kept in the cache but never retransmitted or timed, and it is
+    /// retransmitted as the window grows again (RFC 1122).
+    pub fn update_tcp_shrink(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        let (sequence, size) = match self.tcp_cache_map.get(&key) {
+            Some(cache) => (cache.get_sequence(), cache.get_size()),
+            None => (0, 0),
+        };
+        let window = *self.tcp_send_window_map.get(&key).unwrap_or(&0);
+        let edge = sequence.wrapping_add(min(window, size) as u32);
+
+        // The window grows again, data between the last right edge and the new one is
+        // retransmitted, as the source may have dropped it
+        if let Some(last_edge) = self.tcp_shrink_map.get(&key).cloned() {
+            let begin = match seq_lt(sequence, last_edge) {
+                true => last_edge.wrapping_sub(sequence) as usize,
+                false => 0,
+            };
+            let end = min(window, size);
+            if begin < end {
+                trace!(
+                    "{}retransmit TCP of {} -> {} from {} to {} beyond the shrunk window",
+                    self.get_tcp_tag(dst, src_port),
+                    dst,
+                    src_port,
+                    sequence.wrapping_add(begin as u32),
+                    edge
+                );
+                self.count_tcp_retransmission(dst, src_port);
+                for (begin, end) in self.get_tcp_holes(dst, src_port, begin, end) {
+                    self.send_tcp_ack_from_cache(dst, src_port, begin, end)?;
+                }
+            }
+        }
+
+        if window < size {
+            if self.tcp_shrink_map.insert(key, edge).is_none() {
+                trace!(
+                    "{}shrink TCP send window of {} -> {} to {} ({} Bytes in flight)",
+                    self.get_tcp_tag(dst, src_port),
+                    dst,
+                    src_port,
+                    window,
+                    size
+                );
+            }
+            // A segment beyond the right edge is never timed
+            if let Some(&(sequence_tail, _)) = self.tcp_timing_map.get(&key) {
+                if seq_lt(edge, sequence_tail) {
+                    self.tcp_timing_map.remove(&key);
+                }
+            }
+        } else if self.tcp_shrink_map.remove(&key).is_some() {
+            trace!(
+                "{}exit TCP shrunk window of {} -> {}",
+                self.get_tcp_tag(dst, src_port),
+                dst,
+                src_port
+            );
+        }
+
+        Ok(())
+    }
+
     /// Sets the sequence of a TCP connection. In fact, this function should never be used.
     #[deprecated(note = "this function should never be used")]
     pub fn set_tcp_sequence(&mut self, dst: SocketAddrV4, src_port: u16, acknowledgement: u32) {
@@ -194,6 +976,29 @@ impl Downstreamer {
         );
     }
 
+    /// Picks up a TCP connection mid-stream without a handshake. The sequence is the next one
+    /// the source expects, and the acknowledgement is the next one expected from the source.
+    pub fn pick_up_tcp(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        sequence: u32,
+        acknowledgement: u32,
+    ) {
+        let key = (src_port, dst);
+        self.tcp_sequence_map.insert(key, sequence);
+        self.tcp_initial_sequence_map
+            .insert(key, sequence.wrapping_sub(1));
+        self.tcp_acknowledgement_map.insert(key, acknowledgement);
+        trace!(
+            "pick up TCP {} -> {} at sequence {} and acknowledgement {}",
+            dst,
+            src_port,
+            sequence,
+            acknowledgement
+        );
+    }
+
     /// Adds acknowledgement to a TCP connection.
     pub fn add_tcp_acknowledgement(&mut self, dst: SocketAddrV4, src_port: u16, n: u32) {
         let entry = self
@@ -211,1459 +1016,10107 @@ impl Downstreamer {
         );
     }
 
-    /// Sets the window size of a TCP connection.
-    pub fn set_tcp_window(&mut self, dst: SocketAddrV4, src_port: u16, window: u16) {
-        self.tcp_window_map.insert((src_port, dst), window);
-        trace!("set TCP window of {} -> {} to {}", dst, src_port, window);
+    /// Sets the MSS advertised by the source of a TCP connection.
+    pub fn set_tcp_mss(&mut self, dst: SocketAddrV4, src_port: u16, mss: u16) {
+        self.tcp_mss_map.insert((src_port, dst), mss);
+        trace!("set TCP MSS of {} -> {} to {}", dst, src_port, mss);
     }
 
-    /// Invalidates TCP cache to the given sequence.
-    pub fn invalidate_cache_to(&mut self, dst: SocketAddrV4, src_port: u16, sequence: u32) {
-        if let Some(cache) = self.tcp_cache_map.get_mut(&(src_port, dst)) {
-            cache.invalidate_to(sequence);
+    /// Sets the tag of a TCP connection prefixed to log lines.
+    pub fn set_tcp_tag(&mut self, dst: SocketAddrV4, src_port: u16, tag: Tag) {
+        self.tcp_tag_map.insert((src_port, dst), tag);
+        trace!("{}set TCP tag of {} -> {}", tag, dst, src_port);
+    }
+
+    /// Sets the connection of a TCP connection, which counts the bytes sent to the source.
+    pub fn set_tcp_connection(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        connection: Arc<Mutex<Connection>>,
+    ) {
+        self.tcp_connection_map.insert((src_port, dst), connection);
+    }
+
+    /// Get the tag of a TCP connection prefixed to log lines.
+    fn get_tcp_tag(&self, dst: SocketAddrV4, src_port: u16) -> Tag {
+        self.tcp_tag_map
+            .get(&(src_port, dst))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Get the MSS of a TCP connection, which is the minimum of the MSS advertised by the source,
+    /// the MSS allowed by the MTU and the MSS for clamping.
+    fn get_tcp_mss(&self, dst: SocketAddrV4, src_port: u16) -> u16 {
+        let mut mss = self.mtu - IPV4_TCP_HEADER_SIZE;
+        if let Some(src_mss) = self.tcp_mss_map.get(&(src_port, dst)) {
+            mss = min(mss, *src_mss);
+        }
+        if let Some(clamp_mss) = self.mss {
+            mss = min(mss, clamp_mss);
         }
+
+        mss
+    }
+
+    /// Sets the window scale shift count advertised by the source of a TCP connection, which
+    /// enables the window scaling of the connection.
+    pub fn set_tcp_wscale(&mut self, dst: SocketAddrV4, src_port: u16, wscale: u8) {
+        let wscale = min(wscale, MAX_TCP_WINDOW_SCALE);
+        self.tcp_wscale_map.insert((src_port, dst), wscale);
         trace!(
-            "invalidate cache {} -> {} to sequence {}",
+            "set TCP window scale of {} -> {} to {}",
             dst,
             src_port,
-            sequence
+            wscale
         );
     }
 
-    /// Removes all information related to a TCP connection.
-    pub fn remove(&mut self, dst: SocketAddrV4, src_port: u16) {
-        let key = (src_port, dst);
+    /// Get the window scale shift count advertised by the source of a TCP connection. Returns
+    /// `None` if the window scaling is not enabled.
+    pub fn get_tcp_wscale(&self, dst: SocketAddrV4, src_port: u16) -> Option<u8> {
+        self.tcp_wscale_map.get(&(src_port, dst)).cloned()
+    }
 
-        self.tcp_sequence_map.remove(&key);
-        self.tcp_acknowledgement_map.remove(&key);
-        self.tcp_window_map.remove(&key);
-        self.tcp_cache_map.remove(&key);
-        trace!("remove {} -> {}", dst, src_port);
+    /// Enables the SACK of a TCP connection, which is permitted by the source.
+    pub fn set_tcp_sack_perm(&mut self, dst: SocketAddrV4, src_port: u16) {
+        self.tcp_sack_perm_set.insert((src_port, dst));
+        trace!("set TCP SACK permitted of {} -> {}", dst, src_port);
     }
 
-    /// Get the size of the cache of a TCP connection.
-    pub fn get_cache_size(&mut self, dst: SocketAddrV4, src_port: u16) -> usize {
+    /// Sets the DSCP of a TCP connection, which is applied to all the packets sent to the source.
+    /// Returns if the DSCP is changed.
+    pub fn set_tcp_dscp(&mut self, dst: SocketAddrV4, src_port: u16, dscp: u8) -> bool {
         let key = (src_port, dst);
-
-        let mut size = 0;
-        if let Some(cache) = self.tcp_cache_map.get(&key) {
-            size += cache.get_size();
-        }
-        if let Some(cache) = self.tcp_cache2_map.get(&key) {
-            size += cache.get_size();
+        let prev_dscp = match dscp {
+            0 => self.tcp_dscp_map.remove(&key),
+            _ => self.tcp_dscp_map.insert(key, dscp),
+        };
+        let is_changed = prev_dscp.unwrap_or(0) != dscp;
+        if is_changed {
+            trace!("set TCP DSCP of {} -> {} to {}", dst, src_port, dscp);
         }
 
-        size
+        is_changed
     }
 
-    /// Sends an ARP reply packet.
-    pub fn send_arp_reply(&mut self) -> io::Result<()> {
-        // ARP
-        let arp = Arp::new_reply(
-            self.local_hardware_addr,
-            self.local_ip_addr,
-            self.src_hardware_addr,
-            self.src_ip_addr,
-        );
+    /// Sets the DSCP of a UDP flow, which is applied to the datagrams sent to the source. Only
+    /// the DSCP of recent flows is kept.
+    pub fn set_udp_dscp(&mut self, dst: SocketAddrV4, src_port: u16, dscp: u8) {
+        let key = (src_port, dst);
+        if dscp == 0 {
+            self.udp_dscp_map.pop(&key);
+            return;
+        }
+        self.udp_dscp_map.put(key, dscp);
+    }
 
-        // Ethernet
-        let ethernet = Ethernet::new(
-            arp.get_type(),
-            arp.get_src_hardware_addr(),
-            arp.get_dst_hardware_addr(),
-        )
-        .unwrap();
+    /// Sets if the source of a TCP connection sets the DF bit, which is propagated to all the
+    /// packets sent to the source, so they are dropped instead of fragmented.
+    pub fn set_tcp_dont_fragment(&mut self, dst: SocketAddrV4, src_port: u16, is_df: bool) {
+        let key = (src_port, dst);
+        match is_df {
+            true => {
+                self.tcp_dont_fragment_map.entry(key).or_insert(0);
+            }
+            false => {
+                self.tcp_dont_fragment_map.remove(&key);
+            }
+        }
+    }
 
-        // Indicator
-        let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(Layers::Arp(arp)), None);
+    /// Sets if the source of a UDP flow sets the DF bit, which is propagated to the datagrams
+    /// sent to the source, so they are dropped instead of fragmented. Only the DF bit of recent
+    /// flows is kept.
+    pub fn set_udp_dont_fragment(&mut self, dst: SocketAddrV4, src_port: u16, is_df: bool) {
+        let key = (src_port, dst);
+        if !is_df {
+            self.udp_dont_fragment_map.pop(&key);
+            return;
+        }
+        if self.udp_dont_fragment_map.get(&key).is_none() {
+            self.udp_dont_fragment_map.put(key, 0);
+        }
+    }
 
-        // Send
-        self.send(&indicator)
+    /// Counts a packet of the flow dropped because it cannot be fragmented.
+    pub fn add_dont_fragment_drop(&mut self, dst: SocketAddrV4, src_port: u16) {
+        let key = (src_port, dst);
+        if let Some(count) = self.tcp_dont_fragment_map.get_mut(&key) {
+            *count += 1;
+        } else if let Some(count) = self.udp_dont_fragment_map.get_mut(&key) {
+            *count += 1;
+        }
+        self.ipv4_dont_fragment_drop_count += 1;
+        metrics::IPV4_DONT_FRAGMENT_DROPS.increase();
     }
 
-    /// Appends TCP ACK payload to cache.
-    pub fn append_to_cache(
-        &mut self,
-        dst: SocketAddrV4,
-        src_port: u16,
-        payload: &[u8],
-    ) -> io::Result<()> {
+    /// Get the count of packets of the flow dropped because they cannot be fragmented.
+    pub fn get_dont_fragment_drops(&self, dst: SocketAddrV4, src_port: u16) -> usize {
         let key = (src_port, dst);
+        match self.tcp_dont_fragment_map.get(&key) {
+            Some(&count) => count,
+            None => self.udp_dont_fragment_map.peek(&key).cloned().unwrap_or(0),
+        }
+    }
 
-        // TCP sequence
-        let sequence = *self.tcp_sequence_map.get(&key).unwrap_or(&0);
+    /// Enables the ECN of a TCP connection, which is requested by the source in the SYN.
+    pub fn set_tcp_ecn(&mut self, dst: SocketAddrV4, src_port: u16) {
+        self.tcp_ecn_map
+            .insert((src_port, dst), TcpEcnState::default());
+        trace!("set TCP ECN of {} -> {}", dst, src_port);
+    }
 
-        // Append to cache
-        let cache = self
-            .tcp_cache2_map
-            .entry(key)
-            .or_insert_with(|| Cacher::new_expandable(sequence));
-        cache.append(payload)?;
+    /// Updates the ECN of a TCP connection with a segment from the source (RFC 3168). A CE mark
+    /// is echoed by ECE until the source answers with CWR, and an ECE from the source reduces the
+    /// congestion window at most once per window of data.
+    pub fn update_tcp_ecn(&mut self, dst: SocketAddrV4, src_port: u16, ecn: u8, tcp: &Tcp) {
+        let key = (src_port, dst);
 
-        self.send_tcp_ack(dst, src_port)
+        let (sequence, size) = match self.tcp_cache_map.get(&key) {
+            Some(cache) => (cache.get_sequence(), cache.get_size()),
+            None => (0, 0),
+        };
+        let state = match self.tcp_ecn_map.get_mut(&key) {
+            Some(state) => state,
+            None => return,
+        };
+        if tcp.is_cwr() {
+            state.is_ece = false;
+        }
+        if ecn == ECN_CE {
+            if !state.is_ece {
+                trace!("receive TCP CE of {} -> {}", dst, src_port);
+            }
+            state.is_ece = true;
+        }
+        if !tcp.is_ece() || size == 0 {
+            return;
+        }
+        if let Some(recover) = state.recover {
+            if seq_lt(tcp.get_acknowledgement(), recover) {
+                return;
+            }
+        }
+        state.recover = Some(sequence.wrapping_add(size as u32));
+        state.is_cwr = true;
+
+        let cc = self.get_tcp_cc(dst, src_port);
+        cc.on_loss(size);
+        let cwnd = cc.cwnd();
+        trace!(
+            "{}reduce TCP congestion window of {} -> {} for ECE (congestion window {})",
+            self.get_tcp_tag(dst, src_port),
+            dst,
+            src_port,
+            cwnd
+        );
     }
 
-    /// Resends TCP ACK packets from first (sent) cache.
-    pub fn resend_tcp_ack(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+    /// Updates the SACK blocks reported by the source of a TCP connection. Blocks which are
+    /// acknowledged or beyond the sent data are ignored. The sacked data is tentative and is kept
+    /// in the cache until it is acknowledged, because the source may renege.
+    pub fn update_tcp_sacks(&mut self, dst: SocketAddrV4, src_port: u16, sacks: &[(u32, u32)]) {
         let key = (src_port, dst);
+        if !self.tcp_sack_perm_set.contains(&key) {
+            return;
+        }
 
-        // Resend
-        let payload;
-        let sequence;
-        match self.tcp_cache_map.get(&key) {
-            Some(cache) => {
-                match cache.get_all() {
-                    Ok(buffer) => {
-                        payload = buffer;
-                    }
-                    Err(e) => return Err(e),
-                }
-                sequence = cache.get_sequence();
-            }
-            None => return Ok(()),
+        // The source reneges if it does not report any SACK block
+        let mut blocks = match self.tcp_sack_map.remove(&key) {
+            Some(blocks) if !sacks.is_empty() => blocks,
+            _ => Vec::new(),
         };
+        blocks.extend_from_slice(sacks);
 
-        if payload.len() > 0 {
-            return self.send_tcp_ack_raw(dst, src_port, sequence, payload.as_slice());
+        if let Some(cache) = self.tcp_cache_map.get(&key) {
+            let sequence = cache.get_sequence();
+            let ranges = sack_ranges(sequence, cache.get_size(), &blocks);
+            if !ranges.is_empty() {
+                let blocks: Vec<_> = ranges
+                    .iter()
+                    .map(|(begin, end)| {
+                        (
+                            sequence.wrapping_add(*begin as u32),
+                            sequence.wrapping_add(*end as u32),
+                        )
+                    })
+                    .collect();
+                trace!("update TCP SACK of {} -> {} to {:?}", dst, src_port, blocks);
+                self.tcp_sack_map.insert(key, blocks);
+            }
         }
+    }
 
-        Ok(())
+    /// Sets the SACK blocks reported to the source of a TCP connection.
+    pub fn set_tcp_recv_sacks(&mut self, dst: SocketAddrV4, src_port: u16, sacks: Vec<(u32, u32)>) {
+        self.tcp_recv_sack_map.insert((src_port, dst), sacks);
     }
 
-    /// Sends TCP ACK packets from second (unsent) cache.
-    pub fn send_tcp_ack(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+    /// Enables the timestamps of a TCP connection with the timestamp value of the source.
+    pub fn set_tcp_timestamps(&mut self, dst: SocketAddrV4, src_port: u16, tsval: u32) {
+        self.tcp_ts_recent_map.insert((src_port, dst), tsval);
+        trace!("set TCP timestamps of {} -> {} to {}", dst, src_port, tsval);
+    }
+
+    /// Get the timestamp value of the local clock, which ticks every millisecond.
+    fn get_timestamp(&self) -> u32 {
+        self.clock.elapsed(self.timestamp_epoch).as_millis() as u32
+    }
+
+    /// Get the initial sequence of a TCP connection to the source (RFC 6528), which is a keyed
+    /// hash of the connection plus a timer ticking every 4 microseconds, so sequences are
+    /// unpredictable and never reused by an incarnation of the connection soon.
+    fn get_tcp_initial_sequence(&self, dst: SocketAddrV4, src_port: u16) -> u32 {
+        let mut hasher = self.isn_key.build_hasher();
+        (self.src_ip_addr, src_port, dst).hash(&mut hasher);
+        let timer = (self.clock.elapsed(self.timestamp_epoch).as_micros() / 4) as u32;
+
+        timer.wrapping_add(hasher.finish() as u32)
+    }
+
+    /// Updates the RTT of a TCP connection with an acknowledgement from the source. The RTT is
+    /// measured by the timestamps if they are enabled, or by timing one segment otherwise.
+    pub fn update_tcp_rtt(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        acknowledgement: u32,
+        timestamps: Option<(u32, u32)>,
+    ) {
         let key = (src_port, dst);
 
-        if let None = self.tcp_cache2_map.get(&key) {
-            return Ok(());
+        // Only an acknowledgement of new data is taken into measurement
+        let is_new = match self.tcp_cache_map.get(&key) {
+            Some(cache) => {
+                let size = acknowledgement.wrapping_sub(cache.get_sequence()) as usize;
+                size > 0 && size <= cache.get_size()
+            }
+            None => false,
+        };
+
+        let mut rtt = None;
+        match (self.tcp_ts_recent_map.get(&key).cloned(), timestamps) {
+            (Some(ts_recent), Some((tsval, tsecr))) => {
+                // Update the recent timestamp
+                if seq_le(ts_recent, tsval) {
+                    self.tcp_ts_recent_map.insert(key, tsval);
+                }
+                if is_new && tsecr != 0 {
+                    let elapsed = self.get_timestamp().wrapping_sub(tsecr);
+                    rtt = Some(Duration::from_millis(elapsed as u64));
+                }
+            }
+            _ => {
+                if is_new {
+                    if let Some((sequence, instant)) = self.tcp_timing_map.get(&key).cloned() {
+                        if seq_le(sequence, acknowledgement) {
+                            self.tcp_timing_map.remove(&key);
+                            rtt = Some(self.clock.elapsed(instant));
+                        }
+                    }
+                }
+            }
         }
 
-        let cache2 = self.tcp_cache2_map.get_mut(&key).unwrap();
-        let sequence = cache2.get_sequence();
-        let window = *self.tcp_send_window_map.get(&key).unwrap_or(&0);
-        if window > 0 {
-            let cache = self
-                .tcp_cache_map
+        if let Some(rtt) = rtt {
+            let min_rto = self.min_rto;
+            let estimator = self
+                .tcp_rtt_map
                 .entry(key)
-                .or_insert_with(|| Cacher::new(sequence));
-            let sent_size = cache.get_size();
-            let remain_size = (window as usize).checked_sub(sent_size).unwrap_or(0);
-            let remain_size = min(remain_size, u16::MAX as usize) as u16;
+                .or_insert_with(|| RttEstimator::new(min_rto));
+            estimator.update(rtt);
+            trace!(
+                "update TCP RTT of {} -> {} to {} ms (SRTT {} ms, RTO {} ms)",
+                dst,
+                src_port,
+                rtt.as_millis(),
+                estimator.get_srtt().unwrap().as_millis(),
+                estimator.get_rto().as_millis()
+            );
+        }
+    }
 
-            let size = min(remain_size as usize, cache2.get_size());
-            if size > 0 {
-                let payload = cache2.get(size).unwrap();
+    /// Get the smoothed RTT of a TCP connection. Returns `None` if the RTT is never measured.
+    pub fn get_tcp_srtt(&self, dst: SocketAddrV4, src_port: u16) -> Option<Duration> {
+        self.tcp_rtt_map
+            .get(&(src_port, dst))
+            .and_then(|estimator| estimator.get_srtt())
+    }
 
-                let sequence_tail = sequence
-                    .checked_add(size as u32)
-                    .unwrap_or_else(|| size as u32 - (u32::MAX - sequence));
-                cache2.invalidate_to(sequence_tail);
+    /// Get the size of data of a TCP connection sent to the source but not acknowledged.
+    pub fn get_tcp_in_flight(&self, dst: SocketAddrV4, src_port: u16) -> usize {
+        match self.tcp_cache_map.get(&(src_port, dst)) {
+            Some(cache) => cache.get_size(),
+            None => 0,
+        }
+    }
 
-                // Append to cache
-                let cache = self
-                    .tcp_cache_map
-                    .entry(key)
-                    .or_insert_with(|| Cacher::new(sequence));
-                cache.append(&payload)?;
+    /// Get the count of TCP segments of a TCP connection retransmitted to the source.
+    pub fn get_tcp_retransmission_count(&self, dst: SocketAddrV4, src_port: u16) -> usize {
+        *self
+            .tcp_retransmission_count_map
+            .get(&(src_port, dst))
+            .unwrap_or(&0)
+    }
 
-                // Send
-                self.send_tcp_ack_raw(dst, src_port, sequence, &payload)?;
-            }
-        }
+    fn count_tcp_retransmission(&mut self, dst: SocketAddrV4, src_port: u16) {
+        *self
+            .tcp_retransmission_count_map
+            .entry((src_port, dst))
+            .or_insert(0) += 1;
+        metrics::TCP_RETRANSMISSIONS.increase();
+    }
 
-        Ok(())
+    /// Get the retransmission timeout of a TCP connection.
+    pub fn get_tcp_rto(&self, dst: SocketAddrV4, src_port: u16) -> Duration {
+        match self.tcp_rtt_map.get(&(src_port, dst)) {
+            Some(estimator) => estimator.get_rto(),
+            None => RttEstimator::new(self.min_rto).get_rto(),
+        }
     }
 
-    fn send_tcp_ack_raw(
+    /// Updates the loss recovery of a TCP connection with an acknowledgement from the source
+    /// (RFC 5681, RFC 6582). The first unacknowledged segment is retransmitted after 3 duplicate
+    /// acknowledgements, and the window is halved until all the data sent before is acknowledged.
+    /// A partial acknowledgement in the recovery retransmits the next unacknowledged segment.
+    pub fn update_tcp_recovery(
         &mut self,
         dst: SocketAddrV4,
         src_port: u16,
-        sequence: u32,
-        payload: &[u8],
+        acknowledgement: u32,
+        window: usize,
+        is_pure: bool,
     ) -> io::Result<()> {
         let key = (src_port, dst);
 
-        // Pseudo headers
-        let tcp = Tcp::new_ack(Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED, 0, 0, 0, 0, 0);
-        let ipv4 = Ipv4::new(
-            0,
-            tcp.get_type(),
-            Ipv4Addr::UNSPECIFIED,
-            Ipv4Addr::UNSPECIFIED,
-        )
-        .unwrap();
+        let (sequence, size) = match self.tcp_cache_map.get(&key) {
+            Some(cache) => (cache.get_sequence(), cache.get_size()),
+            None => return Ok(()),
+        };
 
-        // Segmentation
-        let header_size = ipv4.get_size() + tcp.get_size();
-        let max_payload_size = self.mtu as usize - header_size;
-        let mut i = 0;
-        while max_payload_size * i < payload.len() {
-            let length = min(max_payload_size, payload.len() - i * max_payload_size);
-            let payload = &payload[i * max_payload_size..i * max_payload_size + length];
-            let sequence = sequence
-                .checked_add((i * max_payload_size) as u32)
-                .unwrap_or_else(|| (i * max_payload_size) as u32 - (u32::MAX - sequence));
+        let acknowledged = acknowledgement.wrapping_sub(sequence) as usize;
+        if acknowledged > 0 && acknowledged <= size {
+            // New data is acknowledged
+            self.tcp_duplicate_map.remove(&key);
+            match self.tcp_recovery_map.get(&key).cloned() {
+                Some(recover) => {
+                    if seq_le(recover, acknowledgement) {
+                        self.tcp_recovery_map.remove(&key);
+                        trace!(
+                            "{}exit TCP fast recovery of {} -> {}",
+                            self.get_tcp_tag(dst, src_port),
+                            dst,
+                            src_port
+                        );
+                    } else {
+                        // Partial acknowledgement
+                        self.resend_tcp_ack_from(dst, src_port, acknowledgement)?;
+                        self.tcp_last_retransmission_map
+                            .insert(key, self.clock.now());
+                    }
+                }
+                None => {
+                    // The congestion window is not increased in the recovery
+                    let rtt = self
+                        .tcp_rtt_map
+                        .get(&key)
+                        .and_then(|estimator| estimator.get_srtt());
+                    let now = self.clock.now();
+                    self.get_tcp_cc(dst, src_port)
+                        .on_ack(acknowledged, rtt, now);
+                }
+            }
 
-            // TCP
-            let tcp = Tcp::new_ack(
-                dst.ip().clone(),
-                self.src_ip_addr,
-                dst.port(),
-                src_port,
-                sequence,
-                *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0),
-                *self.tcp_window_map.get(&key).unwrap_or(&65535),
-            );
+            return Ok(());
+        }
 
-            // Send
-            self.send_ipv4_with_transport(Layers::Tcp(tcp), Some(payload))?;
+        // An acknowledgement which updates the window is not a duplicate
+        let is_duplicate = is_pure
+            && acknowledged == 0
+            && size > 0
+            && window > 0
+            && self.tcp_send_window_map.get(&key) == Some(&window);
+        if !is_duplicate {
+            return Ok(());
+        }
 
-            // Update TCP sequence
-            let next_sequence = sequence
-                .checked_add(length as u32)
-                .unwrap_or_else(|| length as u32 - (u32::MAX - sequence));
-            let record_sequence = *self.tcp_sequence_map.get(&key).unwrap_or(&0);
-            let sub_sequence = next_sequence
-                .checked_sub(record_sequence)
-                .unwrap_or_else(|| next_sequence + (u32::MAX - record_sequence));
-            if (sub_sequence as usize) < MAX_U32_WINDOW_SIZE {
-                self.tcp_sequence_map.insert(key, next_sequence);
+        let entry = self.tcp_duplicate_map.entry(key).or_insert(0);
+        *entry = entry.checked_add(1).unwrap_or(usize::MAX);
+        let duplicates = *entry;
+
+        if self.tcp_recovery_map.contains_key(&key) {
+            // The retransmission may be lost, retransmit all the unsacked data after the RTO
+            let is_timed_out = match self.tcp_last_retransmission_map.get(&key) {
+                Some(instant) => self.clock.elapsed(*instant) >= self.get_tcp_rto(dst, src_port),
+                None => true,
+            };
+            if is_timed_out {
+                self.resend_tcp_ack(dst, src_port)?;
+                self.tcp_last_retransmission_map
+                    .insert(key, self.clock.now());
             }
+        } else if duplicates == DUPLICATES_BEFORE_FAST_RETRANSMISSION {
+            self.tcp_recovery_map
+                .insert(key, sequence.wrapping_add(size as u32));
+            let cc = self.get_tcp_cc(dst, src_port);
+            cc.on_loss(size);
+            let cwnd = cc.cwnd();
+            trace!(
+                "{}enter TCP fast recovery of {} -> {} at {} (congestion window {})",
+                self.get_tcp_tag(dst, src_port),
+                dst,
+                src_port,
+                sequence,
+                cwnd
+            );
 
-            i = i + 1;
+            // Fast retransmit
+            self.resend_tcp_ack_from(dst, src_port, sequence)?;
+            self.tcp_last_retransmission_map
+                .insert(key, self.clock.now());
         }
 
         Ok(())
     }
 
-    /// Sends an TCP ACK packet without payload.
-    pub fn send_tcp_ack_0(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
-        let key = (src_port, dst);
+    /// Get the congestion control of a TCP connection, which will be created if not exists.
+    fn get_tcp_cc(&mut self, dst: SocketAddrV4, src_port: u16) -> &mut Box<dyn CongestionControl> {
+        let mss = self.get_tcp_max_payload_size(dst, src_port);
+        let cc = self.cc;
 
-        // TCP
-        let tcp = Tcp::new_ack(
-            dst.ip().clone(),
-            self.src_ip_addr,
-            dst.port(),
-            src_port,
-            *self.tcp_sequence_map.get(&key).unwrap_or(&0),
-            *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0),
-            *self.tcp_window_map.get(&key).unwrap_or(&65535),
-        );
+        self.tcp_cc_map
+            .entry((src_port, dst))
+            .or_insert_with(|| cc.new_congestion_control(mss))
+    }
 
-        // Send
-        self.send_ipv4_with_transport(Layers::Tcp(tcp), None)
+    /// Sets the timestamps option of a TCP packet if the timestamps of the TCP connection is
+    /// enabled.
+    fn set_tcp_timestamps_option(&self, dst: SocketAddrV4, src_port: u16, tcp: &mut Tcp) {
+        if let Some(ts_recent) = self.tcp_ts_recent_map.get(&(src_port, dst)) {
+            tcp.set_timestamps(self.get_timestamp(), *ts_recent);
+        }
     }
 
-    /// Sends an TCP ACK/SYN packet.
-    pub fn send_tcp_ack_syn(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
-        let key = (src_port, dst);
+    /// Sets the window size of a TCP connection.
+    pub fn set_tcp_window(&mut self, dst: SocketAddrV4, src_port: u16, window: usize) {
+        self.tcp_window_map.insert((src_port, dst), window);
+        trace!("set TCP window of {} -> {} to {}", dst, src_port, window);
+    }
 
-        // TCP
-        let tcp = Tcp::new_ack_syn(
-            dst.ip().clone(),
-            self.src_ip_addr,
-            dst.port(),
-            src_port,
-            *self.tcp_sequence_map.get(&key).unwrap_or(&0),
-            *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0),
-            *self.tcp_window_map.get(&key).unwrap_or(&65535),
-        );
+    /// Get the window size of a TCP connection set by `set_tcp_window`, which is not scaled.
+    pub fn get_tcp_window_size(&self, dst: SocketAddrV4, src_port: u16) -> Option<usize> {
+        self.tcp_window_map.get(&(src_port, dst)).cloned()
+    }
 
-        // Send
-        self.send_ipv4_with_transport(Layers::Tcp(tcp), None)?;
+    /// Get the window field of a TCP connection, which is scaled if the window scaling is enabled.
+    fn get_tcp_window(&self, dst: SocketAddrV4, src_port: u16) -> u16 {
+        let key = (src_port, dst);
 
-        // Update TCP sequence
-        let tcp_sequence_entry = self.tcp_sequence_map.entry(key).or_insert(0);
-        *tcp_sequence_entry = tcp_sequence_entry.checked_add(1).unwrap_or(0);
+        let mut window = *self.tcp_window_map.get(&key).unwrap_or(&65535);
+        if self.tcp_wscale_map.contains_key(&key) {
+            window >>= self.tcp_window_scale;
+        }
 
-        Ok(())
+        min(window, u16::MAX as usize) as u16
     }
 
-    /// Sends an TCP ACK/RST packet.
-    pub fn send_tcp_ack_rst(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
-        let key = (src_port, dst);
-
-        // TCP
-        let tcp = Tcp::new_ack_rst(
-            dst.ip().clone(),
-            self.src_ip_addr,
-            dst.port(),
+    /// Invalidates TCP cache to the given sequence.
+    pub fn invalidate_cache_to(&mut self, dst: SocketAddrV4, src_port: u16, sequence: u32) {
+        if let Some(cache) = self.tcp_cache_map.get_mut(&(src_port, dst)) {
+            cache.invalidate_to(sequence);
+        }
+        trace!(
+            "invalidate cache {} -> {} to sequence {}",
+            dst,
             src_port,
-            *self.tcp_sequence_map.get(&key).unwrap_or(&0),
-            *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0),
-            *self.tcp_window_map.get(&key).unwrap_or(&65535),
+            sequence
         );
+    }
 
-        // Send
-        self.send_ipv4_with_transport(Layers::Tcp(tcp), None)
+    /// Releases the buffers of empty caches of TCP connections grown beyond the initial size.
+    pub fn shrink_tcp_caches(&mut self) {
+        for cache in self
+            .tcp_cache_map
+            .values_mut()
+            .chain(self.tcp_cache2_map.values_mut())
+        {
+            cache.shrink();
+        }
     }
 
-    /// Sends an TCP ACK/FIN packet.
-    pub fn send_tcp_ack_fin(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+    /// Removes all information related to a TCP connection.
+    pub fn remove(&mut self, dst: SocketAddrV4, src_port: u16) {
         let key = (src_port, dst);
 
-        // TCP
-        let tcp = Tcp::new_ack_fin(
-            dst.ip().clone(),
-            self.src_ip_addr,
-            dst.port(),
-            src_port,
-            *self.tcp_sequence_map.get(&key).unwrap_or(&0),
-            *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0),
-            *self.tcp_window_map.get(&key).unwrap_or(&65535),
-        );
-
-        // Send
-        self.send_ipv4_with_transport(Layers::Tcp(tcp), None)
+        self.tcp_sequence_map.remove(&key);
+        self.tcp_initial_sequence_map.remove(&key);
+        self.tcp_acknowledgement_map.remove(&key);
+        self.tcp_window_map.remove(&key);
+        self.tcp_cache_map.remove(&key);
+        self.tcp_mss_map.remove(&key);
+        self.tcp_wscale_map.remove(&key);
+        self.tcp_sack_perm_set.remove(&key);
+        self.tcp_sack_map.remove(&key);
+        self.tcp_recv_sack_map.remove(&key);
+        self.tcp_delayed_ack_map.remove(&key);
+        self.tcp_ts_recent_map.remove(&key);
+        self.tcp_timing_map.remove(&key);
+        self.tcp_duplicate_map.remove(&key);
+        self.tcp_recovery_map.remove(&key);
+        self.tcp_last_retransmission_map.remove(&key);
+        self.tcp_retransmission_count_map.remove(&key);
+        self.tcp_cc_map.remove(&key);
+        self.tcp_fin_map.remove(&key);
+        self.tcp_persist_map.remove(&key);
+        self.tcp_shrink_map.remove(&key);
+        self.tcp_ecn_map.remove(&key);
+        self.tcp_dscp_map.remove(&key);
+        self.tcp_dont_fragment_map.remove(&key);
+        match self
+            .tcp_rtt_map
+            .remove(&key)
+            .and_then(|estimator| estimator.get_srtt())
+        {
+            Some(srtt) => trace!(
+                "{}remove {} -> {} (RTT {} ms)",
+                self.get_tcp_tag(dst, src_port),
+                dst,
+                src_port,
+                srtt.as_millis()
+            ),
+            None => trace!(
+                "{}remove {} -> {}",
+                self.get_tcp_tag(dst, src_port),
+                dst,
+                src_port
+            ),
+        }
+        self.tcp_tag_map.remove(&key);
+        self.tcp_connection_map.remove(&key);
     }
 
-    /// Sends an TCP RST packet.
-    pub fn send_tcp_rst(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+    /// Updates the FIN of a TCP connection sent to the source with an acknowledgement from the
+    /// source.
+    pub fn update_tcp_fin(&mut self, dst: SocketAddrV4, src_port: u16, acknowledgement: u32) {
         let key = (src_port, dst);
 
-        // TCP
-        let tcp = Tcp::new_rst(
-            dst.ip().clone(),
-            self.src_ip_addr,
-            dst.port(),
-            src_port,
-            *self.tcp_sequence_map.get(&key).unwrap_or(&0),
-            0,
-            *self.tcp_window_map.get(&key).unwrap_or(&65535),
-        );
+        if let Some(TcpFinState::Sent(sequence, _, _)) = self.tcp_fin_map.get(&key).cloned() {
+            if seq_le(sequence.wrapping_add(1), acknowledgement) {
+                self.tcp_fin_map.insert(key, TcpFinState::Acknowledged);
+                trace!(
+                    "{}TCP FIN of {} -> {} is acknowledged",
+                    self.get_tcp_tag(dst, src_port),
+                    dst,
+                    src_port
+                );
+            }
+        }
+    }
 
-        // Send
-        self.send_ipv4_with_transport(Layers::Tcp(tcp), None)
+    /// Returns if the FIN of a TCP connection is sent to the source.
+    pub fn is_tcp_fin_sent(&self, dst: SocketAddrV4, src_port: u16) -> bool {
+        self.tcp_fin_map.contains_key(&(src_port, dst))
     }
 
-    /// Sends UDP packets.
-    pub fn send_udp(&mut self, dst: SocketAddrV4, src_port: u16, payload: &[u8]) -> io::Result<()> {
-        // Pseudo headers
-        let udp = Udp::new(Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED, 0, 0);
-        let ipv4 = Ipv4::new(
-            0,
-            udp.get_type(),
-            Ipv4Addr::UNSPECIFIED,
-            Ipv4Addr::UNSPECIFIED,
+    /// Returns if the FIN of a TCP connection sent to the source is acknowledged.
+    pub fn is_tcp_fin_acknowledged(&self, dst: SocketAddrV4, src_port: u16) -> bool {
+        matches!(
+            self.tcp_fin_map.get(&(src_port, dst)),
+            Some(TcpFinState::Acknowledged)
         )
-        .unwrap();
+    }
 
-        // Fragmentation
-        let ipv4_header_size = ipv4.get_size();
-        let udp_header_size = udp.get_size();
+    /// Resends the FIN of a TCP connection if it is not acknowledged in the RTO. Returns `false`
+    /// if the FIN is retransmitted too many times and the connection should be given up.
+    pub fn resend_tcp_ack_fin_if_timed_out(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+    ) -> io::Result<bool> {
+        let key = (src_port, dst);
 
-        let size = udp_header_size + payload.len();
-        let mut n = 0;
-        while n < size {
-            let mut length = min(size - n, self.mtu as usize - ipv4_header_size);
-            let mut remain = size - n - length;
+        if let Some(TcpFinState::Sent(sequence, instant, count)) =
+            self.tcp_fin_map.get(&key).cloned()
+        {
+            if self.clock.elapsed(instant) >= self.get_tcp_rto(dst, src_port) {
+                if count >= MAX_FIN_RETRANSMISSIONS {
+                    return Ok(false);
+                }
 
-            // Alignment
-            if remain > 0 {
-                length = length / 8 * 8;
-                remain = size - n - length;
+                let min_rto = self.min_rto;
+                self.tcp_rtt_map
+                    .entry(key)
+                    .or_insert_with(|| RttEstimator::new(min_rto))
+                    .backoff();
+                self.tcp_fin_map.insert(
+                    key,
+                    TcpFinState::Sent(sequence, self.clock.now(), count + 1),
+                );
+                self.count_tcp_retransmission(dst, src_port);
+                self.send_tcp_ack_fin_raw(dst, src_port, sequence)?;
             }
+        }
 
-            // Leave at least 8 Bytes for last fragment
-            if remain > 0 && remain < 8 {
-                length = length - 8;
-            }
+        Ok(true)
+    }
 
-            // Send
-            if n == 0 {
-                if remain > 0 {
-                    // UDP
-                    let udp = Udp::new(dst.ip().clone(), self.src_ip_addr, dst.port(), src_port);
-
-                    self.send_ipv4_more_fragment(
-                        dst.ip().clone(),
-                        udp.get_type(),
-                        (n / 8) as u16,
-                        Some(Layers::Udp(udp)),
-                        &payload[..length - udp_header_size],
-                    )?;
-                } else {
-                    self.send_udp_raw(dst, src_port, payload)?;
-                }
-            } else {
-                if remain > 0 {
-                    self.send_ipv4_more_fragment(
-                        dst.ip().clone(),
-                        udp.get_type(),
-                        (n / 8) as u16,
-                        None,
-                        &payload[n - udp_header_size..n + length - udp_header_size],
-                    )?;
-                } else {
-                    self.send_ipv4_last_fragment(
-                        dst.ip().clone(),
-                        udp.get_type(),
-                        (n / 8) as u16,
-                        &payload[n - udp_header_size..n + length - udp_header_size],
-                    )?;
+    /// Sends a TCP zero window probe if the persist timer of a TCP connection expires. The interval
+    /// of probes is backed off from the RTO exponentially. Probes are never counted as
+    /// retransmissions, the connection is probed as long as the source acknowledges.
+    pub fn send_tcp_window_probe_if_timed_out(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+    ) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        let (instant, count) = match self.tcp_persist_map.get(&key) {
+            Some(persist) => *persist,
+            None => return Ok(()),
+        };
+        let timeout = self
+            .get_tcp_rto(dst, src_port)
+            .as_millis()
+            .checked_shl(min(count, 16))
+            .unwrap_or(MAX_PERSIST_TIMEOUT);
+        if self.clock.elapsed(instant).as_millis() < min(timeout, MAX_PERSIST_TIMEOUT) {
+            return Ok(());
+        }
+        self.tcp_persist_map.insert(
+            key,
+            (self.clock.now(), count.checked_add(1).unwrap_or(count)),
+        );
+
+        // Probe with the first unacknowledged byte, or the first unsent byte
+        let sent = match self.tcp_cache_map.get(&key) {
+            Some(cache) if cache.get_size() > 0 => Some((cache.get_sequence(), cache.get(1)?)),
+            _ => None,
+        };
+        match sent {
+            Some((sequence, payload)) => {
+                trace!(
+                    "{}send TCP zero window probe of {} -> {} at {}",
+                    self.get_tcp_tag(dst, src_port),
+                    dst,
+                    src_port,
+                    sequence
+                );
+                self.send_tcp_ack_raw(dst, src_port, sequence, &payload)?;
+            }
+            None => {
+                let pending = match self.tcp_cache2_map.get(&key) {
+                    Some(cache) if cache.get_size() > 0 => Some(cache.get_sequence()),
+                    _ => None,
+                };
+                if let Some(sequence) = pending {
+                    trace!(
+                        "{}send TCP zero window probe of {} -> {}",
+                        self.get_tcp_tag(dst, src_port),
+                        dst,
+                        src_port
+                    );
+                    self.send_tcp_ack_in_window(dst, src_port, 1, false)?;
+                    // The probe is beyond the right edge, it is retransmitted after the window is
+                    // reopened
+                    self.tcp_shrink_map.entry(key).or_insert(sequence);
                 }
             }
-
-            n = n + length;
         }
 
+        // A probe is never timed
+        self.tcp_timing_map.remove(&key);
+
         Ok(())
     }
 
-    fn send_udp_raw(&mut self, dst: SocketAddrV4, src_port: u16, payload: &[u8]) -> io::Result<()> {
-        // UDP
-        let udp = Udp::new(dst.ip().clone(), self.src_ip_addr, dst.port(), src_port);
+    /// Get the size of the cache of a TCP connection.
+    pub fn get_cache_size(&mut self, dst: SocketAddrV4, src_port: u16) -> usize {
+        let key = (src_port, dst);
+
+        let mut size = 0;
+        if let Some(cache) = self.tcp_cache_map.get(&key) {
+            size += cache.get_size();
+        }
+        if let Some(cache) = self.tcp_cache2_map.get(&key) {
+            size += cache.get_size();
+        }
 
-        self.send_ipv4_with_transport(Layers::Udp(udp), Some(payload))
+        size
     }
 
-    fn send_ipv4_more_fragment(
-        &mut self,
-        dst_ip_addr: Ipv4Addr,
-        t: LayerType,
-        fragment_offset: u16,
-        transport: Option<Layers>,
-        payload: &[u8],
-    ) -> io::Result<()> {
-        // IPv4
-        let ipv4 = Ipv4::new_more_fragment(
-            *self.ipv4_identification_map.get(&dst_ip_addr).unwrap_or(&0),
-            t,
-            fragment_offset,
-            dst_ip_addr,
+    /// Sends an ARP reply packet of the given address.
+    pub fn send_arp_reply(&mut self, ip_addr: Ipv4Addr) -> io::Result<()> {
+        // ARP
+        let arp = Arp::new_reply(
+            self.local_hardware_addr,
+            ip_addr,
+            self.src_hardware_addr,
             self.src_ip_addr,
-        )
-        .unwrap();
+        );
+
+        // Indicator
+        let indicator = self.new_indicator(Layers::Arp(arp), None);
 
         // Send
-        self.send_ethernet(Layers::Ipv4(ipv4), transport, Some(payload))
+        self.send(&indicator)
     }
 
-    fn send_ipv4_last_fragment(
+    /// Appends TCP ACK payload to cache.
+    pub fn append_to_cache(
         &mut self,
-        dst_ip_addr: Ipv4Addr,
-        t: LayerType,
-        fragment_offset: u16,
+        dst: SocketAddrV4,
+        src_port: u16,
         payload: &[u8],
     ) -> io::Result<()> {
-        // IPv4
-        let ipv4 = Ipv4::new_last_fragment(
-            *self.ipv4_identification_map.get(&dst_ip_addr).unwrap_or(&0),
-            t,
-            fragment_offset,
-            dst_ip_addr,
-            self.src_ip_addr,
-        )
-        .unwrap();
+        let key = (src_port, dst);
 
-        // Send
-        self.send_ethernet(Layers::Ipv4(ipv4), None, Some(payload))?;
+        // TCP sequence
+        let sequence = *self.tcp_sequence_map.get(&key).unwrap_or(&0);
 
-        // Update IPv4 identification
-        self.increase_ipv4_identification(dst_ip_addr);
+        // Append to cache
+        let cache = self
+            .tcp_cache2_map
+            .entry(key)
+            .or_insert_with(|| Cacher::new_expandable(sequence));
+        cache.append(payload)?;
 
-        Ok(())
+        self.send_tcp_ack(dst, src_port)
     }
 
-    fn send_ipv4_with_transport(
-        &mut self,
-        transport: Layers,
-        payload: Option<&[u8]>,
-    ) -> io::Result<()> {
-        let dst_ip_addr = match transport {
-            Layers::Tcp(ref tcp) => tcp.get_src_ip_addr(),
-            Layers::Udp(ref udp) => udp.get_src_ip_addr(),
-            _ => unreachable!(),
+    /// Resends TCP ACK packets from first (sent) cache.
+    pub fn resend_tcp_ack(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // Resend, data beyond the right edge of the send window is never retransmitted, and the
+        // zero window is probed by the persist timer
+        let size = match self.tcp_cache_map.get(&key) {
+            Some(cache) => cache.get_size(),
+            None => return Ok(()),
         };
+        let window = *self.tcp_send_window_map.get(&key).unwrap_or(&0);
+        let size = min(size, window);
+        if size == 0 {
+            return Ok(());
+        }
+        self.count_tcp_retransmission(dst, src_port);
 
-        // IPv4
-        let ipv4 = Ipv4::new(
-            *self.ipv4_identification_map.get(&dst_ip_addr).unwrap_or(&0),
-            transport.get_type(),
-            dst_ip_addr,
-            self.src_ip_addr,
-        )
-        .unwrap();
+        // Skip sacked ranges
+        let holes = self.get_tcp_holes(dst, src_port, 0, size);
 
-        // Send
-        self.send_ethernet(Layers::Ipv4(ipv4), Some(transport), payload)?;
+        // A retransmitted segment is never timed (Karn's algorithm)
+        self.tcp_timing_map.remove(&key);
+        self.get_tcp_cc(dst, src_port).on_rto(size);
+        let min_rto = self.min_rto;
+        self.tcp_rtt_map
+            .entry(key)
+            .or_insert_with(|| RttEstimator::new(min_rto))
+            .backoff();
 
-        // Update IPv4 identification
-        self.increase_ipv4_identification(dst_ip_addr);
+        for (begin, end) in holes {
+            self.send_tcp_ack_from_cache(dst, src_port, begin, end)?;
+        }
 
         Ok(())
     }
 
-    fn send_ethernet(
+    /// Get the ranges from the beginning of first (sent) cache in the given range which are not
+    /// sacked.
+    fn get_tcp_holes(
+        &self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        begin: usize,
+        end: usize,
+    ) -> Vec<(usize, usize)> {
+        let key = (src_port, dst);
+
+        let mut holes = Vec::new();
+        let mut begin = begin;
+        if let (Some(cache), Some(sacks)) =
+            (self.tcp_cache_map.get(&key), self.tcp_sack_map.get(&key))
+        {
+            for (left, right) in sack_ranges(cache.get_sequence(), cache.get_size(), sacks) {
+                if left >= end {
+                    break;
+                }
+                if left > begin {
+                    holes.push((begin, left));
+                }
+                begin = max(begin, right);
+            }
+        }
+        if begin < end {
+            holes.push((begin, end));
+        }
+
+        holes
+    }
+
+    /// Sends the bytes in the given range from the beginning of first (sent) cache. The bytes are
+    /// read in place, the cache is taken out of the map while sending. Bytes in first (sent)
+    /// cache are sent before, so they are counted as retransmitted.
+    fn send_tcp_ack_from_cache(
         &mut self,
-        network: Layers,
-        transport: Option<Layers>,
-        payload: Option<&[u8]>,
+        dst: SocketAddrV4,
+        src_port: u16,
+        begin: usize,
+        end: usize,
     ) -> io::Result<()> {
-        // Ethernet
-        let ethernet = Ethernet::new(
-            network.get_type(),
-            self.local_hardware_addr,
-            self.src_hardware_addr,
-        )
-        .unwrap();
-
-        // Indicator
-        let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(network), transport);
+        let key = (src_port, dst);
 
-        // Send
-        match payload {
-            Some(payload) => self.send_with_payload(&indicator, payload),
-            None => self.send(&indicator),
+        let cache = match self.tcp_cache_map.remove(&key) {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        if let Some(connection) = self.tcp_connection_map.get(&key) {
+            connection
+                .lock()
+                .unwrap()
+                .add_retransmitted_down(end - begin);
         }
+        let sequence = cache.get_sequence().wrapping_add(begin as u32);
+        let result = cache.get_slices(begin, end - begin).and_then(|(a, b)| {
+            self.send_tcp_ack_raw(dst, src_port, sequence, a)?;
+            self.send_tcp_ack_raw(dst, src_port, sequence.wrapping_add(a.len() as u32), b)
+        });
+        self.tcp_cache_map.insert(key, cache);
+
+        result
     }
 
-    fn send(&mut self, indicator: &Indicator) -> io::Result<()> {
-        // Serialize
-        let size = indicator.get_size();
-        let mut buffer = vec![0u8; size];
-        indicator.serialize(&mut buffer)?;
+    /// Resends the first unsacked segment from the given sequence of first (sent) cache.
+    fn resend_tcp_ack_from(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        sequence: u32,
+    ) -> io::Result<()> {
+        let key = (src_port, dst);
 
-        // Send
-        self.tx.send_to(&buffer, None).unwrap_or(Ok(()))?;
-        debug!("send to pcap: {} ({} Bytes)", indicator.brief(), size);
+        let (cache_sequence, size) = match self.tcp_cache_map.get(&key) {
+            Some(cache) => (cache.get_sequence(), cache.get_size()),
+            None => return Ok(()),
+        };
 
-        Ok(())
-    }
+        // Find the first hole
+        let mut begin = sequence.wrapping_sub(cache_sequence) as usize;
+        let mut end = size;
+        if let Some(sacks) = self.tcp_sack_map.get(&key) {
+            for (left, right) in sack_ranges(cache_sequence, size, sacks) {
+                if right <= begin {
+                    continue;
+                }
+                if left <= begin {
+                    begin = right;
+                } else {
+                    end = left;
+                    break;
+                }
+            }
+        }
+        // Data beyond the right edge of the send window is never retransmitted
+        let window = *self.tcp_send_window_map.get(&key).unwrap_or(&0);
+        let end = min(end, window);
+        if begin >= end {
+            return Ok(());
+        }
+        let end = min(end, begin + self.get_tcp_max_payload_size(dst, src_port));
 
-    fn send_with_payload(&mut self, indicator: &Indicator, payload: &[u8]) -> io::Result<()> {
-        // Serialize
-        let size = indicator.get_size();
-        let mut buffer = vec![0u8; size + payload.len()];
-        indicator.serialize_with_payload(&mut buffer, payload)?;
+        // A retransmitted segment is never timed (Karn's algorithm)
+        self.tcp_timing_map.remove(&key);
 
-        // Send
-        self.tx.send_to(&buffer, None).unwrap_or(Ok(()))?;
-        debug!(
-            "send to pcap: {} ({} + {} Bytes)",
-            indicator.brief(),
-            size,
-            payload.len()
+        self.count_tcp_retransmission(dst, src_port);
+        trace!(
+            "{}fast retransmit TCP of {} -> {} at {} ({} Bytes)",
+            self.get_tcp_tag(dst, src_port),
+            dst,
+            src_port,
+            cache_sequence.wrapping_add(begin as u32),
+            end - begin
         );
-
-        Ok(())
+        self.send_tcp_ack_from_cache(dst, src_port, begin, end)
     }
-}
 
-/// Represents the TCP ACK duplicates before trigger a fast retransmission.
-const DUPLICATES_BEFORE_FAST_RETRANSMISSION: usize = 3;
-/// Represents the cool down time between 2 retransmissions.
-const RETRANSMISSION_COOL_DOWN: u128 = 1000;
+    /// Appends a TCP FIN to cache, the FIN will be sent after all the data in the cache is sent.
+    pub fn append_fin_to_cache(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
 
-/// Represents the initial UDP port for binding in local.
-const INITIAL_PORT: u16 = 32768;
-/// Represents the max limit of UDP port for binding in local.
-const PORT_COUNT: usize = 64;
+        if !self.tcp_sequence_map.contains_key(&key) {
+            return Ok(());
+        }
+        self.tcp_fin_map.entry(key).or_insert(TcpFinState::Pending);
 
-/// Represents the channel upstream traffic to the proxy of SOCKS or loopback to the source in pcap.
-pub struct Upstreamer {
-    tx: Arc<Mutex<Downstreamer>>,
-    is_tx_src_hardware_addr_set: bool,
-    src_ip_addr: Ipv4Addr,
-    local_ip_addr: Option<Ipv4Addr>,
-    remote: SocketAddrV4,
-    streams: HashMap<(u16, SocketAddrV4), StreamWorker>,
-    tcp_sequence_map: HashMap<(u16, SocketAddrV4), u32>,
-    tcp_acknowledgement_map: HashMap<(u16, SocketAddrV4), u32>,
-    tcp_duplicate_map: HashMap<(u16, SocketAddrV4), usize>,
-    tcp_last_retransmission_map: HashMap<(u16, SocketAddrV4), Instant>,
-    tcp_cache_map: HashMap<(u16, SocketAddrV4), RandomCacher>,
-    datagrams: Vec<Option<DatagramWorker>>,
-    /// Represents the map mapping a source port to a local port (datagram)
-    datagram_map: Vec<u16>,
-    /// Represents the LRU mapping a local port to a source port
-    udp_lru: LruCache<u16, u16>,
-    defrag: Defraggler,
-}
+        self.send_tcp_ack(dst, src_port)
+    }
 
-impl Upstreamer {
-    /// Creates a new `Upstreamer`.
-    pub fn new(
-        tx: Arc<Mutex<Downstreamer>>,
-        src_ip_addr: Ipv4Addr,
-        local_ip_addr: Option<Ipv4Addr>,
-        remote: SocketAddrV4,
-    ) -> Upstreamer {
-        let mut upstreamer = Upstreamer {
-            tx,
-            is_tx_src_hardware_addr_set: false,
-            src_ip_addr,
-            local_ip_addr,
-            remote,
-            streams: HashMap::new(),
-            tcp_sequence_map: HashMap::new(),
-            tcp_acknowledgement_map: HashMap::new(),
-            tcp_duplicate_map: HashMap::new(),
-            tcp_last_retransmission_map: HashMap::new(),
-            tcp_cache_map: HashMap::new(),
-            datagrams: (0..PORT_COUNT).map(|_| None).collect(),
-            datagram_map: vec![0u16; u16::MAX as usize],
-            udp_lru: LruCache::new(PORT_COUNT),
-            defrag: Defraggler::new(),
-        };
-        if let Some(local_ip_addr) = local_ip_addr {
-            upstreamer
-                .tx
-                .lock()
-                .unwrap()
-                .set_local_ip_addr(local_ip_addr);
-        }
-        for i in 0..PORT_COUNT {
-            upstreamer.udp_lru.put(i as u16, 0);
-        }
+    /// Sends TCP ACK packets from second (unsent) cache.
+    pub fn send_tcp_ack(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
 
-        upstreamer
+        // The window is limited by both the receive window and the congestion window
+        let window = *self.tcp_send_window_map.get(&key).unwrap_or(&0);
+        let window = min(window, self.get_tcp_cc(dst, src_port).cwnd());
+
+        self.send_tcp_ack_in_window(dst, src_port, window, self.is_nagle)
+    }
+
+    /// Sends TCP ACK packets from second (unsent) cache in the given window. If Nagle is enabled,
+    /// the last small segment is held while data is in flight, unless the FIN is pending.
+    fn send_tcp_ack_in_window(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        window: usize,
+        is_nagle: bool,
+    ) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        if let None = self.tcp_cache2_map.get(&key) {
+            return self.send_tcp_ack_fin_if_ready(dst, src_port);
+        }
+
+        // New caches shrink under the memory pressure
+        let capacity = match self.tcp_wscale_map.contains_key(&key) {
+            true => self.tcp_send_buffer,
+            false => min(self.tcp_send_buffer, u16::MAX as usize + 1),
+        };
+        let new_cache = |sequence| Cacher::with_capacity(sequence, memory::scale(capacity));
+        let max_payload_size = self.get_tcp_max_payload_size(dst, src_port);
+        let is_fin_pending = matches!(self.tcp_fin_map.get(&key), Some(TcpFinState::Pending));
+
+        let cache2 = self.tcp_cache2_map.get_mut(&key).unwrap();
+        let sequence = cache2.get_sequence();
+        if window > 0 {
+            let cache = self
+                .tcp_cache_map
+                .entry(key)
+                .or_insert_with(|| new_cache(sequence));
+            let sent_size = cache.get_size();
+            let remain_size = window.checked_sub(sent_size).unwrap_or(0);
+            let remain_size = min(remain_size, cache.get_remaining_size());
+
+            let mut size = min(remain_size, cache2.get_size());
+            // Nagle's algorithm (RFC 896)
+            if is_nagle && !is_fin_pending && (sent_size > 0 || size > max_payload_size) {
+                size -= size % max_payload_size;
+            }
+            if size > 0 {
+                let payload = cache2.get(size).unwrap();
+
+                let sequence_tail = sequence.wrapping_add(size as u32);
+                cache2.invalidate_to(sequence_tail);
+
+                // Append to cache
+                let cache = self
+                    .tcp_cache_map
+                    .entry(key)
+                    .or_insert_with(|| new_cache(sequence));
+                cache.append(&payload)?;
+
+                // Time the segment if no segment is being timed
+                if !self.tcp_ts_recent_map.contains_key(&key) {
+                    let now = self.clock.now();
+                    self.tcp_timing_map
+                        .entry(key)
+                        .or_insert((sequence_tail, now));
+                }
+
+                // Send
+                self.send_tcp_ack_raw(dst, src_port, sequence, &payload)?;
+            }
+        }
+
+        self.send_tcp_ack_fin_if_ready(dst, src_port)
+    }
+
+    /// Sends the pending TCP FIN if all the data in the cache is sent.
+    fn send_tcp_ack_fin_if_ready(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        if let Some(TcpFinState::Pending) = self.tcp_fin_map.get(&key) {
+            let is_sent = match self.tcp_cache2_map.get(&key) {
+                Some(cache) => cache.get_size() == 0,
+                None => true,
+            };
+            if is_sent {
+                let sequence = *self.tcp_sequence_map.get(&key).unwrap_or(&0);
+                self.send_tcp_ack_fin_raw(dst, src_port, sequence)?;
+
+                // Update TCP sequence, the FIN occupies a sequence
+                self.tcp_sequence_map.insert(key, sequence.wrapping_add(1));
+                self.tcp_fin_map
+                    .insert(key, TcpFinState::Sent(sequence, self.clock.now(), 0));
+                trace!(
+                    "{}send TCP FIN of {} -> {} at {}",
+                    self.get_tcp_tag(dst, src_port),
+                    dst,
+                    src_port,
+                    sequence
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the max payload size of a TCP packet of a TCP connection.
+    fn get_tcp_max_payload_size(&self, dst: SocketAddrV4, src_port: u16) -> usize {
+        // Pseudo headers
+        let mut tcp = Tcp::new_ack(
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            0,
+            0,
+            0,
+            0,
+            0,
+        );
+        self.set_tcp_timestamps_option(dst, src_port, &mut tcp);
+        let ipv4 = Ipv4::new(
+            0,
+            tcp.get_type(),
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::UNSPECIFIED,
+        )
+        .unwrap();
+
+        // The MSS excludes options
+        let header_size = ipv4.get_size() + tcp.get_size();
+        let options_size = header_size - IPV4_TCP_HEADER_SIZE as usize;
+
+        min(
+            self.mtu as usize - header_size,
+            self.get_tcp_mss(dst, src_port) as usize - options_size,
+        )
+    }
+
+    fn send_tcp_ack_raw(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        sequence: u32,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // Segmentation
+        let max_payload_size = self.get_tcp_max_payload_size(dst, src_port);
+        let mut i = 0;
+        while max_payload_size * i < payload.len() {
+            let length = min(max_payload_size, payload.len() - i * max_payload_size);
+            let payload = &payload[i * max_payload_size..i * max_payload_size + length];
+            let sequence = sequence.wrapping_add((i * max_payload_size) as u32);
+
+            // TCP
+            let mut tcp = Tcp::new_ack(
+                IpAddr::V4(*dst.ip()),
+                IpAddr::V4(self.src_ip_addr),
+                dst.port(),
+                src_port,
+                sequence,
+                *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0),
+                self.get_tcp_window(dst, src_port),
+            );
+            self.set_tcp_timestamps_option(dst, src_port, &mut tcp);
+
+            // Send, the segment acknowledges as well
+            self.send_ipv4_with_transport(Layers::Tcp(tcp), Some(payload))?;
+            self.tcp_delayed_ack_map.remove(&key);
+
+            // Update TCP sequence
+            let next_sequence = sequence.wrapping_add(length as u32);
+            let record_sequence = *self.tcp_sequence_map.get(&key).unwrap_or(&0);
+            let sub_sequence = next_sequence.wrapping_sub(record_sequence);
+            if (sub_sequence as usize) < MAX_U32_WINDOW_SIZE {
+                self.tcp_sequence_map.insert(key, next_sequence);
+            }
+
+            i = i + 1;
+        }
+
+        Ok(())
+    }
+
+    /// Sends an TCP ACK packet without payload.
+    pub fn send_tcp_ack_0(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // TCP
+        let mut tcp = Tcp::new_ack(
+            IpAddr::V4(*dst.ip()),
+            IpAddr::V4(self.src_ip_addr),
+            dst.port(),
+            src_port,
+            *self.tcp_sequence_map.get(&key).unwrap_or(&0),
+            *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0),
+            self.get_tcp_window(dst, src_port),
+        );
+
+        self.set_tcp_timestamps_option(dst, src_port, &mut tcp);
+        if self.tcp_sack_perm_set.contains(&key) {
+            if let Some(sacks) = self.tcp_recv_sack_map.get(&key) {
+                tcp.set_sacks(sacks);
+            }
+        }
+        self.tcp_delayed_ack_map.remove(&key);
+
+        // Send
+        self.send_ipv4_with_transport(Layers::Tcp(tcp), None)
+    }
+
+    /// Sends an TCP ACK packet without payload for a segment received in order. The ACK is
+    /// delayed until the second segment or the timeout of delayed ACKs (RFC 1122), and a segment
+    /// sent in the meantime acknowledges instead.
+    pub fn send_tcp_ack_0_delayed(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        if self.delayed_ack.as_millis() == 0 || self.tcp_delayed_ack_map.contains_key(&key) {
+            return self.send_tcp_ack_0(dst, src_port);
+        }
+        self.tcp_delayed_ack_map.insert(key, self.clock.now());
+
+        Ok(())
+    }
+
+    /// Sends the delayed TCP ACK packets whose timeout expires.
+    pub fn send_tcp_ack_0_if_timed_out(&mut self) -> io::Result<()> {
+        let delayed_ack = self.delayed_ack;
+        let keys: Vec<_> = self
+            .tcp_delayed_ack_map
+            .iter()
+            .filter(|(_, instant)| self.clock.elapsed(**instant) >= delayed_ack)
+            .map(|(key, _)| *key)
+            .collect();
+        for (src_port, dst) in keys {
+            self.send_tcp_ack_0(dst, src_port)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends an TCP keepalive packet, which is an ACK with the sequence before the next sequence
+    /// and no data (RFC 1122).
+    pub fn send_tcp_keepalive(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // TCP
+        let mut tcp = Tcp::new_ack(
+            IpAddr::V4(*dst.ip()),
+            IpAddr::V4(self.src_ip_addr),
+            dst.port(),
+            src_port,
+            self.tcp_sequence_map
+                .get(&key)
+                .unwrap_or(&0)
+                .wrapping_sub(1),
+            *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0),
+            self.get_tcp_window(dst, src_port),
+        );
+        self.set_tcp_timestamps_option(dst, src_port, &mut tcp);
+
+        // Send
+        self.send_ipv4_with_transport(Layers::Tcp(tcp), None)
+    }
+
+    /// Sends an TCP ACK/SYN packet.
+    pub fn send_tcp_ack_syn(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // A new connection starts from the initial sequence
+        if !self.tcp_sequence_map.contains_key(&key) {
+            let sequence = self.get_tcp_initial_sequence(dst, src_port);
+            self.tcp_sequence_map.insert(key, sequence);
+        }
+        let sequence = *self.tcp_sequence_map.get(&key).unwrap_or(&0);
+        self.tcp_initial_sequence_map.insert(key, sequence);
+
+        self.send_tcp_ack_syn_raw(dst, src_port, sequence)?;
+
+        // Update TCP sequence
+        let tcp_sequence_entry = self.tcp_sequence_map.entry(key).or_insert(0);
+        *tcp_sequence_entry = tcp_sequence_entry.wrapping_add(1);
+
+        Ok(())
+    }
+
+    /// Resends the TCP ACK/SYN of a TCP connection from the initial sequence, like for a TCP SYN
+    /// retransmitted by the source, or for the TCP SYN of the source in a simultaneous open.
+    /// Other states of the connection are kept.
+    pub fn resend_tcp_ack_syn(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let sequence = match self.tcp_initial_sequence_map.get(&(src_port, dst)) {
+            Some(&sequence) => sequence,
+            None => return self.send_tcp_ack_syn(dst, src_port),
+        };
+
+        self.send_tcp_ack_syn_raw(dst, src_port, sequence)
+    }
+
+    fn send_tcp_ack_syn_raw(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        sequence: u32,
+    ) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // TCP
+        let mut tcp = Tcp::new_ack_syn(
+            IpAddr::V4(*dst.ip()),
+            IpAddr::V4(self.src_ip_addr),
+            dst.port(),
+            src_port,
+            sequence,
+            *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0),
+            // The window field of a TCP SYN is never scaled
+            min(
+                *self.tcp_window_map.get(&key).unwrap_or(&65535),
+                u16::MAX as usize,
+            ) as u16,
+        );
+        tcp.set_mss(self.get_tcp_mss(dst, src_port));
+        if self.tcp_wscale_map.contains_key(&key) {
+            tcp.set_wscale(self.tcp_window_scale);
+        }
+        if self.tcp_sack_perm_set.contains(&key) {
+            tcp.set_sack_perm();
+        }
+        self.set_tcp_timestamps_option(dst, src_port, &mut tcp);
+        // An ECN-setup SYN-ACK (RFC 3168)
+        if self.tcp_ecn_map.contains_key(&key) {
+            tcp.set_ece();
+        }
+
+        // Send
+        self.send_ipv4_with_transport(Layers::Tcp(tcp), None)
+    }
+
+    /// Sends an TCP SYN packet, which opens a TCP connection to the source.
+    pub fn send_tcp_syn(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // A new connection starts from the initial sequence
+        if !self.tcp_sequence_map.contains_key(&key) {
+            let sequence = self.get_tcp_initial_sequence(dst, src_port);
+            self.tcp_sequence_map.insert(key, sequence);
+        }
+        let sequence = *self.tcp_sequence_map.get(&key).unwrap_or(&0);
+        self.tcp_initial_sequence_map.insert(key, sequence);
+
+        // TCP
+        let mut tcp = Tcp::new_syn(
+            IpAddr::V4(*dst.ip()),
+            IpAddr::V4(self.src_ip_addr),
+            dst.port(),
+            src_port,
+            sequence,
+            // The window field of a TCP SYN is never scaled
+            min(
+                *self.tcp_window_map.get(&key).unwrap_or(&65535),
+                u16::MAX as usize,
+            ) as u16,
+        );
+        tcp.set_mss(self.get_tcp_mss(dst, src_port));
+        tcp.set_wscale(self.tcp_window_scale);
+        tcp.set_sack_perm();
+
+        // Send
+        self.send_ipv4_with_transport(Layers::Tcp(tcp), None)?;
+
+        // Update TCP sequence
+        let tcp_sequence_entry = self.tcp_sequence_map.entry(key).or_insert(0);
+        *tcp_sequence_entry = tcp_sequence_entry.wrapping_add(1);
+
+        Ok(())
+    }
+
+    /// Resends the TCP SYN of a TCP connection to the source from the same initial sequence,
+    /// other states of the connection are cleared.
+    pub fn resend_tcp_syn(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        let sequence = self
+            .tcp_sequence_map
+            .get(&key)
+            .map(|sequence| sequence.wrapping_sub(1));
+        self.remove(dst, src_port);
+        if let Some(sequence) = sequence {
+            self.tcp_sequence_map.insert(key, sequence);
+        }
+
+        self.send_tcp_syn(dst, src_port)
+    }
+
+    /// Sends an TCP ACK/RST packet.
+    pub fn send_tcp_ack_rst(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // TCP
+        let tcp = Tcp::new_ack_rst(
+            IpAddr::V4(*dst.ip()),
+            IpAddr::V4(self.src_ip_addr),
+            dst.port(),
+            src_port,
+            *self.tcp_sequence_map.get(&key).unwrap_or(&0),
+            *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0),
+            self.get_tcp_window(dst, src_port),
+        );
+
+        // Send
+        self.send_ipv4_with_transport(Layers::Tcp(tcp), None)
+    }
+
+    /// Sends an TCP ACK/FIN packet.
+    pub fn send_tcp_ack_fin(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let sequence = *self.tcp_sequence_map.get(&(src_port, dst)).unwrap_or(&0);
+
+        self.send_tcp_ack_fin_raw(dst, src_port, sequence)
+    }
+
+    fn send_tcp_ack_fin_raw(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        sequence: u32,
+    ) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // TCP
+        let mut tcp = Tcp::new_ack_fin(
+            IpAddr::V4(*dst.ip()),
+            IpAddr::V4(self.src_ip_addr),
+            dst.port(),
+            src_port,
+            sequence,
+            *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0),
+            self.get_tcp_window(dst, src_port),
+        );
+        self.set_tcp_timestamps_option(dst, src_port, &mut tcp);
+        self.tcp_delayed_ack_map.remove(&key);
+
+        // Send
+        self.send_ipv4_with_transport(Layers::Tcp(tcp), None)
+    }
+
+    /// Sends an TCP RST packet.
+    pub fn send_tcp_rst(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // TCP
+        let tcp = Tcp::new_rst(
+            IpAddr::V4(*dst.ip()),
+            IpAddr::V4(self.src_ip_addr),
+            dst.port(),
+            src_port,
+            *self.tcp_sequence_map.get(&key).unwrap_or(&0),
+            0,
+            self.get_tcp_window(dst, src_port),
+        );
+
+        // Send
+        self.send_ipv4_with_transport(Layers::Tcp(tcp), None)
+    }
+
+    /// Sends an ICMPv4 echo reply packet from the local IP address.
+    pub fn send_icmpv4_echo_reply(
+        &mut self,
+        identifier: u16,
+        sequence: u16,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        // ICMPv4
+        let icmpv4 = Icmpv4::new_echo_reply(identifier, sequence, payload);
+
+        // IPv4
+        let ipv4 = Ipv4::new(
+            self.next_ipv4_identification(self.local_ip_addr, icmpv4.get_type()),
+            icmpv4.get_type(),
+            self.local_ip_addr,
+            self.src_ip_addr,
+        )
+        .unwrap();
+
+        // Send
+        self.send_ethernet(Layers::Ipv4(ipv4), Some(Layers::Icmpv4(icmpv4)), None)?;
+
+        Ok(())
+    }
+
+    /// Sends an ICMPv4 destination unreachable packet from the local IP address in reply to the
+    /// given packet. At most one packet is sent in a flow per `ICMPV4_UNREACHABLE_INTERVAL`.
+    pub fn send_icmpv4_destination_unreachable(
+        &mut self,
+        code: u8,
+        indicator: &Indicator,
+    ) -> io::Result<()> {
+        if indicator.get_ipv4().is_none() || self.is_icmpv4_unreachable_limited(indicator) {
+            return Ok(());
+        }
+
+        // ICMPv4
+        let icmpv4 = Icmpv4::new_destination_unreachable(code, &quote_ipv4(indicator)?);
+
+        self.send_icmpv4_error(icmpv4)
+    }
+
+    /// Sends an ICMPv4 fragmentation needed packet with the MTU from the local IP address in
+    /// reply to the given packet, so the source can send smaller datagrams. The packet is rate
+    /// limited as a destination unreachable.
+    pub fn send_icmpv4_fragmentation_needed(
+        &mut self,
+        mtu: u16,
+        indicator: &Indicator,
+    ) -> io::Result<()> {
+        if indicator.get_ipv4().is_none() || self.is_icmpv4_unreachable_limited(indicator) {
+            return Ok(());
+        }
+
+        // ICMPv4, the MTU is at least the minimum of IPv4 (RFC 791)
+        let mtu = max(min(mtu, self.mtu), 68);
+        let icmpv4 = Icmpv4::new_fragmentation_needed(mtu, &quote_ipv4(indicator)?);
+
+        self.send_icmpv4_error(icmpv4)
+    }
+
+    /// Returns if an ICMPv4 destination unreachable in reply to the given packet is rate
+    /// limited, otherwise the flow is counted for the next one.
+    fn is_icmpv4_unreachable_limited(&mut self, indicator: &Indicator) -> bool {
+        let ipv4 = indicator.get_ipv4().unwrap();
+
+        // Rate limit
+        let key = if let Some(tcp) = indicator.get_tcp() {
+            (
+                tcp.get_src(),
+                SocketAddrV4::new(ipv4.get_dst(), tcp.get_dst()),
+            )
+        } else if let Some(udp) = indicator.get_udp() {
+            (
+                udp.get_src(),
+                SocketAddrV4::new(ipv4.get_dst(), udp.get_dst()),
+            )
+        } else {
+            (0, SocketAddrV4::new(ipv4.get_dst(), 0))
+        };
+        if let Some(instant) = self.icmpv4_unreachable_map.peek(&key) {
+            if self.clock.elapsed(*instant) < ICMPV4_UNREACHABLE_INTERVAL {
+                trace!(
+                    "skip ICMPv4 destination unreachable of {} for rate limit",
+                    indicator.brief()
+                );
+
+                return true;
+            }
+        }
+        self.icmpv4_unreachable_map.put(key, self.clock.now());
+
+        false
+    }
+
+    /// Sends an ICMPv4 time exceeded packet from the local IP address in reply to the given
+    /// packet, so pcap2socks is displayed as a hop in traceroute.
+    pub fn send_icmpv4_time_exceeded(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if indicator.get_ipv4().is_none() {
+            return Ok(());
+        }
+
+        // ICMPv4 TTL exceeded in transit
+        let icmpv4 = Icmpv4::new_time_exceeded(0, &quote_ipv4(indicator)?);
+
+        self.send_icmpv4_error(icmpv4)
+    }
+
+    fn send_icmpv4_error(&mut self, icmpv4: Icmpv4) -> io::Result<()> {
+        // IPv4
+        let ipv4 = Ipv4::new(
+            self.next_ipv4_identification(self.local_ip_addr, icmpv4.get_type()),
+            icmpv4.get_type(),
+            self.local_ip_addr,
+            self.src_ip_addr,
+        )
+        .unwrap();
+
+        // Send
+        self.send_ethernet(Layers::Ipv4(ipv4), Some(Layers::Icmpv4(icmpv4)), None)?;
+
+        Ok(())
+    }
+
+    /// Sends UDP packets, which are fragmented if they are larger than the MTU. Returns the bytes
+    /// of the frames transmitted, including headers.
+    pub fn send_udp(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        payload: &[u8],
+    ) -> io::Result<usize> {
+        // UDP
+        let udp = Udp::new(
+            IpAddr::V4(*dst.ip()),
+            IpAddr::V4(self.src_ip_addr),
+            dst.port(),
+            src_port,
+        );
+
+        let wire_size = self.wire_size;
+        self.send_ipv4_with_transport(Layers::Udp(udp), Some(payload))?;
+
+        Ok((self.wire_size - wire_size) as usize)
+    }
+
+    fn send_ipv4_with_transport(
+        &mut self,
+        mut transport: Layers,
+        payload: Option<&[u8]>,
+    ) -> io::Result<()> {
+        let (dst_ip_addr, src_port, dst_port) = match transport {
+            Layers::Tcp(ref tcp) => (
+                ipv4_addr(tcp.get_src_ip_addr()),
+                tcp.get_dst(),
+                tcp.get_src(),
+            ),
+            Layers::Udp(ref udp) => (
+                ipv4_addr(udp.get_src_ip_addr()),
+                udp.get_dst(),
+                udp.get_src(),
+            ),
+            _ => unreachable!(),
+        };
+        let dst_ip_addr = match dst_ip_addr {
+            Some(ip_addr) => ip_addr,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "transport to IPv6",
+                ))
+            }
+        };
+        let key = (src_port, SocketAddrV4::new(dst_ip_addr, dst_port));
+
+        // DSCP, ECN and DF of the flow, data segments of TCP connections with ECN are ECN capable
+        let dscp;
+        let mut ecn = 0;
+        let is_df;
+        match transport {
+            Layers::Tcp(ref mut tcp) => {
+                dscp = self.tcp_dscp_map.get(&key).cloned().unwrap_or(0);
+                is_df = self.tcp_dont_fragment_map.contains_key(&key);
+                if let Some(state) = self.tcp_ecn_map.get_mut(&key) {
+                    if state.is_ece {
+                        tcp.set_ece();
+                    }
+                    if payload.map_or(false, |payload| !payload.is_empty()) {
+                        ecn = ECN_ECT0;
+                        if state.is_cwr {
+                            tcp.set_cwr();
+                            state.is_cwr = false;
+                        }
+                    }
+                }
+            }
+            _ => {
+                dscp = self.udp_dscp_map.peek(&key).cloned().unwrap_or(0);
+                is_df = self.udp_dont_fragment_map.contains(&key);
+            }
+        }
+
+        // IPv4
+        let mut ipv4 = Ipv4::new(
+            self.next_ipv4_identification(dst_ip_addr, transport.get_type()),
+            transport.get_type(),
+            dst_ip_addr,
+            self.src_ip_addr,
+        )
+        .unwrap();
+        ipv4.set_dscp(dscp);
+        ipv4.set_ecn(ecn);
+        if is_df {
+            ipv4.set_dont_fragment();
+        }
+
+        // Send
+        let wire_size = self.wire_size;
+        let is_tcp = matches!(transport, Layers::Tcp(_));
+        self.send_ethernet(Layers::Ipv4(ipv4), Some(transport), payload)?;
+        if is_tcp {
+            if let Some(connection) = self.tcp_connection_map.get(&key) {
+                connection
+                    .lock()
+                    .unwrap()
+                    .add_wire_down((self.wire_size - wire_size) as usize);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send_ethernet(
+        &mut self,
+        network: Layers,
+        transport: Option<Layers>,
+        payload: Option<&[u8]>,
+    ) -> io::Result<()> {
+        // Indicator
+        let indicator = self.new_indicator(network, transport);
+
+        // Send
+        match payload {
+            Some(payload) => self.send_with_payload(&indicator, payload),
+            None => self.send(&indicator),
+        }
+    }
+
+    /// Creates an `Indicator` to the source, which is tagged if the source is in a VLAN.
+    fn new_indicator(&self, network: Layers, transport: Option<Layers>) -> Indicator {
+        let (ethernet, vlan) = new_link(
+            network.get_type(),
+            self.local_hardware_addr,
+            self.src_hardware_addr,
+            self.src_vlan.as_ref(),
+        );
+
+        Indicator::with_vlan(ethernet, vlan, Some(network), transport)
+    }
+
+    fn send(&mut self, indicator: &Indicator) -> io::Result<()> {
+        // Serialize
+        let size = indicator.get_size();
+        let (buffer, result) = serialize_frame(&mut self.buffer_pool, indicator, None);
+        let result = match result {
+            Ok(n) => self.send_frame(indicator, &buffer[..n]),
+            Err(e) => Err(e),
+        };
+        self.buffer_pool.put(buffer);
+
+        // Send
+        result?;
+        debug!("send to pcap: {} ({} Bytes)", indicator.describe(), size);
+
+        Ok(())
+    }
+
+    fn send_with_payload(&mut self, indicator: &Indicator, payload: &[u8]) -> io::Result<()> {
+        // Serialize
+        let size = indicator.get_size();
+        let (buffer, result) = serialize_frame(&mut self.buffer_pool, indicator, Some(payload));
+        let result = match result {
+            Ok(n) => self.send_frame(indicator, &buffer[..n]),
+            Err(e) => Err(e),
+        };
+        self.buffer_pool.put(buffer);
+
+        // Send
+        result?;
+        debug!(
+            "send to pcap: {} ({} + {} Bytes)",
+            indicator.describe(),
+            size,
+            payload.len()
+        );
+
+        Ok(())
+    }
+
+    /// Sends a serialized frame, the frame is passed to the hook first if it is set.
+    fn send_frame(&mut self, indicator: &Indicator, frame: &[u8]) -> io::Result<()> {
+        if let Some(hook) = self.hook.clone() {
+            // The hook sees the frame as it is captured, including the payload
+            let hooked = Indicator::from(frame)?;
+            match hook.on_packet(&hooked, Direction::Sent) {
+                HookAction::Continue => {}
+                HookAction::Drop => {
+                    debug!("drop {} by hook", hooked.brief());
+                    event::drop_packet(
+                        DropReason::Hook,
+                        hooked.get_transport_type().unwrap_or(LayerTypes::Ipv4),
+                    );
+
+                    return Ok(());
+                }
+                HookAction::ReplacePayload(payload) => {
+                    let replaced = hook::replace_payload(&hooked, &payload)?;
+                    let frame = replaced.to_vec()?;
+
+                    return self.transmit_frame(&replaced, &frame);
+                }
+            }
+        }
+
+        self.transmit_frame(indicator, frame)
+    }
+
+    /// Transmits a serialized frame, the frame is fragmented if its IPv4 packet is larger than
+    /// the MTU.
+    fn transmit_frame(&mut self, indicator: &Indicator, frame: &[u8]) -> io::Result<()> {
+        if let Some(mode) = self.verify_tx {
+            verify_frame(indicator, frame, mode);
+        }
+        if let Some(ref tracer) = self.tracer {
+            tracer.trace(indicator, frame, Direction::Sent);
+        }
+        if let Some(ipv4) = indicator.get_ipv4() {
+            let begin = indicator.get_link_size();
+            if frame.len() - begin > self.mtu as usize {
+                return self.send_ipv4_fragments(indicator, &frame[begin + ipv4.get_size()..]);
+            }
+        }
+
+        // ACK only TCP segments and small UDP datagrams are latency sensitive
+        let is_immediate = match indicator.get_transport_type() {
+            Some(LayerTypes::Tcp) => frame.len() <= indicator.get_size(),
+            Some(LayerTypes::Udp) => frame.len() <= indicator.get_size() + SMALL_UDP_SIZE,
+            _ => true,
+        };
+
+        self.tx.lock().unwrap().send(frame, is_immediate)?;
+        self.count_wire(indicator, frame.len());
+
+        Ok(())
+    }
+
+    /// Counts the bytes of a frame transmitted of the packet, including headers.
+    fn count_wire(&mut self, indicator: &Indicator, n: usize) {
+        self.wire_size += n as u64;
+        match indicator.get_transport_type() {
+            Some(LayerTypes::Tcp) => metrics::count_wire_down(Protocol::Tcp, n),
+            Some(LayerTypes::Udp) => metrics::count_wire_down(Protocol::Udp, n),
+            _ => {}
+        }
+    }
+
+    /// Sends the payload of an IPv4 packet in fragments (RFC 791).
+    fn send_ipv4_fragments(&mut self, indicator: &Indicator, payload: &[u8]) -> io::Result<()> {
+        let ipv4 = indicator.get_ipv4().unwrap();
+        if ipv4.is_dont_fragment() {
+            let key = match indicator.get_transport() {
+                Some(Layers::Tcp(tcp)) => Some((tcp.get_dst(), tcp.get_src())),
+                Some(Layers::Udp(udp)) => Some((udp.get_dst(), udp.get_src())),
+                _ => None,
+            };
+            match key {
+                Some((src_port, dst_port)) => self
+                    .add_dont_fragment_drop(SocketAddrV4::new(ipv4.get_src(), dst_port), src_port),
+                None => {
+                    self.ipv4_dont_fragment_drop_count += 1;
+                    metrics::IPV4_DONT_FRAGMENT_DROPS.increase();
+                }
+            }
+            debug!(
+                "drop {} for exceeding MTU without fragmentation ({} dropped)",
+                indicator.brief(),
+                self.ipv4_dont_fragment_drop_count
+            );
+            event::drop_packet(DropReason::MtuExceeded, LayerTypes::Ipv4);
+
+            return Ok(());
+        }
+
+        // Fragments except the last one are aligned to 8 Bytes
+        let max_length = (self.mtu as usize - ipv4.get_size()) / 8 * 8;
+        let mut n = 0;
+        while n < payload.len() {
+            let length = min(max_length, payload.len() - n);
+            let ipv4 = Ipv4::fragment(ipv4, (n / 8) as u16, n + length < payload.len());
+            let fragment = Indicator::with_vlan(
+                indicator.get_link().clone(),
+                indicator.vlan.clone(),
+                Some(Layers::Ipv4(ipv4)),
+                None,
+            );
+
+            // Serialize
+            let size = fragment.get_size();
+            let mut buffer = self.buffer_pool.take(size + length);
+            let result = match fragment.serialize_with_payload(&mut buffer, &payload[n..n + length])
+            {
+                Ok(m) => {
+                    if let Some(mode) = self.verify_tx {
+                        verify_frame(&fragment, &buffer[..m], mode);
+                    }
+                    let result = self.tx.lock().unwrap().send(&buffer[..m], false);
+                    if result.is_ok() {
+                        self.count_wire(indicator, m);
+                    }
+
+                    result
+                }
+                Err(e) => Err(e.into()),
+            };
+            self.buffer_pool.put(buffer);
+
+            // Send
+            result?;
+            debug!(
+                "send to pcap: {} ({} + {} Bytes)",
+                fragment.describe(),
+                size,
+                length
+            );
+
+            n += length;
+        }
+
+        Ok(())
+    }
+}
+
+/// Serializes the `Indicator` with the payload into a buffer taken from the pool. Returns the
+/// buffer, which should be put back to the pool, and the size of the frame in it.
+fn serialize_frame(
+    pool: &mut BufferPool,
+    indicator: &Indicator,
+    payload: Option<&[u8]>,
+) -> (Vec<u8>, io::Result<usize>) {
+    let size = indicator.get_size();
+    let mut buffer;
+    let result = match payload {
+        Some(payload) => {
+            buffer = pool.take(size + payload.len());
+            indicator.serialize_with_payload(&mut buffer, payload)
+        }
+        None => {
+            buffer = pool.take(size);
+            indicator.serialize(&mut buffer)
+        }
+    };
+
+    (buffer, result.map_err(|e| e.into()))
+}
+
+/// Verifies a frame serialized from the `Indicator` before it is transmitted. A frame failing the
+/// verification is logged with its differences and its bytes, and the application aborts in the
+/// fatal mode.
+fn verify_frame(indicator: &Indicator, frame: &[u8], mode: VerifyMode) {
+    if let Err(ref e) = verify::verify(indicator, frame) {
+        warn!("send {} ({} Bytes): {}", indicator.brief(), frame.len(), e);
+        for line in hex_dump(frame, frame.len()) {
+            warn!("    {}", line);
+        }
+        if mode == VerifyMode::Fatal {
+            process::abort();
+        }
+    }
+}
+
+/// Represents the TCP ACK duplicates before trigger a fast retransmission.
+const DUPLICATES_BEFORE_FAST_RETRANSMISSION: usize = 3;
+/// Represents the interval of timers in milliseconds.
+const TIMER_INTERVAL: u128 = 100;
+/// Represents the timeout of a TCP connection in `TIME_WAIT` in milliseconds.
+const TIME_WAIT_TIMEOUT: u128 = 10000;
+/// Represents the max number of TCP connections closed recently kept.
+const MAX_RECENTLY_CLOSED: usize = 4096;
+/// Represents the time a TCP connection is kept as closed recently, segments of which are
+/// stale rather than of an unknown connection.
+const RECENTLY_CLOSED_TIMEOUT: Duration = Duration::from_secs(120);
+/// Represents the max number of RSTs to TCP segments of connections not found in a second.
+const MAX_UNKNOWN_RSTS: usize = 50;
+/// Represents the max number of logs of TCP segments of connections not found in a second.
+const MAX_UNKNOWN_LOGS: usize = 5;
+/// Represents the interval of TCP keepalive probes in milliseconds.
+const KEEPALIVE_INTERVAL: u128 = 10000;
+/// Represents the TCP keepalive probes unanswered before the connection is closed.
+const KEEPALIVE_PROBES: usize = 5;
+
+/// Represents the default max TCP connections of all sources.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 4096;
+/// Represents the default max TCP connections of a source waiting for the handshake.
+pub const DEFAULT_MAX_HALF_OPEN: usize = 256;
+/// Represents the initial interval of retransmissions of the TCP ACK/SYN of a connection waiting
+/// for the handshake, which is backed off exponentially (RFC 6298).
+const SYN_ACK_INITIAL_INTERVAL: Duration = Duration::from_secs(1);
+/// Represents the default retransmissions of the TCP ACK/SYN before the handshake times out.
+pub const DEFAULT_SYN_ACK_RETRIES: usize = 3;
+/// Represents the default max interval of retransmissions of the TCP ACK/SYN in seconds.
+pub const DEFAULT_SYN_ACK_MAX_INTERVAL: u64 = 8;
+
+/// Represents the default timeout of the handshake with the proxy in seconds.
+pub const DEFAULT_PROXY_TIMEOUT: u64 = 10;
+/// Represents the default max retries of a TCP connection when the proxy is unreachable.
+pub const DEFAULT_CONNECT_RETRIES: usize = 3;
+/// Represents the initial interval between retries of a TCP connection in milliseconds, which is
+/// backed off exponentially.
+const CONNECT_RETRY_INTERVAL: u128 = 500;
+/// Represents the timeout of the retry state of a TCP connection in milliseconds.
+const CONNECT_RETRY_TIMEOUT: u128 = 60000;
+/// Represents the max size of data carried in a TCP SYN which is relayed, larger data is left to
+/// the retransmission of the source.
+const MAX_EARLY_DATA_SIZE: usize = 64 * 1024;
+/// Represents the MSS of TCP connections picked up mid-stream, whose MSS is unknown (RFC 1122).
+const PICKUP_TCP_MSS: u16 = 536;
+/// Represents the consecutive failures before the proxy is considered unreachable.
+const PROXY_FAILURES_BEFORE_PROBE: usize = 3;
+/// Represents the initial interval of probes of an unreachable proxy.
+const PROXY_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// Represents the max interval of probes of an unreachable proxy.
+const MAX_PROXY_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Represents the timeout of waiting for an inbound connection through the proxy in milliseconds.
+const BIND_TIMEOUT: u64 = 60000;
+/// Represents the interval of TCP SYNs of an inbound connection to the source in milliseconds.
+const BIND_SYN_INTERVAL: u128 = 1000;
+/// Represents the TCP SYNs of an inbound connection unanswered before the connection is closed.
+const BIND_SYN_RETRIES: usize = 3;
+
+/// Represents the result of waiting for an inbound connection, with the port of the source and
+/// the address of the peer.
+type BindResult = (u16, Ipv4Addr, io::Result<(TcpStream, SocketAddrV4)>);
+
+/// Represents the default timeout of an idle UDP association in seconds.
+pub const DEFAULT_UDP_TIMEOUT: u64 = 60;
+/// Represents the timeout of an idle UDP association which is only used by DNS in seconds.
+const UDP_DNS_TIMEOUT: u64 = 10;
+/// Represents the min timeout of an idle UDP association detected as QUIC in seconds, QUIC
+/// connections are often idle longer than other UDP flows between their keepalives.
+const UDP_QUIC_TIMEOUT: u64 = 300;
+
+/// Represents the initial UDP port for binding in local.
+const INITIAL_PORT: u16 = 32768;
+/// Represents the max limit of UDP port for binding in local.
+const PORT_COUNT: usize = 64;
+/// Represents the max number of ranges of UDP ports for binding in local, each upstreamer binds
+/// UDP ports in its own range.
+const MAX_PORT_RANGES: usize = (u16::MAX as usize + 1 - INITIAL_PORT as usize) / PORT_COUNT;
+/// Represents the max number of workers.
+pub const MAX_WORKERS: usize = 64;
+/// Represents the max number of sources.
+pub const MAX_SOURCES: usize = MAX_PORT_RANGES;
+
+/// Represents the channel upstream traffic to the proxy of SOCKS or loopback to the source in pcap.
+pub struct Upstreamer {
+    tx: Arc<Mutex<Downstreamer>>,
+    is_tx_src_hardware_addr_set: bool,
+    src_ip_addr: Ipv4Addr,
+    /// Represents the addresses ARP requests of the source are replied for
+    publish: Option<Ipv4Network>,
+    local_ip_addr: Option<Ipv4Addr>,
+    proxy: Arc<dyn ProxyConnector>,
+    rules: Rules,
+    access_list: AccessList,
+    /// Represents the blocklist of destinations, which is checked before other rules
+    blocklist: Option<Arc<BlocklistFile>>,
+    /// Represents the checks of UDP flows against the blocklist, a flow is checked once so a
+    /// reload never changes the flows seen before
+    udp_block_map: LruCache<(u16, SocketAddrV4), Option<BlockAction>>,
+    /// Represents the rules rewriting destinations, which apply before other rules
+    rewrites: Rewrites,
+    checksum_mode: ChecksumMode,
+    /// Represents the tracer of frames captured, which are never formatted if it is not set
+    tracer: Option<Arc<Tracer>>,
+    /// Represents the hook of packets from the source
+    hook: Option<Arc<dyn Hook>>,
+    /// Represents the resolver DNS queries are redirected to
+    dns_resolver: Option<SocketAddrV4>,
+    keepalive: Option<Duration>,
+    /// Represents if TCP_NODELAY is set on streams
+    is_nodelay: bool,
+    /// Represents the rate limits of the traffic of the source
+    limits: Limits,
+    /// Represents the timeout of the handshake with the proxy
+    proxy_timeout: Duration,
+    /// Represents the timeout of the inactivity of streams
+    relay_timeout: Option<Duration>,
+    /// Represents the count of TCP connections failed because the proxy timed out
+    proxy_timeout_count: usize,
+    /// Represents the count of TCP connections reset because the stream was inactive
+    relay_timeout_count: usize,
+    /// Represents the timeout of writes to streams not drained
+    relay_stale: Option<Duration>,
+    /// Represents the count of TCP connections reset because the writes to the stream were not
+    /// drained
+    relay_stale_count: usize,
+    /// Represents the time streams closed for errors are read until their ends
+    linger: Option<Duration>,
+    connect_retries: usize,
+    /// Represents the map mapping a TCP connection to the instant of the last failed attempt
+    /// because of the unreachable proxy and the count of failures
+    tcp_connect_map: HashMap<(u16, SocketAddrV4), (Instant, usize)>,
+    /// Represents the consecutive failures because of the unreachable proxy
+    proxy_failure_count: usize,
+    /// Represents the time since the proxy is unreachable
+    proxy_unreachable_since: Option<SystemTime>,
+    /// Represents if the proxy is reachable again, which is set by the probe
+    is_proxy_reachable: Arc<AtomicBool>,
+    /// Represents the ports the source listens on for inbound connections through the proxy
+    bind_ports: Vec<u16>,
+    /// Represents the responder of port mappings, whose mapped TCP ports are listened on like
+    /// the bind ports
+    igd_server: Option<Arc<igd::Server>>,
+    /// Represents the test responder, which answers its port on the published address in local
+    responder: Option<Arc<Responder>>,
+    /// Represents the set of ports of the source and peers waiting for inbound connections
+    bind_set: HashSet<(u16, Ipv4Addr)>,
+    bind_tx: mpsc::Sender<BindResult>,
+    bind_rx: mpsc::Receiver<BindResult>,
+    /// Represents the index of the worker and the channels of all workers inbound connections
+    /// are forwarded to
+    shard: usize,
+    shard_bind_txs: Vec<mpsc::Sender<BindResult>>,
+    /// Represents the map mapping an inbound connection to the stream, the instant of the last
+    /// SYN and the count of SYNs
+    tcp_bind_map: HashMap<(u16, SocketAddrV4), (TcpStream, Instant, usize)>,
+    udp_timeout: Duration,
+    /// Represents the interval of keepalives of SOCKS associations
+    udp_keepalive: Option<Duration>,
+    /// Represents if an association is closed once its control connection is closed
+    is_udp_assoc_strict: bool,
+    /// Represents the endpoint and the interval of liveness probes through idle relays
+    udp_probe: Option<(SocketAddrV4, Duration)>,
+    udp_nat: UdpNat,
+    is_udp_fragmentation: bool,
+    /// Represents if the path MTU to the relay is probed once an association is bound
+    is_udp_pmtu: bool,
+    is_udp_send_fragmentation: bool,
+    streams: HashMap<(u16, SocketAddrV4), StreamWorker>,
+    tcp_sequence_map: HashMap<(u16, SocketAddrV4), u32>,
+    /// Represents the initial sequence of the source in TCP connections, which tells a TCP SYN
+    /// retransmitted from the one of a new connection
+    tcp_initial_sequence_map: HashMap<(u16, SocketAddrV4), u32>,
+    tcp_acknowledgement_map: HashMap<(u16, SocketAddrV4), u32>,
+    tcp_duplicate_map: HashMap<(u16, SocketAddrV4), usize>,
+    tcp_cache_map: HashMap<(u16, SocketAddrV4), RandomCacher>,
+    tcp_fin_set: HashSet<(u16, SocketAddrV4)>,
+    /// Represents the set of TCP connections whose destination closes the stream before the
+    /// source sends its FIN
+    tcp_eof_set: HashSet<(u16, SocketAddrV4)>,
+    tcp_time_wait_map: HashMap<(u16, SocketAddrV4), (Instant, u32)>,
+    /// Represents the map mapping a TCP connection closed recently to the instant it is closed,
+    /// so its stale segments are told from segments of connections never known
+    tcp_closed_map: LruCache<(u16, SocketAddrV4), Instant>,
+    /// Represents the start of the second, the RSTs sent and the logs written to TCP segments of
+    /// connections not found in the second, and the logs suppressed
+    tcp_unknown_limit: (Instant, usize, usize, usize),
+    /// Represents the map mapping a TCP connection to the instant the source sent the last
+    /// segment
+    tcp_activity_map: HashMap<(u16, SocketAddrV4), Instant>,
+    /// Represents the map mapping a TCP connection to the instant of the last keepalive probe and
+    /// the count of probes
+    tcp_keepalive_map: HashMap<(u16, SocketAddrV4), (Instant, usize)>,
+    max_connections: usize,
+    max_half_open: usize,
+    is_evict_idle: bool,
+    /// Represents the rules of destinations whose TCP connections are picked up mid-stream
+    pickup_established: Vec<Access>,
+    /// Represents the map mapping a TCP connection waiting for the handshake to the instant of
+    /// the last ACK/SYN sent and the count of retransmissions of it
+    tcp_half_open_map: HashMap<(u16, SocketAddrV4), (Instant, usize)>,
+    syn_ack_retries: usize,
+    syn_ack_max_interval: Duration,
+    /// Represents the clock of timers
+    clock: Arc<dyn Clock>,
+    timer_instant: Instant,
+    /// Represents the generation of the last connection table dumped
+    table_generation: usize,
+    /// Represents the generation of the settings reloaded in effect
+    settings_generation: usize,
+    /// Represents the bytes of the frame being handled, including headers, which is the
+    /// reassembled one of fragments
+    frame_size: usize,
+    /// Represents the initial UDP port for binding in local of the worker
+    initial_port: u16,
+    datagrams: Vec<Option<DatagramWorker>>,
+    /// Represents the map mapping a source port to a local port (datagram)
+    datagram_map: Vec<u16>,
+    /// Represents the map mapping a source port to a datagram sent directly
+    direct_datagrams: HashMap<u16, DatagramWorker>,
+    /// Represents the map mapping a source port to the QUIC connection of its datagram
+    quic_map: HashMap<u16, quic::Flow>,
+    /// Represents the LRU mapping a local port to a source port
+    udp_lru: LruCache<u16, u16>,
+    /// Represents the count of TCP segments and UDP datagrams dropped because of invalid checksums
+    checksum_error_count: usize,
+    /// Represents the MTU of the device, TCP segments exceeding it are coalesced by segmentation
+    /// offload and split before they are handled
+    mtu: usize,
+    /// Represents if segmentation offload of the source has been detected
+    is_segmentation_offload: bool,
+    /// Represents if the warning of UDP not supported by the SOCKS proxy has been shown
+    is_udp_unsupported_warned: bool,
+    defrag: Defraggler,
+    /// Represents if the upstreamer is draining for shutting down
+    is_draining: bool,
+    /// Represents the count of TCP connections when the draining starts
+    drain_count: usize,
+}
+
+impl Upstreamer {
+    /// Creates a new `Upstreamer`.
+    pub fn new(
+        tx: Arc<Mutex<Downstreamer>>,
+        src_ip_addr: Ipv4Addr,
+        publish: Option<Ipv4Network>,
+        proxy: Arc<dyn ProxyConnector>,
+    ) -> Upstreamer {
+        // A single published address is the local address, which answers pings
+        let local_ip_addr = match publish {
+            Some(publish) if publish.prefix() == 32 => Some(publish.ip()),
+            _ => None,
+        };
+        let (bind_tx, bind_rx) = mpsc::channel();
+        let mtu = tx.lock().unwrap().get_mtu() as usize;
+        let mut upstreamer = Upstreamer {
+            tx,
+            is_tx_src_hardware_addr_set: false,
+            src_ip_addr,
+            publish,
+            local_ip_addr,
+            proxy,
+            rules: Rules::default(),
+            access_list: AccessList::new(),
+            blocklist: None,
+            udp_block_map: LruCache::new(MAX_UDP_BLOCK_FLOWS),
+            rewrites: Rewrites::new(),
+            checksum_mode: ChecksumMode::Auto,
+            tracer: None,
+            hook: None,
+            dns_resolver: None,
+            keepalive: None,
+            is_nodelay: false,
+            limits: Limits::default(),
+            proxy_timeout: Duration::from_secs(DEFAULT_PROXY_TIMEOUT),
+            relay_timeout: None,
+            proxy_timeout_count: 0,
+            relay_timeout_count: 0,
+            relay_stale: None,
+            relay_stale_count: 0,
+            linger: None,
+            connect_retries: DEFAULT_CONNECT_RETRIES,
+            tcp_connect_map: HashMap::new(),
+            proxy_failure_count: 0,
+            proxy_unreachable_since: None,
+            is_proxy_reachable: Arc::new(AtomicBool::new(true)),
+            bind_ports: Vec::new(),
+            igd_server: None,
+            responder: None,
+            bind_set: HashSet::new(),
+            bind_tx,
+            bind_rx,
+            shard: 0,
+            shard_bind_txs: Vec::new(),
+            tcp_bind_map: HashMap::new(),
+            udp_timeout: Duration::from_secs(DEFAULT_UDP_TIMEOUT),
+            udp_keepalive: None,
+            is_udp_assoc_strict: false,
+            udp_probe: None,
+            udp_nat: UdpNat::FullCone,
+            is_udp_fragmentation: true,
+            is_udp_pmtu: false,
+            is_udp_send_fragmentation: false,
+            streams: HashMap::new(),
+            tcp_sequence_map: HashMap::new(),
+            tcp_initial_sequence_map: HashMap::new(),
+            tcp_acknowledgement_map: HashMap::new(),
+            tcp_duplicate_map: HashMap::new(),
+            tcp_cache_map: HashMap::new(),
+            tcp_fin_set: HashSet::new(),
+            tcp_eof_set: HashSet::new(),
+            tcp_time_wait_map: HashMap::new(),
+            tcp_closed_map: LruCache::new(MAX_RECENTLY_CLOSED),
+            tcp_unknown_limit: (Instant::now(), 0, 0, 0),
+            tcp_activity_map: HashMap::new(),
+            tcp_keepalive_map: HashMap::new(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_half_open: DEFAULT_MAX_HALF_OPEN,
+            is_evict_idle: false,
+            pickup_established: Vec::new(),
+            tcp_half_open_map: HashMap::new(),
+            syn_ack_retries: DEFAULT_SYN_ACK_RETRIES,
+            syn_ack_max_interval: Duration::from_secs(DEFAULT_SYN_ACK_MAX_INTERVAL),
+            clock: Arc::new(MonotonicClock::new()),
+            timer_instant: Instant::now(),
+            table_generation: TABLE_GENERATION.load(Ordering::Relaxed),
+            // Settings reloaded before it is created are taken at its first frame
+            settings_generation: 0,
+            frame_size: 0,
+            initial_port: INITIAL_PORT,
+            datagrams: (0..PORT_COUNT).map(|_| None).collect(),
+            datagram_map: vec![0u16; u16::MAX as usize],
+            direct_datagrams: HashMap::new(),
+            quic_map: HashMap::new(),
+            udp_lru: LruCache::new(PORT_COUNT),
+            checksum_error_count: 0,
+            mtu,
+            is_segmentation_offload: false,
+            is_udp_unsupported_warned: false,
+            defrag: Defraggler::new(),
+            is_draining: false,
+            drain_count: 0,
+        };
+        if let Some(local_ip_addr) = local_ip_addr {
+            upstreamer
+                .tx
+                .lock()
+                .unwrap()
+                .set_local_ip_addr(local_ip_addr);
+        }
+        for i in 0..PORT_COUNT {
+            upstreamer.udp_lru.put(i as u16, 0);
+        }
+
+        upstreamer
+    }
+
+    /// Sets the index of the worker and the channels of inbound connections of all workers of
+    /// the source.
+    fn set_shard(&mut self, shard: usize, bind_txs: Vec<mpsc::Sender<BindResult>>) {
+        self.shard = shard;
+        self.shard_bind_txs = bind_txs;
+    }
+
+    /// Sets the index of the range of UDP ports for binding in local, the upstreamer binds UDP
+    /// ports after the ones of the previous ranges.
+    fn set_port_range(&mut self, index: usize) {
+        self.initial_port = INITIAL_PORT + (index * PORT_COUNT) as u16;
+    }
+
+    /// Sets the rules of destinations.
+    pub fn set_rules(&mut self, rules: Rules) {
+        self.rules = rules;
+        trace!("set {} rules", self.rules.get_rules().len());
+    }
+
+    /// Sets the access lists of relayed traffic.
+    pub fn set_access_list(&mut self, access_list: AccessList) {
+        self.access_list = access_list;
+        trace!(
+            "set access lists of {} allowed and {} denied",
+            self.access_list.get_allow().len(),
+            self.access_list.get_deny().len()
+        );
+    }
+
+    /// Sets the blocklist of destinations, which is shared by the upstreamers and reloaded in the
+    /// background.
+    pub fn set_blocklist(&mut self, blocklist: Arc<BlocklistFile>) {
+        trace!("set blocklist {}", blocklist.get_path().display());
+        self.blocklist = Some(blocklist);
+    }
+
+    /// Get the action of the destination if it is blocked.
+    fn get_block_action(&self, dst: SocketAddrV4) -> Option<BlockAction> {
+        let blocklist = self.blocklist.as_ref()?.get();
+        if blocklist.is_blocked(dst) {
+            Some(blocklist.get_action())
+        } else {
+            None
+        }
+    }
+
+    /// Sets the rules rewriting destinations.
+    pub fn set_rewrites(&mut self, rewrites: Rewrites) {
+        self.rewrites = rewrites;
+        trace!("set {} rewrites", self.rewrites.get_rewrites().len());
+    }
+
+    /// Sets the mode of checksums of TCP segments and UDP datagrams from the source.
+    pub fn set_checksum_mode(&mut self, mode: ChecksumMode) {
+        self.checksum_mode = mode;
+        trace!("set checksum mode to {}", mode);
+    }
+
+    /// Sets the tracer of frames captured.
+    pub fn set_tracer(&mut self, tracer: Arc<Tracer>) {
+        self.tracer = Some(tracer);
+        trace!("set tracer of {}", self.src_ip_addr);
+    }
+
+    /// Sets the hook of packets from the source.
+    pub fn set_hook(&mut self, hook: Arc<dyn Hook>) {
+        self.hook = Some(hook);
+        trace!("set hook of {}", self.src_ip_addr);
+    }
+
+    /// Sets the clock of timers, including the reassembly of IPv4 datagrams.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.timer_instant = clock.now();
+        self.defrag.set_clock(Arc::clone(&clock));
+        self.clock = clock;
+        trace!("set clock of {}", self.src_ip_addr);
+    }
+
+    /// Sets the resolver which DNS queries to any destination are redirected to.
+    pub fn set_dns_resolver(&mut self, resolver: SocketAddrV4) {
+        self.dns_resolver = Some(resolver);
+        trace!("set DNS resolver to {}", resolver);
+    }
+
+    /// Sets the idle time before TCP keepalive probes, which enables the keepalive of TCP
+    /// connections.
+    pub fn set_keepalive(&mut self, keepalive: Duration) {
+        self.keepalive = Some(keepalive);
+        trace!("set TCP keepalive to {} s", keepalive.as_secs());
+    }
+
+    /// Sets the max TCP connections of all sources and the max TCP connections of the source
+    /// waiting for the handshake, new connections exceeding the limits are reset.
+    pub fn set_max_connections(&mut self, max_connections: usize, max_half_open: usize) {
+        self.max_connections = max_connections;
+        self.max_half_open = max_half_open;
+        trace!(
+            "set max TCP connections to {} and {} half-open",
+            max_connections,
+            max_half_open
+        );
+    }
+
+    /// Sets the retransmissions of the TCP ACK/SYN of a connection waiting for the handshake and
+    /// the max interval between them. The interval starts from 1 second and is doubled up to the
+    /// max interval, and the connection is closed if the source does not acknowledge the last
+    /// retransmission in the interval.
+    pub fn set_syn_ack_retries(&mut self, retries: usize, max_interval: Duration) {
+        self.syn_ack_retries = retries;
+        self.syn_ack_max_interval = max_interval;
+        trace!(
+            "set TCP ACK/SYN retries to {} up to {} s apart",
+            retries,
+            max_interval.as_secs()
+        );
+    }
+
+    /// Sets if the least recently active TCP connection of the source is closed for a new one
+    /// when connections are full.
+    pub fn set_evict_idle(&mut self, is_evict_idle: bool) {
+        self.is_evict_idle = is_evict_idle;
+        trace!("set TCP eviction to {}", is_evict_idle);
+    }
+
+    /// Sets the rules of destinations whose TCP connections are picked up mid-stream, like
+    /// connections established before a restart.
+    pub fn set_pickup_established(&mut self, pickup_established: Vec<Access>) {
+        self.pickup_established = pickup_established;
+        trace!(
+            "set {} rules of picking up TCP connections",
+            self.pickup_established.len()
+        );
+    }
+
+    /// Sets if TCP_NODELAY is set on streams, which disables the Nagle's algorithm of the
+    /// connections to the proxy and destinations.
+    pub fn set_nodelay(&mut self, is_nodelay: bool) {
+        self.is_nodelay = is_nodelay;
+        trace!("set TCP no delay to {}", is_nodelay);
+    }
+
+    /// Sets the rate limits, the traffic of the source is limited by the global limits and the
+    /// limits of the source in the shaper.
+    pub fn set_shaper(&mut self, shaper: &Shaper) {
+        self.limits = shaper.get_limits(self.src_ip_addr);
+        trace!("set rate limits of {}", self.src_ip_addr);
+    }
+
+    /// Sets the timeout of the handshake with the proxy, including the reply to the request.
+    pub fn set_proxy_timeout(&mut self, timeout: Duration) {
+        self.proxy_timeout = timeout;
+        trace!("set proxy timeout to {} s", timeout.as_secs());
+    }
+
+    /// Sets the timeout of the inactivity of streams, which enables the reset of TCP connections
+    /// without data read or written on the streams for the timeout.
+    pub fn set_relay_timeout(&mut self, timeout: Duration) {
+        self.relay_timeout = Some(timeout);
+        trace!("set relay timeout to {} s", timeout.as_secs());
+    }
+
+    /// Sets the timeout of writes to streams not drained, which enables the reset of TCP
+    /// connections whose proxies are dead without a RST or a FIN. Streams are also probed by
+    /// aggressive keepalive.
+    pub fn set_relay_stale(&mut self, timeout: Duration) {
+        self.relay_stale = Some(timeout);
+        trace!("set relay stale timeout to {} s", timeout.as_secs());
+    }
+
+    /// Sets the time streams closed for errors are read until their ends after they are shut
+    /// down in the write half, so the FIN reaches the proxy. A zero linger resets the streams
+    /// instead.
+    pub fn set_linger(&mut self, linger: Duration) {
+        self.linger = Some(linger);
+        trace!("set linger to {} s", linger.as_secs());
+    }
+
+    /// Sets the max retries of a TCP connection when the proxy is unreachable.
+    pub fn set_connect_retries(&mut self, retries: usize) {
+        self.connect_retries = retries;
+        trace!("set connect retries to {}", retries);
+    }
+
+    /// Sets the ports the source listens on. A TCP connection to a destination through the proxy
+    /// makes the proxy listen for inbound connections from the destination to the ports.
+    pub fn set_bind_ports(&mut self, ports: Vec<u16>) {
+        self.bind_ports = ports;
+        trace!("set {} bind ports", self.bind_ports.len());
+    }
+
+    /// Sets the responder of port mappings. Connections to its HTTP port on the published
+    /// address are redirected to it, TCP ports mapped by the source are listened on like the bind
+    /// ports, and UDP associations of ports mapped accept datagrams from any peer.
+    pub fn set_igd_server(&mut self, server: Arc<igd::Server>) {
+        self.igd_server = Some(server);
+        trace!("set IGD server");
+    }
+
+    /// Sets the test responder. Datagrams and connections to its port on the published address
+    /// are redirected to it directly, without the proxy.
+    pub fn set_responder(&mut self, responder: Arc<Responder>) {
+        trace!("set test responder on port {}", responder.get_port());
+        self.responder = Some(responder);
+    }
+
+    /// Get the test responder if the destination is its port on the published address.
+    fn get_responder(&self, dst: SocketAddrV4) -> Option<&Responder> {
+        match self.responder {
+            Some(ref responder)
+                if Some(*dst.ip()) == self.local_ip_addr && dst.port() == responder.get_port() =>
+            {
+                Some(responder)
+            }
+            _ => None,
+        }
+    }
+
+    /// Sets the timeout of idle UDP associations.
+    pub fn set_udp_timeout(&mut self, timeout: Duration) {
+        self.udp_timeout = timeout;
+        trace!("set UDP timeout to {} s", timeout.as_secs());
+    }
+
+    /// Sets the interval of keepalives of SOCKS associations, which enables the keepalive of
+    /// their control connections and keepalive datagrams through idle relays.
+    pub fn set_udp_keepalive(&mut self, keepalive: Duration) {
+        self.udp_keepalive = Some(keepalive);
+        trace!("set UDP keepalive to {} s", keepalive.as_secs());
+    }
+
+    /// Sets if SOCKS associations are closed once their control connections are closed (RFC
+    /// 1928), or they survive as long as their relays still relay datagrams.
+    pub fn set_udp_assoc_strict(&mut self, is_udp_assoc_strict: bool) {
+        self.is_udp_assoc_strict = is_udp_assoc_strict;
+        trace!("set UDP association strict to {}", is_udp_assoc_strict);
+    }
+
+    /// Sets the endpoint and the interval of liveness probes through idle relays of SOCKS
+    /// associations. An association whose relay never replies a probe is re-established with
+    /// the same local port and peers.
+    pub fn set_udp_probe(&mut self, probe: SocketAddrV4, interval: Duration) {
+        self.udp_probe = Some((probe, interval));
+        trace!("set UDP probe to {} every {} s", probe, interval.as_secs());
+    }
+
+    /// Sets the filtering of datagrams to the source from peers of its UDP associations.
+    pub fn set_udp_nat(&mut self, nat: UdpNat) {
+        self.udp_nat = nat;
+        trace!("set UDP NAT to {}", nat);
+    }
+
+    /// Sets if fragmented SOCKS5 UDP datagrams are reassembled, or they will be dropped.
+    pub fn set_udp_fragmentation(&mut self, is_udp_fragmentation: bool) {
+        self.is_udp_fragmentation = is_udp_fragmentation;
+        trace!("set UDP fragmentation to {}", is_udp_fragmentation);
+    }
+
+    /// Sets if the path MTU to the relay of UDP associations is probed once they are bound.
+    pub fn set_udp_pmtu(&mut self, is_udp_pmtu: bool) {
+        self.is_udp_pmtu = is_udp_pmtu;
+        trace!("set UDP path MTU probe to {}", is_udp_pmtu);
+    }
+
+    /// Sets if UDP datagrams larger than the path MTU to the relay are sent in SOCKS5 fragments,
+    /// or they will be dropped with ICMPv4 fragmentation needed to the source.
+    pub fn set_udp_send_fragmentation(&mut self, is_udp_send_fragmentation: bool) {
+        self.is_udp_send_fragmentation = is_udp_send_fragmentation;
+        trace!(
+            "set UDP send fragmentation to {}",
+            is_udp_send_fragmentation
+        );
+    }
+
+    /// Sets the timeout of the reassembly of IPv4 datagrams.
+    pub fn set_reassembly_timeout(&mut self, timeout: Duration) {
+        self.defrag.set_timeout(timeout);
+        trace!("set IPv4 reassembly timeout to {} s", timeout.as_secs());
     }
 
     /// Opens an `Interface` for upstream.
     pub fn open(&mut self, rx: &mut Receiver) -> io::Result<()> {
+        let mut pool = FramePool::new();
+        loop {
+            self.handle_timers();
+
+            match rx.next_frame() {
+                Ok(frame) => self.handle_frame(&pool.copy_from(frame)),
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
+                        continue;
+                    }
+                    // The capture may be interrupted by signals like SIGUSR1
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+        }
+    }
+
+    /// Handles a frame captured from the source. Payloads of the frame are never copied until
+    /// they are relayed.
+    fn handle_frame(&mut self, frame: &Frame) {
+        self.handle_settings();
+
+        match Indicator::from_shared(frame) {
+            Ok(ref indicator) => {
+                if let Some(ref tracer) = self.tracer {
+                    tracer.trace(indicator, frame, Direction::Received);
+                }
+                if let Some(t) = indicator.get_network_type() {
+                    match t {
+                        LayerTypes::Arp => {
+                            if let Err(ref e) = self.handle_arp(indicator) {
+                                warn!("handle {}: {}", indicator.brief(), e);
+                            }
+                        }
+                        LayerTypes::Ipv4 => {
+                            if let Err(ref e) = self.handle_ipv4(indicator, frame) {
+                                warn!("handle {}: {}", indicator.brief(), e);
+                            }
+                        }
+                        LayerTypes::Ipv6 => {
+                            // IPv6 is not redirected yet
+                            trace!("ignore {}", indicator.brief());
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+            }
+            // Stacked VLANs are not supported
+            Err(ref e @ ParseError::StackedTag { .. }) => {
+                debug!("drop frame ({} Bytes): {}", frame.len(), e);
+                event::drop_packet(DropReason::StackedVlan, LayerTypes::Vlan);
+            }
+            Err(ref e) => {
+                trace!("parse frame ({} Bytes): {}", frame.len(), e);
+                event::drop_packet(DropReason::Malformed, e.get_type());
+            }
+        };
+    }
+
+    /// Handles ARP of the source. Requests of the published addresses are answered, and
+    /// announcements refresh the hardware address of the source. Probes, whose senders are
+    /// unspecified, are answered by the dispatcher, and the others are ignored quietly.
+    fn handle_arp(&mut self, indicator: &Indicator) -> io::Result<()> {
+        let arp = match indicator.get_arp() {
+            Some(arp) => arp,
+            None => return Ok(()),
+        };
+        if arp.get_src() != self.src_ip_addr {
+            return Ok(());
+        }
+
+        match arp.get_kind() {
+            Some(ArpKind::Request) => {
+                let dst = arp.get_dst();
+                match self.publish {
+                    Some(publish) if publish.contains(dst) && dst != self.src_ip_addr => {}
+                    _ => return Ok(()),
+                }
+                debug!(
+                    "receive from pcap: {} ({} Bytes)",
+                    indicator.describe(),
+                    indicator.get_size()
+                );
+
+                // Set downstreamer's hardware address
+                if !self.is_tx_src_hardware_addr_set {
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    tx_locked.set_src_hardware_addr(arp.get_src_hardware_addr());
+                    tx_locked.set_src_vlan(indicator.get_vlan().cloned());
+                    self.is_tx_src_hardware_addr_set = true;
+                }
+
+                // Send
+                self.tx.lock().unwrap().send_arp_reply(dst)?
+            }
+            // The source is alive, and its hardware address may change on the link up
+            Some(ArpKind::Announcement) => {
+                let hardware_addr = arp.get_src_hardware_addr();
+                let mut tx_locked = self.tx.lock().unwrap();
+                tx_locked.set_src_hardware_addr(hardware_addr);
+                tx_locked.set_src_vlan(indicator.get_vlan().cloned());
+                self.is_tx_src_hardware_addr_set = true;
+                trace!(
+                    "refresh hardware address of {} to {} by ARP announcement",
+                    self.src_ip_addr,
+                    hardware_addr
+                );
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn handle_ipv4(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<()> {
+        if let Some(ref ipv4) = indicator.get_ipv4() {
+            if ipv4.get_src() == self.src_ip_addr {
+                debug!(
+                    "receive from pcap: {} ({} Bytes)",
+                    indicator.describe(),
+                    indicator.get_size()
+                );
+                // Set downstreamer's hardware address
+                if !self.is_tx_src_hardware_addr_set {
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    tx_locked.set_src_hardware_addr(indicator.get_ethernet().unwrap().get_src());
+                    tx_locked.set_src_vlan(indicator.get_vlan().cloned());
+                    self.is_tx_src_hardware_addr_set = true;
+                }
+
+                // Packets are forwarded as a router except those to the local IP address
+                if Some(ipv4.get_dst()) != self.local_ip_addr && ipv4.get_ttl() <= 1 {
+                    debug!("drop {} for TTL exceeded", indicator.brief());
+                    event::drop_packet(DropReason::TtlExceeded, LayerTypes::Ipv4);
+
+                    // Send ICMPv4 time exceeded
+                    return self.tx.lock().unwrap().send_icmpv4_time_exceeded(indicator);
+                }
+
+                if ipv4.is_fragment() {
+                    // Fragmentation
+                    let evicted_count = self.defrag.get_evicted_count();
+                    let invalid_count = self.defrag.get_invalid_count();
+                    let frag = self.defrag.add(indicator, buffer);
+                    if self.defrag.get_evicted_count() > evicted_count {
+                        debug!(
+                            "evict IPv4 datagrams in reassembly for exceeding the memory or count limit ({} evicted)",
+                            self.defrag.get_evicted_count()
+                        );
+                    }
+                    if self.defrag.get_invalid_count() > invalid_count {
+                        debug!(
+                            "drop datagram of {} for invalid fragmentations ({} dropped)",
+                            indicator.brief(),
+                            self.defrag.get_invalid_count()
+                        );
+                        event::drop_packet(DropReason::Reassembly, LayerTypes::Ipv4);
+                    }
+                    let frag = match frag {
+                        Some(frag) => frag,
+                        None => return Ok(()),
+                    };
+                    let (indicator, frame) = match frag.concatenate() {
+                        Some(datagram) => datagram,
+                        None => {
+                            debug!("drop reassembled datagram of {}", indicator.brief());
+                            event::drop_packet(DropReason::Reassembly, LayerTypes::Ipv4);
+
+                            return Ok(());
+                        }
+                    };
+                    self.count_wire(&indicator, frame.len());
+                    if !self.accept_checksum(&indicator, &frame[indicator.get_link_size()..]) {
+                        return Ok(());
+                    }
+
+                    self.handle_transport(&indicator)?;
+                } else if let Some(segments) = packet::segment(indicator, self.mtu) {
+                    // The checksum of the coalesced segment is checked before it is split
+                    if !self.accept_checksum(indicator, &buffer[indicator.get_link_size()..]) {
+                        self.count_wire(indicator, buffer.len());
+                        return Ok(());
+                    }
+                    self.count_segmentation_offload(indicator, segments.len());
+
+                    for (indicator, frame) in &segments {
+                        self.count_wire(indicator, frame.len());
+                        self.handle_transport(indicator)?;
+                    }
+                } else {
+                    self.count_wire(indicator, buffer.len());
+                    if !self.accept_checksum(indicator, &buffer[indicator.get_link_size()..]) {
+                        return Ok(());
+                    }
+
+                    self.handle_transport(indicator)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts the bytes of the frame of an IPv4 packet from the source, including headers. The
+    /// frame is the reassembled one of fragments.
+    fn count_wire(&mut self, indicator: &Indicator, n: usize) {
+        self.frame_size = n;
+        match indicator.get_transport_type() {
+            Some(LayerTypes::Tcp) => metrics::count_wire_up(Protocol::Tcp, n),
+            Some(LayerTypes::Udp) => metrics::count_wire_up(Protocol::Udp, n),
+            _ => {}
+        }
+    }
+
+    /// Counts a TCP segment coalesced by segmentation offload, which is split into the given
+    /// count of segments. The segmentation offload is logged once detected.
+    fn count_segmentation_offload(&mut self, indicator: &Indicator, count: usize) {
+        metrics::COALESCED_SEGMENTS.increase();
+        if !self.is_segmentation_offload {
+            info!(
+                "Detect segmentation offload in {}, coalesced segments are split",
+                indicator.brief()
+            );
+            self.is_segmentation_offload = true;
+        }
+        trace!(
+            "split {} ({} Bytes) into {} segments",
+            indicator.brief(),
+            indicator.get_size(),
+            count
+        );
+    }
+
+    /// Handles the transport layer of an IPv4 packet from the source, the packet is passed to the
+    /// hook first if it is set.
+    fn handle_transport(&mut self, indicator: &Indicator) -> io::Result<()> {
+        let replaced;
+        let indicator = match self.hook {
+            Some(ref hook) => match hook.on_packet(indicator, Direction::Received) {
+                HookAction::Continue => indicator,
+                HookAction::Drop => {
+                    debug!("drop {} by hook", indicator.brief());
+                    event::drop_packet(
+                        DropReason::Hook,
+                        indicator.get_transport_type().unwrap_or(LayerTypes::Ipv4),
+                    );
+
+                    return Ok(());
+                }
+                HookAction::ReplacePayload(payload) => {
+                    replaced = hook::replace_payload(indicator, &payload)?;
+                    &replaced
+                }
+            },
+            None => indicator,
+        };
+
+        if let Some(t) = indicator.get_transport_type() {
+            match t {
+                LayerTypes::Tcp => self.handle_tcp(indicator)?,
+                LayerTypes::Udp => self.handle_udp(indicator)?,
+                LayerTypes::Icmpv4 => self.handle_icmpv4(indicator)?,
+                _ => unreachable!(),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns if the TCP or UDP checksum of the serialized IPv4 packet is accepted in the mode of
+    /// checksums. The auto mode turns into fixing checksums once checksum offloading is detected.
+    fn accept_checksum(&mut self, indicator: &Indicator, ip: &[u8]) -> bool {
+        if self.checksum_mode == ChecksumMode::Fix || self.checksum_mode == ChecksumMode::Ignore {
+            return true;
+        }
+
+        match packet::check_checksum(ip) {
+            ChecksumState::Valid => true,
+            ChecksumState::Partial if self.checksum_mode == ChecksumMode::Auto => {
+                info!(
+                    "Detect checksum offloading in {}, checksums are fixed",
+                    indicator.brief()
+                );
+                self.checksum_mode = ChecksumMode::Fix;
+
+                true
+            }
+            _ => {
+                self.checksum_error_count += 1;
+                debug!(
+                    "drop {} for invalid checksum ({} dropped)",
+                    indicator.brief(),
+                    self.checksum_error_count
+                );
+                event::drop_packet(
+                    DropReason::InvalidChecksum,
+                    indicator.get_transport_type().unwrap_or(LayerTypes::Ipv4),
+                );
+
+                false
+            }
+        }
+    }
+
+    fn handle_tcp(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(ref tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => {
+                    // IPv6 is not redirected yet
+                    trace!("ignore {}", indicator.brief());
+
+                    return Ok(());
+                }
+            };
+            let key = (tcp.get_src(), dst);
+
+            // Any segment from the source means the connection is alive
+            if let Some(stream) = self.streams.get(&key) {
+                stream.add_wire_up(self.frame_size);
+                self.tcp_activity_map.insert(key, self.clock.now());
+                self.tcp_keepalive_map.remove(&key);
+            }
+
+            // Drop stale segments in TIME_WAIT
+            if let Some((_, sequence)) = self.tcp_time_wait_map.get(&key).cloned() {
+                if tcp.is_syn() && !tcp.is_ack() && seq_lt(sequence, tcp.get_sequence()) {
+                    // A new connection
+                    self.tcp_time_wait_map.remove(&key);
+                } else {
+                    if tcp.is_fin() {
+                        // Retransmission
+                        let mut tx_locked = self.tx.lock().unwrap();
+                        #[allow(deprecated)]
+                        tx_locked.set_tcp_sequence(dst, tcp.get_src(), tcp.get_acknowledgement());
+                        tx_locked.set_tcp_acknowledgement(
+                            dst,
+                            tcp.get_src(),
+                            tcp.get_sequence().wrapping_add(1),
+                        );
+                        // Send ACK0
+                        tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
+
+                        // Clean up
+                        tx_locked.remove(dst, tcp.get_src());
+                    } else {
+                        trace!("ignore {} in TIME_WAIT", indicator.brief());
+                    }
+
+                    return Ok(());
+                }
+            }
+
+            if self.tcp_bind_map.contains_key(&key) {
+                return self.handle_tcp_bind(indicator);
+            }
+
+            if tcp.is_illegal() {
+                return self.handle_tcp_illegal(indicator);
+            } else if tcp.is_rst() {
+                self.handle_tcp_rst(indicator);
+            } else if tcp.is_ack() {
+                return self.handle_tcp_ack(indicator);
+            } else if tcp.is_syn() {
+                // Pure TCP SYN
+                return self.handle_tcp_syn(indicator);
+            } else if tcp.is_fin() {
+                // Pure TCP FIN, its data is delivered before the FIN
+                let is_alive = match self.streams.get(&key) {
+                    Some(stream) => !stream.is_closed(),
+                    None => false,
+                };
+                if is_alive && !tcp.get_payload().is_empty() {
+                    self.handle_tcp_data(indicator)?;
+                }
+                return self.handle_tcp_fin(indicator);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_tcp_ack(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return Ok(()),
+            };
+            let key = (tcp.get_src(), dst);
+            let is_exist = self.streams.get(&key).is_some();
+            let is_alive = match self.streams.get(&key) {
+                Some(ref stream) => !stream.is_closed(),
+                None => false,
+            };
+
+            if is_exist {
+                if is_alive {
+                    // The handshake is completed
+                    self.tcp_half_open_map.remove(&key);
+
+                    // ACK, a window probe is never a duplicate ACK
+                    let is_probe = self.is_tcp_window_probe(indicator);
+                    if !is_probe {
+                        self.update_tcp_sequence(indicator);
+                        self.update_tcp_acknowledgement(indicator);
+                    }
+                    {
+                        let mut tx_locked = self.tx.lock().unwrap();
+                        if let Some(ipv4) = indicator.get_ipv4() {
+                            // The DSCP may be set after the connection is established
+                            if tx_locked.set_tcp_dscp(dst, tcp.get_src(), ipv4.get_dscp()) {
+                                let stream = self.streams.get(&key).unwrap();
+                                if let Err(ref e) = stream.set_dscp(ipv4.get_dscp()) {
+                                    debug!(
+                                        "{}set DSCP of {}: {}",
+                                        stream.get_tag(),
+                                        indicator.brief(),
+                                        e
+                                    );
+                                }
+                            }
+                            tx_locked.update_tcp_ecn(dst, tcp.get_src(), ipv4.get_ecn(), tcp);
+                        }
+                        let window = match tx_locked.get_tcp_wscale(dst, tcp.get_src()) {
+                            Some(wscale) => (tcp.get_window() as usize) << wscale,
+                            None => tcp.get_window() as usize,
+                        };
+                        tx_locked.update_tcp_rtt(
+                            dst,
+                            tcp.get_src(),
+                            tcp.get_acknowledgement(),
+                            tcp.get_timestamps(),
+                        );
+                        tx_locked.update_tcp_recovery(
+                            dst,
+                            tcp.get_src(),
+                            tcp.get_acknowledgement(),
+                            window,
+                            tcp.get_payload().is_empty() && !tcp.is_fin() && !is_probe,
+                        )?;
+                        tx_locked.invalidate_cache_to(
+                            dst,
+                            tcp.get_src(),
+                            tcp.get_acknowledgement(),
+                        );
+                        tx_locked.update_tcp_fin(dst, tcp.get_src(), tcp.get_acknowledgement());
+                        tx_locked.set_tcp_send_window(dst, tcp.get_src(), window);
+                        tx_locked.update_tcp_sacks(dst, tcp.get_src(), &tcp.get_sacks());
+                        tx_locked.update_tcp_shrink(dst, tcp.get_src())?;
+                    }
+
+                    if is_probe {
+                        // A window probe never changes the reassembly, it is only answered
+                        trace!(
+                            "{}TCP window probe of {}",
+                            self.get_tcp_tag(&key),
+                            indicator.brief()
+                        );
+                        self.tx.lock().unwrap().send_tcp_ack_0(dst, tcp.get_src())?;
+                    } else if !tcp.get_payload().is_empty() {
+                        self.handle_tcp_data(indicator)?;
+                    }
+
+                    if tcp.is_fin() {
+                        // FIN
+                        return self.handle_tcp_fin(indicator);
+                    }
+
+                    // Trigger sending remaining data
+                    self.tx.lock().unwrap().send_tcp_ack(dst, tcp.get_src())?;
+
+                    self.update_tcp_close(indicator);
+                } else {
+                    // Expect in LAST_ACK state (or the stream met an error)
+                    if tcp.is_fin() {
+                        let mut tx_locked = self.tx.lock().unwrap();
+                        tx_locked.set_tcp_acknowledgement(
+                            dst,
+                            tcp.get_src(),
+                            tcp.get_sequence().wrapping_add(1),
+                        );
+                        // Send ACK/FIN
+                        tx_locked.send_tcp_ack_fin(dst, tcp.get_src())?;
+                    } else {
+                        self.terminate_key(key, CloseReason::ProxyError);
+                    }
+                }
+            } else {
+                return self.handle_tcp_unknown(indicator);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Delivers the payload of a segment of an alive connection to its stream in order, urgent
+    /// data is delivered inline like the rest of the payload.
+    fn handle_tcp_data(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return Ok(()),
+            };
+            let key = (tcp.get_src(), dst);
+
+            if tcp.is_urg() {
+                metrics::TCP_URGENT.increase();
+                trace!(
+                    "{}TCP urgent data of {} at {} inline",
+                    self.get_tcp_tag(&key),
+                    indicator.brief(),
+                    tcp.get_urgent_pointer()
+                );
+            }
+
+            // ACK
+            // Append to cache
+            let capacity = self
+                .tx
+                .lock()
+                .unwrap()
+                .get_tcp_recv_capacity(dst, tcp.get_src());
+            // The window advertised is the capacity less the bytes in reassembly and the backlog
+            // of the stream, so its right edge is never beyond the capacity less the backlog
+            let window =
+                capacity.saturating_sub(self.streams.get(&key).unwrap().get_backlog_size());
+            let next = self
+                .tcp_cache_map
+                .entry(key)
+                .or_insert_with(|| RandomCacher::with_capacity(tcp.get_sequence(), capacity))
+                .get_sequence();
+            // Bytes before the next sequence expected are received already, which are counted as
+            // retransmitted
+            if seq_lt(tcp.get_sequence(), next) {
+                let size = min(
+                    next.wrapping_sub(tcp.get_sequence()) as usize,
+                    tcp.get_payload().len(),
+                );
+                if size > 0 {
+                    self.streams.get(&key).unwrap().add_retransmitted_up(size);
+                }
+            }
+            // A segment spanning the edges of the window is trimmed to the bytes in the window,
+            // which are reassembled and acknowledged, so the source never resends a segment larger
+            // than the window forever
+            let (sequence, segment) =
+                match trim_to_window(next, window, tcp.get_sequence(), tcp.get_payload().len()) {
+                    Some((sequence, range)) => {
+                        if range.end < tcp.get_payload().len() {
+                            trace!(
+                                "{}trim {} to {} Bytes in TCP window {}",
+                                self.get_tcp_tag(&key),
+                                indicator.brief(),
+                                range.len(),
+                                window
+                            );
+                        }
+                        (sequence, &tcp.get_payload()[range])
+                    }
+                    None => (tcp.get_sequence(), &tcp.get_payload()[..0]),
+                };
+            let cache = self.tcp_cache_map.get_mut(&key).unwrap();
+            // A segment filling a hole is acknowledged immediately (RFC 5681)
+            let is_delayed = cache.get_blocks().is_empty();
+            // Segments out of order are discarded with the ones cached under the memory pressure,
+            // the source retransmits them
+            let sub_sequence = sequence.wrapping_sub(cache.get_sequence());
+            let payload = if !segment.is_empty()
+                && sub_sequence != 0
+                && sub_sequence < MAX_U32_WINDOW_SIZE as u32
+                && memory::is_tight()
+            {
+                cache.discard();
+                metrics::MEMORY_DISCARDS.increase();
+                trace!(
+                    "{}discard {} out of order for memory pressure",
+                    self.streams
+                        .get(&key)
+                        .map(|stream| stream.get_tag())
+                        .unwrap_or_default(),
+                    indicator.brief()
+                );
+
+                None
+            } else if segment.is_empty() {
+                None
+            } else {
+                cache.append(sequence, segment)?
+            };
+
+            // SACK blocks, the block contains this segment is the first
+            let mut sacks = cache.get_blocks();
+            if let Some(i) = sacks
+                .iter()
+                .position(|(left, right)| sequence.wrapping_sub(*left) < right.wrapping_sub(*left))
+            {
+                let block = sacks.remove(i);
+                sacks.insert(0, block);
+            }
+
+            match payload {
+                Some(payload) => {
+                    // Send
+                    let stream = self.streams.get_mut(&key).unwrap();
+                    match stream.send(payload.as_slice()) {
+                        Ok(_) => {
+                            // Update window size, data not written to the stream yet
+                            // shrinks the window
+                            let mut tx_locked = self.tx.lock().unwrap();
+                            tx_locked.set_tcp_window(
+                                dst,
+                                tcp.get_src(),
+                                cache
+                                    .get_remaining_size()
+                                    .saturating_sub(stream.get_backlog_size()),
+                            );
+
+                            // Update TCP acknowledgement
+                            tx_locked.add_tcp_acknowledgement(
+                                dst,
+                                tcp.get_src(),
+                                payload.len() as u32,
+                            );
+
+                            // Update SACK blocks
+                            tx_locked.set_tcp_recv_sacks(dst, tcp.get_src(), sacks);
+
+                            // Send ACK0, a FIN right after the data is acknowledged with the data
+                            // If there is a heavy traffic, the ACK reported may be inaccurate, which would results in retransmission
+                            let sequence = tcp
+                                .get_sequence()
+                                .wrapping_add(tcp.get_payload().len() as u32);
+                            if tcp.is_fin() && cache.get_sequence() == sequence {
+                                trace!("TCP data of {} before FIN", indicator.brief());
+                            } else if is_delayed {
+                                tx_locked.send_tcp_ack_0_delayed(dst, tcp.get_src())?;
+                            } else {
+                                tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
+                            }
+                        }
+                        Err(e) => {
+                            self.terminate_key(key, CloseReason::ProxyError);
+
+                            return Err(e);
+                        }
+                    }
+                }
+                None => {
+                    // Retransmission or unordered
+                    // Segments after the expected sequence are out of order
+                    let sub_sequence = tcp.get_sequence().wrapping_sub(cache.get_sequence());
+                    if !tcp.get_payload().is_empty()
+                        && sub_sequence != 0
+                        && sub_sequence < MAX_U32_WINDOW_SIZE as u32
+                    {
+                        metrics::TCP_OUT_OF_ORDER.increase();
+                    }
+
+                    // Update window size
+                    let backlog = self.streams.get(&key).unwrap().get_backlog_size();
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    tx_locked.set_tcp_window(
+                        dst,
+                        tcp.get_src(),
+                        cache.get_remaining_size().saturating_sub(backlog),
+                    );
+
+                    // Update SACK blocks
+                    tx_locked.set_tcp_recv_sacks(dst, tcp.get_src(), sacks);
+
+                    // Send ACK0
+                    tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_tcp_syn(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return Ok(()),
+            };
+            let key = (tcp.get_src(), dst);
+            let is_exist = self.streams.get(&key).is_some();
+
+            // A TCP SYN of a connection in use never connects again
+            if is_exist {
+                let mut tx_locked = self.tx.lock().unwrap();
+                match self.tcp_initial_sequence_map.get(&key) {
+                    Some(&sequence) if sequence == tcp.get_sequence() => {
+                        if self.tcp_half_open_map.contains_key(&key) {
+                            // The TCP ACK/SYN is lost, the retransmission timer restarts without
+                            // backing off
+                            trace!(
+                                "{}resend ACK/SYN for {}",
+                                self.get_tcp_tag(&key),
+                                indicator.brief()
+                            );
+                            let now = self.clock.now();
+                            if let Some(entry) = self.tcp_half_open_map.get_mut(&key) {
+                                entry.0 = now;
+                            }
+                            // Send ACK/SYN
+                            tx_locked.resend_tcp_ack_syn(dst, tcp.get_src())?;
+                        } else {
+                            trace!(
+                                "{}acknowledge {} of an established connection",
+                                self.get_tcp_tag(&key),
+                                indicator.brief()
+                            );
+                            // Send ACK0
+                            tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
+                        }
+                    }
+                    _ => {
+                        // A new connection in the same address and port replaces the one in use
+                        // only after the source resets it in response to the challenge ACK
+                        // (RFC 5961)
+                        debug!(
+                            "{}challenge {} of a connection in use",
+                            self.get_tcp_tag(&key),
+                            indicator.brief()
+                        );
+                        // Send ACK0
+                        tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
+                    }
+                }
+
+                return Ok(());
+            }
+
+            // Clean up
+            self.remove(indicator);
+
+            // Destinations are rewritten first, other rules apply to the destination rewritten to
+            let rewritten = self.rewrites.rewrite(Protocol::Tcp, dst);
+            let target = rewritten.unwrap_or(dst);
+
+            // The blocklist is checked before any state of the connection is created
+            if let Some(action) = self.get_block_action(target) {
+                debug!("block {}", indicator.brief());
+                event::drop_packet(DropReason::Blocked, LayerTypes::Tcp);
+                if action == BlockAction::Reset {
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    tx_locked.set_tcp_acknowledgement(
+                        dst,
+                        tcp.get_src(),
+                        tcp.get_sequence().wrapping_add(1),
+                    );
+                    // Send ACK/RST
+                    tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
+
+                    // Clean up
+                    tx_locked.remove(dst, tcp.get_src());
+                }
+
+                return Ok(());
+            }
+
+            // Access lists are checked before any state of the connection is created
+            let is_allowed = self.access_list.is_allowed(Protocol::Tcp, target);
+            if !is_allowed {
+                event::drop_packet(DropReason::Denied, LayerTypes::Tcp);
+                if self.access_list.is_silent() {
+                    debug!("deny {}", indicator.brief());
+
+                    return Ok(());
+                }
+            }
+
+            let action = self.rules.get_action(target);
+            if !is_allowed || action == Action::Reject || self.is_draining {
+                debug!("reject {}", indicator.brief());
+
+                let mut tx_locked = self.tx.lock().unwrap();
+                tx_locked.set_tcp_acknowledgement(
+                    dst,
+                    tcp.get_src(),
+                    tcp.get_sequence().wrapping_add(1),
+                );
+                // Send ACK/RST
+                tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
+
+                // Clean up
+                tx_locked.remove(dst, tcp.get_src());
+
+                return Ok(());
+            }
+
+            // Wait for the backoff after a failed attempt, the source will retransmit the SYN
+            if let Some(&(instant, failures)) = self.tcp_connect_map.get(&key) {
+                let backoff = CONNECT_RETRY_INTERVAL << min(failures - 1, 16);
+                if self.clock.elapsed(instant).as_millis() < backoff {
+                    debug!("drop {} for connection retry backoff", indicator.brief());
+                    event::drop_packet(DropReason::RetryBackoff, LayerTypes::Tcp);
+
+                    return Ok(());
+                }
+            }
+
+            // Limit connections waiting for the handshake first, so a SYN flood from the source
+            // cannot take up all connections
+            let limit = if self.tcp_half_open_map.len() >= self.max_half_open {
+                Some(DropReason::HalfOpenLimit)
+            } else if metrics::get_connections(Protocol::Tcp) >= self.max_connections as u64
+                && !(self.is_evict_idle
+                    && self.evict_tcp(&metrics::TCP_EVICTIONS, CloseReason::Evicted))
+            {
+                Some(DropReason::ConnectionLimit)
+            } else if !self
+                .limits
+                .connections
+                .as_ref()
+                .map_or(true, |quota| quota.acquire())
+            {
+                Some(DropReason::ClientQuota)
+            } else {
+                None
+            };
+            if let Some(reason) = limit {
+                debug!("reset {} for {}", indicator.brief(), reason.get_name());
+                event::drop_packet(reason, LayerTypes::Tcp);
+
+                let mut tx_locked = self.tx.lock().unwrap();
+                tx_locked.set_tcp_acknowledgement(
+                    dst,
+                    tcp.get_src(),
+                    tcp.get_sequence().wrapping_add(1),
+                );
+                // Send ACK/RST
+                tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
+
+                // Clean up
+                tx_locked.remove(dst, tcp.get_src());
+
+                return Ok(());
+            }
+
+            self.tcp_sequence_map.insert(key, tcp.get_sequence());
+            self.tcp_initial_sequence_map
+                .insert(key, tcp.get_sequence());
+
+            // Connect, DNS over TCP is redirected to the resolver through the proxy, and the
+            // HTTP port of the IGD server and the port of the test responder on the local address
+            // are redirected to their listeners, unless the destination is rewritten
+            let responder = self
+                .get_responder(dst)
+                .map(|responder| responder.get_tcp_addr());
+            let (target, proxy) = match (responder, self.igd_server.as_ref(), self.dns_resolver) {
+                (Some(responder), _, _) if rewritten.is_none() => (responder, None),
+                (_, Some(server), _)
+                    if rewritten.is_none()
+                        && Some(*dst.ip()) == self.local_ip_addr
+                        && dst.port() == igd::HTTP_PORT =>
+                {
+                    (server.get_addr(), None)
+                }
+                (_, _, Some(resolver)) if rewritten.is_none() && dst.port() == DNS_PORT => {
+                    (resolver, Some(self.proxy.as_ref()))
+                }
+                _ => match action {
+                    Action::Proxy => (target, Some(self.proxy.as_ref())),
+                    _ => (target, None),
+                },
+            };
+            let is_proxied = proxy.is_some();
+            let mut connection = Connection::new(
+                self.src_ip_addr,
+                tcp.get_src(),
+                dst,
+                Protocol::Tcp,
+                is_proxied,
+            );
+            let tag = connection.get_tag();
+            if let Some(rewritten) = rewritten {
+                connection.set_rewritten(rewritten);
+                debug!("{}rewrite {} -> {}", tag, dst, rewritten);
+            }
+
+            // The ports of the NAT exhausting fails the new connection, never an existing one
+            if let Err(ref e) = connection.map_nat() {
+                debug!("{}reset {}: {}", tag, indicator.brief(), e);
+                event::drop_packet(DropReason::NatExhausted, LayerTypes::Tcp);
+                if let Some(ref quota) = self.limits.connections {
+                    quota.release();
+                }
+
+                let mut tx_locked = self.tx.lock().unwrap();
+                tx_locked.set_tcp_acknowledgement(
+                    dst,
+                    tcp.get_src(),
+                    tcp.get_sequence().wrapping_add(1),
+                );
+                // Send ACK/RST
+                tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
+
+                // Clean up
+                tx_locked.remove(dst, tcp.get_src());
+                drop(tx_locked);
+                self.tcp_sequence_map.remove(&key);
+                self.tcp_initial_sequence_map.remove(&key);
+
+                return Ok(());
+            }
+            let stream = StreamWorker::connect(
+                self.get_tx(),
+                connection,
+                target,
+                proxy,
+                self.proxy_timeout,
+                self.keepalive,
+                self.limits.clone(),
+            );
+
+            let stream = match stream {
+                Ok(mut stream) => {
+                    self.tcp_connect_map.remove(&key);
+                    if self.is_nodelay {
+                        if let Err(ref e) = stream.set_nodelay(true) {
+                            debug!("{}set TCP no delay of {}: {}", tag, indicator.brief(), e);
+                        }
+                    }
+                    if let Some(relay_stale) = self.relay_stale {
+                        if let Err(ref e) = stream.set_stale_timeout(relay_stale) {
+                            debug!("{}set stale timeout of {}: {}", tag, indicator.brief(), e);
+                        }
+                    }
+                    if is_proxied {
+                        self.update_proxy_reachable();
+                    }
+
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    // Clean up
+                    tx_locked.remove(dst, tcp.get_src());
+
+                    tx_locked.set_tcp_tag(dst, tcp.get_src(), tag);
+                    tx_locked.set_tcp_connection(dst, tcp.get_src(), stream.get_connection());
+                    if let Some(mss) = tcp.get_mss() {
+                        tx_locked.set_tcp_mss(dst, tcp.get_src(), mss);
+                    }
+                    if let Some(wscale) = tcp.get_wscale() {
+                        tx_locked.set_tcp_wscale(dst, tcp.get_src(), wscale);
+                    }
+                    if tcp.is_sack_perm() {
+                        tx_locked.set_tcp_sack_perm(dst, tcp.get_src());
+                    }
+                    if let Some((tsval, _)) = tcp.get_timestamps() {
+                        tx_locked.set_tcp_timestamps(dst, tcp.get_src(), tsval);
+                    }
+                    // An ECN-setup SYN (RFC 3168)
+                    if tcp.is_ece() && tcp.is_cwr() {
+                        tx_locked.set_tcp_ecn(dst, tcp.get_src());
+                    }
+                    if let Some(ipv4) = indicator.get_ipv4() {
+                        if tx_locked.set_tcp_dscp(dst, tcp.get_src(), ipv4.get_dscp()) {
+                            if let Err(ref e) = stream.set_dscp(ipv4.get_dscp()) {
+                                debug!("{}set DSCP of {}: {}", tag, indicator.brief(), e);
+                            }
+                        }
+                        tx_locked.set_tcp_dont_fragment(
+                            dst,
+                            tcp.get_src(),
+                            ipv4.is_dont_fragment(),
+                        );
+                    }
+
+                    // Data carried in the SYN, like a TCP Fast Open attempt, is relayed once the
+                    // stream is connected and acknowledged by the ACK/SYN, though TFO is never
+                    // negotiated, so the first bytes of the source do not wait for a
+                    // retransmission
+                    let mut sequence = tcp.get_sequence().wrapping_add(1);
+                    let early_data = tcp.get_payload();
+                    if !early_data.is_empty() && early_data.len() <= MAX_EARLY_DATA_SIZE {
+                        match stream.send(early_data) {
+                            Ok(_) => {
+                                debug!(
+                                    "{}relay {} Bytes of early data of {}",
+                                    tag,
+                                    early_data.len(),
+                                    indicator.brief()
+                                );
+                                sequence = sequence.wrapping_add(early_data.len() as u32);
+                            }
+                            Err(ref e) => {
+                                debug!("{}relay early data of {}: {}", tag, indicator.brief(), e);
+                            }
+                        }
+                    }
+
+                    // Reassemble from the sequence of the first byte not relayed
+                    let cache = RandomCacher::with_capacity(
+                        sequence,
+                        tx_locked.get_tcp_recv_capacity(dst, tcp.get_src()),
+                    );
+                    self.tcp_cache_map.insert(key, cache);
+                    tx_locked.set_tcp_acknowledgement(dst, tcp.get_src(), sequence);
+                    // Send ACK/SYN
+                    tx_locked.send_tcp_ack_syn(dst, tcp.get_src())?;
+
+                    stream
+                }
+                Err(e) => {
+                    // Clean up
+                    self.remove(indicator);
+                    if let Some(ref quota) = self.limits.connections {
+                        quota.release();
+                    }
+
+                    // Retry if the proxy is unreachable, the source is answered after the
+                    // last retry
+                    if UnreachableError::is_unreachable(&e) {
+                        self.update_proxy_unreachable(&e);
+
+                        let entry = self
+                            .tcp_connect_map
+                            .entry(key)
+                            .or_insert((self.clock.now(), 0));
+                        entry.0 = self.clock.now();
+                        entry.1 += 1;
+                        if entry.1 <= self.connect_retries {
+                            debug!(
+                                "{}drop {} for proxy unreachable, retry {}/{}",
+                                tag,
+                                indicator.brief(),
+                                entry.1,
+                                self.connect_retries
+                            );
+                            event::drop_packet(DropReason::ProxyUnreachable, LayerTypes::Tcp);
+
+                            return Ok(());
+                        }
+                        self.tcp_connect_map.remove(&key);
+                    }
+                    if is_proxied && e.kind() == io::ErrorKind::TimedOut {
+                        self.proxy_timeout_count += 1;
+                        debug!(
+                            "{}reset {} for proxy timed out ({} timed out)",
+                            tag,
+                            indicator.brief(),
+                            self.proxy_timeout_count
+                        );
+                    }
+
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    match ReplyError::from_io_error(&e) {
+                        // Unreachable destinations are reported as ICMP, so the source can
+                        // fail fast as it does with a real router
+                        Some(ReplyError::NetworkUnreachable) => {
+                            // Send ICMPv4 network unreachable
+                            tx_locked.send_icmpv4_destination_unreachable(0, indicator)?;
+                        }
+                        Some(ReplyError::HostUnreachable) => {
+                            // Send ICMPv4 host unreachable
+                            tx_locked.send_icmpv4_destination_unreachable(1, indicator)?;
+                        }
+                        _ => {
+                            tx_locked.set_tcp_acknowledgement(
+                                dst,
+                                tcp.get_src(),
+                                tcp.get_sequence().wrapping_add(1),
+                            );
+                            // Send ACK/RST
+                            tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
+                        }
+                    }
+
+                    // Clean up
+                    tx_locked.remove(dst, tcp.get_src());
+
+                    return Err(e);
+                }
+            };
+
+            self.streams.insert(key, stream);
+            self.tcp_half_open_map.insert(key, (self.clock.now(), 0));
+            if is_proxied {
+                self.update_bind(dst);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles the answer of the source to the TCP SYN of an inbound connection. A TCP SYN of the
+    /// source opening the connection at the same time is answered by a TCP ACK/SYN, and the
+    /// connection is established by its acknowledgement (RFC 793).
+    fn handle_tcp_bind(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return Ok(()),
+            };
+            let key = (tcp.get_src(), dst);
+
+            if tcp.is_rst() {
+                debug!("inbound {} -> {} is reset", dst, tcp.get_src());
+
+                // Clean up
+                self.tcp_bind_map.remove(&key);
+                self.tcp_initial_sequence_map.remove(&key);
+                self.tx.lock().unwrap().remove(dst, tcp.get_src());
+
+                return Ok(());
+            }
+            // Simultaneous open
+            if tcp.is_syn() && !tcp.is_ack() {
+                trace!("simultaneous open of inbound {} -> {}", dst, tcp.get_src());
+                self.tcp_initial_sequence_map
+                    .insert(key, tcp.get_sequence());
+
+                let mut tx_locked = self.tx.lock().unwrap();
+                if let Some(mss) = tcp.get_mss() {
+                    tx_locked.set_tcp_mss(dst, tcp.get_src(), mss);
+                }
+                if let Some(wscale) = tcp.get_wscale() {
+                    tx_locked.set_tcp_wscale(dst, tcp.get_src(), wscale);
+                }
+                if tcp.is_sack_perm() {
+                    tx_locked.set_tcp_sack_perm(dst, tcp.get_src());
+                }
+                tx_locked.set_tcp_acknowledgement(
+                    dst,
+                    tcp.get_src(),
+                    tcp.get_sequence().wrapping_add(1),
+                );
+                // Send ACK/SYN
+                tx_locked.resend_tcp_ack_syn(dst, tcp.get_src())?;
+
+                return Ok(());
+            }
+            // A TCP ACK/SYN, or a TCP ACK after a simultaneous open
+            let initial_sequence = match self.tcp_initial_sequence_map.get(&key) {
+                _ if tcp.is_syn() && tcp.is_ack() => tcp.get_sequence(),
+                Some(&sequence) if tcp.is_ack() => sequence,
+                _ => {
+                    trace!("ignore {} of inbound connection", indicator.brief());
+
+                    return Ok(());
+                }
+            };
+
+            let (stream, _, _) = self.tcp_bind_map.remove(&key).unwrap();
+            self.tcp_sequence_map.insert(key, initial_sequence);
+            self.tcp_initial_sequence_map.insert(key, initial_sequence);
+
+            let mut tx_locked = self.tx.lock().unwrap();
+            if tcp.is_syn() {
+                if let Some(mss) = tcp.get_mss() {
+                    tx_locked.set_tcp_mss(dst, tcp.get_src(), mss);
+                }
+                if let Some(wscale) = tcp.get_wscale() {
+                    tx_locked.set_tcp_wscale(dst, tcp.get_src(), wscale);
+                }
+                if tcp.is_sack_perm() {
+                    tx_locked.set_tcp_sack_perm(dst, tcp.get_src());
+                }
+            }
+
+            // Reassemble from the sequence of the first byte
+            let sequence = initial_sequence.wrapping_add(1);
+            let cache = RandomCacher::with_capacity(
+                sequence,
+                tx_locked.get_tcp_recv_capacity(dst, tcp.get_src()),
+            );
+            self.tcp_cache_map.insert(key, cache);
+            tx_locked.set_tcp_acknowledgement(dst, tcp.get_src(), sequence);
+            // The TCP ACK/SYN is acknowledged, the acknowledgement itself is not
+            if tcp.is_syn() {
+                // Send ACK0
+                tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
+            }
+            drop(tx_locked);
+
+            let connection =
+                Connection::new(self.src_ip_addr, tcp.get_src(), dst, Protocol::Tcp, true);
+            let stream = StreamWorker::new(
+                self.get_tx(),
+                connection,
+                stream,
+                self.keepalive,
+                self.limits.clone(),
+            )?;
+            let tag = stream.get_tag();
+            {
+                let mut tx_locked = self.tx.lock().unwrap();
+                tx_locked.set_tcp_tag(dst, tcp.get_src(), tag);
+                tx_locked.set_tcp_connection(dst, tcp.get_src(), stream.get_connection());
+            }
+            if self.is_nodelay {
+                if let Err(ref e) = stream.set_nodelay(true) {
+                    debug!("{}set TCP no delay of {}: {}", tag, indicator.brief(), e);
+                }
+            }
+            if let Some(relay_stale) = self.relay_stale {
+                if let Err(ref e) = stream.set_stale_timeout(relay_stale) {
+                    debug!("{}set stale timeout of {}: {}", tag, indicator.brief(), e);
+                }
+            }
+            // Inbound connections count in the quota of the source, but they are never reset
+            if let Some(ref quota) = self.limits.connections {
+                quota.force_acquire();
+            }
+            self.streams.insert(key, stream);
+            trace!("{}establish inbound {} -> {}", tag, dst, tcp.get_src());
+        }
+
+        Ok(())
+    }
+
+    /// Asks the proxy to listen for inbound connections from the destination to the bind ports
+    /// in the background.
+    fn update_bind(&mut self, dst: SocketAddrV4) {
+        let mut ports = self.bind_ports.clone();
+        if let Some(ref server) = self.igd_server {
+            for port in server.get_ports(Protocol::Tcp, self.src_ip_addr) {
+                if !ports.contains(&port) {
+                    ports.push(port);
+                }
+            }
+        }
+        for port in ports {
+            if !self.bind_set.insert((port, *dst.ip())) {
+                continue;
+            }
+
+            let proxy = Arc::clone(&self.proxy);
+            let timeout = self.proxy_timeout;
+            let bind_tx = self.bind_tx.clone();
+            let igd_server = self.igd_server.clone();
+            thread::spawn(move || {
+                let result = proxy
+                    .listen(dst, timeout)
+                    .and_then(|listener| {
+                        info!("Bind {} for {} on {}", port, dst.ip(), listener.get_addr());
+                        // The proxy listens on its external address if it is a public address
+                        if let (Some(server), SocketAddr::V4(addr)) =
+                            (igd_server, listener.get_addr())
+                        {
+                            if igd::is_public(*addr.ip()) {
+                                server.set_external_ip_addr(*addr.ip());
+                            }
+                        }
+                        listener.accept(Duration::from_millis(BIND_TIMEOUT))
+                    })
+                    .and_then(|(stream, peer)| match peer {
+                        SocketAddr::V4(peer) => Ok((stream, peer)),
+                        SocketAddr::V6(_) => Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "inbound connection from IPv6",
+                        )),
+                    });
+                let _ = bind_tx.send((port, *dst.ip(), result));
+            });
+        }
+    }
+
+    /// Handles inbound connections accepted by the proxy, including the retransmission of TCP
+    /// SYNs to the source.
+    fn handle_binds(&mut self) {
+        while let Ok((port, ip_addr, result)) = self.bind_rx.try_recv() {
+            self.bind_set.remove(&(port, ip_addr));
+            let (stream, peer) = match result {
+                Ok(result) => result,
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                    debug!("close bind {} for {}: {}", port, ip_addr, e);
+                    continue;
+                }
+                Err(ref e) => {
+                    warn!("handle {}: {}", "TCP", e);
+                    continue;
+                }
+            };
+
+            // Forward to the worker the connection belongs to
+            if !self.shard_bind_txs.is_empty() {
+                let shard = get_tcp_shard(port, peer, self.shard_bind_txs.len());
+                if shard != self.shard {
+                    let _ = self.shard_bind_txs[shard].send((port, ip_addr, Ok((stream, peer))));
+                    continue;
+                }
+            }
+
+            let key = (port, peer);
+            if self.streams.contains_key(&key) || self.tcp_bind_map.contains_key(&key) {
+                debug!("drop inbound {} -> {} for conflict", peer, port);
+                continue;
+            }
+            trace!("accept inbound {} -> {}", peer, port);
+
+            let mut tx_locked = self.tx.lock().unwrap();
+            // Send SYN
+            if let Err(ref e) = tx_locked.send_tcp_syn(peer, port) {
+                warn!("handle {}: {}", "TCP", e);
+            }
+            drop(tx_locked);
+            self.tcp_bind_map.insert(key, (stream, self.clock.now(), 1));
+        }
+
+        let keys: Vec<_> = self.tcp_bind_map.keys().cloned().collect();
+        for key in keys {
+            let (src_port, dst) = key;
+            let (_, instant, count) = self.tcp_bind_map.get_mut(&key).unwrap();
+            if self.clock.elapsed(*instant).as_millis() < BIND_SYN_INTERVAL {
+                continue;
+            }
+            if *count > BIND_SYN_RETRIES {
+                debug!("give up inbound {} -> {}", dst, src_port);
+
+                // Clean up
+                self.tcp_bind_map.remove(&key);
+                self.tcp_initial_sequence_map.remove(&key);
+                self.tx.lock().unwrap().remove(dst, src_port);
+
+                continue;
+            }
+            *instant = self.clock.now();
+            *count += 1;
+
+            let mut tx_locked = self.tx.lock().unwrap();
+            // Resend SYN from the initial sequence, or ACK/SYN after a simultaneous open
+            let result = if self.tcp_initial_sequence_map.contains_key(&key) {
+                tx_locked.resend_tcp_ack_syn(dst, src_port)
+            } else {
+                tx_locked.resend_tcp_syn(dst, src_port)
+            };
+            if let Err(ref e) = result {
+                warn!("handle {}: {}", "TCP", e);
+            }
+        }
+    }
+
+    /// Rejects a segment in an illegal combination of flags, like a SYN with a FIN. The connection
+    /// of the segment is reset, but a segment with a RST is never answered (RFC 793).
+    fn handle_tcp_illegal(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(ref tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return Ok(()),
+            };
+            let key = (tcp.get_src(), dst);
+
+            debug!("drop {}: illegal flags", indicator.brief());
+            event::drop_packet(DropReason::InvalidFlags, LayerTypes::Tcp);
+            if tcp.is_rst() {
+                return Ok(());
+            }
+
+            if self.streams.contains_key(&key) {
+                // Clean up
+                self.close(indicator, CloseReason::Reset);
+            }
+            // Send RST
+            return self.handle_tcp_unknown(indicator);
+        }
+
+        Ok(())
+    }
+
+    /// Returns if the segment is a window probe of an alive connection, a keepalive of at most 1
+    /// byte just before the expected sequence, or a byte probing the window while it is zero.
+    fn is_tcp_window_probe(&self, indicator: &Indicator) -> bool {
+        if let Some(ref tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return false,
+            };
+            let key = (tcp.get_src(), dst);
+
+            if tcp.is_syn() || tcp.is_fin() || tcp.get_payload().len() > 1 {
+                return false;
+            }
+            let sequence = match self.tcp_cache_map.get(&key) {
+                Some(cache) => cache.get_sequence(),
+                None => return false,
+            };
+            if tcp.get_sequence() == sequence.wrapping_sub(1) {
+                return true;
+            }
+
+            return tcp.get_payload().len() == 1
+                && tcp.get_sequence() == sequence
+                && self.tx.lock().unwrap().get_tcp_window(dst, tcp.get_src()) == 0;
+        }
+
+        false
+    }
+
+    fn handle_tcp_rst(&mut self, indicator: &Indicator) {
+        if let Some(ref tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return,
+            };
+            let key = (tcp.get_src(), dst);
+            let is_exist = self.streams.get(&key).is_some();
+
+            if is_exist {
+                // Clean up
+                self.close(indicator, CloseReason::Reset);
+                self.tx.lock().unwrap().remove(dst, tcp.get_src());
+            }
+        }
+    }
+
+    fn handle_tcp_fin(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(ref tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return Ok(()),
+            };
+            let key = (tcp.get_src(), dst);
+            let is_exist = self.streams.get(&key).is_some();
+
+            if is_exist {
+                // The FIN is accepted only if all the data before is received
+                let sequence = tcp
+                    .get_sequence()
+                    .wrapping_add(tcp.get_payload().len() as u32);
+                let is_in_order = match self.tcp_cache_map.get(&key) {
+                    Some(cache) => cache.get_sequence() == sequence,
+                    None => true,
+                };
+
+                if self.tcp_fin_set.contains(&key) {
+                    // Retransmission
+                    self.tx.lock().unwrap().send_tcp_ack_0(dst, tcp.get_src())?;
+                } else if is_in_order {
+                    self.tcp_fin_set.insert(key);
+                    if self.tx.lock().unwrap().is_tcp_fin_sent(dst, tcp.get_src()) {
+                        self.tcp_eof_set.insert(key);
+                    }
+
+                    // Close the write half of the stream, data from the destination is still
+                    // redirected to the source. Streams are already shut down in draining
+                    if !self.is_draining {
+                        let stream = self.streams.get_mut(&key).unwrap();
+                        if let Err(ref e) = stream.shutdown_write() {
+                            warn!("{}handle {}: {}", stream.get_tag(), "TCP", e);
+                        }
+                    }
+
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    tx_locked.add_tcp_acknowledgement(dst, tcp.get_src(), 1);
+                    // Send ACK0
+                    tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
+                }
+
+                self.update_tcp_close(indicator);
+            } else {
+                return self.handle_tcp_unknown(indicator);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resets a segment of a TCP connection which does not exist (RFC 793), so a source with a
+    /// stale connection can recover quickly. A segment with a RST is never answered. Segments of
+    /// connections closed recently are counted quietly, segments of connections never known are
+    /// warned, and both the RSTs and the logs are limited in rate.
+    fn handle_tcp_unknown(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(ref tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return Ok(()),
+            };
+            let key = (tcp.get_src(), dst);
+
+            if tcp.is_rst() {
+                return Ok(());
+            }
+
+            let is_recent = match self.tcp_closed_map.get(&key) {
+                Some(&instant) => self.clock.elapsed(instant) < RECENTLY_CLOSED_TIMEOUT,
+                None => false,
+            };
+            // A data segment of a connection never known, like one established before a restart,
+            // may be picked up mid-stream
+            if !is_recent && self.is_tcp_pickup(indicator) && self.pick_up_tcp(indicator)? {
+                return self.handle_tcp_ack(indicator);
+            }
+
+            let (is_rst_allowed, is_log_allowed) = self.update_tcp_unknown_limit();
+            if is_recent {
+                metrics::TCP_STALE_SEGMENTS.increase();
+                if is_log_allowed {
+                    trace!("reset stale {} of a closed connection", indicator.brief());
+                }
+            } else {
+                metrics::TCP_UNKNOWN_SEGMENTS.increase();
+                if is_log_allowed {
+                    warn!("reset {} of an unknown connection", indicator.brief());
+                }
+            }
+            if !is_rst_allowed {
+                metrics::TCP_UNKNOWN_RSTS_SUPPRESSED.increase();
+
+                return Ok(());
+            }
+
+            let mut tx_locked = self.tx.lock().unwrap();
+            if tcp.is_ack() {
+                #[allow(deprecated)]
+                tx_locked.set_tcp_sequence(dst, tcp.get_src(), tcp.get_acknowledgement());
+                // Send RST
+                tx_locked.send_tcp_rst(dst, tcp.get_src())?;
+            } else {
+                let mut len = tcp.get_payload().len() as u32;
+                if tcp.is_fin() {
+                    len += 1;
+                }
+                #[allow(deprecated)]
+                tx_locked.set_tcp_sequence(dst, tcp.get_src(), 0);
+                tx_locked.set_tcp_acknowledgement(
+                    dst,
+                    tcp.get_src(),
+                    tcp.get_sequence().wrapping_add(len),
+                );
+                // Send ACK/RST
+                tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
+            }
+
+            // Clean up
+            tx_locked.remove(dst, tcp.get_src());
+        }
+
+        Ok(())
+    }
+
+    /// Returns if a TCP segment of a connection not found is picked up mid-stream, which is a
+    /// segment carrying data to a destination matching the rules of picking up.
+    fn is_tcp_pickup(&self, indicator: &Indicator) -> bool {
+        if self.pickup_established.is_empty() || self.is_draining {
+            return false;
+        }
+        match indicator.get_tcp() {
+            Some(tcp) => {
+                let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                    Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                    None => return false,
+                };
+                let target = self.rewrites.rewrite(Protocol::Tcp, dst).unwrap_or(dst);
+
+                tcp.is_ack()
+                    && !tcp.is_syn()
+                    && !tcp.is_fin()
+                    && !tcp.get_payload().is_empty()
+                    && self
+                        .pickup_established
+                        .iter()
+                        .any(|access| access.is_match(Protocol::Tcp, target))
+            }
+            None => false,
+        }
+    }
+
+    /// Picks up a TCP connection whose handshake is never seen, like one established before a
+    /// restart. The destination is connected as for a new connection, and the sequences of both
+    /// sides are adopted from the segment, so the relay starts from it while data in flight
+    /// before is lost. The options of the handshake are unknown: the MSS is the minimum, the
+    /// window of the source is read unscaled, and SACK and ECN are never enabled. Returns if the
+    /// connection is picked up, otherwise the segment is answered as one of an unknown
+    /// connection.
+    fn pick_up_tcp(&mut self, indicator: &Indicator) -> io::Result<bool> {
+        let tcp = match indicator.get_tcp() {
+            Some(tcp) => tcp,
+            None => return Ok(false),
+        };
+        let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+            Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+            None => return Ok(false),
+        };
+        let key = (tcp.get_src(), dst);
+
+        // Clean up
+        self.remove(indicator);
+
+        // Blocked, denied and rejected destinations are never picked up
+        let rewritten = self.rewrites.rewrite(Protocol::Tcp, dst);
+        let target = rewritten.unwrap_or(dst);
+        let action = self.rules.get_action(target);
+        if self.get_block_action(target).is_some()
+            || !self.access_list.is_allowed(Protocol::Tcp, target)
+            || action == Action::Reject
+        {
+            debug!("refuse to pick up {}", indicator.brief());
+
+            return Ok(false);
+        }
+        if metrics::get_connections(Protocol::Tcp) >= self.max_connections as u64
+            || !self
+                .limits
+                .connections
+                .as_ref()
+                .map_or(true, |quota| quota.acquire())
+        {
+            debug!(
+                "refuse to pick up {} for connections full",
+                indicator.brief()
+            );
+
+            return Ok(false);
+        }
+
+        let proxy = match action {
+            Action::Proxy => Some(self.proxy.as_ref()),
+            _ => None,
+        };
+        let is_proxied = proxy.is_some();
+        let mut connection = Connection::new(
+            self.src_ip_addr,
+            tcp.get_src(),
+            dst,
+            Protocol::Tcp,
+            is_proxied,
+        );
+        connection.set_picked_up();
+        let tag = connection.get_tag();
+        if let Some(rewritten) = rewritten {
+            connection.set_rewritten(rewritten);
+            debug!("{}rewrite {} -> {}", tag, dst, rewritten);
+        }
+        let stream = match connection.map_nat().and_then(|_| {
+            StreamWorker::connect(
+                self.get_tx(),
+                connection,
+                target,
+                proxy,
+                self.proxy_timeout,
+                self.keepalive,
+                self.limits.clone(),
+            )
+        }) {
+            Ok(stream) => stream,
+            Err(ref e) => {
+                debug!("{}pick up {}: {}", tag, indicator.brief(), e);
+                if let Some(ref quota) = self.limits.connections {
+                    quota.release();
+                }
+                if is_proxied && UnreachableError::is_unreachable(e) {
+                    self.update_proxy_unreachable(e);
+                }
+
+                return Ok(false);
+            }
+        };
+        if self.is_nodelay {
+            if let Err(ref e) = stream.set_nodelay(true) {
+                debug!("{}set TCP no delay of {}: {}", tag, indicator.brief(), e);
+            }
+        }
+        if let Some(relay_stale) = self.relay_stale {
+            if let Err(ref e) = stream.set_stale_timeout(relay_stale) {
+                debug!("{}set stale timeout of {}: {}", tag, indicator.brief(), e);
+            }
+        }
+        if is_proxied {
+            self.update_proxy_reachable();
+        }
+
+        // The segment is the first one of the connection, which is relayed as it is handled
+        // again
+        let sequence = tcp.get_sequence();
+        self.tcp_sequence_map.insert(key, sequence.wrapping_sub(1));
+        self.tcp_initial_sequence_map
+            .insert(key, sequence.wrapping_sub(1));
+        self.tcp_acknowledgement_map
+            .insert(key, tcp.get_acknowledgement());
+        {
+            let mut tx_locked = self.tx.lock().unwrap();
+            tx_locked.set_tcp_tag(dst, tcp.get_src(), tag);
+            tx_locked.set_tcp_connection(dst, tcp.get_src(), stream.get_connection());
+            tx_locked.pick_up_tcp(dst, tcp.get_src(), tcp.get_acknowledgement(), sequence);
+            tx_locked.set_tcp_mss(dst, tcp.get_src(), PICKUP_TCP_MSS);
+            // The window of the source is read unscaled, which is never larger than it is, and
+            // the window to the source is scaled as if the scaling is negotiated
+            tx_locked.set_tcp_wscale(dst, tcp.get_src(), 0);
+            if let Some((tsval, _)) = tcp.get_timestamps() {
+                tx_locked.set_tcp_timestamps(dst, tcp.get_src(), tsval);
+            }
+            if let Some(ipv4) = indicator.get_ipv4() {
+                if tx_locked.set_tcp_dscp(dst, tcp.get_src(), ipv4.get_dscp()) {
+                    if let Err(ref e) = stream.set_dscp(ipv4.get_dscp()) {
+                        debug!("{}set DSCP of {}: {}", tag, indicator.brief(), e);
+                    }
+                }
+                tx_locked.set_tcp_dont_fragment(dst, tcp.get_src(), ipv4.is_dont_fragment());
+            }
+
+            let cache = RandomCacher::with_capacity(
+                sequence,
+                tx_locked.get_tcp_recv_capacity(dst, tcp.get_src()),
+            );
+            self.tcp_cache_map.insert(key, cache);
+        }
+
+        info!("{}pick up {} mid-stream", tag, indicator.brief());
+        metrics::TCP_PICKUPS.increase();
+        self.streams.insert(key, stream);
+        if is_proxied {
+            self.update_bind(dst);
+        }
+
+        Ok(true)
+    }
+
+    /// Counts a TCP segment of a connection not found in the current second. Returns if a RST
+    /// and a log are allowed for it, the logs suppressed are summed up when the second ends.
+    fn update_tcp_unknown_limit(&mut self) -> (bool, bool) {
+        let (instant, _, _, suppressed) = self.tcp_unknown_limit;
+        // The second also restarts if the clock is behind it, like a clock driven by tests
+        let now = self.clock.now();
+        if now < instant || now.duration_since(instant) >= Duration::from_secs(1) {
+            if suppressed > 0 {
+                debug!(
+                    "suppress {} logs of TCP segments of connections not found",
+                    suppressed
+                );
+            }
+            self.tcp_unknown_limit = (now, 0, 0, 0);
+        }
+
+        let limit = &mut self.tcp_unknown_limit;
+        let is_rst_allowed = limit.1 < MAX_UNKNOWN_RSTS;
+        let is_log_allowed = limit.2 < MAX_UNKNOWN_LOGS;
+        if is_rst_allowed {
+            limit.1 += 1;
+        }
+        if is_log_allowed {
+            limit.2 += 1;
+        } else {
+            limit.3 += 1;
+        }
+
+        (is_rst_allowed, is_log_allowed)
+    }
+
+    /// Removes a TCP connection closed by both sides, the connection is kept in `TIME_WAIT` for a
+    /// while to drop stale segments.
+    fn update_tcp_close(&mut self, indicator: &Indicator) {
+        if let Some(ref tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return,
+            };
+            let key = (tcp.get_src(), dst);
+
+            if self.tcp_fin_set.contains(&key)
+                && self
+                    .tx
+                    .lock()
+                    .unwrap()
+                    .is_tcp_fin_acknowledged(dst, tcp.get_src())
+            {
+                let sequence = match self.tcp_cache_map.get(&key) {
+                    Some(cache) => cache.get_sequence(),
+                    None => tcp.get_sequence(),
+                };
+
+                // Clean up
+                let tag = self.get_tcp_tag(&key);
+                let reason = if self.tcp_eof_set.contains(&key) {
+                    CloseReason::Eof
+                } else {
+                    CloseReason::Fin
+                };
+                self.close(indicator, reason);
+                self.tx.lock().unwrap().remove(dst, tcp.get_src());
+
+                self.tcp_time_wait_map
+                    .insert(key, (self.clock.now(), sequence));
+                trace!("{}TIME_WAIT {} -> {}", tag, tcp.get_src(), dst);
+            }
+        }
+    }
+
+    /// Takes the settings reloaded if they change. Only new connections and associations are
+    /// limited by the quotas reloaded.
+    fn handle_settings(&mut self) {
+        let generation = reload::get_generation();
+        if generation == self.settings_generation {
+            return;
+        }
+        self.settings_generation = generation;
+
+        let settings = match reload::get_settings() {
+            Some(settings) => settings,
+            None => return,
+        };
+        self.set_rules(settings.rules.clone());
+        self.set_access_list(settings.access_list.clone());
+        self.set_rewrites(settings.rewrites.clone());
+        if settings.shaper.is_enabled() {
+            self.set_shaper(&settings.shaper);
+        } else {
+            self.limits = Limits::default();
+        }
+        self.set_proxy_timeout(settings.proxy_timeout);
+        self.relay_timeout = settings.relay_timeout;
+        self.relay_stale = settings.relay_stale;
+        self.set_connect_retries(settings.connect_retries);
+        self.set_udp_timeout(settings.udp_timeout);
+        debug!("reload settings of {}", self.src_ip_addr);
+    }
+
+    /// Dumps the connection table if it is requested. The table is taken in the worker under the
+    /// lock of the downstreamer so it is consistent, and it is logged in the background.
+    fn handle_table_dump(&mut self) {
+        let generation = TABLE_GENERATION.load(Ordering::Relaxed);
+        if generation == self.table_generation {
+            return;
+        }
+        self.table_generation = generation;
+
+        let src_ip_addr = self.src_ip_addr;
+        let mut lines = Vec::with_capacity(
+            self.streams.len() + self.tcp_time_wait_map.len() + self.direct_datagrams.len(),
+        );
+        {
+            let tx_locked = self.tx.lock().unwrap();
+            for (&(src_port, dst), stream) in self.streams.iter() {
+                let key = (src_port, dst);
+                let state = match (
+                    self.tcp_fin_set.contains(&key),
+                    tx_locked.is_tcp_fin_sent(dst, src_port),
+                ) {
+                    (false, false) => "ESTABLISHED",
+                    (true, false) => "CLOSE_WAIT",
+                    (false, true) => "FIN_WAIT",
+                    (true, true) => "LAST_ACK",
+                };
+                let (bytes_up, bytes_down) = stream.get_bytes();
+                let (retransmitted_up, retransmitted_down) = stream.get_retransmitted_bytes();
+                let (wire_up, wire_down) = stream.get_wire_bytes();
+                let srtt = match tx_locked.get_tcp_srtt(dst, src_port) {
+                    Some(srtt) => format!("{} ms", srtt.as_millis()),
+                    None => String::from("-"),
+                };
+                lines.push(format!(
+                    "{}TCP {}:{}{} -> {}{} {}: {} Bytes up ({} on the wire, {} retransmitted), {} Bytes down ({} on the wire, {} retransmitted), {} Bytes in flight, SRTT {}, {} retransmissions, {} DF drops",
+                    stream.get_tag(),
+                    src_ip_addr,
+                    src_port,
+                    nat_string(stream.get_nat_port()),
+                    dst,
+                    rewrite_string(stream.get_rewritten()),
+                    state,
+                    bytes_up,
+                    wire_up,
+                    retransmitted_up,
+                    bytes_down,
+                    wire_down,
+                    retransmitted_down,
+                    tx_locked.get_tcp_in_flight(dst, src_port),
+                    srtt,
+                    tx_locked.get_tcp_retransmission_count(dst, src_port),
+                    tx_locked.get_dont_fragment_drops(dst, src_port)
+                ));
+            }
+        }
+        for (src_port, dst) in self.tcp_time_wait_map.keys() {
+            lines.push(format!(
+                "TCP {}:{} -> {} TIME_WAIT",
+                src_ip_addr, src_port, dst
+            ));
+        }
+        let tcp_count = lines.len();
+        let datagrams = self
+            .datagrams
+            .iter()
+            .filter_map(|worker| worker.as_ref())
+            .map(|worker| (worker, "proxy"))
+            .chain(
+                self.direct_datagrams
+                    .values()
+                    .map(|worker| (worker, "direct")),
+            );
+        let tx_locked = self.tx.lock().unwrap();
+        for (worker, action) in datagrams {
+            let (bytes_up, bytes_down) = worker.get_bytes();
+            let (wire_up, wire_down) = worker.get_wire_bytes();
+            let (overhead_up, overhead_down) = worker.get_overhead_bytes();
+            let (packets_up, packets_down, send_failures) = worker.get_packets();
+            let (oversized, relay_mtu) = worker.get_oversized();
+            let (peers, unsolicited) = worker.get_peers();
+            lines.push(format!(
+                "{}UDP {}:{}{} = {} -> {}{} ({}{}): {} Bytes up in {} packets ({} on the wire, {} SOCKS5 headers), {} Bytes down in {} packets ({} on the wire, {} SOCKS5 headers), {} send failures, {} oversized, {} DF drops, relay MTU {}, jitter {:.1} ms, {} peers, {} unsolicited, idle for {} ms",
+                worker.get_tag(),
+                src_ip_addr,
+                worker.get_src_port(),
+                nat_string(worker.get_nat_port()),
+                worker.local_port,
+                worker.get_dst(),
+                rewrite_string(worker.get_rewritten()),
+                action,
+                match worker.is_quic() {
+                    true => ", QUIC",
+                    false => "",
+                },
+                bytes_up,
+                packets_up,
+                wire_up,
+                overhead_up,
+                bytes_down,
+                packets_down,
+                wire_down,
+                overhead_down,
+                send_failures,
+                oversized,
+                tx_locked.get_dont_fragment_drops(worker.get_dst(), worker.get_src_port()),
+                relay_mtu.map_or_else(|| String::from("-"), |mtu| mtu.to_string()),
+                worker.get_jitter().as_micros() as f64 / 1000.0,
+                peers,
+                unsolicited,
+                worker.get_idle().as_millis()
+            ));
+        }
+        drop(tx_locked);
+        let udp_count = lines.len() - tcp_count;
+        let mut quotas = Vec::new();
+        if let Some(ref quota) = self.limits.connections {
+            quotas.push(format!(
+                "{}/{} TCP connections, {} reset",
+                quota.get_count(),
+                quota.get_max(),
+                quota.get_rejections()
+            ));
+        }
+        if let Some((ref up, ref down)) = self.limits.quota {
+            quotas.push(format!(
+                "{} Bytes/s, exhausted {} times up and {} times down",
+                up.get_rate(),
+                up.get_exhaustions(),
+                down.get_exhaustions()
+            ));
+        }
+
+        // Tables of tens of thousands of connections are slow to log
+        let shard = self.shard;
+        let device = match pcap::bridge::get_device(src_ip_addr) {
+            Some(device) => format!(" on {}", device),
+            None => String::new(),
+        };
+        thread::spawn(move || {
+            info!(
+                "Connection table of {}{} in worker {}: {} TCP connections, {} UDP associations",
+                src_ip_addr, device, shard, tcp_count, udp_count
+            );
+            if !quotas.is_empty() {
+                info!("    Quota of {}: {}", src_ip_addr, quotas.join(", "));
+            }
+            for line in lines {
+                info!("    {}", line);
+            }
+        });
+    }
+
+    /// Handles timers of TCP connections, including the retransmission of TCP FINs and the
+    /// expiration of `TIME_WAIT`.
+    fn handle_timers(&mut self) {
+        self.handle_table_dump();
+
+        // Delayed TCP ACKs, whose timeout is shorter than the interval of timers
+        if let Err(ref e) = self.tx.lock().unwrap().send_tcp_ack_0_if_timed_out() {
+            warn!("handle {}: {}", "TCP", e);
+        }
+
+        if self.clock.elapsed(self.timer_instant).as_millis() < TIMER_INTERVAL {
+            return;
+        }
+        self.timer_instant = self.clock.now();
+
+        // Memory, buffers shrink under the pressure, and the least recently active TCP
+        // connection is reset as the last resort
+        let pressure = memory::get_pressure();
+        if pressure != Pressure::Normal {
+            let mut discarded = 0;
+            for cache in self.tcp_cache_map.values_mut() {
+                if cache.discard() {
+                    discarded += 1;
+                }
+            }
+            if discarded > 0 {
+                debug!(
+                    "discard TCP data out of order of {} connections for memory {}",
+                    discarded, pressure
+                );
+            }
+            self.tx.lock().unwrap().shrink_tcp_caches();
+            if pressure == Pressure::Exhausted {
+                self.evict_tcp(&metrics::MEMORY_EVICTIONS, CloseReason::MemoryExhausted);
+            }
+        }
+
+        // IPv4 reassembly
+        let expired = self.defrag.expire();
+        metrics::REASSEMBLY_EXPIRATIONS.add(expired as u64);
+        if expired > 0 {
+            debug!(
+                "expire {} IPv4 datagrams in reassembly ({} expired)",
+                expired,
+                self.defrag.get_expired_count()
+            );
+        }
+
+        // UDP
+        let udp_keepalive = self.udp_keepalive;
+        let is_udp_assoc_strict = self.is_udp_assoc_strict;
+        let mut dead = Vec::new();
+        for index in 0..PORT_COUNT {
+            let reason = match self.datagrams[index] {
+                Some(ref mut worker) => {
+                    let timeout = if worker.is_dns() {
+                        min(self.udp_timeout, Duration::from_secs(UDP_DNS_TIMEOUT))
+                    } else if worker.is_quic() {
+                        max(self.udp_timeout, Duration::from_secs(UDP_QUIC_TIMEOUT))
+                    } else {
+                        self.udp_timeout
+                    };
+                    if worker.is_closed() {
+                        Some(CloseReason::Error)
+                    } else if worker.get_idle() >= timeout {
+                        Some(CloseReason::Idle)
+                    } else if let Err(ref e) =
+                        worker.update_control(udp_keepalive, is_udp_assoc_strict)
+                    {
+                        debug!(
+                            "{}close datagram {} = {}: {}",
+                            worker.get_tag(),
+                            worker.get_src_port(),
+                            worker.local_port,
+                            e
+                        );
+                        Some(CloseReason::ControlClosed)
+                    } else {
+                        if let Some(ref server) = self.igd_server {
+                            worker.set_mapped(server.is_mapped(
+                                Protocol::Udp,
+                                self.src_ip_addr,
+                                worker.get_src_port(),
+                            ));
+                        }
+                        if let Some((probe, interval)) = self.udp_probe {
+                            match worker.update_probe(probe, interval) {
+                                Ok(true) => dead.push(index),
+                                Ok(false) => {}
+                                Err(ref e) => debug!(
+                                    "{}probe datagram {} = {}: {}",
+                                    worker.get_tag(),
+                                    worker.get_src_port(),
+                                    worker.local_port,
+                                    e
+                                ),
+                            }
+                        }
+
+                        None
+                    }
+                }
+                None => None,
+            };
+            if let Some(reason) = reason {
+                self.remove_datagram(index, reason);
+            }
+        }
+        for index in dead {
+            self.reassociate_datagram(index);
+        }
+        let udp_timeout = self.udp_timeout;
+        self.direct_datagrams.retain(|_, worker| {
+            let timeout = match worker.is_dns() {
+                true => min(udp_timeout, Duration::from_secs(UDP_DNS_TIMEOUT)),
+                false => udp_timeout,
+            };
+            if worker.is_closed() {
+                worker.close_connection(CloseReason::Error);
+            } else if worker.get_idle() >= timeout {
+                worker.close_connection(CloseReason::Idle);
+            } else {
+                return true;
+            }
+
+            false
+        });
+
+        // Proxy reachability
+        if self.proxy_unreachable_since.is_some() && self.is_proxy_reachable.load(Ordering::Relaxed)
+        {
+            self.update_proxy_reachable();
+        }
+        let clock = &self.clock;
+        self.tcp_connect_map
+            .retain(|_, (instant, _)| clock.elapsed(*instant).as_millis() < CONNECT_RETRY_TIMEOUT);
+
+        // Inbound connections
+        self.handle_binds();
+
+        // TIME_WAIT
+        let clock = &self.clock;
+        self.tcp_time_wait_map
+            .retain(|_, (instant, _)| clock.elapsed(*instant).as_millis() < TIME_WAIT_TIMEOUT);
+
+        let keys: Vec<_> = self.streams.keys().cloned().collect();
+        for key in keys {
+            let (src_port, dst) = key;
+            let tag = self.get_tcp_tag(&key);
+
+            // Backlog of the stream
+            if let Err(ref e) = self.update_tcp_window(key) {
+                warn!("{}handle {}: {}", tag, "TCP", e);
+            }
+
+            // Handshake timeout, the source does not acknowledge the ACK/SYN and its
+            // retransmissions, the stream to the proxy is closed too
+            if let Some(&(instant, retries)) = self.tcp_half_open_map.get(&key) {
+                let interval = min(
+                    SYN_ACK_INITIAL_INTERVAL * (1u32 << min(retries, 16)),
+                    self.syn_ack_max_interval,
+                );
+                if self.clock.elapsed(instant) >= interval {
+                    if retries < self.syn_ack_retries {
+                        trace!(
+                            "{}resend ACK/SYN of {} -> {} ({}/{})",
+                            tag,
+                            src_port,
+                            dst,
+                            retries + 1,
+                            self.syn_ack_retries
+                        );
+                        metrics::TCP_RETRANSMISSIONS.increase();
+                        self.tcp_half_open_map
+                            .insert(key, (self.clock.now(), retries + 1));
+                        // Send ACK/SYN from the initial sequence with the same options
+                        let mut tx_locked = self.tx.lock().unwrap();
+                        if let Err(ref e) = tx_locked.resend_tcp_ack_syn(dst, src_port) {
+                            warn!("{}handle {}: {}", tag, "TCP", e);
+                        }
+                    } else {
+                        debug!(
+                            "{}close {} -> {} for handshake timed out after {} retransmissions",
+                            tag, src_port, dst, retries
+                        );
+                        self.terminate_key(key, CloseReason::HandshakeTimeout);
+
+                        continue;
+                    }
+                }
+            }
+
+            // Proxy error, the stream met an error while relaying, so the source is reset rather
+            // than left waiting for data never coming
+            if self.streams.get(&key).unwrap().is_closed() && !self.is_draining {
+                debug!("{}close {} -> {} for proxy error", tag, src_port, dst);
+                self.terminate_key(key, CloseReason::ProxyError);
+
+                continue;
+            }
+
+            // Relay timeout, the stream is reset rather than closed as the destination closes it
+            if let Some(relay_timeout) = self.relay_timeout {
+                if self.streams.get(&key).unwrap().get_idle() >= relay_timeout {
+                    self.relay_timeout_count += 1;
+                    debug!(
+                        "{}close {} -> {} for relay timed out ({} timed out)",
+                        tag, src_port, dst, self.relay_timeout_count
+                    );
+                    self.terminate_key(key, CloseReason::RelayTimeout);
+
+                    continue;
+                }
+            }
+
+            // Stale relay, the proxy is dead without a RST or a FIN, so the writes to the stream
+            // are never drained while the source is still acknowledged
+            if let Some(relay_stale) = self.relay_stale {
+                if self.streams.get_mut(&key).unwrap().get_stall() >= relay_stale {
+                    self.relay_stale_count += 1;
+                    debug!(
+                        "{}close {} -> {} for relay stale ({} stale)",
+                        tag, src_port, dst, self.relay_stale_count
+                    );
+                    self.terminate_key(key, CloseReason::RelayStale);
+
+                    continue;
+                }
+            }
+
+            // TCP keepalive
+            match self.update_tcp_keepalive(key) {
+                Ok(true) => {}
+                Ok(false) => {
+                    debug!(
+                        "{}close {} -> {} for TCP keepalive timed out",
+                        tag, src_port, dst
+                    );
+                    self.terminate_key(key, CloseReason::KeepaliveTimeout);
+
+                    continue;
+                }
+                Err(ref e) => warn!("{}handle {}: {}", tag, "TCP", e),
+            }
+
+            let mut tx_locked = self.tx.lock().unwrap();
+            // TCP zero window probe
+            if let Err(ref e) = tx_locked.send_tcp_window_probe_if_timed_out(dst, src_port) {
+                warn!("{}handle {}: {}", tag, "TCP", e);
+            }
+
+            // TCP FIN
+            match tx_locked.resend_tcp_ack_fin_if_timed_out(dst, src_port) {
+                Ok(true) => {}
+                Ok(false) => {
+                    trace!("{}give up TCP FIN of {} -> {}", tag, src_port, dst);
+                    drop(tx_locked);
+                    self.terminate_key(key, CloseReason::FinTimeout);
+                }
+                Err(ref e) => warn!("{}handle {}: {}", tag, "TCP", e),
+            }
+        }
+    }
+
+    /// Sends TCP keepalive probes to the source of an idle TCP connection. Returns `false` if the
+    /// source does not answer the probes and the connection should be closed. The probes never
+    /// reset the idle time, only segments from the source do.
+    fn update_tcp_keepalive(&mut self, key: (u16, SocketAddrV4)) -> io::Result<bool> {
+        let (src_port, dst) = key;
+
+        let keepalive = match self.keepalive {
+            Some(keepalive) => keepalive,
+            None => return Ok(true),
+        };
+        let now = self.clock.now();
+        let activity = *self.tcp_activity_map.entry(key).or_insert(now);
+        if self.clock.elapsed(activity) < keepalive {
+            return Ok(true);
+        }
+
+        let mut tx_locked = self.tx.lock().unwrap();
+        // Data in flight is covered by the retransmission
+        if tx_locked.get_cache_size(dst, src_port) > 0 {
+            return Ok(true);
+        }
+
+        let count = match self.tcp_keepalive_map.get(&key) {
+            Some((instant, count)) => {
+                if self.clock.elapsed(*instant).as_millis() < KEEPALIVE_INTERVAL {
+                    return Ok(true);
+                }
+                *count
+            }
+            None => 0,
+        };
+        if count >= KEEPALIVE_PROBES {
+            return Ok(false);
+        }
+
+        trace!(
+            "{}send TCP keepalive of {} -> {} ({})",
+            self.get_tcp_tag(&key),
+            dst,
+            src_port,
+            count + 1
+        );
+        tx_locked.send_tcp_keepalive(dst, src_port)?;
+        self.tcp_keepalive_map
+            .insert(key, (self.clock.now(), count + 1));
+
+        Ok(true)
+    }
+
+    /// Writes the backlog of a TCP stream, and updates the window advertised to the source. A
+    /// window update is sent if the window is reopened. The backlog may also be drained by the
+    /// writer of the stream, so the window is compared with the one advertised.
+    fn update_tcp_window(&mut self, key: (u16, SocketAddrV4)) -> io::Result<()> {
+        let (src_port, dst) = key;
+
+        let stream = match self.streams.get_mut(&key) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        if stream.get_backlog_size() > 0 {
+            stream.flush()?;
+        }
+        let backlog = stream.get_backlog_size();
+
+        let remaining_size = match self.tcp_cache_map.get(&key) {
+            Some(cache) => cache.get_remaining_size(),
+            None => return Ok(()),
+        };
+        let window = remaining_size.saturating_sub(backlog);
+        let mut tx_locked = self.tx.lock().unwrap();
+        let prev_window = match tx_locked.get_tcp_window_size(dst, src_port) {
+            Some(prev_window) if prev_window < window => prev_window,
+            _ => return Ok(()),
+        };
+        tx_locked.set_tcp_window(dst, src_port, window);
+
+        // Avoid the silly window syndrome, the window is updated by at least a segment
+        if backlog == 0 || window - prev_window >= tx_locked.get_tcp_max_payload_size(dst, src_port)
+        {
+            // Send ACK0
+            tx_locked.send_tcp_ack_0(dst, src_port)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_udp(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(ref udp) = indicator.get_udp() {
+            let dst = match ipv4_addr(udp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, udp.get_dst()),
+                None => {
+                    // IPv6 is not redirected yet
+                    trace!("ignore {}", indicator.brief());
+
+                    return Ok(());
+                }
+            };
+
+            // Drop datagrams in draining, associations are closed
+            if self.is_draining {
+                debug!("drop {} for shutting down", indicator.brief());
+                event::drop_packet(DropReason::Shutdown, LayerTypes::Udp);
+
+                return Ok(());
+            }
+
+            // Destinations are rewritten first, other rules apply to the destination rewritten to
+            let rewritten = self.rewrites.rewrite(Protocol::Udp, dst);
+            let target = rewritten.unwrap_or(dst);
+
+            // The blocklist is checked at the first datagram of a flow
+            if self.blocklist.is_some() {
+                let key = (udp.get_src(), target);
+                let action = match self.udp_block_map.get(&key) {
+                    Some(&action) => action,
+                    None => {
+                        let action = self.get_block_action(target);
+                        self.udp_block_map.put(key, action);
+
+                        action
+                    }
+                };
+                if let Some(action) = action {
+                    debug!("block {}", indicator.brief());
+                    event::drop_packet(DropReason::Blocked, LayerTypes::Udp);
+                    if action == BlockAction::Drop {
+                        return Ok(());
+                    }
+
+                    // Send ICMPv4 port unreachable
+                    return self
+                        .tx
+                        .lock()
+                        .unwrap()
+                        .send_icmpv4_destination_unreachable(3, indicator);
+                }
+            }
+
+            // Access lists are checked before any state of the association is created
+            if !self.access_list.is_allowed(Protocol::Udp, target) {
+                debug!("deny {}", indicator.brief());
+                event::drop_packet(DropReason::Denied, LayerTypes::Udp);
+                if self.access_list.is_silent() {
+                    return Ok(());
+                }
+
+                // Send ICMPv4 port unreachable
+                return self
+                    .tx
+                    .lock()
+                    .unwrap()
+                    .send_icmpv4_destination_unreachable(3, indicator);
+            }
+
+            // Answer the test responder directly, so the proxy is never involved
+            if let (Some(responder), None) = (self.get_responder(dst), rewritten) {
+                let target = responder.get_udp_addr();
+
+                return self.handle_udp_direct(indicator, Some(target));
+            }
+
+            // Redirect DNS queries, malformed queries are passed through
+            if let (Some(resolver), None) = (self.dns_resolver, rewritten) {
+                if dst.port() == DNS_PORT {
+                    if let Some(query) = Query::parse(udp.get_payload()) {
+                        return self.handle_udp_dns(indicator, resolver, query);
+                    }
+                }
+            }
+
+            match self.rules.get_action(target) {
+                Action::Proxy => {}
+                Action::Direct => return self.handle_udp_direct(indicator, rewritten),
+                Action::Reject => {
+                    debug!("reject {}", indicator.brief());
+
+                    // Send ICMPv4 communication administratively prohibited
+                    return self
+                        .tx
+                        .lock()
+                        .unwrap()
+                        .send_icmpv4_destination_unreachable(13, indicator);
+                }
+            }
+
+            // Drop datagrams which cannot be relayed by the proxy
+            if !self.proxy.is_udp_supported() {
+                if !self.is_udp_unsupported_warned {
+                    self.is_udp_unsupported_warned = true;
+                    warn!(
+                        "handle {}: {} does not support UDP, UDP traffic will be dropped",
+                        "UDP", self.proxy
+                    );
+                }
+                debug!("drop {} for UDP not supported", indicator.brief());
+                event::drop_packet(DropReason::UdpUnsupported, LayerTypes::Udp);
+
+                return Ok(());
+            }
+
+            // A QUIC connection from a new source port keeps its association
+            if self.datagram_map[udp.get_src() as usize] == 0 {
+                self.migrate_quic(udp.get_src(), dst, udp.get_payload());
+            }
+
+            let index = match self.bind_datagram(udp.get_src(), dst) {
+                Ok(index) => index,
+                Err(e) => {
+                    self.send_udp_unreachable(&e, indicator)?;
+
+                    return Err(e);
+                }
+            };
+            self.update_quic(index, dst, udp.get_payload());
+            let worker = self.datagrams[index].as_mut().unwrap();
+            if let Some(ipv4) = indicator.get_ipv4() {
+                let dscp = ipv4.get_dscp();
+                {
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    tx_locked.set_udp_dscp(dst, udp.get_src(), dscp);
+                    tx_locked.set_udp_dont_fragment(dst, udp.get_src(), ipv4.is_dont_fragment());
+                }
+                worker.set_dont_fragment(ipv4.is_dont_fragment());
+                if let Err(ref e) = worker.set_dscp(dscp) {
+                    debug!(
+                        "{}set DSCP of {}: {}",
+                        worker.get_tag(),
+                        indicator.brief(),
+                        e
+                    );
+                }
+            }
+
+            worker.add_wire_up(self.frame_size);
+
+            // Send
+            let result = match rewritten {
+                Some(target) => worker.send_rewritten_to(udp.get_payload(), target, dst),
+                None => worker.send_to(udp.get_payload(), dst),
+            };
+            if let Err(e) = result {
+                self.send_udp_unreachable(&e, indicator)?;
+
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reports a failed UDP relay to the source as an ICMPv4 destination unreachable, as a real
+    /// router does instead of leaving the source waiting.
+    fn send_udp_unreachable(&mut self, e: &io::Error, indicator: &Indicator) -> io::Result<()> {
+        // Oversized datagrams are reported with the MTU the source can send in, so it can adapt
+        if let Some(max_size) = OversizedError::get_max_size(e) {
+            let mtu = min(max_size + IPV4_UDP_HEADER_SIZE, u16::MAX as usize) as u16;
+            debug!(
+                "send ICMPv4 fragmentation needed ({}) for {}: {}",
+                mtu,
+                indicator.brief(),
+                e
+            );
+
+            let mut tx_locked = self.tx.lock().unwrap();
+            if let (Some(ipv4), Some(udp)) = (indicator.get_ipv4(), indicator.get_udp()) {
+                if ipv4.is_dont_fragment() {
+                    let dst = SocketAddrV4::new(ipv4.get_dst(), udp.get_dst());
+                    tx_locked.add_dont_fragment_drop(dst, udp.get_src());
+                }
+            }
+
+            return tx_locked.send_icmpv4_fragmentation_needed(mtu, indicator);
+        }
+        let code = match ReplyError::from_io_error(e) {
+            // Network unreachable
+            Some(ReplyError::NetworkUnreachable) => 0,
+            // Host unreachable
+            Some(ReplyError::HostUnreachable) | Some(ReplyError::TtlExpired) => 1,
+            // The destination cannot be reached if the proxy is unreachable
+            _ if UnreachableError::is_unreachable(e) || e.kind() == io::ErrorKind::TimedOut => 1,
+            // Port unreachable
+            _ => 3,
+        };
+        debug!(
+            "send ICMPv4 destination unreachable ({}) for {}: {}",
+            code,
+            indicator.brief(),
+            e
+        );
+
+        self.tx
+            .lock()
+            .unwrap()
+            .send_icmpv4_destination_unreachable(code, indicator)
+    }
+
+    fn handle_udp_dns(
+        &mut self,
+        indicator: &Indicator,
+        resolver: SocketAddrV4,
+        query: Query,
+    ) -> io::Result<()> {
+        if let Some(ref udp) = indicator.get_udp() {
+            let dst = match ipv4_addr(udp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, udp.get_dst()),
+                None => return Ok(()),
+            };
+
+            // Answer an empty truncated response if the proxy cannot relay UDP traffic, so the
+            // source retries over TCP
+            if !self.proxy.is_udp_supported() {
+                debug!("truncate {} for UDP not supported", indicator.brief());
+                let response = query.new_truncated_response(udp.get_payload());
+
+                self.tx
+                    .lock()
+                    .unwrap()
+                    .send_udp(dst, udp.get_src(), &response)?;
+
+                return Ok(());
+            }
+
+            let index = match self.bind_datagram(udp.get_src(), dst) {
+                Ok(index) => index,
+                Err(e) => {
+                    self.send_udp_unreachable(&e, indicator)?;
+
+                    return Err(e);
+                }
+            };
+
+            // Send
+            self.datagrams[index].as_mut().unwrap().send_dns_to(
+                udp.get_payload(),
+                resolver,
+                dst,
+                query,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Migrates the association of a QUIC connection to a new source port, if a datagram from a
+    /// source port without an association is addressed with a connection ID of the connection.
+    /// The association to the proxy is kept, so the destination sees the connection unchanged.
+    fn migrate_quic(&mut self, src_port: u16, dst: SocketAddrV4, payload: &[u8]) {
+        if self.quic_map.is_empty() || !quic::is_short_header(payload) {
+            return;
+        }
+        let prev_src_port = match self
+            .quic_map
+            .iter()
+            .find(|(_, flow)| flow.get_dst() == dst && flow.is_match(payload))
+        {
+            Some((&prev_src_port, _)) => prev_src_port,
+            None => return,
+        };
+        let local_port = self.datagram_map[prev_src_port as usize];
+        if local_port == 0 {
+            self.quic_map.remove(&prev_src_port);
+
+            return;
+        }
+        let index = (local_port - self.initial_port) as usize;
+        match self.datagrams[index] {
+            Some(ref mut worker)
+                if !worker.is_closed() && worker.get_src_port() == prev_src_port =>
+            {
+                worker.migrate_src_port(src_port)
+            }
+            _ => return,
+        }
+
+        // Move the port mapping
+        self.datagram_map[prev_src_port as usize] = 0;
+        self.datagram_map[src_port as usize] = local_port;
+        self.udp_lru.put(index as u16, src_port);
+        if let Some(flow) = self.quic_map.remove(&prev_src_port) {
+            self.quic_map.insert(src_port, flow);
+        }
+        metrics::UDP_QUIC_MIGRATIONS.increase();
+    }
+
+    /// Detects a QUIC connection at the first datagram of the association, and learns the
+    /// connection IDs of a connection detected. Datagrams not detected leave the association as
+    /// plain UDP.
+    fn update_quic(&mut self, index: usize, dst: SocketAddrV4, payload: &[u8]) {
+        let worker = match self.datagrams[index] {
+            Some(ref mut worker) => worker,
+            None => return,
+        };
+        let src_port = worker.get_src_port();
+        if let Some(flow) = self.quic_map.get_mut(&src_port) {
+            if flow.get_dst() == dst {
+                flow.update(payload);
+            }
+
+            return;
+        }
+        if worker.get_packets().0 > 0 {
+            return;
+        }
+        if let Some(header) = quic::detect(payload) {
+            debug!(
+                "{}detect QUIC (version {:#010x}) in datagram {} = {} to {}",
+                worker.get_tag(),
+                header.get_version(),
+                src_port,
+                worker.local_port,
+                dst
+            );
+            worker.set_quic();
+            self.quic_map
+                .insert(src_port, quic::Flow::new(dst, &header));
+            metrics::UDP_QUIC_FLOWS.increase();
+        }
+    }
+
+    /// Binds a datagram for the source port if there is not one, returns the index of the
+    /// datagram.
+    fn bind_datagram(&mut self, src_port: u16, dst: SocketAddrV4) -> io::Result<usize> {
+        let port = self.get_local_udp_port(src_port);
+        let index = (port - self.initial_port) as usize;
+
+        let is_create;
+        let is_set;
+        match self.datagrams[index] {
+            Some(ref worker) => {
+                is_create = worker.is_closed();
+                is_set = worker.get_src_port() != src_port;
+            }
+            None => {
+                is_create = true;
+                is_set = false;
+            }
+        };
+        if is_create || is_set {
+            // A new connection is detected as QUIC again
+            self.quic_map.remove(&src_port);
+        }
+        if is_create {
+            // Bind
+            let worker = self.new_datagram(src_port, dst, port)?;
+            self.datagrams[index] = Some(worker);
+        } else if is_set {
+            // Replace
+            self.datagrams[index]
+                .as_mut()
+                .unwrap()
+                .set_src_port(src_port, dst);
+        }
+
+        Ok(index)
+    }
+
+    /// Creates a datagram of the source port to the destination bound to the local port.
+    fn new_datagram(
+        &self,
+        src_port: u16,
+        dst: SocketAddrV4,
+        port: u16,
+    ) -> io::Result<DatagramWorker> {
+        let mut connection = Connection::new(self.src_ip_addr, src_port, dst, Protocol::Udp, true);
+        connection.map_nat()?;
+        let mut worker = DatagramWorker::bind(
+            self.get_tx(),
+            connection,
+            port,
+            self.proxy.as_ref(),
+            self.is_udp_fragmentation,
+            self.limits.clone(),
+            self.udp_nat,
+            Arc::clone(&self.clock),
+        )?;
+        self.set_datagram_mtu(&mut worker);
+        if let Some(keepalive) = self.udp_keepalive {
+            if let Err(ref e) = worker.set_keepalive(keepalive) {
+                warn!(
+                    "{}SOCKS: {}: {} = {}: {}",
+                    worker.get_tag(),
+                    "UDP",
+                    port,
+                    src_port,
+                    e
+                );
+            }
+        }
+
+        Ok(worker)
+    }
+
+    /// Re-establishes the datagram whose relay is dead. The new association is bound to the same
+    /// local port and restores the peers of the old one, so the source keeps its port mapping
+    /// and sees no difference. The datagram is removed if it cannot be re-established.
+    fn reassociate_datagram(&mut self, index: usize) {
+        let worker = match self.datagrams[index].as_mut() {
+            Some(worker) => worker,
+            None => return,
+        };
+        let src_port = worker.get_src_port();
+        let dst = worker.get_dst();
+        let state = worker.get_state(self.src_ip_addr);
+        let is_quic = worker.is_quic();
+        let tag = worker.get_tag();
+        let port = self.initial_port + index as u16;
+        // The old association releases the local port before the new one binds it
+        worker.close_connection(CloseReason::RelayDead);
+        self.datagrams[index] = None;
+
+        match self.new_datagram(src_port, dst, port) {
+            Ok(mut worker) => {
+                worker.restore(&state);
+                if is_quic {
+                    worker.set_quic();
+                }
+                metrics::UDP_REASSOCIATIONS.increase();
+                info!(
+                    "{}reassociate datagram {} = {} for its relay is dead",
+                    worker.get_tag(),
+                    src_port,
+                    port
+                );
+                self.datagrams[index] = Some(worker);
+            }
+            Err(ref e) => {
+                warn!(
+                    "{}SOCKS: {}: {} = {}: reassociate: {}",
+                    tag, "UDP", port, src_port, e
+                );
+                self.unmap_datagram(index, src_port);
+            }
+        }
+    }
+
+    /// Probes the path MTU to the relay of the association if set, and sets how datagrams larger
+    /// than it are sent.
+    fn set_datagram_mtu(&self, worker: &mut DatagramWorker) {
+        worker.set_send_fragmentation(self.is_udp_send_fragmentation);
+        if self.is_udp_pmtu {
+            match worker.probe_mtu() {
+                Ok(Some(mtu)) => debug!(
+                    "{}probe path MTU to the relay of datagram {} = {}: {}",
+                    worker.get_tag(),
+                    worker.get_src_port(),
+                    worker.local_port,
+                    mtu
+                ),
+                Ok(None) => {}
+                Err(ref e) => warn!(
+                    "{}SOCKS: {}: {} = {}: {}",
+                    worker.get_tag(),
+                    "UDP",
+                    worker.local_port,
+                    worker.get_src_port(),
+                    e
+                ),
+            }
+        }
+    }
+
+    fn handle_udp_direct(
+        &mut self,
+        indicator: &Indicator,
+        rewritten: Option<SocketAddrV4>,
+    ) -> io::Result<()> {
+        if let Some(ref udp) = indicator.get_udp() {
+            let dst = match ipv4_addr(udp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, udp.get_dst()),
+                None => return Ok(()),
+            };
+
+            // Bind
+            let is_create = match self.direct_datagrams.get(&udp.get_src()) {
+                Some(worker) => worker.is_closed(),
+                None => true,
+            };
+            if is_create {
+                let connection =
+                    Connection::new(self.src_ip_addr, udp.get_src(), dst, Protocol::Udp, false);
+                let worker = DatagramWorker::bind_direct(
+                    self.get_tx(),
+                    connection,
+                    self.limits.clone(),
+                    self.udp_nat,
+                    Arc::clone(&self.clock),
+                )?;
+                self.direct_datagrams.insert(udp.get_src(), worker);
+            }
+
+            let worker = self.direct_datagrams.get_mut(&udp.get_src()).unwrap();
+            if let Some(ipv4) = indicator.get_ipv4() {
+                let dscp = ipv4.get_dscp();
+                {
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    tx_locked.set_udp_dscp(dst, udp.get_src(), dscp);
+                    tx_locked.set_udp_dont_fragment(dst, udp.get_src(), ipv4.is_dont_fragment());
+                }
+                worker.set_dont_fragment(ipv4.is_dont_fragment());
+                if let Err(ref e) = worker.set_dscp(dscp) {
+                    debug!(
+                        "{}set DSCP of {}: {}",
+                        worker.get_tag(),
+                        indicator.brief(),
+                        e
+                    );
+                }
+            }
+
+            worker.add_wire_up(self.frame_size);
+
+            // Send
+            let result = match rewritten {
+                Some(target) => worker.send_rewritten_to(udp.get_payload(), target, dst),
+                None => worker.send_to(udp.get_payload(), dst),
+            };
+            if let Err(e) = result {
+                self.send_udp_unreachable(&e, indicator)?;
+
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_icmpv4(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(local_ip_addr) = self.local_ip_addr {
+            if let Some(icmpv4) = indicator.get_icmpv4() {
+                // Only the published address answers, others are dropped
+                if icmpv4.is_echo_request()
+                    && indicator.get_ipv4().unwrap().get_dst() == local_ip_addr
+                {
+                    // Send
+                    self.tx.lock().unwrap().send_icmpv4_echo_reply(
+                        icmpv4.get_identifier(),
+                        icmpv4.get_sequence(),
+                        icmpv4.get_payload(),
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn update_tcp_sequence(&mut self, indicator: &Indicator) {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return,
+            };
+            let key = (tcp.get_src(), dst);
+
+            let record_sequence = *self.tcp_sequence_map.get(&key).unwrap_or(&0);
+            let sub_sequence = tcp
+                .get_sequence()
+                .checked_sub(record_sequence)
+                .unwrap_or_else(|| tcp.get_sequence() + (u32::MAX - record_sequence));
+
+            if sub_sequence == 0 {
+                // Duplicate
+                trace!(
+                    "{}TCP retransmission of {} -> {} at {}",
+                    self.get_tcp_tag(&key),
+                    tcp.get_src(),
+                    dst,
+                    tcp.get_sequence()
+                );
+            } else if sub_sequence < MAX_U32_WINDOW_SIZE as u32 {
+                self.tcp_sequence_map.insert(key, tcp.get_sequence());
+
+                trace!(
+                    "{}set TCP sequence of {} -> {} to {}",
+                    self.get_tcp_tag(&key),
+                    tcp.get_src(),
+                    dst,
+                    tcp.get_sequence()
+                );
+            } else {
+                trace!(
+                    "{}TCP out of order of {} -> {} at {}",
+                    self.get_tcp_tag(&key),
+                    tcp.get_src(),
+                    dst,
+                    tcp.get_sequence()
+                );
+            }
+        }
+    }
+
+    fn update_tcp_acknowledgement(&mut self, indicator: &Indicator) {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return,
+            };
+            let key = (tcp.get_src(), dst);
+
+            let record_acknowledgement = *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0);
+            let sub_acknowledgement = tcp
+                .get_acknowledgement()
+                .checked_sub(record_acknowledgement)
+                .unwrap_or_else(|| tcp.get_acknowledgement() + (u32::MAX - record_acknowledgement));
+
+            if sub_acknowledgement == 0 {
+                // Duplicate
+                let entry = self.tcp_duplicate_map.entry(key).or_insert(0);
+                *entry = entry.checked_add(1).unwrap_or(usize::MAX);
+                trace!(
+                    "{}duplicate TCP acknowledgement of {} -> {} at {}",
+                    self.get_tcp_tag(&key),
+                    tcp.get_src(),
+                    dst,
+                    tcp.get_acknowledgement()
+                );
+            } else if sub_acknowledgement < MAX_U32_WINDOW_SIZE as u32 {
+                self.tcp_acknowledgement_map
+                    .insert(key, tcp.get_acknowledgement());
+
+                self.tcp_duplicate_map.insert(key, 0);
+                trace!(
+                    "{}set TCP acknowledgement of {} -> {} to {}",
+                    self.get_tcp_tag(&key),
+                    tcp.get_src(),
+                    dst,
+                    tcp.get_acknowledgement()
+                );
+            }
+        }
+    }
+
+    fn remove(&mut self, indicator: &Indicator) {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return,
+            };
+            let key = (tcp.get_src(), dst);
+
+            self.remove_key(key);
+        }
+    }
+
+    /// Get the states of the UDP associations relayed by the proxy. Associations only of DNS
+    /// queries are short-lived and never kept.
+    pub fn get_udp_states(&self) -> Vec<UdpState> {
+        self.datagrams
+            .iter()
+            .filter_map(|worker| worker.as_ref())
+            .filter(|worker| !worker.is_closed() && !worker.is_dns())
+            .map(|worker| worker.get_state(self.src_ip_addr))
+            .collect()
+    }
+
+    /// Restores the UDP associations of the source in the last run. An association binds the
+    /// same local port and associates with the proxy again, so its mapping stays the same where
+    /// the proxy allows. Associations whose local ports are out of the range of the upstreamer
+    /// are skipped. Returns the count of associations restored.
+    pub fn restore_udp_states(&mut self, states: &[UdpState]) -> usize {
+        if !self.proxy.is_udp_supported() {
+            return 0;
+        }
+
+        let src_ip_addr = self.src_ip_addr;
+        let mut count = 0;
+        for state in states.iter().filter(|state| *state.src.ip() == src_ip_addr) {
+            let src_port = state.src.port();
+            let index = match state.local_port.checked_sub(self.initial_port) {
+                Some(index) if (index as usize) < PORT_COUNT => index as usize,
+                _ => continue,
+            };
+            if self.datagrams[index].is_some() || self.datagram_map[src_port as usize] != 0 {
+                continue;
+            }
+
+            // Bind
+            let mut connection =
+                Connection::new(self.src_ip_addr, src_port, state.dst, Protocol::Udp, true);
+            let tag = connection.get_tag();
+            if let Err(ref e) = connection.map_nat() {
+                debug!(
+                    "{}restore UDP association {}:{} -> {}: {}",
+                    tag, self.src_ip_addr, src_port, state.dst, e
+                );
+                continue;
+            }
+            let mut worker = match DatagramWorker::bind(
+                self.get_tx(),
+                connection,
+                state.local_port,
+                self.proxy.as_ref(),
+                self.is_udp_fragmentation,
+                self.limits.clone(),
+                self.udp_nat,
+                Arc::clone(&self.clock),
+            ) {
+                Ok(worker) => worker,
+                Err(ref e) => {
+                    debug!(
+                        "{}restore datagram {} = {}: {}",
+                        tag, src_port, state.local_port, e
+                    );
+                    continue;
+                }
+            };
+            self.set_datagram_mtu(&mut worker);
+            if let Some(keepalive) = self.udp_keepalive {
+                if let Err(ref e) = worker.set_keepalive(keepalive) {
+                    warn!(
+                        "{}SOCKS: {}: {} = {}: {}",
+                        tag, "UDP", state.local_port, src_port, e
+                    );
+                }
+            }
+            worker.restore(state);
+            self.datagrams[index] = Some(worker);
+            self.datagram_map[src_port as usize] = state.local_port;
+            self.udp_lru.put(index as u16, src_port);
+            trace!(
+                "{}restore datagram {} = {}",
+                tag,
+                src_port,
+                state.local_port
+            );
+            count += 1;
+        }
+
+        count
+    }
+
+    /// Starts draining for shutting down. New TCP connections are reset and UDP associations are
+    /// closed. The SOCKS streams of TCP connections are shut down, so a FIN is sent to the source
+    /// after all the data in the cache is sent.
+    pub fn drain(&mut self) {
+        if self.is_draining {
+            return;
+        }
+        self.is_draining = true;
+        self.drain_count = self.streams.len();
+
+        // UDP
+        for index in 0..PORT_COUNT {
+            self.remove_datagram(index, CloseReason::Shutdown);
+        }
+        for (_, mut worker) in self.direct_datagrams.drain() {
+            worker.close_connection(CloseReason::Shutdown);
+        }
+        self.tcp_bind_map.clear();
+
+        // TCP
+        for stream in self.streams.values_mut() {
+            if let Err(ref e) = stream.shutdown() {
+                warn!("handle {}: {}", "TCP", e);
+            }
+        }
+        trace!(
+            "drain {} TCP connections of {}",
+            self.drain_count,
+            self.src_ip_addr
+        );
+    }
+
+    /// Returns if all the TCP connections are closed in draining.
+    pub fn is_drained(&self) -> bool {
+        self.is_draining && self.streams.is_empty()
+    }
+
+    /// Finishes draining, the TCP connections left are reset. Returns the count of TCP
+    /// connections closed in draining and the count of TCP connections reset.
+    pub fn finish_drain(&mut self) -> (usize, usize) {
+        let keys: Vec<_> = self.streams.keys().cloned().collect();
+        let reset_count = keys.len();
+        for key in keys {
+            let (src_port, dst) = key;
+            if let Err(ref e) = self.tx.lock().unwrap().send_tcp_rst(dst, src_port) {
+                warn!("handle {}: {}", "TCP", e);
+            }
+
+            // Clean up
+            self.close_key(key, CloseReason::Shutdown);
+            self.tx.lock().unwrap().remove(dst, src_port);
+        }
+
+        (self.drain_count.saturating_sub(reset_count), reset_count)
+    }
+
+    /// Closes the connection of a TCP connection for the reason and removes it.
+    fn close(&mut self, indicator: &Indicator, reason: CloseReason) {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = match ipv4_addr(tcp.get_dst_ip_addr()) {
+                Some(ip_addr) => SocketAddrV4::new(ip_addr, tcp.get_dst()),
+                None => return,
+            };
+            let key = (tcp.get_src(), dst);
+
+            self.close_key(key, reason);
+        }
+    }
+
+    /// Closes the least recently active established TCP connection of the source for the cause,
+    /// which is counted. Returns `false` if there is no connection to close.
+    fn evict_tcp(&mut self, counter: &metrics::Counter, reason: CloseReason) -> bool {
+        let half_open_map = &self.tcp_half_open_map;
+        let key = match self
+            .streams
+            .iter()
+            .filter(|(key, _)| !half_open_map.contains_key(*key))
+            .max_by_key(|(_, stream)| stream.get_idle())
+        {
+            Some((&key, _)) => key,
+            None => return false,
+        };
+        let (src_port, dst) = key;
+        let tag = self.get_tcp_tag(&key);
+
+        counter.increase();
+        debug!("{}close {} -> {} for {}", tag, src_port, dst, reason);
+        self.terminate_key(key, reason);
+
+        true
+    }
+
+    /// Terminates a TCP connection for the reason and removes it. The source is signaled by a FIN
+    /// if the reason is graceful, or by a RST otherwise.
+    fn terminate_key(&mut self, key: (u16, SocketAddrV4), reason: CloseReason) {
+        let (src_port, dst) = key;
+        let tag = self.get_tcp_tag(&key);
+
+        let mut tx_locked = self.tx.lock().unwrap();
+        let result = if reason.is_graceful() {
+            // Send ACK/FIN
+            tx_locked.send_tcp_ack_fin(dst, src_port)
+        } else {
+            // Send ACK/RST
+            tx_locked.send_tcp_ack_rst(dst, src_port)
+        };
+        if let Err(ref e) = result {
+            warn!("{}handle {}: {}", tag, "TCP", e);
+        }
+
+        // Clean up
+        tx_locked.remove(dst, src_port);
+        drop(tx_locked);
+        self.close_key(key, reason);
+    }
+
+    fn close_key(&mut self, key: (u16, SocketAddrV4), reason: CloseReason) {
+        if let Some(stream) = self.streams.get_mut(&key) {
+            stream.close_connection(reason);
+            if let Err(ref e) = stream.teardown(reason, self.linger) {
+                warn!("{}handle {}: {}", stream.get_tag(), "TCP", e);
+            }
+        }
+        self.remove_key(key);
+    }
+
+    fn remove_key(&mut self, key: (u16, SocketAddrV4)) {
+        let tag = self.get_tcp_tag(&key);
+        let is_removed = self.streams.remove(&key).is_some();
+        if is_removed {
+            if let Some(ref quota) = self.limits.connections {
+                quota.release();
+            }
+        }
+        self.tcp_sequence_map.remove(&key);
+        self.tcp_initial_sequence_map.remove(&key);
+        self.tcp_acknowledgement_map.remove(&key);
+        self.tcp_duplicate_map.remove(&key);
+        self.tcp_cache_map.remove(&key);
+        self.tcp_fin_set.remove(&key);
+        self.tcp_eof_set.remove(&key);
+        self.tcp_activity_map.remove(&key);
+        self.tcp_keepalive_map.remove(&key);
+        self.tcp_half_open_map.remove(&key);
+        if is_removed {
+            self.tcp_closed_map.put(key, self.clock.now());
+        }
+        trace!("{}remove {} -> {}", tag, key.1, key.0);
+    }
+
+    /// Get the tag of the TCP connection prefixed to log lines, which is empty if the connection
+    /// has no stream.
+    fn get_tcp_tag(&self, key: &(u16, SocketAddrV4)) -> Tag {
+        self.streams
+            .get(key)
+            .map(|stream| stream.get_tag())
+            .unwrap_or_default()
+    }
+
+    fn get_tx(&self) -> Arc<Mutex<Downstreamer>> {
+        Arc::clone(&self.tx)
+    }
+
+    /// Removes a UDP association, the SOCKS association is closed and the local port can be reused.
+    /// Counts a failure because of the unreachable proxy. The proxy is probed in the background
+    /// after consecutive failures until it is reachable.
+    fn update_proxy_unreachable(&mut self, e: &io::Error) {
+        self.proxy_failure_count += 1;
+        if self.proxy_failure_count < PROXY_FAILURES_BEFORE_PROBE
+            || self.proxy_unreachable_since.is_some()
+        {
+            return;
+        }
+
+        let since = SystemTime::now();
+        self.proxy_unreachable_since = Some(since);
+        warn!(
+            "proxy unreachable since {}, retrying: {}",
+            humantime::format_rfc3339_seconds(since),
+            e
+        );
+
+        // Probe
+        let remote = self.proxy.get_remote();
+        let is_reachable = Arc::new(AtomicBool::new(false));
+        self.is_proxy_reachable = Arc::clone(&is_reachable);
+        thread::spawn(move || {
+            let mut interval = PROXY_PROBE_INTERVAL;
+            while !is_reachable.load(Ordering::Relaxed) {
+                if socks::probe(remote) {
+                    is_reachable.store(true, Ordering::Relaxed);
+                    break;
+                }
+                thread::sleep(interval);
+                interval = min(interval * 2, MAX_PROXY_PROBE_INTERVAL);
+            }
+        });
+    }
+
+    /// Resets the failures because of the unreachable proxy.
+    fn update_proxy_reachable(&mut self) {
+        self.proxy_failure_count = 0;
+        if let Some(since) = self.proxy_unreachable_since.take() {
+            // Stop the probe
+            self.is_proxy_reachable.store(true, Ordering::Relaxed);
+            info!(
+                "proxy reachable again, which is unreachable since {}",
+                humantime::format_rfc3339_seconds(since)
+            );
+        }
+    }
+
+    fn remove_datagram(&mut self, index: usize, reason: CloseReason) {
+        let mut worker = match self.datagrams[index].take() {
+            Some(worker) => worker,
+            None => return,
+        };
+        worker.close_connection(reason);
+
+        // Remove the port mapping
+        let src_port = worker.get_src_port();
+        let local_port = self.initial_port + index as u16;
+        self.unmap_datagram(index, src_port);
+
+        // The worker is dropped after the port mapping is removed, so a datagram arrives later
+        // creates a new association
+        let tag = worker.get_tag();
+        drop(worker);
+        trace!("{}remove datagram {} = {}", tag, src_port, local_port);
+    }
+
+    /// Removes the mapping of the source port to the local port of the datagram.
+    fn unmap_datagram(&mut self, index: usize, src_port: u16) {
+        let local_port = self.initial_port + index as u16;
+        if self.datagram_map[src_port as usize] == local_port {
+            self.datagram_map[src_port as usize] = 0;
+            self.quic_map.remove(&src_port);
+        }
+        self.udp_lru.pop(&(index as u16));
+        self.udp_lru.put(index as u16, 0);
+    }
+
+    fn get_local_udp_port(&mut self, src_port: u16) -> u16 {
+        let local_port = self.datagram_map[src_port as usize];
+        if local_port == 0 {
+            let pair = self.udp_lru.pop_lru().unwrap();
+            let index = pair.0;
+            let prev_src_port = pair.1;
+            let local_port = self.initial_port + index;
+
+            // Update LRU
+            self.udp_lru.put(index, src_port);
+
+            if prev_src_port != 0 {
+                // Reuse
+                self.datagram_map[prev_src_port as usize] = 0;
+                self.quic_map.remove(&prev_src_port);
+                trace!(
+                    "reuse UDP port {} = {} to {} = {}",
+                    prev_src_port,
+                    local_port,
+                    src_port,
+                    local_port
+                );
+            }
+            self.datagram_map[src_port as usize] = local_port;
+
+            local_port
+        } else {
+            // Update LRU
+            self.udp_lru.get(&local_port);
+
+            local_port
+        }
+    }
+}
+
+/// Represents the time in milliseconds waiting for the proxy after the end of the capture file.
+const EOF_WAIT: u128 = 1000;
+/// Represents the initial interval of reopening the device lost.
+const DEVICE_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// Represents the max interval of reopening the device lost.
+const MAX_DEVICE_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+/// Represents the timeout of discovery ports of sources passed through to the LAN.
+const LAN_PEER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Represents the max number of discovery ports of sources passed through to the LAN.
+const MAX_LAN_PEERS: usize = 256;
+/// Represents the timeout of memberships of sources in multicast groups, which is the group
+/// membership interval of IGMP (RFC 3376).
+const LAN_GROUP_TIMEOUT: Duration = Duration::from_secs(260);
+/// Represents the max number of multicast groups joined by sources.
+const MAX_LAN_GROUPS: usize = 64;
+/// Represents the min interval of warnings of conflicts of the published address.
+const CONFLICT_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+/// Represents the multicast group of SSDP.
+const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+/// Represents the interval of unsolicited router advertisements.
+const RA_INTERVAL: Duration = Duration::from_secs(200);
+/// Represents the min interval of router advertisements answering router solicitations.
+const MIN_RA_INTERVAL: Duration = Duration::from_secs(3);
+/// Represents the lifetime in seconds of the default router in router advertisements.
+const RA_LIFETIME: u16 = 1800;
+
+/// Represents the default timeout of draining connections in shutting down in seconds.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+
+/// Represents if the application is shutting down.
+static IS_SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Shuts down the dispatcher, which closes itself in its next loop. The function is
+/// async-signal-safe.
+pub fn shutdown() {
+    IS_SHUTDOWN.store(true, Ordering::Relaxed);
+}
+
+/// Returns if the dispatcher is shutting down. The function is async-signal-safe.
+pub fn is_shutdown() -> bool {
+    IS_SHUTDOWN.load(Ordering::Relaxed)
+}
+
+/// Represents the generation of the connection table requested, a table is dumped by every
+/// upstreamer when the generation changes.
+static TABLE_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Requests all the upstreamers to dump their connection tables, which are logged in the next
+/// loop of the workers. The function is async-signal-safe.
+pub fn dump_table() {
+    TABLE_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Represents a message to a worker.
+enum WorkerMessage {
+    /// Represents a new upstreamer of a source
+    Upstreamer(Ipv4Addr, Box<Upstreamer>),
+    /// Represents a frame captured from a source
+    Frame(Ipv4Addr, Frame),
+    /// Represents draining the upstreamers before the deadline, the counts of TCP connections
+    /// closed and reset are reported to the channel
+    Drain(Instant, mpsc::Sender<(usize, usize)>),
+    /// Represents keeping the UDP associations of the upstreamers, which are reported to the
+    /// channel
+    Save(mpsc::Sender<Vec<UdpState>>),
+}
+
+/// Represents the dispatcher of frames captured to the upstreamers of sources in workers.
+pub struct Dispatcher {
+    /// Represents the function creating an upstreamer of the source
+    factory: Box<dyn FnMut(Ipv4Addr) -> Upstreamer>,
+    src: Ipv4Network,
+    workers: usize,
+    tx: Arc<Mutex<SendQueue>>,
+    local_hardware_addr: HardwareAddr,
+    /// Represents the sources served by upstreamers in the workers
+    srcs: HashSet<Ipv4Addr>,
+    /// Represents the map mapping a source to its hardware address
+    src_hardware_addr_map: HashMap<Ipv4Addr, HardwareAddr>,
+    /// Represents the count of ranges of UDP ports used by upstreamers
+    port_range_count: usize,
+    /// Represents the count of frames dropped because there are too many sources
+    src_drop_count: usize,
+    /// Represents the count of frames forwarded between sources locally
+    hairpin_count: usize,
+    frame_count: usize,
+    /// Represents the published address announced by gratuitous ARP
+    announce_ip_addr: Option<Ipv4Addr>,
+    arp_interval: Option<Duration>,
+    /// Represents the instant of the last announcement
+    arp_instant: Option<Instant>,
+    /// Represents the hardware address of the real owner of the published address
+    owner_hardware_addr: Option<HardwareAddr>,
+    /// Represents the instant of the last warning of a conflict of the published address
+    conflict_instant: Option<Instant>,
+    dhcp_server: Option<dhcp::Server>,
+    igd_server: Option<Arc<igd::Server>>,
+    dumper: Option<Arc<Mutex<Dumper>>>,
+    filter: Option<Expression>,
+    /// Represents the count of frames dropped by the capture filter
+    filter_drop_count: usize,
+    /// Represents the mode of checksums of frames forwarded between sources
+    checksum_mode: ChecksumMode,
+    /// Represents if broadcast and multicast datagrams from sources are passed through to the LAN
+    is_lan_passthrough: bool,
+    /// Represents the map mapping a discovery port of sources to the source and the instant its
+    /// datagram is passed through to the LAN
+    lan_peers: HashMap<u16, (Ipv4Addr, Instant)>,
+    /// Represents the map mapping a host in the LAN to the instant it answers datagrams passed
+    /// through, unicast datagrams from sources to it are passed through too
+    lan_hosts: HashMap<Ipv4Addr, Instant>,
+    /// Represents the map mapping a multicast group to the sources joined it by IGMP and the
+    /// instants of their last reports, datagrams from the LAN to the group are relayed to them
+    lan_groups: HashMap<Ipv4Addr, HashMap<Ipv4Addr, Instant>>,
+    /// Represents the local address, which is the sender of ARP requests
+    local_ip_addr: Option<Ipv4Addr>,
+    /// Represents the resolver of hardware addresses of hosts in the LAN
+    resolver: Resolver,
+    /// Represents the addresses answered in NDP, which are the link-local address and the
+    /// published IPv6 address
+    ndp_ip_addrs: Vec<Ipv6Addr>,
+    /// Represents the prefix advertised in router advertisements
+    ra_prefix: Option<Ipv6Network>,
+    /// Represents the instant of the last router advertisement
+    ra_instant: Option<Instant>,
+    shutdown_timeout: Duration,
+    /// Represents if the dispatcher is stopped besides the application shutting down
+    is_stopped: Arc<AtomicBool>,
+    /// Represents the timeout of reopening the device lost
+    device_retry: Duration,
+    /// Represents the function reopening the device lost
+    reopen: Option<Box<dyn FnMut() -> io::Result<(Sender, Receiver)>>>,
+    /// Represents if UDP associations are kept in shutting down
+    is_udp_state: bool,
+    /// Represents the UDP associations of the last run waiting for their sources
+    udp_states: Vec<UdpState>,
+    /// Represents the UDP associations kept in shutting down
+    saved_udp_states: Option<Vec<UdpState>>,
+    /// Represents the pool of frames handled by upstreamers, which are copied out of the
+    /// capture once
+    pool: FramePool,
+    /// Represents the key of IPv4 identifications of packets sent by the dispatcher
+    isn_key: RandomState,
+    /// Represents the next IPv4 identifications of packets by their sources and protocols
+    ipv4_identification_map: HashMap<(Ipv4Addr, LayerType), u16>,
+}
+
+impl Dispatcher {
+    /// Creates a new `Dispatcher`.
+    pub fn new(
+        factory: Box<dyn FnMut(Ipv4Addr) -> Upstreamer>,
+        src: Ipv4Network,
+        workers: usize,
+        tx: Arc<Mutex<SendQueue>>,
+        local_hardware_addr: HardwareAddr,
+    ) -> Dispatcher {
+        Dispatcher {
+            factory,
+            src,
+            workers: max(workers, 1),
+            tx,
+            local_hardware_addr,
+            srcs: HashSet::new(),
+            src_hardware_addr_map: HashMap::new(),
+            port_range_count: 0,
+            src_drop_count: 0,
+            hairpin_count: 0,
+            frame_count: 0,
+            announce_ip_addr: None,
+            arp_interval: None,
+            arp_instant: None,
+            owner_hardware_addr: None,
+            conflict_instant: None,
+            dhcp_server: None,
+            igd_server: None,
+            dumper: None,
+            filter: None,
+            filter_drop_count: 0,
+            checksum_mode: ChecksumMode::Auto,
+            is_lan_passthrough: false,
+            lan_peers: HashMap::new(),
+            lan_hosts: HashMap::new(),
+            lan_groups: HashMap::new(),
+            local_ip_addr: None,
+            resolver: Resolver::new(),
+            ndp_ip_addrs: Vec::new(),
+            ra_prefix: None,
+            ra_instant: None,
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT),
+            is_stopped: Arc::new(AtomicBool::new(false)),
+            device_retry: Duration::from_secs(0),
+            reopen: None,
+            is_udp_state: false,
+            udp_states: Vec::new(),
+            saved_udp_states: None,
+            pool: FramePool::new(),
+            isn_key: RandomState::new(),
+            ipv4_identification_map: HashMap::new(),
+        }
+    }
+
+    /// Sets the function reopening the device lost, and the timeout of retrying it. Connections
+    /// are kept while the device is reopened.
+    pub fn set_reopen(
+        &mut self,
+        timeout: Duration,
+        reopen: Box<dyn FnMut() -> io::Result<(Sender, Receiver)>>,
+    ) {
+        self.device_retry = timeout;
+        self.reopen = Some(reopen);
+        trace!("set device retry to {} s", timeout.as_secs());
+    }
+
+    /// Sets the capture filter, frames the filter does not match are dropped before parsing.
+    pub fn set_filter(&mut self, filter: Expression) {
+        trace!("set capture filter to {}", filter);
+        self.filter = Some(filter);
+    }
+
+    /// Sets the dumper of frames received.
+    pub fn set_dumper(&mut self, dumper: Arc<Mutex<Dumper>>) {
+        self.dumper = Some(dumper);
+        trace!("set dumper");
+    }
+
+    /// Sets the mode of checksums of frames forwarded between sources.
+    pub fn set_checksum_mode(&mut self, mode: ChecksumMode) {
+        self.checksum_mode = mode;
+        trace!("set checksum mode to {}", mode);
+    }
+
+    /// Sets if broadcast and multicast datagrams from sources are passed through to the LAN, and
+    /// the responses from the LAN are forwarded back. They are dropped if not set.
+    pub fn set_lan_passthrough(&mut self, is_passthrough: bool) {
+        self.is_lan_passthrough = is_passthrough;
+        trace!("set LAN passthrough to {}", is_passthrough);
+    }
+
+    /// Sets the UDP associations of the last run, which are restored before the first frames of
+    /// their sources are processed. UDP associations are kept in shutting down to be taken by
+    /// `take_udp_states`.
+    pub fn set_udp_states(&mut self, states: Vec<UdpState>) {
+        trace!("set {} UDP associations to restore", states.len());
+        self.is_udp_state = true;
+        self.udp_states = states;
+    }
+
+    /// Takes the UDP associations kept in shutting down, returns `None` if the dispatcher is not
+    /// shut down gracefully.
+    pub fn take_udp_states(&mut self) -> Option<Vec<UdpState>> {
+        self.saved_udp_states.take()
+    }
+
+    /// Sets the local address, which is the sender of ARP requests resolving hosts in the LAN.
+    /// Unicast datagrams are never passed through to the LAN if it is not set.
+    pub fn set_local_ip_addr(&mut self, ip_addr: Ipv4Addr) {
+        self.local_ip_addr = Some(ip_addr);
+        trace!("set local address to {}", ip_addr);
+    }
+
+    /// Sets the published IPv6 address, which is answered in NDP and echoes besides the
+    /// link-local address of local.
+    pub fn set_ndp_publish(&mut self, ip_addr: Ipv6Addr) {
+        self.insert_ndp_ip_addr(ip_addr);
+        trace!("set NDP publish of {}", ip_addr);
+    }
+
+    /// Sets the prefix advertised in router advertisements on opening, periodically and
+    /// answering router solicitations. Sources configure their addresses in the prefix and use
+    /// the link-local address of local as the default router.
+    pub fn set_router_advertisement(&mut self, prefix: Ipv6Network) {
+        self.ra_prefix = Some(prefix);
+        self.insert_ndp_ip_addr(icmpv6::link_local(self.local_hardware_addr));
+        trace!("set router advertisement of {}", prefix);
+    }
+
+    fn insert_ndp_ip_addr(&mut self, ip_addr: Ipv6Addr) {
+        // The link-local address is always answered once NDP is enabled
+        let link_local = icmpv6::link_local(self.local_hardware_addr);
+        if !self.ndp_ip_addrs.contains(&link_local) {
+            self.ndp_ip_addrs.push(link_local);
+        }
+        if !self.ndp_ip_addrs.contains(&ip_addr) {
+            self.ndp_ip_addrs.push(ip_addr);
+        }
+    }
+
+    /// Sets the timeout of draining TCP connections in shutting down.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+        trace!("set shutdown timeout to {} s", timeout.as_secs());
+    }
+
+    /// Sets the flag stopping the dispatcher, which shuts down the dispatcher gracefully like
+    /// shutting down the application.
+    pub fn set_stop(&mut self, is_stopped: Arc<AtomicBool>) {
+        self.is_stopped = is_stopped;
+        trace!("set stop");
+    }
+
+    /// Sets the DHCP server leasing addresses to sources.
+    pub fn set_dhcp_server(&mut self, server: dhcp::Server) {
+        self.dhcp_server = Some(server);
+        trace!("set DHCP server");
+    }
+
+    /// Sets the responder of port mappings answering SSDP searches and NAT-PMP requests of
+    /// sources.
+    pub fn set_igd_server(&mut self, server: Arc<igd::Server>) {
+        self.igd_server = Some(server);
+        trace!("set IGD server");
+    }
+
+    /// Sets the published address announced by gratuitous ARP on opening, and periodically in
+    /// the given interval.
+    pub fn set_announcement(&mut self, ip_addr: Ipv4Addr, interval: Option<Duration>) {
+        self.announce_ip_addr = Some(ip_addr);
+        self.arp_interval = interval;
+        match interval {
+            Some(interval) => trace!(
+                "set announcement of {} in {} s",
+                ip_addr,
+                interval.as_secs()
+            ),
+            None => trace!("set announcement of {}", ip_addr),
+        }
+    }
+
+    /// Opens the dispatcher. Frames of a TCP connection or of a UDP source port of a source are
+    /// always dispatched to the same worker, and the others, like ARP and IPv4 fragments, are
+    /// dispatched to the first worker. Each source has its upstreamers in all the workers.
+    pub fn open(&mut self, rx: &mut Receiver) -> io::Result<()> {
+        // Process in place if there is a single worker and a single source
+        let mut upstreamer = None;
+        let mut txs = Vec::with_capacity(self.workers);
+        let mut handles = Vec::with_capacity(self.workers);
+        if self.workers == 1 && self.src.prefix() == 32 {
+            let mut in_place = (self.factory)(self.src.ip());
+            self.restore_udp_states(&mut in_place);
+            self.udp_states.clear();
+            upstreamer = Some(in_place);
+        } else {
+            for _ in 0..self.workers {
+                let (tx, rx) = mpsc::channel();
+                txs.push(tx);
+                handles.push(thread::spawn(move || run_worker(rx)));
+            }
+        }
+
+        // Represents the deadline of draining in shutting down
+        let mut deadline = None;
+        let (result_tx, result_rx) = mpsc::channel();
+        let mut results = Vec::with_capacity(self.workers);
+        let (state_tx, state_rx) = mpsc::channel();
+        let mut states = Vec::new();
         loop {
-            match rx.next() {
+            if self.is_shutting_down() {
+                match deadline {
+                    None => {
+                        info!(
+                            "Shut down in {} s, interrupt again to exit immediately",
+                            self.shutdown_timeout.as_secs()
+                        );
+                        let instant = Instant::now() + self.shutdown_timeout;
+                        deadline = Some(instant);
+                        // UDP associations are kept before they are closed in draining
+                        if let Some(ref mut upstreamer) = upstreamer {
+                            if self.is_udp_state {
+                                states.extend(upstreamer.get_udp_states());
+                            }
+                            upstreamer.drain();
+                        }
+                        for tx in txs.iter() {
+                            if self.is_udp_state {
+                                let _ = tx.send(WorkerMessage::Save(state_tx.clone()));
+                            }
+                            let _ = tx.send(WorkerMessage::Drain(instant, result_tx.clone()));
+                        }
+                    }
+                    Some(instant) => {
+                        if let Some(ref mut upstreamer) = upstreamer {
+                            if results.is_empty()
+                                && (upstreamer.is_drained() || Instant::now() >= instant)
+                            {
+                                results.push(upstreamer.finish_drain());
+                            }
+                        }
+                        results.extend(result_rx.try_iter());
+
+                        // Workers which never report are not waited for after the deadline
+                        let is_late =
+                            Instant::now() >= instant + Duration::from_millis(EOF_WAIT as u64);
+                        if results.len() >= max(txs.len(), 1) || is_late {
+                            drop(txs);
+                            for handle in handles {
+                                let _ = handle.join();
+                            }
+                            if self.is_udp_state {
+                                states.extend(state_rx.try_iter().flatten());
+                                self.saved_udp_states = Some(states);
+                            }
+                            let (closed, reset) =
+                                results.iter().fold((0, 0), |(closed, reset), result| {
+                                    (closed + result.0, reset + result.1)
+                                });
+                            info!(
+                                "Shut down, {} TCP connections are closed and {} are reset",
+                                closed, reset
+                            );
+
+                            return self.close();
+                        }
+                    }
+                }
+            }
+            if let Some(ref mut upstreamer) = upstreamer {
+                upstreamer.handle_timers();
+            }
+            if let Err(ref e) = self.handle_announcement() {
+                warn!("handle {}: {}", "ARP", e);
+            }
+            if let Err(ref e) = self.handle_resolver() {
+                warn!("handle {}: {}", "ARP", e);
+            }
+            if let Err(ref e) = self.handle_router_advertisement() {
+                warn!("handle {}: {}", "NDP", e);
+            }
+
+            match rx.next_frame() {
                 Ok(frame) => {
-                    if let Some(ref indicator) = Indicator::from(frame) {
-                        if let Some(t) = indicator.get_network_type() {
-                            match t {
-                                LayerTypes::Arp => {
-                                    if let Err(ref e) = self.handle_arp(indicator) {
-                                        warn!("handle {}: {}", indicator.brief(), e);
-                                    }
-                                }
-                                LayerTypes::Ipv4 => {
-                                    if let Err(ref e) = self.handle_ipv4(indicator, frame) {
-                                        warn!("handle {}: {}", indicator.brief(), e);
-                                    }
+                    self.frame_count += 1;
+                    metrics::PACKETS_CAPTURED.increase();
+                    if pcap::is_stacked(frame) {
+                        debug!("drop frame ({} Bytes) in stacked VLANs", frame.len());
+                        event::drop_packet(DropReason::StackedVlan, LayerTypes::Vlan);
+                        continue;
+                    }
+                    if let Some(ref filter) = self.filter {
+                        if !filter.is_match(frame) {
+                            self.filter_drop_count += 1;
+                            event::drop_packet(DropReason::Filter, LayerTypes::Ethernet);
+                            continue;
+                        }
+                    }
+                    if let Err(ref e) = self.dump(frame) {
+                        warn!("handle {}: {}", "dump", e);
+                    }
+                    capture::record(frame, Direction::Received);
+                    // Control frames of switches are expected, which are counted silently
+                    if let Some(kind) = pcap::get_control_kind(frame) {
+                        metrics::count_control(kind);
+                        continue;
+                    }
+                    if let Err(ref e) = self.handle_arp(frame) {
+                        warn!("handle {}: {}", "ARP", e);
+                    }
+                    match self.handle_ndp(frame) {
+                        Ok(true) => continue,
+                        Ok(false) => {}
+                        Err(ref e) => {
+                            warn!("handle {}: {}", "NDP", e);
+                            continue;
+                        }
+                    }
+                    match self.handle_dhcp(frame) {
+                        Ok(true) => continue,
+                        Ok(false) => {}
+                        Err(ref e) => {
+                            warn!("handle {}: {}", "DHCP", e);
+                            continue;
+                        }
+                    }
+                    match self.handle_igd(frame) {
+                        Ok(true) => continue,
+                        Ok(false) => {}
+                        Err(ref e) => {
+                            warn!("handle {}: {}", "IGD", e);
+                            continue;
+                        }
+                    }
+                    match self.handle_lan(frame) {
+                        Ok(true) => continue,
+                        Ok(false) => {}
+                        Err(ref e) => {
+                            warn!("handle {}: {}", "LAN", e);
+                            continue;
+                        }
+                    }
+                    match upstreamer {
+                        Some(ref mut upstreamer) => {
+                            upstreamer.handle_frame(&self.pool.copy_from(frame))
+                        }
+                        None => self.dispatch(frame, &txs)?,
+                    }
+                }
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
+                        continue;
+                    }
+                    // The capture may be interrupted by signals
+                    if self.is_shutting_down() || e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    // End of the capture file, wait for the proxy before closing
+                    if e.kind() == io::ErrorKind::UnexpectedEof {
+                        let instant = Instant::now();
+                        while instant.elapsed().as_millis() < EOF_WAIT {
+                            if let Some(ref mut upstreamer) = upstreamer {
+                                upstreamer.handle_timers();
+                            }
+                            thread::sleep(Duration::from_millis(TIMER_INTERVAL as u64));
+                        }
+                        // Workers process the frames left before they stop
+                        drop(txs);
+                        for handle in handles {
+                            let _ = handle.join();
+                        }
+
+                        return self.close();
+                    }
+                    // The device may come back, like a USB adapter plugged again
+                    if self.reopen.is_some() && pcap::is_device_lost(&e) {
+                        if self.recover(rx, &mut upstreamer, &e) {
+                            continue;
+                        }
+                        warn!(
+                            "Device is not recovered in {} s",
+                            self.device_retry.as_secs()
+                        );
+                    }
+                    self.close()?;
+                    return Err(e);
+                }
+            };
+        }
+    }
+
+    /// Reopens the device lost with an exponential backoff in the device retry timeout. Timers
+    /// of the upstreamer keep running and frames to the sources are kept in the send queue, so
+    /// connections survive. Returns `false` if the device is not reopened.
+    fn recover(
+        &mut self,
+        rx: &mut Receiver,
+        upstreamer: &mut Option<Upstreamer>,
+        e: &io::Error,
+    ) -> bool {
+        warn!(
+            "Device lost: {}, reopen in {} s",
+            e,
+            self.device_retry.as_secs()
+        );
+        self.tx.lock().unwrap().set_down();
+
+        let instant = Instant::now();
+        let mut interval = DEVICE_RETRY_INTERVAL;
+        let mut retry_instant = instant + interval;
+        while instant.elapsed() < self.device_retry {
+            // Shutting down continues in the dispatcher
+            if self.is_shutting_down() {
+                return true;
+            }
+            if let Some(ref mut upstreamer) = upstreamer {
+                upstreamer.handle_timers();
+            }
+            if Instant::now() < retry_instant {
+                thread::sleep(Duration::from_millis(TIMER_INTERVAL as u64));
+                continue;
+            }
+
+            match (self.reopen.as_mut().unwrap())() {
+                Ok((tx, reopened_rx)) => {
+                    *rx = reopened_rx;
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    if let Err(ref e) = tx_locked.set_sender(tx) {
+                        warn!("handle {}: {}", "device", e);
+                    }
+                    metrics::DEVICE_RESTARTS.increase();
+                    info!(
+                        "Device recovered in {} ms, {} frames dropped while it is down",
+                        instant.elapsed().as_millis(),
+                        tx_locked.get_down_drop_count()
+                    );
+
+                    return true;
+                }
+                Err(ref e) => debug!("reopen device: {}", e),
+            }
+            interval = min(interval * 2, MAX_DEVICE_RETRY_INTERVAL);
+            retry_instant = Instant::now() + interval;
+        }
+
+        false
+    }
+
+    /// Returns if the application is shutting down or the dispatcher is stopped.
+    fn is_shutting_down(&self) -> bool {
+        is_shutdown() || self.is_stopped.load(Ordering::Relaxed)
+    }
+
+    /// Closes the dispatcher. The published address is announced with the hardware address of
+    /// its real owner if it is known, so the sources recover immediately. Local is withdrawn from
+    /// the default router if it is advertised.
+    fn close(&mut self) -> io::Result<()> {
+        if let (Some(ip_addr), Some(hardware_addr)) =
+            (self.announce_ip_addr, self.owner_hardware_addr)
+        {
+            self.announce(ip_addr, hardware_addr)?;
+            info!("Restore {} at {}", ip_addr, hardware_addr);
+        }
+        if self.ra_prefix.is_some() {
+            self.advertise_router(0)?;
+        }
+
+        self.tx.lock().unwrap().flush()?;
+        if let Some(ref dumper) = self.dumper {
+            dumper.lock().unwrap().flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Dumps a frame received, frames sent by local are not dumped.
+    fn dump(&mut self, frame: &[u8]) -> io::Result<()> {
+        let dumper = match self.dumper {
+            Some(ref dumper) => dumper,
+            None => return Ok(()),
+        };
+        if frame.len() < ETHERNET_HEADER_SIZE {
+            return Ok(());
+        }
+        let hardware_addr =
+            HardwareAddr::new(frame[6], frame[7], frame[8], frame[9], frame[10], frame[11]);
+        if hardware_addr == self.local_hardware_addr {
+            return Ok(());
+        }
+
+        dumper.lock().unwrap().dump(frame, Direction::Received)
+    }
+
+    fn handle_announcement(&mut self) -> io::Result<()> {
+        let ip_addr = match self.announce_ip_addr {
+            Some(ip_addr) => ip_addr,
+            None => return Ok(()),
+        };
+        if let Some(instant) = self.arp_instant {
+            match self.arp_interval {
+                Some(interval) if instant.elapsed() >= interval => {}
+                _ => return Ok(()),
+            }
+        }
+        self.arp_instant = Some(Instant::now());
+
+        self.announce(ip_addr, self.local_hardware_addr)
+    }
+
+    /// Get the IPv4 identification of the next packet the dispatcher sends from the address in
+    /// the protocol, which starts from a secret random value like the ones of downstreamers.
+    fn next_ipv4_identification(&mut self, ip_addr: Ipv4Addr, t: LayerType) -> u16 {
+        let isn_key = &self.isn_key;
+        let entry = self
+            .ipv4_identification_map
+            .entry((ip_addr, t))
+            .or_insert_with(|| {
+                let mut hasher = isn_key.build_hasher();
+                (ip_addr, t).hash(&mut hasher);
+                hasher.finish() as u16
+            });
+        let identification = *entry;
+        *entry = entry.wrapping_add(1);
+
+        identification
+    }
+
+    /// Announces the address at the hardware address by a gratuitous ARP request and a
+    /// gratuitous ARP reply.
+    fn announce(&mut self, ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) -> io::Result<()> {
+        let request = Arp::new_request(hardware_addr, ip_addr, ip_addr);
+        self.send_arp(request, HardwareAddr::broadcast(), None)?;
+        let reply = Arp::new_reply(hardware_addr, ip_addr, HardwareAddr::broadcast(), ip_addr);
+        self.send_arp(reply, HardwareAddr::broadcast(), None)?;
+        debug!("announce {} at {}", ip_addr, hardware_addr);
+
+        Ok(())
+    }
+
+    /// Sends ARP requests of hosts in the LAN being resolved, and of hosts in use before their
+    /// hardware addresses expire.
+    fn handle_resolver(&mut self) -> io::Result<()> {
+        let local_ip_addr = match self.local_ip_addr {
+            Some(ip_addr) => ip_addr,
+            None => return Ok(()),
+        };
+
+        for ip_addr in self.resolver.handle_timers() {
+            // Hardware addresses are refreshed by unicast requests
+            let dst_hardware_addr = self
+                .resolver
+                .get(ip_addr)
+                .unwrap_or_else(HardwareAddr::broadcast);
+            let request = Arp::new_request(self.local_hardware_addr, local_ip_addr, ip_addr);
+            self.send_arp(request, dst_hardware_addr, None)?;
+            trace!("resolve {} by ARP", ip_addr);
+        }
+
+        Ok(())
+    }
+
+    fn handle_router_advertisement(&mut self) -> io::Result<()> {
+        if self.ra_prefix.is_none() {
+            return Ok(());
+        }
+        if let Some(instant) = self.ra_instant {
+            if instant.elapsed() < RA_INTERVAL {
+                return Ok(());
+            }
+        }
+
+        self.advertise_router(RA_LIFETIME)
+    }
+
+    /// Advertises local as the default router of the lifetime with the prefix to all the nodes,
+    /// a lifetime of zero withdraws local from the default router.
+    fn advertise_router(&mut self, lifetime: u16) -> io::Result<()> {
+        let prefix = match self.ra_prefix {
+            Some(prefix) => prefix,
+            None => return Ok(()),
+        };
+        self.ra_instant = Some(Instant::now());
+
+        let advert = Icmpv6::new_router_advert(
+            icmpv6::link_local(self.local_hardware_addr),
+            icmpv6::ALL_NODES,
+            self.local_hardware_addr,
+            lifetime,
+            prefix.network(),
+            prefix.prefix(),
+        );
+        self.send_icmpv6(
+            advert,
+            ethernet::ipv6_multicast_hardware_addr(icmpv6::ALL_NODES),
+            None,
+        )?;
+        debug!("advertise router with prefix {} in {} s", prefix, lifetime);
+
+        Ok(())
+    }
+
+    /// Handles NDP messages and echoes to the addresses answered in NDP, returns `true` if the
+    /// frame is handled, which is never dispatched to upstreamers. Neighbor solicitations of
+    /// the duplicate address detection of other addresses are never answered (RFC 4862).
+    fn handle_ndp(&mut self, frame: &[u8]) -> io::Result<bool> {
+        if self.ndp_ip_addrs.is_empty() {
+            return Ok(false);
+        }
+        // Filter ICMPv6 frames before parsing
+        match pcap::get_network(frame) {
+            Some((0x86dd, ip)) if ip.len() >= 40 && ip[6] == 58 => {}
+            _ => return Ok(false),
+        }
+        let indicator = match Indicator::from(frame) {
+            Ok(indicator) => indicator,
+            Err(_) => return Ok(false),
+        };
+        let (ipv6, icmpv6) = match (indicator.get_ipv6(), indicator.get_icmpv6()) {
+            (Some(ipv6), Some(icmpv6)) => (ipv6, icmpv6),
+            _ => return Ok(false),
+        };
+        let ethernet = indicator.get_ethernet().unwrap();
+        // Frames sent from local are captured too
+        if ethernet.get_src() == self.local_hardware_addr {
+            return Ok(true);
+        }
+        // Solicitations are sent to the solicited-node multicast address, which is mapped to a
+        // multicast hardware address
+        let dst = ipv6.get_dst();
+        let is_to_local = ethernet.get_dst() == self.local_hardware_addr
+            || (dst.is_multicast()
+                && ethernet.get_dst() == ethernet::ipv6_multicast_hardware_addr(dst));
+        if !is_to_local {
+            return Ok(false);
+        }
+
+        let src = ipv6.get_src();
+        let hardware_addr = ethernet.get_src();
+        if icmpv6.is_echo_request() {
+            if !self.ndp_ip_addrs.contains(&dst) {
+                return Ok(false);
+            }
+            if !icmpv6.verify_checksum() {
+                debug!("drop {} for invalid checksum", indicator.brief());
+
+                return Ok(true);
+            }
+            let reply = Icmpv6::new_echo_reply(
+                dst,
+                src,
+                icmpv6.get_identifier(),
+                icmpv6.get_sequence(),
+                icmpv6.get_payload(),
+            );
+            self.send_icmpv6(reply, hardware_addr, indicator.get_vlan())?;
+
+            return Ok(true);
+        }
+
+        // NDP messages are always sent in the hop limit of 255, others are forged from outside
+        // the link (RFC 4861)
+        if ipv6.get_hop_limit() != icmpv6::NDP_HOP_LIMIT
+            || icmpv6.get_code() != 0
+            || !icmpv6.verify_checksum()
+        {
+            return Ok(false);
+        }
+        if icmpv6.is_router_solicit() {
+            if self.ra_prefix.is_none() {
+                return Ok(false);
+            }
+            match self.ra_instant {
+                Some(instant) if instant.elapsed() < MIN_RA_INTERVAL => {}
+                _ => self.advertise_router(RA_LIFETIME)?,
+            }
+
+            return Ok(true);
+        }
+        if !icmpv6.is_neighbor_solicit() {
+            return Ok(false);
+        }
+        let target = match icmpv6.get_target() {
+            Some(target) if self.ndp_ip_addrs.contains(&target) => target,
+            _ => return Ok(false),
+        };
+        debug!(
+            "receive from pcap: {} ({} Bytes)",
+            indicator.describe(),
+            indicator.get_size()
+        );
+
+        let is_router = self.ra_prefix.is_some();
+        if src.is_unspecified() {
+            // Defend the address against the duplicate address detection, the advertisement is
+            // unsolicited and sent to all the nodes
+            let advert = Icmpv6::new_neighbor_advert(
+                target,
+                icmpv6::ALL_NODES,
+                target,
+                self.local_hardware_addr,
+                is_router,
+                false,
+            );
+            self.send_icmpv6(
+                advert,
+                ethernet::ipv6_multicast_hardware_addr(icmpv6::ALL_NODES),
+                indicator.get_vlan(),
+            )?;
+            debug!("defend {} from DAD of {}", target, hardware_addr);
+        } else {
+            let dst_hardware_addr = icmpv6.get_src_hardware_addr().unwrap_or(hardware_addr);
+            let advert = Icmpv6::new_neighbor_advert(
+                target,
+                src,
+                target,
+                self.local_hardware_addr,
+                is_router,
+                true,
+            );
+            self.send_icmpv6(advert, dst_hardware_addr, indicator.get_vlan())?;
+        }
+
+        Ok(true)
+    }
+
+    /// Sends an ICMPv6 message from local, which is in the hop limit of NDP messages.
+    fn send_icmpv6(
+        &mut self,
+        icmpv6: Icmpv6,
+        dst_hardware_addr: HardwareAddr,
+        vlan: Option<&Vlan>,
+    ) -> io::Result<()> {
+        let mut ipv6 = Ipv6::new(
+            icmpv6.get_type(),
+            icmpv6.get_src_ip_addr(),
+            icmpv6.get_dst_ip_addr(),
+        )
+        .unwrap();
+        ipv6.set_hop_limit(icmpv6::NDP_HOP_LIMIT);
+        let (ethernet, vlan) = new_link(
+            ipv6.get_type(),
+            self.local_hardware_addr,
+            dst_hardware_addr,
+            vlan,
+        );
+        let indicator = Indicator::with_vlan(
+            ethernet,
+            vlan,
+            Some(Layers::Ipv6(ipv6)),
+            Some(Layers::Icmpv6(icmpv6)),
+        );
+        let frame = indicator.to_vec()?;
+
+        self.tx.lock().unwrap().send(&frame, true)
+    }
+
+    fn handle_arp(&mut self, frame: &[u8]) -> io::Result<()> {
+        match pcap::get_network(frame) {
+            Some((0x0806, _)) => {}
+            _ => return Ok(()),
+        }
+        let indicator = match Indicator::from(frame) {
+            Ok(indicator) => indicator,
+            Err(_) => return Ok(()),
+        };
+        let arp = match indicator.get_arp() {
+            Some(arp) => arp,
+            None => return Ok(()),
+        };
+        let kind = match arp.get_kind() {
+            Some(kind) => kind,
+            None => return Ok(()),
+        };
+
+        // Learn hosts in the LAN being resolved from the addresses they claim, and send the
+        // frames queued for them
+        let hardware_addr = arp.get_src_hardware_addr();
+        if let (Some(claimed), true) =
+            (arp.get_claimed(), hardware_addr != self.local_hardware_addr)
+        {
+            for mut frame in self.resolver.learn(claimed, hardware_addr) {
+                frame[0..6].copy_from_slice(&[
+                    hardware_addr.0,
+                    hardware_addr.1,
+                    hardware_addr.2,
+                    hardware_addr.3,
+                    hardware_addr.4,
+                    hardware_addr.5,
+                ]);
+                metrics::PACKETS_FORWARDED.increase();
+
+                self.tx.lock().unwrap().send(&frame, false)?;
+            }
+        }
+
+        let ip_addr = match self.announce_ip_addr {
+            Some(ip_addr) => ip_addr,
+            None => return Ok(()),
+        };
+        match kind {
+            // Defend the published address against the duplicate address detection, a prober
+            // claims no address so it never conflicts, and probes of other addresses are never
+            // replied
+            ArpKind::Probe => {
+                if arp.get_requested() == Some(ip_addr) {
+                    let reply = Arp::new_reply(
+                        self.local_hardware_addr,
+                        ip_addr,
+                        hardware_addr,
+                        Ipv4Addr::UNSPECIFIED,
+                    );
+                    self.send_arp(reply, hardware_addr, indicator.get_vlan())?;
+                    debug!("defend {} from ARP probe of {}", ip_addr, hardware_addr);
+                }
+            }
+            // Learn the real owner of the published address, which conflicts with local
+            ArpKind::Request | ArpKind::Reply | ArpKind::Announcement => {
+                if let Some(claimant) = arp::get_claimant(arp, ip_addr, self.local_hardware_addr) {
+                    metrics::ARP_CONFLICTS.increase();
+                    let is_warned = match self.conflict_instant {
+                        Some(instant) => instant.elapsed() < CONFLICT_WARNING_INTERVAL,
+                        None => false,
+                    };
+                    if !is_warned {
+                        self.conflict_instant = Some(Instant::now());
+                        warn!(
+                            "ADDRESS CONFLICT: {} is also claimed by {}, sources may lose connectivity until it leaves or another address is published",
+                            ip_addr, claimant
+                        );
+                    }
+                    if self.owner_hardware_addr != Some(claimant) {
+                        self.owner_hardware_addr = Some(claimant);
+                        trace!("set owner hardware address of {} to {}", ip_addr, claimant);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles DHCP messages, returns `true` if the frame is a DHCP message to servers, which
+    /// is never dispatched to upstreamers.
+    fn handle_dhcp(&mut self, frame: &[u8]) -> io::Result<bool> {
+        let server = match self.dhcp_server {
+            Some(ref mut server) => server,
+            None => return Ok(false),
+        };
+        // Filter UDP frames of DHCP before parsing
+        let ip = match pcap::get_network(frame) {
+            Some((0x0800, ip)) if ip.len() >= 20 && ip[9] == 17 => ip,
+            _ => return Ok(false),
+        };
+        let offset = (ip[0] & 0x0f) as usize * 4;
+        let dst = match ip.get(offset + 2..offset + 4) {
+            Some(dst) => u16::from_be_bytes([dst[0], dst[1]]),
+            None => return Ok(false),
+        };
+        if dst != dhcp::SERVER_PORT && dst != dhcp::CLIENT_PORT {
+            return Ok(false);
+        }
+
+        let indicator = match Indicator::from(frame) {
+            Ok(indicator) => indicator,
+            Err(_) => return Ok(false),
+        };
+        let udp = match indicator.get_udp() {
+            Some(udp) => udp,
+            None => return Ok(false),
+        };
+        let message = match dhcp::Message::parse(udp.get_payload()) {
+            Some(message) => message,
+            None => return Ok(dst == dhcp::SERVER_PORT),
+        };
+
+        // Another DHCP server
+        if udp.get_src() == dhcp::SERVER_PORT && dst == dhcp::CLIENT_PORT {
+            let hardware_addr = indicator.get_ethernet().unwrap().get_src();
+            if hardware_addr != self.local_hardware_addr && server.handle_other(&message) {
+                warn!(
+                    "Another DHCP server {} is present at {}",
+                    message.get_server_id().unwrap(),
+                    hardware_addr
+                );
+            }
+
+            return Ok(false);
+        }
+        if dst != dhcp::SERVER_PORT {
+            return Ok(false);
+        }
+        debug!("receive from pcap: {}", message);
+
+        let reply = match server.handle(&message) {
+            Some(reply) => reply,
+            None => return Ok(true),
+        };
+        match reply.get_type() {
+            dhcp::MessageType::Ack if !reply.get_yiaddr().is_unspecified() => {
+                info!("Lease {} to {}", reply.get_yiaddr(), reply.get_chaddr())
+            }
+            dhcp::MessageType::Nak => debug!("{}", reply),
+            _ => {}
+        }
+
+        // Reply to the client address if it is configured, or broadcast if the client asks
+        // for or the reply is a DHCPNAK (RFC 2131)
+        let (dst_hardware_addr, dst_ip_addr) = if reply.get_type() == dhcp::MessageType::Nak
+            || (reply.is_broadcast() && reply.get_ciaddr().is_unspecified())
+        {
+            (HardwareAddr::broadcast(), Ipv4Addr::BROADCAST)
+        } else if !reply.get_ciaddr().is_unspecified() {
+            (reply.get_chaddr(), reply.get_ciaddr())
+        } else {
+            (reply.get_chaddr(), reply.get_yiaddr())
+        };
+        let src_ip_addr = reply.get_server_id().unwrap();
+        let payload = reply.serialize();
+
+        let (ethernet, vlan) = new_link(
+            LayerTypes::Ipv4,
+            self.local_hardware_addr,
+            dst_hardware_addr,
+            indicator.get_vlan(),
+        );
+        let identification = self.next_ipv4_identification(src_ip_addr, LayerTypes::Udp);
+        let ipv4 = Ipv4::new(identification, LayerTypes::Udp, src_ip_addr, dst_ip_addr).unwrap();
+        let udp = Udp::new(
+            IpAddr::V4(src_ip_addr),
+            IpAddr::V4(dst_ip_addr),
+            dhcp::SERVER_PORT,
+            dhcp::CLIENT_PORT,
+        );
+        let indicator = Indicator::with_vlan(
+            ethernet,
+            vlan,
+            Some(Layers::Ipv4(ipv4)),
+            Some(Layers::Udp(udp)),
+        );
+
+        let frame = indicator.to_vec_with_payload(&payload)?;
+        self.tx.lock().unwrap().send(&frame, true)?;
+        debug!("send to pcap: {}", reply);
+
+        Ok(true)
+    }
+
+    /// Handles SSDP searches and NAT-PMP requests of sources, returns `true` if the frame is a
+    /// request to the IGD server, which is never dispatched to upstreamers. Searches to the
+    /// multicast address are answered and still handled as the LAN.
+    fn handle_igd(&mut self, frame: &[u8]) -> io::Result<bool> {
+        let server = match self.igd_server {
+            Some(ref server) => Arc::clone(server),
+            None => return Ok(false),
+        };
+        // Filter UDP frames of SSDP and NAT-PMP before parsing
+        let ip = match pcap::get_network(frame) {
+            Some((0x0800, ip)) if ip.len() >= 20 && ip[9] == 17 => ip,
+            _ => return Ok(false),
+        };
+        let offset = (ip[0] & 0x0f) as usize * 4;
+        let dst_port = match ip.get(offset + 2..offset + 4) {
+            Some(dst_port) => u16::from_be_bytes([dst_port[0], dst_port[1]]),
+            None => return Ok(false),
+        };
+        let dst = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+        let is_ssdp =
+            dst_port == igd::SSDP_PORT && (dst == igd::SSDP_ADDR || dst == server.get_ip_addr());
+        let is_natpmp = dst_port == igd::NATPMP_PORT && dst == server.get_ip_addr();
+        if !is_ssdp && !is_natpmp {
+            return Ok(false);
+        }
+
+        let indicator = match Indicator::from(frame) {
+            Ok(indicator) => indicator,
+            Err(_) => return Ok(false),
+        };
+        let udp = match indicator.get_udp() {
+            Some(udp) => udp,
+            None => return Ok(false),
+        };
+        let src_ip_addr = match ipv4_addr(udp.get_src_ip_addr()) {
+            Some(ip_addr) => ip_addr,
+            None => return Ok(false),
+        };
+        let hardware_addr = indicator.get_ethernet().unwrap().get_src();
+        if !self.src.contains(src_ip_addr) || hardware_addr == self.local_hardware_addr {
+            return Ok(false);
+        }
+
+        let (src_port, payloads) = if is_ssdp {
+            (igd::SSDP_PORT, server.handle_ssdp(udp.get_payload()))
+        } else {
+            let payloads: Vec<Vec<u8>> = server
+                .handle_natpmp(src_ip_addr, udp.get_payload())
+                .into_iter()
+                .collect();
+
+            (igd::NATPMP_PORT, payloads)
+        };
+        if !payloads.is_empty() {
+            trace!(
+                "answer {} from {}:{} with {} responses",
+                if is_ssdp { "SSDP" } else { "NAT-PMP" },
+                src_ip_addr,
+                udp.get_src(),
+                payloads.len()
+            );
+        }
+        for payload in payloads {
+            let (ethernet, vlan) = new_link(
+                LayerTypes::Ipv4,
+                self.local_hardware_addr,
+                hardware_addr,
+                indicator.get_vlan(),
+            );
+            let identification =
+                self.next_ipv4_identification(server.get_ip_addr(), LayerTypes::Udp);
+            let ipv4 = Ipv4::new(
+                identification,
+                LayerTypes::Udp,
+                server.get_ip_addr(),
+                src_ip_addr,
+            )
+            .unwrap();
+            let udp = Udp::new(
+                IpAddr::V4(server.get_ip_addr()),
+                IpAddr::V4(src_ip_addr),
+                src_port,
+                udp.get_src(),
+            );
+            let indicator = Indicator::with_vlan(
+                ethernet,
+                vlan,
+                Some(Layers::Ipv4(ipv4)),
+                Some(Layers::Udp(udp)),
+            );
+
+            let frame = indicator.to_vec_with_payload(&payload)?;
+            self.tx.lock().unwrap().send(&frame, true)?;
+        }
+
+        Ok(dst == server.get_ip_addr())
+    }
+
+    fn send_arp(
+        &mut self,
+        arp: Arp,
+        dst_hardware_addr: HardwareAddr,
+        vlan: Option<&Vlan>,
+    ) -> io::Result<()> {
+        let (ethernet, vlan) = new_link(
+            arp.get_type(),
+            self.local_hardware_addr,
+            dst_hardware_addr,
+            vlan,
+        );
+        let indicator = Indicator::with_vlan(ethernet, vlan, Some(Layers::Arp(arp)), None);
+        let frame = indicator.to_vec()?;
+
+        self.tx.lock().unwrap().send(&frame, true)
+    }
+
+    /// Handles a frame of the LAN. Broadcast and multicast datagrams from sources are never
+    /// relayed by the proxy, they are dropped or passed through to the LAN, and the datagrams
+    /// from the LAN to the discovery ports passed through are forwarded back to the sources.
+    /// Unicast datagrams from the sources to the hosts forwarded back are passed through too.
+    /// IGMP messages are counted silently, and the multicast groups joined by sources are
+    /// tracked, so datagrams from the LAN to the groups are relayed to the sources. Returns
+    /// `true` if the frame is handled.
+    fn handle_lan(&mut self, frame: &[u8]) -> io::Result<bool> {
+        let ip = match pcap::get_network(frame) {
+            Some((0x0800, ip)) if ip.len() >= 20 && ip[0] >> 4 == 4 => ip,
+            _ => return Ok(false),
+        };
+        let protocol = ip[9];
+        let src = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+        let dst = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+        // Ports of UDP in the first fragment
+        let ihl = (ip[0] & 0x0f) as usize * 4;
+        let fragment_offset = u16::from_be_bytes([ip[6], ip[7]]) & 0x1fff;
+        let ports = match ip.get(ihl..ihl + 4) {
+            Some(ports) if protocol == 17 && fragment_offset == 0 => Some((
+                u16::from_be_bytes([ports[0], ports[1]]),
+                u16::from_be_bytes([ports[2], ports[3]]),
+            )),
+            _ => None,
+        };
+        // Frames sent from local are captured too
+        let is_local = frame[6..12]
+            == [
+                self.local_hardware_addr.0,
+                self.local_hardware_addr.1,
+                self.local_hardware_addr.2,
+                self.local_hardware_addr.3,
+                self.local_hardware_addr.4,
+                self.local_hardware_addr.5,
+            ];
+
+        // Responses from the LAN
+        if !self.src.contains(src) {
+            if is_local {
+                return Ok(false);
+            }
+            // IGMP queries of the LAN
+            if protocol == 2 {
+                if let Some(igmp) = ip.get(ihl..).and_then(Igmp::parse) {
+                    metrics::count_igmp(igmp.get_kind());
+                }
+
+                return Ok(true);
+            }
+            // Datagrams to the multicast groups joined by sources
+            if dst.is_multicast() {
+                if !self.is_lan_passthrough || !self.is_lan_group(dst) || ports.is_none() {
+                    return Ok(false);
+                }
+                self.send_to_group(frame, src, dst)?;
+
+                return Ok(true);
+            }
+            if !self.src.contains(dst) {
+                return Ok(false);
+            }
+            let dst_port = match ports {
+                Some((_, dst_port)) => dst_port,
+                None => return Ok(false),
+            };
+            match self.lan_peers.get(&dst_port) {
+                Some(&(ip_addr, instant))
+                    if ip_addr == dst && instant.elapsed() < LAN_PEER_TIMEOUT => {}
+                _ => return Ok(false),
+            }
+            let hardware_addr = match self.src_hardware_addr_map.get(&dst).cloned() {
+                Some(hardware_addr) => hardware_addr,
+                None => return Ok(false),
+            };
+            if !src.is_unspecified() {
+                if self.lan_hosts.len() >= MAX_LAN_PEERS {
+                    self.lan_hosts
+                        .retain(|_, instant| instant.elapsed() < LAN_PEER_TIMEOUT);
+                }
+                if self.lan_hosts.len() < MAX_LAN_PEERS || self.lan_hosts.contains_key(&src) {
+                    self.lan_hosts.insert(src, Instant::now());
+                }
+            }
+            self.forward(frame, src, dst, hardware_addr)?;
+
+            return Ok(true);
+        }
+
+        // IGMP membership reports and leaves, which are passed through so the LAN delivers the
+        // datagrams to the groups
+        if protocol == 2 {
+            if is_local {
+                return Ok(true);
+            }
+            match ip.get(ihl..).and_then(Igmp::parse) {
+                Some(igmp) => {
+                    metrics::count_igmp(igmp.get_kind());
+                    self.update_lan_groups(src, &igmp);
+                    trace!("IGMP {} from {} to {}", igmp.get_kind(), src, dst);
+                }
+                None => trace!("ignore IGMP from {} to {}", src, dst),
+            }
+            if self.is_lan_passthrough {
+                self.pass_through(frame, dst)?;
+            }
+
+            return Ok(true);
+        }
+        let is_lan = dst.is_broadcast()
+            || dst.is_multicast()
+            || (self.src.prefix() < 31 && dst == self.src.broadcast());
+        if !is_lan {
+            // Unicast datagrams to hosts in the LAN answering datagrams passed through
+            let is_host = match self.lan_hosts.get(&dst) {
+                Some(instant) => instant.elapsed() < LAN_PEER_TIMEOUT,
+                None => false,
+            };
+            let (src_port, dst_port) = match ports {
+                Some(ports) if is_host && self.local_ip_addr.is_some() => ports,
+                _ => return Ok(false),
+            };
+            if is_local {
+                return Ok(true);
+            }
+            self.insert_lan_peer(src_port, src);
+            trace!(
+                "pass through frame from {}:{} to {}:{}",
+                src,
+                src_port,
+                dst,
+                dst_port
+            );
+
+            self.send_to_lan(frame, dst)?;
+
+            return Ok(true);
+        }
+        if is_local {
+            return Ok(true);
+        }
+
+        // Multicast datagrams are passed through to SSDP and the groups the source joined
+        let is_passable = !dst.is_multicast()
+            || dst == SSDP_GROUP
+            || self
+                .lan_groups
+                .get(&dst)
+                .map_or(false, |members| members.contains_key(&src));
+        match ports {
+            Some((src_port, dst_port)) if self.is_lan_passthrough && is_passable => {
+                self.insert_lan_peer(src_port, src);
+                trace!(
+                    "pass through frame from {}:{} to {}:{}",
+                    src,
+                    src_port,
+                    dst,
+                    dst_port
+                );
+
+                self.pass_through(frame, dst)?;
+            }
+            _ => {
+                let dst = SocketAddrV4::new(dst, ports.map(|(_, dst_port)| dst_port).unwrap_or(0));
+                match metrics::count_lan_drop(dst) {
+                    Some(count) => debug!(
+                        "drop frame from {} to {} for broadcast or multicast ({} dropped)",
+                        src, dst, count
+                    ),
+                    None => debug!(
+                        "drop frame from {} to {} for broadcast or multicast",
+                        src, dst
+                    ),
+                }
+                let layer = match ports {
+                    Some(_) => LayerTypes::Udp,
+                    None => LayerTypes::Ipv4,
+                };
+                event::drop_packet(DropReason::Lan, layer);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Passes a broadcast or multicast frame from a source through to the LAN. The frame is sent
+    /// from local, and the destination of the link layer of a multicast frame is the Ethernet
+    /// address of the group.
+    fn pass_through(&mut self, frame: &[u8], dst: Ipv4Addr) -> io::Result<()> {
+        let mut frame = frame.to_vec();
+        let mut hardware_addrs = vec![(6, self.local_hardware_addr)];
+        if dst.is_multicast() {
+            hardware_addrs.push((0, igmp_packet::multicast_hardware_addr(dst)));
+        }
+        for (begin, hardware_addr) in hardware_addrs {
+            frame[begin..begin + 6].copy_from_slice(&[
+                hardware_addr.0,
+                hardware_addr.1,
+                hardware_addr.2,
+                hardware_addr.3,
+                hardware_addr.4,
+                hardware_addr.5,
+            ]);
+        }
+        metrics::PACKETS_FORWARDED.increase();
+
+        self.tx.lock().unwrap().send(&frame, false)
+    }
+
+    /// Relays a datagram from the LAN to a multicast group to the sources joined it.
+    fn send_to_group(&mut self, frame: &[u8], src: Ipv4Addr, group: Ipv4Addr) -> io::Result<()> {
+        trace!("relay frame from {} to group {}", src, group);
+
+        self.pass_through(frame, group)
+    }
+
+    /// Updates the multicast groups joined by the source by the IGMP message.
+    fn update_lan_groups(&mut self, src: Ipv4Addr, igmp: &Igmp) {
+        for group in igmp.get_leaves() {
+            if let Some(members) = self.lan_groups.get_mut(group) {
+                members.remove(&src);
+                if members.is_empty() {
+                    self.lan_groups.remove(group);
+                }
+                trace!("{} leaves group {}", src, group);
+            }
+        }
+        for &group in igmp.get_joins() {
+            if self.lan_groups.len() >= MAX_LAN_GROUPS && !self.lan_groups.contains_key(&group) {
+                self.lan_groups.retain(|_, members| {
+                    members.retain(|_, instant| instant.elapsed() < LAN_GROUP_TIMEOUT);
+                    !members.is_empty()
+                });
+                if self.lan_groups.len() >= MAX_LAN_GROUPS {
+                    debug!(
+                        "ignore group {} joined by {} for too many groups",
+                        group, src
+                    );
+                    continue;
+                }
+            }
+            let members = self.lan_groups.entry(group).or_insert_with(HashMap::new);
+            if members.insert(src, Instant::now()).is_none() {
+                debug!("{} joins group {}", src, group);
+            }
+        }
+    }
+
+    /// Returns if a source is a member of the multicast group.
+    fn is_lan_group(&self, group: Ipv4Addr) -> bool {
+        match self.lan_groups.get(&group) {
+            Some(members) => members
+                .values()
+                .any(|instant| instant.elapsed() < LAN_GROUP_TIMEOUT),
+            None => false,
+        }
+    }
+
+    /// Inserts a discovery port of the source passed through to the LAN.
+    fn insert_lan_peer(&mut self, port: u16, src: Ipv4Addr) {
+        if self.lan_peers.len() >= MAX_LAN_PEERS {
+            self.lan_peers
+                .retain(|_, (_, instant)| instant.elapsed() < LAN_PEER_TIMEOUT);
+        }
+        if self.lan_peers.len() < MAX_LAN_PEERS || self.lan_peers.contains_key(&port) {
+            self.lan_peers.insert(port, (src, Instant::now()));
+        }
+    }
+
+    /// Sends a frame from a source to a host in the LAN from local. The frame is queued while
+    /// the hardware address of the host is resolved by ARP.
+    fn send_to_lan(&mut self, frame: &[u8], ip_addr: Ipv4Addr) -> io::Result<()> {
+        let local_ip_addr = match self.local_ip_addr {
+            Some(local_ip_addr) => local_ip_addr,
+            None => return Ok(()),
+        };
+
+        let mut frame = frame.to_vec();
+        frame[6..12].copy_from_slice(&[
+            self.local_hardware_addr.0,
+            self.local_hardware_addr.1,
+            self.local_hardware_addr.2,
+            self.local_hardware_addr.3,
+            self.local_hardware_addr.4,
+            self.local_hardware_addr.5,
+        ]);
+        match self.resolver.resolve(ip_addr, &frame) {
+            Resolution::Resolved(hardware_addr) => {
+                frame[0..6].copy_from_slice(&[
+                    hardware_addr.0,
+                    hardware_addr.1,
+                    hardware_addr.2,
+                    hardware_addr.3,
+                    hardware_addr.4,
+                    hardware_addr.5,
+                ]);
+                metrics::PACKETS_FORWARDED.increase();
+
+                self.tx.lock().unwrap().send(&frame, false)
+            }
+            Resolution::Request => {
+                let request = Arp::new_request(self.local_hardware_addr, local_ip_addr, ip_addr);
+                self.send_arp(request, HardwareAddr::broadcast(), None)?;
+                trace!("resolve {} by ARP", ip_addr);
+
+                Ok(())
+            }
+            Resolution::Pending | Resolution::Dropped => Ok(()),
+        }
+    }
+
+    fn dispatch(&mut self, frame: &[u8], txs: &[mpsc::Sender<WorkerMessage>]) -> io::Result<()> {
+        let src = match get_frame_src(frame) {
+            Some(src) if self.src.contains(src) => src,
+            _ => return Ok(()),
+        };
+        // Frames sent from local are captured too
+        let hardware_addr =
+            HardwareAddr::new(frame[6], frame[7], frame[8], frame[9], frame[10], frame[11]);
+        if hardware_addr == self.local_hardware_addr {
+            return Ok(());
+        }
+        self.src_hardware_addr_map.insert(src, hardware_addr);
+
+        // Forward between sources locally
+        if let Some(dst) = get_frame_dst(frame) {
+            if dst != src && self.src.contains(dst) {
+                // Frames of which the TTL is exceeded are answered by the upstreamer
+                let is_ttl_exceeded = match pcap::get_network(frame) {
+                    Some((_, ip)) => ipv4::is_ttl_exceeded(ip),
+                    None => true,
+                };
+                if !is_ttl_exceeded {
+                    if let Some(dst_hardware_addr) = self.src_hardware_addr_map.get(&dst).cloned() {
+                        return self.forward(frame, src, dst, dst_hardware_addr);
+                    }
+                }
+            }
+        }
+
+        // Upstreamers of the source
+        if !self.srcs.contains(&src) && !self.add_src(src, txs)? {
+            self.src_drop_count += 1;
+            debug!(
+                "drop frame from {} for too many sources ({} dropped)",
+                src, self.src_drop_count
+            );
+            event::drop_packet(DropReason::TooManySources, LayerTypes::Ipv4);
+
+            return Ok(());
+        }
+
+        let shard = get_shard(frame, txs.len());
+        match txs[shard].send(WorkerMessage::Frame(src, self.pool.copy_from(frame))) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("worker {} stopped", shard),
+            )),
+        }
+    }
+
+    /// Creates the upstreamers of the source in workers. Returns `false` if there are no UDP
+    /// ports for binding in local left.
+    fn add_src(&mut self, src: Ipv4Addr, txs: &[mpsc::Sender<WorkerMessage>]) -> io::Result<bool> {
+        // Flows of each source are spread over all the workers
+        let count = txs.len();
+        if self.port_range_count + count > MAX_PORT_RANGES {
+            return Ok(false);
+        }
+
+        let upstreamers: Vec<_> = (0..count).map(|_| (self.factory)(src)).collect();
+        let bind_txs: Vec<_> = match count {
+            1 => Vec::new(),
+            _ => upstreamers
+                .iter()
+                .map(|upstreamer| upstreamer.bind_tx.clone())
+                .collect(),
+        };
+        for (i, mut upstreamer) in upstreamers.into_iter().enumerate() {
+            upstreamer.set_port_range(self.port_range_count);
+            self.port_range_count += 1;
+            self.restore_udp_states(&mut upstreamer);
+            upstreamer.set_shard(i, bind_txs.clone());
+            if txs[i]
+                .send(WorkerMessage::Upstreamer(src, Box::new(upstreamer)))
+                .is_err()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    format!("worker {} stopped", i),
+                ));
+            }
+        }
+        self.srcs.insert(src);
+        self.udp_states.retain(|state| *state.src.ip() != src);
+        info!("Serve source {}", src);
+
+        Ok(true)
+    }
+
+    /// Restores the UDP associations of the last run in the upstreamer.
+    fn restore_udp_states(&self, upstreamer: &mut Upstreamer) {
+        if self.udp_states.is_empty() {
+            return;
+        }
+        let count = upstreamer.restore_udp_states(&self.udp_states);
+        if count > 0 {
+            info!(
+                "Restore {} UDP associations of {}",
+                count, upstreamer.src_ip_addr
+            );
+        }
+    }
+
+    /// Forwards a frame between sources as a router.
+    fn forward(
+        &mut self,
+        frame: &[u8],
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        hardware_addr: HardwareAddr,
+    ) -> io::Result<()> {
+        // The VLAN tag is kept
+        let offset = match pcap::get_network_offset(frame) {
+            Some(offset) => offset,
+            None => return Ok(()),
+        };
+
+        let mut frame = frame.to_vec();
+        for (i, hardware_addr) in [hardware_addr, self.local_hardware_addr].iter().enumerate() {
+            frame[i * 6..(i + 1) * 6].copy_from_slice(&[
+                hardware_addr.0,
+                hardware_addr.1,
+                hardware_addr.2,
+                hardware_addr.3,
+                hardware_addr.4,
+                hardware_addr.5,
+            ]);
+        }
+
+        // Decrease TTL
+        if !ipv4::decrease_ttl(&mut frame[offset..]) {
+            debug!("drop frame from {} to {} for TTL exceeded", src, dst);
+            event::drop_packet(DropReason::TtlExceeded, LayerTypes::Ipv4);
+
+            return Ok(());
+        }
+
+        // Checksums
+        if self.checksum_mode != ChecksumMode::Ignore {
+            match packet::check_checksum(&frame[offset..]) {
+                ChecksumState::Valid => {}
+                ChecksumState::Partial if self.checksum_mode == ChecksumMode::Auto => {
+                    info!(
+                        "Detect checksum offloading in frames from {}, checksums are fixed",
+                        src
+                    );
+                    self.checksum_mode = ChecksumMode::Fix;
+                    packet::fix_checksum(&mut frame[offset..]);
+                }
+                _ if self.checksum_mode == ChecksumMode::Fix => {
+                    packet::fix_checksum(&mut frame[offset..]);
+                }
+                _ => {
+                    debug!("drop frame from {} to {} for invalid checksum", src, dst);
+                    event::drop_packet(DropReason::InvalidChecksum, LayerTypes::Ipv4);
+
+                    return Ok(());
+                }
+            }
+        }
+
+        self.hairpin_count += 1;
+        metrics::PACKETS_FORWARDED.increase();
+        trace!("forward frame from {} to {} locally", src, dst);
+
+        self.tx.lock().unwrap().send(&frame, false)
+    }
+
+    /// Get the count of frames forwarded between sources locally.
+    pub fn get_hairpin_count(&self) -> usize {
+        self.hairpin_count
+    }
+
+    /// Get the count of frames received.
+    pub fn get_frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// Get the count of frames dropped by the capture filter.
+    pub fn get_filter_drop_count(&self) -> usize {
+        self.filter_drop_count
+    }
+
+    /// Get the count of frames dropped because there are too many sources.
+    pub fn get_src_drop_count(&self) -> usize {
+        self.src_drop_count
+    }
+}
+
+/// Runs a worker processing frames with the upstreamers in it.
+fn run_worker(rx: mpsc::Receiver<WorkerMessage>) {
+    let mut upstreamers: HashMap<Ipv4Addr, Upstreamer> = HashMap::new();
+    // Represents the deadline of draining and the channel the result is reported to
+    let mut drain: Option<(Instant, mpsc::Sender<(usize, usize)>)> = None;
+    loop {
+        for upstreamer in upstreamers.values_mut() {
+            upstreamer.handle_timers();
+        }
+
+        // Report once all the upstreamers are drained or the deadline passes
+        let is_drained = match drain {
+            Some((deadline, _)) => {
+                Instant::now() >= deadline || upstreamers.values().all(|u| u.is_drained())
+            }
+            None => false,
+        };
+        if is_drained {
+            if let Some((_, result_tx)) = drain.take() {
+                let result = upstreamers
+                    .values_mut()
+                    .map(|upstreamer| upstreamer.finish_drain())
+                    .fold((0, 0), |(closed, reset), result| {
+                        (closed + result.0, reset + result.1)
+                    });
+                let _ = result_tx.send(result);
+            }
+        }
+
+        match rx.recv_timeout(Duration::from_millis(TIMER_INTERVAL as u64)) {
+            Ok(WorkerMessage::Upstreamer(src, mut upstreamer)) => {
+                if drain.is_some() {
+                    upstreamer.drain();
+                }
+                upstreamers.insert(src, *upstreamer);
+            }
+            Ok(WorkerMessage::Save(state_tx)) => {
+                let states = upstreamers
+                    .values()
+                    .flat_map(|upstreamer| upstreamer.get_udp_states())
+                    .collect();
+                let _ = state_tx.send(states);
+            }
+            Ok(WorkerMessage::Drain(deadline, result_tx)) => {
+                for upstreamer in upstreamers.values_mut() {
+                    upstreamer.drain();
+                }
+                drain = Some((deadline, result_tx));
+            }
+            Ok(WorkerMessage::Frame(src, frame)) => {
+                if let Some(upstreamer) = upstreamers.get_mut(&src) {
+                    upstreamer.handle_frame(&frame);
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// Creates the Ethernet layer and the VLAN layer of a frame of the network layer type, the frame
+/// is tagged with the tag of the VLAN if it is given.
+fn new_link(
+    t: LayerType,
+    src: HardwareAddr,
+    dst: HardwareAddr,
+    vlan: Option<&Vlan>,
+) -> (Layers, Option<Layers>) {
+    match vlan.and_then(|vlan| Vlan::from_tag(vlan, t)) {
+        Some(vlan) => (
+            Layers::Ethernet(Ethernet::new(LayerTypes::Vlan, src, dst).unwrap()),
+            Some(Layers::Vlan(vlan)),
+        ),
+        None => (Layers::Ethernet(Ethernet::new(t, src, dst).unwrap()), None),
+    }
+}
+
+/// Get the source of an ARP or IPv4 frame.
+fn get_frame_src(frame: &[u8]) -> Option<Ipv4Addr> {
+    let (t, packet) = pcap::get_network(frame)?;
+    if packet.len() < 20 {
+        return None;
+    }
+    match t {
+        // ARP
+        0x0806 => Some(Ipv4Addr::new(
+            packet[14], packet[15], packet[16], packet[17],
+        )),
+        // IPv4
+        0x0800 => Some(Ipv4Addr::new(
+            packet[12], packet[13], packet[14], packet[15],
+        )),
+        _ => None,
+    }
+}
+
+/// Get the destination of an IPv4 frame.
+fn get_frame_dst(frame: &[u8]) -> Option<Ipv4Addr> {
+    match pcap::get_network(frame)? {
+        (0x0800, ip) if ip.len() >= 20 => Some(Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19])),
+        _ => None,
+    }
+}
+
+/// Get the worker of the frame. The frame is parsed as few as possible, only the ports of TCP
+/// and UDP in non-fragmented IPv4 packets are concerned.
+fn get_shard(frame: &[u8], count: usize) -> usize {
+    // Ethernet
+    let ip = match pcap::get_network(frame) {
+        Some((0x0800, ip)) => ip,
+        _ => return 0,
+    };
+    // IPv4
+    if ip.len() < 20 || ip[0] >> 4 != 4 {
+        return 0;
+    }
+    let ihl = (ip[0] & 0x0f) as usize * 4;
+    let flags_fragment_offset = u16::from_be_bytes([ip[6], ip[7]]);
+    if flags_fragment_offset & 0x3fff != 0 || ip.len() < ihl + 4 {
+        return 0;
+    }
+    let src_port = u16::from_be_bytes([ip[ihl], ip[ihl + 1]]);
+    match ip[9] {
+        // TCP
+        6 => {
+            let dst_ip_addr = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+            let dst_port = u16::from_be_bytes([ip[ihl + 2], ip[ihl + 3]]);
+
+            get_tcp_shard(src_port, SocketAddrV4::new(dst_ip_addr, dst_port), count)
+        }
+        // UDP
+        17 => src_port as usize % count,
+        _ => 0,
+    }
+}
+
+/// Get the worker of the TCP connection.
+fn get_tcp_shard(src_port: u16, dst: SocketAddrV4, count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    (src_port, dst).hash(&mut hasher);
+
+    (hasher.finish() % count as u64) as usize
+}
+
+/// Represents the times the stream received 0 byte data continuously before close itself.
+#[cfg(not(feature = "async"))]
+const ZEROES_BEFORE_CLOSE: usize = 3;
+/// Represents the write timeout of a stream in milliseconds, data not written in time is kept in
+/// the backlog.
+#[cfg(not(feature = "async"))]
+const WRITE_TIMEOUT: u64 = 1;
+/// Represents the timeout of a connection sent directly in milliseconds.
+const DIRECT_CONNECT_TIMEOUT: u64 = 5000;
+/// Represents the wait of a stream before reading again while the device is congested in
+/// milliseconds.
+const BACKPRESSURE_WAIT: u64 = 1;
+
+/// Represents a worker of a SOCKS TCP stream.
+#[cfg(not(feature = "async"))]
+struct StreamWorker {
+    dst: SocketAddrV4,
+    /// Represents the tag of the connection prefixed to log lines
+    tag: Tag,
+    stream: TcpStream,
+    connection: Arc<Mutex<Connection>>,
+    thread: Option<JoinHandle<()>>,
+    is_closed: Arc<AtomicBool>,
+    /// Represents the instant of the last data read or written on the stream
+    activity: Arc<Mutex<Instant>>,
+    backlog: Vec<u8>,
+    limits: Limits,
+    is_write_shutdown: bool,
+    is_shutdown: bool,
+    /// Represents if the stream is read until its end by another thread after the worker is torn
+    /// down
+    is_lingering: bool,
+    /// Represents the sizes of data pending in the backlog and in the send buffer of the stream
+    /// when they were last sampled, and the instant they were last drained
+    drain: (usize, usize, Instant),
+}
+
+#[cfg(not(feature = "async"))]
+impl StreamWorker {
+    /// Opens a new `StreamWorker` of the destination, which connects to the target.
+    pub fn connect(
+        tx: Arc<Mutex<Downstreamer>>,
+        mut connection: Connection,
+        target: SocketAddrV4,
+        proxy: Option<&dyn ProxyConnector>,
+        timeout: Duration,
+        keepalive: Option<Duration>,
+        limits: Limits,
+    ) -> io::Result<StreamWorker> {
+        let selected = proxy.and_then(|proxy| proxy.select(false));
+        let proxy = selected.as_deref().or(proxy);
+        if let Some(proxy) = proxy {
+            connection.set_proxy(proxy.to_string());
+        }
+        // Connect directly without a proxy
+        let stream = match proxy {
+            Some(proxy) => proxy.connect(target, timeout),
+            None => socks::connect_tcp(target, Some(Duration::from_millis(DIRECT_CONNECT_TIMEOUT))),
+        };
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                if proxy.is_some() {
+                    metrics::SOCKS_HANDSHAKE_FAILURES.increase();
+                }
+                connection.fail(&e);
+                metrics::count_close(CloseReason::ConnectFailed);
+                return Err(e);
+            }
+        };
+
+        StreamWorker::new(tx, connection, stream, keepalive, limits)
+    }
+
+    /// Creates a new `StreamWorker` of the connection on a connected stream.
+    pub fn new(
+        tx: Arc<Mutex<Downstreamer>>,
+        mut connection: Connection,
+        stream: TcpStream,
+        keepalive: Option<Duration>,
+        limits: Limits,
+    ) -> io::Result<StreamWorker> {
+        let src_port = connection.get_src_port();
+        let dst = connection.get_dst();
+        let tag = connection.get_tag();
+        stream.set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT)))?;
+        if let Some(keepalive) = keepalive {
+            if let Err(ref e) = socks::set_keepalive(&stream, keepalive) {
+                warn!("{}SOCKS: {}: {} -> {}: {}", tag, "TCP", 0, dst, e);
+            }
+        }
+        let mut stream_cloned = stream.try_clone()?;
+
+        connection.open();
+        let connection = Arc::new(Mutex::new(connection));
+        let connection_cloned = Arc::clone(&connection);
+        let is_closed = AtomicBool::new(false);
+        let a_is_closed = Arc::new(is_closed);
+        let a_is_closed_cloned = Arc::clone(&a_is_closed);
+        let activity = Arc::new(Mutex::new(Instant::now()));
+        let activity_cloned = Arc::clone(&activity);
+        let limit_down = limits.down.clone();
+        let congestion = tx.lock().unwrap().get_congestion();
+        // The relay buffer shrinks under the memory pressure
+        let relay_buffer = memory::scale(tx.lock().unwrap().get_relay_buffer());
+        let thread = thread::spawn(move || {
+            let mut buffer = vec![0u8; relay_buffer];
+            let _allocation = memory::Allocation::new(relay_buffer);
+            let mut zero = 0;
+            loop {
+                if a_is_closed_cloned.load(Ordering::Relaxed) {
+                    break;
+                }
+                // Stop reading while the device is congested, so the destination is pushed back
+                while congestion.is_congested() && !a_is_closed_cloned.load(Ordering::Relaxed) {
+                    thread::sleep(Duration::from_millis(BACKPRESSURE_WAIT));
+                }
+                match stream_cloned.read(&mut buffer) {
+                    Ok(size) => {
+                        if a_is_closed_cloned.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        if size == 0 {
+                            zero += 1;
+                            if zero >= ZEROES_BEFORE_CLOSE {
+                                // TODO: a potential bug
+                                /* This may happen frequently for unknown reason
+                                warn!(
+                                    "SOCKS: {}: {} -> {}: {}",
+                                    "TCP",
+                                    0,
+                                    dst,
+                                    io::Error::from(io::ErrorKind::UnexpectedEof)
+                                );
+                                */
+                                // The destination closes the stream, send a FIN after all the
+                                // data is sent
+                                if let Err(ref e) =
+                                    tx.lock().unwrap().append_fin_to_cache(dst, src_port)
+                                {
+                                    warn!("{}handle {}: {}", tag, "TCP", e);
                                 }
-                                _ => unreachable!(),
+                                break;
                             }
+                            continue;
                         }
-                    };
+                        *activity_cloned.lock().unwrap() = Instant::now();
+                        connection_cloned.lock().unwrap().add_bytes_down(size);
+                        debug!(
+                            "{}receive from SOCKS: {}: {} -> {} ({} Bytes)",
+                            tag, "TCP", dst, 0, size
+                        );
+
+                        // Send
+                        if let Err(ref e) =
+                            tx.lock()
+                                .unwrap()
+                                .append_to_cache(dst, src_port, &buffer[..size])
+                        {
+                            warn!("{}handle {}: {}", tag, "TCP", e);
+                        }
+
+                        // Pause reading if the rate limit is exceeded
+                        let delay = limit_down.consume(size);
+                        if !delay.is_zero() {
+                            thread::sleep(delay);
+                        }
+                    }
+                    Err(ref e) => {
+                        if e.kind() == io::ErrorKind::TimedOut {
+                            thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
+                            continue;
+                        }
+                        if e.kind() == io::ErrorKind::Interrupted {
+                            continue;
+                        }
+                        warn!("{}SOCKS: {}: {} -> {}: {}", tag, "TCP", 0, dst, e);
+                        connection_cloned.lock().unwrap().fail(e);
+                        a_is_closed_cloned.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                }
+            }
+        });
+
+        trace!("{}open stream {} -> {}", tag, 0, dst);
+
+        Ok(StreamWorker {
+            dst,
+            tag,
+            stream,
+            connection,
+            thread: Some(thread),
+            is_closed: a_is_closed,
+            activity,
+            backlog: Vec::new(),
+            limits,
+            is_write_shutdown: false,
+            is_shutdown: false,
+            is_lingering: false,
+            drain: (0, 0, Instant::now()),
+        })
+    }
+
+    /// Sends data on the SOCKS in TCP to the destination. Data which cannot be written
+    /// immediately is kept in the backlog.
+    pub fn send(&mut self, buffer: &[u8]) -> io::Result<()> {
+        debug!(
+            "{}send to SOCKS {}: {} -> {} ({} Bytes)",
+            self.tag,
+            "TCP",
+            "0",
+            self.dst,
+            buffer.len()
+        );
+        self.connection.lock().unwrap().add_payload_up(buffer);
+
+        // Send
+        self.backlog.extend_from_slice(buffer);
+        self.flush()
+    }
+
+    /// Writes the backlog on the SOCKS in TCP to the destination. Data exceeding the rate limit
+    /// is kept in the backlog, which shrinks the window advertised to the source.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.backlog.is_empty() {
+            if !self.limits.up.is_available() {
+                trace!(
+                    "{}stream {} -> {} is rate limited ({} Bytes)",
+                    self.tag,
+                    0,
+                    self.dst,
+                    self.backlog.len()
+                );
+                return Ok(());
+            }
+            match self.stream.write(&self.backlog) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(size) => {
+                    self.backlog.drain(..size);
+                    self.limits.up.consume(size);
+                    *self.activity.lock().unwrap() = Instant::now();
+                }
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut
+                        || e.kind() == io::ErrorKind::Interrupted =>
+                {
+                    trace!(
+                        "{}stream {} -> {} backlogs ({} Bytes)",
+                        self.tag,
+                        0,
+                        self.dst,
+                        self.backlog.len()
+                    );
+                    return Ok(());
                 }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
                 Err(e) => {
-                    if e.kind() == io::ErrorKind::TimedOut {
-                        thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
-                        continue;
-                    }
+                    self.connection.lock().unwrap().fail(&e);
                     return Err(e);
                 }
-            };
+            }
+        }
+
+        if self.is_write_shutdown {
+            self.stream.shutdown(Shutdown::Write)?;
+            self.is_write_shutdown = false;
+            trace!("{}shutdown stream {} -> {}", self.tag, 0, self.dst);
+        }
+
+        Ok(())
+    }
+
+    /// Sets the DSCP of IPv4 packets sent on the SOCKS stream.
+    pub fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        socks::set_tos(&self.stream, dscp << 2)
+    }
+
+    /// Sets TCP_NODELAY on the SOCKS stream.
+    pub fn set_nodelay(&self, is_nodelay: bool) -> io::Result<()> {
+        self.stream.set_nodelay(is_nodelay)
+    }
+
+    /// Enables the detection of the dead peer of the stream in about the timeout.
+    pub fn set_stale_timeout(&self, timeout: Duration) -> io::Result<()> {
+        socks::set_stale_timeout(&self.stream, timeout)
+    }
+
+    /// Get the time data pending on the stream is not drained. Data is drained if it is less in
+    /// either the backlog or the send buffer of the stream than the last time, so an idle stream
+    /// with nothing pending is never stalled, while writes to a blackholed peer pile up.
+    pub fn get_stall(&mut self) -> Duration {
+        let backlog = self.get_backlog_size();
+        // The send buffer is only known in Linux
+        let unsent = socks::get_unsent_size(&self.stream).unwrap_or(0);
+        let (last_backlog, last_unsent, instant) = self.drain;
+        self.drain = (backlog, unsent, instant);
+        if (backlog == 0 && unsent == 0) || backlog < last_backlog || unsent < last_unsent {
+            self.drain.2 = Instant::now();
+
+            return Duration::from_secs(0);
+        }
+
+        instant.elapsed()
+    }
+
+    /// Get the size of the backlog.
+    pub fn get_backlog_size(&self) -> usize {
+        self.backlog.len()
+    }
+
+    /// Shuts down the write half of the SOCKS in TCP after the backlog is written, the
+    /// destination can still send data.
+    pub fn shutdown_write(&mut self) -> io::Result<()> {
+        self.is_write_shutdown = true;
+
+        self.flush()
+    }
+
+    /// Shuts down both halves of the SOCKS stream after the backlog is written. The worker reads
+    /// the end of the stream, so a FIN is sent to the source after all the data in the cache is
+    /// sent.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.stream.shutdown(Shutdown::Both)?;
+        self.is_shutdown = true;
+        trace!(
+            "{}shutdown stream {} -> {} in both halves",
+            self.tag,
+            0,
+            self.dst
+        );
+
+        Ok(())
+    }
+
+    /// Tears down the SOCKS stream closed for the reason. Streams closed gracefully are shut down
+    /// before. Streams reset by the source are shut down in both halves. Streams closed for
+    /// errors are shut down in both halves without a linger, reset with a zero linger, or shut
+    /// down in the write half and read until their ends in the linger, so the FIN is not turned
+    /// into a RST by data left unread when the stream is closed.
+    pub fn teardown(&mut self, reason: CloseReason, linger: Option<Duration>) -> io::Result<()> {
+        if self.is_shutdown || reason.is_graceful() || reason == CloseReason::Shutdown {
+            return Ok(());
+        }
+        // The worker stops relaying before the stream is shut down
+        self.is_closed.store(true, Ordering::Relaxed);
+
+        match linger {
+            Some(linger) if reason != CloseReason::Reset => {
+                if linger.as_secs() == 0 {
+                    socks::set_linger(&self.stream, linger)?;
+                    // Wake the worker, the stream is reset once it is dropped
+                    self.stream.shutdown(Shutdown::Read)?;
+                    self.is_shutdown = true;
+                    trace!("{}reset stream {} -> {}", self.tag, 0, self.dst);
+                } else {
+                    let stream = self.stream.try_clone()?;
+                    stream.set_read_timeout(Some(linger))?;
+                    self.stream.shutdown(Shutdown::Write)?;
+                    self.is_shutdown = true;
+                    self.is_lingering = true;
+                    let deadline = Instant::now() + linger;
+                    thread::spawn(move || drain_stream(stream, deadline));
+                    trace!(
+                        "{}linger stream {} -> {} for {} s",
+                        self.tag,
+                        0,
+                        self.dst,
+                        linger.as_secs()
+                    );
+                }
+            }
+            _ => {
+                self.stream.shutdown(Shutdown::Both)?;
+                self.is_shutdown = true;
+                trace!(
+                    "{}shutdown stream {} -> {} in both halves",
+                    self.tag,
+                    0,
+                    self.dst
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Closes the worker.
+    pub fn close(&mut self) {
+        self.is_closed.store(true, Ordering::Relaxed);
+        trace!("{}close stream {} -> {}", self.tag, 0, self.dst);
+    }
+
+    /// Closes the connection of the worker for the reason.
+    pub fn close_connection(&mut self, reason: CloseReason) {
+        self.connection.lock().unwrap().close(reason);
+    }
+
+    /// Returns if the worker is closed.
+    pub fn is_closed(&self) -> bool {
+        self.is_closed.load(Ordering::Relaxed)
+    }
+
+    /// Get the time since data was last read or written on the stream.
+    pub fn get_idle(&self) -> Duration {
+        self.activity.lock().unwrap().elapsed()
+    }
+
+    /// Get the tag of the connection of the stream prefixed to log lines.
+    pub fn get_tag(&self) -> Tag {
+        self.tag
+    }
+
+    /// Get the translated port of the connection of the stream in the NAT.
+    pub fn get_nat_port(&self) -> Option<u16> {
+        self.connection.lock().unwrap().get_nat_port()
+    }
+
+    /// Get the destination the stream is rewritten to.
+    pub fn get_rewritten(&self) -> Option<SocketAddrV4> {
+        self.connection.lock().unwrap().get_rewritten()
+    }
+
+    /// Get the bytes sent from and to the source on the stream.
+    pub fn get_bytes(&self) -> (u64, u64) {
+        let connection = self.connection.lock().unwrap();
+
+        (connection.get_bytes_up(), connection.get_bytes_down())
+    }
+
+    /// Get the bytes of payload retransmitted from and to the source on the stream.
+    pub fn get_retransmitted_bytes(&self) -> (u64, u64) {
+        self.connection.lock().unwrap().get_retransmitted_bytes()
+    }
+
+    /// Get the bytes of frames received from and sent to the source on the stream, including
+    /// headers.
+    pub fn get_wire_bytes(&self) -> (u64, u64) {
+        self.connection.lock().unwrap().get_wire_bytes()
+    }
+
+    /// Counts bytes of payload retransmitted by the source on the stream.
+    pub fn add_retransmitted_up(&self, n: usize) {
+        self.connection.lock().unwrap().add_retransmitted_up(n);
+    }
+
+    /// Counts bytes of a frame received from the source on the stream, including headers.
+    pub fn add_wire_up(&self, n: usize) {
+        self.connection.lock().unwrap().add_wire_up(n);
+    }
+
+    /// Get the connection of the stream, which counts the bytes sent to the source in the
+    /// downstreamer.
+    pub fn get_connection(&self) -> Arc<Mutex<Connection>> {
+        Arc::clone(&self.connection)
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl Drop for StreamWorker {
+    fn drop(&mut self) {
+        // Connections closed for other reasons are closed before
+        self.close_connection(CloseReason::Shutdown);
+        self.close();
+        if !self.is_shutdown {
+            if let Err(ref e) = self.stream.shutdown(Shutdown::Both) {
+                warn!("{}handle {}: {}", self.tag, "TCP", e);
+            }
+        }
+        if let Some(thread) = self.thread.take() {
+            // The worker of a lingering stream exits after its next read
+            if !self.is_lingering {
+                thread.join().unwrap();
+            }
+        }
+        trace!("{}drop stream {} -> {}", self.tag, 0, self.dst);
+    }
+}
+
+/// Reads a stream shut down in the write half until its end or the deadline, and discards the
+/// data read. The stream is closed after.
+#[cfg(not(feature = "async"))]
+fn drain_stream(mut stream: TcpStream, deadline: Instant) {
+    let mut buffer = [0u8; 1024];
+    while Instant::now() < deadline {
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+}
+
+/// Represents the read timeout of a datagram in milliseconds.
+#[cfg(not(feature = "async"))]
+const DATAGRAM_READ_TIMEOUT: u64 = 100;
+/// Represents the port of DNS.
+const DNS_PORT: u16 = 53;
+/// Represents the max number of redirected DNS queries waiting for responses in a datagram.
+const MAX_DNS_QUERIES: usize = 1024;
+/// Represents the default interval in seconds of liveness probes through idle relays.
+pub const DEFAULT_UDP_PROBE_INTERVAL: u64 = 30;
+/// Represents the time a liveness probe is waited for before the relay is regarded as dead.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Represents the payload of a liveness probe to an endpoint other than a DNS resolver, which is
+/// expected to be echoed.
+const PROBE_PAYLOAD: &[u8] = b"pcap2socks probe";
+/// Represents the time an association whose control connection is closed survives without a
+/// datagram from its relay in the lenient mode.
+const CONTROL_CLOSED_GRACE: Duration = Duration::from_secs(60);
+
+/// Represents the map mapping the ID of a redirected DNS query to the resolver, the original
+/// destination and the max size of the response.
+type DnsMap = HashMap<u16, (SocketAddrV4, SocketAddrV4, usize)>;
+
+/// Represents the max number of peers of a UDP association kept.
+const MAX_UDP_PEERS: usize = 256;
+
+/// Represents the filtering of datagrams to a source from peers of its UDP association
+/// (RFC 4787). The mapping of an association is always independent of destinations.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UdpNat {
+    /// Represents datagrams from any peer are sent to the source, which is the
+    /// endpoint-independent filtering.
+    FullCone,
+    /// Represents only datagrams from peers the source sent to are sent to the source, which is
+    /// the address and port-dependent filtering.
+    Restricted,
+}
+
+impl UdpNat {
+    /// Parses a UDP NAT from its name.
+    pub fn parse(s: &str) -> Option<UdpNat> {
+        match s {
+            "full-cone" => Some(UdpNat::FullCone),
+            "restricted" => Some(UdpNat::Restricted),
+            _ => None,
         }
     }
+}
 
-    fn handle_arp(&mut self, indicator: &Indicator) -> io::Result<()> {
-        if let Some(local_ip_addr) = self.local_ip_addr {
-            if let Some(arp) = indicator.get_arp() {
-                if arp.is_request_of(self.src_ip_addr, local_ip_addr) {
-                    debug!(
-                        "receive from pcap: {} ({} Bytes)",
-                        indicator.brief(),
-                        indicator.get_size()
-                    );
+impl Display for UdpNat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            UdpNat::FullCone => write!(f, "full-cone"),
+            UdpNat::Restricted => write!(f, "restricted"),
+        }
+    }
+}
 
-                    // Set downstreamer's hardware address
-                    if !self.is_tx_src_hardware_addr_set {
-                        self.tx
-                            .lock()
-                            .unwrap()
-                            .set_src_hardware_addr(arp.get_src_hardware_addr());
-                        self.is_tx_src_hardware_addr_set = true;
-                    }
+/// Represents the recent peers a UDP association sent to, which are kept for statistics and
+/// filter datagrams to the source in the restricted NAT.
+struct UdpPeers {
+    nat: UdpNat,
+    peers: LruCache<SocketAddrV4, ()>,
+    /// Represents if the port of the association is mapped by the source, which accepts
+    /// datagrams from any peer
+    is_mapped: bool,
+    /// Represents the count of datagrams from peers the association never sent to
+    unsolicited_count: u64,
+    /// Represents the original destinations of peers rewritten to, the latest destination wins
+    /// peers rewritten to from several destinations
+    rewrites: LruCache<SocketAddrV4, SocketAddrV4>,
+    /// Represents the endpoint of liveness probes through the relay, whose replies are never
+    /// sent to the source
+    probe: Option<SocketAddrV4>,
+}
 
-                    // Send
-                    self.tx.lock().unwrap().send_arp_reply()?
-                }
-            }
+impl UdpPeers {
+    fn new(nat: UdpNat) -> UdpPeers {
+        UdpPeers {
+            nat,
+            peers: LruCache::new(MAX_UDP_PEERS),
+            is_mapped: false,
+            unsolicited_count: 0,
+            rewrites: LruCache::new(MAX_UDP_PEERS),
+            probe: None,
         }
+    }
 
-        Ok(())
+    /// Adds a peer the association sends to.
+    fn add(&mut self, peer: SocketAddrV4) {
+        self.peers.put(peer, ());
     }
 
-    fn handle_ipv4(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<()> {
-        if let Some(ref ipv4) = indicator.get_ipv4() {
-            if ipv4.get_src() == self.src_ip_addr {
-                debug!(
-                    "receive from pcap: {} ({} + {} Bytes)",
-                    indicator.brief(),
-                    indicator.get_size(),
-                    buffer.len() - indicator.get_size()
-                );
-                // Set downstreamer's hardware address
-                if !self.is_tx_src_hardware_addr_set {
-                    self.tx
-                        .lock()
-                        .unwrap()
-                        .set_src_hardware_addr(indicator.get_ethernet().unwrap().get_src());
-                    self.is_tx_src_hardware_addr_set = true;
+    /// Adds a peer the association sends to in place of the original destination.
+    fn add_rewrite(&mut self, peer: SocketAddrV4, dst: SocketAddrV4) {
+        self.rewrites.put(peer, dst);
+    }
+
+    /// Get the original destination of the peer if it is rewritten to.
+    fn get_original(&mut self, peer: SocketAddrV4) -> Option<SocketAddrV4> {
+        self.rewrites.get(&peer).copied()
+    }
+
+    /// Returns if a datagram from the peer is sent to the source. Datagrams from peers the
+    /// association never sent to are counted.
+    fn accept(&mut self, peer: SocketAddrV4) -> bool {
+        if self.peers.contains(&peer) {
+            return true;
+        }
+        self.unsolicited_count += 1;
+
+        self.nat == UdpNat::FullCone || self.is_mapped
+    }
+
+    fn set_mapped(&mut self, is_mapped: bool) {
+        self.is_mapped = is_mapped;
+    }
+
+    fn set_probe(&mut self, probe: SocketAddrV4) {
+        self.probe = Some(probe);
+    }
+
+    /// Returns if a datagram from the peer is the reply of a liveness probe. Datagrams from the
+    /// endpoint of probes are sent to the source only if the association sent to it.
+    fn is_probe_reply(&self, peer: SocketAddrV4) -> bool {
+        self.probe == Some(peer) && !self.peers.contains(&peer)
+    }
+
+    /// Removes all the peers, when the association is reused by another source port.
+    fn clear(&mut self) {
+        self.peers.clear();
+        self.is_mapped = false;
+        self.unsolicited_count = 0;
+        self.rewrites.clear();
+    }
+
+    fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Get the peers from the least recent.
+    fn get_peers(&self) -> Vec<SocketAddrV4> {
+        self.peers.iter().rev().map(|(&peer, _)| peer).collect()
+    }
+
+    fn get_unsolicited_count(&self) -> u64 {
+        self.unsolicited_count
+    }
+}
+
+/// Sends a datagram received on the SOCKS to the source. Responses of redirected DNS queries and
+/// datagrams from rewritten destinations are sent as from the original destination. Returns the
+/// bytes of the frames sent, including headers.
+fn receive_datagram(
+    tx: &Mutex<Downstreamer>,
+    dns_map: &Mutex<DnsMap>,
+    peers: &Mutex<UdpPeers>,
+    addr: SocketAddrV4,
+    src_port: u16,
+    buffer: &[u8],
+) -> io::Result<usize> {
+    let original = {
+        let mut peers = peers.lock().unwrap();
+        if peers.is_probe_reply(addr) {
+            trace!("receive probe reply from {} to {}", addr, src_port);
+
+            return Ok(0);
+        }
+        if !peers.accept(addr) {
+            trace!(
+                "drop datagram from {} to {} for restricted NAT",
+                addr,
+                src_port
+            );
+            event::drop_packet(DropReason::NatFiltered, LayerTypes::Udp);
+
+            return Ok(0);
+        }
+
+        peers.get_original(addr)
+    };
+
+    let mut src = original.unwrap_or(addr);
+    let mut payload = buffer;
+    let truncated;
+    if buffer.len() >= 2 {
+        let id = u16::from_be_bytes([buffer[0], buffer[1]]);
+        let mut dns_map = dns_map.lock().unwrap();
+        let is_redirected = match dns_map.get(&id) {
+            Some(&(resolver, _, _)) => resolver == addr,
+            None => false,
+        };
+        if is_redirected {
+            let (_, dst, udp_size) = dns_map.remove(&id).unwrap();
+            src = dst;
+            truncated = dns::truncate(payload, udp_size);
+            payload = &truncated;
+        }
+    }
+
+    tx.lock().unwrap().send_udp(src, src_port, payload)
+}
+
+/// Represents a worker of a SOCKS5 UDP client.
+#[cfg(not(feature = "async"))]
+struct DatagramWorker {
+    src_port: Arc<AtomicU16>,
+    connection: Arc<Mutex<Connection>>,
+    local_port: u16,
+    datagram: Arc<SocksDatagram>,
+    thread: Option<JoinHandle<()>>,
+    is_closed: Arc<AtomicBool>,
+    /// Represents the instant of the last datagram in either direction
+    activity: Arc<Mutex<Instant>>,
+    /// Represents if all the datagrams sent are DNS queries
+    is_dns: bool,
+    dns_map: Arc<Mutex<DnsMap>>,
+    peers: Arc<Mutex<UdpPeers>>,
+    limits: Limits,
+    dscp: u8,
+    /// Represents the instant of the last keepalive datagram
+    keepalive_instant: Instant,
+    /// Represents the max size of data of a datagram not exceeding the path MTU to the relay
+    max_size: Option<usize>,
+    is_send_fragmentation: bool,
+    /// Represents if the source sets the DF bit in the datagram being sent, which is never sent
+    /// in SOCKS5 fragments
+    is_dont_fragment: bool,
+    /// Represents the instant of the last datagram from the relay
+    relay_activity: Arc<Mutex<Instant>>,
+    /// Represents if the control connection is closed while the relay still relays
+    is_control_closed: bool,
+    /// Represents the instant of the liveness probe not replied yet
+    probe_instant: Option<Instant>,
+    /// Represents the clock of the idle time, keepalives and probes
+    clock: Arc<dyn Clock>,
+}
+
+#[cfg(not(feature = "async"))]
+impl DatagramWorker {
+    /// Creates a new `DatagramWorker`.
+    pub fn bind(
+        tx: Arc<Mutex<Downstreamer>>,
+        mut connection: Connection,
+        local_port: u16,
+        proxy: &dyn ProxyConnector,
+        is_fragmentation: bool,
+        limits: Limits,
+        nat: UdpNat,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<DatagramWorker> {
+        let selected = proxy.select(true);
+        let proxy = selected.as_deref().unwrap_or(proxy);
+        connection.set_proxy(proxy.to_string());
+        let mut datagram = match proxy.bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, local_port)) {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                metrics::SOCKS_HANDSHAKE_FAILURES.increase();
+                connection.fail(&e);
+                metrics::count_close(CloseReason::ConnectFailed);
+                return Err(e);
+            }
+        };
+        datagram.set_fragmentation(is_fragmentation);
+
+        DatagramWorker::new(tx, connection, local_port, datagram, limits, nat, clock)
+    }
+
+    /// Creates a new `DatagramWorker` which sends datagrams directly from an ephemeral port.
+    pub fn bind_direct(
+        tx: Arc<Mutex<Downstreamer>>,
+        connection: Connection,
+        limits: Limits,
+        nat: UdpNat,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<DatagramWorker> {
+        let datagram = SocksDatagram::bind_direct(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+        let local_port = datagram.get_local_port()?;
+
+        DatagramWorker::new(tx, connection, local_port, datagram, limits, nat, clock)
+    }
+
+    fn new(
+        tx: Arc<Mutex<Downstreamer>>,
+        mut connection: Connection,
+        local_port: u16,
+        datagram: SocksDatagram,
+        limits: Limits,
+        nat: UdpNat,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<DatagramWorker> {
+        // Wake up periodically so the worker can be closed
+        datagram.set_read_timeout(Some(Duration::from_millis(DATAGRAM_READ_TIMEOUT)))?;
+
+        let src_port = connection.get_src_port();
+        let tag = connection.get_tag();
+        connection.open();
+        let a_connection = Arc::new(Mutex::new(connection));
+        let a_connection_cloned = Arc::clone(&a_connection);
+
+        let a_src_port = Arc::new(AtomicU16::from(src_port));
+        let a_src_port_cloned = Arc::clone(&a_src_port);
+        let header_size = datagram.get_header_size();
+        let a_datagram = Arc::new(datagram);
+        let a_datagram_cloned = Arc::clone(&a_datagram);
+        let is_closed = AtomicBool::new(false);
+        let a_is_closed = Arc::new(is_closed);
+        let a_is_closed_cloned = Arc::clone(&a_is_closed);
+        let a_activity = Arc::new(Mutex::new(clock.now()));
+        let a_activity_cloned = Arc::clone(&a_activity);
+        let a_relay_activity = Arc::new(Mutex::new(clock.now()));
+        let a_relay_activity_cloned = Arc::clone(&a_relay_activity);
+        let a_dns_map = Arc::new(Mutex::new(HashMap::new()));
+        let a_dns_map_cloned = Arc::clone(&a_dns_map);
+        let a_peers = Arc::new(Mutex::new(UdpPeers::new(nat)));
+        let a_peers_cloned = Arc::clone(&a_peers);
+        let limit_down = limits.down.clone();
+        let clock_cloned = Arc::clone(&clock);
+        let receive = move |data: &[u8], addr: SocketAddrV4| {
+            // Any datagram tells the relay is alive
+            *a_relay_activity_cloned.lock().unwrap() = clock_cloned.now();
+            // Drop-tail if the rate limit is exceeded
+            if !limit_down.try_consume(data.len()) {
+                event::drop_packet(DropReason::RateLimited, LayerTypes::Udp);
+                return;
+            }
+            *a_activity_cloned.lock().unwrap() = clock_cloned.now();
+            {
+                let mut connection = a_connection_cloned.lock().unwrap();
+                connection.add_datagram_down(data.len());
+                connection.add_overhead_down(header_size);
+            }
+            debug!(
+                "{}receive from SOCKS: {}: {} -> {} ({} Bytes)",
+                tag,
+                "UDP",
+                addr,
+                local_port,
+                data.len()
+            );
+
+            // Send
+            match receive_datagram(
+                &tx,
+                &a_dns_map_cloned,
+                &a_peers_cloned,
+                addr,
+                a_src_port_cloned.load(Ordering::Relaxed),
+                data,
+            ) {
+                Ok(size) => a_connection_cloned.lock().unwrap().add_wire_down(size),
+                Err(ref e) => warn!("{}handle {}: {}", tag, "UDP", e),
+            }
+        };
+
+        // Datagrams of a shared socket are received by the reader of the socket
+        let thread = if a_datagram.is_shared() {
+            a_datagram.set_handler(Box::new(move |data: &[u8], addr: SocketAddrV4| {
+                if !a_is_closed_cloned.load(Ordering::Relaxed) {
+                    receive(data, addr);
                 }
+            }))?;
 
-                if ipv4.is_fragment() {
-                    // Fragmentation
-                    let frag = match self.defrag.add(indicator, buffer) {
-                        Some(frag) => frag,
-                        None => return Ok(()),
-                    };
-                    let (indicator, buffer) = frag.concatenate();
+            None
+        } else {
+            let a_src_port_closed = Arc::clone(&a_src_port);
+            let a_connection_closed = Arc::clone(&a_connection);
+            Some(thread::spawn(move || {
+                let mut buffer = [0u8; u16::MAX as usize];
+                loop {
+                    if a_is_closed_cloned.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    match a_datagram_cloned.recv_from(&mut buffer) {
+                        Ok((size, addr)) => {
+                            if a_is_closed_cloned.load(Ordering::Relaxed) {
+                                break;
+                            }
+                            receive(&buffer[..size], addr);
+                        }
+                        Err(ref e) => {
+                            if e.kind() == io::ErrorKind::TimedOut
+                                || e.kind() == io::ErrorKind::WouldBlock
+                                || e.kind() == io::ErrorKind::Interrupted
+                            {
+                                continue;
+                            }
+                            if a_is_closed_cloned.load(Ordering::Relaxed) {
+                                break;
+                            }
+                            warn!(
+                                "{}SOCKS: {}: {} = {}: {}",
+                                tag,
+                                "UDP",
+                                local_port,
+                                a_src_port_closed.load(Ordering::Relaxed),
+                                e
+                            );
+                            a_connection_closed.lock().unwrap().fail(e);
+                            a_is_closed_cloned.store(true, Ordering::Relaxed);
 
-                    if let Some(t) = indicator.get_transport_type() {
-                        match t {
-                            LayerTypes::Tcp => self.handle_tcp(&indicator, buffer)?,
-                            LayerTypes::Udp => self.handle_udp(&indicator, buffer)?,
-                            _ => unreachable!(),
+                            break;
                         }
                     }
-                } else {
-                    if let Some(t) = indicator.get_transport_type() {
-                        match t {
-                            LayerTypes::Tcp => self.handle_tcp(indicator, buffer)?,
-                            LayerTypes::Udp => self.handle_udp(indicator, buffer)?,
-                            _ => unreachable!(),
+                }
+            }))
+        };
+
+        trace!("{}create datagram {} = {}", tag, src_port, local_port);
+
+        Ok(DatagramWorker {
+            src_port: a_src_port,
+            connection: a_connection,
+            local_port,
+            datagram: a_datagram,
+            thread,
+            is_closed: a_is_closed,
+            activity: a_activity,
+            is_dns: true,
+            dns_map: a_dns_map,
+            peers: a_peers,
+            limits,
+            dscp: 0,
+            keepalive_instant: clock.now(),
+            max_size: None,
+            is_send_fragmentation: false,
+            is_dont_fragment: false,
+            relay_activity: a_relay_activity,
+            is_control_closed: false,
+            probe_instant: None,
+            clock,
+        })
+    }
+
+    /// Sends data on the SOCKS5 in UDP to the destination.
+    pub fn send_to(&mut self, buffer: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
+        // Drop-tail if the rate limit is exceeded
+        if !self.limits.up.try_consume(buffer.len()) {
+            event::drop_packet(DropReason::RateLimited, LayerTypes::Udp);
+            return Ok(0);
+        }
+        debug!(
+            "{}send to SOCKS {}: {} -> {} ({} Bytes)",
+            self.get_tag(),
+            "UDP",
+            self.local_port,
+            dst,
+            buffer.len()
+        );
+        *self.activity.lock().unwrap() = self.clock.now();
+        self.connection
+            .lock()
+            .unwrap()
+            .add_datagram_up(buffer.len());
+        self.peers.lock().unwrap().add(dst);
+        if dst.port() != DNS_PORT {
+            self.is_dns = false;
+        }
+
+        // Send
+        if let Some(max_size) = self.max_size {
+            if buffer.len() > max_size {
+                return self.send_oversized(buffer, dst, max_size);
+            }
+        }
+        let result = self.datagram.send_to(buffer, dst);
+        match result {
+            Ok(_) => self
+                .connection
+                .lock()
+                .unwrap()
+                .add_overhead_up(self.datagram.get_header_size()),
+            Err(ref e) => {
+                // The OS learns the path MTU from the failure
+                if socks::is_message_too_long(e) && self.probe_mtu().is_ok() {
+                    if let Some(max_size) = self.max_size {
+                        if buffer.len() > max_size {
+                            return self.send_oversized(buffer, dst, max_size);
                         }
                     }
                 }
+                self.connection.lock().unwrap().add_send_failure();
             }
         }
 
-        Ok(())
+        result
     }
 
-    fn handle_tcp(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<()> {
-        if let Some(ref tcp) = indicator.get_tcp() {
-            if tcp.is_rst() {
-                self.handle_tcp_rst(indicator);
-            } else if tcp.is_ack() {
-                return self.handle_tcp_ack(indicator, buffer);
-            } else if tcp.is_syn() {
-                // Pure TCP SYN
-                return self.handle_tcp_syn(indicator);
-            } else if tcp.is_fin() {
-                // Pure TCP FIN
-                return self.handle_tcp_fin(indicator);
+    /// Sends a datagram larger than the path MTU to the relay in SOCKS5 fragments if the
+    /// fragmentation is set and the source does not set the DF bit, otherwise the datagram is
+    /// dropped with an `OversizedError`.
+    fn send_oversized(
+        &mut self,
+        buffer: &[u8],
+        dst: SocketAddrV4,
+        max_size: usize,
+    ) -> io::Result<usize> {
+        self.connection.lock().unwrap().add_oversized();
+        if self.is_send_fragmentation && !self.is_dont_fragment {
+            trace!(
+                "{}fragment datagram to {} ({} Bytes) in {} Bytes",
+                self.get_tag(),
+                dst,
+                buffer.len(),
+                max_size
+            );
+            let result = self.datagram.send_fragments_to(buffer, dst, max_size);
+            let mut connection = self.connection.lock().unwrap();
+            match result {
+                // Each fragment carries its own header
+                Ok(_) => connection.add_overhead_up(
+                    self.datagram.get_header_size() * ((buffer.len() + max_size - 1) / max_size),
+                ),
+                Err(_) => connection.add_send_failure(),
+            }
+
+            return result;
+        }
+        event::drop_packet(DropReason::MtuExceeded, LayerTypes::Udp);
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            OversizedError::new(buffer.len(), max_size),
+        ))
+    }
+
+    /// Probes the path MTU to the relay of the association, datagrams larger than it are sent
+    /// in SOCKS5 fragments or dropped afterwards.
+    pub fn probe_mtu(&mut self) -> io::Result<Option<u16>> {
+        let mtu = self.datagram.probe_mtu()?;
+        self.max_size = match mtu {
+            Some(mtu) => Some(self.datagram.get_max_size(mtu)?),
+            None => None,
+        };
+        self.connection.lock().unwrap().set_relay_mtu(mtu);
+
+        Ok(mtu)
+    }
+
+    /// Sets if datagrams larger than the path MTU to the relay are sent in SOCKS5 fragments, or
+    /// they will be dropped.
+    pub fn set_send_fragmentation(&mut self, is_send_fragmentation: bool) {
+        self.is_send_fragmentation = is_send_fragmentation;
+    }
+
+    /// Sets if the source sets the DF bit in the datagrams sent next, which are dropped instead
+    /// of sent in SOCKS5 fragments if they are larger than the path MTU to the relay.
+    pub fn set_dont_fragment(&mut self, is_dont_fragment: bool) {
+        self.is_dont_fragment = is_dont_fragment;
+    }
+
+    /// Sends a DNS query on the SOCKS5 in UDP to the resolver instead of the destination. The
+    /// response is sent to the source as from the destination.
+    pub fn send_dns_to(
+        &mut self,
+        buffer: &[u8],
+        resolver: SocketAddrV4,
+        dst: SocketAddrV4,
+        query: Query,
+    ) -> io::Result<usize> {
+        {
+            let mut dns_map = self.dns_map.lock().unwrap();
+            // Queries never answered are abandoned
+            if dns_map.len() >= MAX_DNS_QUERIES {
+                dns_map.clear();
+            }
+            dns_map.insert(query.get_id(), (resolver, dst, query.get_udp_size()));
+        }
+        trace!(
+            "{}redirect DNS query {} from {} to {}",
+            self.get_tag(),
+            query.get_id(),
+            dst,
+            resolver
+        );
+
+        self.send_to(buffer, resolver)
+    }
+
+    /// Sends data on the SOCKS5 in UDP to the destination rewritten to instead of the original
+    /// destination. Datagrams from the destination rewritten to are sent to the source as from
+    /// the original destination.
+    pub fn send_rewritten_to(
+        &mut self,
+        buffer: &[u8],
+        target: SocketAddrV4,
+        dst: SocketAddrV4,
+    ) -> io::Result<usize> {
+        {
+            let mut connection = self.connection.lock().unwrap();
+            if connection.get_dst() == dst && connection.get_rewritten().is_none() {
+                connection.set_rewritten(target);
+                debug!("{}rewrite {} -> {}", connection.get_tag(), dst, target);
+            }
+        }
+        self.peers.lock().unwrap().add_rewrite(target, dst);
+
+        self.send_to(buffer, target)
+    }
+
+    /// Sets the source port of the `DatagramWorker`, the connection of the previous source port
+    /// is replaced with the one to the destination.
+    pub fn set_src_port(&mut self, src_port: u16, dst: SocketAddrV4) {
+        {
+            let mut connection = self.connection.lock().unwrap();
+            let relay_mtu = connection.get_relay_mtu();
+            connection.close(CloseReason::Replaced);
+            *connection = Connection::new(
+                connection.get_client(),
+                src_port,
+                dst,
+                connection.get_protocol(),
+                connection.is_proxied(),
+            );
+            connection.set_relay_mtu(relay_mtu);
+            if let Err(ref e) = connection.map_nat() {
+                debug!(
+                    "{}map datagram {} in NAT: {}",
+                    connection.get_tag(),
+                    src_port,
+                    e
+                );
+            }
+            connection.open();
+        }
+        self.src_port.store(src_port, Ordering::Relaxed);
+        self.peers.lock().unwrap().clear();
+        trace!(
+            "{}set datagram {} = {}",
+            self.get_tag(),
+            src_port,
+            self.local_port
+        );
+    }
+
+    /// Migrates the `DatagramWorker` to a new source port of the same connection, like a QUIC
+    /// connection whose source moves to a new port. Unlike setting the source port, the
+    /// connection and the peers are kept.
+    pub fn migrate_src_port(&mut self, src_port: u16) {
+        let prev_src_port = self.get_src_port();
+        {
+            let mut connection = self.connection.lock().unwrap();
+            if let Err(ref e) = connection.migrate(src_port) {
+                debug!(
+                    "{}map datagram {} in NAT: {}",
+                    connection.get_tag(),
+                    src_port,
+                    e
+                );
             }
         }
+        self.src_port.store(src_port, Ordering::Relaxed);
+        debug!(
+            "{}migrate datagram {} = {} to {} = {}",
+            self.get_tag(),
+            prev_src_port,
+            self.local_port,
+            src_port,
+            self.local_port
+        );
+    }
+
+    /// Sets if the source port is mapped by the source, which makes the association accept
+    /// datagrams from any peer even if the NAT is restricted.
+    pub fn set_mapped(&mut self, is_mapped: bool) {
+        self.peers.lock().unwrap().set_mapped(is_mapped);
+    }
+
+    /// Sets the DSCP of IPv4 packets sent on the SOCKS5 in UDP.
+    pub fn set_dscp(&mut self, dscp: u8) -> io::Result<()> {
+        if self.dscp != dscp {
+            self.datagram.set_tos(dscp << 2)?;
+            self.dscp = dscp;
+        }
 
         Ok(())
     }
 
-    fn handle_tcp_ack(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<()> {
-        if let Some(tcp) = indicator.get_tcp() {
-            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
-            let key = (tcp.get_src(), dst);
-            let is_exist = self.streams.get(&key).is_some();
-            let is_alive = match self.streams.get(&key) {
-                Some(ref stream) => !stream.is_closed(),
-                None => false,
-            };
+    /// Get the source port of the `DatagramWorker`.
+    pub fn get_src_port(&self) -> u16 {
+        self.src_port.load(Ordering::Relaxed)
+    }
 
-            if is_exist {
-                if is_alive {
-                    // ACK
-                    self.update_tcp_sequence(indicator);
-                    self.update_tcp_acknowledgement(indicator);
-                    {
-                        let mut tx_locked = self.tx.lock().unwrap();
-                        tx_locked.invalidate_cache_to(
-                            dst,
-                            tcp.get_src(),
-                            tcp.get_acknowledgement(),
-                        );
-                        tx_locked.set_tcp_send_window(dst, tcp.get_src(), tcp.get_window());
-                    }
+    /// Get the time since the last datagram in either direction.
+    pub fn get_idle(&self) -> Duration {
+        self.clock.elapsed(*self.activity.lock().unwrap())
+    }
+
+    /// Get the tag of the connection of the association prefixed to log lines.
+    pub fn get_tag(&self) -> Tag {
+        self.connection.lock().unwrap().get_tag()
+    }
+
+    /// Get the translated port of the connection of the association in the NAT.
+    pub fn get_nat_port(&self) -> Option<u16> {
+        self.connection.lock().unwrap().get_nat_port()
+    }
+
+    /// Get the destination of the first datagram of the association.
+    pub fn get_dst(&self) -> SocketAddrV4 {
+        self.connection.lock().unwrap().get_dst()
+    }
+
+    /// Get the destination the first datagram of the association is rewritten to.
+    pub fn get_rewritten(&self) -> Option<SocketAddrV4> {
+        self.connection.lock().unwrap().get_rewritten()
+    }
 
-                    if buffer.len() > indicator.get_size() {
-                        // ACK
-                        // Append to cache
-                        let cache = self
-                            .tcp_cache_map
-                            .entry(key)
-                            .or_insert_with(|| RandomCacher::new(tcp.get_sequence()));
-                        let payload =
-                            cache.append(tcp.get_sequence(), &buffer[indicator.get_size()..])?;
-
-                        match payload {
-                            Some(payload) => {
-                                // Send
-                                match self.streams.get_mut(&key).unwrap().send(payload.as_slice()) {
-                                    Ok(_) => {
-                                        // Update window size
-                                        let mut tx_locked = self.tx.lock().unwrap();
-                                        tx_locked.set_tcp_window(
-                                            dst,
-                                            tcp.get_src(),
-                                            cache.get_remaining_size(),
-                                        );
-
-                                        // Update TCP acknowledgement
-                                        tx_locked.add_tcp_acknowledgement(
-                                            dst,
-                                            tcp.get_src(),
-                                            payload.len() as u32,
-                                        );
-
-                                        // Send ACK0
-                                        // If there is a heavy traffic, the ACK reported may be inaccurate, which would results in retransmission
-                                        tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
-                                    }
-                                    Err(e) => {
-                                        // Clean up
-                                        self.remove(indicator);
-
-                                        // Send ACK/RST
-                                        let mut tx_locked = self.tx.lock().unwrap();
-                                        tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
-
-                                        // Clean up
-                                        tx_locked.remove(dst, tcp.get_src());
-
-                                        return Err(e);
-                                    }
-                                }
-                            }
-                            None => {
-                                // Retransmission or unordered
-                                // Update window size
-                                let mut tx_locked = self.tx.lock().unwrap();
-                                tx_locked.set_tcp_window(
-                                    dst,
-                                    tcp.get_src(),
-                                    cache.get_remaining_size(),
-                                );
+    /// Returns if the association is detected as QUIC.
+    pub fn is_quic(&self) -> bool {
+        self.connection.lock().unwrap().is_quic()
+    }
 
-                                // Send ACK0
-                                tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
-                            }
-                        }
-                    } else {
-                        // ACK0 or FIN
-                        if *self.tcp_duplicate_map.get(&key).unwrap_or(&0)
-                            >= DUPLICATES_BEFORE_FAST_RETRANSMISSION
-                        {
-                            if !tcp.is_zero_window() {
-                                let is_cooled_down =
-                                    match self.tcp_last_retransmission_map.get(&key) {
-                                        Some(instant) => {
-                                            instant.elapsed().as_millis() < RETRANSMISSION_COOL_DOWN
-                                        }
-                                        None => false,
-                                    };
-                                if !is_cooled_down {
-                                    if tcp.is_fin() {
-                                        // Expect all the data is handled
-                                        let mut tx_locked = self.tx.lock().unwrap();
-                                        tx_locked.set_tcp_acknowledgement(
-                                            dst,
-                                            tcp.get_src(),
-                                            tcp.get_sequence().checked_add(1).unwrap_or(0),
-                                        );
-                                        // Send ACK/FIN
-                                        tx_locked.send_tcp_ack_fin(dst, tcp.get_src())?;
-                                    } else {
-                                        // Fast retransmit
-                                        // TODO: the procedure is in back N
-                                        self.tx
-                                            .lock()
-                                            .unwrap()
-                                            .resend_tcp_ack(dst, tcp.get_src())?;
-
-                                        self.tcp_duplicate_map.insert(key, 0);
-                                        self.tcp_last_retransmission_map
-                                            .insert(key, Instant::now());
-                                    }
-                                }
-                            }
-                        }
-                    }
+    /// Sets the association is detected as QUIC.
+    pub fn set_quic(&mut self) {
+        self.connection.lock().unwrap().set_quic();
+    }
 
-                    // Trigger sending remaining data
-                    self.tx.lock().unwrap().send_tcp_ack(dst, tcp.get_src())?;
-                } else {
-                    // Expect in LAST_ACK state (or the stream met an error)
-                    if tcp.is_fin() {
-                        let mut tx_locked = self.tx.lock().unwrap();
-                        tx_locked.set_tcp_acknowledgement(
-                            dst,
-                            tcp.get_src(),
-                            tcp.get_sequence().checked_add(1).unwrap_or(0),
-                        );
-                        // Send ACK/FIN
-                        tx_locked.send_tcp_ack_fin(dst, tcp.get_src())?;
-                    } else {
-                        self.remove(indicator);
-                        self.tx.lock().unwrap().remove(dst, tcp.get_src());
-                    }
-                }
-            } else {
-                if tcp.is_fin() {
-                    // Though a RST is enough, reply with respect
-                    let mut tx_locked = self.tx.lock().unwrap();
-                    #[allow(deprecated)]
-                    tx_locked.set_tcp_sequence(dst, tcp.get_src(), tcp.get_acknowledgement());
-                    tx_locked.set_tcp_acknowledgement(
-                        dst,
-                        tcp.get_src(),
-                        tcp.get_sequence().checked_add(1).unwrap_or(0),
-                    );
-                    // Send ACK/FIN
-                    tx_locked.send_tcp_ack_fin(dst, tcp.get_src())?;
+    /// Get the count of peers the association sent to, and the count of datagrams from other
+    /// peers.
+    pub fn get_peers(&self) -> (usize, u64) {
+        let peers = self.peers.lock().unwrap();
 
-                    // Clean up
-                    tx_locked.remove(dst, tcp.get_src());
-                } else {
-                    let mut tx_locked = self.tx.lock().unwrap();
-                    #[allow(deprecated)]
-                    tx_locked.set_tcp_sequence(dst, tcp.get_src(), tcp.get_acknowledgement());
-                    tx_locked.set_tcp_acknowledgement(
-                        dst,
-                        tcp.get_src(),
-                        tcp.get_sequence().checked_add(1).unwrap_or(0),
-                    );
-                    // Send ACK/RST
-                    tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
+        (peers.len(), peers.get_unsolicited_count())
+    }
 
-                    // Clean up
-                    tx_locked.remove(dst, tcp.get_src());
-                }
+    /// Get the state of the association.
+    pub fn get_state(&self, src_ip_addr: Ipv4Addr) -> UdpState {
+        UdpState {
+            src: SocketAddrV4::new(src_ip_addr, self.get_src_port()),
+            local_port: self.local_port,
+            dst: self.get_dst(),
+            peers: self.peers.lock().unwrap().get_peers(),
+            active: state::unix_time_before(self.get_idle()),
+        }
+    }
+
+    /// Restores the peers the association sent to and the time of the last datagram from the
+    /// state of the association in the last run.
+    pub fn restore(&mut self, state: &UdpState) {
+        {
+            let mut peers = self.peers.lock().unwrap();
+            for &peer in state.peers.iter() {
+                peers.add(peer);
             }
         }
+        if let Some(instant) = self.clock.now().checked_sub(state.get_idle()) {
+            *self.activity.lock().unwrap() = instant;
+        }
+    }
 
-        Ok(())
+    /// Get the bytes sent from and to the source in the association.
+    pub fn get_bytes(&self) -> (u64, u64) {
+        let connection = self.connection.lock().unwrap();
+
+        (connection.get_bytes_up(), connection.get_bytes_down())
     }
 
-    fn handle_tcp_syn(&mut self, indicator: &Indicator) -> io::Result<()> {
-        if let Some(tcp) = indicator.get_tcp() {
-            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
-            let key = (tcp.get_src(), dst);
-            let is_exist = self.streams.get(&key).is_some();
+    /// Get the bytes of frames received from and sent to the source in the association,
+    /// including headers.
+    pub fn get_wire_bytes(&self) -> (u64, u64) {
+        self.connection.lock().unwrap().get_wire_bytes()
+    }
 
-            // Connect if not connected, drop if established
-            if !is_exist {
-                // Clean up
-                self.remove(indicator);
+    /// Get the bytes of SOCKS5 UDP headers of datagrams sent to and received from the relay in
+    /// the association.
+    pub fn get_overhead_bytes(&self) -> (u64, u64) {
+        self.connection.lock().unwrap().get_overhead_bytes()
+    }
 
-                self.tcp_sequence_map.insert(key, tcp.get_sequence());
+    /// Counts bytes of a frame received from the source in the association, including headers.
+    pub fn add_wire_up(&self, n: usize) {
+        self.connection.lock().unwrap().add_wire_up(n);
+    }
 
-                // Connect
-                let stream = StreamWorker::connect(self.get_tx(), tcp.get_src(), dst, self.remote);
+    /// Get the datagrams sent from and to the source in the association, and the count of
+    /// datagrams which cannot be sent to the relay.
+    pub fn get_packets(&self) -> (u64, u64, u64) {
+        let connection = self.connection.lock().unwrap();
+        let (packets_up, packets_down) = connection.get_packets();
 
-                let stream = match stream {
-                    Ok(stream) => {
-                        let mut tx_locked = self.tx.lock().unwrap();
-                        // Clean up
-                        tx_locked.remove(dst, tcp.get_src());
+        (packets_up, packets_down, connection.get_send_failures())
+    }
 
-                        tx_locked.set_tcp_acknowledgement(
-                            dst,
-                            tcp.get_src(),
-                            tcp.get_sequence().checked_add(1).unwrap_or(0),
-                        );
-                        // Send ACK/SYN
-                        tx_locked.send_tcp_ack_syn(dst, tcp.get_src())?;
+    /// Get the count of datagrams larger than the path MTU to the relay in the association, and
+    /// the path MTU.
+    pub fn get_oversized(&self) -> (u64, Option<u16>) {
+        let connection = self.connection.lock().unwrap();
 
-                        stream
-                    }
-                    Err(e) => {
-                        // Clean up
-                        self.remove(indicator);
+        (connection.get_oversized(), connection.get_relay_mtu())
+    }
 
-                        let mut tx_locked = self.tx.lock().unwrap();
-                        tx_locked.set_tcp_acknowledgement(
-                            dst,
-                            tcp.get_src(),
-                            tcp.get_sequence().checked_add(1).unwrap_or(0),
-                        );
-                        // Send ACK/RST
-                        tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
+    /// Get the jitter of datagrams sent to the source in the association.
+    pub fn get_jitter(&self) -> Duration {
+        self.connection.lock().unwrap().get_jitter()
+    }
 
-                        // Clean up
-                        tx_locked.remove(dst, tcp.get_src());
+    /// Sets the idle time before TCP keepalive probes of the control connection of the SOCKS
+    /// association.
+    pub fn set_keepalive(&self, idle: Duration) -> io::Result<()> {
+        self.datagram.set_keepalive(idle)
+    }
 
-                        return Err(e);
-                    }
-                };
+    /// Get the time since the last datagram from the relay.
+    pub fn get_relay_idle(&self) -> Duration {
+        self.clock.elapsed(*self.relay_activity.lock().unwrap())
+    }
+
+    /// Checks the control connection of the SOCKS association, and sends a keepalive datagram
+    /// through the relay if the association is idle for the keepalive. Returns an error if the
+    /// control connection is closed, unless the association is lenient and its relay still
+    /// relays datagrams. Keepalive datagrams never reset the idle time.
+    pub fn update_control(
+        &mut self,
+        keepalive: Option<Duration>,
+        is_strict: bool,
+    ) -> io::Result<()> {
+        if let Err(e) = self.datagram.check_control() {
+            if is_strict || self.get_relay_idle() >= CONTROL_CLOSED_GRACE {
+                return Err(e);
+            }
+            if !self.is_control_closed {
+                self.is_control_closed = true;
+                debug!(
+                    "{}keep datagram {} = {} whose relay still relays: {}",
+                    self.get_tag(),
+                    self.get_src_port(),
+                    self.local_port,
+                    e
+                );
+            }
+        }
 
-                self.streams.insert(key, stream);
+        if let Some(keepalive) = keepalive {
+            if self.get_idle() >= keepalive
+                && self.clock.elapsed(self.keepalive_instant) >= keepalive
+            {
+                self.keepalive_instant = self.clock.now();
+                trace!(
+                    "{}send UDP keepalive of {} = {}",
+                    self.get_tag(),
+                    self.get_src_port(),
+                    self.local_port
+                );
+                self.datagram.send_keepalive()?;
             }
         }
 
         Ok(())
     }
 
-    fn handle_tcp_rst(&mut self, indicator: &Indicator) {
-        if let Some(ref tcp) = indicator.get_tcp() {
-            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
-            let key = (tcp.get_src(), dst);
-            let is_exist = self.streams.get(&key).is_some();
-
-            if is_exist {
-                // Clean up
-                self.remove(indicator);
-                self.tx.lock().unwrap().remove(dst, tcp.get_src());
+    /// Sends a liveness probe through the relay to the endpoint once no datagram comes from the
+    /// relay for the interval. Returns if the relay is dead, which sends nothing back in the
+    /// timeout of the probe. A DNS query is sent to port 53, and a payload to be echoed is sent
+    /// to other ports.
+    pub fn update_probe(&mut self, probe: SocketAddrV4, interval: Duration) -> io::Result<bool> {
+        let relay_idle = self.get_relay_idle();
+        if let Some(instant) = self.probe_instant {
+            let elapsed = self.clock.elapsed(instant);
+            if relay_idle >= elapsed {
+                return Ok(elapsed >= PROBE_TIMEOUT);
             }
+            self.probe_instant = None;
         }
+        if relay_idle < interval {
+            return Ok(false);
+        }
+
+        let payload = match probe.port() {
+            DNS_PORT => dns::new_root_query(self.local_port),
+            _ => PROBE_PAYLOAD.to_vec(),
+        };
+        self.peers.lock().unwrap().set_probe(probe);
+        self.probe_instant = Some(self.clock.now());
+        trace!(
+            "{}send UDP probe of {} = {} to {}",
+            self.get_tag(),
+            self.get_src_port(),
+            self.local_port,
+            probe
+        );
+        self.datagram.send_to(&payload, probe)?;
+
+        Ok(false)
     }
 
-    fn handle_tcp_fin(&mut self, indicator: &Indicator) -> io::Result<()> {
-        if let Some(ref tcp) = indicator.get_tcp() {
-            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
-            let key = (tcp.get_src(), dst);
-            let is_exist = self.streams.get(&key).is_some();
+    /// Returns if all the datagrams sent by the worker are DNS queries.
+    pub fn is_dns(&self) -> bool {
+        self.is_dns
+    }
 
-            if is_exist {
-                let remain_cache_size = self.tx.lock().unwrap().get_cache_size(dst, tcp.get_src());
-                if remain_cache_size > 0 {
-                    // Trigger sending remaining data
-                    self.tx.lock().unwrap().send_tcp_ack(dst, tcp.get_src())?;
-                } else {
-                    let stream = self.streams.get_mut(&key).unwrap();
-                    stream.close();
+    /// Closes the worker.
+    pub fn close(&mut self) {
+        self.is_closed.store(true, Ordering::Relaxed);
+        trace!(
+            "{}close datagram {} = {}",
+            self.get_tag(),
+            self.get_src_port(),
+            self.local_port
+        );
+    }
 
-                    let mut tx_locked = self.tx.lock().unwrap();
-                    tx_locked.set_tcp_acknowledgement(
-                        dst,
-                        tcp.get_src(),
-                        tcp.get_sequence().checked_add(1).unwrap_or(0),
-                    );
-                    // Send ACK/FIN
-                    tx_locked.send_tcp_ack_fin(dst, tcp.get_src())?;
-                }
-            } else {
-                // Though a RST is enough, reply with respect
-                let mut tx_locked = self.tx.lock().unwrap();
-                #[allow(deprecated)]
-                tx_locked.set_tcp_sequence(dst, tcp.get_src(), tcp.get_acknowledgement());
-                tx_locked.set_tcp_acknowledgement(
-                    dst,
-                    tcp.get_src(),
-                    tcp.get_sequence().checked_add(1).unwrap_or(0),
-                );
-                // Send ACK/FIN
-                tx_locked.send_tcp_ack_fin(dst, tcp.get_src())?;
+    /// Returns if the worker is closed.
+    pub fn is_closed(&self) -> bool {
+        self.is_closed.load(Ordering::Relaxed)
+    }
 
-                // Clean up
-                tx_locked.remove(dst, tcp.get_src());
-            }
+    /// Closes the connection of the worker for the reason.
+    pub fn close_connection(&mut self, reason: CloseReason) {
+        self.connection.lock().unwrap().close(reason);
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl Drop for DatagramWorker {
+    fn drop(&mut self) {
+        // Connections closed for other reasons are closed before
+        self.close_connection(CloseReason::Shutdown);
+        self.close();
+        // The SOCKS association and the local port are released after the thread exits
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
         }
+        trace!(
+            "{}drop datagram {} = {}",
+            self.get_tag(),
+            self.get_src_port(),
+            self.local_port
+        );
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::pcap::channel::{self, Peer};
+    use crate::socks::{SocksConnector, SocksOption};
+    use std::collections::VecDeque;
+    use std::io::Read;
+    use std::net::{Shutdown, TcpListener, UdpSocket};
+
+    const SRC_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 1);
+    const LOCAL_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 254);
+    const SRC_PORT: u16 = 40000;
+
+    fn dst() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 80)
     }
 
-    fn handle_udp(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<()> {
-        if let Some(ref udp) = indicator.get_udp() {
-            let port = self.get_local_udp_port(udp.get_src());
-            let index = (port - INITIAL_PORT) as usize;
+    /// Creates a `Downstreamer` on the clock sending to the peer of a channel, with an
+    /// established TCP connection.
+    fn downstreamer(clock: &ManualClock) -> (Downstreamer, Peer) {
+        let (tx, _, peer) = channel::open();
+        let queue = Arc::new(Mutex::new(SendQueue::new(tx, 1)));
+        let mut downstreamer = Downstreamer::new(
+            queue,
+            1500,
+            HardwareAddr::new(0x02, 0, 0, 0, 0, 0x01),
+            SRC_IP_ADDR,
+            LOCAL_IP_ADDR,
+        );
+        downstreamer.set_src_hardware_addr(HardwareAddr::new(0x02, 0, 0, 0, 0, 0x02));
+        downstreamer.set_clock(Arc::new(clock.clone()));
+        let key = (SRC_PORT, dst());
+        downstreamer.tcp_sequence_map.insert(key, 1000);
+        downstreamer.tcp_initial_sequence_map.insert(key, 999);
+        downstreamer.tcp_acknowledgement_map.insert(key, 5000);
+
+        (downstreamer, peer)
+    }
 
-            // Bind
-            let is_create;
-            let is_set;
-            match self.datagrams[index] {
-                Some(ref worker) => {
-                    is_create = worker.is_closed();
-                    is_set = worker.get_src_port() != udp.get_src();
-                }
-                None => {
-                    is_create = true;
-                    is_set = false;
-                }
-            };
-            if is_create {
-                // Bind
-                self.datagrams[index] = Some(DatagramWorker::bind(
-                    self.get_tx(),
-                    udp.get_src(),
-                    port,
-                    self.remote,
-                )?);
-            } else if is_set {
-                // Replace
-                self.datagrams[index]
-                    .as_mut()
+    /// Collects the sequences of TCP segments sent to the source.
+    fn collect_sequences(peer: &Peer) -> Vec<u32> {
+        peer.collect_all()
+            .iter()
+            .map(|frame| {
+                Indicator::from(frame)
                     .unwrap()
-                    .set_src_port(udp.get_src());
-            }
+                    .get_tcp()
+                    .unwrap()
+                    .get_sequence()
+            })
+            .collect()
+    }
 
-            // Send
-            self.datagrams[index].as_mut().unwrap().send_to(
-                &buffer[indicator.get_size()..],
-                SocketAddrV4::new(udp.get_dst_ip_addr(), udp.get_dst()),
-            )?;
+    #[test]
+    fn fin_backoff() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
+
+        downstreamer.append_fin_to_cache(dst(), SRC_PORT).unwrap();
+        assert_eq!(collect_sequences(&peer), vec![1000]);
+
+        // The RTO starts from 1 second, and is doubled by every retransmission up to 60 seconds
+        let rtos = [1, 2, 4, 8, 16, 32, 60, 60];
+        for rto in rtos.iter() {
+            clock.advance(Duration::from_millis(rto * 1000 - 1));
+            assert!(downstreamer
+                .resend_tcp_ack_fin_if_timed_out(dst(), SRC_PORT)
+                .unwrap());
+            assert!(collect_sequences(&peer).is_empty());
+            clock.advance(Duration::from_millis(1));
+            assert!(downstreamer
+                .resend_tcp_ack_fin_if_timed_out(dst(), SRC_PORT)
+                .unwrap());
+            assert_eq!(collect_sequences(&peer), vec![1000]);
         }
+        assert_eq!(
+            downstreamer.get_tcp_retransmission_count(dst(), SRC_PORT),
+            MAX_FIN_RETRANSMISSIONS
+        );
 
-        Ok(())
+        // Given up after the max retransmissions
+        clock.advance(Duration::from_secs(60));
+        assert!(!downstreamer
+            .resend_tcp_ack_fin_if_timed_out(dst(), SRC_PORT)
+            .unwrap());
+        assert!(collect_sequences(&peer).is_empty());
     }
 
-    fn update_tcp_sequence(&mut self, indicator: &Indicator) {
-        if let Some(tcp) = indicator.get_tcp() {
-            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
-            let key = (tcp.get_src(), dst);
+    #[test]
+    fn persist_backoff() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
+
+        // The zero window holds the data
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, 0);
+        downstreamer
+            .append_to_cache(dst(), SRC_PORT, b"zero window")
+            .unwrap();
+        assert!(collect_sequences(&peer).is_empty());
+
+        // Probes are backed off from the RTO up to 60 seconds, and all probe the first byte
+        let intervals = [1, 2, 4, 8, 16, 32, 60, 60, 60];
+        for interval in intervals.iter() {
+            clock.advance(Duration::from_millis(interval * 1000 - 1));
+            downstreamer
+                .send_tcp_window_probe_if_timed_out(dst(), SRC_PORT)
+                .unwrap();
+            assert!(collect_sequences(&peer).is_empty());
+            clock.advance(Duration::from_millis(1));
+            downstreamer
+                .send_tcp_window_probe_if_timed_out(dst(), SRC_PORT)
+                .unwrap();
+            assert_eq!(collect_sequences(&peer), vec![1000]);
+        }
+        // Probes are never retransmissions
+        assert_eq!(
+            downstreamer.get_tcp_retransmission_count(dst(), SRC_PORT),
+            0
+        );
 
-            let record_sequence = *self.tcp_sequence_map.get(&key).unwrap_or(&0);
-            let sub_sequence = tcp
-                .get_sequence()
-                .checked_sub(record_sequence)
-                .unwrap_or_else(|| tcp.get_sequence() + (u32::MAX - record_sequence));
+        // A window update stops the timer
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, 65535);
+        clock.advance(Duration::from_secs(60));
+        downstreamer
+            .send_tcp_window_probe_if_timed_out(dst(), SRC_PORT)
+            .unwrap();
+        assert!(collect_sequences(&peer).is_empty());
+    }
 
-            if sub_sequence == 0 {
-                // Duplicate
-                trace!(
-                    "TCP retransmission of {} -> {} at {}",
-                    tcp.get_src(),
-                    dst,
-                    tcp.get_sequence()
-                );
-            } else if sub_sequence < MAX_U32_WINDOW_SIZE as u32 {
-                self.tcp_sequence_map.insert(key, tcp.get_sequence());
+    #[test]
+    fn udp_expiry() {
+        let clock = ManualClock::new();
+        let (downstreamer, _peer) = downstreamer(&clock);
+        let proxy = SocksConnector::new(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080),
+            SocksOption::default(),
+        );
+        let mut upstreamer = Upstreamer::new(
+            Arc::new(Mutex::new(downstreamer)),
+            SRC_IP_ADDR,
+            None,
+            Arc::new(proxy),
+        );
+        upstreamer.set_clock(Arc::new(clock.clone()));
+        let timeout = upstreamer.udp_timeout;
+
+        // A datagram to a port other than DNS makes the association expire in the UDP timeout
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer_addr = match peer.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let connection = Connection::new(SRC_IP_ADDR, SRC_PORT, peer_addr, Protocol::Udp, false);
+        let mut worker = DatagramWorker::bind_direct(
+            upstreamer.get_tx(),
+            connection,
+            upstreamer.limits.clone(),
+            upstreamer.udp_nat,
+            Arc::new(clock.clone()),
+        )
+        .unwrap();
+        worker.send_to(b"ping", peer_addr).unwrap();
+        upstreamer.direct_datagrams.insert(SRC_PORT, worker);
+
+        // Timers are handled every interval
+        let interval = Duration::from_millis(TIMER_INTERVAL as u64);
+        clock.advance(timeout - interval);
+        upstreamer.handle_timers();
+        assert!(upstreamer.direct_datagrams.contains_key(&SRC_PORT));
+        clock.advance(interval);
+        upstreamer.handle_timers();
+        assert!(!upstreamer.direct_datagrams.contains_key(&SRC_PORT));
+    }
 
-                trace!(
-                    "set TCP sequence of {} -> {} to {}",
-                    tcp.get_src(),
-                    dst,
-                    tcp.get_sequence()
-                );
-            } else {
-                trace!(
-                    "TCP out of order of {} -> {} at {}",
-                    tcp.get_src(),
-                    dst,
-                    tcp.get_sequence()
-                );
-            }
-        }
+    #[test]
+    fn ipv6_syn() {
+        let src = "fd00::1".parse().unwrap();
+        let dst = "2001:db8::7".parse().unwrap();
+        let indicator = Indicator::new(
+            Layers::Ethernet(
+                Ethernet::new(
+                    LayerTypes::Ipv6,
+                    HardwareAddr::new(0x02, 0, 0, 0, 0, 0x02),
+                    HardwareAddr::new(0x02, 0, 0, 0, 0, 0x01),
+                )
+                .unwrap(),
+            ),
+            Some(Layers::Ipv6(Ipv6::new(LayerTypes::Tcp, src, dst).unwrap())),
+            Some(Layers::Tcp(Tcp::new_syn(
+                IpAddr::V6(src),
+                IpAddr::V6(dst),
+                SRC_PORT,
+                80,
+                1000,
+                65535,
+            ))),
+        );
+        let frame = indicator.to_vec().unwrap();
+
+        let indicator = Indicator::from(&frame).unwrap();
+        assert_eq!(indicator.get_network_type(), Some(LayerTypes::Ipv6));
+        let tcp = indicator.get_tcp().unwrap();
+        assert!(tcp.is_syn());
+        assert_eq!(tcp.get_dst_ip_addr(), IpAddr::V6(dst));
+        assert_eq!(ipv4_addr(tcp.get_dst_ip_addr()), None);
+
+        // The segment is ignored instead of being redirected
+        let (downstreamer, peer) = downstreamer(&ManualClock::new());
+        let proxy = SocksConnector::new(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080),
+            SocksOption::default(),
+        );
+        let mut upstreamer = Upstreamer::new(
+            Arc::new(Mutex::new(downstreamer)),
+            SRC_IP_ADDR,
+            None,
+            Arc::new(proxy),
+        );
+        upstreamer.handle_tcp(&indicator).unwrap();
+        assert!(peer.collect_all().is_empty());
     }
 
-    fn update_tcp_acknowledgement(&mut self, indicator: &Indicator) {
-        if let Some(tcp) = indicator.get_tcp() {
-            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
-            let key = (tcp.get_src(), dst);
+    #[test]
+    fn ipv4_identification_window() {
+        let (mut downstreamer, peer) = downstreamer(&ManualClock::new());
+        let other = SocketAddrV4::new(Ipv4Addr::new(198, 51, 100, 1), 53);
+        let mut last_map: HashMap<u16, usize> = HashMap::new();
+        let mut count = 0;
+        for i in 0..70000u32 {
+            // Datagrams from other ports of the destination and from other destinations
+            // interleave
+            let src = SocketAddrV4::new(*dst().ip(), 1000 + (i % 3) as u16);
+            downstreamer.send_udp(src, SRC_PORT, b"ping").unwrap();
+            if i % 7 == 0 {
+                downstreamer.send_udp(other, SRC_PORT, b"ping").unwrap();
+            }
 
-            let record_acknowledgement = *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0);
-            let sub_acknowledgement = tcp
-                .get_acknowledgement()
-                .checked_sub(record_acknowledgement)
-                .unwrap_or_else(|| tcp.get_acknowledgement() + (u32::MAX - record_acknowledgement));
+            for frame in peer.collect_all() {
+                let indicator = Indicator::from(&frame).unwrap();
+                let ipv4 = indicator.get_ipv4().unwrap();
+                if ipv4.get_src() != *dst().ip() {
+                    continue;
+                }
+                assert_eq!(ipv4.get_dst(), SRC_IP_ADDR);
 
-            if sub_acknowledgement == 0 {
-                // Duplicate
-                let entry = self.tcp_duplicate_map.entry(key).or_insert(0);
-                *entry = entry.checked_add(1).unwrap_or(usize::MAX);
-                trace!(
-                    "duplicate TCP acknowledgement of {} -> {} at {}",
-                    tcp.get_src(),
-                    dst,
-                    tcp.get_acknowledgement()
-                );
-            } else if sub_acknowledgement < MAX_U32_WINDOW_SIZE as u32 {
-                self.tcp_acknowledgement_map
-                    .insert(key, tcp.get_acknowledgement());
+                // No identification repeats in a sliding window of 2^15 packets
+                let identification = ipv4.get_identification();
+                if let Some(last) = last_map.insert(identification, count) {
+                    assert!(count - last >= 1 << 15);
+                }
+                count += 1;
+            }
+        }
+        assert_eq!(count, 70000);
+    }
+
+    #[test]
+    fn udp_fragments() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
+        let payload: Vec<u8> = (0..4000).map(|i| (i * 7 + i / 256) as u8).collect();
+        downstreamer.send_udp(dst(), SRC_PORT, &payload).unwrap();
+
+        // Fragments fit in the MTU and all but the last one are aligned to 8 Bytes
+        let frames = peer.collect_all();
+        assert_eq!(frames.len(), 3);
+        let mut offsets = Vec::new();
+        for (i, frame) in frames.iter().enumerate() {
+            let indicator = Indicator::from(frame).unwrap();
+            let ipv4 = indicator.get_ipv4().unwrap();
+            assert!(ipv4.get_total_length() <= 1500);
+            assert_eq!(ipv4.is_more_fragment(), i + 1 < frames.len());
+            if ipv4.is_more_fragment() {
+                assert_eq!((ipv4.get_total_length() as usize - ipv4.get_size()) % 8, 0);
+            }
+            offsets.push(ipv4.get_fragment_offset());
+        }
+        assert_eq!(offsets[0], 0);
+
+        // Fragments are reassembled into the original datagram in any order
+        let orders: [&[usize]; 2] = [&[0, 1, 2], &[2, 0, 1]];
+        for order in orders.iter() {
+            let mut defrag = Defraggler::new();
+            let mut frag = None;
+            for &i in order.iter() {
+                let indicator = Indicator::from(&frames[i]).unwrap();
+                assert!(frag.is_none());
+                frag = defrag.add(&indicator, &frames[i]);
+            }
+            let (indicator, _) = frag.unwrap().concatenate().unwrap();
+            let ipv4 = indicator.get_ipv4().unwrap();
+            assert!(!ipv4.is_more_fragment());
+            assert_eq!(ipv4.get_fragment_offset(), 0);
+            let udp = indicator.get_udp().unwrap();
+            assert_eq!(udp.get_src(), dst().port());
+            assert_eq!(udp.get_dst(), SRC_PORT);
+            assert_eq!(udp.get_payload(), &payload[..]);
+            assert!(udp.verify_checksum());
+        }
+    }
 
-                self.tcp_duplicate_map.insert(key, 0);
-                trace!(
-                    "set TCP acknowledgement of {} -> {} to {}",
-                    tcp.get_src(),
-                    dst,
-                    tcp.get_acknowledgement()
+    #[test]
+    fn trim_window() {
+        // A 2 KB segment retransmitted against a 1 KB window makes progress every round
+        for &next in &[1000u32, u32::MAX - 1500] {
+            let (sequence, size) = (next, 2048);
+            let mut received = next;
+            let mut rounds = 0;
+            while let Some((begin, range)) = trim_to_window(received, 1024, sequence, size) {
+                assert_eq!(begin, received);
+                assert_eq!(range.start, received.wrapping_sub(sequence) as usize);
+                assert!(!range.is_empty() && range.len() <= 1024);
+                received = received.wrapping_add(range.len() as u32);
+                rounds += 1;
+            }
+            assert_eq!(rounds, 2);
+            assert_eq!(received, sequence.wrapping_add(size as u32));
+        }
+
+        // A segment starting beyond the right edge, received already, or to a zero window
+        assert_eq!(trim_to_window(1000, 1024, 2024, 100), None);
+        assert_eq!(trim_to_window(1000, 1024, 500, 500), None);
+        assert_eq!(trim_to_window(1000, 0, 1000, 100), None);
+
+        // A segment overlapping both edges
+        assert_eq!(
+            trim_to_window(1000, 1024, 900, 2048),
+            Some((1000, 100..1124))
+        );
+    }
+
+    #[test]
+    fn wscale_in_flight() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
+        let recv_window = 192 * 1024;
+        downstreamer.set_tcp_recv_window(recv_window);
+        downstreamer.set_tcp_window(dst(), SRC_PORT, recv_window);
+        downstreamer.set_tcp_wscale(dst(), SRC_PORT, 7);
+        // The source advertises 128 KB in the window field of 1024
+        let wscale = downstreamer.get_tcp_wscale(dst(), SRC_PORT).unwrap();
+        let window = 1024 << wscale;
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, window);
+
+        let data: Vec<u8> = (0..512 * 1024).map(|i| i as u8).collect();
+        downstreamer
+            .append_to_cache(dst(), SRC_PORT, &data)
+            .unwrap();
+
+        // Every other segment is acknowledged, so the congestion window grows in the slow start
+        // until the flight is limited by the window of the source
+        let mut acknowledgement = 1000u32;
+        let mut ends = VecDeque::new();
+        let mut max_in_flight = 0;
+        loop {
+            for frame in peer.collect_all() {
+                let indicator = Indicator::from(&frame).unwrap();
+                let tcp = indicator.get_tcp().unwrap();
+                // Our window is advertised scaled by our shift count
+                assert_eq!(
+                    tcp.get_window() as usize,
+                    recv_window >> tcp_window_scale_of(recv_window)
+                );
+                ends.push_back(
+                    tcp.get_sequence()
+                        .wrapping_add(tcp.get_payload().len() as u32),
                 );
             }
+            let end = match ends.back() {
+                Some(&end) => end,
+                None => break,
+            };
+            let in_flight = end.wrapping_sub(acknowledgement) as usize;
+            assert!(in_flight <= window);
+            max_in_flight = max(max_in_flight, in_flight);
+
+            ends.pop_front();
+            acknowledgement = ends.pop_front().unwrap_or(end);
+            downstreamer
+                .update_tcp_recovery(dst(), SRC_PORT, acknowledgement, window, true)
+                .unwrap();
+            downstreamer.invalidate_cache_to(dst(), SRC_PORT, acknowledgement);
+            downstreamer.set_tcp_send_window(dst(), SRC_PORT, window);
+            downstreamer.send_tcp_ack(dst(), SRC_PORT).unwrap();
         }
+        assert!(max_in_flight > u16::MAX as usize);
+        assert_eq!(acknowledgement, 1000 + data.len() as u32);
     }
 
-    fn remove(&mut self, indicator: &Indicator) {
-        if let Some(tcp) = indicator.get_tcp() {
-            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
-            let key = (tcp.get_src(), dst);
+    #[test]
+    fn sequence_compare_wrap() {
+        assert!(seq_lt(u32::MAX, 0));
+        assert!(seq_lt(u32::MAX - 1000, 1000));
+        assert!(!seq_lt(1000, u32::MAX - 1000));
+        assert!(!seq_lt(0, 0));
+        assert!(seq_le(0, 0));
+        assert!(seq_le(u32::MAX, 0));
+        assert!(!seq_le(0, u32::MAX));
+        // Sequences half the ring apart are ordered one way only
+        assert!(seq_lt(0, 0x7fffffff));
+        assert!(!seq_lt(0x7fffffff, 0));
+    }
 
-            self.streams.remove(&key);
-            self.tcp_sequence_map.remove(&key);
-            self.tcp_acknowledgement_map.remove(&key);
-            self.tcp_duplicate_map.remove(&key);
-            self.tcp_last_retransmission_map.remove(&key);
-            self.tcp_cache_map.remove(&key);
-            trace!("remove {} -> {}", dst, tcp.get_dst());
+    #[test]
+    fn sequence_wrap() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
+        let sequence = u32::MAX - 1000;
+        downstreamer.pick_up_tcp(dst(), SRC_PORT, sequence, 5000);
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, 65535);
+
+        // Full segments are sent across the wrap-around in the initial congestion window
+        let data: Vec<u8> = (0..2920).map(|i| i as u8).collect();
+        downstreamer
+            .append_to_cache(dst(), SRC_PORT, &data)
+            .unwrap();
+        let mut sent = Vec::new();
+        for frame in peer.collect_all() {
+            let indicator = Indicator::from(&frame).unwrap();
+            let tcp = indicator.get_tcp().unwrap();
+            assert_eq!(tcp.get_sequence(), sequence.wrapping_add(sent.len() as u32));
+            assert_eq!(tcp.get_acknowledgement(), 5000);
+            sent.extend_from_slice(tcp.get_payload());
         }
-    }
+        assert_eq!(sent, data);
+        assert_eq!(downstreamer.get_tcp_in_flight(dst(), SRC_PORT), 2920);
+
+        // Acknowledged across the wrap-around
+        let acknowledgement = sequence.wrapping_add(1200);
+        assert!(acknowledgement < sequence);
+        downstreamer.invalidate_cache_to(dst(), SRC_PORT, acknowledgement);
+        assert_eq!(downstreamer.get_tcp_in_flight(dst(), SRC_PORT), 1720);
+
+        // Retransmitted from the acknowledgement
+        downstreamer.resend_tcp_ack(dst(), SRC_PORT).unwrap();
+        let mut resent = Vec::new();
+        for frame in peer.collect_all() {
+            let indicator = Indicator::from(&frame).unwrap();
+            let tcp = indicator.get_tcp().unwrap();
+            assert_eq!(
+                tcp.get_sequence(),
+                acknowledgement.wrapping_add(resent.len() as u32)
+            );
+            resent.extend_from_slice(tcp.get_payload());
+        }
+        assert_eq!(resent, &data[1200..]);
 
-    fn get_tx(&self) -> Arc<Mutex<Downstreamer>> {
-        Arc::clone(&self.tx)
+        // An old acknowledgement before the wrap-around is ignored
+        downstreamer.invalidate_cache_to(dst(), SRC_PORT, sequence.wrapping_add(500));
+        assert_eq!(downstreamer.get_tcp_in_flight(dst(), SRC_PORT), 1720);
+        downstreamer.invalidate_cache_to(dst(), SRC_PORT, sequence.wrapping_add(2920));
+        assert_eq!(downstreamer.get_tcp_in_flight(dst(), SRC_PORT), 0);
     }
 
-    fn get_local_udp_port(&mut self, src_port: u16) -> u16 {
-        let local_port = self.datagram_map[src_port as usize];
-        if local_port == 0 {
-            let pair = self.udp_lru.pop_lru().unwrap();
-            let index = pair.0;
-            let prev_src_port = pair.1;
-            let local_port = INITIAL_PORT + index;
+    /// Receives a burst of full segments in order, and returns the acknowledgements of the ACKs
+    /// sent to the source.
+    fn receive_burst(downstreamer: &mut Downstreamer, peer: &Peer, segments: u32) -> Vec<u32> {
+        let key = (SRC_PORT, dst());
+        for _ in 0..segments {
+            *downstreamer.tcp_acknowledgement_map.get_mut(&key).unwrap() += 1460;
+            downstreamer
+                .send_tcp_ack_0_delayed(dst(), SRC_PORT)
+                .unwrap();
+        }
 
-            // Update LRU
-            self.udp_lru.put(index, src_port);
+        peer.collect_all()
+            .iter()
+            .map(|frame| {
+                Indicator::from(frame)
+                    .unwrap()
+                    .get_tcp()
+                    .unwrap()
+                    .get_acknowledgement()
+            })
+            .collect()
+    }
 
-            if prev_src_port != 0 {
-                // Reuse
-                self.datagram_map[prev_src_port as usize] = 0;
-                trace!(
-                    "reuse UDP port {} = {} to {} = {}",
-                    prev_src_port,
-                    local_port,
-                    src_port,
-                    local_port
-                );
-            }
-            self.datagram_map[src_port as usize] = local_port;
+    #[test]
+    fn delayed_ack_burst() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
 
-            local_port
-        } else {
-            // Update LRU
-            self.udp_lru.get(&local_port);
+        // Every second segment is acknowledged
+        let acks = receive_burst(&mut downstreamer, &peer, 100);
+        assert_eq!(acks.len(), 50);
+        for (i, ack) in acks.iter().enumerate() {
+            assert_eq!(*ack, 5000 + 1460 * 2 * (i as u32 + 1));
+        }
 
-            local_port
+        // The last odd segment is acknowledged in the timeout
+        assert!(receive_burst(&mut downstreamer, &peer, 1).is_empty());
+        clock.advance(Duration::from_millis(DEFAULT_DELAYED_ACK - 1));
+        downstreamer.send_tcp_ack_0_if_timed_out().unwrap();
+        assert!(peer.collect_all().is_empty());
+        clock.advance(Duration::from_millis(1));
+        downstreamer.send_tcp_ack_0_if_timed_out().unwrap();
+        assert_eq!(peer.collect_all().len(), 1);
+        downstreamer.send_tcp_ack_0_if_timed_out().unwrap();
+        assert!(peer.collect_all().is_empty());
+    }
+
+    #[test]
+    fn delayed_ack_disabled() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
+        downstreamer.set_delayed_ack(Duration::from_millis(0));
+
+        // Every segment is acknowledged
+        let acks = receive_burst(&mut downstreamer, &peer, 100);
+        assert_eq!(acks.len(), 100);
+        for (i, ack) in acks.iter().enumerate() {
+            assert_eq!(*ack, 5000 + 1460 * (i as u32 + 1));
         }
+        clock.advance(Duration::from_secs(1));
+        downstreamer.send_tcp_ack_0_if_timed_out().unwrap();
+        assert!(peer.collect_all().is_empty());
     }
-}
 
-/// Represents the times the stream received 0 byte data continuously before close itself.
-const ZEROES_BEFORE_CLOSE: usize = 3;
+    #[test]
+    fn delayed_ack_piggybacked() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, 65535);
+
+        // A segment sent in the meantime acknowledges instead
+        assert!(receive_burst(&mut downstreamer, &peer, 1).is_empty());
+        downstreamer
+            .append_to_cache(dst(), SRC_PORT, b"piggyback")
+            .unwrap();
+        assert_eq!(collect_sequences(&peer), vec![1000]);
+        clock.advance(Duration::from_millis(DEFAULT_DELAYED_ACK));
+        downstreamer.send_tcp_ack_0_if_timed_out().unwrap();
+        assert!(peer.collect_all().is_empty());
+    }
 
-/// Represents a worker of a SOCKS5 TCP stream.
-struct StreamWorker {
-    dst: SocketAddrV4,
-    stream: TcpStream,
-    thread: Option<JoinHandle<()>>,
-    is_closed: Arc<AtomicBool>,
-}
+    #[test]
+    fn nagle() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, 65535);
+
+        // A small segment is held while data is in flight
+        downstreamer
+            .append_to_cache(dst(), SRC_PORT, &[0u8; 100])
+            .unwrap();
+        assert_eq!(collect_sequences(&peer), vec![1000]);
+        downstreamer
+            .append_to_cache(dst(), SRC_PORT, &[0u8; 100])
+            .unwrap();
+        assert!(collect_sequences(&peer).is_empty());
+
+        // And sent once the data in flight is acknowledged
+        downstreamer.invalidate_cache_to(dst(), SRC_PORT, 1100);
+        downstreamer.send_tcp_ack(dst(), SRC_PORT).unwrap();
+        assert_eq!(collect_sequences(&peer), vec![1100]);
+    }
 
-impl StreamWorker {
-    /// Opens a new `StreamWorker`.
-    pub fn connect(
-        tx: Arc<Mutex<Downstreamer>>,
-        src_port: u16,
-        dst: SocketAddrV4,
-        remote: SocketAddrV4,
-    ) -> io::Result<StreamWorker> {
-        let stream = socks::connect(remote, dst)?;
-        let mut stream_cloned = stream.try_clone()?;
+    #[test]
+    fn no_delay() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, 65535);
+        downstreamer.set_nagle(false);
+
+        // Small segments are sent immediately while data is in flight
+        for i in 0..4 {
+            downstreamer
+                .append_to_cache(dst(), SRC_PORT, &[0u8; 100])
+                .unwrap();
+            assert_eq!(collect_sequences(&peer), vec![1000 + i * 100]);
+        }
+    }
 
-        let is_closed = AtomicBool::new(false);
-        let a_is_closed = Arc::new(is_closed);
-        let a_is_closed_cloned = Arc::clone(&a_is_closed);
-        let thread = thread::spawn(move || {
-            let mut buffer = [0u8; u16::MAX as usize];
-            let mut zero = 0;
-            loop {
-                if a_is_closed_cloned.load(Ordering::Relaxed) {
-                    break;
-                }
-                match stream_cloned.read(&mut buffer) {
-                    Ok(size) => {
-                        if a_is_closed_cloned.load(Ordering::Relaxed) {
-                            break;
-                        }
-                        if size == 0 {
-                            zero += 1;
-                            if zero >= ZEROES_BEFORE_CLOSE {
-                                // TODO: a potential bug
-                                /* This may happen frequently for unknown reason
-                                warn!(
-                                    "SOCKS: {}: {} -> {}: {}",
-                                    "TCP",
-                                    0,
-                                    dst,
-                                    io::Error::from(io::ErrorKind::UnexpectedEof)
-                                );
-                                */
-                                a_is_closed_cloned.store(true, Ordering::Relaxed);
-                                break;
-                            }
-                        }
-                        debug!(
-                            "receive from SOCKS: {}: {} -> {} ({} Bytes)",
-                            "TCP", dst, 0, size
-                        );
+    /// Handles an acknowledgement from the source in the order of the `Upstreamer`.
+    fn receive_ack(downstreamer: &mut Downstreamer, acknowledgement: u32, window: usize) {
+        downstreamer
+            .update_tcp_recovery(dst(), SRC_PORT, acknowledgement, window, true)
+            .unwrap();
+        downstreamer.invalidate_cache_to(dst(), SRC_PORT, acknowledgement);
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, window);
+        downstreamer.update_tcp_shrink(dst(), SRC_PORT).unwrap();
+        downstreamer.send_tcp_ack(dst(), SRC_PORT).unwrap();
+    }
 
-                        // Send
-                        if let Err(ref e) =
-                            tx.lock()
-                                .unwrap()
-                                .append_to_cache(dst, src_port, &buffer[..size])
-                        {
-                            warn!("handle {}: {}", "TCP", e);
-                        }
-                    }
-                    Err(ref e) => {
-                        if e.kind() == io::ErrorKind::TimedOut {
-                            thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
-                            continue;
-                        }
-                        warn!("SOCKS: {}: {} -> {}: {}", "TCP", 0, dst, e);
-                        a_is_closed_cloned.store(true, Ordering::Relaxed);
-                        break;
-                    }
+    #[test]
+    fn window_shrink_grow() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, 65535);
+
+        let data: Vec<u8> = (0..32 * 1024).map(|i| i as u8).collect();
+        downstreamer
+            .append_to_cache(dst(), SRC_PORT, &data)
+            .unwrap();
+
+        // The source acknowledges every segment, and shrinks the window below the data in flight
+        // to zero, then grows it again as the application reads
+        let mut windows: VecDeque<usize> = vec![65535, 500, 0, 0, 0, 1000, 3000, 8000]
+            .into_iter()
+            .collect();
+        let mut received = Vec::new();
+        let mut next = 1000u32;
+        let mut edge = next.wrapping_add(65535);
+        let mut segments = VecDeque::new();
+        let mut duplicates = 0;
+        let mut dropped = 0;
+        let mut rounds = 0;
+        while received.len() < data.len() {
+            rounds += 1;
+            assert!(rounds < 1000, "stall at {}", next);
+
+            for frame in peer.collect_all() {
+                let indicator = Indicator::from(&frame).unwrap();
+                let tcp = indicator.get_tcp().unwrap();
+                let payload = tcp.get_payload();
+                if payload.is_empty() {
+                    continue;
                 }
+                let sequence = tcp.get_sequence();
+                let end = sequence.wrapping_add(payload.len() as u32);
+                // Nothing is sent beyond the right edge but a zero window probe
+                assert!(
+                    seq_le(end, edge) || (edge == next && payload.len() == 1),
+                    "send {} to {} beyond {}",
+                    sequence,
+                    end,
+                    edge
+                );
+                segments.push_back((sequence, payload.to_vec()));
             }
-        });
 
-        trace!("open stream {} -> {}", 0, dst);
+            let window = match segments.pop_front() {
+                Some((sequence, payload)) => {
+                    let duplicate = match seq_lt(sequence, next) {
+                        true => min(next.wrapping_sub(sequence) as usize, payload.len()),
+                        false => 0,
+                    };
+                    duplicates += duplicate;
+                    // The source accepts data in order, and trims it to the window
+                    let window = edge.wrapping_sub(next) as usize;
+                    let accepted = match trim_to_window(next, window, sequence, payload.len()) {
+                        Some((_, range)) => {
+                            received.extend_from_slice(&payload[range.clone()]);
+                            range.len()
+                        }
+                        None => 0,
+                    };
+                    next = next.wrapping_add(accepted as u32);
+                    dropped += payload.len() - duplicate - accepted;
+                    windows.pop_front().unwrap_or(65535)
+                }
+                // The zero window is probed after the timeout
+                None if edge == next => {
+                    clock.advance(Duration::from_secs(1));
+                    downstreamer
+                        .send_tcp_window_probe_if_timed_out(dst(), SRC_PORT)
+                        .unwrap();
+                    continue;
+                }
+                // The application reads, and the source updates the window
+                None => windows.pop_front().unwrap_or(65535),
+            };
+            edge = next.wrapping_add(window as u32);
+            receive_ack(&mut downstreamer, next, window);
+        }
+        assert!(windows.is_empty());
+        assert_eq!(received, data);
+        assert_eq!(duplicates, 0);
+        // Data beyond the shrunk window is dropped by the source, and retransmitted
+        assert!(dropped > 0);
+        assert_eq!(downstreamer.get_tcp_in_flight(dst(), SRC_PORT), 0);
+    }
 
-        Ok(StreamWorker {
-            dst,
-            stream,
-            thread: Some(thread),
-            is_closed: a_is_closed,
-        })
+    /// Collects the sequences and the sizes of TCP segments sent to the source.
+    fn collect_segments(peer: &Peer) -> Vec<(u32, usize)> {
+        peer.collect_all()
+            .iter()
+            .map(|frame| {
+                let indicator = Indicator::from(frame).unwrap();
+                let tcp = indicator.get_tcp().unwrap();
+
+                (tcp.get_sequence(), tcp.get_payload().len())
+            })
+            .collect()
     }
 
-    /// Sends data on the SOCKS5 in TCP to the destination.
-    pub fn send(&mut self, buffer: &[u8]) -> io::Result<()> {
-        debug!(
-            "send to SOCKS {}: {} -> {} ({} Bytes)",
-            "TCP",
-            "0",
-            self.dst,
-            buffer.len()
+    #[test]
+    fn sack_holes() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, 65535);
+        downstreamer.set_tcp_sack_perm(dst(), SRC_PORT);
+
+        downstreamer
+            .append_to_cache(dst(), SRC_PORT, &[0u8; 4380])
+            .unwrap();
+        assert_eq!(
+            collect_segments(&peer),
+            vec![(1000, 1460), (2460, 1460), (3920, 1460)]
         );
 
-        // Send
-        self.stream.write_all(buffer)
+        // Overlapping blocks are merged, and blocks acknowledged or beyond the sent data are
+        // ignored
+        downstreamer.update_tcp_sacks(
+            dst(),
+            SRC_PORT,
+            &[(2460, 3000), (2900, 3920), (900, 1000), (5380, 6000)],
+        );
+        downstreamer.resend_tcp_ack(dst(), SRC_PORT).unwrap();
+        assert_eq!(collect_segments(&peer), vec![(1000, 1460), (3920, 1460)]);
+
+        // Holes beyond the right edge of the window are not resent
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, 2920);
+        downstreamer.resend_tcp_ack(dst(), SRC_PORT).unwrap();
+        assert_eq!(collect_segments(&peer), vec![(1000, 1460)]);
+
+        // The source reneges, all the data is resent
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, 65535);
+        downstreamer.update_tcp_sacks(dst(), SRC_PORT, &[]);
+        downstreamer.resend_tcp_ack(dst(), SRC_PORT).unwrap();
+        assert_eq!(
+            collect_segments(&peer),
+            vec![(1000, 1460), (2460, 1460), (3920, 1460)]
+        );
     }
 
-    /// Closes the worker.
-    pub fn close(&mut self) {
-        self.is_closed.store(true, Ordering::Relaxed);
-        trace!("close stream {} -> {}", 0, self.dst);
+    #[test]
+    fn fast_retransmit() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, 65535);
+
+        downstreamer
+            .append_to_cache(dst(), SRC_PORT, &[0u8; 4380])
+            .unwrap();
+        assert_eq!(collect_segments(&peer).len(), 3);
+        let cwnd = downstreamer.get_tcp_cc(dst(), SRC_PORT).cwnd();
+
+        // An acknowledgement which updates the window is not a duplicate
+        receive_ack(&mut downstreamer, 1000, 65535);
+        receive_ack(&mut downstreamer, 1000, 65535);
+        receive_ack(&mut downstreamer, 1000, 30000);
+        assert!(collect_segments(&peer).is_empty());
+        assert_eq!(downstreamer.get_tcp_cc(dst(), SRC_PORT).cwnd(), cwnd);
+
+        // The first segment is retransmitted after 3 duplicate acknowledgements
+        receive_ack(&mut downstreamer, 1000, 30000);
+        assert_eq!(collect_segments(&peer), vec![(1000, 1460)]);
+        assert!(downstreamer.get_tcp_cc(dst(), SRC_PORT).cwnd() < cwnd);
+        receive_ack(&mut downstreamer, 1000, 30000);
+        assert!(collect_segments(&peer).is_empty());
+
+        // A partial acknowledgement retransmits the next segment
+        receive_ack(&mut downstreamer, 2460, 30000);
+        assert_eq!(collect_segments(&peer), vec![(2460, 1460)]);
+
+        // The recovery exits once all the data sent before is acknowledged
+        receive_ack(&mut downstreamer, 5380, 30000);
+        assert!(collect_segments(&peer).is_empty());
+        assert!(downstreamer.tcp_recovery_map.is_empty());
     }
 
-    /// Returns if the worker is closed.
-    pub fn is_closed(&self) -> bool {
-        self.is_closed.load(Ordering::Relaxed)
+    /// Represents the states of TCP sockets in `/proc/net/tcp`.
+    #[cfg(target_os = "linux")]
+    const TCP_TIME_WAIT: u8 = 6;
+    #[cfg(target_os = "linux")]
+    const TCP_CLOSE_WAIT: u8 = 8;
+
+    /// Get the state of the TCP socket of the local and remote addresses, or `None` if the socket
+    /// is closed.
+    #[cfg(target_os = "linux")]
+    fn get_tcp_state(local: SocketAddr, remote: SocketAddr) -> Option<u8> {
+        let hex = |addr: SocketAddr| match addr {
+            SocketAddr::V4(addr) => format!(
+                "{:08X}:{:04X}",
+                u32::from_ne_bytes(addr.ip().octets()),
+                addr.port()
+            ),
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let (local, remote) = (hex(local), hex(remote));
+
+        std::fs::read_to_string("/proc/net/tcp")
+            .unwrap()
+            .lines()
+            .skip(1)
+            .find_map(|line| {
+                let fields: Vec<_> = line.split_whitespace().collect();
+                match fields[1] == local && fields[2] == remote {
+                    true => u8::from_str_radix(fields[3], 16).ok(),
+                    false => None,
+                }
+            })
     }
-}
 
-impl Drop for StreamWorker {
-    fn drop(&mut self) {
-        self.close();
-        if let Err(ref e) = self.stream.shutdown(Shutdown::Both) {
-            warn!("handle {}: {}", "TCP", e);
-        }
-        if let Some(thread) = self.thread.take() {
-            thread.join().unwrap();
+    /// Waits for the TCP socket of the local and remote addresses to be in the state.
+    #[cfg(target_os = "linux")]
+    fn wait_tcp_state(local: SocketAddr, remote: SocketAddr, state: Option<u8>) {
+        let instant = Instant::now();
+        while get_tcp_state(local, remote) != state {
+            assert!(
+                instant.elapsed() < Duration::from_secs(5),
+                "TCP socket {} -> {} in state {:?} instead of {:?}",
+                local,
+                remote,
+                get_tcp_state(local, remote),
+                state
+            );
+            thread::sleep(Duration::from_millis(10));
         }
-        trace!("drop stream {} -> {}", 0, self.dst);
     }
-}
-
-/// Represents a worker of a SOCKS5 UDP client.
-struct DatagramWorker {
-    src_port: Arc<AtomicU16>,
-    local_port: u16,
-    datagram: Arc<SocksDatagram>,
-    #[allow(dead_code)]
-    thread: Option<JoinHandle<()>>,
-    is_closed: Arc<AtomicBool>,
-}
-
-impl DatagramWorker {
-    /// Creates a new `DatagramWorker`.
-    pub fn bind(
-        tx: Arc<Mutex<Downstreamer>>,
-        src_port: u16,
-        local_port: u16,
-        remote: SocketAddrV4,
-    ) -> io::Result<DatagramWorker> {
-        let datagram =
-            SocksDatagram::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, local_port), remote)?;
 
-        let a_src_port = Arc::new(AtomicU16::from(src_port));
-        let a_src_port_cloned = Arc::clone(&a_src_port);
-        let a_datagram = Arc::new(datagram);
-        let a_datagram_cloned = Arc::clone(&a_datagram);
-        let is_closed = AtomicBool::new(false);
-        let a_is_closed = Arc::new(is_closed);
-        let a_is_closed_cloned = Arc::clone(&a_is_closed);
-        let thread = thread::spawn(move || {
-            let mut buffer = [0u8; u16::MAX as usize];
-            loop {
-                if a_is_closed_cloned.load(Ordering::Relaxed) {
-                    break;
-                }
-                match a_datagram_cloned.recv_from(&mut buffer) {
-                    Ok((size, addr)) => {
-                        if a_is_closed_cloned.load(Ordering::Relaxed) {
-                            break;
-                        }
-                        debug!(
-                            "receive from SOCKS: {}: {} -> {} ({} Bytes)",
-                            "UDP", addr, local_port, size
-                        );
+    /// Opens a `StreamWorker` of the established TCP connection on a stream to a mock server,
+    /// returns it with the stream of the server.
+    #[cfg(target_os = "linux")]
+    fn open_stream(downstreamer: Downstreamer) -> (StreamWorker, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let connection = Connection::new(SRC_IP_ADDR, SRC_PORT, dst(), Protocol::Tcp, false);
+        let worker = StreamWorker::new(
+            Arc::new(Mutex::new(downstreamer)),
+            connection,
+            stream,
+            None,
+            Limits::default(),
+        )
+        .unwrap();
 
-                        // Send
-                        if let Err(ref e) = tx.lock().unwrap().send_udp(
-                            addr,
-                            a_src_port_cloned.load(Ordering::Relaxed),
-                            &buffer[..size],
-                        ) {
-                            warn!("handle {}: {}", "UDP", e);
-                        }
-                    }
-                    Err(ref e) => {
-                        if e.kind() == io::ErrorKind::TimedOut {
-                            thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
-                            continue;
-                        }
-                        warn!(
-                            "SOCKS: {}: {} = {}: {}",
-                            "UDP",
-                            local_port,
-                            a_src_port_cloned.load(Ordering::Relaxed),
-                            e
-                        );
-                        a_is_closed_cloned.store(true, Ordering::Relaxed);
+        (worker, server)
+    }
 
-                        break;
-                    }
-                }
+    /// Reads the server stream until its end, returns the error if the stream is reset.
+    #[cfg(target_os = "linux")]
+    fn read_to_end(server: &mut TcpStream) -> io::Result<()> {
+        let mut buffer = [0u8; 1024];
+        loop {
+            if server.read(&mut buffer)? == 0 {
+                return Ok(());
             }
-        });
-
-        trace!("create datagram {} = {}", src_port, local_port);
+        }
+    }
 
-        Ok(DatagramWorker {
-            src_port: a_src_port,
-            local_port,
-            datagram: a_datagram,
-            thread: Some(thread),
-            is_closed: a_is_closed,
-        })
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn teardown_reset() {
+        let clock = ManualClock::new();
+        let (downstreamer, _peer) = downstreamer(&clock);
+        let (mut worker, mut server) = open_stream(downstreamer);
+        let (local, remote) = (server.local_addr().unwrap(), server.peer_addr().unwrap());
+
+        // The stream is shut down in both halves even with a linger, the server is notified at
+        // once
+        worker
+            .teardown(CloseReason::Reset, Some(Duration::from_secs(5)))
+            .unwrap();
+        read_to_end(&mut server).unwrap();
+        assert_eq!(get_tcp_state(local, remote), Some(TCP_CLOSE_WAIT));
+        drop(worker);
+
+        // And the server closes without a leak
+        drop(server);
+        wait_tcp_state(remote, local, Some(TCP_TIME_WAIT));
+        wait_tcp_state(local, remote, None);
     }
 
-    /// Sends data on the SOCKS5 in UDP to the destination.
-    pub fn send_to(&mut self, buffer: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
-        debug!(
-            "send to SOCKS {}: {} -> {} ({} Bytes)",
-            "UDP",
-            self.local_port,
-            dst,
-            buffer.len()
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn teardown_fin() {
+        let clock = ManualClock::new();
+        let (mut downstreamer, peer) = downstreamer(&clock);
+        downstreamer.set_tcp_send_window(dst(), SRC_PORT, 65535);
+        let (mut worker, mut server) = open_stream(downstreamer);
+        let (local, remote) = (server.local_addr().unwrap(), server.peer_addr().unwrap());
+
+        // The write half is shut down, and the stream is read until its end
+        worker.shutdown_write().unwrap();
+        read_to_end(&mut server).unwrap();
+        assert_eq!(get_tcp_state(local, remote), Some(TCP_CLOSE_WAIT));
+        server.write_all(b"late").unwrap();
+        let frame = peer.collect(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            Indicator::from(&frame)
+                .unwrap()
+                .get_tcp()
+                .unwrap()
+                .get_payload(),
+            b"late"
         );
-
-        // Send
-        self.datagram.send_to(buffer, dst)
+        drop(server);
+        let frame = peer.collect(Duration::from_secs(5)).unwrap();
+        assert!(Indicator::from(&frame).unwrap().get_tcp().unwrap().is_fin());
+
+        // Nothing is torn down after a FIN
+        worker.teardown(CloseReason::Fin, None).unwrap();
+        drop(worker);
+        wait_tcp_state(remote, local, Some(TCP_TIME_WAIT));
     }
 
-    /// Sets the source port of the `DatagramWorker`.
-    pub fn set_src_port(&mut self, src_port: u16) {
-        self.src_port.store(src_port, Ordering::Relaxed);
-        trace!("set datagram {} = {}", src_port, self.local_port);
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn teardown_error() {
+        let clock = ManualClock::new();
+        let (downstreamer, _peer) = downstreamer(&clock);
+        let (mut worker, mut server) = open_stream(downstreamer);
+        let (local, remote) = (server.local_addr().unwrap(), server.peer_addr().unwrap());
+
+        // The stream is shut down in both halves without a linger
+        worker.teardown(CloseReason::ProxyError, None).unwrap();
+        read_to_end(&mut server).unwrap();
+        assert_eq!(get_tcp_state(local, remote), Some(TCP_CLOSE_WAIT));
+        drop(worker);
+        drop(server);
+        wait_tcp_state(local, remote, None);
     }
 
-    /// Get the source port of the `DatagramWorker`.
-    pub fn get_src_port(&self) -> u16 {
-        self.src_port.load(Ordering::Relaxed)
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn teardown_error_linger() {
+        let clock = ManualClock::new();
+        let (downstreamer, _peer) = downstreamer(&clock);
+        let (mut worker, mut server) = open_stream(downstreamer);
+        let (local, remote) = (server.local_addr().unwrap(), server.peer_addr().unwrap());
+
+        // The stream is shut down in the write half, and read until its end in the linger, so
+        // the data of the server never turns the close into a RST
+        worker
+            .teardown(CloseReason::ProxyError, Some(Duration::from_secs(5)))
+            .unwrap();
+        read_to_end(&mut server).unwrap();
+        assert_eq!(get_tcp_state(local, remote), Some(TCP_CLOSE_WAIT));
+        drop(worker);
+        server.write_all(&[0u8; 4096]).unwrap();
+        server.shutdown(Shutdown::Write).unwrap();
+        wait_tcp_state(remote, local, Some(TCP_TIME_WAIT));
+        read_to_end(&mut server).unwrap();
     }
 
-    /// Returns if the worker is closed.
-    pub fn is_closed(&self) -> bool {
-        self.is_closed.load(Ordering::Relaxed)
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn teardown_error_linger_zero() {
+        let clock = ManualClock::new();
+        let (downstreamer, _peer) = downstreamer(&clock);
+        let (mut worker, mut server) = open_stream(downstreamer);
+        let (local, remote) = (server.local_addr().unwrap(), server.peer_addr().unwrap());
+
+        // The stream is reset once it is dropped
+        worker
+            .teardown(CloseReason::ProxyError, Some(Duration::from_secs(0)))
+            .unwrap();
+        drop(worker);
+        assert_eq!(
+            read_to_end(&mut server).unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+        wait_tcp_state(local, remote, None);
+        wait_tcp_state(remote, local, None);
     }
 }
@@ -1,14 +1,48 @@
 use log::{error, info};
-use std::sync::{Arc, Mutex};
+use std::process;
 
 use pcap2socks as lib;
 
+/// Counts allocations in the stages of the bench subcommand, and forwards to the system
+/// allocator otherwise.
+#[global_allocator]
+static ALLOCATOR: lib::bench::CountingAllocator = lib::bench::CountingAllocator;
+
 fn main() {
     // Parse arguments
-    let flags = lib::args::parse();
+    let flags = match lib::args::parse() {
+        Ok(flags) => flags,
+        Err(ref e) => {
+            eprintln!("error: {}", e);
+            return;
+        }
+    };
 
     // Log
-    lib::set_logger(&flags);
+    if let Err(ref e) = lib::set_logger(&flags) {
+        eprintln!("error: {}", e);
+        return;
+    }
+
+    if let Some(lib::args::Command::Devices) = flags.command {
+        print!("{}", lib::interface_table());
+        return;
+    }
+    if let Some(lib::args::Command::Bench(bench)) = flags.command {
+        let mix = match bench.to_mix() {
+            Ok(mix) => mix,
+            Err(ref e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        let mut generator = lib::bench::Generator::new(bench.get_seed(), mix);
+        print!(
+            "{}",
+            lib::bench::run(&mut generator, bench.get_packets(), bench.checksum)
+        );
+        return;
+    }
 
     // Validate arguments
     let opts = match lib::args::Opts::validate(&flags) {
@@ -18,49 +52,71 @@ fn main() {
             return;
         }
     };
+    if flags.print_config {
+        print!("{}", lib::config::print(&flags));
+        return;
+    }
 
-    // Interface
-    let inter = match lib::interface(opts.inter) {
-        Some(inter) => inter,
-        None => {
-            println!("Cannot determine interface. Available interfaces are listed below, use -i <INTERFACE> to designate:");
-            for inter in lib::interfaces().iter() {
-                println!("    {}", inter);
+    #[cfg(unix)]
+    set_signal_handler();
+    let is_offline = opts.input_file.is_some();
+    match lib::Pcap2Socks::from_opts(opts).reload(flags).run() {
+        // Summary of the capture file
+        Ok(summary) => {
+            if is_offline {
+                info!(
+                    "Replay {} frames, {} frames sent, {} frames filtered, {} frames of too many sources dropped",
+                    summary.frames,
+                    summary.frames_sent,
+                    summary.frames_filtered,
+                    summary.frames_dropped
+                );
             }
-            return;
         }
-    };
-    info!("Listen on {}", inter);
-    info!("MTU {}", opts.mtu);
-
-    // Publish
-    if let Some(publish) = opts.publish {
-        info!("Publish {}", publish);
+        Err(lib::Error::UndeterminedInterfaceError) => {
+            println!("Cannot determine interface. Available interfaces are listed below, use -i <INTERFACE> to designate by the name, an address or the index:");
+            print!("{}", lib::interface_table());
+        }
+        Err(ref e @ lib::Error::InterfaceNotFoundError(_))
+        | Err(ref e @ lib::Error::AmbiguousInterfaceError(_)) => {
+            error!("{}", e);
+            println!("Available interfaces are listed below, use -i <INTERFACE> to designate by the name, an address or the index:");
+            print!("{}", lib::interface_table());
+        }
+        Err(ref e @ lib::Error::SelfTestError(_))
+        | Err(ref e @ lib::Error::PublishConflictError(_, _)) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+        Err(ref e) => error!("{}", e),
     }
+}
 
-    // Proxy
-    info!("Proxy {} to {}", opts.src, opts.dst);
-    let (tx, mut rx) = match inter.open() {
-        Ok((tx, rx)) => (tx, rx),
-        Err(ref e) => {
-            error!("{}", e);
-            return;
+/// Sets the handler of SIGINT and SIGTERM, which shuts down the dispatcher gracefully and exits
+/// immediately on the second signal, the handler of SIGUSR1, which dumps the connection
+/// table, and the handler of SIGHUP, which reloads the configuration.
+#[cfg(unix)]
+fn set_signal_handler() {
+    extern "C" fn handle(signal: libc::c_int) {
+        if lib::is_shutdown() {
+            unsafe { libc::_exit(128 + signal) };
         }
-    };
-    let downstreamer = lib::Downstreamer::new(
-        tx,
-        opts.mtu,
-        inter.hardware_addr,
-        opts.src,
-        inter.ip_addrs[0],
-    );
-    let mut upstreamer = lib::Upstreamer::new(
-        Arc::new(Mutex::new(downstreamer)),
-        opts.src,
-        opts.publish,
-        opts.dst,
-    );
-    if let Err(ref e) = upstreamer.open(&mut rx) {
-        error!("{}", e);
+        lib::shutdown();
+    }
+    extern "C" fn handle_dump(_: libc::c_int) {
+        lib::dump_table();
+    }
+    extern "C" fn handle_reload(_: libc::c_int) {
+        lib::reload::request();
+    }
+
+    let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    let dump_handler = handle_dump as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    let reload_handler = handle_reload as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGUSR1, dump_handler);
+        libc::signal(libc::SIGHUP, reload_handler);
     }
 }
@@ -0,0 +1,172 @@
+use std::cell::Cell;
+use std::cmp::{max, min};
+use std::fmt::{self, Display, Formatter};
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+
+/// Represents the default memory budget of buffers.
+pub const DEFAULT_MAX_MEMORY: usize = 256 * 1024 * 1024;
+/// Represents the min size of a new buffer under memory pressure.
+pub const MIN_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Represents the count of shards of the usage, threads account in their own shard so they
+/// rarely contend.
+const SHARDS: usize = 16;
+/// Represents the ratio of the usage to the budget from which the memory is tight.
+const TIGHT_RATIO: f64 = 0.75;
+
+/// Represents a shard of the usage, which is in its own cache line.
+#[repr(align(64))]
+struct Shard(AtomicIsize);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const SHARD: Shard = Shard(AtomicIsize::new(0));
+
+/// Represents the memory budget of buffers.
+static BUDGET: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_MEMORY);
+/// Represents the usage of buffers in shards, a shard may go negative if buffers are released
+/// on another thread, only the sum is meaningful.
+static USAGE: [Shard; SHARDS] = [SHARD; SHARDS];
+/// Represents the shard of the next thread.
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Represents the shard of the thread, which is assigned when the thread first accounts.
+    static THREAD_SHARD: Cell<Option<usize>> = Cell::new(None);
+}
+
+/// Represents the memory pressure of buffers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Pressure {
+    /// Represents buffers are in their full sizes.
+    Normal,
+    /// Represents new buffers are smaller and out-of-order data is discarded.
+    Tight,
+    /// Represents the budget is exhausted, and connections are reset.
+    Exhausted,
+}
+
+impl Pressure {
+    /// Get the name of the pressure.
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Pressure::Normal => "normal",
+            Pressure::Tight => "tight",
+            Pressure::Exhausted => "exhausted",
+        }
+    }
+}
+
+impl Display for Pressure {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
+
+fn get_shard() -> &'static AtomicIsize {
+    let index = THREAD_SHARD.with(|shard| match shard.get() {
+        Some(index) => index,
+        None => {
+            let index = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+            shard.set(Some(index));
+
+            index
+        }
+    });
+
+    &USAGE[index].0
+}
+
+/// Sets the memory budget of buffers.
+pub fn set_budget(size: usize) {
+    BUDGET.store(max(size, 1), Ordering::Relaxed);
+}
+
+/// Get the memory budget of buffers.
+pub fn get_budget() -> usize {
+    BUDGET.load(Ordering::Relaxed)
+}
+
+/// Get the memory in use of buffers, which sums the shards.
+pub fn get_usage() -> usize {
+    let usage: isize = USAGE
+        .iter()
+        .map(|shard| shard.0.load(Ordering::Relaxed))
+        .sum();
+
+    max(usage, 0) as usize
+}
+
+/// Get the memory pressure of buffers.
+pub fn get_pressure() -> Pressure {
+    let usage = get_usage();
+    let budget = get_budget();
+    if usage >= budget {
+        Pressure::Exhausted
+    } else if usage as f64 >= budget as f64 * TIGHT_RATIO {
+        Pressure::Tight
+    } else {
+        Pressure::Normal
+    }
+}
+
+/// Returns if the memory is tight or exhausted.
+pub fn is_tight() -> bool {
+    get_pressure() != Pressure::Normal
+}
+
+/// Get the capacity of a new buffer under the memory pressure. A buffer is in full capacity
+/// before the memory is tight, then it shrinks in proportion to the budget left, but never
+/// below the min size.
+pub fn scale(capacity: usize) -> usize {
+    let usage = get_usage() as f64;
+    let budget = get_budget() as f64;
+    let tight = budget * TIGHT_RATIO;
+    if usage < tight {
+        return capacity;
+    }
+
+    let ratio = ((budget - usage) / (budget - tight)).max(0.0);
+
+    max(
+        (capacity as f64 * ratio) as usize,
+        min(capacity, MIN_BUFFER_SIZE),
+    )
+}
+
+/// Represents the memory of a buffer accounted in the budget, which is released once it is
+/// dropped.
+#[derive(Debug, Default)]
+pub struct Allocation {
+    size: usize,
+}
+
+impl Allocation {
+    /// Accounts a buffer in the given size.
+    pub fn new(size: usize) -> Allocation {
+        let mut allocation = Allocation::default();
+        allocation.resize(size);
+
+        allocation
+    }
+
+    /// Accounts the buffer in a new size.
+    pub fn resize(&mut self, size: usize) {
+        if size > self.size {
+            get_shard().fetch_add((size - self.size) as isize, Ordering::Relaxed);
+        } else if size < self.size {
+            get_shard().fetch_sub((self.size - size) as isize, Ordering::Relaxed);
+        }
+        self.size = size;
+    }
+
+    /// Get the size of the buffer.
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.resize(0);
+    }
+}
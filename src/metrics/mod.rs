@@ -0,0 +1,1352 @@
+use log::{debug, warn};
+use std::cmp::max;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddrV4, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::classify;
+use crate::event::{CloseReason, Protocol, CLOSE_REASONS};
+use crate::memory;
+use crate::packet::igmp::IgmpKind;
+use crate::packet::layer::ethernet::ControlKind;
+use crate::packet::layer::{LayerType, LayerTypes};
+use crate::pcap::bridge;
+use crate::rule::AccessList;
+use crate::socks::{ProxyGroup, ProxyState};
+
+mod printer;
+
+pub use printer::{rank_connection, Printer};
+
+/// Represents the timeout of reading a request and writing a response in milliseconds.
+const HTTP_TIMEOUT: u64 = 5000;
+/// Represents the max size of a request which is read.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Represents a counter which only increases.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    /// Creates a new `Counter`.
+    pub const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    /// Increases the counter by 1.
+    pub fn increase(&self) {
+        self.add(1);
+    }
+
+    /// Increases the counter by the value.
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Get the value of the counter.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Represents a gauge which increases and decreases.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    /// Creates a new `Gauge`.
+    pub const fn new() -> Gauge {
+        Gauge(AtomicI64::new(0))
+    }
+
+    /// Increases the gauge by 1.
+    pub fn increase(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Decreases the gauge by 1.
+    pub fn decrease(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Get the value of the gauge.
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Represents the reasons a packet is dropped.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DropReason {
+    /// Represents the frame does not match the capture filter.
+    Filter,
+    /// Represents the frame is in stacked VLANs.
+    StackedVlan,
+    /// Represents the source cannot be served for too many sources.
+    TooManySources,
+    /// Represents the TTL of the frame forwarded between sources is exceeded.
+    TtlExceeded,
+    /// Represents the reassembled IPv4 datagram is invalid.
+    Reassembly,
+    /// Represents the packet exceeds the MTU and is not allowed to be fragmented.
+    MtuExceeded,
+    /// Represents the TCP SYN is in the backoff after a failed attempt.
+    RetryBackoff,
+    /// Represents the TCP SYN is retried later for the unreachable proxy.
+    ProxyUnreachable,
+    /// Represents the TCP segment or UDP datagram has an invalid checksum.
+    InvalidChecksum,
+    /// Represents the UDP datagram cannot be relayed by the proxy.
+    UdpUnsupported,
+    /// Represents the UDP datagram is received in shutting down.
+    Shutdown,
+    /// Represents the UDP datagram exceeds the rate limit.
+    RateLimited,
+    /// Represents the frame sent exceeds the queue while the device is down.
+    DeviceDown,
+    /// Represents the packet is denied by the access lists.
+    Denied,
+    /// Represents the frame has header fields inconsistent with its size.
+    Malformed,
+    /// Represents the datagram is to a broadcast or multicast address.
+    Lan,
+    /// Represents the TCP SYN exceeds the max TCP connections.
+    ConnectionLimit,
+    /// Represents the TCP SYN exceeds the max TCP connections waiting for the handshake.
+    HalfOpenLimit,
+    /// Represents the datagram is from a peer the source never sent to in the restricted NAT.
+    NatFiltered,
+    /// Represents the TCP SYN exceeds the quota of TCP connections of the client.
+    ClientQuota,
+    /// Represents the TCP segment is in an illegal combination of flags.
+    InvalidFlags,
+    /// Represents the packet is dropped by the hook.
+    Hook,
+    /// Represents the TCP SYN opens a connection when the ports of the NAT are exhausted.
+    NatExhausted,
+    /// Represents the TCP SYN or the UDP datagram is to a destination in the blocklist.
+    Blocked,
+}
+
+/// Represents all the reasons a packet is dropped, in the order of the counters.
+const DROP_REASONS: [DropReason; 24] = [
+    DropReason::Filter,
+    DropReason::StackedVlan,
+    DropReason::TooManySources,
+    DropReason::TtlExceeded,
+    DropReason::Reassembly,
+    DropReason::MtuExceeded,
+    DropReason::RetryBackoff,
+    DropReason::ProxyUnreachable,
+    DropReason::InvalidChecksum,
+    DropReason::UdpUnsupported,
+    DropReason::Shutdown,
+    DropReason::RateLimited,
+    DropReason::DeviceDown,
+    DropReason::Denied,
+    DropReason::Malformed,
+    DropReason::Lan,
+    DropReason::ConnectionLimit,
+    DropReason::HalfOpenLimit,
+    DropReason::NatFiltered,
+    DropReason::ClientQuota,
+    DropReason::InvalidFlags,
+    DropReason::Hook,
+    DropReason::NatExhausted,
+    DropReason::Blocked,
+];
+
+impl DropReason {
+    /// Get the name of the reason.
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            DropReason::Filter => "filter",
+            DropReason::StackedVlan => "stacked_vlan",
+            DropReason::TooManySources => "too_many_sources",
+            DropReason::TtlExceeded => "ttl_exceeded",
+            DropReason::Reassembly => "reassembly",
+            DropReason::MtuExceeded => "mtu_exceeded",
+            DropReason::RetryBackoff => "retry_backoff",
+            DropReason::ProxyUnreachable => "proxy_unreachable",
+            DropReason::InvalidChecksum => "invalid_checksum",
+            DropReason::UdpUnsupported => "udp_unsupported",
+            DropReason::Shutdown => "shutdown",
+            DropReason::RateLimited => "rate_limited",
+            DropReason::DeviceDown => "device_down",
+            DropReason::Denied => "denied",
+            DropReason::Malformed => "malformed",
+            DropReason::Lan => "lan",
+            DropReason::ConnectionLimit => "connection_limit",
+            DropReason::HalfOpenLimit => "half_open_limit",
+            DropReason::NatFiltered => "nat_filtered",
+            DropReason::ClientQuota => "client_quota",
+            DropReason::InvalidFlags => "invalid_flags",
+            DropReason::Hook => "hook",
+            DropReason::NatExhausted => "nat_exhausted",
+            DropReason::Blocked => "blocked",
+        }
+    }
+}
+
+/// Represents the count of frames captured.
+pub static PACKETS_CAPTURED: Counter = Counter::new();
+/// Represents the count of frames sent.
+pub static PACKETS_SENT: Counter = Counter::new();
+/// Represents the count of frames forwarded between sources locally.
+pub static PACKETS_FORWARDED: Counter = Counter::new();
+/// Represents the counts of packets dropped by reasons.
+static PACKETS_DROPPED: [Counter; 24] = [
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+];
+/// Represents the bytes relayed from sources in TCP and UDP, which are unique payload.
+static BYTES_UP: [Counter; 2] = [Counter::new(), Counter::new()];
+/// Represents the bytes relayed to sources in TCP and UDP, which are unique payload.
+static BYTES_DOWN: [Counter; 2] = [Counter::new(), Counter::new()];
+/// Represents the bytes of payload retransmitted by sources in TCP and UDP.
+static BYTES_RETRANSMITTED_UP: [Counter; 2] = [Counter::new(), Counter::new()];
+/// Represents the bytes of payload retransmitted to sources in TCP and UDP.
+static BYTES_RETRANSMITTED_DOWN: [Counter; 2] = [Counter::new(), Counter::new()];
+/// Represents the bytes of frames received from sources in TCP and UDP, including headers.
+static BYTES_WIRE_UP: [Counter; 2] = [Counter::new(), Counter::new()];
+/// Represents the bytes of frames sent to sources in TCP and UDP, including headers.
+static BYTES_WIRE_DOWN: [Counter; 2] = [Counter::new(), Counter::new()];
+/// Represents the count of TCP connections and UDP associations open.
+static CONNECTIONS: [Gauge; 2] = [Gauge::new(), Gauge::new()];
+/// Represents the counts of TCP connections and UDP associations closed by reasons.
+static CONNECTIONS_CLOSED: [Counter; 18] = [
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+];
+/// Represents the count of failed handshakes with the proxy.
+pub static SOCKS_HANDSHAKE_FAILURES: Counter = Counter::new();
+/// Represents the count of connections to proxies taken from pools.
+pub static PROXY_POOL_HITS: Counter = Counter::new();
+/// Represents the count of connections to proxies opened because pools are empty.
+pub static PROXY_POOL_MISSES: Counter = Counter::new();
+/// Represents the count of pooled connections found closed by proxies.
+pub static PROXY_POOL_STALE: Counter = Counter::new();
+/// Represents the count of UDP datagrams dropped.
+static UDP_PACKETS_DROPPED: Counter = Counter::new();
+/// Represents the count of UDP datagrams relayed from sources.
+pub static UDP_PACKETS_UP: Counter = Counter::new();
+/// Represents the count of UDP datagrams relayed to sources.
+pub static UDP_PACKETS_DOWN: Counter = Counter::new();
+/// Represents the bytes of SOCKS5 UDP headers of datagrams sent to relays.
+pub static UDP_OVERHEAD_UP: Counter = Counter::new();
+/// Represents the bytes of SOCKS5 UDP headers of datagrams received from relays.
+pub static UDP_OVERHEAD_DOWN: Counter = Counter::new();
+/// Represents the count of UDP datagrams which cannot be sent to relays.
+pub static UDP_SEND_FAILURES: Counter = Counter::new();
+/// Represents the count of UDP datagrams larger than the path MTU to relays.
+pub static UDP_OVERSIZED: Counter = Counter::new();
+/// Represents the count of IPv4 packets dropped because their sources set the DF bit and they
+/// cannot be sent without fragmentation.
+pub static IPV4_DONT_FRAGMENT_DROPS: Counter = Counter::new();
+/// Represents the count of UDP associations detected as QUIC.
+pub static UDP_QUIC_FLOWS: Counter = Counter::new();
+/// Represents the count of QUIC connections migrated to new source ports.
+pub static UDP_QUIC_MIGRATIONS: Counter = Counter::new();
+/// Represents the sum of jitter estimates of UDP datagrams relayed to sources in microseconds.
+static UDP_JITTER_SUM: Counter = Counter::new();
+/// Represents the count of jitter estimates of UDP datagrams relayed to sources.
+static UDP_JITTER_COUNT: Counter = Counter::new();
+/// Represents the count of TCP segments retransmitted to sources.
+pub static TCP_RETRANSMISSIONS: Counter = Counter::new();
+/// Represents the count of TCP segments received out of order from sources.
+pub static TCP_OUT_OF_ORDER: Counter = Counter::new();
+/// Represents the count of TCP segments with urgent data, which is delivered inline.
+pub static TCP_URGENT: Counter = Counter::new();
+/// Represents the count of TCP segments of connections closed recently.
+pub static TCP_STALE_SEGMENTS: Counter = Counter::new();
+/// Represents the count of TCP segments of connections never known.
+pub static TCP_UNKNOWN_SEGMENTS: Counter = Counter::new();
+/// Represents the count of RSTs to TCP segments of connections not found, which are suppressed
+/// by the rate limit.
+pub static TCP_UNKNOWN_RSTS_SUPPRESSED: Counter = Counter::new();
+/// Represents the count of TCP connections picked up mid-stream without a handshake.
+pub static TCP_PICKUPS: Counter = Counter::new();
+/// Represents the count of IPv4 datagrams expired in reassembly.
+pub static REASSEMBLY_EXPIRATIONS: Counter = Counter::new();
+/// Represents the count of retries of frames which fail to be sent for transient errors.
+pub static SEND_RETRIES: Counter = Counter::new();
+/// Represents the count of frames which fail to be sent for transient errors after all the
+/// retries.
+pub static SEND_FAILURES: Counter = Counter::new();
+/// Represents the count of the device reopened after it is lost.
+pub static DEVICE_RESTARTS: Counter = Counter::new();
+/// Represents the count of TCP connections closed for new ones when connections are full.
+pub static TCP_EVICTIONS: Counter = Counter::new();
+/// Represents the count of TCP connections reset when the memory budget is exhausted.
+pub static MEMORY_EVICTIONS: Counter = Counter::new();
+/// Represents the count of TCP segments out of order discarded under the memory pressure.
+pub static MEMORY_DISCARDS: Counter = Counter::new();
+/// Represents the count of datagrams received on shared sockets belonging to no association.
+pub static UDP_UNROUTED: Counter = Counter::new();
+/// Represents the count of UDP tunnels over TCP open.
+pub static UDP_TUNNELS: Gauge = Gauge::new();
+/// Represents the count of streams of UDP tunnels reconnected after they break.
+pub static UDP_TUNNEL_RECONNECTIONS: Counter = Counter::new();
+/// Represents the count of TCP segments coalesced by segmentation offload which are split.
+pub static COALESCED_SEGMENTS: Counter = Counter::new();
+/// Represents the count of UDP associations re-established after their relays stop relaying.
+pub static UDP_REASSOCIATIONS: Counter = Counter::new();
+/// Represents the count of ARP requests sent to resolve hosts in the LAN.
+pub static ARP_REQUESTS: Counter = Counter::new();
+/// Represents the count of hosts in the LAN unresolved after all the ARP requests.
+pub static ARP_FAILURES: Counter = Counter::new();
+/// Represents the count of frames to hosts in the LAN dropped while resolving them.
+pub static ARP_QUEUE_DROPS: Counter = Counter::new();
+/// Represents the count of ARP messages of other hosts claiming the published address.
+pub static ARP_CONFLICTS: Counter = Counter::new();
+/// Represents the count of reloads of the configuration.
+pub static RELOADS: Counter = Counter::new();
+/// Represents the count of reloads of the configuration failing to read or validate, which
+/// leave the configuration in effect.
+pub static RELOAD_FAILURES: Counter = Counter::new();
+/// Represents the time of the last reload of the configuration in seconds since the Unix epoch,
+/// which is 0 if it is never reloaded.
+static LAST_RELOAD: AtomicU64 = AtomicU64::new(0);
+/// Represents the counts of IGMP messages seen by kinds.
+static IGMP_MESSAGES: [Counter; 4] = [
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+];
+/// Represents all the kinds of IGMP messages, in the order of the counters.
+const IGMP_KINDS: [IgmpKind; 4] = [
+    IgmpKind::Query,
+    IgmpKind::Report,
+    IgmpKind::Leave,
+    IgmpKind::Other,
+];
+/// Represents the counts of control frames of switches seen by kinds.
+static CONTROL_FRAMES: [Counter; 4] = [
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+    Counter::new(),
+];
+/// Represents all the kinds of control frames, in the order of the counters.
+const CONTROL_KINDS: [ControlKind; 4] = [
+    ControlKind::Stp,
+    ControlKind::Lldp,
+    ControlKind::Cdp,
+    ControlKind::Llc,
+];
+/// Represents the path of UDP traffic through the proxy once it is decided.
+static UDP_PATH: Mutex<Option<&'static str>> = Mutex::new(None);
+/// Represents the access lists, whose entries count the traffic they match.
+static ACCESS_LIST: Mutex<Option<AccessList>> = Mutex::new(None);
+/// Represents the group of proxies, whose proxies count their health and connections.
+static PROXY_GROUP: Mutex<Option<Arc<ProxyGroup>>> = Mutex::new(None);
+/// Represents the max number of broadcast and multicast destinations counted.
+const MAX_LAN_DESTINATIONS: usize = 64;
+/// Represents the counts of datagrams dropped by broadcast and multicast destinations.
+static LAN_DROPS: Mutex<Vec<(SocketAddrV4, u64)>> = Mutex::new(Vec::new());
+
+/// Sets the access lists whose counts are rendered.
+pub fn set_access_list(access_list: AccessList) {
+    *ACCESS_LIST.lock().unwrap() = Some(access_list);
+}
+
+/// Sets the group of proxies whose states are rendered.
+pub fn set_proxy_group(group: Arc<ProxyGroup>) {
+    *PROXY_GROUP.lock().unwrap() = Some(group);
+}
+
+/// Get the states of the proxies of the group, which are empty without a group.
+pub fn get_proxy_states() -> Vec<ProxyState> {
+    match *PROXY_GROUP.lock().unwrap() {
+        Some(ref group) => group.get_states(),
+        None => Vec::new(),
+    }
+}
+
+/// Counts a reload of the configuration at the time.
+pub fn count_reload(time: SystemTime) {
+    RELOADS.increase();
+    let time = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    LAST_RELOAD.store(time, Ordering::Relaxed);
+}
+
+/// Sets the path of UDP traffic through the proxy, like `associate`, `tunnel` or `drop`.
+pub fn set_udp_path(path: &'static str) {
+    *UDP_PATH.lock().unwrap() = Some(path);
+}
+
+/// Get the path of UDP traffic through the proxy if it is decided.
+pub fn get_udp_path() -> Option<&'static str> {
+    *UDP_PATH.lock().unwrap()
+}
+
+/// Counts a packet dropped for the reason in the layer.
+pub fn count_drop(reason: DropReason, layer: LayerType) {
+    PACKETS_DROPPED[reason as usize].increase();
+    if layer == LayerTypes::Udp {
+        UDP_PACKETS_DROPPED.increase();
+    }
+}
+
+/// Counts a datagram dropped for the broadcast or multicast destination, returns the count of
+/// the destination. Destinations after the max number are not counted by themselves.
+pub fn count_lan_drop(dst: SocketAddrV4) -> Option<u64> {
+    let mut drops = LAN_DROPS.lock().unwrap();
+    if let Some((_, count)) = drops.iter_mut().find(|(addr, _)| *addr == dst) {
+        *count += 1;
+
+        return Some(*count);
+    }
+    if drops.len() < MAX_LAN_DESTINATIONS {
+        drops.push((dst, 1));
+
+        Some(1)
+    } else {
+        None
+    }
+}
+
+/// Counts an IGMP message of the kind, which is never logged.
+pub fn count_igmp(kind: IgmpKind) {
+    IGMP_MESSAGES[kind as usize].increase();
+}
+
+/// Counts a control frame of switches of the kind, which is never logged.
+pub fn count_control(kind: ControlKind) {
+    CONTROL_FRAMES[kind as usize].increase();
+}
+
+/// Observes a jitter estimate in microseconds of a UDP datagram relayed to a source.
+pub fn observe_udp_jitter(jitter: u64) {
+    UDP_JITTER_SUM.add(jitter);
+    UDP_JITTER_COUNT.increase();
+}
+
+/// Counts bytes relayed from sources.
+pub fn count_bytes_up(protocol: Protocol, n: usize) {
+    BYTES_UP[protocol as usize].add(n as u64);
+}
+
+/// Counts bytes relayed to sources.
+pub fn count_bytes_down(protocol: Protocol, n: usize) {
+    BYTES_DOWN[protocol as usize].add(n as u64);
+}
+
+/// Counts bytes of payload retransmitted by sources.
+pub fn count_retransmitted_up(protocol: Protocol, n: usize) {
+    BYTES_RETRANSMITTED_UP[protocol as usize].add(n as u64);
+}
+
+/// Counts bytes of payload retransmitted to sources.
+pub fn count_retransmitted_down(protocol: Protocol, n: usize) {
+    BYTES_RETRANSMITTED_DOWN[protocol as usize].add(n as u64);
+}
+
+/// Counts bytes of a frame received from a source, including headers.
+pub fn count_wire_up(protocol: Protocol, n: usize) {
+    BYTES_WIRE_UP[protocol as usize].add(n as u64);
+}
+
+/// Counts bytes of a frame sent to a source, including headers.
+pub fn count_wire_down(protocol: Protocol, n: usize) {
+    BYTES_WIRE_DOWN[protocol as usize].add(n as u64);
+}
+
+/// Counts a connection opened.
+pub fn open_connection(protocol: Protocol) {
+    CONNECTIONS[protocol as usize].increase();
+}
+
+/// Counts a connection closed for the reason.
+pub fn close_connection(protocol: Protocol, reason: CloseReason) {
+    CONNECTIONS[protocol as usize].decrease();
+    count_close(reason);
+}
+
+/// Counts a connection closed for the reason, which is never opened.
+pub fn count_close(reason: CloseReason) {
+    CONNECTIONS_CLOSED[reason as usize].increase();
+}
+
+/// Get the counts of connections closed by reasons, reasons never seen are skipped.
+pub fn get_closes() -> Vec<(CloseReason, u64)> {
+    CLOSE_REASONS
+        .iter()
+        .map(|reason| (*reason, CONNECTIONS_CLOSED[*reason as usize].get()))
+        .filter(|(_, count)| *count > 0)
+        .collect()
+}
+
+/// Get the count of connections open.
+pub fn get_connections(protocol: Protocol) -> u64 {
+    max(CONNECTIONS[protocol as usize].get(), 0) as u64
+}
+
+/// Represents a snapshot of the metrics, bytes and connections are summed over TCP and UDP.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Stats {
+    pub packets_captured: u64,
+    pub packets_sent: u64,
+    pub packets_forwarded: u64,
+    pub packets_dropped: u64,
+    /// Represents the bytes of unique payload relayed
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    /// Represents the bytes of payload retransmitted
+    pub bytes_retransmitted_up: u64,
+    pub bytes_retransmitted_down: u64,
+    /// Represents the bytes of frames on the wire, including headers
+    pub bytes_wire_up: u64,
+    pub bytes_wire_down: u64,
+    pub tcp_connections: u64,
+    pub udp_associations: u64,
+    pub udp_tunnels: u64,
+    pub udp_packets_dropped: u64,
+    pub tcp_retransmissions: u64,
+    pub tcp_out_of_order: u64,
+    pub reassembly_expirations: u64,
+    /// Represents the local ports in use of the local port range, which are 0 without a range
+    pub local_ports_in_use: u64,
+    pub local_ports: u64,
+    /// Represents the ARP messages of other hosts claiming the published address
+    pub arp_conflicts: u64,
+    /// Represents the memory in use of buffers
+    pub memory_usage: u64,
+    pub memory_budget: u64,
+    /// Represents the reloads of the configuration, and the time of the last one in seconds
+    /// since the Unix epoch, which is 0 if it is never reloaded
+    pub reloads: u64,
+    pub last_reload: u64,
+}
+
+/// Get a snapshot of the metrics.
+pub fn stats() -> Stats {
+    let (local_ports_in_use, local_ports) = crate::socks::get_port_occupancy().unwrap_or((0, 0));
+
+    Stats {
+        packets_captured: PACKETS_CAPTURED.get(),
+        packets_sent: PACKETS_SENT.get(),
+        packets_forwarded: PACKETS_FORWARDED.get(),
+        packets_dropped: PACKETS_DROPPED.iter().map(|counter| counter.get()).sum(),
+        bytes_up: BYTES_UP.iter().map(|counter| counter.get()).sum(),
+        bytes_down: BYTES_DOWN.iter().map(|counter| counter.get()).sum(),
+        bytes_retransmitted_up: BYTES_RETRANSMITTED_UP
+            .iter()
+            .map(|counter| counter.get())
+            .sum(),
+        bytes_retransmitted_down: BYTES_RETRANSMITTED_DOWN
+            .iter()
+            .map(|counter| counter.get())
+            .sum(),
+        bytes_wire_up: BYTES_WIRE_UP.iter().map(|counter| counter.get()).sum(),
+        bytes_wire_down: BYTES_WIRE_DOWN.iter().map(|counter| counter.get()).sum(),
+        tcp_connections: get_connections(Protocol::Tcp),
+        udp_associations: get_connections(Protocol::Udp),
+        udp_tunnels: max(UDP_TUNNELS.get(), 0) as u64,
+        udp_packets_dropped: UDP_PACKETS_DROPPED.get(),
+        tcp_retransmissions: TCP_RETRANSMISSIONS.get(),
+        tcp_out_of_order: TCP_OUT_OF_ORDER.get(),
+        reassembly_expirations: REASSEMBLY_EXPIRATIONS.get(),
+        local_ports_in_use: local_ports_in_use as u64,
+        local_ports: local_ports as u64,
+        arp_conflicts: ARP_CONFLICTS.get(),
+        memory_usage: memory::get_usage() as u64,
+        memory_budget: memory::get_budget() as u64,
+        reloads: RELOADS.get(),
+        last_reload: LAST_RELOAD.load(Ordering::Relaxed),
+    }
+}
+
+/// Renders all the metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut s = String::new();
+    let protocols = [Protocol::Tcp, Protocol::Udp];
+
+    counter(
+        &mut s,
+        "pcap2socks_packets_captured_total",
+        "Frames captured",
+        PACKETS_CAPTURED.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_packets_sent_total",
+        "Frames sent",
+        PACKETS_SENT.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_packets_forwarded_total",
+        "Frames forwarded between sources locally",
+        PACKETS_FORWARDED.get(),
+    );
+
+    header(
+        &mut s,
+        "pcap2socks_packets_dropped_total",
+        "Packets dropped by reason",
+        "counter",
+    );
+    for reason in DROP_REASONS.iter() {
+        let _ = writeln!(
+            s,
+            "pcap2socks_packets_dropped_total{{reason=\"{}\"}} {}",
+            reason.get_name(),
+            PACKETS_DROPPED[*reason as usize].get()
+        );
+    }
+
+    header(
+        &mut s,
+        "pcap2socks_connections_closed_total",
+        "Connections closed by reason",
+        "counter",
+    );
+    for reason in CLOSE_REASONS.iter() {
+        let _ = writeln!(
+            s,
+            "pcap2socks_connections_closed_total{{reason=\"{}\"}} {}",
+            reason.get_name(),
+            CONNECTIONS_CLOSED[*reason as usize].get()
+        );
+    }
+
+    header(
+        &mut s,
+        "pcap2socks_bytes_relayed_total",
+        "Bytes of unique payload relayed by direction and protocol",
+        "counter",
+    );
+    for protocol in protocols.iter() {
+        let _ = writeln!(
+            s,
+            "pcap2socks_bytes_relayed_total{{direction=\"up\",protocol=\"{}\"}} {}",
+            protocol,
+            BYTES_UP[*protocol as usize].get()
+        );
+        let _ = writeln!(
+            s,
+            "pcap2socks_bytes_relayed_total{{direction=\"down\",protocol=\"{}\"}} {}",
+            protocol,
+            BYTES_DOWN[*protocol as usize].get()
+        );
+    }
+    header(
+        &mut s,
+        "pcap2socks_bytes_retransmitted_total",
+        "Bytes of payload retransmitted by direction and protocol",
+        "counter",
+    );
+    for protocol in protocols.iter() {
+        let _ = writeln!(
+            s,
+            "pcap2socks_bytes_retransmitted_total{{direction=\"up\",protocol=\"{}\"}} {}",
+            protocol,
+            BYTES_RETRANSMITTED_UP[*protocol as usize].get()
+        );
+        let _ = writeln!(
+            s,
+            "pcap2socks_bytes_retransmitted_total{{direction=\"down\",protocol=\"{}\"}} {}",
+            protocol,
+            BYTES_RETRANSMITTED_DOWN[*protocol as usize].get()
+        );
+    }
+    header(
+        &mut s,
+        "pcap2socks_bytes_wire_total",
+        "Bytes of frames on the wire including headers by direction and protocol",
+        "counter",
+    );
+    for protocol in protocols.iter() {
+        let _ = writeln!(
+            s,
+            "pcap2socks_bytes_wire_total{{direction=\"up\",protocol=\"{}\"}} {}",
+            protocol,
+            BYTES_WIRE_UP[*protocol as usize].get()
+        );
+        let _ = writeln!(
+            s,
+            "pcap2socks_bytes_wire_total{{direction=\"down\",protocol=\"{}\"}} {}",
+            protocol,
+            BYTES_WIRE_DOWN[*protocol as usize].get()
+        );
+    }
+
+    header(
+        &mut s,
+        "pcap2socks_tcp_connections",
+        "TCP connections open",
+        "gauge",
+    );
+    let _ = writeln!(
+        s,
+        "pcap2socks_tcp_connections {}",
+        CONNECTIONS[Protocol::Tcp as usize].get()
+    );
+    header(
+        &mut s,
+        "pcap2socks_udp_associations",
+        "UDP associations open",
+        "gauge",
+    );
+    let _ = writeln!(
+        s,
+        "pcap2socks_udp_associations {}",
+        CONNECTIONS[Protocol::Udp as usize].get()
+    );
+
+    header(
+        &mut s,
+        "pcap2socks_udp_tunnels",
+        "UDP associations tunneled over TCP open",
+        "gauge",
+    );
+    let _ = writeln!(s, "pcap2socks_udp_tunnels {}", UDP_TUNNELS.get());
+    counter(
+        &mut s,
+        "pcap2socks_udp_tunnel_reconnections_total",
+        "Streams of UDP tunnels reconnected after they break",
+        UDP_TUNNEL_RECONNECTIONS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_coalesced_segments_total",
+        "TCP segments coalesced by segmentation offload split before they are handled",
+        COALESCED_SEGMENTS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_udp_reassociations_total",
+        "UDP associations re-established after their relays stop relaying",
+        UDP_REASSOCIATIONS.get(),
+    );
+    if let Some(path) = get_udp_path() {
+        header(
+            &mut s,
+            "pcap2socks_udp_path",
+            "Path of UDP traffic through the proxy",
+            "gauge",
+        );
+        let _ = writeln!(s, "pcap2socks_udp_path{{path=\"{}\"}} 1", path);
+    }
+
+    if let Some((in_use, size)) = crate::socks::get_port_occupancy() {
+        header(
+            &mut s,
+            "pcap2socks_local_ports_in_use",
+            "Local ports in use of the local port range",
+            "gauge",
+        );
+        let _ = writeln!(s, "pcap2socks_local_ports_in_use {}", in_use);
+        header(
+            &mut s,
+            "pcap2socks_local_ports",
+            "Local ports of the local port range",
+            "gauge",
+        );
+        let _ = writeln!(s, "pcap2socks_local_ports {}", size);
+    }
+    if let Some((sockets, associations)) = crate::socks::get_shared_occupancy() {
+        header(
+            &mut s,
+            "pcap2socks_udp_shared_sockets",
+            "Local UDP sockets shared by UDP associations open",
+            "gauge",
+        );
+        let _ = writeln!(s, "pcap2socks_udp_shared_sockets {}", sockets);
+        header(
+            &mut s,
+            "pcap2socks_udp_shared_associations",
+            "UDP associations routed through shared sockets",
+            "gauge",
+        );
+        let _ = writeln!(s, "pcap2socks_udp_shared_associations {}", associations);
+        counter(
+            &mut s,
+            "pcap2socks_udp_unrouted_total",
+            "Datagrams received on shared sockets belonging to no association",
+            UDP_UNROUTED.get(),
+        );
+    }
+    if let Some((in_use, size)) = crate::nat::get_nat_occupancy() {
+        header(
+            &mut s,
+            "pcap2socks_nat_ports_in_use",
+            "Translated ports in use of the NAT",
+            "gauge",
+        );
+        let _ = writeln!(s, "pcap2socks_nat_ports_in_use {}", in_use);
+        header(
+            &mut s,
+            "pcap2socks_nat_ports",
+            "Translated ports of the NAT",
+            "gauge",
+        );
+        let _ = writeln!(s, "pcap2socks_nat_ports {}", size);
+    }
+
+    header(
+        &mut s,
+        "pcap2socks_igmp_messages_total",
+        "IGMP messages seen by kind",
+        "counter",
+    );
+    for kind in IGMP_KINDS.iter() {
+        let _ = writeln!(
+            s,
+            "pcap2socks_igmp_messages_total{{kind=\"{}\"}} {}",
+            kind.get_name(),
+            IGMP_MESSAGES[*kind as usize].get()
+        );
+    }
+
+    header(
+        &mut s,
+        "pcap2socks_control_frames_total",
+        "Control frames of switches seen by kind",
+        "counter",
+    );
+    for kind in CONTROL_KINDS.iter() {
+        let _ = writeln!(
+            s,
+            "pcap2socks_control_frames_total{{kind=\"{}\"}} {}",
+            kind.get_name(),
+            CONTROL_FRAMES[*kind as usize].get()
+        );
+    }
+
+    counter(
+        &mut s,
+        "pcap2socks_arp_requests_total",
+        "ARP requests sent to resolve hosts in the LAN",
+        ARP_REQUESTS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_arp_failures_total",
+        "Hosts in the LAN unresolved by ARP",
+        ARP_FAILURES.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_arp_queue_dropped_total",
+        "Frames to hosts in the LAN dropped while resolving them",
+        ARP_QUEUE_DROPS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_arp_conflicts_total",
+        "ARP messages of other hosts claiming the published address",
+        ARP_CONFLICTS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_reloads_total",
+        "Reloads of the configuration",
+        RELOADS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_reload_failures_total",
+        "Reloads of the configuration failing to read or validate",
+        RELOAD_FAILURES.get(),
+    );
+    header(
+        &mut s,
+        "pcap2socks_last_reload_timestamp_seconds",
+        "Time of the last reload of the configuration",
+        "gauge",
+    );
+    let _ = writeln!(
+        s,
+        "pcap2socks_last_reload_timestamp_seconds {}",
+        LAST_RELOAD.load(Ordering::Relaxed)
+    );
+
+    counter(
+        &mut s,
+        "pcap2socks_socks_handshake_failures_total",
+        "Failed handshakes with the proxy",
+        SOCKS_HANDSHAKE_FAILURES.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_proxy_pool_hits_total",
+        "Connections to proxies taken from pools",
+        PROXY_POOL_HITS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_proxy_pool_misses_total",
+        "Connections to proxies opened because pools are empty",
+        PROXY_POOL_MISSES.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_proxy_pool_stale_total",
+        "Pooled connections found closed by proxies",
+        PROXY_POOL_STALE.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_tcp_retransmissions_total",
+        "TCP segments retransmitted to sources",
+        TCP_RETRANSMISSIONS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_tcp_out_of_order_total",
+        "TCP segments received out of order from sources",
+        TCP_OUT_OF_ORDER.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_udp_packets_dropped_total",
+        "UDP datagrams dropped",
+        UDP_PACKETS_DROPPED.get(),
+    );
+    header(
+        &mut s,
+        "pcap2socks_udp_packets_relayed_total",
+        "UDP datagrams relayed by direction",
+        "counter",
+    );
+    let _ = writeln!(
+        s,
+        "pcap2socks_udp_packets_relayed_total{{direction=\"up\"}} {}",
+        UDP_PACKETS_UP.get()
+    );
+    let _ = writeln!(
+        s,
+        "pcap2socks_udp_packets_relayed_total{{direction=\"down\"}} {}",
+        UDP_PACKETS_DOWN.get()
+    );
+    header(
+        &mut s,
+        "pcap2socks_udp_overhead_bytes_total",
+        "Bytes of SOCKS5 UDP headers by direction",
+        "counter",
+    );
+    let _ = writeln!(
+        s,
+        "pcap2socks_udp_overhead_bytes_total{{direction=\"up\"}} {}",
+        UDP_OVERHEAD_UP.get()
+    );
+    let _ = writeln!(
+        s,
+        "pcap2socks_udp_overhead_bytes_total{{direction=\"down\"}} {}",
+        UDP_OVERHEAD_DOWN.get()
+    );
+    counter(
+        &mut s,
+        "pcap2socks_udp_send_failures_total",
+        "UDP datagrams which cannot be sent to relays",
+        UDP_SEND_FAILURES.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_udp_oversized_total",
+        "UDP datagrams larger than the path MTU to relays",
+        UDP_OVERSIZED.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_ipv4_dont_fragment_drops_total",
+        "IPv4 packets dropped for the DF bit instead of fragmented",
+        IPV4_DONT_FRAGMENT_DROPS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_udp_quic_flows_total",
+        "UDP associations detected as QUIC",
+        UDP_QUIC_FLOWS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_udp_quic_migrations_total",
+        "QUIC connections migrated to new source ports",
+        UDP_QUIC_MIGRATIONS.get(),
+    );
+    header(
+        &mut s,
+        "pcap2socks_udp_jitter_microseconds",
+        "Inter-arrival jitter of UDP datagrams relayed to sources",
+        "summary",
+    );
+    let _ = writeln!(
+        s,
+        "pcap2socks_udp_jitter_microseconds_sum {}",
+        UDP_JITTER_SUM.get()
+    );
+    let _ = writeln!(
+        s,
+        "pcap2socks_udp_jitter_microseconds_count {}",
+        UDP_JITTER_COUNT.get()
+    );
+    counter(
+        &mut s,
+        "pcap2socks_ipv4_reassembly_expired_total",
+        "IPv4 datagrams expired in reassembly",
+        REASSEMBLY_EXPIRATIONS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_tcp_urgent_total",
+        "TCP segments with urgent data delivered inline",
+        TCP_URGENT.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_tcp_stale_segments_total",
+        "TCP segments of connections closed recently",
+        TCP_STALE_SEGMENTS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_tcp_unknown_segments_total",
+        "TCP segments of connections never known",
+        TCP_UNKNOWN_SEGMENTS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_tcp_unknown_rsts_suppressed_total",
+        "RSTs to TCP segments of connections not found suppressed by the rate limit",
+        TCP_UNKNOWN_RSTS_SUPPRESSED.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_tcp_pickups_total",
+        "TCP connections picked up mid-stream",
+        TCP_PICKUPS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_send_retries_total",
+        "Retries of frames which fail to be sent for transient errors",
+        SEND_RETRIES.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_send_failures_total",
+        "Frames which fail to be sent for transient errors after all the retries",
+        SEND_FAILURES.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_device_restarts_total",
+        "Device reopened after it is lost",
+        DEVICE_RESTARTS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_tcp_evictions_total",
+        "TCP connections closed for new ones when connections are full",
+        TCP_EVICTIONS.get(),
+    );
+    header(
+        &mut s,
+        "pcap2socks_memory_bytes",
+        "Memory in use of buffers",
+        "gauge",
+    );
+    let _ = writeln!(s, "pcap2socks_memory_bytes {}", memory::get_usage());
+    header(
+        &mut s,
+        "pcap2socks_memory_budget_bytes",
+        "Memory budget of buffers",
+        "gauge",
+    );
+    let _ = writeln!(s, "pcap2socks_memory_budget_bytes {}", memory::get_budget());
+    counter(
+        &mut s,
+        "pcap2socks_memory_evictions_total",
+        "TCP connections reset when the memory budget is exhausted",
+        MEMORY_EVICTIONS.get(),
+    );
+    counter(
+        &mut s,
+        "pcap2socks_memory_discards_total",
+        "TCP segments out of order discarded under the memory pressure",
+        MEMORY_DISCARDS.get(),
+    );
+
+    let states = get_proxy_states();
+    if !states.is_empty() {
+        header(
+            &mut s,
+            "pcap2socks_proxy_up",
+            "Health of proxies of the group",
+            "gauge",
+        );
+        for state in states.iter() {
+            let _ = writeln!(
+                s,
+                "pcap2socks_proxy_up{{proxy=\"{}\"}} {}",
+                state.name, state.is_up as u8
+            );
+        }
+        header(
+            &mut s,
+            "pcap2socks_proxy_connections_total",
+            "Connections through proxies of the group",
+            "counter",
+        );
+        for state in states.iter() {
+            let _ = writeln!(
+                s,
+                "pcap2socks_proxy_connections_total{{proxy=\"{}\"}} {}",
+                state.name, state.flows
+            );
+        }
+    }
+
+    let devices = bridge::get_states();
+    if !devices.is_empty() {
+        header(
+            &mut s,
+            "pcap2socks_device_frames_total",
+            "Frames of bridged interfaces by direction",
+            "counter",
+        );
+        for device in devices.iter() {
+            let _ = writeln!(
+                s,
+                "pcap2socks_device_frames_total{{device=\"{}\",direction=\"received\"}} {}",
+                device.name, device.frames_received
+            );
+            let _ = writeln!(
+                s,
+                "pcap2socks_device_frames_total{{device=\"{}\",direction=\"sent\"}} {}",
+                device.name, device.frames_sent
+            );
+        }
+        header(
+            &mut s,
+            "pcap2socks_device_bytes_total",
+            "Bytes of frames of bridged interfaces by direction",
+            "counter",
+        );
+        for device in devices.iter() {
+            let _ = writeln!(
+                s,
+                "pcap2socks_device_bytes_total{{device=\"{}\",direction=\"received\"}} {}",
+                device.name, device.bytes_received
+            );
+            let _ = writeln!(
+                s,
+                "pcap2socks_device_bytes_total{{device=\"{}\",direction=\"sent\"}} {}",
+                device.name, device.bytes_sent
+            );
+        }
+        header(
+            &mut s,
+            "pcap2socks_device_hosts",
+            "IPv4 hosts learned on bridged interfaces",
+            "gauge",
+        );
+        for device in devices.iter() {
+            let _ = writeln!(
+                s,
+                "pcap2socks_device_hosts{{device=\"{}\"}} {}",
+                device.name, device.hosts
+            );
+        }
+        header(
+            &mut s,
+            "pcap2socks_device_moves_total",
+            "Hosts moved to bridged interfaces from another interface",
+            "counter",
+        );
+        for device in devices.iter() {
+            let _ = writeln!(
+                s,
+                "pcap2socks_device_moves_total{{device=\"{}\"}} {}",
+                device.name, device.moves
+            );
+        }
+    }
+
+    if let Some(ref access_list) = *ACCESS_LIST.lock().unwrap() {
+        header(
+            &mut s,
+            "pcap2socks_access_matches_total",
+            "TCP connections and UDP datagrams matched by access lists",
+            "counter",
+        );
+        let lists = [
+            ("allow", access_list.get_allow()),
+            ("deny", access_list.get_deny()),
+        ];
+        for (list, entries) in lists.iter() {
+            for access in entries.iter() {
+                let _ = writeln!(
+                    s,
+                    "pcap2socks_access_matches_total{{list=\"{}\",rule=\"{}\"}} {}",
+                    list,
+                    access,
+                    access.get_count()
+                );
+            }
+        }
+    }
+
+    let drops = LAN_DROPS.lock().unwrap();
+    if !drops.is_empty() {
+        header(
+            &mut s,
+            "pcap2socks_lan_dropped_total",
+            "Datagrams dropped by broadcast and multicast destinations",
+            "counter",
+        );
+        for (dst, count) in drops.iter() {
+            let _ = writeln!(
+                s,
+                "pcap2socks_lan_dropped_total{{dst=\"{}\"}} {}",
+                dst, count
+            );
+        }
+    }
+    drop(drops);
+
+    let classes = classify::get_classes();
+    if !classes.is_empty() {
+        header(
+            &mut s,
+            "pcap2socks_classified_bytes_total",
+            "Bytes of TCP connections by direction, port and name of services",
+            "counter",
+        );
+        for (class, up, down) in classes.iter() {
+            let name = class.get_name().unwrap_or("");
+            let _ = writeln!(
+                s,
+                "pcap2socks_classified_bytes_total{{direction=\"up\",port=\"{}\",name=\"{}\"}} {}",
+                class.get_port(),
+                name,
+                up
+            );
+            let _ = writeln!(
+                s,
+                "pcap2socks_classified_bytes_total{{direction=\"down\",port=\"{}\",name=\"{}\"}} {}",
+                class.get_port(),
+                name,
+                down
+            );
+        }
+    }
+
+    s
+}
+
+fn header(s: &mut String, name: &str, help: &str, t: &str) {
+    let _ = writeln!(s, "# HELP {} {}", name, help);
+    let _ = writeln!(s, "# TYPE {} {}", name, t);
+}
+
+fn counter(s: &mut String, name: &str, help: &str, value: u64) {
+    header(s, name, help, "counter");
+    let _ = writeln!(s, "{} {}", name, value);
+}
+
+/// Serves the metrics at `/metrics` over HTTP on the address in the background.
+pub fn serve(addr: SocketAddrV4) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(ref e) = respond(stream) {
+                        debug!("handle {}: {}", "metrics", e);
+                    }
+                }
+                Err(ref e) => warn!("handle {}: {}", "metrics", e),
+            }
+        }
+    }))
+}
+
+/// Answers a request in HTTP/1.0, the stream is closed after the response.
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_millis(HTTP_TIMEOUT)))?;
+    stream.set_write_timeout(Some(Duration::from_millis(HTTP_TIMEOUT)))?;
+
+    // Read until the end of the request line
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.contains(&b'\n') && request.len() < MAX_REQUEST_SIZE {
+        let size = stream.read(&mut buffer)?;
+        if size == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..size]);
+    }
+    let line = String::from_utf8_lossy(&request);
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    let (status, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", render()),
+        ("GET", _) => ("404 Not Found", String::from("Not Found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            String::from("Method Not Allowed\n"),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    stream.write_all(response.as_bytes())
+}
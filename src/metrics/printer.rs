@@ -0,0 +1,282 @@
+use log::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use super::Stats;
+use crate::classify;
+use crate::event::Connection;
+use crate::pcap::bridge;
+
+/// Represents the interval of checking if the printer is stopped in milliseconds.
+const POLL_INTERVAL: u64 = 100;
+/// Represents the number of the top connections by bytes printed at exit.
+const TOP_CONNECTIONS: usize = 5;
+/// Represents the number of the top classes of connections by bytes printed.
+const TOP_CLASSES: usize = 5;
+
+/// Represents if connections are ranked by bytes, which is enabled by the printer.
+static IS_RANKED: AtomicBool = AtomicBool::new(false);
+/// Represents the top connections by bytes.
+static RANKED_CONNECTIONS: Mutex<Vec<Ranked>> = Mutex::new(Vec::new());
+
+/// Represents a connection of the top connections by bytes.
+#[derive(Debug)]
+struct Ranked {
+    name: String,
+    bytes_up: u64,
+    bytes_down: u64,
+}
+
+impl Ranked {
+    fn get_bytes(&self) -> u64 {
+        self.bytes_up + self.bytes_down
+    }
+}
+
+/// Ranks a closed connection by its bytes if the printer is open.
+pub fn rank_connection(connection: &Connection) {
+    if !IS_RANKED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let bytes = connection.get_bytes_up() + connection.get_bytes_down();
+    let mut ranked = RANKED_CONNECTIONS.lock().unwrap();
+    if ranked.len() >= TOP_CONNECTIONS && ranked.last().unwrap().get_bytes() >= bytes {
+        return;
+    }
+    let i = ranked
+        .iter()
+        .position(|current| current.get_bytes() < bytes)
+        .unwrap_or_else(|| ranked.len());
+    ranked.insert(
+        i,
+        Ranked {
+            name: format!(
+                "{}{}:{} -> {} ({})",
+                connection.get_tag(),
+                connection.get_client(),
+                connection.get_src_port(),
+                connection.get_dst(),
+                connection.get_protocol()
+            ),
+            bytes_up: connection.get_bytes_up(),
+            bytes_down: connection.get_bytes_down(),
+        },
+    );
+    ranked.truncate(TOP_CONNECTIONS);
+}
+
+/// Represents the printer of statistics, which prints the statistics since the last interval
+/// until it is closed.
+pub struct Printer {
+    is_stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Printer {
+    /// Opens a printer printing in the interval on its own thread.
+    pub fn open(interval: Duration) -> Printer {
+        IS_RANKED.store(true, Ordering::Relaxed);
+
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let is_stopped_cloned = Arc::clone(&is_stopped);
+        let thread = thread::spawn(move || {
+            let mut last = super::stats();
+            let mut instant = Instant::now();
+            while !is_stopped_cloned.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(POLL_INTERVAL));
+                if instant.elapsed() < interval {
+                    continue;
+                }
+
+                let current = super::stats();
+                print_interval(&last, &current, instant.elapsed());
+                last = current;
+                instant = Instant::now();
+            }
+        });
+
+        Printer {
+            is_stopped,
+            thread: Some(thread),
+        }
+    }
+
+    /// Closes the printer, prints the cumulative statistics and the top connections by bytes.
+    pub fn close(mut self) {
+        self.stop();
+
+        let stats = super::stats();
+        info!(
+            "Total: up {} packets {} ({} on the wire, {} retransmitted), down {} packets {} ({} on the wire, {} retransmitted), {} forwarded, {} dropped, {} UDP dropped, {} retransmitted, {} out of order, {} reassembly expired",
+            stats.packets_captured,
+            size_string(stats.bytes_up),
+            size_string(stats.bytes_wire_up),
+            size_string(stats.bytes_retransmitted_up),
+            stats.packets_sent,
+            size_string(stats.bytes_down),
+            size_string(stats.bytes_wire_down),
+            size_string(stats.bytes_retransmitted_down),
+            stats.packets_forwarded,
+            stats.packets_dropped,
+            stats.udp_packets_dropped,
+            stats.tcp_retransmissions,
+            stats.tcp_out_of_order,
+            stats.reassembly_expirations
+        );
+        let closes = super::get_closes();
+        if !closes.is_empty() {
+            let closes: Vec<_> = closes
+                .iter()
+                .map(|(reason, count)| format!("{} {}", reason, count))
+                .collect();
+            info!("Closed: {}", closes.join(", "));
+        }
+        for (i, ranked) in RANKED_CONNECTIONS.lock().unwrap().iter().enumerate() {
+            info!(
+                "Top {}: {}, up {}, down {}",
+                i + 1,
+                ranked.name,
+                size_string(ranked.bytes_up),
+                size_string(ranked.bytes_down)
+            );
+        }
+        for (i, (class, up, down)) in classify::get_classes().iter().take(TOP_CLASSES).enumerate() {
+            info!(
+                "Top service {}: {}, up {}, down {}",
+                i + 1,
+                class,
+                size_string(*up),
+                size_string(*down)
+            );
+        }
+    }
+
+    fn stop(&mut self) {
+        self.is_stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        IS_RANKED.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Prints the statistics since the last interval, connections are the ones open now.
+fn print_interval(last: &Stats, current: &Stats, elapsed: Duration) {
+    info!(
+        "Stats in {} s: {} TCP, {} UDP, up {} packets {}, down {} packets {}, {} retransmitted, {} out of order, {} UDP dropped, {} reassembly expired, memory {}/{}{}{}",
+        elapsed.as_secs(),
+        current.tcp_connections,
+        udp_string(current),
+        current.packets_captured - last.packets_captured,
+        size_string(current.bytes_up - last.bytes_up),
+        current.packets_sent - last.packets_sent,
+        size_string(current.bytes_down - last.bytes_down),
+        current.tcp_retransmissions - last.tcp_retransmissions,
+        current.tcp_out_of_order - last.tcp_out_of_order,
+        current.udp_packets_dropped - last.udp_packets_dropped,
+        current.reassembly_expirations - last.reassembly_expirations,
+        size_string(current.memory_usage),
+        size_string(current.memory_budget),
+        local_ports_string(current),
+        reload_string(current)
+    );
+    let states = super::get_proxy_states();
+    if !states.is_empty() {
+        let states: Vec<String> = states
+            .iter()
+            .map(|state| {
+                format!(
+                    "{} {} ({} connections)",
+                    state.name,
+                    match state.is_up {
+                        true => "up",
+                        false => "down",
+                    },
+                    state.flows
+                )
+            })
+            .collect();
+        info!("Proxies: {}", states.join(", "));
+    }
+    let devices = bridge::get_states();
+    if !devices.is_empty() {
+        let devices: Vec<String> = devices
+            .iter()
+            .map(|device| {
+                format!(
+                    "{} ({} hosts, {} moves, received {} frames {}, sent {} frames {})",
+                    device.name,
+                    device.hosts,
+                    device.moves,
+                    device.frames_received,
+                    size_string(device.bytes_received),
+                    device.frames_sent,
+                    size_string(device.bytes_sent)
+                )
+            })
+            .collect();
+        info!("Devices: {}", devices.join(", "));
+    }
+    let classes: Vec<String> = classify::get_classes()
+        .iter()
+        .take(TOP_CLASSES)
+        .map(|(class, up, down)| {
+            format!(
+                "{} (up {}, down {})",
+                class,
+                size_string(*up),
+                size_string(*down)
+            )
+        })
+        .collect();
+    if !classes.is_empty() {
+        info!("Services: {}", classes.join(", "));
+    }
+}
+
+/// Get the string of local ports in use of the local port range if there is one.
+fn local_ports_string(stats: &Stats) -> String {
+    match stats.local_ports {
+        0 => String::new(),
+        size => format!(", {}/{} local ports", stats.local_ports_in_use, size),
+    }
+}
+
+/// Get the string of reloads of the configuration, with the time of the last one if there are.
+fn reload_string(stats: &Stats) -> String {
+    match stats.reloads {
+        0 => String::new(),
+        reloads => format!(
+            ", {} reloads (last at {})",
+            reloads,
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(stats.last_reload))
+        ),
+    }
+}
+
+/// Get the string of UDP associations, with the ones tunneled over TCP if there are.
+fn udp_string(stats: &Stats) -> String {
+    match stats.udp_tunnels {
+        0 => stats.udp_associations.to_string(),
+        tunnels => format!("{} ({} tunneled)", stats.udp_associations, tunnels),
+    }
+}
+
+/// Get the string of a size in bytes in a unit of at most 1024.
+fn size_string(size: u64) -> String {
+    match size {
+        size if size >= 1 << 30 => format!("{:.1} GB", size as f64 / (1u64 << 30) as f64),
+        size if size >= 1 << 20 => format!("{:.1} MB", size as f64 / (1u64 << 20) as f64),
+        size if size >= 1 << 10 => format!("{:.1} KB", size as f64 / (1u64 << 10) as f64),
+        size => format!("{} B", size),
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Mutex;
+
+use crate::event::Protocol;
+use crate::socks::PortRange;
+
+/// Represents the NAT of flows of sources, which is shared by all upstreamers.
+static NAT: Mutex<Option<Nat>> = Mutex::new(None);
+
+/// Represents the NAT mapping flows of sources through the proxy to unique translated ports of a
+/// range, so flows of sources using the same port never collide on the side of the proxy. A
+/// flow keeps its port until it is released, and a new flow of the same source port of the
+/// source takes the same port if it is still mapped.
+#[derive(Debug)]
+struct Nat {
+    range: PortRange,
+    /// Represents the next port tried
+    cursor: u16,
+    /// Represents the map mapping a flow to the ID of the connection owning the mapping and the
+    /// translated port
+    flows: HashMap<(Ipv4Addr, u16, Protocol), (u64, u16)>,
+    /// Represents the map mapping a translated port back to the flow
+    ports: HashMap<(Protocol, u16), (Ipv4Addr, u16)>,
+}
+
+impl Nat {
+    fn map(
+        &mut self,
+        client: Ipv4Addr,
+        src_port: u16,
+        protocol: Protocol,
+        id: u64,
+    ) -> io::Result<u16> {
+        let key = (client, src_port, protocol);
+        if let Some(entry) = self.flows.get_mut(&key) {
+            // The connection replacing the former one of the flow owns the mapping
+            entry.0 = id;
+
+            return Ok(entry.1);
+        }
+
+        for _ in 0..self.range.get_size() {
+            let port = self.cursor;
+            self.cursor = if port >= self.range.get_hi() {
+                self.range.get_lo()
+            } else {
+                port + 1
+            };
+            if self.ports.contains_key(&(protocol, port)) {
+                continue;
+            }
+
+            self.flows.insert(key, (id, port));
+            self.ports.insert((protocol, port), (client, src_port));
+
+            return Ok(port);
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("NAT ports {} of {} are exhausted", self.range, protocol),
+        ))
+    }
+
+    fn release(&mut self, client: Ipv4Addr, src_port: u16, protocol: Protocol, id: u64) {
+        let key = (client, src_port, protocol);
+        match self.flows.get(&key) {
+            Some(&(owner, port)) if owner == id => {
+                self.flows.remove(&key);
+                self.ports.remove(&(protocol, port));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Sets the range of translated ports of flows, or `None` to disable the NAT. Flows mapped
+/// before are forgotten.
+pub fn set_nat_range(range: Option<PortRange>) {
+    *NAT.lock().unwrap() = range.map(|range| Nat {
+        range,
+        cursor: range.get_lo(),
+        flows: HashMap::new(),
+        ports: HashMap::new(),
+    });
+}
+
+/// Get the range of translated ports of flows.
+pub fn get_nat_range() -> Option<PortRange> {
+    NAT.lock().unwrap().as_ref().map(|nat| nat.range)
+}
+
+/// Maps the flow of the source port of the client to a translated port for the connection of
+/// the ID. Returns `None` if the NAT is disabled, and an error if all the ports of the range are
+/// taken by other flows, which fails the new flow alone.
+pub fn map(
+    client: Ipv4Addr,
+    src_port: u16,
+    protocol: Protocol,
+    id: u64,
+) -> Option<io::Result<u16>> {
+    let mut nat = NAT.lock().unwrap();
+    let nat = nat.as_mut()?;
+
+    Some(nat.map(client, src_port, protocol, id))
+}
+
+/// Releases the translated port of the flow if it is still owned by the connection of the ID.
+pub fn release(client: Ipv4Addr, src_port: u16, protocol: Protocol, id: u64) {
+    if let Some(nat) = NAT.lock().unwrap().as_mut() {
+        nat.release(client, src_port, protocol, id);
+    }
+}
+
+/// Translates a translated port back to the client and the source port of its flow.
+pub fn translate(protocol: Protocol, port: u16) -> Option<SocketAddrV4> {
+    let nat = NAT.lock().unwrap();
+    let nat = nat.as_ref()?;
+
+    nat.ports
+        .get(&(protocol, port))
+        .map(|&(client, src_port)| SocketAddrV4::new(client, src_port))
+}
+
+/// Get the number of translated ports in use and the number of ports in the range, or `None` if
+/// the NAT is disabled.
+pub fn get_nat_occupancy() -> Option<(usize, usize)> {
+    let nat = NAT.lock().unwrap();
+    let nat = nat.as_ref()?;
+
+    Some((nat.ports.len(), nat.range.get_size()))
+}
@@ -0,0 +1,134 @@
+use pnet::util::MacAddr;
+use std::fmt::{self, Display, Formatter};
+use std::net::Ipv4Addr;
+
+/// Represents the size of IGMPv1 and IGMPv2 messages.
+const MESSAGE_SIZE: usize = 8;
+/// Represents the size of the fixed part of IGMPv3 membership reports.
+const REPORT_V3_HEADER_SIZE: usize = 8;
+/// Represents the size of the fixed part of group records of IGMPv3 membership reports.
+const GROUP_RECORD_HEADER_SIZE: usize = 8;
+
+/// Represents the kinds of IGMP messages.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IgmpKind {
+    /// Represents a membership query of any version.
+    Query,
+    /// Represents a membership report of any version.
+    Report,
+    /// Represents an IGMPv2 leave group.
+    Leave,
+    /// Represents a message of other types.
+    Other,
+}
+
+impl IgmpKind {
+    /// Get the name of the kind.
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            IgmpKind::Query => "query",
+            IgmpKind::Report => "report",
+            IgmpKind::Leave => "leave",
+            IgmpKind::Other => "other",
+        }
+    }
+}
+
+impl Display for IgmpKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
+
+/// Represents an IGMP message classified, with the multicast groups it joins and leaves. The
+/// message is only parsed, it is never serialized.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Igmp {
+    kind: IgmpKind,
+    joins: Vec<Ipv4Addr>,
+    leaves: Vec<Ipv4Addr>,
+}
+
+impl Igmp {
+    /// Parses an IGMP message in the payload of an IPv4 packet (RFC 1112, RFC 2236 and RFC
+    /// 3376). Returns `None` if the message is truncated.
+    pub fn parse(buffer: &[u8]) -> Option<Igmp> {
+        if buffer.len() < MESSAGE_SIZE {
+            return None;
+        }
+        let group = Ipv4Addr::new(buffer[4], buffer[5], buffer[6], buffer[7]);
+
+        let igmp = match buffer[0] {
+            0x11 => Igmp::new(IgmpKind::Query, vec![], vec![]),
+            // IGMPv1 and IGMPv2 membership reports
+            0x12 | 0x16 => Igmp::new(IgmpKind::Report, vec![group], vec![]),
+            0x17 => Igmp::new(IgmpKind::Leave, vec![], vec![group]),
+            0x22 => Igmp::parse_report_v3(buffer)?,
+            _ => Igmp::new(IgmpKind::Other, vec![], vec![]),
+        };
+
+        Some(igmp)
+    }
+
+    fn new(kind: IgmpKind, joins: Vec<Ipv4Addr>, leaves: Vec<Ipv4Addr>) -> Igmp {
+        Igmp {
+            kind,
+            joins: joins.into_iter().filter(|ip| ip.is_multicast()).collect(),
+            leaves: leaves.into_iter().filter(|ip| ip.is_multicast()).collect(),
+        }
+    }
+
+    /// Parses an IGMPv3 membership report. A group is joined in the exclude mode or with
+    /// sources, and left in the include mode without sources.
+    fn parse_report_v3(buffer: &[u8]) -> Option<Igmp> {
+        if buffer.len() < REPORT_V3_HEADER_SIZE {
+            return None;
+        }
+        let count = u16::from_be_bytes([buffer[6], buffer[7]]) as usize;
+
+        let mut joins = Vec::new();
+        let mut leaves = Vec::new();
+        let mut begin = REPORT_V3_HEADER_SIZE;
+        for _ in 0..count {
+            let record = buffer.get(begin..begin + GROUP_RECORD_HEADER_SIZE)?;
+            let record_type = record[0];
+            let aux_size = record[1] as usize * 4;
+            let sources = u16::from_be_bytes([record[2], record[3]]) as usize;
+            let group = Ipv4Addr::new(record[4], record[5], record[6], record[7]);
+            match record_type {
+                // MODE_IS_EXCLUDE and CHANGE_TO_EXCLUDE_MODE
+                2 | 4 => joins.push(group),
+                // MODE_IS_INCLUDE, CHANGE_TO_INCLUDE_MODE and ALLOW_NEW_SOURCES
+                1 | 3 | 5 if sources > 0 => joins.push(group),
+                1 | 3 => leaves.push(group),
+                _ => {}
+            }
+            begin += GROUP_RECORD_HEADER_SIZE + sources * 4 + aux_size;
+        }
+
+        Some(Igmp::new(IgmpKind::Report, joins, leaves))
+    }
+
+    /// Get the kind of the message.
+    pub fn get_kind(&self) -> IgmpKind {
+        self.kind
+    }
+
+    /// Get the multicast groups joined by the message.
+    pub fn get_joins(&self) -> &[Ipv4Addr] {
+        &self.joins
+    }
+
+    /// Get the multicast groups left by the message.
+    pub fn get_leaves(&self) -> &[Ipv4Addr] {
+        &self.leaves
+    }
+}
+
+/// Get the Ethernet multicast address of an IPv4 multicast group, which is `01:00:5e` followed
+/// by the low 23 bits of the group (RFC 1112).
+pub fn multicast_hardware_addr(group: Ipv4Addr) -> MacAddr {
+    let octets = group.octets();
+
+    MacAddr::new(0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3])
+}
@@ -1,11 +1,47 @@
-pub use super::{Layer, LayerType, LayerTypes};
+pub use super::{
+    Layer, LayerDeserialize, LayerType, LayerTypes, ParseContext, ParseError, Result,
+    SerializeError, Verbosity,
+};
 use pnet::datalink::MacAddr;
 use pnet::packet::arp::{self, ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
 use pnet::packet::ethernet::EtherTypes;
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
-use std::io;
 use std::net::Ipv4Addr;
+use std::result;
+
+/// Represents the kinds of ARP messages.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArpKind {
+    /// Represents an ARP request of the target from the sender.
+    Request,
+    /// Represents an ARP reply of the sender to the target.
+    Reply,
+    /// Represents an ARP probe of the duplicate address detection, which is a request whose
+    /// sender is unspecified (RFC 5227).
+    Probe,
+    /// Represents a gratuitous ARP announcement, which is a request or a reply whose sender and
+    /// target are the same address (RFC 5227).
+    Announcement,
+}
+
+impl ArpKind {
+    /// Get the name of the kind.
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            ArpKind::Request => "Request",
+            ArpKind::Reply => "Reply",
+            ArpKind::Probe => "Probe",
+            ArpKind::Announcement => "Announcement",
+        }
+    }
+}
+
+impl Display for ArpKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
 
 /// Represents an ARP layer.
 #[derive(Clone, Debug)]
@@ -37,6 +73,28 @@ impl Arp {
         }
     }
 
+    /// Creates a `Arp` represents an ARP request.
+    pub fn new_request(
+        src_hardware_addr: MacAddr,
+        src_ip_addr: Ipv4Addr,
+        dst_ip_addr: Ipv4Addr,
+    ) -> Arp {
+        Arp {
+            layer: arp::Arp {
+                hardware_type: ArpHardwareTypes::Ethernet,
+                protocol_type: EtherTypes::Ipv4,
+                hw_addr_len: 6,
+                proto_addr_len: 4,
+                operation: ArpOperations::Request,
+                sender_hw_addr: src_hardware_addr,
+                sender_proto_addr: src_ip_addr,
+                target_hw_addr: MacAddr::zero(),
+                target_proto_addr: dst_ip_addr,
+                payload: vec![],
+            },
+        }
+    }
+
     /// Creates an `Arp` according to the given `Arp`.
     pub fn from(arp: arp::Arp) -> Arp {
         Arp { layer: arp }
@@ -88,13 +146,48 @@ impl Arp {
         self.layer.operation == ArpOperations::Reply
     }
 
-    /// Returns if the `Arp` is an ARP request of the given source and destination.
-    pub fn is_request_of(&self, src: Ipv4Addr, dst: Ipv4Addr) -> bool {
-        match self.layer.operation {
-            ArpOperations::Request => {
-                self.layer.sender_proto_addr == src && self.layer.target_proto_addr == dst
+    /// Get the kind of the `Arp`, returns `None` if the operation is neither a request nor a
+    /// reply. Probes and announcements are never requests or replies.
+    pub fn get_kind(&self) -> Option<ArpKind> {
+        let sender = self.layer.sender_proto_addr;
+        let target = self.layer.target_proto_addr;
+        let kind = match self.layer.operation {
+            ArpOperations::Request if sender == Ipv4Addr::UNSPECIFIED => ArpKind::Probe,
+            ArpOperations::Request | ArpOperations::Reply if sender == target => {
+                ArpKind::Announcement
             }
-            _ => false,
+            ArpOperations::Request => ArpKind::Request,
+            ArpOperations::Reply => ArpKind::Reply,
+            _ => return None,
+        };
+
+        Some(kind)
+    }
+
+    /// Returns if the `Arp` is an ARP probe of the duplicate address detection.
+    pub fn is_probe(&self) -> bool {
+        self.get_kind() == Some(ArpKind::Probe)
+    }
+
+    /// Returns if the `Arp` is a gratuitous ARP announcement.
+    pub fn is_announcement(&self) -> bool {
+        self.get_kind() == Some(ArpKind::Announcement)
+    }
+
+    /// Get the address asked for by the `Arp`, which is the target of a request or a probe.
+    pub fn get_requested(&self) -> Option<Ipv4Addr> {
+        match self.get_kind()? {
+            ArpKind::Request | ArpKind::Probe => Some(self.layer.target_proto_addr),
+            ArpKind::Reply | ArpKind::Announcement => None,
+        }
+    }
+
+    /// Get the address claimed by the sender of the `Arp`, which is the sender of any kind but
+    /// a probe.
+    pub fn get_claimed(&self) -> Option<Ipv4Addr> {
+        match self.get_kind()? {
+            ArpKind::Probe => None,
+            _ => Some(self.layer.sender_proto_addr),
         }
     }
 
@@ -127,10 +220,9 @@ impl Display for Arp {
             self.get_type(),
             self.layer.sender_proto_addr,
             self.layer.target_proto_addr,
-            match self.layer.operation {
-                ArpOperations::Request => "Request",
-                ArpOperations::Reply => "Reply",
-                _ => "unknown",
+            match self.get_kind() {
+                Some(kind) => kind.get_name(),
+                None => "unknown",
             }
         )
     }
@@ -141,20 +233,142 @@ impl Layer for Arp {
         LayerTypes::Arp
     }
 
+    fn get_details(&self, verbosity: Verbosity) -> String {
+        let mut details = String::new();
+        if verbosity >= Verbosity::Normal {
+            details.push_str(&format!(
+                ", Sender = {}, Target = {}",
+                self.layer.sender_hw_addr, self.layer.target_hw_addr
+            ));
+        }
+        if verbosity >= Verbosity::Verbose {
+            details.push_str(&format!(
+                ", Hardware Type = {}, Protocol Type = {:#06x}",
+                self.layer.hardware_type.0, self.layer.protocol_type.0
+            ));
+        }
+
+        details
+    }
+
     fn get_size(&self) -> usize {
         ArpPacket::packet_size(&self.layer)
     }
 
-    fn serialize(&self, buffer: &mut [u8], _: usize) -> io::Result<usize> {
-        let mut packet = MutableArpPacket::new(buffer)
-            .ok_or(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
+    fn serialize(&self, buffer: &mut [u8], _: usize) -> Result {
+        let required = self.get_size();
+        if buffer.len() < required {
+            return Err(SerializeError::BufferTooSmall {
+                t: self.get_type(),
+                required,
+                actual: buffer.len(),
+            });
+        }
+
+        let mut packet = MutableArpPacket::new(&mut buffer[..required]).unwrap();
 
         packet.populate(&self.layer);
 
         Ok(self.get_size())
     }
 
-    fn serialize_with_payload(&self, buffer: &mut [u8], _: &[u8], n: usize) -> io::Result<usize> {
+    fn serialize_with_payload(&self, buffer: &mut [u8], _: &[u8], n: usize) -> Result {
         self.serialize(buffer, n)
     }
 }
+
+impl LayerDeserialize for Arp {
+    fn deserialize(buffer: &[u8], _: &ParseContext) -> result::Result<(Arp, usize), ParseError> {
+        let packet = ArpPacket::new(buffer).ok_or(ParseError::BufferTooSmall {
+            t: LayerTypes::Arp,
+            required: ArpPacket::minimum_packet_size(),
+            actual: buffer.len(),
+        })?;
+        let layer = Arp::parse(&packet);
+        let size = layer.get_size();
+
+        Ok((layer, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Represents an ARP request of a gateway from a host, in an Ethernet frame.
+    const REQUEST: [u8; 42] = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x3c, 0x22, 0xfb, 0x8e, 0x4a, 0x17, 0x08, 0x06, 0x00,
+        0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01, 0x3c, 0x22, 0xfb, 0x8e, 0x4a, 0x17, 0xc0, 0xa8,
+        0x01, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc0, 0xa8, 0x01, 0x01,
+    ];
+
+    /// Represents the ARP reply of the gateway, in an Ethernet frame.
+    const REPLY: [u8; 42] = [
+        0x3c, 0x22, 0xfb, 0x8e, 0x4a, 0x17, 0xb0, 0xbe, 0x76, 0xc2, 0x1d, 0x90, 0x08, 0x06, 0x00,
+        0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x02, 0xb0, 0xbe, 0x76, 0xc2, 0x1d, 0x90, 0xc0, 0xa8,
+        0x01, 0x01, 0x3c, 0x22, 0xfb, 0x8e, 0x4a, 0x17, 0xc0, 0xa8, 0x01, 0x64,
+    ];
+
+    /// Represents an ARP probe of an address offered by DHCP, in an Ethernet frame.
+    const PROBE: [u8; 42] = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x3c, 0x22, 0xfb, 0x8e, 0x4a, 0x17, 0x08, 0x06, 0x00,
+        0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01, 0x3c, 0x22, 0xfb, 0x8e, 0x4a, 0x17, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc0, 0xa8, 0x01, 0x64,
+    ];
+
+    /// Represents the ARP announcement of the address after the probe, in an Ethernet frame.
+    const ANNOUNCEMENT: [u8; 42] = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x3c, 0x22, 0xfb, 0x8e, 0x4a, 0x17, 0x08, 0x06, 0x00,
+        0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01, 0x3c, 0x22, 0xfb, 0x8e, 0x4a, 0x17, 0xc0, 0xa8,
+        0x01, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc0, 0xa8, 0x01, 0x64,
+    ];
+
+    /// Represents a gratuitous ARP reply of a router on link-up, in an Ethernet frame.
+    const GRATUITOUS_REPLY: [u8; 42] = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xb0, 0xbe, 0x76, 0xc2, 0x1d, 0x90, 0x08, 0x06, 0x00,
+        0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x02, 0xb0, 0xbe, 0x76, 0xc2, 0x1d, 0x90, 0xc0, 0xa8,
+        0x01, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc0, 0xa8, 0x01, 0x01,
+    ];
+
+    fn deserialize(frame: &[u8]) -> Arp {
+        let (arp, size) = Arp::deserialize(&frame[14..], &ParseContext::new()).unwrap();
+        assert_eq!(size, 28);
+
+        arp
+    }
+
+    #[test]
+    fn kind() {
+        let host = Ipv4Addr::new(192, 168, 1, 100);
+        let gateway = Ipv4Addr::new(192, 168, 1, 1);
+        let cases = [
+            (&REQUEST, ArpKind::Request, Some(gateway), Some(host)),
+            (&REPLY, ArpKind::Reply, None, Some(gateway)),
+            (&PROBE, ArpKind::Probe, Some(host), None),
+            (&ANNOUNCEMENT, ArpKind::Announcement, None, Some(host)),
+            (
+                &GRATUITOUS_REPLY,
+                ArpKind::Announcement,
+                None,
+                Some(gateway),
+            ),
+        ];
+        for (frame, kind, requested, claimed) in cases.iter() {
+            let arp = deserialize(*frame);
+            assert_eq!(arp.get_kind(), Some(*kind));
+            assert_eq!(arp.is_probe(), *kind == ArpKind::Probe);
+            assert_eq!(arp.is_announcement(), *kind == ArpKind::Announcement);
+            assert_eq!(arp.get_requested(), *requested);
+            assert_eq!(arp.get_claimed(), *claimed);
+            assert_eq!(
+                arp.get_src_hardware_addr(),
+                MacAddr::new(frame[6], frame[7], frame[8], frame[9], frame[10], frame[11])
+            );
+        }
+
+        // Neither a request nor a reply, like a RARP request
+        let mut frame = REQUEST;
+        frame[21] = 3;
+        assert_eq!(deserialize(&frame).get_kind(), None);
+    }
+}
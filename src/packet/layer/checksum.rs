@@ -0,0 +1,52 @@
+/// Computes the one's complement sum of the data in 16-bit words, an odd byte at the end is
+/// padded with zero.
+pub fn sum(data: &[u8]) -> u32 {
+    let mut sum = 0u32;
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum = add(sum, u16::from_be_bytes([chunk[0], chunk[1]]));
+    }
+    if let [b] = chunks.remainder() {
+        sum = add(sum, u16::from_be_bytes([*b, 0]));
+    }
+
+    sum
+}
+
+/// Folds a one's complement sum to 16 bits.
+pub fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    sum as u16
+}
+
+fn add(sum: u32, word: u16) -> u32 {
+    let sum = sum + word as u32;
+
+    (sum & 0xffff) + (sum >> 16)
+}
+
+/// Updates a checksum after a 16-bit word is changed from `old` to `new`, by the equation
+/// `HC' = ~(~HC + ~m + m')` (RFC 1624).
+pub fn update(checksum: u16, old: u16, new: u16) -> u16 {
+    let sum = add(add(!checksum as u32, !old), new);
+
+    !fold(sum)
+}
+
+/// Updates a checksum after the data is changed from `old` to `new`. The data should be of the
+/// same length and aligned to 16-bit words in the checksummed data.
+pub fn update_slice(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    debug_assert_eq!(old.len(), new.len());
+
+    let mut sum = !checksum as u32;
+    for (old, new) in old.chunks(2).zip(new.chunks(2)) {
+        let old = u16::from_be_bytes([old[0], *old.get(1).unwrap_or(&0)]);
+        let new = u16::from_be_bytes([new[0], *new.get(1).unwrap_or(&0)]);
+        sum = add(add(sum, !old), new);
+    }
+
+    !fold(sum)
+}
@@ -1,9 +1,126 @@
-pub use super::{Layer, LayerType, LayerTypes};
-use pnet::packet::ethernet::{self, EtherTypes, EthernetPacket, MutableEthernetPacket};
+pub use super::{
+    Layer, LayerDeserialize, LayerType, LayerTypes, ParseContext, ParseError, Result,
+    SerializeError, Verbosity,
+};
+use pnet::packet::ethernet::{self, EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
 use pnet::util::MacAddr;
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
-use std::io;
+use std::net::Ipv6Addr;
+use std::result;
+
+/// Represents the max value of the length field of IEEE 802.3 frames, larger values of the field
+/// are EtherTypes of Ethernet II frames.
+pub const MAX_LENGTH: u16 = 1500;
+/// Represents the size of LLC headers.
+const LLC_HEADER_SIZE: usize = 3;
+/// Represents the size of SNAP headers following LLC headers.
+const SNAP_HEADER_SIZE: usize = 5;
+/// Represents the SAP of SNAP.
+const SAP_SNAP: u8 = 0xaa;
+/// Represents the SAP of the spanning tree protocol.
+const SAP_STP: u8 = 0x42;
+/// Represents the OUI and the protocol of CDP in SNAP.
+const SNAP_CDP: (u32, u16) = (0x00000c, 0x2000);
+/// Represents the EtherType of LLDP.
+const ETHERTYPE_LLDP: u16 = 0x88cc;
+
+/// Represents the LLC encapsulation of IEEE 802.3 frames.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Llc {
+    /// Represents an LLC header with the destination and source SAPs.
+    Llc { dsap: u8, ssap: u8 },
+    /// Represents an LLC header followed by a SNAP header with the OUI and the protocol, which
+    /// is an EtherType if the OUI is zero.
+    Snap { oui: u32, protocol: u16 },
+}
+
+impl Llc {
+    /// Parses the LLC header, and the SNAP header if any, in the payload of an IEEE 802.3 frame.
+    /// Returns `None` if the payload is truncated.
+    pub fn parse(payload: &[u8]) -> Option<(Llc, usize)> {
+        let llc = payload.get(..LLC_HEADER_SIZE)?;
+        if llc[0] != SAP_SNAP || llc[1] != SAP_SNAP {
+            return Some((
+                Llc::Llc {
+                    dsap: llc[0],
+                    ssap: llc[1],
+                },
+                LLC_HEADER_SIZE,
+            ));
+        }
+        let snap = payload.get(LLC_HEADER_SIZE..LLC_HEADER_SIZE + SNAP_HEADER_SIZE)?;
+
+        Some((
+            Llc::Snap {
+                oui: u32::from_be_bytes([0, snap[0], snap[1], snap[2]]),
+                protocol: u16::from_be_bytes([snap[3], snap[4]]),
+            },
+            LLC_HEADER_SIZE + SNAP_HEADER_SIZE,
+        ))
+    }
+
+    /// Get the EtherType encapsulated in SNAP.
+    pub fn get_ethertype(&self) -> Option<EtherType> {
+        match *self {
+            Llc::Snap { oui: 0, protocol } => Some(EtherType::new(protocol)),
+            _ => None,
+        }
+    }
+}
+
+/// Represents the kinds of control frames of switches, which are expected in a link and never
+/// processed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ControlKind {
+    /// Represents a BPDU of the spanning tree protocol.
+    Stp,
+    /// Represents an LLDP frame.
+    Lldp,
+    /// Represents a CDP frame.
+    Cdp,
+    /// Represents an IEEE 802.3 frame of other protocols in LLC.
+    Llc,
+}
+
+impl ControlKind {
+    /// Classifies a frame by its EtherType or length field and its payload, which is trimmed to
+    /// the length field. Returns `None` if the frame is not a control frame.
+    pub fn classify(ethertype: u16, payload: &[u8]) -> Option<ControlKind> {
+        if ethertype == ETHERTYPE_LLDP {
+            return Some(ControlKind::Lldp);
+        }
+        if ethertype > MAX_LENGTH {
+            return None;
+        }
+
+        let kind = match Llc::parse(payload) {
+            Some((Llc::Llc { dsap: SAP_STP, .. }, _)) => ControlKind::Stp,
+            Some((Llc::Snap { oui, protocol }, _)) if (oui, protocol) == SNAP_CDP => {
+                ControlKind::Cdp
+            }
+            _ => ControlKind::Llc,
+        };
+
+        Some(kind)
+    }
+
+    /// Get the name of the kind.
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            ControlKind::Stp => "stp",
+            ControlKind::Lldp => "lldp",
+            ControlKind::Cdp => "cdp",
+            ControlKind::Llc => "llc",
+        }
+    }
+}
+
+impl Display for ControlKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
 
 /// Represents an Ethernet layer.
 #[derive(Clone, Debug)]
@@ -31,6 +148,22 @@ impl Ethernet {
                     payload: vec![],
                 },
             }),
+            LayerTypes::Ipv6 => Some(Ethernet {
+                layer: ethernet::Ethernet {
+                    destination: dst,
+                    source: src,
+                    ethertype: EtherTypes::Ipv6,
+                    payload: vec![],
+                },
+            }),
+            LayerTypes::Vlan => Some(Ethernet {
+                layer: ethernet::Ethernet {
+                    destination: dst,
+                    source: src,
+                    ethertype: EtherTypes::Vlan,
+                    payload: vec![],
+                },
+            }),
             _ => None,
         }
     }
@@ -61,6 +194,28 @@ impl Ethernet {
     pub fn get_dst(&self) -> MacAddr {
         self.layer.destination
     }
+
+    /// Get the EtherType of the layer.
+    pub fn get_ethertype(&self) -> EtherType {
+        self.layer.ethertype
+    }
+
+    /// Get the length field of the layer if it is an IEEE 802.3 frame, whose payload is in LLC
+    /// instead of an EtherType.
+    pub fn get_length(&self) -> Option<usize> {
+        match self.layer.ethertype.0 {
+            length if length <= MAX_LENGTH => Some(length as usize),
+            _ => None,
+        }
+    }
+}
+
+/// Get the hardware address an IPv6 multicast address is mapped to, which is `33:33` followed by
+/// the last 4 octets of the address (RFC 2464).
+pub fn ipv6_multicast_hardware_addr(ip_addr: Ipv6Addr) -> MacAddr {
+    let octets = ip_addr.octets();
+
+    MacAddr::new(0x33, 0x33, octets[12], octets[13], octets[14], octets[15])
 }
 
 impl Display for Ethernet {
@@ -80,20 +235,57 @@ impl Layer for Ethernet {
         LayerTypes::Ethernet
     }
 
+    fn get_details(&self, verbosity: Verbosity) -> String {
+        let mut details = String::new();
+        if verbosity >= Verbosity::Normal {
+            match self.get_length() {
+                Some(length) => details.push_str(&format!(", Length = {}", length)),
+                None => details.push_str(&format!(", Type = {:#06x}", self.layer.ethertype.0)),
+            }
+        }
+
+        details
+    }
+
     fn get_size(&self) -> usize {
         EthernetPacket::packet_size(&self.layer)
     }
 
-    fn serialize(&self, buffer: &mut [u8], _: usize) -> io::Result<usize> {
-        let mut packet = MutableEthernetPacket::new(buffer)
-            .ok_or(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
+    fn serialize(&self, buffer: &mut [u8], _: usize) -> Result {
+        let required = self.get_size();
+        if buffer.len() < required {
+            return Err(SerializeError::BufferTooSmall {
+                t: self.get_type(),
+                required,
+                actual: buffer.len(),
+            });
+        }
+
+        let mut packet = MutableEthernetPacket::new(&mut buffer[..required]).unwrap();
 
         packet.populate(&self.layer);
 
         Ok(self.get_size())
     }
 
-    fn serialize_with_payload(&self, buffer: &mut [u8], _: &[u8], n: usize) -> io::Result<usize> {
+    fn serialize_with_payload(&self, buffer: &mut [u8], _: &[u8], n: usize) -> Result {
         self.serialize(buffer, n)
     }
 }
+
+impl LayerDeserialize for Ethernet {
+    fn deserialize(
+        buffer: &[u8],
+        _: &ParseContext,
+    ) -> result::Result<(Ethernet, usize), ParseError> {
+        let packet = EthernetPacket::new(buffer).ok_or(ParseError::BufferTooSmall {
+            t: LayerTypes::Ethernet,
+            required: EthernetPacket::minimum_packet_size(),
+            actual: buffer.len(),
+        })?;
+        let layer = Ethernet::parse(&packet);
+        let size = layer.get_size();
+
+        Ok((layer, size))
+    }
+}
@@ -0,0 +1,258 @@
+pub use super::{
+    Layer, LayerDeserialize, LayerType, LayerTypes, ParseContext, ParseError, Result,
+    SerializeError, Verbosity,
+};
+use pnet::packet::icmp::{self, IcmpCode, IcmpPacket, IcmpTypes, MutableIcmpPacket};
+use pnet::packet::Packet;
+use std::clone::Clone;
+use std::fmt::{self, Display, Formatter};
+use std::result;
+
+/// Represents the size of the rest of the header of an ICMPv4 packet.
+const REST_OF_HEADER_SIZE: usize = 4;
+
+/// Represents an ICMPv4 layer. The rest of the header, like the identifier and the sequence number
+/// in an echo, is kept in the front of the payload of the inner `Icmp`.
+#[derive(Clone, Debug)]
+pub struct Icmpv4 {
+    pub layer: icmp::Icmp,
+}
+
+impl Icmpv4 {
+    /// Creates an `Icmpv4` represents an ICMPv4 echo reply.
+    pub fn new_echo_reply(identifier: u16, sequence: u16, payload: &[u8]) -> Icmpv4 {
+        let mut buffer = vec![0u8; REST_OF_HEADER_SIZE + payload.len()];
+        buffer[0..2].copy_from_slice(&identifier.to_be_bytes());
+        buffer[2..4].copy_from_slice(&sequence.to_be_bytes());
+        buffer[REST_OF_HEADER_SIZE..].copy_from_slice(payload);
+
+        Icmpv4 {
+            layer: icmp::Icmp {
+                icmp_type: IcmpTypes::EchoReply,
+                icmp_code: IcmpCode(0),
+                checksum: 0,
+                payload: buffer,
+            },
+        }
+    }
+
+    /// Creates an `Icmpv4` represents an ICMPv4 destination unreachable. The payload should be the
+    /// IP header and the first 8 bytes of the original datagram.
+    pub fn new_destination_unreachable(code: u8, payload: &[u8]) -> Icmpv4 {
+        let mut buffer = vec![0u8; REST_OF_HEADER_SIZE + payload.len()];
+        buffer[REST_OF_HEADER_SIZE..].copy_from_slice(payload);
+
+        Icmpv4 {
+            layer: icmp::Icmp {
+                icmp_type: IcmpTypes::DestinationUnreachable,
+                icmp_code: IcmpCode(code),
+                checksum: 0,
+                payload: buffer,
+            },
+        }
+    }
+
+    /// Creates an `Icmpv4` represents an ICMPv4 fragmentation needed and DF set with the MTU of
+    /// the next hop (RFC 1191). The payload should be the IP header and the first 8 bytes of the
+    /// original datagram.
+    pub fn new_fragmentation_needed(mtu: u16, payload: &[u8]) -> Icmpv4 {
+        let mut buffer = vec![0u8; REST_OF_HEADER_SIZE + payload.len()];
+        buffer[2..4].copy_from_slice(&mtu.to_be_bytes());
+        buffer[REST_OF_HEADER_SIZE..].copy_from_slice(payload);
+
+        Icmpv4 {
+            layer: icmp::Icmp {
+                icmp_type: IcmpTypes::DestinationUnreachable,
+                icmp_code: IcmpCode(4),
+                checksum: 0,
+                payload: buffer,
+            },
+        }
+    }
+
+    /// Creates an `Icmpv4` represents an ICMPv4 time exceeded. The payload should be the IP header
+    /// and the first 8 bytes of the original datagram.
+    pub fn new_time_exceeded(code: u8, payload: &[u8]) -> Icmpv4 {
+        let mut buffer = vec![0u8; REST_OF_HEADER_SIZE + payload.len()];
+        buffer[REST_OF_HEADER_SIZE..].copy_from_slice(payload);
+
+        Icmpv4 {
+            layer: icmp::Icmp {
+                icmp_type: IcmpTypes::TimeExceeded,
+                icmp_code: IcmpCode(code),
+                checksum: 0,
+                payload: buffer,
+            },
+        }
+    }
+
+    /// Creates an `Icmpv4` according to the given `Icmp`.
+    pub fn from(icmp: icmp::Icmp) -> Icmpv4 {
+        Icmpv4 { layer: icmp }
+    }
+
+    /// Creates an `Icmpv4` according to the given ICMPv4 packet.
+    pub fn parse(packet: &IcmpPacket) -> Option<Icmpv4> {
+        if packet.payload().len() < REST_OF_HEADER_SIZE {
+            return None;
+        }
+
+        Some(Icmpv4 {
+            layer: icmp::Icmp {
+                icmp_type: packet.get_icmp_type(),
+                icmp_code: packet.get_icmp_code(),
+                checksum: packet.get_checksum(),
+                payload: packet.payload().to_vec(),
+            },
+        })
+    }
+
+    /// Get the identifier of the layer. The identifier is only meaningful in an echo.
+    pub fn get_identifier(&self) -> u16 {
+        u16::from_be_bytes([self.layer.payload[0], self.layer.payload[1]])
+    }
+
+    /// Get the sequence of the layer. The sequence is only meaningful in an echo.
+    pub fn get_sequence(&self) -> u16 {
+        u16::from_be_bytes([self.layer.payload[2], self.layer.payload[3]])
+    }
+
+    /// Get the payload of the layer, excluding the rest of the header.
+    pub fn get_payload(&self) -> &[u8] {
+        &self.layer.payload[REST_OF_HEADER_SIZE..]
+    }
+
+    /// Returns if the `Icmpv4` is an ICMPv4 echo request.
+    pub fn is_echo_request(&self) -> bool {
+        self.layer.icmp_type == IcmpTypes::EchoRequest
+    }
+
+    /// Returns if the `Icmpv4` is an ICMPv4 echo reply.
+    pub fn is_echo_reply(&self) -> bool {
+        self.layer.icmp_type == IcmpTypes::EchoReply
+    }
+}
+
+impl Display for Icmpv4 {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.layer.icmp_type {
+            IcmpTypes::EchoRequest | IcmpTypes::EchoReply => write!(
+                f,
+                "{}: {}, Identifier = {}, Sequence = {}",
+                LayerTypes::Icmpv4,
+                if self.is_echo_request() {
+                    "Echo Request"
+                } else {
+                    "Echo Reply"
+                },
+                self.get_identifier(),
+                self.get_sequence()
+            ),
+            _ => write!(
+                f,
+                "{}: Type = {}, Code = {}",
+                LayerTypes::Icmpv4,
+                self.layer.icmp_type.0,
+                self.layer.icmp_code.0
+            ),
+        }
+    }
+}
+
+impl Layer for Icmpv4 {
+    fn get_type(&self) -> LayerType {
+        LayerTypes::Icmpv4
+    }
+
+    fn get_details(&self, verbosity: Verbosity) -> String {
+        let mut details = String::new();
+        if verbosity >= Verbosity::Normal {
+            details.push_str(&format!(", Checksum = {:#06x}", self.layer.checksum));
+        }
+        if verbosity >= Verbosity::Verbose {
+            // Echoes are brief without the type and the code
+            if self.is_echo_request() || self.is_echo_reply() {
+                details.push_str(&format!(
+                    ", Type = {}, Code = {}",
+                    self.layer.icmp_type.0, self.layer.icmp_code.0
+                ));
+            }
+            details.push_str(&format!(", Payload = {} Bytes", self.layer.payload.len()));
+        }
+
+        details
+    }
+
+    fn get_size(&self) -> usize {
+        IcmpPacket::packet_size(&self.layer)
+    }
+
+    fn get_payload_size(&self) -> usize {
+        // The rest of the header is kept in the front of the payload
+        self.layer.payload.len().saturating_sub(REST_OF_HEADER_SIZE)
+    }
+
+    fn serialize(&self, buffer: &mut [u8], _: usize) -> Result {
+        let required = self.get_size();
+        if buffer.len() < required {
+            return Err(SerializeError::BufferTooSmall {
+                t: self.get_type(),
+                required,
+                actual: buffer.len(),
+            });
+        }
+
+        let mut packet = MutableIcmpPacket::new(&mut buffer[..required]).unwrap();
+
+        packet.populate(&self.layer);
+
+        // Compute checksum
+        let checksum = icmp::checksum(&packet.to_immutable());
+        packet.set_checksum(checksum);
+
+        Ok(self.get_size())
+    }
+
+    fn serialize_with_payload(&self, buffer: &mut [u8], payload: &[u8], _: usize) -> Result {
+        let size = self.get_size();
+        let required = size + payload.len();
+        if buffer.len() < required {
+            return Err(SerializeError::BufferTooSmall {
+                t: self.get_type(),
+                required,
+                actual: buffer.len(),
+            });
+        }
+
+        // Copies payload
+        buffer[size..size + payload.len()].copy_from_slice(payload);
+
+        let mut packet = MutableIcmpPacket::new(&mut buffer[..required]).unwrap();
+
+        packet.populate(&self.layer);
+
+        // Compute checksum
+        let checksum = icmp::checksum(&packet.to_immutable());
+        packet.set_checksum(checksum);
+
+        Ok(size + payload.len())
+    }
+}
+
+impl LayerDeserialize for Icmpv4 {
+    fn deserialize(buffer: &[u8], _: &ParseContext) -> result::Result<(Icmpv4, usize), ParseError> {
+        let required = IcmpPacket::minimum_packet_size() + REST_OF_HEADER_SIZE;
+        let packet = IcmpPacket::new(buffer).ok_or(ParseError::BufferTooSmall {
+            t: LayerTypes::Icmpv4,
+            required,
+            actual: buffer.len(),
+        })?;
+        let layer = Icmpv4::parse(&packet).ok_or(ParseError::BufferTooSmall {
+            t: LayerTypes::Icmpv4,
+            required,
+            actual: buffer.len(),
+        })?;
+
+        Ok((layer, buffer.len()))
+    }
+}
@@ -0,0 +1,464 @@
+use super::checksum;
+pub use super::{
+    Layer, LayerDeserialize, LayerType, LayerTypes, ParseContext, ParseError, Result,
+    SerializeError, Verbosity,
+};
+use pnet::packet::icmpv6::ndp::{NdpOptionTypes, NeighborAdvertFlags};
+use pnet::packet::icmpv6::{self, Icmpv6Code, Icmpv6Packet, Icmpv6Types, MutableIcmpv6Packet};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use std::clone::Clone;
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, Ipv6Addr};
+use std::result;
+
+/// Represents the size of the rest of the header of an ICMPv6 packet.
+const REST_OF_HEADER_SIZE: usize = 4;
+/// Represents the size of the rest of the header of a neighbor solicitation or advertisement,
+/// including the target address.
+const NEIGHBOR_HEADER_SIZE: usize = 20;
+/// Represents the size of the rest of the header of a router advertisement.
+const ROUTER_ADVERT_HEADER_SIZE: usize = 12;
+/// Represents the hop limit of NDP messages, which are never forwarded by routers (RFC 4861).
+pub const NDP_HOP_LIMIT: u8 = 255;
+/// Represents the flags of the prefix information of on-link and autonomous address
+/// configuration.
+const PREFIX_FLAGS: u8 = 0b1100_0000;
+/// Represents the valid lifetime in seconds of the prefix advertised.
+const PREFIX_VALID_LIFETIME: u32 = 86400;
+/// Represents the preferred lifetime in seconds of the prefix advertised.
+const PREFIX_PREFERRED_LIFETIME: u32 = 14400;
+/// Represents the hop limit sources are advised to send packets in.
+const ADVERTISED_HOP_LIMIT: u8 = 64;
+
+/// Represents the link-local all-nodes multicast address.
+pub const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+/// Represents the link-local all-routers multicast address.
+pub const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+
+/// Represents an ICMPv6 layer. The rest of the header, like the target address in a neighbor
+/// solicitation, and NDP options are kept in the front of the payload of the inner `Icmpv6`.
+#[derive(Clone, Debug)]
+pub struct Icmpv6 {
+    pub layer: icmpv6::Icmpv6,
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+}
+
+impl Icmpv6 {
+    /// Creates an `Icmpv6` represents an ICMPv6 echo reply.
+    pub fn new_echo_reply(
+        src: Ipv6Addr,
+        dst: Ipv6Addr,
+        identifier: u16,
+        sequence: u16,
+        payload: &[u8],
+    ) -> Icmpv6 {
+        let mut buffer = vec![0u8; REST_OF_HEADER_SIZE + payload.len()];
+        buffer[0..2].copy_from_slice(&identifier.to_be_bytes());
+        buffer[2..4].copy_from_slice(&sequence.to_be_bytes());
+        buffer[REST_OF_HEADER_SIZE..].copy_from_slice(payload);
+
+        Icmpv6::new(Icmpv6Types::EchoReply, src, dst, buffer)
+    }
+
+    /// Creates an `Icmpv6` represents a neighbor advertisement of the target at the hardware
+    /// address, which overrides the cached hardware address of the target.
+    pub fn new_neighbor_advert(
+        src: Ipv6Addr,
+        dst: Ipv6Addr,
+        target: Ipv6Addr,
+        hardware_addr: MacAddr,
+        is_router: bool,
+        is_solicited: bool,
+    ) -> Icmpv6 {
+        let mut flags = NeighborAdvertFlags::Override;
+        if is_router {
+            flags |= NeighborAdvertFlags::Router;
+        }
+        if is_solicited {
+            flags |= NeighborAdvertFlags::Solicited;
+        }
+
+        let mut buffer = vec![0u8; NEIGHBOR_HEADER_SIZE];
+        buffer[0] = flags;
+        buffer[4..20].copy_from_slice(&target.octets());
+        push_hardware_addr(&mut buffer, NdpOptionTypes::TargetLLAddr.0, hardware_addr);
+
+        Icmpv6::new(Icmpv6Types::NeighborAdvert, src, dst, buffer)
+    }
+
+    /// Creates an `Icmpv6` represents a router advertisement of a default router at the hardware
+    /// address, with the prefix for the stateless address autoconfiguration.
+    pub fn new_router_advert(
+        src: Ipv6Addr,
+        dst: Ipv6Addr,
+        hardware_addr: MacAddr,
+        lifetime: u16,
+        prefix: Ipv6Addr,
+        prefix_len: u8,
+    ) -> Icmpv6 {
+        let mut buffer = vec![0u8; ROUTER_ADVERT_HEADER_SIZE];
+        buffer[0] = ADVERTISED_HOP_LIMIT;
+        buffer[2..4].copy_from_slice(&lifetime.to_be_bytes());
+        push_hardware_addr(&mut buffer, NdpOptionTypes::SourceLLAddr.0, hardware_addr);
+
+        // Prefix information
+        buffer.extend_from_slice(&[NdpOptionTypes::PrefixInformation.0, 4, prefix_len]);
+        buffer.push(PREFIX_FLAGS);
+        buffer.extend_from_slice(&PREFIX_VALID_LIFETIME.to_be_bytes());
+        buffer.extend_from_slice(&PREFIX_PREFERRED_LIFETIME.to_be_bytes());
+        buffer.extend_from_slice(&[0u8; 4]);
+        buffer.extend_from_slice(&prefix.octets());
+
+        Icmpv6::new(Icmpv6Types::RouterAdvert, src, dst, buffer)
+    }
+
+    fn new(t: icmpv6::Icmpv6Type, src: Ipv6Addr, dst: Ipv6Addr, payload: Vec<u8>) -> Icmpv6 {
+        Icmpv6 {
+            layer: icmpv6::Icmpv6 {
+                icmpv6_type: t,
+                icmpv6_code: Icmpv6Code(0),
+                checksum: 0,
+                payload,
+            },
+            src,
+            dst,
+        }
+    }
+
+    /// Creates an `Icmpv6` according to the given `Icmpv6`, source and destination.
+    pub fn from(icmpv6: icmpv6::Icmpv6, src: Ipv6Addr, dst: Ipv6Addr) -> Icmpv6 {
+        Icmpv6 {
+            layer: icmpv6,
+            src,
+            dst,
+        }
+    }
+
+    /// Creates an `Icmpv6` according to the given ICMPv6 packet, source and destination.
+    pub fn parse(packet: &Icmpv6Packet, src: Ipv6Addr, dst: Ipv6Addr) -> Option<Icmpv6> {
+        if packet.payload().len() < REST_OF_HEADER_SIZE {
+            return None;
+        }
+
+        Some(Icmpv6 {
+            layer: icmpv6::Icmpv6 {
+                icmpv6_type: packet.get_icmpv6_type(),
+                icmpv6_code: packet.get_icmpv6_code(),
+                checksum: packet.get_checksum(),
+                payload: packet.payload().to_vec(),
+            },
+            src,
+            dst,
+        })
+    }
+
+    /// Get the source IP address of the layer.
+    pub fn get_src_ip_addr(&self) -> Ipv6Addr {
+        self.src
+    }
+
+    /// Get the destination IP address of the layer.
+    pub fn get_dst_ip_addr(&self) -> Ipv6Addr {
+        self.dst
+    }
+
+    /// Get the code of the layer.
+    pub fn get_code(&self) -> u8 {
+        self.layer.icmpv6_code.0
+    }
+
+    /// Get the identifier of the layer. The identifier is only meaningful in an echo.
+    pub fn get_identifier(&self) -> u16 {
+        u16::from_be_bytes([self.layer.payload[0], self.layer.payload[1]])
+    }
+
+    /// Get the sequence of the layer. The sequence is only meaningful in an echo.
+    pub fn get_sequence(&self) -> u16 {
+        u16::from_be_bytes([self.layer.payload[2], self.layer.payload[3]])
+    }
+
+    /// Get the payload of the layer, excluding the rest of the header.
+    pub fn get_payload(&self) -> &[u8] {
+        &self.layer.payload[REST_OF_HEADER_SIZE..]
+    }
+
+    /// Get the target address of the layer. The target is only meaningful in a neighbor
+    /// solicitation or advertisement.
+    pub fn get_target(&self) -> Option<Ipv6Addr> {
+        if !self.is_neighbor_solicit() && !self.is_neighbor_advert() {
+            return None;
+        }
+        let target = self.layer.payload.get(4..NEIGHBOR_HEADER_SIZE)?;
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(target);
+
+        Some(Ipv6Addr::from(octets))
+    }
+
+    /// Get the source link-layer address option of the layer, which is in a neighbor
+    /// solicitation or a router solicitation. Returns `None` if the option is absent or the
+    /// options are malformed.
+    pub fn get_src_hardware_addr(&self) -> Option<MacAddr> {
+        let offset = match self.layer.icmpv6_type {
+            Icmpv6Types::NeighborSolicit => NEIGHBOR_HEADER_SIZE,
+            Icmpv6Types::RouterSolicit => REST_OF_HEADER_SIZE,
+            _ => return None,
+        };
+        let mut options = self.layer.payload.get(offset..)?;
+        while options.len() >= 2 {
+            let length = options[1] as usize * 8;
+            if length == 0 || length > options.len() {
+                return None;
+            }
+            if options[0] == NdpOptionTypes::SourceLLAddr.0 && length >= 8 {
+                return Some(MacAddr::new(
+                    options[2], options[3], options[4], options[5], options[6], options[7],
+                ));
+            }
+            options = &options[length..];
+        }
+
+        None
+    }
+
+    /// Returns if the `Icmpv6` is an ICMPv6 echo request.
+    pub fn is_echo_request(&self) -> bool {
+        self.layer.icmpv6_type == Icmpv6Types::EchoRequest
+    }
+
+    /// Returns if the `Icmpv6` is an ICMPv6 echo reply.
+    pub fn is_echo_reply(&self) -> bool {
+        self.layer.icmpv6_type == Icmpv6Types::EchoReply
+    }
+
+    /// Returns if the `Icmpv6` is a router solicitation.
+    pub fn is_router_solicit(&self) -> bool {
+        self.layer.icmpv6_type == Icmpv6Types::RouterSolicit
+    }
+
+    /// Returns if the `Icmpv6` is a neighbor solicitation.
+    pub fn is_neighbor_solicit(&self) -> bool {
+        self.layer.icmpv6_type == Icmpv6Types::NeighborSolicit
+    }
+
+    /// Returns if the `Icmpv6` is a neighbor advertisement.
+    pub fn is_neighbor_advert(&self) -> bool {
+        self.layer.icmpv6_type == Icmpv6Types::NeighborAdvert
+    }
+
+    /// Returns if the checksum of the layer is valid.
+    pub fn verify_checksum(&self) -> bool {
+        // The sum of the pseudo-header, the header and the payload is all ones if the checksum is
+        // valid, so the layer is not serialized again
+        let length = (Icmpv6Packet::minimum_packet_size() + self.layer.payload.len()) as u32;
+        let mut sum = checksum::sum(&self.src.octets()) + checksum::sum(&self.dst.octets());
+        sum += IpNextHeaderProtocols::Icmpv6.0 as u32 + (length >> 16) + (length & 0xffff);
+        sum += u16::from_be_bytes([self.layer.icmpv6_type.0, self.layer.icmpv6_code.0]) as u32
+            + self.layer.checksum as u32;
+        sum += checksum::sum(&self.layer.payload);
+
+        checksum::fold(sum) == 0xffff
+    }
+}
+
+/// Appends a link-layer address option of the type to the NDP message.
+fn push_hardware_addr(buffer: &mut Vec<u8>, t: u8, hardware_addr: MacAddr) {
+    buffer.extend_from_slice(&[
+        t,
+        1,
+        hardware_addr.0,
+        hardware_addr.1,
+        hardware_addr.2,
+        hardware_addr.3,
+        hardware_addr.4,
+        hardware_addr.5,
+    ]);
+}
+
+/// Get the solicited-node multicast address of the address, which neighbor solicitations of
+/// the address are sent to (RFC 4291).
+pub fn solicited_node(ip_addr: Ipv6Addr) -> Ipv6Addr {
+    let octets = ip_addr.octets();
+
+    Ipv6Addr::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | octets[13] as u16,
+        u16::from_be_bytes([octets[14], octets[15]]),
+    )
+}
+
+/// Get the link-local address of the hardware address in the modified EUI-64 format (RFC 4291).
+pub fn link_local(hardware_addr: MacAddr) -> Ipv6Addr {
+    Ipv6Addr::new(
+        0xfe80,
+        0,
+        0,
+        0,
+        u16::from_be_bytes([hardware_addr.0 ^ 0x02, hardware_addr.1]),
+        u16::from_be_bytes([hardware_addr.2, 0xff]),
+        u16::from_be_bytes([0xfe, hardware_addr.3]),
+        u16::from_be_bytes([hardware_addr.4, hardware_addr.5]),
+    )
+}
+
+impl Display for Icmpv6 {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.layer.icmpv6_type {
+            Icmpv6Types::EchoRequest | Icmpv6Types::EchoReply => write!(
+                f,
+                "{}: {}, Identifier = {}, Sequence = {}",
+                LayerTypes::Icmpv6,
+                if self.is_echo_request() {
+                    "Echo Request"
+                } else {
+                    "Echo Reply"
+                },
+                self.get_identifier(),
+                self.get_sequence()
+            ),
+            Icmpv6Types::NeighborSolicit | Icmpv6Types::NeighborAdvert => match self.get_target() {
+                Some(target) => write!(
+                    f,
+                    "{}: {}, Target = {}",
+                    LayerTypes::Icmpv6,
+                    if self.is_neighbor_solicit() {
+                        "Neighbor Solicitation"
+                    } else {
+                        "Neighbor Advertisement"
+                    },
+                    target
+                ),
+                None => write!(
+                    f,
+                    "{}: Type = {}, Code = {}",
+                    LayerTypes::Icmpv6,
+                    self.layer.icmpv6_type.0,
+                    self.layer.icmpv6_code.0
+                ),
+            },
+            Icmpv6Types::RouterSolicit => {
+                write!(f, "{}: {}", LayerTypes::Icmpv6, "Router Solicitation")
+            }
+            Icmpv6Types::RouterAdvert => {
+                write!(f, "{}: {}", LayerTypes::Icmpv6, "Router Advertisement")
+            }
+            _ => write!(
+                f,
+                "{}: Type = {}, Code = {}",
+                LayerTypes::Icmpv6,
+                self.layer.icmpv6_type.0,
+                self.layer.icmpv6_code.0
+            ),
+        }
+    }
+}
+
+impl Layer for Icmpv6 {
+    fn get_type(&self) -> LayerType {
+        LayerTypes::Icmpv6
+    }
+
+    fn get_details(&self, verbosity: Verbosity) -> String {
+        let mut details = String::new();
+        if verbosity >= Verbosity::Normal {
+            details.push_str(&format!(", Checksum = {:#06x}", self.layer.checksum));
+        }
+        if verbosity >= Verbosity::Verbose {
+            details.push_str(&format!(
+                ", Type = {}, Code = {}, Payload = {} Bytes",
+                self.layer.icmpv6_type.0,
+                self.layer.icmpv6_code.0,
+                self.layer.payload.len()
+            ));
+        }
+
+        details
+    }
+
+    fn get_size(&self) -> usize {
+        Icmpv6Packet::packet_size(&self.layer)
+    }
+
+    fn serialize(&self, buffer: &mut [u8], _: usize) -> Result {
+        let required = self.get_size();
+        if buffer.len() < required {
+            return Err(SerializeError::BufferTooSmall {
+                t: self.get_type(),
+                required,
+                actual: buffer.len(),
+            });
+        }
+
+        let mut packet = MutableIcmpv6Packet::new(&mut buffer[..required]).unwrap();
+
+        packet.populate(&self.layer);
+
+        // Compute checksum
+        let checksum = icmpv6::checksum(&packet.to_immutable(), &self.src, &self.dst);
+        packet.set_checksum(checksum);
+
+        Ok(self.get_size())
+    }
+
+    fn serialize_with_payload(&self, buffer: &mut [u8], payload: &[u8], _: usize) -> Result {
+        let size = self.get_size();
+        let required = size + payload.len();
+        if buffer.len() < required {
+            return Err(SerializeError::BufferTooSmall {
+                t: self.get_type(),
+                required,
+                actual: buffer.len(),
+            });
+        }
+
+        // Copies payload
+        buffer[size..size + payload.len()].copy_from_slice(payload);
+
+        let mut packet = MutableIcmpv6Packet::new(&mut buffer[..required]).unwrap();
+
+        packet.populate(&self.layer);
+
+        // Compute checksum
+        let checksum = icmpv6::checksum(&packet.to_immutable(), &self.src, &self.dst);
+        packet.set_checksum(checksum);
+
+        Ok(size + payload.len())
+    }
+}
+
+impl LayerDeserialize for Icmpv6 {
+    fn deserialize(
+        buffer: &[u8],
+        context: &ParseContext,
+    ) -> result::Result<(Icmpv6, usize), ParseError> {
+        let (src, dst) = match context.get_ip_addrs(LayerTypes::Icmpv6)? {
+            (IpAddr::V6(src), IpAddr::V6(dst)) => (src, dst),
+            _ => {
+                return Err(ParseError::MissingContext {
+                    t: LayerTypes::Icmpv6,
+                })
+            }
+        };
+        let required = Icmpv6Packet::minimum_packet_size() + REST_OF_HEADER_SIZE;
+        let packet = Icmpv6Packet::new(buffer).ok_or(ParseError::BufferTooSmall {
+            t: LayerTypes::Icmpv6,
+            required,
+            actual: buffer.len(),
+        })?;
+        let layer = Icmpv6::parse(&packet, src, dst).ok_or(ParseError::BufferTooSmall {
+            t: LayerTypes::Icmpv6,
+            required,
+            actual: buffer.len(),
+        })?;
+
+        Ok((layer, buffer.len()))
+    }
+}
@@ -1,15 +1,59 @@
-pub use super::{Layer, LayerType, LayerTypes};
-use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4OptionPacket, Ipv4Packet, MutableIpv4Packet};
+use super::checksum;
+pub use super::{
+    Layer, LayerDeserialize, LayerType, LayerTypes, ParseContext, ParseError, Result,
+    SerializeError, Verbosity,
+};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{
+    self, Ipv4Flags, Ipv4OptionPacket, Ipv4Packet, MutableIpv4OptionPacket, MutableIpv4Packet,
+};
+use pnet::packet::MutablePacket;
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
-use std::io;
 use std::net::Ipv4Addr;
+use std::result;
+
+/// Represents the ECN codepoint of ECN capable transport ECT(0).
+pub const ECN_ECT0: u8 = 2;
+/// Represents the ECN codepoint of congestion experienced.
+pub const ECN_CE: u8 = 3;
+
+/// Represents the max size of an IPv4 header with options.
+const MAX_HEADER_SIZE: usize = 60;
+/// Represents the offset of the TTL in an IPv4 header.
+const TTL_OFFSET: usize = 8;
+/// Represents the offset of the checksum in an IPv4 header.
+const CHECKSUM_OFFSET: usize = 10;
+
+/// Decreases the TTL of the IPv4 packet in the buffer by 1, and updates the checksum
+/// incrementally. Returns `false` without changing the packet if the TTL is exceeded.
+pub fn decrease_ttl(buffer: &mut [u8]) -> bool {
+    if buffer.len() < CHECKSUM_OFFSET + 2 || buffer[TTL_OFFSET] <= 1 {
+        return false;
+    }
+
+    // The TTL shares a 16-bit word with the protocol
+    let prev_word = u16::from_be_bytes([buffer[TTL_OFFSET], buffer[TTL_OFFSET + 1]]);
+    buffer[TTL_OFFSET] -= 1;
+    let word = u16::from_be_bytes([buffer[TTL_OFFSET], buffer[TTL_OFFSET + 1]]);
+    let checksum = u16::from_be_bytes([buffer[CHECKSUM_OFFSET], buffer[CHECKSUM_OFFSET + 1]]);
+    let checksum = checksum::update(checksum, prev_word, word);
+    buffer[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 2].copy_from_slice(&checksum.to_be_bytes());
+
+    true
+}
+
+/// Returns if the TTL of the IPv4 packet in the buffer is exceeded if it is forwarded.
+pub fn is_ttl_exceeded(buffer: &[u8]) -> bool {
+    buffer.len() <= TTL_OFFSET || buffer[TTL_OFFSET] <= 1
+}
 
 /// Represents an IPv4 layer.
 #[derive(Clone, Debug)]
 pub struct Ipv4 {
     layer: ipv4::Ipv4,
+    /// Represents the raw options, which are serialized verbatim
+    options: Vec<u8>,
 }
 
 impl Ipv4 {
@@ -18,6 +62,7 @@ impl Ipv4 {
         let next_level_protocol = match t {
             LayerTypes::Tcp => IpNextHeaderProtocols::Tcp,
             LayerTypes::Udp => IpNextHeaderProtocols::Udp,
+            LayerTypes::Icmpv4 => IpNextHeaderProtocols::Icmp,
             _ => return None,
         };
         Some(Ipv4 {
@@ -38,6 +83,7 @@ impl Ipv4 {
                 options: vec![],
                 payload: vec![],
             },
+            options: vec![],
         })
     }
 
@@ -77,11 +123,29 @@ impl Ipv4 {
     }
 
     /// Creates an `Ipv4` according to the given `Ipv4`.
-    pub fn from(ipv4: ipv4::Ipv4) -> Ipv4 {
-        Ipv4 { layer: ipv4 }
+    pub fn from(mut ipv4: ipv4::Ipv4) -> Ipv4 {
+        let size = ipv4
+            .options
+            .iter()
+            .map(|option| Ipv4OptionPacket::packet_size(option))
+            .sum();
+        let mut options = vec![0u8; size];
+        let mut begin = 0;
+        for option in ipv4.options.drain(..) {
+            let end = begin + Ipv4OptionPacket::packet_size(&option);
+            MutableIpv4OptionPacket::new(&mut options[begin..end])
+                .unwrap()
+                .populate(&option);
+            begin = end;
+        }
+
+        Ipv4 {
+            layer: ipv4,
+            options,
+        }
     }
 
-    /// Creates an `Ipv4` according to the given IPv4 packet.
+    /// Creates an `Ipv4` according to the given IPv4 packet. The options are kept in raw bytes.
     pub fn parse(packet: &Ipv4Packet) -> Ipv4 {
         Ipv4 {
             layer: ipv4::Ipv4 {
@@ -98,9 +162,10 @@ impl Ipv4 {
                 checksum: packet.get_checksum(),
                 source: packet.get_source(),
                 destination: packet.get_destination(),
-                options: packet.get_options(),
+                options: vec![],
                 payload: vec![],
             },
+            options: packet.get_options_raw().to_vec(),
         }
     }
 
@@ -121,12 +186,70 @@ impl Ipv4 {
                 checksum: 0,
                 source: ipv4.get_src(),
                 destination: ipv4.get_dst(),
-                options: ipv4.layer.options.clone(),
+                options: vec![],
                 payload: vec![],
             },
+            options: ipv4.options.clone(),
+        }
+    }
+
+    /// Creates an `Ipv4` represents an IPv4 fragment according to an `Ipv4`. The fragment offset is
+    /// in units of 8 Bytes.
+    pub fn fragment(ipv4: &Ipv4, fragment_offset: u16, is_more_fragment: bool) -> Ipv4 {
+        let mut layer = ipv4.layer.clone();
+        layer.flags = match is_more_fragment {
+            true => Ipv4Flags::MoreFragments,
+            false => 0,
+        };
+        layer.fragment_offset = fragment_offset;
+        layer.checksum = 0;
+
+        // Options are not copied as no option is used in outbound packets
+        Ipv4 {
+            layer,
+            options: vec![],
+        }
+    }
+
+    /// Creates an `Ipv4` represents a segment split from a packet coalesced by segmentation
+    /// offload according to an `Ipv4`. The identification is advanced by the index of the segment
+    /// as the NIC does.
+    pub fn segment(ipv4: &Ipv4, index: u16) -> Ipv4 {
+        let mut layer = ipv4.layer.clone();
+        layer.identification = layer.identification.wrapping_add(index);
+        layer.checksum = 0;
+
+        Ipv4 {
+            layer,
+            options: ipv4.options.clone(),
         }
     }
 
+    /// Get the DSCP of the layer.
+    pub fn get_dscp(&self) -> u8 {
+        self.layer.dscp
+    }
+
+    /// Sets the DSCP of the layer.
+    pub fn set_dscp(&mut self, dscp: u8) {
+        self.layer.dscp = dscp & 0x3f;
+    }
+
+    /// Get the ECN codepoint of the layer.
+    pub fn get_ecn(&self) -> u8 {
+        self.layer.ecn
+    }
+
+    /// Sets the ECN codepoint of the layer.
+    pub fn set_ecn(&mut self, ecn: u8) {
+        self.layer.ecn = ecn & 0x3;
+    }
+
+    /// Get the TTL of the layer.
+    pub fn get_ttl(&self) -> u8 {
+        self.layer.ttl
+    }
+
     /// Get the total length of the layer.
     pub fn get_total_length(&self) -> u16 {
         self.layer.total_length
@@ -137,6 +260,16 @@ impl Ipv4 {
         self.layer.identification
     }
 
+    /// Returns if the `Ipv4` should not be fragmented.
+    pub fn is_dont_fragment(&self) -> bool {
+        self.layer.flags & Ipv4Flags::DontFragment != 0
+    }
+
+    /// Sets the layer should not be fragmented.
+    pub fn set_dont_fragment(&mut self) {
+        self.layer.flags |= Ipv4Flags::DontFragment;
+    }
+
     /// Returns if more fragments are follows this `Ipv4`.
     pub fn is_more_fragment(&self) -> bool {
         self.layer.flags & Ipv4Flags::MoreFragments != 0
@@ -152,6 +285,11 @@ impl Ipv4 {
         self.is_more_fragment() || self.get_fragment_offset() > 0
     }
 
+    /// Get the next level protocol of the layer.
+    pub fn get_next_level_protocol(&self) -> IpNextHeaderProtocol {
+        self.layer.next_level_protocol
+    }
+
     /// Get the source of the layer.
     pub fn get_src(&self) -> Ipv4Addr {
         self.layer.source
@@ -161,6 +299,11 @@ impl Ipv4 {
     pub fn get_dst(&self) -> Ipv4Addr {
         self.layer.destination
     }
+
+    /// Get the raw options of the layer.
+    pub fn get_options(&self) -> &[u8] {
+        &self.options
+    }
 }
 
 impl Display for Ipv4 {
@@ -187,34 +330,83 @@ impl Layer for Ipv4 {
         LayerTypes::Ipv4
     }
 
-    fn get_size(&self) -> usize {
-        let mut ipv4_size = Ipv4Packet::packet_size(&self.layer);
-        let mut ipv4_options_size = 0;
-        for option in &self.layer.options {
-            ipv4_size -= 1;
-            ipv4_options_size += Ipv4OptionPacket::packet_size(option);
+    fn get_details(&self, verbosity: Verbosity) -> String {
+        let mut details = String::new();
+        if verbosity >= Verbosity::Normal {
+            let mut flags = Vec::new();
+            if self.is_dont_fragment() {
+                flags.push("DF");
+            }
+            if self.is_more_fragment() {
+                flags.push("MF");
+            }
+            details.push_str(&format!(
+                ", Identification = {:#06x}, TTL = {}, Flags = [{}], Checksum = {:#06x}",
+                self.layer.identification,
+                self.layer.ttl,
+                flags.join(" "),
+                self.layer.checksum
+            ));
+        }
+        if verbosity >= Verbosity::Verbose {
+            details.push_str(&format!(
+                ", DSCP = {}, ECN = {}, Protocol = {}, Options = {} Bytes",
+                self.layer.dscp,
+                self.layer.ecn,
+                self.layer.next_level_protocol.0,
+                self.options.len()
+            ));
         }
 
-        ipv4_size + ipv4_options_size
+        details
+    }
+
+    fn get_size(&self) -> usize {
+        // Options are padded to 32-bit words
+        Ipv4Packet::minimum_packet_size() + (self.options.len() + 3) / 4 * 4
     }
 
-    fn serialize(&self, buffer: &mut [u8], n: usize) -> io::Result<usize> {
-        let mut packet = MutableIpv4Packet::new(buffer)
-            .ok_or(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
+    fn serialize(&self, buffer: &mut [u8], n: usize) -> Result {
+        let required = self.get_size();
+        if buffer.len() < required {
+            return Err(SerializeError::BufferTooSmall {
+                t: self.get_type(),
+                required,
+                actual: buffer.len(),
+            });
+        }
+
+        let mut packet = MutableIpv4Packet::new(&mut buffer[..required]).unwrap();
 
         packet.populate(&self.layer);
 
-        // Fix length
+        // Copy options, and pad them with the end of option list
         let header_length = self.get_size();
-        if header_length / 4 > u8::MAX as usize {
-            return Err(io::Error::new(io::ErrorKind::Other, "IPv4 too big"));
+        let options = &mut packet.packet_mut()[Ipv4Packet::minimum_packet_size()..header_length];
+        options[..self.options.len()].copy_from_slice(&self.options);
+        for b in &mut options[self.options.len()..] {
+            *b = 0;
+        }
+
+        // Fix length
+        if header_length > MAX_HEADER_SIZE {
+            return Err(SerializeError::HeaderTooLarge {
+                t: self.get_type(),
+                size: header_length,
+            });
         }
         packet.set_header_length((header_length / 4) as u8);
+        debug_assert!(
+            n >= header_length,
+            "serialize {} of {} Bytes",
+            self.get_type(),
+            n
+        );
         if n > u16::MAX as usize {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "length too big",
-            ));
+            return Err(SerializeError::PayloadTooLarge {
+                t: self.get_type(),
+                length: n,
+            });
         }
         packet.set_total_length(n as u16);
 
@@ -225,7 +417,53 @@ impl Layer for Ipv4 {
         Ok(header_length)
     }
 
-    fn serialize_with_payload(&self, buffer: &mut [u8], _: &[u8], n: usize) -> io::Result<usize> {
+    fn serialize_with_payload(&self, buffer: &mut [u8], _: &[u8], n: usize) -> Result {
         self.serialize(buffer, n)
     }
 }
+
+impl LayerDeserialize for Ipv4 {
+    fn deserialize(buffer: &[u8], _: &ParseContext) -> result::Result<(Ipv4, usize), ParseError> {
+        let packet = Ipv4Packet::new(buffer).ok_or(ParseError::BufferTooSmall {
+            t: LayerTypes::Ipv4,
+            required: Ipv4Packet::minimum_packet_size(),
+            actual: buffer.len(),
+        })?;
+        if packet.get_version() != 4 {
+            return Err(ParseError::InvalidVersion {
+                t: LayerTypes::Ipv4,
+                version: packet.get_version(),
+            });
+        }
+        let header_length = packet.get_header_length() as usize * 4;
+        if header_length < Ipv4Packet::minimum_packet_size() || header_length > buffer.len() {
+            return Err(ParseError::InvalidHeaderLength {
+                t: LayerTypes::Ipv4,
+                length: header_length,
+            });
+        }
+        let total_length = packet.get_total_length() as usize;
+        if total_length < header_length || total_length > buffer.len() {
+            return Err(ParseError::InvalidLength {
+                t: LayerTypes::Ipv4,
+                length: total_length,
+            });
+        }
+        // A fragment never extends the datagram beyond the max size
+        let end = packet.get_fragment_offset() as usize * 8 + total_length;
+        if end > u16::MAX as usize {
+            return Err(ParseError::InvalidLength {
+                t: LayerTypes::Ipv4,
+                length: end,
+            });
+        }
+        // The option number takes the lower 5 bits of the option type
+        super::validate_options(
+            LayerTypes::Ipv4,
+            &buffer[Ipv4Packet::minimum_packet_size()..header_length],
+            0x1f,
+        )?;
+
+        Ok((Ipv4::parse(&packet), header_length))
+    }
+}
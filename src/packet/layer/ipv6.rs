@@ -0,0 +1,197 @@
+pub use super::{
+    Layer, LayerDeserialize, LayerType, LayerTypes, ParseContext, ParseError, Result,
+    SerializeError, Verbosity,
+};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv6::{self, Ipv6Packet, MutableIpv6Packet};
+use std::clone::Clone;
+use std::fmt::{self, Display, Formatter};
+use std::net::Ipv6Addr;
+use std::result;
+
+/// Represents an IPv6 layer.
+#[derive(Clone, Debug)]
+pub struct Ipv6 {
+    layer: ipv6::Ipv6,
+}
+
+impl Ipv6 {
+    /// Creates an `Ipv6`.
+    pub fn new(t: LayerType, src: Ipv6Addr, dst: Ipv6Addr) -> Option<Ipv6> {
+        let next_header = match t {
+            LayerTypes::Tcp => IpNextHeaderProtocols::Tcp,
+            LayerTypes::Udp => IpNextHeaderProtocols::Udp,
+            LayerTypes::Icmpv6 => IpNextHeaderProtocols::Icmpv6,
+            _ => return None,
+        };
+        Some(Ipv6 {
+            layer: ipv6::Ipv6 {
+                version: 6,
+                traffic_class: 0,
+                flow_label: 0,
+                payload_length: 0,
+                next_header,
+                hop_limit: 128,
+                source: src,
+                destination: dst,
+                payload: vec![],
+            },
+        })
+    }
+
+    /// Creates an `Ipv6` according to the given `Ipv6`.
+    pub fn from(ipv6: ipv6::Ipv6) -> Ipv6 {
+        Ipv6 { layer: ipv6 }
+    }
+
+    /// Creates an `Ipv6` according to the given IPv6 packet.
+    pub fn parse(packet: &Ipv6Packet) -> Ipv6 {
+        Ipv6 {
+            layer: ipv6::Ipv6 {
+                version: packet.get_version(),
+                traffic_class: packet.get_traffic_class(),
+                flow_label: packet.get_flow_label(),
+                payload_length: packet.get_payload_length(),
+                next_header: packet.get_next_header(),
+                hop_limit: packet.get_hop_limit(),
+                source: packet.get_source(),
+                destination: packet.get_destination(),
+                payload: vec![],
+            },
+        }
+    }
+
+    /// Sets the hop limit of the layer.
+    pub fn set_hop_limit(&mut self, hop_limit: u8) {
+        self.layer.hop_limit = hop_limit;
+    }
+
+    /// Get the payload length of the layer.
+    pub fn get_payload_length(&self) -> u16 {
+        self.layer.payload_length
+    }
+
+    /// Get the hop limit of the layer.
+    pub fn get_hop_limit(&self) -> u8 {
+        self.layer.hop_limit
+    }
+
+    /// Get the next header of the layer.
+    pub fn get_next_header(&self) -> IpNextHeaderProtocol {
+        self.layer.next_header
+    }
+
+    /// Get the source of the layer.
+    pub fn get_src(&self) -> Ipv6Addr {
+        self.layer.source
+    }
+
+    /// Get the destination of the layer.
+    pub fn get_dst(&self) -> Ipv6Addr {
+        self.layer.destination
+    }
+}
+
+impl Display for Ipv6 {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {}, Length = {}",
+            LayerTypes::Ipv6,
+            self.layer.source,
+            self.layer.destination,
+            self.layer.payload_length
+        )
+    }
+}
+
+impl Layer for Ipv6 {
+    fn get_type(&self) -> LayerType {
+        LayerTypes::Ipv6
+    }
+
+    fn get_details(&self, verbosity: Verbosity) -> String {
+        let mut details = String::new();
+        if verbosity >= Verbosity::Normal {
+            details.push_str(&format!(
+                ", Next Header = {}, Hop Limit = {}",
+                self.layer.next_header.0, self.layer.hop_limit
+            ));
+        }
+        if verbosity >= Verbosity::Verbose {
+            details.push_str(&format!(
+                ", Traffic Class = {}, Flow Label = {:#07x}",
+                self.layer.traffic_class, self.layer.flow_label
+            ));
+        }
+
+        details
+    }
+
+    fn get_size(&self) -> usize {
+        Ipv6Packet::packet_size(&self.layer)
+    }
+
+    fn serialize(&self, buffer: &mut [u8], n: usize) -> Result {
+        let required = self.get_size();
+        if buffer.len() < required {
+            return Err(SerializeError::BufferTooSmall {
+                t: self.get_type(),
+                required,
+                actual: buffer.len(),
+            });
+        }
+
+        let mut packet = MutableIpv6Packet::new(&mut buffer[..required]).unwrap();
+
+        packet.populate(&self.layer);
+
+        // Fix length
+        let header_length = self.get_size();
+        let payload_length =
+            n.checked_sub(header_length)
+                .ok_or(SerializeError::LengthTooSmall {
+                    t: self.get_type(),
+                    length: n,
+                })?;
+        if payload_length > u16::MAX as usize {
+            return Err(SerializeError::PayloadTooLarge {
+                t: self.get_type(),
+                length: payload_length,
+            });
+        }
+        packet.set_payload_length(payload_length as u16);
+
+        Ok(header_length)
+    }
+
+    fn serialize_with_payload(&self, buffer: &mut [u8], _: &[u8], n: usize) -> Result {
+        self.serialize(buffer, n)
+    }
+}
+
+impl LayerDeserialize for Ipv6 {
+    fn deserialize(buffer: &[u8], _: &ParseContext) -> result::Result<(Ipv6, usize), ParseError> {
+        let packet = Ipv6Packet::new(buffer).ok_or(ParseError::BufferTooSmall {
+            t: LayerTypes::Ipv6,
+            required: Ipv6Packet::minimum_packet_size(),
+            actual: buffer.len(),
+        })?;
+        if packet.get_version() != 6 {
+            return Err(ParseError::InvalidVersion {
+                t: LayerTypes::Ipv6,
+                version: packet.get_version(),
+            });
+        }
+        let header_length = Ipv6Packet::minimum_packet_size();
+        let payload_length = packet.get_payload_length() as usize;
+        if header_length + payload_length > buffer.len() {
+            return Err(ParseError::InvalidLength {
+                t: LayerTypes::Ipv6,
+                length: payload_length,
+            });
+        }
+
+        Ok((Ipv6::parse(&packet), header_length))
+    }
+}
@@ -1,14 +1,26 @@
 use std::clone::Clone;
 use std::cmp::{Eq, PartialEq};
+use std::error::Error;
 use std::fmt::{self, Display, Formatter};
 use std::hash::Hash;
 use std::io;
+use std::net::IpAddr;
+use std::result;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use payload::Frame;
 
 pub mod arp;
+pub mod checksum;
 pub mod ethernet;
+pub mod icmpv4;
+pub mod icmpv6;
 pub mod ipv4;
+pub mod ipv6;
+pub mod payload;
 pub mod tcp;
 pub mod udp;
+pub mod vlan;
 
 /// Represents the type of the layer.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -23,8 +35,12 @@ impl Display for LayerType {
                 LayerTypes::Ethernet => "Ethernet",
                 LayerTypes::Arp => "ARP",
                 LayerTypes::Ipv4 => "IPv4",
+                LayerTypes::Ipv6 => "IPv6",
                 LayerTypes::Tcp => "TCP",
                 LayerTypes::Udp => "UDP",
+                LayerTypes::Icmpv4 => "ICMPv4",
+                LayerTypes::Vlan => "VLAN",
+                LayerTypes::Icmpv6 => "ICMPv6",
                 _ => "unknown",
             }
         )
@@ -42,10 +58,277 @@ pub mod LayerTypes {
     pub const Arp: LayerType = LayerType(1);
     // IPv4
     pub const Ipv4: LayerType = LayerType(2);
+    // IPv6
+    pub const Ipv6: LayerType = LayerType(3);
     // TCP
-    pub const Tcp: LayerType = LayerType(3);
+    pub const Tcp: LayerType = LayerType(4);
     // UDP
-    pub const Udp: LayerType = LayerType(4);
+    pub const Udp: LayerType = LayerType(5);
+    // ICMPv4
+    pub const Icmpv4: LayerType = LayerType(6);
+    // VLAN
+    pub const Vlan: LayerType = LayerType(7);
+    // ICMPv6
+    pub const Icmpv6: LayerType = LayerType(8);
+}
+
+/// Represents an error when serialize a layer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SerializeError {
+    BufferTooSmall {
+        t: LayerType,
+        required: usize,
+        actual: usize,
+    },
+    HeaderTooLarge {
+        t: LayerType,
+        size: usize,
+    },
+    LengthTooSmall {
+        t: LayerType,
+        length: usize,
+    },
+    PayloadTooLarge {
+        t: LayerType,
+        length: usize,
+    },
+    IpVersionMismatch {
+        t: LayerType,
+    },
+}
+
+impl SerializeError {
+    /// Get the type of the layer which causes the error.
+    pub fn get_type(&self) -> LayerType {
+        match *self {
+            SerializeError::BufferTooSmall { t, .. } => t,
+            SerializeError::HeaderTooLarge { t, .. } => t,
+            SerializeError::LengthTooSmall { t, .. } => t,
+            SerializeError::PayloadTooLarge { t, .. } => t,
+            SerializeError::IpVersionMismatch { t } => t,
+        }
+    }
+}
+
+impl Display for SerializeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            SerializeError::BufferTooSmall {
+                t,
+                required,
+                actual,
+            } => write!(
+                f,
+                "serialize {}: buffer too small, require {} Bytes but {} Bytes",
+                t, required, actual
+            ),
+            SerializeError::HeaderTooLarge { t, size } => {
+                write!(f, "serialize {}: header too large ({} Bytes)", t, size)
+            }
+            SerializeError::LengthTooSmall { t, length } => {
+                write!(f, "serialize {}: length too small ({} Bytes)", t, length)
+            }
+            SerializeError::PayloadTooLarge { t, length } => {
+                write!(f, "serialize {}: length too large ({} Bytes)", t, length)
+            }
+            SerializeError::IpVersionMismatch { t } => {
+                write!(f, "serialize {}: IP version mismatch", t)
+            }
+        }
+    }
+}
+
+impl Error for SerializeError {}
+
+impl From<SerializeError> for io::Error {
+    fn from(e: SerializeError) -> Self {
+        let kind = match e {
+            SerializeError::BufferTooSmall { .. } => io::ErrorKind::WriteZero,
+            _ => io::ErrorKind::InvalidInput,
+        };
+
+        io::Error::new(kind, e)
+    }
+}
+
+pub type Result = result::Result<usize, SerializeError>;
+
+/// Represents an error when deserialize a layer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    BufferTooSmall {
+        t: LayerType,
+        required: usize,
+        actual: usize,
+    },
+    InvalidVersion {
+        t: LayerType,
+        version: u8,
+    },
+    InvalidHeaderLength {
+        t: LayerType,
+        length: usize,
+    },
+    InvalidLength {
+        t: LayerType,
+        length: usize,
+    },
+    InvalidOption {
+        t: LayerType,
+    },
+    MissingContext {
+        t: LayerType,
+    },
+    StackedTag {
+        t: LayerType,
+    },
+}
+
+impl ParseError {
+    /// Get the type of the layer which causes the error.
+    pub fn get_type(&self) -> LayerType {
+        match *self {
+            ParseError::BufferTooSmall { t, .. } => t,
+            ParseError::InvalidVersion { t, .. } => t,
+            ParseError::InvalidHeaderLength { t, .. } => t,
+            ParseError::InvalidLength { t, .. } => t,
+            ParseError::InvalidOption { t } => t,
+            ParseError::MissingContext { t } => t,
+            ParseError::StackedTag { t } => t,
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            ParseError::BufferTooSmall {
+                t,
+                required,
+                actual,
+            } => write!(
+                f,
+                "parse {}: buffer too small, require {} Bytes but {} Bytes",
+                t, required, actual
+            ),
+            ParseError::InvalidVersion { t, version } => {
+                write!(f, "parse {}: invalid version {}", t, version)
+            }
+            ParseError::InvalidHeaderLength { t, length } => {
+                write!(f, "parse {}: invalid header length ({} Bytes)", t, length)
+            }
+            ParseError::InvalidLength { t, length } => {
+                write!(f, "parse {}: invalid length ({} Bytes)", t, length)
+            }
+            ParseError::InvalidOption { t } => write!(f, "parse {}: invalid option", t),
+            ParseError::MissingContext { t } => {
+                write!(f, "parse {}: missing source and destination", t)
+            }
+            ParseError::StackedTag { t } => write!(f, "parse {}: stacked tags not supported", t),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Represents the context when deserialize a layer, like the source and destination IP address
+/// used in the pseudo header of TCP and UDP, and the frame shared by the payloads.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ParseContext {
+    pub src_ip_addr: Option<IpAddr>,
+    pub dst_ip_addr: Option<IpAddr>,
+    pub frame: Option<Frame>,
+}
+
+impl ParseContext {
+    /// Creates an empty `ParseContext`.
+    pub fn new() -> ParseContext {
+        ParseContext::default()
+    }
+
+    /// Creates a `ParseContext` with the given source and destination IP address.
+    pub fn with_ip_addrs(src: IpAddr, dst: IpAddr) -> ParseContext {
+        ParseContext {
+            src_ip_addr: Some(src),
+            dst_ip_addr: Some(dst),
+            frame: None,
+        }
+    }
+
+    /// Sets the frame the buffer being deserialized is in, so payloads refer to the frame.
+    pub fn with_frame(mut self, frame: Option<&Frame>) -> ParseContext {
+        self.frame = frame.cloned();
+        self
+    }
+
+    /// Get the source and destination IP address of the context.
+    pub fn get_ip_addrs(&self, t: LayerType) -> result::Result<(IpAddr, IpAddr), ParseError> {
+        match (self.src_ip_addr, self.dst_ip_addr) {
+            (Some(src), Some(dst)) => Ok((src, dst)),
+            _ => Err(ParseError::MissingContext { t }),
+        }
+    }
+}
+
+/// Validates the options of an IPv4 or TCP header, all options other than the end of option list
+/// and the no operation must have a length covers its own type and length fields.
+fn validate_options(
+    t: LayerType,
+    options: &[u8],
+    number_mask: u8,
+) -> result::Result<(), ParseError> {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] & number_mask {
+            0 | 1 => i = i + 1,
+            _ => {
+                let length = match options.get(i + 1) {
+                    Some(length) => *length as usize,
+                    None => return Err(ParseError::InvalidOption { t }),
+                };
+                if length < 2 || i + length > options.len() {
+                    return Err(ParseError::InvalidOption { t });
+                }
+                i = i + length;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Represents the verbosity of summaries of layers.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    /// Represents the addresses and the key fields, which is the display of the layer
+    Brief,
+    /// Represents additionally flags, checksums and identifiers
+    Normal,
+    /// Represents all the fields
+    Verbose,
+}
+
+/// Represents the verbosity of summaries of packets in logs.
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Brief as u8);
+
+/// Sets the verbosity of summaries of packets in logs.
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+/// Get the verbosity of summaries of packets in logs.
+pub fn get_verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Brief,
+        1 => Verbosity::Normal,
+        _ => Verbosity::Verbose,
+    }
 }
 
 /// Represents a layer.
@@ -53,19 +336,53 @@ pub trait Layer: Display {
     // Get the type of the `Layer`.
     fn get_type(&self) -> LayerType;
 
-    // Get The size of the `Layer` when converted into a byte-array.
+    // Get the fields of the `Layer` beyond its display at the verbosity, each starting with `, `.
+    // Fields are empty in the brief verbosity.
+    fn get_details(&self, verbosity: Verbosity) -> String;
+
+    // Get the summary of the `Layer` at the verbosity.
+    fn summary(&self, verbosity: Verbosity) -> String {
+        format!("{}{}", self, self.get_details(verbosity))
+    }
+
+    // Get the exact size of the `Layer` when converted into a byte-array, including its options,
+    // its padding and the payload it owns, which is the size `serialize` writes.
     fn get_size(&self) -> usize;
 
-    // Serialize the `Layer` into a byte-array.
-    fn serialize(&self, buffer: &mut [u8], n: usize) -> io::Result<usize>;
+    // Get the size of the payload the `Layer` owns, which is included in `get_size`.
+    fn get_payload_size(&self) -> usize {
+        0
+    }
+
+    // Serialize the `Layer` into a byte-array, returns `get_size`. `n` is the size of the `Layer`
+    // and the layers after it, which is only used by layers whose length fields cover the layers
+    // after them, and it must be consistent with `get_size`.
+    fn serialize(&self, buffer: &mut [u8], n: usize) -> Result;
+
+    // Serialize the `Layer` into a byte-array with payload, which replaces the payload the `Layer`
+    // owns. Returns `get_size` without the payload it owns plus the size of the payload if the
+    // `Layer` is the last layer. `n` includes the size of the payload.
+    fn serialize_with_payload(&self, buffer: &mut [u8], payload: &[u8], n: usize) -> Result;
 
-    // Serialize the `Layer` into a byte-array with payload.
-    fn serialize_with_payload(
-        &self,
-        buffer: &mut [u8],
-        payload: &[u8],
-        n: usize,
-    ) -> io::Result<usize>;
+    // Serialize the `Layer` into a byte-array of its exact size as if it is the last layer.
+    fn to_vec(&self) -> result::Result<Vec<u8>, SerializeError> {
+        let size = self.get_size();
+        let mut buffer = vec![0u8; size];
+        let n = self.serialize(&mut buffer, size)?;
+        debug_assert_eq!(n, size, "serialize {} into {} Bytes", self.get_type(), n);
+
+        Ok(buffer)
+    }
+}
+
+/// Represents a layer which can be deserialized from a byte-array.
+pub trait LayerDeserialize: Layer + Sized {
+    // Deserialize a `Layer` from the front of the byte-array, returns the `Layer` and the size it
+    // occupies in the byte-array.
+    fn deserialize(
+        buffer: &[u8],
+        context: &ParseContext,
+    ) -> result::Result<(Self, usize), ParseError>;
 }
 
 #[derive(Debug, Clone)]
@@ -73,8 +390,12 @@ pub enum Layers {
     Ethernet(ethernet::Ethernet),
     Arp(arp::Arp),
     Ipv4(ipv4::Ipv4),
+    Ipv6(ipv6::Ipv6),
     Tcp(tcp::Tcp),
     Udp(udp::Udp),
+    Icmpv4(icmpv4::Icmpv4),
+    Vlan(vlan::Vlan),
+    Icmpv6(icmpv6::Icmpv6),
 }
 
 impl Display for Layers {
@@ -83,8 +404,12 @@ impl Display for Layers {
             Layers::Ethernet(ref layer) => layer.fmt(f),
             Layers::Arp(ref layer) => layer.fmt(f),
             Layers::Ipv4(ref layer) => layer.fmt(f),
+            Layers::Ipv6(ref layer) => layer.fmt(f),
             Layers::Tcp(ref layer) => layer.fmt(f),
             Layers::Udp(ref layer) => layer.fmt(f),
+            Layers::Icmpv4(ref layer) => layer.fmt(f),
+            Layers::Vlan(ref layer) => layer.fmt(f),
+            Layers::Icmpv6(ref layer) => layer.fmt(f),
         }
     }
 }
@@ -95,8 +420,26 @@ impl Layer for Layers {
             Layers::Ethernet(ref layer) => layer.get_type(),
             Layers::Arp(ref layer) => layer.get_type(),
             Layers::Ipv4(ref layer) => layer.get_type(),
+            Layers::Ipv6(ref layer) => layer.get_type(),
             Layers::Tcp(ref layer) => layer.get_type(),
             Layers::Udp(ref layer) => layer.get_type(),
+            Layers::Icmpv4(ref layer) => layer.get_type(),
+            Layers::Vlan(ref layer) => layer.get_type(),
+            Layers::Icmpv6(ref layer) => layer.get_type(),
+        }
+    }
+
+    fn get_details(&self, verbosity: Verbosity) -> String {
+        match self {
+            Layers::Ethernet(ref layer) => layer.get_details(verbosity),
+            Layers::Arp(ref layer) => layer.get_details(verbosity),
+            Layers::Ipv4(ref layer) => layer.get_details(verbosity),
+            Layers::Ipv6(ref layer) => layer.get_details(verbosity),
+            Layers::Tcp(ref layer) => layer.get_details(verbosity),
+            Layers::Udp(ref layer) => layer.get_details(verbosity),
+            Layers::Icmpv4(ref layer) => layer.get_details(verbosity),
+            Layers::Vlan(ref layer) => layer.get_details(verbosity),
+            Layers::Icmpv6(ref layer) => layer.get_details(verbosity),
         }
     }
 
@@ -105,33 +448,496 @@ impl Layer for Layers {
             Layers::Ethernet(ref layer) => layer.get_size(),
             Layers::Arp(ref layer) => layer.get_size(),
             Layers::Ipv4(ref layer) => layer.get_size(),
+            Layers::Ipv6(ref layer) => layer.get_size(),
             Layers::Tcp(ref layer) => layer.get_size(),
             Layers::Udp(ref layer) => layer.get_size(),
+            Layers::Icmpv4(ref layer) => layer.get_size(),
+            Layers::Vlan(ref layer) => layer.get_size(),
+            Layers::Icmpv6(ref layer) => layer.get_size(),
         }
     }
 
-    fn serialize(&self, buffer: &mut [u8], n: usize) -> io::Result<usize> {
+    fn get_payload_size(&self) -> usize {
+        match self {
+            Layers::Ethernet(ref layer) => layer.get_payload_size(),
+            Layers::Arp(ref layer) => layer.get_payload_size(),
+            Layers::Ipv4(ref layer) => layer.get_payload_size(),
+            Layers::Ipv6(ref layer) => layer.get_payload_size(),
+            Layers::Tcp(ref layer) => layer.get_payload_size(),
+            Layers::Udp(ref layer) => layer.get_payload_size(),
+            Layers::Icmpv4(ref layer) => layer.get_payload_size(),
+            Layers::Vlan(ref layer) => layer.get_payload_size(),
+            Layers::Icmpv6(ref layer) => layer.get_payload_size(),
+        }
+    }
+
+    fn serialize(&self, buffer: &mut [u8], n: usize) -> Result {
         match self {
             Layers::Ethernet(ref layer) => layer.serialize(buffer, n),
             Layers::Arp(ref layer) => layer.serialize(buffer, n),
             Layers::Ipv4(ref layer) => layer.serialize(buffer, n),
+            Layers::Ipv6(ref layer) => layer.serialize(buffer, n),
             Layers::Tcp(ref layer) => layer.serialize(buffer, n),
             Layers::Udp(ref layer) => layer.serialize(buffer, n),
+            Layers::Icmpv4(ref layer) => layer.serialize(buffer, n),
+            Layers::Vlan(ref layer) => layer.serialize(buffer, n),
+            Layers::Icmpv6(ref layer) => layer.serialize(buffer, n),
         }
     }
 
-    fn serialize_with_payload(
-        &self,
-        buffer: &mut [u8],
-        payload: &[u8],
-        n: usize,
-    ) -> io::Result<usize> {
+    fn serialize_with_payload(&self, buffer: &mut [u8], payload: &[u8], n: usize) -> Result {
         match self {
             Layers::Ethernet(ref layer) => layer.serialize_with_payload(buffer, payload, n),
             Layers::Arp(ref layer) => layer.serialize_with_payload(buffer, payload, n),
             Layers::Ipv4(ref layer) => layer.serialize_with_payload(buffer, payload, n),
+            Layers::Ipv6(ref layer) => layer.serialize_with_payload(buffer, payload, n),
             Layers::Tcp(ref layer) => layer.serialize_with_payload(buffer, payload, n),
             Layers::Udp(ref layer) => layer.serialize_with_payload(buffer, payload, n),
+            Layers::Icmpv4(ref layer) => layer.serialize_with_payload(buffer, payload, n),
+            Layers::Vlan(ref layer) => layer.serialize_with_payload(buffer, payload, n),
+            Layers::Icmpv6(ref layer) => layer.serialize_with_payload(buffer, payload, n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::datalink::MacAddr;
+    use pnet::packet::ipv4::Ipv4Packet;
+    use pnet::packet::udp::{self as pnet_udp, UdpPacket};
+    use pnet::packet::FromPacket;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use arp::Arp;
+    use ethernet::Ethernet;
+    use icmpv4::Icmpv4;
+    use icmpv6::Icmpv6;
+    use ipv4::Ipv4;
+    use ipv6::Ipv6;
+    use payload::Payload;
+    use tcp::Tcp;
+    use udp::Udp;
+    use vlan::Vlan;
+
+    const SRC_HARDWARE_ADDR: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0x02);
+    const DST_HARDWARE_ADDR: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0x01);
+
+    fn src_ipv4() -> Ipv4Addr {
+        Ipv4Addr::new(10, 6, 0, 1)
+    }
+
+    fn dst_ipv4() -> Ipv4Addr {
+        Ipv4Addr::new(192, 0, 2, 1)
+    }
+
+    fn src_ipv6() -> Ipv6Addr {
+        Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)
+    }
+
+    fn dst_ipv6() -> Ipv6Addr {
+        Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2)
+    }
+
+    /// Get a layer of each kind, including layers with options and payloads.
+    fn new_layers() -> Vec<Layers> {
+        let (src, dst) = (IpAddr::V4(src_ipv4()), IpAddr::V4(dst_ipv4()));
+        let payload: Vec<u8> = (0..100).collect();
+
+        let mut tcp = Tcp::new_ack_syn(src, dst, 40000, 80, 1000, 2000, 65535);
+        tcp.set_mss(1460);
+        tcp.set_wscale(7);
+        tcp.set_sack_perm();
+        tcp.set_timestamps(1, 2);
+        let mut tcp_with_payload = Tcp::new_ack(src, dst, 40000, 80, 1000, 2000, 65535);
+        tcp_with_payload.set_sacks(&[(3000, 4000), (5000, 6000)]);
+        tcp_with_payload.payload = Payload::copy_from(&payload);
+        let mut udp = Udp::new(src, dst, 40000, 53);
+        udp.payload = Payload::copy_from(&payload);
+
+        vec![
+            Layers::Ethernet(
+                Ethernet::new(LayerTypes::Ipv4, SRC_HARDWARE_ADDR, DST_HARDWARE_ADDR).unwrap(),
+            ),
+            Layers::Vlan(Vlan::new(LayerTypes::Ipv6, 3, true, 100).unwrap()),
+            Layers::Arp(Arp::new_reply(
+                SRC_HARDWARE_ADDR,
+                src_ipv4(),
+                DST_HARDWARE_ADDR,
+                dst_ipv4(),
+            )),
+            Layers::Arp(Arp::new_request(SRC_HARDWARE_ADDR, src_ipv4(), dst_ipv4())),
+            Layers::Ipv4(Ipv4::new(1, LayerTypes::Tcp, src_ipv4(), dst_ipv4()).unwrap()),
+            Layers::Ipv6(Ipv6::new(LayerTypes::Icmpv6, src_ipv6(), dst_ipv6()).unwrap()),
+            Layers::Tcp(Tcp::new_ack(src, dst, 40000, 80, 1000, 2000, 65535)),
+            Layers::Tcp(tcp),
+            Layers::Tcp(tcp_with_payload),
+            Layers::Udp(Udp::new(src, dst, 40000, 53)),
+            Layers::Udp(udp),
+            Layers::Icmpv4(Icmpv4::new_echo_reply(1, 2, &payload)),
+            Layers::Icmpv4(Icmpv4::new_destination_unreachable(3, &payload)),
+            Layers::Icmpv4(Icmpv4::new_fragmentation_needed(1280, &payload)),
+            Layers::Icmpv4(Icmpv4::new_time_exceeded(0, &payload)),
+            Layers::Icmpv6(Icmpv6::new_echo_reply(
+                src_ipv6(),
+                dst_ipv6(),
+                1,
+                2,
+                &payload,
+            )),
+            Layers::Icmpv6(Icmpv6::new_neighbor_advert(
+                src_ipv6(),
+                dst_ipv6(),
+                src_ipv6(),
+                SRC_HARDWARE_ADDR,
+                true,
+                true,
+            )),
+            Layers::Icmpv6(Icmpv6::new_router_advert(
+                src_ipv6(),
+                dst_ipv6(),
+                SRC_HARDWARE_ADDR,
+                1800,
+                Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0),
+                64,
+            )),
+        ]
+    }
+
+    #[test]
+    fn serialize_size() {
+        for layer in new_layers() {
+            let size = layer.get_size();
+
+            // Bytes after the layer are left untouched
+            let mut buffer = vec![0xaa; size + 64];
+            let n = layer.serialize(&mut buffer, size).unwrap();
+            assert_eq!(n, size, "serialize {}", layer);
+            assert!(
+                buffer[size..].iter().all(|b| *b == 0xaa),
+                "serialize {}",
+                layer
+            );
+
+            assert_eq!(layer.to_vec().unwrap(), &buffer[..size], "to_vec {}", layer);
+
+            // A buffer of the exact size is sufficient, and a smaller one is not
+            assert_eq!(layer.serialize(&mut vec![0u8; size], size), Ok(size));
+            assert!(layer.serialize(&mut vec![0u8; size - 1], size).is_err());
+        }
+    }
+
+    #[test]
+    fn serialize_with_payload_size() {
+        let context = ParseContext::with_ip_addrs(IpAddr::V4(src_ipv4()), IpAddr::V4(dst_ipv4()));
+        let payload: Vec<u8> = (0..10).collect();
+        for layer in new_layers() {
+            match layer {
+                Layers::Tcp(_) | Layers::Udp(_) => {}
+                _ => continue,
+            }
+            // The payload replaces the one the layer owns
+            let size = layer.get_size() - layer.get_payload_size() + payload.len();
+            let mut buffer = vec![0xaa; size + 64];
+            let n = layer
+                .serialize_with_payload(&mut buffer, &payload, size)
+                .unwrap();
+            assert_eq!(n, size, "serialize {}", layer);
+            assert!(buffer[size..].iter().all(|b| *b == 0xaa));
+            assert_eq!(&buffer[size - payload.len()..size], &payload[..]);
+
+            let (again, m) = match layer {
+                Layers::Tcp(_) => {
+                    let (tcp, m) = Tcp::deserialize(&buffer[..size], &context).unwrap();
+                    (Layers::Tcp(tcp), m)
+                }
+                _ => {
+                    let (udp, m) = Udp::deserialize(&buffer[..size], &context).unwrap();
+                    (Layers::Udp(udp), m)
+                }
+            };
+            assert_eq!(m, size, "deserialize {}", again);
+            assert_eq!(again.get_payload_size(), payload.len());
+        }
+    }
+
+    /// Deserialize a layer from the byte-array, serialize it back and deserialize it again.
+    fn round_trip<T: LayerDeserialize>(buffer: &[u8], context: &ParseContext) {
+        let (layer, size) = T::deserialize(buffer, context).unwrap();
+        assert_eq!(size, buffer.len(), "deserialize {}", layer);
+        assert_eq!(layer.get_size(), buffer.len(), "deserialize {}", layer);
+
+        let vec = layer.to_vec().unwrap();
+        assert_eq!(vec, buffer, "serialize {}", layer);
+
+        let (again, size) = T::deserialize(&vec, context).unwrap();
+        assert_eq!(size, vec.len(), "deserialize {}", again);
+        assert_eq!(again.to_vec().unwrap(), vec, "serialize {}", again);
+        assert_eq!(
+            again.summary(Verbosity::Verbose),
+            layer.summary(Verbosity::Verbose)
+        );
+    }
+
+    #[test]
+    fn deserialize_round_trip() {
+        let ipv4_context =
+            ParseContext::with_ip_addrs(IpAddr::V4(src_ipv4()), IpAddr::V4(dst_ipv4()));
+        let ipv6_context =
+            ParseContext::with_ip_addrs(IpAddr::V6(src_ipv6()), IpAddr::V6(dst_ipv6()));
+        for layer in new_layers() {
+            let buffer = layer.to_vec().unwrap();
+            match layer {
+                Layers::Ethernet(_) => round_trip::<Ethernet>(&buffer, &ParseContext::new()),
+                Layers::Arp(_) => round_trip::<Arp>(&buffer, &ParseContext::new()),
+                Layers::Ipv4(_) => round_trip::<Ipv4>(&buffer, &ParseContext::new()),
+                Layers::Ipv6(_) => round_trip::<Ipv6>(&buffer, &ParseContext::new()),
+                Layers::Tcp(_) => round_trip::<Tcp>(&buffer, &ipv4_context),
+                Layers::Udp(_) => round_trip::<Udp>(&buffer, &ipv4_context),
+                Layers::Icmpv4(_) => round_trip::<Icmpv4>(&buffer, &ParseContext::new()),
+                Layers::Vlan(_) => round_trip::<Vlan>(&buffer, &ParseContext::new()),
+                Layers::Icmpv6(_) => round_trip::<Icmpv6>(&buffer, &ipv6_context),
+            }
         }
     }
+
+    #[test]
+    fn summary_snapshots() {
+        // Logs are parsed, so the formats of summaries are pinned down
+        let snapshots = [
+            [
+                "Ethernet: 02:00:00:00:00:02 -> 02:00:00:00:00:01",
+                "Ethernet: 02:00:00:00:00:02 -> 02:00:00:00:00:01, Type = 0x0800",
+                "Ethernet: 02:00:00:00:00:02 -> 02:00:00:00:00:01, Type = 0x0800",
+            ],
+            [
+                "VLAN: VID = 100, Priority = 3",
+                "VLAN: VID = 100, Priority = 3, DEI = 1",
+                "VLAN: VID = 100, Priority = 3, DEI = 1, Type = 0x86dd",
+            ],
+            [
+                "ARP: 10.6.0.1 -> 192.0.2.1, Operation = Reply",
+                "ARP: 10.6.0.1 -> 192.0.2.1, Operation = Reply, Sender = 02:00:00:00:00:02, Target = 02:00:00:00:00:01",
+                "ARP: 10.6.0.1 -> 192.0.2.1, Operation = Reply, Sender = 02:00:00:00:00:02, Target = 02:00:00:00:00:01, Hardware Type = 1, Protocol Type = 0x0800",
+            ],
+            [
+                "ARP: 10.6.0.1 -> 192.0.2.1, Operation = Request",
+                "ARP: 10.6.0.1 -> 192.0.2.1, Operation = Request, Sender = 02:00:00:00:00:02, Target = 00:00:00:00:00:00",
+                "ARP: 10.6.0.1 -> 192.0.2.1, Operation = Request, Sender = 02:00:00:00:00:02, Target = 00:00:00:00:00:00, Hardware Type = 1, Protocol Type = 0x0800",
+            ],
+            [
+                "IPv4: 10.6.0.1 -> 192.0.2.1, Length = 0",
+                "IPv4: 10.6.0.1 -> 192.0.2.1, Length = 0, Identification = 0x0001, TTL = 128, Flags = [], Checksum = 0x0000",
+                "IPv4: 10.6.0.1 -> 192.0.2.1, Length = 0, Identification = 0x0001, TTL = 128, Flags = [], Checksum = 0x0000, DSCP = 0, ECN = 0, Protocol = 6, Options = 0 Bytes",
+            ],
+            [
+                "IPv6: fe80::1 -> fe80::2, Length = 0",
+                "IPv6: fe80::1 -> fe80::2, Length = 0, Next Header = 58, Hop Limit = 128",
+                "IPv6: fe80::1 -> fe80::2, Length = 0, Next Header = 58, Hop Limit = 128, Traffic Class = 0, Flow Label = 0x00000",
+            ],
+            [
+                "TCP: 40000 -> 80 [.]",
+                "TCP: 40000 -> 80 [.], Sequence = 1000, Acknowledgement = 2000, Window = 65535, Checksum = 0x3b84",
+                "TCP: 40000 -> 80 [.], Sequence = 1000, Acknowledgement = 2000, Window = 65535, Checksum = 0x3b84, Urgent Pointer = 0, Options = 0 Bytes, Payload = 0 Bytes",
+            ],
+            [
+                "TCP: 40000 -> 80 [S.]",
+                "TCP: 40000 -> 80 [S.], Sequence = 1000, Acknowledgement = 2000, Window = 65535, Checksum = 0xcaa6",
+                "TCP: 40000 -> 80 [S.], Sequence = 1000, Acknowledgement = 2000, Window = 65535, Checksum = 0xcaa6, Urgent Pointer = 0, Options = 20 Bytes, MSS = 1460, Window Scale = 7, SACK Permitted, Timestamps = 1 2, Payload = 0 Bytes",
+            ],
+            [
+                "TCP: 40000 -> 80 [.]",
+                "TCP: 40000 -> 80 [.], Sequence = 1000, Acknowledgement = 2000, Window = 65535, Checksum = 0x03dc",
+                "TCP: 40000 -> 80 [.], Sequence = 1000, Acknowledgement = 2000, Window = 65535, Checksum = 0x03dc, Urgent Pointer = 0, Options = 20 Bytes, SACK = 3000-4000, SACK = 5000-6000, Payload = 100 Bytes",
+            ],
+            [
+                "UDP: 40000 -> 53, Length = 8",
+                "UDP: 40000 -> 53, Length = 8, Checksum = 0x9760",
+                "UDP: 40000 -> 53, Length = 8, Checksum = 0x9760, Payload = 0 Bytes",
+            ],
+            [
+                "UDP: 40000 -> 53, Length = 108",
+                "UDP: 40000 -> 53, Length = 108, Checksum = 0xfaca",
+                "UDP: 40000 -> 53, Length = 108, Checksum = 0xfaca, Payload = 100 Bytes",
+            ],
+            [
+                "ICMPv4: Echo Reply, Identifier = 1, Sequence = 2",
+                "ICMPv4: Echo Reply, Identifier = 1, Sequence = 2, Checksum = 0x0000",
+                "ICMPv4: Echo Reply, Identifier = 1, Sequence = 2, Checksum = 0x0000, Type = 0, Code = 0, Payload = 104 Bytes",
+            ],
+            [
+                "ICMPv4: Type = 3, Code = 3",
+                "ICMPv4: Type = 3, Code = 3, Checksum = 0x0000",
+                "ICMPv4: Type = 3, Code = 3, Checksum = 0x0000, Payload = 104 Bytes",
+            ],
+            [
+                "ICMPv4: Type = 3, Code = 4",
+                "ICMPv4: Type = 3, Code = 4, Checksum = 0x0000",
+                "ICMPv4: Type = 3, Code = 4, Checksum = 0x0000, Payload = 104 Bytes",
+            ],
+            [
+                "ICMPv4: Type = 11, Code = 0",
+                "ICMPv4: Type = 11, Code = 0, Checksum = 0x0000",
+                "ICMPv4: Type = 11, Code = 0, Checksum = 0x0000, Payload = 104 Bytes",
+            ],
+            [
+                "ICMPv6: Echo Reply, Identifier = 1, Sequence = 2",
+                "ICMPv6: Echo Reply, Identifier = 1, Sequence = 2, Checksum = 0xe583",
+                "ICMPv6: Echo Reply, Identifier = 1, Sequence = 2, Checksum = 0xe583, Type = 129, Code = 0, Payload = 104 Bytes",
+            ],
+            [
+                "ICMPv6: Neighbor Advertisement, Target = fe80::1",
+                "ICMPv6: Neighbor Advertisement, Target = fe80::1, Checksum = 0x981a",
+                "ICMPv6: Neighbor Advertisement, Target = fe80::1, Checksum = 0x981a, Type = 136, Code = 0, Payload = 28 Bytes",
+            ],
+            [
+                "ICMPv6: Router Advertisement",
+                "ICMPv6: Router Advertisement, Checksum = 0x67f6",
+                "ICMPv6: Router Advertisement, Checksum = 0x67f6, Type = 134, Code = 0, Payload = 52 Bytes",
+            ],
+        ];
+        let ipv4_context =
+            ParseContext::with_ip_addrs(IpAddr::V4(src_ipv4()), IpAddr::V4(dst_ipv4()));
+        let ipv6_context =
+            ParseContext::with_ip_addrs(IpAddr::V6(src_ipv6()), IpAddr::V6(dst_ipv6()));
+        let layers = new_layers();
+        assert_eq!(layers.len(), snapshots.len());
+        for (layer, snapshot) in layers.into_iter().zip(snapshots.iter()) {
+            // Checksums are filled in serialization
+            let buffer = layer.to_vec().unwrap();
+            let layer = match layer {
+                Layers::Tcp(_) => Layers::Tcp(Tcp::deserialize(&buffer, &ipv4_context).unwrap().0),
+                Layers::Udp(_) => Layers::Udp(Udp::deserialize(&buffer, &ipv4_context).unwrap().0),
+                Layers::Icmpv6(_) => {
+                    Layers::Icmpv6(Icmpv6::deserialize(&buffer, &ipv6_context).unwrap().0)
+                }
+                layer => layer,
+            };
+            let verbosities = [Verbosity::Brief, Verbosity::Normal, Verbosity::Verbose];
+            for (verbosity, expected) in verbosities.iter().zip(snapshot.iter()) {
+                assert_eq!(layer.summary(*verbosity), *expected);
+            }
+            assert_eq!(layer.to_string(), snapshot[0]);
+        }
+    }
+
+    #[test]
+    fn udp_zero_checksum() {
+        let addrs = [
+            (IpAddr::V4(src_ipv4()), IpAddr::V4(dst_ipv4())),
+            (IpAddr::V6(src_ipv6()), IpAddr::V6(dst_ipv6())),
+        ];
+        for &(src, dst) in addrs.iter() {
+            let context = ParseContext::with_ip_addrs(src, dst);
+            let mut udp = Udp::new(src, dst, 40000, 53);
+            udp.payload = Payload::copy_from(&[0x12, 0x34, 0, 0]);
+            let buffer = udp.to_vec().unwrap();
+            let checksum = u16::from_be_bytes([buffer[6], buffer[7]]);
+            assert_ne!(checksum, 0);
+
+            // A word of the checksum in the payload makes the sum all ones, whose checksum
+            // computes to zero
+            let word = checksum.to_be_bytes();
+            udp.payload = Payload::copy_from(&[0x12, 0x34, word[0], word[1]]);
+            let buffer = udp.to_vec().unwrap();
+            let packet = UdpPacket::new(&buffer).unwrap();
+            let computed = match (src, dst) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => pnet_udp::ipv4_checksum(&packet, &src, &dst),
+                (IpAddr::V6(src), IpAddr::V6(dst)) => pnet_udp::ipv6_checksum(&packet, &src, &dst),
+                _ => unreachable!(),
+            };
+            assert_eq!(computed, 0);
+            assert_eq!(&buffer[6..8], &[0xff, 0xff]);
+            let (layer, _) = Udp::deserialize(&buffer, &context).unwrap();
+            assert!(layer.verify_checksum());
+
+            // A corrupted datagram fails the verification
+            let mut buffer = buffer;
+            buffer[8] ^= 0x01;
+            let (layer, _) = Udp::deserialize(&buffer, &context).unwrap();
+            assert!(!layer.verify_checksum());
+        }
+    }
+
+    /// Represents an IGMPv2 membership report with a router alert option.
+    const ROUTER_ALERT_PACKET: [u8; 32] = [
+        0x46, 0xc0, 0x00, 0x20, 0x00, 0x00, 0x40, 0x00, 0x01, 0x02, 0xf9, 0x15, 0x0a, 0x06, 0x00,
+        0x01, 0xe0, 0x00, 0x00, 0xfb, 0x94, 0x04, 0x00, 0x00, 0x16, 0x00, 0x09, 0x04, 0xe0, 0x00,
+        0x00, 0xfb,
+    ];
+
+    /// Represents an ICMPv4 echo request with a record route option, whose header is in the max
+    /// size.
+    const RECORD_ROUTE_PACKET: [u8; 76] = [
+        0x4f, 0x00, 0x00, 0x4c, 0x61, 0xb3, 0x40, 0x00, 0x40, 0x01, 0xf7, 0xce, 0x0a, 0x06, 0x00,
+        0x01, 0xc0, 0x00, 0x02, 0x01, 0x07, 0x27, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x08, 0x00, 0xc1, 0xd7, 0x2a, 0x17, 0x00, 0x01, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
+        0x07,
+    ];
+
+    #[test]
+    fn ipv4_options_round_trip() {
+        for (packet, header_size) in &[
+            (&ROUTER_ALERT_PACKET[..], 24),
+            (&RECORD_ROUTE_PACKET[..], 60),
+        ] {
+            let (layer, size) = Ipv4::deserialize(packet, &ParseContext::new()).unwrap();
+            assert_eq!(size, *header_size);
+            assert_eq!(layer.get_size(), *header_size);
+            assert_eq!(layer.get_options(), &packet[20..*header_size]);
+
+            // The header is serialized verbatim, including the checksum
+            let mut buffer = packet.to_vec();
+            for b in &mut buffer[..*header_size] {
+                *b = 0;
+            }
+            assert_eq!(layer.serialize(&mut buffer, packet.len()), Ok(*header_size));
+            assert_eq!(&buffer, packet);
+
+            // Options of an `Ipv4` of pnet are kept
+            let layer = Ipv4::from(Ipv4Packet::new(packet).unwrap().from_packet());
+            assert_eq!(layer.get_size(), *header_size);
+            let mut buffer = packet.to_vec();
+            assert_eq!(layer.serialize(&mut buffer, packet.len()), Ok(*header_size));
+            assert_eq!(&buffer, packet);
+        }
+
+        // The header length is less than the minimum or beyond the buffer
+        let mut packet = ROUTER_ALERT_PACKET.to_vec();
+        packet[0] = 0x44;
+        assert_eq!(
+            Ipv4::deserialize(&packet, &ParseContext::new()).err(),
+            Some(ParseError::InvalidHeaderLength {
+                t: LayerTypes::Ipv4,
+                length: 16
+            })
+        );
+        packet[0] = 0x49;
+        assert_eq!(
+            Ipv4::deserialize(&packet, &ParseContext::new()).err(),
+            Some(ParseError::InvalidHeaderLength {
+                t: LayerTypes::Ipv4,
+                length: 36
+            })
+        );
+    }
+
+    #[test]
+    fn deserialize_without_context() {
+        let (src, dst) = (IpAddr::V4(src_ipv4()), IpAddr::V4(dst_ipv4()));
+        let buffer = Tcp::new_ack(src, dst, 40000, 80, 1000, 2000, 65535)
+            .to_vec()
+            .unwrap();
+        assert_eq!(
+            Tcp::deserialize(&buffer, &ParseContext::new()).err(),
+            Some(ParseError::MissingContext { t: LayerTypes::Tcp })
+        );
+        let buffer = Udp::new(src, dst, 40000, 53).to_vec().unwrap();
+        assert_eq!(
+            Udp::deserialize(&buffer, &ParseContext::new()).err(),
+            Some(ParseError::MissingContext { t: LayerTypes::Udp })
+        );
+    }
 }
@@ -0,0 +1,100 @@
+use std::fmt::{self, Debug, Formatter};
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// Represents a frame shared by the layers parsed from it.
+pub type Frame = Arc<Vec<u8>>;
+
+/// Represents the payload of a layer, which is a range of a frame. Payloads parsed from a shared
+/// frame refer to the frame, so the bytes are never copied until they are written out.
+#[derive(Clone, Default)]
+pub struct Payload {
+    frame: Option<Frame>,
+    begin: usize,
+    end: usize,
+}
+
+impl Payload {
+    /// Creates an empty `Payload`.
+    pub fn new() -> Payload {
+        Payload::default()
+    }
+
+    /// Creates a `Payload` with a copy of the bytes.
+    pub fn copy_from(bytes: &[u8]) -> Payload {
+        if bytes.is_empty() {
+            return Payload::new();
+        }
+
+        Payload {
+            frame: Some(Arc::new(bytes.to_vec())),
+            begin: 0,
+            end: bytes.len(),
+        }
+    }
+
+    /// Creates a `Payload` refers to the bytes in the frame, the bytes are copied if they are not
+    /// in the frame.
+    pub fn from_frame(frame: Option<&Frame>, bytes: &[u8]) -> Payload {
+        let frame = match frame {
+            Some(frame) => frame,
+            None => return Payload::copy_from(bytes),
+        };
+        let base = frame.as_ptr() as usize;
+        let begin = bytes.as_ptr() as usize;
+        if bytes.is_empty() || begin < base || begin + bytes.len() > base + frame.len() {
+            return Payload::copy_from(bytes);
+        }
+
+        Payload {
+            frame: Some(Arc::clone(frame)),
+            begin: begin - base,
+            end: begin - base + bytes.len(),
+        }
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.frame {
+            Some(ref frame) => &frame[self.begin..self.end],
+            None => &[],
+        }
+    }
+}
+
+impl Debug for Payload {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Payload({} Bytes)", self.end - self.begin)
+    }
+}
+
+/// Represents a pool of a frame, whose buffer is reused once the layers parsed from it are
+/// dropped.
+#[derive(Debug, Default)]
+pub struct FramePool {
+    frame: Frame,
+}
+
+impl FramePool {
+    /// Creates a `FramePool`.
+    pub fn new() -> FramePool {
+        FramePool::default()
+    }
+
+    /// Copies the bytes into a frame. The buffer of the pool is reused if it is not shared any
+    /// more, otherwise a new buffer is allocated.
+    pub fn copy_from(&mut self, bytes: &[u8]) -> Frame {
+        match Arc::get_mut(&mut self.frame) {
+            Some(frame) => {
+                frame.clear();
+                frame.extend_from_slice(bytes);
+            }
+            None => self.frame = Arc::new(bytes.to_vec()),
+        }
+
+        Arc::clone(&self.frame)
+    }
+}
@@ -1,23 +1,36 @@
-pub use super::{Layer, LayerType, LayerTypes};
-use pnet::packet::tcp::{self, MutableTcpPacket, TcpFlags, TcpOptionPacket, TcpPacket};
+use super::payload::{Frame, Payload};
+pub use super::{
+    Layer, LayerDeserialize, LayerType, LayerTypes, ParseContext, ParseError, Result,
+    SerializeError, Verbosity,
+};
+use pnet::packet::tcp::{
+    self, MutableTcpOptionPacket, MutableTcpPacket, TcpFlags, TcpOption, TcpOptionNumber,
+    TcpOptionNumbers, TcpOptionPacket, TcpPacket,
+};
+use pnet::packet::{MutablePacket, Packet};
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
-use std::io;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+use std::result;
 
-/// Represents a TCP packet.
+/// Represents the max number of SACK blocks in a TCP packet.
+const MAX_SACKS: usize = 4;
+
+/// Represents a TCP packet. The payload is kept out of the layer of pnet, which is always
+/// empty, so payloads parsed from a shared frame are never copied.
 #[derive(Clone, Debug)]
 pub struct Tcp {
     pub layer: tcp::Tcp,
-    pub src: Ipv4Addr,
-    pub dst: Ipv4Addr,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub payload: Payload,
 }
 
 impl Tcp {
     /// Creates a `Tcp` represents a TCP ACK.
     pub fn new_ack(
-        src_ip_addr: Ipv4Addr,
-        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: IpAddr,
+        dst_ip_addr: IpAddr,
         src: u16,
         dst: u16,
         sequence: u32,
@@ -41,13 +54,28 @@ impl Tcp {
             },
             src: src_ip_addr,
             dst: dst_ip_addr,
+            payload: Payload::new(),
         }
     }
 
+    /// Creates a `Tcp` represents a TCP SYN.
+    pub fn new_syn(
+        src_ip_addr: IpAddr,
+        dst_ip_addr: IpAddr,
+        src: u16,
+        dst: u16,
+        sequence: u32,
+        window: u16,
+    ) -> Tcp {
+        let mut tcp = Tcp::new_ack(src_ip_addr, dst_ip_addr, src, dst, sequence, 0, window);
+        tcp.layer.flags = TcpFlags::SYN;
+        tcp
+    }
+
     /// Creates a `Tcp` represents a TCP ACK/SYN.
     pub fn new_ack_syn(
-        src_ip_addr: Ipv4Addr,
-        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: IpAddr,
+        dst_ip_addr: IpAddr,
         src: u16,
         dst: u16,
         sequence: u32,
@@ -69,8 +97,8 @@ impl Tcp {
 
     /// Creates a `Tcp` represents a TCP ACK/RST.
     pub fn new_ack_rst(
-        src_ip_addr: Ipv4Addr,
-        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: IpAddr,
+        dst_ip_addr: IpAddr,
         src: u16,
         dst: u16,
         sequence: u32,
@@ -92,8 +120,8 @@ impl Tcp {
 
     /// Creates a `Tcp` represents a TCP ACK/FIN.
     pub fn new_ack_fin(
-        src_ip_addr: Ipv4Addr,
-        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: IpAddr,
+        dst_ip_addr: IpAddr,
         src: u16,
         dst: u16,
         sequence: u32,
@@ -115,8 +143,8 @@ impl Tcp {
 
     /// Creates a `Tcp` represents a TCP RST.
     pub fn new_rst(
-        src_ip_addr: Ipv4Addr,
-        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: IpAddr,
+        dst_ip_addr: IpAddr,
         src: u16,
         dst: u16,
         sequence: u32,
@@ -137,16 +165,26 @@ impl Tcp {
     }
 
     /// Creates a `Tcp` according to the given `Tcp`.
-    pub fn from(tcp: tcp::Tcp, src: Ipv4Addr, dst: Ipv4Addr) -> Tcp {
+    pub fn from(mut tcp: tcp::Tcp, src: IpAddr, dst: IpAddr) -> Tcp {
+        let payload = Payload::copy_from(&tcp.payload);
+        tcp.payload = vec![];
+
         Tcp {
             layer: tcp,
             src,
             dst,
+            payload,
         }
     }
 
     /// Creates a `Tcp` according to the given TCP packet, source and destination.
-    pub fn parse(packet: &TcpPacket, src: Ipv4Addr, dst: Ipv4Addr) -> Tcp {
+    pub fn parse(packet: &TcpPacket, src: IpAddr, dst: IpAddr) -> Tcp {
+        Tcp::parse_in(packet, src, dst, None)
+    }
+
+    /// Creates a `Tcp` according to the given TCP packet in the frame, the payload refers to the
+    /// frame.
+    fn parse_in(packet: &TcpPacket, src: IpAddr, dst: IpAddr, frame: Option<&Frame>) -> Tcp {
         Tcp {
             layer: tcp::Tcp {
                 source: packet.get_source(),
@@ -164,16 +202,17 @@ impl Tcp {
             },
             src,
             dst,
+            payload: Payload::from_frame(frame, packet.payload()),
         }
     }
 
     /// Get the source IP address of the layer.
-    pub fn get_src_ip_addr(&self) -> Ipv4Addr {
+    pub fn get_src_ip_addr(&self) -> IpAddr {
         self.src
     }
 
     /// Get the destination IP address of the layer.
-    pub fn get_dst_ip_addr(&self) -> Ipv4Addr {
+    pub fn get_dst_ip_addr(&self) -> IpAddr {
         self.dst
     }
 
@@ -209,9 +248,21 @@ impl Tcp {
         if self.is_fin() {
             flags = flags + "F";
         }
+        if self.is_psh() {
+            flags = flags + "P";
+        }
+        if self.is_urg() {
+            flags = flags + "U";
+        }
         if self.is_ack() {
             flags = flags + ".";
         }
+        if self.is_ece() {
+            flags = flags + "E";
+        }
+        if self.is_cwr() {
+            flags = flags + "W";
+        }
         flags = flags + "]";
 
         flags
@@ -222,6 +273,134 @@ impl Tcp {
         self.layer.window
     }
 
+    /// Get the payload of the layer.
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Get the size of the header of the layer, including the padding of options.
+    pub fn get_header_size(&self) -> usize {
+        // Options are padded to a 4 Bytes boundary
+        TcpPacket::minimum_packet_size() + (self.get_options_size() + 3) / 4 * 4
+    }
+
+    /// Get the size of options of the layer, excluding the padding.
+    fn get_options_size(&self) -> usize {
+        self.layer
+            .options
+            .iter()
+            .map(TcpOptionPacket::packet_size)
+            .sum()
+    }
+
+    /// Get the MSS option of the layer.
+    pub fn get_mss(&self) -> Option<u16> {
+        match self.get_option(TcpOptionNumbers::MSS) {
+            Some(ref data) if data.len() == 2 => Some(u16::from_be_bytes([data[0], data[1]])),
+            _ => None,
+        }
+    }
+
+    /// Sets the MSS option of the layer.
+    pub fn set_mss(&mut self, mss: u16) {
+        self.set_option(TcpOptionNumbers::MSS, TcpOption::mss(mss));
+    }
+
+    /// Get the window scale option of the layer.
+    pub fn get_wscale(&self) -> Option<u8> {
+        match self.get_option(TcpOptionNumbers::WSCALE) {
+            Some(ref data) if data.len() == 1 => Some(data[0]),
+            _ => None,
+        }
+    }
+
+    /// Sets the window scale option of the layer.
+    pub fn set_wscale(&mut self, wscale: u8) {
+        self.set_option(TcpOptionNumbers::WSCALE, TcpOption::wscale(wscale));
+    }
+
+    /// Returns if the `Tcp` has the SACK permitted option.
+    pub fn is_sack_perm(&self) -> bool {
+        self.get_option(TcpOptionNumbers::SACK_PERMITTED).is_some()
+    }
+
+    /// Sets the SACK permitted option of the layer.
+    pub fn set_sack_perm(&mut self) {
+        self.set_option(TcpOptionNumbers::SACK_PERMITTED, TcpOption::sack_perm());
+    }
+
+    /// Get the SACK blocks of the layer, each block is represented by its left edge and right
+    /// edge.
+    pub fn get_sacks(&self) -> Vec<(u32, u32)> {
+        let mut sacks = Vec::new();
+        if let Some(data) = self.get_option(TcpOptionNumbers::SACK) {
+            for block in data.chunks_exact(8) {
+                let left = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+                let right = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+                sacks.push((left, right));
+            }
+        }
+
+        sacks
+    }
+
+    /// Sets the SACK blocks of the layer. At most 4 blocks will be set.
+    pub fn set_sacks(&mut self, sacks: &[(u32, u32)]) {
+        if sacks.is_empty() {
+            return;
+        }
+
+        let mut acks = Vec::new();
+        for (left, right) in sacks.iter().take(MAX_SACKS) {
+            acks.push(*left);
+            acks.push(*right);
+        }
+        self.set_option(TcpOptionNumbers::SACK, TcpOption::selective_ack(&acks));
+    }
+
+    /// Get the timestamps option of the layer, represented by the timestamp value and the
+    /// timestamp echo reply.
+    pub fn get_timestamps(&self) -> Option<(u32, u32)> {
+        match self.get_option(TcpOptionNumbers::TIMESTAMPS) {
+            Some(ref data) if data.len() == 8 => Some((
+                u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+                u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Sets the timestamps option of the layer.
+    pub fn set_timestamps(&mut self, tsval: u32, tsecr: u32) {
+        self.set_option(
+            TcpOptionNumbers::TIMESTAMPS,
+            TcpOption::timestamp(tsval, tsecr),
+        );
+    }
+
+    /// Get the data of the option in the given number of the layer.
+    fn get_option(&self, number: TcpOptionNumber) -> Option<Vec<u8>> {
+        for option in &self.layer.options {
+            let (option_number, data) = parse_option(option);
+            if option_number == number {
+                return Some(data);
+            }
+        }
+
+        None
+    }
+
+    /// Sets an option of the layer, the option in the same number will be replaced.
+    fn set_option(&mut self, number: TcpOptionNumber, option: TcpOption) {
+        self.layer
+            .options
+            .retain(|option| parse_option(option).0 != number);
+        self.layer.options.push(option);
+
+        // Fix length
+        self.layer.data_offset = (self.get_header_size() / 4) as u8;
+    }
+
     /// Returns if the `Tcp` is a TCP acknowledgement.
     pub fn is_ack(&self) -> bool {
         self.layer.flags & TcpFlags::ACK != 0
@@ -247,6 +426,48 @@ impl Tcp {
         self.layer.flags & TcpFlags::FIN != 0
     }
 
+    /// Returns if the `Tcp` has the push flag.
+    pub fn is_psh(&self) -> bool {
+        self.layer.flags & TcpFlags::PSH != 0
+    }
+
+    /// Returns if the `Tcp` has the urgent flag.
+    pub fn is_urg(&self) -> bool {
+        self.layer.flags & TcpFlags::URG != 0
+    }
+
+    /// Get the urgent pointer of the layer, which is meaningful only with the urgent flag.
+    pub fn get_urgent_pointer(&self) -> u16 {
+        self.layer.urgent_ptr
+    }
+
+    /// Returns if the `Tcp` is in an illegal combination of flags, a SYN with a FIN or a RST, or
+    /// a segment without any of SYN, ACK, RST and FIN.
+    pub fn is_illegal(&self) -> bool {
+        (self.is_syn() && (self.is_fin() || self.is_rst()))
+            || (!self.is_syn() && !self.is_ack() && !self.is_rst() && !self.is_fin())
+    }
+
+    /// Returns if the `Tcp` has the ECN-Echo flag.
+    pub fn is_ece(&self) -> bool {
+        self.layer.flags & TcpFlags::ECE != 0
+    }
+
+    /// Sets the ECN-Echo flag of the layer.
+    pub fn set_ece(&mut self) {
+        self.layer.flags |= TcpFlags::ECE;
+    }
+
+    /// Returns if the `Tcp` has the congestion window reduced flag.
+    pub fn is_cwr(&self) -> bool {
+        self.layer.flags & TcpFlags::CWR != 0
+    }
+
+    /// Sets the congestion window reduced flag of the layer.
+    pub fn set_cwr(&mut self) {
+        self.layer.flags |= TcpFlags::CWR;
+    }
+
     /// Returns if the `Tcp` is a TCP reset or finish.
     pub fn is_rst_or_fin(&self) -> bool {
         self.is_rst() || self.is_fin()
@@ -258,6 +479,15 @@ impl Tcp {
     }
 }
 
+/// Parses the number and the data of a TCP option.
+fn parse_option(option: &TcpOption) -> (TcpOptionNumber, Vec<u8>) {
+    let mut buffer = vec![0u8; TcpOptionPacket::packet_size(option)];
+    let mut packet = MutableTcpOptionPacket::new(&mut buffer).unwrap();
+    packet.populate(option);
+
+    (packet.get_number(), packet.payload().to_vec())
+}
+
 impl Display for Tcp {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(
@@ -276,70 +506,209 @@ impl Layer for Tcp {
         LayerTypes::Tcp
     }
 
-    fn get_size(&self) -> usize {
-        let mut tcp_size = TcpPacket::packet_size(&self.layer);
-        let mut tcp_options_size = 0;
-        for option in &self.layer.options {
-            tcp_size -= 1;
-            tcp_options_size += TcpOptionPacket::packet_size(option);
+    fn get_details(&self, verbosity: Verbosity) -> String {
+        let mut details = String::new();
+        if verbosity >= Verbosity::Normal {
+            details.push_str(&format!(
+                ", Sequence = {}, Acknowledgement = {}, Window = {}, Checksum = {:#06x}",
+                self.layer.sequence,
+                self.layer.acknowledgement,
+                self.layer.window,
+                self.layer.checksum
+            ));
+        }
+        if verbosity >= Verbosity::Verbose {
+            details.push_str(&format!(
+                ", Urgent Pointer = {}, Options = {} Bytes",
+                self.layer.urgent_ptr,
+                self.get_options_size()
+            ));
+            if let Some(mss) = self.get_mss() {
+                details.push_str(&format!(", MSS = {}", mss));
+            }
+            if let Some(wscale) = self.get_wscale() {
+                details.push_str(&format!(", Window Scale = {}", wscale));
+            }
+            if self.is_sack_perm() {
+                details.push_str(", SACK Permitted");
+            }
+            for (left, right) in self.get_sacks() {
+                details.push_str(&format!(", SACK = {}-{}", left, right));
+            }
+            if let Some((ts, ecr)) = self.get_timestamps() {
+                details.push_str(&format!(", Timestamps = {} {}", ts, ecr));
+            }
+            details.push_str(&format!(", Payload = {} Bytes", self.payload.len()));
         }
 
-        tcp_size + tcp_options_size
+        details
     }
 
-    fn serialize(&self, buffer: &mut [u8], _: usize) -> io::Result<usize> {
-        let mut packet = MutableTcpPacket::new(buffer)
-            .ok_or(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
+    fn get_size(&self) -> usize {
+        self.get_header_size() + self.payload.len()
+    }
+
+    fn get_payload_size(&self) -> usize {
+        self.payload.len()
+    }
+
+    fn serialize(&self, buffer: &mut [u8], _: usize) -> Result {
+        let required = self.get_size();
+        if buffer.len() < required {
+            return Err(SerializeError::BufferTooSmall {
+                t: self.get_type(),
+                required,
+                actual: buffer.len(),
+            });
+        }
+
+        let mut packet = MutableTcpPacket::new(&mut buffer[..required]).unwrap();
 
         packet.populate(&self.layer);
 
+        // Pad options with the end of option list
+        let options_size = self.get_options_size();
+        let header_length = self.get_header_size();
+        for b in
+            &mut packet.packet_mut()[TcpPacket::minimum_packet_size() + options_size..header_length]
+        {
+            *b = 0;
+        }
+
+        // Copies payload after the padded options
+        packet.packet_mut()[header_length..].copy_from_slice(&self.payload);
+
         // Fix length
-        let header_length = self.get_size();
         if header_length / 4 > u8::MAX as usize {
-            return Err(io::Error::new(io::ErrorKind::Other, "TCP too big"));
+            return Err(SerializeError::HeaderTooLarge {
+                t: self.get_type(),
+                size: header_length,
+            });
         }
         packet.set_data_offset((header_length / 4) as u8);
 
         // Compute checksum
-        let checksum = tcp::ipv4_checksum(
-            &packet.to_immutable(),
-            &self.get_src_ip_addr(),
-            &self.get_dst_ip_addr(),
-        );
+        let checksum = match (self.get_src_ip_addr(), self.get_dst_ip_addr()) {
+            (IpAddr::V4(ref src), IpAddr::V4(ref dst)) => {
+                tcp::ipv4_checksum(&packet.to_immutable(), src, dst)
+            }
+            (IpAddr::V6(ref src), IpAddr::V6(ref dst)) => {
+                tcp::ipv6_checksum(&packet.to_immutable(), src, dst)
+            }
+            _ => return Err(SerializeError::IpVersionMismatch { t: self.get_type() }),
+        };
         packet.set_checksum(checksum);
 
-        Ok(header_length)
+        Ok(self.get_size())
     }
 
-    fn serialize_with_payload(
-        &self,
-        buffer: &mut [u8],
-        payload: &[u8],
-        n: usize,
-    ) -> io::Result<usize> {
-        let mut packet = MutableTcpPacket::new(buffer)
-            .ok_or(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
+    fn serialize_with_payload(&self, buffer: &mut [u8], payload: &[u8], _: usize) -> Result {
+        let required = self.get_header_size() + payload.len();
+        if buffer.len() < required {
+            return Err(SerializeError::BufferTooSmall {
+                t: self.get_type(),
+                required,
+                actual: buffer.len(),
+            });
+        }
+
+        let mut packet = MutableTcpPacket::new(&mut buffer[..required]).unwrap();
 
         packet.populate(&self.layer);
 
+        // Pad options with the end of option list
+        let options_size = self.get_options_size();
+        let header_length = self.get_header_size();
+        for b in
+            &mut packet.packet_mut()[TcpPacket::minimum_packet_size() + options_size..header_length]
+        {
+            *b = 0;
+        }
+
         // Copies payload
         packet.set_payload(payload);
 
         // Fix length
-        let header_length = self.get_size();
         if header_length / 4 > u8::MAX as usize {
-            return Err(io::Error::new(io::ErrorKind::Other, "TCP too big"));
+            return Err(SerializeError::HeaderTooLarge {
+                t: self.get_type(),
+                size: header_length,
+            });
         }
         packet.set_data_offset((header_length / 4) as u8);
 
         // Compute checksum
-        let checksum = tcp::ipv4_checksum(
-            &packet.to_immutable(),
-            &self.get_src_ip_addr(),
-            &self.get_dst_ip_addr(),
-        );
+        let checksum = match (self.get_src_ip_addr(), self.get_dst_ip_addr()) {
+            (IpAddr::V4(ref src), IpAddr::V4(ref dst)) => {
+                tcp::ipv4_checksum(&packet.to_immutable(), src, dst)
+            }
+            (IpAddr::V6(ref src), IpAddr::V6(ref dst)) => {
+                tcp::ipv6_checksum(&packet.to_immutable(), src, dst)
+            }
+            _ => return Err(SerializeError::IpVersionMismatch { t: self.get_type() }),
+        };
         packet.set_checksum(checksum);
 
-        Ok(header_length + n)
+        Ok(required)
     }
 }
+
+impl LayerDeserialize for Tcp {
+    fn deserialize(
+        buffer: &[u8],
+        context: &ParseContext,
+    ) -> result::Result<(Tcp, usize), ParseError> {
+        let (src, dst) = context.get_ip_addrs(LayerTypes::Tcp)?;
+        let packet = TcpPacket::new(buffer).ok_or(ParseError::BufferTooSmall {
+            t: LayerTypes::Tcp,
+            required: TcpPacket::minimum_packet_size(),
+            actual: buffer.len(),
+        })?;
+        let header_length = packet.get_data_offset() as usize * 4;
+        if header_length < TcpPacket::minimum_packet_size() || header_length > buffer.len() {
+            return Err(ParseError::InvalidHeaderLength {
+                t: LayerTypes::Tcp,
+                length: header_length,
+            });
+        }
+        let options = &buffer[TcpPacket::minimum_packet_size()..header_length];
+        super::validate_options(LayerTypes::Tcp, options, 0xff)?;
+        validate_option_lengths(options)?;
+
+        Ok((
+            Tcp::parse_in(&packet, src, dst, context.frame.as_ref()),
+            buffer.len(),
+        ))
+    }
+}
+
+/// Validates the lengths of the TCP options of fixed sizes and of the SACK option, whose blocks
+/// are 8 Bytes each. The options must be validated by `validate_options` before.
+fn validate_option_lengths(options: &[u8]) -> result::Result<(), ParseError> {
+    let mut i = 0;
+    while i < options.len() {
+        let number = TcpOptionNumber(options[i]);
+        if number == TcpOptionNumbers::EOL {
+            break;
+        }
+        if number == TcpOptionNumbers::NOP {
+            i = i + 1;
+            continue;
+        }
+        let length = options[i + 1] as usize;
+        let is_valid = match number {
+            TcpOptionNumbers::MSS => length == 4,
+            TcpOptionNumbers::WSCALE => length == 3,
+            TcpOptionNumbers::SACK_PERMITTED => length == 2,
+            TcpOptionNumbers::SACK => length > 2 && (length - 2) % 8 == 0,
+            TcpOptionNumbers::TIMESTAMPS => length == 10,
+            _ => true,
+        };
+        if !is_valid {
+            return Err(ParseError::InvalidOption { t: LayerTypes::Tcp });
+        }
+        i = i + length;
+    }
+
+    Ok(())
+}
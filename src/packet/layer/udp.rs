@@ -1,21 +1,30 @@
-pub use super::{Layer, LayerType, LayerTypes};
+use super::checksum;
+use super::payload::{Frame, Payload};
+pub use super::{
+    Layer, LayerDeserialize, LayerType, LayerTypes, ParseContext, ParseError, Result,
+    SerializeError, Verbosity,
+};
+use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
+use pnet::packet::Packet;
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
-use std::io;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+use std::result;
 
-/// Represents an UDP packet.
+/// Represents an UDP packet. The payload is kept out of the layer of pnet, which is always
+/// empty, so payloads parsed from a shared frame are never copied.
 #[derive(Clone, Debug)]
 pub struct Udp {
     pub layer: udp::Udp,
-    pub src: Ipv4Addr,
-    pub dst: Ipv4Addr,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub payload: Payload,
 }
 
 impl Udp {
     /// Creates an `Udp`.
-    pub fn new(src_ip_addr: Ipv4Addr, dst_ip_addr: Ipv4Addr, src: u16, dst: u16) -> Udp {
+    pub fn new(src_ip_addr: IpAddr, dst_ip_addr: IpAddr, src: u16, dst: u16) -> Udp {
         Udp {
             layer: udp::Udp {
                 source: src,
@@ -26,21 +35,45 @@ impl Udp {
             },
             src: src_ip_addr,
             dst: dst_ip_addr,
+            payload: Payload::new(),
         }
     }
 
     /// Creates an `Udp` according to the given `Udp`.
-    pub fn from(udp: udp::Udp, src: Ipv4Addr, dst: Ipv4Addr) -> Udp {
+    pub fn from(mut udp: udp::Udp, src: IpAddr, dst: IpAddr) -> Udp {
+        let payload = Payload::copy_from(&udp.payload);
+        udp.payload = vec![];
+
         Udp {
             layer: udp,
             src,
             dst,
+            payload,
         }
     }
 
-    /// Creates an `Udp` according to the given UDP packet, source and destination.
-    pub fn parse(packet: &UdpPacket, src: Ipv4Addr, dst: Ipv4Addr) -> Udp {
-        Udp {
+    /// Creates an `Udp` according to the given UDP packet, source and destination. The payload is
+    /// truncated to the length declared in the header, and `None` is returned if the declared
+    /// length exceeds the packet.
+    pub fn parse(packet: &UdpPacket, src: IpAddr, dst: IpAddr) -> Option<Udp> {
+        Udp::parse_in(packet, src, dst, None)
+    }
+
+    /// Creates an `Udp` according to the given UDP packet in the frame, the payload refers to the
+    /// frame.
+    fn parse_in(
+        packet: &UdpPacket,
+        src: IpAddr,
+        dst: IpAddr,
+        frame: Option<&Frame>,
+    ) -> Option<Udp> {
+        let length = packet.get_length() as usize;
+        let header_length = UdpPacket::minimum_packet_size();
+        if length < header_length || length > packet.packet().len() {
+            return None;
+        }
+
+        Some(Udp {
             layer: udp::Udp {
                 source: packet.get_source(),
                 destination: packet.get_destination(),
@@ -50,16 +83,17 @@ impl Udp {
             },
             src,
             dst,
-        }
+            payload: Payload::from_frame(frame, &packet.payload()[..length - header_length]),
+        })
     }
 
     /// Get the source IP address of the layer.
-    pub fn get_src_ip_addr(&self) -> Ipv4Addr {
+    pub fn get_src_ip_addr(&self) -> IpAddr {
         self.src
     }
 
     /// Get the destination IP address of the layer.
-    pub fn get_dst_ip_addr(&self) -> Ipv4Addr {
+    pub fn get_dst_ip_addr(&self) -> IpAddr {
         self.dst
     }
 
@@ -77,6 +111,54 @@ impl Udp {
     pub fn get_length(&self) -> u16 {
         self.layer.length
     }
+
+    /// Get the payload of the layer.
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Returns if the checksum of the layer is valid. A zero checksum means no checksum in IPv4,
+    /// but it is illegal in IPv6.
+    pub fn verify_checksum(&self) -> bool {
+        if self.layer.checksum == 0 {
+            return self.get_src_ip_addr().is_ipv4();
+        }
+
+        // The sum of the pseudo-header, the header and the payload is all ones if the checksum is
+        // valid, so the layer is not serialized again
+        let mut sum = match (self.get_src_ip_addr(), self.get_dst_ip_addr()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                checksum::sum(&src.octets()) + checksum::sum(&dst.octets())
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                checksum::sum(&src.octets()) + checksum::sum(&dst.octets())
+            }
+            _ => return false,
+        };
+        sum += IpNextHeaderProtocols::Udp.0 as u32 + self.layer.length as u32;
+        sum += self.layer.source as u32
+            + self.layer.destination as u32
+            + self.layer.length as u32
+            + self.layer.checksum as u32;
+        sum += checksum::sum(&self.payload);
+
+        checksum::fold(sum) == 0xffff
+    }
+
+    fn compute_checksum(&self, packet: &UdpPacket) -> result::Result<u16, SerializeError> {
+        let checksum = match (self.get_src_ip_addr(), self.get_dst_ip_addr()) {
+            (IpAddr::V4(ref src), IpAddr::V4(ref dst)) => udp::ipv4_checksum(packet, src, dst),
+            (IpAddr::V6(ref src), IpAddr::V6(ref dst)) => udp::ipv6_checksum(packet, src, dst),
+            _ => return Err(SerializeError::IpVersionMismatch { t: self.get_type() }),
+        };
+
+        // A computed zero checksum is transmitted as all ones (RFC 768)
+        if checksum == 0 {
+            Ok(0xffff)
+        } else {
+            Ok(checksum)
+        }
+    }
 }
 
 impl Display for Udp {
@@ -97,67 +179,114 @@ impl Layer for Udp {
         LayerTypes::Udp
     }
 
+    fn get_details(&self, verbosity: Verbosity) -> String {
+        let mut details = String::new();
+        if verbosity >= Verbosity::Normal {
+            details.push_str(&format!(", Checksum = {:#06x}", self.layer.checksum));
+        }
+        if verbosity >= Verbosity::Verbose {
+            details.push_str(&format!(", Payload = {} Bytes", self.payload.len()));
+        }
+
+        details
+    }
+
     fn get_size(&self) -> usize {
-        UdpPacket::packet_size(&self.layer)
+        UdpPacket::minimum_packet_size() + self.payload.len()
     }
 
-    fn serialize(&self, buffer: &mut [u8], n: usize) -> io::Result<usize> {
-        let mut packet = MutableUdpPacket::new(buffer)
-            .ok_or(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
+    fn get_payload_size(&self) -> usize {
+        self.payload.len()
+    }
+
+    fn serialize(&self, buffer: &mut [u8], n: usize) -> Result {
+        let required = self.get_size();
+        if buffer.len() < required {
+            return Err(SerializeError::BufferTooSmall {
+                t: self.get_type(),
+                required,
+                actual: buffer.len(),
+            });
+        }
+
+        let mut packet = MutableUdpPacket::new(&mut buffer[..required]).unwrap();
 
         packet.populate(&self.layer);
 
-        // Fix length
-        if n > u16::MAX as usize {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "length too big",
-            ));
+        // Copies payload
+        packet.set_payload(&self.payload);
+
+        // Fix length, which is the size of the layer and the payload
+        debug_assert_eq!(n, required, "serialize {} of {} Bytes", self.get_type(), n);
+        if required > u16::MAX as usize {
+            return Err(SerializeError::PayloadTooLarge {
+                t: self.get_type(),
+                length: required,
+            });
         }
-        packet.set_length(n as u16);
+        packet.set_length(required as u16);
 
         // Compute checksum
-        let checksum = udp::ipv4_checksum(
-            &packet.to_immutable(),
-            &self.get_src_ip_addr(),
-            &self.get_dst_ip_addr(),
-        );
+        let checksum = self.compute_checksum(&packet.to_immutable())?;
         packet.set_checksum(checksum);
 
         Ok(self.get_size())
     }
 
-    fn serialize_with_payload(
-        &self,
-        buffer: &mut [u8],
-        payload: &[u8],
-        n: usize,
-    ) -> io::Result<usize> {
-        let mut packet = MutableUdpPacket::new(buffer)
-            .ok_or(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
+    fn serialize_with_payload(&self, buffer: &mut [u8], payload: &[u8], n: usize) -> Result {
+        let required = UdpPacket::minimum_packet_size() + payload.len();
+        if buffer.len() < required {
+            return Err(SerializeError::BufferTooSmall {
+                t: self.get_type(),
+                required,
+                actual: buffer.len(),
+            });
+        }
+
+        let mut packet = MutableUdpPacket::new(&mut buffer[..required]).unwrap();
 
         packet.populate(&self.layer);
 
         // Copies payload
         packet.set_payload(payload);
 
-        // Fix length
-        if n > u16::MAX as usize {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "length too big",
-            ));
+        // Fix length, which is the size of the layer and the payload
+        debug_assert_eq!(n, required, "serialize {} of {} Bytes", self.get_type(), n);
+        if required > u16::MAX as usize {
+            return Err(SerializeError::PayloadTooLarge {
+                t: self.get_type(),
+                length: required,
+            });
         }
-        packet.set_length(n as u16);
+        packet.set_length(required as u16);
 
         // Compute checksum
-        let checksum = udp::ipv4_checksum(
-            &packet.to_immutable(),
-            &self.get_src_ip_addr(),
-            &self.get_dst_ip_addr(),
-        );
+        let checksum = self.compute_checksum(&packet.to_immutable())?;
         packet.set_checksum(checksum);
 
-        Ok(self.get_size() + n)
+        Ok(required)
+    }
+}
+
+impl LayerDeserialize for Udp {
+    fn deserialize(
+        buffer: &[u8],
+        context: &ParseContext,
+    ) -> result::Result<(Udp, usize), ParseError> {
+        let (src, dst) = context.get_ip_addrs(LayerTypes::Udp)?;
+        let packet = UdpPacket::new(buffer).ok_or(ParseError::BufferTooSmall {
+            t: LayerTypes::Udp,
+            required: UdpPacket::minimum_packet_size(),
+            actual: buffer.len(),
+        })?;
+        let layer = Udp::parse_in(&packet, src, dst, context.frame.as_ref()).ok_or(
+            ParseError::InvalidLength {
+                t: LayerTypes::Udp,
+                length: packet.get_length() as usize,
+            },
+        )?;
+        let size = layer.get_length() as usize;
+
+        Ok((layer, size))
     }
 }
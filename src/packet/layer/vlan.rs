@@ -0,0 +1,161 @@
+pub use super::{
+    Layer, LayerDeserialize, LayerType, LayerTypes, ParseContext, ParseError, Result,
+    SerializeError, Verbosity,
+};
+use pnet::packet::ethernet::{EtherType, EtherTypes};
+use pnet::packet::vlan::{self, ClassOfService, MutableVlanPacket, VlanPacket};
+use std::clone::Clone;
+use std::fmt::{self, Display, Formatter};
+use std::result;
+
+/// Represents the size of the VLAN tag.
+pub const VLAN_TAG_SIZE: usize = 4;
+
+/// Represents a VLAN layer, the 802.1Q tag between the Ethernet header and the network layer.
+#[derive(Clone, Debug)]
+pub struct Vlan {
+    pub layer: vlan::Vlan,
+}
+
+impl Vlan {
+    /// Creates a `Vlan`.
+    pub fn new(t: LayerType, priority: u8, dei: bool, vid: u16) -> Option<Vlan> {
+        let ethertype = match t {
+            LayerTypes::Arp => EtherTypes::Arp,
+            LayerTypes::Ipv4 => EtherTypes::Ipv4,
+            LayerTypes::Ipv6 => EtherTypes::Ipv6,
+            _ => return None,
+        };
+
+        Some(Vlan {
+            layer: vlan::Vlan {
+                priority_code_point: ClassOfService::new(priority & 0x07),
+                drop_eligible_indicator: dei as u8,
+                vlan_identifier: vid & 0x0fff,
+                ethertype,
+                payload: vec![],
+            },
+        })
+    }
+
+    /// Creates a `Vlan` with the tag of the given `Vlan` for the network layer of the type.
+    pub fn from_tag(vlan: &Vlan, t: LayerType) -> Option<Vlan> {
+        Vlan::new(
+            t,
+            vlan.get_priority(),
+            vlan.is_drop_eligible(),
+            vlan.get_vid(),
+        )
+    }
+
+    /// Creates a `Vlan` according to the given VLAN packet.
+    pub fn parse(packet: &VlanPacket) -> Vlan {
+        Vlan {
+            layer: vlan::Vlan {
+                priority_code_point: packet.get_priority_code_point(),
+                drop_eligible_indicator: packet.get_drop_eligible_indicator(),
+                vlan_identifier: packet.get_vlan_identifier(),
+                ethertype: packet.get_ethertype(),
+                payload: vec![],
+            },
+        }
+    }
+
+    /// Get the priority code point of the layer.
+    pub fn get_priority(&self) -> u8 {
+        self.layer.priority_code_point.0
+    }
+
+    /// Returns if the frame is eligible to be dropped.
+    pub fn is_drop_eligible(&self) -> bool {
+        self.layer.drop_eligible_indicator != 0
+    }
+
+    /// Get the VLAN identifier of the layer.
+    pub fn get_vid(&self) -> u16 {
+        self.layer.vlan_identifier
+    }
+
+    /// Get the inner EtherType of the layer.
+    pub fn get_ethertype(&self) -> EtherType {
+        self.layer.ethertype
+    }
+}
+
+impl Display for Vlan {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: VID = {}, Priority = {}",
+            LayerTypes::Vlan,
+            self.layer.vlan_identifier,
+            self.layer.priority_code_point.0
+        )
+    }
+}
+
+impl Layer for Vlan {
+    fn get_type(&self) -> LayerType {
+        LayerTypes::Vlan
+    }
+
+    fn get_details(&self, verbosity: Verbosity) -> String {
+        let mut details = String::new();
+        if verbosity >= Verbosity::Normal {
+            details.push_str(&format!(", DEI = {}", self.layer.drop_eligible_indicator));
+        }
+        if verbosity >= Verbosity::Verbose {
+            details.push_str(&format!(", Type = {:#06x}", self.layer.ethertype.0));
+        }
+
+        details
+    }
+
+    fn get_size(&self) -> usize {
+        VlanPacket::packet_size(&self.layer)
+    }
+
+    fn serialize(&self, buffer: &mut [u8], _: usize) -> Result {
+        let required = self.get_size();
+        if buffer.len() < required {
+            return Err(SerializeError::BufferTooSmall {
+                t: self.get_type(),
+                required,
+                actual: buffer.len(),
+            });
+        }
+
+        let mut packet = MutableVlanPacket::new(&mut buffer[..required]).unwrap();
+
+        packet.populate(&self.layer);
+
+        Ok(self.get_size())
+    }
+
+    fn serialize_with_payload(&self, buffer: &mut [u8], _: &[u8], n: usize) -> Result {
+        self.serialize(buffer, n)
+    }
+}
+
+impl LayerDeserialize for Vlan {
+    fn deserialize(buffer: &[u8], _: &ParseContext) -> result::Result<(Vlan, usize), ParseError> {
+        let packet = VlanPacket::new(buffer).ok_or(ParseError::BufferTooSmall {
+            t: LayerTypes::Vlan,
+            required: VlanPacket::minimum_packet_size(),
+            actual: buffer.len(),
+        })?;
+        let layer = Vlan::parse(&packet);
+        // Only one level of tags is supported
+        match layer.get_ethertype() {
+            EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ => {
+                return Err(ParseError::StackedTag {
+                    t: LayerTypes::Vlan,
+                })
+            }
+            _ => {}
+        }
+        let size = layer.get_size();
+
+        Ok((layer, size))
+    }
+}
@@ -1,28 +1,41 @@
-use pnet::packet::arp::ArpPacket;
-use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
-use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
-use pnet::packet::tcp::TcpPacket;
-use pnet::packet::udp::UdpPacket;
+use lru::LruCache;
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::tcp::TcpFlags;
 use pnet::packet::Packet;
-use std::collections::HashMap;
+use std::cmp::{max, min};
 use std::fmt::{self, Display, Formatter};
-use std::io;
-use std::net::Ipv4Addr;
-use std::time::Instant;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::result;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::clock::{Clock, MonotonicClock};
+
+pub mod igmp;
 pub mod layer;
+pub mod verify;
 use layer::arp::Arp;
 use layer::ethernet::Ethernet;
+use layer::icmpv4::Icmpv4;
+use layer::icmpv6::Icmpv6;
 use layer::ipv4::Ipv4;
+use layer::ipv6::Ipv6;
+use layer::payload::{Frame, Payload};
 use layer::tcp::Tcp;
 use layer::udp::Udp;
-use layer::{Layer, LayerType, LayerTypes, Layers};
+use layer::vlan::Vlan;
+use layer::{
+    Layer, LayerDeserialize, LayerType, LayerTypes, Layers, ParseContext, ParseError,
+    SerializeError, Verbosity,
+};
 
 /// Represents a packet indicator.
 #[derive(Debug)]
 pub struct Indicator {
     pub link: Layers,
+    pub vlan: Option<Layers>,
     pub network: Option<Layers>,
     pub transport: Option<Layers>,
 }
@@ -32,113 +45,174 @@ impl Indicator {
     pub fn new(link: Layers, network: Option<Layers>, transport: Option<Layers>) -> Indicator {
         Indicator {
             link,
+            vlan: None,
             network,
             transport,
         }
     }
 
-    /// Creates a `Indicator` by the given Ethernet packet.
-    pub fn parse(packet: &EthernetPacket) -> Indicator {
-        let mut transport = None;
-
-        let link = Layers::Ethernet(Ethernet::parse(packet));
-        let network = match packet.get_ethertype() {
-            EtherTypes::Arp => match ArpPacket::new(packet.payload()) {
-                Some(ref arp_packet) => Some(Layers::Arp(Arp::parse(arp_packet))),
-                None => None,
-            },
-            EtherTypes::Ipv4 => match Ipv4Packet::new(packet.payload()) {
-                Some(ref ipv4_packet) => {
-                    let this_ipv4 = Ipv4::parse(ipv4_packet);
-                    let src = this_ipv4.get_src();
-                    let dst = this_ipv4.get_dst();
-                    let this_ipv4 = Some(Layers::Ipv4(this_ipv4));
-                    // Fragment
-                    if ipv4_packet.get_flags() & Ipv4Flags::MoreFragments == 0
-                        && ipv4_packet.get_fragment_offset() <= 0
-                    {
-                        transport = match ipv4_packet.get_next_level_protocol() {
-                            IpNextHeaderProtocols::Tcp => {
-                                match TcpPacket::new(ipv4_packet.payload()) {
-                                    Some(ref tcp_packet) => {
-                                        Some(Layers::Tcp(Tcp::parse(tcp_packet, src, dst)))
-                                    }
-                                    None => None,
-                                }
-                            }
-                            IpNextHeaderProtocols::Udp => {
-                                match UdpPacket::new(ipv4_packet.payload()) {
-                                    Some(ref udp_packet) => {
-                                        Some(Layers::Udp(Udp::parse(udp_packet, src, dst)))
-                                    }
-                                    None => None,
-                                }
-                            }
-                            _ => None,
-                        };
-                    }
-
-                    this_ipv4
-                }
-                None => None,
-            },
-            _ => None,
-        };
-
+    /// Creates a `Indicator` with a VLAN layer between the link layer and the network layer.
+    pub fn with_vlan(
+        link: Layers,
+        vlan: Option<Layers>,
+        network: Option<Layers>,
+        transport: Option<Layers>,
+    ) -> Indicator {
         Indicator {
             link,
+            vlan,
             network,
             transport,
         }
     }
 
-    /// Creates a `Indicator` by the given frame.
-    pub fn from(frame: &[u8]) -> Option<Indicator> {
-        match EthernetPacket::new(frame) {
-            Some(ref packet) => Some(Indicator::parse(packet)),
-            None => None,
+    /// Creates a `Indicator` by the given Ethernet packet.
+    pub fn parse(packet: &EthernetPacket) -> result::Result<Indicator, ParseError> {
+        Indicator::from(packet.packet())
+    }
+
+    /// Creates a `Indicator` by the given frame. Payloads are copied out of the frame, so the
+    /// frame can be reused after parsing.
+    pub fn from(frame: &[u8]) -> result::Result<Indicator, ParseError> {
+        Indicator::deserialize(frame, None)
+    }
+
+    /// Creates a `Indicator` by the given shared frame. Payloads refer to the frame instead of
+    /// being copied, so they are only copied once written out.
+    pub fn from_shared(frame: &Frame) -> result::Result<Indicator, ParseError> {
+        Indicator::deserialize(frame, Some(frame))
+    }
+
+    fn deserialize(frame: &[u8], shared: Option<&Frame>) -> result::Result<Indicator, ParseError> {
+        // Link
+        let (ethernet, n) = Ethernet::deserialize(frame, &ParseContext::new())?;
+        let mut ethertype = ethernet.get_ethertype();
+        let link = Layers::Ethernet(ethernet);
+        let mut buffer = &frame[n..];
+
+        // VLAN
+        let vlan = match ethertype {
+            EtherTypes::Vlan => {
+                let (vlan, n) = Vlan::deserialize(buffer, &ParseContext::new())?;
+                ethertype = vlan.get_ethertype();
+                buffer = &buffer[n..];
+
+                Some(Layers::Vlan(vlan))
+            }
+            // Service tags of stacked VLANs
+            EtherTypes::PBridge | EtherTypes::QinQ => {
+                return Err(ParseError::StackedTag {
+                    t: LayerTypes::Vlan,
+                })
+            }
+            _ => None,
+        };
+        // IEEE 802.3 frames carry a length field in LLC instead of an EtherType, which are never
+        // parsed further
+        if ethertype.0 <= layer::ethernet::MAX_LENGTH {
+            return Ok(Indicator::with_vlan(link, vlan, None, None));
         }
+
+        // Network
+        let (network, n) = match Indicator::deserialize_network(ethertype, buffer)? {
+            Some((network, n)) => (network, n),
+            None => return Ok(Indicator::with_vlan(link, vlan, None, None)),
+        };
+
+        // Transport
+        let (protocol, payload, context) = match network {
+            Layers::Ipv4(ref ipv4) => {
+                // Fragment
+                if ipv4.is_fragment() {
+                    return Ok(Indicator::with_vlan(link, vlan, Some(network), None));
+                }
+                (
+                    ipv4.get_next_level_protocol(),
+                    &buffer[n..ipv4.get_total_length() as usize],
+                    ParseContext::with_ip_addrs(
+                        IpAddr::V4(ipv4.get_src()),
+                        IpAddr::V4(ipv4.get_dst()),
+                    ),
+                )
+            }
+            Layers::Ipv6(ref ipv6) => (
+                ipv6.get_next_header(),
+                &buffer[n..n + ipv6.get_payload_length() as usize],
+                ParseContext::with_ip_addrs(IpAddr::V6(ipv6.get_src()), IpAddr::V6(ipv6.get_dst())),
+            ),
+            _ => return Ok(Indicator::with_vlan(link, vlan, Some(network), None)),
+        };
+        let context = context.with_frame(shared);
+        let transport = Indicator::deserialize_transport(protocol, payload, &context)?;
+
+        Ok(Indicator::with_vlan(link, vlan, Some(network), transport))
+    }
+
+    fn deserialize_network(
+        ethertype: EtherType,
+        buffer: &[u8],
+    ) -> result::Result<Option<(Layers, usize)>, ParseError> {
+        let context = ParseContext::new();
+        let layer = match ethertype {
+            EtherTypes::Arp => {
+                let (layer, n) = Arp::deserialize(buffer, &context)?;
+                (Layers::Arp(layer), n)
+            }
+            EtherTypes::Ipv4 => {
+                let (layer, n) = Ipv4::deserialize(buffer, &context)?;
+                (Layers::Ipv4(layer), n)
+            }
+            EtherTypes::Ipv6 => {
+                let (layer, n) = Ipv6::deserialize(buffer, &context)?;
+                (Layers::Ipv6(layer), n)
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(layer))
+    }
+
+    fn deserialize_transport(
+        protocol: IpNextHeaderProtocol,
+        buffer: &[u8],
+        context: &ParseContext,
+    ) -> result::Result<Option<Layers>, ParseError> {
+        let layer = match protocol {
+            IpNextHeaderProtocols::Tcp => Layers::Tcp(Tcp::deserialize(buffer, context)?.0),
+            IpNextHeaderProtocols::Udp => Layers::Udp(Udp::deserialize(buffer, context)?.0),
+            IpNextHeaderProtocols::Icmp => Layers::Icmpv4(Icmpv4::deserialize(buffer, context)?.0),
+            // ICMPv6 is only carried in IPv6
+            IpNextHeaderProtocols::Icmpv6 if matches!(context.src_ip_addr, Some(IpAddr::V6(_))) => {
+                Layers::Icmpv6(Icmpv6::deserialize(buffer, context)?.0)
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(layer))
     }
 
-    /// Get the brief of the `Indicator`.
+    /// Get the brief of the `Indicator`, a line of the transport layer, or the network layer if
+    /// there is not, in the verbosity of logs up to the normal one.
     pub fn brief(&self) -> String {
+        let verbosity = min(layer::get_verbosity(), Verbosity::Normal);
         match self.get_network_type() {
             Some(t) => match t {
                 LayerTypes::Arp => {
                     let layer = self.get_arp().unwrap();
-                    format!("{}", layer)
+                    layer.summary(verbosity)
                 }
-                LayerTypes::Ipv4 => match self.get_transport_type() {
-                    Some(t) => match t {
-                        LayerTypes::Tcp => {
-                            let layer = self.get_tcp().unwrap();
-                            format!(
-                                "{}: {}:{} -> {}:{} {}",
-                                layer.get_type(),
-                                layer.get_src_ip_addr(),
-                                layer.get_src(),
-                                layer.get_dst_ip_addr(),
-                                layer.get_dst(),
-                                layer.get_flag_string(),
-                            )
-                        }
-                        LayerTypes::Udp => {
-                            let layer = self.get_udp().unwrap();
-                            format!(
-                                "{}: {}:{} -> {}:{}, Length = {}",
-                                layer.get_type(),
-                                layer.get_src_ip_addr(),
-                                layer.get_src(),
-                                layer.get_dst_ip_addr(),
-                                layer.get_dst(),
-                                layer.get_length(),
-                            )
-                        }
-                        _ => unreachable!(),
-                    },
+                LayerTypes::Ipv4 => match self.brief_transport(verbosity) {
+                    Some(brief) => brief,
                     None => {
                         let layer = self.get_ipv4().unwrap();
-                        format!("{}", layer)
+                        layer.summary(verbosity)
+                    }
+                },
+                LayerTypes::Ipv6 => match self.brief_transport(verbosity) {
+                    Some(brief) => brief,
+                    None => {
+                        let layer = self.get_ipv6().unwrap();
+                        layer.summary(verbosity)
                     }
                 },
                 _ => unreachable!(),
@@ -146,19 +220,87 @@ impl Indicator {
             None => match self.get_link_type() {
                 LayerTypes::Ethernet => {
                     let layer = self.get_ethernet().unwrap();
-                    format!("{}", layer)
+                    layer.summary(verbosity)
+                }
+                _ => unreachable!(),
+            },
+        }
+    }
+
+    fn brief_transport(&self, verbosity: Verbosity) -> Option<String> {
+        match self.get_transport_type() {
+            Some(t) => match t {
+                LayerTypes::Tcp => {
+                    let layer = self.get_tcp().unwrap();
+                    Some(format!(
+                        "{}: {}:{} -> {}:{} {}{}",
+                        layer.get_type(),
+                        layer.get_src_ip_addr(),
+                        layer.get_src(),
+                        layer.get_dst_ip_addr(),
+                        layer.get_dst(),
+                        layer.get_flag_string(),
+                        layer.get_details(verbosity)
+                    ))
+                }
+                LayerTypes::Udp => {
+                    let layer = self.get_udp().unwrap();
+                    Some(format!(
+                        "{}: {}:{} -> {}:{}, Length = {}{}",
+                        layer.get_type(),
+                        layer.get_src_ip_addr(),
+                        layer.get_src(),
+                        layer.get_dst_ip_addr(),
+                        layer.get_dst(),
+                        layer.get_length(),
+                        layer.get_details(verbosity)
+                    ))
+                }
+                LayerTypes::Icmpv4 => {
+                    let layer = self.get_icmpv4().unwrap();
+                    Some(layer.summary(verbosity))
+                }
+                LayerTypes::Icmpv6 => {
+                    let layer = self.get_icmpv6().unwrap();
+                    Some(layer.summary(verbosity))
                 }
                 _ => unreachable!(),
             },
+            None => None,
         }
     }
 
-    /// Get The size of the `Indicator` when converted into a byte-array.
+    /// Get the details of the `Indicator`, every layer with all the fields in a line.
+    pub fn detailed(&self) -> String {
+        let mut layers = vec![self.link.summary(Verbosity::Verbose)];
+        if let Some(ref vlan) = self.vlan {
+            layers.push(vlan.summary(Verbosity::Verbose));
+        }
+        if let Some(ref network) = self.network {
+            layers.push(network.summary(Verbosity::Verbose));
+        }
+        if let Some(ref transport) = self.transport {
+            layers.push(transport.summary(Verbosity::Verbose));
+        }
+
+        layers.join(" | ")
+    }
+
+    /// Get the brief of the `Indicator`, or the details if logs are in the verbose verbosity.
+    pub fn describe(&self) -> String {
+        match layer::get_verbosity() {
+            Verbosity::Verbose => self.detailed(),
+            _ => self.brief(),
+        }
+    }
+
+    /// Get the exact size of the `Indicator` when converted into a byte-array, which is the sum of
+    /// the sizes of its layers.
     pub fn get_size(&self) -> usize {
         let mut size = 0;
 
         // Link
-        size = size + self.get_link().get_size();
+        size = size + self.get_link_size();
         // Network
         if let Some(network) = self.get_network() {
             size = size + network.get_size();
@@ -171,8 +313,19 @@ impl Indicator {
         size
     }
 
-    /// Serialize the `Indicator` into a byte-array.
-    pub fn serialize(&self, buffer: &mut [u8]) -> io::Result<usize> {
+    /// Get the exact size of the `Indicator` when converted into a byte-array with payload, which
+    /// replaces the payload the transport layer owns.
+    pub fn get_size_with_payload(&self, payload: &[u8]) -> usize {
+        let owned = self
+            .get_transport()
+            .map_or(0, |transport| transport.get_payload_size());
+
+        self.get_size() - owned + payload.len()
+    }
+
+    /// Serialize the `Indicator` into the front of a byte-array, returns the size it occupies in the
+    /// byte-array. The byte-array may be larger than the `Indicator`.
+    pub fn serialize(&self, buffer: &mut [u8]) -> layer::Result {
         let mut begin = 0;
         let mut total = self.get_size();
 
@@ -180,6 +333,12 @@ impl Indicator {
         let m = self.get_link().serialize(&mut buffer[begin..], total)?;
         begin = begin + m;
         total = total - m;
+        // VLAN
+        if let Some(vlan) = self.get_vlan_layer() {
+            let m = vlan.serialize(&mut buffer[begin..], total)?;
+            begin += m;
+            total -= m;
+        };
         // Network
         if let Some(network) = self.get_network() {
             let m = network.serialize(&mut buffer[begin..], total)?;
@@ -191,14 +350,33 @@ impl Indicator {
             let m = transport.serialize(&mut buffer[begin..], total)?;
             begin = begin + m;
         };
+        debug_assert_eq!(begin, self.get_size(), "serialize {}", self.brief());
 
         Ok(begin)
     }
 
-    /// Serialize the `Indicator` into a byte-array with payload.
-    pub fn serialize_with_payload(&self, buffer: &mut [u8], payload: &[u8]) -> io::Result<usize> {
+    /// Converts the `Indicator` into a frame of its exact size, the lengths and checksums of all
+    /// the layers are fixed.
+    pub fn to_vec(&self) -> result::Result<Vec<u8>, SerializeError> {
+        let mut buffer = vec![0u8; self.get_size()];
+        self.serialize(&mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    /// Converts the `Indicator` into a frame of its exact size with payload.
+    pub fn to_vec_with_payload(&self, payload: &[u8]) -> result::Result<Vec<u8>, SerializeError> {
+        let mut buffer = vec![0u8; self.get_size_with_payload(payload)];
+        self.serialize_with_payload(&mut buffer, payload)?;
+
+        Ok(buffer)
+    }
+
+    /// Serialize the `Indicator` into the front of a byte-array with payload, returns the size it
+    /// occupies in the byte-array. The byte-array may be larger than the `Indicator`.
+    pub fn serialize_with_payload(&self, buffer: &mut [u8], payload: &[u8]) -> layer::Result {
         let mut begin = 0;
-        let mut total = self.get_size() + payload.len();
+        let mut total = self.get_size_with_payload(payload);
 
         // Link
         let m = self
@@ -206,6 +384,12 @@ impl Indicator {
             .serialize_with_payload(&mut buffer[begin..], payload, total)?;
         begin = begin + m;
         total = total - m;
+        // VLAN
+        if let Some(vlan) = self.get_vlan_layer() {
+            let m = vlan.serialize_with_payload(&mut buffer[begin..], payload, total)?;
+            begin += m;
+            total -= m;
+        };
         // Network
         if let Some(network) = self.get_network() {
             let m = network.serialize_with_payload(&mut buffer[begin..], payload, total)?;
@@ -213,10 +397,30 @@ impl Indicator {
             total = total - m;
         };
         // Transport
-        if let Some(transport) = self.get_transport() {
-            let m = transport.serialize_with_payload(&mut buffer[begin..], payload, total)?;
-            begin = begin + m;
+        match self.get_transport() {
+            Some(transport) => {
+                let m = transport.serialize_with_payload(&mut buffer[begin..], payload, total)?;
+                begin = begin + m;
+            }
+            None => {
+                // Payload, like in an IPv4 fragment
+                if buffer.len() < begin + payload.len() {
+                    return Err(SerializeError::BufferTooSmall {
+                        t: self.get_network_type().unwrap_or(self.get_link_type()),
+                        required: begin + payload.len(),
+                        actual: buffer.len(),
+                    });
+                }
+                buffer[begin..begin + payload.len()].copy_from_slice(payload);
+                begin = begin + payload.len();
+            }
         };
+        debug_assert_eq!(
+            begin,
+            self.get_size_with_payload(payload),
+            "serialize {}",
+            self.brief()
+        );
 
         Ok(begin)
     }
@@ -240,6 +444,33 @@ impl Indicator {
         None
     }
 
+    /// Get the size of the link layer and the VLAN layer.
+    pub fn get_link_size(&self) -> usize {
+        let mut size = self.get_link().get_size();
+        if let Some(vlan) = self.get_vlan_layer() {
+            size += vlan.get_size();
+        }
+
+        size
+    }
+
+    fn get_vlan_layer(&self) -> Option<&Layers> {
+        if let Some(layer) = &self.vlan {
+            return Some(layer);
+        }
+
+        None
+    }
+
+    /// Get the VLAN.
+    pub fn get_vlan(&self) -> Option<&Vlan> {
+        if let Some(Layers::Vlan(layer)) = self.get_vlan_layer() {
+            return Some(layer);
+        }
+
+        None
+    }
+
     /// Get the network layer.
     pub fn get_network(&self) -> Option<&Layers> {
         if let Some(layer) = &self.network {
@@ -280,6 +511,17 @@ impl Indicator {
         None
     }
 
+    /// Get the IPv6.
+    pub fn get_ipv6(&self) -> Option<&Ipv6> {
+        if let Some(layer) = self.get_network() {
+            if let Layers::Ipv6(layer) = layer {
+                return Some(layer);
+            }
+        }
+
+        None
+    }
+
     /// Get the transport layer.
     pub fn get_transport(&self) -> Option<&Layers> {
         if let Some(layer) = &self.transport {
@@ -319,11 +561,37 @@ impl Indicator {
 
         None
     }
+
+    /// Get the ICMPv4.
+    pub fn get_icmpv4(&self) -> Option<&Icmpv4> {
+        if let Some(layer) = self.get_transport() {
+            if let Layers::Icmpv4(layer) = layer {
+                return Some(layer);
+            }
+        }
+
+        None
+    }
+
+    /// Get the ICMPv6.
+    pub fn get_icmpv6(&self) -> Option<&Icmpv6> {
+        if let Some(layer) = self.get_transport() {
+            if let Layers::Icmpv6(layer) = layer {
+                return Some(layer);
+            }
+        }
+
+        None
+    }
 }
 
 impl Display for Indicator {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let link_string = format!("\n- {} ({} Bytes)", self.link, self.link.get_size());
+        let mut vlan_string = String::new();
+        if let Some(vlan) = &self.vlan {
+            vlan_string = format!("\n- {} ({} Bytes)", vlan, vlan.get_size());
+        }
         let mut network_string = String::new();
         if let Some(network) = &self.network {
             network_string = format!("\n- {} ({} Bytes)", network, network.get_size());
@@ -335,23 +603,332 @@ impl Display for Indicator {
 
         write!(
             f,
-            "Indicator{}{}{}",
-            link_string, network_string, transport_string
+            "Indicator{}{}{}{}",
+            link_string, vlan_string, network_string, transport_string
         )
     }
 }
 
-const EXPIRE_TIME: u128 = 10000;
+/// Represents the size of the Ethernet header.
+const ETHERNET_HEADER_SIZE: usize = 14;
+/// Represents the min size of the IPv4 header.
+const MIN_IPV4_HEADER_SIZE: usize = 20;
+
+/// Rewrites the source and the destination of a serialized IPv4 TCP or UDP frame in place. The
+/// checksums are updated incrementally (RFC 1624) instead of computed again over the payload, so
+/// an invalid checksum is kept invalid. Returns `false` and leaves the frame untouched if the
+/// frame is not a non-fragmented IPv4 TCP or UDP frame.
+pub fn rewrite(frame: &mut [u8], src: SocketAddrV4, dst: SocketAddrV4) -> bool {
+    // Ethernet
+    if frame.len() < ETHERNET_HEADER_SIZE || frame[12..14] != EtherTypes::Ipv4.0.to_be_bytes() {
+        return false;
+    }
+
+    // IPv4
+    let ip = &mut frame[ETHERNET_HEADER_SIZE..];
+    if ip.len() < MIN_IPV4_HEADER_SIZE || ip[0] >> 4 != 4 {
+        return false;
+    }
+    let header_size = (ip[0] & 0x0f) as usize * 4;
+    let flags_fragment_offset = u16::from_be_bytes([ip[6], ip[7]]);
+    if header_size < MIN_IPV4_HEADER_SIZE || flags_fragment_offset & 0x3fff != 0 {
+        return false;
+    }
+    let protocol = IpNextHeaderProtocol::new(ip[9]);
+    let checksum_offset = match protocol {
+        IpNextHeaderProtocols::Tcp => 16,
+        IpNextHeaderProtocols::Udp => 6,
+        _ => return false,
+    };
+    if ip.len() < header_size + checksum_offset + 2 {
+        return false;
+    }
+    #[cfg(feature = "checksum-check")]
+    let is_valid = is_checksum_valid(ip);
+
+    let mut prev_addrs = [0u8; 8];
+    prev_addrs.copy_from_slice(&ip[12..20]);
+    let mut addrs = [0u8; 8];
+    addrs[..4].copy_from_slice(&src.ip().octets());
+    addrs[4..].copy_from_slice(&dst.ip().octets());
+    let checksum = u16::from_be_bytes([ip[10], ip[11]]);
+    let checksum = layer::checksum::update_slice(checksum, &prev_addrs, &addrs);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+    ip[12..20].copy_from_slice(&addrs);
+
+    // TCP or UDP
+    let transport = &mut ip[header_size..];
+    let mut prev_ports = [0u8; 4];
+    prev_ports.copy_from_slice(&transport[..4]);
+    let mut ports = [0u8; 4];
+    ports[..2].copy_from_slice(&src.port().to_be_bytes());
+    ports[2..].copy_from_slice(&dst.port().to_be_bytes());
+    let checksum = u16::from_be_bytes([transport[checksum_offset], transport[checksum_offset + 1]]);
+    // A zero UDP checksum means no checksum
+    if protocol == IpNextHeaderProtocols::Tcp || checksum != 0 {
+        let checksum = layer::checksum::update_slice(checksum, &prev_addrs, &addrs);
+        let mut checksum = layer::checksum::update_slice(checksum, &prev_ports, &ports);
+        // A computed zero checksum is transmitted as all ones (RFC 768)
+        if protocol == IpNextHeaderProtocols::Udp && checksum == 0 {
+            checksum = 0xffff;
+        }
+        transport[checksum_offset..checksum_offset + 2].copy_from_slice(&checksum.to_be_bytes());
+    }
+    transport[..4].copy_from_slice(&ports);
+
+    #[cfg(feature = "checksum-check")]
+    assert_eq!(
+        is_checksum_valid(&frame[ETHERNET_HEADER_SIZE..]),
+        is_valid,
+        "checksum mismatch in rewriting"
+    );
+
+    true
+}
+
+/// Returns if the checksums of a serialized IPv4 TCP or UDP packet are valid, which are computed
+/// in full.
+#[cfg(feature = "checksum-check")]
+fn is_checksum_valid(packet: &[u8]) -> bool {
+    use pnet::packet::ipv4::{self, Ipv4Packet};
+    use pnet::packet::tcp::{self, TcpPacket};
+    use pnet::packet::udp::{self, UdpPacket};
+
+    let packet = Ipv4Packet::new(packet).unwrap();
+    if ipv4::checksum(&packet) != packet.get_checksum() {
+        return false;
+    }
+    let (src, dst) = (packet.get_source(), packet.get_destination());
+    match packet.get_next_level_protocol() {
+        IpNextHeaderProtocols::Tcp => {
+            let tcp = TcpPacket::new(packet.payload()).unwrap();
+            tcp::ipv4_checksum(&tcp, &src, &dst) == tcp.get_checksum()
+        }
+        IpNextHeaderProtocols::Udp => {
+            let udp = UdpPacket::new(packet.payload()).unwrap();
+            let checksum = match udp::ipv4_checksum(&udp, &src, &dst) {
+                0 => 0xffff,
+                checksum => checksum,
+            };
+            udp.get_checksum() == 0 || udp.get_checksum() == checksum
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Represents the modes of checksums of TCP segments and UDP datagrams from sources.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChecksumMode {
+    /// Represents checksums are strict until checksum offloading is detected, then fixed.
+    Auto,
+    /// Represents packets with invalid checksums are dropped.
+    Strict,
+    /// Represents invalid checksums are accepted, and computed again in frames forwarded.
+    Fix,
+    /// Represents invalid checksums are accepted, and kept in frames forwarded.
+    Ignore,
+}
+
+impl ChecksumMode {
+    /// Parses a checksum mode from its name.
+    pub fn parse(s: &str) -> Option<ChecksumMode> {
+        match s {
+            "auto" => Some(ChecksumMode::Auto),
+            "strict" => Some(ChecksumMode::Strict),
+            "fix" => Some(ChecksumMode::Fix),
+            "ignore" => Some(ChecksumMode::Ignore),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ChecksumMode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            ChecksumMode::Auto => write!(f, "auto"),
+            ChecksumMode::Strict => write!(f, "strict"),
+            ChecksumMode::Fix => write!(f, "fix"),
+            ChecksumMode::Ignore => write!(f, "ignore"),
+        }
+    }
+}
+
+/// Represents the state of the checksum of a serialized IPv4 TCP or UDP packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChecksumState {
+    /// Represents the checksum is valid, or the packet cannot be checked.
+    Valid,
+    /// Represents the checksum is left to the NIC in checksum offloading, which is zero or the
+    /// sum of the pseudo-header.
+    Partial,
+    /// Represents the checksum is invalid.
+    Invalid,
+}
+
+/// Get the state of the TCP or UDP checksum of a serialized IPv4 packet. Fragments and packets
+/// of other protocols are valid as their checksums cannot be checked.
+pub fn check_checksum(packet: &[u8]) -> ChecksumState {
+    let (protocol, header_size, total_length, checksum_offset, pseudo_sum) =
+        match get_checksummed(packet) {
+            Some(checksummed) => checksummed,
+            None => return ChecksumState::Valid,
+        };
+    let transport = &packet[header_size..total_length];
+    let checksum = u16::from_be_bytes([transport[checksum_offset], transport[checksum_offset + 1]]);
+    // A zero UDP checksum means no checksum
+    if protocol == IpNextHeaderProtocols::Udp && checksum == 0 {
+        return ChecksumState::Valid;
+    }
+
+    // The sum of the pseudo-header and the packet is all ones if the checksum is valid
+    if layer::checksum::fold(pseudo_sum + layer::checksum::sum(transport)) == 0xffff {
+        ChecksumState::Valid
+    } else if checksum == 0 || checksum == layer::checksum::fold(pseudo_sum) {
+        ChecksumState::Partial
+    } else {
+        ChecksumState::Invalid
+    }
+}
+
+/// Computes the TCP or UDP checksum of a serialized IPv4 packet again in place. Returns `false`
+/// and leaves the packet untouched if the packet is not a non-fragmented IPv4 TCP or UDP packet.
+pub fn fix_checksum(packet: &mut [u8]) -> bool {
+    let (protocol, header_size, total_length, checksum_offset, pseudo_sum) =
+        match get_checksummed(packet) {
+            Some(checksummed) => checksummed,
+            None => return false,
+        };
+    let transport = &mut packet[header_size..total_length];
+    transport[checksum_offset..checksum_offset + 2].copy_from_slice(&[0, 0]);
+    let mut checksum = !layer::checksum::fold(pseudo_sum + layer::checksum::sum(transport));
+    // A computed zero checksum is transmitted as all ones (RFC 768)
+    if protocol == IpNextHeaderProtocols::Udp && checksum == 0 {
+        checksum = 0xffff;
+    }
+    transport[checksum_offset..checksum_offset + 2].copy_from_slice(&checksum.to_be_bytes());
+
+    true
+}
+
+/// Get the protocol, the header size, the total length, the offset of the checksum in the
+/// transport layer and the sum of the pseudo-header of a serialized IPv4 TCP or UDP packet.
+fn get_checksummed(packet: &[u8]) -> Option<(IpNextHeaderProtocol, usize, usize, usize, u32)> {
+    if packet.len() < MIN_IPV4_HEADER_SIZE || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let header_size = (packet[0] & 0x0f) as usize * 4;
+    let total_length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    let flags_fragment_offset = u16::from_be_bytes([packet[6], packet[7]]);
+    if header_size < MIN_IPV4_HEADER_SIZE
+        || total_length > packet.len()
+        || flags_fragment_offset & 0x3fff != 0
+    {
+        return None;
+    }
+    let protocol = IpNextHeaderProtocol::new(packet[9]);
+    let checksum_offset = match protocol {
+        IpNextHeaderProtocols::Tcp => 16,
+        IpNextHeaderProtocols::Udp => 6,
+        _ => return None,
+    };
+    if total_length < header_size + checksum_offset + 2 {
+        return None;
+    }
+
+    let length = total_length - header_size;
+    let pseudo_sum = layer::checksum::sum(&packet[12..20]) + protocol.0 as u32 + length as u32;
+
+    Some((
+        protocol,
+        header_size,
+        total_length,
+        checksum_offset,
+        pseudo_sum,
+    ))
+}
+
+/// Splits a TCP segment coalesced by segmentation offload, whose IPv4 packet exceeds the MTU,
+/// into segments fitting the MTU. Each segment carries the headers and the options of the
+/// coalesced one with its sequence advanced, the CWR flag is only kept on the first segment, and
+/// the PSH and FIN flags are only kept on the last one. Returns the indicators and the frames of
+/// the segments, or `None` if the packet is not such a segment.
+pub fn segment(indicator: &Indicator, mtu: usize) -> Option<Vec<(Indicator, Vec<u8>)>> {
+    let ipv4 = indicator.get_ipv4()?;
+    let tcp = indicator.get_tcp()?;
+    let payload = tcp.get_payload();
+    if ipv4.is_fragment() || tcp.is_syn() || ipv4.get_total_length() as usize <= mtu {
+        return None;
+    }
+    let mss = mtu.checked_sub(ipv4.get_size() + tcp.get_header_size())?;
+    if mss == 0 {
+        return None;
+    }
+
+    let mut segments = Vec::with_capacity(payload.len().div_ceil(mss));
+    let mut n = 0;
+    while n < payload.len() {
+        let length = min(mss, payload.len() - n);
+        let mut new_tcp = tcp.clone();
+        new_tcp.layer.sequence = tcp.get_sequence().wrapping_add(n as u32);
+        if n > 0 {
+            new_tcp.layer.flags &= !TcpFlags::CWR;
+        }
+        if n + length < payload.len() {
+            new_tcp.layer.flags &= !(TcpFlags::PSH | TcpFlags::FIN);
+        }
+        new_tcp.payload = Payload::copy_from(&payload[n..n + length]);
+        let new_indicator = Indicator::with_vlan(
+            indicator.get_link().clone(),
+            indicator.vlan.clone(),
+            Some(Layers::Ipv4(Ipv4::segment(ipv4, segments.len() as u16))),
+            Some(Layers::Tcp(new_tcp)),
+        );
+
+        let frame = new_indicator.to_vec().ok()?;
+        let new_indicator = Indicator::from(&frame).ok()?;
+        segments.push((new_indicator, frame));
+
+        n += length;
+    }
+
+    Some(segments)
+}
+
+/// Represents the default timeout of the reassembly of a datagram in seconds.
+pub const DEFAULT_REASSEMBLY_TIMEOUT: u64 = 30;
+/// Represents the max memory of all the incomplete datagrams.
+const MAX_REASSEMBLY_SIZE: usize = 4 * 1024 * 1024;
+/// Represents the max number of incomplete datagrams.
+const MAX_REASSEMBLY_COUNT: usize = 1024;
+/// Represents the max size of the payload of an IPv4 datagram.
+const MAX_IPV4_PAYLOAD_SIZE: usize = u16::MAX as usize - 20;
+/// Represents the max number of fragmentations of an IPv4 datagram.
+const MAX_FRAGMENTATIONS: usize = 64;
+
+/// Get the min size of the header of the transport layer, which the first fragmentation must
+/// cover.
+fn get_min_transport_size(protocol: IpNextHeaderProtocol) -> usize {
+    match protocol {
+        IpNextHeaderProtocols::Tcp => 20,
+        IpNextHeaderProtocols::Udp | IpNextHeaderProtocols::Icmp => 8,
+        _ => 0,
+    }
+}
 
 /// Represents a fragmentation.
 #[derive(Debug)]
 pub struct Fragmentation {
     ethernet: Ethernet,
+    vlan: Option<Vlan>,
     ipv4: Ipv4,
-    transport: Option<Layers>,
     buffer: Vec<u8>,
-    last_seen: Instant,
-    length: usize,
+    /// Represents the received ranges of the payload, which are sorted and never overlap
+    ranges: Vec<(usize, usize)>,
+    /// Represents the length of the payload, which is known after the last fragment is received
+    length: Option<usize>,
+    /// Represents the count of fragmentations received, excluding duplicates
+    count: usize,
+    instant: Instant,
 }
 
 impl Fragmentation {
@@ -366,90 +943,185 @@ impl Fragmentation {
             None => return None,
         };
 
-        let mut frag = Fragmentation {
+        Some(Fragmentation {
             ethernet: ethernet.clone(),
-            ipv4: new_ipv4.clone(),
-            transport: None,
-            // TODO: u16 is not safe
-            buffer: vec![0; u16::MAX as usize],
-            last_seen: Instant::now(),
-            length: 0,
-        };
+            vlan: indicator.get_vlan().cloned(),
+            ipv4: new_ipv4,
+            buffer: Vec::new(),
+            ranges: Vec::new(),
+            length: None,
+            count: 0,
+            instant: Instant::now(),
+        })
+    }
 
-        // Indicator
-        let new_indicator = Indicator::new(
-            Layers::Ethernet(ethernet.clone()),
-            Some(Layers::Ipv4(new_ipv4)),
-            None,
-        );
+    /// Adds a fragmentation. Returns `false` if the fragmentation invalidates the datagram, that
+    /// is, it overlaps the received bytes with different bytes, it conflicts with the length fixed
+    /// by the last fragmentation, it exceeds the max number of fragmentations, or it is the first
+    /// fragmentation but does not cover the header of the transport layer. Exact duplicates are
+    /// accepted and ignored.
+    pub fn add(&mut self, indicator: &Indicator, payload: &[u8]) -> bool {
+        let ipv4 = match indicator.get_ipv4() {
+            Some(ipv4) => ipv4,
+            None => return false,
+        };
+        let begin = (ipv4.get_fragment_offset() as usize) * 8;
+        let end = begin + payload.len();
+        if end > MAX_IPV4_PAYLOAD_SIZE {
+            return false;
+        }
 
-        // Serialize
-        if let Err(_) = new_indicator.serialize(&mut frag.buffer[0..]) {
-            return None;
+        // Tiny fragments (RFC 1858)
+        let protocol = ipv4.get_next_level_protocol();
+        if begin == 0 && payload.len() < get_min_transport_size(protocol) {
+            return false;
+        }
+        if protocol == IpNextHeaderProtocols::Tcp && ipv4.get_fragment_offset() == 1 {
+            return false;
         }
 
-        Some(frag)
-    }
+        // Length
+        if !ipv4.is_more_fragment() {
+            match self.length {
+                Some(length) if length != end => return false,
+                _ => self.length = Some(end),
+            }
+        }
+        if let Some(length) = self.length {
+            if end > length || self.ranges.last().map_or(false, |last| last.1 > length) {
+                return false;
+            }
+        }
 
-    /// Adds a fragmentation.
-    pub fn add(&mut self, indicator: &Indicator, payload: &[u8]) {
-        // Transport
-        if let None = self.transport {
-            if let Some(transport) = indicator.get_transport() {
-                self.transport = Some(transport.clone());
+        // Overlaps must be of the same bytes
+        let mut is_duplicate = false;
+        for &(left, right) in &self.ranges {
+            if right <= begin {
+                continue;
             }
+            if left >= end {
+                break;
+            }
+            let (left, right) = (max(left, begin), min(right, end));
+            if self.buffer[left..right] != payload[left - begin..right - begin] {
+                return false;
+            }
+            is_duplicate = left == begin && right == end;
+        }
+        if is_duplicate {
+            return true;
         }
+        if self.count >= MAX_FRAGMENTATIONS {
+            return false;
+        }
+        self.count += 1;
 
         // Payload
-        let ipv4 = match indicator.get_ipv4() {
-            Some(ipv4) => ipv4,
-            None => return,
-        };
-        let offset = (ipv4.get_fragment_offset() as usize) * 8;
-        let header_size = self.ethernet.get_size() + self.ipv4.get_size();
+        if self.buffer.len() < end {
+            self.buffer.resize(end, 0);
+        }
+        self.buffer[begin..end].copy_from_slice(payload);
+
+        // Merge ranges
+        self.ranges.push((begin, end));
+        self.ranges.sort_unstable();
+        let mut ranges: Vec<(usize, usize)> = Vec::with_capacity(self.ranges.len());
+        for &(left, right) in &self.ranges {
+            match ranges.last_mut() {
+                Some(last) if left <= last.1 => last.1 = max(last.1, right),
+                _ => ranges.push((left, right)),
+            }
+        }
+        self.ranges = ranges;
 
-        self.buffer[header_size + offset..].copy_from_slice(payload);
-        self.length += payload.len();
+        true
     }
 
-    /// Concatenates fragmentations and returns an indicator of the buffer and the buffer itself.
-    pub fn concatenate(&self) -> (Indicator, &[u8]) {
-        let new_indicator = Indicator::new(
+    /// Concatenates fragmentations and returns an indicator of the datagram and the frame of the
+    /// datagram. Returns `None` if the datagram cannot be parsed.
+    pub fn concatenate(&self) -> Option<(Indicator, Vec<u8>)> {
+        let new_indicator = Indicator::with_vlan(
             Layers::Ethernet(self.ethernet.clone()),
+            self.vlan.clone().map(Layers::Vlan),
             Some(Layers::Ipv4(self.ipv4.clone())),
-            self.transport.clone(),
+            None,
         );
 
-        let header_size = self.ethernet.get_size() + self.ipv4.get_size();
+        let frame = match new_indicator.to_vec_with_payload(&self.buffer) {
+            Ok(frame) => frame,
+            Err(_) => return None,
+        };
 
-        (new_indicator, &self.buffer[0..header_size + self.length])
+        match Indicator::from(&frame) {
+            Ok(indicator) => Some((indicator, frame)),
+            Err(_) => None,
+        }
     }
 
     /// Returns if the `Fragmentation` is completed.
     pub fn is_completed(&self) -> bool {
-        self.length == self.ipv4.get_total_length() as usize - self.ipv4.get_size()
+        match self.length {
+            Some(length) => self.ranges.len() == 1 && self.ranges[0] == (0, length),
+            None => false,
+        }
     }
 
-    /// Returns if the `Fragmentation` is expired.
-    pub fn is_expired(&self) -> bool {
-        self.last_seen.elapsed().as_millis() > EXPIRE_TIME
+    /// Returns if the `Fragmentation` is expired at the instant in the given timeout.
+    pub fn is_expired(&self, timeout: Duration, now: Instant) -> bool {
+        now.checked_duration_since(self.instant).unwrap_or_default() > timeout
+    }
+
+    /// Get the memory charged for the `Fragmentation`, which covers the `Fragmentation` itself,
+    /// its buffer and its ranges.
+    pub fn get_size(&self) -> usize {
+        mem::size_of::<Fragmentation>()
+            + self.buffer.len()
+            + self.ranges.len() * mem::size_of::<(usize, usize)>()
     }
 }
 
 /// Represents a defragmentation machine.
 #[derive(Debug)]
 pub struct Defraggler {
-    frags: HashMap<(Ipv4Addr, Ipv4Addr, u16), Fragmentation>,
+    frags: LruCache<(Ipv4Addr, Ipv4Addr, u16, u8), Fragmentation>,
+    timeout: Duration,
+    clock: Arc<dyn Clock>,
+    /// Represents the memory charged for all the incomplete datagrams
+    size: usize,
+    /// Represents the count of datagrams discarded for the reassembly timed out
+    expired_count: usize,
+    /// Represents the count of datagrams discarded for the reassembly exceeds the memory limit or
+    /// the count limit
+    evicted_count: usize,
+    /// Represents the count of datagrams discarded for invalid fragmentations or protocols which
+    /// are never relayed
+    invalid_count: usize,
 }
 
 impl Defraggler {
     /// Creates a new empty `Defraggler`.
     pub fn new() -> Defraggler {
         Defraggler {
-            frags: HashMap::new(),
+            frags: LruCache::unbounded(),
+            timeout: Duration::from_secs(DEFAULT_REASSEMBLY_TIMEOUT),
+            clock: Arc::new(MonotonicClock::new()),
+            size: 0,
+            expired_count: 0,
+            evicted_count: 0,
+            invalid_count: 0,
         }
     }
 
+    /// Sets the timeout of the reassembly of a datagram.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets the clock of the timeout of the reassembly.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
     /// Adds a fragmentation and returns the fragmentation if it is completed.
     pub fn add(&mut self, indicator: &Indicator, buffer: &[u8]) -> Option<Fragmentation> {
         let ipv4 = match indicator.get_ipv4() {
@@ -457,36 +1129,649 @@ impl Defraggler {
             None => return None,
         };
 
-        let key = (ipv4.get_src(), ipv4.get_dst(), ipv4.get_identification());
+        // Fragmentations of protocols which are never relayed are not reassembled
+        if get_min_transport_size(ipv4.get_next_level_protocol()) == 0 {
+            self.invalid_count += 1;
 
-        let mut is_create = false;
-        if self.frags.contains_key(&key) {
-            if self.frags.get(&key).unwrap().is_expired() {
-                // Expired
-                is_create = true;
-            }
-        } else {
-            is_create = true;
+            return None;
+        }
+
+        let key = (
+            ipv4.get_src(),
+            ipv4.get_dst(),
+            ipv4.get_identification(),
+            ipv4.get_next_level_protocol().0,
+        );
+
+        // Expired
+        let now = self.clock.now();
+        let is_expired = match self.frags.peek(&key) {
+            Some(frag) => frag.is_expired(self.timeout, now),
+            None => false,
+        };
+        if is_expired {
+            self.remove(&key);
+            self.expired_count += 1;
         }
 
-        if is_create {
-            let frag = match Fragmentation::new(indicator) {
+        if !self.frags.contains(&key) {
+            let mut frag = match Fragmentation::new(indicator) {
                 Some(frag) => frag,
                 None => return None,
             };
+            frag.instant = now;
 
-            self.frags.insert(key, frag);
+            self.size += frag.get_size();
+            self.frags.put(key, frag);
         }
 
         let frag = self.frags.get_mut(&key).unwrap();
 
-        // Add fragmentation
-        let header_size = indicator.get_ethernet().unwrap().get_size() + ipv4.get_size();
-        frag.add(indicator, &buffer[header_size..]);
+        // Add fragmentation, the padding of the frame is excluded
+        let header_size = indicator.get_link_size() + ipv4.get_size();
+        let end = indicator.get_link_size() + ipv4.get_total_length() as usize;
+        if end < header_size || end > buffer.len() {
+            return None;
+        }
+        let prev_size = frag.get_size();
+        let is_valid = frag.add(indicator, &buffer[header_size..end]);
+        self.size = self.size + frag.get_size() - prev_size;
+        if !is_valid {
+            self.remove(&key);
+            self.invalid_count += 1;
+
+            return None;
+        }
+
         if frag.is_completed() {
-            self.frags.remove(&key)
-        } else {
-            None
+            return self.remove(&key);
+        }
+
+        // Evict the least recently used datagrams
+        while self.size > MAX_REASSEMBLY_SIZE || self.frags.len() > MAX_REASSEMBLY_COUNT {
+            match self.frags.pop_lru() {
+                Some((_, frag)) => {
+                    self.size -= frag.get_size();
+                    self.evicted_count += 1;
+                }
+                None => break,
+            }
+        }
+
+        None
+    }
+
+    /// Removes expired datagrams, returns the count of the removed datagrams.
+    pub fn expire(&mut self) -> usize {
+        let timeout = self.timeout;
+        let now = self.clock.now();
+        let keys: Vec<_> = self
+            .frags
+            .iter()
+            .filter(|(_, frag)| frag.is_expired(timeout, now))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &keys {
+            self.remove(key);
+        }
+        self.expired_count += keys.len();
+
+        keys.len()
+    }
+
+    fn remove(&mut self, key: &(Ipv4Addr, Ipv4Addr, u16, u8)) -> Option<Fragmentation> {
+        let frag = self.frags.pop(key);
+        if let Some(ref frag) = frag {
+            self.size -= frag.get_size();
+        }
+
+        frag
+    }
+
+    /// Get the count of datagrams discarded for the reassembly timed out.
+    pub fn get_expired_count(&self) -> usize {
+        self.expired_count
+    }
+
+    /// Get the count of datagrams discarded for the reassembly exceeds the memory limit or the
+    /// count limit.
+    pub fn get_evicted_count(&self) -> usize {
+        self.evicted_count
+    }
+
+    /// Get the count of datagrams discarded for invalid fragmentations or protocols which are
+    /// never relayed.
+    pub fn get_invalid_count(&self) -> usize {
+        self.invalid_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::datalink::MacAddr;
+    use pnet::packet::ipv4::{self, Ipv4Packet};
+    use pnet::packet::tcp::{self, TcpPacket};
+    use pnet::packet::udp::{self, UdpPacket};
+
+    /// Represents a xorshift generator of pseudo-random numbers, which is seeded so failures
+    /// reproduce.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn next_addr(&mut self) -> SocketAddrV4 {
+            SocketAddrV4::new(Ipv4Addr::from(self.next() as u32), self.next() as u16)
+        }
+
+        /// Get a payload of random bytes less than the max size.
+        fn next_payload(&mut self, max_size: usize) -> Vec<u8> {
+            let size = self.next() as usize % max_size;
+            (0..size).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    /// Builds a frame of an IPv4 TCP or UDP packet with full checksums.
+    fn new_frame(t: LayerType, src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+        let transport = match t {
+            LayerTypes::Tcp => Layers::Tcp(Tcp::new_ack(
+                IpAddr::V4(*src.ip()),
+                IpAddr::V4(*dst.ip()),
+                src.port(),
+                dst.port(),
+                0x12345678,
+                0x9abcdef0,
+                65535,
+            )),
+            LayerTypes::Udp => Layers::Udp(Udp::new(
+                IpAddr::V4(*src.ip()),
+                IpAddr::V4(*dst.ip()),
+                src.port(),
+                dst.port(),
+            )),
+            _ => unreachable!(),
+        };
+        let indicator = Indicator::new(
+            Layers::Ethernet(
+                Ethernet::new(
+                    LayerTypes::Ipv4,
+                    MacAddr::new(0x02, 0, 0, 0, 0, 0x02),
+                    MacAddr::new(0x02, 0, 0, 0, 0, 0x01),
+                )
+                .unwrap(),
+            ),
+            Some(Layers::Ipv4(Ipv4::new(1, t, *src.ip(), *dst.ip()).unwrap())),
+            Some(transport),
+        );
+
+        indicator.to_vec_with_payload(payload).unwrap()
+    }
+
+    /// Get the IPv4 checksum and the TCP or UDP checksum of a frame, and their values computed in
+    /// full.
+    fn get_checksums(frame: &[u8]) -> ((u16, u16), (u16, u16)) {
+        let packet = Ipv4Packet::new(&frame[ETHERNET_HEADER_SIZE..]).unwrap();
+        let ipv4 = (packet.get_checksum(), ipv4::checksum(&packet));
+        let (src, dst) = (packet.get_source(), packet.get_destination());
+        let transport = match packet.get_next_level_protocol() {
+            IpNextHeaderProtocols::Tcp => {
+                let tcp = TcpPacket::new(packet.payload()).unwrap();
+                (tcp.get_checksum(), tcp::ipv4_checksum(&tcp, &src, &dst))
+            }
+            IpNextHeaderProtocols::Udp => {
+                let udp = UdpPacket::new(packet.payload()).unwrap();
+                let checksum = match udp::ipv4_checksum(&udp, &src, &dst) {
+                    0 => 0xffff,
+                    checksum => checksum,
+                };
+                (udp.get_checksum(), checksum)
+            }
+            _ => unreachable!(),
+        };
+
+        (ipv4, transport)
+    }
+
+    #[test]
+    fn rewrite_checksums() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for i in 0..2000 {
+            let t = match i % 2 {
+                0 => LayerTypes::Tcp,
+                _ => LayerTypes::Udp,
+            };
+            // Odd and even lengths of payloads
+            let payload = rng.next_payload(1400);
+            let mut frame = new_frame(t, rng.next_addr(), rng.next_addr(), &payload);
+            let (src, dst) = (rng.next_addr(), rng.next_addr());
+
+            assert!(rewrite(&mut frame, src, dst));
+            let (ipv4, transport) = get_checksums(&frame);
+            assert_eq!(ipv4.0, ipv4.1, "IPv4 checksum of {} to {}", src, dst);
+            assert_eq!(
+                transport.0,
+                transport.1,
+                "{} checksum of {} to {} with {} Bytes",
+                t,
+                src,
+                dst,
+                payload.len()
+            );
+            let packet = Ipv4Packet::new(&frame[ETHERNET_HEADER_SIZE..]).unwrap();
+            assert_eq!(
+                (packet.get_source(), packet.get_destination()),
+                (*src.ip(), *dst.ip())
+            );
+            let ports = &packet.payload()[..4];
+            assert_eq!(ports[..2], src.port().to_be_bytes());
+            assert_eq!(ports[2..], dst.port().to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn rewrite_zero_udp_checksum() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..500 {
+            let payload = rng.next_payload(1400);
+            let mut frame = new_frame(LayerTypes::Udp, rng.next_addr(), rng.next_addr(), &payload);
+            // A zero UDP checksum means no checksum, which is kept
+            let offset = ETHERNET_HEADER_SIZE + MIN_IPV4_HEADER_SIZE + 6;
+            frame[offset..offset + 2].copy_from_slice(&[0, 0]);
+            let (src, dst) = (rng.next_addr(), rng.next_addr());
+
+            assert!(rewrite(&mut frame, src, dst));
+            let (ipv4, transport) = get_checksums(&frame);
+            assert_eq!(ipv4.0, ipv4.1);
+            assert_eq!(transport.0, 0);
+        }
+    }
+
+    #[test]
+    fn rewrite_keeps_invalid_checksums() {
+        let mut rng = Rng(0xd1b54a32d192ed03);
+        for i in 0..500 {
+            let t = match i % 2 {
+                0 => LayerTypes::Tcp,
+                _ => LayerTypes::Udp,
+            };
+            let payload = rng.next_payload(1400);
+            let mut frame = new_frame(t, rng.next_addr(), rng.next_addr(), &payload);
+            // Corrupt the payload
+            let last = frame.len() - 1;
+            frame[last] ^= 0x5a;
+
+            assert!(rewrite(&mut frame, rng.next_addr(), rng.next_addr()));
+            let (_, transport) = get_checksums(&frame);
+            assert_ne!(transport.0, transport.1);
+        }
+    }
+
+    #[test]
+    fn parse_padded_frames() {
+        let (src, dst) = (Ipv4Addr::new(10, 6, 0, 1), Ipv4Addr::new(192, 0, 2, 1));
+        let src_hardware_addr = MacAddr::new(0x02, 0, 0, 0, 0, 0x02);
+        let arp = Indicator::new(
+            Layers::Ethernet(
+                Ethernet::new(LayerTypes::Arp, src_hardware_addr, MacAddr::broadcast()).unwrap(),
+            ),
+            Some(Layers::Arp(Arp::new_request(src_hardware_addr, src, dst))),
+            None,
+        )
+        .to_vec()
+        .unwrap();
+        let tcp = new_frame(
+            LayerTypes::Tcp,
+            SocketAddrV4::new(src, 40000),
+            SocketAddrV4::new(dst, 80),
+            &[],
+        );
+        assert_eq!((arp.len(), tcp.len()), (42, 54));
+
+        // Frames are padded to the minimum size with bytes looking like data
+        for frame in &[arp, tcp] {
+            let mut padded = frame.clone();
+            padded.resize(60, 0x45);
+
+            let indicator = Indicator::from(&padded).unwrap();
+            assert_eq!(indicator.get_size(), frame.len());
+            assert_eq!(indicator.to_vec().unwrap(), *frame);
+            if let Some(tcp) = indicator.get_tcp() {
+                assert!(tcp.get_payload().is_empty());
+
+                // The checksum excludes the padding
+                let ((_, _), (checksum, computed)) = get_checksums(&padded);
+                assert_eq!(checksum, computed);
+            }
+
+            let (_, packet) = crate::pcap::get_network(&padded).unwrap();
+            assert_eq!(packet, &frame[ETHERNET_HEADER_SIZE..]);
+        }
+    }
+
+    #[test]
+    fn indicator_snapshots() {
+        let snapshots = [
+            (
+                LayerTypes::Tcp,
+                [
+                    "TCP: 10.6.0.1:40000 -> 192.0.2.1:80 [.]",
+                    "TCP: 10.6.0.1:40000 -> 192.0.2.1:80 [.], Sequence = 305419896, Acknowledgement = 2596069104, Window = 65535, Checksum = 0xa7ab",
+                ],
+                "Ethernet: 02:00:00:00:00:02 -> 02:00:00:00:00:01, Type = 0x0800 | IPv4: 10.6.0.1 -> 192.0.2.1, Length = 47, Identification = 0x0001, TTL = 128, Flags = [], Checksum = 0x6ec0, DSCP = 0, ECN = 0, Protocol = 6, Options = 0 Bytes | TCP: 40000 -> 80 [.], Sequence = 305419896, Acknowledgement = 2596069104, Window = 65535, Checksum = 0xa7ab, Urgent Pointer = 0, Options = 0 Bytes, Payload = 7 Bytes",
+            ),
+            (
+                LayerTypes::Udp,
+                [
+                    "UDP: 10.6.0.1:40000 -> 192.0.2.1:80, Length = 15",
+                    "UDP: 10.6.0.1:40000 -> 192.0.2.1:80, Length = 15, Checksum = 0xda07",
+                ],
+                "Ethernet: 02:00:00:00:00:02 -> 02:00:00:00:00:01, Type = 0x0800 | IPv4: 10.6.0.1 -> 192.0.2.1, Length = 35, Identification = 0x0001, TTL = 128, Flags = [], Checksum = 0x6ec1, DSCP = 0, ECN = 0, Protocol = 17, Options = 0 Bytes | UDP: 40000 -> 80, Length = 15, Checksum = 0xda07, Payload = 7 Bytes",
+            ),
+        ];
+
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 6, 0, 1), 40000);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80);
+        for (t, briefs, detailed) in snapshots.iter() {
+            let frame = new_frame(*t, src, dst, b"payload");
+            let indicator = Indicator::from(&frame).unwrap();
+            assert_eq!(indicator.detailed(), *detailed);
+
+            // The brief is up to the normal verbosity, and the details are described in the
+            // verbose verbosity
+            layer::set_verbosity(Verbosity::Brief);
+            assert_eq!(indicator.brief(), briefs[0]);
+            assert_eq!(indicator.describe(), briefs[0]);
+            layer::set_verbosity(Verbosity::Normal);
+            assert_eq!(indicator.brief(), briefs[1]);
+            assert_eq!(indicator.describe(), briefs[1]);
+            layer::set_verbosity(Verbosity::Verbose);
+            assert_eq!(indicator.brief(), briefs[1]);
+            assert_eq!(indicator.describe(), *detailed);
+            layer::set_verbosity(Verbosity::Brief);
+        }
+    }
+
+    /// Builds a frame of an IPv4 fragment of a UDP datagram, the fragment offset is in 8 bytes.
+    fn new_fragment(
+        identification: u16,
+        fragment_offset: u16,
+        is_more: bool,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let (src, dst) = (Ipv4Addr::new(10, 6, 0, 1), Ipv4Addr::new(192, 0, 2, 1));
+        let ipv4 = match is_more {
+            true => {
+                Ipv4::new_more_fragment(identification, LayerTypes::Udp, fragment_offset, src, dst)
+            }
+            false => {
+                Ipv4::new_last_fragment(identification, LayerTypes::Udp, fragment_offset, src, dst)
+            }
+        };
+        let indicator = Indicator::new(
+            Layers::Ethernet(
+                Ethernet::new(
+                    LayerTypes::Ipv4,
+                    MacAddr::new(0x02, 0, 0, 0, 0, 0x02),
+                    MacAddr::new(0x02, 0, 0, 0, 0, 0x01),
+                )
+                .unwrap(),
+            ),
+            Some(Layers::Ipv4(ipv4.unwrap())),
+            None,
+        );
+
+        indicator.to_vec_with_payload(payload).unwrap()
+    }
+
+    #[test]
+    fn defrag_orphan_first_fragments() {
+        let mut defrag = Defraggler::new();
+        for i in 0..10000 {
+            let frame = new_fragment(i as u16, 0, true, &[0u8; 8]);
+            let indicator = Indicator::from(&frame).unwrap();
+            assert!(defrag.add(&indicator, &frame).is_none());
+
+            assert!(defrag.frags.len() <= MAX_REASSEMBLY_COUNT);
+            assert!(defrag.size <= MAX_REASSEMBLY_SIZE);
+            let size: usize = defrag.frags.iter().map(|(_, frag)| frag.get_size()).sum();
+            assert_eq!(defrag.size, size);
+        }
+        assert_eq!(defrag.frags.len(), MAX_REASSEMBLY_COUNT);
+        assert_eq!(defrag.get_evicted_count(), 10000 - MAX_REASSEMBLY_COUNT);
+
+        // Each datagram is charged beyond its payload
+        assert!(defrag.size > MAX_REASSEMBLY_COUNT * mem::size_of::<Fragmentation>());
+    }
+
+    #[test]
+    fn defrag_orphan_last_fragments() {
+        // A last fragment at a large offset takes a buffer of the whole datagram
+        let mut defrag = Defraggler::new();
+        for i in 0..100 {
+            let frame = new_fragment(i as u16, 8000, false, &[0u8; 8]);
+            let indicator = Indicator::from(&frame).unwrap();
+            assert!(defrag.add(&indicator, &frame).is_none());
+
+            assert!(defrag.size <= MAX_REASSEMBLY_SIZE);
+        }
+        assert!(defrag.get_evicted_count() > 0);
+        assert_eq!(defrag.frags.len() + defrag.get_evicted_count(), 100);
+    }
+
+    #[test]
+    fn defrag_unrelayed_protocols() {
+        let mut frame = new_fragment(1, 0, true, &[0u8; 8]);
+        // GRE
+        frame[ETHERNET_HEADER_SIZE + 9] = 47;
+        let indicator = Indicator::from(&frame).unwrap();
+        assert_eq!(
+            indicator.get_ipv4().unwrap().get_next_level_protocol(),
+            IpNextHeaderProtocol(47)
+        );
+
+        let mut defrag = Defraggler::new();
+        assert!(defrag.add(&indicator, &frame).is_none());
+        assert_eq!(defrag.frags.len(), 0);
+        assert_eq!(defrag.size, 0);
+        assert_eq!(defrag.get_invalid_count(), 1);
+    }
+
+    /// Reassembles a UDP datagram of the payload in 2 fragments.
+    fn defrag_udp(identification: u16, payload: &[u8]) -> Option<Fragmentation> {
+        let mut defrag = Defraggler::new();
+        let first = new_fragment(identification, 0, true, &payload[..16]);
+        let indicator = Indicator::from(&first).unwrap();
+        assert!(defrag.add(&indicator, &first).is_none());
+        let last = new_fragment(identification, 2, false, &payload[16..]);
+        let indicator = Indicator::from(&last).unwrap();
+
+        defrag.add(&indicator, &last)
+    }
+
+    #[test]
+    fn defrag_length_mismatch() {
+        let mut payload = vec![0u8; 40];
+        payload[..4].copy_from_slice(&[0x9c, 0x40, 0x00, 0x35]);
+        for (i, b) in payload[8..].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        // The length of the UDP header matches the reassembled length
+        payload[4..6].copy_from_slice(&40u16.to_be_bytes());
+        let (indicator, _) = defrag_udp(1, &payload).unwrap().concatenate().unwrap();
+        assert_eq!(indicator.get_ipv4().unwrap().get_total_length(), 60);
+        assert_eq!(indicator.get_udp().unwrap().get_payload(), &payload[8..]);
+
+        // The length of the UDP header exceeds the reassembled length
+        payload[4..6].copy_from_slice(&48u16.to_be_bytes());
+        let frag = defrag_udp(2, &payload).unwrap();
+        assert!(frag.is_completed());
+        assert!(frag.concatenate().is_none());
+
+        // The last fragment conflicts with the length fixed by another last fragment
+        let mut defrag = Defraggler::new();
+        let last = new_fragment(3, 2, false, &payload[16..]);
+        let indicator = Indicator::from(&last).unwrap();
+        assert!(defrag.add(&indicator, &last).is_none());
+        let last = new_fragment(3, 2, false, &payload[16..32]);
+        let indicator = Indicator::from(&last).unwrap();
+        assert!(defrag.add(&indicator, &last).is_none());
+        assert_eq!(defrag.frags.len(), 0);
+        assert_eq!(defrag.get_invalid_count(), 1);
+    }
+
+    #[test]
+    fn defrag_tiny_first_fragment() {
+        // The first fragment does not cover the UDP header
+        let mut defrag = Defraggler::new();
+        let first = new_fragment(1, 0, true, &[0u8; 4]);
+        let indicator = Indicator::from(&first).unwrap();
+        assert!(defrag.add(&indicator, &first).is_none());
+        assert_eq!(defrag.frags.len(), 0);
+        assert_eq!(defrag.size, 0);
+        assert_eq!(defrag.get_invalid_count(), 1);
+
+        // The rest of the datagram is never completed without a valid first fragment
+        let last = new_fragment(1, 1, false, &[0u8; 8]);
+        let indicator = Indicator::from(&last).unwrap();
+        assert!(defrag.add(&indicator, &last).is_none());
+        assert_eq!(defrag.frags.len(), 1);
+    }
+
+    #[test]
+    fn parse_fuzz_corpus() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/indicator");
+        let mut count = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let frame = std::fs::read(&path).unwrap();
+            // Each frame in the corpus is malformed
+            assert!(Indicator::from(&frame).is_err(), "parse {}", path.display());
+            count += 1;
+        }
+        assert!(count > 0);
+    }
+
+    #[test]
+    fn indicator_size() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for i in 0..100 {
+            let t = match i % 2 {
+                0 => LayerTypes::Tcp,
+                _ => LayerTypes::Udp,
+            };
+            let payload = rng.next_payload(1400);
+            let frame = new_frame(t, rng.next_addr(), rng.next_addr(), &payload);
+            let indicator = Indicator::from(&frame).unwrap();
+
+            let mut size = indicator.get_link().get_size();
+            if let Some(vlan) = indicator.get_vlan_layer() {
+                size += vlan.get_size();
+            }
+            size += indicator.get_network().unwrap().get_size();
+            size += indicator.get_transport().unwrap().get_size();
+            assert_eq!(indicator.get_size(), size);
+            assert_eq!(indicator.to_vec().unwrap().len(), size);
+            assert_eq!(frame.len(), size);
+        }
+
+        // ARP and VLAN
+        let link = Layers::Ethernet(
+            Ethernet::new(
+                LayerTypes::Vlan,
+                MacAddr::new(0x02, 0, 0, 0, 0, 0x02),
+                MacAddr::new(0x02, 0, 0, 0, 0, 0x01),
+            )
+            .unwrap(),
+        );
+        let vlan = Layers::Vlan(Vlan::new(LayerTypes::Arp, 0, false, 100).unwrap());
+        let network = Layers::Arp(Arp::new_request(
+            MacAddr::new(0x02, 0, 0, 0, 0, 0x02),
+            Ipv4Addr::new(10, 6, 0, 1),
+            Ipv4Addr::new(10, 6, 0, 2),
+        ));
+        let size = link.get_size() + vlan.get_size() + network.get_size();
+        let indicator = Indicator::with_vlan(link, Some(vlan), Some(network), None);
+        assert_eq!(indicator.get_size(), size);
+        assert_eq!(indicator.to_vec().unwrap().len(), size);
+        let payload = [0u8; 16];
+        assert_eq!(
+            indicator.to_vec_with_payload(&payload).unwrap().len(),
+            size + payload.len()
+        );
+    }
+
+    #[test]
+    fn segment_coalesced() {
+        let (src, dst) = (Ipv4Addr::new(10, 6, 0, 1), Ipv4Addr::new(192, 0, 2, 1));
+        let mut rng = Rng(0xd1b54a32d192ed03);
+        let payload: Vec<u8> = (0..32 * 1024).map(|_| rng.next() as u8).collect();
+        // Sequences and identifications wrap in the segments
+        let sequence = u32::MAX - 10000;
+        let identification = u16::MAX - 10;
+
+        let mut tcp = Tcp::new_ack(src.into(), dst.into(), 40000, 80, sequence, 1, 65535);
+        tcp.layer.flags |= TcpFlags::PSH | TcpFlags::FIN | TcpFlags::CWR;
+        tcp.set_timestamps(1, 2);
+        let indicator = Indicator::new(
+            Layers::Ethernet(
+                Ethernet::new(
+                    LayerTypes::Ipv4,
+                    MacAddr::new(0x02, 0, 0, 0, 0, 0x02),
+                    MacAddr::new(0x02, 0, 0, 0, 0, 0x01),
+                )
+                .unwrap(),
+            ),
+            Some(Layers::Ipv4(
+                Ipv4::new(identification, LayerTypes::Tcp, src, dst).unwrap(),
+            )),
+            Some(Layers::Tcp(tcp)),
+        );
+        let frame = indicator.to_vec_with_payload(&payload).unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+
+        let mtu = 1500;
+        let segments = segment(&indicator, mtu).unwrap();
+        // The MSS is the MTU less the IPv4 header, and the TCP header with the timestamps
+        let mss = mtu - 20 - 32;
+        assert_eq!(segments.len(), payload.len().div_ceil(mss));
+
+        let mut new_payload = Vec::new();
+        for (i, (indicator, frame)) in segments.iter().enumerate() {
+            assert_eq!(indicator.to_vec().unwrap(), *frame);
+            assert!(frame.len() - ETHERNET_HEADER_SIZE <= mtu);
+
+            let ipv4 = indicator.get_ipv4().unwrap();
+            assert_eq!(
+                ipv4.get_identification(),
+                identification.wrapping_add(i as u16)
+            );
+            let tcp = indicator.get_tcp().unwrap();
+            assert_eq!(
+                tcp.get_sequence(),
+                sequence.wrapping_add(new_payload.len() as u32)
+            );
+            assert_eq!(tcp.get_acknowledgement(), 1);
+            assert!(tcp.is_ack());
+            assert_eq!(tcp.get_timestamps(), Some((1, 2)));
+            // CWR is only on the first segment, PSH and FIN are only on the last one
+            let is_last = i == segments.len() - 1;
+            assert_eq!(tcp.is_cwr(), i == 0);
+            assert_eq!(tcp.is_psh(), is_last);
+            assert_eq!(tcp.is_fin(), is_last);
+
+            let (ipv4, transport) = get_checksums(frame);
+            assert_eq!(ipv4.0, ipv4.1);
+            assert_eq!(transport.0, transport.1);
+
+            new_payload.extend_from_slice(tcp.get_payload());
         }
+        assert_eq!(new_payload, payload);
     }
 }
@@ -0,0 +1,381 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use super::layer::{checksum, Layer, ParseError};
+use super::{check_checksum, ChecksumState, Indicator};
+
+/// Represents the modes of verifying frames sent to sources before they are transmitted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VerifyMode {
+    /// Represents frames failing the verification are logged and still transmitted.
+    Log,
+    /// Represents the application aborts once a frame fails the verification.
+    Fatal,
+}
+
+impl VerifyMode {
+    /// Parses a verify mode from its name.
+    pub fn parse(s: &str) -> Option<VerifyMode> {
+        match s {
+            "log" => Some(VerifyMode::Log),
+            "fatal" => Some(VerifyMode::Fatal),
+            _ => None,
+        }
+    }
+}
+
+impl Display for VerifyMode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            VerifyMode::Log => write!(f, "log"),
+            VerifyMode::Fatal => write!(f, "fatal"),
+        }
+    }
+}
+
+/// Represents a field of a serialized frame which differs from the layer it is serialized from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mismatch {
+    /// Represents the name of the field
+    pub field: &'static str,
+    /// Represents the value in the layer
+    pub expected: String,
+    /// Represents the value in the frame
+    pub actual: String,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} (expected {})",
+            self.field, self.actual, self.expected
+        )
+    }
+}
+
+/// Represents an error in verifying a serialized frame.
+#[derive(Debug)]
+pub enum VerifyError {
+    ParseError(ParseError),
+    MismatchError(Vec<Mismatch>),
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            VerifyError::ParseError(ref e) => write!(f, "verify: {}", e),
+            VerifyError::MismatchError(ref mismatches) => {
+                let mismatches: Vec<String> = mismatches.iter().map(|m| m.to_string()).collect();
+                write!(f, "verify: {}", mismatches.join(", "))
+            }
+        }
+    }
+}
+
+impl Error for VerifyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self {
+            VerifyError::ParseError(ref e) => Some(e),
+            VerifyError::MismatchError(_) => None,
+        }
+    }
+}
+
+impl From<ParseError> for VerifyError {
+    fn from(e: ParseError) -> Self {
+        VerifyError::ParseError(e)
+    }
+}
+
+/// Records a mismatch if the value in the frame differs from the one in the layer.
+fn compare<T: Display + PartialEq>(
+    mismatches: &mut Vec<Mismatch>,
+    field: &'static str,
+    expected: T,
+    actual: T,
+) {
+    if expected != actual {
+        mismatches.push(Mismatch {
+            field,
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        });
+    }
+}
+
+/// Verifies a frame serialized from the `Indicator` by parsing it again in the same way frames
+/// from sources are parsed. The lengths and the checksums of the frame are verified, and the key
+/// fields are compared against the layers of the `Indicator`.
+pub fn verify(indicator: &Indicator, frame: &[u8]) -> Result<(), VerifyError> {
+    let parsed = Indicator::from(frame)?;
+    let mut mismatches = Vec::new();
+
+    // Link
+    if let (Some(expected), Some(actual)) = (indicator.get_ethernet(), parsed.get_ethernet()) {
+        compare(
+            &mut mismatches,
+            "Ethernet source",
+            expected.get_src(),
+            actual.get_src(),
+        );
+        compare(
+            &mut mismatches,
+            "Ethernet destination",
+            expected.get_dst(),
+            actual.get_dst(),
+        );
+        compare(
+            &mut mismatches,
+            "Ethernet type",
+            expected.get_ethertype(),
+            actual.get_ethertype(),
+        );
+    }
+    compare(
+        &mut mismatches,
+        "VLAN",
+        indicator.get_vlan().map_or(0, |vlan| vlan.get_vid()),
+        parsed.get_vlan().map_or(0, |vlan| vlan.get_vid()),
+    );
+    let begin = parsed.get_link_size();
+
+    // Network
+    compare(
+        &mut mismatches,
+        "network",
+        indicator
+            .get_network_type()
+            .map(|t| t.to_string())
+            .unwrap_or_default(),
+        parsed
+            .get_network_type()
+            .map(|t| t.to_string())
+            .unwrap_or_default(),
+    );
+    if let (Some(expected), Some(actual)) = (indicator.get_arp(), parsed.get_arp()) {
+        compare(
+            &mut mismatches,
+            "ARP source",
+            expected.get_src(),
+            actual.get_src(),
+        );
+        compare(
+            &mut mismatches,
+            "ARP destination",
+            expected.get_dst(),
+            actual.get_dst(),
+        );
+        compare(
+            &mut mismatches,
+            "ARP source hardware address",
+            expected.get_src_hardware_addr(),
+            actual.get_src_hardware_addr(),
+        );
+        compare(
+            &mut mismatches,
+            "ARP destination hardware address",
+            expected.get_dst_hardware_addr(),
+            actual.get_dst_hardware_addr(),
+        );
+    }
+    if let (Some(expected), Some(actual)) = (indicator.get_ipv4(), parsed.get_ipv4()) {
+        compare(
+            &mut mismatches,
+            "IPv4 source",
+            expected.get_src(),
+            actual.get_src(),
+        );
+        compare(
+            &mut mismatches,
+            "IPv4 destination",
+            expected.get_dst(),
+            actual.get_dst(),
+        );
+        compare(
+            &mut mismatches,
+            "IPv4 protocol",
+            expected.get_next_level_protocol(),
+            actual.get_next_level_protocol(),
+        );
+        compare(
+            &mut mismatches,
+            "IPv4 TTL",
+            expected.get_ttl(),
+            actual.get_ttl(),
+        );
+        compare(
+            &mut mismatches,
+            "IPv4 identification",
+            expected.get_identification(),
+            actual.get_identification(),
+        );
+        compare(
+            &mut mismatches,
+            "IPv4 DSCP",
+            expected.get_dscp(),
+            actual.get_dscp(),
+        );
+        compare(
+            &mut mismatches,
+            "IPv4 ECN",
+            expected.get_ecn(),
+            actual.get_ecn(),
+        );
+        compare(
+            &mut mismatches,
+            "IPv4 DF",
+            expected.is_dont_fragment(),
+            actual.is_dont_fragment(),
+        );
+        compare(
+            &mut mismatches,
+            "IPv4 MF",
+            expected.is_more_fragment(),
+            actual.is_more_fragment(),
+        );
+        compare(
+            &mut mismatches,
+            "IPv4 fragment offset",
+            expected.get_fragment_offset(),
+            actual.get_fragment_offset(),
+        );
+        compare(
+            &mut mismatches,
+            "IPv4 total length",
+            frame.len() - begin,
+            actual.get_total_length() as usize,
+        );
+
+        // The sum of the header is all ones if its checksum is valid
+        let ip = &frame[begin..];
+        let header_size = actual.get_size();
+        if ip.len() >= header_size {
+            compare(
+                &mut mismatches,
+                "IPv4 checksum",
+                0xffff,
+                checksum::fold(checksum::sum(&ip[..header_size])),
+            );
+        }
+        if !actual.is_fragment() {
+            if parsed.get_transport().is_some() {
+                let state = match parsed.get_icmpv4() {
+                    Some(_) => {
+                        let end = (actual.get_total_length() as usize)
+                            .min(ip.len())
+                            .max(header_size);
+                        match checksum::fold(checksum::sum(&ip[header_size..end])) {
+                            0xffff => ChecksumState::Valid,
+                            _ => ChecksumState::Invalid,
+                        }
+                    }
+                    None => check_checksum(ip),
+                };
+                if state != ChecksumState::Valid {
+                    mismatches.push(Mismatch {
+                        field: "transport checksum",
+                        expected: String::from("valid"),
+                        actual: format!("{:?}", state).to_lowercase(),
+                    });
+                }
+            }
+        }
+    }
+
+    // Transport, the payload of the frame is in addition to the payload of the layer
+    let payload_size = frame.len().saturating_sub(indicator.get_size());
+    compare(
+        &mut mismatches,
+        "transport",
+        indicator
+            .get_transport_type()
+            .map(|t| t.to_string())
+            .unwrap_or_default(),
+        parsed
+            .get_transport_type()
+            .map(|t| t.to_string())
+            .unwrap_or_default(),
+    );
+    if let (Some(expected), Some(actual)) = (indicator.get_tcp(), parsed.get_tcp()) {
+        compare(
+            &mut mismatches,
+            "TCP source",
+            expected.get_src(),
+            actual.get_src(),
+        );
+        compare(
+            &mut mismatches,
+            "TCP destination",
+            expected.get_dst(),
+            actual.get_dst(),
+        );
+        compare(
+            &mut mismatches,
+            "TCP sequence",
+            expected.get_sequence(),
+            actual.get_sequence(),
+        );
+        compare(
+            &mut mismatches,
+            "TCP acknowledgement",
+            expected.get_acknowledgement(),
+            actual.get_acknowledgement(),
+        );
+        compare(
+            &mut mismatches,
+            "TCP flags",
+            expected.get_flag_string(),
+            actual.get_flag_string(),
+        );
+        compare(
+            &mut mismatches,
+            "TCP window",
+            expected.get_window(),
+            actual.get_window(),
+        );
+        compare(
+            &mut mismatches,
+            "TCP header size",
+            expected.get_header_size(),
+            actual.get_header_size(),
+        );
+        compare(
+            &mut mismatches,
+            "TCP payload size",
+            expected.get_payload().len() + payload_size,
+            actual.get_payload().len(),
+        );
+    }
+    if let (Some(expected), Some(actual)) = (indicator.get_udp(), parsed.get_udp()) {
+        compare(
+            &mut mismatches,
+            "UDP source",
+            expected.get_src(),
+            actual.get_src(),
+        );
+        compare(
+            &mut mismatches,
+            "UDP destination",
+            expected.get_dst(),
+            actual.get_dst(),
+        );
+        compare(
+            &mut mismatches,
+            "UDP payload size",
+            expected.get_payload().len() + payload_size,
+            actual.get_payload().len(),
+        );
+        compare(
+            &mut mismatches,
+            "UDP length",
+            actual.get_size(),
+            actual.get_length() as usize,
+        );
+    }
+
+    match mismatches.is_empty() {
+        true => Ok(()),
+        false => Err(VerifyError::MismatchError(mismatches)),
+    }
+}
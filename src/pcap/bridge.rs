@@ -0,0 +1,354 @@
+//! Bridges of devices into one device, so sources on different devices share one redirector
+//! and its tables of connections and associations. Frames of all the devices are received in
+//! order of arrival, and the device each host is learned on is learned from the frames it sends.
+//! Frames to a host are sent out of the device it is learned on, and frames to broadcast,
+//! multicast and unknown hardware addresses are flooded to all the devices. A host moving to
+//! another device is learned again by its next frame, so connections of it follow it instead of
+//! being opened again.
+
+use log::info;
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{
+    Capture, HardwareAddr, Receiver, Sender, Transmit, ETHERTYPE_ARP, ETHERTYPE_IPV4, READ_TIMEOUT,
+};
+
+/// Represents the time a host is kept after its last frame, after which frames to it are
+/// flooded.
+const AGING_TIME: Duration = Duration::from_secs(300);
+/// Represents the max number of hosts learned before hosts aged out are purged.
+const MAX_HOSTS: usize = 1024;
+
+/// Represents the bridge of the devices in effect.
+static BRIDGE: Mutex<Option<Arc<Bridge>>> = Mutex::new(None);
+
+/// Sets the bridge whose devices are rendered and tagged to clients.
+pub fn set_bridge(bridge: Arc<Bridge>) {
+    *BRIDGE.lock().unwrap() = Some(bridge);
+}
+
+/// Get the name of the device the host of the address is learned on. Returns `None` if devices
+/// are not bridged, or if the host is not learned.
+pub fn get_device(ip_addr: Ipv4Addr) -> Option<String> {
+    match *BRIDGE.lock().unwrap() {
+        Some(ref bridge) => bridge.get_device(ip_addr),
+        None => None,
+    }
+}
+
+/// Get the states of the devices of the bridge, which are empty without a bridge.
+pub fn get_states() -> Vec<DeviceState> {
+    match *BRIDGE.lock().unwrap() {
+        Some(ref bridge) => bridge.get_states(),
+        None => Vec::new(),
+    }
+}
+
+/// Represents a snapshot of a device of a bridge.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeviceState {
+    pub name: String,
+    pub frames_received: u64,
+    pub bytes_received: u64,
+    pub frames_sent: u64,
+    pub bytes_sent: u64,
+    /// Represents the IPv4 hosts learned on the device
+    pub hosts: u64,
+    /// Represents the hosts moved to the device from another device
+    pub moves: u64,
+}
+
+/// Represents a device of a bridge and its counters.
+#[derive(Debug)]
+struct Port {
+    name: String,
+    frames_received: AtomicU64,
+    bytes_received: AtomicU64,
+    frames_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    moves: AtomicU64,
+}
+
+/// Represents a host learned, with the device it is learned on.
+#[derive(Debug)]
+struct Host {
+    port: usize,
+    ip_addr: Option<Ipv4Addr>,
+    instant: Instant,
+}
+
+/// Represents the devices bridged, and the hosts learned on them.
+#[derive(Debug)]
+pub struct Bridge {
+    ports: Vec<Port>,
+    /// Represents the hardware address of the bridge, frames from which are never learned
+    local_hardware_addr: HardwareAddr,
+    hosts: Mutex<HashMap<HardwareAddr, Host>>,
+}
+
+impl Bridge {
+    fn new(names: Vec<String>, local_hardware_addr: HardwareAddr) -> Bridge {
+        Bridge {
+            ports: names
+                .into_iter()
+                .map(|name| Port {
+                    name,
+                    frames_received: AtomicU64::new(0),
+                    bytes_received: AtomicU64::new(0),
+                    frames_sent: AtomicU64::new(0),
+                    bytes_sent: AtomicU64::new(0),
+                    moves: AtomicU64::new(0),
+                })
+                .collect(),
+            local_hardware_addr,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Learns the host sending a frame received from the device of the index.
+    fn learn(&self, port: usize, frame: &[u8]) {
+        if frame.len() < 12 {
+            return;
+        }
+        let hardware_addr =
+            HardwareAddr::new(frame[6], frame[7], frame[8], frame[9], frame[10], frame[11]);
+        // Frames sent by the bridge are captured too
+        if hardware_addr == self.local_hardware_addr || frame[6] & 1 != 0 {
+            return;
+        }
+        let ip_addr = match super::get_network(frame) {
+            Some((ETHERTYPE_IPV4, packet)) if packet.len() >= 16 => Some(Ipv4Addr::new(
+                packet[12], packet[13], packet[14], packet[15],
+            )),
+            Some((ETHERTYPE_ARP, packet)) if packet.len() >= 18 => Some(Ipv4Addr::new(
+                packet[14], packet[15], packet[16], packet[17],
+            )),
+            _ => None,
+        }
+        .filter(|ip_addr| !ip_addr.is_unspecified());
+
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock().unwrap();
+        if hosts.len() >= MAX_HOSTS && !hosts.contains_key(&hardware_addr) {
+            hosts.retain(|_, host| now.duration_since(host.instant) < AGING_TIME);
+        }
+        let host = hosts.entry(hardware_addr).or_insert_with(|| Host {
+            port,
+            ip_addr: None,
+            instant: now,
+        });
+        // Frames of a host on the old device are sent out of the new device from now on
+        if host.port != port && now.duration_since(host.instant) < AGING_TIME {
+            self.ports[port].moves.fetch_add(1, Ordering::Relaxed);
+            info!(
+                "Host {} ({}) moves from {} to {}",
+                host.ip_addr
+                    .map_or_else(|| String::from("-"), |ip_addr| ip_addr.to_string()),
+                hardware_addr,
+                self.ports[host.port].name,
+                self.ports[port].name
+            );
+        }
+        host.port = port;
+        host.instant = now;
+        if ip_addr.is_some() {
+            host.ip_addr = ip_addr;
+        }
+    }
+
+    /// Get the index of the device a frame is sent out of. Returns `None` if the frame is flooded.
+    fn get_port(&self, frame: &[u8]) -> Option<usize> {
+        if frame.len() < 6 || frame[0] & 1 != 0 {
+            return None;
+        }
+        let hardware_addr =
+            HardwareAddr::new(frame[0], frame[1], frame[2], frame[3], frame[4], frame[5]);
+        let hosts = self.hosts.lock().unwrap();
+        match hosts.get(&hardware_addr) {
+            Some(host) if host.instant.elapsed() < AGING_TIME => Some(host.port),
+            _ => None,
+        }
+    }
+
+    fn get_device(&self, ip_addr: Ipv4Addr) -> Option<String> {
+        let hosts = self.hosts.lock().unwrap();
+        hosts
+            .values()
+            .filter(|host| host.ip_addr == Some(ip_addr))
+            .max_by_key(|host| host.instant)
+            .map(|host| self.ports[host.port].name.clone())
+    }
+
+    fn get_states(&self) -> Vec<DeviceState> {
+        let mut hosts = vec![0u64; self.ports.len()];
+        for host in self.hosts.lock().unwrap().values() {
+            if host.ip_addr.is_some() && host.instant.elapsed() < AGING_TIME {
+                hosts[host.port] += 1;
+            }
+        }
+
+        self.ports
+            .iter()
+            .zip(hosts)
+            .map(|(port, hosts)| DeviceState {
+                name: port.name.clone(),
+                frames_received: port.frames_received.load(Ordering::Relaxed),
+                bytes_received: port.bytes_received.load(Ordering::Relaxed),
+                frames_sent: port.frames_sent.load(Ordering::Relaxed),
+                bytes_sent: port.bytes_sent.load(Ordering::Relaxed),
+                hosts,
+                moves: port.moves.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// Represents a receiver of frames of all the devices of a bridge.
+struct BridgeReceiver {
+    rx: mpsc::Receiver<(usize, io::Result<Vec<u8>>)>,
+    bridge: Arc<Bridge>,
+    /// Represents if the receiver is dropped, which stops the threads of the devices
+    is_closed: Arc<AtomicBool>,
+    buffer: Vec<u8>,
+}
+
+impl Capture for BridgeReceiver {
+    fn next_frame(&mut self) -> io::Result<&[u8]> {
+        match self.rx.recv_timeout(Duration::from_millis(READ_TIMEOUT)) {
+            Ok((port, Ok(frame))) => {
+                self.bridge.learn(port, &frame);
+                self.buffer = frame;
+
+                Ok(&self.buffer)
+            }
+            Ok((port, Err(e))) => Err(io::Error::new(
+                e.kind(),
+                format!("{}: {}", self.bridge.ports[port].name, e),
+            )),
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+            }
+        }
+    }
+}
+
+impl Drop for BridgeReceiver {
+    fn drop(&mut self) {
+        self.is_closed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Represents a sender of frames out of the devices of a bridge.
+struct BridgeSender {
+    txs: Vec<Sender>,
+    bridge: Arc<Bridge>,
+}
+
+impl BridgeSender {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        match self.bridge.get_port(frame) {
+            Some(port) => self.send_to_port(port, frame),
+            None => {
+                // A flood fails only if it fails on all the devices
+                let mut result = Ok(());
+                let mut is_sent = false;
+                for port in 0..self.txs.len() {
+                    match self.send_to_port(port, frame) {
+                        Ok(_) => is_sent = true,
+                        Err(e) => result = Err(e),
+                    }
+                }
+
+                match is_sent {
+                    true => Ok(()),
+                    false => result,
+                }
+            }
+        }
+    }
+
+    fn send_to_port(&mut self, port: usize, frame: &[u8]) -> io::Result<()> {
+        self.txs[port].send_frame(frame)?;
+        let counters = &self.bridge.ports[port];
+        counters.frames_sent.fetch_add(1, Ordering::Relaxed);
+        counters
+            .bytes_sent
+            .fetch_add(frame.len() as u64, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+impl Transmit for BridgeSender {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.send(frame)
+    }
+}
+
+/// Bridges the senders and the receivers of Ethernet devices of the names into one sender and
+/// receiver, which answer in the hardware address. Frames of each device are received on its own
+/// thread until the receiver is dropped. Returns the bridge with the counters of the devices.
+pub fn bridge(
+    devices: Vec<(String, Sender, Receiver)>,
+    local_hardware_addr: HardwareAddr,
+) -> (Sender, Receiver, Arc<Bridge>) {
+    let names = devices.iter().map(|(name, _, _)| name.clone()).collect();
+    let bridge = Arc::new(Bridge::new(names, local_hardware_addr));
+    let is_closed = Arc::new(AtomicBool::new(false));
+    let (frame_tx, frame_rx) = mpsc::channel();
+
+    let mut txs = Vec::with_capacity(devices.len());
+    for (port, (_, tx, mut rx)) in devices.into_iter().enumerate() {
+        txs.push(tx);
+
+        let bridge = Arc::clone(&bridge);
+        let is_closed = Arc::clone(&is_closed);
+        let frame_tx = frame_tx.clone();
+        thread::spawn(move || {
+            while !is_closed.load(Ordering::Relaxed) {
+                let result = match rx.next_frame() {
+                    Ok(frame) => {
+                        let counters = &bridge.ports[port];
+                        counters.frames_received.fetch_add(1, Ordering::Relaxed);
+                        counters
+                            .bytes_received
+                            .fetch_add(frame.len() as u64, Ordering::Relaxed);
+
+                        Ok(frame.to_vec())
+                    }
+                    Err(e) => match e.kind() {
+                        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted => continue,
+                        _ => Err(e),
+                    },
+                };
+                let is_err = result.is_err();
+                // The receiver is dropped
+                if frame_tx.send((port, result)).is_err() || is_err {
+                    break;
+                }
+            }
+        });
+    }
+
+    (
+        Box::new(BridgeSender {
+            txs,
+            bridge: Arc::clone(&bridge),
+        }),
+        Box::new(BridgeReceiver {
+            rx: frame_rx,
+            bridge: Arc::clone(&bridge),
+            is_closed,
+            buffer: Vec::new(),
+        }),
+        bridge,
+    )
+}
@@ -0,0 +1,296 @@
+//! Captures of connections closed for errors. The last frames of each connection are kept in a
+//! ring, and written to a standalone pcapng file when the connection closes for an error, so a
+//! bug report comes with only the packets of the connection rather than a whole capture.
+
+use log::{trace, warn};
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use super::dump::{self, Direction};
+use crate::event::Protocol;
+
+/// Represents the default number of frames kept for each connection.
+pub const DEFAULT_CAPTURE_FRAMES: usize = 256;
+/// Represents the max size of a frame kept, longer frames are truncated.
+const SLOT_SIZE: usize = 2048;
+/// Represents the max memory of frames kept for all connections.
+const MAX_MEMORY: usize = 32 * 1024 * 1024;
+
+/// Represents the EtherType of IPv4.
+const ETHERTYPE_IPV4: u16 = 0x0800;
+/// Represents the protocol numbers of TCP and UDP.
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+/// Represents if connections are captured.
+static IS_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Represents the captures of connections.
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+
+/// Represents a connection by its protocol, the source and the destination.
+type Flow = (Protocol, SocketAddrV4, SocketAddrV4);
+
+/// Represents a frame kept, whose buffer is reused once the frame leaves the ring.
+#[derive(Debug)]
+struct Frame {
+    buffer: Vec<u8>,
+    /// Represents the original length of the frame, which may be truncated in the buffer
+    len: usize,
+    direction: Direction,
+    /// Represents the timestamp in microseconds since the Unix epoch
+    timestamp: u64,
+}
+
+/// Represents the ring of the last frames of a connection.
+#[derive(Debug)]
+struct Ring {
+    frames: VecDeque<Frame>,
+    /// Represents the instant the connection opens
+    instant: Instant,
+}
+
+/// Represents the rings of all connections, whose buffers are allocated up to the max memory and
+/// reused.
+#[derive(Debug)]
+struct Capture {
+    dir: PathBuf,
+    /// Represents the max number of frames kept for each connection
+    frames: usize,
+    rings: HashMap<Flow, Ring>,
+    /// Represents the buffers free to reuse
+    buffers: Vec<Vec<u8>>,
+    /// Represents the number of buffers allocated
+    allocated: usize,
+}
+
+impl Capture {
+    /// Keeps a frame of the connection, the oldest frame is replaced once the ring is full.
+    fn record(&mut self, flow: &Flow, frame: &[u8], direction: Direction) {
+        let is_full = match self.rings.get(flow) {
+            Some(ring) => ring.frames.len() >= self.frames,
+            None => return,
+        };
+        let buffer = match is_full {
+            true => self
+                .rings
+                .get_mut(flow)
+                .and_then(|ring| ring.frames.pop_front())
+                .map(|frame| frame.buffer),
+            false => self.take_buffer(flow),
+        };
+        let mut buffer = match buffer {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        buffer.clear();
+        buffer.extend_from_slice(&frame[..min(frame.len(), SLOT_SIZE)]);
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        if let Some(ring) = self.rings.get_mut(flow) {
+            ring.frames.push_back(Frame {
+                buffer,
+                len: frame.len(),
+                direction,
+                timestamp,
+            });
+        }
+    }
+
+    /// Takes a buffer free to reuse, or allocates one under the max memory. Otherwise, the ring
+    /// of the longest-lived connection is evicted, which is healthy so far, or the oldest frame of
+    /// the connection is replaced if it is the only one.
+    fn take_buffer(&mut self, flow: &Flow) -> Option<Vec<u8>> {
+        if let Some(buffer) = self.buffers.pop() {
+            return Some(buffer);
+        }
+        if self.allocated < MAX_MEMORY / SLOT_SIZE {
+            self.allocated += 1;
+
+            return Some(Vec::with_capacity(SLOT_SIZE));
+        }
+
+        let evicted = self
+            .rings
+            .iter()
+            .filter(|(key, ring)| *key != flow && !ring.frames.is_empty())
+            .min_by_key(|(_, ring)| ring.instant)
+            .map(|(key, _)| *key);
+        match evicted {
+            Some(evicted) => {
+                let ring = self.rings.get_mut(&evicted)?;
+                trace!(
+                    "evict {} frames captured of {} {} -> {}",
+                    ring.frames.len(),
+                    evicted.0,
+                    evicted.1,
+                    evicted.2
+                );
+                self.buffers
+                    .extend(ring.frames.drain(..).map(|frame| frame.buffer));
+
+                self.buffers.pop()
+            }
+            None => self
+                .rings
+                .get_mut(flow)?
+                .frames
+                .pop_front()
+                .map(|frame| frame.buffer),
+        }
+    }
+}
+
+/// Sets the directory captures of connections closed for errors are written to, and the max
+/// number of frames kept for each connection.
+pub fn set_capture_on_error(dir: PathBuf, frames: usize) {
+    *CAPTURE.lock().unwrap() = Some(Capture {
+        dir,
+        frames,
+        rings: HashMap::new(),
+        buffers: Vec::new(),
+        allocated: 0,
+    });
+    IS_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns if connections are captured.
+fn is_enabled() -> bool {
+    IS_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Starts keeping the frames of a connection.
+pub(crate) fn open(protocol: Protocol, src: SocketAddrV4, dst: SocketAddrV4) {
+    if !is_enabled() {
+        return;
+    }
+    if let Some(ref mut capture) = *CAPTURE.lock().unwrap() {
+        capture.rings.insert(
+            (protocol, src, dst),
+            Ring {
+                frames: VecDeque::new(),
+                instant: Instant::now(),
+            },
+        );
+    }
+}
+
+/// Keeps a frame received from or sent to a source if it belongs to a connection. Only the first
+/// fragment of a fragmented IPv4 datagram is kept.
+pub(crate) fn record(frame: &[u8], direction: Direction) {
+    if !is_enabled() {
+        return;
+    }
+    let flow = match get_flow(frame, direction) {
+        Some(flow) => flow,
+        None => return,
+    };
+    if let Some(ref mut capture) = *CAPTURE.lock().unwrap() {
+        capture.record(&flow, frame, direction);
+    }
+}
+
+/// Stops keeping the frames of a connection, which are written to a file named with the ID and
+/// the 4-tuple of the connection if it closes for an error. Returns the path of the file
+/// written.
+pub(crate) fn close(
+    id: u64,
+    protocol: Protocol,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    is_error: bool,
+) -> Option<PathBuf> {
+    if !is_enabled() {
+        return None;
+    }
+    let (ring, dir) = match *CAPTURE.lock().unwrap() {
+        Some(ref mut capture) => (
+            capture.rings.remove(&(protocol, src, dst))?,
+            capture.dir.clone(),
+        ),
+        None => return None,
+    };
+
+    // The file is written without holding the captures
+    let mut path = None;
+    if is_error && !ring.frames.is_empty() {
+        let file = dir.join(format!(
+            "{}-{}-{}_{}-{}_{}.pcapng",
+            id,
+            protocol,
+            src.ip(),
+            src.port(),
+            dst.ip(),
+            dst.port()
+        ));
+        match write(&file, &ring) {
+            Ok(_) => path = Some(file),
+            Err(ref e) => warn!("write capture {}: {}", file.display(), e),
+        }
+    }
+
+    if let Some(ref mut capture) = *CAPTURE.lock().unwrap() {
+        capture
+            .buffers
+            .extend(ring.frames.into_iter().map(|frame| frame.buffer));
+    }
+
+    path
+}
+
+/// Writes the frames of a ring to a pcapng file.
+fn write(path: &Path, ring: &Ring) -> io::Result<()> {
+    let (mut writer, _) = dump::create_file(path)?;
+    for frame in ring.frames.iter() {
+        dump::write_packet(
+            &mut writer,
+            &frame.buffer,
+            frame.len,
+            frame.direction,
+            frame.timestamp,
+        )?;
+    }
+
+    writer.flush()
+}
+
+/// Get the connection of a TCP or UDP frame, whose source is the source of frames received and
+/// the destination of frames sent.
+fn get_flow(frame: &[u8], direction: Direction) -> Option<Flow> {
+    let (t, packet) = super::get_network(frame)?;
+    if t != ETHERTYPE_IPV4 || packet.len() < 20 {
+        return None;
+    }
+    let protocol = match packet[9] {
+        PROTOCOL_TCP => Protocol::Tcp,
+        PROTOCOL_UDP => Protocol::Udp,
+        _ => return None,
+    };
+    let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+    if fragment_offset != 0 {
+        return None;
+    }
+    let offset = (packet[0] & 0x0f) as usize * 4;
+    let ports = packet.get(offset..offset + 4)?;
+    let src = SocketAddrV4::new(
+        Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]),
+        u16::from_be_bytes([ports[0], ports[1]]),
+    );
+    let dst = SocketAddrV4::new(
+        Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+        u16::from_be_bytes([ports[2], ports[3]]),
+    );
+
+    match direction {
+        Direction::Received => Some((protocol, src, dst)),
+        Direction::Sent => Some((protocol, dst, src)),
+    }
+}
@@ -0,0 +1,117 @@
+//! An in-memory channel in place of a device. Frames injected into its peer are captured as if
+//! they come from sources, and frames sent to sources are collected from the peer, so the whole
+//! pipeline runs without a device or the privileges of capturing.
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+use super::link::LinkType;
+use super::{Capture, HardwareAddr, Interface, Receiver, Sender, Transmit, READ_TIMEOUT};
+
+/// Represents the name of the interface of channels.
+const CHANNEL_NAME: &str = "channel";
+
+/// Represents a receiver of frames injected into the peer of a channel.
+pub struct ChannelReceiver {
+    rx: mpsc::Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl Capture for ChannelReceiver {
+    fn next_frame(&mut self) -> io::Result<&[u8]> {
+        match self.rx.recv_timeout(Duration::from_millis(READ_TIMEOUT)) {
+            Ok(frame) => {
+                self.buffer = frame;
+
+                Ok(&self.buffer)
+            }
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+            // The peer is closed, which ends the capture like the end of a capture file
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+            }
+        }
+    }
+}
+
+/// Represents a sender of frames collected by the peer of a channel.
+pub struct ChannelSender {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl Transmit for ChannelSender {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.tx
+            .send(frame.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+/// Represents the peer of a channel on the side of sources, which injects frames as if they
+/// are captured, and collects frames sent to sources.
+#[derive(Debug)]
+pub struct Peer {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl Peer {
+    /// Injects a frame, which is captured in order.
+    pub fn inject(&self, frame: &[u8]) -> io::Result<()> {
+        self.tx
+            .send(frame.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    /// Collects the next frame sent in the timeout.
+    pub fn collect(&self, timeout: Duration) -> io::Result<Vec<u8>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(frame) => Ok(frame),
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+            }
+        }
+    }
+
+    /// Collects all the frames sent so far without waiting.
+    pub fn collect_all(&self) -> Vec<Vec<u8>> {
+        self.rx.try_iter().collect()
+    }
+}
+
+/// Opens a channel of Ethernet frames, returns the sender and the receiver in place of a
+/// device, and the peer on the side of sources. Dropping the peer ends the capture.
+pub fn open() -> (Sender, Receiver, Peer) {
+    let (inject_tx, inject_rx) = mpsc::channel();
+    let (collect_tx, collect_rx) = mpsc::channel();
+
+    (
+        Box::new(ChannelSender { tx: collect_tx }),
+        Box::new(ChannelReceiver {
+            rx: inject_rx,
+            buffer: Vec::new(),
+        }),
+        Peer {
+            tx: inject_tx,
+            rx: collect_rx,
+        },
+    )
+}
+
+/// Get the emulated interface of a channel, which has the hardware address, the IPv4 address
+/// and the MTU.
+pub fn interface(hardware_addr: HardwareAddr, ip_addr: Ipv4Addr, mtu: u16) -> Interface {
+    Interface {
+        name: String::from(CHANNEL_NAME),
+        alias: None,
+        hardware_addr,
+        ip_addrs: vec![ip_addr],
+        link_type: LinkType::Ethernet,
+        mtu: Some(mtu),
+        is_up: true,
+        is_loopback: false,
+    }
+}
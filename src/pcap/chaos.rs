@@ -0,0 +1,242 @@
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Represents the granularity of the timing wheel.
+const TICK: Duration = Duration::from_millis(1);
+/// Represents the max delay plus jitter.
+const MAX_DELAY: Duration = Duration::from_secs(10);
+/// Represents the time a frame held for reordering waits for the next frame.
+const REORDER_TIMEOUT: Duration = Duration::from_millis(10);
+/// Represents the denominator of probabilities in parts per million.
+const PPM: u64 = 1_000_000;
+
+/// Represents the impairment of frames sent for testing, with the delay, the jitter, and the
+/// probabilities of loss and reordering.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Chaos {
+    delay: Duration,
+    jitter: Duration,
+    /// Represents the probability of loss in parts per million
+    loss: u32,
+    /// Represents the probability of reordering in parts per million
+    reorder: u32,
+    seed: Option<u64>,
+}
+
+impl Chaos {
+    /// Parses a chaos from comma separated settings like `delay=50ms,jitter=10ms,loss=1%,
+    /// reorder=0.5%,seed=1`. Settings omitted are off, and the seed is random if it is omitted.
+    pub fn parse(s: &str) -> Option<Chaos> {
+        let mut chaos = Chaos {
+            delay: Duration::from_secs(0),
+            jitter: Duration::from_secs(0),
+            loss: 0,
+            reorder: 0,
+            seed: None,
+        };
+        for setting in s.split(',') {
+            let mut kv = setting.splitn(2, '=');
+            let key = kv.next()?.trim();
+            let value = kv.next()?.trim();
+            match key {
+                "delay" => chaos.delay = humantime::parse_duration(value).ok()?,
+                "jitter" => chaos.jitter = humantime::parse_duration(value).ok()?,
+                "loss" => chaos.loss = parse_probability(value)?,
+                "reorder" => chaos.reorder = parse_probability(value)?,
+                "seed" => chaos.seed = Some(value.parse().ok()?),
+                _ => return None,
+            }
+        }
+        if chaos.jitter > chaos.delay || chaos.delay + chaos.jitter > MAX_DELAY {
+            return None;
+        }
+
+        Some(chaos)
+    }
+
+    /// Returns the chaos with the seed, the seed is decided from the time if it is random.
+    pub fn seeded(self) -> Chaos {
+        let seed = match self.seed {
+            Some(seed) => seed,
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_nanos() as u64)
+                .unwrap_or(0),
+        };
+
+        Chaos {
+            seed: Some(seed),
+            ..self
+        }
+    }
+}
+
+impl Display for Chaos {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "delay {} ms, jitter {} ms, loss {}%, reorder {}%",
+            self.delay.as_millis(),
+            self.jitter.as_millis(),
+            self.loss as f64 / (PPM / 100) as f64,
+            self.reorder as f64 / (PPM / 100) as f64
+        )?;
+        if let Some(seed) = self.seed {
+            write!(f, ", seed {}", seed)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a probability like `1%` or `0.01` in parts per million.
+fn parse_probability(s: &str) -> Option<u32> {
+    let p: f64 = match s.strip_suffix('%') {
+        Some(s) => s.trim().parse::<f64>().ok()? / 100.0,
+        None => s.parse().ok()?,
+    };
+    if !(0.0..=1.0).contains(&p) {
+        return None;
+    }
+
+    Some((p * PPM as f64).round() as u32)
+}
+
+/// Represents a queue of frames impaired by a chaos. Frames are held in a timing wheel for the
+/// delay with the jitter, dropped and swapped with the next frames pseudo-randomly from the
+/// seed, so a run is reproducible.
+#[derive(Debug)]
+pub struct ChaosQueue {
+    chaos: Chaos,
+    state: u64,
+    /// Represents the slots of frames in ticks, with if they are sent immediately
+    wheel: Vec<Vec<(Vec<u8>, bool)>>,
+    /// Represents the slot of the current tick
+    cursor: usize,
+    /// Represents the instant of the current tick
+    instant: Instant,
+    /// Represents the frame held to be sent after the next frame, with the instant it is held
+    held: Option<(Vec<u8>, bool, Instant)>,
+    due: VecDeque<(Vec<u8>, bool)>,
+    drop_count: usize,
+    reorder_count: usize,
+}
+
+impl ChaosQueue {
+    /// Creates a new `ChaosQueue`.
+    pub fn new(chaos: Chaos) -> ChaosQueue {
+        let chaos = chaos.seeded();
+        let slots = ((chaos.delay + chaos.jitter).as_millis() / TICK.as_millis()) as usize + 2;
+
+        ChaosQueue {
+            chaos,
+            // Xorshift never leaves a state of zero
+            state: chaos.seed.unwrap() | 1,
+            wheel: (0..slots).map(|_| Vec::new()).collect(),
+            cursor: 0,
+            instant: Instant::now(),
+            held: None,
+            due: VecDeque::new(),
+            drop_count: 0,
+            reorder_count: 0,
+        }
+    }
+
+    /// Pushes a frame into the queue, the frame may be dropped.
+    pub fn push(&mut self, frame: &[u8], is_immediate: bool) {
+        self.advance();
+        if self.chance(self.chaos.loss) {
+            self.drop_count += 1;
+            return;
+        }
+
+        match self.held.take() {
+            Some((held, is_held_immediate, _)) => {
+                self.schedule(frame.to_vec(), is_immediate);
+                self.schedule(held, is_held_immediate);
+            }
+            None if self.chance(self.chaos.reorder) => {
+                self.reorder_count += 1;
+                self.held = Some((frame.to_vec(), is_immediate, Instant::now()));
+            }
+            None => self.schedule(frame.to_vec(), is_immediate),
+        }
+    }
+
+    /// Takes the frames which are due, with if they are sent immediately.
+    pub fn pop(&mut self) -> Vec<(Vec<u8>, bool)> {
+        self.advance();
+        if let Some((_, _, instant)) = self.held {
+            if instant.elapsed() >= REORDER_TIMEOUT {
+                let (held, is_immediate, _) = self.held.take().unwrap();
+                self.schedule(held, is_immediate);
+            }
+        }
+
+        self.due.drain(..).collect()
+    }
+
+    /// Get the count of frames dropped.
+    pub fn get_drop_count(&self) -> usize {
+        self.drop_count
+    }
+
+    /// Get the count of frames reordered.
+    pub fn get_reorder_count(&self) -> usize {
+        self.reorder_count
+    }
+
+    /// Returns the chaos with its seed.
+    pub fn get_chaos(&self) -> Chaos {
+        self.chaos
+    }
+
+    /// Schedules a frame after the delay with the jitter, frames without a delay are due at once.
+    fn schedule(&mut self, frame: Vec<u8>, is_immediate: bool) {
+        let mut delay = self.chaos.delay.as_millis() as u64;
+        let jitter = self.chaos.jitter.as_millis() as u64;
+        if jitter > 0 {
+            delay = delay - jitter + self.next() % (2 * jitter + 1);
+        }
+        let ticks = (delay / TICK.as_millis() as u64) as usize;
+        if ticks == 0 {
+            self.due.push_back((frame, is_immediate));
+            return;
+        }
+
+        let slot = (self.cursor + ticks) % self.wheel.len();
+        self.wheel[slot].push((frame, is_immediate));
+    }
+
+    /// Advances the wheel to now, frames of the slots passed are due.
+    fn advance(&mut self) {
+        let ticks = (self.instant.elapsed().as_millis() / TICK.as_millis()) as usize;
+        if ticks == 0 {
+            return;
+        }
+        self.instant += TICK * ticks as u32;
+
+        // A whole round passes all the slots
+        for _ in 0..min(ticks, self.wheel.len()) {
+            self.cursor = (self.cursor + 1) % self.wheel.len();
+            let slot = &mut self.wheel[self.cursor];
+            self.due.extend(slot.drain(..));
+        }
+    }
+
+    /// Returns `true` in the probability in parts per million.
+    fn chance(&mut self, p: u32) -> bool {
+        p > 0 && self.next() % PPM < p as u64
+    }
+
+    /// Generates the next pseudo-random number by xorshift64*.
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
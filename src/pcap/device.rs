@@ -0,0 +1,180 @@
+use std::io;
+
+/// Represents the max size of interface names including the terminating null.
+#[cfg(target_os = "linux")]
+const IF_NAMESIZE: usize = 16;
+
+/// Represents the interface request of ioctls.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+pub struct IfReq {
+    pub name: [libc::c_char; IF_NAMESIZE],
+    /// Represents the union of the request, only the flags and the MTU are used
+    pub data: [u8; 24],
+}
+
+#[cfg(target_os = "linux")]
+impl IfReq {
+    pub fn new(name: &str) -> io::Result<IfReq> {
+        let name = std::ffi::CString::new(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+        let bytes = name.as_bytes_with_nul();
+        if bytes.len() > IF_NAMESIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "interface name too long",
+            ));
+        }
+
+        let mut req = IfReq {
+            name: [0; IF_NAMESIZE],
+            data: [0; 24],
+        };
+        for (i, b) in bytes.iter().enumerate() {
+            req.name[i] = *b as libc::c_char;
+        }
+
+        Ok(req)
+    }
+}
+
+/// Get the MTU of the interface by the ioctl.
+#[cfg(target_os = "linux")]
+pub fn get_mtu(name: &str) -> io::Result<u16> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut req = IfReq::new(name)?;
+    let result = unsafe { libc::ioctl(fd, libc::SIOCGIFMTU, &mut req) };
+    let e = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if result < 0 {
+        return Err(e);
+    }
+    let mtu = libc::c_int::from_ne_bytes([req.data[0], req.data[1], req.data[2], req.data[3]]);
+
+    Ok(clamp_mtu(mtu as u64))
+}
+
+/// Get the MTU of the interface from the link statistics of the interface, which are the same
+/// as the ones of the sysctl.
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+pub fn get_mtu(name: &str) -> io::Result<u16> {
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut mtu = None;
+    let mut addr = addrs;
+    while !addr.is_null() {
+        let ifa = unsafe { &*addr };
+        addr = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() || ifa.ifa_data.is_null() {
+            continue;
+        }
+        // Link statistics are in the link level address of the interface
+        if unsafe { (*ifa.ifa_addr).sa_family } as libc::c_int != libc::AF_LINK {
+            continue;
+        }
+        let ifa_name = unsafe { std::ffi::CStr::from_ptr(ifa.ifa_name) };
+        if ifa_name.to_bytes() != name.as_bytes() {
+            continue;
+        }
+
+        let data = unsafe { &*(ifa.ifa_data as *const libc::if_data) };
+        mtu = Some(clamp_mtu(data.ifi_mtu as u64));
+        break;
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+
+    mtu.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "interface not found"))
+}
+
+/// Get the MTU of the interface from its adapter. The name of the interface in WinPcap is like
+/// `\Device\NPF_{GUID}`, and the name of the adapter is the GUID.
+#[cfg(windows)]
+pub fn get_mtu(name: &str) -> io::Result<u16> {
+    use std::ffi::CStr;
+    use winapi::shared::winerror::{ERROR_BUFFER_OVERFLOW, NO_ERROR};
+    use winapi::shared::ws2def::AF_UNSPEC;
+    use winapi::um::iphlpapi::GetAdaptersAddresses;
+    use winapi::um::iptypes::{
+        GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_DNS_SERVER, GAA_FLAG_SKIP_MULTICAST,
+        IP_ADAPTER_ADDRESSES,
+    };
+
+    let guid = match name.find('{') {
+        Some(i) => &name[i..],
+        None => name,
+    };
+    let flags = GAA_FLAG_SKIP_ANYCAST | GAA_FLAG_SKIP_MULTICAST | GAA_FLAG_SKIP_DNS_SERVER;
+
+    // The buffer is enlarged until it holds all the adapters
+    let mut size: u32 = 16 * 1024;
+    let mut buffer: Vec<u64>;
+    loop {
+        buffer = vec![0u64; (size as usize + 7) / 8];
+        let result = unsafe {
+            GetAdaptersAddresses(
+                AF_UNSPEC as u32,
+                flags,
+                std::ptr::null_mut(),
+                buffer.as_mut_ptr() as *mut IP_ADAPTER_ADDRESSES,
+                &mut size,
+            )
+        };
+        match result {
+            NO_ERROR => break,
+            ERROR_BUFFER_OVERFLOW => continue,
+            _ => return Err(io::Error::from_raw_os_error(result as i32)),
+        }
+    }
+
+    let mut adapter = buffer.as_ptr() as *const IP_ADAPTER_ADDRESSES;
+    while !adapter.is_null() {
+        let current = unsafe { &*adapter };
+        adapter = current.Next;
+        let adapter_name = unsafe { CStr::from_ptr(current.AdapterName) };
+        if adapter_name
+            .to_bytes()
+            .eq_ignore_ascii_case(guid.as_bytes())
+        {
+            return Ok(clamp_mtu(current.Mtu as u64));
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "interface not found",
+    ))
+}
+
+/// Get the MTU of the interface, which is not supported on the platform.
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    windows
+)))]
+pub fn get_mtu(_: &str) -> io::Result<u16> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "MTU query not supported",
+    ))
+}
+
+/// Clamps the MTU to the range of IPv4 packets, the MTU of loopback interfaces is usually 65536.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    windows
+))]
+fn clamp_mtu(mtu: u64) -> u16 {
+    mtu.min(u16::MAX as u64) as u16
+}
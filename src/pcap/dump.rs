@@ -0,0 +1,276 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Represents the type of section header blocks in pcapng files.
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+/// Represents the type of interface description blocks in pcapng files.
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 1;
+/// Represents the type of enhanced packet blocks in pcapng files.
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+/// Represents the byte-order magic of pcapng files.
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+/// Represents the option of the name of an interface.
+const OPTION_IF_NAME: u16 = 2;
+/// Represents the link type of Ethernet.
+const LINKTYPE_ETHERNET: u16 = 1;
+/// Represents the max size of frames in dumps.
+const SNAPLEN: u32 = 65535;
+
+/// Represents the protocol numbers of TCP and UDP.
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+/// Represents the direction of a frame, which is dumped as an interface.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// Represents frames captured and accepted.
+    Received,
+    /// Represents frames synthesized and sent.
+    Sent,
+}
+
+impl Direction {
+    fn get_interface_id(&self) -> u32 {
+        match self {
+            Direction::Received => 0,
+            Direction::Sent => 1,
+        }
+    }
+}
+
+/// Represents a filter of frames dumped, by an address or a flow.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Filter {
+    /// Represents frames from or to the address.
+    Address(Ipv4Addr),
+    /// Represents TCP or UDP frames between the addresses in both directions.
+    Flow(u8, SocketAddrV4, SocketAddrV4),
+}
+
+impl Filter {
+    /// Parses a filter in the form of `ADDRESS`, or `PROTOCOL,ADDRESS:PORT,ADDRESS:PORT` like
+    /// `tcp,10.6.0.1:50000,1.1.1.1:443`. Returns `None` if the filter is not valid.
+    pub fn parse(s: &str) -> Option<Filter> {
+        let fields: Vec<&str> = s.split(',').map(|field| field.trim()).collect();
+        match fields.len() {
+            1 => Some(Filter::Address(fields[0].parse().ok()?)),
+            3 => {
+                let protocol = match fields[0].to_ascii_lowercase().as_str() {
+                    "tcp" => PROTOCOL_TCP,
+                    "udp" => PROTOCOL_UDP,
+                    _ => return None,
+                };
+
+                Some(Filter::Flow(
+                    protocol,
+                    fields[1].parse().ok()?,
+                    fields[2].parse().ok()?,
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns if the filter matches the frame. Only the first fragment of a fragmented IPv4
+    /// datagram matches a flow.
+    pub fn is_match(&self, frame: &[u8]) -> bool {
+        let (t, packet) = match super::get_network(frame) {
+            Some(network) => network,
+            None => return false,
+        };
+        let (src, dst) = match t {
+            // ARP
+            0x0806 if packet.len() >= 28 => {
+                if let Filter::Flow(_, _, _) = self {
+                    return false;
+                }
+                (
+                    Ipv4Addr::new(packet[14], packet[15], packet[16], packet[17]),
+                    Ipv4Addr::new(packet[24], packet[25], packet[26], packet[27]),
+                )
+            }
+            // IPv4
+            0x0800 if packet.len() >= 20 => (
+                Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]),
+                Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+            ),
+            _ => return false,
+        };
+
+        match *self {
+            Filter::Address(ip_addr) => src == ip_addr || dst == ip_addr,
+            Filter::Flow(protocol, a, b) => {
+                let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+                if packet[9] != protocol || fragment_offset != 0 {
+                    return false;
+                }
+                let offset = (packet[0] & 0x0f) as usize * 4;
+                let ports = match packet.get(offset..offset + 4) {
+                    Some(ports) => ports,
+                    None => return false,
+                };
+                let src = SocketAddrV4::new(src, u16::from_be_bytes([ports[0], ports[1]]));
+                let dst = SocketAddrV4::new(dst, u16::from_be_bytes([ports[2], ports[3]]));
+
+                (src == a && dst == b) || (src == b && dst == a)
+            }
+        }
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Filter::Address(ip_addr) => write!(f, "{}", ip_addr),
+            Filter::Flow(protocol, a, b) => match *protocol {
+                PROTOCOL_TCP => write!(f, "tcp,{},{}", a, b),
+                _ => write!(f, "udp,{},{}", a, b),
+            },
+        }
+    }
+}
+
+/// Represents a dumper writing frames received and sent to a pcapng file, in which frames
+/// received and sent are in different interfaces. The file is rotated to the file with the
+/// suffix `.1` when it reaches the max size.
+pub struct Dumper {
+    path: String,
+    writer: BufWriter<File>,
+    size: u64,
+    max_size: Option<u64>,
+    filter: Option<Filter>,
+}
+
+impl Dumper {
+    /// Creates a new `Dumper`.
+    pub fn create(path: &str) -> io::Result<Dumper> {
+        let (writer, size) = create_file(path)?;
+
+        Ok(Dumper {
+            path: path.to_string(),
+            writer,
+            size,
+            max_size: None,
+            filter: None,
+        })
+    }
+
+    /// Sets the max size of a file in bytes.
+    pub fn set_max_size(&mut self, max_size: u64) {
+        self.max_size = Some(max_size);
+    }
+
+    /// Sets the filter of frames dumped.
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = Some(filter);
+    }
+
+    /// Dumps a frame.
+    pub fn dump(&mut self, frame: &[u8], direction: Direction) -> io::Result<()> {
+        if let Some(ref filter) = self.filter {
+            if !filter.is_match(frame) {
+                return Ok(());
+            }
+        }
+        if let Some(max_size) = self.max_size {
+            if self.size + frame.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        self.size += write_packet(&mut self.writer, frame, frame.len(), direction, timestamp)?;
+
+        Ok(())
+    }
+
+    /// Flushes frames dumped to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        fs::rename(&self.path, format!("{}.1", self.path))?;
+
+        let (writer, size) = create_file(&self.path)?;
+        self.writer = writer;
+        self.size = size;
+
+        Ok(())
+    }
+}
+
+/// Writes a frame in the direction at the timestamp in microseconds since the Unix epoch,
+/// returns the size written. The frame may be truncated from its original length.
+pub(crate) fn write_packet<W: Write>(
+    writer: &mut W,
+    frame: &[u8],
+    original_len: usize,
+    direction: Direction,
+    timestamp: u64,
+) -> io::Result<u64> {
+    let padding = (4 - frame.len() % 4) % 4;
+    let len = 32 + frame.len() + padding;
+
+    let mut header = Vec::with_capacity(28);
+    header.extend_from_slice(&BLOCK_ENHANCED_PACKET.to_le_bytes());
+    header.extend_from_slice(&(len as u32).to_le_bytes());
+    header.extend_from_slice(&direction.get_interface_id().to_le_bytes());
+    header.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    header.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    header.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    header.extend_from_slice(&(original_len as u32).to_le_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(frame)?;
+    writer.write_all(&[0u8; 3][..padding])?;
+    writer.write_all(&(len as u32).to_le_bytes())?;
+
+    Ok(len as u64)
+}
+
+/// Creates a pcapng file with the interfaces of frames received and sent, returns the writer
+/// and the size written.
+pub(crate) fn create_file<P: AsRef<Path>>(path: P) -> io::Result<(BufWriter<File>, u64)> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    // Section header block
+    let mut block = Vec::new();
+    block.extend_from_slice(&BLOCK_SECTION_HEADER.to_le_bytes());
+    block.extend_from_slice(&28u32.to_le_bytes());
+    block.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    // Version 1.0
+    block.extend_from_slice(&1u16.to_le_bytes());
+    block.extend_from_slice(&0u16.to_le_bytes());
+    // Unspecified section length
+    block.extend_from_slice(&(-1i64).to_le_bytes());
+    block.extend_from_slice(&28u32.to_le_bytes());
+
+    // Interface description blocks
+    for name in ["received", "sent"].iter() {
+        let padding = (4 - name.len() % 4) % 4;
+        let len = 20 + 4 + name.len() + padding + 4;
+        block.extend_from_slice(&BLOCK_INTERFACE_DESCRIPTION.to_le_bytes());
+        block.extend_from_slice(&(len as u32).to_le_bytes());
+        block.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        block.extend_from_slice(&0u16.to_le_bytes());
+        block.extend_from_slice(&SNAPLEN.to_le_bytes());
+        block.extend_from_slice(&OPTION_IF_NAME.to_le_bytes());
+        block.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        block.extend_from_slice(name.as_bytes());
+        block.extend_from_slice(&[0u8; 3][..padding]);
+        // End of options
+        block.extend_from_slice(&[0u8; 4]);
+        block.extend_from_slice(&(len as u32).to_le_bytes());
+    }
+    writer.write_all(&block)?;
+
+    Ok((writer, block.len() as u64))
+}
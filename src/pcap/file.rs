@@ -0,0 +1,181 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::link::LinkType;
+use super::{Capture, Transmit};
+
+/// Represents the magic number of pcap files with timestamps in microseconds.
+const MAGIC: u32 = 0xa1b2_c3d4;
+/// Represents the magic number of pcap files with timestamps in nanoseconds.
+const MAGIC_NANO: u32 = 0xa1b2_3c4d;
+/// Represents the size of the global header of pcap files.
+const HEADER_SIZE: usize = 24;
+/// Represents the size of the header of records in pcap files.
+const RECORD_HEADER_SIZE: usize = 16;
+/// Represents the max size of frames in pcap files written.
+const SNAPLEN: u32 = 65535;
+/// Represents the max size of records in pcap files read.
+const MAX_RECORD_SIZE: u32 = 256 * 1024;
+
+/// Represents a receiver reading frames from a pcap file. Frames are read at full speed, or in
+/// their original timing.
+pub struct FileReceiver {
+    reader: BufReader<File>,
+    /// Represents if the file is in the opposite byte order
+    is_swapped: bool,
+    is_nano: bool,
+    link_type: LinkType,
+    is_timing: bool,
+    /// Represents the timestamp of the first frame and the instant it is read
+    start: Option<(Duration, Instant)>,
+    buffer: Vec<u8>,
+}
+
+impl FileReceiver {
+    /// Opens a pcap file. Returns an error if the link type of the file is not supported.
+    pub fn open(path: &str) -> io::Result<FileReceiver> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        let (is_swapped, is_nano) =
+            match u32::from_le_bytes([header[0], header[1], header[2], header[3]]) {
+                MAGIC => (false, false),
+                MAGIC_NANO => (false, true),
+                magic if magic.swap_bytes() == MAGIC => (true, false),
+                magic if magic.swap_bytes() == MAGIC_NANO => (true, true),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "not a pcap file",
+                    ))
+                }
+            };
+        let link_type = LinkType::from_dlt(get_u32(&header[20..24], is_swapped))?;
+
+        Ok(FileReceiver {
+            reader,
+            is_swapped,
+            is_nano,
+            link_type,
+            is_timing: false,
+            start: None,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Get the link type of the file.
+    pub fn get_link_type(&self) -> LinkType {
+        self.link_type
+    }
+
+    /// Sets if frames are read in their original timing.
+    pub fn set_timing(&mut self, is_timing: bool) {
+        self.is_timing = is_timing;
+    }
+}
+
+impl Capture for FileReceiver {
+    fn next_frame(&mut self) -> io::Result<&[u8]> {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        self.reader.read_exact(&mut header)?;
+        let sec = get_u32(&header[0..4], self.is_swapped) as u64;
+        let subsec = get_u32(&header[4..8], self.is_swapped);
+        let incl_len = get_u32(&header[8..12], self.is_swapped);
+        if incl_len > MAX_RECORD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record too large",
+            ));
+        }
+
+        self.buffer.resize(incl_len as usize, 0);
+        self.reader.read_exact(&mut self.buffer)?;
+
+        // Timing
+        if self.is_timing {
+            let timestamp = match self.is_nano {
+                true => Duration::new(sec, subsec),
+                false => Duration::new(sec, 0) + Duration::from_micros(subsec as u64),
+            };
+            match self.start {
+                Some((start, instant)) => {
+                    let offset = timestamp.checked_sub(start).unwrap_or_default();
+                    if let Some(wait) = offset.checked_sub(instant.elapsed()) {
+                        thread::sleep(wait);
+                    }
+                }
+                None => self.start = Some((timestamp, Instant::now())),
+            }
+        }
+
+        Ok(&self.buffer)
+    }
+}
+
+/// Represents a sender writing frames to a pcap file, or discarding them.
+pub struct FileSender {
+    file: Option<File>,
+}
+
+impl FileSender {
+    /// Creates a pcap file of the link type.
+    pub fn create(path: &str, link_type: LinkType) -> io::Result<FileSender> {
+        let mut file = File::create(path)?;
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(&MAGIC.to_le_bytes());
+        // Version 2.4
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // Time zone and accuracy of timestamps
+        header.extend_from_slice(&[0u8; 8]);
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&link_type.get_dlt().to_le_bytes());
+        file.write_all(&header)?;
+
+        Ok(FileSender { file: Some(file) })
+    }
+
+    /// Creates a `FileSender` discarding all the frames.
+    pub fn discard() -> FileSender {
+        FileSender { file: None }
+    }
+
+    fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => return Ok(()),
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        // Write a record in a single write
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + frame.len());
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(frame);
+
+        file.write_all(&record)
+    }
+}
+
+impl Transmit for FileSender {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.write(frame)
+    }
+}
+
+/// Get the 32-bit integer in the byte order of the file.
+fn get_u32(buffer: &[u8], is_swapped: bool) -> u32 {
+    let n = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+    match is_swapped {
+        true => n.swap_bytes(),
+        false => n,
+    }
+}
@@ -0,0 +1,426 @@
+use ipnetwork::Ipv4Network;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::net::Ipv4Addr;
+
+/// Represents the protocol numbers of ICMP, TCP and UDP.
+const PROTOCOL_ICMP: u8 = 1;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+/// Represents the port of DHCP servers.
+const DHCP_SERVER_PORT: u16 = 67;
+/// Represents the port of DHCP clients.
+const DHCP_CLIENT_PORT: u16 = 68;
+
+/// Represents the protocol of a primitive.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Protocol {
+    Arp,
+    Ip,
+    Icmp,
+    Tcp,
+    Udp,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            Protocol::Arp => write!(f, "arp"),
+            Protocol::Ip => write!(f, "ip"),
+            Protocol::Icmp => write!(f, "icmp"),
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// Represents the direction of a primitive.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Dir {
+    Src,
+    Dst,
+    Any,
+}
+
+impl Display for Dir {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            Dir::Src => write!(f, "src "),
+            Dir::Dst => write!(f, "dst "),
+            Dir::Any => Ok(()),
+        }
+    }
+}
+
+/// Represents a capture filter expression in the syntax of pcap filters, like
+/// `not tcp port 445 and src net 10.6.0.0/24`. Only a subset of the syntax is supported, which
+/// consists of protocols `arp`, `ip`, `icmp`, `tcp` and `udp`, `host`, `net`, `port` and
+/// `portrange` with optional `src` or `dst`, and `and`, `or`, `not` and parentheses.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Expression {
+    Protocol(Protocol),
+    /// Represents the addresses of IPv4 or the protocol addresses of ARP in the network.
+    Net(Dir, Ipv4Network),
+    /// Represents the ports of TCP or UDP in the range.
+    Port(Option<Protocol>, Dir, u16, u16),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+}
+
+impl Expression {
+    /// Parses a capture filter expression.
+    pub fn parse(s: &str) -> Result<Expression, FilterError> {
+        let tokens = tokenize(s);
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        let expression = parser.parse_or().map_err(|e| FilterError::new(s, e))?;
+        if let Some(token) = tokens.get(parser.pos) {
+            return Err(FilterError::new(s, format!("unexpected {}", token)));
+        }
+
+        Ok(expression)
+    }
+
+    /// Returns an expression which is the conjunction of the expressions.
+    pub fn and(self, other: Expression) -> Expression {
+        Expression::And(Box::new(self), Box::new(other))
+    }
+
+    /// Returns an expression which is the disjunction of the expressions.
+    pub fn or(self, other: Expression) -> Expression {
+        Expression::Or(Box::new(self), Box::new(other))
+    }
+
+    /// Returns if the expression matches the frame.
+    pub fn is_match(&self, frame: &[u8]) -> bool {
+        match self {
+            Expression::Protocol(protocol) => match protocol {
+                Protocol::Arp => is_arp(frame),
+                Protocol::Ip => is_ipv4(frame),
+                Protocol::Icmp => get_protocol(frame) == Some(PROTOCOL_ICMP),
+                Protocol::Tcp => get_protocol(frame) == Some(PROTOCOL_TCP),
+                Protocol::Udp => get_protocol(frame) == Some(PROTOCOL_UDP),
+            },
+            Expression::Net(dir, network) => match get_addrs(frame) {
+                Some((src, dst)) => match dir {
+                    Dir::Src => network.contains(src),
+                    Dir::Dst => network.contains(dst),
+                    Dir::Any => network.contains(src) || network.contains(dst),
+                },
+                None => false,
+            },
+            Expression::Port(protocol, dir, begin, end) => {
+                let (src, dst) = match get_ports(frame, *protocol) {
+                    Some(ports) => ports,
+                    None => return false,
+                };
+                let is_in = |port: u16| port >= *begin && port <= *end;
+                match dir {
+                    Dir::Src => is_in(src),
+                    Dir::Dst => is_in(dst),
+                    Dir::Any => is_in(src) || is_in(dst),
+                }
+            }
+            Expression::And(a, b) => a.is_match(frame) && b.is_match(frame),
+            Expression::Or(a, b) => a.is_match(frame) || b.is_match(frame),
+            Expression::Not(a) => !a.is_match(frame),
+        }
+    }
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Expression::Protocol(protocol) => write!(f, "{}", protocol),
+            Expression::Net(dir, network) => match network.prefix() {
+                32 => write!(f, "{}host {}", dir, network.ip()),
+                _ => write!(f, "{}net {}", dir, network),
+            },
+            Expression::Port(protocol, dir, begin, end) => {
+                if let Some(protocol) = protocol {
+                    write!(f, "{} ", protocol)?;
+                }
+                if begin == end {
+                    write!(f, "{}port {}", dir, begin)
+                } else {
+                    write!(f, "{}portrange {}-{}", dir, begin, end)
+                }
+            }
+            Expression::And(a, b) => write!(f, "({} and {})", a, b),
+            Expression::Or(a, b) => write!(f, "({} or {})", a, b),
+            Expression::Not(a) => write!(f, "not {}", a),
+        }
+    }
+}
+
+/// Represents an error parsing a capture filter expression.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FilterError {
+    expression: String,
+    reason: String,
+}
+
+impl FilterError {
+    fn new(expression: &str, reason: String) -> FilterError {
+        FilterError {
+            expression: expression.to_string(),
+            reason,
+        }
+    }
+}
+
+impl Display for FilterError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "invalid filter \"{}\": {}", self.expression, self.reason)
+    }
+}
+
+impl Error for FilterError {}
+
+/// Creates the internal capture filter, which matches ARP, IPv4 from the source and DHCP.
+pub fn internal(src: Ipv4Network) -> Expression {
+    let dhcp = Expression::Port(
+        Some(Protocol::Udp),
+        Dir::Any,
+        DHCP_SERVER_PORT,
+        DHCP_CLIENT_PORT,
+    );
+
+    Expression::Protocol(Protocol::Arp)
+        .or(Expression::Protocol(Protocol::Ip).and(Expression::Net(Dir::Src, src)))
+        .or(dhcp)
+}
+
+struct Parser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|token| token.as_str())
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or_else(|| "unexpected end".to_string())?;
+        self.pos += 1;
+
+        Ok(token.as_str())
+    }
+
+    fn parse_or(&mut self) -> Result<Expression, String> {
+        let mut expression = self.parse_and()?;
+        while let Some("or") | Some("||") = self.peek() {
+            self.pos += 1;
+            expression = expression.or(self.parse_and()?);
+        }
+
+        Ok(expression)
+    }
+
+    fn parse_and(&mut self) -> Result<Expression, String> {
+        let mut expression = self.parse_not()?;
+        while let Some("and") | Some("&&") = self.peek() {
+            self.pos += 1;
+            expression = expression.and(self.parse_not()?);
+        }
+
+        Ok(expression)
+    }
+
+    fn parse_not(&mut self) -> Result<Expression, String> {
+        match self.peek() {
+            Some("not") | Some("!") => {
+                self.pos += 1;
+
+                Ok(Expression::Not(Box::new(self.parse_not()?)))
+            }
+            Some("(") => {
+                self.pos += 1;
+                let expression = self.parse_or()?;
+                match self.next()? {
+                    ")" => Ok(expression),
+                    token => Err(format!("unexpected {}, expect )", token)),
+                }
+            }
+            _ => self.parse_primitive(),
+        }
+    }
+
+    fn parse_primitive(&mut self) -> Result<Expression, String> {
+        let mut token = self.next()?;
+        let protocol = match token {
+            "arp" => Some(Protocol::Arp),
+            "ip" => Some(Protocol::Ip),
+            "icmp" => Some(Protocol::Icmp),
+            "tcp" => Some(Protocol::Tcp),
+            "udp" => Some(Protocol::Udp),
+            _ => None,
+        };
+        if let Some(protocol) = protocol {
+            match self.peek() {
+                Some("src") | Some("dst") | Some("host") | Some("net") | Some("port")
+                | Some("portrange") => token = self.next()?,
+                // A protocol only
+                _ => return Ok(Expression::Protocol(protocol)),
+            }
+        }
+
+        let (dir, token) = match token {
+            "src" => (Dir::Src, self.next()?),
+            "dst" => (Dir::Dst, self.next()?),
+            _ => (Dir::Any, token),
+        };
+        let expression = match token {
+            "host" | "net" => {
+                let s = self.next()?;
+                let network: Ipv4Network =
+                    s.parse().map_err(|_| format!("invalid address {}", s))?;
+                if token == "host" && network.prefix() != 32 {
+                    return Err(format!("invalid host {}", s));
+                }
+                let expression = Expression::Net(dir, network);
+                match protocol {
+                    Some(Protocol::Arp) | Some(Protocol::Ip) | None => {}
+                    Some(protocol) => {
+                        return Err(format!("{} does not support {}", protocol, token));
+                    }
+                }
+                match protocol {
+                    Some(protocol) => Expression::Protocol(protocol).and(expression),
+                    None => expression,
+                }
+            }
+            "port" | "portrange" => {
+                let s = self.next()?;
+                let (begin, end) = parse_ports(s, token == "portrange")
+                    .ok_or_else(|| format!("invalid {} {}", token, s))?;
+                match protocol {
+                    None | Some(Protocol::Tcp) | Some(Protocol::Udp) => {}
+                    Some(protocol) => {
+                        return Err(format!("{} does not support {}", protocol, token));
+                    }
+                }
+                Expression::Port(protocol, dir, begin, end)
+            }
+            token => return Err(format!("unexpected {}", token)),
+        };
+
+        Ok(expression)
+    }
+}
+
+/// Splits an expression into tokens, parentheses and `!` are tokens themselves.
+fn tokenize(s: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    for c in s.chars() {
+        match c {
+            '(' | ')' | '!' => {
+                if !token.is_empty() {
+                    tokens.push(token.to_ascii_lowercase());
+                    token.clear();
+                }
+                tokens.push(c.to_string());
+            }
+            c if c.is_whitespace() => {
+                if !token.is_empty() {
+                    tokens.push(token.to_ascii_lowercase());
+                    token.clear();
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token.to_ascii_lowercase());
+    }
+
+    tokens
+}
+
+/// Parses a port like `53`, or a range of ports like `8000-8080` if it is allowed.
+fn parse_ports(s: &str, is_range: bool) -> Option<(u16, u16)> {
+    let mut parts = s.splitn(2, '-');
+    let begin = parts.next()?.parse().ok()?;
+    let end = match parts.next() {
+        Some(end) if is_range => end.parse().ok()?,
+        Some(_) => return None,
+        None => begin,
+    };
+    if begin > end {
+        return None;
+    }
+
+    Some((begin, end))
+}
+
+/// Get the ARP packet of the frame.
+fn get_arp(frame: &[u8]) -> Option<&[u8]> {
+    match super::get_network(frame) {
+        Some((0x0806, arp)) if arp.len() >= 28 => Some(arp),
+        _ => None,
+    }
+}
+
+/// Get the IPv4 packet of the frame.
+fn get_ipv4(frame: &[u8]) -> Option<&[u8]> {
+    match super::get_network(frame) {
+        Some((0x0800, ip)) if ip.len() >= 20 => Some(ip),
+        _ => None,
+    }
+}
+
+fn is_arp(frame: &[u8]) -> bool {
+    get_arp(frame).is_some()
+}
+
+fn is_ipv4(frame: &[u8]) -> bool {
+    get_ipv4(frame).is_some()
+}
+
+fn get_protocol(frame: &[u8]) -> Option<u8> {
+    get_ipv4(frame).map(|ip| ip[9])
+}
+
+/// Get the addresses of IPv4, or the protocol addresses of ARP.
+fn get_addrs(frame: &[u8]) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    match (get_ipv4(frame), get_arp(frame)) {
+        (Some(ip), _) => Some((
+            Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]),
+            Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]),
+        )),
+        (None, Some(arp)) => Some((
+            Ipv4Addr::new(arp[14], arp[15], arp[16], arp[17]),
+            Ipv4Addr::new(arp[24], arp[25], arp[26], arp[27]),
+        )),
+        (None, None) => None,
+    }
+}
+
+/// Get the ports of TCP or UDP, non-first IPv4 fragments have no ports.
+fn get_ports(frame: &[u8], protocol: Option<Protocol>) -> Option<(u16, u16)> {
+    let ip = get_ipv4(frame)?;
+    match (ip[9], protocol) {
+        (PROTOCOL_TCP, None) | (PROTOCOL_TCP, Some(Protocol::Tcp)) => {}
+        (PROTOCOL_UDP, None) | (PROTOCOL_UDP, Some(Protocol::Udp)) => {}
+        _ => return None,
+    }
+    if u16::from_be_bytes([ip[6], ip[7]]) & 0x1fff != 0 {
+        return None;
+    }
+    let offset = (ip[0] & 0x0f) as usize * 4;
+    let ports = ip.get(offset..offset + 4)?;
+
+    Some((
+        u16::from_be_bytes([ports[0], ports[1]]),
+        u16::from_be_bytes([ports[2], ports[3]]),
+    ))
+}
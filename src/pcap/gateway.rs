@@ -0,0 +1,114 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+
+use super::HardwareAddr;
+
+/// Represents the source hardware address of frames sent to sources.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpoofMac {
+    /// Represents frames are sent from the hardware address of the interface.
+    Own,
+    /// Represents frames are sent from the hardware address of the default gateway of the
+    /// interface.
+    Gateway,
+    /// Represents frames are sent from the hardware address.
+    Addr(HardwareAddr),
+}
+
+impl SpoofMac {
+    /// Parses a spoof MAC from `own`, `gateway` or a hardware address like `00:11:22:33:44:55`.
+    pub fn parse(s: &str) -> Option<SpoofMac> {
+        match s {
+            "own" => Some(SpoofMac::Own),
+            "gateway" => Some(SpoofMac::Gateway),
+            _ => s.parse().ok().map(SpoofMac::Addr),
+        }
+    }
+}
+
+impl Display for SpoofMac {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            SpoofMac::Own => write!(f, "own"),
+            SpoofMac::Gateway => write!(f, "gateway"),
+            SpoofMac::Addr(ref hardware_addr) => write!(f, "{}", hardware_addr),
+        }
+    }
+}
+
+/// Represents the default gateway of an interface.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Gateway {
+    /// Represents the IP address of the gateway
+    pub ip_addr: Ipv4Addr,
+    /// Represents the hardware address of the gateway, which is resolved by the ARP cache
+    pub hardware_addr: HardwareAddr,
+}
+
+impl Display for Gateway {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.ip_addr, self.hardware_addr)
+    }
+}
+
+/// Finds the default gateway of the interface by the routing table and the ARP cache. A gateway
+/// found is reachable, since its entry in the ARP cache is complete.
+#[cfg(target_os = "linux")]
+pub fn find_gateway(name: &str) -> io::Result<Gateway> {
+    use std::fs;
+
+    // The default route is in the form of `eth0 00000000 0102A8C0 0003 ...`, where the gateway is
+    // in little-endian hex
+    let routes = fs::read_to_string("/proc/net/route")?;
+    let ip_addr = routes
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|fields| fields.len() > 2 && fields[0] == name && fields[1] == "00000000")
+        .filter_map(|fields| u32::from_str_radix(fields[2], 16).ok())
+        .find(|&gateway| gateway != 0)
+        .map(|gateway| Ipv4Addr::from(u32::from_be(gateway)))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no default gateway", name),
+            )
+        })?;
+
+    // An ARP entry is in the form of `192.168.2.1 0x1 0x2 00:11:22:33:44:55 * eth0`, where the
+    // flag 0x2 represents the entry is complete
+    let arps = fs::read_to_string("/proc/net/arp")?;
+    let hardware_addr = arps
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|fields| fields.len() > 5 && fields[5] == name)
+        .filter(|fields| fields[0].parse::<Ipv4Addr>().ok() == Some(ip_addr))
+        .filter(|fields| {
+            u32::from_str_radix(fields[2].trim_start_matches("0x"), 16)
+                .map(|flags| flags & 0x2 != 0)
+                .unwrap_or(false)
+        })
+        .find_map(|fields| fields[3].parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("gateway {} is not resolved by ARP", ip_addr),
+            )
+        })?;
+
+    Ok(Gateway {
+        ip_addr,
+        hardware_addr,
+    })
+}
+
+/// Finds the default gateway of the interface, which is not supported except Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn find_gateway(_: &str) -> io::Result<Gateway> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "finding gateway is not supported",
+    ))
+}
@@ -0,0 +1,437 @@
+use pnet::datalink::MacAddr;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+use super::{Capture, HardwareAddr, Receiver, Sender, Transmit};
+
+/// Represents the size of the Ethernet header.
+const ETHERNET_HEADER_SIZE: usize = 14;
+/// Represents the size of the Linux cooked header.
+const SLL_HEADER_SIZE: usize = 16;
+
+/// Represents the packet type of the Linux cooked header of packets sent by local.
+const SLL_OUTGOING: u16 = 4;
+/// Represents the packet type of the Linux cooked header of broadcast packets.
+const SLL_BROADCAST: u16 = 1;
+/// Represents the packet type of the Linux cooked header of multicast packets.
+const SLL_MULTICAST: u16 = 2;
+/// Represents the ARPHRD type of Ethernet.
+const ARPHRD_ETHER: u16 = 1;
+/// Represents the size of the header of the loopback link layer.
+const LOOP_HEADER_SIZE: usize = 4;
+
+/// Represents the protocol family of IPv4 in the header of the loopback link layer.
+const AF_INET: u32 = 2;
+/// Represents the protocol families of IPv6 on Linux, Windows, NetBSD and OpenBSD, FreeBSD, and
+/// macOS.
+const AF_INET6S: [u32; 5] = [10, 23, 24, 28, 30];
+/// Represents the protocol family of IPv6 of the platform.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const AF_INET6: u32 = 30;
+#[cfg(target_os = "freebsd")]
+const AF_INET6: u32 = 28;
+#[cfg(windows)]
+const AF_INET6: u32 = 23;
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd", windows)))]
+const AF_INET6: u32 = 24;
+
+/// Represents the hardware address of the peer of links without link headers.
+const RAW_HARDWARE_ADDR: HardwareAddr = MacAddr(0x02, 0, 0, 0, 0, 0x01);
+
+/// Represents the types of the link layer of an interface or a capture file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LinkType {
+    /// Represents Ethernet (DLT_EN10MB).
+    Ethernet,
+    /// Represents raw IP without link headers (DLT_RAW).
+    Raw,
+    /// Represents the Linux cooked capture (DLT_LINUX_SLL).
+    LinuxSll,
+    /// Represents the loopback link layer with a protocol family in host byte order
+    /// (DLT_NULL), like `lo0` and `utun` devices on macOS.
+    Null,
+    /// Represents the loopback link layer with a protocol family in network byte order
+    /// (DLT_LOOP).
+    Loop,
+}
+
+impl LinkType {
+    /// Get the link type of the DLT of capture files. Returns an error naming the DLT if it is
+    /// not supported.
+    pub fn from_dlt(dlt: u32) -> io::Result<LinkType> {
+        match dlt {
+            0 => Ok(LinkType::Null),
+            1 => Ok(LinkType::Ethernet),
+            // LINKTYPE_RAW, the DLT_RAW of different platforms, and LINKTYPE_IPV4
+            101 | 12 | 14 | 228 => Ok(LinkType::Raw),
+            108 => Ok(LinkType::Loop),
+            113 => Ok(LinkType::LinuxSll),
+            _ => {
+                let name = match dlt {
+                    9 => "DLT_PPP",
+                    50 => "DLT_PPP_SERIAL",
+                    105 => "DLT_IEEE802_11",
+                    127 => "DLT_IEEE802_11_RADIO",
+                    229 => "DLT_IPV6",
+                    276 => "DLT_LINUX_SLL2",
+                    _ => "DLT unknown",
+                };
+
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported link type {} ({})", name, dlt),
+                ))
+            }
+        }
+    }
+
+    /// Get the DLT of the link type in capture files.
+    pub fn get_dlt(&self) -> u32 {
+        match self {
+            LinkType::Ethernet => 1,
+            LinkType::Raw => 101,
+            LinkType::LinuxSll => 113,
+            LinkType::Null => 0,
+            LinkType::Loop => 108,
+        }
+    }
+}
+
+impl Display for LinkType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            LinkType::Ethernet => write!(f, "DLT_EN10MB"),
+            LinkType::Raw => write!(f, "DLT_RAW"),
+            LinkType::LinuxSll => write!(f, "DLT_LINUX_SLL"),
+            LinkType::Null => write!(f, "DLT_NULL"),
+            LinkType::Loop => write!(f, "DLT_LOOP"),
+        }
+    }
+}
+
+/// Wraps the sender and the receiver of a link, so frames of the link are received and sent as
+/// Ethernet frames. ARP frames are never sent to links without link headers.
+pub fn wrap(
+    tx: Sender,
+    rx: Receiver,
+    link_type: LinkType,
+    local_hardware_addr: HardwareAddr,
+) -> (Sender, Receiver) {
+    match link_type {
+        LinkType::Ethernet => (tx, rx),
+        _ => (
+            Box::new(LinkSender {
+                tx,
+                link_type,
+                padding: 0,
+            }),
+            Box::new(LinkReceiver {
+                rx,
+                link_type,
+                local_hardware_addr,
+                padding: 0,
+                buffer: Vec::new(),
+            }),
+        ),
+    }
+}
+
+/// Wraps the sender and the receiver of a link whose link header is replaced with a zeroed
+/// Ethernet header by pnet, like loopback interfaces captured through BPF, so frames of the link
+/// are received and sent as Ethernet frames.
+pub fn wrap_emulated(
+    tx: Sender,
+    rx: Receiver,
+    local_hardware_addr: HardwareAddr,
+) -> (Sender, Receiver) {
+    (
+        Box::new(LinkSender {
+            tx,
+            link_type: LinkType::Raw,
+            padding: ETHERNET_HEADER_SIZE,
+        }),
+        Box::new(LinkReceiver {
+            rx,
+            link_type: LinkType::Raw,
+            local_hardware_addr,
+            padding: ETHERNET_HEADER_SIZE,
+            buffer: Vec::new(),
+        }),
+    )
+}
+
+/// Represents a receiver converting frames of a link to Ethernet frames.
+struct LinkReceiver {
+    rx: Receiver,
+    link_type: LinkType,
+    local_hardware_addr: HardwareAddr,
+    /// Represents the size of the emulated header before IP packets of links without link headers
+    padding: usize,
+    buffer: Vec<u8>,
+}
+
+impl Capture for LinkReceiver {
+    fn next_frame(&mut self) -> io::Result<&[u8]> {
+        loop {
+            let frame = self.rx.next_frame()?;
+            let (dst, src, t, payload) = match self.link_type {
+                LinkType::Raw => {
+                    let payload = match frame.get(self.padding..) {
+                        Some(payload) => payload,
+                        None => continue,
+                    };
+                    let t = match payload.first().map(|b| b >> 4) {
+                        Some(4) => 0x0800u16,
+                        Some(6) => 0x86dd,
+                        // Unknown IP version
+                        _ => continue,
+                    };
+
+                    (self.local_hardware_addr, RAW_HARDWARE_ADDR, t, payload)
+                }
+                LinkType::Null | LinkType::Loop => {
+                    if frame.len() < LOOP_HEADER_SIZE {
+                        continue;
+                    }
+                    let family = match self.link_type {
+                        // The byte order of the family is detected as it is in host byte order
+                        // of the capturing host, and families are always less than 65536
+                        LinkType::Null => {
+                            match u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]) {
+                                family if family > 0xffff => family.swap_bytes(),
+                                family => family,
+                            }
+                        }
+                        _ => u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]),
+                    };
+                    let t = match family {
+                        AF_INET => 0x0800u16,
+                        family if AF_INET6S.contains(&family) => 0x86dd,
+                        // Unknown protocol family
+                        _ => continue,
+                    };
+
+                    (
+                        self.local_hardware_addr,
+                        RAW_HARDWARE_ADDR,
+                        t,
+                        &frame[LOOP_HEADER_SIZE..],
+                    )
+                }
+                LinkType::LinuxSll => {
+                    if frame.len() < SLL_HEADER_SIZE {
+                        continue;
+                    }
+                    let packet_type = u16::from_be_bytes([frame[0], frame[1]]);
+                    let hardware_type = u16::from_be_bytes([frame[2], frame[3]]);
+                    let addr_len = u16::from_be_bytes([frame[4], frame[5]]);
+                    let t = u16::from_be_bytes([frame[14], frame[15]]);
+
+                    let src = match packet_type {
+                        // Frames sent from local
+                        SLL_OUTGOING => self.local_hardware_addr,
+                        _ if hardware_type == ARPHRD_ETHER && addr_len == 6 => HardwareAddr::new(
+                            frame[6], frame[7], frame[8], frame[9], frame[10], frame[11],
+                        ),
+                        _ => RAW_HARDWARE_ADDR,
+                    };
+                    let dst = match packet_type {
+                        SLL_BROADCAST | SLL_MULTICAST => MacAddr::broadcast(),
+                        _ => self.local_hardware_addr,
+                    };
+
+                    (dst, src, t, &frame[SLL_HEADER_SIZE..])
+                }
+                LinkType::Ethernet => unreachable!(),
+            };
+
+            self.buffer.clear();
+            self.buffer
+                .extend_from_slice(&[dst.0, dst.1, dst.2, dst.3, dst.4, dst.5]);
+            self.buffer
+                .extend_from_slice(&[src.0, src.1, src.2, src.3, src.4, src.5]);
+            self.buffer.extend_from_slice(&t.to_be_bytes());
+            self.buffer.extend_from_slice(payload);
+
+            return Ok(&self.buffer);
+        }
+    }
+}
+
+/// Represents a sender converting Ethernet frames to frames of a link.
+struct LinkSender {
+    tx: Sender,
+    link_type: LinkType,
+    /// Represents the size of the emulated header before IP packets of links without link headers
+    padding: usize,
+}
+
+impl Transmit for LinkSender {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if frame.len() < ETHERNET_HEADER_SIZE {
+            return Ok(());
+        }
+        let t = u16::from_be_bytes([frame[12], frame[13]]);
+        match self.link_type {
+            LinkType::Raw => match t {
+                0x0800 | 0x86dd => self
+                    .tx
+                    .send_frame(&frame[ETHERNET_HEADER_SIZE - self.padding..]),
+                // ARP and the others
+                _ => Ok(()),
+            },
+            LinkType::Null | LinkType::Loop => {
+                let family = match t {
+                    0x0800 => AF_INET,
+                    0x86dd => AF_INET6,
+                    // ARP and the others
+                    _ => return Ok(()),
+                };
+                let mut buffer =
+                    Vec::with_capacity(LOOP_HEADER_SIZE + frame.len() - ETHERNET_HEADER_SIZE);
+                match self.link_type {
+                    LinkType::Null => buffer.extend_from_slice(&family.to_ne_bytes()),
+                    _ => buffer.extend_from_slice(&family.to_be_bytes()),
+                }
+                buffer.extend_from_slice(&frame[ETHERNET_HEADER_SIZE..]);
+
+                self.tx.send_frame(&buffer)
+            }
+            LinkType::LinuxSll => {
+                let mut buffer = Vec::with_capacity(SLL_HEADER_SIZE + frame.len());
+                buffer.extend_from_slice(&SLL_OUTGOING.to_be_bytes());
+                buffer.extend_from_slice(&ARPHRD_ETHER.to_be_bytes());
+                buffer.extend_from_slice(&6u16.to_be_bytes());
+                buffer.extend_from_slice(&frame[6..12]);
+                buffer.extend_from_slice(&[0u8; 2]);
+                buffer.extend_from_slice(&frame[12..]);
+
+                self.tx.send_frame(&buffer)
+            }
+            LinkType::Ethernet => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcap::channel::{self, Peer};
+    use std::time::Duration;
+
+    const LOCAL_HARDWARE_ADDR: HardwareAddr = MacAddr(0x02, 0, 0, 0, 0, 0x02);
+    /// Represents the head of an IPv4 packet.
+    const IPV4: [u8; 4] = [0x45, 0x00, 0x00, 0x14];
+    /// Represents the head of an IPv6 packet.
+    const IPV6: [u8; 4] = [0x60, 0x00, 0x00, 0x00];
+
+    /// Opens a channel of the link, returns the sender and the receiver of Ethernet frames and
+    /// the peer of frames of the link.
+    fn open(link_type: LinkType) -> (Sender, Receiver, Peer) {
+        let (tx, rx, peer) = channel::open();
+        let (tx, rx) = wrap(tx, rx, link_type, LOCAL_HARDWARE_ADDR);
+
+        (tx, rx, peer)
+    }
+
+    /// Get the frame of the link of the loopback header and the packet.
+    fn new_loop_frame(header: [u8; 4], packet: &[u8]) -> Vec<u8> {
+        let mut frame = header.to_vec();
+        frame.extend_from_slice(packet);
+
+        frame
+    }
+
+    /// Get the Ethernet frame of the type and the packet, as sent to the source.
+    fn new_ethernet_frame(t: u16, packet: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x02, 0, 0, 0, 0, 0x01];
+        frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+        frame.extend_from_slice(&t.to_be_bytes());
+        frame.extend_from_slice(packet);
+
+        frame
+    }
+
+    /// Asserts the next frame received is the Ethernet frame of the type and the packet.
+    fn assert_received(rx: &mut Receiver, t: u16, packet: &[u8]) {
+        let frame = rx.next_frame().unwrap();
+        let (dst, src) = (LOCAL_HARDWARE_ADDR, RAW_HARDWARE_ADDR);
+        assert_eq!(&frame[..6], &[dst.0, dst.1, dst.2, dst.3, dst.4, dst.5]);
+        assert_eq!(&frame[6..12], &[src.0, src.1, src.2, src.3, src.4, src.5]);
+        assert_eq!(&frame[12..14], &t.to_be_bytes());
+        assert_eq!(&frame[14..], packet);
+    }
+
+    #[test]
+    fn null_round_trip() {
+        let (mut tx, mut rx, peer) = open(LinkType::Null);
+
+        // The family is in host byte order of the capturing host, either little-endian or
+        // big-endian
+        for header in [[2, 0, 0, 0], [0, 0, 0, 2]].iter() {
+            peer.inject(&new_loop_frame(*header, &IPV4)).unwrap();
+            assert_received(&mut rx, 0x0800, &IPV4);
+        }
+        // IPv6 of every platform
+        for family in AF_INET6S.iter() {
+            peer.inject(&new_loop_frame(family.to_le_bytes(), &IPV6))
+                .unwrap();
+            assert_received(&mut rx, 0x86dd, &IPV6);
+            peer.inject(&new_loop_frame(family.to_be_bytes(), &IPV6))
+                .unwrap();
+            assert_received(&mut rx, 0x86dd, &IPV6);
+        }
+
+        // Frames are sent in host byte order, and IPv6 in the family of the platform
+        tx.send_frame(&new_ethernet_frame(0x0800, &IPV4)).unwrap();
+        assert_eq!(
+            peer.collect(Duration::from_secs(1)).unwrap(),
+            new_loop_frame(AF_INET.to_ne_bytes(), &IPV4)
+        );
+        tx.send_frame(&new_ethernet_frame(0x86dd, &IPV6)).unwrap();
+        assert_eq!(
+            peer.collect(Duration::from_secs(1)).unwrap(),
+            new_loop_frame(AF_INET6.to_ne_bytes(), &IPV6)
+        );
+    }
+
+    #[test]
+    fn loop_round_trip() {
+        let (mut tx, mut rx, peer) = open(LinkType::Loop);
+
+        // The family is always in network byte order, a little-endian one is unknown
+        peer.inject(&new_loop_frame([2, 0, 0, 0], &IPV4)).unwrap();
+        peer.inject(&new_loop_frame([0, 0, 0, 2], &IPV4)).unwrap();
+        assert_received(&mut rx, 0x0800, &IPV4);
+        peer.inject(&new_loop_frame(AF_INET6.to_be_bytes(), &IPV6))
+            .unwrap();
+        assert_received(&mut rx, 0x86dd, &IPV6);
+
+        tx.send_frame(&new_ethernet_frame(0x0800, &IPV4)).unwrap();
+        assert_eq!(
+            peer.collect(Duration::from_secs(1)).unwrap(),
+            new_loop_frame([0, 0, 0, 2], &IPV4)
+        );
+        tx.send_frame(&new_ethernet_frame(0x86dd, &IPV6)).unwrap();
+        assert_eq!(
+            peer.collect(Duration::from_secs(1)).unwrap(),
+            new_loop_frame(AF_INET6.to_be_bytes(), &IPV6)
+        );
+    }
+
+    #[test]
+    fn loop_skipped() {
+        let (mut tx, mut rx, peer) = open(LinkType::Null);
+
+        // Truncated headers and unknown families are skipped
+        peer.inject(&[2, 0, 0]).unwrap();
+        peer.inject(&new_loop_frame([7, 0, 0, 0], &IPV4)).unwrap();
+        peer.inject(&new_loop_frame([2, 0, 0, 0], &IPV4)).unwrap();
+        assert_received(&mut rx, 0x0800, &IPV4);
+
+        // ARP is never sent
+        tx.send_frame(&new_ethernet_frame(0x0806, &[0; 28]))
+            .unwrap();
+        assert!(peer.collect_all().is_empty());
+    }
+}
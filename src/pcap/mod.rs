@@ -1,18 +1,575 @@
+use log::{debug, warn};
 use pnet::datalink::{self, Channel, Config, DataLinkReceiver, DataLinkSender, MacAddr};
 use std::clone::Clone;
+use std::cmp::{max, min};
+use std::collections::VecDeque;
 use std::fmt::{self, Display, Formatter};
 use std::io;
 use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::event;
+use crate::metrics::{self, DropReason};
+use crate::packet::layer::ethernet::{self, ControlKind};
+use crate::packet::layer::LayerTypes;
+
+pub mod bridge;
+pub mod capture;
+pub mod channel;
+pub mod chaos;
+pub mod device;
+pub mod dump;
+pub mod file;
+pub mod filter;
+pub mod gateway;
+pub mod link;
+pub mod trace;
+#[cfg(all(target_os = "linux", feature = "tun"))]
+pub mod tun;
 
 pub type HardwareAddr = pnet::datalink::MacAddr;
 
 pub const HARDWARE_ADDR_UNSPECIFIED: HardwareAddr = pnet::datalink::MacAddr(0, 0, 0, 0, 0, 0);
 
-pub type Sender = Box<dyn DataLinkSender>;
-pub type Receiver = Box<dyn DataLinkReceiver>;
+/// Represents a transmitter of frames to sources, like a device, a capture file or an in-memory
+/// channel.
+pub trait Transmit: Send {
+    /// Sends a frame.
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()>;
+}
+
+/// Represents a source of frames captured from sources, like a device, a capture file or an
+/// in-memory channel.
+pub trait Capture: Send {
+    /// Get the next frame captured. Returns an error of `TimedOut` if no frame is captured in the
+    /// read timeout, or `UnexpectedEof` if the capture ends.
+    fn next_frame(&mut self) -> io::Result<&[u8]>;
+}
+
+impl Transmit for Box<dyn DataLinkSender> {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.send_to(frame, None).unwrap_or(Ok(()))
+    }
+}
+
+impl Capture for Box<dyn DataLinkReceiver> {
+    fn next_frame(&mut self) -> io::Result<&[u8]> {
+        self.next()
+    }
+}
+
+pub type Sender = Box<dyn Transmit>;
+pub type Receiver = Box<dyn Capture>;
 
 /// Represents the buffer size of pcap channels.
 const BUFFER_SIZE: usize = 256 * 1024;
+/// Represents the read timeout of pcap channels in milliseconds.
+const READ_TIMEOUT: u64 = 100;
+
+/// Represents the name of the Npcap loopback adapter, which captures and injects traffic of the
+/// local host in the loopback link layer.
+const NPCAP_LOOPBACK: &str = "\\Device\\NPF_Loopback";
+
+/// Represents the max size of frames in a send queue.
+const MAX_QUEUE_SIZE: usize = 64 * 1024;
+/// Represents the max age of frames in a send queue in milliseconds.
+const MAX_QUEUE_AGE: u128 = 1;
+/// Represents the max size of frames kept in a send queue while the device is down.
+const MAX_DOWN_QUEUE_SIZE: usize = 256 * 1024;
+
+/// Represents the max retries of a frame which fails to be sent for a transient error.
+const MAX_SEND_RETRIES: usize = 4;
+/// Represents the initial interval between retries of a frame in microseconds, which is backed
+/// off exponentially.
+const SEND_RETRY_INTERVAL: u64 = 50;
+/// Represents the timeout of a congestion in milliseconds, after which relays resume reading
+/// even if no frame is sent since.
+const CONGESTION_TIMEOUT: u128 = 100;
+
+/// Represents the size of the Ethernet header.
+const ETHERNET_HEADER_SIZE: usize = 14;
+/// Represents the size of the VLAN tag.
+const VLAN_TAG_SIZE: usize = 4;
+/// Represents the EtherType of VLAN tags.
+const ETHERTYPE_VLAN: u16 = 0x8100;
+/// Represents the EtherTypes of service tags of stacked VLANs.
+const ETHERTYPE_SERVICE_VLANS: [u16; 2] = [0x88a8, 0x9100];
+/// Represents the EtherType of ARP.
+const ETHERTYPE_ARP: u16 = 0x0806;
+/// Represents the EtherType of IPv4.
+const ETHERTYPE_IPV4: u16 = 0x0800;
+/// Represents the EtherType of IPv6.
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+/// Represents the size of the fixed part of ARP packets before the addresses.
+const ARP_HEADER_SIZE: usize = 8;
+/// Represents the size of the IPv4 header without options.
+const IPV4_HEADER_SIZE: usize = 20;
+/// Represents the size of the IPv6 header.
+const IPV6_HEADER_SIZE: usize = 40;
+
+/// Represents the congestion of a send queue, which begins when a frame fails to be sent for a
+/// transient error like the exhaustion of the send buffer, and ends when a frame is sent.
+/// Relays stop reading from streams while the send queue is congested, so destinations are
+/// pushed back by TCP flow control instead of frames being dropped.
+#[derive(Debug)]
+pub struct Congestion {
+    is_congested: AtomicBool,
+    /// Represents the instant the congestion begins
+    instant: Mutex<Instant>,
+}
+
+impl Congestion {
+    /// Creates a new `Congestion`.
+    pub fn new() -> Congestion {
+        Congestion {
+            is_congested: AtomicBool::new(false),
+            instant: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn begin(&self) {
+        if !self.is_congested.swap(true, Ordering::Relaxed) {
+            *self.instant.lock().unwrap() = Instant::now();
+        }
+    }
+
+    fn end(&self) {
+        if self.is_congested.load(Ordering::Relaxed) {
+            self.is_congested.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns if the send queue is congested. A congestion ends after the timeout, so relays
+    /// never wait forever.
+    pub fn is_congested(&self) -> bool {
+        self.is_congested.load(Ordering::Relaxed)
+            && self.instant.lock().unwrap().elapsed().as_millis() < CONGESTION_TIMEOUT
+    }
+}
+
+impl Default for Congestion {
+    fn default() -> Congestion {
+        Congestion::new()
+    }
+}
+
+/// Represents a queue of frames sent in batch. Frames are queued until the queue reaches the max
+/// number of frames, the max size or the max age, and a frame sent immediately flushes the queue
+/// before it, so frames are always sent in order. Frames are kept while the device is down, and
+/// sent after the device is reopened.
+pub struct SendQueue {
+    tx: Sender,
+    max_count: usize,
+    buffer: Vec<u8>,
+    sizes: Vec<usize>,
+    /// Represents the instant of the first frame in the queue
+    instant: Instant,
+    batched_count: usize,
+    immediate_count: usize,
+    dumper: Option<Arc<Mutex<dump::Dumper>>>,
+    is_down: bool,
+    down_queue: VecDeque<Vec<u8>>,
+    down_size: usize,
+    down_drop_count: usize,
+    mtu: Option<u16>,
+    mtu_drop_count: usize,
+    /// Represents the impairment of frames for testing
+    chaos: Option<chaos::ChaosQueue>,
+    congestion: Arc<Congestion>,
+}
+
+impl SendQueue {
+    /// Creates a new `SendQueue`. Frames are always sent immediately if the max number of frames
+    /// is not greater than 1.
+    pub fn new(tx: Sender, max_count: usize) -> SendQueue {
+        SendQueue {
+            tx,
+            max_count,
+            buffer: Vec::new(),
+            sizes: Vec::new(),
+            instant: Instant::now(),
+            batched_count: 0,
+            immediate_count: 0,
+            dumper: None,
+            is_down: false,
+            down_queue: VecDeque::new(),
+            down_size: 0,
+            down_drop_count: 0,
+            mtu: None,
+            mtu_drop_count: 0,
+            chaos: None,
+            congestion: Arc::new(Congestion::new()),
+        }
+    }
+
+    /// Sets the dumper of frames sent.
+    pub fn set_dumper(&mut self, dumper: Arc<Mutex<dump::Dumper>>) {
+        self.dumper = Some(dumper);
+    }
+
+    /// Sets the max number of frames in the queue.
+    pub fn set_max_count(&mut self, max_count: usize) {
+        self.max_count = max_count;
+    }
+
+    /// Sets the MTU of the device. Frames larger than the MTU are dropped instead of being sent
+    /// to the device.
+    pub fn set_mtu(&mut self, mtu: u16) {
+        self.mtu = Some(mtu);
+    }
+
+    /// Sets the chaos impairing frames sent, which are delayed, dropped and reordered before
+    /// they are sent. Frames delayed are sent by `release`.
+    pub fn set_chaos(&mut self, chaos: chaos::Chaos) {
+        self.chaos = Some(chaos::ChaosQueue::new(chaos));
+    }
+
+    /// Returns the chaos impairing frames sent with its seed.
+    pub fn get_chaos(&self) -> Option<chaos::Chaos> {
+        self.chaos.as_ref().map(|chaos| chaos.get_chaos())
+    }
+
+    /// Marks the device down. Frames are kept until the max size while the device is down, and
+    /// dropped after it.
+    pub fn set_down(&mut self) {
+        self.is_down = true;
+    }
+
+    /// Sets the sender of the device reopened, and sends the frames kept while the device is
+    /// down.
+    pub fn set_sender(&mut self, tx: Sender) -> io::Result<()> {
+        self.tx = tx;
+        self.is_down = false;
+
+        self.flush()?;
+        while let Some(frame) = self.down_queue.pop_front() {
+            self.down_size -= frame.len();
+            self.immediate_count += 1;
+            metrics::PACKETS_SENT.increase();
+            self.send_to(&frame)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns if the device is down.
+    pub fn is_down(&self) -> bool {
+        self.is_down
+    }
+
+    /// Get the congestion of the queue.
+    pub fn get_congestion(&self) -> Arc<Congestion> {
+        Arc::clone(&self.congestion)
+    }
+
+    /// Sends a frame, or queues it if it is not sent immediately.
+    pub fn send(&mut self, frame: &[u8], is_immediate: bool) -> io::Result<()> {
+        if let Some(mtu) = self.mtu {
+            // Frames are fragmented and segmented by the MTU before, a frame exceeding the MTU
+            // would fail in the device
+            if let Some((_, payload)) = get_network(frame) {
+                if payload.len() > mtu as usize {
+                    self.mtu_drop_count += 1;
+                    debug!(
+                        "drop frame of {} Bytes for exceeding MTU {} ({} dropped)",
+                        payload.len(),
+                        mtu,
+                        self.mtu_drop_count
+                    );
+                    event::drop_packet(DropReason::MtuExceeded, LayerTypes::Ethernet);
+
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(ref mut chaos) = self.chaos {
+            chaos.push(frame, is_immediate);
+
+            return self.release();
+        }
+
+        self.transmit(frame, is_immediate)
+    }
+
+    /// Sends the frames impaired by the chaos which are due.
+    pub fn release(&mut self) -> io::Result<()> {
+        let frames = match self.chaos {
+            Some(ref mut chaos) => chaos.pop(),
+            None => return Ok(()),
+        };
+
+        for (frame, is_immediate) in frames {
+            self.transmit(&frame, is_immediate)?;
+        }
+
+        Ok(())
+    }
+
+    fn transmit(&mut self, frame: &[u8], is_immediate: bool) -> io::Result<()> {
+        if self.is_down {
+            self.keep(frame);
+
+            return Ok(());
+        }
+        if is_immediate || self.max_count <= 1 {
+            self.flush()?;
+            self.immediate_count += 1;
+            metrics::PACKETS_SENT.increase();
+
+            return self.send_to(frame);
+        }
+
+        if self.buffer.len() + frame.len() > MAX_QUEUE_SIZE {
+            self.flush()?;
+        }
+        if self.sizes.is_empty() {
+            self.instant = Instant::now();
+        }
+        self.buffer.extend_from_slice(frame);
+        self.sizes.push(frame.len());
+        self.batched_count += 1;
+        metrics::PACKETS_SENT.increase();
+
+        if self.sizes.len() >= self.max_count {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Keeps a frame while the device is down, or drops it if the queue is full.
+    fn keep(&mut self, frame: &[u8]) {
+        if self.down_size + frame.len() > MAX_DOWN_QUEUE_SIZE {
+            self.down_drop_count += 1;
+            event::drop_packet(DropReason::DeviceDown, LayerTypes::Ethernet);
+
+            return;
+        }
+        self.down_size += frame.len();
+        self.down_queue.push_back(frame.to_vec());
+    }
+
+    /// Sends all the frames in the queue. Frames are kept in the queue while the device is down.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.is_down {
+            return Ok(());
+        }
+
+        let mut result = Ok(());
+        let mut begin = 0;
+        for size in self.sizes.drain(..) {
+            if result.is_ok() {
+                let frame = &self.buffer[begin..begin + size];
+                result = send_with_retry(&mut self.tx, frame, &self.congestion);
+                if result.is_ok() {
+                    dump_frame(&self.dumper, frame);
+                }
+            }
+            begin += size;
+        }
+        self.buffer.clear();
+
+        result
+    }
+
+    fn send_to(&mut self, frame: &[u8]) -> io::Result<()> {
+        send_with_retry(&mut self.tx, frame, &self.congestion)?;
+        dump_frame(&self.dumper, frame);
+
+        Ok(())
+    }
+
+    /// Returns if the frames in the queue reaches the max age.
+    pub fn is_expired(&self) -> bool {
+        !self.sizes.is_empty() && self.instant.elapsed().as_millis() >= MAX_QUEUE_AGE
+    }
+
+    /// Get the count of frames sent in batch.
+    pub fn get_batched_count(&self) -> usize {
+        self.batched_count
+    }
+
+    /// Get the count of frames sent immediately.
+    pub fn get_immediate_count(&self) -> usize {
+        self.immediate_count
+    }
+
+    /// Get the count of frames dropped for exceeding the MTU.
+    pub fn get_mtu_drop_count(&self) -> usize {
+        self.mtu_drop_count
+    }
+
+    /// Get the count of frames dropped by the chaos.
+    pub fn get_chaos_drop_count(&self) -> usize {
+        match self.chaos {
+            Some(ref chaos) => chaos.get_drop_count(),
+            None => 0,
+        }
+    }
+
+    /// Get the count of frames reordered by the chaos.
+    pub fn get_chaos_reorder_count(&self) -> usize {
+        match self.chaos {
+            Some(ref chaos) => chaos.get_reorder_count(),
+            None => 0,
+        }
+    }
+
+    /// Get the count of frames dropped while the device is down.
+    pub fn get_down_drop_count(&self) -> usize {
+        self.down_drop_count
+    }
+}
+
+/// Sends a frame, a frame which fails to be sent for a transient error is retried with a backoff
+/// in microseconds and marks the queue congested. Other errors like the device being lost fail
+/// at once.
+fn send_with_retry(tx: &mut Sender, frame: &[u8], congestion: &Congestion) -> io::Result<()> {
+    let mut retries = 0;
+    loop {
+        match tx.send_frame(frame) {
+            Ok(_) => {
+                congestion.end();
+
+                return Ok(());
+            }
+            Err(ref e) if is_transient(e) && retries < MAX_SEND_RETRIES => {
+                congestion.begin();
+                metrics::SEND_RETRIES.increase();
+                thread::sleep(Duration::from_micros(SEND_RETRY_INTERVAL << retries));
+                retries += 1;
+            }
+            Err(e) => {
+                if is_transient(&e) {
+                    metrics::SEND_FAILURES.increase();
+                }
+
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Returns if the error of sending a frame is transient, like the send buffer of the device
+/// being exhausted.
+fn is_transient(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        if let Some(code) = e.raw_os_error() {
+            return code == libc::ENOBUFS || code == libc::EAGAIN || code == libc::EINTR;
+        }
+    }
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted
+}
+
+/// Returns if the error of a capture is caused by the device being lost, like a USB adapter
+/// unplugged or a Wi-Fi interface roaming.
+pub fn is_device_lost(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        if let Some(code) = e.raw_os_error() {
+            return code == libc::ENETDOWN
+                || code == libc::ENODEV
+                || code == libc::ENXIO
+                || code == libc::EIO;
+        }
+    }
+
+    // Errors of libpcap and BPF are reported by pnet in their messages only
+    e.kind() == io::ErrorKind::Other
+}
+
+/// Get the EtherType and the payload of an Ethernet frame, a VLAN tag is skipped. The padding
+/// of short frames is excluded from ARP, IPv4 and IPv6 packets by their lengths. Returns `None`
+/// if the frame is truncated, or if it is an IEEE 802.3 frame carrying no EtherType.
+pub fn get_network(frame: &[u8]) -> Option<(u16, &[u8])> {
+    let (t, offset) = get_ethertype(frame)?;
+    if t <= ethernet::MAX_LENGTH {
+        return None;
+    }
+
+    Some((t, trim_padding(t, &frame[offset..])))
+}
+
+/// Get the offset of the payload of an Ethernet frame, a VLAN tag is skipped.
+pub fn get_network_offset(frame: &[u8]) -> Option<usize> {
+    get_ethertype(frame).map(|(_, offset)| offset)
+}
+
+/// Get the kind of a control frame of switches, like BPDUs and LLDP, which are expected in a
+/// link and never processed.
+pub fn get_control_kind(frame: &[u8]) -> Option<ControlKind> {
+    let (t, offset) = get_ethertype(frame)?;
+    let payload = &frame[offset..];
+    let payload = match t {
+        length if length <= ethernet::MAX_LENGTH => &payload[..min(length as usize, payload.len())],
+        _ => payload,
+    };
+
+    ControlKind::classify(t, payload)
+}
+
+/// Get the EtherType or the length field of an Ethernet frame and the offset of its payload, a
+/// VLAN tag is skipped.
+fn get_ethertype(frame: &[u8]) -> Option<(u16, usize)> {
+    if frame.len() < ETHERNET_HEADER_SIZE {
+        return None;
+    }
+    match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETHERTYPE_VLAN => {
+            let tag = frame.get(ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + VLAN_TAG_SIZE)?;
+
+            Some((
+                u16::from_be_bytes([tag[2], tag[3]]),
+                ETHERNET_HEADER_SIZE + VLAN_TAG_SIZE,
+            ))
+        }
+        t => Some((t, ETHERNET_HEADER_SIZE)),
+    }
+}
+
+/// Trims the padding of a short frame after the packet, the length in the header is trusted
+/// over the size of the frame. Packets whose lengths exceed the frame are left to their parsers.
+fn trim_padding(t: u16, packet: &[u8]) -> &[u8] {
+    let length = match t {
+        ETHERTYPE_ARP if packet.len() >= ARP_HEADER_SIZE => {
+            ARP_HEADER_SIZE + 2 * (packet[4] as usize + packet[5] as usize)
+        }
+        ETHERTYPE_IPV4 if packet.len() >= IPV4_HEADER_SIZE && packet[0] >> 4 == 4 => max(
+            u16::from_be_bytes([packet[2], packet[3]]) as usize,
+            IPV4_HEADER_SIZE,
+        ),
+        // A zero payload length is of jumbograms (RFC 2675)
+        ETHERTYPE_IPV6 if packet.len() >= IPV6_HEADER_SIZE && packet[4..6] != [0, 0] => {
+            IPV6_HEADER_SIZE + u16::from_be_bytes([packet[4], packet[5]]) as usize
+        }
+        _ => packet.len(),
+    };
+
+    &packet[..min(length, packet.len())]
+}
+
+/// Returns if the Ethernet frame is in stacked VLANs, which are not supported.
+pub fn is_stacked(frame: &[u8]) -> bool {
+    match get_network(frame) {
+        Some((t, _)) => t == ETHERTYPE_VLAN || ETHERTYPE_SERVICE_VLANS.contains(&t),
+        None => false,
+    }
+}
+
+/// Dumps a frame sent and captures it for its connection, a failed dump never fails sending the
+/// frame.
+fn dump_frame(dumper: &Option<Arc<Mutex<dump::Dumper>>>, frame: &[u8]) {
+    capture::record(frame, dump::Direction::Sent);
+    if let Some(ref dumper) = dumper {
+        if let Err(ref e) = dumper.lock().unwrap().dump(frame, dump::Direction::Sent) {
+            warn!("handle {}: {}", "dump", e);
+        }
+    }
+}
 
 /// Represents a network interface and its associated addresses.
 #[derive(Clone, Debug)]
@@ -21,6 +578,9 @@ pub struct Interface {
     pub alias: Option<String>,
     pub hardware_addr: MacAddr,
     pub ip_addrs: Vec<Ipv4Addr>,
+    pub link_type: link::LinkType,
+    /// Represents the MTU, which is `None` if it cannot be read
+    pub mtu: Option<u16>,
     pub is_up: bool,
     pub is_loopback: bool,
 }
@@ -33,12 +593,25 @@ impl Interface {
             alias: None,
             hardware_addr: MacAddr::zero(),
             ip_addrs: vec![],
+            link_type: link::LinkType::Ethernet,
+            mtu: None,
             is_up: false,
             is_loopback: false,
         }
     }
 
-    // Opens the network interface for sending and receiving data.
+    /// Returns if the interface is the Npcap loopback adapter in Windows.
+    pub fn is_npcap_loopback(&self) -> bool {
+        cfg!(windows) && self.name.eq_ignore_ascii_case(NPCAP_LOOPBACK)
+    }
+
+    /// Returns if the interface can be opened, which requires the permission of capturing.
+    pub fn is_openable(&self) -> bool {
+        self.open().is_ok()
+    }
+
+    // Opens the network interface for sending and receiving data, frames are always sent and
+    // received as Ethernet frames despite the link type of the interface.
     pub fn open(&self) -> io::Result<(Sender, Receiver)> {
         let inters = datalink::interfaces();
         let inter = inters
@@ -53,13 +626,32 @@ impl Interface {
         let mut config = Config::default();
         config.write_buffer_size = BUFFER_SIZE;
         config.read_buffer_size = BUFFER_SIZE;
+        config.read_timeout = Some(Duration::from_millis(READ_TIMEOUT));
         let channel = datalink::channel(&inter, config)?;
-        let channel = match channel {
-            Channel::Ethernet(tx, rx) => (tx, rx),
+        let channel: (Sender, Receiver) = match channel {
+            Channel::Ethernet(tx, rx) => (Box::new(tx), Box::new(rx)),
             _ => return Err(io::Error::new(io::ErrorKind::Other, "unknown link type")),
         };
 
-        Ok(channel)
+        // pnet replaces the header of loopback links captured through BPF with a zeroed
+        // Ethernet header
+        #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+        {
+            if self.link_type == link::LinkType::Null {
+                return Ok(link::wrap_emulated(
+                    channel.0,
+                    channel.1,
+                    self.hardware_addr,
+                ));
+            }
+        }
+
+        Ok(link::wrap(
+            channel.0,
+            channel.1,
+            self.link_type,
+            self.hardware_addr,
+        ))
     }
 }
 
@@ -84,8 +676,12 @@ impl Display for Interface {
         );
 
         let mut flags = String::new();
-        if self.is_loopback {
+        if self.is_npcap_loopback() {
+            flags = String::from(" (Npcap Loopback)");
+        } else if self.is_loopback {
             flags = String::from(" (Loopback)");
+        } else if self.link_type != link::LinkType::Ethernet {
+            flags = format!(" ({})", self.link_type);
         }
 
         write!(f, "{}{}{}: {}", name, hardware_addr, flags, ip_addrs)
@@ -107,8 +703,27 @@ pub fn interfaces() -> Vec<Interface> {
 
             let mut i = Interface::new();
             i.name = inter.name.clone();
+            // Point-to-point interfaces like TUN and PPP have no link headers
+            if inter.is_point_to_point() && !inter.is_loopback() {
+                i.link_type = link::LinkType::Raw;
+            }
+            // Loopback and point-to-point interfaces like `lo0` and `utun` have loopback link
+            // headers through BPF
+            #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+            {
+                if inter.is_point_to_point() || inter.is_loopback() {
+                    i.link_type = link::LinkType::Null;
+                }
+            }
+            // The Npcap loopback adapter has no hardware address and no adapter addresses, and
+            // frames of it have loopback link headers
+            let is_npcap_loopback = i.is_npcap_loopback();
+            if is_npcap_loopback {
+                i.link_type = link::LinkType::Null;
+            }
             i.hardware_addr = match inter.mac {
                 Some(mac) => mac,
+                None if i.link_type != link::LinkType::Ethernet => MacAddr::zero(),
                 None => return Err(()),
             };
             i.ip_addrs = inter
@@ -120,14 +735,18 @@ pub fn interfaces() -> Vec<Interface> {
                 })
                 .filter_map(Result::ok)
                 .collect();
+            if is_npcap_loopback && i.ip_addrs.is_empty() {
+                i.ip_addrs.push(Ipv4Addr::LOCALHOST);
+            }
 
             // Exclude interface without any IPv4 address
             if i.ip_addrs.len() <= 0 {
                 return Err(());
             }
 
+            i.mtu = device::get_mtu(&i.name).ok();
             i.is_up = inter.is_up();
-            i.is_loopback = inter.is_loopback();
+            i.is_loopback = inter.is_loopback() || is_npcap_loopback;
 
             Ok(i)
         })
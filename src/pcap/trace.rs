@@ -0,0 +1,220 @@
+use log::info;
+use std::fmt::{self, Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use super::dump::Direction;
+use crate::packet::Indicator;
+
+/// Represents the count of bytes in a line of hex dumps.
+const BYTES_PER_LINE: usize = 16;
+
+/// Represents the protocol of a trace rule.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TraceProtocol {
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+impl Display for TraceProtocol {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            TraceProtocol::Tcp => write!(f, "tcp"),
+            TraceProtocol::Udp => write!(f, "udp"),
+            TraceProtocol::Icmp => write!(f, "icmp"),
+        }
+    }
+}
+
+/// Represents an endpoint of a trace rule, an address with an optional port.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Endpoint {
+    ip_addr: Ipv4Addr,
+    port: Option<u16>,
+}
+
+impl Endpoint {
+    fn parse(s: &str) -> Option<Endpoint> {
+        match s.parse::<SocketAddrV4>() {
+            Ok(addr) => Some(Endpoint {
+                ip_addr: *addr.ip(),
+                port: Some(addr.port()),
+            }),
+            Err(_) => Some(Endpoint {
+                ip_addr: s.parse().ok()?,
+                port: None,
+            }),
+        }
+    }
+
+    fn is_match(&self, ip_addr: Ipv4Addr, port: Option<u16>) -> bool {
+        self.ip_addr == ip_addr && (self.port.is_none() || self.port == port)
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}:{}", self.ip_addr, port),
+            None => write!(f, "{}", self.ip_addr),
+        }
+    }
+}
+
+/// Represents a rule of flows traced, by a protocol and up to 2 endpoints.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceRule {
+    protocol: TraceProtocol,
+    endpoints: Vec<Endpoint>,
+}
+
+impl TraceRule {
+    /// Parses a trace rule in the form of `PROTOCOL[,ENDPOINT[,ENDPOINT]]` like
+    /// `udp,10.6.0.2:3074`, where an endpoint is an address with an optional port. A packet
+    /// matches if it is in the protocol and each endpoint is its source or destination. Returns
+    /// `None` if the rule is not valid.
+    pub fn parse(s: &str) -> Option<TraceRule> {
+        let fields: Vec<&str> = s.split(',').map(|field| field.trim()).collect();
+        if fields.len() > 3 {
+            return None;
+        }
+        let protocol = match fields[0].to_ascii_lowercase().as_str() {
+            "tcp" => TraceProtocol::Tcp,
+            "udp" => TraceProtocol::Udp,
+            "icmp" => TraceProtocol::Icmp,
+            _ => return None,
+        };
+        let endpoints = fields[1..]
+            .iter()
+            .map(|field| Endpoint::parse(field))
+            .collect::<Option<Vec<_>>>()?;
+        // ICMP has no ports
+        if protocol == TraceProtocol::Icmp && endpoints.iter().any(|e| e.port.is_some()) {
+            return None;
+        }
+
+        Some(TraceRule {
+            protocol,
+            endpoints,
+        })
+    }
+
+    /// Returns if the packet of the indicator matches the rule.
+    pub fn is_match(&self, indicator: &Indicator) -> bool {
+        let ipv4 = match indicator.get_ipv4() {
+            Some(ipv4) => ipv4,
+            None => return false,
+        };
+        let (src_port, dst_port) = match self.protocol {
+            TraceProtocol::Tcp => match indicator.get_tcp() {
+                Some(tcp) => (Some(tcp.get_src()), Some(tcp.get_dst())),
+                None => return false,
+            },
+            TraceProtocol::Udp => match indicator.get_udp() {
+                Some(udp) => (Some(udp.get_src()), Some(udp.get_dst())),
+                None => return false,
+            },
+            TraceProtocol::Icmp => match indicator.get_icmpv4() {
+                Some(_) => (None, None),
+                None => return false,
+            },
+        };
+        let is_src = |endpoint: &Endpoint| endpoint.is_match(ipv4.get_src(), src_port);
+        let is_dst = |endpoint: &Endpoint| endpoint.is_match(ipv4.get_dst(), dst_port);
+
+        match self.endpoints.as_slice() {
+            [] => true,
+            [a] => is_src(a) || is_dst(a),
+            [a, b] => (is_src(a) && is_dst(b)) || (is_src(b) && is_dst(a)),
+            _ => false,
+        }
+    }
+}
+
+impl Display for TraceRule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.protocol)?;
+        for endpoint in self.endpoints.iter() {
+            write!(f, ",{}", endpoint)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Represents a tracer logging packets of flows matching trace rules, with a summary and a hex
+/// dump of each frame.
+#[derive(Clone, Debug)]
+pub struct Tracer {
+    rules: Vec<TraceRule>,
+    max_size: usize,
+}
+
+impl Tracer {
+    /// Creates a new `Tracer` of the rules, which dumps frames up to the max size in bytes.
+    pub fn new(rules: Vec<TraceRule>, max_size: usize) -> Tracer {
+        Tracer { rules, max_size }
+    }
+
+    /// Returns if the packet of the indicator matches any rule.
+    pub fn is_match(&self, indicator: &Indicator) -> bool {
+        self.rules.iter().any(|rule| rule.is_match(indicator))
+    }
+
+    /// Logs the frame if the packet of its indicator matches any rule.
+    pub fn trace(&self, indicator: &Indicator, frame: &[u8], direction: Direction) {
+        if !self.is_match(indicator) {
+            return;
+        }
+
+        let direction = match direction {
+            Direction::Received => "receive",
+            Direction::Sent => "send",
+        };
+        info!(
+            "Trace {}: {} ({} Bytes)",
+            direction,
+            indicator.detailed(),
+            frame.len()
+        );
+        for line in hex_dump(frame, self.max_size) {
+            info!("    {}", line);
+        }
+    }
+}
+
+/// Dumps the bytes up to the max size in lines of 16 bytes in hex and ASCII, like
+/// `0010  45 00 00 3c 1c 46 40 00  40 06 b1 e6 0a 06 00 02  E..<.F@.@.......`.
+pub fn hex_dump(buffer: &[u8], max_size: usize) -> Vec<String> {
+    let size = buffer.len().min(max_size);
+    let mut lines: Vec<String> = buffer[..size]
+        .chunks(BYTES_PER_LINE)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut hex = String::with_capacity(3 * BYTES_PER_LINE + 1);
+            for j in 0..BYTES_PER_LINE {
+                if j == BYTES_PER_LINE / 2 {
+                    hex.push(' ');
+                }
+                match chunk.get(j) {
+                    Some(b) => hex.push_str(&format!("{:02x} ", b)),
+                    None => hex.push_str("   "),
+                }
+            }
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| match b {
+                    0x20..=0x7e => b as char,
+                    _ => '.',
+                })
+                .collect();
+
+            format!("{:04x}  {} {}", i * BYTES_PER_LINE, hex, ascii)
+        })
+        .collect();
+    if buffer.len() > size {
+        lines.push(format!("... {} Bytes more", buffer.len() - size));
+    }
+
+    lines
+}
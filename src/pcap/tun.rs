@@ -0,0 +1,79 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+
+use super::device::IfReq;
+use super::{Capture, Transmit};
+
+/// Represents the path of the clone device of TUN devices.
+const CLONE_DEVICE_PATH: &str = "/dev/net/tun";
+/// Represents the request of attaching to a TUN device.
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+/// Represents the max size of packets read from TUN devices.
+const MAX_PACKET_SIZE: usize = 65535;
+/// Represents the read timeout of TUN devices in milliseconds.
+const READ_TIMEOUT: libc::c_int = 100;
+
+/// Opens the TUN device, which is created if it does not exist. Packets in the device are IP
+/// packets without packet information.
+pub fn open(name: &str) -> io::Result<(TunSender, TunReceiver)> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(CLONE_DEVICE_PATH)?;
+
+    let mut req = IfReq::new(name)?;
+    let flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+    req.data[..2].copy_from_slice(&flags.to_ne_bytes());
+    if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF, &mut req) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let tx = TunSender {
+        file: file.try_clone()?,
+    };
+    let rx = TunReceiver {
+        file,
+        buffer: vec![0u8; MAX_PACKET_SIZE],
+    };
+
+    Ok((tx, rx))
+}
+
+/// Represents a receiver reading IP packets from a TUN device.
+pub struct TunReceiver {
+    file: File,
+    buffer: Vec<u8>,
+}
+
+impl Capture for TunReceiver {
+    fn next_frame(&mut self) -> io::Result<&[u8]> {
+        // Wait for packets in the read timeout, as pcap channels do
+        let mut fds = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fds, 1, READ_TIMEOUT) } {
+            n if n < 0 => return Err(io::Error::last_os_error()),
+            0 => return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+            _ => {}
+        }
+
+        let size = self.file.read(&mut self.buffer)?;
+
+        Ok(&self.buffer[..size])
+    }
+}
+
+/// Represents a sender writing IP packets to a TUN device.
+pub struct TunSender {
+    file: File,
+}
+
+impl Transmit for TunSender {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        // A packet is written in a single write
+        self.file.write(frame).map(|_| ())
+    }
+}
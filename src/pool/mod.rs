@@ -0,0 +1,46 @@
+/// Represents the max number of idle buffers kept in the pool.
+const MAX_IDLE_BUFFERS: usize = 64;
+
+/// Represents a pool of reusable fixed-size buffers. A buffer taken from the pool is owned by the
+/// taker until it is put back, so buffers are never shared between two packets.
+#[derive(Debug)]
+pub struct BufferPool {
+    size: usize,
+    buffers: Vec<Vec<u8>>,
+}
+
+impl BufferPool {
+    /// Creates a new `BufferPool` whose buffers are in the given size.
+    pub fn new(size: usize) -> BufferPool {
+        BufferPool {
+            size,
+            buffers: Vec::new(),
+        }
+    }
+
+    /// Takes a buffer which is at least in the given size from the pool. A buffer will be
+    /// allocated if the pool is empty or the given size is larger than the size of the pool.
+    pub fn take(&mut self, size: usize) -> Vec<u8> {
+        if size > self.size {
+            return vec![0u8; size];
+        }
+
+        match self.buffers.pop() {
+            Some(buffer) => buffer,
+            None => vec![0u8; self.size],
+        }
+    }
+
+    /// Puts a buffer back to the pool. The buffer will be dropped if it is not in the size of
+    /// the pool or the pool is full.
+    pub fn put(&mut self, buffer: Vec<u8>) {
+        if buffer.len() == self.size && self.buffers.len() < MAX_IDLE_BUFFERS {
+            self.buffers.push(buffer);
+        }
+    }
+
+    /// Get the size of buffers in the pool.
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+}
@@ -0,0 +1,499 @@
+//! Detection of the permission of capturing, and the drop of privileges once the device is
+//! opened, so the relays never run with the privileges of capturing.
+
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+#[cfg(unix)]
+use std::io;
+
+/// Represents the capability of opening raw sockets in Linux.
+const CAP_NET_RAW: u32 = 13;
+/// Represents the capability of configuring interfaces in Linux.
+const CAP_NET_ADMIN: u32 = 12;
+
+/// Represents the platforms, which differ in the permission of capturing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Platform {
+    /// Represents Linux, which captures with `CAP_NET_RAW`.
+    Linux,
+    /// Represents macOS and BSDs, which capture through `/dev/bpf*`.
+    Bpf,
+    /// Represents Windows, which captures through Npcap.
+    Windows,
+    /// Represents other platforms, whose permission is unknown.
+    Other,
+}
+
+impl Platform {
+    /// Get the platform of the build.
+    pub fn current() -> Platform {
+        if cfg!(target_os = "linux") {
+            Platform::Linux
+        } else if cfg!(any(
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd"
+        )) {
+            Platform::Bpf
+        } else if cfg!(windows) {
+            Platform::Windows
+        } else {
+            Platform::Other
+        }
+    }
+}
+
+/// Represents a probe of the permission of capturing, which is mocked in tests.
+pub trait Probe {
+    /// Returns if the process runs as root.
+    fn is_root(&self) -> bool;
+
+    /// Get the effective capabilities of the process in Linux, or `None` if they are unknown.
+    fn get_capabilities(&self) -> Option<u64>;
+
+    /// Returns if the process can open a BPF device, or `None` if it is unknown.
+    fn is_bpf_accessible(&self) -> Option<bool>;
+
+    /// Returns if Npcap or WinPcap is installed.
+    fn is_npcap_installed(&self) -> bool;
+}
+
+/// Represents the probe of the running system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemProbe;
+
+impl Probe for SystemProbe {
+    #[cfg(unix)]
+    fn is_root(&self) -> bool {
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    #[cfg(not(unix))]
+    fn is_root(&self) -> bool {
+        false
+    }
+
+    fn get_capabilities(&self) -> Option<u64> {
+        let status = fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|line| line.starts_with("CapEff:"))?;
+
+        u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok()
+    }
+
+    fn is_bpf_accessible(&self) -> Option<bool> {
+        let mut is_found = false;
+        for i in 0..256 {
+            let path = format!("/dev/bpf{}", i);
+            match fs::OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(_) => return Some(true),
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => break,
+                Err(ref e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    return Some(false)
+                }
+                // The device is opened by another process
+                Err(_) => is_found = true,
+            }
+        }
+        if is_found {
+            return Some(true);
+        }
+
+        // Some systems clone BPF devices from a single node
+        match fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/bpf")
+        {
+            Ok(_) => Some(true),
+            Err(ref e) if e.kind() == std::io::ErrorKind::PermissionDenied => Some(false),
+            Err(_) => None,
+        }
+    }
+
+    fn is_npcap_installed(&self) -> bool {
+        let root = std::env::var("SystemRoot").unwrap_or_else(|_| String::from("C:\\Windows"));
+        [
+            "System32\\Npcap\\wpcap.dll",
+            "System32\\Npcap\\Packet.dll",
+            "System32\\wpcap.dll",
+        ]
+        .iter()
+        .any(|dll| fs::metadata(format!("{}\\{}", root, dll)).is_ok())
+    }
+}
+
+/// Represents a lack of the permission of capturing, and what to do with it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnosis {
+    problem: String,
+    hint: String,
+}
+
+impl Diagnosis {
+    fn new(problem: &str, hint: String) -> Diagnosis {
+        Diagnosis {
+            problem: String::from(problem),
+            hint,
+        }
+    }
+
+    /// Get the problem.
+    pub fn get_problem(&self) -> &str {
+        &self.problem
+    }
+
+    /// Get what to do with the problem.
+    pub fn get_hint(&self) -> &str {
+        &self.hint
+    }
+}
+
+impl Display for Diagnosis {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}; {}", self.problem, self.hint)
+    }
+}
+
+/// Get the path of the running binary shown in hints.
+fn get_binary() -> String {
+    std::env::current_exe()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| String::from("pcap2socks"))
+}
+
+/// Checks the permission of capturing on the platform. Returns the diagnosis if the permission
+/// is lacking, or `None` if it is there or cannot be told.
+pub fn check(platform: Platform, probe: &dyn Probe) -> Option<Diagnosis> {
+    match platform {
+        Platform::Linux => {
+            if probe.is_root() {
+                return None;
+            }
+            let capabilities = probe.get_capabilities()?;
+            let is_raw = capabilities & (1 << CAP_NET_RAW) != 0;
+            let is_admin = capabilities & (1 << CAP_NET_ADMIN) != 0;
+            let problem = match (is_raw, is_admin) {
+                (true, _) => return None,
+                (false, true) => "CAP_NET_RAW missing",
+                (false, false) => "CAP_NET_RAW and CAP_NET_ADMIN missing",
+            };
+
+            Some(Diagnosis::new(
+                problem,
+                format!(
+                    "run as root, or run `sudo setcap cap_net_raw,cap_net_admin+ep {}` on the binary",
+                    get_binary()
+                ),
+            ))
+        }
+        Platform::Bpf => {
+            if probe.is_root() || probe.is_bpf_accessible()? {
+                return None;
+            }
+
+            Some(Diagnosis::new(
+                "no permission to open /dev/bpf*",
+                String::from(
+                    "run as root with sudo, or grant your user access to /dev/bpf* like the ChmodBPF of Wireshark does",
+                ),
+            ))
+        }
+        Platform::Windows => {
+            if probe.is_npcap_installed() {
+                return None;
+            }
+
+            Some(Diagnosis::new(
+                "Npcap missing",
+                String::from(
+                    "install Npcap from https://npcap.com in the WinPcap API-compatible mode, and allow the binary through the Windows Defender Firewall if the proxy is not reachable",
+                ),
+            ))
+        }
+        Platform::Other => None,
+    }
+}
+
+/// Checks the permission of capturing of the running system.
+pub fn check_system() -> Option<Diagnosis> {
+    check(Platform::current(), &SystemProbe)
+}
+
+/// Get the hint of a failure opening the device on the platform, which is given when the
+/// permission seems there but the device still cannot be opened.
+pub fn get_open_hint(platform: Platform) -> &'static str {
+    match platform {
+        Platform::Linux => "check the interface is up, and run as root or with CAP_NET_RAW",
+        Platform::Bpf => "check the interface is up, and run as root with sudo",
+        Platform::Windows => {
+            "run as Administrator if Npcap restricts its access to Administrators, and check the interface is up"
+        }
+        Platform::Other => "check the interface is up and the permission of capturing",
+    }
+}
+
+/// Represents the privileges dropped.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Dropped {
+    /// Represents nothing is dropped, as the process holds no privileges.
+    Nothing,
+    /// Represents the capabilities and the supplementary groups are dropped.
+    Capabilities,
+    /// Represents the process runs as the user.
+    User(String),
+}
+
+impl Display for Dropped {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            Dropped::Nothing => write!(f, "nothing"),
+            Dropped::Capabilities => write!(f, "capabilities and supplementary groups"),
+            Dropped::User(ref user) => write!(f, "privileges to run as {}", user),
+        }
+    }
+}
+
+/// Drops the privileges of the process, which runs as the user if there is one, or drops its
+/// capabilities and supplementary groups otherwise. The device and the sockets needing the
+/// privileges must be opened before, as they can never be opened again.
+#[cfg(unix)]
+pub fn drop_privileges(user: Option<&str>) -> io::Result<Dropped> {
+    let is_root = unsafe { libc::geteuid() } == 0;
+    if let Some(user) = user {
+        return set_user(user);
+    }
+
+    if is_root && unsafe { libc::setgroups(0, std::ptr::null()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[cfg(target_os = "linux")]
+    {
+        if SystemProbe.get_capabilities().unwrap_or(0) != 0 {
+            clear_capabilities()?;
+
+            return Ok(Dropped::Capabilities);
+        }
+    }
+    if is_root {
+        return Ok(Dropped::Capabilities);
+    }
+
+    Ok(Dropped::Nothing)
+}
+
+/// Drops the privileges of the process, which is not supported on the platform.
+#[cfg(not(unix))]
+pub fn drop_privileges(user: Option<&str>) -> std::io::Result<Dropped> {
+    match user {
+        Some(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "running as another user is only supported on Unix",
+        )),
+        None => Ok(Dropped::Nothing),
+    }
+}
+
+/// Sets the user and the group of the process to the user, and drops the supplementary groups.
+#[cfg(unix)]
+fn set_user(user: &str) -> io::Result<Dropped> {
+    let name =
+        std::ffi::CString::new(user).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("user {} not found", user),
+        ));
+    }
+    let (uid, gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+
+    // The groups are set before the user, as the user can no longer set them
+    if unsafe { libc::setgroups(0, std::ptr::null()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::setuid(uid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Root can never be regained
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "root is regained after dropping privileges",
+        ));
+    }
+
+    Ok(Dropped::User(String::from(user)))
+}
+
+/// Clears the effective, permitted and inheritable capabilities of the process in Linux.
+#[cfg(target_os = "linux")]
+fn clear_capabilities() -> io::Result<()> {
+    /// Represents the version 3 of capabilities, which has 64-bit sets.
+    const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+    #[repr(C)]
+    struct Header {
+        version: u32,
+        pid: libc::c_int,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Data {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    let header = Header {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [Data {
+        effective: 0,
+        permitted: 0,
+        inheritable: 0,
+    }; 2];
+    if unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Represents a probe of a mocked system.
+    #[derive(Default)]
+    struct MockProbe {
+        is_root: bool,
+        capabilities: Option<u64>,
+        is_bpf_accessible: Option<bool>,
+        is_npcap_installed: bool,
+    }
+
+    impl Probe for MockProbe {
+        fn is_root(&self) -> bool {
+            self.is_root
+        }
+
+        fn get_capabilities(&self) -> Option<u64> {
+            self.capabilities
+        }
+
+        fn is_bpf_accessible(&self) -> Option<bool> {
+            self.is_bpf_accessible
+        }
+
+        fn is_npcap_installed(&self) -> bool {
+            self.is_npcap_installed
+        }
+    }
+
+    fn get_problem(platform: Platform, probe: MockProbe) -> Option<String> {
+        check(platform, &probe).map(|diagnosis| String::from(diagnosis.get_problem()))
+    }
+
+    #[test]
+    fn check_linux() {
+        let (raw, admin) = (1 << CAP_NET_RAW, 1 << CAP_NET_ADMIN);
+
+        // Root or CAP_NET_RAW is sufficient
+        let probe = MockProbe {
+            is_root: true,
+            capabilities: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(get_problem(Platform::Linux, probe), None);
+        for &capabilities in &[raw, raw | admin] {
+            let probe = MockProbe {
+                capabilities: Some(capabilities),
+                ..Default::default()
+            };
+            assert_eq!(get_problem(Platform::Linux, probe), None);
+        }
+
+        // Capabilities missing
+        let probe = MockProbe {
+            capabilities: Some(admin),
+            ..Default::default()
+        };
+        let diagnosis = check(Platform::Linux, &probe).unwrap();
+        assert_eq!(diagnosis.get_problem(), "CAP_NET_RAW missing");
+        assert!(diagnosis
+            .get_hint()
+            .contains("setcap cap_net_raw,cap_net_admin+ep"));
+        assert!(diagnosis.to_string().starts_with("CAP_NET_RAW missing; "));
+        let probe = MockProbe {
+            capabilities: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            get_problem(Platform::Linux, probe).as_deref(),
+            Some("CAP_NET_RAW and CAP_NET_ADMIN missing")
+        );
+
+        // Capabilities unknown
+        assert_eq!(get_problem(Platform::Linux, MockProbe::default()), None);
+    }
+
+    #[test]
+    fn check_bpf() {
+        let probe = MockProbe {
+            is_root: true,
+            is_bpf_accessible: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(get_problem(Platform::Bpf, probe), None);
+        let probe = MockProbe {
+            is_bpf_accessible: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(get_problem(Platform::Bpf, probe), None);
+        let probe = MockProbe {
+            is_bpf_accessible: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            get_problem(Platform::Bpf, probe).as_deref(),
+            Some("no permission to open /dev/bpf*")
+        );
+        assert_eq!(get_problem(Platform::Bpf, MockProbe::default()), None);
+    }
+
+    #[test]
+    fn check_windows() {
+        let probe = MockProbe {
+            is_npcap_installed: true,
+            ..Default::default()
+        };
+        assert_eq!(get_problem(Platform::Windows, probe), None);
+        let diagnosis = check(Platform::Windows, &MockProbe::default()).unwrap();
+        assert_eq!(diagnosis.get_problem(), "Npcap missing");
+        assert!(diagnosis.get_hint().contains("https://npcap.com"));
+
+        // Privileges of Unix never matter
+        let probe = MockProbe {
+            is_root: true,
+            capabilities: Some(!0),
+            is_bpf_accessible: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            get_problem(Platform::Windows, probe).as_deref(),
+            Some("Npcap missing")
+        );
+    }
+
+    #[test]
+    fn check_other() {
+        assert_eq!(get_problem(Platform::Other, MockProbe::default()), None);
+    }
+}
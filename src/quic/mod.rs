@@ -0,0 +1,403 @@
+//! Detection of QUIC connections over UDP by the invariants of their headers (RFC 8999), which
+//! are never encrypted. Only the version and the connection IDs are parsed, so anything not
+//! looking like QUIC is left as plain UDP.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddrV4;
+
+/// Represents the version of QUIC v1 (RFC 9000).
+const VERSION_1: u32 = 0x0000_0001;
+/// Represents the version of QUIC v2 (RFC 9369).
+const VERSION_2: u32 = 0x6b33_43cf;
+/// Represents the prefix of the versions of QUIC drafts.
+const VERSION_DRAFT: u32 = 0xff00_0000;
+
+/// Represents the header form bit, which is set in long headers.
+const FORM_LONG: u8 = 0x80;
+/// Represents the fixed bit, which is set in all the packets of QUIC v1 and v2.
+const FIXED_BIT: u8 = 0x40;
+/// Represents the packet type bits of long headers.
+const LONG_PACKET_TYPE: u8 = 0x30;
+
+/// Represents the max length of a connection ID.
+const MAX_CID_LENGTH: usize = 20;
+/// Represents the min length of the destination connection ID of the first Initial packet of a
+/// client.
+const MIN_INITIAL_CID_LENGTH: usize = 8;
+/// Represents the min size of a datagram carrying an Initial packet of a client.
+const MIN_INITIAL_SIZE: usize = 1200;
+/// Represents the max number of connection IDs kept for each connection.
+const MAX_CIDS: usize = 4;
+
+/// Represents a connection ID of QUIC.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectionId {
+    len: usize,
+    bytes: [u8; MAX_CID_LENGTH],
+}
+
+impl ConnectionId {
+    /// Creates a `ConnectionId` from the bytes. Returns `None` if it is too long.
+    fn from_slice(bytes: &[u8]) -> Option<ConnectionId> {
+        if bytes.len() > MAX_CID_LENGTH {
+            return None;
+        }
+        let mut cid = ConnectionId {
+            len: bytes.len(),
+            bytes: [0u8; MAX_CID_LENGTH],
+        };
+        cid.bytes[..bytes.len()].copy_from_slice(bytes);
+
+        Some(cid)
+    }
+
+    /// Get the bytes of the connection ID.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl Display for ConnectionId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for b in self.as_slice() {
+            write!(f, "{:02x}", b)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Represents the long header of a QUIC packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LongHeader {
+    version: u32,
+    is_initial: bool,
+    dcid: ConnectionId,
+    scid: ConnectionId,
+}
+
+impl LongHeader {
+    /// Parses the long header of a QUIC packet in a known version. Returns `None` if the
+    /// datagram does not begin with one, including version negotiation packets.
+    pub fn parse(buffer: &[u8]) -> Option<LongHeader> {
+        let first = *buffer.first()?;
+        if first & FORM_LONG == 0 || first & FIXED_BIT == 0 {
+            return None;
+        }
+        let version = u32::from_be_bytes([
+            *buffer.get(1)?,
+            *buffer.get(2)?,
+            *buffer.get(3)?,
+            *buffer.get(4)?,
+        ]);
+        let initial_type = match version {
+            VERSION_1 => 0x00,
+            VERSION_2 => 0x10,
+            _ if version & 0xffff_ff00 == VERSION_DRAFT => 0x00,
+            _ => return None,
+        };
+
+        let dcid_length = *buffer.get(5)? as usize;
+        let dcid = ConnectionId::from_slice(buffer.get(6..6 + dcid_length)?)?;
+        let offset = 6 + dcid_length;
+        let scid_length = *buffer.get(offset)? as usize;
+        let scid = ConnectionId::from_slice(buffer.get(offset + 1..offset + 1 + scid_length)?)?;
+
+        Some(LongHeader {
+            version,
+            is_initial: first & LONG_PACKET_TYPE == initial_type,
+            dcid,
+            scid,
+        })
+    }
+
+    /// Get the version of QUIC.
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    /// Returns if the packet is an Initial packet.
+    pub fn is_initial(&self) -> bool {
+        self.is_initial
+    }
+
+    /// Get the destination connection ID.
+    pub fn get_dcid(&self) -> ConnectionId {
+        self.dcid
+    }
+
+    /// Get the source connection ID.
+    pub fn get_scid(&self) -> ConnectionId {
+        self.scid
+    }
+}
+
+/// Detects the first datagram of a QUIC connection from a client, which is an Initial packet
+/// padded to at least 1200 bytes with a destination connection ID of at least 8 bytes. Returns
+/// `None` if the datagram is not confidently one, and the flow is left as plain UDP.
+pub fn detect(buffer: &[u8]) -> Option<LongHeader> {
+    if buffer.len() < MIN_INITIAL_SIZE {
+        return None;
+    }
+    let header = LongHeader::parse(buffer)?;
+    if !header.is_initial() || header.get_dcid().as_slice().len() < MIN_INITIAL_CID_LENGTH {
+        return None;
+    }
+
+    Some(header)
+}
+
+/// Returns if the datagram begins with a short header, which carries the destination
+/// connection ID without its length.
+pub fn is_short_header(buffer: &[u8]) -> bool {
+    match buffer.first() {
+        Some(first) => first & FORM_LONG == 0 && first & FIXED_BIT != 0,
+        None => false,
+    }
+}
+
+/// Represents a QUIC connection from a client, which is tracked by the connection IDs the client
+/// addresses the destination with, so the connection is found again after the client migrates
+/// to a new source port.
+#[derive(Clone, Debug)]
+pub struct Flow {
+    dst: SocketAddrV4,
+    version: u32,
+    /// Represents the connection IDs known, the latest last
+    cids: VecDeque<ConnectionId>,
+}
+
+impl Flow {
+    /// Creates a new `Flow` to the destination from its first Initial packet.
+    pub fn new(dst: SocketAddrV4, header: &LongHeader) -> Flow {
+        let mut flow = Flow {
+            dst,
+            version: header.get_version(),
+            cids: VecDeque::with_capacity(MAX_CIDS),
+        };
+        flow.add(header.get_dcid());
+
+        flow
+    }
+
+    /// Get the destination of the connection.
+    pub fn get_dst(&self) -> SocketAddrV4 {
+        self.dst
+    }
+
+    /// Get the version of QUIC of the connection.
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    fn add(&mut self, cid: ConnectionId) {
+        if self.cids.back() == Some(&cid) {
+            return;
+        }
+        self.cids.retain(|known| known != &cid);
+        if self.cids.len() >= MAX_CIDS {
+            self.cids.pop_front();
+        }
+        self.cids.push_back(cid);
+    }
+
+    /// Learns the destination connection ID of a datagram of the connection. The length of the
+    /// connection ID in a short header is taken from the latest one known, the destination keeps
+    /// its length in practice.
+    pub fn update(&mut self, buffer: &[u8]) {
+        if is_short_header(buffer) {
+            let len = match self.cids.back() {
+                Some(cid) => cid.as_slice().len(),
+                None => return,
+            };
+            if let Some(cid) = buffer.get(1..1 + len).and_then(ConnectionId::from_slice) {
+                self.add(cid);
+            }
+        } else if let Some(header) = LongHeader::parse(buffer) {
+            self.add(header.get_dcid());
+        }
+    }
+
+    /// Returns if a datagram with a short header is addressed with a connection ID of the
+    /// connection.
+    pub fn is_match(&self, buffer: &[u8]) -> bool {
+        if !is_short_header(buffer) {
+            return false;
+        }
+
+        self.cids.iter().any(|cid| {
+            let len = cid.as_slice().len();
+            len > 0 && buffer.get(1..1 + len) == Some(cid.as_slice())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const DCID: [u8; 8] = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+    const SCID: [u8; 4] = [0xf0, 0x67, 0xa5, 0x50];
+
+    fn dst() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 443)
+    }
+
+    /// Creates a datagram of a long header of the type bits, padded to the size.
+    fn new_long(version: u32, t: u8, dcid: &[u8], scid: &[u8], size: usize) -> Vec<u8> {
+        let mut buffer = vec![FORM_LONG | FIXED_BIT | t];
+        buffer.extend_from_slice(&version.to_be_bytes());
+        buffer.push(dcid.len() as u8);
+        buffer.extend_from_slice(dcid);
+        buffer.push(scid.len() as u8);
+        buffer.extend_from_slice(scid);
+        if buffer.len() < size {
+            buffer.resize(size, 0);
+        }
+
+        buffer
+    }
+
+    /// Creates a datagram of a short header addressed with the connection ID.
+    fn new_short(dcid: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![FIXED_BIT | 0x01];
+        buffer.extend_from_slice(dcid);
+        buffer.extend_from_slice(&[0u8; 32]);
+
+        buffer
+    }
+
+    #[test]
+    fn parse_long_header() {
+        // Initial packets of v1, v2 and drafts
+        for &(version, t) in &[(VERSION_1, 0x00), (VERSION_2, 0x10), (0xff00_001d, 0x00)] {
+            let buffer = new_long(version, t, &DCID, &SCID, 0);
+            let header = LongHeader::parse(&buffer).unwrap();
+            assert_eq!(header.get_version(), version);
+            assert!(header.is_initial());
+            assert_eq!(header.get_dcid().as_slice(), &DCID);
+            assert_eq!(header.get_scid().as_slice(), &SCID);
+        }
+
+        // Other types, and empty connection IDs
+        let header = LongHeader::parse(&new_long(VERSION_1, 0x20, &DCID, &[], 0)).unwrap();
+        assert!(!header.is_initial());
+        assert!(header.get_scid().as_slice().is_empty());
+        let header = LongHeader::parse(&new_long(VERSION_2, 0x00, &[], &SCID, 0)).unwrap();
+        assert!(!header.is_initial());
+        assert_eq!(header.get_dcid().to_string(), "");
+        assert_eq!(header.get_scid().to_string(), "f067a550");
+    }
+
+    #[test]
+    fn parse_long_header_negative() {
+        let buffer = new_long(VERSION_1, 0x00, &DCID, &SCID, 0);
+
+        // Truncated anywhere
+        for size in 0..buffer.len() {
+            assert_eq!(LongHeader::parse(&buffer[..size]), None, "size {}", size);
+        }
+
+        // Short headers, without the fixed bit, version negotiation and unknown versions
+        assert_eq!(LongHeader::parse(&new_short(&DCID)), None);
+        let mut fixed = buffer.clone();
+        fixed[0] &= !FIXED_BIT;
+        assert_eq!(LongHeader::parse(&fixed), None);
+        assert_eq!(LongHeader::parse(&new_long(0, 0x00, &DCID, &SCID, 0)), None);
+        assert_eq!(
+            LongHeader::parse(&new_long(0x1a2a_3a4a, 0x00, &DCID, &SCID, 0)),
+            None
+        );
+
+        // Connection IDs longer than the max length
+        assert_eq!(
+            LongHeader::parse(&new_long(VERSION_1, 0x00, &[0u8; 21], &SCID, 0)),
+            None
+        );
+        assert_eq!(
+            LongHeader::parse(&new_long(VERSION_1, 0x00, &DCID, &[0u8; 21], 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn detect_fail_open() {
+        let header = detect(&new_long(VERSION_1, 0x00, &DCID, &SCID, MIN_INITIAL_SIZE)).unwrap();
+        assert_eq!(header.get_dcid().as_slice(), &DCID);
+
+        // Datagrams under the min size, short destination connection IDs, and packets other
+        // than Initial packets
+        assert_eq!(
+            detect(&new_long(
+                VERSION_1,
+                0x00,
+                &DCID,
+                &SCID,
+                MIN_INITIAL_SIZE - 1
+            )),
+            None
+        );
+        assert_eq!(
+            detect(&new_long(
+                VERSION_1,
+                0x00,
+                &DCID[..7],
+                &SCID,
+                MIN_INITIAL_SIZE
+            )),
+            None
+        );
+        assert_eq!(
+            detect(&new_long(VERSION_1, 0x20, &DCID, &SCID, MIN_INITIAL_SIZE)),
+            None
+        );
+        let mut short = new_short(&DCID);
+        short.resize(MIN_INITIAL_SIZE, 0);
+        assert_eq!(detect(&short), None);
+        assert_eq!(detect(&[0u8; MIN_INITIAL_SIZE]), None);
+    }
+
+    #[test]
+    fn flow_migration() {
+        let header = detect(&new_long(VERSION_1, 0x00, &DCID, &SCID, MIN_INITIAL_SIZE)).unwrap();
+        let mut flow = Flow::new(dst(), &header);
+        assert_eq!(flow.get_dst(), dst());
+        assert_eq!(flow.get_version(), VERSION_1);
+
+        // The server chooses its connection ID in the handshake, and the client migrates to a
+        // new source port keeping it
+        let cid_a = [0xa0u8; 8];
+        let cid_b = [0xb0u8; 8];
+        flow.update(&new_long(VERSION_1, 0x20, &cid_a, &SCID, 0));
+        flow.update(&new_short(&cid_a));
+        assert!(flow.is_match(&new_short(&cid_a)));
+        assert!(!flow.is_match(&new_short(&cid_b)));
+
+        // The client switches to another connection ID issued by the server, and migrates with
+        // it, connection IDs never seen fail open to a new association
+        flow.update(&new_short(&cid_b));
+        assert!(flow.is_match(&new_short(&cid_b)));
+        assert!(flow.is_match(&new_short(&cid_a)));
+        assert!(!flow.is_match(&new_long(VERSION_1, 0x20, &cid_b, &SCID, 0)));
+        assert!(!flow.is_match(&new_short(&[0xc0u8; 8])));
+
+        // Only the latest connection IDs are kept
+        for i in 0..MAX_CIDS {
+            flow.update(&new_short(&[i as u8; 8]));
+        }
+        assert!(!flow.is_match(&new_short(&cid_b)));
+        assert!(flow.is_match(&new_short(&[0u8; 8])));
+    }
+
+    #[test]
+    fn flow_empty_cid() {
+        // A connection of an empty connection ID is never matched
+        let header = LongHeader::parse(&new_long(VERSION_1, 0x00, &[], &SCID, 0)).unwrap();
+        let mut flow = Flow::new(dst(), &header);
+        flow.update(&new_short(&DCID));
+        assert!(!flow.is_match(&new_short(&DCID)));
+        assert!(!flow.is_match(&new_short(&[])));
+    }
+}
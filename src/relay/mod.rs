@@ -0,0 +1,1234 @@
+//! Relays of SOCKS streams and datagrams in tasks on a shared tokio runtime, replacing the
+//! threads per connection of the synchronous relays.
+
+use log::{debug, trace, warn};
+use std::io;
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use super::{
+    receive_datagram, DnsMap, Downstreamer, UdpNat, UdpPeers, BACKPRESSURE_WAIT,
+    CONTROL_CLOSED_GRACE, DIRECT_CONNECT_TIMEOUT, DNS_PORT, MAX_DNS_QUERIES, PROBE_PAYLOAD,
+    PROBE_TIMEOUT,
+};
+use crate::clock::Clock;
+use crate::dns::{self, Query};
+use crate::event::{self, CloseReason, Connection, Tag};
+use crate::memory::{self, Allocation};
+use crate::metrics::{self, DropReason};
+use crate::packet::layer::LayerTypes;
+use crate::shaper::Limits;
+use crate::socks::{self, OversizedError, ProxyConnector, SocksDatagram};
+use crate::state::{self, UdpState};
+
+/// Represents the runtime of the relays.
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// Get the runtime of the relays, which is created on first use.
+pub fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .thread_name("pcap2socks-relay")
+            .enable_all()
+            .build()
+            .expect("create runtime")
+    })
+}
+
+/// Waits for an aborted task to exit. Tasks are only waited for outside of the runtime, where
+/// the workers are dropped by the upstreamers.
+fn join(task: JoinHandle<()>) {
+    task.abort();
+    if tokio::runtime::Handle::try_current().is_err() {
+        let _ = runtime().block_on(task);
+    }
+}
+
+/// Represents a message to the writer of a stream.
+#[derive(Debug)]
+enum StreamMessage {
+    Data(Vec<u8>),
+    Shutdown(Shutdown),
+}
+
+/// Represents a worker of a SOCKS TCP stream.
+pub struct StreamWorker {
+    dst: SocketAddrV4,
+    stream: TcpStream,
+    connection: Arc<Mutex<Connection>>,
+    tx: mpsc::UnboundedSender<StreamMessage>,
+    reader: Option<JoinHandle<()>>,
+    writer: Option<JoinHandle<()>>,
+    is_closed: Arc<AtomicBool>,
+    /// Represents the instant of the last data read or written on the stream
+    activity: Arc<Mutex<Instant>>,
+    /// Represents the size of data sent to the writer but not written yet
+    backlog: Arc<AtomicUsize>,
+    is_shutdown: bool,
+    /// Represents the sizes of data pending in the backlog and in the send buffer of the stream
+    /// when they were last sampled, and the instant they were last drained
+    drain: (usize, usize, Instant),
+    tag: Tag,
+}
+
+impl StreamWorker {
+    /// Opens a new `StreamWorker` of the destination, which connects to the target.
+    pub fn connect(
+        tx: Arc<Mutex<Downstreamer>>,
+        mut connection: Connection,
+        target: SocketAddrV4,
+        proxy: Option<&dyn ProxyConnector>,
+        timeout: Duration,
+        keepalive: Option<Duration>,
+        limits: Limits,
+    ) -> io::Result<StreamWorker> {
+        let selected = proxy.and_then(|proxy| proxy.select(false));
+        let proxy = selected.as_deref().or(proxy);
+        if let Some(proxy) = proxy {
+            connection.set_proxy(proxy.to_string());
+        }
+        // Connect directly without a proxy
+        let stream = runtime().block_on(async {
+            match proxy {
+                Some(proxy) => proxy.connect_async(target, timeout).await,
+                None => tokio::time::timeout(
+                    Duration::from_millis(DIRECT_CONNECT_TIMEOUT),
+                    socks::connect_tcp_async(target),
+                )
+                .await
+                .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut))),
+            }
+        });
+        let stream = match stream.and_then(|stream| stream.into_std()) {
+            Ok(stream) => stream,
+            Err(e) => {
+                if proxy.is_some() {
+                    metrics::SOCKS_HANDSHAKE_FAILURES.increase();
+                }
+                connection.fail(&e);
+                metrics::count_close(CloseReason::ConnectFailed);
+                return Err(e);
+            }
+        };
+
+        StreamWorker::new(tx, connection, stream, keepalive, limits)
+    }
+
+    /// Creates a new `StreamWorker` of the connection on a connected stream.
+    pub fn new(
+        tx: Arc<Mutex<Downstreamer>>,
+        mut connection: Connection,
+        stream: TcpStream,
+        keepalive: Option<Duration>,
+        limits: Limits,
+    ) -> io::Result<StreamWorker> {
+        let src_port = connection.get_src_port();
+        let dst = connection.get_dst();
+        let tag = connection.get_tag();
+        if let Some(keepalive) = keepalive {
+            if let Err(ref e) = socks::set_keepalive(&stream, keepalive) {
+                warn!("{}SOCKS: {}: {} -> {}: {}", tag, "TCP", 0, dst, e);
+            }
+        }
+        stream.set_nonblocking(true)?;
+        let stream_cloned = stream.try_clone()?;
+        // The halves are streams of their own rather than split, as a split write half shuts
+        // the stream down once it is dropped, which sends a FIN before a reset of a zero linger
+        let (mut read_half, mut write_half) = {
+            let _guard = runtime().enter();
+            (
+                tokio::net::TcpStream::from_std(stream.try_clone()?)?,
+                tokio::net::TcpStream::from_std(stream.try_clone()?)?,
+            )
+        };
+
+        connection.open();
+        let connection = Arc::new(Mutex::new(connection));
+        let is_closed = Arc::new(AtomicBool::new(false));
+        let activity = Arc::new(Mutex::new(Instant::now()));
+        let backlog = Arc::new(AtomicUsize::new(0));
+
+        // Reader
+        let connection_cloned = Arc::clone(&connection);
+        let is_closed_cloned = Arc::clone(&is_closed);
+        let activity_cloned = Arc::clone(&activity);
+        let limit_down = limits.down;
+        let congestion = tx.lock().unwrap().get_congestion();
+        // The relay buffer shrinks under the memory pressure
+        let relay_buffer = memory::scale(tx.lock().unwrap().get_relay_buffer());
+        let reader = runtime().spawn(async move {
+            let mut buffer = vec![0u8; relay_buffer];
+            let _allocation = Allocation::new(relay_buffer);
+            loop {
+                // Stop reading while the device is congested, so the destination is pushed back
+                while congestion.is_congested() {
+                    tokio::time::sleep(Duration::from_millis(BACKPRESSURE_WAIT)).await;
+                }
+                match read_half.read(&mut buffer).await {
+                    Ok(0) => {
+                        // The destination closes the stream, send a FIN after all the data is
+                        // sent
+                        if let Err(ref e) = tx.lock().unwrap().append_fin_to_cache(dst, src_port) {
+                            warn!("{}handle {}: {}", tag, "TCP", e);
+                        }
+                        break;
+                    }
+                    Ok(size) => {
+                        *activity_cloned.lock().unwrap() = Instant::now();
+                        connection_cloned.lock().unwrap().add_bytes_down(size);
+                        debug!(
+                            "{}receive from SOCKS: {}: {} -> {} ({} Bytes)",
+                            tag, "TCP", dst, 0, size
+                        );
+
+                        // Send
+                        if let Err(ref e) =
+                            tx.lock()
+                                .unwrap()
+                                .append_to_cache(dst, src_port, &buffer[..size])
+                        {
+                            warn!("{}handle {}: {}", tag, "TCP", e);
+                        }
+
+                        // Pause reading if the rate limit is exceeded
+                        let delay = limit_down.consume(size);
+                        if !delay.is_zero() {
+                            tokio::time::sleep(delay).await;
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(ref e) => {
+                        warn!("{}SOCKS: {}: {} -> {}: {}", tag, "TCP", 0, dst, e);
+                        connection_cloned.lock().unwrap().fail(e);
+                        is_closed_cloned.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                }
+            }
+        });
+
+        // Writer
+        let (writer_tx, mut writer_rx) = mpsc::unbounded_channel();
+        let connection_cloned = Arc::clone(&connection);
+        let is_closed_cloned = Arc::clone(&is_closed);
+        let activity_cloned = Arc::clone(&activity);
+        let backlog_cloned = Arc::clone(&backlog);
+        let limit_up = limits.up;
+        let writer = runtime().spawn(async move {
+            while let Some(message) = writer_rx.recv().await {
+                match message {
+                    StreamMessage::Data(data) => {
+                        // Data exceeding the rate limit is kept in the backlog, which shrinks the
+                        // window advertised to the source
+                        let delay = limit_up.consume(data.len());
+                        if !delay.is_zero() {
+                            tokio::time::sleep(delay).await;
+                        }
+                        if let Err(ref e) = write_half.write_all(&data).await {
+                            warn!("{}SOCKS: {}: {} -> {}: {}", tag, "TCP", 0, dst, e);
+                            connection_cloned.lock().unwrap().fail(e);
+                            is_closed_cloned.store(true, Ordering::Relaxed);
+                            break;
+                        }
+                        backlog_cloned.fetch_sub(data.len(), Ordering::Relaxed);
+                        *activity_cloned.lock().unwrap() = Instant::now();
+                    }
+                    StreamMessage::Shutdown(how) => {
+                        if let Err(ref e) = stream_cloned.shutdown(how) {
+                            warn!("{}handle {}: {}", tag, "TCP", e);
+                        }
+                        trace!("{}shutdown stream {} -> {}", tag, 0, dst);
+                    }
+                }
+            }
+        });
+
+        trace!("{}open stream {} -> {}", tag, 0, dst);
+
+        Ok(StreamWorker {
+            dst,
+            stream,
+            connection,
+            tx: writer_tx,
+            reader: Some(reader),
+            writer: Some(writer),
+            is_closed,
+            activity,
+            backlog,
+            is_shutdown: false,
+            drain: (0, 0, Instant::now()),
+            tag,
+        })
+    }
+
+    /// Sends data on the SOCKS in TCP to the destination. Data is kept in the backlog until the
+    /// writer writes it.
+    pub fn send(&mut self, buffer: &[u8]) -> io::Result<()> {
+        debug!(
+            "{}send to SOCKS {}: {} -> {} ({} Bytes)",
+            self.tag,
+            "TCP",
+            "0",
+            self.dst,
+            buffer.len()
+        );
+        self.connection.lock().unwrap().add_payload_up(buffer);
+
+        // Send
+        self.backlog.fetch_add(buffer.len(), Ordering::Relaxed);
+        self.tx
+            .send(StreamMessage::Data(buffer.to_vec()))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    /// Writes the backlog on the SOCKS in TCP to the destination. The backlog is written by the
+    /// writer continuously, so this function does nothing.
+    pub fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Sets the DSCP of IPv4 packets sent on the SOCKS stream.
+    pub fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        socks::set_tos(&self.stream, dscp << 2)
+    }
+
+    /// Sets TCP_NODELAY on the SOCKS stream.
+    pub fn set_nodelay(&self, is_nodelay: bool) -> io::Result<()> {
+        self.stream.set_nodelay(is_nodelay)
+    }
+
+    /// Enables the detection of the dead peer of the stream in about the timeout.
+    pub fn set_stale_timeout(&self, timeout: Duration) -> io::Result<()> {
+        socks::set_stale_timeout(&self.stream, timeout)
+    }
+
+    /// Get the time data pending on the stream is not drained. Data is drained if it is less in
+    /// either the backlog or the send buffer of the stream than the last time, so an idle stream
+    /// with nothing pending is never stalled, while writes to a blackholed peer pile up.
+    pub fn get_stall(&mut self) -> Duration {
+        let backlog = self.get_backlog_size();
+        // The send buffer is only known in Linux
+        let unsent = socks::get_unsent_size(&self.stream).unwrap_or(0);
+        let (last_backlog, last_unsent, instant) = self.drain;
+        self.drain = (backlog, unsent, instant);
+        if (backlog == 0 && unsent == 0) || backlog < last_backlog || unsent < last_unsent {
+            self.drain.2 = Instant::now();
+
+            return Duration::from_secs(0);
+        }
+
+        instant.elapsed()
+    }
+
+    /// Get the size of the backlog.
+    pub fn get_backlog_size(&self) -> usize {
+        self.backlog.load(Ordering::Relaxed)
+    }
+
+    /// Shuts down the write half of the SOCKS in TCP after the backlog is written, the
+    /// destination can still send data.
+    pub fn shutdown_write(&mut self) -> io::Result<()> {
+        self.tx
+            .send(StreamMessage::Shutdown(Shutdown::Write))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    /// Shuts down both halves of the SOCKS stream after the backlog is written. The worker reads
+    /// the end of the stream, so a FIN is sent to the source after all the data in the cache is
+    /// sent.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.tx
+            .send(StreamMessage::Shutdown(Shutdown::Both))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.is_shutdown = true;
+        trace!(
+            "{}shutdown stream {} -> {} in both halves",
+            self.tag,
+            0,
+            self.dst
+        );
+
+        Ok(())
+    }
+
+    /// Tears down the SOCKS stream closed for the reason. Streams closed gracefully are shut down
+    /// before. Streams reset by the source are shut down in both halves. Streams closed for
+    /// errors are shut down in both halves without a linger, reset with a zero linger, or shut
+    /// down in the write half and read until their ends in the linger, so the FIN is not turned
+    /// into a RST by data left unread when the stream is closed.
+    pub fn teardown(&mut self, reason: CloseReason, linger: Option<Duration>) -> io::Result<()> {
+        if self.is_shutdown || reason.is_graceful() || reason == CloseReason::Shutdown {
+            return Ok(());
+        }
+        // The reader stops relaying before the stream is shut down
+        self.close();
+
+        match linger {
+            Some(linger) if reason != CloseReason::Reset => {
+                if linger.as_secs() == 0 {
+                    // The stream is reset once it is dropped
+                    socks::set_linger(&self.stream, linger)?;
+                    self.is_shutdown = true;
+                    trace!("{}reset stream {} -> {}", self.tag, 0, self.dst);
+                } else {
+                    let stream = self.stream.try_clone()?;
+                    self.stream.shutdown(Shutdown::Write)?;
+                    self.is_shutdown = true;
+                    runtime().spawn(async move {
+                        let mut stream = match tokio::net::TcpStream::from_std(stream) {
+                            Ok(stream) => stream,
+                            Err(_) => return,
+                        };
+                        let _ = tokio::time::timeout(linger, drain_stream(&mut stream)).await;
+                    });
+                    trace!(
+                        "{}linger stream {} -> {} for {} s",
+                        self.tag,
+                        0,
+                        self.dst,
+                        linger.as_secs()
+                    );
+                }
+            }
+            _ => {
+                self.stream.shutdown(Shutdown::Both)?;
+                self.is_shutdown = true;
+                trace!(
+                    "{}shutdown stream {} -> {} in both halves",
+                    self.tag,
+                    0,
+                    self.dst
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Closes the worker.
+    pub fn close(&mut self) {
+        self.is_closed.store(true, Ordering::Relaxed);
+        if let Some(ref reader) = self.reader {
+            reader.abort();
+        }
+        trace!("{}close stream {} -> {}", self.tag, 0, self.dst);
+    }
+
+    /// Closes the connection of the worker for the reason.
+    pub fn close_connection(&mut self, reason: CloseReason) {
+        self.connection.lock().unwrap().close(reason);
+    }
+
+    /// Returns if the worker is closed.
+    pub fn is_closed(&self) -> bool {
+        self.is_closed.load(Ordering::Relaxed)
+    }
+
+    /// Get the time since data was last read or written on the stream.
+    pub fn get_idle(&self) -> Duration {
+        self.activity.lock().unwrap().elapsed()
+    }
+
+    /// Get the tag of the connection of the stream prefixed to log lines.
+    pub fn get_tag(&self) -> Tag {
+        self.tag
+    }
+
+    /// Get the translated port of the connection of the stream in the NAT.
+    pub fn get_nat_port(&self) -> Option<u16> {
+        self.connection.lock().unwrap().get_nat_port()
+    }
+
+    /// Get the destination the stream is rewritten to.
+    pub fn get_rewritten(&self) -> Option<SocketAddrV4> {
+        self.connection.lock().unwrap().get_rewritten()
+    }
+
+    /// Get the bytes sent from and to the source on the stream.
+    pub fn get_bytes(&self) -> (u64, u64) {
+        let connection = self.connection.lock().unwrap();
+
+        (connection.get_bytes_up(), connection.get_bytes_down())
+    }
+
+    /// Get the bytes of payload retransmitted from and to the source on the stream.
+    pub fn get_retransmitted_bytes(&self) -> (u64, u64) {
+        self.connection.lock().unwrap().get_retransmitted_bytes()
+    }
+
+    /// Get the bytes of frames received from and sent to the source on the stream, including
+    /// headers.
+    pub fn get_wire_bytes(&self) -> (u64, u64) {
+        self.connection.lock().unwrap().get_wire_bytes()
+    }
+
+    /// Counts bytes of payload retransmitted by the source on the stream.
+    pub fn add_retransmitted_up(&self, n: usize) {
+        self.connection.lock().unwrap().add_retransmitted_up(n);
+    }
+
+    /// Counts bytes of a frame received from the source on the stream, including headers.
+    pub fn add_wire_up(&self, n: usize) {
+        self.connection.lock().unwrap().add_wire_up(n);
+    }
+
+    /// Get the connection of the stream, which counts the bytes sent to the source in the
+    /// downstreamer.
+    pub fn get_connection(&self) -> Arc<Mutex<Connection>> {
+        Arc::clone(&self.connection)
+    }
+}
+
+impl Drop for StreamWorker {
+    fn drop(&mut self) {
+        // Connections closed for other reasons are closed before
+        self.close_connection(CloseReason::Shutdown);
+        self.close();
+        if !self.is_shutdown {
+            if let Err(ref e) = self.stream.shutdown(Shutdown::Both) {
+                warn!("{}handle {}: {}", self.tag, "TCP", e);
+            }
+        }
+        if let Some(reader) = self.reader.take() {
+            join(reader);
+        }
+        if let Some(writer) = self.writer.take() {
+            join(writer);
+        }
+        trace!("{}drop stream {} -> {}", self.tag, 0, self.dst);
+    }
+}
+
+/// Reads a stream shut down in the write half until its end, and discards the data read.
+async fn drain_stream(stream: &mut tokio::net::TcpStream) {
+    let mut buffer = [0u8; 1024];
+    while let Ok(size) = stream.read(&mut buffer).await {
+        if size == 0 {
+            break;
+        }
+    }
+}
+
+/// Represents a worker of a SOCKS5 UDP client.
+pub struct DatagramWorker {
+    src_port: Arc<AtomicU16>,
+    connection: Arc<Mutex<Connection>>,
+    pub(crate) local_port: u16,
+    datagram: Arc<SocksDatagram>,
+    task: Option<JoinHandle<()>>,
+    is_closed: Arc<AtomicBool>,
+    /// Represents the instant of the last datagram in either direction
+    activity: Arc<Mutex<Instant>>,
+    /// Represents if all the datagrams sent are DNS queries
+    is_dns: bool,
+    dns_map: Arc<Mutex<DnsMap>>,
+    peers: Arc<Mutex<UdpPeers>>,
+    limits: Limits,
+    dscp: u8,
+    /// Represents the instant of the last keepalive datagram
+    keepalive_instant: Instant,
+    /// Represents the max size of data of a datagram not exceeding the path MTU to the relay
+    max_size: Option<usize>,
+    is_send_fragmentation: bool,
+    /// Represents if the source sets the DF bit in the datagram being sent, which is never sent
+    /// in SOCKS5 fragments
+    is_dont_fragment: bool,
+    /// Represents the instant of the last datagram from the relay
+    relay_activity: Arc<Mutex<Instant>>,
+    /// Represents if the control connection is closed while the relay still relays
+    is_control_closed: bool,
+    /// Represents the instant of the liveness probe not replied yet
+    probe_instant: Option<Instant>,
+    /// Represents the clock of the idle time, keepalives and probes
+    clock: Arc<dyn Clock>,
+}
+
+impl DatagramWorker {
+    /// Creates a new `DatagramWorker`.
+    pub fn bind(
+        tx: Arc<Mutex<Downstreamer>>,
+        mut connection: Connection,
+        local_port: u16,
+        proxy: &dyn ProxyConnector,
+        is_fragmentation: bool,
+        limits: Limits,
+        nat: UdpNat,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<DatagramWorker> {
+        let selected = proxy.select(true);
+        let proxy = selected.as_deref().unwrap_or(proxy);
+        connection.set_proxy(proxy.to_string());
+        let mut datagram = match proxy.bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, local_port)) {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                metrics::SOCKS_HANDSHAKE_FAILURES.increase();
+                connection.fail(&e);
+                metrics::count_close(CloseReason::ConnectFailed);
+                return Err(e);
+            }
+        };
+        datagram.set_fragmentation(is_fragmentation);
+
+        DatagramWorker::new(tx, connection, local_port, datagram, limits, nat, clock)
+    }
+
+    /// Creates a new `DatagramWorker` which sends datagrams directly from an ephemeral port.
+    pub fn bind_direct(
+        tx: Arc<Mutex<Downstreamer>>,
+        connection: Connection,
+        limits: Limits,
+        nat: UdpNat,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<DatagramWorker> {
+        let datagram = SocksDatagram::bind_direct(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+        let local_port = datagram.get_local_port()?;
+
+        DatagramWorker::new(tx, connection, local_port, datagram, limits, nat, clock)
+    }
+
+    fn new(
+        tx: Arc<Mutex<Downstreamer>>,
+        mut connection: Connection,
+        local_port: u16,
+        datagram: SocksDatagram,
+        limits: Limits,
+        nat: UdpNat,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<DatagramWorker> {
+        // Datagrams of a shared socket are received by the reader of the socket
+        let socket = match datagram.is_shared() {
+            true => None,
+            false => {
+                let _guard = runtime().enter();
+                Some(datagram.try_clone_async()?)
+            }
+        };
+
+        let src_port = connection.get_src_port();
+        let tag = connection.get_tag();
+        connection.open();
+        let a_connection = Arc::new(Mutex::new(connection));
+        let a_connection_cloned = Arc::clone(&a_connection);
+
+        let a_src_port = Arc::new(AtomicU16::from(src_port));
+        let a_src_port_cloned = Arc::clone(&a_src_port);
+        let header_size = datagram.get_header_size();
+        let a_datagram = Arc::new(datagram);
+        let a_datagram_cloned = Arc::clone(&a_datagram);
+        let a_is_closed = Arc::new(AtomicBool::new(false));
+        let a_is_closed_cloned = Arc::clone(&a_is_closed);
+        let a_activity = Arc::new(Mutex::new(clock.now()));
+        let a_activity_cloned = Arc::clone(&a_activity);
+        let a_relay_activity = Arc::new(Mutex::new(clock.now()));
+        let a_relay_activity_cloned = Arc::clone(&a_relay_activity);
+        let a_dns_map = Arc::new(Mutex::new(DnsMap::new()));
+        let a_dns_map_cloned = Arc::clone(&a_dns_map);
+        let a_peers = Arc::new(Mutex::new(UdpPeers::new(nat)));
+        let a_peers_cloned = Arc::clone(&a_peers);
+        let limit_down = limits.down.clone();
+        let clock_cloned = Arc::clone(&clock);
+        let receive = move |data: &[u8], addr: SocketAddrV4| {
+            // Any datagram tells the relay is alive
+            *a_relay_activity_cloned.lock().unwrap() = clock_cloned.now();
+            // Drop-tail if the rate limit is exceeded
+            if !limit_down.try_consume(data.len()) {
+                event::drop_packet(DropReason::RateLimited, LayerTypes::Udp);
+                return;
+            }
+            *a_activity_cloned.lock().unwrap() = clock_cloned.now();
+            {
+                let mut connection = a_connection_cloned.lock().unwrap();
+                connection.add_datagram_down(data.len());
+                connection.add_overhead_down(header_size);
+            }
+            debug!(
+                "{}receive from SOCKS: {}: {} -> {} ({} Bytes)",
+                tag,
+                "UDP",
+                addr,
+                local_port,
+                data.len()
+            );
+
+            // Send
+            match receive_datagram(
+                &tx,
+                &a_dns_map_cloned,
+                &a_peers_cloned,
+                addr,
+                a_src_port_cloned.load(Ordering::Relaxed),
+                data,
+            ) {
+                Ok(size) => a_connection_cloned.lock().unwrap().add_wire_down(size),
+                Err(ref e) => warn!("{}handle {}: {}", tag, "UDP", e),
+            }
+        };
+
+        let task = match socket {
+            None => {
+                a_datagram.set_handler(Box::new(move |data: &[u8], addr: SocketAddrV4| {
+                    if !a_is_closed_cloned.load(Ordering::Relaxed) {
+                        receive(data, addr);
+                    }
+                }))?;
+
+                None
+            }
+            Some(socket) => {
+                let a_src_port_closed = Arc::clone(&a_src_port);
+                let a_connection_closed = Arc::clone(&a_connection);
+                Some(runtime().spawn(async move {
+                    let mut buffer = vec![0u8; u16::MAX as usize];
+                    loop {
+                        match a_datagram_cloned
+                            .recv_from_async(&socket, &mut buffer)
+                            .await
+                        {
+                            Ok((size, addr)) => receive(&buffer[..size], addr),
+                            Err(ref e) => {
+                                if e.kind() == io::ErrorKind::Interrupted {
+                                    continue;
+                                }
+                                warn!(
+                                    "{}SOCKS: {}: {} = {}: {}",
+                                    tag,
+                                    "UDP",
+                                    local_port,
+                                    a_src_port_closed.load(Ordering::Relaxed),
+                                    e
+                                );
+                                a_connection_closed.lock().unwrap().fail(e);
+                                a_is_closed_cloned.store(true, Ordering::Relaxed);
+
+                                break;
+                            }
+                        }
+                    }
+                }))
+            }
+        };
+
+        trace!("{}create datagram {} = {}", tag, src_port, local_port);
+
+        Ok(DatagramWorker {
+            src_port: a_src_port,
+            connection: a_connection,
+            local_port,
+            datagram: a_datagram,
+            task,
+            is_closed: a_is_closed,
+            activity: a_activity,
+            is_dns: true,
+            dns_map: a_dns_map,
+            peers: a_peers,
+            limits,
+            dscp: 0,
+            keepalive_instant: clock.now(),
+            max_size: None,
+            is_send_fragmentation: false,
+            is_dont_fragment: false,
+            relay_activity: a_relay_activity,
+            is_control_closed: false,
+            probe_instant: None,
+            clock,
+        })
+    }
+
+    /// Sends data on the SOCKS5 in UDP to the destination.
+    pub fn send_to(&mut self, buffer: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
+        // Drop-tail if the rate limit is exceeded
+        if !self.limits.up.try_consume(buffer.len()) {
+            event::drop_packet(DropReason::RateLimited, LayerTypes::Udp);
+            return Ok(0);
+        }
+        debug!(
+            "{}send to SOCKS {}: {} -> {} ({} Bytes)",
+            self.get_tag(),
+            "UDP",
+            self.local_port,
+            dst,
+            buffer.len()
+        );
+        *self.activity.lock().unwrap() = self.clock.now();
+        self.connection
+            .lock()
+            .unwrap()
+            .add_datagram_up(buffer.len());
+        self.peers.lock().unwrap().add(dst);
+        if dst.port() != DNS_PORT {
+            self.is_dns = false;
+        }
+
+        // Send, the socket is non-blocking so a datagram which cannot be sent immediately is
+        // dropped
+        if let Some(max_size) = self.max_size {
+            if buffer.len() > max_size {
+                return self.send_oversized(buffer, dst, max_size);
+            }
+        }
+        let result = self.datagram.send_to(buffer, dst);
+        match result {
+            Ok(_) => self
+                .connection
+                .lock()
+                .unwrap()
+                .add_overhead_up(self.datagram.get_header_size()),
+            Err(ref e) => {
+                // The OS learns the path MTU from the failure
+                if socks::is_message_too_long(e) && self.probe_mtu().is_ok() {
+                    if let Some(max_size) = self.max_size {
+                        if buffer.len() > max_size {
+                            return self.send_oversized(buffer, dst, max_size);
+                        }
+                    }
+                }
+                self.connection.lock().unwrap().add_send_failure();
+            }
+        }
+
+        result
+    }
+
+    /// Sends a datagram larger than the path MTU to the relay in SOCKS5 fragments if the
+    /// fragmentation is set and the source does not set the DF bit, otherwise the datagram is
+    /// dropped with an `OversizedError`.
+    fn send_oversized(
+        &mut self,
+        buffer: &[u8],
+        dst: SocketAddrV4,
+        max_size: usize,
+    ) -> io::Result<usize> {
+        self.connection.lock().unwrap().add_oversized();
+        if self.is_send_fragmentation && !self.is_dont_fragment {
+            trace!(
+                "{}fragment datagram to {} ({} Bytes) in {} Bytes",
+                self.get_tag(),
+                dst,
+                buffer.len(),
+                max_size
+            );
+            let result = self.datagram.send_fragments_to(buffer, dst, max_size);
+            let mut connection = self.connection.lock().unwrap();
+            match result {
+                // Each fragment carries its own header
+                Ok(_) => connection.add_overhead_up(
+                    self.datagram.get_header_size() * ((buffer.len() + max_size - 1) / max_size),
+                ),
+                Err(_) => connection.add_send_failure(),
+            }
+
+            return result;
+        }
+        event::drop_packet(DropReason::MtuExceeded, LayerTypes::Udp);
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            OversizedError::new(buffer.len(), max_size),
+        ))
+    }
+
+    /// Probes the path MTU to the relay of the association, datagrams larger than it are sent
+    /// in SOCKS5 fragments or dropped afterwards.
+    pub fn probe_mtu(&mut self) -> io::Result<Option<u16>> {
+        let mtu = self.datagram.probe_mtu()?;
+        self.max_size = match mtu {
+            Some(mtu) => Some(self.datagram.get_max_size(mtu)?),
+            None => None,
+        };
+        self.connection.lock().unwrap().set_relay_mtu(mtu);
+
+        Ok(mtu)
+    }
+
+    /// Sets if datagrams larger than the path MTU to the relay are sent in SOCKS5 fragments, or
+    /// they will be dropped.
+    pub fn set_send_fragmentation(&mut self, is_send_fragmentation: bool) {
+        self.is_send_fragmentation = is_send_fragmentation;
+    }
+
+    /// Sets if the source sets the DF bit in the datagrams sent next, which are dropped instead
+    /// of sent in SOCKS5 fragments if they are larger than the path MTU to the relay.
+    pub fn set_dont_fragment(&mut self, is_dont_fragment: bool) {
+        self.is_dont_fragment = is_dont_fragment;
+    }
+
+    /// Sends a DNS query on the SOCKS5 in UDP to the resolver instead of the destination. The
+    /// response is sent to the source as from the destination.
+    pub fn send_dns_to(
+        &mut self,
+        buffer: &[u8],
+        resolver: SocketAddrV4,
+        dst: SocketAddrV4,
+        query: Query,
+    ) -> io::Result<usize> {
+        {
+            let mut dns_map = self.dns_map.lock().unwrap();
+            // Queries never answered are abandoned
+            if dns_map.len() >= MAX_DNS_QUERIES {
+                dns_map.clear();
+            }
+            dns_map.insert(query.get_id(), (resolver, dst, query.get_udp_size()));
+        }
+        trace!(
+            "{}redirect DNS query {} from {} to {}",
+            self.get_tag(),
+            query.get_id(),
+            dst,
+            resolver
+        );
+
+        self.send_to(buffer, resolver)
+    }
+
+    /// Sends data on the SOCKS5 in UDP to the destination rewritten to instead of the original
+    /// destination. Datagrams from the destination rewritten to are sent to the source as from
+    /// the original destination.
+    pub fn send_rewritten_to(
+        &mut self,
+        buffer: &[u8],
+        target: SocketAddrV4,
+        dst: SocketAddrV4,
+    ) -> io::Result<usize> {
+        {
+            let mut connection = self.connection.lock().unwrap();
+            if connection.get_dst() == dst && connection.get_rewritten().is_none() {
+                connection.set_rewritten(target);
+                debug!("{}rewrite {} -> {}", connection.get_tag(), dst, target);
+            }
+        }
+        self.peers.lock().unwrap().add_rewrite(target, dst);
+
+        self.send_to(buffer, target)
+    }
+
+    /// Sets the source port of the `DatagramWorker`, the connection of the previous source port
+    /// is replaced with the one to the destination.
+    pub fn set_src_port(&mut self, src_port: u16, dst: SocketAddrV4) {
+        {
+            let mut connection = self.connection.lock().unwrap();
+            let relay_mtu = connection.get_relay_mtu();
+            connection.close(CloseReason::Replaced);
+            *connection = Connection::new(
+                connection.get_client(),
+                src_port,
+                dst,
+                connection.get_protocol(),
+                connection.is_proxied(),
+            );
+            connection.set_relay_mtu(relay_mtu);
+            connection.open();
+        }
+        self.src_port.store(src_port, Ordering::Relaxed);
+        self.peers.lock().unwrap().clear();
+        trace!(
+            "{}set datagram {} = {}",
+            self.get_tag(),
+            src_port,
+            self.local_port
+        );
+    }
+
+    /// Migrates the `DatagramWorker` to a new source port of the same connection, like a QUIC
+    /// connection whose source moves to a new port. Unlike setting the source port, the
+    /// connection and the peers are kept.
+    pub fn migrate_src_port(&mut self, src_port: u16) {
+        let prev_src_port = self.get_src_port();
+        {
+            let mut connection = self.connection.lock().unwrap();
+            if let Err(ref e) = connection.migrate(src_port) {
+                debug!(
+                    "{}map datagram {} in NAT: {}",
+                    connection.get_tag(),
+                    src_port,
+                    e
+                );
+            }
+        }
+        self.src_port.store(src_port, Ordering::Relaxed);
+        debug!(
+            "{}migrate datagram {} = {} to {} = {}",
+            self.get_tag(),
+            prev_src_port,
+            self.local_port,
+            src_port,
+            self.local_port
+        );
+    }
+
+    /// Sets if the source port is mapped by the source, which makes the association accept
+    /// datagrams from any peer even if the NAT is restricted.
+    pub fn set_mapped(&mut self, is_mapped: bool) {
+        self.peers.lock().unwrap().set_mapped(is_mapped);
+    }
+
+    /// Sets the DSCP of IPv4 packets sent on the SOCKS5 in UDP.
+    pub fn set_dscp(&mut self, dscp: u8) -> io::Result<()> {
+        if self.dscp != dscp {
+            self.datagram.set_tos(dscp << 2)?;
+            self.dscp = dscp;
+        }
+
+        Ok(())
+    }
+
+    /// Get the source port of the `DatagramWorker`.
+    pub fn get_src_port(&self) -> u16 {
+        self.src_port.load(Ordering::Relaxed)
+    }
+
+    /// Get the time since the last datagram in either direction.
+    pub fn get_idle(&self) -> Duration {
+        self.clock.elapsed(*self.activity.lock().unwrap())
+    }
+
+    /// Get the tag of the connection of the association prefixed to log lines.
+    pub fn get_tag(&self) -> Tag {
+        self.connection.lock().unwrap().get_tag()
+    }
+
+    /// Get the translated port of the connection of the association in the NAT.
+    pub fn get_nat_port(&self) -> Option<u16> {
+        self.connection.lock().unwrap().get_nat_port()
+    }
+
+    /// Get the destination of the first datagram of the association.
+    pub fn get_dst(&self) -> SocketAddrV4 {
+        self.connection.lock().unwrap().get_dst()
+    }
+
+    /// Get the destination the first datagram of the association is rewritten to.
+    pub fn get_rewritten(&self) -> Option<SocketAddrV4> {
+        self.connection.lock().unwrap().get_rewritten()
+    }
+
+    /// Returns if the association is detected as QUIC.
+    pub fn is_quic(&self) -> bool {
+        self.connection.lock().unwrap().is_quic()
+    }
+
+    /// Sets the association is detected as QUIC.
+    pub fn set_quic(&mut self) {
+        self.connection.lock().unwrap().set_quic();
+    }
+
+    /// Get the count of peers the association sent to, and the count of datagrams from other
+    /// peers.
+    pub fn get_peers(&self) -> (usize, u64) {
+        let peers = self.peers.lock().unwrap();
+
+        (peers.len(), peers.get_unsolicited_count())
+    }
+
+    /// Get the state of the association.
+    pub fn get_state(&self, src_ip_addr: Ipv4Addr) -> UdpState {
+        UdpState {
+            src: SocketAddrV4::new(src_ip_addr, self.get_src_port()),
+            local_port: self.local_port,
+            dst: self.get_dst(),
+            peers: self.peers.lock().unwrap().get_peers(),
+            active: state::unix_time_before(self.get_idle()),
+        }
+    }
+
+    /// Restores the peers the association sent to and the time of the last datagram from the
+    /// state of the association in the last run.
+    pub fn restore(&mut self, state: &UdpState) {
+        {
+            let mut peers = self.peers.lock().unwrap();
+            for &peer in state.peers.iter() {
+                peers.add(peer);
+            }
+        }
+        if let Some(instant) = self.clock.now().checked_sub(state.get_idle()) {
+            *self.activity.lock().unwrap() = instant;
+        }
+    }
+
+    /// Get the bytes sent from and to the source in the association.
+    pub fn get_bytes(&self) -> (u64, u64) {
+        let connection = self.connection.lock().unwrap();
+
+        (connection.get_bytes_up(), connection.get_bytes_down())
+    }
+
+    /// Get the bytes of frames received from and sent to the source in the association,
+    /// including headers.
+    pub fn get_wire_bytes(&self) -> (u64, u64) {
+        self.connection.lock().unwrap().get_wire_bytes()
+    }
+
+    /// Get the bytes of SOCKS5 UDP headers of datagrams sent to and received from the relay in
+    /// the association.
+    pub fn get_overhead_bytes(&self) -> (u64, u64) {
+        self.connection.lock().unwrap().get_overhead_bytes()
+    }
+
+    /// Counts bytes of a frame received from the source in the association, including headers.
+    pub fn add_wire_up(&self, n: usize) {
+        self.connection.lock().unwrap().add_wire_up(n);
+    }
+
+    /// Get the datagrams sent from and to the source in the association, and the count of
+    /// datagrams which cannot be sent to the relay.
+    pub fn get_packets(&self) -> (u64, u64, u64) {
+        let connection = self.connection.lock().unwrap();
+        let (packets_up, packets_down) = connection.get_packets();
+
+        (packets_up, packets_down, connection.get_send_failures())
+    }
+
+    /// Get the count of datagrams larger than the path MTU to the relay in the association, and
+    /// the path MTU.
+    pub fn get_oversized(&self) -> (u64, Option<u16>) {
+        let connection = self.connection.lock().unwrap();
+
+        (connection.get_oversized(), connection.get_relay_mtu())
+    }
+
+    /// Get the jitter of datagrams sent to the source in the association.
+    pub fn get_jitter(&self) -> Duration {
+        self.connection.lock().unwrap().get_jitter()
+    }
+
+    /// Sets the idle time before TCP keepalive probes of the control connection of the SOCKS
+    /// association.
+    pub fn set_keepalive(&self, idle: Duration) -> io::Result<()> {
+        self.datagram.set_keepalive(idle)
+    }
+
+    /// Get the time since the last datagram from the relay.
+    pub fn get_relay_idle(&self) -> Duration {
+        self.clock.elapsed(*self.relay_activity.lock().unwrap())
+    }
+
+    /// Checks the control connection of the SOCKS association, and sends a keepalive datagram
+    /// through the relay if the association is idle for the keepalive. Returns an error if the
+    /// control connection is closed, unless the association is lenient and its relay still
+    /// relays datagrams. Keepalive datagrams never reset the idle time.
+    pub fn update_control(
+        &mut self,
+        keepalive: Option<Duration>,
+        is_strict: bool,
+    ) -> io::Result<()> {
+        if let Err(e) = self.datagram.check_control() {
+            if is_strict || self.get_relay_idle() >= CONTROL_CLOSED_GRACE {
+                return Err(e);
+            }
+            if !self.is_control_closed {
+                self.is_control_closed = true;
+                debug!(
+                    "{}keep datagram {} = {} whose relay still relays: {}",
+                    self.get_tag(),
+                    self.get_src_port(),
+                    self.local_port,
+                    e
+                );
+            }
+        }
+
+        if let Some(keepalive) = keepalive {
+            if self.get_idle() >= keepalive
+                && self.clock.elapsed(self.keepalive_instant) >= keepalive
+            {
+                self.keepalive_instant = self.clock.now();
+                trace!(
+                    "{}send UDP keepalive of {} = {}",
+                    self.get_tag(),
+                    self.get_src_port(),
+                    self.local_port
+                );
+                self.datagram.send_keepalive()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends a liveness probe through the relay to the endpoint once no datagram comes from the
+    /// relay for the interval. Returns if the relay is dead, which sends nothing back in the
+    /// timeout of the probe. A DNS query is sent to port 53, and a payload to be echoed is sent
+    /// to other ports.
+    pub fn update_probe(&mut self, probe: SocketAddrV4, interval: Duration) -> io::Result<bool> {
+        let relay_idle = self.get_relay_idle();
+        if let Some(instant) = self.probe_instant {
+            let elapsed = self.clock.elapsed(instant);
+            if relay_idle >= elapsed {
+                return Ok(elapsed >= PROBE_TIMEOUT);
+            }
+            self.probe_instant = None;
+        }
+        if relay_idle < interval {
+            return Ok(false);
+        }
+
+        let payload = match probe.port() {
+            DNS_PORT => dns::new_root_query(self.local_port),
+            _ => PROBE_PAYLOAD.to_vec(),
+        };
+        self.peers.lock().unwrap().set_probe(probe);
+        self.probe_instant = Some(self.clock.now());
+        trace!(
+            "{}send UDP probe of {} = {} to {}",
+            self.get_tag(),
+            self.get_src_port(),
+            self.local_port,
+            probe
+        );
+        self.datagram.send_to(&payload, probe)?;
+
+        Ok(false)
+    }
+
+    /// Returns if all the datagrams sent by the worker are DNS queries.
+    pub fn is_dns(&self) -> bool {
+        self.is_dns
+    }
+
+    /// Closes the worker.
+    pub fn close(&mut self) {
+        self.is_closed.store(true, Ordering::Relaxed);
+        if let Some(ref task) = self.task {
+            task.abort();
+        }
+        trace!(
+            "{}close datagram {} = {}",
+            self.get_tag(),
+            self.get_src_port(),
+            self.local_port
+        );
+    }
+
+    /// Returns if the worker is closed.
+    pub fn is_closed(&self) -> bool {
+        self.is_closed.load(Ordering::Relaxed)
+    }
+
+    /// Closes the connection of the worker for the reason.
+    pub fn close_connection(&mut self, reason: CloseReason) {
+        self.connection.lock().unwrap().close(reason);
+    }
+}
+
+impl Drop for DatagramWorker {
+    fn drop(&mut self) {
+        // Connections closed for other reasons are closed before
+        self.close_connection(CloseReason::Shutdown);
+        self.close();
+        // The SOCKS association and the local port are released after the task exits
+        if let Some(task) = self.task.take() {
+            join(task);
+        }
+        trace!(
+            "{}drop datagram {} = {}",
+            self.get_tag(),
+            self.get_src_port(),
+            self.local_port
+        );
+    }
+}
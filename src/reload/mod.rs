@@ -0,0 +1,209 @@
+//! Reloads of the configuration at runtime, which are requested by SIGHUP on Unix. The
+//! configuration file is read again, and the settings safe to change at runtime are published
+//! as a whole snapshot: rules, access lists, quotas of clients, log levels and timeouts. Other
+//! settings changed are rejected and logged, they take effect after a restart. A configuration
+//! failing to read or validate leaves the one in effect untouched.
+//!
+//! Upstreamers compare the generation of settings before each frame, which is a load of an
+//! atomic, and take the snapshot only once it changes. A frame is always handled under one
+//! snapshot, and flows open before keep the limits they are opened with.
+
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use toml::value::Table;
+
+use crate::args::{self, Flags, Opts, ParseError};
+use crate::config;
+use crate::metrics;
+use crate::rule::{AccessList, Rewrites, Rules};
+use crate::shaper::Shaper;
+
+/// Represents the interval of checking requests of reloads.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Represents the keys of the settings safe to change at runtime.
+const RELOADABLE_KEYS: [&str; 17] = [
+    "logging.verbose",
+    "logging.vverbose",
+    "rules.list",
+    "rules.file",
+    "rules.default-action",
+    "rules.direct-private",
+    "rules.rewrites",
+    "access.allow",
+    "access.deny",
+    "access.deny-silent",
+    "limit.client-quotas",
+    "limit.client-quota-default",
+    "proxy.timeout",
+    "proxy.relay-timeout",
+    "proxy.relay-stale",
+    "proxy.connect-retries",
+    "udp.timeout",
+];
+
+/// Represents if a reload is requested.
+static IS_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Represents the generation of settings, which increases on each reload.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+/// Represents the settings of the last reload.
+static SETTINGS: Mutex<Option<Arc<Settings>>> = Mutex::new(None);
+
+/// Represents a snapshot of the settings safe to change at runtime, which is never changed once
+/// it is published.
+pub(crate) struct Settings {
+    pub(crate) rules: Rules,
+    pub(crate) access_list: AccessList,
+    pub(crate) rewrites: Rewrites,
+    /// Represents the shaper with the quotas of clients
+    pub(crate) shaper: Arc<Shaper>,
+    pub(crate) proxy_timeout: Duration,
+    pub(crate) relay_timeout: Option<Duration>,
+    pub(crate) relay_stale: Option<Duration>,
+    pub(crate) connect_retries: usize,
+    pub(crate) udp_timeout: Duration,
+}
+
+impl Settings {
+    fn from_opts(opts: &Opts, shaper: Arc<Shaper>) -> Settings {
+        Settings {
+            rules: opts.rules.clone(),
+            access_list: opts.access_list.clone(),
+            rewrites: opts.rewrites.clone(),
+            shaper,
+            proxy_timeout: Duration::from_secs(opts.proxy_timeout),
+            relay_timeout: opts.relay_timeout.map(Duration::from_secs),
+            relay_stale: opts.relay_stale.map(Duration::from_secs),
+            connect_retries: opts.connect_retries,
+            udp_timeout: Duration::from_secs(opts.udp_timeout),
+        }
+    }
+}
+
+/// Requests a reload of the configuration, which is safe in a signal handler.
+pub fn request() {
+    IS_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Get the generation of settings, which is 0 if the configuration is never reloaded.
+pub(crate) fn get_generation() -> usize {
+    GENERATION.load(Ordering::Relaxed)
+}
+
+/// Get the settings of the last reload.
+pub(crate) fn get_settings() -> Option<Arc<Settings>> {
+    SETTINGS.lock().unwrap().as_ref().map(Arc::clone)
+}
+
+/// Publishes the settings, which are taken by upstreamers before their next frames.
+fn publish(settings: Settings) {
+    *SETTINGS.lock().unwrap() = Some(Arc::new(settings));
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Represents the reloader of the configuration, which reloads the configuration on its own
+/// thread once a reload is requested until it is closed.
+pub(crate) struct Reloader {
+    is_stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reloader {
+    /// Opens a reloader of the configuration of the flags in effect, whose quotas of clients are
+    /// in the shaper.
+    pub(crate) fn open(flags: Flags, shaper: Arc<Shaper>) -> Reloader {
+        // Requests before are dropped, the configuration is just read
+        IS_REQUESTED.store(false, Ordering::Relaxed);
+
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let is_stopped_cloned = Arc::clone(&is_stopped);
+        let thread = thread::spawn(move || {
+            let mut table = config::table(&flags);
+            let mut shaper = shaper;
+            while !is_stopped_cloned.load(Ordering::Relaxed) {
+                thread::sleep(POLL_INTERVAL);
+                if !IS_REQUESTED.swap(false, Ordering::Relaxed) {
+                    continue;
+                }
+
+                match reload(&mut table, &mut shaper) {
+                    Ok(keys) if keys.is_empty() => info!("Reload configuration, nothing changed"),
+                    Ok(keys) => info!("Reload configuration, {} changed", keys.join(", ")),
+                    Err(ref e) => {
+                        metrics::RELOAD_FAILURES.increase();
+                        warn!("reload configuration: {}, the configuration is kept", e);
+                    }
+                }
+            }
+        });
+
+        Reloader {
+            is_stopped,
+            thread: Some(thread),
+        }
+    }
+
+    /// Closes the reloader.
+    pub(crate) fn close(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.is_stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Reloader {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Reloads the configuration in effect in the table, whose quotas of clients are in the shaper.
+/// Returns the keys of the settings changed, settings which require a restart are rejected and
+/// stay in effect.
+fn reload(table: &mut Table, shaper: &mut Arc<Shaper>) -> Result<Vec<String>, ParseError> {
+    // Nothing applies until the whole configuration is validated
+    let flags = args::parse()?;
+    let opts = Opts::validate(&flags)?;
+
+    let mut reloaded = table.clone();
+    let mut keys = Vec::new();
+    let mut rejected = Vec::new();
+    let new_table = config::table(&flags);
+    for key in config::diff(table, &new_table) {
+        if RELOADABLE_KEYS.contains(&key.as_str()) {
+            config::copy(&new_table, &mut reloaded, &key);
+            keys.push(key);
+        } else {
+            rejected.push(key);
+        }
+    }
+    if !rejected.is_empty() {
+        warn!(
+            "reload configuration: {} cannot change without a restart, which are kept",
+            rejected.join(", ")
+        );
+    }
+
+    // Rules, access lists and timeouts are always published, since files they are read from
+    // may change
+    crate::set_log_level(crate::get_log_level(&flags));
+    let reloaded_shaper =
+        Arc::new(shaper.reload_quotas(opts.client_quotas.clone(), opts.client_quota_default));
+    if !opts.access_list.is_empty() {
+        metrics::set_access_list(opts.access_list.clone());
+    }
+    publish(Settings::from_opts(&opts, Arc::clone(&reloaded_shaper)));
+    *shaper = reloaded_shaper;
+    *table = reloaded;
+    metrics::count_reload(SystemTime::now());
+
+    Ok(keys)
+}
@@ -0,0 +1,262 @@
+//! Resolution of host names of proxies and rules. Names are resolved at startup, their answers
+//! are cached for the TTL but at least the floor, and they are resolved again in the background
+//! before the answers expire, so a proxy behind a dynamic DNS is followed without a restart. The
+//! system resolver gives no TTL, so its answers are cached for the floor.
+
+use log::{debug, info, warn};
+use std::cmp::max;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Represents the default min time in seconds answers are cached.
+pub const DEFAULT_RESOLVE_TTL: u64 = 60;
+/// Represents the interval of checking names whose answers expire.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// Represents the interval of resolving a name again after it fails.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Represents the resolver of names, the system resolver is used if it is not set.
+static RESOLVER: Mutex<Option<Arc<dyn Resolve>>> = Mutex::new(None);
+/// Represents the names resolved, which are resolved again before their answers expire.
+static NAMES: Mutex<Vec<Weak<Name>>> = Mutex::new(Vec::new());
+
+/// Represents an answer of a resolution.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Answer {
+    /// Represents the IPv4 addresses in order of preference
+    pub addrs: Vec<Ipv4Addr>,
+    /// Represents the TTL of the answer, or `None` if it is unknown
+    pub ttl: Option<Duration>,
+}
+
+/// Represents a resolver of host names.
+pub trait Resolve: Send + Sync {
+    /// Resolves the IPv4 addresses of a host name.
+    fn resolve(&self, host: &str) -> io::Result<Answer>;
+}
+
+/// Represents the resolver of the system, which gives no TTL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve(&self, host: &str) -> io::Result<Answer> {
+        let mut addrs = Vec::new();
+        for addr in (host, 0).to_socket_addrs()? {
+            if let SocketAddr::V4(addr) = addr {
+                if !addrs.contains(addr.ip()) {
+                    addrs.push(*addr.ip());
+                }
+            }
+        }
+
+        Ok(Answer { addrs, ttl: None })
+    }
+}
+
+/// Sets the resolver of names, which replaces the system resolver.
+pub fn set_resolver(resolver: Arc<dyn Resolve>) {
+    *RESOLVER.lock().unwrap() = Some(resolver);
+}
+
+/// Resolves a host name by the resolver. Returns the addresses and the time they are cached for.
+fn lookup(host: &str, floor: Duration) -> io::Result<(Vec<Ipv4Addr>, Duration)> {
+    let resolver = RESOLVER.lock().unwrap().clone();
+    let answer = match resolver {
+        Some(resolver) => resolver.resolve(host)?,
+        None => SystemResolver.resolve(host)?,
+    };
+    if answer.addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no IPv4 address", host),
+        ));
+    }
+    let ttl = max(answer.ttl.unwrap_or(floor), floor);
+
+    Ok((answer.addrs, ttl))
+}
+
+/// Represents the addresses of a name.
+#[derive(Debug)]
+struct State {
+    addrs: Vec<Ipv4Addr>,
+    /// Represents the instant the name is resolved again, which is before the answer expires
+    refresh: Instant,
+}
+
+/// Represents a host name and its addresses, which are updated when the name is resolved again.
+#[derive(Debug)]
+pub struct Name {
+    host: String,
+    /// Represents the min time answers are cached
+    floor: Duration,
+    state: Mutex<State>,
+}
+
+impl Name {
+    /// Resolves a host name, whose answers are cached for at least the floor. Fails if the name
+    /// has no IPv4 address.
+    pub fn resolve(host: &str, floor: Duration) -> io::Result<Arc<Name>> {
+        let (addrs, ttl) = lookup(host, floor)?;
+        debug!(
+            "resolve {} to {} for {} s",
+            host,
+            join(&addrs),
+            ttl.as_secs()
+        );
+        let name = Arc::new(Name {
+            host: host.to_string(),
+            floor,
+            state: Mutex::new(State {
+                addrs,
+                refresh: Instant::now() + refresh_of(ttl),
+            }),
+        });
+        NAMES.lock().unwrap().push(Arc::downgrade(&name));
+
+        Ok(name)
+    }
+
+    /// Get the host name.
+    pub fn get_host(&self) -> &str {
+        &self.host
+    }
+
+    /// Get the addresses of the name in order of preference.
+    pub fn get_addrs(&self) -> Vec<Ipv4Addr> {
+        self.state.lock().unwrap().addrs.clone()
+    }
+
+    /// Returns if the address is an address of the name.
+    pub fn contains(&self, ip_addr: Ipv4Addr) -> bool {
+        self.state.lock().unwrap().addrs.contains(&ip_addr)
+    }
+
+    /// Resolves the name again immediately. Returns if the addresses change, the addresses are
+    /// kept if the resolution fails.
+    pub fn refresh(&self) -> io::Result<bool> {
+        let (addrs, ttl) = match lookup(&self.host, self.floor) {
+            Ok(answer) => answer,
+            Err(e) => {
+                self.state.lock().unwrap().refresh = Instant::now() + RETRY_INTERVAL;
+
+                return Err(e);
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.refresh = Instant::now() + refresh_of(ttl);
+        if state.addrs == addrs {
+            return Ok(false);
+        }
+        info!(
+            "Resolve {}: {} -> {}",
+            self.host,
+            join(&state.addrs),
+            join(&addrs)
+        );
+        state.addrs = addrs;
+
+        Ok(true)
+    }
+
+    /// Returns if the name is due to be resolved again.
+    fn is_due(&self, instant: Instant) -> bool {
+        self.state.lock().unwrap().refresh <= instant
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Name) -> bool {
+        self.host == other.host
+    }
+}
+
+impl Eq for Name {}
+
+impl Display for Name {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.host)
+    }
+}
+
+/// Get the time after which a name is resolved again, which leaves a fifth of the TTL to resolve
+/// before the answer expires.
+fn refresh_of(ttl: Duration) -> Duration {
+    ttl - ttl / 5
+}
+
+/// Joins addresses with commas.
+fn join(addrs: &[Ipv4Addr]) -> String {
+    addrs
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Represents the refresher of names, which resolves names again before their answers expire on
+/// its own thread until it is closed.
+pub(crate) struct Refresher {
+    is_stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Refresher {
+    /// Opens a refresher of names.
+    pub(crate) fn open() -> Refresher {
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let is_stopped_cloned = Arc::clone(&is_stopped);
+        let thread = thread::spawn(move || {
+            while !is_stopped_cloned.load(Ordering::Relaxed) {
+                thread::sleep(REFRESH_INTERVAL);
+
+                // Names dropped, like the ones of rules replaced in reloads, are forgotten
+                let now = Instant::now();
+                let names: Vec<Arc<Name>> = {
+                    let mut names = NAMES.lock().unwrap();
+                    names.retain(|name| name.strong_count() > 0);
+                    names
+                        .iter()
+                        .filter_map(|name| name.upgrade())
+                        .filter(|name| name.is_due(now))
+                        .collect()
+                };
+                for name in names {
+                    if let Err(ref e) = name.refresh() {
+                        warn!("resolve {}: {}, the addresses are kept", name, e);
+                    }
+                }
+            }
+        });
+
+        Refresher {
+            is_stopped,
+            thread: Some(thread),
+        }
+    }
+
+    /// Closes the refresher.
+    pub(crate) fn close(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.is_stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Refresher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,195 @@
+//! A test responder echoing UDP datagrams and TCP streams to the port of the published address,
+//! which is answered in local without the proxy. Sources testing it exercise capturing, parsing,
+//! the state of connections, serializing and transmitting, so a path failing through the proxy
+//! but not to the responder is broken at the proxy.
+
+use log::{debug, trace, warn};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::shaper::Bucket;
+
+/// Represents the rate in bytes per second of the echoes of all sources.
+pub const RATE: u64 = 256 * 1024;
+/// Represents the max number of TCP connections echoed at the same time.
+pub const MAX_CONNECTIONS: usize = 16;
+
+/// Represents the interval in milliseconds of polling the listener and the socket.
+const POLL_INTERVAL: u64 = 100;
+/// Represents the timeout of an idle TCP connection.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Represents a responder echoing UDP datagrams and TCP streams in local, which datagrams and
+/// connections to its port of the published address are redirected to. Echoes are limited in
+/// rate, datagrams over the rate are dropped and streams are slowed down.
+#[derive(Debug)]
+pub struct Responder {
+    port: u16,
+    tcp_addr: SocketAddrV4,
+    udp_addr: SocketAddrV4,
+    is_stopped: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Responder {
+    /// Opens a `Responder` answering the port of the published address. The echoes are served
+    /// by a TCP listener and a UDP socket in local on their own threads.
+    pub fn open(port: u16) -> io::Result<Responder> {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        listener.set_nonblocking(true)?;
+        let tcp_addr = match listener.local_addr()? {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        socket.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL)))?;
+        let udp_addr = match socket.local_addr()? {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+
+        let bucket = Arc::new(Bucket::with_name(RATE, String::from("test responder")));
+        let is_stopped = Arc::new(AtomicBool::new(false));
+
+        let is_stopped_cloned = Arc::clone(&is_stopped);
+        let bucket_cloned = Arc::clone(&bucket);
+        let tcp_thread = thread::spawn(move || listen(listener, bucket_cloned, is_stopped_cloned));
+
+        let is_stopped_cloned = Arc::clone(&is_stopped);
+        let udp_thread = thread::spawn(move || echo_datagrams(socket, bucket, is_stopped_cloned));
+
+        Ok(Responder {
+            port,
+            tcp_addr,
+            udp_addr,
+            is_stopped,
+            threads: vec![tcp_thread, udp_thread],
+        })
+    }
+
+    /// Get the port of the published address answered.
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Get the address of the TCP listener in local.
+    pub fn get_tcp_addr(&self) -> SocketAddrV4 {
+        self.tcp_addr
+    }
+
+    /// Get the address of the UDP socket in local.
+    pub fn get_udp_addr(&self) -> SocketAddrV4 {
+        self.udp_addr
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn listen(listener: TcpListener, bucket: Arc<Bucket>, is_stopped: Arc<AtomicBool>) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    while !is_stopped.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                // Connections over the limit are closed at once
+                if connections.load(Ordering::Relaxed) >= MAX_CONNECTIONS {
+                    debug!(
+                        "close {} of {}: too many connections",
+                        addr, "test responder"
+                    );
+                    continue;
+                }
+                connections.fetch_add(1, Ordering::Relaxed);
+                let connections = Arc::clone(&connections);
+                let bucket = Arc::clone(&bucket);
+                let is_stopped = Arc::clone(&is_stopped);
+                thread::spawn(move || {
+                    if let Err(ref e) = echo_stream(stream, &bucket, &is_stopped) {
+                        debug!("echo {} of {}: {}", addr, "test responder", e);
+                    }
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(POLL_INTERVAL))
+            }
+            Err(ref e) => {
+                warn!("handle {}: {}", "test responder", e);
+                thread::sleep(Duration::from_millis(POLL_INTERVAL));
+            }
+        }
+    }
+}
+
+fn echo_stream(mut stream: TcpStream, bucket: &Bucket, is_stopped: &AtomicBool) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL)))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    let mut buffer = [0u8; 4096];
+    let mut idle = Duration::from_millis(0);
+    while !is_stopped.load(Ordering::Relaxed) && idle < IDLE_TIMEOUT {
+        let size = match stream.read(&mut buffer) {
+            Ok(0) => return stream.shutdown(std::net::Shutdown::Write),
+            Ok(size) => size,
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                idle += Duration::from_millis(POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        idle = Duration::from_millis(0);
+
+        // Streams over the rate are slowed down instead of dropped
+        thread::sleep(bucket.consume(size));
+        stream.write_all(&buffer[..size])?;
+    }
+
+    Ok(())
+}
+
+fn echo_datagrams(socket: UdpSocket, bucket: Arc<Bucket>, is_stopped: Arc<AtomicBool>) {
+    let mut buffer = [0u8; u16::MAX as usize];
+    while !is_stopped.load(Ordering::Relaxed) {
+        let (size, addr) = match socket.recv_from(&mut buffer) {
+            Ok(result) => result,
+            // ICMP errors of echoes are reported on the socket in some platforms
+            Err(ref e) => match e.kind() {
+                io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionRefused => continue,
+                _ => {
+                    warn!("handle {}: {}", "test responder", e);
+                    thread::sleep(Duration::from_millis(POLL_INTERVAL));
+                    continue;
+                }
+            },
+        };
+
+        // Datagrams over the rate are dropped
+        if !bucket.is_available() {
+            trace!(
+                "drop datagram from {} of {}: rate limited",
+                addr,
+                "test responder"
+            );
+            continue;
+        }
+        bucket.consume(size);
+        if let Err(ref e) = socket.send_to(&buffer[..size], addr) {
+            debug!("echo {} of {}: {}", addr, "test responder", e);
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! A blocklist of destinations loaded from a file, which is checked when TCP connections and UDP
+//! flows open, before any work of the proxy, and reloaded when the file changes.
+
+use log::{info, warn};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use super::{parse_cidr, parse_ports, CidrIndex};
+
+/// Represents the interval of polling the blocklist file for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+/// Represents the interval in milliseconds of checking if the watcher is stopped.
+const STOP_INTERVAL: u64 = 100;
+
+/// Represents the action of blocked traffic.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BlockAction {
+    /// Represents blocked traffic is answered, TCP connections are reset and UDP datagrams are
+    /// answered with an ICMPv4 port unreachable.
+    Reset,
+    /// Represents blocked traffic is dropped silently.
+    Drop,
+}
+
+impl BlockAction {
+    /// Parses an action from its name.
+    pub fn parse(s: &str) -> Option<BlockAction> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "reset" => Some(BlockAction::Reset),
+            "drop" => Some(BlockAction::Drop),
+            _ => None,
+        }
+    }
+}
+
+impl Display for BlockAction {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            BlockAction::Reset => write!(f, "reset"),
+            BlockAction::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// Represents an entry of a blocklist matching destinations by a CIDR and a range of ports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Block {
+    addr: Ipv4Addr,
+    prefix: u8,
+    ports: (u16, u16),
+}
+
+impl Block {
+    /// Parses an entry in the form of `CIDR[:PORT[-PORT]]`, like `203.0.113.0/24` or
+    /// `198.51.100.7:3074`. An omitted range of ports matches all. Returns `None` if the entry is
+    /// not valid.
+    fn parse(s: &str) -> Option<Block> {
+        let mut parts = s.splitn(2, ':');
+        let (addr, prefix) = parse_cidr(parts.next()?.trim())?;
+        let ports = match parts.next() {
+            Some(ports) => parse_ports(ports)?,
+            None => (0, u16::MAX),
+        };
+
+        Some(Block {
+            addr,
+            prefix,
+            ports,
+        })
+    }
+}
+
+/// Represents a blocklist of destinations, whose CIDRs are indexed so a destination is checked
+/// in O(log n).
+#[derive(Clone, Debug)]
+pub struct Blocklist {
+    blocks: Vec<Block>,
+    index: CidrIndex,
+    action: BlockAction,
+}
+
+impl Blocklist {
+    /// Creates a new empty `Blocklist`, which blocks nothing.
+    pub fn new() -> Blocklist {
+        Blocklist {
+            blocks: Vec::new(),
+            index: CidrIndex::default(),
+            action: BlockAction::Reset,
+        }
+    }
+
+    /// Parses a blocklist with an entry in a line, in the form of `CIDR[:PORT[-PORT]]`. Empty
+    /// lines and comments starting with `#` are ignored, and the directive `action reset` or
+    /// `action drop` sets the action of the blocklist, which is `reset` by default. Returns the
+    /// blocklist of the valid lines, and the line numbers of the invalid lines with the errors.
+    pub fn parse(s: &str) -> (Blocklist, Vec<(usize, String)>) {
+        let mut blocks = Vec::new();
+        let mut action = BlockAction::Reset;
+        let mut errors = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(j) => line[..j].trim(),
+                None => line.trim(),
+            };
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            if words.next() == Some("action") {
+                match words.next().and_then(BlockAction::parse) {
+                    Some(a) if words.next().is_none() => action = a,
+                    _ => errors.push((i + 1, format!("invalid directive {}", line))),
+                }
+                continue;
+            }
+            match Block::parse(line) {
+                Some(block) => blocks.push(block),
+                None => errors.push((i + 1, format!("invalid entry {}", line))),
+            }
+        }
+
+        let index = CidrIndex::new(blocks.iter().map(|block| (block.addr, block.prefix)));
+
+        (
+            Blocklist {
+                blocks,
+                index,
+                action,
+            },
+            errors,
+        )
+    }
+
+    /// Returns if the destination is blocked.
+    pub fn is_blocked(&self, dst: SocketAddrV4) -> bool {
+        self.index.get(*dst.ip()).iter().any(|&i| {
+            let ports = self.blocks[i].ports;
+
+            dst.port() >= ports.0 && dst.port() <= ports.1
+        })
+    }
+
+    /// Get the action of blocked traffic.
+    pub fn get_action(&self) -> BlockAction {
+        self.action
+    }
+
+    /// Get the number of entries.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Returns if there is no entry.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+impl Default for Blocklist {
+    fn default() -> Self {
+        Blocklist::new()
+    }
+}
+
+/// Represents a blocklist loaded from a file, which is polled for changes and reloaded on its
+/// own thread. A reload swaps the whole blocklist at once, so a lookup always sees either the
+/// old or the new one.
+#[derive(Debug)]
+pub struct BlocklistFile {
+    path: PathBuf,
+    blocklist: Arc<Mutex<Arc<Blocklist>>>,
+    is_stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BlocklistFile {
+    /// Opens a `BlocklistFile` loading the file, which is reloaded when it changes.
+    pub fn open(path: &Path) -> io::Result<BlocklistFile> {
+        let modified = get_modified(path)?;
+        let blocklist = Arc::new(Mutex::new(Arc::new(load(path)?)));
+        let is_stopped = Arc::new(AtomicBool::new(false));
+
+        let path_cloned = path.to_path_buf();
+        let blocklist_cloned = Arc::clone(&blocklist);
+        let is_stopped_cloned = Arc::clone(&is_stopped);
+        let thread = thread::spawn(move || {
+            watch(path_cloned, modified, blocklist_cloned, is_stopped_cloned)
+        });
+
+        Ok(BlocklistFile {
+            path: path.to_path_buf(),
+            blocklist,
+            is_stopped,
+            thread: Some(thread),
+        })
+    }
+
+    /// Get the path of the file.
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the blocklist currently loaded.
+    pub fn get(&self) -> Arc<Blocklist> {
+        Arc::clone(&self.blocklist.lock().unwrap())
+    }
+}
+
+impl Drop for BlocklistFile {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Get the modified time and the size of the file, either of which changes when the file is
+/// rewritten.
+fn get_modified(path: &Path) -> io::Result<(Option<SystemTime>, u64)> {
+    let metadata = fs::metadata(path)?;
+
+    Ok((metadata.modified().ok(), metadata.len()))
+}
+
+/// Loads the blocklist from the file, the invalid lines are warned with their line numbers and
+/// skipped.
+fn load(path: &Path) -> io::Result<Blocklist> {
+    let (blocklist, errors) = Blocklist::parse(&fs::read_to_string(path)?);
+    for (line, e) in errors.iter() {
+        warn!("parse blocklist {}:{}: {}", path.display(), line, e);
+    }
+    info!(
+        "Load blocklist {}: {} entries ({}), {} invalid lines",
+        path.display(),
+        blocklist.len(),
+        blocklist.get_action(),
+        errors.len()
+    );
+
+    Ok(blocklist)
+}
+
+fn watch(
+    path: PathBuf,
+    mut modified: (Option<SystemTime>, u64),
+    blocklist: Arc<Mutex<Arc<Blocklist>>>,
+    is_stopped: Arc<AtomicBool>,
+) {
+    let mut elapsed = Duration::from_millis(0);
+    while !is_stopped.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(STOP_INTERVAL));
+        elapsed += Duration::from_millis(STOP_INTERVAL);
+        if elapsed < RELOAD_INTERVAL {
+            continue;
+        }
+        elapsed = Duration::from_millis(0);
+
+        // A file missing or unreadable keeps the blocklist loaded until it is fixed
+        let current = match get_modified(&path) {
+            Ok(current) => current,
+            Err(ref e) => {
+                if modified.0.is_some() {
+                    warn!("reload blocklist {}: {}", path.display(), e);
+                    modified = (None, 0);
+                }
+                continue;
+            }
+        };
+        if current == modified {
+            continue;
+        }
+        match load(&path) {
+            Ok(reloaded) => {
+                modified = current;
+                *blocklist.lock().unwrap() = Arc::new(reloaded);
+            }
+            Err(ref e) => warn!("reload blocklist {}: {}", path.display(), e),
+        }
+    }
+}
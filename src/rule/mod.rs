@@ -0,0 +1,740 @@
+use std::collections::BTreeSet;
+use std::fmt::{self, Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+
+use crate::event::Protocol;
+use crate::metrics::Counter;
+use crate::resolver::Name;
+
+mod blocklist;
+
+pub use blocklist::{BlockAction, Blocklist, BlocklistFile};
+
+/// Represents the action of a rule.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Represents traffic redirected to the proxy.
+    Proxy,
+    /// Represents traffic sent from local directly.
+    Direct,
+    /// Represents traffic rejected.
+    Reject,
+}
+
+impl Action {
+    /// Parses an action from its name.
+    pub fn parse(s: &str) -> Option<Action> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "proxy" => Some(Action::Proxy),
+            "direct" => Some(Action::Direct),
+            "reject" => Some(Action::Reject),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            Action::Proxy => write!(f, "proxy"),
+            Action::Direct => write!(f, "direct"),
+            Action::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+/// Represents a rule matching destinations by a CIDR or the addresses of a host name, and a range
+/// of ports.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rule {
+    addr: Ipv4Addr,
+    prefix: u8,
+    /// Represents the host name whose addresses are matched in place of the CIDR
+    host: Option<Arc<Name>>,
+    ports: (u16, u16),
+    action: Action,
+}
+
+impl Rule {
+    /// Creates a new `Rule`.
+    pub fn new(addr: Ipv4Addr, prefix: u8, ports: (u16, u16), action: Action) -> Rule {
+        Rule {
+            addr,
+            prefix,
+            host: None,
+            ports,
+            action,
+        }
+    }
+
+    /// Creates a new `Rule` matching the addresses of the host name, which follow the name when
+    /// it is resolved again.
+    pub fn with_host(name: Arc<Name>, ports: (u16, u16), action: Action) -> Rule {
+        Rule {
+            addr: Ipv4Addr::UNSPECIFIED,
+            prefix: 0,
+            host: Some(name),
+            ports,
+            action,
+        }
+    }
+
+    /// Parses a rule in the form of `[CIDR,][PORT[-PORT],]ACTION`, like `192.168.0.0/16,direct`
+    /// or `0.0.0.0/0,25,reject`. An omitted CIDR or range of ports matches all. Returns `None` if
+    /// the rule is not valid.
+    pub fn parse(s: &str) -> Option<Rule> {
+        let mut fields: Vec<&str> = s.split(',').map(|field| field.trim()).collect();
+        let action = Action::parse(fields.pop()?)?;
+
+        let mut addr = Ipv4Addr::UNSPECIFIED;
+        let mut prefix = 0;
+        let mut ports = (0, u16::MAX);
+        match fields.len() {
+            0 => {}
+            1 => {
+                if fields[0].contains('.') {
+                    let (a, p) = parse_cidr(fields[0])?;
+                    addr = a;
+                    prefix = p;
+                } else {
+                    ports = parse_ports(fields[0])?;
+                }
+            }
+            2 => {
+                let (a, p) = parse_cidr(fields[0])?;
+                addr = a;
+                prefix = p;
+                ports = parse_ports(fields[1])?;
+            }
+            _ => return None,
+        }
+
+        Some(Rule::new(addr, prefix, ports, action))
+    }
+
+    /// Parses a rule in the form of `HOST,[PORT[-PORT],]ACTION`, like `example.com,443,direct`.
+    /// Returns the host name, the range of ports and the action, or `None` if the rule is not
+    /// valid or has a CIDR in place of the host name.
+    pub fn parse_host(s: &str) -> Option<(&str, (u16, u16), Action)> {
+        let mut fields: Vec<&str> = s.split(',').map(|field| field.trim()).collect();
+        let action = Action::parse(fields.pop()?)?;
+
+        let ports = match fields.len() {
+            1 => (0, u16::MAX),
+            2 => parse_ports(fields[1])?,
+            _ => return None,
+        };
+        if !is_host(fields[0]) {
+            return None;
+        }
+
+        Some((fields[0], ports, action))
+    }
+
+    /// Returns if the rule matches the destination.
+    pub fn is_match(&self, dst: SocketAddrV4) -> bool {
+        let is_addr_match = match self.host {
+            Some(ref name) => name.contains(*dst.ip()),
+            None => {
+                let mask = mask(self.prefix);
+
+                u32::from(*dst.ip()) & mask == u32::from(self.addr) & mask
+            }
+        };
+
+        is_addr_match && dst.port() >= self.ports.0 && dst.port() <= self.ports.1
+    }
+
+    /// Get the action of the rule.
+    pub fn get_action(&self) -> Action {
+        self.action
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.host {
+            Some(ref name) => write!(f, "{}", name)?,
+            None => write!(f, "{}/{}", self.addr, self.prefix)?,
+        }
+        if self.ports != (0, u16::MAX) {
+            if self.ports.0 == self.ports.1 {
+                write!(f, ",{}", self.ports.0)?;
+            } else {
+                write!(f, ",{}-{}", self.ports.0, self.ports.1)?;
+            }
+        }
+
+        write!(f, ",{}", self.action)
+    }
+}
+
+/// Get the mask of the given prefix length.
+fn mask(prefix: u8) -> u32 {
+    match prefix {
+        0 => 0,
+        _ => u32::MAX << (32 - prefix as u32),
+    }
+}
+
+/// Represents an index of CIDRs, which finds the CIDRs containing an address in O(log n).
+/// Addresses are split into segments at the bounds of the CIDRs, and each segment keeps the
+/// indexes of the CIDRs containing it in order, so the first CIDR of a segment is the first one
+/// inserted.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CidrIndex {
+    /// Represents the first addresses of the segments in order
+    starts: Vec<u32>,
+    /// Represents the indexes of the CIDRs containing each segment
+    segments: Vec<Vec<usize>>,
+}
+
+impl CidrIndex {
+    /// Creates a new `CidrIndex` of the CIDRs, which are referred to by their indexes.
+    pub fn new<I: IntoIterator<Item = (Ipv4Addr, u8)>>(cidrs: I) -> CidrIndex {
+        // Bounds of CIDRs, the ends are exclusive and sorted before the starts at the same address
+        let mut bounds = Vec::new();
+        for (i, (addr, prefix)) in cidrs.into_iter().enumerate() {
+            let mask = mask(prefix);
+            let start = (u32::from(addr) & mask) as u64;
+            bounds.push((start, true, i));
+            bounds.push((start + !mask as u64 + 1, false, i));
+        }
+        bounds.sort_unstable();
+
+        let mut starts = Vec::new();
+        let mut segments = Vec::new();
+        let mut active = BTreeSet::new();
+        let mut i = 0;
+        while i < bounds.len() {
+            let position = bounds[i].0;
+            while i < bounds.len() && bounds[i].0 == position {
+                let (_, is_start, index) = bounds[i];
+                if is_start {
+                    active.insert(index);
+                } else {
+                    active.remove(&index);
+                }
+                i += 1;
+            }
+            if position <= u32::MAX as u64 {
+                starts.push(position as u32);
+                segments.push(active.iter().cloned().collect());
+            }
+        }
+
+        CidrIndex { starts, segments }
+    }
+
+    /// Get the indexes of the CIDRs containing the address in order.
+    pub fn get(&self, addr: Ipv4Addr) -> &[usize] {
+        match self.starts.binary_search(&u32::from(addr)) {
+            Ok(i) => &self.segments[i],
+            Err(0) => &[],
+            Err(i) => &self.segments[i - 1],
+        }
+    }
+}
+
+/// Parses a CIDR like `192.168.0.0/16`. An address without the prefix length is a /32.
+fn parse_cidr(s: &str) -> Option<(Ipv4Addr, u8)> {
+    let mut parts = s.splitn(2, '/');
+    let addr = parts.next()?.parse().ok()?;
+    let prefix = match parts.next() {
+        Some(prefix) => prefix.parse().ok()?,
+        None => 32,
+    };
+    if prefix > 32 {
+        return None;
+    }
+
+    Some((addr, prefix))
+}
+
+/// Returns if the string is a host name, which has a letter so it is never an address.
+fn is_host(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= 253
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        && s.chars().any(|c| c.is_ascii_alphabetic())
+}
+
+/// Parses a port like `53`, or a range of ports like `8000-8080`.
+fn parse_ports(s: &str) -> Option<(u16, u16)> {
+    let mut parts = s.splitn(2, '-');
+    let start = parts.next()?.trim().parse().ok()?;
+    let end = match parts.next() {
+        Some(end) => end.trim().parse().ok()?,
+        None => start,
+    };
+    if start > end {
+        return None;
+    }
+
+    Some((start, end))
+}
+
+/// Represents the private and link-local networks (RFC 1918, RFC 3927).
+const PRIVATE_NETWORKS: [([u8; 4], u8); 4] = [
+    ([10, 0, 0, 0], 8),
+    ([172, 16, 0, 0], 12),
+    ([192, 168, 0, 0], 16),
+    ([169, 254, 0, 0], 16),
+];
+
+/// Represents rules evaluated in order, with a default action of destinations no rules match.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    index: CidrIndex,
+    default: Action,
+}
+
+impl Rules {
+    /// Creates a new `Rules` with the given default action.
+    pub fn new(default: Action) -> Rules {
+        Rules {
+            rules: Vec::new(),
+            index: CidrIndex::default(),
+            default,
+        }
+    }
+
+    /// Appends a rule.
+    pub fn push(&mut self, rule: Rule) {
+        self.extend(vec![rule]);
+    }
+
+    /// Appends rules, the index is rebuilt once for all of them.
+    pub fn extend(&mut self, rules: Vec<Rule>) {
+        self.rules.extend(rules);
+        // Rules of host names are indexed as 0.0.0.0/0, their addresses are matched in order
+        self.index = CidrIndex::new(self.rules.iter().map(|rule| (rule.addr, rule.prefix)));
+    }
+
+    /// Appends rules sending private and link-local networks directly.
+    pub fn push_private(&mut self) {
+        self.extend(
+            PRIVATE_NETWORKS
+                .iter()
+                .map(|(addr, prefix)| {
+                    Rule::new(
+                        Ipv4Addr::from(*addr),
+                        *prefix,
+                        (0, u16::MAX),
+                        Action::Direct,
+                    )
+                })
+                .collect(),
+        );
+    }
+
+    /// Get the action of the destination. Only the rules whose CIDRs contain the destination are
+    /// evaluated.
+    pub fn get_action(&self, dst: SocketAddrV4) -> Action {
+        for &i in self.index.get(*dst.ip()) {
+            let rule = &self.rules[i];
+            if rule.is_match(dst) {
+                return rule.get_action();
+            }
+        }
+
+        self.default
+    }
+
+    /// Get the rules.
+    pub fn get_rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Get the default action.
+    pub fn get_default(&self) -> Action {
+        self.default
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules::new(Action::Proxy)
+    }
+}
+
+/// Represents an entry of access lists matching relayed traffic by the protocol, a range of
+/// ports and a CIDR of destinations. Entries are counted when they match.
+#[derive(Clone, Debug)]
+pub struct Access {
+    protocol: Protocol,
+    ports: (u16, u16),
+    addr: Ipv4Addr,
+    prefix: u8,
+    count: Arc<Counter>,
+}
+
+impl Access {
+    /// Creates a new `Access`.
+    pub fn new(protocol: Protocol, ports: (u16, u16), addr: Ipv4Addr, prefix: u8) -> Access {
+        Access {
+            protocol,
+            ports,
+            addr,
+            prefix,
+            count: Arc::new(Counter::new()),
+        }
+    }
+
+    /// Parses an entry in the form of `PROTOCOL:PORT[-PORT][@CIDR]`, like `udp:3074-3658` or
+    /// `tcp:443@10.0.0.0/8`. An omitted CIDR matches all. Returns `None` if the entry is not
+    /// valid.
+    pub fn parse(s: &str) -> Option<Access> {
+        let mut parts = s.trim().splitn(2, ':');
+        let protocol = match parts.next()?.trim().to_ascii_lowercase().as_str() {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            _ => return None,
+        };
+
+        let mut parts = parts.next()?.splitn(2, '@');
+        let ports = parse_ports(parts.next()?)?;
+        let (addr, prefix) = match parts.next() {
+            Some(cidr) => parse_cidr(cidr.trim())?,
+            None => (Ipv4Addr::UNSPECIFIED, 0),
+        };
+
+        Some(Access::new(protocol, ports, addr, prefix))
+    }
+
+    /// Returns if the entry matches traffic of the protocol to the destination.
+    pub fn is_match(&self, protocol: Protocol, dst: SocketAddrV4) -> bool {
+        let mask = mask(self.prefix);
+
+        self.protocol == protocol
+            && u32::from(*dst.ip()) & mask == u32::from(self.addr) & mask
+            && dst.port() >= self.ports.0
+            && dst.port() <= self.ports.1
+    }
+
+    /// Get the protocol the entry matches.
+    pub fn get_protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Get the CIDR of destinations the entry matches.
+    fn get_cidr(&self) -> (Ipv4Addr, u8) {
+        (self.addr, self.prefix)
+    }
+
+    /// Get the count of traffic the entry matches.
+    pub fn get_count(&self) -> u64 {
+        self.count.get()
+    }
+}
+
+impl Display for Access {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}:", self.protocol)?;
+        if self.ports.0 == self.ports.1 {
+            write!(f, "{}", self.ports.0)?;
+        } else {
+            write!(f, "{}-{}", self.ports.0, self.ports.1)?;
+        }
+        if self.prefix != 0 {
+            write!(f, "@{}/{}", self.addr, self.prefix)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Represents the allow list and the deny list of relayed traffic. Traffic matching the deny
+/// list is denied even if it matches the allow list, and an empty allow list allows all.
+#[derive(Clone, Debug, Default)]
+pub struct AccessList {
+    allow: Vec<Access>,
+    allow_index: CidrIndex,
+    deny: Vec<Access>,
+    deny_index: CidrIndex,
+    is_silent: bool,
+}
+
+impl AccessList {
+    /// Creates a new empty `AccessList`, which allows all.
+    pub fn new() -> AccessList {
+        AccessList::default()
+    }
+
+    /// Appends an entry to the allow list.
+    pub fn push_allow(&mut self, access: Access) {
+        self.allow.push(access);
+        self.allow_index = CidrIndex::new(self.allow.iter().map(|access| access.get_cidr()));
+    }
+
+    /// Appends an entry to the deny list.
+    pub fn push_deny(&mut self, access: Access) {
+        self.deny.push(access);
+        self.deny_index = CidrIndex::new(self.deny.iter().map(|access| access.get_cidr()));
+    }
+
+    /// Sets if denied traffic is dropped silently instead of being answered.
+    pub fn set_silent(&mut self, is_silent: bool) {
+        self.is_silent = is_silent;
+    }
+
+    /// Returns if denied traffic is dropped silently.
+    pub fn is_silent(&self) -> bool {
+        self.is_silent
+    }
+
+    /// Returns if the lists are both empty.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Returns if traffic of the protocol to the destination is allowed. The entry deciding it
+    /// is counted.
+    pub fn is_allowed(&self, protocol: Protocol, dst: SocketAddrV4) -> bool {
+        if let Some(access) = self
+            .deny_index
+            .get(*dst.ip())
+            .iter()
+            .map(|&i| &self.deny[i])
+            .find(|access| access.is_match(protocol, dst))
+        {
+            access.count.increase();
+
+            return false;
+        }
+        if self.allow.is_empty() {
+            return true;
+        }
+        match self
+            .allow_index
+            .get(*dst.ip())
+            .iter()
+            .map(|&i| &self.allow[i])
+            .find(|access| access.is_match(protocol, dst))
+        {
+            Some(access) => {
+                access.count.increase();
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Get the allow list.
+    pub fn get_allow(&self) -> &[Access] {
+        &self.allow
+    }
+
+    /// Get the deny list.
+    pub fn get_deny(&self) -> &[Access] {
+        &self.deny
+    }
+}
+
+/// Represents a rule rewriting the destination of relayed traffic, which matches traffic by the
+/// protocol, and the address and the port of the destination, either of which may be a wildcard.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rewrite {
+    protocol: Protocol,
+    addr: Option<Ipv4Addr>,
+    port: Option<u16>,
+    target: SocketAddrV4,
+}
+
+impl Rewrite {
+    /// Creates a new `Rewrite`. `None` of the address or the port matches all.
+    pub fn new(
+        protocol: Protocol,
+        addr: Option<Ipv4Addr>,
+        port: Option<u16>,
+        target: SocketAddrV4,
+    ) -> Rewrite {
+        Rewrite {
+            protocol,
+            addr,
+            port,
+            target,
+        }
+    }
+
+    /// Parses a rule in the form of `PROTOCOL,ADDR:PORT -> ADDR:PORT`, like
+    /// `tcp,203.0.113.10:443 -> 198.51.100.7:8443`. The address or the port matched may be `*`,
+    /// like `udp,*:53 -> 10.0.0.1:53`. Returns `None` if the rule is not valid.
+    pub fn parse(s: &str) -> Option<Rewrite> {
+        let mut parts = s.trim().splitn(2, ',');
+        let protocol = match parts.next()?.trim().to_ascii_lowercase().as_str() {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            _ => return None,
+        };
+
+        let mut parts = parts.next()?.splitn(2, "->");
+        let mut matched = parts.next()?.trim().rsplitn(2, ':');
+        let port = match matched.next()?.trim() {
+            "*" => None,
+            port => Some(port.parse().ok()?),
+        };
+        let addr = match matched.next()?.trim() {
+            "*" => None,
+            addr => Some(addr.parse().ok()?),
+        };
+        let target = parts.next()?.trim().parse().ok()?;
+
+        Some(Rewrite::new(protocol, addr, port, target))
+    }
+
+    /// Returns if the rule matches traffic of the protocol to the destination.
+    pub fn is_match(&self, protocol: Protocol, dst: SocketAddrV4) -> bool {
+        self.protocol == protocol
+            && self.addr.map_or(true, |addr| addr == *dst.ip())
+            && self.port.map_or(true, |port| port == dst.port())
+    }
+
+    /// Get the destination the rule rewrites to.
+    pub fn get_target(&self) -> SocketAddrV4 {
+        self.target
+    }
+}
+
+impl Display for Rewrite {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{},", self.protocol)?;
+        match self.addr {
+            Some(addr) => write!(f, "{}:", addr)?,
+            None => write!(f, "*:")?,
+        }
+        match self.port {
+            Some(port) => write!(f, "{}", port)?,
+            None => write!(f, "*")?,
+        }
+
+        write!(f, " -> {}", self.target)
+    }
+}
+
+/// Represents rules rewriting destinations of relayed traffic, which are evaluated in order and
+/// the first rule matched wins.
+#[derive(Clone, Debug, Default)]
+pub struct Rewrites {
+    rewrites: Vec<Rewrite>,
+}
+
+impl Rewrites {
+    /// Creates a new empty `Rewrites`, which rewrites nothing.
+    pub fn new() -> Rewrites {
+        Rewrites::default()
+    }
+
+    /// Appends a rule.
+    pub fn push(&mut self, rewrite: Rewrite) {
+        self.rewrites.push(rewrite);
+    }
+
+    /// Returns if there is no rule.
+    pub fn is_empty(&self) -> bool {
+        self.rewrites.is_empty()
+    }
+
+    /// Get the destination traffic of the protocol to the destination is rewritten to, or `None`
+    /// if no rule matches.
+    pub fn rewrite(&self, protocol: Protocol, dst: SocketAddrV4) -> Option<SocketAddrV4> {
+        self.rewrites
+            .iter()
+            .find(|rewrite| rewrite.is_match(protocol, dst))
+            .map(|rewrite| rewrite.get_target())
+    }
+
+    /// Get the rules.
+    pub fn get_rewrites(&self) -> &[Rewrite] {
+        &self.rewrites
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cidr_index_overlap() {
+        let index = CidrIndex::new(vec![
+            (Ipv4Addr::new(10, 0, 0, 0), 8),
+            (Ipv4Addr::new(10, 1, 0, 0), 16),
+            (Ipv4Addr::new(10, 1, 2, 3), 32),
+        ]);
+        assert_eq!(index.get(Ipv4Addr::new(9, 255, 255, 255)), &[] as &[usize]);
+        assert_eq!(index.get(Ipv4Addr::new(10, 0, 0, 0)), &[0]);
+        assert_eq!(index.get(Ipv4Addr::new(10, 1, 0, 0)), &[0, 1]);
+        assert_eq!(index.get(Ipv4Addr::new(10, 1, 2, 3)), &[0, 1, 2]);
+        assert_eq!(index.get(Ipv4Addr::new(10, 1, 2, 4)), &[0, 1]);
+        assert_eq!(index.get(Ipv4Addr::new(10, 2, 0, 0)), &[0]);
+        assert_eq!(index.get(Ipv4Addr::new(11, 0, 0, 0)), &[] as &[usize]);
+    }
+
+    #[test]
+    fn blocklist_entries() {
+        let (blocklist, errors) = Blocklist::parse(
+            "# CIDRs\n\
+             203.0.113.0/24\n\
+             \n\
+             198.51.100.7:3074 # a host and a port\n\
+             192.0.2.1:8000-8010\n",
+        );
+        assert!(errors.is_empty());
+        assert_eq!(blocklist.len(), 3);
+        assert_eq!(blocklist.get_action(), BlockAction::Reset);
+
+        let dst = |a, b, c, d, port| SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port);
+        // A CIDR blocks all ports
+        assert!(blocklist.is_blocked(dst(203, 0, 113, 0, 0)));
+        assert!(blocklist.is_blocked(dst(203, 0, 113, 255, 65535)));
+        assert!(!blocklist.is_blocked(dst(203, 0, 114, 0, 80)));
+        // A host blocks only its port
+        assert!(blocklist.is_blocked(dst(198, 51, 100, 7, 3074)));
+        assert!(!blocklist.is_blocked(dst(198, 51, 100, 7, 3075)));
+        assert!(!blocklist.is_blocked(dst(198, 51, 100, 8, 3074)));
+        // A host blocks its range of ports
+        assert!(blocklist.is_blocked(dst(192, 0, 2, 1, 8000)));
+        assert!(blocklist.is_blocked(dst(192, 0, 2, 1, 8010)));
+        assert!(!blocklist.is_blocked(dst(192, 0, 2, 1, 8011)));
+    }
+
+    #[test]
+    fn blocklist_action() {
+        let (blocklist, errors) = Blocklist::parse("action drop\n203.0.113.0/24\n");
+        assert!(errors.is_empty());
+        assert_eq!(blocklist.get_action(), BlockAction::Drop);
+
+        // The last directive wins, and is case-insensitive
+        let (blocklist, errors) = Blocklist::parse("action drop\naction RESET # answered\n");
+        assert!(errors.is_empty());
+        assert_eq!(blocklist.get_action(), BlockAction::Reset);
+        assert!(blocklist.is_empty());
+    }
+
+    #[test]
+    fn blocklist_errors() {
+        let (blocklist, errors) = Blocklist::parse(
+            "203.0.113.0/24\n\
+             203.0.113.0/33\n\
+             # skipped\n\
+             198.51.100.7:http\n\
+             action reject\n\
+             action drop now\n\
+             192.0.2.1:80\n",
+        );
+        // Invalid lines are skipped with their line numbers, the valid ones are kept
+        assert_eq!(
+            errors.iter().map(|(line, _)| *line).collect::<Vec<_>>(),
+            vec![2, 4, 5, 6]
+        );
+        assert!(errors[0].1.starts_with("invalid entry"));
+        assert!(errors[2].1.starts_with("invalid directive"));
+        assert_eq!(blocklist.len(), 2);
+        assert_eq!(blocklist.get_action(), BlockAction::Reset);
+        assert!(blocklist.is_blocked(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80)));
+    }
+}
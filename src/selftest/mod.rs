@@ -0,0 +1,452 @@
+//! Checks of the device and the proxy in starting, which tell why nothing works before any
+//! traffic of sources is touched.
+
+use log::{info, warn};
+use std::cmp::min;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::sync::mpsc::{self, Receiver as ChannelReceiver};
+use std::time::{Duration, Instant};
+
+use crate::packet::layer::arp::Arp;
+use crate::packet::layer::ethernet::Ethernet;
+use crate::packet::layer::ipv4::Ipv4;
+use crate::packet::layer::udp::Udp;
+use crate::packet::layer::{Layer, LayerTypes, Layers};
+use crate::packet::Indicator;
+use crate::pcap::{HardwareAddr, Receiver, SendQueue};
+use crate::socks::{self, ProxyConnector, SocksDatagram};
+use crate::DEFAULT_PROXY_TIMEOUT;
+
+/// Represents the default endpoint connected through the proxy in the full self-test.
+pub const DEFAULT_SELF_TEST_CONNECT: &str = "1.1.1.1:80";
+
+/// Represents the timeout of capturing the ARP probe injected.
+const LOOPBACK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Represents the timeout of the echo of the UDP test server.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(3);
+/// Represents the payload sent to the UDP test server.
+const ECHO_PAYLOAD: &[u8] = b"pcap2socks self-test";
+/// Represents the discard port the max-MTU frame is sent to.
+const DISCARD_PORT: u16 = 9;
+
+/// Represents the levels of the self-test.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Level {
+    /// Represents the device and the reachability of the proxy are checked, and only failures of
+    /// the device stop starting.
+    Light,
+    /// Represents the device, a handshake and a CONNECT through the proxy, and the UDP relay are
+    /// checked, and any mandatory failure stops starting.
+    Full,
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            Level::Light => write!(f, "light"),
+            Level::Full => write!(f, "full"),
+        }
+    }
+}
+
+/// Represents the result of a check.
+#[derive(Debug)]
+pub struct Check {
+    name: &'static str,
+    is_mandatory: bool,
+    result: Result<String, (String, &'static str)>,
+}
+
+impl Check {
+    fn pass(name: &'static str, is_mandatory: bool, detail: String) -> Check {
+        Check {
+            name,
+            is_mandatory,
+            result: Ok(detail),
+        }
+    }
+
+    fn fail(name: &'static str, is_mandatory: bool, e: String, hint: &'static str) -> Check {
+        Check {
+            name,
+            is_mandatory,
+            result: Err((e, hint)),
+        }
+    }
+
+    /// Get the name of the check.
+    pub fn get_name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns if the check passes.
+    pub fn is_passed(&self) -> bool {
+        self.result.is_ok()
+    }
+
+    /// Returns if a failure of the check stops starting.
+    pub fn is_mandatory(&self) -> bool {
+        self.is_mandatory
+    }
+
+    /// Get the hint of fixing the failure of the check.
+    pub fn get_hint(&self) -> Option<&'static str> {
+        match self.result {
+            Ok(_) => None,
+            Err((_, hint)) => Some(hint),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.result {
+            Ok(ref detail) => write!(f, "{}: pass, {}", self.name, detail),
+            Err((ref e, hint)) => write!(f, "{}: fail, {} ({})", self.name, e, hint),
+        }
+    }
+}
+
+/// Represents the self-test of the device and the proxy.
+pub struct SelfTest<'a> {
+    level: Level,
+    hardware_addr: HardwareAddr,
+    local_ip_addr: Ipv4Addr,
+    mtu: u16,
+    /// Represents if the device carries ARP, frames are not looped back otherwise
+    is_arp: bool,
+    proxy: &'a dyn ProxyConnector,
+    proxy_timeout: Duration,
+    connect: SocketAddrV4,
+    echo: Option<SocketAddrV4>,
+}
+
+impl<'a> SelfTest<'a> {
+    /// Creates a new `SelfTest` of the level.
+    pub fn new(
+        level: Level,
+        hardware_addr: HardwareAddr,
+        local_ip_addr: Ipv4Addr,
+        mtu: u16,
+        proxy: &'a dyn ProxyConnector,
+    ) -> SelfTest<'a> {
+        SelfTest {
+            level,
+            hardware_addr,
+            local_ip_addr,
+            mtu,
+            is_arp: true,
+            proxy,
+            proxy_timeout: Duration::from_secs(DEFAULT_PROXY_TIMEOUT),
+            connect: DEFAULT_SELF_TEST_CONNECT.parse().unwrap(),
+            echo: None,
+        }
+    }
+
+    /// Sets if the device carries ARP.
+    pub fn set_arp(&mut self, is_arp: bool) {
+        self.is_arp = is_arp;
+    }
+
+    /// Sets the timeout of the handshake with the proxy.
+    pub fn set_proxy_timeout(&mut self, timeout: Duration) {
+        self.proxy_timeout = timeout;
+    }
+
+    /// Sets the endpoint connected through the proxy in the full self-test.
+    pub fn set_connect(&mut self, connect: SocketAddrV4) {
+        self.connect = connect;
+    }
+
+    /// Sets the UDP test server which echoes datagrams through the proxy in the full self-test.
+    pub fn set_echo(&mut self, echo: SocketAddrV4) {
+        self.echo = Some(echo);
+    }
+
+    /// Runs the checks on the device and the proxy, returns the results in order. Frames
+    /// captured in the meantime are discarded.
+    pub fn run(&self, tx: &mut SendQueue, rx: &mut Receiver) -> Vec<Check> {
+        let mut checks = Vec::new();
+        if self.is_arp {
+            checks.push(self.check_loopback(tx, rx));
+        }
+        checks.push(self.check_mtu(tx));
+        match self.level {
+            Level::Light => checks.push(self.check_proxy()),
+            Level::Full => {
+                checks.push(self.check_connect());
+                if let Some(check) = self.check_associate() {
+                    checks.push(check);
+                }
+            }
+        }
+
+        checks
+    }
+
+    /// Injects an ARP probe and captures it back, which confirms frames are both sent and
+    /// received on the device.
+    fn check_loopback(&self, tx: &mut SendQueue, rx: &mut Receiver) -> Check {
+        const NAME: &str = "device send and receive";
+        const HINT: &str =
+            "run with the permission of capturing, like root or CAP_NET_RAW and CAP_NET_ADMIN, and check the interface is up";
+
+        // An ARP probe (RFC 5227) of the local address never updates caches of hosts
+        let request = Arp::new_request(
+            self.hardware_addr,
+            Ipv4Addr::UNSPECIFIED,
+            self.local_ip_addr,
+        );
+        let indicator = Indicator::new(
+            Layers::Ethernet(
+                Ethernet::new(
+                    LayerTypes::Arp,
+                    self.hardware_addr,
+                    HardwareAddr::broadcast(),
+                )
+                .unwrap(),
+            ),
+            Some(Layers::Arp(request)),
+            None,
+        );
+        let frame = match indicator.to_vec() {
+            Ok(frame) => frame,
+            Err(ref e) => return Check::fail(NAME, true, e.to_string(), HINT),
+        };
+        if let Err(ref e) = tx.send(&frame, true) {
+            return Check::fail(NAME, true, format!("send: {}", e), HINT);
+        }
+
+        let instant = Instant::now();
+        while instant.elapsed() < LOOPBACK_TIMEOUT {
+            match rx.next_frame() {
+                Ok(captured) => {
+                    if is_probe_of(captured, self.hardware_addr, self.local_ip_addr) {
+                        return Check::pass(
+                            NAME,
+                            true,
+                            format!("ARP probe captured in {} ms", instant.elapsed().as_millis()),
+                        );
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) => return Check::fail(NAME, true, format!("receive: {}", e), HINT),
+            }
+        }
+
+        Check::fail(
+            NAME,
+            true,
+            format!("ARP probe not captured in {} s", LOOPBACK_TIMEOUT.as_secs()),
+            HINT,
+        )
+    }
+
+    /// Sends a UDP datagram filling the MTU to the discard port of the broadcast address, which
+    /// confirms the device accepts frames of the MTU.
+    fn check_mtu(&self, tx: &mut SendQueue) -> Check {
+        const NAME: &str = "device MTU";
+        const HINT: &str = "set --mtu to the MTU of the interface or lower";
+
+        let dst = Ipv4Addr::BROADCAST;
+        let udp = Udp::new(
+            IpAddr::V4(self.local_ip_addr),
+            IpAddr::V4(dst),
+            DISCARD_PORT,
+            DISCARD_PORT,
+        );
+        let ipv4 = Ipv4::new(0, LayerTypes::Udp, self.local_ip_addr, dst).unwrap();
+        let payload = vec![0u8; self.mtu as usize - ipv4.get_size() - udp.get_size()];
+        let indicator = Indicator::new(
+            Layers::Ethernet(
+                Ethernet::new(
+                    LayerTypes::Ipv4,
+                    self.hardware_addr,
+                    HardwareAddr::broadcast(),
+                )
+                .unwrap(),
+            ),
+            Some(Layers::Ipv4(ipv4)),
+            Some(Layers::Udp(udp)),
+        );
+        let frame = match indicator.to_vec_with_payload(&payload) {
+            Ok(frame) => frame,
+            Err(ref e) => return Check::fail(NAME, true, e.to_string(), HINT),
+        };
+        match tx.send(&frame, true) {
+            Ok(_) => Check::pass(NAME, true, format!("frame of MTU {} sent", self.mtu)),
+            Err(ref e) => Check::fail(
+                NAME,
+                true,
+                format!("send frame of MTU {}: {}", self.mtu, e),
+                HINT,
+            ),
+        }
+    }
+
+    /// Connects to the proxy without a request.
+    fn check_proxy(&self) -> Check {
+        const NAME: &str = "proxy reachable";
+        const HINT: &str = "check the address of the proxy and that it is running";
+
+        let remote = self.proxy.get_remote();
+        match socks::probe(remote) {
+            true => Check::pass(NAME, false, format!("{} accepts connections", remote)),
+            false => Check::fail(NAME, false, format!("cannot connect to {}", remote), HINT),
+        }
+    }
+
+    /// Connects to the endpoint through the proxy, which goes through the handshake.
+    fn check_connect(&self) -> Check {
+        const NAME: &str = "proxy CONNECT";
+        const HINT: &str =
+            "check the address, the version and the credentials of the proxy, or designate a reachable endpoint by --self-test-connect";
+
+        match self.proxy.connect(self.connect, self.proxy_timeout) {
+            Ok(_) => Check::pass(NAME, true, format!("connected to {}", self.connect)),
+            Err(ref e) => Check::fail(
+                NAME,
+                true,
+                format!("connect to {}: {}", self.connect, e),
+                HINT,
+            ),
+        }
+    }
+
+    /// Associates with the proxy, and echoes a datagram through the relay if a UDP test server
+    /// is set. The association is mandatory only if the test server is set.
+    fn check_associate(&self) -> Option<Check> {
+        const NAME: &str = "proxy UDP ASSOCIATE";
+        const HINT: &str =
+            "use a SOCKS5 proxy with UDP enabled, or relay UDP traffic by --udp-fallback";
+
+        let is_mandatory = self.echo.is_some();
+        if !self.proxy.is_udp_supported() {
+            return Some(Check::fail(
+                NAME,
+                is_mandatory,
+                format!("{} does not support UDP", self.proxy),
+                HINT,
+            ));
+        }
+        let datagram = match self.proxy.bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)) {
+            Ok(datagram) => datagram,
+            Err(ref e) => {
+                return Some(Check::fail(NAME, is_mandatory, e.to_string(), HINT));
+            }
+        };
+        let echo = match self.echo {
+            Some(echo) => echo,
+            None => return Some(Check::pass(NAME, false, String::from("association bound"))),
+        };
+
+        const ECHO_HINT: &str =
+            "check the proxy relays UDP traffic, the firewall passes UDP from the relay, and the test server echoes";
+        if let Err(ref e) = datagram.set_read_timeout(Some(ECHO_TIMEOUT)) {
+            return Some(Check::fail(NAME, true, e.to_string(), ECHO_HINT));
+        }
+        let echoes = match listen(&datagram) {
+            Ok(echoes) => echoes,
+            Err(ref e) => return Some(Check::fail(NAME, true, e.to_string(), ECHO_HINT)),
+        };
+        if let Err(ref e) = datagram.send_to(ECHO_PAYLOAD, echo) {
+            return Some(Check::fail(
+                NAME,
+                true,
+                format!("send to {}: {}", echo, e),
+                ECHO_HINT,
+            ));
+        }
+        let mut buffer = [0u8; u16::MAX as usize];
+        let instant = Instant::now();
+        let check = match recv_echo(&datagram, echoes, &mut buffer) {
+            Ok((size, addr)) if &buffer[..size] == ECHO_PAYLOAD => Check::pass(
+                NAME,
+                true,
+                format!("echoed by {} in {} ms", addr, instant.elapsed().as_millis()),
+            ),
+            Ok((_, addr)) => {
+                Check::fail(NAME, true, format!("echo from {} differs", addr), ECHO_HINT)
+            }
+            Err(ref e) => Check::fail(
+                NAME,
+                true,
+                format!("receive echo from {}: {}", echo, e),
+                ECHO_HINT,
+            ),
+        };
+
+        Some(check)
+    }
+}
+
+/// Passes datagrams of a shared socket from its reader to a channel, which must be done before
+/// sending so the echo is never dropped. Returns `None` if the socket is not shared.
+fn listen(
+    datagram: &SocksDatagram,
+) -> io::Result<Option<ChannelReceiver<(Vec<u8>, SocketAddrV4)>>> {
+    if !datagram.is_shared() {
+        return Ok(None);
+    }
+
+    let (tx, rx) = mpsc::channel();
+    datagram.set_handler(Box::new(move |data: &[u8], addr: SocketAddrV4| {
+        let _ = tx.send((data.to_vec(), addr));
+    }))?;
+
+    Ok(Some(rx))
+}
+
+/// Receives the echo from the socket, or from the channel of a shared socket.
+fn recv_echo(
+    datagram: &SocksDatagram,
+    echoes: Option<ChannelReceiver<(Vec<u8>, SocketAddrV4)>>,
+    buffer: &mut [u8],
+) -> io::Result<(usize, SocketAddrV4)> {
+    let echoes = match echoes {
+        Some(echoes) => echoes,
+        None => return datagram.recv_from(buffer),
+    };
+
+    match echoes.recv_timeout(ECHO_TIMEOUT) {
+        Ok((data, addr)) => {
+            let size = min(data.len(), buffer.len());
+            buffer[..size].copy_from_slice(&data[..size]);
+
+            Ok((size, addr))
+        }
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+    }
+}
+
+/// Returns if the frame is the ARP probe of the address sent from the hardware address.
+fn is_probe_of(frame: &[u8], hardware_addr: HardwareAddr, ip_addr: Ipv4Addr) -> bool {
+    match Indicator::from(frame) {
+        Ok(indicator) => match indicator.get_arp() {
+            Some(arp) => {
+                arp.is_probe()
+                    && arp.get_src_hardware_addr() == hardware_addr
+                    && arp.get_dst() == ip_addr
+            }
+            None => false,
+        },
+        Err(_) => false,
+    }
+}
+
+/// Logs the results of checks, returns the first mandatory check failed.
+pub fn report(checks: &[Check]) -> Option<&Check> {
+    for check in checks {
+        match check.is_passed() {
+            true => info!("Self-test {}", check),
+            false => warn!("Self-test {}", check),
+        }
+    }
+
+    checks
+        .iter()
+        .find(|check| !check.is_passed() && check.is_mandatory())
+}
@@ -0,0 +1,1972 @@
+use ipnetwork::{Ipv4Network, Ipv6Network};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::args::{Flags, Opts, DEFAULT_MTU};
+use crate::arp;
+use crate::classify;
+use crate::clock::Clock;
+use crate::event::{self, Event};
+use crate::hook::{self, Hook};
+use crate::memory;
+use crate::metrics::{self, Printer, Stats};
+use crate::nat;
+use crate::packet::verify::VerifyMode;
+use crate::packet::ChecksumMode;
+use crate::pcap::bridge;
+use crate::pcap::capture;
+use crate::pcap::chaos::Chaos;
+use crate::pcap::dump::Dumper;
+use crate::pcap::filter::{self, Expression};
+use crate::pcap::gateway::{self, SpoofMac};
+use crate::pcap::link::{self, LinkType};
+use crate::pcap::trace::{TraceRule, Tracer};
+use crate::pcap::{self, HardwareAddr, Interface, Receiver, SendQueue, Sender};
+use crate::privilege::{self, Dropped, Platform};
+use crate::reload::{self, Reloader};
+use crate::resolver::Refresher;
+use crate::responder::Responder;
+use crate::rule::{Access, AccessList, Action, BlocklistFile, Rewrites, Rules};
+use crate::selftest::{self, Level, SelfTest};
+use crate::shaper::{ClientQuota, Quota, Shaper};
+use crate::socks::{
+    self, Outbound, PortRange, ProxyConnector, ProxyGroup, ProxyPolicy, UdpFallback,
+    UdpFallbackConnector,
+};
+use crate::state;
+use crate::{
+    default_tcp_buffer_sizes, dhcp, igd, tcp_window_scale_of, Dispatcher, Downstreamer, UdpNat,
+    Upstreamer, IPV4_TCP_HEADER_SIZE, MAX_SOURCES, MAX_WORKERS,
+};
+
+/// Represents the DNS resolver of sources leased by DHCP if no resolver is designated.
+const DEFAULT_DNS_RESOLVER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53);
+
+/// Represents the device frames are captured from and sent to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Device {
+    /// Represents the only interface which is up.
+    Auto,
+    /// Represents the interface of the name.
+    Name(String),
+    /// Represents the interface which has the IPv4 address.
+    Address(Ipv4Addr),
+    /// Represents the interface of the index in the listing of interfaces, from 1.
+    Index(usize),
+    /// Represents the TUN device of the name, which is created if it does not exist.
+    Tun(String),
+}
+
+impl Display for Device {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            Device::Auto => write!(f, "auto"),
+            Device::Name(ref name) => write!(f, "{}", name),
+            Device::Address(ref ip_addr) => write!(f, "{}", ip_addr),
+            Device::Index(ref index) => write!(f, "interface #{}", index),
+            Device::Tun(ref name) => write!(f, "{} (TUN)", name),
+        }
+    }
+}
+
+impl Device {
+    /// Parses a device designated by the name, the friendly name, an IPv4 address or the index in
+    /// the listing of interfaces.
+    pub fn parse(s: &str) -> Device {
+        if let Ok(ip_addr) = s.parse() {
+            return Device::Address(ip_addr);
+        }
+        match s.parse() {
+            Ok(index) => Device::Index(index),
+            Err(_) => Device::Name(s.to_string()),
+        }
+    }
+}
+
+/// Represents an error when set up or run.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Represents no device is designated and there is not exactly one interface which is up.
+    UndeterminedInterfaceError,
+    /// Represents the device is not found.
+    InterfaceNotFoundError(Device),
+    /// Represents more than one interface match the device.
+    AmbiguousInterfaceError(Device),
+    /// Represents the device has no IPv4 address.
+    AddressError(Device),
+    /// Represents the address and the prefix length are not a network.
+    NetworkError(Ipv4Addr, u8),
+    OutOfRangeError(&'static str, &'static str),
+    RequirementError(&'static str, &'static str),
+    ConflictError(&'static str, &'static str),
+    IoError(io::Error),
+    /// Represents the thread of the dispatcher panicked.
+    PanicError,
+    /// Represents a mandatory check of the self-test failed.
+    SelfTestError(String),
+    /// Represents the published address is owned by the host of the hardware address.
+    PublishConflictError(Ipv4Addr, HardwareAddr),
+    /// Represents the device cannot be opened, with the hint of the permission of capturing.
+    PrivilegeError(io::Error, String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            Error::UndeterminedInterfaceError => write!(f, "cannot determine interface"),
+            Error::InterfaceNotFoundError(ref device) => write!(f, "{} not found", device),
+            Error::AmbiguousInterfaceError(ref device) => {
+                write!(f, "{} matches more than one interface", device)
+            }
+            Error::AddressError(ref device) => write!(f, "{} has no IPv4 address", device),
+            Error::NetworkError(ref ip_addr, ref prefix) => {
+                write!(f, "{}/{} is not a network", ip_addr, prefix)
+            }
+            Error::OutOfRangeError(ref value, ref range) => {
+                write!(f, "{} is out of range {}", value, range)
+            }
+            Error::RequirementError(ref value, ref requirement) => {
+                write!(f, "{} requires {}", value, requirement)
+            }
+            Error::ConflictError(ref value, ref conflict) => {
+                write!(f, "{} conflicts with {}", value, conflict)
+            }
+            Error::IoError(ref e) => write!(f, "{}", e),
+            Error::PanicError => write!(f, "dispatcher panicked"),
+            Error::SelfTestError(ref check) => write!(f, "self-test {}", check),
+            Error::PublishConflictError(ref ip_addr, ref hardware_addr) => write!(
+                f,
+                "{} is owned by {}, publish another address or override by --force-publish",
+                ip_addr, hardware_addr
+            ),
+            Error::PrivilegeError(ref e, ref hint) => write!(f, "{}: {}", e, hint),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self {
+            Error::IoError(ref e) => Some(e),
+            Error::PrivilegeError(ref e, _) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(s: io::Error) -> Self {
+        Error::IoError(s)
+    }
+}
+
+/// Represents the summary of a run.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Summary {
+    /// Represents the count of frames captured
+    pub frames: usize,
+    /// Represents the count of frames sent
+    pub frames_sent: usize,
+    /// Represents the count of frames dropped by the capture filter
+    pub frames_filtered: usize,
+    /// Represents the count of frames dropped because of too many sources
+    pub frames_dropped: usize,
+}
+
+/// Represents the builder of redirecting traffic of sources to the proxy. Counters of metrics
+/// and events are shared by the whole process, so only one instance is expected to run at a
+/// time.
+pub struct Pcap2Socks {
+    opts: Opts,
+    device: Device,
+    /// Represents the devices bridged with the device
+    bridges: Vec<Device>,
+    src: (Ipv4Addr, u8),
+    publish: Option<(Ipv4Addr, u8)>,
+    events: Option<mpsc::Sender<Event>>,
+    hook: Option<Arc<dyn Hook>>,
+    clock: Option<Arc<dyn Clock>>,
+    /// Represents the flags in effect, whose configuration file is reloaded on requests
+    reload: Option<Flags>,
+    /// Represents the channel in place of the device and its emulated interface
+    channel: Option<(Interface, Sender, Receiver)>,
+}
+
+impl Pcap2Socks {
+    /// Creates a new `Pcap2Socks` redirecting traffic of the source to the proxy.
+    pub fn new(src: Ipv4Addr, proxy: Arc<dyn ProxyConnector>) -> Pcap2Socks {
+        let mut opts = Opts::new();
+        opts.proxy = proxy;
+
+        Pcap2Socks {
+            opts,
+            device: Device::Auto,
+            bridges: Vec::new(),
+            src: (src, 32),
+            publish: None,
+            events: None,
+            hook: None,
+            clock: None,
+            reload: None,
+            channel: None,
+        }
+    }
+
+    /// Creates a new `Pcap2Socks` from validated options.
+    pub fn from_opts(opts: Opts) -> Pcap2Socks {
+        let device = match (&opts.tun, opts.inter.first()) {
+            (Some(tun), _) => Device::Tun(tun.clone()),
+            (None, Some(inter)) => Device::parse(inter),
+            (None, None) => Device::Auto,
+        };
+        let bridges = opts
+            .inter
+            .iter()
+            .skip(1)
+            .map(|inter| Device::parse(inter))
+            .collect();
+        let src = (opts.src.ip(), opts.src.prefix());
+        let publish = opts.publish.map(|publish| (publish.ip(), publish.prefix()));
+
+        Pcap2Socks {
+            opts,
+            device,
+            bridges,
+            src,
+            publish,
+            events: None,
+            hook: None,
+            clock: None,
+            reload: None,
+            channel: None,
+        }
+    }
+
+    /// Sets the device frames are captured from and sent to.
+    pub fn device(mut self, device: Device) -> Pcap2Socks {
+        self.device = device;
+        self
+    }
+
+    /// Adds a device bridged with the device, frames to sources are sent out of the device they
+    /// are learned on. The device and the devices bridged are all Ethernet.
+    pub fn bridge(mut self, device: Device) -> Pcap2Socks {
+        self.bridges.push(device);
+        self
+    }
+
+    /// Sets the channel frames are captured from and sent to in place of the device, like the
+    /// in-memory channel of `pcap::channel`, with its emulated interface. A channel needs no
+    /// privileges, so the self-test, the probe of the published address and the drop of
+    /// privileges are skipped.
+    pub fn channel(mut self, inter: Interface, tx: Sender, rx: Receiver) -> Pcap2Socks {
+        self.channel = Some((inter, tx, rx));
+        self
+    }
+
+    /// Sets the source, an address or a network of at most 512 addresses.
+    pub fn source(mut self, ip_addr: Ipv4Addr, prefix: u8) -> Pcap2Socks {
+        self.src = (ip_addr, prefix);
+        self
+    }
+
+    /// Sets the published address or network, which is answered in ARP.
+    pub fn publish(mut self, ip_addr: Ipv4Addr, prefix: u8) -> Pcap2Socks {
+        self.publish = Some((ip_addr, prefix));
+        self
+    }
+
+    /// Sets if a single published address is published even if another host in the LAN owns
+    /// it, starting fails otherwise.
+    pub fn force_publish(mut self, is_force: bool) -> Pcap2Socks {
+        self.opts.force_publish = is_force;
+        self
+    }
+
+    /// Sets the published IPv6 address, which is answered in NDP and echoes.
+    pub fn publish6(mut self, ip_addr: Ipv6Addr) -> Pcap2Socks {
+        self.opts.publish6 = Some(ip_addr);
+        self
+    }
+
+    /// Sets the prefix of IPv6 router advertisements, which advertise local as the default
+    /// router of sources in the prefix of 64 bits.
+    pub fn ipv6_ra(mut self, prefix: Ipv6Network) -> Pcap2Socks {
+        self.opts.ipv6_ra = Some(prefix);
+        self
+    }
+
+    /// Sets the proxy.
+    pub fn proxy(mut self, proxy: Arc<dyn ProxyConnector>) -> Pcap2Socks {
+        self.opts.proxy = proxy;
+        self.opts.proxy_group = None;
+        self
+    }
+
+    /// Sets a group of proxies, new connections go to a healthy proxy of the group by the policy.
+    pub fn proxies(
+        mut self,
+        proxies: Vec<Arc<dyn ProxyConnector>>,
+        policy: ProxyPolicy,
+    ) -> Pcap2Socks {
+        let group = Arc::new(ProxyGroup::new(proxies, policy));
+        self.opts.proxy = Arc::clone(&group) as Arc<dyn ProxyConnector>;
+        self.opts.proxy_group = Some(group);
+        self
+    }
+
+    /// Sets the interval in seconds of health checks of proxies in a group.
+    pub fn proxy_check_interval(mut self, interval: u64) -> Pcap2Socks {
+        self.opts.proxy_check_interval = interval;
+        self
+    }
+
+    /// Sets the max number of idle connections pooled to each proxy, which are connected and
+    /// authenticated ahead, or 0 to never pool connections.
+    pub fn proxy_pool(mut self, size: usize) -> Pcap2Socks {
+        self.opts.proxy_pool = size;
+        self
+    }
+
+    /// Sets the MTU, which overrides the MTU of the device.
+    pub fn mtu(mut self, mtu: u16) -> Pcap2Socks {
+        self.opts.mtu = Some(mtu);
+        self
+    }
+
+    /// Sets the number of workers processing packets.
+    pub fn workers(mut self, workers: usize) -> Pcap2Socks {
+        self.opts.workers = workers;
+        self
+    }
+
+    /// Sets the rules deciding the action of destinations.
+    pub fn rules(mut self, rules: Rules) -> Pcap2Socks {
+        self.opts.rules = rules;
+        self
+    }
+
+    /// Sets the access lists of relayed traffic.
+    pub fn access_list(mut self, access_list: AccessList) -> Pcap2Socks {
+        self.opts.access_list = access_list;
+        self
+    }
+
+    /// Sets the blocklist file of destinations, which is checked before the access lists and
+    /// reloaded when it changes.
+    pub fn blocklist(mut self, path: String) -> Pcap2Socks {
+        self.opts.blocklist = Some(path);
+        self
+    }
+
+    /// Sets the rules rewriting destinations, which apply before the access lists and the rules
+    /// of destinations.
+    pub fn rewrites(mut self, rewrites: Rewrites) -> Pcap2Socks {
+        self.opts.rewrites = rewrites;
+        self
+    }
+
+    /// Sets the resolver DNS queries of sources are redirected to.
+    pub fn redirect_dns(mut self, resolver: SocketAddrV4) -> Pcap2Socks {
+        self.opts.redirect_dns = Some(resolver);
+        self
+    }
+
+    /// Sets if addresses are leased to sources by DHCP, which requires a single published
+    /// address.
+    pub fn dhcp(mut self, is_dhcp: bool) -> Pcap2Socks {
+        self.opts.dhcp = is_dhcp;
+        self
+    }
+
+    /// Sets if UPnP IGD and NAT-PMP requests of port mappings are answered, which requires a
+    /// single published address.
+    pub fn igd(mut self, is_igd: bool) -> Pcap2Socks {
+        self.opts.igd = is_igd;
+        self
+    }
+
+    /// Sets the port of the published address echoing UDP datagrams and TCP streams in local
+    /// without the proxy, which requires a single published address.
+    pub fn test_responder(mut self, port: u16) -> Pcap2Socks {
+        self.opts.test_responder = Some(port);
+        self
+    }
+
+    /// Sets if small TCP segments are sent immediately to sources and to the proxy, or they are
+    /// held while data is in flight.
+    pub fn no_delay(mut self, is_no_delay: bool) -> Pcap2Socks {
+        self.opts.no_delay = is_no_delay;
+        self
+    }
+
+    /// Sets the timeout of delayed TCP ACKs to sources, every segment is acknowledged
+    /// immediately if the timeout is 0.
+    pub fn delayed_ack(mut self, timeout: Duration) -> Pcap2Socks {
+        self.opts.delayed_ack = timeout.as_millis() as u64;
+        self
+    }
+
+    /// Sets the receive window of TCP connections advertised to sources, the default is derived
+    /// from the MSS.
+    pub fn recv_window(mut self, size: usize) -> Pcap2Socks {
+        self.opts.recv_window = Some(size);
+        self
+    }
+
+    /// Sets the max size of data of TCP connections not acknowledged by sources, the default is
+    /// derived from the MSS.
+    pub fn send_buffer(mut self, size: usize) -> Pcap2Socks {
+        self.opts.send_buffer = Some(size);
+        self
+    }
+
+    /// Sets the size of reads of TCP connections from the proxy, the default is derived from the
+    /// MSS.
+    pub fn relay_buffer(mut self, size: usize) -> Pcap2Socks {
+        self.opts.relay_buffer = Some(size);
+        self
+    }
+
+    /// Sets the memory budget of buffers, new buffers shrink when the memory is tight and the
+    /// least recently active TCP connection is reset when it is exhausted.
+    pub fn max_memory(mut self, size: usize) -> Pcap2Socks {
+        self.opts.max_memory = size;
+        self
+    }
+
+    /// Sets the max TCP connections of all sources and the max TCP connections of a source
+    /// waiting for the handshake.
+    pub fn max_connections(mut self, max_connections: usize, max_half_open: usize) -> Pcap2Socks {
+        self.opts.max_connections = max_connections;
+        self.opts.max_half_open = max_half_open;
+        self
+    }
+
+    /// Sets the retransmissions of TCP ACK/SYNs before the handshake with a source times out,
+    /// and the max interval between them, which starts from 1 second and is doubled.
+    pub fn syn_ack_retries(mut self, retries: usize, max_interval: Duration) -> Pcap2Socks {
+        self.opts.syn_ack_retries = retries;
+        self.opts.syn_ack_max_interval = max_interval.as_secs();
+        self
+    }
+
+    /// Sets if the least recently active TCP connection of a source is closed for a new one when
+    /// connections are full, or the new one will be reset.
+    pub fn evict_idle(mut self, is_evict_idle: bool) -> Pcap2Socks {
+        self.opts.evict_idle = is_evict_idle;
+        self
+    }
+
+    /// Sets the rules of destinations whose TCP connections are picked up mid-stream, like
+    /// connections established before a restart, or they will be reset.
+    pub fn pickup_established(mut self, pickup_established: Vec<Access>) -> Pcap2Socks {
+        self.opts.pickup_established = pickup_established;
+        self
+    }
+
+    /// Sets the mode of TCP and UDP checksums from sources.
+    pub fn checksum_mode(mut self, mode: ChecksumMode) -> Pcap2Socks {
+        self.opts.checksum_mode = mode;
+        self
+    }
+
+    /// Sets the interval of keepalives of SOCKS UDP associations.
+    pub fn udp_keepalive(mut self, keepalive: Duration) -> Pcap2Socks {
+        self.opts.udp_keepalive = Some(keepalive.as_secs());
+        self
+    }
+
+    /// Sets if SOCKS UDP associations are closed once their control connections are closed.
+    pub fn udp_assoc_strict(mut self, is_udp_assoc_strict: bool) -> Pcap2Socks {
+        self.opts.udp_assoc_strict = is_udp_assoc_strict;
+        self
+    }
+
+    /// Sets the endpoint and the interval of liveness probes through idle relays of SOCKS UDP
+    /// associations.
+    pub fn udp_probe(mut self, probe: SocketAddrV4, interval: Duration) -> Pcap2Socks {
+        self.opts.udp_probe = Some(probe);
+        self.opts.udp_probe_interval = interval.as_secs();
+        self
+    }
+
+    /// Sets the min bytes of UDP associations whose summaries are logged when they close.
+    pub fn udp_summary(mut self, threshold: u64) -> Pcap2Socks {
+        self.opts.udp_summary = Some(threshold);
+        self
+    }
+
+    /// Sets the local address or interface sockets to the proxy and targets are bound to.
+    pub fn outbound(mut self, outbound: Outbound) -> Pcap2Socks {
+        self.opts.outbound = Some(outbound);
+        self
+    }
+
+    /// Sets the range of local ports sockets to the proxy and targets are bound to.
+    pub fn local_port_range(mut self, range: PortRange) -> Pcap2Socks {
+        self.opts.local_port_range = Some(range);
+        self
+    }
+
+    /// Sets the range of translated ports of the NAT of flows of sources through the proxy.
+    pub fn nat_ports(mut self, range: PortRange) -> Pcap2Socks {
+        self.opts.nat_ports = Some(range);
+        self
+    }
+
+    /// Sets the directory captures of connections closed for errors are written to, and the
+    /// number of the last frames of each connection captured.
+    pub fn capture_on_error(mut self, dir: PathBuf, frames: usize) -> Pcap2Socks {
+        self.opts.capture_on_error = Some(dir);
+        self.opts.capture_frames = frames;
+        self
+    }
+
+    /// Sets the rules of flows whose packets are traced, and the max size in bytes of each packet
+    /// traced.
+    pub fn traces(mut self, traces: Vec<TraceRule>, max_size: usize) -> Pcap2Socks {
+        self.opts.traces = traces;
+        self.opts.trace_max_size = max_size;
+        self
+    }
+
+    /// Sets the level of the self-test before starting.
+    pub fn self_test(mut self, level: Level) -> Pcap2Socks {
+        self.opts.self_test = level;
+        self
+    }
+
+    /// Sets the endpoint connected through the proxy in the full self-test.
+    pub fn self_test_connect(mut self, connect: SocketAddrV4) -> Pcap2Socks {
+        self.opts.self_test_connect = connect;
+        self
+    }
+
+    /// Sets the UDP echo server datagrams are relayed to in the full self-test.
+    pub fn self_test_echo(mut self, echo: SocketAddrV4) -> Pcap2Socks {
+        self.opts.self_test_echo = Some(echo);
+        self
+    }
+
+    /// Sets the chaos impairing frames sent for testing.
+    pub fn chaos(mut self, chaos: Chaos) -> Pcap2Socks {
+        self.opts.chaos = Some(chaos);
+        self
+    }
+
+    /// Sets the source hardware address of frames sent to sources.
+    pub fn spoof_mac(mut self, spoof_mac: SpoofMac) -> Pcap2Socks {
+        self.opts.spoof_mac = spoof_mac;
+        self
+    }
+
+    /// Sets the mode of verifying frames sent to sources before they are transmitted.
+    pub fn verify_tx(mut self, mode: VerifyMode) -> Pcap2Socks {
+        self.opts.verify_tx = Some(mode);
+        self
+    }
+
+    /// Sets the fallback of UDP traffic when the proxy does not support UDP ASSOCIATE.
+    pub fn udp_fallback(mut self, fallback: UdpFallback) -> Pcap2Socks {
+        self.opts.udp_fallback = fallback;
+        self
+    }
+
+    /// Sets the companion endpoint UDP traffic is tunneled to in the TCP fallback.
+    pub fn udp_tunnel(mut self, tunnel: SocketAddrV4) -> Pcap2Socks {
+        self.opts.udp_tunnel = Some(tunnel);
+        self
+    }
+
+    /// Sets the count of local UDP sockets of each address family shared by UDP associations, or
+    /// 0 to bind a socket for each association.
+    pub fn udp_sockets(mut self, count: usize) -> Pcap2Socks {
+        self.opts.udp_sockets = count;
+        self
+    }
+
+    /// Sets the filtering of UDP datagrams to sources from peers.
+    pub fn udp_nat(mut self, nat: UdpNat) -> Pcap2Socks {
+        self.opts.udp_nat = nat;
+        self
+    }
+
+    /// Sets if broadcast and multicast UDP datagrams from sources are passed through to the LAN.
+    pub fn lan_passthrough(mut self, is_passthrough: bool) -> Pcap2Socks {
+        self.opts.lan_passthrough = is_passthrough;
+        self
+    }
+
+    /// Sets the file UDP associations are kept in across restarts. The associations are saved in
+    /// shutting down gracefully, and restored with the same local ports in starting.
+    pub fn state_file(mut self, path: String) -> Pcap2Socks {
+        self.opts.state_file = Some(path);
+        self
+    }
+
+    /// Sets the timeout of draining TCP connections in stopping.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Pcap2Socks {
+        self.opts.shutdown_timeout = timeout.as_secs();
+        self
+    }
+
+    /// Sets the timeout of reopening the device after it is lost, the device is not reopened if
+    /// it is not set.
+    pub fn device_retry(mut self, timeout: Duration) -> Pcap2Socks {
+        self.opts.device_retry = Some(timeout.as_secs().max(1));
+        self
+    }
+
+    /// Sets the user to run as after the device is opened on Unix, the capabilities and the
+    /// supplementary groups are dropped instead if it is not set.
+    pub fn run_as(mut self, user: &str) -> Pcap2Socks {
+        self.opts.run_as = Some(String::from(user));
+        self
+    }
+
+    /// Sets to keep the privileges of capturing after the device is opened.
+    pub fn keep_privileges(mut self) -> Pcap2Socks {
+        self.opts.keep_privileges = true;
+        self
+    }
+
+    /// Sets the rate limits in bytes per second from and to all the sources, and of each source
+    /// in either direction.
+    pub fn limits(
+        mut self,
+        up: Option<u64>,
+        down: Option<u64>,
+        per_client: Option<u64>,
+    ) -> Pcap2Socks {
+        self.opts.limit_up = up;
+        self.opts.limit_down = down;
+        self.opts.limit_per_client = per_client;
+        self
+    }
+
+    /// Sets the quotas of clients, the first quota containing a client is taken, and clients in
+    /// no quotas have the default quota.
+    pub fn client_quotas(mut self, quotas: Vec<ClientQuota>, default: Option<Quota>) -> Pcap2Socks {
+        self.opts.client_quotas = quotas;
+        self.opts.client_quota_default = default;
+        self
+    }
+
+    /// Sets the interval of printing statistics, the cumulative statistics are printed at exit.
+    pub fn stats_interval(mut self, interval: Duration) -> Pcap2Socks {
+        self.opts.stats_interval = Some(interval.as_secs().max(1));
+        self
+    }
+
+    /// Sets if bytes of TCP connections are counted by their destination ports and the names of
+    /// services sniffed in their first payloads.
+    pub fn classify(mut self, is_classify: bool) -> Pcap2Socks {
+        self.opts.classify = is_classify;
+        self
+    }
+
+    /// Sets the channel events of connections are sent to.
+    pub fn events(mut self, tx: mpsc::Sender<Event>) -> Pcap2Socks {
+        self.events = Some(tx);
+        self
+    }
+
+    /// Sets the hook observing and vetoing packets and connections of sources.
+    pub fn hook(mut self, hook: Arc<dyn Hook>) -> Pcap2Socks {
+        self.hook = Some(hook);
+        self
+    }
+
+    /// Sets the clock of timeouts of TCP and UDP, which is the monotonic clock of the system by
+    /// default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Pcap2Socks {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Sets the flags the options are validated from, which enables reloads of their
+    /// configuration file on requests. Rules, access lists, quotas of clients, log levels and
+    /// timeouts are reloaded, other settings require a restart.
+    pub fn reload(mut self, flags: Flags) -> Pcap2Socks {
+        self.reload = Some(flags);
+        self
+    }
+
+    /// Runs until the application shuts down, or the capture file ends.
+    pub fn run(self) -> Result<Summary, Error> {
+        let session = self.open()?;
+
+        session.dispatch(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Runs in the background, returns the handle which stops it. Errors in setting up are
+    /// returned immediately.
+    pub fn spawn(self) -> Result<Handle, Error> {
+        let session = self.open()?;
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let is_stopped_cloned = Arc::clone(&is_stopped);
+        let thread = thread::spawn(move || session.dispatch(is_stopped_cloned));
+
+        Ok(Handle {
+            is_stopped,
+            thread: Some(thread),
+        })
+    }
+
+    /// Validates the options and opens the device.
+    fn open(mut self) -> Result<Session, Error> {
+        let src = network(self.src)?;
+        if src.size() as usize > MAX_SOURCES {
+            return Err(Error::OutOfRangeError("source", "[/23, /32]"));
+        }
+        let publish = match self.publish {
+            Some(publish) => Some(network(publish)?),
+            None => None,
+        };
+        if let Some(mtu) = self.opts.mtu {
+            if mtu < 576 {
+                return Err(Error::OutOfRangeError("MTU", "[576, 65535]"));
+            }
+        }
+        if !(1..=MAX_WORKERS).contains(&self.opts.workers) {
+            return Err(Error::OutOfRangeError("workers", "[1, 64]"));
+        }
+        if let Some(ipv6_ra) = self.opts.ipv6_ra {
+            if ipv6_ra.prefix() != 64 {
+                return Err(Error::OutOfRangeError("IPv6 RA prefix", "[/64]"));
+            }
+        }
+        if self.opts.dhcp {
+            match publish {
+                Some(publish) if publish.prefix() == 32 => {}
+                _ => {
+                    return Err(Error::RequirementError(
+                        "DHCP",
+                        "a single publishing address",
+                    ))
+                }
+            }
+        }
+        if self.opts.igd {
+            match publish {
+                Some(publish) if publish.prefix() == 32 => {}
+                _ => {
+                    return Err(Error::RequirementError(
+                        "IGD",
+                        "a single publishing address",
+                    ))
+                }
+            }
+        }
+        if let Some(test_responder) = self.opts.test_responder {
+            if test_responder == 0 {
+                return Err(Error::OutOfRangeError("test responder port", "[1, 65535]"));
+            }
+            match publish {
+                Some(publish) if publish.prefix() == 32 => {}
+                _ => {
+                    return Err(Error::RequirementError(
+                        "test responder",
+                        "a single publishing address",
+                    ))
+                }
+            }
+        }
+        if let Device::Tun(_) = self.device {
+            if publish.is_some() {
+                return Err(Error::ConflictError("TUN device", "a publishing address"));
+            }
+            if self.opts.publish6.is_some() || self.opts.ipv6_ra.is_some() {
+                return Err(Error::ConflictError("TUN device", "NDP"));
+            }
+            if self.opts.input_file.is_some() {
+                return Err(Error::ConflictError("TUN device", "an input file"));
+            }
+        }
+        if self.channel.is_some() {
+            if let Device::Tun(_) = self.device {
+                return Err(Error::ConflictError("channel", "TUN device"));
+            }
+            if self.opts.input_file.is_some() {
+                return Err(Error::ConflictError("channel", "an input file"));
+            }
+        }
+        if !self.bridges.is_empty() {
+            if let Device::Tun(_) = self.device {
+                return Err(Error::ConflictError("bridged devices", "TUN device"));
+            }
+            if self.opts.input_file.is_some() {
+                return Err(Error::ConflictError("bridged devices", "an input file"));
+            }
+            if self.channel.is_some() {
+                return Err(Error::ConflictError("bridged devices", "channel"));
+            }
+            // A lost device is reopened alone
+            if self.opts.device_retry.is_some() {
+                return Err(Error::ConflictError("bridged devices", "device retry"));
+            }
+        }
+        if self.opts.udp_fallback == UdpFallback::Tcp && self.opts.udp_tunnel.is_none() {
+            return Err(Error::RequirementError("TCP UDP fallback", "a UDP tunnel"));
+        }
+        if self.opts.run_as.is_some() {
+            if self.opts.keep_privileges {
+                return Err(Error::ConflictError("run as", "keeping privileges"));
+            }
+            if self.opts.device_retry.is_some() {
+                return Err(Error::ConflictError("run as", "device retry"));
+            }
+            // Binding to interfaces requires CAP_NET_RAW
+            if let Some(Outbound::Interface(_)) = self.opts.outbound {
+                return Err(Error::ConflictError("run as", "an outbound interface"));
+            }
+            if let Some(range) = self.opts.local_port_range {
+                if range.get_lo() < 1024 {
+                    return Err(Error::ConflictError(
+                        "run as",
+                        "a local port range below 1024",
+                    ));
+                }
+            }
+        }
+        self.opts.src = src;
+        self.opts.publish = publish;
+        // UDP traffic falls back on the proxy connector, which is shared by all upstreamers
+        if self.opts.udp_fallback != UdpFallback::None {
+            self.opts.proxy = Arc::new(UdpFallbackConnector::new(
+                Arc::clone(&self.opts.proxy),
+                self.opts.udp_fallback,
+                self.opts.udp_tunnel,
+                Duration::from_secs(self.opts.proxy_timeout),
+            ));
+        }
+        let channel = self.channel.take();
+        let is_channel = channel.is_some();
+        let opts = &self.opts;
+
+        // TUN device, which is opened before the interface is determined as it is created if it
+        // does not exist
+        let tun = match self.device {
+            Device::Tun(ref name) => Some(open_tun(name)?),
+            _ => None,
+        };
+
+        // Interface
+        let inter = match channel {
+            Some((ref inter, _, _)) => inter.clone(),
+            None => interface(&self.device)?,
+        };
+        let local_ip_addr = *inter
+            .ip_addrs
+            .first()
+            .ok_or_else(|| Error::AddressError(self.device.clone()))?;
+        if inter.is_npcap_loopback() && opts.dhcp {
+            return Err(Error::ConflictError("Npcap loopback adapter", "DHCP"));
+        }
+        // Links without link headers carry no ARP, so the published address is not announced
+        let is_arp = opts.input_file.is_some() || inter.link_type == LinkType::Ethernet;
+        // Devices are bridged by hardware addresses
+        let mut bridged = Vec::with_capacity(self.bridges.len());
+        for device in self.bridges.iter() {
+            let bridged_inter = interface(device)?;
+            if inter.link_type != LinkType::Ethernet
+                || bridged_inter.link_type != LinkType::Ethernet
+            {
+                return Err(Error::RequirementError("bridged devices", "Ethernet"));
+            }
+            if bridged_inter.name == inter.name
+                || bridged
+                    .iter()
+                    .any(|other: &Interface| other.name == bridged_inter.name)
+            {
+                return Err(Error::ConflictError("bridged device", "itself"));
+            }
+            bridged.push(bridged_inter);
+        }
+        match opts.input_file {
+            Some(ref input_file) => {
+                info!("Read from {} as {}", input_file, inter);
+                if opts.replay_timing {
+                    info!("Replay in the original timing");
+                }
+                match opts.output_file {
+                    Some(ref output_file) => info!("Write to {}", output_file),
+                    None => info!("Discard frames sent"),
+                }
+            }
+            None if tun.is_some() || is_channel => info!("Attach to {}", inter),
+            None => info!("Listen on {}", inter),
+        }
+        for bridged_inter in bridged.iter() {
+            info!("Bridge {}", bridged_inter);
+        }
+        if let Some(ref log_file) = opts.log_file {
+            info!("Log to {} in the {} format", log_file, opts.log_format);
+        }
+        // Frames to sources are sent from the spoofed hardware address, which is answered in ARP
+        // too, so sources see the address only
+        let hardware_addr = match opts.spoof_mac {
+            SpoofMac::Own => inter.hardware_addr,
+            _ if !is_arp => return Err(Error::RequirementError("spoof MAC", "Ethernet")),
+            SpoofMac::Gateway => {
+                let gateway = gateway::find_gateway(&inter.name)?;
+                info!("Spoof the MAC of gateway {}", gateway);
+                warn!(
+                    "Gateway {} is reachable, sources may receive frames of both the gateway and {}",
+                    gateway.ip_addr, gateway.hardware_addr
+                );
+                gateway.hardware_addr
+            }
+            SpoofMac::Addr(hardware_addr) => {
+                info!("Spoof the MAC {}", hardware_addr);
+                if let Ok(gateway) = gateway::find_gateway(&inter.name) {
+                    if gateway.hardware_addr == hardware_addr {
+                        warn!(
+                            "Gateway {} is reachable, sources may receive frames of both the gateway and {}",
+                            gateway.ip_addr, hardware_addr
+                        );
+                    }
+                }
+                hardware_addr
+            }
+        };
+        // Capture filter
+        let mut filter = filter::internal(opts.src);
+        if let Some(ref user_filter) = opts.filter {
+            filter = filter.and(user_filter.clone());
+        }
+        info!("Capture filter {}", filter);
+        if let Some(ref dump) = opts.dump {
+            info!("Dump to {}", dump);
+            if let Some(dump_max_size) = opts.dump_max_size {
+                info!("Dump max size {} MB", dump_max_size);
+            }
+            if let Some(dump_filter) = opts.dump_filter {
+                info!("Dump filter {}", dump_filter);
+            }
+        }
+        if let Some(ref capture_on_error) = opts.capture_on_error {
+            info!(
+                "Capture the last {} frames of connections closed for errors to {}",
+                opts.capture_frames,
+                capture_on_error.display()
+            );
+        }
+        for trace in opts.traces.iter() {
+            info!("Trace {} up to {} Bytes", trace, opts.trace_max_size);
+        }
+        // The MTU comes from the device, and the MTU designated overrides it
+        let device_mtu = match tun {
+            Some((_, _, mtu)) => Some(mtu),
+            None if opts.input_file.is_some() => None,
+            None if is_channel => inter.mtu,
+            None => match pcap::device::get_mtu(&inter.name) {
+                Ok(mtu) => Some(mtu),
+                Err(ref e) => {
+                    warn!("get MTU of {}: {}", inter.name, e);
+                    None
+                }
+            },
+        };
+        let mtu = match (opts.mtu, device_mtu) {
+            (Some(mtu), Some(device_mtu)) => {
+                if mtu > device_mtu {
+                    warn!(
+                        "MTU {} exceeds the MTU {} of {}, frames larger than it may be dropped",
+                        mtu, device_mtu, inter.name
+                    );
+                }
+                mtu
+            }
+            (Some(mtu), None) => mtu,
+            (None, Some(device_mtu)) if device_mtu < 576 => {
+                warn!(
+                    "MTU {} of {} is less than 576, use 576",
+                    device_mtu, inter.name
+                );
+                576
+            }
+            (None, Some(device_mtu)) => device_mtu,
+            (None, None) => DEFAULT_MTU,
+        };
+        for bridged_inter in bridged.iter() {
+            if let Ok(bridged_mtu) = pcap::device::get_mtu(&bridged_inter.name) {
+                if mtu > bridged_mtu {
+                    warn!(
+                        "MTU {} exceeds the MTU {} of {}, frames larger than it may be dropped",
+                        mtu, bridged_mtu, bridged_inter.name
+                    );
+                }
+            }
+        }
+        info!("MTU {}", mtu);
+        // The MSS for clamping never exceeds the MSS allowed by the MTU
+        let max_mss = mtu - IPV4_TCP_HEADER_SIZE;
+        let mss = match opts.mss {
+            Some(mss) if mss > max_mss => {
+                warn!(
+                    "MSS {} exceeds the MSS {} allowed by the MTU, use {}",
+                    mss, max_mss, max_mss
+                );
+                Some(max_mss)
+            }
+            mss => mss,
+        };
+        if let Some(mss) = mss {
+            info!("MSS {}", mss);
+        }
+        if opts.tx_batch > 1 {
+            info!("TX batch {}", opts.tx_batch);
+        }
+        info!("Minimum RTO {} ms", opts.min_rto);
+        info!("Congestion control {}", opts.cc);
+        if let Some(tcp_keepalive) = opts.tcp_keepalive {
+            info!("TCP keepalive {} s", tcp_keepalive);
+        }
+        if opts.no_delay {
+            info!("TCP no delay");
+        }
+        info!("TCP delayed ACK {} ms", opts.delayed_ack);
+        info!(
+            "Max TCP connections {}, {} half-open",
+            opts.max_connections, opts.max_half_open
+        );
+        info!(
+            "TCP ACK/SYN retries {}, up to {} s apart",
+            opts.syn_ack_retries, opts.syn_ack_max_interval
+        );
+        let (default_recv_window, default_send_buffer, default_relay_buffer) =
+            default_tcp_buffer_sizes(mss.unwrap_or(max_mss) as usize);
+        let buffer_sizes = (
+            opts.recv_window.unwrap_or(default_recv_window),
+            opts.send_buffer.unwrap_or(default_send_buffer),
+            opts.relay_buffer.unwrap_or(default_relay_buffer),
+        );
+        let (recv_window, send_buffer, relay_buffer) = buffer_sizes;
+        // Buffers grow on demand, so the memory is the worst case when all the buffers are full
+        let memory =
+            (recv_window + send_buffer + relay_buffer).saturating_mul(opts.max_connections);
+        info!(
+            "TCP receive window {} Bytes (window scale {}), send buffer {} Bytes, relay buffer {} \
+             Bytes, up to {} MB of {} connections",
+            recv_window,
+            tcp_window_scale_of(recv_window),
+            send_buffer,
+            relay_buffer,
+            memory / (1024 * 1024),
+            opts.max_connections
+        );
+        memory::set_budget(opts.max_memory);
+        info!("Memory budget {} MB", opts.max_memory / (1024 * 1024));
+        if memory > opts.max_memory {
+            warn!(
+                "Buffers of {} TCP connections may take up to {} MB, which exceeds the max memory \
+                 {} MB, buffers will shrink when the memory is tight",
+                opts.max_connections,
+                memory / (1024 * 1024),
+                opts.max_memory / (1024 * 1024)
+            );
+        }
+        if opts.evict_idle {
+            info!("Evict the least recently active TCP connection when connections are full");
+        }
+        for access in opts.pickup_established.iter() {
+            info!("Pick up {} connections mid-stream", access);
+        }
+        // Sources leased by DHCP use the published address as the DNS server, queries to which
+        // are redirected to the resolver
+        let redirect_dns = match opts.redirect_dns {
+            Some(redirect_dns) => Some(redirect_dns),
+            None if opts.dhcp => Some(DEFAULT_DNS_RESOLVER),
+            None => None,
+        };
+        if let Some(redirect_dns) = redirect_dns {
+            info!("Redirect DNS to {}", redirect_dns);
+        }
+        if !opts.bind_ports.is_empty() {
+            let ports: Vec<String> = opts
+                .bind_ports
+                .iter()
+                .map(|port| port.to_string())
+                .collect();
+            info!("Bind ports {}", ports.join(", "));
+        }
+        if let Some(ref group) = opts.proxy_group {
+            info!(
+                "Proxy policy {}, check proxies every {} s",
+                group.get_policy(),
+                opts.proxy_check_interval
+            );
+        }
+        info!("Proxy timeout {} s", opts.proxy_timeout);
+        if let Some(relay_timeout) = opts.relay_timeout {
+            info!("Relay timeout {} s", relay_timeout);
+        }
+        if let Some(relay_stale) = opts.relay_stale {
+            info!("Relay stale timeout {} s", relay_stale);
+        }
+        match opts.linger {
+            Some(0) => info!("Reset TCP connections to the proxy closed for errors"),
+            Some(linger) => info!(
+                "Read TCP connections to the proxy closed for errors for up to {} s",
+                linger
+            ),
+            None => {}
+        }
+        info!("Connect retries {}", opts.connect_retries);
+        if opts.proxy_pool > 0 {
+            info!("Pool {} idle connections to each proxy", opts.proxy_pool);
+        }
+        if let Some(ref outbound) = opts.outbound {
+            info!("Bind sockets to the {}", outbound);
+        }
+        if let Some(range) = opts.local_port_range {
+            info!("Bind sockets to local ports {}", range);
+        }
+        if let Some(range) = opts.nat_ports {
+            info!("Translate flows to NAT ports {}", range);
+        }
+        info!("UDP timeout {} s", opts.udp_timeout);
+        if let Some(udp_keepalive) = opts.udp_keepalive {
+            info!("UDP keepalive {} s", udp_keepalive);
+        }
+        if opts.udp_assoc_strict {
+            info!("Close UDP associations once their control connections are closed");
+        }
+        if let Some(udp_probe) = opts.udp_probe {
+            info!(
+                "Probe idle UDP relays through {} every {} s",
+                udp_probe, opts.udp_probe_interval
+            );
+        }
+        if let Some(udp_summary) = opts.udp_summary {
+            info!("UDP summary of at least {} Bytes", udp_summary);
+        }
+        info!("UDP NAT {}", opts.udp_nat);
+        if opts.udp_fallback != UdpFallback::None {
+            info!("UDP fallback {}", opts.udp_fallback);
+        }
+        if let Some(udp_tunnel) = opts.udp_tunnel {
+            info!("UDP tunnel {}", udp_tunnel);
+        }
+        if !opts.udp_frag {
+            info!("Drop fragmented SOCKS UDP datagrams");
+        }
+        if opts.udp_pmtu {
+            info!("Probe path MTU to UDP relays");
+        }
+        if opts.udp_send_frag {
+            info!("Send UDP datagrams larger than the path MTU in SOCKS fragments");
+        }
+        match opts.udp_sockets {
+            0 => info!("Bind a UDP socket for each UDP association"),
+            count => info!(
+                "Share {} UDP sockets of each family by UDP associations",
+                count
+            ),
+        }
+        if opts.lan_passthrough {
+            info!("Pass broadcast and multicast UDP datagrams through to the LAN");
+        }
+        if let Some(ref state_file) = opts.state_file {
+            info!("Keep UDP associations in {}", state_file);
+        }
+        info!("IPv4 reassembly timeout {} s", opts.reassembly_timeout);
+        info!("Checksum mode {}", opts.checksum_mode);
+        info!("Shutdown timeout {} s", opts.shutdown_timeout);
+        if let Some(limit_up) = opts.limit_up {
+            info!("Limit up {}/s", rate_string(limit_up));
+        }
+        if let Some(limit_down) = opts.limit_down {
+            info!("Limit down {}/s", rate_string(limit_down));
+        }
+        if let Some(limit_per_client) = opts.limit_per_client {
+            info!("Limit per client {}/s", rate_string(limit_per_client));
+        }
+        for quota in opts.client_quotas.iter() {
+            info!("Quota of {}", quota);
+        }
+        if let Some(quota) = opts.client_quota_default {
+            info!("Default quota {}", quota);
+        }
+        info!("Workers {}", opts.workers);
+        if let Some(device_retry) = opts.device_retry {
+            if opts.input_file.is_none() {
+                info!("Device retry {} s", device_retry);
+            }
+        }
+
+        // Publish
+        if let Some(publish) = opts.publish {
+            info!("Publish {}", network_string(publish));
+            if opts.force_publish {
+                info!("Publish even if another host owns it");
+            }
+            if let Some(arp_interval) = opts.arp_interval {
+                info!("ARP interval {} s", arp_interval);
+            }
+        }
+        if let Some(publish6) = opts.publish6 {
+            info!("Publish {} by NDP", publish6);
+        }
+        if let Some(ipv6_ra) = opts.ipv6_ra {
+            info!("Advertise IPv6 router with prefix {}", ipv6_ra);
+        }
+        if opts.dhcp {
+            info!("Lease {} by DHCP", network_string(opts.src));
+            if opts.dhcp_force {
+                info!("Lease even if another DHCP server is present");
+            }
+        }
+        if opts.igd {
+            info!(
+                "Answer port mappings by UPnP IGD and NAT-PMP on {}",
+                network_string(opts.src)
+            );
+        }
+        if let (Some(test_responder), Some(publish)) = (opts.test_responder, opts.publish) {
+            info!(
+                "Echo UDP and TCP on {}:{} without the proxy",
+                publish.ip(),
+                test_responder
+            );
+        }
+
+        // Proxy
+        info!("Proxy {} to {}", network_string(opts.src), opts.proxy);
+        if let Some(ref auth_source) = opts.auth_source {
+            info!("Authentication from {}", auth_source);
+            if auth_source.is_world_readable() {
+                warn!("{} is readable by others", auth_source);
+            }
+        }
+        for rule in opts.rules.get_rules() {
+            info!("Rule {}", rule);
+        }
+        if !opts.rules.get_rules().is_empty() || opts.rules.get_default() != Action::Proxy {
+            info!("Default action {}", opts.rules.get_default());
+        }
+        for access in opts.access_list.get_allow() {
+            info!("Allow {}", access);
+        }
+        for access in opts.access_list.get_deny() {
+            info!("Deny {}", access);
+        }
+        if !opts.access_list.is_empty() && opts.access_list.is_silent() {
+            info!("Drop denied traffic silently");
+        }
+        if let Some(ref blocklist) = opts.blocklist {
+            info!("Block destinations in {}", blocklist);
+        }
+        for rewrite in opts.rewrites.get_rewrites() {
+            info!("Rewrite {}", rewrite);
+        }
+        let (tx, mut rx) = match opts.input_file {
+            Some(ref input_file) => open_file(
+                input_file,
+                hardware_addr,
+                &opts.output_file,
+                opts.replay_timing,
+            )?,
+            None => match (tun, channel) {
+                (Some((tx, rx, _)), _) => link::wrap(tx, rx, LinkType::Raw, hardware_addr),
+                (None, Some((_, tx, rx))) => (tx, rx),
+                (None, None) => {
+                    let diagnosis = privilege::check_system();
+                    if let Some(ref diagnosis) = diagnosis {
+                        warn!("{}", diagnosis);
+                    }
+                    let open = |inter: &Interface| {
+                        inter.open().map_err(|e| match e.kind() {
+                            io::ErrorKind::PermissionDenied | io::ErrorKind::Other => {
+                                let hint = match diagnosis {
+                                    Some(ref diagnosis) => diagnosis.get_hint().to_string(),
+                                    None => {
+                                        privilege::get_open_hint(Platform::current()).to_string()
+                                    }
+                                };
+
+                                Error::PrivilegeError(e, hint)
+                            }
+                            _ => Error::IoError(e),
+                        })
+                    };
+                    let (tx, rx) = open(&inter)?;
+                    match bridged.is_empty() {
+                        true => (tx, rx),
+                        false => {
+                            let mut devices = vec![(inter.name.clone(), tx, rx)];
+                            for bridged_inter in bridged.iter() {
+                                let (tx, rx) = open(bridged_inter)?;
+                                devices.push((bridged_inter.name.clone(), tx, rx));
+                            }
+                            let (tx, rx, bridge) = bridge::bridge(devices, hardware_addr);
+                            bridge::set_bridge(bridge);
+
+                            (tx, rx)
+                        }
+                    }
+                }
+            },
+        };
+        let mut queue = SendQueue::new(tx, opts.tx_batch);
+        if opts.input_file.is_none() {
+            queue.set_mtu(mtu);
+        }
+        if opts.input_file.is_none() && !is_channel {
+            // Self-test
+            info!("Self-test {}", opts.self_test);
+            let mut self_test = SelfTest::new(
+                opts.self_test,
+                hardware_addr,
+                local_ip_addr,
+                mtu,
+                opts.proxy.as_ref(),
+            );
+            self_test.set_arp(is_arp);
+            self_test.set_proxy_timeout(Duration::from_secs(opts.proxy_timeout));
+            if opts.self_test == Level::Full {
+                self_test.set_connect(opts.self_test_connect);
+                if let Some(echo) = opts.self_test_echo {
+                    self_test.set_echo(echo);
+                }
+            }
+            let checks = self_test.run(&mut queue, &mut rx);
+            if let Some(check) = selftest::report(&checks) {
+                return Err(Error::SelfTestError(check.to_string()));
+            }
+
+            // Another host owning the published address would fight local with ARP
+            if let Some(publish) = opts.publish {
+                if is_arp && publish.prefix() == 32 {
+                    if let Some(owner) =
+                        arp::probe(&mut queue, &mut rx, hardware_addr, publish.ip())?
+                    {
+                        if !opts.force_publish {
+                            return Err(Error::PublishConflictError(publish.ip(), owner));
+                        }
+                        warn!("{} is owned by {}, publish it anyway", publish.ip(), owner);
+                    }
+                }
+            }
+        }
+        if let Some(chaos) = opts.chaos {
+            queue.set_chaos(chaos);
+            warn!("Impair frames sent by chaos {}", queue.get_chaos().unwrap());
+        }
+        if let Some(mode) = opts.verify_tx {
+            warn!("Verify frames sent in {}, which is slow", mode);
+        }
+        let tx = Arc::new(Mutex::new(queue));
+        let dumper = match opts.dump {
+            Some(ref dump) => {
+                let mut dumper = Dumper::create(dump)?;
+                if let Some(dump_max_size) = opts.dump_max_size {
+                    dumper.set_max_size(dump_max_size * 1024 * 1024);
+                }
+                if let Some(dump_filter) = opts.dump_filter {
+                    dumper.set_filter(dump_filter);
+                }
+                let dumper = Arc::new(Mutex::new(dumper));
+                tx.lock().unwrap().set_dumper(Arc::clone(&dumper));
+
+                Some(dumper)
+            }
+            None => None,
+        };
+        if let Some(ref capture_on_error) = opts.capture_on_error {
+            fs::create_dir_all(capture_on_error)?;
+            capture::set_capture_on_error(capture_on_error.clone(), opts.capture_frames);
+        }
+        if opts.tx_batch > 1 || opts.chaos.is_some() {
+            crate::open_flusher(Arc::clone(&tx));
+        }
+        if !opts.access_list.is_empty() {
+            metrics::set_access_list(opts.access_list.clone());
+        }
+        socks::set_outbound(opts.outbound.clone());
+        socks::set_port_range(opts.local_port_range);
+        socks::set_shared_sockets(opts.udp_sockets);
+        socks::set_proxy_pool(opts.proxy_pool);
+        opts.proxy.fill_pool();
+        nat::set_nat_range(opts.nat_ports);
+        if let Some(ref group) = opts.proxy_group {
+            ProxyGroup::check_in_background(group, Duration::from_secs(opts.proxy_check_interval));
+            metrics::set_proxy_group(Arc::clone(group));
+        }
+        if let Some(udp_summary) = opts.udp_summary {
+            event::set_udp_summary(udp_summary);
+        }
+        if let Some(metrics) = opts.metrics {
+            metrics::serve(metrics)?;
+            info!("Serve metrics on http://{}/metrics", metrics);
+        }
+        if let Some(stats_interval) = opts.stats_interval {
+            info!("Print statistics every {} s", stats_interval);
+        }
+        classify::set_enabled(opts.classify);
+        if opts.classify {
+            info!("Classify TCP connections by ports and names of services");
+        }
+        let is_bind_supported = opts.proxy.is_bind_supported();
+        if !opts.bind_ports.is_empty() && !is_bind_supported {
+            warn!(
+                "{} does not support BIND, inbound connections are disabled",
+                opts.proxy
+            );
+        }
+        if opts.igd && !is_bind_supported {
+            warn!(
+                "{} does not support BIND, TCP port mappings are refused",
+                opts.proxy
+            );
+        }
+        let is_subscribed = match self.events {
+            Some(events) => {
+                event::subscribe(events);
+                true
+            }
+            None => false,
+        };
+        if let Some(ref hook) = self.hook {
+            hook::set_hook(Arc::clone(hook));
+        }
+
+        // Privileges, which are dropped once everything needing them is opened
+        if opts.input_file.is_none() && !is_channel {
+            if opts.keep_privileges {
+                info!("Keep privileges");
+            } else if opts.device_retry.is_some() {
+                info!("Keep privileges for reopening the device");
+            } else if opts
+                .local_port_range
+                .map_or(false, |range| range.get_lo() < 1024)
+            {
+                info!("Keep privileges for binding local ports below 1024");
+            } else if let Some(Outbound::Interface(_)) = opts.outbound {
+                info!("Keep privileges for binding to the outbound interface");
+            } else {
+                let dropped = privilege::drop_privileges(opts.run_as.as_deref())?;
+                if dropped != Dropped::Nothing {
+                    info!("Drop {}", dropped);
+                }
+            }
+        }
+
+        Ok(Session {
+            opts: self.opts,
+            device: self.device,
+            filter,
+            mtu,
+            buffer_sizes,
+            redirect_dns,
+            is_bind_supported,
+            is_arp,
+            hardware_addr,
+            local_ip_addr,
+            tx,
+            rx,
+            dumper,
+            is_subscribed,
+            hook: self.hook,
+            clock: self.clock,
+            reload: self.reload,
+        })
+    }
+}
+
+/// Represents the handle of a `Pcap2Socks` running in the background. Dropping the handle stops
+/// it and waits for it.
+pub struct Handle {
+    is_stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<Summary, Error>>>,
+}
+
+impl Handle {
+    /// Stops gracefully, TCP connections are drained in the shutdown timeout.
+    pub fn stop(&self) {
+        self.is_stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Requests a reload of the configuration file, which requires the flags set by
+    /// `Pcap2Socks::reload`.
+    pub fn reload(&self) {
+        reload::request();
+    }
+
+    /// Get a snapshot of the metrics.
+    pub fn get_stats(&self) -> Stats {
+        metrics::stats()
+    }
+
+    /// Waits until it stops, or the capture file ends.
+    pub fn join(mut self) -> Result<Summary, Error> {
+        match self.thread.take().unwrap().join() {
+            Ok(result) => result,
+            Err(_) => Err(Error::PanicError),
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Represents the device and the options opened, which are moved to the thread of the
+/// dispatcher.
+struct Session {
+    opts: Opts,
+    device: Device,
+    filter: Expression,
+    mtu: u16,
+    /// Represents the receive window, the send buffer and the relay buffer of TCP connections
+    buffer_sizes: (usize, usize, usize),
+    redirect_dns: Option<SocketAddrV4>,
+    is_bind_supported: bool,
+    is_arp: bool,
+    hardware_addr: HardwareAddr,
+    local_ip_addr: Ipv4Addr,
+    tx: Arc<Mutex<SendQueue>>,
+    rx: Receiver,
+    dumper: Option<Arc<Mutex<Dumper>>>,
+    is_subscribed: bool,
+    hook: Option<Arc<dyn Hook>>,
+    clock: Option<Arc<dyn Clock>>,
+    reload: Option<Flags>,
+}
+
+impl Session {
+    /// Dispatches frames until the application shuts down or the flag is set.
+    fn dispatch(self, is_stopped: Arc<AtomicBool>) -> Result<Summary, Error> {
+        let Session {
+            opts,
+            device,
+            filter,
+            mtu,
+            buffer_sizes,
+            redirect_dns,
+            is_bind_supported,
+            is_arp,
+            hardware_addr,
+            local_ip_addr,
+            tx,
+            mut rx,
+            dumper,
+            is_subscribed,
+            hook,
+            clock,
+            reload,
+        } = self;
+        let src = opts.src;
+        let publish = opts.publish;
+        let publish6 = opts.publish6;
+        let ipv6_ra = opts.ipv6_ra;
+        let arp_interval = opts.arp_interval;
+        let dhcp = opts.dhcp;
+        let dhcp_force = opts.dhcp_force;
+        // The IGD server is shared by the dispatcher and the upstreamers
+        let igd_server = match publish {
+            Some(publish) if opts.igd && publish.prefix() == 32 => {
+                let server = igd::Server::open(publish.ip(), src, is_bind_supported)?;
+                let remote = opts.proxy.get_remote();
+                if igd::is_public(*remote.ip()) {
+                    server.set_external_ip_addr(*remote.ip());
+                }
+
+                Some(Arc::new(server))
+            }
+            _ => None,
+        };
+        let factory_igd_server = igd_server.clone();
+        // The test responder is shared by the upstreamers
+        let responder = match opts.test_responder {
+            Some(port) => Some(Arc::new(Responder::open(port)?)),
+            None => None,
+        };
+        // The blocklist is shared by the upstreamers and reloaded in the background
+        let blocklist = match opts.blocklist {
+            Some(ref path) => Some(Arc::new(BlocklistFile::open(Path::new(path))?)),
+            None => None,
+        };
+        let is_hooked = hook.is_some();
+        let workers = opts.workers;
+        let shutdown_timeout = opts.shutdown_timeout;
+        let checksum_mode = opts.checksum_mode;
+        let lan_passthrough = opts.lan_passthrough;
+        let state_file = opts.state_file.clone();
+        let udp_timeout = Duration::from_secs(opts.udp_timeout);
+        let stats_interval = opts.stats_interval;
+        // Capture files are never lost
+        let device_retry = match opts.input_file {
+            Some(_) => None,
+            None => opts.device_retry,
+        };
+        let queue = Arc::clone(&tx);
+        let mut shaper = Shaper::new(opts.limit_up, opts.limit_down, opts.limit_per_client);
+        shaper.set_quotas(opts.client_quotas.clone(), opts.client_quota_default);
+        // The shaper is shared with the reloader, which replaces the quotas of clients
+        let shaper = Arc::new(shaper);
+        let reloader = reload.map(|flags| Reloader::open(flags, Arc::clone(&shaper)));
+        // Packets are never matched against trace rules without any rule
+        let tracer = match opts.traces.len() {
+            0 => None,
+            _ => Some(Arc::new(Tracer::new(
+                opts.traces.clone(),
+                opts.trace_max_size,
+            ))),
+        };
+        // Upstreamers of a source in workers share the downstreamer of the source
+        let mut downstreamers = HashMap::new();
+        let factory = move |src| {
+            let tx = downstreamers.entry(src).or_insert_with(|| {
+                let mut downstreamer =
+                    Downstreamer::new(Arc::clone(&queue), mtu, hardware_addr, src, local_ip_addr);
+                if let Some(mss) = opts.mss {
+                    downstreamer.set_mss(mss);
+                }
+                downstreamer.set_min_rto(Duration::from_millis(opts.min_rto));
+                downstreamer.set_cc(opts.cc);
+                downstreamer.set_nagle(!opts.no_delay);
+                downstreamer.set_delayed_ack(Duration::from_millis(opts.delayed_ack));
+                let (recv_window, send_buffer, relay_buffer) = buffer_sizes;
+                downstreamer.set_tcp_recv_window(recv_window);
+                downstreamer.set_tcp_send_buffer(send_buffer);
+                downstreamer.set_relay_buffer(relay_buffer);
+                if let Some(ref tracer) = tracer {
+                    downstreamer.set_tracer(Arc::clone(tracer));
+                }
+                if let Some(mode) = opts.verify_tx {
+                    downstreamer.set_verify_tx(mode);
+                }
+                if let Some(ref hook) = hook {
+                    downstreamer.set_hook(Arc::clone(hook));
+                }
+                if let Some(ref clock) = clock {
+                    downstreamer.set_clock(Arc::clone(clock));
+                }
+
+                Arc::new(Mutex::new(downstreamer))
+            });
+            let mut upstreamer =
+                Upstreamer::new(Arc::clone(tx), src, opts.publish, Arc::clone(&opts.proxy));
+            upstreamer.set_rules(opts.rules.clone());
+            if !opts.access_list.is_empty() {
+                upstreamer.set_access_list(opts.access_list.clone());
+            }
+            if let Some(ref blocklist) = blocklist {
+                upstreamer.set_blocklist(Arc::clone(blocklist));
+            }
+            if !opts.rewrites.is_empty() {
+                upstreamer.set_rewrites(opts.rewrites.clone());
+            }
+            if let Some(redirect_dns) = redirect_dns {
+                upstreamer.set_dns_resolver(redirect_dns);
+            }
+            if !opts.bind_ports.is_empty() && is_bind_supported {
+                upstreamer.set_bind_ports(opts.bind_ports.clone());
+            }
+            if let Some(ref server) = factory_igd_server {
+                upstreamer.set_igd_server(Arc::clone(server));
+            }
+            if let Some(ref responder) = responder {
+                upstreamer.set_responder(Arc::clone(responder));
+            }
+            if let Some(tcp_keepalive) = opts.tcp_keepalive {
+                upstreamer.set_keepalive(Duration::from_secs(tcp_keepalive));
+            }
+            upstreamer.set_nodelay(opts.no_delay);
+            upstreamer.set_max_connections(opts.max_connections, opts.max_half_open);
+            upstreamer.set_syn_ack_retries(
+                opts.syn_ack_retries,
+                Duration::from_secs(opts.syn_ack_max_interval),
+            );
+            upstreamer.set_evict_idle(opts.evict_idle);
+            if !opts.pickup_established.is_empty() {
+                upstreamer.set_pickup_established(opts.pickup_established.clone());
+            }
+            upstreamer.set_proxy_timeout(Duration::from_secs(opts.proxy_timeout));
+            if let Some(relay_timeout) = opts.relay_timeout {
+                upstreamer.set_relay_timeout(Duration::from_secs(relay_timeout));
+            }
+            if let Some(relay_stale) = opts.relay_stale {
+                upstreamer.set_relay_stale(Duration::from_secs(relay_stale));
+            }
+            if let Some(linger) = opts.linger {
+                upstreamer.set_linger(Duration::from_secs(linger));
+            }
+            upstreamer.set_connect_retries(opts.connect_retries);
+            upstreamer.set_udp_timeout(Duration::from_secs(opts.udp_timeout));
+            upstreamer.set_udp_nat(opts.udp_nat);
+            if let Some(udp_keepalive) = opts.udp_keepalive {
+                upstreamer.set_udp_keepalive(Duration::from_secs(udp_keepalive));
+            }
+            upstreamer.set_udp_assoc_strict(opts.udp_assoc_strict);
+            if let Some(udp_probe) = opts.udp_probe {
+                upstreamer.set_udp_probe(udp_probe, Duration::from_secs(opts.udp_probe_interval));
+            }
+            upstreamer.set_udp_fragmentation(opts.udp_frag);
+            upstreamer.set_udp_pmtu(opts.udp_pmtu);
+            upstreamer.set_udp_send_fragmentation(opts.udp_send_frag);
+            upstreamer.set_reassembly_timeout(Duration::from_secs(opts.reassembly_timeout));
+            upstreamer.set_checksum_mode(opts.checksum_mode);
+            if let Some(ref tracer) = tracer {
+                upstreamer.set_tracer(Arc::clone(tracer));
+            }
+            if shaper.is_enabled() {
+                upstreamer.set_shaper(&shaper);
+            }
+            if let Some(ref hook) = hook {
+                upstreamer.set_hook(Arc::clone(hook));
+            }
+            if let Some(ref clock) = clock {
+                upstreamer.set_clock(Arc::clone(clock));
+            }
+
+            upstreamer
+        };
+        let mut dispatcher = Dispatcher::new(
+            Box::new(factory),
+            src,
+            workers,
+            Arc::clone(&tx),
+            hardware_addr,
+        );
+        dispatcher.set_filter(filter);
+        dispatcher.set_checksum_mode(checksum_mode);
+        dispatcher.set_lan_passthrough(lan_passthrough);
+        // A corrupt state file never stops starting, the associations are lost as without it
+        if let Some(ref state_file) = state_file {
+            match state::load(Path::new(state_file), udp_timeout) {
+                Ok(states) => {
+                    if !states.is_empty() {
+                        info!("Load {} UDP associations from {}", states.len(), state_file);
+                    }
+                    dispatcher.set_udp_states(states);
+                }
+                Err(ref e) => {
+                    warn!("Ignore state file {}: {}", state_file, e);
+                    dispatcher.set_udp_states(Vec::new());
+                }
+            }
+        }
+        // Hosts in the LAN are resolved by ARP
+        if is_arp {
+            dispatcher.set_local_ip_addr(local_ip_addr);
+        }
+        dispatcher.set_shutdown_timeout(Duration::from_secs(shutdown_timeout));
+        dispatcher.set_stop(is_stopped);
+        if let Some(dumper) = dumper {
+            dispatcher.set_dumper(dumper);
+        }
+        if let Some(device_retry) = device_retry {
+            dispatcher.set_reopen(
+                Duration::from_secs(device_retry),
+                Box::new(move || reopen(&device, hardware_addr)),
+            );
+        }
+        if let Some(publish) = publish {
+            if publish.prefix() == 32 {
+                if is_arp {
+                    dispatcher
+                        .set_announcement(publish.ip(), arp_interval.map(Duration::from_secs));
+                }
+                if dhcp {
+                    let mut server = dhcp::Server::new(publish.ip(), src, publish.ip());
+                    server.set_force(dhcp_force);
+                    dispatcher.set_dhcp_server(server);
+                }
+            }
+        }
+        if let Some(server) = igd_server {
+            dispatcher.set_igd_server(server);
+        }
+        // NDP is in link headers like ARP
+        if is_arp {
+            if let Some(publish6) = publish6 {
+                dispatcher.set_ndp_publish(publish6);
+            }
+            if let Some(ipv6_ra) = ipv6_ra {
+                dispatcher.set_router_advertisement(ipv6_ra);
+            }
+        }
+        let printer = stats_interval.map(|interval| Printer::open(Duration::from_secs(interval)));
+        let refresher = Refresher::open();
+        let result = dispatcher.open(&mut rx);
+        refresher.close();
+        if let Some(reloader) = reloader {
+            reloader.close();
+        }
+        socks::drain_pools();
+        if let Some(printer) = printer {
+            printer.close();
+        }
+        if is_subscribed {
+            event::unsubscribe();
+        }
+        if is_hooked {
+            hook::unset_hook();
+        }
+        if let Some(ref state_file) = state_file {
+            if let Some(states) = dispatcher.take_udp_states() {
+                match state::save(Path::new(state_file), &states) {
+                    Ok(_) => info!("Save {} UDP associations to {}", states.len(), state_file),
+                    Err(ref e) => warn!("Save state file {}: {}", state_file, e),
+                }
+            }
+        }
+        result?;
+
+        let tx = tx.lock().unwrap();
+        if tx.get_chaos().is_some() {
+            info!(
+                "Chaos dropped {} frames and reordered {} frames",
+                tx.get_chaos_drop_count(),
+                tx.get_chaos_reorder_count()
+            );
+        }
+        Ok(Summary {
+            frames: dispatcher.get_frame_count(),
+            frames_sent: tx.get_batched_count() + tx.get_immediate_count(),
+            frames_filtered: dispatcher.get_filter_drop_count(),
+            frames_dropped: dispatcher.get_src_drop_count(),
+        })
+    }
+}
+
+/// Get the network of the address and the prefix length.
+fn network((ip_addr, prefix): (Ipv4Addr, u8)) -> Result<Ipv4Network, Error> {
+    Ipv4Network::new(ip_addr, prefix).map_err(|_| Error::NetworkError(ip_addr, prefix))
+}
+
+/// Gets the available network interface of the device.
+fn interface(device: &Device) -> Result<Interface, Error> {
+    select_interface(device, crate::interfaces())
+}
+
+/// Selects the network interface of the device in the interfaces, which are listed in the
+/// order of their indexes.
+pub fn select_interface(device: &Device, mut inters: Vec<Interface>) -> Result<Interface, Error> {
+    match device {
+        Device::Auto => {
+            inters.retain(|inter| inter.is_up);
+            if inters.len() != 1 {
+                return Err(Error::UndeterminedInterfaceError);
+            }
+        }
+        Device::Name(ref name) => inters.retain(|inter| {
+            &inter.name == name || inter.alias.as_ref().map_or(false, |alias| alias == name)
+        }),
+        Device::Tun(ref name) => inters.retain(|inter| &inter.name == name),
+        Device::Address(ref ip_addr) => inters.retain(|inter| inter.ip_addrs.contains(ip_addr)),
+        Device::Index(index) => {
+            inters = match index.checked_sub(1) {
+                Some(i) if i < inters.len() => vec![inters.swap_remove(i)],
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    match inters.len() {
+        1 => Ok(inters.remove(0)),
+        // The TUN device exists as it is opened, but it has no IPv4 address
+        _ if matches!(device, Device::Tun(_)) => Err(Error::AddressError(device.clone())),
+        0 => Err(Error::InterfaceNotFoundError(device.clone())),
+        _ => Err(Error::AmbiguousInterfaceError(device.clone())),
+    }
+}
+
+/// Reopens the device after it is lost.
+fn reopen(device: &Device, hardware_addr: HardwareAddr) -> io::Result<(Sender, Receiver)> {
+    let to_io_error = |e: Error| io::Error::new(io::ErrorKind::Other, e.to_string());
+
+    match device {
+        Device::Tun(ref name) => {
+            let (tx, rx, _) = open_tun(name).map_err(to_io_error)?;
+
+            Ok(link::wrap(tx, rx, LinkType::Raw, hardware_addr))
+        }
+        _ => interface(device).map_err(to_io_error)?.open(),
+    }
+}
+
+/// Opens the capture file for reading, and the capture file for writing if there is one.
+fn open_file(
+    input_file: &str,
+    hardware_addr: HardwareAddr,
+    output_file: &Option<String>,
+    is_timing: bool,
+) -> io::Result<(Sender, Receiver)> {
+    let mut rx = pcap::file::FileReceiver::open(input_file)?;
+    rx.set_timing(is_timing);
+    let link_type = rx.get_link_type();
+    if link_type != LinkType::Ethernet {
+        info!("Link type {}", link_type);
+    }
+    // Frames sent are written in the link type of the input file
+    let tx = match output_file {
+        Some(output_file) => pcap::file::FileSender::create(output_file, link_type)?,
+        None => pcap::file::FileSender::discard(),
+    };
+
+    Ok(link::wrap(
+        Box::new(tx),
+        Box::new(rx),
+        link_type,
+        hardware_addr,
+    ))
+}
+
+/// Opens the TUN device, returns the channel of IP packets and the MTU of the device.
+#[cfg(all(target_os = "linux", feature = "tun"))]
+fn open_tun(name: &str) -> Result<(Sender, Receiver, u16), Error> {
+    let (tx, rx) = pcap::tun::open(name)?;
+    let mtu = pcap::device::get_mtu(name)?;
+
+    Ok((Box::new(tx), Box::new(rx), mtu))
+}
+
+/// Opens the TUN device, which is not supported without the tun feature on Linux.
+#[cfg(not(all(target_os = "linux", feature = "tun")))]
+fn open_tun(_: &str) -> Result<(Sender, Receiver, u16), Error> {
+    Err(Error::RequirementError(
+        "TUN device",
+        "the tun feature on Linux",
+    ))
+}
+
+/// Get the string of a network, a single address is shown without the prefix length.
+fn network_string(network: Ipv4Network) -> String {
+    match network.prefix() {
+        32 => network.ip().to_string(),
+        _ => network.to_string(),
+    }
+}
+
+/// Formats a rate in bytes in the largest unit it is a multiple of.
+fn rate_string(rate: u64) -> String {
+    match rate {
+        rate if rate % (1 << 30) == 0 => format!("{} GB", rate >> 30),
+        rate if rate % (1 << 20) == 0 => format!("{} MB", rate >> 20),
+        rate if rate % (1 << 10) == 0 => format!("{} KB", rate >> 10),
+        rate => format!("{} B", rate),
+    }
+}
@@ -0,0 +1,597 @@
+//! Token buckets limiting the rate of traffic globally and per client, and quotas of clients.
+
+use ipnetwork::Ipv4Network;
+use log::info;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Represents the interval of the coarse clock in milliseconds.
+const CLOCK_INTERVAL: u64 = 10;
+/// Represents the burst of a bucket in milliseconds of its rate.
+const BURST: u64 = 100;
+/// Represents the min burst of a bucket, which holds a read or a write of a stream.
+const MIN_BURST: u64 = u16::MAX as u64 + 1;
+/// Represents the size of UDP datagrams allowed over the budget before they are dropped.
+const UDP_QUEUE_SIZE: u64 = 16 * 1024;
+/// Represents the min interval of logging the exhaustion of a quota of a client in milliseconds.
+const QUOTA_LOG_INTERVAL: u64 = 30 * 1000;
+
+/// Represents the coarse clock in milliseconds since it starts.
+static CLOCK: AtomicU64 = AtomicU64::new(0);
+static CLOCK_TICKER: Once = Once::new();
+
+/// Starts the thread of the coarse clock, which ticks every `CLOCK_INTERVAL` milliseconds so
+/// buckets never read the system time.
+fn start_clock() {
+    CLOCK_TICKER.call_once(|| {
+        let start = Instant::now();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(CLOCK_INTERVAL));
+            CLOCK.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+        });
+    });
+}
+
+/// Get the coarse clock in milliseconds.
+fn now() -> u64 {
+    CLOCK.load(Ordering::Relaxed)
+}
+
+/// Represents the state of a token bucket.
+#[derive(Debug)]
+struct BucketState {
+    /// Represents the tokens in bytes, which are negative if the bucket is in debt
+    tokens: i64,
+    /// Represents the coarse clock of the last refill
+    instant: u64,
+    /// Represents the count of times the bucket goes into debt
+    exhaustions: u64,
+    /// Represents the coarse clock the exhaustion is logged
+    log_instant: Option<u64>,
+}
+
+/// Represents a token bucket refilled at a rate in bytes per second.
+#[derive(Debug)]
+pub struct Bucket {
+    rate: u64,
+    burst: u64,
+    /// Represents the name of the bucket whose exhaustion is logged
+    name: Option<String>,
+    state: Mutex<BucketState>,
+}
+
+impl Bucket {
+    /// Creates a new `Bucket` of the rate in bytes per second, which is full.
+    pub fn new(rate: u64) -> Bucket {
+        start_clock();
+        let rate = rate.max(1);
+        let burst = (rate * BURST / 1000).max(MIN_BURST);
+
+        Bucket {
+            rate,
+            burst,
+            name: None,
+            state: Mutex::new(BucketState {
+                tokens: burst as i64,
+                instant: now(),
+                exhaustions: 0,
+                log_instant: None,
+            }),
+        }
+    }
+
+    /// Creates a new `Bucket` of the rate in bytes per second whose exhaustion is logged with the
+    /// name.
+    pub fn with_name(rate: u64, name: String) -> Bucket {
+        Bucket {
+            name: Some(name),
+            ..Bucket::new(rate)
+        }
+    }
+
+    /// Get the rate of the bucket in bytes per second.
+    pub fn get_rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Get the count of times the bucket goes into debt.
+    pub fn get_exhaustions(&self) -> u64 {
+        self.state.lock().unwrap().exhaustions
+    }
+
+    /// Refills the bucket by the time elapsed and returns the tokens.
+    fn refill(&self, state: &mut BucketState) -> i64 {
+        let now = now();
+        let elapsed = now.saturating_sub(state.instant);
+        if elapsed > 0 {
+            let refilled = self.rate.saturating_mul(elapsed) / 1000;
+            let tokens = state
+                .tokens
+                .saturating_add(refilled.min(i64::MAX as u64) as i64);
+            state.tokens = tokens.min(self.burst as i64);
+            state.instant = now;
+        }
+
+        state.tokens
+    }
+
+    /// Returns if the bucket has tokens.
+    pub fn is_available(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        self.refill(&mut state) > 0
+    }
+
+    /// Takes the size of tokens from the bucket, which may go into debt. Returns the time before
+    /// the debt is paid.
+    pub fn consume(&self, size: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let available = self.refill(&mut state);
+        let tokens = available - size as i64;
+        state.tokens = tokens;
+        if available >= 0 && tokens < 0 {
+            self.exhaust(&mut state);
+        }
+
+        if tokens >= 0 {
+            Duration::from_millis(0)
+        } else {
+            Duration::from_millis((-tokens) as u64 * 1000 / self.rate)
+        }
+    }
+
+    /// Returns if the size of tokens can be taken from the bucket with the debt staying in the UDP
+    /// queue.
+    fn has_room(&self, size: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        self.refill(&mut state) - size as i64 >= -(UDP_QUEUE_SIZE as i64)
+    }
+
+    /// Counts the bucket going into debt, and logs it at most once every `QUOTA_LOG_INTERVAL`.
+    fn exhaust(&self, state: &mut BucketState) {
+        state.exhaustions += 1;
+        if let Some(ref name) = self.name {
+            let now = now();
+            match state.log_instant {
+                Some(instant) if now.saturating_sub(instant) < QUOTA_LOG_INTERVAL => {}
+                _ => {
+                    state.log_instant = Some(now);
+                    info!(
+                        "{} exhausts its quota of {}/s ({} times)",
+                        name,
+                        rate_string(self.rate),
+                        state.exhaustions
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Represents the buckets limiting a flow in a direction.
+#[derive(Clone, Debug, Default)]
+pub struct Limit {
+    buckets: Vec<Arc<Bucket>>,
+}
+
+impl Limit {
+    /// Returns if the flow is not limited.
+    pub fn is_unlimited(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Returns if all the buckets have tokens.
+    pub fn is_available(&self) -> bool {
+        self.buckets.iter().all(|bucket| bucket.is_available())
+    }
+
+    /// Takes the size of tokens from all the buckets. Returns the time before the flow can send
+    /// again.
+    pub fn consume(&self, size: usize) -> Duration {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.consume(size))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Takes the size of tokens from all the buckets for a datagram. Returns `false` if the
+    /// datagram exceeds the UDP queue of any bucket and should be dropped.
+    pub fn try_consume(&self, size: usize) -> bool {
+        if !self.buckets.iter().all(|bucket| bucket.has_room(size)) {
+            return false;
+        }
+        for bucket in self.buckets.iter() {
+            bucket.consume(size);
+        }
+
+        true
+    }
+}
+
+/// Represents the max TCP connections of a client, which is shared by the flows of the client in
+/// all the workers.
+#[derive(Debug)]
+pub struct ConnectionQuota {
+    client: Ipv4Addr,
+    max: usize,
+    count: AtomicUsize,
+    /// Represents the count of connections reset over the quota
+    rejections: AtomicU64,
+    /// Represents the coarse clock the exhaustion is logged
+    log_instant: Mutex<Option<u64>>,
+}
+
+impl ConnectionQuota {
+    /// Creates a new `ConnectionQuota`.
+    pub fn new(client: Ipv4Addr, max: usize) -> ConnectionQuota {
+        start_clock();
+        ConnectionQuota {
+            client,
+            max,
+            count: AtomicUsize::new(0),
+            rejections: AtomicU64::new(0),
+            log_instant: Mutex::new(None),
+        }
+    }
+
+    /// Get the max TCP connections of the client.
+    pub fn get_max(&self) -> usize {
+        self.max
+    }
+
+    /// Get the count of TCP connections of the client.
+    pub fn get_count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Get the count of TCP connections of the client reset over the quota.
+    pub fn get_rejections(&self) -> u64 {
+        self.rejections.load(Ordering::Relaxed)
+    }
+
+    /// Takes a TCP connection from the quota. Returns `false` if the quota is exhausted, and the
+    /// exhaustion is logged at most once every `QUOTA_LOG_INTERVAL`.
+    pub fn acquire(&self) -> bool {
+        let is_acquired = self
+            .count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                if count < self.max {
+                    Some(count + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok();
+        if !is_acquired {
+            let rejections = self.rejections.fetch_add(1, Ordering::Relaxed) + 1;
+
+            let now = now();
+            let mut log_instant = self.log_instant.lock().unwrap();
+            match *log_instant {
+                Some(instant) if now.saturating_sub(instant) < QUOTA_LOG_INTERVAL => {}
+                _ => {
+                    *log_instant = Some(now);
+                    info!(
+                        "Client {} exhausts its quota of {} TCP connections ({} reset)",
+                        self.client, self.max, rejections
+                    );
+                }
+            }
+        }
+
+        is_acquired
+    }
+
+    /// Takes a TCP connection from the quota even if the quota is exhausted, like an inbound
+    /// connection which is never reset.
+    pub fn force_acquire(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Gives a TCP connection back to the quota.
+    pub fn release(&self) {
+        let _ = self
+            .count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            });
+    }
+}
+
+/// Represents the limits of a flow in both directions.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    /// Represents the limit from the source to the destination
+    pub up: Limit,
+    /// Represents the limit from the destination to the source
+    pub down: Limit,
+    /// Represents the quota of TCP connections of the client
+    pub connections: Option<Arc<ConnectionQuota>>,
+    /// Represents the buckets of the rate quota of the client from and to the client
+    pub quota: Option<(Arc<Bucket>, Arc<Bucket>)>,
+}
+
+/// Represents the quota of a client, with the rate in bytes per second in either direction and
+/// the max TCP connections.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Quota {
+    rate: Option<u64>,
+    connections: Option<usize>,
+}
+
+impl Quota {
+    /// Creates a new `Quota`.
+    pub fn new(rate: Option<u64>, connections: Option<usize>) -> Quota {
+        Quota { rate, connections }
+    }
+
+    /// Parses a quota from comma separated settings like `50mbit,20conns`. A rate is in bits per
+    /// second with an optional suffix kbit, mbit or gbit in multiples of 1000, and a count of
+    /// TCP connections ends with `conns`. `unlimited` is a quota without any limit.
+    pub fn parse(s: &str) -> Option<Quota> {
+        let mut quota = Quota::default();
+        if s.trim().eq_ignore_ascii_case("unlimited") {
+            return Some(quota);
+        }
+        for setting in s
+            .split(',')
+            .map(|setting| setting.trim().to_ascii_lowercase())
+        {
+            if let Some(connections) = setting.strip_suffix("conns") {
+                if quota.connections.is_some() {
+                    return None;
+                }
+                quota.connections = Some(connections.trim().parse().ok()?);
+            } else {
+                if quota.rate.is_some() {
+                    return None;
+                }
+                quota.rate = Some(parse_bit_rate(&setting)?);
+            }
+        }
+
+        Some(quota)
+    }
+
+    /// Get the rate in bytes per second in either direction.
+    pub fn get_rate(&self) -> Option<u64> {
+        self.rate
+    }
+
+    /// Get the max TCP connections.
+    pub fn get_connections(&self) -> Option<usize> {
+        self.connections
+    }
+
+    /// Returns if the quota has no limit.
+    pub fn is_unlimited(&self) -> bool {
+        self.rate.is_none() && self.connections.is_none()
+    }
+}
+
+impl Display for Quota {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match (self.rate, self.connections) {
+            (Some(rate), Some(connections)) => {
+                write!(f, "{}/s, {} connections", rate_string(rate), connections)
+            }
+            (Some(rate), None) => write!(f, "{}/s", rate_string(rate)),
+            (None, Some(connections)) => write!(f, "{} connections", connections),
+            (None, None) => write!(f, "unlimited"),
+        }
+    }
+}
+
+/// Represents the quota of clients in a network.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClientQuota {
+    network: Ipv4Network,
+    quota: Quota,
+}
+
+impl ClientQuota {
+    /// Creates a new `ClientQuota`.
+    pub fn new(network: Ipv4Network, quota: Quota) -> ClientQuota {
+        ClientQuota { network, quota }
+    }
+
+    /// Parses a quota of clients like `10.6.0.2=50mbit,20conns`, the clients are an address or a
+    /// network in CIDR notation.
+    pub fn parse(s: &str) -> Option<ClientQuota> {
+        let mut kv = s.splitn(2, '=');
+        let network = kv.next()?.trim().parse().ok()?;
+        let quota = Quota::parse(kv.next()?)?;
+
+        Some(ClientQuota::new(network, quota))
+    }
+
+    /// Returns if the client is in the network of the quota.
+    pub fn contains(&self, client: Ipv4Addr) -> bool {
+        self.network.contains(client)
+    }
+}
+
+impl Display for ClientQuota {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.network.prefix() == 32 {
+            write!(f, "{}: {}", self.network.ip(), self.quota)
+        } else {
+            write!(f, "{}: {}", self.network, self.quota)
+        }
+    }
+}
+
+/// Parses a rate in bits per second with an optional suffix kbit, mbit or gbit in multiples of
+/// 1000, in bytes per second.
+fn parse_bit_rate(s: &str) -> Option<u64> {
+    let (value, unit) = match s {
+        s if s.ends_with("kbit") => (&s[..s.len() - 4], 1_000),
+        s if s.ends_with("mbit") => (&s[..s.len() - 4], 1_000_000),
+        s if s.ends_with("gbit") => (&s[..s.len() - 4], 1_000_000_000),
+        s if s.ends_with("bit") => (&s[..s.len() - 3], 1),
+        _ => return None,
+    };
+    let value: u64 = value.trim().parse().ok()?;
+    let rate = value.checked_mul(unit)? / 8;
+    if rate == 0 {
+        return None;
+    }
+
+    Some(rate)
+}
+
+/// Returns the rate in bytes per second in bits per second with a suffix.
+fn rate_string(rate: u64) -> String {
+    let bits = rate.saturating_mul(8);
+    match bits {
+        bits if bits % 1_000_000_000 == 0 => format!("{} Gbit", bits / 1_000_000_000),
+        bits if bits % 1_000_000 == 0 => format!("{} Mbit", bits / 1_000_000),
+        bits if bits % 1_000 == 0 => format!("{} Kbit", bits / 1_000),
+        bits => format!("{} bit", bits),
+    }
+}
+
+/// Represents the buckets and the quota of TCP connections of a client.
+#[derive(Clone, Debug, Default)]
+struct Client {
+    per_client: Option<(Arc<Bucket>, Arc<Bucket>)>,
+    quota: Option<(Arc<Bucket>, Arc<Bucket>)>,
+    connections: Option<Arc<ConnectionQuota>>,
+}
+
+/// Represents the rate limits and the quotas of the application, which are shared by all the
+/// flows.
+#[derive(Debug)]
+pub struct Shaper {
+    up: Option<Arc<Bucket>>,
+    down: Option<Arc<Bucket>>,
+    per_client: Option<u64>,
+    quotas: Vec<ClientQuota>,
+    default_quota: Option<Quota>,
+    clients: Mutex<HashMap<Ipv4Addr, Client>>,
+}
+
+impl Shaper {
+    /// Creates a new `Shaper` of the global rates from and to the sources, and the rate of each
+    /// client in both directions, in bytes per second.
+    pub fn new(up: Option<u64>, down: Option<u64>, per_client: Option<u64>) -> Shaper {
+        Shaper {
+            up: up.map(|rate| Arc::new(Bucket::new(rate))),
+            down: down.map(|rate| Arc::new(Bucket::new(rate))),
+            per_client,
+            quotas: Vec::new(),
+            default_quota: None,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the quotas of clients, the first quota containing a client is taken, and clients in
+    /// no quotas have the default quota.
+    pub fn set_quotas(&mut self, quotas: Vec<ClientQuota>, default: Option<Quota>) {
+        self.quotas = quotas;
+        self.default_quota = default;
+    }
+
+    /// Creates a new `Shaper` with the quotas of clients replaced, like in a reload. The global
+    /// rates are shared, and clients whose quotas are unchanged keep their buckets and counts of
+    /// TCP connections, while other clients take their new quotas at their next flows.
+    pub fn reload_quotas(&self, quotas: Vec<ClientQuota>, default: Option<Quota>) -> Shaper {
+        let mut shaper = Shaper {
+            up: self.up.clone(),
+            down: self.down.clone(),
+            per_client: self.per_client,
+            quotas,
+            default_quota: default,
+            clients: Mutex::new(HashMap::new()),
+        };
+        let clients = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(&client, _)| shaper.get_quota(client) == self.get_quota(client))
+            .map(|(&client, entry)| (client, entry.clone()))
+            .collect();
+        shaper.clients = Mutex::new(clients);
+
+        shaper
+    }
+
+    /// Returns if any limit is set.
+    pub fn is_enabled(&self) -> bool {
+        self.up.is_some()
+            || self.down.is_some()
+            || self.per_client.is_some()
+            || self.quotas.iter().any(|quota| !quota.quota.is_unlimited())
+            || self
+                .default_quota
+                .map_or(false, |quota| !quota.is_unlimited())
+    }
+
+    /// Get the limits of a flow of the client. The quota of a client is matched once when the
+    /// client is seen first, and kept in the table of clients.
+    pub fn get_limits(&self, client: Ipv4Addr) -> Limits {
+        let mut limits = Limits::default();
+        if let Some(ref up) = self.up {
+            limits.up.buckets.push(Arc::clone(up));
+        }
+        if let Some(ref down) = self.down {
+            limits.down.buckets.push(Arc::clone(down));
+        }
+
+        let mut clients = self.clients.lock().unwrap();
+        let entry = clients
+            .entry(client)
+            .or_insert_with(|| self.new_client(client));
+        if let Some((ref up, ref down)) = entry.per_client {
+            limits.up.buckets.push(Arc::clone(up));
+            limits.down.buckets.push(Arc::clone(down));
+        }
+        if let Some((ref up, ref down)) = entry.quota {
+            limits.up.buckets.push(Arc::clone(up));
+            limits.down.buckets.push(Arc::clone(down));
+            limits.quota = Some((Arc::clone(up), Arc::clone(down)));
+        }
+        limits.connections = entry.connections.as_ref().map(Arc::clone);
+
+        limits
+    }
+
+    /// Get the quota of the client.
+    pub fn get_quota(&self, client: Ipv4Addr) -> Option<Quota> {
+        self.quotas
+            .iter()
+            .find(|quota| quota.contains(client))
+            .map(|quota| quota.quota)
+            .or(self.default_quota)
+    }
+
+    fn new_client(&self, client: Ipv4Addr) -> Client {
+        let per_client = self
+            .per_client
+            .map(|rate| (Arc::new(Bucket::new(rate)), Arc::new(Bucket::new(rate))));
+        let quota = self.get_quota(client).unwrap_or_default();
+        let rate = quota.rate.map(|rate| {
+            (
+                Arc::new(Bucket::with_name(rate, format!("Client {} up", client))),
+                Arc::new(Bucket::with_name(rate, format!("Client {} down", client))),
+            )
+        });
+        let connections = quota
+            .connections
+            .map(|max| Arc::new(ConnectionQuota::new(client, max)));
+
+        Client {
+            per_client,
+            quota: rate,
+            connections,
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use log::{debug, info, warn};
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{SocketAddrV4, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+use super::BoxFuture;
+use super::{ProxyConnector, SocksDatagram, SocksListener, UnreachableError};
+
+/// Represents the default interval of health checks of proxies in a group in seconds.
+pub const DEFAULT_PROXY_CHECK_INTERVAL: u64 = 10;
+/// Represents the timeout of a health check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Represents the policy of choosing a proxy of a group for new connections.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProxyPolicy {
+    /// Represents new connections go to the first healthy proxy.
+    Failover,
+    /// Represents new connections go to healthy proxies in turn.
+    RoundRobin,
+}
+
+impl ProxyPolicy {
+    /// Parses a proxy policy from its name.
+    pub fn parse(s: &str) -> Option<ProxyPolicy> {
+        match s {
+            "failover" => Some(ProxyPolicy::Failover),
+            "round-robin" => Some(ProxyPolicy::RoundRobin),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ProxyPolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            ProxyPolicy::Failover => write!(f, "failover"),
+            ProxyPolicy::RoundRobin => write!(f, "round-robin"),
+        }
+    }
+}
+
+/// Represents the state of a proxy of a group.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProxyState {
+    /// Represents the name of the proxy
+    pub name: String,
+    /// Represents if the proxy is healthy
+    pub is_up: bool,
+    /// Represents the count of connections through the proxy
+    pub flows: u64,
+}
+
+/// Represents a proxy of a group with its health. The health is updated by health checks and by
+/// connections through the proxy, so a failing proxy is avoided before the next check.
+struct Member {
+    proxy: Arc<dyn ProxyConnector>,
+    is_up: AtomicBool,
+    flows: AtomicU64,
+}
+
+impl Member {
+    fn set_up(&self, is_up: bool) {
+        if self.is_up.swap(is_up, Ordering::Relaxed) != is_up {
+            match is_up {
+                true => info!("Proxy {} is up", self.proxy),
+                false => warn!("Proxy {} is down", self.proxy),
+            }
+        }
+        // Connections pooled to a failing proxy are likely dead as well
+        if !is_up {
+            self.proxy.drain_pool();
+        }
+    }
+
+    /// Updates the health by the result of a connection through the proxy. Only errors of
+    /// the proxy itself mark it down, but not errors of the target like a refused connection.
+    fn report<T>(&self, result: io::Result<T>) -> io::Result<T> {
+        match result {
+            Ok(t) => {
+                self.flows.fetch_add(1, Ordering::Relaxed);
+                self.set_up(true);
+
+                Ok(t)
+            }
+            Err(e) => {
+                if UnreachableError::is_unreachable(&e) || e.kind() == io::ErrorKind::TimedOut {
+                    self.set_up(false);
+                }
+
+                Err(e)
+            }
+        }
+    }
+}
+
+impl ProxyConnector for Member {
+    fn get_remote(&self) -> SocketAddrV4 {
+        self.proxy.get_remote()
+    }
+
+    fn handshake(&self, stream: &mut TcpStream, dst: SocketAddrV4) -> io::Result<()> {
+        self.proxy.handshake(stream, dst)
+    }
+
+    fn connect(&self, dst: SocketAddrV4, timeout: Duration) -> io::Result<TcpStream> {
+        self.report(self.proxy.connect(dst, timeout))
+    }
+
+    #[cfg(feature = "async")]
+    fn handshake_async<'a>(
+        &'a self,
+        stream: &'a mut tokio::net::TcpStream,
+        dst: SocketAddrV4,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        self.proxy.handshake_async(stream, dst)
+    }
+
+    #[cfg(feature = "async")]
+    fn connect_async(
+        &self,
+        dst: SocketAddrV4,
+        timeout: Duration,
+    ) -> BoxFuture<'_, io::Result<tokio::net::TcpStream>> {
+        Box::pin(async move { self.report(self.proxy.connect_async(dst, timeout).await) })
+    }
+
+    fn bind(&self, local_src: SocketAddrV4) -> io::Result<SocksDatagram> {
+        self.report(self.proxy.bind(local_src))
+    }
+
+    fn is_udp_supported(&self) -> bool {
+        self.proxy.is_udp_supported()
+    }
+
+    fn is_bind_supported(&self) -> bool {
+        self.proxy.is_bind_supported()
+    }
+
+    fn listen(&self, dst: SocketAddrV4, timeout: Duration) -> io::Result<SocksListener> {
+        self.report(self.proxy.listen(dst, timeout))
+    }
+
+    fn check(&self, timeout: Duration) -> io::Result<()> {
+        self.proxy.check(timeout)
+    }
+
+    fn fill_pool(&self) {
+        self.proxy.fill_pool()
+    }
+
+    fn drain_pool(&self) {
+        self.proxy.drain_pool()
+    }
+}
+
+impl Display for Member {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.proxy)
+    }
+}
+
+/// Represents a group of proxies, new connections go to a healthy proxy by the policy and
+/// existing connections stay on their proxies. Proxies are up until they fail.
+pub struct ProxyGroup {
+    members: Vec<Arc<Member>>,
+    policy: ProxyPolicy,
+    /// Represents the next proxy in the round-robin policy
+    cursor: AtomicUsize,
+}
+
+impl ProxyGroup {
+    /// Creates a new `ProxyGroup`, which has at least a proxy.
+    pub fn new(proxies: Vec<Arc<dyn ProxyConnector>>, policy: ProxyPolicy) -> ProxyGroup {
+        ProxyGroup {
+            members: proxies
+                .into_iter()
+                .map(|proxy| {
+                    Arc::new(Member {
+                        proxy,
+                        is_up: AtomicBool::new(true),
+                        flows: AtomicU64::new(0),
+                    })
+                })
+                .collect(),
+            policy,
+            cursor: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the policy of the group.
+    pub fn get_policy(&self) -> ProxyPolicy {
+        self.policy
+    }
+
+    /// Get the states of the proxies of the group.
+    pub fn get_states(&self) -> Vec<ProxyState> {
+        self.members
+            .iter()
+            .map(|member| ProxyState {
+                name: member.to_string(),
+                is_up: member.is_up.load(Ordering::Relaxed),
+                flows: member.flows.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Checks the health of the proxies in the interval on its own thread, which stops after the
+    /// group is dropped.
+    pub fn check_in_background(group: &Arc<ProxyGroup>, interval: Duration) {
+        let group: Weak<ProxyGroup> = Arc::downgrade(group);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let group = match group.upgrade() {
+                Some(group) => group,
+                None => break,
+            };
+            for member in group.members.iter() {
+                match member.check(CHECK_TIMEOUT) {
+                    Ok(_) => member.set_up(true),
+                    Err(ref e) => {
+                        debug!("check proxy {}: {}", member, e);
+                        member.set_up(false);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Selects the proxy of new connections from the proxies satisfying the predicate. The first
+    /// of them is selected if none is up, so a connection still tries and reports its error.
+    fn select_member<P: Fn(&Member) -> bool>(&self, predicate: P) -> Arc<Member> {
+        let candidates: Vec<&Arc<Member>> = self
+            .members
+            .iter()
+            .filter(|member| predicate(member))
+            .collect();
+        let ups: Vec<&Arc<Member>> = candidates
+            .iter()
+            .filter(|member| member.is_up.load(Ordering::Relaxed))
+            .cloned()
+            .collect();
+
+        let member = match (ups.is_empty(), self.policy) {
+            (true, _) => candidates.first().cloned().unwrap_or(&self.members[0]),
+            (false, ProxyPolicy::Failover) => ups[0],
+            (false, ProxyPolicy::RoundRobin) => {
+                ups[self.cursor.fetch_add(1, Ordering::Relaxed) % ups.len()]
+            }
+        };
+
+        Arc::clone(member)
+    }
+}
+
+impl ProxyConnector for ProxyGroup {
+    fn get_remote(&self) -> SocketAddrV4 {
+        self.select_member(|_| true).get_remote()
+    }
+
+    fn handshake(&self, stream: &mut TcpStream, dst: SocketAddrV4) -> io::Result<()> {
+        self.select_member(|_| true).handshake(stream, dst)
+    }
+
+    fn connect(&self, dst: SocketAddrV4, timeout: Duration) -> io::Result<TcpStream> {
+        self.select_member(|_| true).connect(dst, timeout)
+    }
+
+    #[cfg(feature = "async")]
+    fn handshake_async<'a>(
+        &'a self,
+        stream: &'a mut tokio::net::TcpStream,
+        dst: SocketAddrV4,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.select_member(|_| true)
+                .handshake_async(stream, dst)
+                .await
+        })
+    }
+
+    #[cfg(feature = "async")]
+    fn connect_async(
+        &self,
+        dst: SocketAddrV4,
+        timeout: Duration,
+    ) -> BoxFuture<'_, io::Result<tokio::net::TcpStream>> {
+        Box::pin(async move {
+            self.select_member(|_| true)
+                .connect_async(dst, timeout)
+                .await
+        })
+    }
+
+    fn bind(&self, local_src: SocketAddrV4) -> io::Result<SocksDatagram> {
+        self.select_member(|member| member.is_udp_supported())
+            .bind(local_src)
+    }
+
+    fn is_udp_supported(&self) -> bool {
+        self.members.iter().any(|member| member.is_udp_supported())
+    }
+
+    fn is_bind_supported(&self) -> bool {
+        self.members.iter().any(|member| member.is_bind_supported())
+    }
+
+    fn listen(&self, dst: SocketAddrV4, timeout: Duration) -> io::Result<SocksListener> {
+        self.select_member(|member| member.is_bind_supported())
+            .listen(dst, timeout)
+    }
+
+    fn check(&self, timeout: Duration) -> io::Result<()> {
+        self.select_member(|_| true).check(timeout)
+    }
+
+    fn select(&self, is_udp: bool) -> Option<Arc<dyn ProxyConnector>> {
+        let member = match is_udp {
+            true => self.select_member(|member| member.is_udp_supported()),
+            false => self.select_member(|_| true),
+        };
+
+        Some(member)
+    }
+
+    fn fill_pool(&self) {
+        for member in self.members.iter() {
+            member.fill_pool();
+        }
+    }
+
+    fn drain_pool(&self) {
+        for member in self.members.iter() {
+            member.drain_pool();
+        }
+    }
+}
+
+impl Display for ProxyGroup {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let names: Vec<String> = self
+            .members
+            .iter()
+            .map(|member| member.to_string())
+            .collect();
+
+        write!(f, "{} in {}", names.join(", "), self.policy)
+    }
+}
@@ -0,0 +1,192 @@
+#[cfg(feature = "async")]
+use super::BoxFuture;
+use super::{ProxyConnector, SocksAuth};
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddrV4, TcpStream};
+
+/// Represents the max size of the header of a response.
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+/// Represents an HTTP proxy client using the CONNECT method.
+#[derive(Clone, Debug)]
+pub struct HttpConnector {
+    remote: SocketAddrV4,
+    auth: Option<SocksAuth>,
+}
+
+impl HttpConnector {
+    /// Creates a new `HttpConnector`.
+    pub fn new(remote: SocketAddrV4, auth: Option<SocksAuth>) -> HttpConnector {
+        HttpConnector { remote, auth }
+    }
+
+    /// Creates a CONNECT request to the target.
+    fn new_request(&self, dst: SocketAddrV4) -> String {
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", dst);
+        if let Some(ref auth) = self.auth {
+            let credentials = format!("{}:{}", auth.get_username(), auth.get_password());
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64(credentials.as_bytes())
+            ));
+        }
+        request.push_str("\r\n");
+
+        request
+    }
+}
+
+impl ProxyConnector for HttpConnector {
+    fn get_remote(&self) -> SocketAddrV4 {
+        self.remote
+    }
+
+    fn handshake(&self, stream: &mut TcpStream, dst: SocketAddrV4) -> io::Result<()> {
+        stream.write_all(self.new_request(dst).as_bytes())?;
+
+        let response = read_response(stream)?;
+
+        check_status(parse_status_line(&response)?)
+    }
+
+    #[cfg(feature = "async")]
+    fn handshake_async<'a>(
+        &'a self,
+        stream: &'a mut tokio::net::TcpStream,
+        dst: SocketAddrV4,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        use tokio::io::AsyncWriteExt;
+
+        Box::pin(async move {
+            stream.write_all(self.new_request(dst).as_bytes()).await?;
+
+            let response = read_response_async(stream).await?;
+
+            check_status(parse_status_line(&response)?)
+        })
+    }
+}
+
+impl Display for HttpConnector {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "HTTP {}", self.remote)
+    }
+}
+
+/// Reads the header of a response. The stream is read byte by byte so data after the header is
+/// left in the stream.
+fn read_response(stream: &mut TcpStream) -> io::Result<String> {
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response too large",
+            ));
+        }
+        match stream.read(&mut buffer)? {
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "unexpected end of response",
+                ))
+            }
+            _ => response.push(buffer[0]),
+        }
+    }
+
+    String::from_utf8(response)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid response"))
+}
+
+/// Reads the header of a response asynchronously. The stream is read byte by byte so data after
+/// the header is left in the stream.
+#[cfg(feature = "async")]
+async fn read_response_async(stream: &mut tokio::net::TcpStream) -> io::Result<String> {
+    use tokio::io::AsyncReadExt;
+
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response too large",
+            ));
+        }
+        match stream.read(&mut buffer).await? {
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "unexpected end of response",
+                ))
+            }
+            _ => response.push(buffer[0]),
+        }
+    }
+
+    String::from_utf8(response)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid response"))
+}
+
+/// Checks the status code of a response, a status other than 2xx rejects the request.
+fn check_status(code: u16) -> io::Result<()> {
+    match code {
+        200..=299 => Ok(()),
+        407 => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "proxy authentication required",
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("request rejected with status {}", code),
+        )),
+    }
+}
+
+/// Parses the status line of a response, returns the status code.
+fn parse_status_line(response: &str) -> io::Result<u16> {
+    let line = response.lines().next().unwrap_or("");
+    let mut parts = line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    if !version.starts_with("HTTP/1.") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid response version",
+        ));
+    }
+
+    match parts.next().and_then(|code| code.parse().ok()) {
+        Some(code) => Ok(code),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid response code",
+        )),
+    }
+}
+
+/// Encodes the given data in base64 (RFC 4648).
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut s = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+
+    s
+}
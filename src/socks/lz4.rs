@@ -0,0 +1,250 @@
+//! Compression and decompression of data in the LZ4 block format. A block is a series of
+//! sequences, each of a token, literals and a match copied from the data decompressed before.
+//! The compressor is greedy with a single hash table, which trades the ratio for speed.
+
+use std::cmp::min;
+use std::io;
+
+/// Represents the min length of a match.
+const MIN_MATCH: usize = 4;
+/// Represents the size of trailing data which are always literals.
+const LAST_LITERALS: usize = 5;
+/// Represents the min distance between the start of the last match and the end of the data.
+const MF_LIMIT: usize = 12;
+/// Represents the max offset of a match.
+const MAX_OFFSET: usize = u16::MAX as usize;
+/// Represents the bits of the hash table.
+const HASH_BITS: u32 = 12;
+
+/// Compresses the data to a block.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() + input.len() / 255 + 16);
+    // Positions are stored plus 1, so 0 means no position
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut i = 0;
+    while i + MF_LIMIT <= input.len() {
+        let sequence = read_u32(input, i);
+        let hash = hash(sequence);
+        let candidate = table[hash];
+        table[hash] = i + 1;
+        if candidate > 0 {
+            let candidate = candidate - 1;
+            if i - candidate <= MAX_OFFSET && read_u32(input, candidate) == sequence {
+                let end = input.len() - LAST_LITERALS;
+                let mut size = MIN_MATCH;
+                while i + size < end && input[candidate + size] == input[i + size] {
+                    size += 1;
+                }
+
+                write_sequence(&mut output, &input[anchor..i], i - candidate, size);
+                i += size;
+                anchor = i;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    write_literals(&mut output, &input[anchor..]);
+
+    output
+}
+
+/// Decompresses a block. Returns an error if the block is invalid or its data exceeds the max
+/// size.
+pub fn decompress(input: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid LZ4 block");
+
+    let mut output = Vec::with_capacity(min(input.len() * 4, max_size));
+    let mut i = 0;
+    loop {
+        let token = *input.get(i).ok_or_else(invalid)?;
+        i += 1;
+
+        // Literals
+        let mut size = (token >> 4) as usize;
+        if size == 15 {
+            size += read_length(input, &mut i).ok_or_else(invalid)?;
+        }
+        let literals = input.get(i..i + size).ok_or_else(invalid)?;
+        if output.len() + size > max_size {
+            return Err(invalid());
+        }
+        output.extend_from_slice(literals);
+        i += size;
+        // The last sequence has only literals
+        if i == input.len() {
+            break;
+        }
+
+        // Match
+        let offset = input.get(i..i + 2).ok_or_else(invalid)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        i += 2;
+        if offset == 0 || offset > output.len() {
+            return Err(invalid());
+        }
+        let mut size = (token & 0x0f) as usize;
+        if size == 15 {
+            size += read_length(input, &mut i).ok_or_else(invalid)?;
+        }
+        size += MIN_MATCH;
+        if output.len() + size > max_size {
+            return Err(invalid());
+        }
+        // A match may overlap the data it copies
+        let start = output.len() - offset;
+        for j in 0..size {
+            let b = output[start + j];
+            output.push(b);
+        }
+    }
+
+    Ok(output)
+}
+
+fn read_u32(input: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([input[i], input[i + 1], input[i + 2], input[i + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Reads the extra bytes of a length, returns `None` if the input ends.
+fn read_length(input: &[u8], i: &mut usize) -> Option<usize> {
+    let mut size = 0;
+    loop {
+        let b = *input.get(*i)?;
+        *i += 1;
+        size += b as usize;
+        if b != 255 {
+            return Some(size);
+        }
+    }
+}
+
+fn write_length(output: &mut Vec<u8>, mut size: usize) {
+    while size >= 255 {
+        output.push(255);
+        size -= 255;
+    }
+    output.push(size as u8);
+}
+
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], offset: usize, size: usize) {
+    let size = size - MIN_MATCH;
+    output.push(((min(literals.len(), 15) << 4) | min(size, 15)) as u8);
+    if literals.len() >= 15 {
+        write_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+    output.extend_from_slice(&(offset as u16).to_le_bytes());
+    if size >= 15 {
+        write_length(output, size - 15);
+    }
+}
+
+fn write_literals(output: &mut Vec<u8>, literals: &[u8]) {
+    output.push((min(literals.len(), 15) << 4) as u8);
+    if literals.len() >= 15 {
+        write_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get data of the size in a few words, which compresses well.
+    fn new_text(size: usize) -> Vec<u8> {
+        let words: [&[u8]; 4] = [b"pcap2socks ", b"relays ", b"frames ", b"to proxies\r\n"];
+        words
+            .iter()
+            .cycle()
+            .flat_map(|word| word.iter())
+            .take(size)
+            .cloned()
+            .collect()
+    }
+
+    /// Get pseudo-random data of the size, which never compresses.
+    fn new_noise(size: usize) -> Vec<u8> {
+        let mut state = 0x2545f4914f6cdd1du64;
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for size in [
+            0,
+            1,
+            4,
+            12,
+            13,
+            15,
+            16,
+            255,
+            270,
+            1000,
+            16 * 1024,
+            100 * 1024,
+        ]
+        .iter()
+        {
+            for data in [new_text(*size), new_noise(*size), vec![0u8; *size]].iter() {
+                let compressed = compress(data);
+                assert_eq!(decompress(&compressed, data.len()).unwrap(), *data);
+            }
+        }
+
+        // Repetitive data compresses
+        let data = new_text(16 * 1024);
+        assert!(compress(&data).len() < data.len() / 10);
+        let data = vec![0u8; 16 * 1024];
+        assert!(compress(&data).len() < 100);
+    }
+
+    #[test]
+    fn decompress_max_size() {
+        for data in [new_text(1000), new_noise(1000)].iter() {
+            let compressed = compress(data);
+            assert_eq!(decompress(&compressed, data.len()).unwrap(), *data);
+            assert!(decompress(&compressed, data.len() - 1).is_err());
+        }
+
+        // A tiny block expanding to a large data is bounded
+        let mut block = vec![0x1f, 0];
+        block.extend_from_slice(&1u16.to_le_bytes());
+        block.extend_from_slice(&[255; 256]);
+        block.extend_from_slice(&[0, 0x00]);
+        let data = decompress(&block, 1 << 20).unwrap();
+        assert_eq!(data.len(), 1 + 15 + 255 * 256 + 4);
+        assert!(data.iter().all(|b| *b == 0));
+        assert!(decompress(&block, 16 * 1024).is_err());
+    }
+
+    #[test]
+    fn decompress_invalid() {
+        // Empty
+        assert!(decompress(&[], 1024).is_err());
+        // Literals beyond the block
+        assert!(decompress(&[0x50, 1, 2], 1024).is_err());
+        // Match of offset 0
+        assert!(decompress(&[0x10, 1, 0, 0, 0x00], 1024).is_err());
+        // Match beyond the data decompressed
+        assert!(decompress(&[0x10, 1, 2, 0, 0x00], 1024).is_err());
+        // Truncated offset
+        assert!(decompress(&[0x10, 1, 1], 1024).is_err());
+        // Truncated length
+        assert!(decompress(&[0xf0, 255], 1024).is_err());
+    }
+}
@@ -1,46 +1,2519 @@
-use socks::{self, TargetAddr};
-use socks::{Socks5Datagram, Socks5Stream};
-use std::io;
-use std::net::{SocketAddr, SocketAddrV4, TcpStream};
+use socks;
+use socks::{TargetAddr, ToTargetAddr};
+use std::cmp::min;
+use std::env;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream,
+    ToSocketAddrs, UdpSocket,
+};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-/// Connects to a target server through a SOCKS5 proxy.
-pub fn connect(remote: SocketAddrV4, dst: SocketAddrV4) -> io::Result<TcpStream> {
-    let stream = Socks5Stream::connect(remote, dst)?;
+use crate::metrics;
 
-    Ok(stream.into_inner())
+mod group;
+mod http;
+mod lz4;
+mod mux;
+#[cfg(feature = "async")]
+mod nonblocking;
+mod outbound;
+mod pool;
+mod ports;
+mod resolved;
+mod shared;
+mod tunnel;
+pub use group::{ProxyGroup, ProxyPolicy, ProxyState, DEFAULT_PROXY_CHECK_INTERVAL};
+pub use http::HttpConnector;
+pub use mux::{Accept, MuxConnector, MuxSession};
+#[cfg(feature = "async")]
+pub use outbound::connect_tcp_async;
+pub use outbound::{bind_udp, connect_tcp, get_outbound, set_outbound, Outbound};
+pub use pool::{drain_pools, get_proxy_pool, set_proxy_pool};
+pub use ports::{get_port_occupancy, get_port_range, set_port_range, PortRange};
+pub use resolved::ResolvedProxy;
+pub use shared::{
+    get_shared_occupancy, get_shared_sockets, set_shared_sockets, Handler, DEFAULT_SHARED_SOCKETS,
+};
+pub use tunnel::{new_frame, FrameDecoder, UdpFallback, UdpFallbackConnector};
+
+/// Represents a boxed future of a proxy, which can be sent between threads.
+#[cfg(feature = "async")]
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Represents a client of an upstream proxy.
+pub trait ProxyConnector: Display + Send + Sync {
+    /// Get the address of the proxy.
+    fn get_remote(&self) -> SocketAddrV4;
+
+    /// Issues a request to connect to the target on a stream connected to the proxy.
+    fn handshake(&self, stream: &mut TcpStream, dst: SocketAddrV4) -> io::Result<()>;
+
+    /// Connects to a target server through the proxy. Errors in connecting to the proxy are
+    /// wrapped in `UnreachableError`. The handshake fails with `TimedOut` if the proxy does not
+    /// answer in the timeout.
+    fn connect(&self, dst: SocketAddrV4, timeout: Duration) -> io::Result<TcpStream> {
+        connect_through(self, dst, timeout)
+    }
+
+    /// Issues a request to connect to the target on a stream connected to the proxy
+    /// asynchronously.
+    #[cfg(feature = "async")]
+    fn handshake_async<'a>(
+        &'a self,
+        stream: &'a mut tokio::net::TcpStream,
+        dst: SocketAddrV4,
+    ) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Connects to a target server through the proxy asynchronously, errors are the same as the
+    /// ones of `connect`.
+    #[cfg(feature = "async")]
+    fn connect_async(
+        &self,
+        dst: SocketAddrV4,
+        timeout: Duration,
+    ) -> BoxFuture<'_, io::Result<tokio::net::TcpStream>> {
+        connect_through_async(self, dst, timeout)
+    }
+
+    /// Creates a UDP socket bound to the given address which will have its traffic routed
+    /// through the proxy.
+    fn bind(&self, local_src: SocketAddrV4) -> io::Result<SocksDatagram> {
+        let _ = local_src;
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} does not support UDP", self),
+        ))
+    }
+
+    /// Returns if the proxy can relay UDP traffic.
+    fn is_udp_supported(&self) -> bool {
+        false
+    }
+
+    /// Returns if the proxy can listen for inbound connections.
+    fn is_bind_supported(&self) -> bool {
+        false
+    }
+
+    /// Asks the proxy to listen for an inbound connection from the given address.
+    fn listen(&self, dst: SocketAddrV4, timeout: Duration) -> io::Result<SocksListener> {
+        let _ = (dst, timeout);
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} does not support BIND", self),
+        ))
+    }
+
+    /// Checks the health of the proxy by connecting to it in the timeout.
+    fn check(&self, timeout: Duration) -> io::Result<()> {
+        connect_tcp(self.get_remote(), Some(timeout))?;
+
+        Ok(())
+    }
+
+    /// Selects the proxy new connections go through, or `None` if they go through this proxy.
+    /// Connections over UDP are given if `is_udp` is true.
+    fn select(&self, is_udp: bool) -> Option<Arc<dyn ProxyConnector>> {
+        let _ = is_udp;
+        None
+    }
+
+    /// Starts filling the pool of idle connections to the proxy if connections are pooled.
+    fn fill_pool(&self) {}
+
+    /// Closes the idle connections pooled to the proxy.
+    fn drain_pool(&self) {}
+}
+
+/// Represents the timeout of the connection to a proxy.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connects to a target server through the proxy on a new connection to the proxy.
+fn connect_through<P: ProxyConnector + ?Sized>(
+    proxy: &P,
+    dst: SocketAddrV4,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let remote = proxy.get_remote();
+    let mut stream = connect_tcp(remote, Some(min(timeout, CONNECT_TIMEOUT)))
+        .map_err(|e| io::Error::new(e.kind(), UnreachableError { remote, e }))?;
+
+    // Handshake
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    if let Err(e) = proxy.handshake(&mut stream, dst) {
+        return Err(handshake_error(proxy, e));
+    }
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+
+    Ok(stream)
+}
+
+/// Connects to a target server through the proxy on a new connection to the proxy
+/// asynchronously.
+#[cfg(feature = "async")]
+fn connect_through_async<P: ProxyConnector + ?Sized>(
+    proxy: &P,
+    dst: SocketAddrV4,
+    timeout: Duration,
+) -> BoxFuture<'_, io::Result<tokio::net::TcpStream>> {
+    Box::pin(async move {
+        let remote = proxy.get_remote();
+        let stream = tokio::time::timeout(min(timeout, CONNECT_TIMEOUT), connect_tcp_async(remote))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)));
+        let mut stream =
+            stream.map_err(|e| io::Error::new(e.kind(), UnreachableError { remote, e }))?;
+
+        // Handshake
+        match tokio::time::timeout(timeout, proxy.handshake_async(&mut stream, dst)).await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(e)) => Err(handshake_error(proxy, e)),
+            Err(_) => Err(handshake_error(
+                proxy,
+                io::Error::from(io::ErrorKind::TimedOut),
+            )),
+        }
+    })
+}
+
+/// Get the error of a failed handshake through the proxy, a handshake not answered in the
+/// timeout fails with `TimedOut`.
+fn handshake_error<P: Display + ?Sized>(proxy: &P, e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{}: handshake timed out", proxy),
+        ),
+        _ => e,
+    }
+}
+
+/// Represents an error when connect to a proxy, which means the proxy is unreachable.
+#[derive(Debug)]
+pub struct UnreachableError {
+    remote: SocketAddrV4,
+    e: io::Error,
+}
+
+impl UnreachableError {
+    /// Returns if the given `io::Error` is caused by an unreachable proxy.
+    pub fn is_unreachable(e: &io::Error) -> bool {
+        match e.get_ref() {
+            Some(e) => e.is::<UnreachableError>(),
+            None => false,
+        }
+    }
+}
+
+impl Display for UnreachableError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "proxy {} unreachable: {}", self.remote, self.e)
+    }
+}
+
+impl Error for UnreachableError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.e)
+    }
+}
+
+/// Represents an error when a datagram is larger than the path MTU to the relay.
+#[derive(Debug)]
+pub struct OversizedError {
+    size: usize,
+    max_size: usize,
+}
+
+impl OversizedError {
+    /// Creates a new `OversizedError` of a datagram of the size, where the max size is the most
+    /// data of a datagram not exceeding the path MTU.
+    pub fn new(size: usize, max_size: usize) -> OversizedError {
+        OversizedError { size, max_size }
+    }
+
+    /// Returns the max size of data of a datagram if the given `io::Error` is caused by an
+    /// oversized datagram.
+    pub fn get_max_size(e: &io::Error) -> Option<usize> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<OversizedError>())
+            .map(|e| e.max_size)
+    }
+}
+
+impl Display for OversizedError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "datagram of {} Bytes exceeds the path MTU to the relay of {} Bytes",
+            self.size, self.max_size
+        )
+    }
+}
+
+impl Error for OversizedError {}
+
+/// Returns if the given `io::Error` is caused by a datagram larger than the socket can send.
+#[cfg(unix)]
+pub fn is_message_too_long(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+/// Returns if the given `io::Error` is caused by a datagram larger than the socket can send.
+#[cfg(windows)]
+pub fn is_message_too_long(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(winapi::shared::winerror::WSAEMSGSIZE as i32)
+}
+
+/// Probes if a proxy is reachable.
+pub fn probe(remote: SocketAddrV4) -> bool {
+    connect_tcp(remote, Some(CONNECT_TIMEOUT)).is_ok()
+}
+
+/// Represents the version of the SOCKS protocol.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Version {
+    V4,
+    V4a,
+    V5,
+}
+
+impl Version {
+    /// Returns if the version can relay UDP traffic.
+    pub fn is_udp_supported(&self) -> bool {
+        match self {
+            Version::V4 | Version::V4a => false,
+            Version::V5 => true,
+        }
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            Version::V4 => write!(f, "SOCKS4"),
+            Version::V4a => write!(f, "SOCKS4a"),
+            Version::V5 => write!(f, "SOCKS5"),
+        }
+    }
+}
+
+/// Represents the authentication of a SOCKS proxy. The username is used as the userid in SOCKS4.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SocksAuth {
+    username: String,
+    password: String,
+}
+
+impl SocksAuth {
+    /// Creates a new `SocksAuth`.
+    pub fn new(username: String, password: String) -> SocksAuth {
+        SocksAuth { username, password }
+    }
+
+    /// Get the username.
+    pub fn get_username(&self) -> &str {
+        &self.username
+    }
+
+    /// Get the password.
+    pub fn get_password(&self) -> &str {
+        &self.password
+    }
+
+    /// Reads a `SocksAuth` from a file containing `username:password`. Trailing newlines are
+    /// ignored.
+    pub fn from_file(path: &Path) -> io::Result<SocksAuth> {
+        let s = fs::read_to_string(path)?;
+        let s = s.trim_end_matches(&['\r', '\n'][..]);
+        match s.find(':') {
+            Some(pos) => Ok(SocksAuth::new(
+                s[..pos].to_string(),
+                s[pos + 1..].to_string(),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid credentials in {}", path.display()),
+            )),
+        }
+    }
+
+    /// Reads a `SocksAuth` from the environment variables `PCAP2SOCKS_USERNAME` and
+    /// `PCAP2SOCKS_PASSWORD`. Returns `None` if the username is not set.
+    pub fn from_env() -> Option<SocksAuth> {
+        let username = env::var(ENV_USERNAME).ok()?;
+        let password = env::var(ENV_PASSWORD).unwrap_or_default();
+
+        Some(SocksAuth::new(username, password))
+    }
+}
+
+/// Represents the environment variable of the username.
+const ENV_USERNAME: &str = "PCAP2SOCKS_USERNAME";
+/// Represents the environment variable of the password.
+const ENV_PASSWORD: &str = "PCAP2SOCKS_PASSWORD";
+
+/// Represents the source of a `SocksAuth`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AuthSource {
+    Flags,
+    File(PathBuf),
+    Env,
+}
+
+impl AuthSource {
+    /// Returns if the source is a file readable by others.
+    #[cfg(unix)]
+    pub fn is_world_readable(&self) -> bool {
+        use std::os::unix::fs::PermissionsExt;
+
+        match self {
+            AuthSource::File(ref path) => match fs::metadata(path) {
+                Ok(metadata) => metadata.permissions().mode() & 0o004 != 0,
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns if the source is a file readable by others.
+    #[cfg(not(unix))]
+    pub fn is_world_readable(&self) -> bool {
+        false
+    }
+}
+
+impl Display for AuthSource {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            AuthSource::Flags => write!(f, "arguments"),
+            AuthSource::File(ref path) => write!(f, "file {}", path.display()),
+            AuthSource::Env => write!(f, "environment variables"),
+        }
+    }
+}
+
+/// Represents the options of a SOCKS proxy.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SocksOption {
+    version: Version,
+    auth: Option<SocksAuth>,
+}
+
+impl SocksOption {
+    /// Creates a new `SocksOption`.
+    pub fn new(version: Version, auth: Option<SocksAuth>) -> SocksOption {
+        SocksOption { version, auth }
+    }
+
+    /// Get the version of the SOCKS protocol.
+    pub fn get_version(&self) -> Version {
+        self.version
+    }
+
+    /// Get the authentication.
+    pub fn get_auth(&self) -> Option<&SocksAuth> {
+        self.auth.as_ref()
+    }
+}
+
+impl Default for SocksOption {
+    fn default() -> Self {
+        SocksOption::new(Version::V5, None)
+    }
+}
+
+/// Represents a SOCKS proxy client.
+#[derive(Clone, Debug)]
+pub struct SocksConnector {
+    remote: SocketAddrV4,
+    option: SocksOption,
+}
+
+impl SocksConnector {
+    /// Creates a new `SocksConnector`.
+    pub fn new(remote: SocketAddrV4, option: SocksOption) -> SocksConnector {
+        SocksConnector { remote, option }
+    }
+}
+
+impl ProxyConnector for SocksConnector {
+    fn get_remote(&self) -> SocketAddrV4 {
+        self.remote
+    }
+
+    fn handshake(&self, stream: &mut TcpStream, dst: SocketAddrV4) -> io::Result<()> {
+        handshake(stream, &dst.to_target_addr()?, &self.option)
+    }
+
+    fn connect(&self, dst: SocketAddrV4, timeout: Duration) -> io::Result<TcpStream> {
+        // A pooled connection only issues the request, and a connection closed by the proxy
+        // while idle falls back to a new connection
+        if let Some(mut stream) = pool::take(self.remote, &self.option) {
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            match request(&mut stream, &dst.to_target_addr()?, &self.option) {
+                Ok(()) => {
+                    stream.set_read_timeout(None)?;
+                    stream.set_write_timeout(None)?;
+
+                    return Ok(stream);
+                }
+                Err(ref e) if pool::is_stale(e) => metrics::PROXY_POOL_STALE.increase(),
+                Err(e) => return Err(handshake_error(self, e)),
+            }
+        }
+
+        connect_through(self, dst, timeout)
+    }
+
+    #[cfg(feature = "async")]
+    fn handshake_async<'a>(
+        &'a self,
+        stream: &'a mut tokio::net::TcpStream,
+        dst: SocketAddrV4,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            nonblocking::handshake(stream, &dst.to_target_addr()?, &self.option).await
+        })
+    }
+
+    #[cfg(feature = "async")]
+    fn connect_async(
+        &self,
+        dst: SocketAddrV4,
+        timeout: Duration,
+    ) -> BoxFuture<'_, io::Result<tokio::net::TcpStream>> {
+        Box::pin(async move {
+            // A pooled connection only issues the request, and a connection closed by the proxy
+            // while idle falls back to a new connection
+            if let Some(stream) = pool::take(self.remote, &self.option) {
+                stream.set_nonblocking(true)?;
+                let mut stream = tokio::net::TcpStream::from_std(stream)?;
+                let target = dst.to_target_addr()?;
+                let request = nonblocking::request(&mut stream, &target, &self.option);
+                match tokio::time::timeout(timeout, request).await {
+                    Ok(Ok(())) => return Ok(stream),
+                    Ok(Err(ref e)) if pool::is_stale(e) => metrics::PROXY_POOL_STALE.increase(),
+                    Ok(Err(e)) => return Err(handshake_error(self, e)),
+                    Err(_) => {
+                        return Err(handshake_error(
+                            self,
+                            io::Error::from(io::ErrorKind::TimedOut),
+                        ))
+                    }
+                }
+            }
+
+            connect_through_async(self, dst, timeout).await
+        })
+    }
+
+    fn bind(&self, local_src: SocketAddrV4) -> io::Result<SocksDatagram> {
+        SocksDatagram::bind(local_src, self.remote, &self.option)
+    }
+
+    fn is_udp_supported(&self) -> bool {
+        self.option.version.is_udp_supported()
+    }
+
+    fn is_bind_supported(&self) -> bool {
+        self.option.version == Version::V5
+    }
+
+    fn listen(&self, dst: SocketAddrV4, timeout: Duration) -> io::Result<SocksListener> {
+        SocksListener::bind(self.remote, dst, &self.option, timeout)
+    }
+
+    fn check(&self, timeout: Duration) -> io::Result<()> {
+        let mut stream = connect_tcp(self.remote, Some(timeout))?;
+        // SOCKS5 proxies are checked with the method negotiation
+        if self.option.version == Version::V5 {
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            negotiate_v5(&mut stream, self.option.auth.as_ref())?;
+        }
+
+        Ok(())
+    }
+
+    fn fill_pool(&self) {
+        pool::prepare(self.remote, &self.option);
+    }
+
+    fn drain_pool(&self) {
+        pool::drain(self.remote);
+    }
+}
+
+impl Display for SocksConnector {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.option.version, self.remote)
+    }
+}
+
+/// Represents a failed reply of a SOCKS request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplyError {
+    GeneralFailure,
+    NotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressTypeNotSupported,
+    /// Represents the reply 91 of SOCKS4.
+    Rejected,
+    /// Represents the reply 92 of SOCKS4.
+    IdentdUnreachable,
+    /// Represents the reply 93 of SOCKS4.
+    IdentdMismatch,
+}
+
+impl ReplyError {
+    /// Get the `ReplyError` carried in the given `io::Error`. Returns `None` if the error is not
+    /// caused by a failed reply.
+    pub fn from_io_error(e: &io::Error) -> Option<ReplyError> {
+        if let Some(e) = e.get_ref() {
+            if let Some(e) = e.downcast_ref::<ReplyError>() {
+                return Some(*e);
+            }
+            // Errors in the last hop of a chain are caused by the target
+            if let Some(e) = e.downcast_ref::<ChainError>() {
+                if e.is_last {
+                    return ReplyError::from_io_error(e.get_error());
+                }
+                return None;
+            }
+        }
+
+        // Errors in the `socks` crate is distinguished by their messages only
+        match e.to_string().as_str() {
+            "general SOCKS server failure" => Some(ReplyError::GeneralFailure),
+            "connection not allowed by ruleset" => Some(ReplyError::NotAllowed),
+            "network unreachable" => Some(ReplyError::NetworkUnreachable),
+            "host unreachable" => Some(ReplyError::HostUnreachable),
+            "connection refused" => Some(ReplyError::ConnectionRefused),
+            "TTL expired" => Some(ReplyError::TtlExpired),
+            "command not supported" => Some(ReplyError::CommandNotSupported),
+            "address kind not supported" => Some(ReplyError::AddressTypeNotSupported),
+            "request rejected or failed" => Some(ReplyError::Rejected),
+            "request rejected because SOCKS server cannot connect to idnetd on the client" => {
+                Some(ReplyError::IdentdUnreachable)
+            }
+            "request rejected because the client program and identd report different user-ids" => {
+                Some(ReplyError::IdentdMismatch)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Display for ReplyError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            ReplyError::GeneralFailure => write!(f, "general SOCKS server failure"),
+            ReplyError::NotAllowed => write!(f, "connection not allowed by ruleset"),
+            ReplyError::NetworkUnreachable => write!(f, "network unreachable"),
+            ReplyError::HostUnreachable => write!(f, "host unreachable"),
+            ReplyError::ConnectionRefused => write!(f, "connection refused"),
+            ReplyError::TtlExpired => write!(f, "TTL expired"),
+            ReplyError::CommandNotSupported => write!(f, "command not supported"),
+            ReplyError::AddressTypeNotSupported => write!(f, "address kind not supported"),
+            ReplyError::Rejected => write!(f, "request rejected or failed"),
+            ReplyError::IdentdUnreachable => write!(f, "cannot connect to identd on the client"),
+            ReplyError::IdentdMismatch => write!(f, "identd reports a different userid"),
+        }
+    }
+}
+
+impl Error for ReplyError {}
+
+impl From<ReplyError> for io::Error {
+    fn from(e: ReplyError) -> Self {
+        let kind = match e {
+            ReplyError::NotAllowed
+            | ReplyError::ConnectionRefused
+            | ReplyError::Rejected
+            | ReplyError::IdentdUnreachable
+            | ReplyError::IdentdMismatch => io::ErrorKind::ConnectionRefused,
+            _ => io::ErrorKind::Other,
+        };
+
+        io::Error::new(kind, e)
+    }
+}
+
+/// Enables the keepalive of a `TcpStream` with the given idle time before probes. The idle time
+/// is only applied in Linux.
+#[cfg(unix)]
+pub fn set_keepalive(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let fd = stream.as_raw_fd();
+    let set = |level, name, value: libc::c_int| {
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    };
+
+    set(libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    #[cfg(target_os = "linux")]
+    set(
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPIDLE,
+        min(idle.as_secs(), libc::c_int::MAX as u64) as libc::c_int,
+    )?;
+    #[cfg(not(target_os = "linux"))]
+    let _ = idle;
+
+    Ok(())
+}
+
+/// Enables the keepalive of a `TcpStream`, which is not supported in this platform.
+#[cfg(not(unix))]
+pub fn set_keepalive(_: &TcpStream, _: Duration) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "keepalive is not supported",
+    ))
+}
+
+/// Enables the aggressive keepalive of a `TcpStream`, and bounds the time data stays
+/// unacknowledged by the peer, so the stream fails in about the timeout after the peer is dead.
+/// Only the keepalive is enabled except Linux.
+#[cfg(target_os = "linux")]
+pub fn set_stale_timeout(stream: &TcpStream, timeout: Duration) -> io::Result<()> {
+    use std::cmp::max;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let fd = stream.as_raw_fd();
+    let set = |level, name, value: libc::c_int| {
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    };
+
+    // Probes start after half of the timeout, and the peer is dead after 3 unanswered probes in
+    // the other half
+    let secs = min(timeout.as_secs(), libc::c_int::MAX as u64) as libc::c_int;
+    set_keepalive(stream, Duration::from_secs(max(secs / 2, 1) as u64))?;
+    set(libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, max(secs / 6, 1))?;
+    set(libc::IPPROTO_TCP, libc::TCP_KEEPCNT, 3)?;
+    set(
+        libc::IPPROTO_TCP,
+        libc::TCP_USER_TIMEOUT,
+        min(timeout.as_millis(), libc::c_int::MAX as u128) as libc::c_int,
+    )?;
+
+    Ok(())
+}
+
+/// Enables the keepalive of a `TcpStream`, the probes and the time data stays unacknowledged are
+/// left to the OS in this platform.
+#[cfg(not(target_os = "linux"))]
+pub fn set_stale_timeout(stream: &TcpStream, timeout: Duration) -> io::Result<()> {
+    set_keepalive(stream, timeout / 2)
+}
+
+/// Get the size of data in the send buffer of a `TcpStream`, which is not sent or not
+/// acknowledged by the peer yet.
+#[cfg(target_os = "linux")]
+pub fn get_unsent_size(stream: &TcpStream) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut value: libc::c_int = 0;
+    let result = unsafe { libc::ioctl(stream.as_raw_fd(), libc::TIOCOUTQ, &mut value) };
+    match result {
+        0 => Ok(value as usize),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Get the size of data in the send buffer of a `TcpStream`, which is not supported in this
+/// platform.
+#[cfg(not(target_os = "linux"))]
+pub fn get_unsent_size(_: &TcpStream) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "unsent size is not supported",
+    ))
+}
+
+/// Sets the linger of a `TcpStream`, closing the stream waits for data in the send buffer to be
+/// sent in the linger, and a zero linger discards the data and resets the stream instead.
+#[cfg(unix)]
+pub fn set_linger(stream: &TcpStream, linger: Duration) -> io::Result<()> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let value = libc::linger {
+        l_onoff: 1,
+        l_linger: min(linger.as_secs(), libc::c_int::MAX as u64) as libc::c_int,
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &value as *const libc::linger as *const libc::c_void,
+            mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Sets the linger of a `TcpStream`, which is not supported in this platform.
+#[cfg(not(unix))]
+pub fn set_linger(_: &TcpStream, _: Duration) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "linger is not supported",
+    ))
+}
+
+/// Sets the TOS of IPv4 packets sent on a socket, the ECN bits are left to the OS.
+#[cfg(unix)]
+fn set_tos_raw(fd: std::os::unix::io::RawFd, tos: u8) -> io::Result<()> {
+    use std::mem;
+
+    let value = (tos & !0x3) as libc::c_int;
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_TOS,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Sets the TOS of IPv4 packets sent on a `TcpStream`.
+#[cfg(unix)]
+pub fn set_tos(stream: &TcpStream, tos: u8) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    set_tos_raw(stream.as_raw_fd(), tos)
+}
+
+/// Sets the TOS of IPv4 packets sent on a `TcpStream`, which is not supported in this platform.
+#[cfg(not(unix))]
+pub fn set_tos(_: &TcpStream, _: u8) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "TOS is not supported"))
+}
+
+/// Connects to a target server through a SOCKS proxy. IPv6 targets are only supported in
+/// SOCKS5.
+pub fn connect(
+    remote: SocketAddrV4,
+    dst: SocketAddr,
+    option: &SocksOption,
+) -> io::Result<TcpStream> {
+    let mut stream = connect_tcp(remote, None)?;
+    handshake(&mut stream, &dst.to_target_addr()?, option)?;
+
+    Ok(stream)
+}
+
+/// Connects to a target server known by its host name through a SOCKS proxy. The host name is
+/// resolved by the proxy, which is not supported in SOCKS4.
+pub fn connect_host(
+    remote: SocketAddrV4,
+    host: &str,
+    port: u16,
+    option: &SocksOption,
+) -> io::Result<TcpStream> {
+    let mut stream = connect_tcp(remote, None)?;
+    handshake(&mut stream, &(host, port).to_target_addr()?, option)?;
+
+    Ok(stream)
+}
+
+/// Issues a CONNECT request to the target on a stream connected to a SOCKS proxy.
+fn handshake(stream: &mut TcpStream, dst: &TargetAddr, option: &SocksOption) -> io::Result<()> {
+    match option.version {
+        Version::V4 | Version::V4a => handshake_v4(stream, dst, option),
+        Version::V5 => handshake_v5(stream, dst, option.auth.as_ref()),
+    }
+}
+
+/// Issues a CONNECT request to the target on a stream connected to a SOCKS proxy whose method
+/// is negotiated already. SOCKS4 has no negotiation so the whole handshake is issued.
+fn request(stream: &mut TcpStream, dst: &TargetAddr, option: &SocksOption) -> io::Result<()> {
+    match option.version {
+        Version::V4 | Version::V4a => handshake_v4(stream, dst, option),
+        // The bound address is discarded
+        Version::V5 => request_v5(stream, 1, dst).map(|_| ()),
+    }
+}
+
+/// Issues a SOCKS4 CONNECT request.
+fn handshake_v4(stream: &mut TcpStream, dst: &TargetAddr, option: &SocksOption) -> io::Result<()> {
+    stream.write_all(&new_request_v4(dst, option)?)?;
+
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply)?;
+
+    parse_reply_v4(&reply)
+}
+
+/// Creates a SOCKS4 CONNECT request. The address 0.0.0.1 and the host name are sent for host
+/// names in SOCKS4a.
+fn new_request_v4(dst: &TargetAddr, option: &SocksOption) -> io::Result<Vec<u8>> {
+    let userid = match option.auth {
+        Some(ref auth) => auth.get_username(),
+        None => "",
+    };
+
+    let mut request = vec![4u8, 1];
+    match dst {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            request.extend_from_slice(&addr.port().to_be_bytes());
+            request.extend_from_slice(&addr.ip().octets());
+            request.extend_from_slice(userid.as_bytes());
+            request.push(0);
+        }
+        TargetAddr::Ip(SocketAddr::V6(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SOCKS4 does not support IPv6",
+            ))
+        }
+        TargetAddr::Domain(ref host, port) => {
+            if option.version == Version::V4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "SOCKS4 does not support host names",
+                ));
+            }
+            request.extend_from_slice(&port.to_be_bytes());
+            request.extend_from_slice(&[0, 0, 0, 1]);
+            request.extend_from_slice(userid.as_bytes());
+            request.push(0);
+            request.extend_from_slice(host.as_bytes());
+            request.push(0);
+        }
+    }
+
+    Ok(request)
+}
+
+/// Parses a SOCKS4 reply.
+fn parse_reply_v4(reply: &[u8; 8]) -> io::Result<()> {
+    if reply[0] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid response version",
+        ));
+    }
+    match reply[1] {
+        90 => Ok(()),
+        91 => Err(ReplyError::Rejected.into()),
+        92 => Err(ReplyError::IdentdUnreachable.into()),
+        93 => Err(ReplyError::IdentdMismatch.into()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid response code",
+        )),
+    }
+}
+
+/// Issues a SOCKS5 CONNECT request with an optional username/password authentication (RFC
+/// 1928, RFC 1929).
+fn handshake_v5(
+    stream: &mut TcpStream,
+    dst: &TargetAddr,
+    auth: Option<&SocksAuth>,
+) -> io::Result<()> {
+    negotiate_v5(stream, auth)?;
+
+    // The bound address is discarded
+    request_v5(stream, 1, dst)?;
+
+    Ok(())
+}
+
+/// Negotiates the method of a SOCKS5 proxy with an optional username/password authentication
+/// (RFC 1928, RFC 1929).
+fn negotiate_v5(stream: &mut TcpStream, auth: Option<&SocksAuth>) -> io::Result<()> {
+    // Method
+    stream.write_all(new_methods_v5(auth))?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    if let Some(request) = parse_method_v5(&reply, auth)? {
+        stream.write_all(&request)?;
+
+        stream.read_exact(&mut reply)?;
+        parse_auth_reply_v5(&reply)?;
+    }
+
+    Ok(())
+}
+
+/// Get the methods offered to a SOCKS5 proxy.
+fn new_methods_v5(auth: Option<&SocksAuth>) -> &'static [u8] {
+    match auth {
+        Some(_) => &[5, 2, 0, 2],
+        None => &[5, 1, 0],
+    }
+}
+
+/// Parses the method selected by a SOCKS5 proxy, returns the username/password authentication
+/// request if the method requires it.
+fn parse_method_v5(reply: &[u8; 2], auth: Option<&SocksAuth>) -> io::Result<Option<Vec<u8>>> {
+    if reply[0] != 5 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid response version",
+        ));
+    }
+    match (reply[1], auth) {
+        (0, _) => Ok(None),
+        (2, Some(auth)) => {
+            let username = auth.get_username().as_bytes();
+            let password = auth.get_password().as_bytes();
+            if username.is_empty() || username.len() > 255 || password.len() > 255 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "invalid username or password",
+                ));
+            }
+            let mut request = vec![1u8, username.len() as u8];
+            request.extend_from_slice(username);
+            request.push(password.len() as u8);
+            request.extend_from_slice(password);
+
+            Ok(Some(request))
+        }
+        (0xff, _) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "no acceptable auth methods",
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown auth method",
+        )),
+    }
+}
+
+/// Parses the reply of the username/password authentication (RFC 1929).
+fn parse_auth_reply_v5(reply: &[u8; 2]) -> io::Result<()> {
+    if reply[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "password authentication failed",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Issues a SOCKS5 request with the given command, returns the bound address in the reply.
+fn request_v5(stream: &mut TcpStream, command: u8, dst: &TargetAddr) -> io::Result<TargetAddr> {
+    stream.write_all(&new_request_v5(command, dst)?)?;
+
+    read_reply_v5(stream)
+}
+
+/// Creates a SOCKS5 request with the given command.
+fn new_request_v5(command: u8, dst: &TargetAddr) -> io::Result<Vec<u8>> {
+    let mut request = vec![5u8, command, 0];
+    match dst {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            request.push(1);
+            request.extend_from_slice(&addr.ip().octets());
+            request.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        TargetAddr::Ip(SocketAddr::V6(addr)) => {
+            request.push(4);
+            request.extend_from_slice(&addr.ip().octets());
+            request.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        TargetAddr::Domain(ref host, port) => {
+            if host.len() > 255 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "domain name too long",
+                ));
+            }
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+            request.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+
+    Ok(request)
+}
+
+/// Reads a SOCKS5 reply, returns the bound address.
+fn read_reply_v5(stream: &mut TcpStream) -> io::Result<TargetAddr> {
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    let size = match parse_reply_v5(&reply)? {
+        Some(size) => size,
+        None => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+    };
+    let mut addr = vec![0u8; size + 2];
+    stream.read_exact(&mut addr)?;
+
+    Ok(parse_bound_addr_v5(reply[3], &addr, size))
+}
+
+/// Parses the head of a SOCKS5 reply, returns the size of the bound address. The size is `None`
+/// for a domain name, whose length follows the head.
+fn parse_reply_v5(reply: &[u8; 4]) -> io::Result<Option<usize>> {
+    if reply[0] != 5 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid response version",
+        ));
+    }
+    let e = match reply[1] {
+        0 => None,
+        1 => Some(ReplyError::GeneralFailure),
+        2 => Some(ReplyError::NotAllowed),
+        3 => Some(ReplyError::NetworkUnreachable),
+        4 => Some(ReplyError::HostUnreachable),
+        5 => Some(ReplyError::ConnectionRefused),
+        6 => Some(ReplyError::TtlExpired),
+        7 => Some(ReplyError::CommandNotSupported),
+        8 => Some(ReplyError::AddressTypeNotSupported),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid response code",
+            ))
+        }
+    };
+    if let Some(e) = e {
+        return Err(e.into());
+    }
+
+    match reply[3] {
+        1 => Ok(Some(4)),
+        3 => Ok(None),
+        4 => Ok(Some(16)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid address type",
+        )),
+    }
+}
+
+/// Parses the bound address of a SOCKS5 reply in the address type, which is followed by the
+/// port.
+fn parse_bound_addr_v5(address_type: u8, addr: &[u8], size: usize) -> TargetAddr {
+    let port = u16::from_be_bytes([addr[size], addr[size + 1]]);
+    match address_type {
+        1 => TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]),
+            port,
+        ))),
+        3 => TargetAddr::Domain(String::from_utf8_lossy(&addr[..size]).into_owned(), port),
+        _ => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addr[..16]);
+            TargetAddr::Ip(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(octets),
+                port,
+                0,
+                0,
+            )))
+        }
+    }
+}
+
+/// Issues a SOCKS5 UDP ASSOCIATE request, returns the address of the relay. An unspecified
+/// address like `0.0.0.0` or `::` in the reply means the address the control connection is
+/// connected to, with the port in the reply.
+fn associate_v5(
+    stream: &mut TcpStream,
+    local_src: SocketAddrV4,
+    auth: Option<&SocksAuth>,
+) -> io::Result<SocketAddr> {
+    negotiate_v5(stream, auth)?;
+
+    let addr = match request_v5(stream, 3, &local_src.to_target_addr()?)? {
+        TargetAddr::Ip(addr) => addr,
+        TargetAddr::Domain(ref host, port) => match (host.as_str(), port).to_socket_addrs()?.next()
+        {
+            Some(addr) => addr,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "cannot resolve the relay",
+                ))
+            }
+        },
+    };
+    if addr.port() == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the proxy replies UDP ASSOCIATE with relay {}, whose port 0 cannot be sent to",
+                addr
+            ),
+        ));
+    }
+    if addr.ip().is_unspecified() {
+        return Ok(SocketAddr::new(stream.peer_addr()?.ip(), addr.port()));
+    }
+
+    Ok(addr)
+}
+
+/// Represents a SOCKS5 BIND request waiting for an inbound connection (RFC 1928).
+#[derive(Debug)]
+pub struct SocksListener {
+    stream: TcpStream,
+    addr: SocketAddr,
+}
+
+impl SocksListener {
+    /// Asks a SOCKS5 proxy to listen for an inbound connection from the given address. The first
+    /// reply is waited in the timeout. An unspecified bound address in the reply is substituted
+    /// with the address of the proxy.
+    pub fn bind(
+        remote: SocketAddrV4,
+        dst: SocketAddrV4,
+        option: &SocksOption,
+        timeout: Duration,
+    ) -> io::Result<SocksListener> {
+        if option.version != Version::V5 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} does not support BIND", option.version),
+            ));
+        }
+        let mut stream = connect_tcp(remote, Some(timeout))?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        negotiate_v5(&mut stream, option.auth.as_ref())?;
+
+        let addr = match request_v5(&mut stream, 2, &dst.to_target_addr()?)? {
+            TargetAddr::Ip(addr) => addr,
+            TargetAddr::Domain(_, port) => SocketAddr::V4(SocketAddrV4::new(*remote.ip(), port)),
+        };
+        let addr = match addr.ip().is_unspecified() {
+            true => SocketAddr::V4(SocketAddrV4::new(*remote.ip(), addr.port())),
+            false => addr,
+        };
+
+        Ok(SocksListener { stream, addr })
+    }
+
+    /// Get the address the proxy listens on.
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Waits for the inbound connection in the timeout, returns the stream and the address of
+    /// the peer.
+    pub fn accept(mut self, timeout: Duration) -> io::Result<(TcpStream, SocketAddr)> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        let addr = match read_reply_v5(&mut self.stream) {
+            Ok(TargetAddr::Ip(addr)) => addr,
+            Ok(TargetAddr::Domain(_, _)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid address type",
+                ))
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "inbound connection timed out",
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        self.stream.set_read_timeout(None)?;
+        self.stream.set_write_timeout(None)?;
+
+        Ok((self.stream, addr))
+    }
+}
+
+/// Represents an error in a hop of a proxy chain.
+#[derive(Debug)]
+pub struct ChainError {
+    hop: usize,
+    is_last: bool,
+    proxy: String,
+    e: io::Error,
+}
+
+impl ChainError {
+    /// Get the index of the hop from 1.
+    pub fn get_hop(&self) -> usize {
+        self.hop
+    }
+
+    /// Get the error of the hop.
+    pub fn get_error(&self) -> &io::Error {
+        &self.e
+    }
+}
+
+impl Display for ChainError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "hop {} ({}): {}", self.hop, self.proxy, self.e)
+    }
+}
+
+impl Error for ChainError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.e)
+    }
+}
+
+/// Represents a chain of proxies. The stream to a proxy is connected through all the proxies
+/// before.
+pub struct ProxyChain {
+    proxies: Vec<Arc<dyn ProxyConnector>>,
+}
+
+impl ProxyChain {
+    /// Creates a new `ProxyChain`.
+    pub fn new(proxies: Vec<Arc<dyn ProxyConnector>>) -> ProxyChain {
+        ProxyChain { proxies }
+    }
+}
+
+impl ProxyConnector for ProxyChain {
+    fn get_remote(&self) -> SocketAddrV4 {
+        self.proxies[0].get_remote()
+    }
+
+    fn handshake(&self, stream: &mut TcpStream, dst: SocketAddrV4) -> io::Result<()> {
+        for (i, proxy) in self.proxies.iter().enumerate() {
+            let next = match self.proxies.get(i + 1) {
+                Some(next) => next.get_remote(),
+                None => dst,
+            };
+            if let Err(e) = proxy.handshake(stream, next) {
+                return Err(io::Error::new(
+                    e.kind(),
+                    ChainError {
+                        hop: i + 1,
+                        is_last: i + 1 == self.proxies.len(),
+                        proxy: proxy.to_string(),
+                        e,
+                    },
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    fn handshake_async<'a>(
+        &'a self,
+        stream: &'a mut tokio::net::TcpStream,
+        dst: SocketAddrV4,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            for (i, proxy) in self.proxies.iter().enumerate() {
+                let next = match self.proxies.get(i + 1) {
+                    Some(next) => next.get_remote(),
+                    None => dst,
+                };
+                if let Err(e) = proxy.handshake_async(stream, next).await {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        ChainError {
+                            hop: i + 1,
+                            is_last: i + 1 == self.proxies.len(),
+                            proxy: proxy.to_string(),
+                            e,
+                        },
+                    ));
+                }
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl Display for ProxyChain {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let proxies: Vec<String> = self.proxies.iter().map(|proxy| proxy.to_string()).collect();
+        write!(f, "{}", proxies.join(" -> "))
+    }
+}
+
+/// Represents the timeout of the reassembly of SOCKS5 UDP fragments in milliseconds (RFC 1928).
+const REASSEMBLY_TIMEOUT: u128 = 5000;
+/// Represents the end-of-fragment-sequence bit of the FRAG field.
+const FRAG_END: u8 = 0x80;
+/// Represents the size of an IPv4 header without options.
+const IPV4_HEADER_SIZE: usize = 20;
+/// Represents the size of an IPv6 header.
+const IPV6_HEADER_SIZE: usize = 40;
+/// Represents the size of a UDP header.
+const UDP_HEADER_SIZE: usize = 8;
+/// Represents the option of the path MTU of IPv4 sockets, which is missing in some versions of
+/// libc, see `linux/in.h`.
+#[cfg(target_os = "linux")]
+const IP_MTU: libc::c_int = 14;
+/// Represents the value of IPv6 sockets discovering the path MTU, which is missing in some
+/// versions of libc, see `linux/in6.h`.
+#[cfg(target_os = "linux")]
+const IPV6_PMTUDISC_DO: libc::c_int = 2;
+
+/// Represents the reassembly queue of SOCKS5 UDP fragments.
+#[derive(Debug)]
+struct ReassemblyQueue {
+    addr: SocketAddrV4,
+    position: u8,
+    buffer: Vec<u8>,
+    instant: Instant,
+}
+
+/// Represents the reassembler of SOCKS5 UDP datagrams of an association.
+#[derive(Debug)]
+struct Reassembler {
+    is_fragmentation: bool,
+    queue: Option<ReassemblyQueue>,
+}
+
+impl Reassembler {
+    fn new() -> Reassembler {
+        Reassembler {
+            is_fragmentation: true,
+            queue: None,
+        }
+    }
+
+    /// Parses a SOCKS5 UDP datagram of the given size in the buffer, returns the size and the
+    /// source of the datagram if it is standalone or completes the fragments.
+    fn reassemble(
+        &mut self,
+        buffer: &mut [u8],
+        size: usize,
+    ) -> io::Result<Option<(usize, SocketAddrV4)>> {
+        let (frag, addr, header_size) = parse_header(&buffer[..size])?;
+        let addr = match addr {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(addr) => match addr.ip().to_ipv4_mapped() {
+                Some(ip) => SocketAddrV4::new(ip, addr.port()),
+                None => return Ok(None),
+            },
+        };
+
+        let queue = &mut self.queue;
+        if frag == 0 {
+            // A standalone datagram abandons the fragments
+            *queue = None;
+            buffer.copy_within(header_size..size, 0);
+
+            return Ok(Some((size - header_size, addr)));
+        }
+        if !self.is_fragmentation {
+            return Ok(None);
+        }
+
+        // The fragments are abandoned if the timer expires, or the position does not follow
+        let position = frag & !FRAG_END;
+        let is_next = match *queue {
+            Some(ref queue) => {
+                queue.addr == addr
+                    && position == queue.position.wrapping_add(1)
+                    && queue.instant.elapsed().as_millis() < REASSEMBLY_TIMEOUT
+            }
+            None => false,
+        };
+        if !is_next {
+            *queue = None;
+            if position != 1 {
+                return Ok(None);
+            }
+            *queue = Some(ReassemblyQueue {
+                addr,
+                position: 0,
+                buffer: Vec::new(),
+                instant: Instant::now(),
+            });
+        }
+
+        let fragments = queue.as_mut().unwrap();
+        fragments.position = position;
+        fragments
+            .buffer
+            .extend_from_slice(&buffer[header_size..size]);
+        if fragments.buffer.len() > u16::MAX as usize {
+            *queue = None;
+            return Ok(None);
+        }
+
+        if frag & FRAG_END != 0 {
+            let fragments = queue.take().unwrap();
+            let size = min(fragments.buffer.len(), buffer.len());
+            buffer[..size].copy_from_slice(&fragments.buffer[..size]);
+
+            return Ok(Some((size, addr)));
+        }
+
+        Ok(None)
+    }
 }
 
-/// Represents a SOCKS5 UDP client.
+/// Represents the socket of a `SocksDatagram`.
+#[derive(Debug)]
+enum Socket {
+    /// Represents a UDP socket connected to the relay, and the control connection of the
+    /// association which lives as long as the socket
+    Socks(UdpSocket, TcpStream),
+    /// Represents a route of a local UDP socket shared by associations to the relay, and the
+    /// control connection of the association which lives as long as the route
+    Shared(shared::SharedDatagram, TcpStream),
+    Direct(UdpSocket),
+    /// Represents a UDP socket connected to the loopback end of a tunnel over TCP, which sends
+    /// and receives datagrams as the relay does
+    Tunnel(UdpSocket, tunnel::Tunnel),
+}
+
+/// Represents a SOCKS5 UDP client, or a plain UDP socket for traffic sent directly.
 #[derive(Debug)]
 pub struct SocksDatagram {
-    datagram: Socks5Datagram,
+    datagram: Socket,
+    reassembler: Arc<Mutex<Reassembler>>,
 }
 
 impl SocksDatagram {
     /// Creates a UDP socket bound to the specified address which will have its traffic routed through the specified proxy.
-    pub fn bind(local_src: SocketAddrV4, remote: SocketAddrV4) -> io::Result<SocksDatagram> {
-        let datagram = Socks5Datagram::bind(remote, local_src)?;
+    /// The association is routed through a shared local socket instead if shared sockets are
+    /// enabled, whose datagrams are received by the reader of the shared socket.
+    pub fn bind(
+        local_src: SocketAddrV4,
+        remote: SocketAddrV4,
+        option: &SocksOption,
+    ) -> io::Result<SocksDatagram> {
+        if !option.version.is_udp_supported() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} does not support UDP", option.version),
+            ));
+        }
+        // The socket is bound to a port in the local port range or shared, whose port is unknown
+        // yet, so the request carries no port
+        let is_shared = get_shared_sockets() > 0;
+        let local_src = match get_port_range() {
+            Some(_) => SocketAddrV4::new(*local_src.ip(), 0),
+            None if is_shared => SocketAddrV4::new(*local_src.ip(), 0),
+            None => local_src,
+        };
+        let mut stream = connect_tcp(remote, None)?;
+        let relay = associate_v5(&mut stream, local_src, option.auth.as_ref())?;
+        // The control connection is only polled for its closure
+        stream.set_nonblocking(true)?;
 
-        Ok(SocksDatagram { datagram })
+        let reassembler = Arc::new(Mutex::new(Reassembler::new()));
+        if is_shared {
+            let datagram = shared::SharedDatagram::bind(relay, Arc::clone(&reassembler))?;
+
+            return Ok(SocksDatagram {
+                datagram: Socket::Shared(datagram, stream),
+                reassembler,
+            });
+        }
+
+        // The socket is in the family of the relay, which may differ from the proxy
+        let datagram = match relay {
+            SocketAddr::V4(_) => bind_udp(SocketAddr::V4(local_src))?,
+            SocketAddr::V6(_) => bind_udp(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::UNSPECIFIED,
+                local_src.port(),
+                0,
+                0,
+            )))?,
+        };
+        datagram.connect(relay)?;
+
+        Ok(SocksDatagram {
+            datagram: Socket::Socks(datagram, stream),
+            reassembler,
+        })
+    }
+
+    /// Creates a UDP socket bound to the specified address which will send its traffic directly.
+    pub fn bind_direct(local_src: SocketAddrV4) -> io::Result<SocksDatagram> {
+        let datagram = bind_udp(SocketAddr::V4(local_src))?;
+
+        Ok(SocksDatagram {
+            datagram: Socket::Direct(datagram),
+            reassembler: Arc::new(Mutex::new(Reassembler::new())),
+        })
+    }
+
+    /// Creates a UDP socket whose traffic is tunneled over a TCP stream connected by the
+    /// function in frames, the stream is reconnected by the function after it breaks.
+    pub fn tunnel(connect: tunnel::Connect) -> io::Result<SocksDatagram> {
+        let stream = connect()?;
+        // The socket and the end of the tunnel are connected to each other on loopback
+        let datagram = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        datagram.connect(socket.local_addr()?)?;
+        socket.connect(datagram.local_addr()?)?;
+        let tunnel = tunnel::Tunnel::open(socket, stream, connect)?;
+
+        Ok(SocksDatagram {
+            datagram: Socket::Tunnel(datagram, tunnel),
+            reassembler: Arc::new(Mutex::new(Reassembler::new())),
+        })
+    }
+
+    /// Get the local port of the socket.
+    pub fn get_local_port(&self) -> io::Result<u16> {
+        let addr = match self.datagram {
+            Socket::Socks(ref datagram, _) | Socket::Tunnel(ref datagram, _) => {
+                datagram.local_addr()?
+            }
+            Socket::Shared(ref datagram, _) => datagram.get_socket().local_addr()?,
+            Socket::Direct(ref datagram) => datagram.local_addr()?,
+        };
+
+        Ok(addr.port())
+    }
+
+    /// Sets if fragmented datagrams are reassembled, or they will be dropped.
+    pub fn set_fragmentation(&mut self, is_fragmentation: bool) {
+        self.reassembler.lock().unwrap().is_fragmentation = is_fragmentation;
+    }
+
+    /// Returns if the socket is shared by associations, whose datagrams are received by the
+    /// handler instead of `recv_from`.
+    pub fn is_shared(&self) -> bool {
+        match self.datagram {
+            Socket::Shared(_, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Sets the handler of datagrams received for the association on the shared socket. The
+    /// handler is called by the reader of the shared socket with datagrams reassembled.
+    pub fn set_handler(&self, handler: Handler) -> io::Result<()> {
+        match self.datagram {
+            Socket::Shared(ref datagram, _) => {
+                datagram.set_handler(handler);
+
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the socket is not shared",
+            )),
+        }
+    }
+
+    /// Probes the path MTU to the relay, which is the MTU of the route to the relay unless the OS
+    /// learns a smaller one from ICMP. The socket stops fragmenting datagrams, so the ones larger
+    /// than the path MTU fail with `EMSGSIZE` instead of being dropped on the path silently. A
+    /// plain UDP socket or a tunnel has no path MTU.
+    #[cfg(target_os = "linux")]
+    pub fn probe_mtu(&self) -> io::Result<Option<u16>> {
+        use std::mem;
+        use std::os::unix::io::AsRawFd;
+
+        let (datagram, relay) = match self.datagram {
+            Socket::Socks(ref datagram, _) => (datagram, datagram.peer_addr()?),
+            Socket::Shared(ref datagram, _) => (datagram.get_socket(), datagram.get_relay()),
+            Socket::Direct(_) | Socket::Tunnel(_, _) => return Ok(None),
+        };
+        let (level, discover, value, name) = match relay {
+            SocketAddr::V4(_) => (
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                libc::IP_PMTUDISC_DO,
+                IP_MTU,
+            ),
+            SocketAddr::V6(_) => (
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                IPV6_PMTUDISC_DO,
+                libc::IPV6_MTU,
+            ),
+        };
+
+        let fd = datagram.as_raw_fd();
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                discover,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // A shared socket is not connected, the path MTU is read on a socket connected to the
+        // relay instead, for the OS keeps the path MTU by destinations
+        let connected = match self.datagram {
+            Socket::Shared(_, _) => {
+                let connected = match relay {
+                    SocketAddr::V4(_) => {
+                        UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?
+                    }
+                    SocketAddr::V6(_) => {
+                        UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0))?
+                    }
+                };
+                connected.connect(relay)?;
+
+                Some(connected)
+            }
+            _ => None,
+        };
+        let fd = match connected {
+            Some(ref connected) => connected.as_raw_fd(),
+            None => fd,
+        };
+
+        let mut mtu: libc::c_int = 0;
+        let mut size = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                level,
+                name,
+                &mut mtu as *mut libc::c_int as *mut libc::c_void,
+                &mut size,
+            )
+        };
+        match result {
+            0 => Ok(Some(min(mtu, u16::MAX as libc::c_int) as u16)),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Probes the path MTU to the relay, which is not supported in this platform.
+    #[cfg(not(target_os = "linux"))]
+    pub fn probe_mtu(&self) -> io::Result<Option<u16>> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "path MTU is not supported",
+        ))
+    }
+
+    /// Get the max size of data of a datagram to an IPv4 address not exceeding the path MTU,
+    /// which excludes the IP header, the UDP header and the SOCKS5 UDP header.
+    pub fn get_max_size(&self, mtu: u16) -> io::Result<usize> {
+        let ip_header_size = match self.datagram {
+            Socket::Socks(ref datagram, _) | Socket::Tunnel(ref datagram, _) => {
+                match datagram.peer_addr()? {
+                    SocketAddr::V4(_) => IPV4_HEADER_SIZE,
+                    SocketAddr::V6(_) => IPV6_HEADER_SIZE,
+                }
+            }
+            Socket::Shared(ref datagram, _) => match datagram.get_relay() {
+                SocketAddr::V4(_) => IPV4_HEADER_SIZE,
+                SocketAddr::V6(_) => IPV6_HEADER_SIZE,
+            },
+            Socket::Direct(_) => IPV4_HEADER_SIZE,
+        };
+        let header_size = match self.datagram {
+            Socket::Socks(_, _) | Socket::Shared(_, _) => {
+                new_header(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))).len()
+            }
+            Socket::Direct(_) | Socket::Tunnel(_, _) => 0,
+        };
+
+        Ok((mtu as usize).saturating_sub(ip_header_size + UDP_HEADER_SIZE + header_size))
+    }
+
+    /// Get the size of the SOCKS5 UDP header of a datagram to an IPv4 address, which is 0 in a
+    /// plain UDP socket.
+    pub fn get_header_size(&self) -> usize {
+        match self.datagram {
+            Socket::Socks(_, _) | Socket::Shared(_, _) | Socket::Tunnel(_, _) => {
+                new_header(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))).len()
+            }
+            Socket::Direct(_) => 0,
+        }
+    }
+
+    /// Sets the TOS of IPv4 packets sent on the socket. The TOS of a shared socket applies to
+    /// all the associations sharing it.
+    #[cfg(unix)]
+    pub fn set_tos(&self, tos: u8) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = match self.datagram {
+            Socket::Socks(ref datagram, _) | Socket::Tunnel(ref datagram, _) => {
+                datagram.as_raw_fd()
+            }
+            Socket::Shared(ref datagram, _) => datagram.get_socket().as_raw_fd(),
+            Socket::Direct(ref datagram) => datagram.as_raw_fd(),
+        };
+
+        set_tos_raw(fd, tos)
+    }
+
+    /// Sets the TOS of IPv4 packets sent on the socket, which is not supported in this platform.
+    #[cfg(not(unix))]
+    pub fn set_tos(&self, _: u8) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "TOS is not supported"))
+    }
+
+    /// Sets the read timeout of the socket. A shared socket is never read by associations.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.datagram {
+            Socket::Socks(ref datagram, _) | Socket::Tunnel(ref datagram, _) => {
+                datagram.set_read_timeout(timeout)
+            }
+            Socket::Shared(_, _) => Ok(()),
+            Socket::Direct(ref datagram) => datagram.set_read_timeout(timeout),
+        }
+    }
+
+    /// Sets the idle time before TCP keepalive probes of the control connection. A plain UDP
+    /// socket or a tunnel has no control connection.
+    pub fn set_keepalive(&self, idle: Duration) -> io::Result<()> {
+        match self.datagram {
+            Socket::Socks(_, ref stream) | Socket::Shared(_, ref stream) => {
+                set_keepalive(stream, idle)
+            }
+            Socket::Direct(_) | Socket::Tunnel(_, _) => Ok(()),
+        }
+    }
+
+    /// Checks if the control connection is closed by the proxy, which terminates the
+    /// association (RFC 1928), or if the tunnel fails to reconnect.
+    pub fn check_control(&self) -> io::Result<()> {
+        let stream = match self.datagram {
+            Socket::Socks(_, ref stream) | Socket::Shared(_, ref stream) => stream,
+            Socket::Direct(_) => return Ok(()),
+            Socket::Tunnel(_, ref tunnel) => {
+                return match tunnel.is_failed() {
+                    true => Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "the UDP tunnel is closed",
+                    )),
+                    false => Ok(()),
+                }
+            }
+        };
+
+        let mut buffer = [0u8; 1];
+        match stream.peek(&mut buffer) {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "the control connection is closed by the proxy",
+            )),
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends a datagram without payload to `0.0.0.0:0` through the relay, which refreshes the
+    /// NAT mappings on the path to the relay and is dropped by the relay. The datagram is sent
+    /// in the tunnel as a frame.
+    pub fn send_keepalive(&self) -> io::Result<()> {
+        let header = new_header(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)));
+        match self.datagram {
+            Socket::Socks(ref datagram, _) | Socket::Tunnel(ref datagram, _) => {
+                datagram.send(&header)?;
+            }
+            Socket::Shared(ref datagram, _) => {
+                datagram.send(&header, None)?;
+            }
+            Socket::Direct(_) => {}
+        }
+
+        Ok(())
     }
 
     /// Sends data on the socket to the given address.
     pub fn send_to(&self, buffer: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
-        self.datagram.send_to(buffer, dst)
+        self.send_to_addr(buffer, SocketAddr::V4(dst))
     }
 
-    /// Receives a single datagram message on the socket.
-    pub fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
-        let (size, addr) = self.datagram.recv_from(buffer)?;
+    /// Sends data on the socket to the given IPv4 or IPv6 address.
+    pub fn send_to_addr(&self, buffer: &[u8], dst: SocketAddr) -> io::Result<usize> {
+        match self.datagram {
+            Socket::Socks(ref datagram, _) | Socket::Tunnel(ref datagram, _) => {
+                let mut datagram_buffer = new_header(dst);
+                let header_size = datagram_buffer.len();
+                datagram_buffer.extend_from_slice(buffer);
 
-        let addr = match addr {
-            TargetAddr::Ip(addr) => match addr {
-                SocketAddr::V4(addr) => addr,
-                _ => return Err(io::Error::new(io::ErrorKind::Other, "invalid ip version")),
+                let size = datagram.send(&datagram_buffer)?;
+
+                Ok(size.saturating_sub(header_size))
+            }
+            Socket::Shared(ref datagram, _) => {
+                let mut datagram_buffer = new_header(dst);
+                let header_size = datagram_buffer.len();
+                datagram_buffer.extend_from_slice(buffer);
+
+                let size = datagram.send(&datagram_buffer, Some(dst))?;
+
+                Ok(size.saturating_sub(header_size))
+            }
+            Socket::Direct(ref datagram) => datagram.send_to(buffer, dst),
+        }
+    }
+
+    /// Sends data on the socket to the given address in SOCKS5 UDP fragments, each of which
+    /// carries at most the max size of data (RFC 1928). The relay must support fragmentation to
+    /// reassemble the fragments, and a plain UDP socket or a tunnel cannot send fragments.
+    pub fn send_fragments_to(
+        &self,
+        buffer: &[u8],
+        dst: SocketAddrV4,
+        max_size: usize,
+    ) -> io::Result<usize> {
+        match self.datagram {
+            Socket::Socks(_, _) | Socket::Shared(_, _) => {}
+            Socket::Direct(_) | Socket::Tunnel(_, _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "fragmentation is not supported",
+                ))
+            }
+        }
+        if max_size == 0 || (buffer.len() + max_size - 1) / max_size > (!FRAG_END) as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("datagram of {} Bytes has too many fragments", buffer.len()),
+            ));
+        }
+
+        // Positions of fragments start from 1, and the last one has the end-of-fragment-sequence
+        // bit
+        let chunks = buffer.chunks(max_size).collect::<Vec<_>>();
+        let mut total = 0;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut datagram_buffer = new_header(SocketAddr::V4(dst));
+            let header_size = datagram_buffer.len();
+            datagram_buffer[2] = (i + 1) as u8;
+            if i == chunks.len() - 1 {
+                datagram_buffer[2] |= FRAG_END;
+            }
+            datagram_buffer.extend_from_slice(chunk);
+
+            let size = match self.datagram {
+                Socket::Shared(ref datagram, _) => {
+                    datagram.send(&datagram_buffer, Some(SocketAddr::V4(dst)))?
+                }
+                Socket::Socks(ref datagram, _) => datagram.send(&datagram_buffer)?,
+                Socket::Direct(_) | Socket::Tunnel(_, _) => unreachable!(),
+            };
+            total += size.saturating_sub(header_size);
+        }
+
+        Ok(total)
+    }
+
+    /// Receives a single datagram message on the socket. Fragmented datagrams are reassembled
+    /// (RFC 1928). Datagrams from IPv6 addresses other than IPv4-mapped ones are dropped. A
+    /// shared socket cannot be received, whose datagrams are passed to the handler.
+    pub fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        let datagram = match self.datagram {
+            Socket::Socks(ref datagram, _) | Socket::Tunnel(ref datagram, _) => datagram,
+            Socket::Shared(_, _) => return Err(shared::unreadable()),
+            Socket::Direct(ref datagram) => loop {
+                match datagram.recv_from(buffer)? {
+                    (size, SocketAddr::V4(addr)) => return Ok((size, addr)),
+                    (_, SocketAddr::V6(_)) => continue,
+                }
             },
-            _ => return Err(io::Error::new(io::ErrorKind::Other, "invalid address type")),
         };
+        loop {
+            let size = datagram.recv(buffer)?;
+            if let Some(result) = self.reassemble(buffer, size)? {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Creates a tokio socket sharing the underlying UDP socket, which will set the socket to the
+    /// non-blocking mode. This function must be called within a tokio runtime.
+    #[cfg(feature = "async")]
+    pub fn try_clone_async(&self) -> io::Result<tokio::net::UdpSocket> {
+        let datagram = match self.datagram {
+            Socket::Socks(ref datagram, _) | Socket::Tunnel(ref datagram, _) => {
+                datagram.try_clone()?
+            }
+            Socket::Shared(_, _) => return Err(shared::unreadable()),
+            Socket::Direct(ref datagram) => datagram.try_clone()?,
+        };
+        datagram.set_nonblocking(true)?;
+
+        tokio::net::UdpSocket::from_std(datagram)
+    }
+
+    /// Receives a single datagram message on the socket asynchronously, the given socket is one
+    /// created by `try_clone_async`. Fragmented datagrams are reassembled as `recv_from` does.
+    #[cfg(feature = "async")]
+    pub async fn recv_from_async(
+        &self,
+        socket: &tokio::net::UdpSocket,
+        buffer: &mut [u8],
+    ) -> io::Result<(usize, SocketAddrV4)> {
+        if let Socket::Direct(_) = self.datagram {
+            loop {
+                match socket.recv_from(buffer).await? {
+                    (size, SocketAddr::V4(addr)) => return Ok((size, addr)),
+                    (_, SocketAddr::V6(_)) => continue,
+                }
+            }
+        }
+        loop {
+            let size = socket.recv(buffer).await?;
+            if let Some(result) = self.reassemble(buffer, size)? {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Parses a SOCKS5 UDP datagram of the given size in the buffer, returns the size and the
+    /// source of the datagram if it is standalone or completes the fragments.
+    fn reassemble(
+        &self,
+        buffer: &mut [u8],
+        size: usize,
+    ) -> io::Result<Option<(usize, SocketAddrV4)>> {
+        self.reassembler.lock().unwrap().reassemble(buffer, size)
+    }
+}
+
+impl Drop for SocksDatagram {
+    fn drop(&mut self) {
+        // Close the association
+        match self.datagram {
+            Socket::Socks(_, ref stream) | Socket::Shared(_, ref stream) => {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            Socket::Tunnel(_, ref tunnel) => tunnel.close(),
+            Socket::Direct(_) => {}
+        }
+    }
+}
+
+/// Creates the header of a SOCKS5 UDP datagram to the given address.
+fn new_header(dst: SocketAddr) -> Vec<u8> {
+    let mut header = vec![0u8, 0, 0];
+    match dst.ip() {
+        IpAddr::V4(ip) => {
+            header.push(1);
+            header.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            header.push(4);
+            header.extend_from_slice(&ip.octets());
+        }
+    }
+    header.extend_from_slice(&dst.port().to_be_bytes());
+
+    header
+}
+
+/// Parses the header of a SOCKS5 UDP datagram, returns the FRAG field, the address and the size
+/// of the header.
+fn parse_header(buffer: &[u8]) -> io::Result<(u8, SocketAddr, usize)> {
+    if buffer.len() < 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid datagram header",
+        ));
+    }
+    if buffer[0] != 0 || buffer[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid reserved bytes",
+        ));
+    }
+
+    let addr = match buffer[3] {
+        // IPv4
+        1 => {
+            if buffer.len() < 10 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid datagram header",
+                ));
+            }
+            let addr = SocketAddrV4::new(
+                Ipv4Addr::new(buffer[4], buffer[5], buffer[6], buffer[7]),
+                u16::from_be_bytes([buffer[8], buffer[9]]),
+            );
+
+            (SocketAddr::V4(addr), 10)
+        }
+        // Domain
+        3 => return Err(io::Error::new(io::ErrorKind::Other, "invalid address type")),
+        // IPv6
+        4 => {
+            if buffer.len() < 22 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid datagram header",
+                ));
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buffer[4..20]);
+            let addr = SocketAddrV6::new(
+                Ipv6Addr::from(octets),
+                u16::from_be_bytes([buffer[20], buffer[21]]),
+                0,
+                0,
+            );
+
+            (SocketAddr::V6(addr), 22)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid address type",
+            ))
+        }
+    };
+
+    Ok((buffer[2], addr.0, addr.1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Get a SOCKS5 UDP datagram of the fragment from the source.
+    fn new_fragment(frag: u8, src: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = new_header(SocketAddr::V4(src));
+        datagram[2] = frag;
+        datagram.extend_from_slice(payload);
+
+        datagram
+    }
+
+    /// Reassembles the datagram in a buffer of the max size of UDP datagrams.
+    fn reassemble(
+        reassembler: &mut Reassembler,
+        datagram: &[u8],
+    ) -> Option<(Vec<u8>, SocketAddrV4)> {
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        buffer[..datagram.len()].copy_from_slice(datagram);
+
+        reassembler
+            .reassemble(&mut buffer, datagram.len())
+            .unwrap()
+            .map(|(size, addr)| (buffer[..size].to_vec(), addr))
+    }
+
+    fn src() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 53)
+    }
+
+    #[test]
+    fn reassemble_fragments() {
+        let mut reassembler = Reassembler::new();
+        let payload: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+
+        // A datagram larger than the MTU in 2 fragments
+        let first = new_fragment(1, src(), &payload[..1400]);
+        let second = new_fragment(2 | FRAG_END, src(), &payload[1400..]);
+        assert_eq!(reassemble(&mut reassembler, &first), None);
+        assert_eq!(
+            reassemble(&mut reassembler, &second),
+            Some((payload.clone(), src()))
+        );
+
+        // A standalone datagram is passed through, and abandons the fragments
+        assert_eq!(reassemble(&mut reassembler, &first), None);
+        let standalone = new_fragment(0, src(), b"standalone");
+        assert_eq!(
+            reassemble(&mut reassembler, &standalone),
+            Some((b"standalone".to_vec(), src()))
+        );
+        assert_eq!(reassemble(&mut reassembler, &second), None);
+
+        // Fragments are dropped if the fragmentation is disabled
+        reassembler.is_fragmentation = false;
+        assert_eq!(reassemble(&mut reassembler, &first), None);
+        assert_eq!(reassemble(&mut reassembler, &second), None);
+    }
+
+    #[test]
+    fn reassemble_out_of_order() {
+        let mut reassembler = Reassembler::new();
+        let first = new_fragment(1, src(), &[1; 1000]);
+        let second = new_fragment(2, src(), &[2; 1000]);
+        let third = new_fragment(3 | FRAG_END, src(), &[3; 1000]);
+
+        // The fragments are discarded once a position does not follow
+        assert_eq!(reassemble(&mut reassembler, &first), None);
+        assert_eq!(reassemble(&mut reassembler, &third), None);
+        assert!(reassembler.queue.is_none());
+        assert_eq!(reassemble(&mut reassembler, &second), None);
+        assert_eq!(reassemble(&mut reassembler, &third), None);
+
+        // And so are they once a fragment comes from another source
+        let other = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 8), 53);
+        assert_eq!(reassemble(&mut reassembler, &first), None);
+        assert_eq!(
+            reassemble(&mut reassembler, &new_fragment(2, other, &[2; 1000])),
+            None
+        );
+        assert!(reassembler.queue.is_none());
+
+        // A new first fragment starts over
+        assert_eq!(reassemble(&mut reassembler, &first), None);
+        assert_eq!(reassemble(&mut reassembler, &second), None);
+        let (data, _) = reassemble(&mut reassembler, &third).unwrap();
+        assert_eq!(data.len(), 3000);
+        assert!(data[..1000].iter().all(|&b| b == 1));
+        assert!(data[2000..].iter().all(|&b| b == 3));
+    }
+
+    #[test]
+    fn reassemble_timeout() {
+        let mut reassembler = Reassembler::new();
+        let first = new_fragment(1, src(), &[1; 1000]);
+        let second = new_fragment(2 | FRAG_END, src(), &[2; 1000]);
+
+        // The fragments are discarded once the reassembly timer expires
+        assert_eq!(reassemble(&mut reassembler, &first), None);
+        let queue = reassembler.queue.as_mut().unwrap();
+        queue.instant -= Duration::from_millis(REASSEMBLY_TIMEOUT as u64);
+        assert_eq!(reassemble(&mut reassembler, &second), None);
+        assert!(reassembler.queue.is_none());
+
+        // Fragments in the timer are reassembled
+        assert_eq!(reassemble(&mut reassembler, &first), None);
+        assert_eq!(
+            reassemble(&mut reassembler, &second).map(|(data, _)| data.len()),
+            Some(2000)
+        );
+    }
+
+    /// Represents a SOCKS4 request received by the mock server, the address, the userid and the
+    /// host name of SOCKS4a.
+    type RequestV4 = (SocketAddrV4, String, Option<String>);
+
+    /// Reads a string terminated by a null.
+    fn read_null_terminated(stream: &mut TcpStream) -> String {
+        let mut s = Vec::new();
+        let mut byte = [0u8; 1];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == 0 {
+                return String::from_utf8(s).unwrap();
+            }
+            s.push(byte[0]);
+        }
+    }
+
+    /// Starts a mock SOCKS4 server serving a single CONNECT with the reply code, which echoes
+    /// the stream if the request is granted. Returns its address and the receiver of the request.
+    fn mock_socks4(code: u8) -> (SocketAddrV4, mpsc::Receiver<RequestV4>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0u8; 8];
+            stream.read_exact(&mut header).unwrap();
+            assert_eq!(&header[..2], &[4, 1]);
+            let dst = SocketAddrV4::new(
+                Ipv4Addr::new(header[4], header[5], header[6], header[7]),
+                u16::from_be_bytes([header[2], header[3]]),
+            );
+            let userid = read_null_terminated(&mut stream);
+            // SOCKS4a carries the host name after the address 0.0.0.x
+            let octets = dst.ip().octets();
+            let host = match octets[..3] == [0, 0, 0] && octets[3] != 0 {
+                true => Some(read_null_terminated(&mut stream)),
+                false => None,
+            };
+            tx.send((dst, userid, host)).unwrap();
+
+            stream.write_all(&[0, code, 0, 0, 0, 0, 0, 0]).unwrap();
+            if code == 90 {
+                let mut buffer = [0u8; 1500];
+                while let Ok(n) = stream.read(&mut buffer) {
+                    if n == 0 || stream.write_all(&buffer[..n]).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        (addr, rx)
+    }
+
+    fn socks4_option(version: Version) -> SocksOption {
+        SocksOption::new(
+            version,
+            Some(SocksAuth::new(String::from("alice"), String::new())),
+        )
+    }
+
+    #[test]
+    fn socks4_replies() {
+        let dst = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 80);
+        for &(code, expected) in &[
+            (90, None),
+            (91, Some(ReplyError::Rejected)),
+            (92, Some(ReplyError::IdentdUnreachable)),
+            (93, Some(ReplyError::IdentdMismatch)),
+        ] {
+            let (remote, requests) = mock_socks4(code);
+            let result = connect(remote, SocketAddr::V4(dst), &socks4_option(Version::V4));
+            // The username is sent as the userid
+            assert_eq!(requests.recv().unwrap(), (dst, String::from("alice"), None));
+            match expected {
+                None => {
+                    let mut stream = result.unwrap();
+                    stream.write_all(b"ping").unwrap();
+                    let mut buffer = [0u8; 4];
+                    stream.read_exact(&mut buffer).unwrap();
+                    assert_eq!(&buffer, b"ping");
+                }
+                Some(expected) => {
+                    let e = result.unwrap_err();
+                    assert_eq!(ReplyError::from_io_error(&e), Some(expected));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn socks4a_host() {
+        // Host names are sent after the address 0.0.0.1 in SOCKS4a
+        let (remote, requests) = mock_socks4(90);
+        connect_host(remote, "example.com", 443, &socks4_option(Version::V4a)).unwrap();
+        assert_eq!(
+            requests.recv().unwrap(),
+            (
+                SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 1), 443),
+                String::from("alice"),
+                Some(String::from("example.com"))
+            )
+        );
+
+        // And addresses are sent as in SOCKS4
+        let (remote, requests) = mock_socks4(90);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 80);
+        connect(remote, SocketAddr::V4(dst), &socks4_option(Version::V4a)).unwrap();
+        assert_eq!(requests.recv().unwrap(), (dst, String::from("alice"), None));
+
+        // Neither host names nor IPv6 in SOCKS4, which fail before any request is sent
+        let option = socks4_option(Version::V4);
+        let request = new_request_v4(&("example.com", 443).to_target_addr().unwrap(), &option);
+        assert_eq!(request.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let ipv6 = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 80, 0, 0));
+        let request = new_request_v4(&ipv6.to_target_addr().unwrap(), &option);
+        assert_eq!(request.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // UDP is never relayed in SOCKS4
+        let connector = SocksConnector::new(remote, option);
+        let e = connector
+            .bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    /// Reads the address of a SOCKS5 request in the address type, which is followed by the port.
+    fn read_addr_v5(stream: &mut TcpStream, address_type: u8) -> SocketAddr {
+        let size = match address_type {
+            1 => 4,
+            4 => 16,
+            _ => panic!("unexpected address type {}", address_type),
+        };
+        let mut addr = vec![0u8; size + 2];
+        stream.read_exact(&mut addr).unwrap();
+
+        match parse_bound_addr_v5(address_type, &addr, size) {
+            TargetAddr::Ip(addr) => addr,
+            TargetAddr::Domain(_, _) => unreachable!(),
+        }
+    }
+
+    /// Starts a mock SOCKS5 server serving a single request without authentication, which
+    /// replies the bound address in its address type, and echoes the stream of a CONNECT or
+    /// holds the control connection of a UDP ASSOCIATE. Returns its address and the receiver of
+    /// the command and the address of the request.
+    fn mock_socks5(bound: SocketAddr) -> (SocketAddrV4, mpsc::Receiver<(u8, SocketAddr)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut methods = [0u8; 3];
+            stream.read_exact(&mut methods).unwrap();
+            assert_eq!(methods, [5, 1, 0]);
+            stream.write_all(&[5, 0]).unwrap();
+
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).unwrap();
+            let dst = read_addr_v5(&mut stream, request[3]);
+            tx.send((request[1], dst)).unwrap();
+
+            let mut reply = new_request_v5(0, &bound.to_target_addr().unwrap()).unwrap();
+            reply[1] = 0;
+            stream.write_all(&reply).unwrap();
+            let mut buffer = [0u8; 1500];
+            while let Ok(n) = stream.read(&mut buffer) {
+                if n == 0 {
+                    break;
+                }
+                if request[1] == 1 && stream.write_all(&buffer[..n]).is_err() {
+                    break;
+                }
+            }
+        });
+
+        (addr, rx)
+    }
+
+    #[test]
+    fn socks5_ipv6_connect() {
+        let dst = SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 7),
+            443,
+            0,
+            0,
+        ));
+        let bound = SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 1, 1),
+            1080,
+            0,
+            0,
+        ));
+
+        // The request carries 16 bytes of the address
+        let request = new_request_v5(1, &dst.to_target_addr().unwrap()).unwrap();
+        assert_eq!(request.len(), 4 + 16 + 2);
+        assert_eq!(request[3], 4);
+
+        // The bound address is parsed in IPv6, and the stream is relayed
+        let (remote, requests) = mock_socks5(bound);
+        let mut stream = connect_tcp(remote, None).unwrap();
+        negotiate_v5(&mut stream, None).unwrap();
+        let addr = request_v5(&mut stream, 1, &dst.to_target_addr().unwrap()).unwrap();
+        match addr {
+            TargetAddr::Ip(addr) => assert_eq!(addr, bound),
+            TargetAddr::Domain(_, _) => panic!("unexpected domain"),
+        }
+        assert_eq!(requests.recv().unwrap(), (1, dst));
+        stream.write_all(b"ping").unwrap();
+        let mut buffer = [0u8; 4];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+    }
+
+    #[test]
+    fn socks5_ipv6_associate() {
+        // The relay replied in `::` is substituted with the address of the proxy
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        relay
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let bound = SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::UNSPECIFIED,
+            relay.local_addr().unwrap().port(),
+            0,
+            0,
+        ));
+        let (remote, requests) = mock_socks5(bound);
+        let datagram = SocksDatagram::bind(
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+            remote,
+            &SocksOption::default(),
+        )
+        .unwrap();
+        datagram
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(requests.recv().unwrap().0, 3);
+
+        // The header to an IPv6 address is 22 bytes, and the size sent excludes it
+        let mapped = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 53);
+        let dst = SocketAddr::V6(SocketAddrV6::new(mapped.ip().to_ipv6_mapped(), 53, 0, 0));
+        assert_eq!(datagram.send_to_addr(b"query", dst).unwrap(), 5);
+        let mut buffer = [0u8; 1500];
+        let (size, addr) = relay.recv_from(&mut buffer).unwrap();
+        assert_eq!(size, 22 + 5);
+        assert_eq!(parse_header(&buffer[..size]).unwrap(), (0, dst, 22));
+        assert_eq!(&buffer[22..size], b"query");
 
-        Ok((size, addr))
+        // Datagrams from IPv6 addresses are dropped, and from IPv4-mapped ones are received
+        let ipv6 = SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 7),
+            53,
+            0,
+            0,
+        ));
+        for src in [ipv6, dst].iter() {
+            let mut reply = new_header(*src);
+            reply.extend_from_slice(b"answer");
+            relay.send_to(&reply, addr).unwrap();
+        }
+        let (size, src) = datagram.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"answer");
+        assert_eq!(src, mapped);
     }
 }
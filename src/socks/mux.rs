@@ -0,0 +1,961 @@
+//! Multiplexed tunnel of TCP streams over a single TCP connection to a companion server, with
+//! flow control and optional LZ4 compression per stream.
+//!
+//! Every frame on the connection is the type in 1 byte, the ID of the stream in 4 bytes and the
+//! size of the payload in 2 bytes, both in network byte order, followed by the payload:
+//!
+//! | Type | Name     | Payload                                                      |
+//! |------|----------|--------------------------------------------------------------|
+//! | 1    | OPEN     | `DST.ADDR` in 4 bytes, `DST.PORT` in 2 bytes and the flags   |
+//! | 2    | REPLY    | `REP` in 1 byte, as the reply field of SOCKS5                |
+//! | 3    | DATA     | Data                                                         |
+//! | 4    | DATA_LZ4 | Data compressed as an LZ4 block                              |
+//! | 5    | WINDOW   | Increment of the window in 4 bytes                           |
+//! | 6    | FIN      | None, the sender sends no more data on the stream            |
+//! | 7    | RST      | None, the stream is closed in both directions                |
+//!
+//! The client opens a stream with an OPEN of a new ID, and sends nothing else on the stream until
+//! the server answers a REPLY after it connects to the destination. Bit 0 of the flags offers
+//! LZ4, and the server may only compress data of a stream offered it.
+//!
+//! Each side may send up to 256 KiB of data on a stream before the peer grants more with WINDOW
+//! frames, which the peer sends after the data is written to the destination, so a stream not
+//! drained cannot hold the connection. Data frames of streams are sent in turns, one frame of at
+//! most 16 KiB each, so a bulk transfer cannot starve interactive streams.
+//!
+//! A sender sniffs the first data it sends on a stream, data starting like a TLS record or a
+//! compressed format is never compressed on the stream. Compressed data is only sent if it is
+//! smaller, and windows count data before compression.
+
+use log::{debug, info, trace};
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+use super::BoxFuture;
+use super::{connect_tcp, lz4, parse_reply_v5, ProxyConnector, UnreachableError, CONNECT_TIMEOUT};
+
+/// Represents the size of the header of a frame.
+const HEADER_SIZE: usize = 7;
+/// Represents the max size of data in a frame.
+const MAX_DATA_SIZE: usize = 16 * 1024;
+/// Represents the initial window of a stream in each direction.
+const INITIAL_WINDOW: usize = 256 * 1024;
+/// Represents the flag offering LZ4 in an OPEN.
+const FLAG_LZ4: u8 = 1;
+/// Represents the idle time before keepalive probes of the connection.
+const KEEPALIVE: Duration = Duration::from_secs(60);
+
+const FRAME_OPEN: u8 = 1;
+const FRAME_REPLY: u8 = 2;
+const FRAME_DATA: u8 = 3;
+const FRAME_DATA_LZ4: u8 = 4;
+const FRAME_WINDOW: u8 = 5;
+const FRAME_FIN: u8 = 6;
+const FRAME_RST: u8 = 7;
+
+/// Represents the reply of a stream connected.
+const REPLY_SUCCEEDED: u8 = 0;
+/// Represents the reply of a stream failed for other reasons.
+const REPLY_GENERAL_FAILURE: u8 = 1;
+/// Represents the reply of a stream whose destination is unreachable.
+const REPLY_HOST_UNREACHABLE: u8 = 4;
+/// Represents the reply of a stream refused by the destination.
+const REPLY_CONNECTION_REFUSED: u8 = 5;
+/// Represents the reply of a stream whose destination does not answer in time.
+const REPLY_TTL_EXPIRED: u8 = 6;
+
+/// Represents signatures of data which is encrypted or compressed already.
+const INCOMPRESSIBLE_SIGNATURES: [&[u8]; 12] = [
+    // gzip
+    b"\x1f\x8b",
+    // zstd
+    b"\x28\xb5\x2f\xfd",
+    // ZIP
+    b"PK\x03\x04",
+    // bzip2
+    b"BZh",
+    // xz
+    b"\xfd7zXZ",
+    // 7z
+    b"7z\xbc\xaf",
+    // RAR
+    b"Rar!",
+    // PNG
+    b"\x89PNG",
+    // JPEG
+    b"\xff\xd8\xff",
+    // GIF
+    b"GIF8",
+    // Ogg
+    b"OggS",
+    // SSH, whose data is encrypted after the banner
+    b"SSH-",
+];
+
+/// Represents the function connecting to the destination of a stream on the server.
+pub type Accept = dyn Fn(SocketAddrV4) -> io::Result<TcpStream> + Send + Sync;
+
+/// Represents a frame of the tunnel.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Frame {
+    kind: u8,
+    id: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(kind: u8, id: u32, payload: Vec<u8>) -> Frame {
+        Frame { kind, id, payload }
+    }
+
+    /// Creates a data frame, the data is compressed if it is allowed and gets smaller.
+    fn new_data(id: u32, data: &[u8], is_compressed: bool) -> Frame {
+        if is_compressed {
+            let compressed = lz4::compress(data);
+            if compressed.len() < data.len() {
+                return Frame::new(FRAME_DATA_LZ4, id, compressed);
+            }
+        }
+
+        Frame::new(FRAME_DATA, id, data.to_vec())
+    }
+
+    /// Encodes the frame.
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        buffer.push(self.kind);
+        buffer.extend_from_slice(&self.id.to_be_bytes());
+        buffer.extend_from_slice(&(self.payload.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(&self.payload);
+
+        buffer
+    }
+
+    /// Reads a frame from the stream. Returns `None` if the stream ends between frames.
+    fn read<R: Read>(reader: &mut R) -> io::Result<Option<Frame>> {
+        let mut header = [0u8; HEADER_SIZE];
+        let mut size = 0;
+        while size < HEADER_SIZE {
+            match reader.read(&mut header[size..]) {
+                Ok(0) if size == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => size += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let kind = header[0];
+        let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        let size = u16::from_be_bytes([header[5], header[6]]) as usize;
+        let mut payload = vec![0u8; size];
+        reader.read_exact(&mut payload)?;
+
+        Ok(Some(Frame::new(kind, id, payload)))
+    }
+
+    /// Get the data of a data frame, which is decompressed if it is compressed.
+    fn into_data(self) -> io::Result<Vec<u8>> {
+        match self.kind {
+            FRAME_DATA_LZ4 => lz4::decompress(&self.payload, MAX_DATA_SIZE),
+            _ => Ok(self.payload),
+        }
+    }
+}
+
+/// Returns if data starting with the given bytes is worth compressing. TLS records and formats
+/// compressed already are not.
+fn is_compressible(data: &[u8]) -> bool {
+    // TLS records of change cipher spec, alert, handshake and application data
+    if data.len() >= 3 && (0x14..=0x17).contains(&data[0]) && data[1] == 3 {
+        return false;
+    }
+
+    !INCOMPRESSIBLE_SIGNATURES
+        .iter()
+        .any(|signature| data.starts_with(signature))
+}
+
+/// Represents a chunk received on a stream.
+#[derive(Debug)]
+enum Chunk {
+    Data(Vec<u8>),
+    Fin,
+}
+
+/// Represents a stream of a session.
+struct Stream {
+    /// Represents the size of data the peer can still receive
+    window: Mutex<usize>,
+    condvar: Condvar,
+    is_reset: AtomicBool,
+    /// Represents if data sent on the stream may be compressed
+    is_compressible: bool,
+    tx: Mutex<Option<Sender<Chunk>>>,
+    rx: Mutex<Option<Receiver<Chunk>>>,
+    /// Represents the sender of the reply of the stream, which is only used by the client
+    reply: Mutex<Option<Sender<u8>>>,
+    /// Represents the count of directions of the stream finished
+    halves: AtomicUsize,
+}
+
+impl Stream {
+    fn new(is_compressible: bool, reply: Option<Sender<u8>>) -> Stream {
+        let (tx, rx) = mpsc::channel();
+
+        Stream {
+            window: Mutex::new(INITIAL_WINDOW),
+            condvar: Condvar::new(),
+            is_reset: AtomicBool::new(false),
+            is_compressible,
+            tx: Mutex::new(Some(tx)),
+            rx: Mutex::new(Some(rx)),
+            reply: Mutex::new(reply),
+            halves: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes the window for data of the size, which waits until the peer grants enough window.
+    /// Returns `false` if the stream is reset.
+    fn take_window(&self, size: usize) -> bool {
+        let mut window = self.window.lock().unwrap();
+        while *window < size {
+            if self.is_reset.load(Ordering::Relaxed) {
+                return false;
+            }
+            window = self.condvar.wait(window).unwrap();
+        }
+        *window -= size;
+
+        !self.is_reset.load(Ordering::Relaxed)
+    }
+
+    /// Grants window to the stream.
+    fn grant_window(&self, size: usize) {
+        *self.window.lock().unwrap() += size;
+        self.condvar.notify_all();
+    }
+
+    /// Receives a chunk from the peer.
+    fn receive(&self, chunk: Chunk) {
+        let mut tx = self.tx.lock().unwrap();
+        let is_fin = matches!(chunk, Chunk::Fin);
+        if let Some(ref sender) = *tx {
+            let _ = sender.send(chunk);
+        }
+        // Nothing can be received after a FIN
+        if is_fin {
+            *tx = None;
+        }
+    }
+
+    /// Resets the stream, its threads exit after.
+    fn reset(&self) {
+        // Hold the lock so a thread waiting for window is woken
+        let _window = self.window.lock().unwrap();
+        self.is_reset.store(true, Ordering::Relaxed);
+        self.condvar.notify_all();
+        *self.tx.lock().unwrap() = None;
+        *self.reply.lock().unwrap() = None;
+    }
+
+    fn is_reset(&self) -> bool {
+        self.is_reset.load(Ordering::Relaxed)
+    }
+}
+
+/// Represents frames waiting to be sent on the connection.
+#[derive(Default)]
+struct Queues {
+    /// Represents frames other than data and FINs, which go first
+    control: VecDeque<Vec<u8>>,
+    data: HashMap<u32, VecDeque<Vec<u8>>>,
+    /// Represents the streams with data in turn
+    ready: VecDeque<u32>,
+    is_closed: bool,
+}
+
+/// Represents a session of the tunnel, which multiplexes streams over a TCP connection. The
+/// client opens streams by `connect`, and the server connects to the destinations of streams
+/// opened by the client.
+pub struct MuxSession {
+    stream: TcpStream,
+    streams: Mutex<HashMap<u32, Arc<Stream>>>,
+    queues: Mutex<Queues>,
+    condvar: Condvar,
+    next_id: AtomicU32,
+    is_closed: AtomicBool,
+    accept: Option<Box<Accept>>,
+}
+
+impl MuxSession {
+    /// Opens a session of the client on the connection to the server, which runs on its own
+    /// threads until the connection breaks.
+    pub fn open(stream: TcpStream) -> io::Result<Arc<MuxSession>> {
+        let session = MuxSession::new(stream, None)?;
+        let reader = session.stream.try_clone()?;
+        let session_cloned = Arc::clone(&session);
+        thread::spawn(move || session_cloned.receive(reader));
+
+        Ok(session)
+    }
+
+    /// Serves a session of the server on the connection to the client, which connects to the
+    /// destinations of streams by the given function. Returns after the connection breaks.
+    pub fn serve(stream: TcpStream, accept: Box<Accept>) -> io::Result<()> {
+        let session = MuxSession::new(stream, Some(accept))?;
+        let reader = session.stream.try_clone()?;
+
+        session.receive(reader)
+    }
+
+    fn new(stream: TcpStream, accept: Option<Box<Accept>>) -> io::Result<Arc<MuxSession>> {
+        stream.set_nodelay(true)?;
+        let _ = super::set_keepalive(&stream, KEEPALIVE);
+        let writer = stream.try_clone()?;
+
+        let session = Arc::new(MuxSession {
+            stream,
+            streams: Mutex::new(HashMap::new()),
+            queues: Mutex::new(Queues::default()),
+            condvar: Condvar::new(),
+            next_id: AtomicU32::new(1),
+            is_closed: AtomicBool::new(false),
+            accept,
+        });
+        let session_cloned = Arc::clone(&session);
+        thread::spawn(move || session_cloned.send(writer));
+
+        Ok(session)
+    }
+
+    /// Opens a stream to the destination. The stream fails with `TimedOut` if the server does not
+    /// reply in the timeout. Returns a loopback stream carrying the stream.
+    pub fn connect(
+        self: &Arc<Self>,
+        dst: SocketAddrV4,
+        timeout: Duration,
+    ) -> io::Result<TcpStream> {
+        if self.is_closed() {
+            return Err(closed());
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (reply_tx, reply_rx) = mpsc::channel();
+        let stream = Arc::new(Stream::new(true, Some(reply_tx)));
+        self.streams.lock().unwrap().insert(id, Arc::clone(&stream));
+
+        let mut payload = dst.ip().octets().to_vec();
+        payload.extend_from_slice(&dst.port().to_be_bytes());
+        payload.push(FLAG_LZ4);
+        self.push_control(Frame::new(FRAME_OPEN, id, payload));
+
+        // Reply
+        match reply_rx.recv_timeout(timeout) {
+            Ok(REPLY_SUCCEEDED) => {}
+            Ok(reply) => {
+                self.remove(id);
+                parse_reply_v5(&[5, reply, 0, 1])?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid response code",
+                ));
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                self.reset(id, true);
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            Err(RecvTimeoutError::Disconnected) => return Err(closed()),
+        }
+
+        let (local, remote) = match pair() {
+            Ok(pair) => pair,
+            Err(e) => {
+                self.reset(id, true);
+                return Err(e);
+            }
+        };
+        self.attach(id, stream, local)?;
+        trace!("tunnel: open stream {} to {}", id, dst);
+
+        Ok(remote)
+    }
+
+    /// Returns if the session is closed.
+    pub fn is_closed(&self) -> bool {
+        self.is_closed.load(Ordering::Relaxed)
+    }
+
+    /// Closes the session, all its streams are reset.
+    pub fn close(&self) {
+        if self.is_closed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let _ = self.stream.shutdown(Shutdown::Both);
+        {
+            let mut queues = self.queues.lock().unwrap();
+            queues.is_closed = true;
+            self.condvar.notify_all();
+        }
+        for (_, stream) in self.streams.lock().unwrap().drain() {
+            stream.reset();
+        }
+    }
+
+    /// Reads frames from the connection until it breaks.
+    fn receive(self: &Arc<Self>, mut reader: TcpStream) -> io::Result<()> {
+        let result = loop {
+            let frame = match Frame::read(&mut reader) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            if let Err(e) = self.handle(frame) {
+                break Err(e);
+            }
+        };
+        if let Err(ref e) = result {
+            if !self.is_closed() {
+                debug!("tunnel: {}", e);
+            }
+        }
+        self.close();
+
+        result
+    }
+
+    /// Handles a frame from the peer. Returns an error if the frame is invalid, after which the
+    /// connection cannot be decoded anymore.
+    fn handle(self: &Arc<Self>, frame: Frame) -> io::Result<()> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid frame");
+
+        let id = frame.id;
+        let stream = self.streams.lock().unwrap().get(&id).cloned();
+        match frame.kind {
+            FRAME_OPEN => {
+                if self.accept.is_none() || stream.is_some() || frame.payload.len() != 7 {
+                    return Err(invalid());
+                }
+                let p = &frame.payload;
+                let dst = SocketAddrV4::new(
+                    Ipv4Addr::new(p[0], p[1], p[2], p[3]),
+                    u16::from_be_bytes([p[4], p[5]]),
+                );
+                let stream = Arc::new(Stream::new(p[6] & FLAG_LZ4 != 0, None));
+                self.streams.lock().unwrap().insert(id, Arc::clone(&stream));
+
+                let session = Arc::clone(self);
+                thread::spawn(move || session.accept(id, stream, dst));
+            }
+            FRAME_REPLY => {
+                let reply = *frame.payload.first().ok_or_else(invalid)?;
+                // Replies of streams timed out are dropped
+                if let Some(stream) = stream {
+                    if let Some(tx) = stream.reply.lock().unwrap().take() {
+                        let _ = tx.send(reply);
+                    }
+                }
+            }
+            FRAME_DATA | FRAME_DATA_LZ4 => {
+                let data = frame.into_data()?;
+                // Data of streams reset is dropped
+                if let Some(stream) = stream {
+                    stream.receive(Chunk::Data(data));
+                }
+            }
+            FRAME_WINDOW => {
+                if frame.payload.len() != 4 {
+                    return Err(invalid());
+                }
+                let p = &frame.payload;
+                let size = u32::from_be_bytes([p[0], p[1], p[2], p[3]]) as usize;
+                if let Some(stream) = stream {
+                    stream.grant_window(size);
+                }
+            }
+            FRAME_FIN => {
+                if let Some(stream) = stream {
+                    stream.receive(Chunk::Fin);
+                }
+            }
+            FRAME_RST => self.reset(id, false),
+            _ => return Err(invalid()),
+        }
+
+        Ok(())
+    }
+
+    /// Connects to the destination of a stream on the server and replies.
+    fn accept(self: &Arc<Self>, id: u32, stream: Arc<Stream>, dst: SocketAddrV4) {
+        // The server only opens sessions with a function to connect
+        let result = (self.accept.as_ref().unwrap())(dst);
+        let reply = match result {
+            Ok(_) => REPLY_SUCCEEDED,
+            Err(ref e) => {
+                debug!("tunnel: connect to {}: {}", dst, e);
+                match e.kind() {
+                    io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => REPLY_TTL_EXPIRED,
+                    io::ErrorKind::AddrNotAvailable => REPLY_HOST_UNREACHABLE,
+                    _ => REPLY_GENERAL_FAILURE,
+                }
+            }
+        };
+        self.push_control(Frame::new(FRAME_REPLY, id, vec![reply]));
+
+        match result {
+            Ok(local) => {
+                if self.attach(id, stream, local).is_err() {
+                    self.reset(id, true);
+                }
+            }
+            Err(_) => self.remove(id),
+        }
+    }
+
+    /// Relays a stream between the session and the local stream on their own threads.
+    fn attach(self: &Arc<Self>, id: u32, stream: Arc<Stream>, local: TcpStream) -> io::Result<()> {
+        let rx = match stream.rx.lock().unwrap().take() {
+            Some(rx) => rx,
+            None => return Err(closed()),
+        };
+        let local_cloned = local.try_clone()?;
+
+        let session = Arc::clone(self);
+        let stream_cloned = Arc::clone(&stream);
+        thread::spawn(move || session.upload(id, stream_cloned, local_cloned));
+        let session = Arc::clone(self);
+        thread::spawn(move || session.download(id, stream, local, rx));
+
+        Ok(())
+    }
+
+    /// Sends data read from the local stream to the peer until it ends or the stream is reset.
+    fn upload(&self, id: u32, stream: Arc<Stream>, mut local: TcpStream) {
+        let mut buffer = vec![0u8; MAX_DATA_SIZE];
+        let mut compression = None;
+        loop {
+            let size = match local.read(&mut buffer) {
+                Ok(0) => {
+                    if !stream.is_reset() {
+                        self.push_data(id, Frame::new(FRAME_FIN, id, Vec::new()));
+                    }
+                    break;
+                }
+                Ok(size) => size,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.reset(id, true);
+                    break;
+                }
+            };
+            if !stream.take_window(size) {
+                break;
+            }
+            // The first data decides if the stream is compressed
+            let is_compressed = *compression
+                .get_or_insert_with(|| stream.is_compressible && is_compressible(&buffer[..size]));
+            self.push_data(id, Frame::new_data(id, &buffer[..size], is_compressed));
+        }
+        self.finish(id, &stream);
+    }
+
+    /// Writes data received from the peer to the local stream until the peer ends or the stream
+    /// is reset. Window is granted to the peer after the data is written.
+    fn download(&self, id: u32, stream: Arc<Stream>, mut local: TcpStream, rx: Receiver<Chunk>) {
+        loop {
+            match rx.recv() {
+                Ok(Chunk::Data(data)) => {
+                    if local.write_all(&data).is_err() {
+                        self.reset(id, true);
+                        let _ = local.shutdown(Shutdown::Both);
+                        break;
+                    }
+                    let increment = (data.len() as u32).to_be_bytes().to_vec();
+                    self.push_control(Frame::new(FRAME_WINDOW, id, increment));
+                }
+                Ok(Chunk::Fin) => {
+                    let _ = local.shutdown(Shutdown::Write);
+                    break;
+                }
+                // The stream is reset, which wakes the upload too
+                Err(_) => {
+                    let _ = local.shutdown(Shutdown::Both);
+                    break;
+                }
+            }
+        }
+        self.finish(id, &stream);
+    }
+
+    /// Writes frames to the connection until the session is closed. Control frames go first, and
+    /// streams with data send a frame each in turn.
+    fn send(&self, mut writer: TcpStream) {
+        loop {
+            let frame = {
+                let mut queues = self.queues.lock().unwrap();
+                loop {
+                    if queues.is_closed {
+                        return;
+                    }
+                    if let Some(frame) = queues.control.pop_front() {
+                        break frame;
+                    }
+                    if let Some(id) = queues.ready.pop_front() {
+                        let frame = match queues.data.get_mut(&id) {
+                            Some(queue) => queue.pop_front(),
+                            None => None,
+                        };
+                        if let Some(frame) = frame {
+                            if queues.data.get(&id).map_or(true, |queue| queue.is_empty()) {
+                                queues.data.remove(&id);
+                            } else {
+                                queues.ready.push_back(id);
+                            }
+                            break frame;
+                        }
+                        continue;
+                    }
+                    queues = self.condvar.wait(queues).unwrap();
+                }
+            };
+            if let Err(ref e) = writer.write_all(&frame) {
+                if !self.is_closed() {
+                    debug!("tunnel: {}", e);
+                }
+                self.close();
+                return;
+            }
+        }
+    }
+
+    fn push_control(&self, frame: Frame) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.control.push_back(frame.encode());
+        self.condvar.notify_all();
+    }
+
+    fn push_data(&self, id: u32, frame: Frame) {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.data.entry(id).or_default();
+        let is_idle = queue.is_empty();
+        queue.push_back(frame.encode());
+        if is_idle {
+            queues.ready.push_back(id);
+        }
+        self.condvar.notify_all();
+    }
+
+    /// Resets a stream, the peer is signaled by a RST if `is_notified` is true.
+    fn reset(&self, id: u32, is_notified: bool) {
+        if let Some(stream) = self.streams.lock().unwrap().remove(&id) {
+            stream.reset();
+        }
+        // Data not sent yet is dropped
+        let mut queues = self.queues.lock().unwrap();
+        queues.data.remove(&id);
+        if is_notified {
+            queues
+                .control
+                .push_back(Frame::new(FRAME_RST, id, Vec::new()).encode());
+            self.condvar.notify_all();
+        }
+    }
+
+    /// Finishes a direction of a stream, the stream is removed after both directions finish.
+    fn finish(&self, id: u32, stream: &Stream) {
+        if stream.halves.fetch_add(1, Ordering::Relaxed) + 1 == 2 {
+            self.remove(id);
+        }
+    }
+
+    fn remove(&self, id: u32) {
+        self.streams.lock().unwrap().remove(&id);
+    }
+}
+
+/// Get the error of a stream of a closed session.
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "the tunnel is closed")
+}
+
+/// Creates a pair of connected loopback streams.
+fn pair() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+    let stream = TcpStream::connect(listener.local_addr()?)?;
+    // Connections from others are dropped
+    loop {
+        let (accepted, addr) = listener.accept()?;
+        if addr == stream.local_addr()? {
+            return Ok((accepted, stream));
+        }
+    }
+}
+
+/// Represents a proxy which multiplexes connections over a single TCP connection to a companion
+/// server. The connection is opened on the first connection, and opened again after it breaks.
+pub struct MuxConnector {
+    remote: SocketAddrV4,
+    session: Mutex<Option<Arc<MuxSession>>>,
+}
+
+impl MuxConnector {
+    /// Creates a new `MuxConnector`.
+    pub fn new(remote: SocketAddrV4) -> MuxConnector {
+        MuxConnector {
+            remote,
+            session: Mutex::new(None),
+        }
+    }
+
+    /// Get the session to the server, which is opened if there is none or it is closed.
+    fn get_session(&self, timeout: Duration) -> io::Result<Arc<MuxSession>> {
+        let mut session = self.session.lock().unwrap();
+        if let Some(ref session) = *session {
+            if !session.is_closed() {
+                return Ok(Arc::clone(session));
+            }
+        }
+
+        let remote = self.remote;
+        let stream = connect_tcp(remote, Some(min(timeout, CONNECT_TIMEOUT)))
+            .map_err(|e| io::Error::new(e.kind(), UnreachableError { remote, e }))?;
+        let opened = MuxSession::open(stream)?;
+        info!("Open tunnel to {}", remote);
+        *session = Some(Arc::clone(&opened));
+
+        Ok(opened)
+    }
+}
+
+impl ProxyConnector for MuxConnector {
+    fn get_remote(&self) -> SocketAddrV4 {
+        self.remote
+    }
+
+    fn handshake(&self, _: &mut TcpStream, _: SocketAddrV4) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} does not support handshakes on streams", self),
+        ))
+    }
+
+    fn connect(&self, dst: SocketAddrV4, timeout: Duration) -> io::Result<TcpStream> {
+        let session = self.get_session(timeout)?;
+
+        session.connect(dst, timeout).map_err(|e| match e.kind() {
+            io::ErrorKind::TimedOut => io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{}: handshake timed out", self),
+            ),
+            _ => e,
+        })
+    }
+
+    #[cfg(feature = "async")]
+    fn handshake_async<'a>(
+        &'a self,
+        _: &'a mut tokio::net::TcpStream,
+        _: SocketAddrV4,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} does not support handshakes on streams", self),
+            ))
+        })
+    }
+
+    #[cfg(feature = "async")]
+    fn connect_async(
+        &self,
+        dst: SocketAddrV4,
+        timeout: Duration,
+    ) -> BoxFuture<'_, io::Result<tokio::net::TcpStream>> {
+        // Streams are opened by the threads of the session, which is blocking but brief since
+        // the connection to the server is shared
+        Box::pin(async move {
+            let stream = self.connect(dst, timeout)?;
+            stream.set_nonblocking(true)?;
+
+            tokio::net::TcpStream::from_std(stream)
+        })
+    }
+}
+
+impl Display for MuxConnector {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "tunnel {}", self.remote)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn frame_encode_read() {
+        let frames = [
+            Frame::new(FRAME_OPEN, 1, vec![192, 0, 2, 1, 0, 80, FLAG_LZ4]),
+            Frame::new(FRAME_REPLY, 1, vec![REPLY_SUCCEEDED]),
+            Frame::new(FRAME_DATA, 0x12345678, vec![0xaa; MAX_DATA_SIZE]),
+            Frame::new(FRAME_WINDOW, u32::MAX, (1024u32).to_be_bytes().to_vec()),
+            Frame::new(FRAME_FIN, 2, vec![]),
+            Frame::new(FRAME_RST, 3, vec![]),
+        ];
+        let mut buffer = Vec::new();
+        for frame in frames.iter() {
+            let encoded = frame.encode();
+            assert_eq!(encoded.len(), HEADER_SIZE + frame.payload.len());
+            assert_eq!(encoded[0], frame.kind);
+            assert_eq!(&encoded[1..5], &frame.id.to_be_bytes());
+            assert_eq!(&encoded[5..7], &(frame.payload.len() as u16).to_be_bytes());
+            buffer.extend_from_slice(&encoded);
+        }
+
+        let mut reader = Cursor::new(buffer);
+        for frame in frames.iter() {
+            assert_eq!(Frame::read(&mut reader).unwrap().as_ref(), Some(frame));
+        }
+        // The stream ends between frames
+        assert_eq!(Frame::read(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn frame_read_truncated() {
+        let encoded = Frame::new(FRAME_DATA, 1, vec![1, 2, 3]).encode();
+        for size in 1..encoded.len() {
+            let mut reader = Cursor::new(&encoded[..size]);
+            assert_eq!(
+                Frame::read(&mut reader).unwrap_err().kind(),
+                io::ErrorKind::UnexpectedEof
+            );
+        }
+    }
+
+    #[test]
+    fn frame_data() {
+        // Compressible data is compressed only if allowed
+        let data = b"GET / HTTP/1.1\r\n".repeat(64);
+        let frame = Frame::new_data(1, &data, true);
+        assert_eq!(frame.kind, FRAME_DATA_LZ4);
+        assert!(frame.payload.len() < data.len());
+        assert_eq!(frame.into_data().unwrap(), data);
+        let frame = Frame::new_data(1, &data, false);
+        assert_eq!(frame.kind, FRAME_DATA);
+        assert_eq!(frame.into_data().unwrap(), data);
+
+        // Data not getting smaller is sent as is
+        let data = b"abcdefgh";
+        let frame = Frame::new_data(1, data, true);
+        assert_eq!(frame.kind, FRAME_DATA);
+        assert_eq!(frame.into_data().unwrap(), data);
+
+        // Compressed data is bounded by the max size of data
+        let frame = Frame::new(
+            FRAME_DATA_LZ4,
+            1,
+            lz4::compress(&vec![0u8; MAX_DATA_SIZE + 1]),
+        );
+        assert!(frame.into_data().is_err());
+    }
+
+    #[test]
+    fn compressible() {
+        assert!(is_compressible(b"GET / HTTP/1.1\r\n"));
+        assert!(is_compressible(b""));
+        // TLS handshake and application data
+        assert!(!is_compressible(&[0x16, 3, 1, 0, 0x80]));
+        assert!(!is_compressible(&[0x17, 3, 3, 0, 0x80]));
+        // gzip
+        assert!(!is_compressible(&[0x1f, 0x8b, 8, 0]));
+        assert!(!is_compressible(b"SSH-2.0-OpenSSH_9.0\r\n"));
+    }
+
+    #[test]
+    fn window() {
+        let stream = Arc::new(Stream::new(true, None));
+        assert!(stream.take_window(INITIAL_WINDOW - 100));
+        assert!(stream.take_window(100));
+        assert_eq!(*stream.window.lock().unwrap(), 0);
+
+        // Data waits until the window is granted
+        let stream_cloned = Arc::clone(&stream);
+        let waiter = thread::spawn(move || stream_cloned.take_window(1000));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        stream.grant_window(500);
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        stream.grant_window(600);
+        assert!(waiter.join().unwrap());
+        assert_eq!(*stream.window.lock().unwrap(), 100);
+
+        // Data waiting is released by a reset
+        let stream_cloned = Arc::clone(&stream);
+        let waiter = thread::spawn(move || stream_cloned.take_window(1000));
+        thread::sleep(Duration::from_millis(50));
+        stream.reset();
+        assert!(!waiter.join().unwrap());
+        assert!(!stream.take_window(1));
+    }
+
+    #[test]
+    fn session_beyond_window() {
+        // Echo server as the destination
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let dst = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = stream.try_clone().unwrap();
+            io::copy(&mut reader, &mut stream).unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+        });
+
+        let (client, server) = pair().unwrap();
+        thread::spawn(move || {
+            let _ = MuxSession::serve(server, Box::new(|dst| TcpStream::connect(dst)));
+        });
+        let session = MuxSession::open(client).unwrap();
+        let mut stream = session.connect(dst, Duration::from_secs(5)).unwrap();
+
+        // Data several times the window, half compressible and half not
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let mut data = b"pcap2socks relays frames to proxies\r\n".repeat(16 * 1024);
+        data.extend((0..data.len()).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }));
+        assert!(data.len() > 4 * INITIAL_WINDOW);
+
+        let mut writer = stream.try_clone().unwrap();
+        let data_cloned = data.clone();
+        let writer = thread::spawn(move || {
+            writer.write_all(&data_cloned).unwrap();
+            writer.shutdown(Shutdown::Write).unwrap();
+        });
+        let mut echo = Vec::new();
+        stream.read_to_end(&mut echo).unwrap();
+        writer.join().unwrap();
+        assert_eq!(echo.len(), data.len());
+        assert!(echo == data);
+
+        session.close();
+        assert!(session.is_closed());
+        assert!(session.connect(dst, Duration::from_secs(1)).is_err());
+    }
+}
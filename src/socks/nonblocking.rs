@@ -0,0 +1,105 @@
+use socks::TargetAddr;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::{
+    new_methods_v5, new_request_v4, new_request_v5, parse_auth_reply_v5, parse_bound_addr_v5,
+    parse_method_v5, parse_reply_v4, parse_reply_v5, SocksAuth, SocksOption, Version,
+};
+
+/// Issues a CONNECT request to the target on a stream connected to a SOCKS proxy.
+pub async fn handshake(
+    stream: &mut TcpStream,
+    dst: &TargetAddr,
+    option: &SocksOption,
+) -> io::Result<()> {
+    match option.version {
+        Version::V4 | Version::V4a => handshake_v4(stream, dst, option).await,
+        Version::V5 => handshake_v5(stream, dst, option.auth.as_ref()).await,
+    }
+}
+
+/// Issues a CONNECT request to the target on a stream connected to a SOCKS proxy whose method
+/// is negotiated already. SOCKS4 has no negotiation so the whole handshake is issued.
+pub async fn request(
+    stream: &mut TcpStream,
+    dst: &TargetAddr,
+    option: &SocksOption,
+) -> io::Result<()> {
+    match option.version {
+        Version::V4 | Version::V4a => handshake_v4(stream, dst, option).await,
+        // The bound address is discarded
+        Version::V5 => request_v5(stream, 1, dst).await.map(|_| ()),
+    }
+}
+
+/// Issues a SOCKS4 CONNECT request.
+async fn handshake_v4(
+    stream: &mut TcpStream,
+    dst: &TargetAddr,
+    option: &SocksOption,
+) -> io::Result<()> {
+    stream.write_all(&new_request_v4(dst, option)?).await?;
+
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply).await?;
+
+    parse_reply_v4(&reply)
+}
+
+/// Issues a SOCKS5 CONNECT request with an optional username/password authentication (RFC
+/// 1928, RFC 1929).
+async fn handshake_v5(
+    stream: &mut TcpStream,
+    dst: &TargetAddr,
+    auth: Option<&SocksAuth>,
+) -> io::Result<()> {
+    negotiate_v5(stream, auth).await?;
+
+    // The bound address is discarded
+    request_v5(stream, 1, dst).await?;
+
+    Ok(())
+}
+
+/// Negotiates the method of a SOCKS5 proxy with an optional username/password authentication
+/// (RFC 1928, RFC 1929).
+async fn negotiate_v5(stream: &mut TcpStream, auth: Option<&SocksAuth>) -> io::Result<()> {
+    // Method
+    stream.write_all(new_methods_v5(auth)).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if let Some(request) = parse_method_v5(&reply, auth)? {
+        stream.write_all(&request).await?;
+
+        stream.read_exact(&mut reply).await?;
+        parse_auth_reply_v5(&reply)?;
+    }
+
+    Ok(())
+}
+
+/// Issues a SOCKS5 request with the given command, returns the bound address in the reply.
+async fn request_v5(
+    stream: &mut TcpStream,
+    command: u8,
+    dst: &TargetAddr,
+) -> io::Result<TargetAddr> {
+    stream.write_all(&new_request_v5(command, dst)?).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    let size = match parse_reply_v5(&reply)? {
+        Some(size) => size,
+        None => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+    };
+    let mut addr = vec![0u8; size + 2];
+    stream.read_exact(&mut addr).await?;
+
+    Ok(parse_bound_addr_v5(reply[3], &addr, size))
+}
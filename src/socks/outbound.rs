@@ -0,0 +1,471 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, UdpSocket};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::ports::{bind_in_range, get_port_range};
+
+/// Represents the outbound of sockets to proxies and targets, which is shared by all sockets.
+static OUTBOUND: Mutex<Option<Outbound>> = Mutex::new(None);
+
+/// Represents the local address or interface sockets to proxies and targets are bound to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Outbound {
+    Address(Ipv4Addr),
+    Interface(String),
+}
+
+impl Outbound {
+    /// Parses an outbound from an IPv4 address or an interface name.
+    pub fn parse(s: &str) -> Option<Outbound> {
+        if let Ok(ip_addr) = s.parse() {
+            return Some(Outbound::Address(ip_addr));
+        }
+        // Interface names are limited to 15 bytes in Linux
+        if s.is_empty() || s.len() > 15 || s.bytes().any(|b| b == 0 || b == b'/') {
+            return None;
+        }
+
+        Some(Outbound::Interface(s.to_string()))
+    }
+
+    fn bind_error(&self, e: io::Error) -> io::Error {
+        io::Error::new(e.kind(), format!("cannot bind to {}: {}", self, e))
+    }
+}
+
+impl Display for Outbound {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Outbound::Address(ip_addr) => write!(f, "outbound address {}", ip_addr),
+            Outbound::Interface(name) => write!(f, "outbound interface {}", name),
+        }
+    }
+}
+
+/// Sets the outbound of sockets created later, or `None` to let the OS decide by routes.
+pub fn set_outbound(outbound: Option<Outbound>) {
+    *OUTBOUND.lock().unwrap() = outbound;
+}
+
+/// Get the outbound of sockets.
+pub fn get_outbound() -> Option<Outbound> {
+    OUTBOUND.lock().unwrap().clone()
+}
+
+/// Connects a `TcpStream` to the remote from the outbound and the local port range. The stream
+/// waits for the connection in the timeout, or in the timeout of the OS if the timeout is `None`.
+pub fn connect_tcp(remote: SocketAddrV4, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let outbound = get_outbound();
+    if outbound.is_none() && get_port_range().is_none() {
+        return match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&SocketAddr::V4(remote), timeout),
+            None => TcpStream::connect(remote),
+        };
+    }
+
+    connect_tcp_from(remote, outbound.as_ref(), timeout)
+}
+
+#[cfg(unix)]
+fn connect_tcp_from(
+    remote: SocketAddrV4,
+    outbound: Option<&Outbound>,
+    timeout: Option<Duration>,
+) -> io::Result<TcpStream> {
+    use std::cmp::min;
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The stream owns the socket from now on, so it is closed on errors
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    let bind = |port| {
+        let ip_addr = match outbound {
+            Some(Outbound::Address(ip_addr)) => *ip_addr,
+            _ => Ipv4Addr::UNSPECIFIED,
+        };
+        let addr = new_sockaddr_v4(SocketAddrV4::new(ip_addr, port));
+        let result = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    };
+    if let Some(Outbound::Interface(name)) = outbound {
+        set_device_raw(fd, name).map_err(|e| outbound.unwrap().bind_error(e))?;
+    }
+    // Ports of closed streams in TIME_WAIT are reused at once, streams of the range never share
+    // a port
+    let result = match get_port_range() {
+        Some(_) => {
+            set_reuse_addr_raw(fd)?;
+            bind_in_range(bind, |_| fd).unwrap_or_else(|| bind(0))
+        }
+        None => match outbound {
+            Some(Outbound::Address(_)) => bind(0),
+            _ => Ok(()),
+        },
+    };
+    if let Err(e) = result {
+        return Err(match outbound {
+            Some(outbound) => outbound.bind_error(e),
+            None => e,
+        });
+    }
+
+    // Connect in non-blocking mode for the timeout
+    stream.set_nonblocking(true)?;
+    let addr = new_sockaddr_v4(remote);
+    let result = unsafe {
+        libc::connect(
+            fd,
+            &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(e);
+        }
+
+        let timeout = match timeout {
+            Some(timeout) => min(timeout.as_millis(), libc::c_int::MAX as u128) as libc::c_int,
+            None => -1,
+        };
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        loop {
+            match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+                0 => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+                result if result < 0 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+                _ => break,
+            }
+        }
+        if let Some(e) = stream.take_error()? {
+            return Err(e);
+        }
+    }
+    stream.set_nonblocking(false)?;
+
+    Ok(stream)
+}
+
+#[cfg(not(unix))]
+fn connect_tcp_from(
+    _: SocketAddrV4,
+    outbound: Option<&Outbound>,
+    _: Option<Duration>,
+) -> io::Result<TcpStream> {
+    match outbound {
+        Some(outbound) => Err(outbound.bind_error(io::Error::new(
+            io::ErrorKind::Other,
+            "outbound is not supported",
+        ))),
+        None => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "local port range is not supported",
+        )),
+    }
+}
+
+/// Connects a `TcpStream` to the remote from the outbound and the local port range
+/// asynchronously.
+#[cfg(feature = "async")]
+pub async fn connect_tcp_async(remote: SocketAddrV4) -> io::Result<tokio::net::TcpStream> {
+    let outbound = get_outbound();
+    if outbound.is_none() && get_port_range().is_none() {
+        return tokio::net::TcpStream::connect(remote).await;
+    }
+
+    let socket = tokio::net::TcpSocket::new_v4()?;
+    let ip_addr = match outbound {
+        Some(Outbound::Address(ip_addr)) => ip_addr,
+        _ => Ipv4Addr::UNSPECIFIED,
+    };
+    if let Some(Outbound::Interface(ref name)) = outbound {
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            set_device_raw(socket.as_raw_fd(), name)
+                .map_err(|e| outbound.as_ref().unwrap().bind_error(e))?;
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = name;
+            return Err(outbound.as_ref().unwrap().bind_error(io::Error::new(
+                io::ErrorKind::Other,
+                "binding to an interface is not supported",
+            )));
+        }
+    }
+    let bind = |port| socket.bind(SocketAddr::V4(SocketAddrV4::new(ip_addr, port)));
+    #[cfg(unix)]
+    let fd = |_: &()| {
+        use std::os::unix::io::AsRawFd;
+
+        socket.as_raw_fd()
+    };
+    #[cfg(not(unix))]
+    let fd = |_: &()| 0;
+    let result = match get_port_range() {
+        Some(_) => {
+            socket.set_reuseaddr(true)?;
+            bind_in_range(bind, fd).unwrap_or_else(|| bind(0))
+        }
+        None => match outbound {
+            Some(Outbound::Address(_)) => bind(0),
+            _ => Ok(()),
+        },
+    };
+    if let Err(e) = result {
+        return Err(match outbound {
+            Some(ref outbound) => outbound.bind_error(e),
+            None => e,
+        });
+    }
+
+    socket.connect(SocketAddr::V4(remote)).await
+}
+
+/// Binds a `UdpSocket` to the local address from the outbound. The IP of the local address
+/// is replaced by the outbound address if there is one, and a local address of port 0 is bound to
+/// a port in the local port range if there is one.
+pub fn bind_udp(local: SocketAddr) -> io::Result<UdpSocket> {
+    bind_udp_from(local, get_outbound())
+}
+
+fn bind_udp_from(local: SocketAddr, outbound: Option<Outbound>) -> io::Result<UdpSocket> {
+    let outbound = match outbound {
+        Some(outbound) => outbound,
+        None => return bind_udp_in_range(local),
+    };
+
+    match outbound {
+        Outbound::Address(ip_addr) => match local {
+            SocketAddr::V4(local) => {
+                bind_udp_in_range(SocketAddr::V4(SocketAddrV4::new(ip_addr, local.port())))
+                    .map_err(|e| outbound.bind_error(e))
+            }
+            SocketAddr::V6(_) => Err(outbound.bind_error(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the socket is in IPv6",
+            ))),
+        },
+        Outbound::Interface(ref name) => {
+            let datagram = bind_udp_in_range(local)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::io::AsRawFd;
+
+                set_device_raw(datagram.as_raw_fd(), name).map_err(|e| outbound.bind_error(e))?;
+
+                Ok(datagram)
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = (datagram, name);
+                Err(outbound.bind_error(io::Error::new(
+                    io::ErrorKind::Other,
+                    "binding to an interface is not supported",
+                )))
+            }
+        }
+    }
+}
+
+/// Binds a `UdpSocket` to the local address, a local address of port 0 is bound to a port in the
+/// local port range if there is one.
+fn bind_udp_in_range(local: SocketAddr) -> io::Result<UdpSocket> {
+    if local.port() != 0 {
+        return UdpSocket::bind(local);
+    }
+    let bind = |port| {
+        let mut local = local;
+        local.set_port(port);
+
+        UdpSocket::bind(local)
+    };
+    #[cfg(unix)]
+    let fd = |datagram: &UdpSocket| {
+        use std::os::unix::io::AsRawFd;
+
+        datagram.as_raw_fd()
+    };
+    #[cfg(not(unix))]
+    let fd = |_: &UdpSocket| 0;
+
+    bind_in_range(bind, fd).unwrap_or_else(|| UdpSocket::bind(local))
+}
+
+/// Sets `SO_REUSEADDR` of a socket.
+#[cfg(unix)]
+fn set_reuse_addr_raw(fd: std::os::unix::io::RawFd) -> io::Result<()> {
+    let value: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(unix)]
+fn new_sockaddr_v4(addr: SocketAddrV4) -> libc::sockaddr_in {
+    let mut sockaddr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+    sockaddr.sin_port = addr.port().to_be();
+    sockaddr.sin_addr = libc::in_addr {
+        s_addr: u32::from(*addr.ip()).to_be(),
+    };
+
+    sockaddr
+}
+
+/// Binds a socket to the interface with `SO_BINDTODEVICE`, which may require `CAP_NET_RAW`.
+#[cfg(target_os = "linux")]
+fn set_device_raw(fd: std::os::unix::io::RawFd, name: &str) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Binds a socket to the interface, which is only supported in Linux.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_device_raw(_: std::os::unix::io::RawFd, _: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "binding to an interface is not supported",
+    ))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Represents a loopback address other than the one of the OS, which is local in Linux.
+    const OUTBOUND_IP_ADDR: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+
+    fn listen() -> (TcpListener, SocketAddrV4) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+
+        (listener, addr)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Outbound::parse("192.168.1.2"),
+            Some(Outbound::Address(Ipv4Addr::new(192, 168, 1, 2)))
+        );
+        assert_eq!(
+            Outbound::parse("wg0"),
+            Some(Outbound::Interface(String::from("wg0")))
+        );
+        assert_eq!(Outbound::parse(""), None);
+        assert_eq!(Outbound::parse("interface-too-long"), None);
+        assert_eq!(Outbound::parse("eth0/1"), None);
+    }
+
+    #[test]
+    fn tcp_address() {
+        let (listener, addr) = listen();
+        let outbound = Outbound::Address(OUTBOUND_IP_ADDR);
+
+        let stream = connect_tcp_from(addr, Some(&outbound), Some(Duration::from_secs(1))).unwrap();
+        let local = stream.local_addr().unwrap();
+        assert_eq!(local.ip(), OUTBOUND_IP_ADDR);
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer, local);
+    }
+
+    #[test]
+    fn udp_address() {
+        let outbound = Outbound::Address(OUTBOUND_IP_ADDR);
+
+        let datagram = bind_udp_from("0.0.0.0:0".parse().unwrap(), Some(outbound)).unwrap();
+        let local = datagram.local_addr().unwrap();
+        assert_eq!(local.ip(), OUTBOUND_IP_ADDR);
+        assert_ne!(local.port(), 0);
+    }
+
+    #[test]
+    fn interface() {
+        let (listener, addr) = listen();
+        let outbound = Outbound::Interface(String::from("lo"));
+
+        // Binding to an interface requires CAP_NET_RAW
+        let stream = match connect_tcp_from(addr, Some(&outbound), Some(Duration::from_secs(1))) {
+            Ok(stream) => stream,
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => return,
+            Err(e) => panic!("{}", e),
+        };
+        let local = stream.local_addr().unwrap();
+        assert_eq!(local.ip(), Ipv4Addr::LOCALHOST);
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer, local);
+
+        let datagram = bind_udp_from("0.0.0.0:0".parse().unwrap(), Some(outbound)).unwrap();
+        assert_eq!(datagram.local_addr().unwrap().ip(), Ipv4Addr::UNSPECIFIED);
+    }
+
+    #[test]
+    fn bind_errors() {
+        let (_listener, addr) = listen();
+
+        // Errors name the address or the interface
+        let outbound = Outbound::Address(Ipv4Addr::new(192, 0, 2, 1));
+        let e = connect_tcp_from(addr, Some(&outbound), None).unwrap_err();
+        assert!(e.to_string().contains("outbound address 192.0.2.1"));
+        let e = bind_udp_from("0.0.0.0:0".parse().unwrap(), Some(outbound)).unwrap_err();
+        assert!(e.to_string().contains("outbound address 192.0.2.1"));
+
+        let outbound = Outbound::Interface(String::from("nonexistent0"));
+        let e = connect_tcp_from(addr, Some(&outbound), None).unwrap_err();
+        assert!(e.to_string().contains("outbound interface nonexistent0"));
+        let e = bind_udp_from("0.0.0.0:0".parse().unwrap(), Some(outbound)).unwrap_err();
+        assert!(e.to_string().contains("outbound interface nonexistent0"));
+    }
+}
@@ -0,0 +1,225 @@
+//! A pool of idle connections to proxies, which are connected and negotiated ahead so a new
+//! connection goes straight to its request, saving the round trips of connecting and
+//! authenticating to a distant proxy.
+
+use log::{debug, trace};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddrV4, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::{connect_tcp, negotiate_v5, SocksOption, Version, CONNECT_TIMEOUT};
+use crate::metrics;
+
+/// Represents the max time a connection is pooled, connections idle longer are closed before
+/// the proxy closes them.
+const MAX_IDLE: Duration = Duration::from_secs(30);
+/// Represents the interval of refilling pools.
+const FILL_INTERVAL: Duration = Duration::from_millis(100);
+/// Represents the interval before refilling a pool again after the proxy fails.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Represents the max number of idle connections pooled to each proxy, or 0 if connections are
+/// never pooled.
+static POOL_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// Represents the pools open.
+static POOLS: Mutex<Vec<Arc<Pool>>> = Mutex::new(Vec::new());
+
+/// Sets the max number of idle connections pooled to each proxy, or 0 to never pool
+/// connections.
+pub fn set_proxy_pool(size: usize) {
+    POOL_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// Get the max number of idle connections pooled to each proxy.
+pub fn get_proxy_pool() -> usize {
+    POOL_SIZE.load(Ordering::Relaxed)
+}
+
+/// Closes all the pools and the connections in them, and stops pooling connections until the
+/// size of pools is set again.
+pub fn drain_pools() {
+    set_proxy_pool(0);
+    let pools: Vec<Arc<Pool>> = POOLS.lock().unwrap().drain(..).collect();
+    for pool in pools.iter() {
+        pool.is_stopped.store(true, Ordering::Relaxed);
+        pool.drain();
+    }
+    for pool in pools.iter() {
+        if let Some(thread) = pool.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Represents a pool of idle connections to a proxy, which is refilled on its own thread as
+/// connections are taken or age out.
+#[derive(Debug)]
+struct Pool {
+    remote: SocketAddrV4,
+    option: SocksOption,
+    connections: Mutex<VecDeque<(TcpStream, Instant)>>,
+    is_stopped: AtomicBool,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Pool {
+    /// Opens a connection to the proxy, SOCKS5 proxies are negotiated so only the request is
+    /// left.
+    fn open(&self) -> io::Result<TcpStream> {
+        let mut stream = connect_tcp(self.remote, Some(CONNECT_TIMEOUT))?;
+        if self.option.get_version() == Version::V5 {
+            stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+            stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+            negotiate_v5(&mut stream, self.option.get_auth())?;
+            stream.set_read_timeout(None)?;
+            stream.set_write_timeout(None)?;
+        }
+
+        Ok(stream)
+    }
+
+    /// Takes the latest idle connection alive, dead connections are closed.
+    fn take(&self) -> Option<TcpStream> {
+        let mut connections = self.connections.lock().unwrap();
+        while let Some((stream, instant)) = connections.pop_back() {
+            if instant.elapsed() < MAX_IDLE && is_alive(&stream) {
+                metrics::PROXY_POOL_HITS.increase();
+
+                return Some(stream);
+            }
+            metrics::PROXY_POOL_STALE.increase();
+        }
+        metrics::PROXY_POOL_MISSES.increase();
+
+        None
+    }
+
+    /// Closes the idle connections.
+    fn drain(&self) {
+        let count = {
+            let mut connections = self.connections.lock().unwrap();
+            let count = connections.len();
+            connections.clear();
+            count
+        };
+        if count > 0 {
+            debug!("drain {} idle connections to proxy {}", count, self.remote);
+        }
+    }
+}
+
+/// Get the pool of the proxy, which is opened if connections are pooled.
+fn get_pool(remote: SocketAddrV4, option: &SocksOption) -> Option<Arc<Pool>> {
+    if get_proxy_pool() == 0 {
+        return None;
+    }
+
+    let mut pools = POOLS.lock().unwrap();
+    if let Some(pool) = pools
+        .iter()
+        .find(|pool| pool.remote == remote && &pool.option == option)
+    {
+        return Some(Arc::clone(pool));
+    }
+
+    let pool = Arc::new(Pool {
+        remote,
+        option: option.clone(),
+        connections: Mutex::new(VecDeque::new()),
+        is_stopped: AtomicBool::new(false),
+        thread: Mutex::new(None),
+    });
+    let pool_cloned = Arc::clone(&pool);
+    *pool.thread.lock().unwrap() = Some(thread::spawn(move || fill(pool_cloned)));
+    pools.push(Arc::clone(&pool));
+
+    Some(pool)
+}
+
+/// Starts filling the pool of the proxy if connections are pooled.
+pub fn prepare(remote: SocketAddrV4, option: &SocksOption) {
+    let _ = get_pool(remote, option);
+}
+
+/// Takes an idle connection pooled to the proxy, or `None` if there is none alive. The pool is
+/// opened at the first time so later connections are pooled.
+pub fn take(remote: SocketAddrV4, option: &SocksOption) -> Option<TcpStream> {
+    get_pool(remote, option).and_then(|pool| pool.take())
+}
+
+/// Closes the idle connections pooled to the proxy, like after the proxy fails.
+pub fn drain(remote: SocketAddrV4) {
+    let pools = POOLS.lock().unwrap();
+    for pool in pools.iter().filter(|pool| pool.remote == remote) {
+        pool.drain();
+    }
+}
+
+/// Returns if the error of a request on a pooled connection tells the proxy closed the
+/// connection while it was idle, then the request is retried on a new connection.
+pub fn is_stale(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Returns if an idle connection is alive. The proxy sends nothing before a request, so
+/// anything readable, data or an EOF, tells the connection is dead.
+fn is_alive(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut buffer = [0u8; 1];
+    let is_alive = match stream.peek(&mut buffer) {
+        Ok(_) => false,
+        Err(ref e) => e.kind() == io::ErrorKind::WouldBlock,
+    };
+
+    is_alive && stream.set_nonblocking(false).is_ok()
+}
+
+fn fill(pool: Arc<Pool>) {
+    let mut retry_instant: Option<Instant> = None;
+    while !pool.is_stopped.load(Ordering::Relaxed) {
+        thread::sleep(FILL_INTERVAL);
+
+        // Connections aged out are closed
+        pool.connections
+            .lock()
+            .unwrap()
+            .retain(|(_, instant)| instant.elapsed() < MAX_IDLE);
+
+        if let Some(instant) = retry_instant {
+            if instant.elapsed() < RETRY_INTERVAL {
+                continue;
+            }
+            retry_instant = None;
+        }
+        while pool.connections.lock().unwrap().len() < get_proxy_pool()
+            && !pool.is_stopped.load(Ordering::Relaxed)
+        {
+            match pool.open() {
+                Ok(stream) => {
+                    trace!("pool a connection to proxy {}", pool.remote);
+                    pool.connections
+                        .lock()
+                        .unwrap()
+                        .push_back((stream, Instant::now()));
+                }
+                Err(ref e) => {
+                    debug!("pool a connection to proxy {}: {}", pool.remote, e);
+                    retry_instant = Some(Instant::now());
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::sync::Mutex;
+
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+#[cfg(not(unix))]
+type RawFd = i32;
+
+/// Represents the ports of sockets to proxies and targets, which are shared by all sockets.
+static PORTS: Mutex<Option<Ports>> = Mutex::new(None);
+
+/// Represents a range of local ports sockets to proxies and targets are bound to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PortRange {
+    lo: u16,
+    hi: u16,
+}
+
+impl PortRange {
+    /// Parses a port range in the form of `LO-HI` like `40000-40999`. Returns `None` if the
+    /// range is not valid.
+    pub fn parse(s: &str) -> Option<PortRange> {
+        let mut ports = s.splitn(2, '-');
+        let lo: u16 = ports.next()?.trim().parse().ok()?;
+        let hi: u16 = ports.next()?.trim().parse().ok()?;
+        if lo == 0 || lo > hi {
+            return None;
+        }
+
+        Some(PortRange { lo, hi })
+    }
+
+    /// Get the number of ports in the range.
+    pub fn get_size(&self) -> usize {
+        (self.hi - self.lo) as usize + 1
+    }
+
+    /// Get the first port of the range.
+    pub fn get_lo(&self) -> u16 {
+        self.lo
+    }
+
+    /// Get the last port of the range.
+    pub fn get_hi(&self) -> u16 {
+        self.hi
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.lo, self.hi)
+    }
+}
+
+/// Represents the ports of a range in use, with the sockets bound to them. A port is released
+/// once its socket is closed, which is checked when the port is met again.
+#[derive(Debug)]
+struct Ports {
+    range: PortRange,
+    /// Represents the next port tried
+    cursor: u16,
+    sockets: HashMap<u16, RawFd>,
+}
+
+impl Ports {
+    /// Releases the ports whose sockets are closed.
+    fn prune(&mut self) {
+        self.sockets.retain(|&port, &mut fd| is_bound(fd, port));
+    }
+
+    fn is_in_use(&mut self, port: u16) -> bool {
+        match self.sockets.get(&port) {
+            Some(&fd) if is_bound(fd, port) => true,
+            Some(_) => {
+                self.sockets.remove(&port);
+
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// Sets the range of local ports of sockets created later, or `None` to let the OS decide.
+pub fn set_port_range(range: Option<PortRange>) {
+    *PORTS.lock().unwrap() = range.map(|range| Ports {
+        range,
+        cursor: range.lo,
+        sockets: HashMap::new(),
+    });
+}
+
+/// Get the range of local ports of sockets.
+pub fn get_port_range() -> Option<PortRange> {
+    PORTS.lock().unwrap().as_ref().map(|ports| ports.range)
+}
+
+/// Get the number of ports in use and the number of ports in the range, or `None` if there is no
+/// range.
+pub fn get_port_occupancy() -> Option<(usize, usize)> {
+    let mut ports = PORTS.lock().unwrap();
+    let ports = ports.as_mut()?;
+    ports.prune();
+
+    Some((ports.sockets.len(), ports.range.get_size()))
+}
+
+/// Binds a socket to a port in the range by the function, which is given the port and returns
+/// the socket, and `fd` gets the file descriptor of the socket. Ports are tried from a rotating
+/// cursor and ports in use are skipped. Returns `None` if there is no range, and an error if all
+/// the ports of the range are in use.
+pub fn bind_in_range<T, B, F>(mut bind: B, fd: F) -> Option<io::Result<T>>
+where
+    B: FnMut(u16) -> io::Result<T>,
+    F: Fn(&T) -> RawFd,
+{
+    let mut ports = PORTS.lock().unwrap();
+    let ports = ports.as_mut()?;
+    if !cfg!(unix) {
+        return Some(Err(io::Error::new(
+            io::ErrorKind::Other,
+            "local port range is not supported",
+        )));
+    }
+
+    for _ in 0..ports.range.get_size() {
+        let port = ports.cursor;
+        ports.cursor = if port >= ports.range.hi {
+            ports.range.lo
+        } else {
+            port + 1
+        };
+        if ports.is_in_use(port) {
+            continue;
+        }
+
+        match bind(port) {
+            Ok(socket) => {
+                ports.sockets.insert(port, fd(&socket));
+
+                return Some(Ok(socket));
+            }
+            // Ports in use by others are skipped
+            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Some(Err(e)),
+        }
+    }
+
+    Some(Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("local port range {} is exhausted", ports.range),
+    )))
+}
+
+/// Returns if the socket is open and bound to the port. A file descriptor reused by another
+/// socket of the port is in the range too, so it still holds the port.
+#[cfg(unix)]
+fn is_bound(fd: RawFd, port: u16) -> bool {
+    use std::mem;
+
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return false;
+    }
+
+    let bound = match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { *(&addr as *const _ as *const libc::sockaddr_in) };
+            addr.sin_port
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { *(&addr as *const _ as *const libc::sockaddr_in6) };
+            addr.sin6_port
+        }
+        _ => return false,
+    };
+
+    u16::from_be(bound) == port
+}
+
+#[cfg(not(unix))]
+fn is_bound(_: RawFd, _: u16) -> bool {
+    false
+}
@@ -0,0 +1,212 @@
+use log::debug;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+use super::BoxFuture;
+use super::{ProxyConnector, SocksDatagram, SocksListener, UnreachableError};
+use crate::resolver::Name;
+
+/// Represents the function creating the proxy of an address.
+type NewProxy = dyn Fn(SocketAddrV4) -> Arc<dyn ProxyConnector> + Send + Sync;
+
+/// Represents a proxy addressed by a host name, which follows the addresses of the name. New
+/// connections try the addresses in order, and once all of them are unreachable, the name is
+/// resolved again immediately and the new addresses are tried once.
+pub struct ResolvedProxy {
+    name: Arc<Name>,
+    port: u16,
+    new_proxy: Box<NewProxy>,
+    /// Represents the addresses and their proxies in order
+    proxies: Mutex<(Vec<Ipv4Addr>, Vec<Arc<dyn ProxyConnector>>)>,
+}
+
+impl ResolvedProxy {
+    /// Creates a new `ResolvedProxy` of the name and the port, whose proxies of addresses are
+    /// created by the given function.
+    pub fn new<F>(name: Arc<Name>, port: u16, new_proxy: F) -> ResolvedProxy
+    where
+        F: Fn(SocketAddrV4) -> Arc<dyn ProxyConnector> + Send + Sync + 'static,
+    {
+        ResolvedProxy {
+            name,
+            port,
+            new_proxy: Box::new(new_proxy),
+            proxies: Mutex::new((Vec::new(), Vec::new())),
+        }
+    }
+
+    /// Get the proxies of the addresses of the name in order, which are created again once the
+    /// addresses change.
+    fn get_proxies(&self) -> Vec<Arc<dyn ProxyConnector>> {
+        let addrs = self.name.get_addrs();
+        let mut proxies = self.proxies.lock().unwrap();
+        if proxies.0 != addrs {
+            // Connections pooled to old addresses are closed
+            for proxy in proxies.1.iter() {
+                proxy.drain_pool();
+            }
+            let new_proxies = addrs
+                .iter()
+                .map(|addr| (self.new_proxy)(SocketAddrV4::new(*addr, self.port)))
+                .collect();
+            *proxies = (addrs, new_proxies);
+        }
+
+        proxies.1.clone()
+    }
+
+    /// Get the first proxy.
+    fn get_proxy(&self) -> Arc<dyn ProxyConnector> {
+        // A name always has an address
+        self.get_proxies().remove(0)
+    }
+
+    /// Resolves the name again after the proxies tried are unreachable. Returns the proxies not
+    /// tried yet, which are empty if the addresses are unchanged.
+    fn refresh(&self, tried: &[SocketAddrV4]) -> Vec<Arc<dyn ProxyConnector>> {
+        match self.name.refresh() {
+            Ok(true) => self
+                .get_proxies()
+                .into_iter()
+                .filter(|proxy| !tried.contains(&proxy.get_remote()))
+                .collect(),
+            Ok(false) => Vec::new(),
+            Err(ref e) => {
+                debug!("resolve {}: {}", self.name, e);
+
+                Vec::new()
+            }
+        }
+    }
+
+    /// Runs an operation on the proxies in order until one is reachable.
+    fn try_proxies<T, F>(&self, f: F) -> io::Result<T>
+    where
+        F: Fn(&dyn ProxyConnector) -> io::Result<T>,
+    {
+        let mut tried = Vec::new();
+        let mut proxies = self.get_proxies();
+        let mut last_error = None;
+        for is_refreshed in [false, true] {
+            if is_refreshed {
+                proxies = self.refresh(&tried);
+            }
+            for proxy in proxies.iter() {
+                tried.push(proxy.get_remote());
+                match f(proxy.as_ref()) {
+                    Err(e) if UnreachableError::is_unreachable(&e) => {
+                        debug!("{}: {}", self, e);
+                        last_error = Some(e);
+                    }
+                    result => return result,
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| io::Error::from(io::ErrorKind::NotFound)))
+    }
+}
+
+impl ProxyConnector for ResolvedProxy {
+    fn get_remote(&self) -> SocketAddrV4 {
+        self.get_proxy().get_remote()
+    }
+
+    fn handshake(&self, stream: &mut TcpStream, dst: SocketAddrV4) -> io::Result<()> {
+        self.get_proxy().handshake(stream, dst)
+    }
+
+    fn connect(&self, dst: SocketAddrV4, timeout: Duration) -> io::Result<TcpStream> {
+        self.try_proxies(|proxy| proxy.connect(dst, timeout))
+    }
+
+    #[cfg(feature = "async")]
+    fn handshake_async<'a>(
+        &'a self,
+        stream: &'a mut tokio::net::TcpStream,
+        dst: SocketAddrV4,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { self.get_proxy().handshake_async(stream, dst).await })
+    }
+
+    #[cfg(feature = "async")]
+    fn connect_async(
+        &self,
+        dst: SocketAddrV4,
+        timeout: Duration,
+    ) -> BoxFuture<'_, io::Result<tokio::net::TcpStream>> {
+        Box::pin(async move {
+            let mut tried = Vec::new();
+            let mut proxies = self.get_proxies();
+            let mut last_error = None;
+            for is_refreshed in [false, true] {
+                if is_refreshed {
+                    proxies = self.refresh(&tried);
+                }
+                for proxy in proxies.iter() {
+                    tried.push(proxy.get_remote());
+                    match proxy.connect_async(dst, timeout).await {
+                        Err(e) if UnreachableError::is_unreachable(&e) => {
+                            debug!("{}: {}", self, e);
+                            last_error = Some(e);
+                        }
+                        result => return result,
+                    }
+                }
+            }
+
+            Err(last_error.unwrap_or_else(|| io::Error::from(io::ErrorKind::NotFound)))
+        })
+    }
+
+    fn bind(&self, local_src: SocketAddrV4) -> io::Result<SocksDatagram> {
+        self.try_proxies(|proxy| proxy.bind(local_src))
+    }
+
+    fn is_udp_supported(&self) -> bool {
+        self.get_proxy().is_udp_supported()
+    }
+
+    fn is_bind_supported(&self) -> bool {
+        self.get_proxy().is_bind_supported()
+    }
+
+    fn listen(&self, dst: SocketAddrV4, timeout: Duration) -> io::Result<SocksListener> {
+        self.try_proxies(|proxy| proxy.listen(dst, timeout))
+    }
+
+    fn check(&self, timeout: Duration) -> io::Result<()> {
+        // A failed check means the proxy is unreachable
+        self.try_proxies(|proxy| {
+            proxy.check(timeout).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    UnreachableError {
+                        remote: proxy.get_remote(),
+                        e,
+                    },
+                )
+            })
+        })
+    }
+
+    fn fill_pool(&self) {
+        self.get_proxy().fill_pool()
+    }
+
+    fn drain_pool(&self) {
+        for proxy in self.get_proxies() {
+            proxy.drain_pool();
+        }
+    }
+}
+
+impl Display for ResolvedProxy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.get_proxy(), self.name)
+    }
+}
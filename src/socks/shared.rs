@@ -0,0 +1,314 @@
+use super::{bind_udp, parse_header, Reassembler};
+use log::{trace, warn};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug, Formatter};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+
+/// Represents the default count of shared sockets of each address family.
+pub const DEFAULT_SHARED_SOCKETS: usize = 4;
+/// Represents the max number of peers of an association remembered for demultiplexing, the
+/// peers are forgotten once exceeded.
+const MAX_ROUTE_PEERS: usize = 256;
+/// Represents the interval before reading a shared socket again after an error.
+const ERROR_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Represents the count of shared sockets of each address family, or 0 if sockets are never
+/// shared.
+static SHARED_SOCKETS: AtomicUsize = AtomicUsize::new(0);
+/// Represents the shared sockets open, which live as long as the process.
+static SOCKETS: Mutex<Vec<Arc<SharedSocket>>> = Mutex::new(Vec::new());
+/// Represents the ID of the next route.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Represents the handler of datagrams of an association on a shared socket, which is called
+/// with the data and the source of each datagram reassembled.
+pub type Handler = Box<dyn FnMut(&[u8], SocketAddrV4) + Send>;
+
+/// Sets the count of local UDP sockets of each address family shared by associations, or 0 to
+/// bind a socket for each association. Shared sockets open are kept, and new associations
+/// only use the first sockets of the count.
+pub fn set_shared_sockets(count: usize) {
+    SHARED_SOCKETS.store(count, Ordering::Relaxed);
+}
+
+/// Get the count of local UDP sockets of each address family shared by associations.
+pub fn get_shared_sockets() -> usize {
+    SHARED_SOCKETS.load(Ordering::Relaxed)
+}
+
+/// Get the number of shared sockets open and the number of associations routed through them,
+/// or `None` if sockets are never shared.
+pub fn get_shared_occupancy() -> Option<(usize, usize)> {
+    let sockets = SOCKETS.lock().unwrap();
+    if sockets.is_empty() && get_shared_sockets() == 0 {
+        return None;
+    }
+    let routes = sockets
+        .iter()
+        .map(|socket| socket.routes.lock().unwrap().len())
+        .sum();
+
+    Some((sockets.len(), routes))
+}
+
+/// Represents the error of reading a shared socket by an association.
+pub fn unreadable() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "the shared socket is received by its reader",
+    )
+}
+
+/// Represents the route of an association on a shared socket.
+struct Route {
+    relay: SocketAddr,
+    reassembler: Arc<Mutex<Reassembler>>,
+    /// Represents the peers the association sent to, which tell associations to the same relay
+    /// apart
+    peers: HashSet<SocketAddr>,
+    /// Represents the instant of the last datagram sent, the latest association wins peers sent
+    /// to by several associations
+    instant: Instant,
+    handler: Option<Handler>,
+}
+
+/// Represents a local UDP socket shared by associations, which is read by a single reader
+/// demultiplexing datagrams from relays to the routes of associations.
+struct SharedSocket {
+    socket: UdpSocket,
+    routes: Mutex<HashMap<u64, Route>>,
+}
+
+impl SharedSocket {
+    /// Binds a shared socket in the family of the relay, and spawns its reader.
+    fn bind(relay: SocketAddr) -> io::Result<Arc<SharedSocket>> {
+        let local = match relay {
+            SocketAddr::V4(_) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
+        };
+        let socket = Arc::new(SharedSocket {
+            socket: bind_udp(local)?,
+            routes: Mutex::new(HashMap::new()),
+        });
+
+        let socket_cloned = Arc::clone(&socket);
+        thread::spawn(move || socket_cloned.read());
+        trace!("create shared datagram {}", socket.socket.local_addr()?);
+
+        Ok(socket)
+    }
+
+    fn is_family_of(&self, relay: SocketAddr) -> bool {
+        match self.socket.local_addr() {
+            Ok(addr) => addr.is_ipv4() == relay.is_ipv4(),
+            Err(_) => false,
+        }
+    }
+
+    /// Get the number of routes of the socket, and the number of them to the relay.
+    fn get_load(&self, relay: SocketAddr) -> (usize, usize) {
+        let routes = self.routes.lock().unwrap();
+        let to_relay = routes.values().filter(|route| route.relay == relay).count();
+
+        (to_relay, routes.len())
+    }
+
+    fn read(&self) {
+        let mut buffer = [0u8; u16::MAX as usize];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((size, relay)) => self.dispatch(&mut buffer, size, relay),
+                Err(ref e) => match e.kind() {
+                    // ICMP errors of other datagrams are reported on unconnected sockets in some
+                    // platforms, which never fail the socket
+                    io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionRefused => continue,
+                    _ => {
+                        warn!("SOCKS: {}: shared datagram: {}", "UDP", e);
+                        thread::sleep(ERROR_INTERVAL);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Demultiplexes a datagram from the relay to the association it belongs to. An association
+    /// is the only one to the relay, or the latest one to the relay which sent to the source in
+    /// the SOCKS5 UDP header. Datagrams belonging to no association are dropped.
+    fn dispatch(&self, buffer: &mut [u8], size: usize, relay: SocketAddr) {
+        let peer = match parse_header(&buffer[..size]) {
+            Ok((_, peer, _)) => peer,
+            Err(_) => {
+                metrics::UDP_UNROUTED.increase();
+                return;
+            }
+        };
+
+        let id;
+        let mut handler;
+        let result;
+        {
+            let mut routes = self.routes.lock().unwrap();
+            let mut candidates = routes.iter().filter(|(_, route)| route.relay == relay);
+            let selected = match (candidates.next(), candidates.next()) {
+                (Some((&id, _)), None) => Some(id),
+                (Some(_), Some(_)) => routes
+                    .iter()
+                    .filter(|(_, route)| route.relay == relay && route.peers.contains(&peer))
+                    .max_by_key(|(_, route)| route.instant)
+                    .map(|(&id, _)| id),
+                (None, _) => None,
+            };
+            id = match selected {
+                Some(id) => id,
+                None => {
+                    metrics::UDP_UNROUTED.increase();
+                    trace!(
+                        "drop datagram from {} of {} with no association",
+                        relay,
+                        peer
+                    );
+                    return;
+                }
+            };
+
+            let route = routes.get_mut(&id).unwrap();
+            result = route.reassembler.lock().unwrap().reassemble(buffer, size);
+            // The handler is called without the lock, so associations can send in it
+            handler = route.handler.take();
+        }
+
+        if let (Ok(Some((size, addr))), Some(handler)) = (result, handler.as_mut()) {
+            handler(&buffer[..size], addr);
+        }
+        if let Some(route) = self.routes.lock().unwrap().get_mut(&id) {
+            if route.handler.is_none() {
+                route.handler = handler;
+            }
+        }
+    }
+}
+
+impl Debug for SharedSocket {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("SharedSocket")
+            .field("socket", &self.socket)
+            .field("routes", &self.routes.lock().unwrap().len())
+            .finish()
+    }
+}
+
+/// Represents the route of an association on a shared socket to its relay. The route is removed
+/// once it is dropped, while the shared socket is kept for other associations.
+#[derive(Debug)]
+pub struct SharedDatagram {
+    socket: Arc<SharedSocket>,
+    id: u64,
+    relay: SocketAddr,
+}
+
+impl SharedDatagram {
+    /// Routes an association to the relay through a shared socket in the family of the relay.
+    /// A new socket is bound until the count of shared sockets is reached, then the socket with
+    /// the fewest associations to the relay and the fewest associations in total is shared, so
+    /// associations to the same relay are apart as far as possible.
+    pub fn bind(
+        relay: SocketAddr,
+        reassembler: Arc<Mutex<Reassembler>>,
+    ) -> io::Result<SharedDatagram> {
+        let count = get_shared_sockets();
+        let socket = {
+            let mut sockets = SOCKETS.lock().unwrap();
+            let family = sockets
+                .iter()
+                .filter(|socket| socket.is_family_of(relay))
+                .take(count)
+                .collect::<Vec<_>>();
+            if family.len() < count {
+                let socket = SharedSocket::bind(relay)?;
+                sockets.push(Arc::clone(&socket));
+
+                socket
+            } else {
+                let socket = family
+                    .into_iter()
+                    .min_by_key(|socket| socket.get_load(relay))
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "sockets are never shared")
+                    })?;
+
+                Arc::clone(socket)
+            }
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        socket.routes.lock().unwrap().insert(
+            id,
+            Route {
+                relay,
+                reassembler,
+                peers: HashSet::new(),
+                instant: Instant::now(),
+                handler: None,
+            },
+        );
+        trace!(
+            "route datagram {} to {} through shared datagram {}",
+            id,
+            relay,
+            socket.socket.local_addr()?
+        );
+
+        Ok(SharedDatagram { socket, id, relay })
+    }
+
+    /// Get the shared socket.
+    pub fn get_socket(&self) -> &UdpSocket {
+        &self.socket.socket
+    }
+
+    /// Get the relay of the association.
+    pub fn get_relay(&self) -> SocketAddr {
+        self.relay
+    }
+
+    /// Sets the handler of datagrams of the association.
+    pub fn set_handler(&self, handler: Handler) {
+        if let Some(route) = self.socket.routes.lock().unwrap().get_mut(&self.id) {
+            route.handler = Some(handler);
+        }
+    }
+
+    /// Sends a SOCKS5 UDP datagram to the relay, the peer in the header is remembered so
+    /// datagrams from it are routed back to the association.
+    pub fn send(&self, buffer: &[u8], peer: Option<SocketAddr>) -> io::Result<usize> {
+        if let Some(peer) = peer {
+            if let Some(route) = self.socket.routes.lock().unwrap().get_mut(&self.id) {
+                if route.peers.len() >= MAX_ROUTE_PEERS && !route.peers.contains(&peer) {
+                    route.peers.clear();
+                }
+                route.peers.insert(peer);
+                route.instant = Instant::now();
+            }
+        }
+
+        self.socket.socket.send_to(buffer, self.relay)
+    }
+}
+
+impl Drop for SharedDatagram {
+    fn drop(&mut self) {
+        self.socket.routes.lock().unwrap().remove(&self.id);
+        trace!("remove route of datagram {} to {}", self.id, self.relay);
+    }
+}
@@ -0,0 +1,648 @@
+#[cfg(feature = "async")]
+use super::BoxFuture;
+use super::{parse_header, ProxyConnector, ReplyError, SocksDatagram, SocksListener};
+use log::{debug, info, warn};
+use std::cmp::min;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddrV4, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+
+/// Represents the size of the length prefix of a frame.
+const LENGTH_SIZE: usize = 2;
+/// Represents the size of the RSV and FRAG fields of a SOCKS5 UDP datagram, which are not
+/// carried in frames.
+const RESERVED_SIZE: usize = 3;
+/// Represents the interval of checking if a tunnel is closed in milliseconds.
+const POLL_INTERVAL: u64 = 100;
+/// Represents the timeout of writing a frame to the stream.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Represents the max consecutive failed reconnections before a tunnel is closed.
+const MAX_RECONNECTS: usize = 5;
+/// Represents the interval before the first retry of a failed reconnection.
+const INITIAL_RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+/// Represents the max interval between retries of failed reconnections.
+const MAX_RECONNECT_INTERVAL: Duration = Duration::from_secs(8);
+
+/// Represents the path of UDP traffic through a proxy is not decided.
+const PATH_UNKNOWN: u8 = 0;
+/// Represents UDP traffic is relayed by UDP ASSOCIATE.
+const PATH_ASSOCIATE: u8 = 1;
+/// Represents UDP traffic is dropped.
+const PATH_DROP: u8 = 2;
+/// Represents UDP traffic is tunneled over TCP.
+const PATH_TUNNEL: u8 = 3;
+
+/// Represents the function connecting the stream of a tunnel, which is called again to
+/// reconnect after the stream breaks.
+pub type Connect = Box<dyn Fn() -> io::Result<TcpStream> + Send>;
+
+/// Converts a SOCKS5 UDP datagram to a frame of the tunnel. A frame is the size of the rest of
+/// the frame in 2 bytes in network byte order, followed by the ATYP, DST.ADDR and DST.PORT fields
+/// of the SOCKS5 UDP header and the data, where the address is the destination in frames from
+/// the client and the source in frames to the client. Returns `None` if the datagram is
+/// fragmented or too large.
+pub fn new_frame(datagram: &[u8]) -> Option<Vec<u8>> {
+    if datagram.len() < RESERVED_SIZE || datagram[2] != 0 {
+        return None;
+    }
+    let body = &datagram[RESERVED_SIZE..];
+    if body.len() > u16::MAX as usize {
+        return None;
+    }
+
+    let mut frame = Vec::with_capacity(LENGTH_SIZE + body.len());
+    frame.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    frame.extend_from_slice(body);
+
+    Some(frame)
+}
+
+/// Represents a decoder of frames of the tunnel from a stream.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    /// Creates a new `FrameDecoder`.
+    pub fn new() -> FrameDecoder {
+        FrameDecoder::default()
+    }
+
+    /// Appends data read from the stream.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Takes the next complete frame as a SOCKS5 UDP datagram. Returns an error if the frame is
+    /// invalid, after which the stream cannot be decoded anymore.
+    pub fn next_datagram(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.buffer.len() < LENGTH_SIZE {
+            return Ok(None);
+        }
+        let size = u16::from_be_bytes([self.buffer[0], self.buffer[1]]) as usize;
+        if self.buffer.len() < LENGTH_SIZE + size {
+            return Ok(None);
+        }
+
+        let mut datagram = vec![0u8; RESERVED_SIZE];
+        datagram.extend_from_slice(&self.buffer[LENGTH_SIZE..LENGTH_SIZE + size]);
+        self.buffer.drain(..LENGTH_SIZE + size);
+        parse_header(&datagram)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("frame: {}", e)))?;
+
+        Ok(Some(datagram))
+    }
+
+    /// Abandons the data of partial frames.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+}
+
+/// Represents a tunnel of UDP datagrams over a TCP stream. Datagrams are exchanged with a
+/// `SocksDatagram` on a loopback UDP socket as SOCKS5 UDP datagrams, and with the stream as
+/// frames. A broken stream is reconnected, and datagrams are dropped until it is back.
+#[derive(Debug)]
+pub struct Tunnel {
+    stream: Arc<Mutex<Option<TcpStream>>>,
+    is_closed: Arc<AtomicBool>,
+    is_failed: Arc<AtomicBool>,
+}
+
+impl Tunnel {
+    /// Opens a tunnel between the loopback socket and the stream, which runs on its own
+    /// threads until it is closed or fails to reconnect.
+    pub fn open(socket: UdpSocket, stream: TcpStream, connect: Connect) -> io::Result<Tunnel> {
+        socket.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL)))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let reader = stream.try_clone()?;
+        let socket_cloned = socket.try_clone()?;
+
+        let a_stream = Arc::new(Mutex::new(Some(stream)));
+        let a_stream_cloned = Arc::clone(&a_stream);
+        let a_is_closed = Arc::new(AtomicBool::new(false));
+        let a_is_closed_cloned = Arc::clone(&a_is_closed);
+        let a_is_failed = Arc::new(AtomicBool::new(false));
+        let a_is_failed_cloned = Arc::clone(&a_is_failed);
+
+        // Upstream, datagrams are dropped while the stream is reconnecting
+        let stream_up = Arc::clone(&a_stream);
+        let is_closed_up = Arc::clone(&a_is_closed);
+        thread::spawn(move || {
+            let mut buffer = vec![0u8; u16::MAX as usize + RESERVED_SIZE];
+            while !is_closed_up.load(Ordering::Relaxed) {
+                let size = match socket.recv(&mut buffer) {
+                    Ok(size) => size,
+                    Err(ref e)
+                        if e.kind() == io::ErrorKind::TimedOut
+                            || e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::Interrupted =>
+                    {
+                        continue
+                    }
+                    Err(_) => break,
+                };
+                let frame = match new_frame(&buffer[..size]) {
+                    Some(frame) => frame,
+                    None => continue,
+                };
+
+                let mut stream = stream_up.lock().unwrap();
+                if let Some(ref mut current) = *stream {
+                    if let Err(ref e) = current.write_all(&frame) {
+                        debug!("UDP tunnel: write: {}", e);
+                        let _ = current.shutdown(Shutdown::Both);
+                        *stream = None;
+                    }
+                }
+            }
+        });
+
+        // Downstream, and reconnections of the stream
+        thread::spawn(move || {
+            let mut reader = Some(reader);
+            let mut decoder = FrameDecoder::new();
+            let mut failures = 0;
+            let mut interval = INITIAL_RECONNECT_INTERVAL;
+            while !a_is_closed_cloned.load(Ordering::Relaxed) {
+                let mut current = match reader.take() {
+                    Some(current) => current,
+                    None => {
+                        if failures >= MAX_RECONNECTS {
+                            warn!("UDP tunnel: give up after {} reconnections", failures);
+                            break;
+                        }
+                        if failures > 0 {
+                            sleep(interval, &a_is_closed_cloned);
+                            interval = min(interval * 2, MAX_RECONNECT_INTERVAL);
+                        }
+                        let stream = connect().and_then(|stream| {
+                            stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                            let reader = stream.try_clone()?;
+
+                            Ok((stream, reader))
+                        });
+                        match stream {
+                            Ok((stream, current)) => {
+                                metrics::UDP_TUNNEL_RECONNECTIONS.increase();
+                                debug!("UDP tunnel: reconnected");
+                                *a_stream_cloned.lock().unwrap() = Some(stream);
+
+                                current
+                            }
+                            Err(ref e) => {
+                                failures += 1;
+                                debug!("UDP tunnel: reconnect: {}", e);
+                                continue;
+                            }
+                        }
+                    }
+                };
+
+                let instant = Instant::now();
+                match receive(
+                    &mut current,
+                    &mut decoder,
+                    &socket_cloned,
+                    &a_is_closed_cloned,
+                ) {
+                    Ok(()) => break,
+                    Err(ref e) => {
+                        debug!("UDP tunnel: read: {}", e);
+                        // The stream is reconnected in the next round
+                        decoder.clear();
+                        let mut stream = a_stream_cloned.lock().unwrap();
+                        if let Some(ref stream) = *stream {
+                            let _ = stream.shutdown(Shutdown::Both);
+                        }
+                        *stream = None;
+                        // A stream broken soon after it is connected counts as a failed
+                        // reconnection, so a companion closing streams at once is given up
+                        if instant.elapsed() < MAX_RECONNECT_INTERVAL {
+                            failures += 1;
+                        } else {
+                            failures = 1;
+                            interval = INITIAL_RECONNECT_INTERVAL;
+                        }
+                    }
+                }
+            }
+            a_is_failed_cloned.store(true, Ordering::Relaxed);
+        });
+
+        metrics::UDP_TUNNELS.increase();
+
+        Ok(Tunnel {
+            stream: a_stream,
+            is_closed: a_is_closed,
+            is_failed: a_is_failed,
+        })
+    }
+
+    /// Returns if the tunnel is closed, or it fails to reconnect the stream.
+    pub fn is_failed(&self) -> bool {
+        self.is_failed.load(Ordering::Relaxed) || self.is_closed.load(Ordering::Relaxed)
+    }
+
+    /// Closes the tunnel, its threads exit in the poll interval.
+    pub fn close(&self) {
+        if self.is_closed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        if let Some(ref stream) = *self.stream.lock().unwrap() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        metrics::UDP_TUNNELS.decrease();
+    }
+}
+
+/// Reads frames from the stream and sends them to the loopback socket until the tunnel is
+/// closed. Returns an error if the stream breaks or the frames are invalid.
+fn receive(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+    socket: &UdpSocket,
+    is_closed: &AtomicBool,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL)))?;
+
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    while !is_closed.load(Ordering::Relaxed) {
+        match stream.read(&mut buffer) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the stream is closed by the peer",
+                ))
+            }
+            Ok(size) => decoder.push(&buffer[..size]),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        }
+        while let Some(datagram) = decoder.next_datagram()? {
+            let _ = socket.send(&datagram);
+        }
+    }
+
+    Ok(())
+}
+
+/// Sleeps for the duration, or until the flag is set.
+fn sleep(duration: Duration, is_closed: &AtomicBool) {
+    let instant = Instant::now();
+    while !is_closed.load(Ordering::Relaxed) && instant.elapsed() < duration {
+        thread::sleep(min(
+            Duration::from_millis(POLL_INTERVAL),
+            duration - min(instant.elapsed(), duration),
+        ));
+    }
+}
+
+/// Represents the fallback of UDP traffic when the proxy does not support UDP ASSOCIATE.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UdpFallback {
+    /// Represents UDP ASSOCIATE is tried for every association.
+    None,
+    /// Represents UDP traffic is dropped after the proxy refuses UDP ASSOCIATE.
+    Drop,
+    /// Represents UDP traffic is tunneled over a TCP connection through the proxy to a companion
+    /// endpoint after the proxy refuses UDP ASSOCIATE.
+    Tcp,
+}
+
+impl UdpFallback {
+    /// Parses a UDP fallback from its name.
+    pub fn parse(s: &str) -> Option<UdpFallback> {
+        match s {
+            "none" => Some(UdpFallback::None),
+            "drop" => Some(UdpFallback::Drop),
+            "tcp" => Some(UdpFallback::Tcp),
+            _ => None,
+        }
+    }
+}
+
+impl Display for UdpFallback {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            UdpFallback::None => write!(f, "none"),
+            UdpFallback::Drop => write!(f, "drop"),
+            UdpFallback::Tcp => write!(f, "tcp"),
+        }
+    }
+}
+
+/// Represents a proxy whose UDP traffic falls back when the proxy does not support UDP
+/// ASSOCIATE. The path of UDP traffic is decided once for the proxy.
+pub struct UdpFallbackConnector {
+    proxy: Arc<dyn ProxyConnector>,
+    fallback: UdpFallback,
+    tunnel: Option<SocketAddrV4>,
+    timeout: Duration,
+    path: AtomicU8,
+}
+
+impl UdpFallbackConnector {
+    /// Creates a new `UdpFallbackConnector`. The tunnel is the companion endpoint UDP traffic
+    /// is tunneled to in the TCP fallback, which is connected through the proxy in the timeout.
+    pub fn new(
+        proxy: Arc<dyn ProxyConnector>,
+        fallback: UdpFallback,
+        tunnel: Option<SocketAddrV4>,
+        timeout: Duration,
+    ) -> UdpFallbackConnector {
+        UdpFallbackConnector {
+            proxy,
+            fallback,
+            tunnel,
+            timeout,
+            path: AtomicU8::new(PATH_UNKNOWN),
+        }
+    }
+
+    /// Decides the path of UDP traffic as the one of the fallback, the decision is logged once.
+    fn fall_back(&self, reason: &dyn Display) -> u8 {
+        let (path, name) = match (self.fallback, self.tunnel) {
+            (UdpFallback::Tcp, Some(_)) => (PATH_TUNNEL, "tunnel"),
+            _ => (PATH_DROP, "drop"),
+        };
+        if self.path.swap(path, Ordering::Relaxed) != path {
+            metrics::set_udp_path(name);
+            match path {
+                PATH_TUNNEL => warn!(
+                    "{}: {}, UDP traffic is tunneled over TCP to {}",
+                    self.proxy,
+                    reason,
+                    self.tunnel.unwrap()
+                ),
+                // The upstreamer warns UDP traffic is dropped
+                _ => debug!("{}: {}, UDP traffic will be dropped", self.proxy, reason),
+            }
+        }
+
+        path
+    }
+
+    fn bind_tunnel(&self) -> io::Result<SocksDatagram> {
+        let tunnel = self.tunnel.unwrap();
+        let proxy = Arc::clone(&self.proxy);
+        let timeout = self.timeout;
+
+        SocksDatagram::tunnel(Box::new(move || proxy.connect(tunnel, timeout)))
+    }
+}
+
+impl ProxyConnector for UdpFallbackConnector {
+    fn get_remote(&self) -> SocketAddrV4 {
+        self.proxy.get_remote()
+    }
+
+    fn handshake(&self, stream: &mut TcpStream, dst: SocketAddrV4) -> io::Result<()> {
+        self.proxy.handshake(stream, dst)
+    }
+
+    fn connect(&self, dst: SocketAddrV4, timeout: Duration) -> io::Result<TcpStream> {
+        self.proxy.connect(dst, timeout)
+    }
+
+    #[cfg(feature = "async")]
+    fn handshake_async<'a>(
+        &'a self,
+        stream: &'a mut tokio::net::TcpStream,
+        dst: SocketAddrV4,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        self.proxy.handshake_async(stream, dst)
+    }
+
+    #[cfg(feature = "async")]
+    fn connect_async(
+        &self,
+        dst: SocketAddrV4,
+        timeout: Duration,
+    ) -> BoxFuture<'_, io::Result<tokio::net::TcpStream>> {
+        self.proxy.connect_async(dst, timeout)
+    }
+
+    fn bind(&self, local_src: SocketAddrV4) -> io::Result<SocksDatagram> {
+        let mut path = self.path.load(Ordering::Relaxed);
+        if path == PATH_UNKNOWN || path == PATH_ASSOCIATE {
+            if self.proxy.is_udp_supported() {
+                match self.proxy.bind(local_src) {
+                    Ok(datagram) => {
+                        if self.path.swap(PATH_ASSOCIATE, Ordering::Relaxed) == PATH_UNKNOWN {
+                            metrics::set_udp_path("associate");
+                            info!("{}: UDP traffic is relayed by UDP ASSOCIATE", self.proxy);
+                        }
+
+                        return Ok(datagram);
+                    }
+                    Err(ref e)
+                        if ReplyError::from_io_error(e)
+                            == Some(ReplyError::CommandNotSupported) =>
+                    {
+                        path = self.fall_back(&format_args!("UDP ASSOCIATE failed: {}", e));
+                    }
+                    Err(e) => return Err(e),
+                }
+            } else {
+                path = self.fall_back(&"UDP is not supported");
+            }
+        }
+
+        match path {
+            PATH_TUNNEL => self.bind_tunnel(),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} does not support UDP", self),
+            )),
+        }
+    }
+
+    fn is_udp_supported(&self) -> bool {
+        match self.path.load(Ordering::Relaxed) {
+            PATH_TUNNEL => true,
+            PATH_DROP => false,
+            _ => self.proxy.is_udp_supported() || self.fallback == UdpFallback::Tcp,
+        }
+    }
+
+    fn is_bind_supported(&self) -> bool {
+        self.proxy.is_bind_supported()
+    }
+
+    fn listen(&self, dst: SocketAddrV4, timeout: Duration) -> io::Result<SocksListener> {
+        self.proxy.listen(dst, timeout)
+    }
+
+    fn check(&self, timeout: Duration) -> io::Result<()> {
+        self.proxy.check(timeout)
+    }
+
+    fn select(&self, is_udp: bool) -> Option<Arc<dyn ProxyConnector>> {
+        // UDP traffic keeps going through the fallback
+        match is_udp {
+            true => None,
+            false => self.proxy.select(is_udp),
+        }
+    }
+}
+
+impl Display for UdpFallbackConnector {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.proxy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::new_header;
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+    use std::sync::mpsc::{self, Receiver};
+
+    use crate::socks::SocksDatagram;
+
+    /// Represents the timeout of an echo through a tunnel, which covers a reconnection.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn dst() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 27015)
+    }
+
+    /// Starts a mock companion endpoint echoing frames, which closes each of the first streams
+    /// after its first frame. Returns its address and the receiver of accepted streams.
+    fn mock_companion(breaks: usize) -> (SocketAddrV4, Receiver<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                let _ = tx.send(());
+                thread::spawn(move || {
+                    let mut decoder = FrameDecoder::new();
+                    let mut buffer = [0u8; 1500];
+                    while let Ok(size) = stream.read(&mut buffer) {
+                        if size == 0 {
+                            break;
+                        }
+                        decoder.push(&buffer[..size]);
+                        while let Some(datagram) = decoder.next_datagram().unwrap() {
+                            let frame = new_frame(&datagram).unwrap();
+                            stream.write_all(&frame).unwrap();
+                            if i < breaks {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        (addr, rx)
+    }
+
+    /// Sends the payload through the tunnel until it is echoed in the timeout, as datagrams are
+    /// dropped while the stream is reconnecting.
+    fn echo(datagram: &SocksDatagram, payload: &[u8]) {
+        let instant = Instant::now();
+        let mut buffer = [0u8; 1500];
+        while instant.elapsed() < TIMEOUT {
+            datagram.send_to(payload, dst()).unwrap();
+            if let Ok((size, addr)) = datagram.recv_from(&mut buffer) {
+                assert_eq!(addr, dst());
+                if &buffer[..size] == payload {
+                    return;
+                }
+            }
+        }
+        panic!("no echo in {:?}", TIMEOUT);
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let mut datagram = new_header(SocketAddr::V4(dst()));
+        datagram.extend_from_slice(b"round trip");
+        let frame = new_frame(&datagram).unwrap();
+        assert_eq!(frame.len(), LENGTH_SIZE + datagram.len() - RESERVED_SIZE);
+        assert_eq!(
+            u16::from_be_bytes([frame[0], frame[1]]) as usize,
+            frame.len() - LENGTH_SIZE
+        );
+
+        // Frames split by the stream are decoded once complete
+        let mut decoder = FrameDecoder::new();
+        for b in frame.iter() {
+            assert!(decoder.next_datagram().unwrap().is_none());
+            decoder.push(&[*b]);
+        }
+        assert_eq!(decoder.next_datagram().unwrap().unwrap(), datagram);
+        let frames = [&frame[..], &frame[..]].concat();
+        decoder.push(&frames);
+        assert_eq!(decoder.next_datagram().unwrap().unwrap(), datagram);
+        assert_eq!(decoder.next_datagram().unwrap().unwrap(), datagram);
+        assert!(decoder.next_datagram().unwrap().is_none());
+
+        // Partial frames are abandoned
+        decoder.push(&frame[..frame.len() - 1]);
+        decoder.clear();
+        decoder.push(&frame);
+        assert_eq!(decoder.next_datagram().unwrap().unwrap(), datagram);
+    }
+
+    #[test]
+    fn frame_invalid() {
+        // Fragmented or too large datagrams are not framed
+        let mut datagram = new_header(SocketAddr::V4(dst()));
+        datagram[2] = 1;
+        assert_eq!(new_frame(&datagram), None);
+        assert_eq!(new_frame(&[0, 0]), None);
+        let mut datagram = new_header(SocketAddr::V4(dst()));
+        datagram.resize(RESERVED_SIZE + u16::MAX as usize + 1, 0);
+        assert_eq!(new_frame(&datagram), None);
+
+        // A frame of an unknown address type
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&[0, 7, 9, 203, 0, 113, 7, 0, 53]);
+        assert_eq!(
+            decoder.next_datagram().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn reconnect() {
+        let (addr, accepts) = mock_companion(1);
+        let datagram = SocksDatagram::tunnel(Box::new(move || TcpStream::connect(addr))).unwrap();
+        datagram
+            .set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL)))
+            .unwrap();
+        accepts.recv_timeout(TIMEOUT).unwrap();
+
+        // The companion closes the first stream after the echo, and the tunnel reconnects
+        echo(&datagram, b"before");
+        accepts.recv_timeout(TIMEOUT).unwrap();
+        echo(&datagram, b"after");
+        echo(&datagram, b"again");
+        assert!(accepts.try_recv().is_err());
+    }
+}
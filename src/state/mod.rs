@@ -0,0 +1,191 @@
+//! State of UDP associations kept across restarts in TOML.
+//!
+//! ```toml
+//! saved = 1600000000
+//!
+//! [[udp]]
+//! source = "10.6.0.2:27005"
+//! local-port = 32768
+//! destination = "203.0.113.1:27015"
+//! peers = ["203.0.113.1:27015"]
+//! active = 1599999990
+//! ```
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::net::SocketAddrV4;
+use std::path::Path;
+use std::result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use toml::value::{Table, Value};
+
+/// Represents an error when read or write a state file.
+#[derive(Debug)]
+pub enum StateError {
+    IoError(io::Error),
+    ParseError(String),
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self {
+            StateError::IoError(ref e) => write!(f, "state: {}", e),
+            StateError::ParseError(ref s) => write!(f, "state: {}", s),
+        }
+    }
+}
+
+impl Error for StateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self {
+            StateError::IoError(ref e) => Some(e),
+            StateError::ParseError(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for StateError {
+    fn from(s: io::Error) -> Self {
+        StateError::IoError(s)
+    }
+}
+
+type Result<T> = result::Result<T, StateError>;
+
+/// Represents the state of a UDP association relayed by the proxy.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UdpState {
+    /// Represents the address and the port of the source
+    pub src: SocketAddrV4,
+    /// Represents the local port the association binds for the SOCKS relay
+    pub local_port: u16,
+    /// Represents the destination of the first datagram of the association
+    pub dst: SocketAddrV4,
+    /// Represents the recent peers the association sent to, from the least recent
+    pub peers: Vec<SocketAddrV4>,
+    /// Represents the time of the last datagram in seconds since the UNIX epoch
+    pub active: u64,
+}
+
+impl UdpState {
+    /// Get the time since the last datagram of the association.
+    pub fn get_idle(&self) -> Duration {
+        Duration::from_secs(unix_now().saturating_sub(self.active))
+    }
+}
+
+/// Get the time in seconds since the UNIX epoch of the time which is the duration before now.
+pub fn unix_time_before(duration: Duration) -> u64 {
+    unix_now().saturating_sub(duration.as_secs())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Reads the UDP associations of the state file. Associations idle for the timeout are
+/// discarded, and a state file which does not exist has no association.
+pub fn load(path: &Path, timeout: Duration) -> Result<Vec<UdpState>> {
+    let s = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StateError::IoError(e)),
+    };
+    let value: Value = s
+        .parse()
+        .map_err(|e: toml::de::Error| StateError::ParseError(e.to_string()))?;
+    let invalid = |key: &str| StateError::ParseError(format!("invalid {}", key));
+
+    let table = value.as_table().ok_or_else(|| invalid("state"))?;
+    let mut states = Vec::new();
+    if let Some(udp) = table.get("udp") {
+        for entry in udp.as_array().ok_or_else(|| invalid("udp"))? {
+            let entry = entry.as_table().ok_or_else(|| invalid("udp"))?;
+            let local_port = entry
+                .get("local-port")
+                .and_then(|value| value.as_integer())
+                .filter(|&port| port > 0 && port <= u16::MAX as i64)
+                .ok_or_else(|| invalid("udp.local-port"))?;
+            let peers = match entry.get("peers") {
+                Some(peers) => peers
+                    .as_array()
+                    .ok_or_else(|| invalid("udp.peers"))?
+                    .iter()
+                    .map(|peer| peer.as_str().and_then(|peer| peer.parse().ok()))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| invalid("udp.peers"))?,
+                None => Vec::new(),
+            };
+            let active = entry
+                .get("active")
+                .and_then(|value| value.as_integer())
+                .filter(|&active| active >= 0)
+                .ok_or_else(|| invalid("udp.active"))?;
+
+            states.push(UdpState {
+                src: get_addr(entry, "source").ok_or_else(|| invalid("udp.source"))?,
+                local_port: local_port as u16,
+                dst: get_addr(entry, "destination").ok_or_else(|| invalid("udp.destination"))?,
+                peers,
+                active: active as u64,
+            });
+        }
+    }
+    states.retain(|state| state.get_idle() < timeout);
+
+    Ok(states)
+}
+
+/// Writes the UDP associations to the state file. The file is replaced at once, so it is never
+/// left half written.
+pub fn save(path: &Path, states: &[UdpState]) -> Result<()> {
+    let mut root = Table::new();
+    root.insert(String::from("saved"), Value::Integer(unix_now() as i64));
+    let udp = states
+        .iter()
+        .map(|state| {
+            let mut entry = Table::new();
+            entry.insert(String::from("source"), Value::String(state.src.to_string()));
+            entry.insert(
+                String::from("local-port"),
+                Value::Integer(state.local_port as i64),
+            );
+            entry.insert(
+                String::from("destination"),
+                Value::String(state.dst.to_string()),
+            );
+            entry.insert(
+                String::from("peers"),
+                Value::Array(
+                    state
+                        .peers
+                        .iter()
+                        .map(|peer| Value::String(peer.to_string()))
+                        .collect(),
+                ),
+            );
+            entry.insert(String::from("active"), Value::Integer(state.active as i64));
+
+            Value::Table(entry)
+        })
+        .collect();
+    root.insert(String::from("udp"), Value::Array(udp));
+
+    let s =
+        toml::to_string(&Value::Table(root)).map_err(|e| StateError::ParseError(e.to_string()))?;
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, s)?;
+    fs::rename(&temp, path)?;
+
+    Ok(())
+}
+
+fn get_addr(table: &Table, key: &str) -> Option<SocketAddrV4> {
+    table.get(key)?.as_str()?.parse().ok()
+}
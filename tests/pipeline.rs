@@ -0,0 +1,854 @@
+//! Runs the whole pipeline, from parsing frames to the state of connections to the frames sent,
+//! over an in-memory channel and a mock SOCKS5 server on localhost, and asserts the exact frames
+//! sent to the source. Steps are synchronized by the frames collected, the events of
+//! connections and the permits of the mock SOCKS5 server, and timers by a manual clock.
+
+use pcap2socks::clock::ManualClock;
+use pcap2socks::event::{CloseReason, Event};
+use pcap2socks::metrics;
+use pcap2socks::packet::layer::ethernet::Ethernet;
+use pcap2socks::packet::layer::ipv4::Ipv4;
+use pcap2socks::packet::layer::tcp::Tcp;
+use pcap2socks::packet::layer::udp::Udp;
+use pcap2socks::packet::layer::{LayerType, LayerTypes, Layers};
+use pcap2socks::packet::Indicator;
+use pcap2socks::pcap::channel::{self, Peer};
+use pcap2socks::pcap::chaos::Chaos;
+use pcap2socks::pcap::HardwareAddr;
+use pcap2socks::socks::{SocksConnector, SocksOption};
+use pcap2socks::{Handle, Pcap2Socks, DEFAULT_UDP_TIMEOUT};
+use pnet::datalink::MacAddr;
+use pnet::packet::icmp::{self, IcmpPacket};
+use pnet::packet::ipv4::{self, Ipv4Packet};
+use pnet::packet::tcp::TcpFlags;
+use pnet::packet::Packet;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// Represents the hardware address of the emulated interface.
+const LOCAL_HARDWARE_ADDR: HardwareAddr = MacAddr(0x02, 0x00, 0x00, 0x00, 0x00, 0x01);
+/// Represents the IPv4 address of the emulated interface.
+const LOCAL_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 254);
+/// Represents the hardware address of the source.
+const SOURCE_HARDWARE_ADDR: HardwareAddr = MacAddr(0x02, 0x00, 0x00, 0x00, 0x00, 0x02);
+/// Represents the IPv4 address of the source.
+const SOURCE_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 1);
+/// Represents the IPv4 address of the destination, which is only reached through the proxy.
+const DESTINATION_IP_ADDR: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+/// Represents the MTU of the emulated interface.
+const MTU: u16 = 1500;
+/// Represents the window the source advertises.
+const SOURCE_WINDOW: u16 = 65535;
+/// Represents the window advertised to the source by default.
+const LOCAL_WINDOW: u16 = 65535;
+/// Represents the MSS advertised to the source, the MTU less the IPv4 and TCP headers.
+const LOCAL_MSS: u16 = MTU - 40;
+/// Represents the timeout of collecting a frame.
+const COLLECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Represents the port of the destination whose CONNECTs the mock SOCKS5 server refuses.
+const REFUSED_PORT: u16 = 81;
+/// Represents the time a source in a lossy run waits for a frame before it assumes the frames
+/// sent are lost.
+const LOSS_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Represents a frame of an ICMPv4 echo request captured from `ping` on Linux, from the source to
+/// the local IP address.
+const ECHO_REQUEST: [u8; 98] = [
+    0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x08, 0x00, 0x45, 0x00,
+    0x00, 0x54, 0x4d, 0x2e, 0x40, 0x00, 0x40, 0x01, 0xd8, 0x70, 0x0a, 0x06, 0x00, 0x01, 0x0a, 0x06,
+    0x00, 0xfe, 0x08, 0x00, 0xfb, 0x49, 0x1c, 0x2b, 0x00, 0x01, 0xa1, 0xb2, 0xf8, 0x65, 0x00, 0x00,
+    0x00, 0x00, 0x7c, 0x9e, 0x0b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15,
+    0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f, 0x20, 0x21, 0x22, 0x23, 0x24, 0x25,
+    0x26, 0x27, 0x28, 0x29, 0x2a, 0x2b, 0x2c, 0x2d, 0x2e, 0x2f, 0x30, 0x31, 0x32, 0x33, 0x34, 0x35,
+    0x36, 0x37,
+];
+
+/// Serves a connection of the mock SOCKS5 server without authentication. A CONNECT echoes the
+/// stream until the client closes its half, and then closes, and a UDP ASSOCIATE echoes
+/// datagrams with their SOCKS headers until the control connection is closed. Each echo and
+/// close of a CONNECT waits for a permit, as the ACK of data or a FIN is sent to the source after
+/// it is relayed to the proxy, and an echo or a close in no time may race with it. CONNECTs are
+/// counted, and those to the refused port are refused.
+fn serve(mut stream: TcpStream, connects: &AtomicUsize, permits: &Mutex<mpsc::Receiver<()>>) {
+    // Greeting
+    let mut header = [0u8; 2];
+    if stream.read_exact(&mut header).is_err() {
+        return;
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).unwrap();
+    assert!(methods.contains(&0));
+    stream.write_all(&[5, 0]).unwrap();
+
+    // Request of an IPv4 address
+    let mut request = [0u8; 10];
+    if stream.read_exact(&mut request).is_err() {
+        return;
+    }
+    assert_eq!(request[3], 1);
+    match request[1] {
+        // CONNECT
+        1 => {
+            connects.fetch_add(1, Ordering::Relaxed);
+            if u16::from_be_bytes([request[8], request[9]]) == REFUSED_PORT {
+                stream.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+                return;
+            }
+            stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+            let mut buffer = [0u8; 1500];
+            loop {
+                match stream.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if permits.lock().unwrap().recv().is_err() {
+                            return;
+                        }
+                        stream.write_all(&buffer[..n]).unwrap();
+                    }
+                }
+            }
+            let _ = permits.lock().unwrap().recv();
+        }
+        // UDP ASSOCIATE
+        3 => {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let port = socket.local_addr().unwrap().port().to_be_bytes();
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, port[0], port[1]])
+                .unwrap();
+            thread::spawn(move || {
+                let mut buffer = [0u8; 1500];
+                while let Ok((n, addr)) = socket.recv_from(&mut buffer) {
+                    if socket.send_to(&buffer[..n], addr).is_err() {
+                        break;
+                    }
+                }
+            });
+            let mut buffer = [0u8; 1];
+            while let Ok(n) = stream.read(&mut buffer) {
+                if n == 0 {
+                    break;
+                }
+            }
+        }
+        command => panic!("unexpected command {}", command),
+    }
+}
+
+/// Represents the mock SOCKS5 server.
+struct Proxy {
+    addr: SocketAddrV4,
+    connects: Arc<AtomicUsize>,
+    permits: mpsc::Sender<()>,
+}
+
+impl Proxy {
+    /// Starts the mock SOCKS5 server.
+    fn start() -> Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let connects = Arc::new(AtomicUsize::new(0));
+        let connects_cloned = Arc::clone(&connects);
+        let (permits, permits_rx) = mpsc::channel();
+        let permits_rx = Arc::new(Mutex::new(permits_rx));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                let connects = Arc::clone(&connects_cloned);
+                let permits = Arc::clone(&permits_rx);
+                thread::spawn(move || serve(stream, &connects, &permits));
+            }
+        });
+
+        Proxy {
+            addr,
+            connects,
+            permits,
+        }
+    }
+
+    /// Permits the next echo or close of a CONNECT.
+    fn release(&self) {
+        self.permits.send(()).unwrap();
+    }
+
+    /// Get the count of CONNECTs served.
+    fn get_connects(&self) -> usize {
+        self.connects.load(Ordering::Relaxed)
+    }
+}
+
+/// Represents `Pcap2Socks` running on an in-memory channel to the mock SOCKS5 server, with a
+/// manual clock, so timers never fire unless the clock is advanced.
+struct Harness {
+    handle: Handle,
+    peer: Peer,
+    proxy: Proxy,
+    events: mpsc::Receiver<Event>,
+    clock: ManualClock,
+    /// Represents the guard serializing harnesses, as metrics and the subscriber of events are
+    /// shared by the whole process
+    _guard: MutexGuard<'static, ()>,
+}
+
+/// Represents the lock serializing harnesses.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// Spawns `Pcap2Socks` on an in-memory channel to the mock SOCKS5 server.
+fn spawn() -> Harness {
+    spawn_with(|builder| builder)
+}
+
+/// Spawns `Pcap2Socks` like `spawn`, with the builder configured further.
+fn spawn_with<F: FnOnce(Pcap2Socks) -> Pcap2Socks>(f: F) -> Harness {
+    // A failed test poisons the lock, which does not affect the others
+    let guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let proxy = Proxy::start();
+    let connector = SocksConnector::new(proxy.addr, SocksOption::default());
+    let (tx, rx, peer) = channel::open();
+    let (events_tx, events) = mpsc::channel();
+    let clock = ManualClock::new();
+    let inter = channel::interface(LOCAL_HARDWARE_ADDR, LOCAL_IP_ADDR, MTU);
+    let builder = Pcap2Socks::new(SOURCE_IP_ADDR, Arc::new(connector))
+        .channel(inter, tx, rx)
+        .delayed_ack(Duration::from_millis(0))
+        .shutdown_timeout(Duration::from_secs(0))
+        .events(events_tx)
+        .clock(Arc::new(clock.clone()));
+    let handle = f(builder).spawn().unwrap();
+
+    Harness {
+        handle,
+        peer,
+        proxy,
+        events,
+        clock,
+        _guard: guard,
+    }
+}
+
+impl Harness {
+    /// Waits for the next connection closed, returns the reason.
+    fn wait_close(&self) -> CloseReason {
+        loop {
+            match self
+                .events
+                .recv_timeout(COLLECT_TIMEOUT)
+                .expect("close event")
+            {
+                Event::ConnectionClose { reason, .. } => return reason,
+                _ => {}
+            }
+        }
+    }
+
+    /// Stops `Pcap2Socks` and waits for it, returns the frames sent but not collected.
+    fn stop(self) -> Vec<Vec<u8>> {
+        self.handle.stop();
+        self.handle.join().unwrap();
+
+        self.peer.collect_all()
+    }
+
+    /// Stops `Pcap2Socks`, and asserts no more frames are sent.
+    fn stop_quiet(self) {
+        let frames = self.stop();
+        assert!(frames.is_empty(), "unexpected frames {:?}", frames);
+    }
+}
+
+/// Builds a frame from the source to the destination.
+fn from_source(identification: u16, transport: Layers, payload: &[u8]) -> Vec<u8> {
+    let t = transport_type(&transport);
+    let indicator = Indicator::new(
+        Layers::Ethernet(
+            Ethernet::new(LayerTypes::Ipv4, SOURCE_HARDWARE_ADDR, LOCAL_HARDWARE_ADDR).unwrap(),
+        ),
+        Some(Layers::Ipv4(
+            Ipv4::new(identification, t, SOURCE_IP_ADDR, DESTINATION_IP_ADDR).unwrap(),
+        )),
+        Some(transport),
+    );
+
+    indicator.to_vec_with_payload(payload).unwrap()
+}
+
+/// Builds the frame expected from the destination to the source.
+fn to_source(identification: u16, transport: Layers, payload: &[u8]) -> Vec<u8> {
+    let t = transport_type(&transport);
+    let indicator = Indicator::new(
+        Layers::Ethernet(
+            Ethernet::new(LayerTypes::Ipv4, LOCAL_HARDWARE_ADDR, SOURCE_HARDWARE_ADDR).unwrap(),
+        ),
+        Some(Layers::Ipv4(
+            Ipv4::new(identification, t, DESTINATION_IP_ADDR, SOURCE_IP_ADDR).unwrap(),
+        )),
+        Some(transport),
+    );
+
+    indicator.to_vec_with_payload(payload).unwrap()
+}
+
+fn transport_type(transport: &Layers) -> LayerType {
+    match transport {
+        Layers::Tcp(_) => LayerTypes::Tcp,
+        Layers::Udp(_) => LayerTypes::Udp,
+        _ => unreachable!(),
+    }
+}
+
+/// Collects the next frame sent to the source, returns it with its IPv4 identification.
+fn collect(peer: &Peer) -> (Vec<u8>, u16) {
+    let frame = peer.collect(COLLECT_TIMEOUT).expect("collect frame");
+    let identification = Indicator::from(&frame)
+        .unwrap()
+        .get_ipv4()
+        .expect("IPv4 frame")
+        .get_identification();
+
+    (frame, identification)
+}
+
+/// Builds a TCP layer from the source to the destination.
+fn tcp_from_source(flags: u16, sequence: u32, acknowledgement: u32) -> Layers {
+    let mut tcp = Tcp::new_ack(
+        SOURCE_IP_ADDR.into(),
+        DESTINATION_IP_ADDR.into(),
+        40000,
+        80,
+        sequence,
+        acknowledgement,
+        SOURCE_WINDOW,
+    );
+    tcp.layer.flags = flags;
+
+    Layers::Tcp(tcp)
+}
+
+/// Builds a TCP layer from the destination to the source.
+fn tcp_to_source(flags: u16, sequence: u32, acknowledgement: u32) -> Tcp {
+    let mut tcp = Tcp::new_ack(
+        DESTINATION_IP_ADDR.into(),
+        SOURCE_IP_ADDR.into(),
+        80,
+        40000,
+        sequence,
+        acknowledgement,
+        LOCAL_WINDOW,
+    );
+    tcp.layer.flags = flags;
+
+    tcp
+}
+
+/// Completes the handshake of the source port from the sequence, returns the ISN of the
+/// destination, which is random.
+fn handshake(peer: &Peer, sequence: u32) -> u32 {
+    let syn = tcp_from_source(TcpFlags::SYN, sequence, 0);
+    peer.inject(&from_source(1, syn, &[])).unwrap();
+    let (frame, _) = collect(peer);
+    let isn = Indicator::from(&frame)
+        .unwrap()
+        .get_tcp()
+        .expect("TCP frame")
+        .get_sequence();
+    let ack = tcp_from_source(TcpFlags::ACK, sequence.wrapping_add(1), isn.wrapping_add(1));
+    peer.inject(&from_source(2, ack, &[])).unwrap();
+
+    isn
+}
+
+/// Collects frames sent to the source until a TCP segment satisfying the predicate, returns
+/// the TCP segments collected, the last one satisfies the predicate.
+fn collect_tcp_until<F: Fn(&Tcp) -> bool>(peer: &Peer, f: F) -> Vec<Tcp> {
+    let mut segments = Vec::new();
+    loop {
+        let (frame, _) = collect(peer);
+        let indicator = Indicator::from(&frame).unwrap();
+        let tcp = indicator.get_tcp().expect("TCP frame").clone();
+        let is_done = f(&tcp);
+        segments.push(tcp);
+        if is_done {
+            return segments;
+        }
+    }
+}
+
+/// Collects the next TCP segment sent to the source, skipping window updates of the stale
+/// acknowledgement, returns it with its IPv4 identification and its window. The window may be
+/// shrunk by the data not relayed to the proxy yet, and is updated in the next timer.
+fn collect_windowed(peer: &Peer, stale: Option<u32>, shrink: u32) -> (Vec<u8>, u16, u16) {
+    loop {
+        let (frame, id) = collect(peer);
+        let indicator = Indicator::from(&frame).unwrap();
+        let tcp = indicator.get_tcp().expect("TCP frame");
+        let window = tcp.layer.window;
+        assert!(window <= LOCAL_WINDOW && window >= LOCAL_WINDOW - shrink as u16);
+        let is_update = tcp.layer.flags == TcpFlags::ACK
+            && tcp.get_payload().is_empty()
+            && Some(tcp.get_acknowledgement()) == stale;
+        if !is_update {
+            return (frame, id, window);
+        }
+    }
+}
+
+#[test]
+fn tcp_connect_transfer_close() {
+    let harness = spawn();
+    let peer = &harness.peer;
+    let data = b"GET / HTTP/1.1\r\n\r\n";
+    let size = data.len() as u32;
+
+    // Handshake, the ISN and the identification are random, and taken from the ACK/SYN
+    let syn = tcp_from_source(TcpFlags::SYN, 1000, 0);
+    peer.inject(&from_source(1, syn, &[])).unwrap();
+    let (frame, id) = collect(peer);
+    let isn = Indicator::from(&frame)
+        .unwrap()
+        .get_tcp()
+        .unwrap()
+        .get_sequence();
+    let seq = |n: u32| isn.wrapping_add(n);
+    let mut ack_syn = tcp_to_source(TcpFlags::SYN | TcpFlags::ACK, isn, 1001);
+    ack_syn.set_mss(LOCAL_MSS);
+    assert_eq!(frame, to_source(id, Layers::Tcp(ack_syn), &[]));
+    let ack = tcp_from_source(TcpFlags::ACK, 1001, seq(1));
+    peer.inject(&from_source(2, ack, &[])).unwrap();
+
+    // Data, which is acknowledged immediately and echoed by the proxy once permitted
+    let psh = tcp_from_source(TcpFlags::PSH | TcpFlags::ACK, 1001, seq(1));
+    peer.inject(&from_source(3, psh, data)).unwrap();
+    let (frame, id, window) = collect_windowed(peer, None, size);
+    let mut ack = tcp_to_source(TcpFlags::ACK, seq(1), 1001 + size);
+    ack.layer.window = window;
+    assert_eq!(frame, to_source(id, Layers::Tcp(ack), &[]));
+    harness.proxy.release();
+    let (frame, id, window) = collect_windowed(peer, Some(1001 + size), size);
+    let mut echo = tcp_to_source(TcpFlags::ACK, seq(1), 1001 + size);
+    echo.layer.window = window;
+    assert_eq!(frame, to_source(id, Layers::Tcp(echo), data));
+    let ack = tcp_from_source(TcpFlags::ACK, 1001 + size, seq(1 + size));
+    peer.inject(&from_source(4, ack, &[])).unwrap();
+
+    // Close by the source, the proxy closes after its half is closed
+    let fin = tcp_from_source(TcpFlags::FIN | TcpFlags::ACK, 1001 + size, seq(1 + size));
+    peer.inject(&from_source(5, fin, &[])).unwrap();
+    let (frame, id, window) = collect_windowed(peer, Some(1001 + size), size);
+    let mut ack = tcp_to_source(TcpFlags::ACK, seq(1 + size), 1002 + size);
+    ack.layer.window = window;
+    assert_eq!(frame, to_source(id, Layers::Tcp(ack), &[]));
+    harness.proxy.release();
+    let (frame, id, window) = collect_windowed(peer, Some(1002 + size), size);
+    let mut fin = tcp_to_source(TcpFlags::FIN | TcpFlags::ACK, seq(1 + size), 1002 + size);
+    fin.layer.window = window;
+    assert_eq!(frame, to_source(id, Layers::Tcp(fin), &[]));
+    let ack = tcp_from_source(TcpFlags::ACK, 1002 + size, seq(2 + size));
+    peer.inject(&from_source(6, ack, &[])).unwrap();
+    assert_eq!(harness.wait_close(), CloseReason::Fin);
+
+    harness.stop_quiet();
+}
+
+#[test]
+fn udp_exchange() {
+    let harness = spawn();
+    let data = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00";
+
+    // The datagram is relayed through a UDP association and echoed by the proxy
+    let udp = Udp::new(SOURCE_IP_ADDR.into(), DESTINATION_IP_ADDR.into(), 40001, 53);
+    harness
+        .peer
+        .inject(&from_source(1, Layers::Udp(udp), data))
+        .unwrap();
+    let (frame, id) = collect(&harness.peer);
+    let echo = Udp::new(DESTINATION_IP_ADDR.into(), SOURCE_IP_ADDR.into(), 53, 40001);
+    assert_eq!(frame, to_source(id, Layers::Udp(echo), data));
+
+    harness.stop_quiet();
+}
+
+#[test]
+fn tcp_duplicate_syn() {
+    let harness = spawn();
+    let peer = &harness.peer;
+
+    // Retransmitted SYNs, before and after the ACK/SYN, are answered from the same ISN
+    let syn = tcp_from_source(TcpFlags::SYN, 2000, 0);
+    peer.inject(&from_source(1, syn.clone(), &[])).unwrap();
+    peer.inject(&from_source(1, syn.clone(), &[])).unwrap();
+    let (frame, _) = collect(peer);
+    let isn = Indicator::from(&frame)
+        .unwrap()
+        .get_tcp()
+        .expect("TCP frame")
+        .get_sequence();
+    peer.inject(&from_source(1, syn, &[])).unwrap();
+
+    // The connection is established once, and reset by the source
+    let ack = tcp_from_source(TcpFlags::ACK, 2001, isn.wrapping_add(1));
+    peer.inject(&from_source(2, ack, &[])).unwrap();
+    let rst = tcp_from_source(TcpFlags::RST | TcpFlags::ACK, 2001, isn.wrapping_add(1));
+    peer.inject(&from_source(3, rst, &[])).unwrap();
+    assert_eq!(harness.wait_close(), CloseReason::Reset);
+    assert_eq!(harness.proxy.get_connects(), 1);
+
+    let frames = harness.stop();
+    assert!(!frames.is_empty());
+    for frame in frames {
+        let indicator = Indicator::from(&frame).unwrap();
+        let tcp = indicator.get_tcp().expect("TCP frame");
+        assert!(tcp.is_syn() && tcp.is_ack());
+        assert_eq!(tcp.get_sequence(), isn);
+        assert_eq!(tcp.get_acknowledgement(), 2001);
+    }
+}
+
+#[test]
+fn tcp_corner_flags() {
+    let harness = spawn();
+    let peer = &harness.peer;
+    let isn = handshake(peer, 3000);
+    let seq = |n: u32| isn.wrapping_add(n);
+
+    // Urgent data is delivered inline
+    let data = b"\xff\xf4\xff\xfd\x06";
+    let size = data.len() as u32;
+    let mut urg = tcp_from_source(TcpFlags::URG | TcpFlags::PSH | TcpFlags::ACK, 3001, seq(1));
+    if let Layers::Tcp(ref mut tcp) = urg {
+        tcp.layer.urgent_ptr = 2;
+    }
+    peer.inject(&from_source(3, urg, data)).unwrap();
+    let mut segments = collect_tcp_until(peer, |tcp| tcp.get_acknowledgement() == 3001 + size);
+    harness.proxy.release();
+    segments.extend(collect_tcp_until(peer, |tcp| !tcp.get_payload().is_empty()));
+    for tcp in &segments {
+        assert!(!tcp.is_urg());
+    }
+    let echo = segments.last().unwrap();
+    assert_eq!(echo.get_acknowledgement(), 3001 + size);
+    assert_eq!(echo.get_payload(), &data[..]);
+    let mut next = 3001 + size;
+    let ack = tcp_from_source(TcpFlags::ACK, next, seq(1 + size));
+    peer.inject(&from_source(4, ack, &[])).unwrap();
+
+    // A keepalive of a garbage byte before the expected sequence is answered without
+    // disturbing the reassembly, the next data is delivered in order
+    let keepalive = tcp_from_source(TcpFlags::ACK, next - 1, seq(1 + size));
+    peer.inject(&from_source(5, keepalive, b"\0")).unwrap();
+    let segments = collect_tcp_until(peer, |_| true);
+    assert_eq!(segments[0].get_acknowledgement(), next);
+    assert!(segments[0].get_payload().is_empty());
+    let data = b"PING :keepalive\r\n";
+    next += data.len() as u32;
+    let psh = tcp_from_source(
+        TcpFlags::PSH | TcpFlags::ACK,
+        next - data.len() as u32,
+        seq(1 + size),
+    );
+    peer.inject(&from_source(6, psh, data)).unwrap();
+    collect_tcp_until(peer, |tcp| tcp.get_acknowledgement() == next);
+    harness.proxy.release();
+    let segments = collect_tcp_until(peer, |tcp| !tcp.get_payload().is_empty());
+    assert_eq!(segments.last().unwrap().get_payload(), &data[..]);
+    let echoed = size + data.len() as u32;
+    let ack = tcp_from_source(TcpFlags::ACK, next, seq(1 + echoed));
+    peer.inject(&from_source(7, ack, &[])).unwrap();
+
+    // The data of a FIN is delivered before the FIN, and the proxy closes after it echoes
+    let data = b"QUIT\r\n";
+    let fin = tcp_from_source(TcpFlags::FIN | TcpFlags::ACK, next, seq(1 + echoed));
+    peer.inject(&from_source(8, fin, data)).unwrap();
+    let next = next + data.len() as u32 + 1;
+    let mut segments = collect_tcp_until(peer, |tcp| tcp.get_acknowledgement() == next);
+    harness.proxy.release();
+    harness.proxy.release();
+    segments.extend(collect_tcp_until(peer, |tcp| tcp.is_fin()));
+    for tcp in &segments {
+        assert_eq!(tcp.get_acknowledgement(), next);
+    }
+    let payload: Vec<u8> = segments
+        .iter()
+        .flat_map(|tcp| tcp.get_payload().to_vec())
+        .collect();
+    assert_eq!(payload, data);
+    let echoed = echoed + data.len() as u32;
+    let ack = tcp_from_source(TcpFlags::ACK, next, seq(2 + echoed));
+    peer.inject(&from_source(9, ack, &[])).unwrap();
+    assert_eq!(harness.wait_close(), CloseReason::Fin);
+
+    // Illegal combinations of flags are reset, except with a RST, and never connect. The RST
+    // goes first, so it is handled once the others are reset
+    let port = 40002;
+    for (i, &flags) in [
+        TcpFlags::SYN | TcpFlags::RST,
+        TcpFlags::SYN | TcpFlags::FIN,
+        0,
+    ]
+    .iter()
+    .enumerate()
+    {
+        let mut tcp = tcp_from_source(flags, 5000, 0);
+        if let Layers::Tcp(ref mut tcp) = tcp {
+            tcp.layer.source = port;
+        }
+        peer.inject(&from_source(10 + i as u16, tcp, &[])).unwrap();
+    }
+    for &acknowledgement in &[5001, 5000] {
+        let (frame, id) = collect(peer);
+        let mut rst = tcp_to_source(TcpFlags::RST | TcpFlags::ACK, 0, acknowledgement);
+        rst.layer.destination = port;
+        assert_eq!(frame, to_source(id, Layers::Tcp(rst), &[]));
+    }
+    assert_eq!(harness.proxy.get_connects(), 1);
+
+    harness.stop_quiet();
+}
+
+#[test]
+fn icmp_echo() {
+    // Only a single published address answers pings
+    let harness = spawn_with(|builder| builder.publish(LOCAL_IP_ADDR, 32));
+
+    // The reply swaps the addresses, and copies the identifier, the sequence and the payload
+    harness.peer.inject(&ECHO_REQUEST).unwrap();
+    let frame = loop {
+        let frame = harness
+            .peer
+            .collect(COLLECT_TIMEOUT)
+            .expect("collect frame");
+        // Skip announcements of the published address
+        if Indicator::from(&frame).unwrap().get_arp().is_none() {
+            break frame;
+        }
+    };
+    let request = Indicator::from(&ECHO_REQUEST).unwrap();
+    let reply = Indicator::from(&frame).unwrap();
+    let ethernet = reply.get_ethernet().unwrap();
+    assert_eq!(ethernet.get_src(), LOCAL_HARDWARE_ADDR);
+    assert_eq!(ethernet.get_dst(), SOURCE_HARDWARE_ADDR);
+    let ipv4 = reply.get_ipv4().expect("IPv4 frame");
+    assert_eq!(ipv4.get_src(), LOCAL_IP_ADDR);
+    assert_eq!(ipv4.get_dst(), SOURCE_IP_ADDR);
+    let (request, icmpv4) = (request.get_icmpv4().unwrap(), reply.get_icmpv4().unwrap());
+    assert!(icmpv4.is_echo_reply());
+    assert_eq!(icmpv4.get_identifier(), request.get_identifier());
+    assert_eq!(icmpv4.get_sequence(), request.get_sequence());
+    assert_eq!(icmpv4.get_payload(), request.get_payload());
+
+    // Checksums
+    let packet = Ipv4Packet::new(&frame[reply.get_link_size()..]).unwrap();
+    assert_eq!(packet.get_checksum(), ipv4::checksum(&packet));
+    let packet = IcmpPacket::new(packet.payload()).unwrap();
+    assert_eq!(packet.get_checksum(), icmp::checksum(&packet));
+
+    for frame in harness.stop() {
+        assert!(Indicator::from(&frame).unwrap().get_arp().is_some());
+    }
+}
+
+/// Get the counts of connections closed by reasons since the counts before.
+fn get_closes_since(before: &[(CloseReason, u64)]) -> Vec<(CloseReason, u64)> {
+    metrics::get_closes()
+        .into_iter()
+        .map(|(reason, count)| {
+            let prev = before
+                .iter()
+                .find(|(r, _)| *r == reason)
+                .map_or(0, |(_, count)| *count);
+
+            (reason, count - prev)
+        })
+        .filter(|(_, count)| *count > 0)
+        .collect()
+}
+
+#[test]
+fn close_reasons() {
+    let harness = spawn();
+    let peer = &harness.peer;
+    let before = metrics::get_closes();
+
+    // A refused CONNECT resets the source
+    let mut syn = tcp_from_source(TcpFlags::SYN, 6000, 0);
+    if let Layers::Tcp(ref mut tcp) = syn {
+        tcp.layer.destination = REFUSED_PORT;
+    }
+    peer.inject(&from_source(1, syn, &[])).unwrap();
+    let (frame, _) = collect(peer);
+    let indicator = Indicator::from(&frame).unwrap();
+    let tcp = indicator.get_tcp().expect("TCP frame");
+    assert!(tcp.is_rst());
+    assert_eq!(tcp.get_src(), REFUSED_PORT);
+
+    // A clean close by the source
+    let isn = handshake(peer, 7000);
+    let fin = tcp_from_source(TcpFlags::FIN | TcpFlags::ACK, 7001, isn.wrapping_add(1));
+    peer.inject(&from_source(4, fin, &[])).unwrap();
+    collect_tcp_until(peer, |tcp| tcp.get_acknowledgement() == 7002);
+    harness.proxy.release();
+    let segments = collect_tcp_until(peer, |tcp| tcp.is_fin());
+    assert_eq!(segments.last().unwrap().get_acknowledgement(), 7002);
+    let ack = tcp_from_source(TcpFlags::ACK, 7002, isn.wrapping_add(2));
+    peer.inject(&from_source(5, ack, &[])).unwrap();
+    assert_eq!(harness.wait_close(), CloseReason::Fin);
+
+    // An association idle for the UDP timeout is evicted
+    let udp = Udp::new(
+        SOURCE_IP_ADDR.into(),
+        DESTINATION_IP_ADDR.into(),
+        40001,
+        5000,
+    );
+    peer.inject(&from_source(6, Layers::Udp(udp), b"ping"))
+        .unwrap();
+    collect(peer);
+    harness
+        .clock
+        .advance(Duration::from_secs(DEFAULT_UDP_TIMEOUT + 1));
+    assert_eq!(harness.wait_close(), CloseReason::Idle);
+
+    let mut closes = get_closes_since(&before);
+    closes.sort_by_key(|(reason, _)| *reason as usize);
+    assert_eq!(
+        closes,
+        vec![
+            (CloseReason::Fin, 1),
+            (CloseReason::ConnectFailed, 1),
+            (CloseReason::Idle, 1),
+        ]
+    );
+
+    harness.stop_quiet();
+}
+
+#[test]
+fn tcp_lossy_accounting() {
+    let harness = spawn_with(|builder| builder.chaos(Chaos::parse("loss=20%,seed=7").unwrap()));
+    let peer = &harness.peer;
+    let data: Vec<u8> = (0..48 * 1024).map(|i| (i % 251) as u8).collect();
+    for _ in 0..1000 {
+        harness.proxy.release();
+    }
+
+    // The ACK/SYN may be lost, a retransmitted SYN is answered from the same ISN
+    let syn = tcp_from_source(TcpFlags::SYN, 8000, 0);
+    let isn = loop {
+        peer.inject(&from_source(1, syn.clone(), &[])).unwrap();
+        if let Some(frame) = peer.collect(LOSS_TIMEOUT).ok() {
+            let indicator = Indicator::from(&frame).unwrap();
+            let tcp = indicator.get_tcp().expect("TCP frame");
+            assert!(tcp.is_syn() && tcp.is_ack());
+            break tcp.get_sequence();
+        }
+    };
+    let seq = |n: usize| isn.wrapping_add(1 + n as u32);
+    let ack = tcp_from_source(TcpFlags::ACK, 8001, seq(0));
+    peer.inject(&from_source(2, ack, &[])).unwrap();
+
+    // The source sends the data, which is echoed by the proxy through the lossy path
+    for (i, chunk) in data.chunks(LOCAL_MSS as usize).enumerate() {
+        let psh = tcp_from_source(
+            TcpFlags::PSH | TcpFlags::ACK,
+            8001 + (i * LOCAL_MSS as usize) as u32,
+            seq(0),
+        );
+        peer.inject(&from_source(3, psh, chunk)).unwrap();
+    }
+    let next = 8001 + data.len() as u32;
+
+    // The source reassembles the echo and acknowledges every segment, lost segments are
+    // recovered by duplicate acknowledgements, and lost FINs by the timer
+    let mut received = Vec::new();
+    let mut pending = BTreeMap::new();
+    let mut is_fin = false;
+    let mut is_closed = false;
+    let mut id = 4;
+    let mut rounds = 0;
+    while !is_closed {
+        rounds += 1;
+        assert!(
+            rounds < 2000,
+            "stall at {} of {}",
+            received.len(),
+            data.len()
+        );
+        match peer.collect(LOSS_TIMEOUT).ok() {
+            Some(frame) => {
+                let indicator = Indicator::from(&frame).unwrap();
+                let tcp = indicator.get_tcp().expect("TCP frame");
+                let offset = tcp.get_sequence().wrapping_sub(seq(0)) as usize;
+                if !tcp.get_payload().is_empty() && offset >= received.len() {
+                    pending.insert(offset, tcp.get_payload().to_vec());
+                }
+                while let Some(payload) = pending.remove(&received.len()) {
+                    received.extend_from_slice(&payload);
+                }
+                if tcp.is_fin() && offset + tcp.get_payload().len() == received.len() {
+                    is_closed = true;
+                }
+            }
+            None => harness.clock.advance(Duration::from_secs(1)),
+        }
+        let (flags, acknowledgement) = match (is_closed, received.len() == data.len()) {
+            (true, _) => (TcpFlags::ACK, seq(received.len() + 1)),
+            (false, true) if !is_fin => {
+                is_fin = true;
+                (TcpFlags::FIN | TcpFlags::ACK, seq(received.len()))
+            }
+            _ => (TcpFlags::ACK, seq(received.len())),
+        };
+        let sequence = match is_fin && flags == TcpFlags::ACK {
+            true => next + 1,
+            false => next,
+        };
+        let ack = tcp_from_source(flags, sequence, acknowledgement);
+        peer.inject(&from_source(id, ack, &[])).unwrap();
+        id = id.wrapping_add(1);
+    }
+    assert_eq!(received, data);
+
+    // Unique payload is exact whatever the loss, and the loss is paid by retransmissions
+    let (bytes_up, bytes_down, retransmitted_down, wire_down) = loop {
+        match harness
+            .events
+            .recv_timeout(COLLECT_TIMEOUT)
+            .expect("close event")
+        {
+            Event::ConnectionClose {
+                bytes_up,
+                bytes_down,
+                bytes_retransmitted_down,
+                bytes_wire_down,
+                reason,
+                ..
+            } => {
+                assert_eq!(reason, CloseReason::Fin);
+                break (
+                    bytes_up,
+                    bytes_down,
+                    bytes_retransmitted_down,
+                    bytes_wire_down,
+                );
+            }
+            _ => {}
+        }
+    };
+    assert_eq!(bytes_up, data.len() as u64);
+    assert_eq!(bytes_down, data.len() as u64);
+    assert!(retransmitted_down > 0);
+    assert!(wire_down > bytes_down + retransmitted_down);
+
+    harness.stop();
+}
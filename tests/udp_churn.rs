@@ -0,0 +1,144 @@
+//! Churns UDP associations through shared local sockets to a mock SOCKS5 server on localhost,
+//! and asserts the count of file descriptors stays flat, as associations only open their control
+//! connections and route through the shared sockets.
+
+#![cfg(target_os = "linux")]
+
+use pcap2socks::socks::{
+    set_shared_sockets, ProxyConnector, SocksConnector, SocksOption, DEFAULT_SHARED_SOCKETS,
+};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+/// Represents the number of associations churned, which are opened in a minute in the worst
+/// case seen of clients spraying DNS queries.
+const ASSOCIATIONS: usize = 500;
+/// Represents the number of associations open at once.
+const BATCH: usize = 25;
+/// Represents the timeout of an echo or a close.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Represents the destination of datagrams, which is echoed by the relay.
+const DESTINATION: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 53);
+
+/// Serves a UDP ASSOCIATE of the mock SOCKS5 server without authentication, which replies the
+/// relay shared by all associations and holds the control connection until the client closes
+/// it.
+fn serve(mut stream: TcpStream, relay: SocketAddrV4) {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).unwrap();
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).unwrap();
+    stream.write_all(&[5, 0]).unwrap();
+
+    let mut request = [0u8; 10];
+    stream.read_exact(&mut request).unwrap();
+    assert_eq!(request[1], 3);
+    let port = relay.port().to_be_bytes();
+    stream
+        .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, port[0], port[1]])
+        .unwrap();
+
+    let mut buffer = [0u8; 1];
+    while let Ok(n) = stream.read(&mut buffer) {
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+/// Starts the mock SOCKS5 server with a relay echoing datagrams with their SOCKS headers,
+/// returns its address and the receiver of closes of control connections.
+fn mock_socks() -> (SocketAddrV4, Receiver<()>) {
+    let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let relay_addr = match relay.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    thread::spawn(move || {
+        let mut buffer = [0u8; 1500];
+        while let Ok((n, addr)) = relay.recv_from(&mut buffer) {
+            if relay.send_to(&buffer[..n], addr).is_err() {
+                break;
+            }
+        }
+    });
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => break,
+            };
+            let tx = tx.clone();
+            thread::spawn(move || {
+                serve(stream, relay_addr);
+                let _ = tx.send(());
+            });
+        }
+    });
+
+    (addr, rx)
+}
+
+/// Get the count of file descriptors open by the process.
+fn get_fds() -> usize {
+    fs::read_dir("/proc/self/fd").unwrap().count()
+}
+
+/// Opens a batch of associations, echoes a datagram through each, closes them, and waits for
+/// the mock SOCKS5 server to close their control connections.
+fn churn(proxy: &SocksConnector, closes: &Receiver<()>, size: usize) {
+    let mut associations = Vec::with_capacity(size);
+    for _ in 0..size {
+        let datagram = proxy
+            .bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+            .unwrap();
+        assert!(datagram.is_shared());
+        let (tx, rx) = mpsc::channel();
+        datagram
+            .set_handler(Box::new(move |data: &[u8], addr: SocketAddrV4| {
+                let _ = tx.send((data.to_vec(), addr));
+            }))
+            .unwrap();
+        associations.push((datagram, rx));
+    }
+
+    for (i, (datagram, rx)) in associations.iter().enumerate() {
+        let payload = (i as u32).to_be_bytes();
+        datagram.send_to(&payload, DESTINATION).unwrap();
+        let (data, addr) = rx.recv_timeout(TIMEOUT).expect("echo");
+        assert_eq!(data, payload);
+        assert_eq!(addr, DESTINATION);
+    }
+
+    drop(associations);
+    for _ in 0..size {
+        closes.recv_timeout(TIMEOUT).expect("close");
+    }
+}
+
+#[test]
+fn udp_churn() {
+    set_shared_sockets(DEFAULT_SHARED_SOCKETS);
+    let (addr, closes) = mock_socks();
+    let proxy = SocksConnector::new(addr, SocksOption::default());
+
+    // The shared sockets are bound by the first associations, and live as long as the process
+    churn(&proxy, &closes, DEFAULT_SHARED_SOCKETS);
+    let fds = get_fds();
+
+    for _ in 0..ASSOCIATIONS / BATCH {
+        churn(&proxy, &closes, BATCH);
+        assert_eq!(get_fds(), fds);
+    }
+}